@@ -4,6 +4,7 @@
 
 pub mod clock;
 pub mod error;
+pub mod metrics;
 pub mod shutdown;
 pub mod strutil;
 pub mod time;
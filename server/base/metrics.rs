@@ -0,0 +1,161 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Minimal support for metrics in the [Prometheus text exposition
+//! format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//!
+//! This covers just what `/metrics` needs: counters and gauges are plain numbers owned by
+//! whatever they describe, and histograms have fixed buckets.
+
+use std::fmt::{Display, Write as _};
+
+/// Bucket upper bounds, in seconds, suitable for request and flush latencies.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A histogram of observed values.
+///
+/// This isn't internally synchronized; callers keep it behind a lock they already hold.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+
+    /// The count of observations in each bucket, non-cumulatively. There's one more bucket than
+    /// bound, for observations above the largest bound.
+    buckets: Box<[u64]>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len() + 1].into_boxed_slice(),
+            sum: 0.,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let i = self.bounds.partition_point(|&b| b < value);
+        self.buckets[i] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+}
+
+/// Writes metrics in the text exposition format.
+#[derive(Default)]
+pub struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    /// Starts a metric family, which should be followed by all of its samples.
+    ///
+    /// `type_` is `counter`, `gauge`, or `histogram`.
+    pub fn family(&mut self, name: &str, type_: &str, help: &str) {
+        writeln!(&mut self.out, "# HELP {name} {help}").expect("String write is infallible");
+        writeln!(&mut self.out, "# TYPE {name} {type_}").expect("String write is infallible");
+    }
+
+    /// Writes a counter or gauge sample.
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        self.labels(labels, None);
+        writeln!(&mut self.out, " {value}").expect("String write is infallible");
+    }
+
+    /// Writes all the samples of a histogram.
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], h: &Histogram) {
+        let mut cumulative = 0;
+        for (i, &n) in h.buckets.iter().enumerate() {
+            cumulative += n;
+            let le = match h.bounds.get(i) {
+                Some(b) => b.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            write!(&mut self.out, "{name}_bucket").expect("String write is infallible");
+            self.labels(labels, Some(("le", &le)));
+            writeln!(&mut self.out, " {cumulative}").expect("String write is infallible");
+        }
+        write!(&mut self.out, "{name}_sum").expect("String write is infallible");
+        self.labels(labels, None);
+        writeln!(&mut self.out, " {}", h.sum).expect("String write is infallible");
+        write!(&mut self.out, "{name}_count").expect("String write is infallible");
+        self.labels(labels, None);
+        writeln!(&mut self.out, " {cumulative}").expect("String write is infallible");
+    }
+
+    fn labels(&mut self, labels: &[(&str, &str)], extra: Option<(&str, &str)>) {
+        let mut iter = labels.iter().copied().chain(extra).peekable();
+        if iter.peek().is_none() {
+            return;
+        }
+        self.out.push('{');
+        for (i, (k, v)) in iter.enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            self.out.push_str(k);
+            self.out.push_str("=\"");
+            for c in v.chars() {
+                match c {
+                    '\\' => self.out.push_str("\\\\"),
+                    '"' => self.out.push_str("\\\""),
+                    '\n' => self.out.push_str("\\n"),
+                    c => self.out.push(c),
+                }
+            }
+            self.out.push('"');
+        }
+        self.out.push('}');
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let mut h = Histogram::new(&[0.1, 1.0]);
+        h.observe(0.05);
+        h.observe(0.1);
+        h.observe(0.5);
+        h.observe(5.0);
+        assert_eq!(h.count(), 4);
+        let mut e = Encoder::default();
+        e.family("requests", "counter", "Requests served.");
+        e.sample("requests", &[], 3);
+        e.sample("requests", &[("path", "a\"b\\c\n")], 4);
+        e.family("latency_seconds", "histogram", "Latency.");
+        e.histogram("latency_seconds", &[("code", "200")], &h);
+        assert_eq!(
+            e.finish(),
+            "# HELP requests Requests served.\n\
+             # TYPE requests counter\n\
+             requests 3\n\
+             requests{path=\"a\\\"b\\\\c\\n\"} 4\n\
+             # HELP latency_seconds Latency.\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{code=\"200\",le=\"0.1\"} 2\n\
+             latency_seconds_bucket{code=\"200\",le=\"1\"} 3\n\
+             latency_seconds_bucket{code=\"200\",le=\"+Inf\"} 4\n\
+             latency_seconds_sum{code=\"200\"} 5.65\n\
+             latency_seconds_count{code=\"200\"} 4\n"
+        );
+    }
+}
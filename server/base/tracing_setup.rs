@@ -5,6 +5,7 @@
 //! Logic for setting up a `tracing` subscriber according to our preferences
 //! and [OpenTelemetry conventions](https://opentelemetry.io/docs/reference/specification/logs/).
 
+use opentelemetry_otlp::WithExportConfig as _;
 use tracing::error;
 use tracing_core::{Event, Level, Subscriber};
 use tracing_log::NormalizeEvent;
@@ -12,9 +13,41 @@ use tracing_subscriber::{
     fmt::{format::Writer, time::FormatTime, FmtContext, FormatFields, FormattedFields},
     layer::SubscriberExt,
     registry::LookupSpan,
-    Layer,
+    Layer, Registry,
 };
 
+/// The environment variable which enables exporting spans via OTLP/gRPC, as in
+/// the [OpenTelemetry exporter configuration](https://opentelemetry.io/docs/specs/otel/protocol/exporter/).
+const OTLP_ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// The format of log output on stderr.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Output meant for human consumption, with local timestamps.
+    Human,
+
+    /// Output with [sd-daemon logging prefixes](https://man7.org/linux/man-pages/man3/sd-daemon.3.html).
+    Systemd,
+
+    /// One JSON object per line, for machine consumption.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "systemd" => Ok(Format::Systemd),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown log format {s:?}; expected human, systemd, or json"
+            )),
+        }
+    }
+}
+
 struct FormatSystemd;
 
 struct ChronoTimer;
@@ -107,16 +140,66 @@ fn panic_hook(p: &std::panic::PanicInfo) {
     );
 }
 
-pub fn install() {
-    let filter = tracing_subscriber::EnvFilter::builder()
+fn filter() -> tracing_subscriber::EnvFilter {
+    tracing_subscriber::EnvFilter::builder()
         .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
         .with_env_var("MOONFIRE_LOG")
-        .from_env_lossy();
+        .from_env_lossy()
+}
+
+/// Returns a layer which exports spans to an OpenTelemetry collector (such as Jaeger or Tempo),
+/// if one is configured via `OTEL_EXPORTER_OTLP_ENDPOINT`.
+///
+/// Spans are batched and sent from a dedicated thread, so this can be called before the main
+/// tokio runtime is started.
+fn otlp_layer() -> Option<impl Layer<Registry>> {
+    let endpoint = std::env::var(OTLP_ENDPOINT_VAR).ok()?;
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+                "service.name",
+                "moonfire-nvr",
+            )]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread);
+    match tracer {
+        Ok(t) => Some(
+            tracing_opentelemetry::layer()
+                .with_tracer(t)
+                .with_filter(filter()),
+        ),
+        Err(e) => {
+            // The subscriber isn't installed yet, so there's nowhere else to report this.
+            eprintln!("unable to set up OTLP exporter; continuing without it: {e}");
+            None
+        }
+    }
+}
+
+/// Installs the global subscriber.
+///
+/// `format` is as given on the command line; if absent, it's taken from the `MOONFIRE_FORMAT`
+/// environment variable, defaulting to `Format::Human`.
+pub fn install(format: Option<Format>) {
+    let filter = filter();
     tracing_log::LogTracer::init().unwrap();
+    let otlp = otlp_layer();
+    let format = format.unwrap_or_else(|| {
+        std::env::var("MOONFIRE_FORMAT")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(Format::Human)
+    });
 
-    match std::env::var("MOONFIRE_FORMAT") {
-        Ok(s) if s == "systemd" => {
-            let sub = tracing_subscriber::registry().with(
+    match format {
+        Format::Systemd => {
+            let sub = tracing_subscriber::registry().with(otlp).with(
                 tracing_subscriber::fmt::Layer::new()
                     .with_writer(std::io::stderr)
                     .with_ansi(false)
@@ -125,8 +208,8 @@ pub fn install() {
             );
             tracing::subscriber::set_global_default(sub).unwrap();
         }
-        Ok(s) if s == "json" => {
-            let sub = tracing_subscriber::registry().with(
+        Format::Json => {
+            let sub = tracing_subscriber::registry().with(otlp).with(
                 tracing_subscriber::fmt::Layer::new()
                     .with_writer(std::io::stderr)
                     .with_thread_names(true)
@@ -135,8 +218,8 @@ pub fn install() {
             );
             tracing::subscriber::set_global_default(sub).unwrap();
         }
-        _ => {
-            let sub = tracing_subscriber::registry().with(
+        Format::Human => {
+            let sub = tracing_subscriber::registry().with(otlp).with(
                 tracing_subscriber::fmt::Layer::new()
                     .with_writer(std::io::stderr)
                     .with_timer(ChronoTimer)
@@ -155,11 +238,15 @@ pub fn install() {
     }
 }
 
+/// Sends any spans not yet exported via OTLP. This should be called before exiting.
+pub fn shutdown() {
+    if std::env::var_os(OTLP_ENDPOINT_VAR).is_some() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
 pub fn install_for_tests() {
-    let filter = tracing_subscriber::EnvFilter::builder()
-        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
-        .with_env_var("MOONFIRE_LOG")
-        .from_env_lossy();
+    let filter = filter();
     tracing_log::LogTracer::init().unwrap();
     let sub = tracing_subscriber::registry().with(
         tracing_subscriber::fmt::Layer::new()
@@ -170,3 +257,16 @@ pub fn install_for_tests() {
     );
     tracing::subscriber::set_global_default(sub).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert_eq!("systemd".parse::<Format>(), Ok(Format::Systemd));
+        assert_eq!("human".parse::<Format>(), Ok(Format::Human));
+        "JSON".parse::<Format>().unwrap_err();
+    }
+}
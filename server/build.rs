@@ -188,10 +188,24 @@ fn handle_version() -> Result<(), BoxError> {
     Ok(())
 }
 
+/// Generates the gRPC service code if the `grpc` Cargo feature is selected.
+fn handle_grpc() -> Result<(), BoxError> {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/nvr.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .bytes(["."])
+            .compile(&["proto/nvr.proto"], &["proto"])?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), BoxError> {
     // Explicitly declare dependencies, so this doesn't re-run if other source files change.
     println!("cargo:rerun-if-changed=build.rs");
     handle_bundled_ui()?;
     handle_version()?;
+    handle_grpc()?;
     Ok(())
 }
@@ -0,0 +1,303 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Audit log: an append-only record of security-relevant actions.
+//! See the `audit` table within `schema.sql` for more information.
+
+use crate::auth::{FromSqlIpAddr, Request};
+use base::{bail, err, Error};
+use rusqlite::{named_params, Connection};
+use std::net::IpAddr;
+
+/// The kind of an audited action.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// A login attempt, by password, passkey, or single sign-on.
+    Login,
+    Logout,
+
+    /// A change to cameras, streams, sample file directories, or other system configuration.
+    ConfigChange,
+
+    /// A change to users or their credentials: passwords, sessions, API tokens, and passkeys.
+    UserChange,
+
+    /// Creation or deletion of a clip export.
+    Export,
+
+    /// A download of recorded video or of the database.
+    Download,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Login => "login",
+            Action::Logout => "logout",
+            Action::ConfigChange => "config_change",
+            Action::UserChange => "user_change",
+            Action::Export => "export",
+            Action::Download => "download",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "login" => Some(Action::Login),
+            "logout" => Some(Action::Logout),
+            "config_change" => Some(Action::ConfigChange),
+            "user_change" => Some(Action::UserChange),
+            "export" => Some(Action::Export),
+            "download" => Some(Action::Download),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An entry to append to the audit log.
+#[derive(Debug)]
+pub struct NewEntry<'a> {
+    /// The request which performed the action. Its time is required.
+    pub req: &'a Request,
+    pub user_id: Option<i32>,
+    pub username: Option<&'a str>,
+    pub action: Action,
+    pub success: bool,
+    pub detail: Option<&'a str>,
+}
+
+/// An entry read from the audit log.
+#[derive(Debug)]
+pub struct Entry {
+    pub id: i64,
+    pub time_sec: i64,
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub peer_addr: Option<IpAddr>,
+    pub user_agent: Option<Vec<u8>>,
+    pub action: Action,
+    pub success: bool,
+    pub detail: Option<String>,
+}
+
+/// Which entries to return from `list`, newest first.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Inclusive lower bound on `time_sec`.
+    pub start_time_sec: Option<i64>,
+
+    /// Exclusive upper bound on `time_sec`.
+    pub end_time_sec: Option<i64>,
+
+    pub user_id: Option<i32>,
+    pub action: Option<Action>,
+
+    /// Exclusive upper bound on `id`, for paging through results.
+    pub before_id: Option<i64>,
+
+    /// The maximum number of entries to return.
+    pub limit: u32,
+}
+
+/// Appends an entry, returning its id.
+pub(crate) fn insert(conn: &Connection, e: &NewEntry) -> Result<i64, Error> {
+    let Some(time_sec) = e.req.when_sec else {
+        bail!(InvalidArgument, msg("audit log entry must have a time"));
+    };
+    let addr = e.req.addr_buf();
+    let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+    let mut stmt = conn.prepare_cached(
+        r#"
+        insert into audit (time_sec,  user_id,  username,  peer_addr,  user_agent,  action,
+                           success,  detail)
+                   values (:time_sec, :user_id, :username, :peer_addr, :user_agent, :action,
+                           :success, :detail)
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":time_sec": time_sec,
+        ":user_id": e.user_id,
+        ":username": e.username,
+        ":peer_addr": addr,
+        ":user_agent": &e.req.user_agent,
+        ":action": e.action.as_str(),
+        ":success": e.success,
+        ":detail": e.detail,
+    })?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Lists entries matching `filter`, newest first.
+pub(crate) fn list(conn: &Connection, filter: &Filter) -> Result<Vec<Entry>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+            id,
+            time_sec,
+            user_id,
+            username,
+            peer_addr,
+            user_agent,
+            action,
+            success,
+            detail
+        from
+            audit
+        where
+            (:start_time_sec is null or time_sec >= :start_time_sec)
+            and (:end_time_sec is null or time_sec < :end_time_sec)
+            and (:user_id is null or user_id = :user_id)
+            and (:action is null or action = :action)
+            and (:before_id is null or id < :before_id)
+        order by
+            id desc
+        limit :limit
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":start_time_sec": filter.start_time_sec,
+        ":end_time_sec": filter.end_time_sec,
+        ":user_id": filter.user_id,
+        ":action": filter.action.map(Action::as_str),
+        ":before_id": filter.before_id,
+        ":limit": filter.limit,
+    })?;
+    let mut entries = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id = row.get(0)?;
+        let action: String = row.get(6)?;
+        let peer_addr: FromSqlIpAddr = row.get(4)?;
+        entries.push(Entry {
+            id,
+            time_sec: row.get(1)?,
+            user_id: row.get(2)?,
+            username: row.get(3)?,
+            peer_addr: peer_addr.0,
+            user_agent: row.get(5)?,
+            action: Action::parse(&action).ok_or_else(|| {
+                err!(
+                    DataLoss,
+                    msg("audit entry {id} has unknown action {action:?}")
+                )
+            })?,
+            success: row.get(7)?,
+            detail: row.get(8)?,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::testutil;
+    use rusqlite::params;
+
+    #[test]
+    fn insert_and_list() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: Some(IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))),
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let failed = insert(
+            &conn,
+            &NewEntry {
+                req: &req,
+                user_id: None,
+                username: Some("slamb"),
+                action: Action::Login,
+                success: false,
+                detail: Some("incorrect password"),
+            },
+        )
+        .unwrap();
+        let later = Request {
+            when_sec: Some(43),
+            ..req.clone()
+        };
+        let succeeded = insert(
+            &conn,
+            &NewEntry {
+                req: &later,
+                user_id: Some(1),
+                username: Some("slamb"),
+                action: Action::Login,
+                success: true,
+                detail: None,
+            },
+        )
+        .unwrap();
+        insert(
+            &conn,
+            &NewEntry {
+                req: &later,
+                user_id: Some(1),
+                username: Some("slamb"),
+                action: Action::ConfigChange,
+                success: true,
+                detail: Some("POST /api/cameras/"),
+            },
+        )
+        .unwrap();
+
+        let logins = list(
+            &conn,
+            &Filter {
+                action: Some(Action::Login),
+                limit: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            logins.iter().map(|e| e.id).collect::<Vec<_>>(),
+            [succeeded, failed]
+        );
+        assert!(!logins[1].success);
+        assert_eq!(logins[1].user_id, None);
+        assert_eq!(logins[1].username.as_deref(), Some("slamb"));
+        assert_eq!(logins[1].peer_addr, req.addr);
+        assert_eq!(logins[1].user_agent.as_deref(), Some(&b"some ua"[..]));
+        assert_eq!(logins[1].detail.as_deref(), Some("incorrect password"));
+
+        let page = list(
+            &conn,
+            &Filter {
+                start_time_sec: Some(43),
+                limit: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].action, Action::ConfigChange);
+        let page = list(
+            &conn,
+            &Filter {
+                start_time_sec: Some(43),
+                before_id: Some(page[0].id),
+                limit: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), [succeeded]);
+
+        // Existing entries can't be modified or removed.
+        conn.execute("update audit set success = 1", params![])
+            .unwrap_err();
+        conn.execute("delete from audit", params![]).unwrap_err();
+    }
+}
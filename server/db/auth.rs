@@ -86,6 +86,11 @@ impl User {
         self.password_hash.is_some()
     }
 
+    /// Returns the stored password hash, in PHC string format, as for a configuration export.
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
     /// Checks if the user's password hash matches the supplied password.
     ///
     /// As a side effect, increments `password_failure_count` and sets `dirty`
@@ -120,6 +125,93 @@ impl User {
     }
 }
 
+impl Permissions {
+    /// Returns true if the camera-specific permissions apply to the given camera.
+    ///
+    /// This doesn't check any particular permission; callers should check both.
+    pub fn allows_camera(&self, uuid: uuid::Uuid) -> bool {
+        self.cameras.is_empty() || self.cameras.iter().any(|c| c[..] == uuid.as_bytes()[..])
+    }
+
+    /// Returns true if `update_signals` applies to the given signal.
+    ///
+    /// This doesn't check `update_signals` itself; callers should check both.
+    pub fn allows_signal(&self, id: u32) -> bool {
+        self.signals.is_empty() || self.signals.contains(&id)
+    }
+
+    /// Returns true if everything `self` permits is also permitted by `other`.
+    pub fn is_subset_of(&self, other: &Permissions) -> bool {
+        (!self.view_video || other.view_video)
+            && (!self.read_camera_configs || other.read_camera_configs)
+            && (!self.update_signals || other.update_signals)
+            && (!self.admin_users || other.admin_users)
+            && (!self.ptz || other.ptz)
+            && (!self.update_camera_configs || other.update_camera_configs)
+            && (!self.talk || other.talk)
+            && (other.cameras.is_empty()
+                || (!self.cameras.is_empty()
+                    && self.cameras.iter().all(|c| other.cameras.contains(c))))
+            && (!self.update_signals
+                || other.signals.is_empty()
+                || (!self.signals.is_empty()
+                    && self.signals.iter().all(|s| other.signals.contains(s))))
+    }
+
+    /// Adds everything `other` permits to `self`.
+    ///
+    /// There's a single camera list for all camera-specific permissions, so the result may
+    /// permit more than either alone. E.g., combining `view_video` on camera A with `ptz` on
+    /// camera B permits both on both cameras.
+    pub fn union_with(&mut self, other: &Permissions) {
+        let self_cameras = self.has_camera_permissions();
+        let other_cameras = other.has_camera_permissions();
+        if (self_cameras && self.cameras.is_empty()) || (other_cameras && other.cameras.is_empty())
+        {
+            self.cameras.clear();
+        } else if other_cameras {
+            if !self_cameras {
+                self.cameras.clear();
+            }
+            for c in &other.cameras {
+                if !self.cameras.contains(c) {
+                    self.cameras.push(c.clone());
+                }
+            }
+        }
+        if (self.update_signals && self.signals.is_empty())
+            || (other.update_signals && other.signals.is_empty())
+        {
+            self.signals.clear();
+        } else if other.update_signals {
+            if !self.update_signals {
+                self.signals.clear();
+            }
+            for s in &other.signals {
+                if !self.signals.contains(s) {
+                    self.signals.push(*s);
+                }
+            }
+        }
+        self.view_video |= other.view_video;
+        self.read_camera_configs |= other.read_camera_configs;
+        self.update_signals |= other.update_signals;
+        self.admin_users |= other.admin_users;
+        self.ptz |= other.ptz;
+        self.update_camera_configs |= other.update_camera_configs;
+        self.talk |= other.talk;
+    }
+
+    /// Returns true if any of the permissions limited by `cameras` are granted.
+    fn has_camera_permissions(&self) -> bool {
+        self.view_video
+            || self.read_camera_configs
+            || self.update_camera_configs
+            || self.ptz
+            || self.talk
+    }
+}
+
 /// A change to a user.
 ///
 ///    * an insertion returned via `UserChange::add_user`.
@@ -158,6 +250,14 @@ impl UserChange {
     pub fn clear_password(&mut self) {
         self.set_password_hash = Some(None);
     }
+
+    /// Sets a password hash as returned by [`User::password_hash`], as for a configuration import.
+    pub fn set_password_hash(&mut self, hash: String) -> Result<(), Error> {
+        PasswordHash::new(&hash)
+            .map_err(|e| err!(InvalidArgument, msg("bad password hash"), source(e)))?;
+        self.set_password_hash = Some(Some(hash));
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -168,7 +268,7 @@ pub struct Request {
 }
 
 impl Request {
-    fn addr_buf(&self) -> Option<IpAddrBuf> {
+    pub(crate) fn addr_buf(&self) -> Option<IpAddrBuf> {
         match self.addr {
             None => None,
             Some(IpAddr::V4(ref a)) => Some(IpAddrBuf::V4(a.octets())),
@@ -177,7 +277,7 @@ impl Request {
     }
 }
 
-enum IpAddrBuf {
+pub(crate) enum IpAddrBuf {
     V4([u8; 4]),
     V6([u8; 16]),
 }
@@ -191,7 +291,7 @@ impl AsRef<[u8]> for IpAddrBuf {
     }
 }
 
-pub struct FromSqlIpAddr(Option<IpAddr>);
+pub struct FromSqlIpAddr(pub(crate) Option<IpAddr>);
 
 impl rusqlite::types::FromSql for FromSqlIpAddr {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
@@ -243,6 +343,7 @@ impl FromStr for SessionFlag {
 pub enum RevocationReason {
     LoggedOut = 1,
     AlgorithmChange = 2,
+    Revoked = 3,
 }
 
 #[allow(dead_code)] // Some of these fields are currently only used in Debug. That's fine.
@@ -251,11 +352,11 @@ pub struct Session {
     pub user_id: i32,
     flags: i32, // bitmask of SessionFlag enum values
     domain: Option<Vec<u8>>,
-    description: Option<String>,
+    pub description: Option<String>,
     seed: Seed,
 
     creation_password_id: Option<i32>,
-    creation: Request,
+    pub creation: Request,
 
     revocation: Request,
     revocation_reason: Option<i32>, // see RevocationReason enum
@@ -263,8 +364,8 @@ pub struct Session {
 
     pub permissions: Permissions,
 
-    last_use: Request,
-    use_count: i32,
+    pub last_use: Request,
+    pub use_count: i32,
     dirty: bool,
 }
 
@@ -358,6 +459,70 @@ impl fmt::Debug for SessionHash {
     }
 }
 
+/// A raw API token (not base64-encoded). Sensitive. Never stored in the database.
+#[derive(Copy, Clone)]
+pub struct RawApiToken([u8; 32]);
+
+impl RawApiToken {
+    pub fn decode_base64(input: &[u8]) -> Result<Self, Error> {
+        let mut t = RawApiToken([0u8; 32]);
+        let l = STANDARD_NO_PAD
+            .decode_slice(input, &mut t.0[..])
+            .map_err(|e| err!(InvalidArgument, msg("bad API token"), source(e)))?;
+        if l != 32 {
+            bail!(InvalidArgument, msg("API token must be 32 bytes"));
+        }
+        Ok(t)
+    }
+
+    pub fn encode_base64(&self) -> String {
+        STANDARD_NO_PAD.encode(self.0)
+    }
+
+    pub fn hash(&self) -> SessionHash {
+        let r = blake3::hash(&self.0[..]);
+        let mut h = SessionHash([0u8; 24]);
+        h.0.copy_from_slice(&r.as_bytes()[0..24]);
+        h
+    }
+}
+
+impl fmt::Debug for RawApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "RawApiToken(\"{}\")", &strutil::hex(&self.0[..]))
+    }
+}
+
+/// A long-lived API token, presented via the HTTP `Authorization: Bearer` header.
+#[derive(Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    hash: SessionHash,
+    pub user_id: i32,
+    pub description: Option<String>,
+    pub creation_time_sec: i64,
+    pub expiration_time_sec: Option<i64>,
+    pub permissions: Permissions,
+    pub last_use: Request,
+    pub use_count: i32,
+    dirty: bool,
+}
+
+/// A WebAuthn credential (passkey) with which a user can log in.
+///
+/// The database layer treats the credential's key material as opaque JSON; verifying logins is
+/// up to the caller.
+#[derive(Debug)]
+pub struct Credential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    pub description: Option<String>,
+    pub creation_time_sec: i64,
+    pub last_use_time_sec: Option<i64>,
+    pub passkey: String,
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 struct Seed([u8; 32]);
 
@@ -376,6 +541,109 @@ impl rusqlite::types::FromSql for Seed {
     }
 }
 
+/// Password login failures tolerated from a single user or address before throttling.
+///
+/// Addresses get more leeway, as several users may share one behind NAT.
+const FREE_FAILURES_PER_USER: u32 = 5;
+const FREE_FAILURES_PER_ADDR: u32 = 20;
+
+/// The longest delay imposed after consecutive failures; beyond this, attempts are locked out
+/// for this long after each further failure.
+const MAX_LOGIN_DELAY_SEC: i64 = 15 * 60;
+
+/// How long after the most recent failure a username or address's failures are forgotten.
+const LOGIN_FAILURE_EXPIRY_SEC: i64 = 60 * 60;
+
+/// The maximum number of usernames or addresses tracked. Beyond this, the least recently failed
+/// entry is evicted to make room for a new one.
+const MAX_LOGIN_FAILURE_ENTRIES: usize = 4096;
+
+#[derive(Copy, Clone, Debug)]
+struct LoginFailures {
+    count: u32,
+    last_sec: i64,
+}
+
+impl LoginFailures {
+    /// Returns the time before which another attempt is refused.
+    fn blocked_until(&self, free: u32, now_sec: i64) -> Option<i64> {
+        if self.count < free || now_sec - self.last_sec >= LOGIN_FAILURE_EXPIRY_SEC {
+            return None;
+        }
+        let exp = std::cmp::min(self.count - free, 30);
+        let until = self.last_sec + std::cmp::min(1i64 << exp, MAX_LOGIN_DELAY_SEC);
+        (until > now_sec).then_some(until)
+    }
+}
+
+/// Throttles password logins by username and by client address, with exponential backoff.
+///
+/// This is kept only in RAM; restarting the server forgets it. The persistent
+/// `password_failure_count` is unaffected.
+#[derive(Default)]
+struct LoginThrottle {
+    by_username: FastHashMap<String, LoginFailures>,
+    by_addr: FastHashMap<IpAddr, LoginFailures>,
+}
+
+impl LoginThrottle {
+    fn blocked_until(&self, addr: Option<IpAddr>, username: &str, now_sec: i64) -> Option<i64> {
+        let u = self
+            .by_username
+            .get(username)
+            .and_then(|f| f.blocked_until(FREE_FAILURES_PER_USER, now_sec));
+        let a = addr
+            .and_then(|a| self.by_addr.get(&a))
+            .and_then(|f| f.blocked_until(FREE_FAILURES_PER_ADDR, now_sec));
+        std::cmp::max(u, a)
+    }
+
+    /// Records a failed login from `addr`, for `username` if it names an existing user.
+    ///
+    /// Failures for nonexistent usernames are tracked only by address, so that spraying bogus
+    /// usernames can't crowd real ones out of the table.
+    fn record_failure(&mut self, addr: Option<IpAddr>, username: Option<&str>, now_sec: i64) {
+        fn bump<K: Clone + std::hash::Hash + Eq>(
+            m: &mut FastHashMap<K, LoginFailures>,
+            k: K,
+            now_sec: i64,
+        ) {
+            if m.len() >= MAX_LOGIN_FAILURE_ENTRIES && !m.contains_key(&k) {
+                m.retain(|_, f| now_sec - f.last_sec < LOGIN_FAILURE_EXPIRY_SEC);
+                if m.len() >= MAX_LOGIN_FAILURE_ENTRIES {
+                    let oldest = m
+                        .iter()
+                        .min_by_key(|(_, f)| f.last_sec)
+                        .map(|(k, _)| k.clone())
+                        .expect("table is full");
+                    m.remove(&oldest);
+                }
+            }
+            let f = m.entry(k).or_insert(LoginFailures {
+                count: 0,
+                last_sec: now_sec,
+            });
+            if now_sec - f.last_sec >= LOGIN_FAILURE_EXPIRY_SEC {
+                f.count = 0;
+            }
+            f.count += 1;
+            f.last_sec = now_sec;
+        }
+        if let Some(u) = username {
+            bump(&mut self.by_username, u.to_owned(), now_sec);
+        }
+        if let Some(a) = addr {
+            bump(&mut self.by_addr, a, now_sec);
+        }
+    }
+
+    /// Forgets the user's failures on a successful login. The address's failures are kept, so
+    /// that an attacker who knows one account's password can't use it to reset their budget.
+    fn record_success(&mut self, username: &str) {
+        self.by_username.remove(username);
+    }
+}
+
 pub(crate) struct State {
     users_by_id: BTreeMap<i32, User>,
     users_by_name: BTreeMap<String, i32>,
@@ -389,6 +657,15 @@ pub(crate) struct State {
     /// (and accept more frequent database accesses).
     sessions: FastHashMap<SessionHash, Session>,
 
+    /// All API tokens. Unlike sessions, there are few enough of these to keep them all in RAM.
+    api_tokens_by_id: BTreeMap<i32, ApiToken>,
+    api_token_ids_by_hash: FastHashMap<SessionHash, i32>,
+
+    /// All WebAuthn credentials.
+    credentials_by_id: BTreeMap<i32, Credential>,
+
+    login_throttle: LoginThrottle,
+
     rand: SystemRandom,
 }
 
@@ -398,6 +675,10 @@ impl State {
             users_by_id: BTreeMap::new(),
             users_by_name: BTreeMap::new(),
             sessions: FastHashMap::default(),
+            api_tokens_by_id: BTreeMap::new(),
+            api_token_ids_by_hash: FastHashMap::default(),
+            credentials_by_id: BTreeMap::new(),
+            login_throttle: LoginThrottle::default(),
             rand: ring::rand::SystemRandom::new(),
         };
         let mut stmt = conn.prepare(
@@ -437,6 +718,85 @@ impl State {
             );
             state.users_by_name.insert(name, id);
         }
+        let mut stmt = conn.prepare(
+            r#"
+            select
+                id,
+                token_hash,
+                user_id,
+                description,
+                creation_time_sec,
+                expiration_time_sec,
+                last_use_time_sec,
+                last_use_user_agent,
+                last_use_peer_addr,
+                use_count,
+                permissions
+            from
+                api_token
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let mut hash = SessionHash([0u8; 24]);
+            hash.0.copy_from_slice(row.get_ref(1)?.as_blob()?);
+            let last_use_addr: FromSqlIpAddr = row.get(8)?;
+            let mut permissions = Permissions::new();
+            permissions
+                .merge_from_bytes(row.get_ref(10)?.as_blob()?)
+                .err_kind(ErrorKind::DataLoss)?;
+            state.api_token_ids_by_hash.insert(hash, id);
+            state.api_tokens_by_id.insert(
+                id,
+                ApiToken {
+                    id,
+                    hash,
+                    user_id: row.get(2)?,
+                    description: row.get(3)?,
+                    creation_time_sec: row.get(4)?,
+                    expiration_time_sec: row.get(5)?,
+                    last_use: Request {
+                        when_sec: row.get(6)?,
+                        user_agent: row.get(7)?,
+                        addr: last_use_addr.0,
+                    },
+                    use_count: row.get(9)?,
+                    dirty: false,
+                    permissions,
+                },
+            );
+        }
+        let mut stmt = conn.prepare(
+            r#"
+            select
+                id,
+                user_id,
+                credential_id,
+                description,
+                creation_time_sec,
+                last_use_time_sec,
+                passkey
+            from
+                user_credential
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            state.credentials_by_id.insert(
+                id,
+                Credential {
+                    id,
+                    user_id: row.get(1)?,
+                    credential_id: row.get(2)?,
+                    description: row.get(3)?,
+                    creation_time_sec: row.get(4)?,
+                    last_use_time_sec: row.get(5)?,
+                    passkey: row.get(6)?,
+                },
+            );
+        }
         Ok(state)
     }
 
@@ -559,6 +919,8 @@ impl State {
     pub fn delete_user(&mut self, conn: &mut Connection, id: i32) -> Result<(), base::Error> {
         let tx = conn.transaction()?;
         tx.execute("delete from user_session where user_id = ?", params![id])?;
+        tx.execute("delete from api_token where user_id = ?", params![id])?;
+        tx.execute("delete from user_credential where user_id = ?", params![id])?;
         {
             let mut user_stmt = tx.prepare_cached("delete from user where id = ?")?;
             if user_stmt.execute(params![id])? != 1 {
@@ -571,6 +933,15 @@ impl State {
             .remove(&name)
             .expect("users_by_name should be consistent with users_by_id");
         self.sessions.retain(|_k, ref mut v| v.user_id != id);
+        let api_tokens_by_id = &mut self.api_tokens_by_id;
+        self.api_token_ids_by_hash.retain(|_k, t| {
+            let keep = api_tokens_by_id[t].user_id != id;
+            if !keep {
+                api_tokens_by_id.remove(t);
+            }
+            keep
+        });
+        self.credentials_by_id.retain(|_k, c| c.user_id != id);
         Ok(())
     }
 
@@ -582,6 +953,21 @@ impl State {
         })
     }
 
+    /// Returns the time before which password logins for the given username or from the given
+    /// address are refused due to repeated failures, if any.
+    pub fn login_blocked_until(
+        &self,
+        addr: Option<IpAddr>,
+        username: &str,
+        now_sec: i64,
+    ) -> Option<i64> {
+        self.login_throttle.blocked_until(addr, username, now_sec)
+    }
+
+    /// Logs in by password, creating a session.
+    ///
+    /// Fails with `ResourceExhausted` if there have been too many recent failures; see
+    /// `login_blocked_until`.
     pub fn login_by_password(
         &mut self,
         conn: &Connection,
@@ -591,20 +977,37 @@ impl State {
         domain: Option<Vec<u8>>,
         session_flags: i32,
     ) -> Result<(RawSessionId, &Session), base::Error> {
-        let id = self
-            .users_by_name
-            .get(username)
-            .ok_or_else(|| err!(Unauthenticated, msg("no such user {username:?}")))?;
-        let u = self
-            .users_by_id
-            .get_mut(id)
-            .expect("users_by_name implies users_by_id");
-        if u.config.disabled {
-            bail!(Unauthenticated, msg("user {username:?} is disabled"));
+        if let Some(now_sec) = req.when_sec {
+            if let Some(until) = self
+                .login_throttle
+                .blocked_until(req.addr, username, now_sec)
+            {
+                bail!(
+                    ResourceExhausted,
+                    msg(
+                        "too many failed logins; try again in {} seconds",
+                        until - now_sec
+                    )
+                );
+            }
         }
-        if !u.check_password(Some(&password))? {
-            bail!(Unauthenticated, msg("incorrect password"));
+        let result = self.check_password(username, &password);
+        if let Some(now_sec) = req.when_sec {
+            match result {
+                Ok(_) => self.login_throttle.record_success(username),
+                Err(ref e) if e.kind() == ErrorKind::Unauthenticated => {
+                    let known = self.users_by_name.contains_key(username);
+                    self.login_throttle
+                        .record_failure(req.addr, known.then_some(username), now_sec)
+                }
+                Err(_) => {}
+            }
         }
+        let id = result?;
+        let u = self
+            .users_by_id
+            .get_mut(&id)
+            .expect("check_password returns valid ids");
         let password_id = u.password_id;
         State::make_session_int(
             &self.rand,
@@ -619,6 +1022,25 @@ impl State {
         )
     }
 
+    /// Checks the given user's password, returning their id.
+    fn check_password(&mut self, username: &str, password: &str) -> Result<i32, base::Error> {
+        let id = self
+            .users_by_name
+            .get(username)
+            .ok_or_else(|| err!(Unauthenticated, msg("no such user {username:?}")))?;
+        let u = self
+            .users_by_id
+            .get_mut(id)
+            .expect("users_by_name implies users_by_id");
+        if u.config.disabled {
+            bail!(Unauthenticated, msg("user {username:?} is disabled"));
+        }
+        if !u.check_password(Some(password))? {
+            bail!(Unauthenticated, msg("incorrect password"));
+        }
+        Ok(u.id)
+    }
+
     /// Makes a session directly (no password required).
     pub fn make_session<'s>(
         &'s mut self,
@@ -791,6 +1213,277 @@ impl State {
         Ok(())
     }
 
+    /// Returns the given user's unrevoked sessions, oldest first.
+    ///
+    /// Last-use information reflects requests not yet flushed to the database.
+    pub fn user_sessions(
+        &mut self,
+        conn: &Connection,
+        user_id: i32,
+    ) -> Result<Vec<(SessionHash, &Session)>, Error> {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            select
+                session_id_hash
+            from
+                user_session
+            where
+                user_id = ?
+                and revocation_reason is null
+            order by
+                creation_time_sec
+            "#,
+        )?;
+        let mut rows = stmt.query(params![user_id])?;
+        let mut hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let mut hash = SessionHash([0u8; 24]);
+            hash.0.copy_from_slice(row.get_ref(0)?.as_blob()?);
+            hashes.push(hash);
+        }
+        for hash in &hashes {
+            if let ::std::collections::hash_map::Entry::Vacant(e) = self.sessions.entry(*hash) {
+                e.insert(lookup_session(conn, hash)?);
+            }
+        }
+        Ok(hashes
+            .into_iter()
+            .map(|hash| (hash, &self.sessions[&hash]))
+            .collect())
+    }
+
+    /// Returns the number of unrevoked sessions of all users.
+    pub fn session_count(&self, conn: &Connection) -> Result<i64, Error> {
+        let mut stmt = conn
+            .prepare_cached("select count(*) from user_session where revocation_reason is null")?;
+        Ok(stmt.query_row(params![], |row| row.get(0))?)
+    }
+
+    pub fn api_tokens_by_id(&self) -> &BTreeMap<i32, ApiToken> {
+        &self.api_tokens_by_id
+    }
+
+    /// Makes an API token for the given user.
+    ///
+    /// The caller is responsible for checking `permissions` are appropriate for this user.
+    pub fn make_api_token(
+        &mut self,
+        conn: &Connection,
+        uid: i32,
+        description: Option<String>,
+        creation_time_sec: i64,
+        expiration_time_sec: Option<i64>,
+        permissions: Permissions,
+    ) -> Result<(RawApiToken, &ApiToken), base::Error> {
+        let u = self
+            .users_by_id
+            .get(&uid)
+            .ok_or_else(|| err!(NotFound, msg("no such uid {uid:?}")))?;
+        if u.config.disabled {
+            bail!(FailedPrecondition, msg("user is disabled"));
+        }
+        if matches!(expiration_time_sec, Some(e) if e <= creation_time_sec) {
+            bail!(InvalidArgument, msg("expiration must be in the future"));
+        }
+        let mut token = RawApiToken([0u8; 32]);
+        self.rand.fill(&mut token.0).unwrap();
+        let hash = token.hash();
+        let mut stmt = conn.prepare_cached(
+            r#"
+            insert into api_token (token_hash,  user_id,  description,  creation_time_sec,
+                                   expiration_time_sec,  permissions)
+                           values (:token_hash, :user_id, :description, :creation_time_sec,
+                                   :expiration_time_sec, :permissions)
+            "#,
+        )?;
+        let permissions_blob = permissions
+            .write_to_bytes()
+            .expect("proto3->vec is infallible");
+        stmt.execute(named_params! {
+            ":token_hash": &hash.0[..],
+            ":user_id": &uid,
+            ":description": &description,
+            ":creation_time_sec": &creation_time_sec,
+            ":expiration_time_sec": &expiration_time_sec,
+            ":permissions": &permissions_blob,
+        })?;
+        let id = conn.last_insert_rowid() as i32;
+        self.api_token_ids_by_hash.insert(hash, id);
+        let t = match self.api_tokens_by_id.entry(id) {
+            ::std::collections::btree_map::Entry::Occupied(_) => panic!("duplicate token id!"),
+            ::std::collections::btree_map::Entry::Vacant(e) => e.insert(ApiToken {
+                id,
+                hash,
+                user_id: uid,
+                description,
+                creation_time_sec,
+                expiration_time_sec,
+                permissions,
+                last_use: Request::default(),
+                use_count: 0,
+                dirty: false,
+            }),
+        };
+        Ok((token, t))
+    }
+
+    pub fn authenticate_api_token(
+        &mut self,
+        req: Request,
+        token: &RawApiToken,
+    ) -> Result<(&ApiToken, &User), base::Error> {
+        let id = self
+            .api_token_ids_by_hash
+            .get(&token.hash())
+            .ok_or_else(|| err!(Unauthenticated, msg("no such API token")))?;
+        let t = self
+            .api_tokens_by_id
+            .get_mut(id)
+            .expect("api_token_ids_by_hash implies api_tokens_by_id");
+        let u = match self.users_by_id.get(&t.user_id) {
+            None => bail!(Internal, msg("API token references nonexistent user!")),
+            Some(u) => u,
+        };
+        if let (Some(e), Some(now)) = (t.expiration_time_sec, req.when_sec) {
+            if now >= e {
+                bail!(Unauthenticated, msg("API token {} expired at {e}", t.id));
+            }
+        }
+        if u.config.disabled {
+            bail!(Unauthenticated, msg("user {:?} is disabled", &u.username));
+        }
+        t.last_use = req;
+        t.use_count += 1;
+        t.dirty = true;
+        Ok((t, u))
+    }
+
+    pub fn delete_api_token(&mut self, conn: &Connection, id: i32) -> Result<(), base::Error> {
+        if conn.execute("delete from api_token where id = ?", params![id])? != 1 {
+            bail!(NotFound, msg("API token {id} not found"));
+        }
+        let t = self
+            .api_tokens_by_id
+            .remove(&id)
+            .expect("api_token row implies api_tokens_by_id");
+        self.api_token_ids_by_hash.remove(&t.hash);
+        Ok(())
+    }
+
+    pub fn credentials_by_id(&self) -> &BTreeMap<i32, Credential> {
+        &self.credentials_by_id
+    }
+
+    /// Adds a WebAuthn credential which the caller has verified belongs to the given user.
+    pub fn add_credential(
+        &mut self,
+        conn: &Connection,
+        uid: i32,
+        credential_id: Vec<u8>,
+        description: Option<String>,
+        creation_time_sec: i64,
+        passkey: String,
+    ) -> Result<&Credential, base::Error> {
+        if !self.users_by_id.contains_key(&uid) {
+            bail!(NotFound, msg("no such uid {uid:?}"));
+        }
+        let mut stmt = conn.prepare_cached(
+            r#"
+            insert into user_credential (user_id,  credential_id,  description,
+                                         creation_time_sec,  passkey)
+                                 values (:user_id, :credential_id, :description,
+                                         :creation_time_sec, :passkey)
+            "#,
+        )?;
+        stmt.execute(named_params! {
+            ":user_id": &uid,
+            ":credential_id": &credential_id,
+            ":description": &description,
+            ":creation_time_sec": &creation_time_sec,
+            ":passkey": &passkey,
+        })
+        .map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(ref f, _)
+                if f.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                err!(AlreadyExists, msg("credential is already registered"))
+            }
+            e => e.into(),
+        })?;
+        let id = conn.last_insert_rowid() as i32;
+        Ok(self.credentials_by_id.entry(id).or_insert(Credential {
+            id,
+            user_id: uid,
+            credential_id,
+            description,
+            creation_time_sec,
+            last_use_time_sec: None,
+            passkey,
+        }))
+    }
+
+    /// Logs in via a WebAuthn credential which the caller has verified.
+    ///
+    /// `passkey` is the credential's updated state, such as its signature counter.
+    pub fn login_by_credential(
+        &mut self,
+        conn: &Connection,
+        req: Request,
+        id: i32,
+        passkey: String,
+        domain: Option<Vec<u8>>,
+        session_flags: i32,
+    ) -> Result<(RawSessionId, &Session), base::Error> {
+        let c = self
+            .credentials_by_id
+            .get_mut(&id)
+            .ok_or_else(|| err!(Unauthenticated, msg("no such credential {id}")))?;
+        let u = self
+            .users_by_id
+            .get_mut(&c.user_id)
+            .ok_or_else(|| err!(Internal, msg("credential references nonexistent user!")))?;
+        if u.config.disabled {
+            bail!(Unauthenticated, msg("user {:?} is disabled", &u.username));
+        }
+        conn.prepare_cached(
+            r#"
+            update user_credential
+            set
+                last_use_time_sec = :last_use_time_sec,
+                passkey = :passkey
+            where
+                id = :id
+            "#,
+        )?
+        .execute(named_params! {
+            ":last_use_time_sec": &req.when_sec,
+            ":passkey": &passkey,
+            ":id": &id,
+        })?;
+        c.last_use_time_sec = req.when_sec;
+        c.passkey = passkey;
+        let permissions = u.permissions.clone();
+        State::make_session_int(
+            &self.rand,
+            conn,
+            req,
+            u,
+            domain,
+            None,
+            session_flags,
+            &mut self.sessions,
+            permissions,
+        )
+    }
+
+    pub fn delete_credential(&mut self, conn: &Connection, id: i32) -> Result<(), base::Error> {
+        if conn.execute("delete from user_credential where id = ?", params![id])? != 1 {
+            bail!(NotFound, msg("credential {id} not found"));
+        }
+        self.credentials_by_id.remove(&id);
+        Ok(())
+    }
+
     /// Flushes all pending database changes to the given transaction.
     ///
     /// The caller is expected to call `post_flush` afterward if the transaction is
@@ -847,6 +1540,32 @@ impl State {
             })?;
             debug_assert_eq!(cnt, 1);
         }
+        let mut t_stmt = tx.prepare(
+            r#"
+            update api_token
+            set
+                last_use_time_sec = :last_use_time_sec,
+                last_use_user_agent = :last_use_user_agent,
+                last_use_peer_addr = :last_use_peer_addr,
+                use_count = :use_count
+            where
+                id = :id
+            "#,
+        )?;
+        for (&id, t) in &self.api_tokens_by_id {
+            if !t.dirty {
+                continue;
+            }
+            let addr = t.last_use.addr_buf();
+            let addr: Option<&[u8]> = addr.as_ref().map(|a| a.as_ref());
+            t_stmt.execute(named_params! {
+                ":last_use_time_sec": &t.last_use.when_sec,
+                ":last_use_user_agent": &t.last_use.user_agent,
+                ":last_use_peer_addr": &addr,
+                ":use_count": &t.use_count,
+                ":id": &id,
+            })?;
+        }
         Ok(())
     }
 
@@ -860,6 +1579,9 @@ impl State {
         for s in self.sessions.values_mut() {
             s.dirty = false;
         }
+        for t in self.api_tokens_by_id.values_mut() {
+            t.dirty = false;
+        }
     }
 }
 
@@ -1137,6 +1859,59 @@ mod tests {
         assert_eq!(e.msg().unwrap(), "session is no longer valid (reason=1)");
     }
 
+    #[test]
+    fn user_sessions() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let req = Request {
+            when_sec: Some(42),
+            addr: Some(::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(
+                127, 0, 0, 1,
+            ))),
+            user_agent: Some(b"some ua".to_vec()),
+        };
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.set_password("hunter2".to_owned());
+            state.apply(&conn, c).unwrap().id
+        };
+        let login = |state: &mut State| {
+            state
+                .login_by_password(&conn, req.clone(), "slamb", "hunter2".to_owned(), None, 0)
+                .unwrap()
+                .0
+                .hash()
+        };
+        let first = login(&mut state);
+        let second = login(&mut state);
+
+        // Sessions not in the cache are loaded from the database.
+        drop(state);
+        let mut state = State::init(&conn).unwrap();
+        let sessions = state.user_sessions(&conn, uid).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].1.creation.when_sec, Some(42));
+
+        // Unflushed use is reflected; revoked sessions are omitted.
+        let later = Request {
+            when_sec: Some(43),
+            ..req.clone()
+        };
+        state
+            .authenticate_session(&conn, later.clone(), &second)
+            .unwrap();
+        state
+            .revoke_session(&conn, RevocationReason::Revoked, None, later, &first)
+            .unwrap();
+        let sessions = state.user_sessions(&conn, uid).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].0, second);
+        assert_eq!(sessions[0].1.last_use.when_sec, Some(43));
+        assert_eq!(sessions[0].1.use_count, 1);
+    }
+
     #[test]
     fn disable() {
         testutil::init();
@@ -1309,6 +2084,195 @@ mod tests {
         assert!(u.permissions.update_signals);
     }
 
+    #[test]
+    fn api_token() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let uid = state
+            .apply(&conn, UserChange::add_user("hass".to_owned()))
+            .unwrap()
+            .id;
+        let mut permissions = Permissions::new();
+        permissions.view_video = true;
+        let (token, t) = state
+            .make_api_token(
+                &conn,
+                uid,
+                Some("Home Assistant".to_owned()),
+                42,
+                Some(100),
+                permissions,
+            )
+            .unwrap();
+        let id = t.id;
+        let encoded = token.encode_base64();
+        let token = RawApiToken::decode_base64(encoded.as_bytes()).unwrap();
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            ..Default::default()
+        };
+        let (t, u) = state.authenticate_api_token(req(43), &token).unwrap();
+        assert!(t.permissions.view_video);
+        assert_eq!(u.id, uid);
+        let e = state.authenticate_api_token(req(100), &token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+
+        // Use counts are flushed; tokens survive reload.
+        {
+            let tx = conn.transaction().unwrap();
+            state.flush(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        state.post_flush();
+        let mut state = State::init(&conn).unwrap();
+        assert_eq!(state.api_tokens_by_id()[&id].use_count, 1);
+        state.authenticate_api_token(req(44), &token).unwrap();
+
+        state.delete_api_token(&conn, id).unwrap();
+        let e = state.authenticate_api_token(req(45), &token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+        let state = State::init(&conn).unwrap();
+        assert!(state.api_tokens_by_id().is_empty());
+    }
+
+    #[test]
+    fn credential() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let uid = {
+            let mut c = UserChange::add_user("slamb".to_owned());
+            c.permissions.view_video = true;
+            state.apply(&conn, c).unwrap().id
+        };
+        let id = state
+            .add_credential(&conn, uid, b"cred".to_vec(), None, 42, "{}".to_owned())
+            .unwrap()
+            .id;
+        let e = state
+            .add_credential(&conn, uid, b"cred".to_vec(), None, 42, "{}".to_owned())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+
+        let req = Request {
+            when_sec: Some(43),
+            ..Default::default()
+        };
+        let (sid, s) = state
+            .login_by_credential(&conn, req.clone(), id, "{\"n\":1}".to_owned(), None, 0)
+            .unwrap();
+        assert!(s.permissions.view_video);
+        state.authenticate_session(&conn, req, &sid.hash()).unwrap();
+
+        // The updated state persists.
+        let mut state = State::init(&conn).unwrap();
+        let c = &state.credentials_by_id()[&id];
+        assert_eq!(c.passkey, "{\"n\":1}");
+        assert_eq!(c.last_use_time_sec, Some(43));
+        state.delete_credential(&conn, id).unwrap();
+        assert!(State::init(&conn).unwrap().credentials_by_id().is_empty());
+    }
+
+    #[test]
+    fn allows_camera() {
+        let a = uuid::Uuid::from_u128(1);
+        let b = uuid::Uuid::from_u128(2);
+        let mut p = Permissions::new();
+        assert!(p.allows_camera(a));
+        p.cameras.push(a.as_bytes().to_vec());
+        assert!(p.allows_camera(a));
+        assert!(!p.allows_camera(b));
+    }
+
+    #[test]
+    fn is_subset_of() {
+        let a = uuid::Uuid::from_u128(1).as_bytes().to_vec();
+        let b = uuid::Uuid::from_u128(2).as_bytes().to_vec();
+        let mut all = Permissions::new();
+        all.view_video = true;
+        all.ptz = true;
+        let mut view = Permissions::new();
+        view.view_video = true;
+        assert!(view.is_subset_of(&all));
+        assert!(!all.is_subset_of(&view));
+
+        // A camera restriction can be added but not removed or widened.
+        let mut view_a = view.clone();
+        view_a.cameras.push(a.clone());
+        assert!(view_a.is_subset_of(&view));
+        assert!(!view.is_subset_of(&view_a));
+        let mut view_ab = view_a.clone();
+        view_ab.cameras.push(b);
+        assert!(view_a.is_subset_of(&view_ab));
+        assert!(!view_ab.is_subset_of(&view_a));
+
+        // Likewise a signal restriction, which matters only with update_signals.
+        let mut signals = Permissions::new();
+        signals.update_signals = true;
+        let mut signals_1 = signals.clone();
+        signals_1.signals.push(1);
+        assert!(signals_1.is_subset_of(&signals));
+        assert!(!signals.is_subset_of(&signals_1));
+        let mut signals_12 = signals_1.clone();
+        signals_12.signals.push(2);
+        assert!(signals_1.is_subset_of(&signals_12));
+        assert!(!signals_12.is_subset_of(&signals_1));
+        let mut view_signals_1 = signals_1.clone();
+        view_signals_1.view_video = true;
+        assert!(view.is_subset_of(&view_signals_1));
+    }
+
+    #[test]
+    fn union_with() {
+        let a = uuid::Uuid::from_u128(1).as_bytes().to_vec();
+        let b = uuid::Uuid::from_u128(2).as_bytes().to_vec();
+        let mut signals = Permissions::new();
+        signals.update_signals = true;
+        let mut view_a = Permissions::new();
+        view_a.view_video = true;
+        view_a.cameras.push(a.clone());
+        let mut ptz_b = Permissions::new();
+        ptz_b.ptz = true;
+        ptz_b.cameras.push(b.clone());
+        let mut talk_b = Permissions::new();
+        talk_b.talk = true;
+        talk_b.cameras.push(b.clone());
+
+        let mut p = signals.clone();
+        p.union_with(&view_a);
+        assert!(p.update_signals && p.view_video);
+        assert_eq!(p.cameras, vec![a.clone()]);
+        p.union_with(&ptz_b);
+        assert!(p.ptz);
+        assert_eq!(p.cameras, vec![a.clone(), b.clone()]);
+        p.union_with(&talk_b);
+        assert!(p.talk);
+        assert_eq!(p.cameras, vec![a, b]);
+
+        // A grant without a camera list applies to all cameras.
+        let mut view = Permissions::new();
+        view.view_video = true;
+        p.union_with(&view);
+        assert!(p.cameras.is_empty());
+
+        // Likewise for signals.
+        let mut signals_1 = Permissions::new();
+        signals_1.update_signals = true;
+        signals_1.signals.push(1);
+        let mut signals_2 = signals_1.clone();
+        signals_2.signals = vec![2];
+        let mut p = view.clone();
+        p.union_with(&signals_1);
+        assert_eq!(p.signals, vec![1]);
+        p.union_with(&signals_2);
+        assert_eq!(p.signals, vec![1, 2]);
+        p.union_with(&signals);
+        assert!(p.signals.is_empty());
+    }
+
     #[test]
     fn preferences() {
         testutil::init();
@@ -1341,4 +2305,139 @@ mod tests {
         assert_eq!(u.config.preferences.get("foo"), Some(&42.into()));
         assert_eq!(u.config.preferences.get("bar"), Some(&26.into()));
     }
+
+    #[test]
+    fn login_throttle() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        state.apply(&conn, c).unwrap();
+        let addr = |last| Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, last)));
+        let req = |when_sec, last| Request {
+            when_sec: Some(when_sec),
+            addr: addr(last),
+            user_agent: None,
+        };
+        let mut login = |req, username: &str, password: &str| {
+            state
+                .login_by_password(&conn, req, username, password.to_owned(), None, 0)
+                .map(|_| ())
+        };
+
+        // A few failures are free; then the user is throttled, even from another address and
+        // with the correct password.
+        for _ in 0..FREE_FAILURES_PER_USER {
+            let e = login(req(42, 1), "slamb", "wrong").unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+        }
+        let e = login(req(42, 2), "slamb", "hunter2").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceExhausted);
+        assert_eq!(
+            e.msg().unwrap(),
+            "too many failed logins; try again in 1 seconds"
+        );
+
+        // The delay doubles with each further failure.
+        login(req(43, 1), "slamb", "wrong").unwrap_err();
+        let e = login(req(44, 1), "slamb", "hunter2").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceExhausted);
+        login(req(45, 1), "slamb", "hunter2").unwrap();
+
+        // Success resets the user's failures but not the address's.
+        login(req(45, 1), "slamb", "wrong").unwrap_err();
+        login(req(45, 1), "slamb", "hunter2").unwrap();
+
+        // Many failures from one address throttle it, regardless of username.
+        for i in 0..FREE_FAILURES_PER_ADDR - FREE_FAILURES_PER_USER - 2 {
+            login(req(46, 1), &format!("user{i}"), "wrong").unwrap_err();
+        }
+        let e = login(req(46, 1), "slamb", "hunter2").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceExhausted);
+        login(req(46, 2), "slamb", "hunter2").unwrap();
+        assert!(state.login_blocked_until(addr(1), "slamb", 46).is_some());
+
+        // Failures are forgotten eventually.
+        assert_eq!(
+            state.login_blocked_until(addr(1), "slamb", 46 + LOGIN_FAILURE_EXPIRY_SEC),
+            None
+        );
+    }
+
+    #[test]
+    fn login_throttle_full() {
+        testutil::init();
+        let mut t = LoginThrottle::default();
+        let addr = |i: u32| Some(IpAddr::V4(std::net::Ipv4Addr::from(0x0a00_0000 + i)));
+
+        // Fill the address table with unexpired entries. A fresh address is still tracked and
+        // throttled, evicting the least recently failed entry.
+        t.record_failure(addr(0), None, 100);
+        for i in 1..MAX_LOGIN_FAILURE_ENTRIES as u32 {
+            t.record_failure(addr(i), None, 200);
+        }
+        assert_eq!(t.by_addr.len(), MAX_LOGIN_FAILURE_ENTRIES);
+        let now = 300;
+        let fresh = addr(MAX_LOGIN_FAILURE_ENTRIES as u32);
+        for _ in 0..FREE_FAILURES_PER_ADDR {
+            t.record_failure(fresh, None, now);
+        }
+        assert_eq!(t.by_addr.len(), MAX_LOGIN_FAILURE_ENTRIES);
+        assert!(t.blocked_until(fresh, "slamb", now).is_some());
+        assert!(!t.by_addr.contains_key(&addr(0).unwrap()));
+        assert!(t.by_addr.contains_key(&addr(1).unwrap()));
+
+        // Likewise for usernames.
+        for i in 0..MAX_LOGIN_FAILURE_ENTRIES {
+            t.record_failure(None, Some(&format!("user{i}")), now);
+        }
+        for _ in 0..FREE_FAILURES_PER_USER {
+            t.record_failure(None, Some("slamb"), now + 1);
+        }
+        assert_eq!(t.by_username.len(), MAX_LOGIN_FAILURE_ENTRIES);
+        assert!(t.blocked_until(None, "slamb", now + 1).is_some());
+    }
+
+    #[test]
+    fn login_throttle_unknown_users() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut state = State::init(&conn).unwrap();
+        let mut c = UserChange::add_user("slamb".to_owned());
+        c.set_password("hunter2".to_owned());
+        state.apply(&conn, c).unwrap();
+        let req = |when_sec| Request {
+            when_sec: Some(when_sec),
+            addr: None,
+            user_agent: None,
+        };
+
+        // Failures for nonexistent usernames don't take up room in the username table.
+        for i in 0..MAX_LOGIN_FAILURE_ENTRIES {
+            let e = state
+                .login_by_password(
+                    &conn,
+                    req(42),
+                    &format!("bogus{i}"),
+                    "x".to_owned(),
+                    None,
+                    0,
+                )
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+        }
+        assert!(state.login_throttle.by_username.is_empty());
+        for _ in 0..FREE_FAILURES_PER_USER {
+            state
+                .login_by_password(&conn, req(42), "slamb", "wrong".to_owned(), None, 0)
+                .unwrap_err();
+        }
+        let e = state
+            .login_by_password(&conn, req(42), "slamb", "hunter2".to_owned(), None, 0)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ResourceExhausted);
+    }
 }
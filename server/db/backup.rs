@@ -0,0 +1,112 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Online backups via SQLite's [backup API](https://www.sqlite.org/backup.html).
+//!
+//! Unlike `vacuum into`, a backup can be copied a few pages at a time, so the database lock can
+//! be released between steps to let the syncer and web requests proceed. Changes made through
+//! the source connection between steps are applied to the backup as well, so the result is
+//! consistent as of the final step.
+//!
+//! `rusqlite`'s safe wrapper borrows the source connection for the backup's lifetime, which
+//! doesn't allow releasing the lock. This instead uses the raw API; the caller guarantees the
+//! source connection outlives the backup.
+
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+use base::{bail, err, Error};
+use rusqlite::ffi;
+
+/// A backup in progress.
+pub(crate) struct Backup {
+    raw: NonNull<ffi::sqlite3_backup>,
+    /// The destination connection; always `Some` until `drop`.
+    dst: Option<rusqlite::Connection>,
+    path: PathBuf,
+    done: bool,
+}
+
+// SAFETY: the backup is only used via `&mut self`, and SQLite's default (serialized or
+// multi-thread) threading modes allow using it from any one thread at a time.
+unsafe impl Send for Backup {}
+
+impl Backup {
+    /// Starts a backup of `src`'s main database to a new file at `path`.
+    ///
+    /// # Safety
+    ///
+    /// `src` must outlive the returned `Backup`.
+    pub(crate) unsafe fn new(src: &rusqlite::Connection, path: &Path) -> Result<Self, Error> {
+        if path.exists() {
+            bail!(AlreadyExists, msg("backup path {} exists", path.display()));
+        }
+        let dst = rusqlite::Connection::open(path)
+            .map_err(|e| err!(e, msg("unable to create backup {}", path.display())))?;
+        let main = CStr::from_bytes_with_nul(b"main\0").expect("has trailing nul");
+        let raw =
+            ffi::sqlite3_backup_init(dst.handle(), main.as_ptr(), src.handle(), main.as_ptr());
+        let Some(raw) = NonNull::new(raw) else {
+            let e = CStr::from_ptr(ffi::sqlite3_errmsg(dst.handle()))
+                .to_string_lossy()
+                .into_owned();
+            drop(dst);
+            let _ = std::fs::remove_file(path);
+            bail!(
+                Unknown,
+                msg("unable to start backup to {}: {e}", path.display())
+            );
+        };
+        Ok(Backup {
+            raw,
+            dst: Some(dst),
+            path: path.to_owned(),
+            done: false,
+        })
+    }
+
+    /// Copies up to `pages` pages (or all remaining pages, if negative), returning true when the
+    /// backup is complete. After an error, the backup can't be continued.
+    pub(crate) fn step(&mut self, pages: i32) -> Result<bool, Error> {
+        // SAFETY: `raw` is valid until `drop`, and the caller of `new` guaranteed the source
+        // connection is still open.
+        let rc = unsafe { ffi::sqlite3_backup_step(self.raw.as_ptr(), pages) };
+        match rc {
+            ffi::SQLITE_DONE => {
+                self.done = true;
+                Ok(true)
+            }
+            ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => Ok(false),
+            _ => bail!(
+                rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None),
+                msg("backup to {} failed", self.path.display())
+            ),
+        }
+    }
+
+    /// Returns the number of pages remaining and the total number of pages, as of the last step.
+    pub(crate) fn progress(&self) -> (i32, i32) {
+        // SAFETY: as in `step`.
+        unsafe {
+            (
+                ffi::sqlite3_backup_remaining(self.raw.as_ptr()),
+                ffi::sqlite3_backup_pagecount(self.raw.as_ptr()),
+            )
+        }
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        // SAFETY: as in `step`; `raw` isn't used afterward.
+        unsafe { ffi::sqlite3_backup_finish(self.raw.as_ptr()) };
+        drop(self.dst.take());
+        if !self.done {
+            if let Err(err) = std::fs::remove_file(&self.path) {
+                tracing::warn!(%err, "unable to remove incomplete backup {}", self.path.display());
+            }
+        }
+    }
+}
@@ -15,7 +15,11 @@ use base::{err, Error};
 use base::{FastHashMap, FastHashSet};
 use nix::fcntl::AtFlags;
 use rusqlite::params;
+use std::io::Read;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tracing::{error, info, warn};
 
 pub struct Options {
@@ -23,12 +27,40 @@ pub struct Options {
     pub trash_orphan_sample_files: bool,
     pub delete_orphan_rows: bool,
     pub trash_corrupt_rows: bool,
+
+    /// Deletes leftover database rows of recordings already listed in the `garbage` table.
+    pub requeue_garbage: bool,
+
+    /// Flags recordings whose sample files are missing as corrupt, rather than deleting their
+    /// rows as `delete_orphan_rows` would.
+    pub mark_missing_recordings: bool,
+
+    /// Logs the repairs the options above would make without making them.
+    pub dry_run: bool,
+
+    /// Prints the schema comparison to stdout as a JSON [`compare::SchemaDiff`], or `null` if
+    /// the schema is as expected.
+    pub json: bool,
+
+    /// The number of threads with which to read each sample file directory.
+    pub io_concurrency: usize,
+
+    /// Re-reads each sample file which has a recorded hash, flagging recordings whose contents
+    /// don't match.
+    pub scrub: bool,
+
+    /// When scrubbing, the maximum rate at which to read sample files, or 0 for no limit.
+    pub scrub_bytes_per_sec: u64,
+
+    /// When scrubbing, the master key with which to decrypt encrypted sample files.
+    pub sample_file_key: Option<dir::crypto::MasterKey>,
 }
 
 #[derive(Default)]
 pub struct Context {
     rows_to_delete: FastHashSet<CompositeId>,
     files_to_trash: FastHashSet<(i32, CompositeId)>, // (dir_id, composite_id)
+    rows_to_flag_corrupt: FastHashSet<CompositeId>,
 }
 
 pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error> {
@@ -64,12 +96,19 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
     {
         let mut expected = rusqlite::Connection::open_in_memory()?;
         db::init(&mut expected)?;
-        if let Some(diffs) = compare::get_diffs("actual", conn, "expected", &expected)? {
-            error!("Schema is not as expected:\n{}", &diffs);
+        let diffs = compare::get_diffs("actual", conn, "expected", &expected)?;
+        if let Some(ref diffs) = diffs {
+            error!("Schema is not as expected:\n{}", diffs);
             printed_error = true;
         } else {
             info!("Schema is as expected.");
         }
+        if opts.json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&diffs).expect("SchemaDiff is serializable")
+            );
+        }
     }
 
     if printed_error {
@@ -80,6 +119,7 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
 
     // Scan directories.
     let mut dirs_by_id: FastHashMap<i32, Dir> = FastHashMap::default();
+    let mut sample_file_dirs: FastHashMap<i32, Arc<dir::SampleFileDir>> = FastHashMap::default();
     {
         let mut dir_stmt = conn.prepare(
             r#"
@@ -108,7 +148,22 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
             // Open the directory (checking its metadata) and hold it open (for the lock).
             let dir = dir::SampleFileDir::open(&config.path, &meta)
                 .map_err(|e| err!(e, msg("unable to open dir {}", config.path.display())))?;
-            let mut streams = read_dir(&dir, opts)?;
+            sample_file_dirs.insert(dir_id, dir);
+        }
+
+        // Scan each directory from its own thread(s), as they're likely on separate disks.
+        let scanned = std::thread::scope(|s| {
+            let handles: Vec<_> = sample_file_dirs
+                .iter()
+                .map(|(&dir_id, dir)| (dir_id, s.spawn(move || read_dir(dir, opts))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|(dir_id, h)| (dir_id, h.join().expect("read_dir shouldn't panic")))
+                .collect::<Vec<_>>()
+        });
+        for (dir_id, streams) in scanned {
+            let mut streams = streams?;
             let mut rows = garbage_stmt.query(params![dir_id])?;
             while let Some(row) = rows.next()? {
                 let id = CompositeId(row.get(0)?);
@@ -147,6 +202,7 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
                 Some(d) => d.remove(&stream_id).unwrap_or_default(),
             };
             stream.cum_recordings = Some(cum_recordings);
+            printed_error |= take_archived(conn, &mut dirs_by_id, stream_id, &mut stream)?;
             printed_error |= compare_stream(conn, dir_id, stream_id, opts, stream, &mut ctx)?;
         }
     }
@@ -166,37 +222,112 @@ pub fn run(conn: &mut rusqlite::Connection, opts: &Options) -> Result<i32, Error
                         dir_id, id, r
                     );
                     printed_error = true;
+                    if r.file.is_some() && !r.garbage_row && opts.trash_orphan_sample_files {
+                        ctx.files_to_trash.insert((dir_id, id));
+                    }
+                    if r.garbage_row && opts.requeue_garbage {
+                        ctx.rows_to_delete.insert(id);
+                    }
                 }
             }
         }
     }
 
-    if !ctx.rows_to_delete.is_empty() || !ctx.files_to_trash.is_empty() {
+    if opts.scrub {
+        printed_error |= scrub(conn, &sample_file_dirs, opts, &mut ctx)?;
+    }
+
+    if !ctx.is_empty() {
+        ctx.log_summary();
+        if opts.dry_run {
+            info!("Dry run; not making any changes.");
+        } else {
+            ctx.apply(conn)?;
+        }
+    }
+
+    Ok(if printed_error { 1 } else { 0 })
+}
+
+impl Context {
+    fn is_empty(&self) -> bool {
+        self.rows_to_delete.is_empty()
+            && self.files_to_trash.is_empty()
+            && self.rows_to_flag_corrupt.is_empty()
+    }
+
+    /// Makes the planned changes in a single transaction.
+    fn apply(&self, conn: &mut rusqlite::Connection) -> Result<(), Error> {
         let tx = conn.transaction()?;
-        if !ctx.rows_to_delete.is_empty() {
-            info!("Deleting {} recording rows", ctx.rows_to_delete.len());
+        if !self.rows_to_delete.is_empty() {
+            info!("Deleting {} recording rows", self.rows_to_delete.len());
             let mut d1 = tx.prepare("delete from recording_playback where composite_id = ?")?;
             let mut d2 = tx.prepare("delete from recording_integrity where composite_id = ?")?;
             let mut d3 = tx.prepare("delete from recording where composite_id = ?")?;
-            for &id in &ctx.rows_to_delete {
+            for &id in &self.rows_to_delete {
                 d1.execute(params![id.0])?;
                 d2.execute(params![id.0])?;
                 d3.execute(params![id.0])?;
             }
         }
-        if !ctx.files_to_trash.is_empty() {
-            info!("Trashing {} recording files", ctx.files_to_trash.len());
+        if !self.files_to_trash.is_empty() {
+            info!("Trashing {} recording files", self.files_to_trash.len());
             let mut g = tx.prepare(
                 "insert or ignore into garbage (sample_file_dir_id, composite_id) values (?, ?)",
             )?;
-            for (dir_id, composite_id) in &ctx.files_to_trash {
+            for (dir_id, composite_id) in &self.files_to_trash {
                 g.execute(params![dir_id, composite_id.0])?;
             }
         }
+        if !self.rows_to_flag_corrupt.is_empty() {
+            info!(
+                "Flagging {} recordings as corrupt",
+                self.rows_to_flag_corrupt.len()
+            );
+            let mut u =
+                tx.prepare("update recording set flags = flags | ? where composite_id = ?")?;
+            for &id in &self.rows_to_flag_corrupt {
+                u.execute(params![db::RecordingFlags::Corrupt as i32, id.0])?;
+            }
+        }
         tx.commit()?;
+        Ok(())
     }
 
-    Ok(if printed_error { 1 } else { 0 })
+    /// Logs the changes to be made, so they can be reviewed before (or instead of) making them.
+    fn log_summary(&self) {
+        if !self.rows_to_delete.is_empty() {
+            info!(
+                "Planning to delete {} recording rows: {}",
+                self.rows_to_delete.len(),
+                join_ids(self.rows_to_delete.iter().copied())
+            );
+        }
+        if !self.files_to_trash.is_empty() {
+            info!(
+                "Planning to trash {} recording files: {}",
+                self.files_to_trash.len(),
+                join_ids(self.files_to_trash.iter().map(|&(_, id)| id))
+            );
+        }
+        if !self.rows_to_flag_corrupt.is_empty() {
+            info!(
+                "Planning to flag {} recordings as corrupt: {}",
+                self.rows_to_flag_corrupt.len(),
+                join_ids(self.rows_to_flag_corrupt.iter().copied())
+            );
+        }
+    }
+}
+
+/// Returns a sorted, comma-separated list of the given ids.
+fn join_ids(ids: impl Iterator<Item = CompositeId>) -> String {
+    let mut ids: Vec<_> = ids.collect();
+    ids.sort_by_key(|id| id.0);
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -204,6 +335,7 @@ struct RecordingSummary {
     bytes: u64,
     video_samples: i32,
     video_sync_samples: i32,
+    audio_samples: i32,
     media_duration: i32,
     flags: i32,
 }
@@ -235,7 +367,10 @@ struct Stream {
 
 type Dir = FastHashMap<i32, Stream>;
 
-fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
+fn summarize_index(
+    video_index: &[u8],
+    audio_index: Option<&[u8]>,
+) -> Result<RecordingSummary, Error> {
     let mut it = recording::SampleIndexIterator::default();
     let mut media_duration = 0;
     let mut video_samples = 0;
@@ -247,10 +382,19 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
         video_samples += 1;
         video_sync_samples += it.is_key() as i32;
     }
+    let mut audio_samples = 0;
+    if let Some(audio_index) = audio_index {
+        let (_, mut a) = recording::AudioSampleIndexIterator::new(audio_index)?;
+        while a.next(audio_index)? {
+            bytes += a.bytes as u64;
+            audio_samples += 1;
+        }
+    }
     Ok(RecordingSummary {
         bytes,
         video_samples,
         video_sync_samples,
+        audio_samples,
         media_duration,
         flags: if it.duration_90k == 0 {
             db::RecordingFlags::TrailingZero as i32
@@ -262,11 +406,13 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
 
 /// Reads through the given sample file directory.
 /// Logs unexpected files and creates a hash map of the files found there.
-/// If `opts.compare_lens` is set, the values are lengths; otherwise they're insignificant.
+/// If `opts.compare_lens` is set, the values are lengths, read with `opts.io_concurrency`
+/// threads; otherwise they're insignificant.
 fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
     let mut dir = Dir::default();
     let mut d = d.opendir()?;
     let fd = d.as_raw_fd();
+    let mut files = Vec::new();
     for e in d.iter() {
         let e = e?;
         let f = e.file_name();
@@ -284,21 +430,108 @@ fn read_dir(d: &dir::SampleFileDir, opts: &Options) -> Result<Dir, Error> {
                 continue;
             }
         };
-        let len = if opts.compare_lens {
-            nix::sys::stat::fstatat(fd, f, AtFlags::empty())?.st_size as u64
-        } else {
-            0
-        };
+        files.push((id, f.to_owned()));
+    }
+    let lens = if opts.compare_lens {
+        parallel_map(&files, opts.io_concurrency, |(_, f)| {
+            nix::sys::stat::fstatat(fd, f.as_c_str(), AtFlags::empty()).map(|s| s.st_size as u64)
+        })
+    } else {
+        files.iter().map(|_| Ok(0)).collect()
+    };
+    for ((id, _), len) in files.iter().zip(lens) {
         let stream = dir.entry(id.stream()).or_insert_with(Stream::default);
         stream
             .recordings
             .entry(id.recording())
             .or_insert_with(Recording::default)
-            .file = Some(len);
+            .file = Some(len?);
     }
     Ok(dir)
 }
 
+/// Calls `f` on each of `items` from `concurrency` threads, returning the results in order.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    concurrency: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let mut out: Vec<Option<R>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    let worker = || {
+        let mut results = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(item) = items.get(i) else {
+                return results;
+            };
+            results.push((i, f(item)));
+        }
+    };
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..concurrency.clamp(1, items.len().max(1)))
+            .map(|_| s.spawn(worker))
+            .collect();
+        for h in handles {
+            for (i, r) in h.join().expect("parallel_map worker shouldn't panic") {
+                out[i] = Some(r);
+            }
+        }
+    });
+    out.into_iter()
+        .map(|r| r.expect("every item is processed"))
+        .collect()
+}
+
+/// Replaces the stream's entries for archived recordings with those from their archive
+/// directories. The stream's own directory should have at most a garbage copy of each.
+fn take_archived(
+    conn: &rusqlite::Connection,
+    dirs_by_id: &mut FastHashMap<i32, Dir>,
+    stream_id: i32,
+    stream: &mut Stream,
+) -> Result<bool, Error> {
+    let mut printed_error = false;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          composite_id,
+          sample_file_dir_id
+        from
+          recording
+        where
+          composite_id between ? and ? and
+          sample_file_dir_id is not null
+        "#,
+    )?;
+    let mut rows = stmt.query(params![
+        CompositeId::new(stream_id, 0).0,
+        CompositeId::new(stream_id, i32::max_value()).0
+    ])?;
+    while let Some(row) = rows.next()? {
+        let id = CompositeId(row.get(0)?);
+        let archive_dir_id: i32 = row.get(1)?;
+        let original = stream
+            .recordings
+            .remove(&id.recording())
+            .unwrap_or_default();
+        if original.file.is_some() && !original.garbage_row {
+            error!(
+                "Archived recording {} also has a non-garbage file in its stream's dir",
+                id
+            );
+            printed_error = true;
+        }
+        let archived = dirs_by_id
+            .get_mut(&archive_dir_id)
+            .and_then(|d| d.get_mut(&stream_id))
+            .and_then(|s| s.recordings.remove(&id.recording()))
+            .unwrap_or_default();
+        stream.recordings.insert(id.recording(), archived);
+    }
+    Ok(printed_error)
+}
+
 /// Looks through a known stream for errors.
 fn compare_stream(
     conn: &rusqlite::Connection,
@@ -325,7 +558,8 @@ fn compare_stream(
               sample_file_bytes,
               wall_duration_90k + media_duration_delta_90k,
               video_samples,
-              video_sync_samples
+              video_sync_samples,
+              audio_samples
             from
               recording
             where
@@ -336,11 +570,13 @@ fn compare_stream(
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             let s = RecordingSummary {
-                flags: row.get(1)?,
+                // The corrupt flag is set by scrubbing, not derived from the index.
+                flags: row.get::<_, i32>(1)? & !(db::RecordingFlags::Corrupt as i32),
                 bytes: row.get::<_, i64>(2)? as u64,
                 media_duration: row.get(3)?,
                 video_samples: row.get(4)?,
                 video_sync_samples: row.get(5)?,
+                audio_samples: row.get(6)?,
             };
             stream
                 .recordings
@@ -356,7 +592,9 @@ fn compare_stream(
             r#"
             select
               composite_id,
-              video_index
+              video_index,
+              audio_index,
+              wrapped_key
             from
               recording_playback
             where
@@ -367,10 +605,12 @@ fn compare_stream(
         while let Some(row) = rows.next()? {
             let id = CompositeId(row.get(0)?);
             let video_index: Vec<u8> = row.get(1)?;
-            let s = match summarize_index(&video_index) {
+            let audio_index: Option<Vec<u8>> = row.get(2)?;
+            let wrapped_key: Option<Vec<u8>> = row.get(3)?;
+            let mut s = match summarize_index(&video_index, audio_index.as_deref()) {
                 Ok(s) => s,
                 Err(e) => {
-                    error!("id {} has bad video_index: {}", id, e);
+                    error!("id {} has bad index: {}", id, e);
                     printed_error = true;
                     if opts.trash_corrupt_rows {
                         ctx.rows_to_delete.insert(id);
@@ -379,6 +619,9 @@ fn compare_stream(
                     continue;
                 }
             };
+            if wrapped_key.is_some() {
+                s.flags |= db::RecordingFlags::Encrypted as i32;
+            }
             stream
                 .recordings
                 .entry(id.recording())
@@ -423,6 +666,11 @@ fn compare_stream(
                 if !db_rows_expected {
                     error!("Unexpected recording row for {}: {:#?}", id, recording);
                     printed_error = true;
+                    if recording.garbage_row && opts.requeue_garbage {
+                        // Finish the interrupted deletion, so garbage collection doesn't leave
+                        // rows pointing to a deleted file.
+                        ctx.rows_to_delete.insert(id);
+                    }
                     continue;
                 }
                 r
@@ -440,7 +688,7 @@ fn compare_stream(
                     printed_error = true;
                 } else if recording.playback_row.is_some() {
                     error!("Unexpected playback row for {}: {:#?}", id, recording);
-                    if opts.delete_orphan_rows {
+                    if opts.delete_orphan_rows || (recording.garbage_row && opts.requeue_garbage) {
                         ctx.rows_to_delete.insert(id);
                     }
                     printed_error = true;
@@ -452,7 +700,7 @@ fn compare_stream(
             Some(ref p) => {
                 if r != p {
                     error!(
-                        "Recording {} summary doesn't match video_index: {:#?}",
+                        "Recording {} summary doesn't match index: {:#?}",
                         id, recording
                     );
                     printed_error = true;
@@ -472,14 +720,21 @@ fn compare_stream(
         }
         match recording.file {
             Some(len) => {
-                if opts.compare_lens && r.bytes != len {
+                let expected_len = if (r.flags & db::RecordingFlags::Encrypted as i32) != 0 {
+                    dir::crypto::encrypted_len(r.bytes)
+                } else {
+                    r.bytes
+                };
+                if opts.compare_lens && expected_len != len {
                     error!("Recording {} length mismatch: {:#?}", id, recording);
                     printed_error = true;
                 }
             }
             None => {
                 error!("Recording {} missing file: {:#?}", id, recording);
-                if opts.delete_orphan_rows {
+                if opts.mark_missing_recordings {
+                    ctx.rows_to_flag_corrupt.insert(id);
+                } else if opts.delete_orphan_rows {
                     ctx.rows_to_delete.insert(id);
                 }
                 printed_error = true;
@@ -489,3 +744,412 @@ fn compare_stream(
 
     Ok(printed_error)
 }
+
+/// Limits the total rate of reads, from any number of threads, to a given number of bytes per
+/// second.
+struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    bytes: Mutex<u64>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: Mutex::new(0),
+        }
+    }
+
+    /// Accounts for `n` bytes read, sleeping as necessary to stay within the limit.
+    fn consume(&self, n: usize) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let bytes = {
+            let mut l = self.bytes.lock().unwrap();
+            *l += n as u64;
+            *l
+        };
+        let target = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if target > elapsed {
+            std::thread::sleep(target - elapsed);
+        }
+    }
+}
+
+/// Reads into `buf` until it's full or at end of file, returning the number of bytes read.
+fn read_full(f: &mut impl Read, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match f.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+/// Returns the BLAKE3 hash of a sample file's plaintext, decrypting it with `key` if supplied.
+fn hash_file(
+    f: &mut impl Read,
+    id: CompositeId,
+    key: Option<&dir::crypto::Key>,
+    throttle: &Throttle,
+) -> Result<blake3::Hash, Error> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; dir::crypto::CHUNK_LEN + dir::crypto::TAG_LEN];
+    let mut chunk = 0;
+    loop {
+        let n = read_full(f, &mut buf).map_err(|e| err!(e, msg("unable to read {id}")))?;
+        throttle.consume(n);
+        if n == 0 {
+            break;
+        }
+        match key {
+            None => hasher.update(&buf[..n]),
+            Some(k) => hasher.update(k.open_chunk(id, chunk, &mut buf[..n])?),
+        };
+        if n < buf.len() {
+            break;
+        }
+        chunk += 1;
+    }
+    Ok(hasher.finalize())
+}
+
+/// A sample file to scrub.
+struct ToScrub {
+    id: CompositeId,
+    flags: i32,
+    key: Option<dir::crypto::Key>,
+    expected_blake3: Vec<u8>,
+}
+
+/// The result of scrubbing a single sample file.
+enum Scrubbed {
+    Ok,
+
+    /// The file is absent; `compare_stream` reports this.
+    Missing,
+
+    /// The file couldn't be opened, for reasons that don't implicate its contents.
+    OpenFailed,
+
+    /// The file couldn't be read or doesn't match its hash.
+    Corrupt,
+}
+
+/// Checks each sample file with a recorded hash against it.
+///
+/// Each directory is read from its own `opts.io_concurrency` threads. Recordings which can't be
+/// read or don't match are added to `ctx.rows_to_flag_corrupt`.
+fn scrub(
+    conn: &rusqlite::Connection,
+    dirs: &FastHashMap<i32, Arc<dir::SampleFileDir>>,
+    opts: &Options,
+    ctx: &mut Context,
+) -> Result<bool, Error> {
+    info!("Scrubbing sample files...");
+    let mut printed_error = false;
+    let mut skipped_encrypted = 0;
+    let mut by_dir: FastHashMap<i32, Vec<ToScrub>> = FastHashMap::default();
+    let mut stmt = conn.prepare(
+        r#"
+        select
+          r.composite_id,
+          coalesce(r.sample_file_dir_id, s.sample_file_dir_id),
+          r.flags,
+          p.wrapped_key,
+          i.sample_file_blake3
+        from
+          recording r
+          join stream s on (r.stream_id = s.id)
+          join recording_integrity i on (r.composite_id = i.composite_id)
+          left join recording_playback p on (r.composite_id = p.composite_id)
+        where
+          i.sample_file_blake3 is not null
+        order by
+          r.composite_id
+        "#,
+    )?;
+    let mut rows = stmt.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let id = CompositeId(row.get(0)?);
+        let dir_id: Option<i32> = row.get(1)?;
+        let wrapped_key: Option<Vec<u8>> = row.get(3)?;
+        let Some(dir_id) = dir_id.filter(|d| dirs.contains_key(d)) else {
+            continue;
+        };
+        if ctx.rows_to_delete.contains(&id) {
+            continue;
+        }
+        let key = match (wrapped_key, &opts.sample_file_key) {
+            (None, _) => None,
+            (Some(w), Some(m)) => match m.unwrap_key(id, &w) {
+                Ok(k) => Some(k),
+                Err(e) => {
+                    error!(err = %e.chain(), "unable to scrub recording {}", id);
+                    printed_error = true;
+                    continue;
+                }
+            },
+            (Some(_), None) => {
+                skipped_encrypted += 1;
+                continue;
+            }
+        };
+        by_dir.entry(dir_id).or_default().push(ToScrub {
+            id,
+            flags: row.get(2)?,
+            key,
+            expected_blake3: row.get(4)?,
+        });
+    }
+    if skipped_encrypted > 0 {
+        warn!(
+            "Skipping {} encrypted recordings; supply the sample file key to scrub them.",
+            skipped_encrypted
+        );
+    }
+
+    let throttle = Throttle::new(opts.scrub_bytes_per_sec);
+    let results = std::thread::scope(|s| {
+        let handles: Vec<_> = by_dir
+            .iter()
+            .map(|(dir_id, files)| {
+                let dir = &dirs[dir_id];
+                let throttle = &throttle;
+                s.spawn(move || {
+                    let results =
+                        parallel_map(files, opts.io_concurrency, |f| scrub_file(dir, f, throttle));
+                    files.iter().zip(results).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("scrub shouldn't panic"))
+            .collect::<Vec<_>>()
+    });
+    let mut scrubbed = 0;
+    for (f, r) in results {
+        match r {
+            Scrubbed::Ok => scrubbed += 1,
+            Scrubbed::Missing => {}
+            Scrubbed::OpenFailed => printed_error = true,
+            Scrubbed::Corrupt => {
+                scrubbed += 1;
+                printed_error = true;
+                if (f.flags & db::RecordingFlags::Corrupt as i32) == 0 {
+                    ctx.rows_to_flag_corrupt.insert(f.id);
+                }
+            }
+        }
+    }
+    info!("...scrubbed {} sample files", scrubbed);
+    Ok(printed_error)
+}
+
+/// Scrubs a single sample file, logging any problem.
+fn scrub_file(dir: &dir::SampleFileDir, f: &ToScrub, throttle: &Throttle) -> Scrubbed {
+    let id = f.id;
+    let mut file = match dir.open_raw_file(id) {
+        Ok(file) => file,
+        Err(nix::Error::ENOENT) => return Scrubbed::Missing,
+        Err(e) => {
+            error!(err = %e, "unable to open recording {} to scrub", id);
+            return Scrubbed::OpenFailed;
+        }
+    };
+    let problem = match hash_file(&mut file, id, f.key.as_ref(), throttle) {
+        Ok(h) if h.as_bytes().starts_with(&f.expected_blake3) => return Scrubbed::Ok,
+        Ok(h) => format!(
+            "has hash {} but expected {}",
+            h.to_hex(),
+            base::strutil::hex(&f.expected_blake3)
+        ),
+        Err(e) => e.chain().to_string(),
+    };
+    error!("Recording {} is corrupt: {}", id, problem);
+    Scrubbed::Corrupt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compare_stream, hash_file, parallel_map, Context, Options, Recording, RecordingSummary,
+        Stream, Throttle,
+    };
+    use crate::dir::crypto::{self, MasterKey};
+    use crate::testutil;
+    use crate::CompositeId;
+    use std::sync::Arc;
+
+    fn options() -> Options {
+        Options {
+            compare_lens: false,
+            trash_orphan_sample_files: false,
+            delete_orphan_rows: false,
+            trash_corrupt_rows: false,
+            requeue_garbage: false,
+            mark_missing_recordings: false,
+            dry_run: false,
+            json: false,
+            io_concurrency: 1,
+            scrub: false,
+            scrub_bytes_per_sec: 0,
+            sample_file_key: None,
+        }
+    }
+
+    fn summary() -> RecordingSummary {
+        RecordingSummary {
+            bytes: 100,
+            video_samples: 1,
+            video_sync_samples: 1,
+            audio_samples: 0,
+            media_duration: 90_000,
+            flags: 0,
+        }
+    }
+
+    /// Returns a stream with a recording whose deletion was interrupted (id 0) and a recording
+    /// whose file is missing (id 1).
+    fn stream() -> Stream {
+        let mut s = Stream {
+            cum_recordings: Some(2),
+            ..Default::default()
+        };
+        s.recordings.insert(
+            0,
+            Recording {
+                file: Some(0),
+                recording_row: Some(summary()),
+                playback_row: Some(summary()),
+                integrity_row: true,
+                garbage_row: true,
+            },
+        );
+        s.recordings.insert(
+            1,
+            Recording {
+                file: None,
+                recording_row: Some(summary()),
+                playback_row: Some(summary()),
+                integrity_row: true,
+                garbage_row: false,
+            },
+        );
+        s
+    }
+
+    /// Runs `compare_stream` on [`stream`] against a database with no rows of its own.
+    fn plan(opts: &Options) -> Context {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::db::init(&mut conn).unwrap();
+        let mut ctx = Context::default();
+        assert!(compare_stream(&conn, 1, 1, opts, stream(), &mut ctx).unwrap());
+        ctx
+    }
+
+    #[test]
+    fn repairs() {
+        testutil::init();
+        let interrupted = CompositeId::new(1, 0);
+        let missing = CompositeId::new(1, 1);
+        let ids = |s: &base::FastHashSet<CompositeId>| s.iter().copied().collect::<Vec<_>>();
+
+        // Without any repair options, errors are only reported.
+        assert!(plan(&options()).is_empty());
+
+        let ctx = plan(&Options {
+            requeue_garbage: true,
+            ..options()
+        });
+        assert_eq!(ids(&ctx.rows_to_delete), [interrupted]);
+        assert!(ctx.files_to_trash.is_empty());
+        assert!(ctx.rows_to_flag_corrupt.is_empty());
+
+        let ctx = plan(&Options {
+            delete_orphan_rows: true,
+            ..options()
+        });
+        assert_eq!(ids(&ctx.rows_to_delete), [missing]);
+        assert!(ctx.rows_to_flag_corrupt.is_empty());
+
+        // Marking missing recordings takes precedence over deleting their rows.
+        let ctx = plan(&Options {
+            delete_orphan_rows: true,
+            mark_missing_recordings: true,
+            ..options()
+        });
+        assert!(ctx.rows_to_delete.is_empty());
+        assert_eq!(ids(&ctx.rows_to_flag_corrupt), [missing]);
+    }
+
+    #[test]
+    fn parallel_map_preserves_order() {
+        testutil::init();
+        let items: Vec<u32> = (0..1000).collect();
+        for concurrency in [0, 1, 4, 2000] {
+            assert_eq!(
+                parallel_map(&items, concurrency, |&i| i * 2),
+                items.iter().map(|&i| i * 2).collect::<Vec<_>>()
+            );
+        }
+        assert!(parallel_map(&[] as &[u32], 4, |&i| i).is_empty());
+    }
+
+    #[test]
+    fn hash_plain_file() {
+        testutil::init();
+        let id = CompositeId::new(1, 1);
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let throttle = Throttle::new(0);
+        let h = hash_file(&mut &data[..], id, None, &throttle).unwrap();
+        assert_eq!(h, blake3::hash(&data));
+    }
+
+    #[test]
+    fn hash_encrypted_file() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let id = CompositeId::new(1, 1);
+        let master = MasterKey::new(&[1u8; 32]).unwrap();
+        let wrapped = master.new_wrapped_key(id).unwrap();
+        let key = Arc::new(master.unwrap_key(id, &wrapped).unwrap());
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let path = tmpdir.path().join("f");
+        {
+            let mut f = crypto::EncryptingFile::new(
+                std::fs::File::create(&path).unwrap(),
+                id,
+                key.clone(),
+                Arc::new(crypto::Tails::default()),
+            );
+            let mut buf = &data[..];
+            while !buf.is_empty() {
+                let n = f.write(buf).unwrap();
+                buf = &buf[n..];
+            }
+            f.finish().unwrap();
+        }
+        let throttle = Throttle::new(0);
+        let mut encrypted = std::fs::read(&path).unwrap();
+        let h = hash_file(&mut &encrypted[..], id, Some(&key), &throttle).unwrap();
+        assert_eq!(h, blake3::hash(&data));
+
+        // Flipping a bit causes decryption to fail.
+        encrypted[100_000] ^= 1;
+        let e = hash_file(&mut &encrypted[..], id, Some(&key), &throttle).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::DataLoss);
+    }
+}
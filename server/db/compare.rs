@@ -5,9 +5,14 @@
 //! Comparison of actual and expected on-disk schema.
 //! This is used as part of the `moonfire-nvr check` database integrity checking
 //! and for tests of `moonfire-nvr upgrade`.
+//!
+//! Tables, columns, and indices are compared via SQLite's pragmas. Properties the pragmas don't
+//! expose (table options, partial index `where` clauses, triggers, and views) are compared via
+//! the SQL in `sqlite_master`, normalized to ignore comments, whitespace, and case.
 
 use base::Error;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
 use std::fmt::Write;
 
 #[derive(Debug, PartialEq)]
@@ -33,6 +38,9 @@ struct Index {
     unique: bool,
     origin: String,
     partial: bool,
+
+    /// For a partial index, the normalized `where` clause.
+    where_: Option<String>,
 }
 
 impl std::fmt::Display for Index {
@@ -54,6 +62,132 @@ impl std::fmt::Display for IndexColumn {
     }
 }
 
+/// Options specified after a table's column definitions.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct TableOptions {
+    without_rowid: bool,
+    strict: bool,
+}
+
+impl std::fmt::Display for TableOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A trigger or view.
+#[derive(Debug, Eq, PartialEq)]
+struct SchemaObject {
+    name: String,
+    tbl_name: String,
+
+    /// The normalized `create` statement.
+    sql: String,
+}
+
+impl std::fmt::Display for SchemaObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Normalizes SQL for comparison: strips comments, collapses whitespace, and lowercases all
+/// but quoted strings and identifiers.
+fn normalize_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                pending_space = true;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = '\0';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                pending_space = true;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                pending_space = true;
+                continue;
+            }
+            _ => {}
+        }
+        if pending_space && !out.is_empty() && !out.ends_with('(') && !matches!(c, ')' | ',' | ';')
+        {
+            out.push(' ');
+        }
+        pending_space = false;
+        match c {
+            '\'' | '"' | '`' => {
+                out.push(c);
+                while let Some(q) = chars.next() {
+                    out.push(q);
+                    if q == c {
+                        if chars.peek() == Some(&c) {
+                            out.push(chars.next().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            c => out.push(c.to_ascii_lowercase()),
+        }
+    }
+    out
+}
+
+/// Returns the options from a normalized `create table` statement.
+fn parse_table_options(sql: &str) -> TableOptions {
+    let mut opts = TableOptions::default();
+    let Some(i) = sql.rfind(')') else {
+        return opts;
+    };
+    for o in sql[i + 1..].trim_end_matches(';').split(',') {
+        match o.trim() {
+            "without rowid" => opts.without_rowid = true,
+            "strict" => opts.strict = true,
+            _ => {}
+        }
+    }
+    opts
+}
+
+/// Returns the `where` clause (without the `where` keyword) from a normalized `create index`
+/// statement, if any.
+fn parse_index_where(sql: &str) -> Option<String> {
+    let start = sql.find('(')?;
+    let mut depth = 0;
+    for (i, c) in sql[start..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    let rest = sql[start + i + 1..].trim().trim_end_matches(';');
+                    return rest.strip_prefix("where ").map(|w| w.trim().to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 /// If `slice1` and `slice2` differ, return differences in roughly unified diff form.
 fn diff_slices<T: std::fmt::Display + PartialEq>(
     name1: &str,
@@ -101,6 +235,57 @@ fn get_tables(c: &rusqlite::Connection) -> Result<Vec<String>, rusqlite::Error>
     .collect()
 }
 
+/// Returns the SQL for the given table or index from `sqlite_master`, if any.
+/// Automatic indices have no SQL, and tables or indices which don't exist in this database
+/// (as when comparing against a schema which has them) are treated the same way.
+fn get_sql(c: &rusqlite::Connection, name: &str) -> Result<Option<String>, rusqlite::Error> {
+    Ok(
+        c.prepare_cached("select sql from sqlite_master where name = ?")?
+            .query_row(params![name], |r| r.get::<_, Option<String>>(0))
+            .optional()?
+            .flatten(),
+    )
+}
+
+/// Returns the options of the given table.
+fn get_table_options(
+    c: &rusqlite::Connection,
+    table: &str,
+) -> Result<TableOptions, rusqlite::Error> {
+    Ok(get_sql(c, table)?
+        .map(|s| parse_table_options(&normalize_sql(&s)))
+        .unwrap_or_default())
+}
+
+/// Returns a vec of the triggers or views (as specified by `type_`) in the given connection,
+/// sorted by name.
+fn get_schema_objects(
+    c: &rusqlite::Connection,
+    type_: &str,
+) -> Result<Vec<SchemaObject>, rusqlite::Error> {
+    c.prepare(
+        r#"
+        select
+            name,
+            tbl_name,
+            sql
+        from
+            sqlite_master
+        where
+            type = ?
+        order by name
+        "#,
+    )?
+    .query_map(params![type_], |r| {
+        Ok(SchemaObject {
+            name: r.get(0)?,
+            tbl_name: r.get(1)?,
+            sql: normalize_sql(&r.get::<_, String>(2)?),
+        })
+    })?
+    .collect()
+}
+
 /// Returns a vec of columns in the given table.
 fn get_table_columns(
     c: &rusqlite::Connection,
@@ -126,7 +311,8 @@ fn get_table_columns(
 /// Returns a vec of indices associated with the given table.
 fn get_indices(c: &rusqlite::Connection, table: &str) -> Result<Vec<Index>, rusqlite::Error> {
     // See note at get_tables_columns about placeholders.
-    c.prepare(&format!("pragma index_list(\"{table}\")"))?
+    let mut indices = c
+        .prepare(&format!("pragma index_list(\"{table}\")"))?
         .query_map(params![], |r| {
             Ok(Index {
                 seq: r.get(0)?,
@@ -134,9 +320,16 @@ fn get_indices(c: &rusqlite::Connection, table: &str) -> Result<Vec<Index>, rusq
                 unique: r.get(2)?,
                 origin: r.get(3)?,
                 partial: r.get(4)?,
+                where_: None,
             })
         })?
-        .collect()
+        .collect::<Result<Vec<_>, _>>()?;
+    for i in &mut indices {
+        if i.partial {
+            i.where_ = get_sql(c, &i.name)?.and_then(|s| parse_index_where(&normalize_sql(&s)));
+        }
+    }
+    Ok(indices)
 }
 
 /// Returns a vec of all the columns in the given index.
@@ -156,52 +349,354 @@ fn get_index_columns(
         .collect()
 }
 
+/// Differences between two schemas, as returned by [`get_diffs`].
+///
+/// "Removed" items are present in the first schema but not the second; "added" items are the
+/// reverse. The `Display` impl describes the differences in roughly unified diff form.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaDiff {
+    pub removed_tables: Vec<String>,
+    pub added_tables: Vec<String>,
+
+    /// Tables present in both schemas which differ.
+    pub changed_tables: Vec<TableDiff>,
+
+    pub triggers: ObjectsDiff,
+    pub views: ObjectsDiff,
+
+    #[serde(skip)]
+    text: String,
+}
+
+impl SchemaDiff {
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Differences in a single table present in both schemas.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableDiff {
+    pub name: String,
+
+    /// True iff the table options (`without rowid` or `strict`) differ.
+    pub options_changed: bool,
+    pub removed_columns: Vec<String>,
+    pub added_columns: Vec<String>,
+
+    /// Columns whose type, constraints, default, or position differ.
+    pub changed_columns: Vec<String>,
+    pub removed_indices: Vec<String>,
+    pub added_indices: Vec<String>,
+
+    /// Indices whose properties or columns differ.
+    pub changed_indices: Vec<String>,
+}
+
+impl TableDiff {
+    fn is_empty(&self) -> bool {
+        !self.options_changed
+            && self.removed_columns.is_empty()
+            && self.added_columns.is_empty()
+            && self.changed_columns.is_empty()
+            && self.removed_indices.is_empty()
+            && self.added_indices.is_empty()
+            && self.changed_indices.is_empty()
+    }
+}
+
+/// Differences in the triggers or views of two schemas.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectsDiff {
+    pub removed: Vec<String>,
+    pub added: Vec<String>,
+
+    /// Objects whose definitions differ.
+    pub changed: Vec<String>,
+}
+
+/// Returns the names of items in `items1` and not `items2`, items in `items2` and not `items1`,
+/// and items in both which aren't equal.
+fn classify<T: PartialEq>(
+    items1: &[T],
+    items2: &[T],
+    name: impl Fn(&T) -> &str,
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let (mut removed, mut added, mut changed) = (Vec::new(), Vec::new(), Vec::new());
+    for i1 in items1 {
+        match items2.iter().find(|i2| name(i2) == name(i1)) {
+            None => removed.push(name(i1).to_owned()),
+            Some(i2) if i1 != i2 => changed.push(name(i1).to_owned()),
+            Some(_) => {}
+        }
+    }
+    for i2 in items2 {
+        if !items1.iter().any(|i1| name(i1) == name(i2)) {
+            added.push(name(i2).to_owned());
+        }
+    }
+    (removed, added, changed)
+}
+
+/// Compares the schemas of two databases, returning the differences if any.
 pub fn get_diffs(
     n1: &str,
     c1: &rusqlite::Connection,
     n2: &str,
     c2: &rusqlite::Connection,
-) -> Result<Option<String>, Error> {
-    let mut diffs = String::new();
+) -> Result<Option<SchemaDiff>, Error> {
+    let mut diffs = SchemaDiff::default();
 
     // Compare table list.
     let tables1 = get_tables(c1)?;
     let tables2 = get_tables(c2)?;
     if let Some(diff) = diff_slices(n1, &tables1[..], n2, &tables2[..]) {
-        write!(&mut diffs, "table list mismatch, {n1} vs {n2}:\n{diff}")
-            .expect("write to String shouldn't fail");
+        write!(
+            &mut diffs.text,
+            "table list mismatch, {n1} vs {n2}:\n{diff}"
+        )
+        .expect("write to String shouldn't fail");
     }
+    (diffs.removed_tables, diffs.added_tables, _) = classify(&tables1, &tables2, |t| t.as_str());
 
-    // Compare columns and indices for each table.
+    // Compare options, columns, and indices for each table.
     for t in &tables1 {
+        let mut table_diff = TableDiff {
+            name: t.clone(),
+            ..Default::default()
+        };
+        let options1 = get_table_options(c1, t)?;
+        let options2 = get_table_options(c2, t)?;
+        if let Some(diff) = diff_slices(n1, &[options1], n2, &[options2]) {
+            write!(
+                &mut diffs.text,
+                "table {t:?} options, {n1} vs {n2}:\n{diff}"
+            )
+            .expect("write to String shouldn't fail");
+            table_diff.options_changed = true;
+        }
+
         let columns1 = get_table_columns(c1, t)?;
         let columns2 = get_table_columns(c2, t)?;
         if let Some(diff) = diff_slices(n1, &columns1[..], n2, &columns2[..]) {
-            write!(&mut diffs, "table {t:?} column, {n1} vs {n2}:\n{diff}")
+            write!(&mut diffs.text, "table {t:?} column, {n1} vs {n2}:\n{diff}")
                 .expect("write to String shouldn't fail");
         }
+        (
+            table_diff.removed_columns,
+            table_diff.added_columns,
+            table_diff.changed_columns,
+        ) = classify(&columns1, &columns2, |c| c.name.as_str());
 
         let mut indices1 = get_indices(c1, t)?;
         let mut indices2 = get_indices(c2, t)?;
         indices1.sort_by(|a, b| a.name.cmp(&b.name));
         indices2.sort_by(|a, b| a.name.cmp(&b.name));
         if let Some(diff) = diff_slices(n1, &indices1[..], n2, &indices2[..]) {
-            write!(&mut diffs, "table {t:?} indices, {n1} vs {n2}:\n{diff}")
-                .expect("write to String shouldn't fail");
+            write!(
+                &mut diffs.text,
+                "table {t:?} indices, {n1} vs {n2}:\n{diff}"
+            )
+            .expect("write to String shouldn't fail");
         }
+        (
+            table_diff.removed_indices,
+            table_diff.added_indices,
+            table_diff.changed_indices,
+        ) = classify(&indices1, &indices2, |i| i.name.as_str());
 
         for i in &indices1 {
             let ic1 = get_index_columns(c1, &i.name)?;
             let ic2 = get_index_columns(c2, &i.name)?;
             if let Some(diff) = diff_slices(n1, &ic1[..], n2, &ic2[..]) {
                 write!(
-                    &mut diffs,
+                    &mut diffs.text,
                     "table {t:?} index {i:?} columns {n1} vs {n2}:\n{diff}"
                 )
                 .expect("write to String shouldn't fail");
+                if !ic2.is_empty() && !table_diff.changed_indices.contains(&i.name) {
+                    table_diff.changed_indices.push(i.name.clone());
+                }
             }
         }
+
+        // Removed tables are reported as such, not as having removed all their columns.
+        if !table_diff.is_empty() && tables2.contains(t) {
+            diffs.changed_tables.push(table_diff);
+        }
+    }
+
+    // Compare triggers and views.
+    for (type_, objects_diff) in [("trigger", &mut diffs.triggers), ("view", &mut diffs.views)] {
+        let objects1 = get_schema_objects(c1, type_)?;
+        let objects2 = get_schema_objects(c2, type_)?;
+        if let Some(diff) = diff_slices(n1, &objects1[..], n2, &objects2[..]) {
+            write!(
+                &mut diffs.text,
+                "{type_} list mismatch, {n1} vs {n2}:\n{diff}"
+            )
+            .expect("write to String shouldn't fail");
+        }
+        (
+            objects_diff.removed,
+            objects_diff.added,
+            objects_diff.changed,
+        ) = classify(&objects1, &objects2, |o| o.name.as_str());
     }
 
     Ok(if diffs.is_empty() { None } else { Some(diffs) })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil;
+
+    #[test]
+    fn normalize_sql() {
+        testutil::init();
+        assert_eq!(
+            super::normalize_sql(
+                "CREATE TABLE \"Foo\" ( -- comment\n  id  integer, /* more */ x text default 'A  B'\n) WITHOUT ROWID;"
+            ),
+            "create table \"Foo\" (id integer, x text default 'A  B') without rowid;"
+        );
+        assert_eq!(super::normalize_sql("select 'it''s'"), "select 'it''s'");
+    }
+
+    #[test]
+    fn parse() {
+        testutil::init();
+        assert_eq!(
+            super::parse_table_options("create table t (a, b) strict, without rowid"),
+            super::TableOptions {
+                without_rowid: true,
+                strict: true,
+            }
+        );
+        assert_eq!(
+            super::parse_table_options("create table t (a check (a > 0))"),
+            super::TableOptions::default()
+        );
+        assert_eq!(
+            super::parse_index_where("create index i on t (a, (b + 1)) where a > (1);").as_deref(),
+            Some("a > (1)")
+        );
+        assert_eq!(super::parse_index_where("create index i on t (a)"), None);
+    }
+
+    #[test]
+    fn diffs() {
+        testutil::init();
+        let expected = rusqlite::Connection::open_in_memory().unwrap();
+        expected
+            .execute_batch(
+                "create table t (a integer primary key, b integer);
+                 create index i on t (b) where b > 0;",
+            )
+            .unwrap();
+        let cases = [
+            (
+                "create table t (a integer primary key, b integer) without rowid;
+                 create index i on t (b) where b > 0;",
+                "table \"t\" options",
+            ),
+            (
+                "create table t (a integer primary key, b integer);
+                 create index i on t (b) where b > 1;",
+                "table \"t\" indices",
+            ),
+            (
+                "create table t (a integer primary key, b integer);
+                 create index i on t (b) where b > 0;
+                 create trigger tr after insert on t begin select 1; end;",
+                "trigger list mismatch",
+            ),
+            (
+                "create table t (a integer primary key, b integer);
+                 create index i on t (b) where b > 0;
+                 create view v as select a from t;",
+                "view list mismatch",
+            ),
+            (
+                "create table t (a integer primary key, b integer);
+                 create table u (x integer) without rowid;
+                 create index i on t (b) where b > 0;",
+                "table list mismatch",
+            ),
+            (
+                "create table u (a integer primary key, b integer);",
+                "table list mismatch",
+            ),
+        ];
+        for (schema, expected_diff) in cases {
+            let actual = rusqlite::Connection::open_in_memory().unwrap();
+            actual.execute_batch(schema).unwrap();
+            let diffs = super::get_diffs("actual", &actual, "expected", &expected)
+                .unwrap()
+                .unwrap();
+            assert!(
+                diffs.to_string().contains(expected_diff),
+                "{schema}: {diffs}"
+            );
+        }
+
+        // The structured form has details.
+        let actual = rusqlite::Connection::open_in_memory().unwrap();
+        actual
+            .execute_batch(
+                "create table t (a integer primary key, b text, c integer);
+                 create table u (x);
+                 create index i on t (b);
+                 create view v as select 1;",
+            )
+            .unwrap();
+        let expected = rusqlite::Connection::open_in_memory().unwrap();
+        expected
+            .execute_batch(
+                "create table t (a integer primary key, b integer);
+                 create table w (y);
+                 create index i on t (b) where b > 0;
+                 create index j on t (a);",
+            )
+            .unwrap();
+        let diffs = super::get_diffs("actual", &actual, "expected", &expected)
+            .unwrap()
+            .unwrap();
+        assert_eq!(diffs.removed_tables, ["u"]);
+        assert_eq!(diffs.added_tables, ["w"]);
+        assert_eq!(diffs.changed_tables.len(), 1);
+        let t = &diffs.changed_tables[0];
+        assert_eq!(t.name, "t");
+        assert!(!t.options_changed);
+        assert_eq!(t.removed_columns, ["c"]);
+        assert!(t.added_columns.is_empty());
+        assert_eq!(t.changed_columns, ["b"]);
+        assert!(t.removed_indices.is_empty());
+        assert_eq!(t.added_indices, ["j"]);
+        assert_eq!(t.changed_indices, ["i"]);
+        assert_eq!(diffs.views.removed, ["v"]);
+        assert!(diffs.triggers.added.is_empty());
+
+        // Formatting differences don't matter.
+        let c1 = rusqlite::Connection::open_in_memory().unwrap();
+        c1.execute_batch("create table t (a integer primary key) without rowid;")
+            .unwrap();
+        c1.execute_batch("create view v as select a from t;")
+            .unwrap();
+        let c2 = rusqlite::Connection::open_in_memory().unwrap();
+        c2.execute_batch("CREATE TABLE t (\n  a INTEGER PRIMARY KEY -- the key\n) WITHOUT ROWID;")
+            .unwrap();
+        c2.execute_batch("CREATE VIEW v AS\n  SELECT a FROM t;")
+            .unwrap();
+        assert!(super::get_diffs("a", &c1, "b", &c2).unwrap().is_none());
+    }
+}
@@ -31,6 +31,14 @@ impl Key {
         Ok(s)
     }
 
+    /// Returns the day (in local time) containing `t`.
+    pub fn containing(t: Time) -> Result<Self, Error> {
+        Key::new(time::at(time::Timespec {
+            sec: t.unix_seconds(),
+            nsec: 0,
+        }))
+    }
+
     pub fn bounds(&self) -> Range<Time> {
         let mut my_tm = time::strptime(self.as_ref(), "%Y-%m-%d").expect("days must be parseable");
         my_tm.tm_utcoff = 1; // to the time crate, values != 0 mean local time.
@@ -76,6 +84,10 @@ pub struct StreamValue {
     /// from the time of the next frame, a recording that ends unexpectedly after a single frame
     /// will have 0 duration of that frame and thus the whole recording.
     pub duration: Duration,
+
+    /// The sample file bytes recorded on this day. A recording which spans two days has its bytes
+    /// divided between them in proportion to its duration on each.
+    pub bytes: i64,
 }
 
 impl Value for StreamValue {
@@ -84,6 +96,7 @@ impl Value for StreamValue {
     fn apply(&mut self, c: &StreamValue) {
         self.recordings += c.recordings;
         self.duration += c.duration;
+        self.bytes += c.bytes;
     }
 
     fn is_empty(&self) -> bool {
@@ -203,7 +216,7 @@ impl Map<StreamValue> {
     ///
     /// This function swallows/logs date formatting errors because they shouldn't happen and there's
     /// not much that can be done about them. (The database operation has already gone through.)
-    pub(crate) fn adjust(&mut self, r: Range<Time>, sign: i64) {
+    pub(crate) fn adjust(&mut self, r: Range<Time>, sample_file_bytes: i64, sign: i64) {
         // Find first day key.
         let sec = r.start.unix_seconds();
         let mut my_tm = time::at(time::Timespec { sec, nsec: 0 });
@@ -229,9 +242,12 @@ impl Map<StreamValue> {
         let boundary_90k = boundary.sec * TIME_UNITS_PER_SEC;
 
         // Adjust the first day.
+        let first_day_duration = cmp::min(r.end.0, boundary_90k) - r.start.0;
+        let first_day_bytes = apportion(sample_file_bytes, first_day_duration, r.end.0 - r.start.0);
         let first_day_delta = StreamValue {
             recordings: sign,
-            duration: Duration(sign * (cmp::min(r.end.0, boundary_90k) - r.start.0)),
+            duration: Duration(sign * first_day_duration),
+            bytes: sign * first_day_bytes,
         };
         self.adjust_day(day, first_day_delta);
 
@@ -256,11 +272,29 @@ impl Map<StreamValue> {
         let second_day_delta = StreamValue {
             recordings: sign,
             duration: Duration(sign * (r.end.0 - boundary_90k)),
+            bytes: sign * (sample_file_bytes - first_day_bytes),
         };
         self.adjust_day(day, second_day_delta);
     }
 }
 
+/// Returns the portion of `bytes` corresponding to `part` of `whole` (a recording's duration).
+/// Zero-duration recordings are attributed entirely to their start.
+pub fn apportion(bytes: i64, part: i64, whole: i64) -> i64 {
+    if whole <= 0 {
+        return bytes;
+    }
+    i64::try_from(i128::from(bytes) * i128::from(part) / i128::from(whole)).expect("part <= whole")
+}
+
+/// Returns the bounds of the hour (in local time) containing `t`.
+pub fn hour_containing(t: Time) -> Range<Time> {
+    let sec = t.unix_seconds();
+    let tm = time::at(time::Timespec { sec, nsec: 0 });
+    let start = sec - i64::from(tm.tm_min) * 60 - i64::from(tm.tm_sec);
+    Time(start * TIME_UNITS_PER_SEC)..Time((start + 3600) * TIME_UNITS_PER_SEC)
+}
+
 impl Map<SignalValue> {
     /// Adjusts `self` to reflect the range of the given recording.
     /// Note that the specified range may span several days (unlike StreamValue).
@@ -332,7 +366,7 @@ impl Map<SignalValue> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Key, Map, SignalValue, StreamValue};
+    use super::{hour_containing, Key, Map, SignalValue, StreamValue};
     use crate::testutil;
     use base::time::{Duration, Time, TIME_UNITS_PER_SEC};
     use smallvec::smallvec;
@@ -350,98 +384,107 @@ mod tests {
         let four_min = Duration(4 * 60 * TIME_UNITS_PER_SEC);
         let test_day1 = &Key(*b"2015-12-31");
         let test_day2 = &Key(*b"2016-01-01");
-        m.adjust(test_time..test_time + one_min, 1);
+        m.adjust(test_time..test_time + one_min, 1000, 1);
         assert_eq!(1, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: one_min
+                duration: one_min,
+                bytes: 1000,
             }),
             m.get(test_day1)
         );
 
         // Add to a day.
-        m.adjust(test_time..test_time + one_min, 1);
+        m.adjust(test_time..test_time + one_min, 1000, 1);
         assert_eq!(1, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 2,
-                duration: two_min
+                duration: two_min,
+                bytes: 2000,
             }),
             m.get(test_day1)
         );
 
         // Subtract from a day.
-        m.adjust(test_time..test_time + one_min, -1);
+        m.adjust(test_time..test_time + one_min, 1000, -1);
         assert_eq!(1, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: one_min
+                duration: one_min,
+                bytes: 1000,
             }),
             m.get(test_day1)
         );
 
         // Remove a day.
-        m.adjust(test_time..test_time + one_min, -1);
+        m.adjust(test_time..test_time + one_min, 1000, -1);
         assert_eq!(0, m.len());
 
         // Create two days.
-        m.adjust(test_time..test_time + three_min, 1);
+        m.adjust(test_time..test_time + three_min, 3000, 1);
         assert_eq!(2, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: one_min
+                duration: one_min,
+                bytes: 1000,
             }),
             m.get(test_day1)
         );
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: two_min
+                duration: two_min,
+                bytes: 2000,
             }),
             m.get(test_day2)
         );
 
         // Add to two days.
-        m.adjust(test_time..test_time + three_min, 1);
+        m.adjust(test_time..test_time + three_min, 3000, 1);
         assert_eq!(2, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 2,
-                duration: two_min
+                duration: two_min,
+                bytes: 2000,
             }),
             m.get(test_day1)
         );
         assert_eq!(
             Some(&StreamValue {
                 recordings: 2,
-                duration: four_min
+                duration: four_min,
+                bytes: 4000,
             }),
             m.get(test_day2)
         );
 
         // Subtract from two days.
-        m.adjust(test_time..test_time + three_min, -1);
+        m.adjust(test_time..test_time + three_min, 3000, -1);
         assert_eq!(2, m.len());
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: one_min
+                duration: one_min,
+                bytes: 1000,
             }),
             m.get(test_day1)
         );
         assert_eq!(
             Some(&StreamValue {
                 recordings: 1,
-                duration: two_min
+                duration: two_min,
+                bytes: 2000,
             }),
             m.get(test_day2)
         );
 
         // Remove two days.
-        m.adjust(test_time..test_time + three_min, -1);
+        m.adjust(test_time..test_time + three_min, 3000, -1);
         assert_eq!(0, m.len());
     }
 
@@ -529,4 +572,29 @@ mod tests {
             Time(135887868000000)..Time(135895968000000)
         );
     }
+
+    #[test]
+    fn test_day_containing() {
+        testutil::init();
+        let bounds = Key(*b"2017-10-10").bounds();
+        assert_eq!(Key::containing(bounds.start).unwrap(), Key(*b"2017-10-10"));
+        assert_eq!(
+            Key::containing(bounds.end - Duration(1)).unwrap(),
+            Key(*b"2017-10-10")
+        );
+        assert_eq!(Key::containing(bounds.end).unwrap(), Key(*b"2017-10-11"));
+    }
+
+    #[test]
+    fn test_hour_containing() {
+        testutil::init();
+        let hr = Duration(60 * 60 * TIME_UNITS_PER_SEC);
+        let start = Time(130646844000000i64); // 2015-12-31 23:00:00 (Pacific).
+        assert_eq!(hour_containing(start), start..start + hr);
+        assert_eq!(
+            hour_containing(Time(130647162600000i64)), // 2015-12-31 23:59:00 (Pacific).
+            start..start + hr
+        );
+        assert_eq!(hour_containing(start + hr), start + hr..start + hr * 2);
+    }
 }
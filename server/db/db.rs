@@ -26,6 +26,7 @@
 //!     A list of mutations is built up in-memory and occasionally flushed to reduce SSD write
 //!     cycles.
 
+use crate::audit;
 use crate::auth;
 use crate::days;
 use crate::dir;
@@ -42,7 +43,7 @@ use hashlink::LinkedHashMap;
 use itertools::Itertools;
 use rusqlite::{named_params, params};
 use smallvec::SmallVec;
-use std::cell::RefCell;
+use std::cell::{OnceCell, RefCell};
 use std::cmp;
 use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Write as _;
@@ -59,16 +60,33 @@ use tracing::{error, info, trace};
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_SCHEMA_VERSION: i32 = 7;
+pub const EXPECTED_SCHEMA_VERSION: i32 = 18;
 
 /// Length of the video index cache.
 /// The actual data structure is one bigger than this because we insert before we remove.
 /// Make it one less than a power of two so that the data structure's size is efficient.
 const VIDEO_INDEX_CACHE_LEN: usize = 1023;
 
+/// The number of database pages to copy in each step of an online backup, with the database
+/// lock held. At SQLite's default 4 KiB page size, this is 4 MiB.
+const BACKUP_STEP_PAGES: i32 = 1024;
+
+/// The pause between steps of an online backup, to let others take the database lock.
+const BACKUP_STEP_PAUSE: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The number of freelist pages to reclaim in each step of [`Database::maintain`], with the
+/// database lock held.
+const VACUUM_STEP_PAGES: i64 = 1024;
+
+/// The approximate number of rows of each index examined by `analyze` in
+/// [`LockedDatabase::analyze`]; see <https://www.sqlite.org/lang_analyze.html#approx>.
+const ANALYSIS_LIMIT: i64 = 1000;
+
 const GET_RECORDING_PLAYBACK_SQL: &str = r#"
     select
-      video_index
+      video_index,
+      audio_index,
+      wrapped_key
     from
       recording_playback
     where
@@ -82,6 +100,11 @@ const INSERT_VIDEO_SAMPLE_ENTRY_SQL: &str = r#"
                                     :rfc6381_codec, :data)
 "#;
 
+const INSERT_AUDIO_SAMPLE_ENTRY_SQL: &str = r#"
+    insert into audio_sample_entry (rfc6381_codec,  sample_rate,  channels,  data)
+                            values (:rfc6381_codec, :sample_rate, :channels, :data)
+"#;
+
 const UPDATE_STREAM_COUNTERS_SQL: &str = r#"
     update stream
     set cum_recordings = :cum_recordings,
@@ -123,6 +146,16 @@ impl rusqlite::types::ToSql for SqlUuid {
 
 struct VideoIndex(Box<[u8]>);
 
+/// The contents of a `recording_playback` row, as stored in `LockedDatabase::video_index_cache`.
+struct CachedPlayback {
+    video_index: Box<[u8]>,
+    audio_index: Option<Box<[u8]>>,
+    wrapped_key: Option<Box<[u8]>>,
+
+    /// The key frames parsed from `video_index`, filled on first use.
+    key_frames: OnceCell<recording::KeyFrames>,
+}
+
 impl rusqlite::types::FromSql for VideoIndex {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
         Ok(VideoIndex(value.as_blob()?.to_vec().into_boxed_slice()))
@@ -152,6 +185,47 @@ impl VideoSampleEntry {
             u32::from(self.height) * u32::from(self.pasp_v_spacing),
         )
     }
+
+    /// Returns the four-character box type, such as `avc1` for H.264 or `hvc1` for H.265.
+    pub fn box_type(&self) -> &[u8] {
+        &self.data[4..8]
+    }
+
+    /// Returns the decoder configuration record: the body of the `avcC` or `hvcC` box which
+    /// follows the 8-byte box header and 78-byte `VisualSampleEntry` fields.
+    ///
+    /// Fails with `Unimplemented` for codecs without such a record, such as `jpeg`.
+    pub fn decoder_config(&self) -> Result<&[u8], Error> {
+        let config_type: &[u8] = match self.box_type() {
+            b"avc1" => b"avcC",
+            b"hvc1" => b"hvcC",
+            t => bail!(
+                Unimplemented,
+                msg(
+                    "video sample entry type {:?} has no decoder configuration",
+                    String::from_utf8_lossy(t)
+                ),
+            ),
+        };
+        let config = &self.data[cmp::min(86, self.data.len())..];
+        if config.len() < 8 || &config[4..8] != config_type {
+            bail!(
+                InvalidArgument,
+                msg("video sample entry {} has no configuration box", self.id),
+            );
+        }
+        let len = u32::from_be_bytes(config[0..4].try_into().unwrap()) as usize;
+        config.get(8..len).ok_or_else(|| {
+            err!(
+                InvalidArgument,
+                msg(
+                    "truncated {} box in video sample entry {}",
+                    String::from_utf8_lossy(config_type),
+                    self.id
+                )
+            )
+        })
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -178,6 +252,39 @@ impl std::fmt::Debug for VideoSampleEntryToInsert {
     }
 }
 
+/// A concrete box derived from a ISO/IEC 14496-12 section 12.2.3 AudioSampleEntry box. Describes
+/// the codec, sample rate, and channel count.
+#[derive(Debug)]
+pub struct AudioSampleEntry {
+    pub id: i32,
+
+    // Fields matching AudioSampleEntryToInsert below.
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AudioSampleEntryToInsert {
+    pub data: Vec<u8>,
+    pub rfc6381_codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl std::fmt::Debug for AudioSampleEntryToInsert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use pretty_hex::PrettyHex;
+        f.debug_struct("AudioSampleEntryToInsert")
+            .field("data", &self.data.hex_dump())
+            .field("rfc6381_codec", &self.rfc6381_codec)
+            .field("sample_rate", &self.sample_rate)
+            .field("channels", &self.channels)
+            .finish()
+    }
+}
+
 /// A row used in `list_recordings_by_time` and `list_recordings_by_id`.
 #[derive(Copy, Clone, Debug)]
 pub struct ListRecordingsRow {
@@ -196,12 +303,100 @@ pub struct ListRecordingsRow {
     pub open_id: u32,
     pub flags: i32,
 
+    /// The audio sample entry, if this recording has audio. See `design/schema.md#audio_index`.
+    pub audio_sample_entry_id: Option<i32>,
+    pub audio_samples: i32,
+
+    /// The portion of `sample_file_bytes` used by audio samples, which follow the video samples.
+    pub audio_sample_file_bytes: i32,
+
+    /// The archive directory holding the sample file, or `None` if it's in the stream's own
+    /// directory.
+    pub sample_file_dir_id: Option<i32>,
+
     /// This is populated by `list_recordings_by_id` but not `list_recordings_by_time`.
     /// (It's not included in the `recording_cover` index, so adding it to
     /// `list_recordings_by_time` would be inefficient.)
     pub prev_media_duration_and_runs: Option<(recording::Duration, i32)>,
 }
 
+/// A row used in `list_thumbnails`.
+#[derive(Clone, Debug)]
+pub struct ListThumbnailsRow {
+    pub start: recording::Time,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// An object found in a frame; see `insert_detections` and `list_detections`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Detection {
+    /// The wall time at which the analyzed frame was received.
+    pub time: recording::Time,
+
+    /// The label of the object's class, such as `person`.
+    pub class: String,
+
+    /// The model's confidence, from 0 to 1.
+    pub score: f32,
+
+    /// The bounding box, in fractions of the frame's width and height from its top-left corner.
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Totals describing the health of a stream's ingest over some period; see
+/// `add_stream_health` and the `stream_health` table in `schema.sql`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamHealth {
+    /// Seconds during which a streamer was running for the stream.
+    pub monitored_sec: i64,
+
+    /// Seconds (of `monitored_sec`) during which the stream was receiving frames.
+    pub up_sec: i64,
+
+    /// Times the stream was reopened after an error.
+    pub reconnects: i64,
+
+    /// Times a connected stream went unusually long between video frames.
+    pub frame_gaps: i64,
+
+    /// Video frames received.
+    pub frames: i64,
+
+    /// RTP packets known to be lost.
+    pub packets_lost: i64,
+}
+
+impl StreamHealth {
+    pub fn add(&mut self, other: &StreamHealth) {
+        self.monitored_sec += other.monitored_sec;
+        self.up_sec += other.up_sec;
+        self.reconnects += other.reconnects;
+        self.frame_gaps += other.frame_gaps;
+        self.frames += other.frames;
+        self.packets_lost += other.packets_lost;
+    }
+}
+
+/// A recording copied to cloud storage; see `insert_upload` and the `upload` table in
+/// `schema.sql`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upload {
+    pub id: CompositeId,
+
+    /// The key of the sample file's object; the index manifest's key has a `.json` suffix.
+    pub object_key: String,
+    pub sample_file_bytes: i32,
+    pub sample_file_sha256: [u8; 32],
+    pub upload_time: recording::Time,
+
+    /// When the objects were verified to be present with the expected size, if they have been.
+    pub verify_time: Option<recording::Time>,
+}
+
 /// A row used in `list_aggregated_recordings`.
 #[derive(Clone, Debug)]
 pub struct ListAggregatedRecordingsRow {
@@ -245,16 +440,195 @@ impl ListAggregatedRecordingsRow {
     }
 }
 
+/// Aggregates consecutive recordings into [`ListAggregatedRecordingsRow`]s.
+///
+/// This is used by [`LockedDatabase::list_aggregated_recordings_from`] and by callers of
+/// [`Database::recordings_by_time`]. Rows must be pushed in the order `list_recordings_by_time`
+/// returns them.
+pub struct RecordingAggregator {
+    stream_id: i32,
+    forced_split: recording::Duration,
+    first_id: i32,
+
+    /// A map from a recording id to the aggregated row for the latest batch of recordings from
+    /// the run starting at that id.
+    aggs: BTreeMap<i32, ListAggregatedRecordingsRow>,
+}
+
+impl RecordingAggregator {
+    /// Creates an aggregator which ignores recordings with ids less than `first_id`.
+    pub fn new(stream_id: i32, forced_split: recording::Duration, first_id: i32) -> Self {
+        RecordingAggregator {
+            stream_id,
+            forced_split,
+            first_id,
+            aggs: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a recording, passing any aggregated row it completes to `f`.
+    pub fn push(
+        &mut self,
+        row: ListRecordingsRow,
+        f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        // Runs can be split into multiple batches for a few reasons:
+        //
+        // * forced split (when exceeding a duration limit)
+        // * a missing id (one that was deleted out of order)
+        // * video_sample_entry mismatch (if the parameters changed during a RTSP session)
+        //
+        // This works because in a run, the start_time+duration of recording id r
+        // is equal to the start_time of recording id r+1. Thus ascending times guarantees
+        // ascending ids within a run. (Different runs, however, can be arbitrarily interleaved if
+        // their timestamps overlap. Tracking all active runs prevents that interleaving from
+        // causing problems.) list_recordings_by_time also returns uncommitted recordings in
+        // ascending order by id, and after any committed recordings with lower ids.
+        let stream_id = self.stream_id;
+        let recording_id = row.id.recording();
+        if recording_id < self.first_id {
+            return Ok(());
+        }
+        let run_start_id = recording_id - row.run_offset;
+        let uncommitted = (row.flags & RecordingFlags::Uncommitted as i32) != 0;
+        let growing = (row.flags & RecordingFlags::Growing as i32) != 0;
+        let has_trailing_zero = (row.flags & RecordingFlags::TrailingZero as i32) != 0;
+        use std::collections::btree_map::Entry;
+        match self.aggs.entry(run_start_id) {
+            Entry::Occupied(mut e) => {
+                let a = e.get_mut();
+                let new_dur =
+                    a.time.end - a.time.start + recording::Duration(row.wall_duration_90k as i64);
+                let needs_flush = a.ids.end != recording_id
+                    || row.video_sample_entry_id != a.video_sample_entry_id
+                    || new_dur >= self.forced_split;
+                if needs_flush {
+                    // flush then start a new entry.
+                    f(a)?;
+                    *a = ListAggregatedRecordingsRow::from(row);
+                } else {
+                    // append.
+                    if a.time.end != row.start {
+                        bail!(
+                            Internal,
+                            msg(
+                                "stream {} recording {} ends at {} but {} starts at {}",
+                                stream_id,
+                                a.ids.end - 1,
+                                a.time.end,
+                                row.id,
+                                row.start,
+                            ),
+                        );
+                    }
+                    if a.open_id != row.open_id {
+                        bail!(
+                            Internal,
+                            msg(
+                                "stream {} recording {} has open id {} but {} has {}",
+                                stream_id,
+                                a.ids.end - 1,
+                                a.open_id,
+                                row.id,
+                                row.open_id,
+                            ),
+                        );
+                    }
+                    a.time.end.0 += row.wall_duration_90k as i64;
+                    a.ids.end = recording_id + 1;
+                    a.video_samples += row.video_samples as i64;
+                    a.video_sync_samples += row.video_sync_samples as i64;
+                    a.sample_file_bytes += row.sample_file_bytes as i64;
+                    if uncommitted {
+                        a.first_uncommitted = a.first_uncommitted.or(Some(recording_id));
+                    }
+                    a.growing = growing;
+                    a.has_trailing_zero = has_trailing_zero;
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(ListAggregatedRecordingsRow::from(row));
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the remaining aggregated rows to `f`.
+    pub fn finish(
+        self,
+        f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        for a in self.aggs.values() {
+            f(a)?;
+        }
+        Ok(())
+    }
+}
+
 /// Select fields from the `recordings_playback` table. Retrieve with `with_recording_playback`.
 #[derive(Debug)]
 pub struct RecordingPlayback<'a> {
     pub video_index: &'a [u8],
+
+    /// The audio index, if the recording has audio.
+    pub audio_index: Option<&'a [u8]>,
+
+    /// The wrapped key to the sample file, if it's encrypted. See [`crate::dir::crypto`].
+    pub wrapped_key: Option<&'a [u8]>,
+
+    /// The cached key frames, if this recording is committed.
+    key_frames: Option<&'a OnceCell<recording::KeyFrames>>,
+}
+
+impl<'a> RecordingPlayback<'a> {
+    /// Copies the row, so it can be used after the database lock is released.
+    pub fn to_owned_playback(&self) -> OwnedRecordingPlayback {
+        OwnedRecordingPlayback {
+            video_index: self.video_index.into(),
+            audio_index: self.audio_index.map(Into::into),
+            wrapped_key: self.wrapped_key.map(Into::into),
+        }
+    }
+
+    /// Returns the recording's key frames, parsing and caching them on first use, or `None` if
+    /// the recording isn't committed. Uncommitted recordings' indexes are still growing, so they
+    /// aren't cached.
+    pub fn key_frames(&self) -> Result<Option<&'a recording::KeyFrames>, Error> {
+        let Some(cell) = self.key_frames else {
+            return Ok(None);
+        };
+        if cell.get().is_none() {
+            let _ = cell.set(recording::KeyFrames::parse(self.video_index)?);
+        }
+        Ok(cell.get())
+    }
+}
+
+/// An owned copy of a [`RecordingPlayback`]; see [`RecordingPlayback::to_owned_playback`].
+#[derive(Debug)]
+pub struct OwnedRecordingPlayback {
+    video_index: Box<[u8]>,
+    audio_index: Option<Box<[u8]>>,
+    wrapped_key: Option<Box<[u8]>>,
+}
+
+impl OwnedRecordingPlayback {
+    pub fn as_playback(&self) -> RecordingPlayback<'_> {
+        RecordingPlayback {
+            video_index: &self.video_index,
+            audio_index: self.audio_index.as_deref(),
+            wrapped_key: self.wrapped_key.as_deref(),
+            key_frames: None,
+        }
+    }
 }
 
 /// Bitmask in the `flags` field in the `recordings` table; see `schema.sql`.
 #[repr(u32)]
 pub enum RecordingFlags {
     TrailingZero = 1,
+    Encrypted = 2,
+    Corrupt = 4,
 
     // These values (starting from high bit on down) are never written to the database.
     Growing = 1 << 30,
@@ -284,6 +658,15 @@ pub struct RecordingToInsert {
     pub video_index: Vec<u8>,
     pub sample_file_blake3: Option<[u8; 32]>,
     pub end_reason: Option<String>,
+
+    /// Audio samples, which are filled in only when the recording is closed.
+    pub audio_sample_entry_id: Option<i32>,
+    pub audio_samples: i32,
+    pub audio_sample_file_bytes: i32,
+    pub audio_index: Vec<u8>,
+
+    /// The wrapped key to the sample file, if it's encrypted. Filled in by `add_recording`.
+    pub wrapped_key: Option<Vec<u8>>,
 }
 
 impl RecordingToInsert {
@@ -300,6 +683,10 @@ impl RecordingToInsert {
             run_offset: self.run_offset,
             open_id,
             flags: self.flags | RecordingFlags::Uncommitted as i32,
+            audio_sample_entry_id: self.audio_sample_entry_id,
+            audio_samples: self.audio_samples,
+            audio_sample_file_bytes: self.audio_sample_file_bytes,
+            sample_file_dir_id: None,
             prev_media_duration_and_runs: Some((self.prev_media_duration, self.prev_runs)),
         }
     }
@@ -312,6 +699,26 @@ pub(crate) struct ListOldestRecordingsRow {
     pub start: recording::Time,
     pub wall_duration_90k: i32,
     pub sample_file_bytes: i32,
+
+    /// As in [`ListRecordingsRow::sample_file_dir_id`].
+    pub sample_file_dir_id: Option<i32>,
+}
+
+/// What `db::delete_oldest_recordings` should do with a recording.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Deletion {
+    /// Queues the recording for deletion and continues with the next.
+    Delete,
+
+    /// Queues the recording to be moved to the stream's archive directory and continues with the
+    /// next.
+    Archive,
+
+    /// Keeps the recording and continues with the next.
+    Keep,
+
+    /// Keeps the recording and stops.
+    Stop,
 }
 
 #[derive(Debug)]
@@ -319,6 +726,12 @@ pub struct SampleFileDir {
     pub id: i32,
     pub path: PathBuf,
     pub uuid: Uuid,
+
+    /// If this is an archive directory; see [`SampleFileDirConfig::archive`].
+    pub archive: bool,
+
+    /// If sample files are written with `O_DIRECT`; see [`SampleFileDirConfig::direct_io`].
+    pub direct_io: bool,
     dir: Option<Arc<dir::SampleFileDir>>,
     last_complete_open: Option<Open>,
 
@@ -363,6 +776,9 @@ impl SampleFileDir {
     }
 }
 
+pub use crate::auth::ApiToken;
+pub use crate::auth::Credential;
+pub use crate::auth::RawApiToken;
 pub use crate::auth::RawSessionId;
 pub use crate::auth::Request;
 pub use crate::auth::Session;
@@ -452,16 +868,40 @@ pub struct Stream {
     /// `sample_file_bytes` because it includes the wasted space in the last filesystem block.
     pub fs_bytes: i64,
 
+    /// The total bytes of flushed sample files which have been moved to the archive directory.
+    /// These are not included in `sample_file_bytes`.
+    pub archived_sample_file_bytes: i64,
+
+    /// As `fs_bytes`, for the archive directory.
+    pub archived_fs_bytes: i64,
+
     /// On flush, delete the following recordings (move them to the `garbage` table, to be
-    /// collected later). Note they must be the oldest recordings. The later collection involves
-    /// the syncer unlinking the files on disk and syncing the directory then enqueueing for
-    /// another following flush removal from the `garbage` table.
+    /// collected later). These are in ascending order by id but may skip recordings which the
+    /// retention policy keeps. The later collection involves the syncer unlinking the files on
+    /// disk and syncing the directory then enqueueing for another following flush removal from
+    /// the `garbage` table.
     to_delete: Vec<ListOldestRecordingsRow>,
 
-    /// The total bytes to delete with the next flush.
+    /// The total bytes to delete from the stream's own directory with the next flush.
     pub bytes_to_delete: i64,
     pub fs_bytes_to_delete: i64,
 
+    /// The total bytes to delete from the archive directory with the next flush.
+    pub archived_bytes_to_delete: i64,
+    pub archived_fs_bytes_to_delete: i64,
+
+    /// Recordings to be copied to the archive directory by the syncer.
+    to_archive: Vec<ListOldestRecordingsRow>,
+
+    /// Recordings which have been copied to the given archive directory and synced. On flush,
+    /// they're marked as archived and the originals as garbage.
+    archived: Vec<(ListOldestRecordingsRow, i32)>,
+
+    /// The total bytes of `to_archive` and `archived`, which will move from the stream's own
+    /// directory to the archive directory.
+    pub bytes_to_archive: i64,
+    pub fs_bytes_to_archive: i64,
+
     /// The total bytes to add with the next flush. (`mark_synced` has already been called on these
     /// recordings.)
     pub bytes_to_add: i64,
@@ -478,6 +918,10 @@ pub struct Stream {
     /// The `cum_recordings` currently committed to the database.
     pub(crate) cum_recordings: i32,
 
+    /// The ids of committed recordings which haven't yet been counted in `duration` and
+    /// `committed_days`; see [`Database::load_recordings`]. The byte totals always include them.
+    unloaded: Range<i32>,
+
     /// The `cum_media_duration_90k` currently committed to the database.
     cum_media_duration: recording::Duration,
 
@@ -513,16 +957,41 @@ pub struct LiveSegment {
     /// The pts, relative to the start of the recording, of the start and end of this live segment,
     /// in 90kHz units.
     pub media_off_90k: Range<i32>,
+
+    /// The audio received since the previous live segment, or `None` if the recording has had no
+    /// audio so far.
+    pub audio: Option<Arc<LiveAudio>>,
 }
 
-#[derive(Clone, Debug, Default)]
+/// Audio frames for a live segment. Unlike video, audio isn't written to the sample file until
+/// the recording is closed, so these carry the data itself.
+#[derive(Debug)]
+pub struct LiveAudio {
+    pub audio_sample_entry_id: i32,
+
+    /// The start of the recording's first audio sample, relative to the start of the recording,
+    /// in 90kHz units.
+    pub recording_start_90k: i32,
+
+    /// The start of this batch's first sample, relative to the recording's first audio sample, in
+    /// units of the audio sample entry's sample rate.
+    pub start: i32,
+
+    /// The duration (in units of the sample rate) and length in bytes of each sample.
+    pub samples: Vec<(i32, i32)>,
+
+    /// The samples' data, concatenated.
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct StreamChange {
     pub sample_file_dir_id: Option<i32>,
     pub config: crate::json::StreamConfig,
 }
 
 /// Information about a camera, used by `add_camera` and `update_camera`.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CameraChange {
     pub short_name: String,
     pub config: crate::json::CameraConfig,
@@ -543,7 +1012,8 @@ impl Stream {
         self.duration += r.end - r.start;
         self.sample_file_bytes += i64::from(sample_file_bytes);
         self.fs_bytes += round_up(i64::from(sample_file_bytes));
-        self.committed_days.adjust(r, 1);
+        self.committed_days
+            .adjust(r, i64::from(sample_file_bytes), 1);
     }
 
     /// Returns a days map including unflushed recordings.
@@ -553,57 +1023,166 @@ impl Stream {
             let l = u.lock().unwrap();
             days.adjust(
                 l.start..l.start + recording::Duration(i64::from(l.wall_duration_90k)),
+                i64::from(l.sample_file_bytes),
                 1,
             );
         }
         days
     }
+
+    /// Returns the number of committed recording ids not yet loaded into this stream's totals;
+    /// see [`Database::load_recordings`]. Some of these may have since been deleted.
+    pub fn unloaded_recordings(&self) -> i32 {
+        self.unloaded.end - self.unloaded.start
+    }
+
+    /// Loads the next batch of unloaded recordings into this stream's `duration` and
+    /// `committed_days`, returning the number loaded.
+    fn load_recordings(&mut self, conn: &rusqlite::Connection) -> Result<usize, Error> {
+        let mut stmt = conn.prepare_cached(LOAD_RECORDINGS_SQL)?;
+        let mut rows = stmt.query(named_params! {
+            ":start": CompositeId::new(self.id, self.unloaded.start).0,
+            ":end": CompositeId::new(self.id, self.unloaded.end).0,
+            ":limit": LOAD_RECORDINGS_BATCH_LEN as i64,
+        })?;
+        let mut n = 0;
+        let mut last_id = None;
+        while let Some(row) = rows.next()? {
+            let id = CompositeId(row.get(0)?);
+            let start = recording::Time(row.get(1)?);
+            let duration = recording::Duration(row.get(2)?);
+            let bytes: i32 = row.get(3)?;
+            self.duration += duration;
+            self.committed_days
+                .adjust(start..start + duration, i64::from(bytes), 1);
+            n += 1;
+            last_id = Some(id);
+        }
+        self.unloaded.start = match last_id {
+            Some(id) if n == LOAD_RECORDINGS_BATCH_LEN => id.recording() + 1,
+            _ => self.unloaded.end,
+        };
+        Ok(n)
+    }
+
+    /// Returns the number of recordings ever committed to this stream, which is also the id the
+    /// next committed recording will have.
+    pub fn cum_recordings(&self) -> i32 {
+        self.cum_recordings
+    }
+
+    /// Returns the end time of the oldest recording which is synced but not yet flushed, if any.
+    ///
+    /// The syncer normally flushes within `config.flush_if_sec` of this time.
+    pub fn oldest_unflushed_end(&self) -> Option<recording::Time> {
+        if self.synced_recordings == 0 {
+            return None;
+        }
+        let l = self.uncommitted.front()?.lock().unwrap();
+        Some(l.start + recording::Duration(i64::from(l.wall_duration_90k)))
+    }
+
+    /// Returns how long the oldest synced recording has waited to be flushed as of `now`, or
+    /// zero if there's no such recording.
+    ///
+    /// This bounds how much recorded video would be lost from the database on a crash.
+    pub fn flush_lag(&self, now: recording::Time) -> recording::Duration {
+        self.oldest_unflushed_end()
+            .map_or(recording::Duration(0), |end| {
+                std::cmp::max(now - end, recording::Duration(0))
+            })
+    }
 }
 
-/// Initializes the recordings associated with the given camera.
+/// The number of recordings [`Database::load_recordings`] loads per acquisition of the database
+/// lock.
+const LOAD_RECORDINGS_BATCH_LEN: usize = 16384;
+
+const LOAD_RECORDINGS_SQL: &str = r#"
+    select
+      composite_id,
+      start_time_90k,
+      wall_duration_90k,
+      sample_file_bytes
+    from
+      recording
+    where
+      composite_id >= :start and
+      composite_id < :end
+    order by
+      composite_id
+    limit :limit
+"#;
+
+/// The byte totals of a stream's recordings, split by whether they've been archived.
+const SUM_RECORDING_BYTES_SQL: &str = r#"
+    select
+      sample_file_dir_id is not null,
+      sum(sample_file_bytes),
+      sum((sample_file_bytes + :blk - 1) / :blk * :blk)
+    from
+      recording
+    where
+      composite_id >= :start and
+      composite_id < :end
+    group by
+      1
+"#;
+
+/// Prepares to load the committed recordings of `stream` via [`Database::load_recordings`].
+///
+/// This sets the stream's time range, which is quick to find from the `recording_cover` index,
+/// and its byte totals, which are summed within SQLite. Retention relies on the latter, so they
+/// must be correct before loading finishes. The remaining totals are left to load lazily.
 fn init_recordings(
-    conn: &mut rusqlite::Connection,
+    conn: &rusqlite::Connection,
     stream_id: i32,
-    camera: &Camera,
     stream: &mut Stream,
 ) -> Result<(), Error> {
-    info!(
-        "Loading recordings for camera {} stream {:?}",
-        camera.short_name, stream.type_
-    );
-    let mut stmt = conn.prepare(
-        r#"
-        select
-          recording.start_time_90k,
-          recording.wall_duration_90k,
-          recording.sample_file_bytes
-        from
-          recording
-        where
-          stream_id = :stream_id
-        "#,
-    )?;
-    let mut rows = stmt.query(named_params! {":stream_id": stream_id})?;
-    let mut i = 0;
+    stream.range = raw::get_range(conn, stream_id)?;
+    let mut stmt = conn.prepare_cached(SUM_RECORDING_BYTES_SQL)?;
+    let mut rows = stmt.query(named_params! {
+        ":start": CompositeId::new(stream_id, 0).0,
+        ":end": CompositeId::new(stream_id, stream.cum_recordings).0,
+        ":blk": ASSUMED_BLOCK_SIZE_BYTES,
+    })?;
     while let Some(row) = rows.next()? {
-        let start = recording::Time(row.get(0)?);
-        let duration = recording::Duration(row.get(1)?);
-        let bytes = row.get(2)?;
-        stream.add_recording(start..start + duration, bytes);
-        i += 1;
-    }
-    info!(
-        "Loaded {} recordings for camera {} stream {:?}",
-        i, camera.short_name, stream.type_
-    );
+        let archived: bool = row.get(0)?;
+        let bytes: i64 = row.get(1)?;
+        let fs_bytes: i64 = row.get(2)?;
+        if archived {
+            stream.archived_sample_file_bytes = bytes;
+            stream.archived_fs_bytes = fs_bytes;
+        } else {
+            stream.sample_file_bytes = bytes;
+            stream.fs_bytes = fs_bytes;
+        }
+    }
+    let first: Option<i64> = conn.query_row(
+        "select min(composite_id) from recording where composite_id >= :start and composite_id < :end",
+        named_params! {
+            ":start": CompositeId::new(stream_id, 0).0,
+            ":end": CompositeId::new(stream_id, stream.cum_recordings).0,
+        },
+        |row| row.get(0),
+    )?;
+    let first = first.map_or(stream.cum_recordings, |id| CompositeId(id).recording());
+    stream.unloaded = first..stream.cum_recordings;
     Ok(())
 }
 
 pub struct LockedDatabase {
+    /// The online backup in progress, if any. This refers to `conn`, so it's declared first to
+    /// be dropped first.
+    backup: Option<crate::backup::Backup>,
+
     conn: rusqlite::Connection,
     uuid: Uuid,
     flush_count: usize,
 
+    /// The duration of each successful flush, in seconds.
+    flush_duration: base::metrics::Histogram,
+
     /// If the database is open in read-write mode, the information about the current Open row.
     pub open: Option<Open>,
 
@@ -619,8 +1198,12 @@ pub struct LockedDatabase {
     streams_by_id: BTreeMap<i32, Stream>,
     cameras_by_uuid: BTreeMap<Uuid, i32>, // values are ids.
     video_sample_entries_by_id: BTreeMap<i32, Arc<VideoSampleEntry>>,
-    video_index_cache: RefCell<LinkedHashMap<i64, Box<[u8]>, base::RandomState>>,
+    audio_sample_entries_by_id: BTreeMap<i32, Arc<AudioSampleEntry>>,
+    video_index_cache: RefCell<LinkedHashMap<i64, CachedPlayback, base::RandomState>>,
     on_flush: Vec<Box<dyn Fn() + Send>>,
+
+    /// The master key with which to encrypt new sample files and decrypt existing ones, if any.
+    sample_file_key: Option<dir::crypto::MasterKey>,
 }
 
 /// Represents a row of the `open` database table.
@@ -708,6 +1291,11 @@ impl StreamStateChanger {
                 }
                 if !have_data && sc.config.is_empty() && sc.sample_file_dir_id.is_none() {
                     // Delete stream.
+                    raw::delete_thumbnails(tx, sid, None)?;
+                    raw::delete_detections(tx, sid, None)?;
+                    raw::delete_uploads(tx, sid)?;
+                    raw::delete_replications(tx, sid)?;
+                    raw::delete_stream_health(tx, sid)?;
                     let mut stmt = tx.prepare_cached(
                         r#"
                         delete from stream where id = ?
@@ -807,14 +1395,23 @@ impl StreamStateChanger {
                         range: None,
                         sample_file_bytes: 0,
                         fs_bytes: 0,
+                        archived_sample_file_bytes: 0,
+                        archived_fs_bytes: 0,
                         to_delete: Vec::new(),
                         bytes_to_delete: 0,
                         fs_bytes_to_delete: 0,
+                        archived_bytes_to_delete: 0,
+                        archived_fs_bytes_to_delete: 0,
+                        to_archive: Vec::new(),
+                        archived: Vec::new(),
+                        bytes_to_archive: 0,
+                        fs_bytes_to_archive: 0,
                         bytes_to_add: 0,
                         fs_bytes_to_add: 0,
                         duration: recording::Duration(0),
                         committed_days: days::Map::default(),
                         cum_recordings: 0,
+                        unloaded: 0..0,
                         cum_media_duration: recording::Duration(0),
                         cum_runs: 0,
                         uncommitted: VecDeque::new(),
@@ -840,6 +1437,9 @@ impl StreamStateChanger {
 /// A retention change as expected by `LockedDatabase::update_retention`.
 pub struct RetentionChange {
     pub stream_id: i32,
+
+    /// If false, disables the stream. If true, keeps an existing recording mode (such as
+    /// `on-signal`) or switches to `record`.
     pub new_record: bool,
     pub new_limit: i64,
 }
@@ -858,6 +1458,11 @@ impl LockedDatabase {
         self.flush_count
     }
 
+    /// Returns a histogram of the durations of successful database flushes since startup.
+    pub fn flush_duration(&self) -> &base::metrics::Histogram {
+        &self.flush_duration
+    }
+
     /// Adds a placeholder for an uncommitted recording.
     ///
     /// The caller should write samples and fill the returned `RecordingToInsert` as it goes
@@ -882,6 +1487,10 @@ impl LockedDatabase {
             stream_id,
             stream.cum_recordings + (stream.uncommitted.len() as i32),
         );
+        if let Some(ref k) = self.sample_file_key {
+            r.wrapped_key = Some(k.new_wrapped_key(id)?);
+            r.flags |= RecordingFlags::Encrypted as i32;
+        }
         match stream.uncommitted.back() {
             Some(s) => {
                 let l = s.lock().unwrap();
@@ -1003,6 +1612,7 @@ impl LockedDatabase {
     fn flush<C: Clocks>(&mut self, clocks: &C, reason: &str) -> Result<(), Error> {
         let span = tracing::info_span!("flush", flush_count = self.flush_count, reason);
         let _enter = span.enter();
+        let start = clocks.monotonic();
         let o = match self.open.as_ref() {
             None => bail!(Internal, msg("database is read-only")),
             Some(o) => o,
@@ -1037,8 +1647,20 @@ impl LockedDatabase {
                     })?;
                 }
 
+                // Process archivals.
+                if !s.archived.is_empty() {
+                    new_ranges.entry(stream_id).or_insert(None);
+                    let dir = match s.sample_file_dir_id {
+                        None => bail!(Internal, msg("stream {stream_id} has no directory!")),
+                        Some(d) => d,
+                    };
+                    for (row, archive_dir_id) in &s.archived {
+                        raw::archive_recording(&tx, row.id, dir, *archive_dir_id)?;
+                    }
+                }
+
                 // Process deletions.
-                if let Some(l) = s.to_delete.last() {
+                if !s.to_delete.is_empty() {
                     new_ranges.entry(stream_id).or_insert(None);
                     let dir = match s.sample_file_dir_id {
                         None => bail!(Internal, msg("stream {stream_id} has no directory!")),
@@ -1046,20 +1668,27 @@ impl LockedDatabase {
                     };
 
                     // raw::delete_recordings does a bulk transfer of a range from recording to
-                    // garbage, rather than operating on each element of to_delete. This is
-                    // guaranteed to give the same result because to_delete is guaranteed to be the
-                    // oldest recordings for the stream.
-                    let start = CompositeId::new(stream_id, 0);
-                    let end = CompositeId(l.id.0 + 1);
-                    let n = raw::delete_recordings(&tx, dir, start..end)?;
+                    // garbage, rather than operating on each element of to_delete. to_delete is
+                    // sorted but may skip recordings kept by the retention policy, so transfer
+                    // each run of consecutive ids separately.
+                    let mut n = 0;
+                    let mut i = 0;
+                    while i < s.to_delete.len() {
+                        let start = s.to_delete[i].id;
+                        let mut end = CompositeId(start.0 + 1);
+                        i += 1;
+                        while i < s.to_delete.len() && s.to_delete[i].id == end {
+                            end = CompositeId(end.0 + 1);
+                            i += 1;
+                        }
+                        n += raw::delete_recordings(&tx, dir, start..end)?;
+                    }
                     if n != s.to_delete.len() {
                         bail!(
                             Internal,
                             msg(
-                                "Found {} rows in {} .. {}, expected {}: {:?}",
+                                "Found {} rows to delete, expected {}: {:?}",
                                 n,
-                                start,
-                                end,
                                 s.to_delete.len(),
                                 &s.to_delete,
                             ),
@@ -1069,10 +1698,18 @@ impl LockedDatabase {
             }
         }
         for dir in self.sample_file_dirs_by_id.values() {
-            raw::mark_sample_files_deleted(&tx, &dir.garbage_unlinked)?;
+            raw::mark_sample_files_deleted(&tx, dir.id, &dir.garbage_unlinked)?;
         }
         for (&stream_id, r) in &mut new_ranges {
             *r = raw::get_range(&tx, stream_id)?;
+
+            // Thumbnails and detections go along with the recordings they describe. If there
+            // are no recordings left, keep them; they may belong to recordings which are yet to
+            // be committed.
+            if let Some(r) = r {
+                raw::delete_thumbnails(&tx, stream_id, Some(r.start))?;
+                raw::delete_detections(&tx, stream_id, Some(r.start))?;
+            }
         }
         {
             let mut stmt = tx.prepare_cached(
@@ -1098,6 +1735,12 @@ impl LockedDatabase {
             gced: SmallVec<[CompositeId; 32]>,
             added_bytes: i64,
             deleted_bytes: i64,
+
+            /// Recordings moved from this directory to an archive directory.
+            archived: SmallVec<[CompositeId; 32]>,
+
+            /// Bytes moved into this (archive) directory.
+            archived_bytes: i64,
         }
         let mut dir_logs: FastHashMap<i32, DirLog> = FastHashMap::default();
 
@@ -1115,23 +1758,50 @@ impl LockedDatabase {
         for (stream_id, new_range) in new_ranges.drain() {
             let s = self.streams_by_id.get_mut(&stream_id).unwrap();
             let dir_id = s.sample_file_dir_id.unwrap();
-            let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
-            let log = dir_logs.entry(dir_id).or_default();
+
+            // Process mark_archived. The copies are already in place; the originals are garbage.
+            for (row, archive_dir_id) in s.archived.drain(..) {
+                let bytes = i64::from(row.sample_file_bytes);
+                s.sample_file_bytes -= bytes;
+                s.fs_bytes -= round_up(bytes);
+                s.archived_sample_file_bytes += bytes;
+                s.archived_fs_bytes += round_up(bytes);
+                s.bytes_to_archive -= bytes;
+                s.fs_bytes_to_archive -= round_up(bytes);
+                dir_logs.entry(dir_id).or_default().archived.push(row.id);
+                dir_logs.entry(archive_dir_id).or_default().archived_bytes += bytes;
+                let dir = self.sample_file_dirs_by_id.get_mut(&dir_id).unwrap();
+                dir.garbage_needs_unlink.insert(row.id);
+            }
 
             // Process delete_oldest_recordings.
             s.sample_file_bytes -= s.bytes_to_delete;
             s.fs_bytes -= s.fs_bytes_to_delete;
-            log.deleted_bytes += s.bytes_to_delete;
+            s.archived_sample_file_bytes -= s.archived_bytes_to_delete;
+            s.archived_fs_bytes -= s.archived_fs_bytes_to_delete;
             s.bytes_to_delete = 0;
             s.fs_bytes_to_delete = 0;
-            log.deleted.reserve(s.to_delete.len());
+            s.archived_bytes_to_delete = 0;
+            s.archived_fs_bytes_to_delete = 0;
             for row in s.to_delete.drain(..) {
+                let row_dir_id = row.sample_file_dir_id.unwrap_or(dir_id);
+                let log = dir_logs.entry(row_dir_id).or_default();
                 log.deleted.push(row.id);
+                log.deleted_bytes += i64::from(row.sample_file_bytes);
+                let dir = self.sample_file_dirs_by_id.get_mut(&row_dir_id).unwrap();
                 dir.garbage_needs_unlink.insert(row.id);
+                if s.unloaded.contains(&row.id.recording()) {
+                    continue; // not yet counted in the duration or days.
+                }
                 let d = recording::Duration(i64::from(row.wall_duration_90k));
                 s.duration -= d;
-                s.committed_days.adjust(row.start..row.start + d, -1);
+                s.committed_days.adjust(
+                    row.start..row.start + d,
+                    i64::from(row.sample_file_bytes),
+                    -1,
+                );
             }
+            let log = dir_logs.entry(dir_id).or_default();
 
             // Process add_recordings.
             log.added_bytes += s.bytes_to_add;
@@ -1177,11 +1847,31 @@ impl LockedDatabase {
                 log.gced.iter().join(", ")
             )
             .unwrap();
+            if !log.archived.is_empty() {
+                write!(
+                    &mut log_msg,
+                    " Archived {} recordings ({}).",
+                    log.archived.len(),
+                    log.archived.iter().join(", ")
+                )
+                .unwrap();
+            }
+            if log.archived_bytes > 0 {
+                write!(
+                    &mut log_msg,
+                    " Received {}B of archived recordings.",
+                    &encode_size(log.archived_bytes)
+                )
+                .unwrap();
+            }
         }
         if log_msg.is_empty() {
             log_msg.push_str(" no recording changes");
         }
         info!("flush complete: {log_msg}");
+        let elapsed = clocks.monotonic() - start;
+        self.flush_duration
+            .observe(elapsed.num_microseconds().unwrap_or(i64::MAX) as f64 / 1_000_000.);
         for cb in &self.on_flush {
             cb();
         }
@@ -1236,6 +1926,7 @@ impl LockedDatabase {
             }
             let d = dir::SampleFileDir::open(&dir.path, &expected_meta)
                 .map_err(|e| err!(e, msg("Failed to open dir {}", dir.path.display())))?;
+            d.set_direct_io(dir.direct_io);
             if self.open.is_none() {
                 // read-only mode; it's already fully opened.
                 dir.dir = Some(d);
@@ -1284,6 +1975,10 @@ impl LockedDatabase {
         &self.video_sample_entries_by_id
     }
 
+    pub fn audio_sample_entries_by_id(&self) -> &BTreeMap<i32, Arc<AudioSampleEntry>> {
+        &self.audio_sample_entries_by_id
+    }
+
     /// Gets a given camera by uuid.
     pub fn get_camera(&self, uuid: Uuid) -> Option<&Camera> {
         self.cameras_by_uuid.get(&uuid).map(|id| {
@@ -1304,16 +1999,30 @@ impl LockedDatabase {
         desired_time: Range<recording::Time>,
         f: &mut dyn FnMut(ListRecordingsRow) -> Result<(), base::Error>,
     ) -> Result<(), base::Error> {
+        let span = tracing::info_span!("list_recordings_by_time", stream_id);
+        let _enter = span.enter();
         let s = match self.streams_by_id.get(&stream_id) {
             None => bail!(NotFound, msg("no such stream {stream_id}")),
             Some(s) => s,
         };
         raw::list_recordings_by_time(&self.conn, stream_id, desired_time.clone(), f)?;
-        for (i, u) in s.uncommitted.iter().enumerate() {
-            let row = {
-                let l = u.lock().unwrap();
-                if l.video_samples > 0 {
-                    let end = l.start + recording::Duration(l.wall_duration_90k as i64);
+        self.list_uncommitted_recordings_by_time(stream_id, s, desired_time, f)
+    }
+
+    /// Lists the uncommitted recordings of `s` which overlap `desired_time`, in ascending order
+    /// by id.
+    fn list_uncommitted_recordings_by_time(
+        &self,
+        stream_id: i32,
+        s: &Stream,
+        desired_time: Range<recording::Time>,
+        f: &mut dyn FnMut(ListRecordingsRow) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        for (i, u) in s.uncommitted.iter().enumerate() {
+            let row = {
+                let l = u.lock().unwrap();
+                if l.video_samples > 0 {
+                    let end = l.start + recording::Duration(l.wall_duration_90k as i64);
                     if l.start > desired_time.end || end < desired_time.start {
                         continue; // there's no overlap with the requested range.
                     }
@@ -1337,6 +2046,8 @@ impl LockedDatabase {
         desired_ids: Range<i32>,
         f: &mut dyn FnMut(ListRecordingsRow) -> Result<(), base::Error>,
     ) -> Result<(), base::Error> {
+        let span = tracing::info_span!("list_recordings_by_id", stream_id);
+        let _enter = span.enter();
         let s = match self.streams_by_id.get(&stream_id) {
             None => bail!(NotFound, msg("no such stream {stream_id}")),
             Some(s) => s,
@@ -1378,95 +2089,302 @@ impl LockedDatabase {
         forced_split: recording::Duration,
         f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), base::Error>,
     ) -> Result<(), base::Error> {
-        // Iterate, maintaining a map from a recording_id to the aggregated row for the latest
-        // batch of recordings from the run starting at that id. Runs can be split into multiple
-        // batches for a few reasons:
-        //
-        // * forced split (when exceeding a duration limit)
-        // * a missing id (one that was deleted out of order)
-        // * video_sample_entry mismatch (if the parameters changed during a RTSP session)
-        //
-        // This iteration works because in a run, the start_time+duration of recording id r
-        // is equal to the start_time of recording id r+1. Thus ascending times guarantees
-        // ascending ids within a run. (Different runs, however, can be arbitrarily interleaved if
-        // their timestamps overlap. Tracking all active runs prevents that interleaving from
-        // causing problems.) list_recordings_by_time also returns uncommitted recordings in
-        // ascending order by id, and after any committed recordings with lower ids.
-        let mut aggs: BTreeMap<i32, ListAggregatedRecordingsRow> = BTreeMap::new();
-        self.list_recordings_by_time(stream_id, desired_time, &mut |row| {
-            let recording_id = row.id.recording();
-            let run_start_id = recording_id - row.run_offset;
-            let uncommitted = (row.flags & RecordingFlags::Uncommitted as i32) != 0;
-            let growing = (row.flags & RecordingFlags::Growing as i32) != 0;
-            let has_trailing_zero = (row.flags & RecordingFlags::TrailingZero as i32) != 0;
-            use std::collections::btree_map::Entry;
-            match aggs.entry(run_start_id) {
-                Entry::Occupied(mut e) => {
-                    let a = e.get_mut();
-                    let new_dur = a.time.end - a.time.start
-                        + recording::Duration(row.wall_duration_90k as i64);
-                    let needs_flush = a.ids.end != recording_id
-                        || row.video_sample_entry_id != a.video_sample_entry_id
-                        || new_dur >= forced_split;
-                    if needs_flush {
-                        // flush then start a new entry.
-                        f(a)?;
-                        *a = ListAggregatedRecordingsRow::from(row);
-                    } else {
-                        // append.
-                        if a.time.end != row.start {
-                            bail!(
-                                Internal,
-                                msg(
-                                    "stream {} recording {} ends at {} but {} starts at {}",
-                                    stream_id,
-                                    a.ids.end - 1,
-                                    a.time.end,
-                                    row.id,
-                                    row.start,
-                                ),
-                            );
-                        }
-                        if a.open_id != row.open_id {
-                            bail!(
-                                Internal,
-                                msg(
-                                    "stream {} recording {} has open id {} but {} has {}",
-                                    stream_id,
-                                    a.ids.end - 1,
-                                    a.open_id,
-                                    row.id,
-                                    row.open_id,
-                                ),
-                            );
-                        }
-                        a.time.end.0 += row.wall_duration_90k as i64;
-                        a.ids.end = recording_id + 1;
-                        a.video_samples += row.video_samples as i64;
-                        a.video_sync_samples += row.video_sync_samples as i64;
-                        a.sample_file_bytes += row.sample_file_bytes as i64;
-                        if uncommitted {
-                            a.first_uncommitted = a.first_uncommitted.or(Some(recording_id));
-                        }
-                        a.growing = growing;
-                        a.has_trailing_zero = has_trailing_zero;
-                    }
-                }
-                Entry::Vacant(e) => {
-                    e.insert(ListAggregatedRecordingsRow::from(row));
-                }
+        self.list_aggregated_recordings_from(stream_id, desired_time, forced_split, 0, f)
+    }
+
+    /// As `list_aggregated_recordings`, but ignores recordings with ids less than `first_id`.
+    /// Aggregation starts afresh at `first_id`, so this is suitable for paging through results.
+    pub fn list_aggregated_recordings_from(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+        forced_split: recording::Duration,
+        first_id: i32,
+        f: &mut dyn FnMut(&ListAggregatedRecordingsRow) -> Result<(), base::Error>,
+    ) -> Result<(), base::Error> {
+        let mut aggregator = RecordingAggregator::new(stream_id, forced_split, first_id);
+        self.list_recordings_by_time(stream_id, desired_time, &mut |row| aggregator.push(row, f))?;
+        aggregator.finish(f)
+    }
+
+    /// Stores a thumbnail for the given stream, replacing any existing one at the same time.
+    pub fn insert_thumbnail(
+        &mut self,
+        stream_id: i32,
+        start: recording::Time,
+        width: u16,
+        height: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        raw::insert_thumbnail(&self.conn, stream_id, start, width, height, data)
+    }
+
+    /// Lists the thumbnails of the given stream in ascending order by time, passing them to a
+    /// supplied function. Given that the function is called with the database lock held, it
+    /// should be quick.
+    pub fn list_thumbnails(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+        f: &mut dyn FnMut(ListThumbnailsRow) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        raw::list_thumbnails(&self.conn, stream_id, desired_time, false, &mut |row, _| {
+            f(row)
+        })
+    }
+
+    /// Like `list_thumbnails`, but also passes the JPEG-encoded image.
+    pub fn list_thumbnails_with_data(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+        f: &mut dyn FnMut(ListThumbnailsRow, &[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        raw::list_thumbnails(&self.conn, stream_id, desired_time, true, f)
+    }
+
+    /// Stores objects found in frames of the given stream.
+    pub fn insert_detections(
+        &mut self,
+        stream_id: i32,
+        detections: &[Detection],
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        let tx = self.conn.transaction()?;
+        raw::insert_detections(&tx, stream_id, detections)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Lists the detections of the given stream in ascending order by time, optionally only
+    /// those of the given class, passing them to a supplied function. Given that the function is
+    /// called with the database lock held, it should be quick.
+    pub fn list_detections(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+        class: Option<&str>,
+        f: &mut dyn FnMut(Detection) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        raw::list_detections(&self.conn, stream_id, desired_time, class, f)
+    }
+
+    /// Adds to the given stream's health totals for the given day. Like the audit log, this is
+    /// written immediately rather than on the next flush.
+    pub fn add_stream_health(
+        &mut self,
+        stream_id: i32,
+        day: days::Key,
+        health: &StreamHealth,
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        let tx = self.conn.transaction()?;
+        raw::add_stream_health(&tx, stream_id, day, health)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Lists the daily health totals of the given stream in ascending order by day.
+    pub fn list_stream_health(
+        &self,
+        stream_id: i32,
+        f: &mut dyn FnMut(days::Key, StreamHealth) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        }
+        raw::list_stream_health(&self.conn, stream_id, f)
+    }
+
+    /// Deletes the health totals of all streams for days before `before`.
+    pub fn delete_stream_health_before(&mut self, before: days::Key) -> Result<(), Error> {
+        raw::delete_stream_health_before(&self.conn, before)
+    }
+
+    /// Lists up to `limit` committed recordings after `after` which haven't been uploaded, in
+    /// ascending order by id. Recordings already queued for deletion are skipped.
+    pub fn list_recordings_to_upload(
+        &self,
+        after: Option<CompositeId>,
+        limit: usize,
+    ) -> Result<Vec<CompositeId>, Error> {
+        let mut ids = raw::list_recordings_to_upload(&self.conn, after, limit)?;
+        ids.retain(|id| {
+            self.streams_by_id.get(&id.stream()).map_or(false, |s| {
+                s.to_delete.binary_search_by_key(&id.0, |d| d.id.0).is_err()
+            })
+        });
+        Ok(ids)
+    }
+
+    /// Records that a recording has been uploaded, replacing any previous upload of it.
+    pub fn insert_upload(&mut self, upload: &Upload) -> Result<(), Error> {
+        raw::insert_upload(&self.conn, upload)
+    }
+
+    /// Lists uploads which haven't been verified, in ascending order by id.
+    pub fn list_unverified_uploads(&self) -> Result<Vec<Upload>, Error> {
+        raw::list_unverified_uploads(&self.conn)
+    }
+
+    /// Records that an upload has been verified.
+    pub fn mark_upload_verified(
+        &mut self,
+        id: CompositeId,
+        verify_time: recording::Time,
+    ) -> Result<(), Error> {
+        raw::mark_upload_verified(&self.conn, id, verify_time)
+    }
+
+    /// Queues for deletion the given stream's recordings which have verified uploads, returning
+    /// the number queued. The next flush will mark them as garbage, as with
+    /// `delete_oldest_recordings`.
+    pub fn delete_uploaded_recordings(&mut self, stream_id: i32) -> Result<usize, Error> {
+        let ids = raw::list_verified_uploaded_recordings(&self.conn, stream_id)?;
+        let Some(&last) = ids.last() else {
+            return Ok(0);
+        };
+        let mut n = 0;
+        self.delete_oldest_recordings(stream_id, &mut |r| {
+            if ids.binary_search_by_key(&r.id.0, |id| id.0).is_ok() {
+                n += 1;
+                Deletion::Delete
+            } else if r.id.0 < last.0 {
+                Deletion::Keep
+            } else {
+                Deletion::Stop
             }
-            Ok(())
         })?;
-        for a in aggs.values() {
-            f(a)?;
+        Ok(n)
+    }
+
+    /// Lists up to `limit` committed recordings after `after` which haven't been replicated, in
+    /// ascending order by id. Recordings already queued for deletion are skipped.
+    pub fn list_recordings_to_replicate(
+        &self,
+        after: Option<CompositeId>,
+        limit: usize,
+    ) -> Result<Vec<CompositeId>, Error> {
+        let mut ids = raw::list_recordings_to_replicate(&self.conn, after, limit)?;
+        ids.retain(|id| {
+            self.streams_by_id.get(&id.stream()).map_or(false, |s| {
+                s.to_delete.binary_search_by_key(&id.0, |d| d.id.0).is_err()
+            })
+        });
+        Ok(ids)
+    }
+
+    /// Records that a recording has been copied to `remote_path` on the replication host.
+    pub fn insert_replication(
+        &mut self,
+        id: CompositeId,
+        remote_path: &str,
+        replicate_time: recording::Time,
+    ) -> Result<(), Error> {
+        raw::insert_replication(&self.conn, id, remote_path, replicate_time)
+    }
+
+    /// Writes a consistent copy of the database to `path`, which must not exist.
+    ///
+    /// This holds the database lock while copying, so it may stall recording and requests for
+    /// a moment on large databases.
+    pub fn backup_to(&self, path: &std::path::Path) -> Result<(), Error> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| err!(InvalidArgument, msg("non-UTF-8 path {}", path.display())))?;
+        self.conn.execute("vacuum into ?", params![path])?;
+        Ok(())
+    }
+
+    /// Starts an online backup to `path`, which must not exist. Continue it with
+    /// [`Self::step_backup`]; [`Database::backup`] does both.
+    pub fn start_backup(&mut self, path: &std::path::Path) -> Result<(), Error> {
+        if self.backup.is_some() {
+            bail!(FailedPrecondition, msg("a backup is already in progress"));
         }
+        // SAFETY: `self.backup` is dropped before `self.conn`.
+        self.backup = Some(unsafe { crate::backup::Backup::new(&self.conn, path)? });
         Ok(())
     }
 
+    /// Returns the database's page size and `auto_vacuum` mode and the number of pages on its
+    /// freelist.
+    fn vacuum_state(&self) -> Result<(i64, i64, i64), Error> {
+        let pragma = |p: &str| {
+            self.conn
+                .pragma_query_value(None, p, |r| r.get::<_, i64>(0))
+        };
+        Ok((
+            pragma("page_size")?,
+            pragma("auto_vacuum")?,
+            pragma("freelist_count")?,
+        ))
+    }
+
+    /// Reclaims up to `pages` pages from the freelist, returning the number still on it.
+    ///
+    /// This has no effect unless the database uses `auto_vacuum = incremental`, as databases
+    /// created by `moonfire-nvr init` or vacuumed by `moonfire-nvr upgrade` do.
+    pub fn incremental_vacuum(&mut self, pages: i64) -> Result<i64, Error> {
+        self.conn
+            .execute_batch(&format!("pragma incremental_vacuum({pages})"))?;
+        Ok(self
+            .conn
+            .pragma_query_value(None, "freelist_count", |r| r.get(0))?)
+    }
+
+    /// Updates the statistics the query planner uses to choose indexes, examining a bounded
+    /// number of rows so this takes little time even on large databases.
+    pub fn analyze(&mut self) -> Result<(), Error> {
+        self.conn.execute_batch(&format!(
+            "pragma analysis_limit = {ANALYSIS_LIMIT}; analyze; pragma analysis_limit = 0;"
+        ))?;
+        Ok(())
+    }
+
+    /// Copies up to `pages` more pages of the backup started by [`Self::start_backup`].
+    /// Returns true when the backup is complete. On completion or error, the backup ends; on
+    /// error, its incomplete file is removed.
+    pub fn step_backup(&mut self, pages: i32) -> Result<bool, Error> {
+        let b = self
+            .backup
+            .as_mut()
+            .ok_or_else(|| err!(FailedPrecondition, msg("no backup in progress")))?;
+        match b.step(pages) {
+            Ok(false) => Ok(false),
+            Ok(true) => {
+                let (_, pagecount) = b.progress();
+                self.backup = None;
+                info!("completed backup of {pagecount} pages");
+                Ok(true)
+            }
+            Err(e) => {
+                self.backup = None;
+                Err(e)
+            }
+        }
+    }
+
     /// Calls `f` with a single `recording_playback` row.
     /// Note the lock is held for the duration of `f`.
-    /// This uses a LRU cache to reduce the number of retrievals from the database.
+    /// This uses a LRU cache of committed recordings' indexes and parsed key frames to reduce
+    /// the number of retrievals from the database.
     pub fn with_recording_playback<R>(
         &self,
         id: CompositeId,
@@ -1493,6 +2411,9 @@ impl LockedDatabase {
             let l = s.uncommitted[i as usize].lock().unwrap();
             return f(&RecordingPlayback {
                 video_index: &l.video_index,
+                audio_index: l.audio_sample_entry_id.map(|_| &l.audio_index[..]),
+                wrapped_key: l.wrapped_key.as_deref(),
+                key_frames: None,
             });
         }
 
@@ -1503,8 +2424,13 @@ impl LockedDatabase {
             RawEntryMut::Occupied(mut occupied) => {
                 trace!("cache hit for recording {}", id);
                 occupied.to_back();
-                let video_index = occupied.get();
-                f(&RecordingPlayback { video_index })
+                let p = occupied.get();
+                f(&RecordingPlayback {
+                    video_index: &p.video_index,
+                    audio_index: p.audio_index.as_deref(),
+                    wrapped_key: p.wrapped_key.as_deref(),
+                    key_frames: Some(&p.key_frames),
+                })
             }
             RawEntryMut::Vacant(vacant) => {
                 trace!("cache miss for recording {}", id);
@@ -1512,10 +2438,21 @@ impl LockedDatabase {
                 let mut rows = stmt.query(named_params! {":composite_id": id.0})?;
                 if let Some(row) = rows.next()? {
                     let video_index: VideoIndex = row.get(0)?;
+                    let audio_index: Option<VideoIndex> = row.get(1)?;
+                    let wrapped_key: Option<Vec<u8>> = row.get(2)?;
+                    let p = CachedPlayback {
+                        video_index: video_index.0,
+                        audio_index: audio_index.map(|i| i.0),
+                        wrapped_key: wrapped_key.map(Vec::into_boxed_slice),
+                        key_frames: OnceCell::new(),
+                    };
                     let result = f(&RecordingPlayback {
-                        video_index: &video_index.0[..],
+                        video_index: &p.video_index,
+                        audio_index: p.audio_index.as_deref(),
+                        wrapped_key: p.wrapped_key.as_deref(),
+                        key_frames: Some(&p.key_frames),
                     });
-                    vacant.insert(id.0, video_index.0);
+                    vacant.insert(id.0, p);
                     if cache.len() > VIDEO_INDEX_CACHE_LEN {
                         cache.pop_front();
                     }
@@ -1526,33 +2463,132 @@ impl LockedDatabase {
         }
     }
 
-    /// Queues for deletion the oldest recordings that aren't already queued.
-    /// `f` should return true for each row that should be deleted.
+    /// Sets the master key for sample file encryption. If set, new recordings are encrypted.
+    pub fn set_sample_file_key(&mut self, key: Option<dir::crypto::MasterKey>) {
+        self.sample_file_key = key;
+    }
+
+    /// Applies journal and I/O tuning; this should be called right after opening.
+    pub fn set_tuning(&mut self, tuning: &Tuning) -> Result<(), Error> {
+        if let Some(p) = tuning.wal_autocheckpoint_pages {
+            self.conn.pragma_update(None, "wal_autocheckpoint", p)?;
+        }
+        if let Some(s) = tuning.synchronous {
+            self.conn.pragma_update(None, "synchronous", s as i32)?;
+        }
+        if let Some(b) = tuning.mmap_size_bytes {
+            self.conn.pragma_update(None, "mmap_size", b)?;
+        }
+        let get = |p: &str| {
+            self.conn
+                .pragma_query_value(None, p, |r| r.get::<_, i64>(0))
+        };
+        info!(
+            "SQLite tuning: wal_autocheckpoint={} synchronous={} mmap_size={}",
+            get("wal_autocheckpoint")?,
+            get("synchronous")?,
+            get("mmap_size")?,
+        );
+        Ok(())
+    }
+
+    /// Returns the key to the given recording's sample file, or `None` if it isn't encrypted.
+    pub fn recording_key(&self, id: CompositeId) -> Result<Option<Arc<dir::crypto::Key>>, Error> {
+        self.with_recording_playback(id, &mut |p| {
+            let Some(wrapped) = p.wrapped_key else {
+                return Ok(None);
+            };
+            let Some(ref k) = self.sample_file_key else {
+                bail!(
+                    FailedPrecondition,
+                    msg("recording {id} is encrypted, but no sample file key is configured")
+                );
+            };
+            Ok(Some(Arc::new(k.unwrap_key(id, wrapped)?)))
+        })
+    }
+
+    /// Queues for deletion (or archival) the oldest recordings that aren't already queued.
+    /// `f` is called on each such row, oldest first, until it returns [`Deletion::Stop`].
     pub(crate) fn delete_oldest_recordings(
         &mut self,
         stream_id: i32,
-        f: &mut dyn FnMut(&ListOldestRecordingsRow) -> bool,
+        f: &mut dyn FnMut(&ListOldestRecordingsRow) -> Deletion,
     ) -> Result<(), Error> {
         let s = match self.streams_by_id.get_mut(&stream_id) {
             None => bail!(Internal, msg("no stream {stream_id}")),
             Some(s) => s,
         };
-        let end = match s.to_delete.last() {
-            None => 0,
-            Some(row) => row.id.recording() + 1,
-        };
-        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, end), &mut |r| {
-            if f(&r) {
-                s.to_delete.push(r);
-                let bytes = i64::from(r.sample_file_bytes);
-                s.bytes_to_delete += bytes;
-                s.fs_bytes_to_delete += round_up(bytes);
-                return true;
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), &mut |r| {
+            let Err(i) = s.to_delete.binary_search_by_key(&r.id.0, |d| d.id.0) else {
+                return true; // already enqueued.
+            };
+            if s.to_archive.iter().any(|a| a.id == r.id)
+                || s.archived.iter().any(|(a, _)| a.id == r.id)
+            {
+                return true; // being archived; it can be deleted after the next flush.
+            }
+            match f(&r) {
+                Deletion::Delete => {
+                    s.to_delete.insert(i, r);
+                    let bytes = i64::from(r.sample_file_bytes);
+                    if r.sample_file_dir_id.is_some() {
+                        s.archived_bytes_to_delete += bytes;
+                        s.archived_fs_bytes_to_delete += round_up(bytes);
+                    } else {
+                        s.bytes_to_delete += bytes;
+                        s.fs_bytes_to_delete += round_up(bytes);
+                    }
+                    true
+                }
+                Deletion::Archive => {
+                    s.to_archive.push(r);
+                    let bytes = i64::from(r.sample_file_bytes);
+                    s.bytes_to_archive += bytes;
+                    s.fs_bytes_to_archive += round_up(bytes);
+                    true
+                }
+                Deletion::Keep => true,
+                Deletion::Stop => false,
             }
-            false
         })
     }
 
+    /// Returns the recordings of streams in the given directory which are queued for archival,
+    /// along with the archive directory each should be copied to.
+    pub(crate) fn recordings_to_archive(&self, dir_id: i32) -> Vec<(CompositeId, i32)> {
+        let mut out = Vec::new();
+        for s in self.streams_by_id.values() {
+            if s.sample_file_dir_id != Some(dir_id) {
+                continue;
+            }
+            let Some(archive_dir_id) = s.config.archive_sample_file_dir_id else {
+                continue;
+            };
+            out.extend(s.to_archive.iter().map(|r| (r.id, archive_dir_id)));
+        }
+        out
+    }
+
+    /// Notes that the given recording has been copied to `archive_dir_id` and the copy synced.
+    /// The next flush will point the recording at the copy and mark the original as garbage.
+    pub(crate) fn mark_archived(
+        &mut self,
+        id: CompositeId,
+        archive_dir_id: i32,
+    ) -> Result<(), Error> {
+        let s = match self.streams_by_id.get_mut(&id.stream()) {
+            None => bail!(Internal, msg("no stream for recording {id}")),
+            Some(s) => s,
+        };
+        let Some(i) = s.to_archive.iter().position(|r| r.id == id) else {
+            bail!(Internal, msg("recording {id} isn't queued for archival"));
+        };
+        let row = s.to_archive.remove(i);
+        s.archived.push((row, archive_dir_id));
+        Ok(())
+    }
+
     /// Initializes the video_sample_entries. To be called during construction.
     fn init_video_sample_entries(&mut self) -> Result<(), Error> {
         info!("Loading video sample entries");
@@ -1598,6 +2634,43 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Initializes the audio_sample_entries. To be called during construction.
+    fn init_audio_sample_entries(&mut self) -> Result<(), Error> {
+        info!("Loading audio sample entries");
+        let mut stmt = self.conn.prepare(
+            r#"
+            select
+                id,
+                rfc6381_codec,
+                sample_rate,
+                channels,
+                data
+            from
+                audio_sample_entry
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let channels: i32 = row.get(3)?;
+            self.audio_sample_entries_by_id.insert(
+                id,
+                Arc::new(AudioSampleEntry {
+                    id,
+                    rfc6381_codec: row.get(1)?,
+                    sample_rate: row.get(2)?,
+                    channels: u16::try_from(channels).map_err(|e| err!(OutOfRange, source(e)))?,
+                    data: row.get(4)?,
+                }),
+            );
+        }
+        info!(
+            "Loaded {} audio sample entries",
+            self.audio_sample_entries_by_id.len()
+        );
+        Ok(())
+    }
+
     /// Initializes the sample file dirs.
     /// To be called during construction.
     fn init_sample_file_dirs(&mut self) -> Result<(), Error> {
@@ -1632,6 +2705,8 @@ impl LockedDatabase {
                     id,
                     uuid: dir_uuid.0,
                     path: config.path,
+                    archive: config.archive,
+                    direct_io: config.direct_io,
                     dir: None,
                     last_complete_open,
                     garbage_needs_unlink: raw::list_garbage(&self.conn, id)?,
@@ -1722,14 +2797,23 @@ impl LockedDatabase {
                     range: None,
                     sample_file_bytes: 0,
                     fs_bytes: 0,
+                    archived_sample_file_bytes: 0,
+                    archived_fs_bytes: 0,
                     to_delete: Vec::new(),
                     bytes_to_delete: 0,
                     fs_bytes_to_delete: 0,
+                    archived_bytes_to_delete: 0,
+                    archived_fs_bytes_to_delete: 0,
+                    to_archive: Vec::new(),
+                    archived: Vec::new(),
+                    bytes_to_archive: 0,
+                    fs_bytes_to_archive: 0,
                     bytes_to_add: 0,
                     fs_bytes_to_add: 0,
                     duration: recording::Duration(0),
                     committed_days: days::Map::default(),
                     cum_recordings: row.get(5)?,
+                    unloaded: 0..0,
                     cum_media_duration: recording::Duration(row.get(6)?),
                     cum_runs: row.get(7)?,
                     uncommitted: VecDeque::new(),
@@ -1796,6 +2880,49 @@ impl LockedDatabase {
         Ok(id)
     }
 
+    /// Inserts the specified audio sample entry if absent.
+    /// On success, returns the id of a new or existing row.
+    pub fn insert_audio_sample_entry(
+        &mut self,
+        entry: AudioSampleEntryToInsert,
+    ) -> Result<i32, Error> {
+        for (&id, a) in &self.audio_sample_entries_by_id {
+            if a.data == entry.data {
+                // The other fields are derived from data, so differences indicate a bug.
+                if a.sample_rate != entry.sample_rate || a.channels != entry.channels {
+                    bail!(
+                        Internal,
+                        msg("audio_sample_entry id {id}: existing entry {a:?}, new {entry:?}"),
+                    );
+                }
+                return Ok(id);
+            }
+        }
+
+        let mut stmt = self.conn.prepare_cached(INSERT_AUDIO_SAMPLE_ENTRY_SQL)?;
+        stmt.execute(named_params! {
+            ":rfc6381_codec": &entry.rfc6381_codec,
+            ":sample_rate": entry.sample_rate,
+            ":channels": i32::from(entry.channels),
+            ":data": &entry.data,
+        })
+        .map_err(|e| err!(e, msg("Unable to insert {entry:#?}")))?;
+
+        let id = self.conn.last_insert_rowid() as i32;
+        self.audio_sample_entries_by_id.insert(
+            id,
+            Arc::new(AudioSampleEntry {
+                id,
+                sample_rate: entry.sample_rate,
+                channels: entry.channels,
+                data: entry.data,
+                rfc6381_codec: entry.rfc6381_codec,
+            }),
+        );
+
+        Ok(id)
+    }
+
     pub fn add_sample_file_dir(&mut self, path: PathBuf) -> Result<i32, Error> {
         let mut meta = schema::DirMeta::default();
         let uuid = Uuid::new_v4();
@@ -1834,6 +2961,8 @@ impl LockedDatabase {
                 id,
                 path,
                 uuid,
+                archive: false,
+                direct_io: false,
                 dir: Some(dir),
                 last_complete_open: Some(*o),
                 garbage_needs_unlink: FastHashSet::default(),
@@ -1846,6 +2975,94 @@ impl LockedDatabase {
         Ok(id)
     }
 
+    /// Marks or unmarks the given directory as an archive directory.
+    ///
+    /// Streams may record only into non-archive directories and archive only into archive
+    /// directories, so this fails if the directory is in use in a conflicting way.
+    pub fn set_sample_file_dir_archive(&mut self, dir_id: i32, archive: bool) -> Result<(), Error> {
+        let d = match self.sample_file_dirs_by_id.get(&dir_id) {
+            None => bail!(NotFound, msg("no such dir {dir_id}")),
+            Some(d) => d,
+        };
+        if d.archive == archive {
+            return Ok(());
+        }
+        for (&id, s) in &self.streams_by_id {
+            if archive && s.sample_file_dir_id == Some(dir_id) {
+                bail!(
+                    FailedPrecondition,
+                    msg("can't make dir {dir_id} an archive; stream {id} records into it")
+                );
+            }
+            if !archive && s.config.archive_sample_file_dir_id == Some(dir_id) {
+                bail!(
+                    FailedPrecondition,
+                    msg("dir {dir_id} is still the archive dir of stream {id}")
+                );
+            }
+        }
+        if !archive {
+            let archived: i64 = self.conn.query_row(
+                "select count(*) from recording where sample_file_dir_id = ?",
+                params![dir_id],
+                |row| row.get(0),
+            )?;
+            if archived > 0 {
+                bail!(
+                    FailedPrecondition,
+                    msg("dir {dir_id} still holds {archived} archived recordings")
+                );
+            }
+        }
+        let mut config: SampleFileDirConfig = self.conn.query_row(
+            "select config from sample_file_dir where id = ?",
+            params![dir_id],
+            |row| row.get(0),
+        )?;
+        config.archive = archive;
+        self.conn.execute(
+            "update sample_file_dir set config = ? where id = ?",
+            params![&config, dir_id],
+        )?;
+        self.sample_file_dirs_by_id
+            .get_mut(&dir_id)
+            .expect("dir exists")
+            .archive = archive;
+        Ok(())
+    }
+
+    /// Sets if the given directory's new sample files are written with `O_DIRECT`.
+    ///
+    /// If the directory is open, this takes effect with its next recording.
+    pub fn set_sample_file_dir_direct_io(
+        &mut self,
+        dir_id: i32,
+        direct_io: bool,
+    ) -> Result<(), Error> {
+        let d = match self.sample_file_dirs_by_id.get_mut(&dir_id) {
+            None => bail!(NotFound, msg("no such dir {dir_id}")),
+            Some(d) => d,
+        };
+        if d.direct_io == direct_io {
+            return Ok(());
+        }
+        let mut config: SampleFileDirConfig = self.conn.query_row(
+            "select config from sample_file_dir where id = ?",
+            params![dir_id],
+            |row| row.get(0),
+        )?;
+        config.direct_io = direct_io;
+        self.conn.execute(
+            "update sample_file_dir set config = ? where id = ?",
+            params![&config, dir_id],
+        )?;
+        d.direct_io = direct_io;
+        if let Some(dir) = d.dir.as_ref() {
+            dir.set_direct_io(direct_io);
+        }
+        Ok(())
+    }
+
     pub fn delete_sample_file_dir(&mut self, dir_id: i32) -> Result<(), Error> {
         for (&id, s) in self.streams_by_id.iter() {
             if s.sample_file_dir_id == Some(dir_id) {
@@ -1854,6 +3071,12 @@ impl LockedDatabase {
                     msg("can't delete dir referenced by stream {id}")
                 );
             }
+            if s.config.archive_sample_file_dir_id == Some(dir_id) {
+                bail!(
+                    FailedPrecondition,
+                    msg("can't delete dir used as archive by stream {id}")
+                );
+            }
         }
         let mut d = match self.sample_file_dirs_by_id.entry(dir_id) {
             ::std::collections::btree_map::Entry::Occupied(e) => e,
@@ -1907,6 +3130,7 @@ impl LockedDatabase {
 
     /// Adds a camera.
     pub fn add_camera(&mut self, mut camera: CameraChange) -> Result<i32, Error> {
+        self.check_stream_dirs(None, &camera)?;
         let uuid = Uuid::new_v4();
         let uuid_bytes = &uuid.as_bytes()[..];
         let tx = self.conn.transaction()?;
@@ -1944,6 +3168,62 @@ impl LockedDatabase {
         Ok(camera_id)
     }
 
+    /// Checks that the directories named by `change` suit their use: streams record into
+    /// non-archive directories and archive into archive directories.
+    fn check_stream_dirs(
+        &self,
+        camera_id: Option<i32>,
+        change: &CameraChange,
+    ) -> Result<(), Error> {
+        let existing_streams = camera_id
+            .and_then(|id| self.cameras_by_id.get(&id))
+            .map(|c| c.streams)
+            .unwrap_or_default();
+        for (i, sc) in change.streams.iter().enumerate() {
+            let type_ = StreamType::from_index(i).unwrap();
+            if let Some(d) = sc.sample_file_dir_id {
+                if self
+                    .sample_file_dirs_by_id
+                    .get(&d)
+                    .map_or(false, |d| d.archive)
+                {
+                    bail!(
+                        InvalidArgument,
+                        msg("{type_} stream can't record into archive dir {d}")
+                    );
+                }
+            }
+            if let Some(a) = sc.config.archive_sample_file_dir_id {
+                match self.sample_file_dirs_by_id.get(&a) {
+                    None => bail!(NotFound, msg("no such archive dir {a}")),
+                    Some(d) if !d.archive => bail!(
+                        InvalidArgument,
+                        msg("dir {a} isn't an archive dir; can't archive {type_} stream into it")
+                    ),
+                    Some(_) => {}
+                }
+                if sc.sample_file_dir_id == Some(a) {
+                    bail!(
+                        InvalidArgument,
+                        msg("{type_} stream can't archive into its own dir")
+                    );
+                }
+            }
+            let Some(s) = existing_streams[i].and_then(|id| self.streams_by_id.get(&id)) else {
+                continue;
+            };
+            if s.config.archive_sample_file_dir_id != sc.config.archive_sample_file_dir_id
+                && (!s.to_archive.is_empty() || !s.archived.is_empty())
+            {
+                bail!(
+                    FailedPrecondition,
+                    msg("{type_} stream is archiving recordings now; try again later")
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Returns a `CameraChange` for the given camera which does nothing.
     ///
     /// The caller can modify it to taste then pass it to `update_camera`.
@@ -1975,6 +3255,7 @@ impl LockedDatabase {
 
     /// Updates a camera.
     pub fn update_camera(&mut self, camera_id: i32, mut camera: CameraChange) -> Result<(), Error> {
+        self.check_stream_dirs(Some(camera_id), &camera)?;
         let tx = self.conn.transaction()?;
         let streams;
         let Some(c) = self.cameras_by_id.get_mut(&camera_id) else {
@@ -2028,6 +3309,11 @@ impl LockedDatabase {
                         msg("can't remove camera {id}; has recordings")
                     );
                 }
+                raw::delete_thumbnails(&tx, *stream_id, None)?;
+                raw::delete_detections(&tx, *stream_id, None)?;
+                raw::delete_uploads(&tx, *stream_id)?;
+                raw::delete_replications(&tx, *stream_id)?;
+                raw::delete_stream_health(&tx, *stream_id)?;
                 let rows = stream_stmt.execute(named_params! {":id": stream_id})?;
                 if rows != 1 {
                     bail!(Internal, msg("stream {id} missing from database"));
@@ -2069,7 +3355,11 @@ impl LockedDatabase {
                     bail!(Internal, msg("no such stream {}", c.stream_id));
                 };
                 let mut new_config = stream.config.clone();
-                new_config.mode = (if c.new_record { "record" } else { "" }).into();
+                if !c.new_record {
+                    new_config.mode.clear();
+                } else if !new_config.is_recording() {
+                    new_config.mode = crate::json::STREAM_MODE_RECORD.to_owned();
+                }
                 new_config.retain_bytes = c.new_limit;
                 let rows = stmt.execute(named_params! {
                     ":config": &new_config,
@@ -2084,12 +3374,42 @@ impl LockedDatabase {
                 .streams_by_id
                 .get_mut(&c.stream_id)
                 .expect("stream in db but not state");
-            s.config.mode = (if c.new_record { "record" } else { "" }).into();
+            if !c.new_record {
+                s.config.mode.clear();
+            } else if !s.config.is_recording() {
+                s.config.mode = crate::json::STREAM_MODE_RECORD.to_owned();
+            }
             s.config.retain_bytes = c.new_limit;
         }
         Ok(())
     }
 
+    /// Sets or clears a stream's recording schedule. See [`crate::json::StreamConfig::schedule`].
+    pub fn update_stream_schedule(
+        &mut self,
+        stream_id: i32,
+        schedule: Option<crate::json::ScheduleConfig>,
+    ) -> Result<(), Error> {
+        if let Some(ref s) = schedule {
+            s.validate()?;
+        }
+        let Some(stream) = self.streams_by_id.get_mut(&stream_id) else {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        };
+        let mut new_config = stream.config.clone();
+        new_config.schedule = schedule;
+        let rows = self
+            .conn
+            .prepare_cached("update stream set config = :config where id = :id")?
+            .execute(named_params! {
+                ":config": &new_config,
+                ":id": stream_id,
+            })?;
+        assert_eq!(rows, 1, "missing stream {stream_id}");
+        stream.config = new_config;
+        Ok(())
+    }
+
     // ---- auth ----
 
     pub fn users_by_id(&self) -> &BTreeMap<i32, User> {
@@ -2112,6 +3432,15 @@ impl LockedDatabase {
         self.auth.get_user(username)
     }
 
+    pub fn login_blocked_until(
+        &self,
+        addr: Option<std::net::IpAddr>,
+        username: &str,
+        now_sec: i64,
+    ) -> Option<i64> {
+        self.auth.login_blocked_until(addr, username, now_sec)
+    }
+
     pub fn login_by_password(
         &mut self,
         req: auth::Request,
@@ -2155,6 +3484,101 @@ impl LockedDatabase {
             .revoke_session(&self.conn, reason, detail, req, hash)
     }
 
+    pub fn user_sessions(
+        &mut self,
+        user_id: i32,
+    ) -> Result<Vec<(auth::SessionHash, &auth::Session)>, base::Error> {
+        self.auth.user_sessions(&self.conn, user_id)
+    }
+
+    pub fn session_count(&self) -> Result<i64, base::Error> {
+        self.auth.session_count(&self.conn)
+    }
+
+    pub fn api_tokens_by_id(&self) -> &BTreeMap<i32, ApiToken> {
+        self.auth.api_tokens_by_id()
+    }
+
+    pub fn make_api_token(
+        &mut self,
+        uid: i32,
+        description: Option<String>,
+        creation_time_sec: i64,
+        expiration_time_sec: Option<i64>,
+        permissions: schema::Permissions,
+    ) -> Result<(RawApiToken, &ApiToken), base::Error> {
+        self.auth.make_api_token(
+            &self.conn,
+            uid,
+            description,
+            creation_time_sec,
+            expiration_time_sec,
+            permissions,
+        )
+    }
+
+    pub fn authenticate_api_token(
+        &mut self,
+        req: auth::Request,
+        token: &RawApiToken,
+    ) -> Result<(&ApiToken, &User), base::Error> {
+        self.auth.authenticate_api_token(req, token)
+    }
+
+    pub fn delete_api_token(&mut self, id: i32) -> Result<(), base::Error> {
+        self.auth.delete_api_token(&self.conn, id)
+    }
+
+    pub fn credentials_by_id(&self) -> &BTreeMap<i32, Credential> {
+        self.auth.credentials_by_id()
+    }
+
+    pub fn add_credential(
+        &mut self,
+        uid: i32,
+        credential_id: Vec<u8>,
+        description: Option<String>,
+        creation_time_sec: i64,
+        passkey: String,
+    ) -> Result<&Credential, base::Error> {
+        self.auth.add_credential(
+            &self.conn,
+            uid,
+            credential_id,
+            description,
+            creation_time_sec,
+            passkey,
+        )
+    }
+
+    pub fn login_by_credential(
+        &mut self,
+        req: auth::Request,
+        id: i32,
+        passkey: String,
+        domain: Option<Vec<u8>>,
+        session_flags: i32,
+    ) -> Result<(RawSessionId, &Session), base::Error> {
+        self.auth
+            .login_by_credential(&self.conn, req, id, passkey, domain, session_flags)
+    }
+
+    pub fn delete_credential(&mut self, id: i32) -> Result<(), base::Error> {
+        self.auth.delete_credential(&self.conn, id)
+    }
+
+    // ---- audit ----
+
+    /// Appends an entry to the audit log, returning its id. Unlike most changes, this is
+    /// written immediately rather than on the next flush.
+    pub fn add_audit_entry(&self, entry: &audit::NewEntry) -> Result<i64, Error> {
+        audit::insert(&self.conn, entry)
+    }
+
+    pub fn list_audit_entries(&self, filter: &audit::Filter) -> Result<Vec<audit::Entry>, Error> {
+        audit::list(&self.conn, filter)
+    }
+
     // ---- signal ----
 
     pub fn signals_by_id(&self) -> &BTreeMap<u32, signal::Signal> {
@@ -2163,6 +3587,19 @@ impl LockedDatabase {
     pub fn signal_types_by_uuid(&self) -> &FastHashMap<Uuid, signal::Type> {
         self.signal.types_by_uuid()
     }
+    pub fn signal_mqtt_rules(&self) -> signal::MqttRules {
+        self.signal.mqtt_rules()
+    }
+    pub fn camera_motion_at(&self, camera_id: i32, when: recording::Time) -> bool {
+        self.signal.camera_motion_at(camera_id, when)
+    }
+    pub fn camera_motion_ranges(
+        &self,
+        camera_id: i32,
+        range: Range<recording::Time>,
+    ) -> Vec<Range<recording::Time>> {
+        self.signal.camera_motion_ranges(camera_id, range)
+    }
     pub fn list_changes_by_time(
         &self,
         desired_time: Range<recording::Time>,
@@ -2178,6 +3615,24 @@ impl LockedDatabase {
     ) -> Result<(), base::Error> {
         self.signal.update_signals(when, signals, states)
     }
+    pub fn update_signal_changes(
+        &mut self,
+        signal: u32,
+        changes: &[(recording::Time, u16)],
+        end: recording::Time,
+    ) -> Result<(), base::Error> {
+        self.signal.update_signal_changes(signal, changes, end)
+    }
+    pub fn put_signal_type(
+        &mut self,
+        uuid: Uuid,
+        config: crate::json::SignalTypeConfig,
+    ) -> Result<(), base::Error> {
+        self.signal.put_type(&self.conn, uuid, config)
+    }
+    pub fn delete_signal_type(&mut self, uuid: Uuid) -> Result<(), base::Error> {
+        self.signal.delete_type(&self.conn, uuid)
+    }
 }
 
 /// Pragmas for full database integrity.
@@ -2195,6 +3650,33 @@ pub static INTEGRITY_PRAGMAS: [&str; 3] = [
     "pragma synchronous = 3",
 ];
 
+/// SQLite's `synchronous` setting; see <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Synchronous {
+    Off = 0,
+    Normal = 1,
+    Full = 2,
+    Extra = 3,
+}
+
+/// Journal and I/O tuning, applied by [`LockedDatabase::set_tuning`].
+///
+/// Each `None` leaves the default in place: SQLite's for `wal_autocheckpoint` and `mmap_size`,
+/// and [`INTEGRITY_PRAGMAS`]'s `extra` for `synchronous`.
+#[derive(Clone, Debug, Default)]
+pub struct Tuning {
+    /// The WAL size, in pages, which triggers a checkpoint, or 0 to disable automatic
+    /// checkpoints. Larger values mean fewer, larger writes to the main database file.
+    pub wal_autocheckpoint_pages: Option<u32>,
+
+    /// Lower levels mean fewer `fsync` calls but may lose recent transactions on power loss.
+    pub synchronous: Option<Synchronous>,
+
+    /// The maximum number of bytes of the database to access via memory-mapped I/O.
+    pub mmap_size_bytes: Option<i64>,
+}
+
 /// Sets pragmas for full database integrity.
 pub(crate) fn set_integrity_pragmas(conn: &mut rusqlite::Connection) -> Result<(), Error> {
     for pragma in INTEGRITY_PRAGMAS {
@@ -2224,6 +3706,9 @@ pub(crate) fn check_sqlite_version() -> Result<(), Error> {
 pub fn init(conn: &mut rusqlite::Connection) -> Result<(), Error> {
     check_sqlite_version()?;
     set_integrity_pragmas(conn)?;
+
+    // This must be set before creating any tables; see `LockedDatabase::incremental_vacuum`.
+    conn.execute_batch("pragma auto_vacuum = incremental")?;
     let tx = conn.transaction()?;
     tx.execute_batch(include_str!("schema.sql"))
         .map_err(|e| err!(e, msg("unable to create database schema")))?;
@@ -2338,6 +3823,20 @@ fn operation() -> &'static str {
 impl<C: Clocks + Clone> Database<C> {
     /// Creates the database from a caller-supplied SQLite connection.
     pub fn new(
+        clocks: C,
+        conn: rusqlite::Connection,
+        read_write: bool,
+    ) -> Result<Database<C>, Error> {
+        let db = Self::new_lazy(clocks, conn, read_write)?;
+        db.load_recordings()?;
+        Ok(db)
+    }
+
+    /// As [`Database::new`], but defers loading streams' recording totals to
+    /// [`Database::load_recordings`], which the caller should run (likely on a background
+    /// thread) after this returns. On databases with millions of recordings, that loading
+    /// otherwise dominates startup time.
+    pub fn new_lazy(
         clocks: C,
         mut conn: rusqlite::Connection,
         read_write: bool,
@@ -2375,9 +3874,11 @@ impl<C: Clocks + Clone> Database<C> {
         let signal = signal::State::init(&conn, &config)?;
         let db = Database {
             db: Some(Mutex::new(LockedDatabase {
+                backup: None,
                 conn,
                 uuid: db_uuid,
                 flush_count: 0,
+                flush_duration: base::metrics::Histogram::new(base::metrics::LATENCY_BUCKETS),
                 open,
                 open_monotonic,
                 auth,
@@ -2387,27 +3888,59 @@ impl<C: Clocks + Clone> Database<C> {
                 cameras_by_uuid: BTreeMap::new(),
                 streams_by_id: BTreeMap::new(),
                 video_sample_entries_by_id: BTreeMap::new(),
+                audio_sample_entries_by_id: BTreeMap::new(),
                 video_index_cache: RefCell::new(LinkedHashMap::with_capacity_and_hasher(
                     VIDEO_INDEX_CACHE_LEN + 1,
                     Default::default(),
                 )),
                 on_flush: Vec::new(),
+                sample_file_key: None,
             })),
             clocks,
         };
         {
             let l = &mut *db.lock();
             l.init_video_sample_entries()?;
+            l.init_audio_sample_entries()?;
             l.init_sample_file_dirs()?;
             l.init_cameras()?;
             l.init_streams()?;
             for (&stream_id, ref mut stream) in &mut l.streams_by_id {
-                // TODO: we could use one thread per stream if we had multiple db conns.
-                let camera = l.cameras_by_id.get(&stream.camera_id).unwrap();
-                init_recordings(&mut l.conn, stream_id, camera, stream)?;
+                init_recordings(&l.conn, stream_id, stream)?;
+            }
+        }
+        Ok(db)
+    }
+
+    /// Loads the committed recordings of each stream into its `duration` and `committed_days`,
+    /// as deferred by [`Database::new_lazy`].
+    ///
+    /// This releases the database lock between batches of recordings, so recording and requests
+    /// can proceed meanwhile. Until it's done, those totals are incomplete. The byte totals,
+    /// which retention relies on, are complete from the start.
+    pub fn load_recordings(&self) -> Result<(), Error> {
+        let stream_ids: Vec<i32> = self.lock().streams_by_id.keys().copied().collect();
+        for stream_id in stream_ids {
+            let mut n = 0;
+            loop {
+                let l = &mut *self.lock();
+                let Some(s) = l.streams_by_id.get_mut(&stream_id) else {
+                    break; // deleted in the meantime.
+                };
+                if s.unloaded.is_empty() {
+                    if n > 0 {
+                        let camera = &l.cameras_by_id[&s.camera_id];
+                        info!(
+                            "Loaded {} recordings for camera {} stream {:?}",
+                            n, camera.short_name, s.type_
+                        );
+                    }
+                    break;
+                }
+                n += s.load_recordings(&l.conn)?;
             }
         }
-        Ok(db)
+        Ok(())
     }
 
     #[inline(always)]
@@ -2415,6 +3948,72 @@ impl<C: Clocks + Clone> Database<C> {
         self.clocks.clone()
     }
 
+    /// Writes a consistent copy of the database to `path`, which must not exist.
+    ///
+    /// Unlike [`LockedDatabase::backup_to`], this releases the database lock periodically, so
+    /// recording and requests proceed while it runs. It blocks for the duration, so async
+    /// callers should use `spawn_blocking`.
+    pub fn backup(&self, path: &std::path::Path) -> Result<(), Error> {
+        self.lock().start_backup(path)?;
+        while !self.lock().step_backup(BACKUP_STEP_PAGES)? {
+            std::thread::sleep(BACKUP_STEP_PAUSE);
+        }
+        Ok(())
+    }
+
+    /// Performs routine maintenance: reclaims free pages via [`LockedDatabase::incremental_vacuum`]
+    /// until none remain, `deadline` passes, or shutdown is requested, then runs
+    /// [`LockedDatabase::analyze`].
+    ///
+    /// As with [`Database::backup`], the lock is released between steps so recording and
+    /// requests can proceed meanwhile.
+    pub fn maintain(
+        &self,
+        shutdown_rx: &base::shutdown::Receiver,
+        deadline: std::time::Instant,
+    ) -> Result<MaintenanceStats, Error> {
+        let (page_size, auto_vacuum, freelist_before) = self.lock().vacuum_state()?;
+        let incremental = auto_vacuum == 2;
+        let mut freelist = freelist_before;
+        while incremental
+            && freelist > 0
+            && std::time::Instant::now() < deadline
+            && shutdown_rx.check().is_ok()
+        {
+            freelist = self.lock().incremental_vacuum(VACUUM_STEP_PAGES)?;
+            std::thread::sleep(BACKUP_STEP_PAUSE);
+        }
+        self.lock().analyze()?;
+        Ok(MaintenanceStats {
+            incremental,
+            freed_bytes: (freelist_before - freelist) * page_size,
+            free_bytes: freelist * page_size,
+        })
+    }
+
+    /// Lists recordings as in [`LockedDatabase::list_recordings_by_time`], without holding the
+    /// database lock while the caller processes them.
+    ///
+    /// Committed recordings are fetched in pages, locking the database only while fetching each
+    /// page, so a slow consumer doesn't stall writers or other requests. Uncommitted recordings
+    /// are snapshotted along with the last page and follow in ascending order by id, so
+    /// [`RecordingAggregator`] can process the results as usual.
+    /// Recordings deleted after their page is fetched may still be returned.
+    pub fn recordings_by_time(
+        &self,
+        stream_id: i32,
+        desired_time: Range<recording::Time>,
+    ) -> RecordingsByTime<'_, C> {
+        RecordingsByTime {
+            db: self,
+            stream_id,
+            desired_time,
+            after: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
     /// Locks the database; the returned reference is the only way to perform (read or write)
     /// operations.
     pub fn lock(&self) -> DatabaseGuard<C> {
@@ -2440,6 +4039,101 @@ impl<C: Clocks + Clone> Database<C> {
     }
 }
 
+/// The result of [`Database::maintain`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceStats {
+    /// True if the database uses `auto_vacuum = incremental`. Otherwise, free pages can only be
+    /// reclaimed by a full `vacuum`, and `freed_bytes` is 0.
+    pub incremental: bool,
+
+    /// The bytes returned to the filesystem from the database's freelist.
+    pub freed_bytes: i64,
+
+    /// The bytes remaining on the freelist afterward.
+    pub free_bytes: i64,
+}
+
+/// The number of committed recordings [`RecordingsByTime`] fetches per acquisition of the
+/// database lock.
+const RECORDINGS_BY_TIME_PAGE_LEN: usize = 256;
+
+/// Iterator returned by [`Database::recordings_by_time`].
+pub struct RecordingsByTime<'db, C: Clocks + Clone> {
+    db: &'db Database<C>,
+    stream_id: i32,
+    desired_time: Range<recording::Time>,
+
+    /// The start time and id of the last committed recording fetched, if any.
+    after: Option<(recording::Time, CompositeId)>,
+
+    /// Fetched recordings which haven't yet been returned.
+    page: std::vec::IntoIter<ListRecordingsRow>,
+
+    /// True once the last page (or an error) has been fetched.
+    done: bool,
+}
+
+impl<'db, C: Clocks + Clone> RecordingsByTime<'db, C> {
+    fn fetch(&mut self) -> Result<Vec<ListRecordingsRow>, Error> {
+        let db = self.db.lock();
+        let Some(s) = db.streams_by_id.get(&self.stream_id) else {
+            bail!(NotFound, msg("no such stream {}", self.stream_id));
+        };
+        let mut rows = Vec::with_capacity(RECORDINGS_BY_TIME_PAGE_LEN);
+        let n = raw::list_recordings_by_time_page(
+            &db.conn,
+            self.stream_id,
+            self.desired_time.clone(),
+            self.after,
+            RECORDINGS_BY_TIME_PAGE_LEN,
+            &mut |r| {
+                rows.push(r);
+                Ok(())
+            },
+        )?;
+        if let Some(last) = rows.last() {
+            self.after = Some((last.start, last.id));
+        }
+        if n < RECORDINGS_BY_TIME_PAGE_LEN {
+            // Snapshot the uncommitted recordings under the same lock acquisition as the last
+            // page, so none are missed or duplicated by a concurrent flush.
+            db.list_uncommitted_recordings_by_time(
+                self.stream_id,
+                s,
+                self.desired_time.clone(),
+                &mut |r| {
+                    rows.push(r);
+                    Ok(())
+                },
+            )?;
+            self.done = true;
+        }
+        Ok(rows)
+    }
+}
+
+impl<'db, C: Clocks + Clone> Iterator for RecordingsByTime<'db, C> {
+    type Item = Result<ListRecordingsRow, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.page.next() {
+                return Some(Ok(row));
+            }
+            if self.done {
+                return None;
+            }
+            match self.fetch() {
+                Ok(rows) => self.page = rows.into_iter(),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
 /// Reference to a locked database returned by [Database::lock].
 pub struct DatabaseGuard<'db, C: Clocks> {
     clocks: &'db C,
@@ -2604,13 +4298,13 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (6, 0, '');")
+        c.execute_batch("delete from version; insert into version values (9, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.msg()
                 .unwrap()
-                .starts_with("database schema version 6 is too old (expected 7)"),
+                .starts_with("database schema version 9 is too old (expected 10)"),
             "got: {e:?}"
         );
     }
@@ -2619,13 +4313,13 @@ mod tests {
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (8, 0, '');")
+        c.execute_batch("delete from version; insert into version values (11, 0, '');")
             .unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(
             e.msg()
                 .unwrap()
-                .starts_with("database schema version 8 is too new (expected 7)"),
+                .starts_with("database schema version 11 is too new (expected 10)"),
             "got: {e:?}"
         );
     }
@@ -2822,7 +4516,7 @@ mod tests {
             let mut n = 0;
             db.delete_oldest_recordings(main_stream_id, &mut |_| {
                 n += 1;
-                true
+                Deletion::Delete
             })
             .unwrap();
             assert_eq!(n, 1);
@@ -2836,7 +4530,7 @@ mod tests {
             // A second run
             db.delete_oldest_recordings(main_stream_id, &mut |_| {
                 n += 1;
-                true
+                Deletion::Delete
             })
             .unwrap();
             assert_eq!(n, 0);
@@ -2875,6 +4569,620 @@ mod tests {
         assert_eq!(&g, &[]);
     }
 
+    #[test]
+    fn list_aggregated_recordings_from() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let start = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        let mut db = tdb.db.lock();
+        let video_sample_entry_id = db
+            .insert_video_sample_entry(VideoSampleEntryToInsert {
+                width: 1920,
+                height: 1080,
+                pasp_h_spacing: 1,
+                pasp_v_spacing: 1,
+                data: [0u8; 100].to_vec(),
+                rfc6381_codec: "avc1.000000".to_owned(),
+            })
+            .unwrap();
+        let mut r = RecordingToInsert {
+            start,
+            media_duration_90k: 60 * TIME_UNITS_PER_SEC as i32,
+            wall_duration_90k: 60 * TIME_UNITS_PER_SEC as i32,
+            video_sample_entry_id,
+            ..Default::default()
+        };
+        for _ in 0..5 {
+            let (id, _) = db
+                .add_recording(testutil::TEST_STREAM_ID, r.clone())
+                .unwrap();
+            db.mark_synced(id).unwrap();
+            r.start += recording::Duration(r.wall_duration_90k as i64);
+            r.run_offset += 1;
+        }
+        db.flush("list_aggregated_recordings_from").unwrap();
+        let all = recording::Time::min_value()..recording::Time::max_value();
+        let split = recording::Duration(i64::max_value());
+        let mut rows = Vec::new();
+        db.list_aggregated_recordings_from(testutil::TEST_STREAM_ID, all, split, 2, &mut |row| {
+            rows.push((row.ids.clone(), row.run_start_id, row.time.start));
+            Ok(())
+        })
+        .unwrap();
+        let minute = recording::Duration(60 * TIME_UNITS_PER_SEC);
+        assert_eq!(rows, &[(2..5, 0, start + minute + minute)]);
+    }
+
+    #[test]
+    fn recordings_by_time() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let start = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        let all = recording::Time::min_value()..recording::Time::max_value();
+        let split = recording::Duration(30 * 60 * TIME_UNITS_PER_SEC);
+        {
+            let mut db = tdb.db.lock();
+            let video_sample_entry_id = db
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+            let mut r = RecordingToInsert {
+                start,
+                media_duration_90k: 60 * TIME_UNITS_PER_SEC as i32,
+                wall_duration_90k: 60 * TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sample_entry_id,
+                ..Default::default()
+            };
+
+            // Span several pages, then leave one recording uncommitted.
+            for _ in 0..2 * RECORDINGS_BY_TIME_PAGE_LEN + 1 {
+                let (id, _) = db
+                    .add_recording(testutil::TEST_STREAM_ID, r.clone())
+                    .unwrap();
+                db.mark_synced(id).unwrap();
+                r.start += recording::Duration(r.wall_duration_90k as i64);
+                r.run_offset += 1;
+            }
+            db.flush("recordings_by_time").unwrap();
+            db.add_recording(testutil::TEST_STREAM_ID, r).unwrap();
+        }
+
+        let mut expected_ids = Vec::new();
+        let mut expected_aggs = Vec::new();
+        {
+            let db = tdb.db.lock();
+            db.list_recordings_by_time(testutil::TEST_STREAM_ID, all.clone(), &mut |row| {
+                expected_ids.push(row.id);
+                Ok(())
+            })
+            .unwrap();
+            db.list_aggregated_recordings(testutil::TEST_STREAM_ID, all.clone(), split, &mut |a| {
+                expected_aggs.push((a.ids.clone(), a.first_uncommitted));
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(expected_ids.len(), 2 * RECORDINGS_BY_TIME_PAGE_LEN + 2);
+
+        let mut ids = Vec::new();
+        let mut aggs = Vec::new();
+        let mut aggregator = RecordingAggregator::new(testutil::TEST_STREAM_ID, split, 0);
+        let mut push = |a: &ListAggregatedRecordingsRow| -> Result<(), base::Error> {
+            aggs.push((a.ids.clone(), a.first_uncommitted));
+            Ok(())
+        };
+        for row in tdb.db.recordings_by_time(testutil::TEST_STREAM_ID, all) {
+            let row = row.unwrap();
+            ids.push(row.id);
+            aggregator.push(row, &mut push).unwrap();
+        }
+        aggregator.finish(&mut push).unwrap();
+        assert_eq!(ids, expected_ids);
+        assert_eq!(aggs, expected_aggs);
+
+        let mut it = tdb.db.recordings_by_time(
+            99,
+            recording::Time::min_value()..recording::Time::max_value(),
+        );
+        assert_eq!(
+            it.next().unwrap().unwrap_err().kind(),
+            base::ErrorKind::NotFound
+        );
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn lazy_load() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let path = tmpdir.path().to_owned();
+        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path).unwrap();
+        let camera_id = db
+            .lock()
+            .add_camera(CameraChange {
+                short_name: "testcam".to_owned(),
+                config: crate::json::CameraConfig::default(),
+                streams: [
+                    StreamChange {
+                        sample_file_dir_id: Some(sample_file_dir_id),
+                        config: crate::json::StreamConfig {
+                            url: Some(Url::parse("rtsp://test-camera/main").unwrap()),
+                            mode: crate::json::STREAM_MODE_RECORD.to_owned(),
+                            retain_bytes: 2 * ASSUMED_BLOCK_SIZE_BYTES + 1,
+                            ..Default::default()
+                        },
+                    },
+                    StreamChange::default(),
+                    StreamChange::default(),
+                ],
+            })
+            .unwrap();
+        let stream_id = db.lock().cameras_by_id()[&camera_id].streams[0].unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        {
+            let mut db = db.lock();
+            let video_sample_entry_id = db
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+            let mut r = RecordingToInsert {
+                sample_file_bytes: 42,
+                start,
+                media_duration_90k: TIME_UNITS_PER_SEC as i32,
+                wall_duration_90k: TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sample_entry_id,
+                ..Default::default()
+            };
+            for _ in 0..3 {
+                let (id, _) = db.add_recording(stream_id, r.clone()).unwrap();
+                db.mark_synced(id).unwrap();
+                r.start += recording::Duration(TIME_UNITS_PER_SEC);
+                r.run_offset += 1;
+            }
+            db.flush("lazy_load").unwrap();
+        }
+
+        // On reopening lazily, the range and byte totals are known but the others are not.
+        let conn = db.close();
+        let db = Database::new_lazy(clock::RealClocks {}, conn, true).unwrap();
+        {
+            let l = db.lock();
+            let s = &l.streams_by_id()[&stream_id];
+            assert_eq!(s.unloaded_recordings(), 3);
+            assert_eq!(s.sample_file_bytes, 126);
+            assert_eq!(s.fs_bytes, 3 * ASSUMED_BLOCK_SIZE_BYTES);
+            assert_eq!(s.duration, recording::Duration(0));
+            assert_eq!(
+                s.range,
+                Some(start..start + recording::Duration(3 * TIME_UNITS_PER_SEC))
+            );
+        }
+
+        // Retention sees the stream is over its limit even before its recordings are loaded.
+        // Deleting an unloaded recording shouldn't disturb the totals loaded afterward.
+        {
+            let mut db = db.lock();
+            let now = start + recording::Duration(4 * TIME_UNITS_PER_SEC);
+            let mut policy = crate::retention::Policy::new(&db, stream_id, 0, now).unwrap();
+            assert_eq!(policy.fs_bytes_needed(), ASSUMED_BLOCK_SIZE_BYTES - 1);
+            db.delete_oldest_recordings(stream_id, &mut |row| policy.decide(row))
+                .unwrap();
+            db.flush("lazy_load delete").unwrap();
+            let s = &db.streams_by_id()[&stream_id];
+            assert_eq!(s.unloaded_recordings(), 3);
+            assert_eq!(s.sample_file_bytes, 84);
+            assert_eq!(s.fs_bytes, 2 * ASSUMED_BLOCK_SIZE_BYTES);
+        }
+        db.load_recordings().unwrap();
+        let l = db.lock();
+        let s = &l.streams_by_id()[&stream_id];
+        assert_eq!(s.unloaded_recordings(), 0);
+        assert_eq!(s.sample_file_bytes, 84);
+        assert_eq!(s.duration, recording::Duration(2 * TIME_UNITS_PER_SEC));
+        assert_eq!(
+            s.range,
+            Some(
+                start + recording::Duration(TIME_UNITS_PER_SEC)
+                    ..start + recording::Duration(3 * TIME_UNITS_PER_SEC)
+            )
+        );
+    }
+
+    #[test]
+    fn maintain() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        tdb.db
+            .lock()
+            .conn
+            .execute_batch(
+                r#"
+                create table junk (data blob);
+                with recursive n (i) as (select 1 union all select i + 1 from n where i < 100)
+                insert into junk select zeroblob(65536) from n;
+                drop table junk;
+                "#,
+            )
+            .unwrap();
+
+        // Past the deadline, nothing is vacuumed, but statistics are still updated.
+        let (_shutdown_tx, shutdown_rx) = base::shutdown::channel();
+        let stats = tdb
+            .db
+            .maintain(&shutdown_rx, std::time::Instant::now())
+            .unwrap();
+        assert!(stats.incremental);
+        assert_eq!(stats.freed_bytes, 0);
+        assert!(stats.free_bytes >= 100 * 65536, "{stats:?}");
+        let l = tdb.db.lock();
+        let n: i64 = l
+            .conn
+            .query_row("select count(*) from sqlite_stat1", params![], |r| r.get(0))
+            .unwrap();
+        assert!(n > 0);
+        drop(l);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(60);
+        let stats = tdb.db.maintain(&shutdown_rx, deadline).unwrap();
+        assert!(stats.incremental);
+        assert!(stats.freed_bytes >= 100 * 65536, "{stats:?}");
+        assert_eq!(stats.free_bytes, 0);
+    }
+
+    #[test]
+    fn thumbnails() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let start = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        let minute = recording::Duration(60 * TIME_UNITS_PER_SEC);
+        {
+            let mut db = tdb.db.lock();
+            db.insert_thumbnail(testutil::TEST_STREAM_ID, start - minute, 160, 90, b"before")
+                .unwrap();
+            db.insert_thumbnail(testutil::TEST_STREAM_ID, start, 160, 90, b"first")
+                .unwrap();
+            db.insert_thumbnail(
+                testutil::TEST_STREAM_ID,
+                start + minute,
+                160,
+                120,
+                b"second",
+            )
+            .unwrap();
+            let e = db
+                .insert_thumbnail(testutil::TEST_STREAM_ID + 1, start, 160, 90, b"x")
+                .unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::NotFound);
+        }
+
+        // Adding a recording deletes thumbnails preceding it.
+        let mut r = RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        tdb.insert_recording_from_encoder(r);
+
+        let db = tdb.db.lock();
+        let mut rows = Vec::new();
+        db.list_thumbnails(
+            testutil::TEST_STREAM_ID,
+            recording::Time(i64::min_value())..recording::Time(i64::max_value()),
+            &mut |row| {
+                rows.push((row.start, row.width, row.height));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(rows, &[(start, 160, 90), (start + minute, 160, 120)]);
+        let mut data = Vec::new();
+        db.list_thumbnails_with_data(
+            testutil::TEST_STREAM_ID,
+            start + minute..start + minute + minute,
+            &mut |row, d| {
+                data.push((row.start, d.to_owned()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(data, &[(start + minute, b"second".to_vec())]);
+    }
+
+    #[test]
+    fn detections() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let start = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
+        let sec = recording::Duration(TIME_UNITS_PER_SEC);
+        let detection = |time, class: &str| Detection {
+            time,
+            class: class.to_owned(),
+            score: 0.75,
+            left: 0.25,
+            top: 0.5,
+            width: 0.125,
+            height: 0.25,
+        };
+        {
+            let mut db = tdb.db.lock();
+            db.insert_detections(
+                testutil::TEST_STREAM_ID,
+                &[
+                    detection(start - sec, "person"),
+                    detection(start, "person"),
+                    detection(start, "car"),
+                    detection(start + sec, "person"),
+                ],
+            )
+            .unwrap();
+            let e = db
+                .insert_detections(testutil::TEST_STREAM_ID + 1, &[detection(start, "car")])
+                .unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::NotFound);
+        }
+
+        // Adding a recording deletes detections preceding it.
+        let mut r = RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        tdb.insert_recording_from_encoder(r);
+
+        let db = tdb.db.lock();
+        let all = recording::Time(i64::min_value())..recording::Time(i64::max_value());
+        let mut rows = Vec::new();
+        db.list_detections(testutil::TEST_STREAM_ID, all.clone(), None, &mut |d| {
+            rows.push(d);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            rows,
+            &[
+                detection(start, "person"),
+                detection(start, "car"),
+                detection(start + sec, "person"),
+            ]
+        );
+        rows.clear();
+        db.list_detections(testutil::TEST_STREAM_ID, all, Some("car"), &mut |d| {
+            rows.push(d);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(rows, &[detection(start, "car")]);
+    }
+
+    #[test]
+    fn stream_health() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut db = tdb.db.lock();
+        let h = StreamHealth {
+            monitored_sec: 60,
+            up_sec: 50,
+            reconnects: 1,
+            frame_gaps: 2,
+            frames: 1500,
+            packets_lost: 3,
+        };
+        let (day1, day2) = (days::Key(*b"2024-01-01"), days::Key(*b"2024-01-02"));
+        db.add_stream_health(testutil::TEST_STREAM_ID, day2, &h)
+            .unwrap();
+        db.add_stream_health(testutil::TEST_STREAM_ID, day1, &h)
+            .unwrap();
+        db.add_stream_health(testutil::TEST_STREAM_ID, day2, &h)
+            .unwrap();
+        let e = db
+            .add_stream_health(testutil::TEST_STREAM_ID + 1, day1, &h)
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::NotFound);
+        let list = |db: &LockedDatabase| {
+            let mut rows = Vec::new();
+            db.list_stream_health(testutil::TEST_STREAM_ID, &mut |day, h| {
+                rows.push((day, h));
+                Ok(())
+            })
+            .unwrap();
+            rows
+        };
+        let mut doubled = h;
+        doubled.add(&h);
+        assert_eq!(list(&db), &[(day1, h), (day2, doubled)]);
+        db.delete_stream_health_before(day2).unwrap();
+        assert_eq!(list(&db), &[(day2, doubled)]);
+    }
+
+    #[test]
+    fn uploads() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut r = RecordingToInsert::default();
+            let mut encoder = recording::SampleIndexEncoder::default();
+            encoder.add_sample(1, 1, true, &mut r);
+            ids.push(tdb.insert_recording_from_encoder(r).id);
+        }
+        let mut db = tdb.db.lock();
+        assert_eq!(db.list_recordings_to_upload(None, 10).unwrap(), ids);
+        assert_eq!(db.list_recordings_to_upload(None, 1).unwrap(), &ids[..1]);
+        assert_eq!(
+            db.list_recordings_to_upload(Some(ids[0]), 10).unwrap(),
+            &ids[1..]
+        );
+        let upload = Upload {
+            id: ids[0],
+            object_key: "foo".to_owned(),
+            sample_file_bytes: 1,
+            sample_file_sha256: [0u8; 32],
+            upload_time: recording::Time(1),
+            verify_time: None,
+        };
+        db.insert_upload(&upload).unwrap();
+        assert_eq!(db.list_recordings_to_upload(None, 10).unwrap(), &ids[1..]);
+        assert_eq!(db.list_unverified_uploads().unwrap(), &[upload]);
+
+        // Only verified uploads can be deleted.
+        assert_eq!(
+            db.delete_uploaded_recordings(testutil::TEST_STREAM_ID)
+                .unwrap(),
+            0
+        );
+        db.mark_upload_verified(ids[0], recording::Time(2)).unwrap();
+        assert!(db.list_unverified_uploads().unwrap().is_empty());
+        assert_eq!(
+            db.delete_uploaded_recordings(testutil::TEST_STREAM_ID)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.delete_uploaded_recordings(testutil::TEST_STREAM_ID)
+                .unwrap(),
+            0
+        );
+        db.flush("test").unwrap();
+        assert_eq!(db.list_recordings_to_upload(None, 10).unwrap(), &ids[1..]);
+        let e = db
+            .mark_upload_verified(ids[1], recording::Time(2))
+            .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn replication() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut r = RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        let id = tdb.insert_recording_from_encoder(r).id;
+        let mut db = tdb.db.lock();
+        assert_eq!(db.list_recordings_to_replicate(None, 10).unwrap(), &[id]);
+        db.insert_replication(id, "/backup/foo", recording::Time(1))
+            .unwrap();
+        assert!(db
+            .list_recordings_to_replicate(None, 10)
+            .unwrap()
+            .is_empty());
+
+        let backup = tdb.tmpdir.path().join("backup.db");
+        db.backup_to(&backup).unwrap();
+        let conn = Connection::open(&backup).unwrap();
+        let n: i64 = conn
+            .query_row("select count(*) from replication", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn tuning() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        l.set_tuning(&Tuning {
+            wal_autocheckpoint_pages: Some(4000),
+            synchronous: Some(Synchronous::Normal),
+            mmap_size_bytes: None,
+        })
+        .unwrap();
+        let get = |p: &str| {
+            l.conn
+                .pragma_query_value(None, p, |r| r.get::<_, i64>(0))
+                .unwrap()
+        };
+        assert_eq!(get("wal_autocheckpoint"), 4000);
+        assert_eq!(get("synchronous"), Synchronous::Normal as i64);
+    }
+
+    #[test]
+    fn online_backup() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let path = tdb.tmpdir.path().join("backup.db");
+        {
+            let mut l = tdb.db.lock();
+            l.start_backup(&path).unwrap();
+            assert_eq!(
+                l.start_backup(&path).unwrap_err().kind(),
+                base::ErrorKind::FailedPrecondition
+            );
+            assert!(!l.step_backup(1).unwrap());
+
+            // Changes made between steps are included.
+            l.conn
+                .execute_batch("create table foo (x); insert into foo values (42);")
+                .unwrap();
+            while !l.step_backup(1).unwrap() {}
+            l.step_backup(1).unwrap_err();
+        }
+        let conn = Connection::open(&path).unwrap();
+        let x: i64 = conn
+            .query_row("select x from foo", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(x, 42);
+        let n: i64 = conn
+            .query_row("select count(*) from camera", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(n, 1);
+        drop(conn);
+
+        // The convenience wrapper works too but won't overwrite an existing file.
+        assert_eq!(
+            tdb.db.backup(&path).unwrap_err().kind(),
+            base::ErrorKind::AlreadyExists
+        );
+        std::fs::remove_file(&path).unwrap();
+        tdb.db.backup(&path).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn encryption() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut encoder = recording::SampleIndexEncoder::default();
+        let mut r = RecordingToInsert::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        let plain = tdb.insert_recording_from_encoder(r.clone());
+        assert_eq!(plain.flags & RecordingFlags::Encrypted as i32, 0);
+        assert!(tdb.db.lock().recording_key(plain.id).unwrap().is_none());
+
+        let key = || dir::crypto::MasterKey::new(&[7u8; 32]).unwrap();
+        tdb.db.lock().set_sample_file_key(Some(key()));
+        let encrypted = tdb.insert_recording_from_encoder(r);
+        assert_ne!(encrypted.flags & RecordingFlags::Encrypted as i32, 0);
+        let mut db = tdb.db.lock();
+        assert!(db.recording_key(encrypted.id).unwrap().is_some());
+        assert!(db.recording_key(plain.id).unwrap().is_none());
+
+        // Without the master key, only the unencrypted recording's key can be looked up.
+        db.set_sample_file_key(None);
+        assert_eq!(
+            db.recording_key(encrypted.id).unwrap_err().kind(),
+            base::ErrorKind::FailedPrecondition
+        );
+        db.set_sample_file_key(Some(key()));
+        db.video_index_cache.borrow_mut().clear(); // force reading from the database.
+        assert!(db.recording_key(encrypted.id).unwrap().is_some());
+    }
+
     #[test]
     fn round_up() {
         assert_eq!(super::round_up(0), 0);
@@ -0,0 +1,344 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+//! At-rest encryption of sample files.
+//!
+//! When a master key is configured, each new recording's sample file is encrypted with its own
+//! random key using ChaCha20-Poly1305. The recording's key is stored in
+//! `recording_playback.wrapped_key`, itself encrypted ("wrapped") with the master key. Neither
+//! the sample file directories nor the database suffice to read recordings without the master
+//! key, so it should be kept somewhere that won't be stolen along with them.
+//!
+//! An encrypted sample file is a sequence of chunks, each holding [`CHUNK_LEN`] bytes of
+//! plaintext (or fewer, for the final chunk) followed by a [`TAG_LEN`]-byte authentication tag.
+//! Each chunk's nonce is its index within the file, so any byte range of the plaintext can be read
+//! by decrypting just the chunks which overlap it. Offsets and lengths elsewhere, including
+//! `recording.sample_file_bytes` and the sample indexes, refer to the plaintext. (Disk usage
+//! accounting ignores the tags, which add 16 bytes per 64 KiB.)
+//!
+//! A chunk can't be written until it's full or the recording is closed. Meanwhile, readers
+//! (notably live view) get its plaintext from memory via [`Tails`].
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use base::{bail, err, Error, FastHashMap};
+use ring::aead;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::CompositeId;
+
+/// The number of plaintext bytes in each chunk but the last.
+pub const CHUNK_LEN: usize = 1 << 16;
+
+/// The number of bytes of authentication tag which follow each chunk's ciphertext.
+pub const TAG_LEN: usize = 16;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// The length of a wrapped key: a random nonce, the encrypted key, and its tag.
+const WRAPPED_KEY_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+
+/// Returns the on-disk length of an encrypted sample file with the given plaintext length.
+pub fn encrypted_len(plaintext_len: u64) -> u64 {
+    let chunks = (plaintext_len + CHUNK_LEN as u64 - 1) / CHUNK_LEN as u64;
+    plaintext_len + chunks * TAG_LEN as u64
+}
+
+fn chunk_nonce(chunk: u64) -> aead::Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&chunk.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// The key to a single recording's sample file.
+pub struct Key(aead::LessSafeKey);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    fn new(raw: &[u8]) -> Self {
+        Key(aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::CHACHA20_POLY1305, raw).expect("key has correct length"),
+        ))
+    }
+
+    /// Encrypts `data`, the plaintext of the given chunk, in place, appending the tag.
+    fn seal_chunk(&self, chunk: u64, data: &mut Vec<u8>) {
+        self.0
+            .seal_in_place_append_tag(chunk_nonce(chunk), aead::Aad::empty(), data)
+            .expect("chunk is within ChaCha20-Poly1305's length limit");
+    }
+
+    /// Decrypts `data`, the ciphertext and tag of the given chunk, in place, returning the
+    /// plaintext.
+    pub(crate) fn open_chunk<'a>(
+        &self,
+        id: CompositeId,
+        chunk: u64,
+        data: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Error> {
+        self.0
+            .open_in_place(chunk_nonce(chunk), aead::Aad::empty(), data)
+            .map_err(|_| err!(DataLoss, msg("unable to decrypt chunk {chunk} of {id}")))
+    }
+}
+
+/// The key which wraps each recording's [`Key`].
+pub struct MasterKey {
+    key: aead::LessSafeKey,
+    rand: SystemRandom,
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Creates a master key from its 32 raw bytes.
+    pub fn new(raw: &[u8]) -> Result<Self, Error> {
+        if raw.len() != KEY_LEN {
+            bail!(
+                InvalidArgument,
+                msg("sample file key must be {KEY_LEN} bytes; got {}", raw.len())
+            );
+        }
+        Ok(MasterKey {
+            key: aead::LessSafeKey::new(
+                aead::UnboundKey::new(&aead::CHACHA20_POLY1305, raw).expect("length checked"),
+            ),
+            rand: SystemRandom::new(),
+        })
+    }
+
+    /// Reads a master key from a file holding exactly 32 random bytes, as created by
+    /// `head -c 32 /dev/urandom`.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let raw = fs::read(path)
+            .map_err(|e| err!(e, msg("unable to read sample file key {}", path.display())))?;
+        Self::new(&raw).map_err(|e| err!(e, msg("bad sample file key {}", path.display())))
+    }
+
+    /// Generates a new key for the given recording, returning it in wrapped form.
+    pub(crate) fn new_wrapped_key(&self, id: CompositeId) -> Result<Vec<u8>, Error> {
+        let mut wrapped = vec![0u8; NONCE_LEN + KEY_LEN];
+        self.rand
+            .fill(&mut wrapped)
+            .map_err(|_| err!(Internal, msg("unable to generate key for {id}")))?;
+        let (nonce, key) = wrapped.split_at_mut(NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).expect("nonce has correct len");
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, aead::Aad::from(id.0.to_be_bytes()), key)
+            .expect("key is within ChaCha20-Poly1305's length limit");
+        wrapped.extend_from_slice(tag.as_ref());
+        Ok(wrapped)
+    }
+
+    /// Unwraps the given recording's key.
+    pub(crate) fn unwrap_key(&self, id: CompositeId, wrapped: &[u8]) -> Result<Key, Error> {
+        if wrapped.len() != WRAPPED_KEY_LEN {
+            bail!(
+                DataLoss,
+                msg("wrapped key for {id} has bad length {}", wrapped.len())
+            );
+        }
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(&wrapped[..NONCE_LEN]).expect("length checked");
+        let mut in_out = wrapped[NONCE_LEN..].to_vec();
+        let raw = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(id.0.to_be_bytes()), &mut in_out)
+            .map_err(|_| {
+                err!(
+                    PermissionDenied,
+                    msg("unable to unwrap key for {id}; is the sample file key correct?")
+                )
+            })?;
+        Ok(Key::new(raw))
+    }
+}
+
+/// Plaintext of a sample file being written which isn't yet on disk.
+///
+/// Chunks are [`CHUNK_LEN`] bytes for encrypted files and [`super::direct::BUF_LEN`] bytes for
+/// files written with `O_DIRECT`.
+#[derive(Default)]
+pub(crate) struct Tail {
+    /// The index of the chunk which begins `data`. Chunks before this one are on disk.
+    pub(crate) first_chunk: u64,
+
+    /// The plaintext from `first_chunk` on: at most one full chunk, or a partial chunk.
+    pub(crate) data: Vec<u8>,
+}
+
+/// The [`Tail`]s of a sample file directory's encrypted or `O_DIRECT` files being written, by
+/// recording.
+#[derive(Default)]
+pub(crate) struct Tails(pub(super) Mutex<FastHashMap<CompositeId, Arc<Mutex<Tail>>>>);
+
+impl fmt::Debug for Tails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tails").finish_non_exhaustive()
+    }
+}
+
+impl Tails {
+    pub(crate) fn get(&self, id: CompositeId) -> Option<Arc<Mutex<Tail>>> {
+        self.0.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// A sample file being written with encryption.
+pub struct EncryptingFile {
+    file: fs::File,
+    id: CompositeId,
+    key: Arc<Key>,
+    tails: Arc<Tails>,
+    tail: Arc<Mutex<Tail>>,
+
+    /// The ciphertext of `tail.first_chunk`, if it's been sealed but not fully written.
+    pending: Vec<u8>,
+
+    /// The number of bytes of `pending` which have been written.
+    pending_pos: usize,
+}
+
+impl EncryptingFile {
+    pub(crate) fn new(file: fs::File, id: CompositeId, key: Arc<Key>, tails: Arc<Tails>) -> Self {
+        let tail = Arc::new(Mutex::new(Tail::default()));
+        tails.0.lock().unwrap().insert(id, tail.clone());
+        EncryptingFile {
+            file,
+            id,
+            key,
+            tails,
+            tail,
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+
+    /// Writes the sealed chunk, if any, then drops its plaintext from the tail.
+    ///
+    /// On error, the chunk remains pending, so this can be retried.
+    fn write_pending(&mut self) -> Result<(), io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        while self.pending_pos < self.pending.len() {
+            match self.file.write(&self.pending[self.pending_pos..])? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => self.pending_pos += n,
+            }
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        let mut t = self.tail.lock().unwrap();
+        t.data.clear();
+        t.first_chunk += 1;
+        Ok(())
+    }
+
+    /// Seals the tail's chunk.
+    fn seal(&mut self, t: &Tail) {
+        self.pending.extend_from_slice(&t.data);
+        self.key.seal_chunk(t.first_chunk, &mut self.pending);
+    }
+
+    /// As in `std::io::Write::write`, buffering the plaintext until a chunk is full.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.write_pending()?;
+        let tail = self.tail.clone();
+        let mut t = tail.lock().unwrap();
+        let len = std::cmp::min(buf.len(), CHUNK_LEN - t.data.len());
+        t.data.extend_from_slice(&buf[..len]);
+        if t.data.len() == CHUNK_LEN {
+            self.seal(&t);
+        }
+        Ok(len)
+    }
+
+    /// Writes all buffered data, including the final partial chunk. There must be no further
+    /// writes.
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        self.write_pending()?;
+        let tail = self.tail.clone();
+        let t = tail.lock().unwrap();
+        if !t.data.is_empty() {
+            self.seal(&t);
+        }
+        drop(t);
+        self.write_pending()
+    }
+
+    /// As in `std::fs::File::sync_all`.
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.file.sync_all()
+    }
+
+    pub(super) fn file(&self) -> &fs::File {
+        &self.file
+    }
+}
+
+impl Drop for EncryptingFile {
+    fn drop(&mut self) {
+        self.tails.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn encrypted_len() {
+        testutil::init();
+        assert_eq!(super::encrypted_len(0), 0);
+        assert_eq!(super::encrypted_len(1), 17);
+        assert_eq!(
+            super::encrypted_len(CHUNK_LEN as u64),
+            CHUNK_LEN as u64 + 16
+        );
+        assert_eq!(
+            super::encrypted_len(CHUNK_LEN as u64 + 1),
+            CHUNK_LEN as u64 + 33
+        );
+    }
+
+    #[test]
+    fn wrap() {
+        testutil::init();
+        let master = MasterKey::new(&[1u8; 32]).unwrap();
+        let id = CompositeId::new(1, 2);
+        let wrapped = master.new_wrapped_key(id).unwrap();
+        assert_eq!(wrapped.len(), WRAPPED_KEY_LEN);
+        let key = master.unwrap_key(id, &wrapped).unwrap();
+        let mut data = b"hello".to_vec();
+        key.seal_chunk(3, &mut data);
+        assert_eq!(key.open_chunk(id, 3, &mut data).unwrap(), b"hello");
+
+        // The wrapped key is bound to the recording and the master key.
+        master
+            .unwrap_key(CompositeId::new(1, 3), &wrapped)
+            .unwrap_err();
+        MasterKey::new(&[2u8; 32])
+            .unwrap()
+            .unwrap_key(id, &wrapped)
+            .unwrap_err();
+        MasterKey::new(&[1u8; 31]).unwrap_err();
+    }
+}
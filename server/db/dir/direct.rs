@@ -0,0 +1,160 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Sample file writes with `O_DIRECT`, enabled per directory by
+//! [`crate::json::SampleFileDirConfig::direct_io`].
+//!
+//! Ordinary writes go through the page cache, so on a busy NVR the cache fills with recordings
+//! which likely will never be read, evicting the recent footage that live view and playback do
+//! read. A [`DirectFile`] instead coalesces writes into [`BUF_LEN`]-byte aligned buffers and
+//! writes each straight to disk. The final partial buffer's length generally isn't a multiple of
+//! the block size, so it's written through the page cache when the recording is closed.
+//!
+//! As with encrypted files, readers get the data which isn't yet on disk from memory via
+//! [`super::crypto::Tails`]. Encrypted files are always written through the page cache.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+use super::crypto::{Tail, Tails};
+use crate::CompositeId;
+
+/// The length of each `O_DIRECT` write.
+pub(crate) const BUF_LEN: usize = 1 << 20;
+
+/// The required alignment of `O_DIRECT` buffers, file offsets, and lengths. This is at least the
+/// logical block size of any common device.
+const ALIGN: usize = 4096;
+
+/// A block of memory suitably aligned for `O_DIRECT`.
+#[repr(C, align(4096))]
+struct Block([u8; ALIGN]);
+
+const _: () = assert!(std::mem::align_of::<Block>() == ALIGN);
+const _: () = assert!(BUF_LEN % ALIGN == 0);
+
+fn as_bytes(blocks: &mut [Block]) -> &mut [u8] {
+    // SAFETY: `Block`s are plain bytes, laid out contiguously without padding.
+    unsafe { std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, blocks.len() * ALIGN) }
+}
+
+/// Enables or disables `O_DIRECT` on `file`.
+///
+/// Enabling fails with `EINVAL` on filesystems which don't support `O_DIRECT`.
+pub(crate) fn set_direct(file: &fs::File, direct: bool) -> Result<(), nix::Error> {
+    let mut flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    flags.set(OFlag::O_DIRECT, direct);
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
+/// A sample file being written with `O_DIRECT`.
+pub struct DirectFile {
+    file: fs::File,
+    id: CompositeId,
+    tails: Arc<Tails>,
+    tail: Arc<Mutex<Tail>>,
+
+    /// An aligned copy of the tail's buffer, once it's full (or the file is finished).
+    buf: Box<[Block]>,
+
+    /// True if `buf` holds a full buffer which hasn't been fully written.
+    pending: bool,
+}
+
+impl DirectFile {
+    /// Wraps `file`, which must already have `O_DIRECT` set via [`set_direct`].
+    pub(crate) fn new(file: fs::File, id: CompositeId, tails: Arc<Tails>) -> Self {
+        let tail = Arc::new(Mutex::new(Tail::default()));
+        tails.0.lock().unwrap().insert(id, tail.clone());
+        DirectFile {
+            file,
+            id,
+            tails,
+            tail,
+            buf: (0..BUF_LEN / ALIGN).map(|_| Block([0; ALIGN])).collect(),
+            pending: false,
+        }
+    }
+
+    /// Writes the full buffer, if any, then drops it from the tail.
+    ///
+    /// On error, the buffer remains pending, so this can be retried. A short write is an error
+    /// rather than something to continue from: the rest of the buffer would start at an
+    /// unaligned offset, which `O_DIRECT` rejects with `EINVAL`. A retry rewrites the whole
+    /// buffer instead.
+    fn write_pending(&mut self) -> Result<(), io::Error> {
+        if !self.pending {
+            return Ok(());
+        }
+        let offset = self.tail.lock().unwrap().first_chunk * BUF_LEN as u64;
+        let n = self.file.write_at(as_bytes(&mut self.buf), offset)?;
+        if n < BUF_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short O_DIRECT write of {n} of {BUF_LEN} bytes"),
+            ));
+        }
+        self.pending = false;
+        let mut t = self.tail.lock().unwrap();
+        t.data.clear();
+        t.first_chunk += 1;
+        Ok(())
+    }
+
+    /// As in `std::io::Write::write`, buffering until a full buffer is available.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.write_pending()?;
+        let tail = self.tail.clone();
+        let mut t = tail.lock().unwrap();
+        let len = std::cmp::min(buf.len(), BUF_LEN - t.data.len());
+        t.data.extend_from_slice(&buf[..len]);
+        if t.data.len() == BUF_LEN {
+            as_bytes(&mut self.buf).copy_from_slice(&t.data);
+            self.pending = true;
+        }
+        Ok(len)
+    }
+
+    /// Writes all buffered data, including the final partial buffer. There must be no further
+    /// writes.
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        self.write_pending()?;
+        let (offset, len) = {
+            let t = self.tail.lock().unwrap();
+            as_bytes(&mut self.buf)[..t.data.len()].copy_from_slice(&t.data);
+            (t.first_chunk * BUF_LEN as u64, t.data.len())
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        set_direct(&self.file, false)?;
+        self.file
+            .write_all_at(&as_bytes(&mut self.buf)[..len], offset)?;
+        let mut t = self.tail.lock().unwrap();
+        t.data.clear();
+        t.first_chunk += 1;
+        Ok(())
+    }
+
+    /// As in `std::fs::File::sync_all`.
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.file.sync_all()
+    }
+
+    pub(super) fn file(&self) -> &fs::File {
+        &self.file
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        self.tails.0.lock().unwrap().remove(&self.id);
+    }
+}
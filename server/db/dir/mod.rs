@@ -7,7 +7,13 @@
 //! This mostly includes opening a directory and looking for recordings within it.
 //! Updates to the directory happen through [crate::writer].
 
+pub mod crypto;
+pub mod direct;
 mod reader;
+#[cfg(feature = "io-uring")]
+pub(crate) mod uring;
+
+pub use reader::{find_mapped, FileChunk, MappedBytes, MappedChunk};
 
 use crate::coding;
 use crate::db::CompositeId;
@@ -16,18 +22,19 @@ use base::{bail, err, Error};
 use cstr::cstr;
 use nix::sys::statvfs::Statvfs;
 use nix::{
-    fcntl::{FlockArg, OFlag},
+    fcntl::{FallocateFlags, FlockArg, OFlag},
     sys::stat::Mode,
     NixPath,
 };
 use protobuf::Message;
 use std::ffi::CStr;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::ops::Range;
 use std::os::fd::{AsFd, BorrowedFd};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tracing::warn;
 
@@ -52,6 +59,58 @@ pub struct SampleFileDir {
     pub(crate) fd: Arc<Fd>,
 
     reader: reader::Reader,
+
+    /// Plaintext of encrypted and `O_DIRECT` files being written which isn't yet on disk.
+    tails: Arc<crypto::Tails>,
+
+    /// If new unencrypted sample files should be written with `O_DIRECT`; see
+    /// [`crate::json::SampleFileDirConfig::direct_io`].
+    direct_io: AtomicBool,
+}
+
+/// A sample file opened for writing by [`SampleFileDir::create_sample_file`].
+pub enum SampleFile {
+    Plain(fs::File),
+    Encrypted(crypto::EncryptingFile),
+    Direct(direct::DirectFile),
+}
+
+impl SampleFile {
+    fn file(&self) -> &fs::File {
+        match self {
+            SampleFile::Plain(f) => f,
+            SampleFile::Encrypted(f) => f.file(),
+            SampleFile::Direct(f) => f.file(),
+        }
+    }
+
+    /// Reserves disk space for `len` bytes of sample data without changing the file's length.
+    ///
+    /// This reduces fragmentation when several streams write to the same filesystem at once and
+    /// reports `ENOSPC` before any data is written. It does nothing on filesystems which don't
+    /// support `fallocate`.
+    pub fn preallocate(&self, len: u64) -> Result<(), io::Error> {
+        let len = match self {
+            SampleFile::Encrypted(_) => crypto::encrypted_len(len),
+            _ => len,
+        };
+        let len = libc::off_t::try_from(len).map_err(|_| io::ErrorKind::InvalidInput)?;
+        match nix::fcntl::fallocate(
+            self.file().as_raw_fd(),
+            FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            0,
+            len,
+        ) {
+            Ok(()) | Err(nix::Error::EOPNOTSUPP) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Releases space reserved by [`SampleFile::preallocate`] beyond the end of the file.
+    pub fn trim(&self) -> Result<(), io::Error> {
+        let f = self.file();
+        f.set_len(f.metadata()?.len())
+    }
 }
 
 /// The on-disk filename of a recording file within the sample file directory.
@@ -340,13 +399,39 @@ impl SampleFileDir {
 
     fn open_self(path: &Path, create: bool) -> Result<Arc<SampleFileDir>, Error> {
         let fd = Arc::new(Fd::open(path, create)?);
-        let reader = reader::Reader::spawn(path, fd.clone());
-        Ok(Arc::new(SampleFileDir { fd, reader }))
+        let tails = Arc::new(crypto::Tails::default());
+        let reader = reader::Reader::spawn(path, fd.clone(), tails.clone());
+        Ok(Arc::new(SampleFileDir {
+            fd,
+            reader,
+            tails,
+            direct_io: AtomicBool::new(false),
+        }))
     }
 
     /// Opens the given sample file for reading.
-    pub fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> reader::FileStream {
-        self.reader.open_file(composite_id, range)
+    ///
+    /// `range` is in terms of the plaintext; if the file is encrypted, `key` must be its key, as
+    /// returned by [`crate::db::LockedDatabase::recording_key`].
+    pub fn open_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+    ) -> reader::FileStream {
+        self.reader.open_file(composite_id, range, key)
+    }
+
+    /// As [`SampleFileDir::open_file`], but avoids copying plaintext files' bytes.
+    ///
+    /// Chunks may refer to a `mmap()` of the file, which [`find_mapped`] can trace back to it.
+    pub fn open_mapped_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+    ) -> reader::FileStream<FileChunk> {
+        self.reader.open_mapped_file(composite_id, range, key)
     }
 
     pub fn create_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
@@ -359,6 +444,45 @@ impl SampleFileDir {
         )
     }
 
+    /// Opens the given sample file for blocking reads of its raw (possibly encrypted) contents.
+    pub fn open_raw_file(&self, composite_id: CompositeId) -> Result<fs::File, nix::Error> {
+        let p = CompositeIdPath::from(composite_id);
+        crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty())
+    }
+
+    /// Sets if new unencrypted sample files should be written with `O_DIRECT`.
+    pub fn set_direct_io(&self, direct_io: bool) {
+        self.direct_io.store(direct_io, Ordering::Relaxed);
+    }
+
+    /// Creates a sample file for writing, encrypting it with `key` if supplied.
+    pub fn create_sample_file(
+        &self,
+        composite_id: CompositeId,
+        key: Option<Arc<crypto::Key>>,
+    ) -> Result<SampleFile, nix::Error> {
+        let f = self.create_file(composite_id)?;
+        Ok(match key {
+            None if self.direct_io.load(Ordering::Relaxed) => match direct::set_direct(&f, true) {
+                Ok(()) => {
+                    SampleFile::Direct(direct::DirectFile::new(f, composite_id, self.tails.clone()))
+                }
+                Err(err) => {
+                    warn!(%err, "unable to enable O_DIRECT; writing through the page cache");
+                    self.direct_io.store(false, Ordering::Relaxed);
+                    SampleFile::Plain(f)
+                }
+            },
+            None => SampleFile::Plain(f),
+            Some(k) => SampleFile::Encrypted(crypto::EncryptingFile::new(
+                f,
+                composite_id,
+                k,
+                self.tails.clone(),
+            )),
+        })
+    }
+
     pub(crate) fn write_meta(&self, meta: &schema::DirMeta) -> Result<(), Error> {
         write_meta(self.fd.0, meta)
     }
@@ -367,6 +491,27 @@ impl SampleFileDir {
         self.fd.statfs()
     }
 
+    /// Copies the given sample file from this directory into `dest`, replacing any partial copy
+    /// left by an earlier attempt, and syncs the copy. The caller should sync `dest` itself
+    /// before relying on the copy.
+    pub(crate) fn copy_file(&self, id: CompositeId, dest: &SampleFileDir) -> Result<(), Error> {
+        let p = CompositeIdPath::from(id);
+        let mut src = crate::fs::openat(self.fd.0, &p, OFlag::O_RDONLY, Mode::empty())
+            .map_err(|e| err!(e, msg("unable to open recording {id} to archive")))?;
+        match dest.unlink_file(id) {
+            Ok(()) | Err(nix::Error::ENOENT) => {}
+            Err(e) => bail!(e, msg("unable to unlink partial copy of recording {id}")),
+        }
+        let mut dst = dest
+            .create_file(id)
+            .map_err(|e| err!(e, msg("unable to create archived copy of recording {id}")))?;
+        std::io::copy(&mut src, &mut dst)
+            .map_err(|e| err!(e, msg("unable to copy recording {id} to archive")))?;
+        dst.sync_all()
+            .map_err(|e| err!(e, msg("unable to sync archived copy of recording {id}")))?;
+        Ok(())
+    }
+
     /// Unlinks the given sample file within this directory.
     pub(crate) fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error> {
         let p = CompositeIdPath::from(id);
@@ -413,6 +558,33 @@ mod tests {
         parse_id(b"000000010000000x").unwrap_err();
     }
 
+    #[test]
+    fn preallocate_and_trim() {
+        use std::os::unix::fs::MetadataExt;
+        crate::testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-db-test-dir")
+            .tempdir()
+            .unwrap();
+        let dir = SampleFileDir::create(tmpdir.path(), &schema::DirMeta::default()).unwrap();
+        let mut f = dir
+            .create_sample_file(CompositeId::new(1, 1), None)
+            .unwrap();
+        f.preallocate(1 << 20).unwrap();
+        let SampleFile::Plain(ref mut p) = f else {
+            panic!("expected a plain file");
+        };
+        p.write_all(b"hello").unwrap();
+        let allocated = |f: &SampleFile| f.file().metadata().unwrap().blocks() * 512;
+        let preallocated = allocated(&f);
+        assert_eq!(f.file().metadata().unwrap().len(), 5);
+        f.trim().unwrap();
+        assert_eq!(f.file().metadata().unwrap().len(), 5);
+        if preallocated >= 1 << 20 {
+            assert!(allocated(&f) < 1 << 20);
+        }
+    }
+
     /// Ensures that a DirMeta with all fields filled fits within the maximum size.
     #[test]
     fn max_len_meta() {
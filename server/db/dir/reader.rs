@@ -19,10 +19,28 @@
 //! *   it has fewer thread handoffs because it batches operations on open
 //!     (open, fstat, mmap, madvise, close, memcpy first chunk) and close
 //!     (memcpy last chunk, munmap).
+//!
+//! Encrypted files (see [`super::crypto`]) are instead read with `pread` and
+//! decrypted one chunk at a time, as they may not be entirely on disk yet.
+//! Plaintext files being written with `O_DIRECT` (see [`super::direct`]) are
+//! likewise read a chunk at a time.
+//!
+//! With the `io-uring` feature, plaintext files are also read a chunk at a time
+//! rather than `mmap()`ed, and all reads go through [`super::uring`].
+//!
+//! Files opened via [`Reader::open_mapped_file`] skip the `memcpy`: their chunks
+//! refer to the mapping itself (see [`FileChunk::Mapped`]), after this thread
+//! faults in its pages. [`find_mapped`] locates such bytes on disk again, so that
+//! they can be sent to a socket with `sendfile`.
 
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fs;
 use std::future::Future;
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
+use std::sync::{Mutex, Weak};
 use std::{
     ops::Range,
     pin::Pin,
@@ -35,6 +53,7 @@ use base::clock::{RealClocks, TimerGuard};
 use base::{err, Error, ErrorKind, ResultExt};
 use nix::{fcntl::OFlag, sys::stat::Mode};
 
+use super::crypto;
 use crate::CompositeId;
 
 /// Handle for a reader thread, used to send it commands.
@@ -44,7 +63,7 @@ use crate::CompositeId;
 pub(super) struct Reader(tokio::sync::mpsc::UnboundedSender<ReaderCommand>);
 
 impl Reader {
-    pub(super) fn spawn(path: &Path, dir: Arc<super::Fd>) -> Self {
+    pub(super) fn spawn(path: &Path, dir: Arc<super::Fd>, tails: Arc<crypto::Tails>) -> Self {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         let page_size = usize::try_from(
             nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE)
@@ -58,17 +77,49 @@ impl Reader {
             .name(format!("r-{}", path.display()))
             .spawn(move || {
                 let _guard = span.enter();
-                ReaderInt { dir, page_size }.run(rx)
+                ReaderInt {
+                    dir,
+                    page_size,
+                    tails,
+                }
+                .run(rx)
             })
             .expect("unable to create reader thread");
         Self(tx)
     }
 
-    pub(super) fn open_file(&self, composite_id: CompositeId, range: Range<u64>) -> FileStream {
+    pub(super) fn open_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+    ) -> FileStream {
+        self.open(composite_id, range, key, false)
+    }
+
+    /// As [`Reader::open_file`], but yields [`FileChunk::Mapped`] chunks where the file can be
+    /// `mmap()`ed, rather than copying them.
+    pub(super) fn open_mapped_file(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+    ) -> FileStream<FileChunk> {
+        self.open(composite_id, range, key, true)
+    }
+
+    fn open<C>(
+        &self,
+        composite_id: CompositeId,
+        range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+        share: bool,
+    ) -> FileStream<C> {
         if range.is_empty() {
             return FileStream {
                 state: FileStreamState::Invalid,
                 reader: Reader(self.0.clone()),
+                chunk: PhantomData,
             };
         }
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -76,11 +127,14 @@ impl Reader {
             span: tracing::Span::current(),
             composite_id,
             range,
+            key,
+            share,
             tx,
         });
         FileStream {
             state: FileStreamState::Reading(rx),
             reader: Reader(self.0.clone()),
+            chunk: PhantomData,
         }
     }
 
@@ -92,9 +146,134 @@ impl Reader {
     }
 }
 
-pub struct FileStream {
+/// A stream of a file's chunks, as either `Vec<u8>` or [`FileChunk`].
+pub struct FileStream<C = Vec<u8>> {
     state: FileStreamState,
     reader: Reader,
+    chunk: PhantomData<fn() -> C>,
+}
+
+/// A chunk of a file's plaintext.
+pub enum FileChunk {
+    /// A copy of the bytes.
+    Owned(Vec<u8>),
+
+    /// The bytes within a `mmap()` of the file, whose pages have already been faulted in.
+    Mapped(MappedChunk),
+}
+
+impl From<FileChunk> for Vec<u8> {
+    fn from(c: FileChunk) -> Self {
+        match c {
+            FileChunk::Owned(v) => v,
+            FileChunk::Mapped(m) => m.as_ref().to_vec(),
+        }
+    }
+}
+
+/// A chunk of a shared `mmap()` of a plaintext file, as yielded by [`Reader::open_mapped_file`].
+pub struct MappedChunk {
+    region: Arc<MappedRegion>,
+
+    /// The range of `region` holding this chunk. Invariant: within `0..region.len`.
+    range: Range<usize>,
+}
+
+impl AsRef<[u8]> for MappedChunk {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: `range` is within the mapping, which lives as long as `region`. See also the
+        // caveats in `ReaderInt::mapped_chunk`.
+        unsafe {
+            std::slice::from_raw_parts(
+                (self.region.ptr as *const u8).add(self.range.start),
+                self.range.end - self.range.start,
+            )
+        }
+    }
+}
+
+/// A `mmap()`ed region of a plaintext file, unmapped when the last reference is dropped.
+struct MappedRegion {
+    /// The memory-mapped region backed by the file. Valid up to length `len`.
+    ptr: *mut libc::c_void,
+
+    /// The length of the memory mapping. This may be less than the length of
+    /// the file.
+    len: usize,
+
+    /// The file and the offset within it of the start of the mapping, if the region is shared
+    /// via [`MappedChunk`]s. Such regions are listed in [`SHARED_REGIONS`].
+    shared: Option<(fs::File, u64)>,
+}
+
+// Rust makes us manually state these because of the `*mut` ptr above.
+unsafe impl Send for MappedRegion {}
+unsafe impl Sync for MappedRegion {}
+
+impl Drop for MappedRegion {
+    fn drop(&mut self) {
+        // Remove the region from the list before unmapping it, so that its address can't be
+        // reused while it's still listed.
+        if self.shared.is_some() {
+            SHARED_REGIONS.lock().unwrap().remove(&(self.ptr as usize));
+        }
+        if let Err(e) = unsafe { nix::sys::mman::munmap(self.ptr, self.len) } {
+            // This should never happen.
+            tracing::error!("unable to munmap {:?} len {}: {}", self.ptr, self.len, e);
+        }
+    }
+}
+
+/// The shared regions by starting address, for [`find_mapped`].
+static SHARED_REGIONS: Mutex<BTreeMap<usize, Weak<MappedRegion>>> = Mutex::new(BTreeMap::new());
+
+/// The location within a file of bytes found by [`find_mapped`].
+pub struct MappedBytes {
+    region: Arc<MappedRegion>,
+
+    /// The offset within the file of the first byte.
+    pub offset: u64,
+
+    /// The number of bytes, from the start of those passed to `find_mapped` through the end of
+    /// their mapping.
+    pub len: usize,
+}
+
+impl MappedBytes {
+    pub fn file(&self) -> &fs::File {
+        &self
+            .region
+            .shared
+            .as_ref()
+            .expect("regions are listed iff shared")
+            .0
+    }
+}
+
+/// Finds the file backing `bytes`, if they're within a [`MappedChunk`].
+///
+/// This lets a socket writer handed a `&[u8]` by a library which knows nothing of files (such as
+/// `hyper`) send it with `sendfile` instead.
+pub fn find_mapped(bytes: &[u8]) -> Option<MappedBytes> {
+    let addr = bytes.as_ptr() as usize;
+    let region = {
+        let l = SHARED_REGIONS.lock().unwrap();
+        let (_, region) = l.range(..=addr).next_back()?;
+        region.upgrade()?
+    };
+    let pos = addr - region.ptr as usize;
+    if pos >= region.len {
+        return None;
+    }
+    let (_, offset) = region
+        .shared
+        .as_ref()
+        .expect("regions are listed iff shared");
+    Some(MappedBytes {
+        offset: offset + pos as u64,
+        len: std::cmp::min(bytes.len(), region.len - pos),
+        region,
+    })
 }
 
 type ReadReceiver = tokio::sync::oneshot::Receiver<Result<SuccessfulRead, Error>>;
@@ -105,13 +284,13 @@ enum FileStreamState {
     Invalid,
 }
 
-impl FileStream {
+impl<C: From<FileChunk>> FileStream<C> {
     /// Helper for reading during `poll_next`.
     fn read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         mut rx: ReadReceiver,
-    ) -> Poll<Option<Result<Vec<u8>, Error>>> {
+    ) -> Poll<Option<Result<C, Error>>> {
         match Pin::new(&mut rx).poll(cx) {
             Poll::Ready(Err(_)) => {
                 self.state = FileStreamState::Invalid;
@@ -129,11 +308,11 @@ impl FileStream {
                 file: Some(file),
             }))) => {
                 self.state = FileStreamState::Idle(file);
-                Poll::Ready(Some(Ok(chunk)))
+                Poll::Ready(Some(Ok(chunk.into())))
             }
             Poll::Ready(Ok(Ok(SuccessfulRead { chunk, file: None }))) => {
                 self.state = FileStreamState::Invalid;
-                Poll::Ready(Some(Ok(chunk)))
+                Poll::Ready(Some(Ok(chunk.into())))
             }
             Poll::Pending => {
                 self.state = FileStreamState::Reading(rx);
@@ -143,8 +322,8 @@ impl FileStream {
     }
 }
 
-impl futures::stream::Stream for FileStream {
-    type Item = Result<Vec<u8>, Error>;
+impl<C: From<FileChunk>> futures::stream::Stream for FileStream<C> {
+    type Item = Result<C, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match std::mem::replace(&mut self.state, FileStreamState::Invalid) {
@@ -162,7 +341,7 @@ impl futures::stream::Stream for FileStream {
     }
 }
 
-impl Drop for FileStream {
+impl<C> Drop for FileStream<C> {
     fn drop(&mut self) {
         use FileStreamState::{Idle, Invalid};
         if let Idle(file) = std::mem::replace(&mut self.state, Invalid) {
@@ -174,7 +353,7 @@ impl Drop for FileStream {
     }
 }
 
-/// An open, `mmap()`ed file.
+/// An open file.
 ///
 /// This is only actually used by the reader thread, but ownership is passed
 /// around between it and the [FileStream] to avoid maintaining extra data
@@ -184,38 +363,64 @@ struct OpenFile {
 
     composite_id: CompositeId,
 
-    /// The memory-mapped region backed by the file. Valid up to length `map_len`.
-    map_ptr: *mut libc::c_void,
+    source: Source,
+}
 
-    /// The position within the memory mapping. Invariant: `map_pos < map_len`.
-    map_pos: usize,
+enum Source {
+    Mapped(Mapping),
+    Encrypted(Box<EncryptedFile>),
+    Plain(PlainFile),
+}
 
-    /// The length of the memory mapping. This may be less than the length of
-    /// the file.
-    map_len: usize,
+/// A plaintext file being read via `mmap()`.
+struct Mapping {
+    region: Arc<MappedRegion>,
+
+    /// The position within the memory mapping. Invariant: `pos < region.len`.
+    pos: usize,
 }
 
-// Rust makes us manually state these because of the `*mut` ptr above.
-unsafe impl Send for OpenFile {}
-unsafe impl Sync for OpenFile {}
+/// An encrypted file, read one chunk at a time.
+struct EncryptedFile {
+    file: fs::File,
+    key: Arc<crypto::Key>,
 
-impl Drop for OpenFile {
-    fn drop(&mut self) {
-        if let Err(e) = unsafe { nix::sys::mman::munmap(self.map_ptr, self.map_len) } {
-            // This should never happen.
-            tracing::error!(
-                "unable to munmap {}, {:?} len {}: {}",
-                self.composite_id,
-                self.map_ptr,
-                self.map_len,
-                e
-            );
-        }
+    /// The plaintext which isn't yet on disk, if the file is being written.
+    tail: Option<Arc<Mutex<crypto::Tail>>>,
+
+    /// The remaining plaintext range to read. Invariant: non-empty.
+    range: Range<u64>,
+}
+
+/// A plaintext file, read one chunk at a time via `pread` or io_uring.
+struct PlainFile {
+    file: fs::File,
+
+    /// The data which isn't yet on disk, if the file is being written with `O_DIRECT`.
+    tail: Option<Arc<Mutex<crypto::Tail>>>,
+
+    /// The remaining range to read. Invariant: non-empty.
+    range: Range<u64>,
+}
+
+/// The length of chunks read from plaintext files.
+const PLAIN_CHUNK_LEN: u64 = 1 << 16;
+
+/// The length of [`FileChunk::Mapped`] chunks. These cost no memory beyond the page cache, so
+/// they're larger to reduce thread handoffs.
+const MAPPED_CHUNK_LEN: usize = 1 << 20;
+
+/// Reads from `f` at `offset`, via io_uring if enabled and available.
+fn read_at(f: &fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    #[cfg(feature = "io-uring")]
+    if let Some(r) = super::uring::with_ring(|r| r.read_at(f, buf, offset)) {
+        return r;
     }
+    f.read_at(buf, offset)
 }
 
 struct SuccessfulRead {
-    chunk: Vec<u8>,
+    chunk: FileChunk,
 
     /// If this is not the final requested chunk, the `OpenFile` for next time.
     file: Option<OpenFile>,
@@ -227,6 +432,10 @@ enum ReaderCommand {
         span: tracing::Span,
         composite_id: CompositeId,
         range: std::ops::Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+
+        /// If true, plaintext files which can be `mmap()`ed yield [`FileChunk::Mapped`] chunks.
+        share: bool,
         tx: tokio::sync::oneshot::Sender<Result<SuccessfulRead, Error>>,
     },
 
@@ -246,6 +455,8 @@ struct ReaderInt {
 
     /// The page size as returned by `sysconf`; guaranteed to be a power of two.
     page_size: usize,
+
+    tails: Arc<crypto::Tails>,
 }
 
 impl ReaderInt {
@@ -258,6 +469,8 @@ impl ReaderInt {
                     span,
                     composite_id,
                     range,
+                    key,
+                    share,
                     tx,
                 } => {
                     if tx.is_closed() {
@@ -268,7 +481,7 @@ impl ReaderInt {
                     let _span_enter = span2.enter();
                     let _timer_guard =
                         TimerGuard::new(&RealClocks {}, || format!("open {composite_id}"));
-                    let _ = tx.send(self.open(span, composite_id, range));
+                    let _ = tx.send(self.open(span, composite_id, range, key, share));
                 }
                 ReaderCommand::ReadNextChunk { file, tx } => {
                     if tx.is_closed() {
@@ -280,7 +493,7 @@ impl ReaderInt {
                     let _span_enter = span2.enter();
                     let _guard =
                         TimerGuard::new(&RealClocks {}, || format!("read from {composite_id}"));
-                    let _ = tx.send(Ok(self.chunk(file)));
+                    let _ = tx.send(self.chunk(file));
                 }
                 ReaderCommand::CloseFile(mut file) => {
                     let composite_id = file.composite_id;
@@ -299,8 +512,44 @@ impl ReaderInt {
         span: tracing::Span,
         composite_id: CompositeId,
         range: Range<u64>,
+        key: Option<Arc<crypto::Key>>,
+        share: bool,
     ) -> Result<SuccessfulRead, Error> {
         let p = super::CompositeIdPath::from(composite_id);
+        if let Some(key) = key {
+            // Look up the tail before opening the file, so that if the writer finishes
+            // meanwhile, its last chunk is on disk by the time it's read.
+            let tail = self.tails.get(composite_id);
+            let file = crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty())
+                .err_kind(ErrorKind::Unknown)?;
+            return self.chunk(OpenFile {
+                span,
+                composite_id,
+                source: Source::Encrypted(Box::new(EncryptedFile {
+                    file,
+                    key,
+                    tail,
+                    range,
+                })),
+            });
+        }
+
+        // As above, look up the tail first. A file with a tail may be shorter than `range`, so
+        // it can't be mapped.
+        let tail = self.tails.get(composite_id);
+        #[cfg(feature = "io-uring")]
+        let read = tail.is_some() || super::uring::with_ring(|_| ()).is_some();
+        #[cfg(not(feature = "io-uring"))]
+        let read = tail.is_some();
+        if read {
+            let file = crate::fs::openat(self.dir.0, &p, OFlag::O_RDONLY, Mode::empty())
+                .err_kind(ErrorKind::Unknown)?;
+            return self.chunk(OpenFile {
+                span,
+                composite_id,
+                source: Source::Plain(PlainFile { file, tail, range }),
+            });
+        }
 
         // Reader::open_file checks for an empty range, but check again right
         // before the unsafe block to make it easier to audit the safety constraints.
@@ -379,26 +628,63 @@ impl ReaderInt {
             );
         }
 
-        Ok(self.chunk(OpenFile {
+        let region = Arc::new(MappedRegion {
+            ptr: map_ptr,
+            len: map_len.get(),
+            shared: share.then(|| (file, u64::try_from(offset).unwrap())),
+        });
+        if share {
+            SHARED_REGIONS
+                .lock()
+                .unwrap()
+                .insert(map_ptr as usize, Arc::downgrade(&region));
+        }
+        self.chunk(OpenFile {
             span,
             composite_id,
-            map_ptr,
-            map_pos: unaligned,
-            map_len: map_len.get(),
-        }))
+            source: Source::Mapped(Mapping {
+                region,
+                pos: unaligned,
+            }),
+        })
     }
 
-    fn chunk(&self, mut file: OpenFile) -> SuccessfulRead {
+    fn chunk(&self, mut file: OpenFile) -> Result<SuccessfulRead, Error> {
+        let (chunk, done) = match file.source {
+            Source::Mapped(ref mut m) => {
+                let chunk = if m.region.shared.is_some() {
+                    FileChunk::Mapped(self.shared_chunk(m))
+                } else {
+                    FileChunk::Owned(Self::mapped_chunk(m))
+                };
+                (chunk, m.pos == m.region.len)
+            }
+            Source::Encrypted(ref mut e) => {
+                let chunk = Self::encrypted_chunk(file.composite_id, e)?;
+                (FileChunk::Owned(chunk), e.range.is_empty())
+            }
+            Source::Plain(ref mut p) => {
+                let chunk = Self::plain_chunk(file.composite_id, p)?;
+                (FileChunk::Owned(chunk), p.range.is_empty())
+            }
+        };
+        Ok(SuccessfulRead {
+            chunk,
+            file: if done { None } else { Some(file) },
+        })
+    }
+
+    fn mapped_chunk(m: &mut Mapping) -> Vec<u8> {
         // Read a chunk that's large enough to minimize thread handoffs but
         // short enough to keep memory usage under control. It's hopefully
         // unnecessary to worry about disk seeks; the madvise call should cause
         // the kernel to read ahead.
-        let end = std::cmp::min(file.map_len, file.map_pos.saturating_add(1 << 16));
+        let end = std::cmp::min(m.region.len, m.pos.saturating_add(PLAIN_CHUNK_LEN as usize));
         let mut chunk = Vec::new();
-        let len = end.checked_sub(file.map_pos).unwrap();
+        let len = end.checked_sub(m.pos).unwrap();
         chunk.reserve_exact(len);
 
-        // SAFETY: [map_pos, map_pos + len) is verified to be within map_ptr.
+        // SAFETY: [pos, pos + len) is verified to be within ptr.
         //
         // If the read is out of bounds of the file, we'll get a SIGBUS.
         // That's not a safety violation. It also shouldn't happen because the
@@ -407,25 +693,143 @@ impl ReaderInt {
         // never truncated (only appended to or unlinked).
         unsafe {
             std::ptr::copy_nonoverlapping(
-                file.map_ptr.add(file.map_pos) as *const u8,
+                m.region.ptr.add(m.pos) as *const u8,
                 chunk.as_mut_ptr(),
                 len,
             );
             chunk.set_len(len);
         }
-        let file = if end == file.map_len {
-            None
-        } else {
-            file.map_pos = end;
-            Some(file)
+        m.pos = end;
+        chunk
+    }
+
+    /// Returns the next chunk of a shared mapping, as `mapped_chunk` does but without copying.
+    ///
+    /// This faults in the chunk's pages first, so that the thread which later reads them (or
+    /// the kernel, when it `sendfile`s them) doesn't block on disk IO. The same caveats about
+    /// `SIGBUS` apply.
+    fn shared_chunk(&self, m: &mut Mapping) -> MappedChunk {
+        let end = std::cmp::min(m.region.len, m.pos.saturating_add(MAPPED_CHUNK_LEN));
+        let first_page = m.pos & !(self.page_size - 1);
+        for off in (first_page..end).step_by(self.page_size) {
+            // SAFETY: `off` is within the mapping, as verified above.
+            unsafe { std::ptr::read_volatile(m.region.ptr.add(off) as *const u8) };
+        }
+        let chunk = MappedChunk {
+            region: m.region.clone(),
+            range: m.pos..end,
         };
-        SuccessfulRead { chunk, file }
+        m.pos = end;
+        chunk
+    }
+
+    /// Reads the next chunk of a plaintext file, as `mapped_chunk` does for `mmap()`ed files.
+    fn plain_chunk(composite_id: CompositeId, p: &mut PlainFile) -> Result<Vec<u8>, Error> {
+        let mut len = std::cmp::min(p.range.end - p.range.start, PLAIN_CHUNK_LEN);
+
+        // The chunk may not be on disk yet.
+        if let Some(ref tail) = p.tail {
+            let t = tail.lock().unwrap();
+            let tail_start = t.first_chunk * super::direct::BUF_LEN as u64;
+            if p.range.start >= tail_start {
+                let off = usize::try_from(p.range.start - tail_start).unwrap();
+                let end = off + usize::try_from(len).unwrap();
+                let chunk = t
+                    .data
+                    .get(off..end)
+                    .ok_or_else(|| {
+                        err!(
+                            OutOfRange,
+                            msg("file {composite_id}, range {:?} ends early", p.range)
+                        )
+                    })?
+                    .to_vec();
+                p.range.start += len;
+                return Ok(chunk);
+            }
+
+            // Data before the tail is on disk and stays there as the tail advances.
+            len = std::cmp::min(len, tail_start - p.range.start);
+        }
+        let len = usize::try_from(len).unwrap();
+        let mut chunk = vec![0u8; len];
+        let mut pos = 0;
+        while pos < len {
+            match read_at(&p.file, &mut chunk[pos..], p.range.start + pos as u64) {
+                Ok(0) => bail!(
+                    OutOfRange,
+                    msg("file {composite_id}, range {:?} ends early", p.range)
+                ),
+                Ok(n) => pos += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => bail!(err, msg("unable to read {composite_id}")),
+            }
+        }
+        p.range.start += len as u64;
+        Ok(chunk)
+    }
+
+    /// Reads the plaintext from `e.range.start` through the end of its chunk or the range.
+    fn encrypted_chunk(composite_id: CompositeId, e: &mut EncryptedFile) -> Result<Vec<u8>, Error> {
+        const CHUNK_LEN: u64 = crypto::CHUNK_LEN as u64;
+        let i = e.range.start / CHUNK_LEN;
+        let chunk_start = i * CHUNK_LEN;
+        let start = usize::try_from(e.range.start - chunk_start).unwrap();
+        let end = usize::try_from(std::cmp::min(e.range.end - chunk_start, CHUNK_LEN)).unwrap();
+        let range = e.range.clone();
+        let out_of_range = |len| {
+            err!(
+                OutOfRange,
+                msg("file {composite_id}, range {range:?}, chunk {i} has only {len} bytes")
+            )
+        };
+
+        // The chunk may not be on disk yet.
+        if let Some(ref tail) = e.tail {
+            let t = tail.lock().unwrap();
+            if i >= t.first_chunk {
+                let off = usize::try_from((i - t.first_chunk) * CHUNK_LEN).unwrap();
+                let chunk = t
+                    .data
+                    .get(off + start..off + end)
+                    .ok_or_else(|| out_of_range(t.data.len().saturating_sub(off)))?
+                    .to_vec();
+                e.range.start = chunk_start + end as u64;
+                return Ok(chunk);
+            }
+        }
+
+        let mut buf = vec![0u8; crypto::CHUNK_LEN + crypto::TAG_LEN];
+        let mut len = 0;
+        let pos = i * (CHUNK_LEN + crypto::TAG_LEN as u64);
+        while len < buf.len() {
+            match read_at(&e.file, &mut buf[len..], pos + len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => bail!(err, msg("unable to read {composite_id}")),
+            }
+        }
+        if len <= crypto::TAG_LEN {
+            return Err(out_of_range(0));
+        }
+        let plaintext = e.key.open_chunk(composite_id, i, &mut buf[..len])?;
+        if plaintext.len() < end {
+            return Err(out_of_range(plaintext.len()));
+        }
+        buf.truncate(end);
+        buf.drain(..start);
+        e.range.start = chunk_start + end as u64;
+        Ok(buf)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::{crypto, direct, CompositeIdPath, Fd};
     use futures::TryStreamExt;
+    use nix::{fcntl::OFlag, sys::stat::Mode};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn basic() {
@@ -434,10 +838,149 @@ mod tests {
             .prefix("moonfire-db-test-reader")
             .tempdir()
             .unwrap();
-        let fd = std::sync::Arc::new(super::super::Fd::open(tmpdir.path(), false).unwrap());
-        let reader = super::Reader::spawn(tmpdir.path(), fd);
+        let fd = Arc::new(Fd::open(tmpdir.path(), false).unwrap());
+        let reader = super::Reader::spawn(tmpdir.path(), fd, Arc::default());
         std::fs::write(tmpdir.path().join("0123456789abcdef"), b"blah blah").unwrap();
-        let f = reader.open_file(crate::CompositeId(0x0123_4567_89ab_cdef), 1..8);
+        let f = reader.open_file(crate::CompositeId(0x0123_4567_89ab_cdef), 1..8, None);
         assert_eq!(f.try_concat().await.unwrap(), b"lah bla");
     }
+
+    // io_uring reads don't `mmap()` files.
+    #[cfg(not(feature = "io-uring"))]
+    #[tokio::test]
+    async fn mapped() {
+        crate::testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-db-test-reader")
+            .tempdir()
+            .unwrap();
+        let fd = Arc::new(Fd::open(tmpdir.path(), false).unwrap());
+        let reader = super::Reader::spawn(tmpdir.path(), fd, Arc::default());
+        let data: Vec<u8> = (0..3 * super::MAPPED_CHUNK_LEN as u32 / 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(tmpdir.path().join("0123456789abcdef"), &data).unwrap();
+        let id = crate::CompositeId(0x0123_4567_89ab_cdef);
+        let chunks: Vec<super::FileChunk> = reader
+            .open_mapped_file(id, 1..data.len() as u64, None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        let mut pos = 1;
+        for c in &chunks {
+            let super::FileChunk::Mapped(m) = c else {
+                panic!("expected mapped chunk");
+            };
+            let bytes = m.as_ref();
+            assert_eq!(bytes, &data[pos..pos + bytes.len()]);
+
+            // The bytes can be found on disk again, from any position within the chunk.
+            let found = super::find_mapped(&bytes[10..]).unwrap();
+            assert_eq!(found.offset, (pos + 10) as u64);
+            assert_eq!(found.len, bytes.len() - 10);
+            let mut buf = vec![0u8; 5];
+            std::os::unix::fs::FileExt::read_exact_at(found.file(), &mut buf, found.offset)
+                .unwrap();
+            assert_eq!(buf, &data[pos + 10..pos + 15]);
+            pos += bytes.len();
+        }
+        assert_eq!(pos, data.len());
+
+        // Other bytes aren't found.
+        assert!(super::find_mapped(&data[..]).is_none());
+    }
+
+    #[tokio::test]
+    async fn encrypted() {
+        crate::testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-db-test-reader")
+            .tempdir()
+            .unwrap();
+        let fd = Arc::new(Fd::open(tmpdir.path(), false).unwrap());
+        let tails = Arc::new(crypto::Tails::default());
+        let reader = super::Reader::spawn(tmpdir.path(), fd.clone(), tails.clone());
+        let id = crate::CompositeId::new(1, 2);
+        let master = crypto::MasterKey::new(&[0u8; 32]).unwrap();
+        let wrapped = master.new_wrapped_key(id).unwrap();
+        let key = Arc::new(master.unwrap_key(id, &wrapped).unwrap());
+        let f = crate::fs::openat(
+            fd.0,
+            &CompositeIdPath::from(id),
+            OFlag::O_WRONLY | OFlag::O_EXCL | OFlag::O_CREAT,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )
+        .unwrap();
+        let mut w = crypto::EncryptingFile::new(f, id, key.clone(), tails);
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let mut pos = 0;
+        while pos < data.len() {
+            pos += w.write(&data[pos..]).unwrap();
+        }
+        let read = |r| reader.open_file(id, r, Some(key.clone())).try_concat();
+
+        // The first two chunks are on disk; the last is only in memory.
+        assert_eq!(read(1..140_000).await.unwrap(), &data[1..140_000]);
+        read(1..150_001).await.unwrap_err();
+
+        w.finish().unwrap();
+        drop(w);
+        let len = std::fs::metadata(tmpdir.path().join(format!("{:016x}", id.0)))
+            .unwrap()
+            .len();
+        assert_eq!(len, crypto::encrypted_len(150_000));
+        assert_eq!(read(65_530..150_000).await.unwrap(), &data[65_530..]);
+        read(140_000..150_001).await.unwrap_err();
+
+        // A different key fails authentication.
+        let wrapped = master.new_wrapped_key(id).unwrap();
+        let other_key = Arc::new(master.unwrap_key(id, &wrapped).unwrap());
+        reader
+            .open_file(id, 1..2, Some(other_key))
+            .try_concat()
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn direct() {
+        crate::testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-db-test-reader")
+            .tempdir()
+            .unwrap();
+        let fd = Arc::new(Fd::open(tmpdir.path(), false).unwrap());
+        let tails = Arc::new(crypto::Tails::default());
+        let reader = super::Reader::spawn(tmpdir.path(), fd.clone(), tails.clone());
+        let id = crate::CompositeId::new(1, 2);
+        let f = crate::fs::openat(
+            fd.0,
+            &CompositeIdPath::from(id),
+            OFlag::O_WRONLY | OFlag::O_EXCL | OFlag::O_CREAT,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )
+        .unwrap();
+        if direct::set_direct(&f, true).is_err() {
+            return; // this filesystem doesn't support O_DIRECT; nothing to test.
+        }
+        let mut w = direct::DirectFile::new(f, id, tails);
+        let len = 2 * direct::BUF_LEN + 100_000;
+        let data: Vec<u8> = (0..len as u32).map(|i| (i % 251) as u8).collect();
+        let mut pos = 0;
+        while pos < data.len() {
+            pos += w.write(&data[pos..]).unwrap();
+        }
+        let read = |r| reader.open_file(id, r, None).try_concat();
+
+        // The first two buffers are on disk; the rest is only in memory.
+        assert_eq!(read(1..len as u64).await.unwrap(), &data[1..]);
+        read(1..len as u64 + 1).await.unwrap_err();
+
+        w.finish().unwrap();
+        drop(w);
+        let path = tmpdir.path().join(format!("{:016x}", id.0));
+        assert_eq!(std::fs::metadata(path).unwrap().len(), len as u64);
+        assert_eq!(read(65_530..len as u64).await.unwrap(), &data[65_530..]);
+    }
 }
@@ -0,0 +1,138 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+//! Sample file I/O via [io_uring](https://kernel.dk/io_uring.pdf), enabled by the `io-uring`
+//! feature.
+//!
+//! Each thread which reads or writes sample files (the per-directory reader threads and the
+//! per-stream writer threads) lazily creates its own small ring. Operations are submitted and
+//! awaited one at a time, so callers keep their synchronous structure. The reader thread uses
+//! this in place of `mmap()`, avoiding the page table setup and TLB shootdowns of mapping and
+//! unmapping each file, which add up when serving many streams at once.
+//!
+//! If the kernel doesn't support io_uring (or forbids it, as some container runtimes do), every
+//! operation falls back to ordinary system calls.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tracing::warn;
+
+/// The number of submission queue entries. Only one operation is outstanding at a time.
+const ENTRIES: u32 = 4;
+
+/// An offset meaning "the file's current position", which the position then advances past.
+const CURRENT_POS: u64 = u64::MAX;
+
+pub(crate) struct Ring(IoUring);
+
+thread_local! {
+    /// This thread's ring, `None` if not yet created, or `Some(None)` if creation failed.
+    static RING: RefCell<Option<Option<Ring>>> = RefCell::new(None);
+}
+
+/// Runs `f` with this thread's ring, or returns `None` if io_uring is unavailable.
+pub(crate) fn with_ring<R>(f: impl FnOnce(&mut Ring) -> R) -> Option<R> {
+    RING.with(|r| {
+        let mut r = r.borrow_mut();
+        let ring = r.get_or_insert_with(|| match Ring::new() {
+            Ok(r) => Some(r),
+            Err(err) => {
+                warn!(%err, "io_uring unavailable; falling back to ordinary I/O");
+                None
+            }
+        });
+        ring.as_mut().map(f)
+    })
+}
+
+impl Ring {
+    fn new() -> io::Result<Self> {
+        let ring = IoUring::new(ENTRIES)?;
+        if !ring.params().is_feature_rw_cur_pos() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "kernel's io_uring lacks IORING_FEAT_RW_CUR_POS",
+            ));
+        }
+        Ok(Ring(ring))
+    }
+
+    /// Submits `e` and waits for its completion, returning its non-negative result.
+    ///
+    /// # Safety
+    ///
+    /// Any buffer referenced by `e` must be valid for the duration of the call.
+    unsafe fn run(&mut self, e: squeue::Entry) -> io::Result<usize> {
+        self.0
+            .submission()
+            .push(&e)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "io_uring submission queue full"))?;
+        loop {
+            // Retrying after an interrupted wait is necessary for safety: if the entry was
+            // consumed, the kernel may still be using the buffer.
+            match self.0.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+            if let Some(cqe) = self.0.completion().next() {
+                return match cqe.result() {
+                    r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+                    r => Ok(r as usize),
+                };
+            }
+        }
+    }
+
+    /// As in `std::os::unix::fs::FileExt::read_at`.
+    pub(crate) fn read_at(
+        &mut self,
+        f: &impl AsRawFd,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+        let e = opcode::Read::new(types::Fd(f.as_raw_fd()), buf.as_mut_ptr(), len)
+            .offset(offset)
+            .build();
+
+        // SAFETY: `buf` outlives the call.
+        unsafe { self.run(e) }
+    }
+
+    /// As in `std::io::Write::write`, writing at and advancing `f`'s current position.
+    pub(crate) fn write(&mut self, f: &impl AsRawFd, buf: &[u8]) -> io::Result<usize> {
+        let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+        let e = opcode::Write::new(types::Fd(f.as_raw_fd()), buf.as_ptr(), len)
+            .offset(CURRENT_POS)
+            .build();
+
+        // SAFETY: `buf` outlives the call.
+        unsafe { self.run(e) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+
+    #[test]
+    fn round_trip() {
+        crate::testutil::init();
+        let mut f = tempfile::tempfile().unwrap();
+        let Some(()) = super::with_ring(|r| {
+            assert_eq!(r.write(&f, b"hello ").unwrap(), 6);
+            assert_eq!(r.write(&f, b"world").unwrap(), 5);
+            let mut buf = [0u8; 16];
+            let n = r.read_at(&f, &mut buf, 3).unwrap();
+            assert_eq!(&buf[..n], b"lo world");
+        }) else {
+            return; // io_uring is unavailable here; nothing to test.
+        };
+        assert_eq!(f.seek(SeekFrom::Current(0)).unwrap(), 11);
+    }
+}
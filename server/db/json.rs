@@ -33,6 +33,8 @@
 
 use std::{collections::BTreeMap, path::PathBuf};
 
+use crate::recording;
+use base::{bail, Error};
 use rusqlite::types::{FromSqlError, ValueRef};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -96,6 +98,18 @@ sql!(GlobalConfig);
 pub struct SampleFileDirConfig {
     pub path: PathBuf,
 
+    /// If true, this is an archive directory: streams don't record into it directly, but those
+    /// which name it as their `archiveSampleFileDirId` move their oldest recordings here rather
+    /// than deleting them when over `retainBytes`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archive: bool,
+
+    /// If true, new unencrypted sample files are written with `O_DIRECT`, bypassing the page
+    /// cache, so that it holds recent footage for live view and playback rather than every
+    /// recording as it's written. This is ignored on filesystems which don't support `O_DIRECT`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct_io: bool,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
@@ -104,6 +118,15 @@ sql!(SampleFileDirConfig);
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignalTypeConfig {
+    /// A human-readable name for this signal type, such as `motion` or `alarm mode`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+
+    /// A recommended color to use in UIs for signals of this type, for states which don't set
+    /// their own, as in the [HTML specification](https://html.spec.whatwg.org/#colours).
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub color: String,
+
     /// Information about possible enumeration values of this signal type.
     ///
     /// 0 always means `unknown`. Other values may be specified here to set
@@ -172,17 +195,111 @@ pub struct CameraConfig {
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub password: String,
 
+    /// PEM-encoded CA certificates to trust for this camera's `rtsps://` stream URLs, in place
+    /// of the usual web PKI roots. This suits cameras with certificates from a private CA.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub rtsps_ca_certs: String,
+
+    /// The SHA-256 digest of this camera's TLS certificate, as hex (optionally with colons
+    /// between bytes), for `rtsps://` stream URLs.
+    ///
+    /// If set, the camera must present exactly this certificate; its issuer, name, and
+    /// expiration aren't checked. This suits cameras with self-signed certificates.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub rtsps_cert_sha256: String,
+
+    /// ONVIF event ingestion, if enabled. This requires `onvif_base_url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onvif_events: Option<OnvifEventsConfig>,
+
+    /// Vendor event stream ingestion, if enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor_events: Option<VendorEventsConfig>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(CameraConfig);
 
+/// ONVIF event ingestion configuration, used in [`CameraConfig::onvif_events`].
+///
+/// Moonfire NVR subscribes to the camera's event service and sets each configured signal to
+/// state 2 while the camera reports the condition is active and state 1 while it reports it's
+/// inactive, matching the `motion` and `still` states of the signal type suggested in
+/// `ref/api.md`. While the subscription is down, the signal's state lapses to unknown.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OnvifEventsConfig {
+    /// The signal to set from motion events, such as `RuleEngine/CellMotionDetector/Motion`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion_signal_id: Option<u32>,
+
+    /// The signal to set from tamper events, such as `RuleEngine/TamperDetector/Tamper`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tamper_signal_id: Option<u32>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+/// Vendor event stream ingestion configuration, used in [`CameraConfig::vendor_events`].
+///
+/// Some cameras report events more completely via a proprietary long-lived HTTP stream than
+/// via ONVIF. As with [`OnvifEventsConfig`], Moonfire NVR sets each configured signal to state 2
+/// while the camera reports the condition is active and state 1 while it's inactive. These
+/// streams don't report the current state on connection, so each signal starts inactive; while
+/// the stream is down, the signal's state lapses to unknown.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VendorEventsConfig {
+    /// The camera's event API: `hikvision` for the ISAPI `alertStream`, `dahua` for the
+    /// `eventManager.cgi` attachment, or `axis` for VAPIX event streaming over WebSocket. Other
+    /// values disable ingestion with a warning.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub api: String,
+
+    /// The camera's base URL, such as `http://192.168.1.110/`, against which the API's path is
+    /// resolved. The camera's `username` and `password` are supplied if challenged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<Url>,
+
+    /// The channel whose events to ingest, numbering from 1, as for a NVR or multi-sensor
+    /// camera. If absent, events from all channels are ingested. Ignored for `axis`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u32>,
+
+    /// The signal to set from motion detection events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion_signal_id: Option<u32>,
+
+    /// The signal to set from line crossing events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_crossing_signal_id: Option<u32>,
+
+    /// The signal to set from tamper (video blind) events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tamper_signal_id: Option<u32>,
+
+    /// For `axis`, additional event topics mapped to the signal to set from each, such as
+    /// `tnsaxis:CameraApplicationPlatform/ObjectAnalytics/Device1Scenario1` for the first AXIS
+    /// Object Analytics scenario. Each signal is active while the topic's event is.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub topics: BTreeMap<String, u32>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
 impl CameraConfig {
     pub fn is_empty(&self) -> bool {
         self.description.is_empty()
             && self.onvif_base_url.is_none()
             && self.username.is_empty()
             && self.password.is_empty()
+            && self.rtsps_ca_certs.is_empty()
+            && self.rtsps_cert_sha256.is_empty()
+            && self.onvif_events.is_none()
+            && self.vendor_events.is_none()
             && self.unknown.is_empty()
     }
 }
@@ -193,14 +310,20 @@ impl CameraConfig {
 pub struct StreamConfig {
     /// The mode of operation for this camera on startup.
     ///
-    /// Null means entirely disabled. At present, so does any value other than
-    /// `record`.
+    /// *   `record` ([`STREAM_MODE_RECORD`]) records continuously.
+    /// *   `on-signal` ([`STREAM_MODE_ON_SIGNAL`]) streams continuously but records only
+    ///     while a signal directly associated with the camera is in a `motion` state.
+    ///
+    /// Null means entirely disabled. At present, so does any other value.
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub mode: String,
 
-    /// The `rtsp://` URL to use for this stream, excluding username and
+    /// The `rtsp://` or `rtsps://` URL to use for this stream, excluding username and
     /// password.
     ///
+    /// `rtsps://` URLs always use the TCP transport. See [`CameraConfig::rtsps_ca_certs`] and
+    /// [`CameraConfig::rtsps_cert_sha256`] for how the camera's certificate is verified.
+    ///
     /// In the future, this might support additional protocols such as `rtmp://`
     /// or even a private use URI scheme for the [Baichuan
     /// protocol](https://github.com/thirtythreeforty/neolink).
@@ -209,17 +332,69 @@ pub struct StreamConfig {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub url: Option<Url>,
 
-    /// The RTSP transport (`tcp` or `udp`) to use.
+    /// The RTSP transport (`tcp` or `udp`) to use, or empty for the default (`tcp`).
+    ///
+    /// UDP multicast isn't supported, nor is setting the keepalive interval: Retina 0.4 offers
+    /// neither.
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub rtsp_transport: String,
 
+    /// Seconds to wait for the stream to start playing and send its first frame before giving
+    /// up and retrying, or 0 for the default (30 seconds).
+    ///
+    /// Some cameras are slow to respond after a reboot or when several streams connect at once.
+    #[serde(default)]
+    pub rtsp_connect_timeout_sec: u32,
+
+    /// Seconds to wait for each subsequent video frame before reconnecting, or 0 for the
+    /// default (30 seconds).
+    #[serde(default)]
+    pub rtsp_frame_timeout_sec: u32,
+
+    /// If true, decrypt SRTP-protected media, taking keys from the `a=crypto` attributes of the
+    /// camera's SDP. Only the `AES_CM_128_HMAC_SHA1_80` crypto suite is supported.
+    ///
+    /// This forces the TCP transport. As the keys are sent in the clear over `rtsp://`, it's
+    /// best combined with a `rtsps://` URL.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub srtp: bool,
+
     /// The number of bytes of video to retain, excluding the
     /// currently-recording file.
     ///
-    /// Older files will be deleted as necessary to stay within this limit.
+    /// Older files will be deleted (or archived; see `archive_sample_file_dir_id`) as necessary
+    /// to stay within this limit.
     #[serde(default)]
     pub retain_bytes: i64,
 
+    /// The archive directory, if any, to which recordings are moved rather than deleted when
+    /// over `retain_bytes`. This must be a directory marked as an archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_sample_file_dir_id: Option<i32>,
+
+    /// The number of bytes of archived video to retain in `archive_sample_file_dir_id`.
+    ///
+    /// Older archived files will be deleted as necessary to stay within this limit.
+    #[serde(default)]
+    pub archive_retain_bytes: i64,
+
+    /// The number of days of completed recordings to retain, or 0 for no age limit.
+    ///
+    /// Recordings which ended longer ago are deleted even if within `retain_bytes`; whichever
+    /// limit is hit first applies. Like `retain_bytes`, this is enforced as recordings complete
+    /// and on startup.
+    #[serde(default)]
+    pub retain_days: u32,
+
+    /// The number of days to retain recordings which overlap an event, or 0 to treat them like
+    /// any other recording.
+    ///
+    /// An event is a motion state of a signal directly associated with the camera, or a
+    /// detection on this stream. If greater than `retain_days`, event recordings outlive other
+    /// recordings of the same age. `retain_bytes` still applies to all recordings, oldest first.
+    #[serde(default)]
+    pub retain_event_days: u32,
+
     /// Flush the database when the first instant of completed recording is this
     /// many seconds old. A value of 0 means that every completed recording will
     /// cause an immediate flush. Higher values may allow flushes to be combined,
@@ -234,19 +409,235 @@ pub struct StreamConfig {
     #[serde(default)]
     pub flush_if_sec: u32,
 
+    /// In `on-signal` mode, the number of seconds of video before each signal to include in
+    /// the recording. This video is held in memory until the signal arrives. Recordings start
+    /// at a key frame, so they may include somewhat more than this.
+    #[serde(default)]
+    pub pre_roll_sec: u32,
+
+    /// If true, record the stream's AAC audio (if any) alongside the video.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub record_audio: bool,
+
+    /// If true (and `record_audio` is set), transcode G.711 audio to AAC when the stream has no
+    /// AAC audio. This requires a Moonfire NVR built with the `fdk-aac` feature.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub transcode_audio: bool,
+
+    /// Built-in motion detection, if enabled. This requires a Moonfire NVR built with the
+    /// `ffmpeg` feature, and the stream must be recorded. Typically it's enabled on the `sub`
+    /// stream, as decoding a lower-resolution stream is cheaper.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<MotionConfig>,
+
+    /// Object detection, if enabled. This requires a Moonfire NVR built with the `analytics`
+    /// feature and configured with a model; see `ref/config.md`. As with motion detection, the
+    /// `sub` stream is typically the better choice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detection: Option<DetectionConfig>,
+
+    /// A weekly schedule which overrides `mode` by hour, if the stream is recorded at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ScheduleConfig>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(StreamConfig);
 
+/// A weekly recording schedule, used in [`StreamConfig::schedule`].
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleConfig {
+    /// Seven strings, one per day of the week in the server's time zone, starting with Sunday.
+    /// Each has one character per hour of the day:
+    ///
+    /// *   `r` to record continuously, as in `record` mode.
+    /// *   `e` to record on events, as in `on-signal` mode.
+    /// *   `-` not to record.
+    #[serde(default)]
+    pub days: Vec<String>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+/// Built-in motion detection configuration, used in [`StreamConfig::motion`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MotionConfig {
+    /// The id of the signal to update as motion starts and stops.
+    pub signal_id: u32,
+
+    /// The signal state to set while there's motion.
+    ///
+    /// Defaults to 2, which is `motion` in the signal type suggested in `ref/api.md`. Outside
+    /// motion, the signal's state is left unknown.
+    #[serde(default = "MotionConfig::default_motion_state")]
+    pub motion_state: u16,
+
+    /// How readily changes between frames are considered motion, from 1 (least) to 100 (most).
+    #[serde(default = "MotionConfig::default_sensitivity")]
+    pub sensitivity: u8,
+
+    /// The portions of the frame to examine. If empty, the whole frame is examined.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<MotionRegion>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+impl MotionConfig {
+    pub const DEFAULT_MOTION_STATE: u16 = 2;
+    pub const DEFAULT_SENSITIVITY: u8 = 50;
+
+    fn default_motion_state() -> u16 {
+        Self::DEFAULT_MOTION_STATE
+    }
+
+    fn default_sensitivity() -> u8 {
+        Self::DEFAULT_SENSITIVITY
+    }
+
+    /// Returns a config for the given signal with default settings.
+    pub fn new(signal_id: u32) -> Self {
+        MotionConfig {
+            signal_id,
+            motion_state: Self::DEFAULT_MOTION_STATE,
+            sensitivity: Self::DEFAULT_SENSITIVITY,
+            regions: Vec::new(),
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+/// Object detection configuration, used in [`StreamConfig::detection`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectionConfig {
+    /// The minimum score, in percent, of detections to keep.
+    #[serde(default = "DetectionConfig::default_min_score")]
+    pub min_score: u8,
+
+    /// Signals to update as objects of each class are seen, from class label (such as `person`)
+    /// to signal id. Detections of other classes are still stored.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signals: BTreeMap<String, u32>,
+
+    /// The signal state to set while objects are seen. Defaults to 2, as in
+    /// [`MotionConfig::motion_state`].
+    #[serde(default = "DetectionConfig::default_detected_state")]
+    pub detected_state: u16,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+impl DetectionConfig {
+    pub const DEFAULT_MIN_SCORE: u8 = 50;
+    pub const DEFAULT_DETECTED_STATE: u16 = 2;
+
+    fn default_min_score() -> u8 {
+        Self::DEFAULT_MIN_SCORE
+    }
+
+    fn default_detected_state() -> u16 {
+        Self::DEFAULT_DETECTED_STATE
+    }
+}
+
+/// A rectangular portion of the frame, in percent of the frame's width and height from its
+/// top-left corner.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotionRegion {
+    pub left: u8,
+    pub top: u8,
+    pub width: u8,
+    pub height: u8,
+}
+
 pub const STREAM_MODE_RECORD: &str = "record";
+pub const STREAM_MODE_ON_SIGNAL: &str = "on-signal";
+
+impl ScheduleConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.days.len() != 7 {
+            bail!(
+                InvalidArgument,
+                msg("schedule must have 7 days, not {}", self.days.len())
+            );
+        }
+        for (i, d) in self.days.iter().enumerate() {
+            if d.len() != 24 || !d.bytes().all(|b| matches!(b, b'r' | b'e' | b'-')) {
+                bail!(
+                    InvalidArgument,
+                    msg("schedule day {i} must be 24 characters of r, e, or -, not {d:?}"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the scheduled mode for the given day of the week (0 for Sunday) and hour, or
+    /// `None` if the schedule doesn't say.
+    pub fn mode(&self, weekday: usize, hour: usize) -> Option<&'static str> {
+        match self.days.get(weekday)?.as_bytes().get(hour)? {
+            b'r' => Some(STREAM_MODE_RECORD),
+            b'e' => Some(STREAM_MODE_ON_SIGNAL),
+            b'-' => Some(""),
+            _ => None,
+        }
+    }
+}
 
 impl StreamConfig {
+    /// Returns true if the stream should be streamed and (at least at times) recorded.
+    pub fn is_recording(&self) -> bool {
+        matches!(
+            self.mode.as_str(),
+            STREAM_MODE_RECORD | STREAM_MODE_ON_SIGNAL
+        )
+    }
+
+    /// Returns the mode in effect at the given time, following the schedule (if any) while the
+    /// stream is recorded at all.
+    pub fn mode_at(&self, when: recording::Time) -> &str {
+        if !self.is_recording() {
+            return "";
+        }
+        let Some(schedule) = &self.schedule else {
+            return &self.mode;
+        };
+        let tm = time::at(time::Timespec {
+            sec: when.unix_seconds(),
+            nsec: 0,
+        });
+        schedule
+            .mode(tm.tm_wday as usize, tm.tm_hour as usize)
+            .unwrap_or(&self.mode)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.mode.is_empty()
             && self.url.is_none()
+            && self.rtsp_transport.is_empty()
+            && self.rtsp_connect_timeout_sec == 0
+            && self.rtsp_frame_timeout_sec == 0
+            && !self.srtp
             && self.retain_bytes == 0
+            && self.archive_sample_file_dir_id.is_none()
+            && self.archive_retain_bytes == 0
+            && self.retain_days == 0
+            && self.retain_event_days == 0
             && self.flush_if_sec == 0
+            && self.pre_roll_sec == 0
+            && !self.record_audio
+            && !self.transcode_audio
+            && self.motion.is_none()
+            && self.detection.is_none()
+            && self.schedule.is_none()
             && self.unknown.is_empty()
     }
 }
@@ -278,11 +669,40 @@ pub struct SignalConfig {
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub camera_associations: BTreeMap<i32, String>,
 
+    /// MQTT messages which set this signal's state, when Moonfire NVR is configured with an
+    /// MQTT broker.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mqtt: Vec<SignalMqttRule>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(SignalConfig);
 
+/// A mapping from MQTT messages to signal states; used in [`SignalConfig::mqtt`].
+///
+/// For example, a zigbee2mqtt door sensor might be mapped with topic `zigbee2mqtt/front_door`,
+/// JSON pointer `/contact`, and payloads `{"true": 1, "false": 2}`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignalMqttRule {
+    /// The topic filter to subscribe to, which may contain the MQTT wildcards `+` and `#`.
+    pub topic: String,
+
+    /// If non-empty, a JSON pointer (RFC 6901) such as `/after/label`. The payload is parsed
+    /// as JSON and the value at this pointer is matched, rather than the payload as a whole.
+    /// Strings are matched without quotes; other values are matched in their JSON form.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub json_pointer: String,
+
+    /// Map of payload (or value at `json_pointer`) to signal state. Other payloads are
+    /// ignored.
+    pub payloads: BTreeMap<String, u16>,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
 /// User configuration, used in the `config` column of the `user` table.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -305,9 +725,100 @@ pub struct UserConfig {
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub preferences: UserPreferences,
 
+    /// If set, alerts are emailed to this user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_email: Option<AlertEmail>,
+
     #[serde(flatten)]
     pub unknown: BTreeMap<String, Value>,
 }
 sql!(UserConfig);
 
 pub type UserPreferences = BTreeMap<String, Value>;
+
+/// Email delivery of alerts to a user, via the server's configured SMTP relay.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertEmail {
+    /// The address to which to send alerts.
+    #[serde(default)]
+    pub address: String,
+
+    /// The least severe alerts to send. `critical` sends only critical alerts; any other value,
+    /// including the default of empty, sends all alerts.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub min_severity: String,
+
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn schedule() {
+        testutil::init();
+        let mut days = vec!["-".repeat(24); 7];
+        days[1] = format!("{}{}{}", "r".repeat(8), "e".repeat(10), "-".repeat(6));
+        let schedule = ScheduleConfig {
+            days,
+            ..Default::default()
+        };
+        schedule.validate().unwrap();
+        assert_eq!(schedule.mode(1, 7), Some(STREAM_MODE_RECORD));
+        assert_eq!(schedule.mode(1, 8), Some(STREAM_MODE_ON_SIGNAL));
+        assert_eq!(schedule.mode(1, 18), Some(""));
+        assert_eq!(schedule.mode(7, 0), None);
+
+        let mut config = StreamConfig {
+            mode: STREAM_MODE_ON_SIGNAL.to_owned(),
+            schedule: Some(schedule.clone()),
+            ..Default::default()
+        };
+        let when = recording::Time::parse("2024-04-01T07:30:00").unwrap(); // a Monday
+        assert_eq!(config.mode_at(when), STREAM_MODE_RECORD);
+        config.mode.clear();
+        assert_eq!(
+            config.mode_at(when),
+            "",
+            "schedule only applies to recorded streams"
+        );
+
+        let mut bad = schedule;
+        bad.days[3] = "x".repeat(24);
+        bad.validate().unwrap_err();
+        bad.days.pop();
+        bad.validate().unwrap_err();
+    }
+
+    #[test]
+    fn stream_rtsp_options() {
+        testutil::init();
+        let json = serde_json::json!({
+            "rtspTransport": "udp",
+            "rtspConnectTimeoutSec": 60,
+            "rtspFrameTimeoutSec": 10,
+        });
+        let config: StreamConfig = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(config.rtsp_transport, "udp");
+        assert_eq!(config.rtsp_connect_timeout_sec, 60);
+        assert_eq!(config.rtsp_frame_timeout_sec, 10);
+        let round_tripped = serde_json::to_value(&config).unwrap();
+        for key in [
+            "rtspTransport",
+            "rtspConnectTimeoutSec",
+            "rtspFrameTimeoutSec",
+        ] {
+            assert_eq!(round_tripped[key], json[key], "{key}");
+        }
+
+        // Absent fields mean the defaults.
+        let config: StreamConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config.rtsp_transport, "");
+        assert_eq!(config.rtsp_connect_timeout_sec, 0);
+        assert_eq!(config.rtsp_frame_timeout_sec, 0);
+    }
+}
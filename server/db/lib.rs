@@ -13,7 +13,9 @@
 
 #![cfg_attr(all(feature = "nightly", test), feature(test))]
 
+pub mod audit;
 pub mod auth;
+mod backup;
 pub mod check;
 mod coding;
 mod compare;
@@ -27,8 +29,10 @@ mod proto {
 }
 mod raw;
 pub mod recording;
+mod retention;
 pub use proto::schema;
 pub mod signal;
+pub mod stats;
 pub mod upgrade;
 pub mod writer;
 
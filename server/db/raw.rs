@@ -4,6 +4,7 @@
 
 //! Raw database access: SQLite statements which do not touch any cached state.
 
+use crate::days;
 use crate::db::{self, CompositeId, SqlUuid};
 use crate::json::GlobalConfig;
 use crate::recording;
@@ -26,7 +27,11 @@ const LIST_RECORDINGS_BY_TIME_SQL: &str = r#"
         recording.video_samples,
         recording.video_sync_samples,
         recording.video_sample_entry_id,
-        recording.open_id
+        recording.open_id,
+        recording.audio_sample_entry_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
+        recording.sample_file_dir_id
     from
         recording
     where
@@ -38,6 +43,38 @@ const LIST_RECORDINGS_BY_TIME_SQL: &str = r#"
         recording.start_time_90k
 "#;
 
+const LIST_RECORDINGS_BY_TIME_PAGE_SQL: &str = r#"
+    select
+        recording.composite_id,
+        recording.run_offset,
+        recording.flags,
+        recording.start_time_90k,
+        recording.wall_duration_90k,
+        recording.media_duration_delta_90k,
+        recording.sample_file_bytes,
+        recording.video_samples,
+        recording.video_sync_samples,
+        recording.video_sample_entry_id,
+        recording.open_id,
+        recording.audio_sample_entry_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
+        recording.sample_file_dir_id
+    from
+        recording
+    where
+        stream_id = :stream_id and
+        recording.start_time_90k > :start_time_90k - 27000000 and
+        recording.start_time_90k < :end_time_90k and
+        recording.start_time_90k + recording.wall_duration_90k > :start_time_90k and
+        (recording.start_time_90k, recording.composite_id) >
+            (:after_start_time_90k, :after_composite_id)
+    order by
+        recording.start_time_90k,
+        recording.composite_id
+    limit :limit
+"#;
+
 const LIST_RECORDINGS_BY_ID_SQL: &str = r#"
     select
         recording.composite_id,
@@ -51,6 +88,10 @@ const LIST_RECORDINGS_BY_ID_SQL: &str = r#"
         recording.video_sync_samples,
         recording.video_sample_entry_id,
         recording.open_id,
+        recording.audio_sample_entry_id,
+        recording.audio_samples,
+        recording.audio_sample_file_bytes,
+        recording.sample_file_dir_id,
         recording.prev_media_duration_90k,
         recording.prev_runs
     from
@@ -88,7 +129,8 @@ const LIST_OLDEST_RECORDINGS_SQL: &str = r#"
       composite_id,
       start_time_90k,
       wall_duration_90k,
-      sample_file_bytes
+      sample_file_bytes,
+      sample_file_dir_id
     from
       recording
     where
@@ -119,6 +161,43 @@ pub(crate) fn list_recordings_by_time(
     list_recordings_inner(rows, false, f)
 }
 
+/// Lists up to `limit` of the recordings [`list_recordings_by_time`] would list, starting after
+/// the one with the given start time and id (if any). Returns the number of recordings listed.
+///
+/// Recordings with equal start times are ordered by id, so that repeated calls can page through
+/// the results. The `recording_cover` index (like any SQLite index) ends with the rowid, so this
+/// ordering doesn't require a sort.
+pub(crate) fn list_recordings_by_time_page(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    desired_time: Range<recording::Time>,
+    after: Option<(recording::Time, CompositeId)>,
+    limit: usize,
+    f: &mut dyn FnMut(db::ListRecordingsRow) -> Result<(), base::Error>,
+) -> Result<usize, base::Error> {
+    let (after_start, after_id) =
+        after.unwrap_or((recording::Time::min_value(), CompositeId(i64::MIN)));
+    let mut stmt = conn
+        .prepare_cached(LIST_RECORDINGS_BY_TIME_PAGE_SQL)
+        .err_kind(ErrorKind::Internal)?;
+    let rows = stmt
+        .query(named_params! {
+            ":stream_id": stream_id,
+            ":start_time_90k": desired_time.start.0,
+            ":end_time_90k": desired_time.end.0,
+            ":after_start_time_90k": after_start.0,
+            ":after_composite_id": after_id.0,
+            ":limit": i64::try_from(limit).unwrap_or(i64::MAX),
+        })
+        .err_kind(ErrorKind::Internal)?;
+    let mut n = 0;
+    list_recordings_inner(rows, false, &mut |row| {
+        n += 1;
+        f(row)
+    })?;
+    Ok(n)
+}
+
 /// Lists the specified recordings in ascending order by id.
 pub(crate) fn list_recordings_by_id(
     conn: &rusqlite::Connection,
@@ -158,11 +237,15 @@ fn list_recordings_inner(
             video_sync_samples: row.get(8).err_kind(ErrorKind::Internal)?,
             video_sample_entry_id: row.get(9).err_kind(ErrorKind::Internal)?,
             open_id: row.get(10).err_kind(ErrorKind::Internal)?,
+            audio_sample_entry_id: row.get(11).err_kind(ErrorKind::Internal)?,
+            audio_samples: row.get(12).err_kind(ErrorKind::Internal)?,
+            audio_sample_file_bytes: row.get(13).err_kind(ErrorKind::Internal)?,
+            sample_file_dir_id: row.get(14).err_kind(ErrorKind::Internal)?,
             prev_media_duration_and_runs: match include_prev {
                 false => None,
                 true => Some((
-                    recording::Duration(row.get(11).err_kind(ErrorKind::Internal)?),
-                    row.get(12).err_kind(ErrorKind::Internal)?,
+                    recording::Duration(row.get(15).err_kind(ErrorKind::Internal)?),
+                    row.get(16).err_kind(ErrorKind::Internal)?,
                 )),
             },
         })?;
@@ -195,12 +278,14 @@ pub(crate) fn insert_recording(
                                sample_file_bytes, start_time_90k, prev_media_duration_90k,
                                prev_runs, wall_duration_90k, media_duration_delta_90k,
                                video_samples, video_sync_samples, video_sample_entry_id,
-                               end_reason)
+                               end_reason, audio_sample_entry_id, audio_samples,
+                               audio_sample_file_bytes)
                        values (:composite_id, :stream_id, :open_id, :run_offset, :flags,
                                :sample_file_bytes, :start_time_90k, :prev_media_duration_90k,
                                :prev_runs, :wall_duration_90k, :media_duration_delta_90k,
                                :video_samples, :video_sync_samples, :video_sample_entry_id,
-                               :end_reason)
+                               :end_reason, :audio_sample_entry_id, :audio_samples,
+                               :audio_sample_file_bytes)
             "#,
     )?;
     stmt.execute(named_params! {
@@ -219,6 +304,9 @@ pub(crate) fn insert_recording(
         ":video_sync_samples": r.video_sync_samples,
         ":video_sample_entry_id": r.video_sample_entry_id,
         ":end_reason": r.end_reason.as_deref(),
+        ":audio_sample_entry_id": r.audio_sample_entry_id,
+        ":audio_samples": r.audio_samples,
+        ":audio_sample_file_bytes": r.audio_sample_file_bytes,
     })
     .map_err(|e| {
         err!(
@@ -249,13 +337,18 @@ pub(crate) fn insert_recording(
 
     let mut stmt = tx.prepare_cached(
         r#"
-            insert into recording_playback (composite_id,  video_index)
-                                    values (:composite_id, :video_index)
+            insert into recording_playback (composite_id,  video_index,  audio_index,
+                                            wrapped_key)
+                                    values (:composite_id, :video_index, :audio_index,
+                                            :wrapped_key)
             "#,
     )?;
+    let audio_index = r.audio_sample_entry_id.map(|_| &r.audio_index[..]);
     stmt.execute(named_params! {
         ":composite_id": id.0,
         ":video_index": &r.video_index,
+        ":audio_index": audio_index,
+        ":wrapped_key": &r.wrapped_key,
     })
     .map_err(|e| err!(e, msg("unable to insert recording_playback for {r:#?}")))?;
 
@@ -263,7 +356,8 @@ pub(crate) fn insert_recording(
 }
 
 /// Transfers the given recording range from the `recording` and associated tables to the `garbage`
-/// table. `sample_file_dir_id` is assumed to be correct.
+/// table. `sample_file_dir_id` is assumed to be the stream's directory; archived recordings'
+/// garbage rows name their archive directory instead.
 ///
 /// Returns the number of recordings which were deleted.
 pub(crate) fn delete_recordings(
@@ -275,7 +369,7 @@ pub(crate) fn delete_recordings(
         r#"
         insert into garbage (sample_file_dir_id, composite_id)
         select
-          :sample_file_dir_id,
+          coalesce(sample_file_dir_id, :sample_file_dir_id),
           composite_id
         from
           recording
@@ -350,18 +444,47 @@ pub(crate) fn delete_recordings(
     Ok(n)
 }
 
+/// Notes that the given recording's sample file has been copied from the stream's directory
+/// `from_dir_id` to the archive directory `to_dir_id`, and marks the original as garbage.
+pub(crate) fn archive_recording(
+    tx: &rusqlite::Transaction,
+    id: CompositeId,
+    from_dir_id: i32,
+    to_dir_id: i32,
+) -> Result<(), Error> {
+    let mut update = tx.prepare_cached(
+        r#"
+        update recording set sample_file_dir_id = :to_dir_id
+        where composite_id = :composite_id and sample_file_dir_id is null
+        "#,
+    )?;
+    let n = update.execute(named_params! {
+        ":to_dir_id": to_dir_id,
+        ":composite_id": id.0,
+    })?;
+    if n != 1 {
+        bail!(Internal, msg("no unarchived recording {id} to archive"));
+    }
+    let mut insert =
+        tx.prepare_cached("insert into garbage (sample_file_dir_id, composite_id) values (?, ?)")?;
+    insert.execute(params![from_dir_id, id.0])?;
+    Ok(())
+}
+
 /// Marks the given sample files as deleted. This shouldn't be called until the files have
 /// been `unlink()`ed and the parent directory `fsync()`ed.
 pub(crate) fn mark_sample_files_deleted(
     tx: &rusqlite::Transaction,
+    sample_file_dir_id: i32,
     ids: &[CompositeId],
 ) -> Result<(), Error> {
     if ids.is_empty() {
         return Ok(());
     }
-    let mut stmt = tx.prepare_cached("delete from garbage where composite_id = ?")?;
+    let mut stmt =
+        tx.prepare_cached("delete from garbage where sample_file_dir_id = ? and composite_id = ?")?;
     for &id in ids {
-        let changes = stmt.execute(params![id.0])?;
+        let changes = stmt.execute(params![sample_file_dir_id, id.0])?;
         if changes != 1 {
             // panic rather than return error. Errors get retried indefinitely, but there's no
             // recovery from this condition.
@@ -451,6 +574,7 @@ pub(crate) fn list_oldest_recordings(
             start: recording::Time(row.get(1)?),
             wall_duration_90k: row.get(2)?,
             sample_file_bytes: row.get(3)?,
+            sample_file_dir_id: row.get(4)?,
         });
         if !should_continue {
             break;
@@ -458,3 +582,545 @@ pub(crate) fn list_oldest_recordings(
     }
     Ok(())
 }
+
+/// Inserts a thumbnail, replacing any existing one for the same stream and time.
+pub(crate) fn insert_thumbnail(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    start: recording::Time,
+    width: u16,
+    height: u16,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        insert or replace into thumbnail (stream_id, start_time_90k, width,  height,  data)
+                                   values (:stream_id, :start,       :width, :height, :data)
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":stream_id": stream_id,
+        ":start": start.0,
+        ":width": i32::from(width),
+        ":height": i32::from(height),
+        ":data": data,
+    })?;
+    Ok(())
+}
+
+/// Lists the thumbnails of a stream in ascending order by time, passing them to a supplied
+/// function along with their JPEG data if `include_data` is set, or an empty slice otherwise.
+pub(crate) fn list_thumbnails(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    desired_time: Range<recording::Time>,
+    include_data: bool,
+    f: &mut dyn FnMut(db::ListThumbnailsRow, &[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          start_time_90k,
+          width,
+          height,
+          case when :include_data then data else x'' end
+        from
+          thumbnail
+        where
+          stream_id = :stream_id and
+          :start <= start_time_90k and
+          start_time_90k < :end
+        order by
+          start_time_90k
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":stream_id": stream_id,
+        ":start": desired_time.start.0,
+        ":end": desired_time.end.0,
+        ":include_data": include_data,
+    })?;
+    while let Some(row) = rows.next()? {
+        let data = row.get_ref(3)?.as_blob()?;
+        f(
+            db::ListThumbnailsRow {
+                start: recording::Time(row.get(0)?),
+                width: row.get(1)?,
+                height: row.get(2)?,
+            },
+            data,
+        )?;
+    }
+    Ok(())
+}
+
+/// Deletes the thumbnails of a stream which precede `before`, or all of them if `None`.
+pub(crate) fn delete_thumbnails(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    before: Option<recording::Time>,
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        delete from thumbnail
+        where
+          stream_id = :stream_id and
+          start_time_90k < :before
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":stream_id": stream_id,
+        ":before": before.unwrap_or(recording::Time(i64::MAX)).0,
+    })?;
+    Ok(())
+}
+
+/// Inserts detections for a stream.
+pub(crate) fn insert_detections(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    detections: &[db::Detection],
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        insert into detection (stream_id,  time_90k, class,  score,  box_left, box_top, box_width,
+                               box_height)
+                       values (:stream_id, :time,    :class, :score, :left,    :top,    :width,
+                               :height)
+        "#,
+    )?;
+    for d in detections {
+        stmt.execute(named_params! {
+            ":stream_id": stream_id,
+            ":time": d.time.0,
+            ":class": &d.class,
+            ":score": d.score,
+            ":left": d.left,
+            ":top": d.top,
+            ":width": d.width,
+            ":height": d.height,
+        })?;
+    }
+    Ok(())
+}
+
+/// Lists the detections of a stream in ascending order by time, optionally only those of the
+/// given class.
+pub(crate) fn list_detections(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    desired_time: Range<recording::Time>,
+    class: Option<&str>,
+    f: &mut dyn FnMut(db::Detection) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          time_90k,
+          class,
+          score,
+          box_left,
+          box_top,
+          box_width,
+          box_height
+        from
+          detection
+        where
+          stream_id = :stream_id and
+          :start <= time_90k and
+          time_90k < :end and
+          (:class is null or class = :class)
+        order by
+          time_90k,
+          id
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":stream_id": stream_id,
+        ":start": desired_time.start.0,
+        ":end": desired_time.end.0,
+        ":class": class,
+    })?;
+    while let Some(row) = rows.next()? {
+        f(db::Detection {
+            time: recording::Time(row.get(0)?),
+            class: row.get(1)?,
+            score: row.get(2)?,
+            left: row.get(3)?,
+            top: row.get(4)?,
+            width: row.get(5)?,
+            height: row.get(6)?,
+        })?;
+    }
+    Ok(())
+}
+
+/// Deletes the detections of a stream which precede `before`, or all of them if `None`.
+pub(crate) fn delete_detections(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    before: Option<recording::Time>,
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        delete from detection
+        where
+          stream_id = :stream_id and
+          time_90k < :before
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":stream_id": stream_id,
+        ":before": before.unwrap_or(recording::Time(i64::MAX)).0,
+    })?;
+    Ok(())
+}
+
+/// Lists up to `limit` committed recordings after `after` which have no `upload` row, in ascending
+/// order by id.
+pub(crate) fn list_recordings_to_upload(
+    conn: &rusqlite::Connection,
+    after: Option<CompositeId>,
+    limit: usize,
+) -> Result<Vec<CompositeId>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          composite_id
+        from
+          recording
+        where
+          composite_id > :after and
+          not exists (select 1 from upload where upload.composite_id = recording.composite_id)
+        order by
+          composite_id
+        limit :limit
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":after": after.map_or(-1, |a| a.0),
+        ":limit": i64::try_from(limit).unwrap_or(i64::MAX),
+    })?;
+    let mut ids = Vec::new();
+    while let Some(row) = rows.next()? {
+        ids.push(CompositeId(row.get(0)?));
+    }
+    Ok(ids)
+}
+
+pub(crate) fn insert_upload(conn: &rusqlite::Connection, u: &db::Upload) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        insert or replace into upload (composite_id, object_key,  sample_file_bytes,
+                                       sample_file_sha256,  upload_time_90k, verify_time_90k)
+                               values (:composite_id, :object_key, :sample_file_bytes,
+                                       :sample_file_sha256, :upload_time,    :verify_time)
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":composite_id": u.id.0,
+        ":object_key": &u.object_key,
+        ":sample_file_bytes": u.sample_file_bytes,
+        ":sample_file_sha256": &u.sample_file_sha256[..],
+        ":upload_time": u.upload_time.0,
+        ":verify_time": u.verify_time.map(|t| t.0),
+    })?;
+    Ok(())
+}
+
+/// Lists uploads which haven't been verified, in ascending order by id.
+pub(crate) fn list_unverified_uploads(
+    conn: &rusqlite::Connection,
+) -> Result<Vec<db::Upload>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          composite_id,
+          object_key,
+          sample_file_bytes,
+          sample_file_sha256,
+          upload_time_90k
+        from
+          upload
+        where
+          verify_time_90k is null
+        order by
+          composite_id
+        "#,
+    )?;
+    let mut rows = stmt.query(params![])?;
+    let mut uploads = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id = CompositeId(row.get(0)?);
+        let sha256: Vec<u8> = row.get(3)?;
+        uploads.push(db::Upload {
+            id,
+            object_key: row.get(1)?,
+            sample_file_bytes: row.get(2)?,
+            sample_file_sha256: sha256
+                .try_into()
+                .map_err(|_| err!(DataLoss, msg("upload {id} has bad sha256")))?,
+            upload_time: recording::Time(row.get(4)?),
+            verify_time: None,
+        });
+    }
+    Ok(uploads)
+}
+
+pub(crate) fn mark_upload_verified(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+    verify_time: recording::Time,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        update upload set verify_time_90k = :verify_time where composite_id = :composite_id
+        "#,
+    )?;
+    if stmt.execute(named_params! {
+        ":composite_id": id.0,
+        ":verify_time": verify_time.0,
+    })? != 1
+    {
+        bail!(NotFound, msg("no upload {id}"));
+    }
+    Ok(())
+}
+
+/// Lists the ids of recordings of the given stream which still exist and have verified uploads,
+/// in ascending order.
+pub(crate) fn list_verified_uploaded_recordings(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+) -> Result<Vec<CompositeId>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          recording.composite_id
+        from
+          recording
+          join upload on (recording.composite_id = upload.composite_id)
+        where
+          :start <= recording.composite_id and
+          recording.composite_id < :end and
+          upload.verify_time_90k is not null
+        order by
+          recording.composite_id
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":start": CompositeId::new(stream_id, 0).0,
+        ":end": CompositeId::new(stream_id + 1, 0).0,
+    })?;
+    let mut ids = Vec::new();
+    while let Some(row) = rows.next()? {
+        ids.push(CompositeId(row.get(0)?));
+    }
+    Ok(ids)
+}
+
+/// Deletes all `upload` rows of the given stream, as when deleting the stream itself.
+pub(crate) fn delete_uploads(tx: &rusqlite::Transaction, stream_id: i32) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        delete from upload
+        where
+          :start <= composite_id and
+          composite_id < :end
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":start": CompositeId::new(stream_id, 0).0,
+        ":end": CompositeId::new(stream_id + 1, 0).0,
+    })?;
+    Ok(())
+}
+
+/// Lists up to `limit` committed recordings after `after` which have no `replication` row, in
+/// ascending order by id.
+pub(crate) fn list_recordings_to_replicate(
+    conn: &rusqlite::Connection,
+    after: Option<CompositeId>,
+    limit: usize,
+) -> Result<Vec<CompositeId>, Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          composite_id
+        from
+          recording
+        where
+          composite_id > :after and
+          not exists (select 1 from replication
+                      where replication.composite_id = recording.composite_id)
+        order by
+          composite_id
+        limit :limit
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":after": after.map_or(-1, |a| a.0),
+        ":limit": i64::try_from(limit).unwrap_or(i64::MAX),
+    })?;
+    let mut ids = Vec::new();
+    while let Some(row) = rows.next()? {
+        ids.push(CompositeId(row.get(0)?));
+    }
+    Ok(ids)
+}
+
+pub(crate) fn insert_replication(
+    conn: &rusqlite::Connection,
+    id: CompositeId,
+    remote_path: &str,
+    replicate_time: recording::Time,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        insert or replace into replication (composite_id,  remote_path,  replicate_time_90k)
+                                    values (:composite_id, :remote_path, :replicate_time)
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":composite_id": id.0,
+        ":remote_path": remote_path,
+        ":replicate_time": replicate_time.0,
+    })?;
+    Ok(())
+}
+
+/// Deletes all `replication` rows of the given stream, as when deleting the stream itself.
+pub(crate) fn delete_replications(tx: &rusqlite::Transaction, stream_id: i32) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        delete from replication
+        where
+          :start <= composite_id and
+          composite_id < :end
+        "#,
+    )?;
+    stmt.execute(named_params! {
+        ":start": CompositeId::new(stream_id, 0).0,
+        ":end": CompositeId::new(stream_id + 1, 0).0,
+    })?;
+    Ok(())
+}
+
+/// Adds to a stream's `stream_health` row for the given day, creating it if necessary.
+pub(crate) fn add_stream_health(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+    day: days::Key,
+    h: &db::StreamHealth,
+) -> Result<(), Error> {
+    let params = named_params! {
+        ":stream_id": stream_id,
+        ":day": day.as_ref(),
+        ":monitored_sec": h.monitored_sec,
+        ":up_sec": h.up_sec,
+        ":reconnects": h.reconnects,
+        ":frame_gaps": h.frame_gaps,
+        ":frames": h.frames,
+        ":packets_lost": h.packets_lost,
+    };
+    let mut stmt = tx.prepare_cached(
+        r#"
+        update stream_health
+        set
+          monitored_sec = monitored_sec + :monitored_sec,
+          up_sec = up_sec + :up_sec,
+          reconnects = reconnects + :reconnects,
+          frame_gaps = frame_gaps + :frame_gaps,
+          frames = frames + :frames,
+          packets_lost = packets_lost + :packets_lost
+        where
+          stream_id = :stream_id and
+          day = :day
+        "#,
+    )?;
+    if stmt.execute(params)? == 1 {
+        return Ok(());
+    }
+    let mut stmt = tx.prepare_cached(
+        r#"
+        insert into stream_health (stream_id,  day,  monitored_sec,  up_sec,  reconnects,
+                                   frame_gaps,  frames,  packets_lost)
+                           values (:stream_id, :day, :monitored_sec, :up_sec, :reconnects,
+                                   :frame_gaps, :frames, :packets_lost)
+        "#,
+    )?;
+    stmt.execute(params)?;
+    Ok(())
+}
+
+/// Lists a stream's `stream_health` rows in ascending order by day.
+pub(crate) fn list_stream_health(
+    conn: &rusqlite::Connection,
+    stream_id: i32,
+    f: &mut dyn FnMut(days::Key, db::StreamHealth) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        select
+          day,
+          monitored_sec,
+          up_sec,
+          reconnects,
+          frame_gaps,
+          frames,
+          packets_lost
+        from
+          stream_health
+        where
+          stream_id = :stream_id
+        order by
+          day
+        "#,
+    )?;
+    let mut rows = stmt.query(named_params! {":stream_id": stream_id})?;
+    while let Some(row) = rows.next()? {
+        let day: String = row.get(0)?;
+        let day = <[u8; 10]>::try_from(day.as_bytes())
+            .map_err(|_| err!(DataLoss, msg("bad stream_health day {day:?}")))?;
+        f(
+            days::Key(day),
+            db::StreamHealth {
+                monitored_sec: row.get(1)?,
+                up_sec: row.get(2)?,
+                reconnects: row.get(3)?,
+                frame_gaps: row.get(4)?,
+                frames: row.get(5)?,
+                packets_lost: row.get(6)?,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Deletes all `stream_health` rows of the given stream, as when deleting the stream itself.
+pub(crate) fn delete_stream_health(
+    tx: &rusqlite::Transaction,
+    stream_id: i32,
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(
+        r#"
+        delete from stream_health where stream_id = :stream_id
+        "#,
+    )?;
+    stmt.execute(named_params! {":stream_id": stream_id})?;
+    Ok(())
+}
+
+/// Deletes all `stream_health` rows for days before `before`.
+pub(crate) fn delete_stream_health_before(
+    conn: &rusqlite::Connection,
+    before: days::Key,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        delete from stream_health where day < :before
+        "#,
+    )?;
+    stmt.execute(named_params! {":before": before.as_ref()})?;
+    Ok(())
+}
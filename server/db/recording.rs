@@ -6,9 +6,11 @@
 
 use crate::coding::{append_varint32, decode_varint32, unzigzag32, zigzag32};
 use crate::db;
-use base::{bail, Error};
+use crate::dir::crypto;
+use base::{bail, err, Error};
 use std::convert::TryFrom;
 use std::ops::Range;
+use std::sync::Arc;
 use tracing::trace;
 
 pub use base::time::TIME_UNITS_PER_SEC;
@@ -140,6 +142,47 @@ impl SampleIndexIterator {
     }
 }
 
+/// The key frames of a recording, parsed from its video index.
+///
+/// A [`Segment`] which starts partway through a recording (as when scrubbing back and forth)
+/// begins iterating at the closest preceding key frame rather than at the start of the index.
+/// Committed recordings' key frames are cached along with their indexes; see
+/// [`db::RecordingPlayback::key_frames`].
+#[derive(Debug)]
+pub struct KeyFrames(Box<[SampleIndexIterator]>);
+
+impl KeyFrames {
+    pub fn parse(video_index: &[u8]) -> Result<Self, Error> {
+        let mut it = first_frame(video_index)?;
+        let mut key_frames = vec![it];
+        while it.next(video_index)? {
+            if it.is_key() {
+                key_frames.push(it);
+            }
+        }
+        Ok(KeyFrames(key_frames.into_boxed_slice()))
+    }
+
+    /// Returns an iterator positioned at the last key frame starting at or before `media_90k`,
+    /// or at the first frame if there is none.
+    pub fn seek(&self, media_90k: i32) -> SampleIndexIterator {
+        let i = self.0.partition_point(|k| k.start_90k <= media_90k);
+        self.0[i.saturating_sub(1)]
+    }
+}
+
+/// Returns an iterator positioned at the first frame of `video_index`, which must be a key frame.
+fn first_frame(video_index: &[u8]) -> Result<SampleIndexIterator, Error> {
+    let mut it = SampleIndexIterator::default();
+    if !it.next(video_index)? {
+        bail!(Internal, msg("no index"));
+    }
+    if !it.is_key() {
+        bail!(Internal, msg("not key frame"));
+    }
+    Ok(it)
+}
+
 /// An encoder for a sample index (as described in `design/recording.md`).
 #[derive(Debug, Default)]
 pub struct SampleIndexEncoder {
@@ -180,6 +223,218 @@ impl SampleIndexEncoder {
     }
 }
 
+/// An iterator through an audio sample index (as described in `design/schema.md`).
+/// Initially invalid; call `next()` before each read.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudioSampleIndexIterator {
+    /// The index byte position of the next sample to read.
+    i: u32,
+
+    /// The starting data byte position of this sample, relative to the first audio sample.
+    pub pos: i32,
+
+    /// The starting time of this sample relative to the first audio sample, in units of the
+    /// audio sample entry's sample rate.
+    pub start: i32,
+
+    /// The duration of this sample, in units of the audio sample entry's sample rate.
+    pub duration: i32,
+
+    /// The byte length of this sample.
+    pub bytes: i32,
+}
+
+impl AudioSampleIndexIterator {
+    /// Reads the index's header, returning the offset of the first audio sample from the start of
+    /// the recording (in 90 kHz units) and an iterator positioned before the first sample.
+    pub fn new(data: &[u8]) -> Result<(i32, Self), Error> {
+        let (raw, i) = match decode_varint32(data, 0) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!(DataLoss, msg("bad audio index header")),
+        };
+        Ok((
+            unzigzag32(raw),
+            AudioSampleIndexIterator {
+                i: i as u32,
+                ..Default::default()
+            },
+        ))
+    }
+
+    pub fn next(&mut self, data: &[u8]) -> Result<bool, Error> {
+        self.pos += self.bytes;
+        self.start += self.duration;
+        let i = self.i as usize;
+        if i == data.len() {
+            return Ok(false);
+        }
+        let (raw1, i1) = match decode_varint32(data, i) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!(DataLoss, msg("bad varint 1 at offset {i}")),
+        };
+        let (raw2, i2) = match decode_varint32(data, i1) {
+            Ok(tuple) => tuple,
+            Err(()) => bail!(DataLoss, msg("bad varint 2 at offset {i1}")),
+        };
+        self.i = i2 as u32;
+        self.duration += unzigzag32(raw1);
+        if self.duration <= 0 {
+            bail!(
+                DataLoss,
+                msg(
+                    "non-positive audio duration {} at offset {i}",
+                    self.duration
+                ),
+            );
+        }
+        self.bytes += unzigzag32(raw2);
+        if self.bytes <= 0 {
+            bail!(
+                DataLoss,
+                msg("non-positive audio bytes {} at offset {i}", self.bytes),
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// An encoder for an audio sample index (as described in `design/schema.md`).
+#[derive(Debug)]
+pub struct AudioSampleIndexEncoder {
+    prev_duration: i32,
+    prev_bytes: i32,
+    pub samples: i32,
+    pub bytes: i32,
+    pub index: Vec<u8>,
+}
+
+impl AudioSampleIndexEncoder {
+    /// Creates an encoder whose first sample starts `start_90k` after the start of the recording.
+    pub fn new(start_90k: i32) -> Self {
+        let mut index = Vec::new();
+        append_varint32(zigzag32(start_90k), &mut index);
+        AudioSampleIndexEncoder {
+            prev_duration: 0,
+            prev_bytes: 0,
+            samples: 0,
+            bytes: 0,
+            index,
+        }
+    }
+
+    pub fn add_sample(&mut self, duration: i32, bytes: i32) {
+        append_varint32(zigzag32(duration - self.prev_duration), &mut self.index);
+        append_varint32(zigzag32(bytes - self.prev_bytes), &mut self.index);
+        self.prev_duration = duration;
+        self.prev_bytes = bytes;
+        self.samples += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// The audio portion of a `Segment`.
+#[derive(Debug)]
+pub struct AudioSegment {
+    pub audio_sample_entry_id: i32,
+
+    /// The start of the first audio sample, relative to the start of the recording, in 90 kHz
+    /// units.
+    pub start_90k: i32,
+
+    /// An iterator positioned at the first audio sample in the segment.
+    begin: AudioSampleIndexIterator,
+
+    pub samples: u32,
+
+    /// The total duration of the samples, in units of the audio sample entry's sample rate.
+    pub duration: i32,
+
+    /// The byte range within the sample file.
+    file_range: Range<u64>,
+}
+
+impl AudioSegment {
+    /// Finds the audio samples which start within `media_range_90k` of the recording.
+    fn new(
+        recording: &db::ListRecordingsRow,
+        audio_sample_entry_id: i32,
+        sample_rate: u32,
+        audio_index: &[u8],
+        media_range_90k: Range<i32>,
+    ) -> Result<Self, Error> {
+        let (offset_90k, mut it) = AudioSampleIndexIterator::new(audio_index)?;
+        let to_90k = |start: i32| {
+            offset_90k
+                + i32::try_from(i64::from(start) * TIME_UNITS_PER_SEC / i64::from(sample_rate))
+                    .unwrap()
+        };
+        let audio_file_start =
+            i64::from(recording.sample_file_bytes) - i64::from(recording.audio_sample_file_bytes);
+        let mut begin = None;
+        let mut start_90k = 0;
+        let mut samples = 0;
+        let mut duration = 0;
+        let mut end_pos = 0;
+        while it.next(audio_index)? {
+            let s = to_90k(it.start);
+            if s < media_range_90k.start {
+                continue;
+            }
+            if s >= media_range_90k.end {
+                break;
+            }
+            if begin.is_none() {
+                begin = Some(it);
+                start_90k = s;
+            }
+            samples += 1;
+            duration += it.duration;
+            end_pos = it.pos + it.bytes;
+        }
+        let begin = begin.unwrap_or_default();
+        let file_start = u64::try_from(audio_file_start + i64::from(begin.pos)).unwrap();
+        let file_end = if samples == 0 {
+            file_start
+        } else {
+            u64::try_from(audio_file_start + i64::from(end_pos)).unwrap()
+        };
+        Ok(AudioSegment {
+            audio_sample_entry_id,
+            start_90k,
+            begin,
+            samples,
+            duration,
+            file_range: file_start..file_end,
+        })
+    }
+
+    /// Returns the byte range within the sample file of audio data associated with this segment.
+    pub fn sample_file_range(&self) -> Range<u64> {
+        self.file_range.clone()
+    }
+
+    /// Iterates through each audio sample in the segment.
+    pub fn foreach<F>(&self, playback: &db::RecordingPlayback, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&AudioSampleIndexIterator) -> Result<(), Error>,
+    {
+        let data = playback
+            .audio_index
+            .ok_or_else(|| err!(Internal, msg("missing audio index")))?;
+        let mut it = self.begin;
+        for i in 0..self.samples {
+            if i > 0 && !it.next(data)? {
+                bail!(
+                    Internal,
+                    msg("expected {} audio samples, found only {}", self.samples, i),
+                );
+            }
+            f(&it)?;
+        }
+        Ok(())
+    }
+}
+
 /// A segment represents a view of some or all of a single recording.
 /// This struct is not specific to a container format; for `.mp4`s, it's wrapped in a
 /// `moonfire_nvr::mp4::Segment`. Other container/transport formats could be
@@ -189,6 +444,9 @@ pub struct Segment {
     pub id: db::CompositeId,
     pub open_id: u32,
 
+    /// The directory holding the sample file: the stream's own or an archive directory.
+    pub sample_file_dir_id: i32,
+
     /// An iterator positioned at the beginning of the segment, or `None`. Most segments are
     /// positioned at the beginning of the recording, so this is an optional box to shrink a long
     /// of segments. `None` is equivalent to `SampleIndexIterator::default()`.
@@ -198,6 +456,12 @@ pub struct Segment {
     pub frames: u16,
     pub key_frames: u16,
     video_sample_entry_id_and_trailing_zero: i32,
+
+    /// The audio samples, if the recording has audio.
+    pub audio: Option<Box<AudioSegment>>,
+
+    /// The key to the sample file, if it's encrypted.
+    pub key: Option<Arc<crypto::Key>>,
 }
 
 impl Segment {
@@ -220,9 +484,18 @@ impl Segment {
         desired_media_range_90k: Range<i32>,
         start_at_key: bool,
     ) -> Result<Segment, Error> {
+        let sample_file_dir_id = match recording.sample_file_dir_id {
+            Some(d) => d,
+            None => db
+                .streams_by_id()
+                .get(&recording.id.stream())
+                .and_then(|s| s.sample_file_dir_id)
+                .ok_or_else(|| err!(Internal, msg("no dir for recording {}", recording.id)))?,
+        };
         let mut self_ = Segment {
             id: recording.id,
             open_id: recording.open_id,
+            sample_file_dir_id,
             begin: None,
             file_end: recording.sample_file_bytes,
             frames: recording.video_samples as u16,
@@ -230,6 +503,8 @@ impl Segment {
             video_sample_entry_id_and_trailing_zero: recording.video_sample_entry_id
                 | ((((recording.flags & db::RecordingFlags::TrailingZero as i32) != 0) as i32)
                     << 31),
+            audio: None,
+            key: None,
         };
 
         #[allow(clippy::suspicious_operation_groupings)]
@@ -247,6 +522,31 @@ impl Segment {
             );
         }
 
+        if (recording.flags & db::RecordingFlags::Encrypted as i32) != 0 {
+            self_.key = db.recording_key(recording.id)?;
+        }
+
+        if let Some(id) = recording.audio_sample_entry_id {
+            let sample_rate = db
+                .audio_sample_entries_by_id()
+                .get(&id)
+                .ok_or_else(|| err!(Internal, msg("no such audio sample entry {id}")))?
+                .sample_rate;
+            let audio = db.with_recording_playback(self_.id, &mut |playback| {
+                let audio_index = playback
+                    .audio_index
+                    .ok_or_else(|| err!(Internal, msg("missing audio index")))?;
+                AudioSegment::new(
+                    recording,
+                    id,
+                    sample_rate,
+                    audio_index,
+                    desired_media_range_90k.clone(),
+                )
+            })?;
+            self_.audio = Some(Box::new(audio));
+        }
+
         if desired_media_range_90k.start == 0
             && desired_media_range_90k.end == recording.media_duration_90k
         {
@@ -255,6 +555,7 @@ impl Segment {
                 "recording::Segment::new fast path, recording={:#?}",
                 recording
             );
+            self_.file_end -= recording.audio_sample_file_bytes;
             return Ok(self_);
         }
 
@@ -267,13 +568,14 @@ impl Segment {
         db.with_recording_playback(self_.id, &mut |playback| {
             let mut begin = Box::<SampleIndexIterator>::default();
             let data = &playback.video_index;
-            let mut it = SampleIndexIterator::default();
-            if !it.next(data)? {
-                bail!(Internal, msg("no index"));
-            }
-            if !it.is_key() {
-                bail!(Internal, msg("not key frame"));
-            }
+
+            // When the key frames are cached, skip to the last one at or before the desired start.
+            // No earlier frame can be the start, so this is equivalent to iterating from the
+            // beginning.
+            let mut it = match playback.key_frames()? {
+                Some(k) => k.seek(desired_media_range_90k.start),
+                None => first_frame(data)?,
+            };
 
             // Stop when hitting a frame with this start time.
             // Going until the end of the recording is special-cased because there can be a trailing
@@ -490,6 +792,31 @@ mod tests {
         assert!(!it.next(&r.video_index).unwrap());
     }
 
+    /// Tests a round trip from `AudioSampleIndexEncoder` to `AudioSampleIndexIterator`.
+    #[test]
+    fn test_audio_round_trip() {
+        testutil::init();
+        let samples = [(1024, 300), (1024, 280), (1024, 310), (512, 150)];
+        let mut e = AudioSampleIndexEncoder::new(-1800);
+        for &(duration, bytes) in &samples {
+            e.add_sample(duration, bytes);
+        }
+        assert_eq!(e.samples, 4);
+        assert_eq!(e.bytes, 300 + 280 + 310 + 150);
+        let (offset_90k, mut it) = AudioSampleIndexIterator::new(&e.index).unwrap();
+        assert_eq!(offset_90k, -1800);
+        let mut start = 0;
+        let mut pos = 0;
+        for &(duration, bytes) in &samples {
+            assert!(it.next(&e.index).unwrap());
+            assert_eq!((it.start, it.pos), (start, pos));
+            assert_eq!((it.duration, it.bytes), (duration, bytes));
+            start += duration;
+            pos += bytes;
+        }
+        assert!(!it.next(&e.index).unwrap());
+    }
+
     /// Tests that `SampleIndexIterator` spots several classes of errors.
     /// TODO: test and fix overflow cases.
     #[test]
@@ -527,6 +854,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_key_frames() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = SampleIndexEncoder::default();
+        for i in 1..6 {
+            encoder.add_sample(2 * i, 3 * i, (i % 2) == 1, &mut r);
+        }
+        let k = KeyFrames::parse(&r.video_index).unwrap();
+        let starts: Vec<_> = k.0.iter().map(|it| (it.start_90k, it.pos)).collect();
+        assert_eq!(
+            starts,
+            &[(0, 0), (2 + 4, 3 + 6), (2 + 4 + 6 + 8, 3 + 6 + 9 + 12)]
+        );
+        assert_eq!(k.seek(-1).start_90k, 0);
+        assert_eq!(k.seek(5).start_90k, 0);
+        assert_eq!(k.seek(6).start_90k, 6);
+        assert_eq!(k.seek(1000).start_90k, 20);
+
+        let mut it = k.seek(6);
+        assert!(it.next(&r.video_index).unwrap());
+        assert_eq!((it.start_90k, it.duration_90k, it.is_key()), (12, 8, false));
+
+        let mut not_key = db::RecordingToInsert::default();
+        SampleIndexEncoder::default().add_sample(1, 1, false, &mut not_key);
+        assert_eq!(
+            KeyFrames::parse(&not_key.video_index)
+                .unwrap_err()
+                .msg()
+                .unwrap(),
+            "not key frame"
+        );
+    }
+
+    /// Tests that segments starting partway through a committed recording (which use the cached
+    /// key frames) match those starting at the beginning.
+    #[test]
+    fn test_segment_with_cached_key_frames() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = SampleIndexEncoder::default();
+        for i in 1..6 {
+            encoder.add_sample(2 * i, 3 * i, (i % 2) == 1, &mut r);
+        }
+        let db = TestDb::new(RealClocks {});
+        let row = db.insert_recording_from_encoder(r);
+        for _ in 0..2 {
+            let segment =
+                Segment::new(&db.db.lock(), &row, 2 + 4 + 6..2 + 4 + 6 + 8, false).unwrap();
+            assert_eq!(&get_frames(&db.db, &segment, |it| it.duration_90k), &[8]);
+            let segment = Segment::new(&db.db.lock(), &row, 1..2 + 4 + 6, true).unwrap();
+            assert_eq!(
+                &get_frames(&db.db, &segment, |it| it.duration_90k),
+                &[2, 4, 6]
+            );
+        }
+    }
+
     fn get_frames<F, T>(db: &db::Database, segment: &Segment, f: F) -> Vec<T>
     where
         F: Fn(&SampleIndexIterator) -> T,
@@ -0,0 +1,335 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Retention policy: deciding which of a stream's recordings to delete.
+//!
+//! Recordings are considered oldest first. A recording is deleted if the stream is over its
+//! `retain_bytes` limit, or if the recording is older than its age limit. The age limit is
+//! `retain_event_days` for recordings which overlap an event (see [`crate::json::StreamConfig`])
+//! and `retain_days` for all others.
+//!
+//! If the stream has an archive directory, recordings which would be deleted only to satisfy
+//! `retain_bytes` are instead moved there, and are deleted from there when it exceeds
+//! `archive_retain_bytes` or when they pass their age limit.
+
+use crate::db::{self, Deletion, ListOldestRecordingsRow};
+use crate::recording::{self, Duration, Time};
+use base::{bail, Error};
+use std::ops::Range;
+
+/// The retention policy for a single stream as of a given time.
+pub(crate) struct Policy {
+    /// The number of filesystem bytes which must be freed; may be non-positive.
+    fs_bytes_needed: i64,
+
+    /// The number of filesystem bytes freed by the recordings deleted or archived so far.
+    fs_bytes_to_delete: i64,
+
+    /// True if the stream has an archive directory.
+    archive: bool,
+
+    /// As `fs_bytes_needed`, for the archive directory.
+    archive_fs_bytes_needed: i64,
+
+    /// As `fs_bytes_to_delete`, for the archive directory. Includes recordings archived by
+    /// this policy.
+    archive_fs_bytes_to_delete: i64,
+
+    /// Recordings which end at or before this time are deleted, unless they overlap an event.
+    cutoff: Option<Time>,
+
+    /// Recordings which overlap an event and end at or before this time are deleted.
+    event_cutoff: Option<Time>,
+
+    events: Events,
+}
+
+impl Policy {
+    /// Builds the policy for `stream_id` given it needs `extra_bytes_needed` beyond its current
+    /// usage.
+    pub(crate) fn new(
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        extra_bytes_needed: i64,
+        now: Time,
+    ) -> Result<Self, Error> {
+        let stream = match db.streams_by_id().get(&stream_id) {
+            None => bail!(NotFound, msg("no stream {stream_id}")),
+            Some(s) => s,
+        };
+        let cutoff_for = |days: u32| match days {
+            0 => None,
+            d => Some(now - Duration(i64::from(d) * 86_400 * recording::TIME_UNITS_PER_SEC)),
+        };
+        let cutoff = cutoff_for(stream.config.retain_days);
+        let event_cutoff = match stream.config.retain_event_days {
+            0 => cutoff,
+            d => cutoff_for(d),
+        };
+
+        let mut events = Events::default();
+        if let Some(range) = stream
+            .range
+            .as_ref()
+            .and_then(|r| event_range(r, cutoff, event_cutoff))
+        {
+            let mut v = db.camera_motion_ranges(stream.camera_id, range.clone());
+            db.list_detections(stream_id, range, None, &mut |d| {
+                v.push(d.time..d.time + Duration(1));
+                Ok(())
+            })?;
+            events = Events::new(v);
+        }
+        Ok(Policy {
+            fs_bytes_needed: stream.fs_bytes + stream.fs_bytes_to_add
+                - stream.fs_bytes_to_delete
+                - stream.fs_bytes_to_archive
+                + extra_bytes_needed
+                - stream.config.retain_bytes,
+            fs_bytes_to_delete: 0,
+            archive: stream.config.archive_sample_file_dir_id.is_some(),
+            archive_fs_bytes_needed: stream.archived_fs_bytes + stream.fs_bytes_to_archive
+                - stream.archived_fs_bytes_to_delete
+                - stream.config.archive_retain_bytes,
+            archive_fs_bytes_to_delete: 0,
+            cutoff,
+            event_cutoff,
+            events,
+        })
+    }
+
+    /// Returns the number of filesystem bytes which must be freed; may be non-positive.
+    pub(crate) fn fs_bytes_needed(&self) -> i64 {
+        self.fs_bytes_needed
+    }
+
+    /// Returns true if no recordings can be deleted or archived under this policy.
+    pub(crate) fn is_satisfied(&self) -> bool {
+        self.fs_bytes_needed <= 0
+            && (!self.archive || self.archive_fs_bytes_needed <= 0)
+            && self.cutoff.is_none()
+            && self.event_cutoff.is_none()
+    }
+
+    /// Decides the fate of the given row, which must be the oldest not yet considered.
+    pub(crate) fn decide(&mut self, row: &ListOldestRecordingsRow) -> Deletion {
+        let end = row.start + Duration(i64::from(row.wall_duration_90k));
+        let cutoff = if self.events.overlaps(row.start..end) {
+            self.event_cutoff
+        } else {
+            self.cutoff
+        };
+        let fs_bytes = db::round_up(i64::from(row.sample_file_bytes));
+        let archived = row.sample_file_dir_id.is_some();
+        if cutoff.map_or(false, |c| end <= c) {
+            if archived {
+                self.archive_fs_bytes_to_delete += fs_bytes;
+            } else {
+                self.fs_bytes_to_delete += fs_bytes;
+            }
+            return Deletion::Delete;
+        }
+        if archived
+            && self.archive
+            && self.archive_fs_bytes_needed > 0
+            && self.archive_fs_bytes_needed >= self.archive_fs_bytes_to_delete
+        {
+            self.archive_fs_bytes_to_delete += fs_bytes;
+            return Deletion::Delete;
+        }
+        if !archived && self.fs_bytes_needed > 0 && self.fs_bytes_needed >= self.fs_bytes_to_delete
+        {
+            self.fs_bytes_to_delete += fs_bytes;
+            if self.archive {
+                // The archive directory grows by as much as this one shrinks.
+                self.archive_fs_bytes_needed += fs_bytes;
+                return Deletion::Archive;
+            }
+            return Deletion::Delete;
+        }
+        if archived
+            && (self.fs_bytes_needed > 0 || (self.archive && self.archive_fs_bytes_needed > 0))
+        {
+            return Deletion::Keep; // a later recording might be in the stream's own directory.
+        }
+        if self
+            .cutoff
+            .max(self.event_cutoff)
+            .map_or(false, |c| end <= c)
+        {
+            return Deletion::Keep; // a later recording might still be expired.
+        }
+        Deletion::Stop
+    }
+}
+
+/// Returns the times of events which might affect the fate of a recording in `stream_range`, or
+/// `None` if there are no such times.
+///
+/// Events only matter for recordings which end after the earlier cutoff (or at any time, if
+/// there's no earlier cutoff) and at or before the later one: events keep them (or doom them
+/// early). Earlier recordings are deleted regardless, and later ones are kept regardless. A
+/// recording which ends after the earlier cutoff starts at most
+/// [`recording::MAX_RECORDING_WALL_DURATION`] before it, and only events overlapping such a
+/// recording matter.
+fn event_range(
+    stream_range: &Range<Time>,
+    cutoff: Option<Time>,
+    event_cutoff: Option<Time>,
+) -> Option<Range<Time>> {
+    if cutoff == event_cutoff {
+        return None;
+    }
+    let end = cutoff.max(event_cutoff)?;
+    let start = match cutoff.min(event_cutoff) {
+        None => stream_range.start,
+        Some(c) => stream_range
+            .start
+            .max(c - Duration(recording::MAX_RECORDING_WALL_DURATION)),
+    };
+    (start < end).then_some(start..end)
+}
+
+/// A set of event times, as disjoint ranges in ascending order.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Events(Vec<Range<Time>>);
+
+impl Events {
+    /// Builds from possibly overlapping ranges in any order.
+    fn new(mut ranges: Vec<Range<Time>>) -> Self {
+        ranges.sort_unstable_by_key(|r| r.start);
+        let mut out: Vec<Range<Time>> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            match out.last_mut() {
+                Some(l) if r.start <= l.end => l.end = l.end.max(r.end),
+                _ => out.push(r),
+            }
+        }
+        Events(out)
+    }
+
+    fn overlaps(&self, range: Range<Time>) -> bool {
+        let i = self.0.partition_point(|e| e.end <= range.start);
+        self.0.get(i).map_or(false, |e| e.start < range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_range, Events, Policy};
+    use crate::db::{CompositeId, Deletion, ListOldestRecordingsRow};
+    use crate::recording::{Time, MAX_RECORDING_WALL_DURATION};
+    use crate::testutil;
+
+    fn row(id: i32, sample_file_dir_id: Option<i32>) -> ListOldestRecordingsRow {
+        ListOldestRecordingsRow {
+            id: CompositeId::new(1, id),
+            start: Time(i64::from(id) * 100),
+            wall_duration_90k: 100,
+            sample_file_bytes: 4096,
+            sample_file_dir_id,
+        }
+    }
+
+    fn policy(archive: bool, fs_bytes_needed: i64, archive_fs_bytes_needed: i64) -> Policy {
+        Policy {
+            fs_bytes_needed,
+            fs_bytes_to_delete: 0,
+            archive,
+            archive_fs_bytes_needed,
+            archive_fs_bytes_to_delete: 0,
+            cutoff: None,
+            event_cutoff: None,
+            events: Events::default(),
+        }
+    }
+
+    #[test]
+    fn archive() {
+        testutil::init();
+        let rows = [row(0, Some(2)), row(1, None), row(2, None)];
+
+        // Over the stream's own limit: archive the oldest unarchived recording, skipping over
+        // the already-archived one.
+        let mut p = policy(true, 1, 0);
+        let decisions: Vec<_> = rows.iter().map(|r| p.decide(r)).collect();
+        assert_eq!(
+            decisions,
+            [Deletion::Keep, Deletion::Archive, Deletion::Stop]
+        );
+
+        // Archiving that recording put the archive over its limit, so next time the oldest
+        // archived recording goes.
+        let mut p = policy(true, 0, 4096);
+        assert_eq!(p.decide(&rows[0]), Deletion::Delete);
+        assert_eq!(p.decide(&rows[1]), Deletion::Stop);
+
+        // Without an archive directory, recordings are simply deleted.
+        let mut p = policy(false, 1, 0);
+        assert_eq!(p.decide(&rows[1]), Deletion::Delete);
+        assert_eq!(p.decide(&rows[2]), Deletion::Stop);
+    }
+
+    #[test]
+    fn events() {
+        testutil::init();
+        let e = Events::new(vec![
+            Time(30)..Time(40),
+            Time(10)..Time(20),
+            Time(15)..Time(25),
+            Time(25)..Time(26),
+        ]);
+        assert_eq!(e, Events(vec![Time(10)..Time(26), Time(30)..Time(40)]));
+        assert!(!e.overlaps(Time(0)..Time(10)));
+        assert!(e.overlaps(Time(0)..Time(11)));
+        assert!(e.overlaps(Time(20)..Time(21)));
+        assert!(!e.overlaps(Time(26)..Time(30)));
+        assert!(e.overlaps(Time(39)..Time(50)));
+        assert!(!e.overlaps(Time(40)..Time(50)));
+        assert!(!Events::default().overlaps(Time(0)..Time(50)));
+    }
+
+    #[test]
+    fn event_range_is_limited_to_candidates() {
+        testutil::init();
+        let stream = Time(0)..Time(100 * MAX_RECORDING_WALL_DURATION);
+        let early = Time(10 * MAX_RECORDING_WALL_DURATION);
+        let late = Time(20 * MAX_RECORDING_WALL_DURATION);
+
+        // Events matter only in between the cutoffs, including recordings which cross the
+        // earlier one.
+        assert_eq!(
+            event_range(&stream, Some(late), Some(early)),
+            Some(Time(9 * MAX_RECORDING_WALL_DURATION)..late)
+        );
+        assert_eq!(
+            event_range(&stream, Some(early), Some(late)),
+            Some(Time(9 * MAX_RECORDING_WALL_DURATION)..late)
+        );
+
+        // Without an earlier cutoff, events matter from the beginning of the stream.
+        assert_eq!(
+            event_range(&stream, None, Some(late)),
+            Some(stream.start..late)
+        );
+
+        // The range is clipped to the stream's recordings.
+        assert_eq!(
+            event_range(
+                &(Time(15 * MAX_RECORDING_WALL_DURATION)..stream.end),
+                Some(late),
+                Some(early)
+            ),
+            Some(Time(15 * MAX_RECORDING_WALL_DURATION)..late)
+        );
+        assert_eq!(
+            event_range(&(late..stream.end), Some(late), Some(early)),
+            None
+        );
+
+        // Events don't matter if both cutoffs are the same.
+        assert_eq!(event_range(&stream, Some(late), Some(late)), None);
+        assert_eq!(event_range(&stream, None, None), None);
+    }
+}
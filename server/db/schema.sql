@@ -133,6 +133,11 @@ create table recording (
   -- * 1, or "trailing zero", indicates that this recording is the last in a
   --   stream. As the duration of a sample is not known until the next sample
   --   is received, the final sample in this recording will have duration 0.
+  -- * 2, or "encrypted", indicates that the sample file is encrypted with the
+  --   key in recording_playback.wrapped_key.
+  -- * 4, or "corrupt", indicates that `moonfire-nvr check --scrub` found the
+  --   sample file's contents don't match
+  --   recording_integrity.sample_file_blake3.
   flags integer not null,
 
   sample_file_bytes integer not null check (sample_file_bytes > 0),
@@ -168,7 +173,21 @@ create table recording (
 
   -- The reason this run ended. Absent if there are more recordings in this
   -- run or if this recording predates schema version 7.
-  end_reason text
+  end_reason text,
+
+  -- Audio samples, if any. These are stored in the sample file after all the
+  -- video samples, so the video samples occupy the first
+  -- sample_file_bytes - audio_sample_file_bytes bytes. See
+  -- recording_playback.audio_index for details.
+  audio_sample_entry_id integer references audio_sample_entry (id),
+  audio_samples integer not null default 0 check (audio_samples >= 0),
+  audio_sample_file_bytes integer not null default 0
+      check (audio_sample_file_bytes >= 0),
+
+  -- The archive directory holding this recording's sample file, if it has
+  -- been moved from the stream's own directory. See
+  -- json.SampleFileDirConfig.archive.
+  sample_file_dir_id integer references sample_file_dir (id)
 
   check (composite_id >> 32 = stream_id)
 );
@@ -189,7 +208,11 @@ create index recording_cover on recording (
   video_sample_entry_id,
   sample_file_bytes,
   run_offset,
-  flags
+  flags,
+  audio_sample_entry_id,
+  audio_samples,
+  audio_sample_file_bytes,
+  sample_file_dir_id
 );
 
 -- Fields which are only needed to check/correct database integrity problems
@@ -240,9 +263,16 @@ create table recording_playback (
   composite_id integer primary key references recording (composite_id),
 
   -- See design/schema.md#video_index for a description of this field.
-  video_index blob not null check (length(video_index) > 0)
+  video_index blob not null check (length(video_index) > 0),
 
-  -- audio_index could be added here in the future.
+  -- See design/schema.md#audio_index for a description of this field.
+  -- Present iff recording.audio_sample_entry_id is non-null.
+  audio_index blob,
+
+  -- The key to the sample file, encrypted with the master sample file key.
+  -- Present iff the "encrypted" flag is set on the recording. See
+  -- server/db/dir/crypto.rs for the format.
+  wrapped_key blob
 );
 
 -- Files which are to be deleted (may or may not still exist).
@@ -272,11 +302,11 @@ create table video_sample_entry (
   width integer not null check (width > 0),
   height integer not null check (height > 0),
 
-  -- The codec in RFC-6381 format, such as "avc1.4d001f".
+  -- The codec in RFC-6381 format, such as "avc1.4d001f" or "hvc1.1.6.L153.B0".
   rfc6381_codec text not null,
 
-  -- The serialized box, including the leading length and box type (avcC in
-  -- the case of H.264).
+  -- The serialized box, including the leading length and box type (avc1 in
+  -- the case of H.264, hvc1 in the case of H.265).
   data blob not null check (length(data) > 86),
 
   -- Pixel aspect ratio, if known. As defined in ISO/IEC 14496-12 section
@@ -285,6 +315,134 @@ create table video_sample_entry (
   pasp_v_spacing integer not null default 1 check (pasp_v_spacing > 0)
 );
 
+-- A concrete box derived from a ISO/IEC 14496-12 section 12.2.3
+-- AudioSampleEntry box. Describes the codec, sample rate, etc.
+create table audio_sample_entry (
+  id integer primary key,
+
+  -- The codec in RFC-6381 format, such as "mp4a.40.2".
+  rfc6381_codec text not null,
+
+  -- The sampling rate in Hz, which is also the timescale of durations in
+  -- recording_playback.audio_index.
+  sample_rate integer not null check (sample_rate > 0),
+
+  channels integer not null check (channels > 0),
+
+  -- The serialized box, including the leading length and box type (mp4a in
+  -- the case of AAC).
+  data blob not null check (length(data) > 36)
+);
+
+-- Low-resolution still images for previewing recordings, such as when
+-- hovering over the timeline. These are decoded from key frames periodically
+-- as recordings are written and deleted along with the oldest recordings.
+create table thumbnail (
+  stream_id integer not null references stream (id),
+
+  -- The wall time at which the key frame was received, which is approximately
+  -- its time within the recording.
+  start_time_90k integer not null,
+
+  width integer not null check (width > 0),
+  height integer not null check (height > 0),
+
+  -- The JPEG-encoded image.
+  data blob not null,
+
+  primary key (stream_id, start_time_90k)
+);
+
+-- Objects found in recorded video by the optional object detection
+-- analytics. Like thumbnails, these are deleted along with the oldest
+-- recordings.
+create table detection (
+  id integer primary key,
+  stream_id integer not null references stream (id),
+
+  -- The wall time at which the analyzed frame was received.
+  time_90k integer not null,
+
+  -- The label of the object's class, such as `person` or `car`.
+  class text not null,
+
+  -- The model's confidence in the detection.
+  score real not null check (score >= 0 and score <= 1),
+
+  -- The object's bounding box, in fractions of the frame's width and height
+  -- from its top-left corner.
+  box_left real not null,
+  box_top real not null,
+  box_width real not null,
+  box_height real not null
+);
+create index detection_stream_time on detection (stream_id, time_90k);
+
+-- Recordings copied to S3-compatible cloud storage by the optional uploader.
+-- Rows outlive the recordings they describe, as a record of what the bucket
+-- holds, but are deleted along with their stream.
+create table upload (
+  -- See description on recording table. There's deliberately no foreign key
+  -- constraint, as the recording may have since been deleted.
+  composite_id integer primary key,
+
+  -- The key of the sample file's object within the bucket. The index
+  -- manifest's key is the same with a `.json` suffix.
+  object_key text not null,
+
+  -- The size and SHA-256 hash of the uploaded sample file.
+  sample_file_bytes integer not null check (sample_file_bytes > 0),
+  sample_file_sha256 blob not null check (length(sample_file_sha256) = 32),
+
+  -- When the objects were uploaded, and when they were verified to be
+  -- present with the expected size (or null if not yet verified), both in
+  -- 90 kHz units since 1970-01-01 00:00:00Z excluding leap seconds.
+  upload_time_90k integer not null,
+  verify_time_90k integer
+);
+
+-- Recordings copied to a remote host by the optional SFTP replication. As
+-- with upload, rows outlive the recordings they describe but are deleted
+-- along with their stream.
+create table replication (
+  -- See description on recording table.
+  composite_id integer primary key,
+
+  -- The sample file's path on the remote host.
+  remote_path text not null,
+
+  -- When the copy completed, in 90 kHz units since 1970-01-01 00:00:00Z
+  -- excluding leap seconds.
+  replicate_time_90k integer not null
+);
+
+-- Daily totals describing the health of each stream's ingest, for finding
+-- flaky cameras or cables. Rows are added to as the day goes on, kept for a
+-- limited number of days, and deleted along with their stream.
+create table stream_health (
+  stream_id integer not null references stream (id),
+
+  -- The calendar day in the server's local time zone, as `YYYY-mm-dd`.
+  day text not null check (length(day) = 10),
+
+  -- Seconds during which a streamer was running for the stream, and the
+  -- subset of those during which it was receiving frames.
+  monitored_sec integer not null check (monitored_sec >= 0),
+  up_sec integer not null check (up_sec >= 0 and up_sec <= monitored_sec),
+
+  -- Times the stream was reopened after an error.
+  reconnects integer not null check (reconnects >= 0),
+
+  -- Times a connected stream went unusually long between video frames.
+  frame_gaps integer not null check (frame_gaps >= 0),
+
+  -- Video frames received, and RTP packets known to be lost.
+  frames integer not null check (frames >= 0),
+  packets_lost integer not null check (packets_lost >= 0),
+
+  primary key (stream_id, day)
+) without rowid;
+
 create table user (
   id integer primary key,
   username unique not null,
@@ -363,9 +521,9 @@ create table user_session (
   -- text detail. Enumeration values:
   -- 1: logout link clicked (i.e. from within the session itself)
   -- 2: obsoleted by a change in hashing algorithm (eg schema 5->6 upgrade)
+  -- 3: revoked from another session or by an administrator
   --
   -- This might be extended for a variety of other reasons:
-  -- x: user revoked (while authenticated in another way)
   -- x: password change invalidated all sessions created with that password
   -- x: expired (due to fixed total time or time inactive)
   -- x: evicted (due to too many sessions)
@@ -385,6 +543,101 @@ create table user_session (
 
 create index user_session_uid on user_session (user_id);
 
+-- A long-lived API token, for scripts and integrations. These are presented
+-- via the HTTP `Authorization: Bearer` header rather than a cookie, so unlike
+-- sessions they need no CSRF protection. Revoking a token deletes its row.
+create table api_token (
+  id integer primary key,
+
+  -- The unsalted Blake3 of the unencoded 32-byte token, truncated to 24 bytes.
+  -- As with `user_session.session_id_hash`, the token itself isn't stored.
+  token_hash blob unique not null check (length(token_hash) = 24),
+
+  user_id integer references user (id) not null,
+
+  -- An editable description, such as "Home Assistant".
+  description text,
+
+  creation_time_sec integer not null,  -- sec since epoch
+
+  -- If set, the token is rejected at or after this time. Sec since epoch.
+  expiration_time_sec integer,
+
+  -- Information about requests which used this token, updated lazily on database flush.
+  last_use_time_sec integer,           -- sec since epoch
+  last_use_user_agent text,            -- User-Agent header from inbound HTTP request.
+  last_use_peer_addr blob,             -- IPv4 or IPv6 address, or null for Unix socket.
+  use_count not null default 0,
+
+  -- Permissions associated with this token; a serialized "Permissions" protobuf.
+  -- These are a subset of the user's when the token is created.
+  permissions blob not null default X''
+);
+
+create index api_token_uid on api_token (user_id);
+
+-- A WebAuthn credential (passkey) with which a user can log in instead of
+-- using a password.
+create table user_credential (
+  id integer primary key,
+  user_id integer references user (id) not null,
+
+  -- The credential id chosen by the authenticator.
+  credential_id blob unique not null,
+
+  -- An editable description, such as "YubiKey" or "iPhone".
+  description text,
+
+  creation_time_sec integer not null,  -- sec since epoch
+  last_use_time_sec integer,           -- sec since epoch
+
+  -- The credential's public key, signature counter, and other state needed to
+  -- verify logins, as JSON. The format is defined by the WebAuthn library in
+  -- use, and is updated on each login.
+  passkey text not null
+);
+
+create index user_credential_uid on user_credential (user_id);
+
+-- An append-only log of security-relevant actions, such as logins and
+-- configuration changes. Written only when the audit log is enabled in the
+-- configuration file. The triggers below reject any modification of existing
+-- entries.
+create table audit (
+  id integer primary key,
+  time_sec integer not null,           -- sec since epoch
+
+  -- The user who performed the action, if known. There's deliberately no
+  -- foreign key constraint, so entries outlive deleted users. The username is
+  -- recorded too, for the same reason and for failed logins of unknown users.
+  user_id integer,
+  username text,
+
+  peer_addr blob,                      -- IPv4 or IPv6 address, or null for Unix socket.
+  user_agent text,                     -- User-Agent header from inbound HTTP request.
+
+  -- The kind of action, such as "login" or "config_change".
+  action text not null,
+
+  -- 1 if the action succeeded; 0 if it failed (e.g. a bad password).
+  success integer not null check (success in (0, 1)),
+
+  -- Free-form detail, such as the request method and path.
+  detail text
+);
+
+create index audit_time on audit (time_sec);
+
+create trigger audit_no_update before update on audit
+begin
+  select raise(abort, 'audit log is append-only');
+end;
+
+create trigger audit_no_delete before delete on audit
+begin
+  select raise(abort, 'audit log is append-only');
+end;
+
 -- Timeseries with an enum value, eg:
 -- *   camera motion detection results (unknown, still, moving)
 -- *   security system arm status (unknown, disarmed, away, stay)
@@ -424,4 +677,4 @@ create table signal_change (
 );
 
 insert into version (id, unix_time,                           notes)
-             values (7,  cast(strftime('%s', 'now') as int), 'db creation');
+             values (18, cast(strftime('%s', 'now') as int), 'db creation');
@@ -5,7 +5,7 @@
 //! Schema for "signals": enum-valued timeserieses.
 //! See the `signal` table within `schema.sql` for more information.
 
-use crate::json::{SignalConfig, SignalTypeConfig};
+use crate::json::{SignalConfig, SignalMqttRule, SignalTypeConfig};
 use crate::{coding, days};
 use crate::{recording, SqlUuid};
 use base::FastHashMap;
@@ -277,6 +277,35 @@ impl State {
         Ok(())
     }
 
+    /// Records a sequence of state changes of `signal`, as reported by a client such as an alarm
+    /// system. Each state holds from its time until the next change's, and the last until `end`.
+    ///
+    /// All changes are validated before any is applied.
+    pub fn update_signal_changes(
+        &mut self,
+        signal: u32,
+        changes: &[(recording::Time, u16)],
+        end: recording::Time,
+    ) -> Result<(), base::Error> {
+        let ends = changes
+            .iter()
+            .skip(1)
+            .map(|&(when, _)| when)
+            .chain(std::iter::once(end));
+        let mut ranges = Vec::with_capacity(changes.len());
+        for (&(start, state), end) in changes.iter().zip(ends) {
+            self.update_signals_validate(&[signal], &[state])?;
+            if end < start {
+                bail!(InvalidArgument, msg("signal changes must be in time order"));
+            }
+            ranges.push((start..end, state));
+        }
+        for (when, state) in ranges {
+            self.update_signals(when, &[signal], &[state])?;
+        }
+        Ok(())
+    }
+
     /// Performs garbage collection if the number of points exceeds `max_signal_changes`.
     fn gc(&mut self) {
         let max = match self.max_signal_changes {
@@ -705,28 +734,52 @@ impl State {
         let mut rows = stmt.query(params![])?;
         while let Some(row) = rows.next()? {
             let uuid: SqlUuid = row.get(0)?;
-            let mut type_ = Type {
-                valid_states: 1, // bit 0 (unknown state) is always valid.
-                config: row.get(1)?,
-            };
-            for &value in type_.config.values.keys() {
-                if value == 0 || value >= 16 {
-                    bail!(
-                        OutOfRange,
-                        msg(
-                            "signal type {} value {} out of accepted range [0, 16)",
-                            uuid.0,
-                            value,
-                        ),
-                    );
-                }
-                type_.valid_states |= 1 << value;
-            }
-            types.insert(uuid.0, type_);
+            types.insert(uuid.0, Type::new(uuid.0, row.get(1)?)?);
         }
         Ok(types)
     }
 
+    /// Creates or replaces the signal type `uuid`.
+    pub fn put_type(
+        &mut self,
+        conn: &Connection,
+        uuid: Uuid,
+        config: SignalTypeConfig,
+    ) -> Result<(), Error> {
+        let type_ = Type::new(uuid, config)?;
+        let n = conn.execute(
+            "update signal_type set config = ? where uuid = ?",
+            params![&type_.config, SqlUuid(uuid)],
+        )?;
+        if n == 0 {
+            conn.execute(
+                "insert into signal_type (uuid, config) values (?, ?)",
+                params![SqlUuid(uuid), &type_.config],
+            )?;
+        }
+        self.types_by_uuid.insert(uuid, type_);
+        Ok(())
+    }
+
+    /// Deletes the signal type `uuid`, which must not be used by any signal.
+    pub fn delete_type(&mut self, conn: &Connection, uuid: Uuid) -> Result<(), Error> {
+        if !self.types_by_uuid.contains_key(&uuid) {
+            bail!(NotFound, msg("no such signal type {uuid}"));
+        }
+        if let Some(s) = self.signals_by_id.values().find(|s| s.type_ == uuid) {
+            bail!(
+                FailedPrecondition,
+                msg("signal type {uuid} is used by signal {}", s.id)
+            );
+        }
+        conn.execute(
+            "delete from signal_type where uuid = ?",
+            params![SqlUuid(uuid)],
+        )?;
+        self.types_by_uuid.remove(&uuid);
+        Ok(())
+    }
+
     /// Fills `points_by_time` from the database, also filling the `days`
     /// index of each signal.
     fn fill_points(
@@ -794,6 +847,66 @@ impl State {
         &self.types_by_uuid
     }
 
+    /// Returns the MQTT rules of all signals.
+    pub fn mqtt_rules(&self) -> MqttRules {
+        MqttRules::from_signal_configs(self.signals_by_id.iter().map(|(&id, s)| (id, &s.config)))
+    }
+
+    /// Returns true if any signal directly associated with the given camera is, as of `when`,
+    /// in a state which its type marks as `motion`.
+    pub fn camera_motion_at(&self, camera_id: i32, when: recording::Time) -> bool {
+        let Some((_, p)) = self.points_by_time.range(..=when).next_back() else {
+            return false;
+        };
+        self.is_camera_motion(camera_id, &p.after())
+    }
+
+    /// Returns the disjoint, ascending time ranges within `range` during which
+    /// [`State::camera_motion_at`] would return true.
+    pub fn camera_motion_ranges(
+        &self,
+        camera_id: i32,
+        range: Range<recording::Time>,
+    ) -> Vec<Range<recording::Time>> {
+        let mut out = Vec::new();
+        let mut motion_start = self
+            .points_by_time
+            .range(..range.start)
+            .next_back()
+            .filter(|(_, p)| self.is_camera_motion(camera_id, &p.after()))
+            .map(|_| range.start);
+        for (&t, p) in self.points_by_time.range(range.clone()) {
+            match (motion_start, self.is_camera_motion(camera_id, &p.after())) {
+                (None, true) => motion_start = Some(t),
+                (Some(s), false) => {
+                    out.push(s..t);
+                    motion_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = motion_start {
+            out.push(s..range.end);
+        }
+        out
+    }
+
+    /// Returns true if `states` has any signal directly associated with the given camera in a
+    /// motion state.
+    fn is_camera_motion(&self, camera_id: i32, states: &BTreeMap<u32, u16>) -> bool {
+        self.signals_by_id.values().any(|s| {
+            s.config
+                .camera_associations
+                .get(&camera_id)
+                .map(String::as_str)
+                == Some("direct")
+                && states
+                    .get(&s.id)
+                    .and_then(|state| self.types_by_uuid.get(&s.type_)?.config.values.get(state))
+                    .map_or(false, |v| v.motion)
+        })
+    }
+
     #[cfg(not(debug_assertions))]
     fn debug_assert_point_invariants(&self) {}
 
@@ -830,6 +943,110 @@ pub struct Type {
     pub config: SignalTypeConfig,
 }
 
+impl Type {
+    /// Validates `config`, the configuration of signal type `uuid`.
+    fn new(uuid: Uuid, config: SignalTypeConfig) -> Result<Self, Error> {
+        let mut valid_states = 1; // bit 0 (unknown state) is always valid.
+        for &value in config.values.keys() {
+            if value == 0 || value >= 16 {
+                bail!(
+                    OutOfRange,
+                    msg("signal type {uuid} value {value} out of accepted range [0, 16)"),
+                );
+            }
+            valid_states |= 1 << value;
+        }
+        Ok(Type {
+            valid_states,
+            config,
+        })
+    }
+}
+
+/// Rules mapping MQTT messages to signal states, from each signal's [`SignalConfig::mqtt`].
+#[derive(Debug, Default)]
+pub struct MqttRules {
+    /// `(signal id, rule)` pairs, ordered by signal id.
+    rules: Vec<(u32, SignalMqttRule)>,
+}
+
+impl MqttRules {
+    pub fn from_signal_configs<'a>(
+        configs: impl IntoIterator<Item = (u32, &'a SignalConfig)>,
+    ) -> Self {
+        let mut rules: Vec<_> = configs
+            .into_iter()
+            .flat_map(|(id, c)| c.mqtt.iter().map(move |r| (id, r.clone())))
+            .collect();
+        rules.sort_by_key(|&(id, _)| id); // stable, so each signal's rules stay in order.
+        MqttRules { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the distinct topic filters to subscribe to.
+    pub fn topics(&self) -> BTreeSet<&str> {
+        self.rules.iter().map(|(_, r)| r.topic.as_str()).collect()
+    }
+
+    /// Returns the `(signal, state)` pairs set by the given message, ordered by signal id.
+    /// If several rules of a signal match, the last one wins.
+    pub fn matches(&self, topic: &str, payload: &[u8]) -> Vec<(u32, u16)> {
+        let Ok(payload) = std::str::from_utf8(payload) else {
+            return Vec::new();
+        };
+        let mut json: Option<Option<serde_json::Value>> = None;
+        let mut matches: Vec<(u32, u16)> = Vec::new();
+        for (signal, rule) in &self.rules {
+            if !topic_matches(&rule.topic, topic) {
+                continue;
+            }
+            let state = if rule.json_pointer.is_empty() {
+                rule.payloads.get(payload)
+            } else {
+                let json = json.get_or_insert_with(|| serde_json::from_str(payload).ok());
+                match json.as_ref().and_then(|j| j.pointer(&rule.json_pointer)) {
+                    Some(serde_json::Value::String(v)) => rule.payloads.get(v),
+                    Some(v) => rule.payloads.get(&v.to_string()),
+                    None => None,
+                }
+            };
+            let Some(&state) = state else {
+                continue;
+            };
+            match matches.last_mut() {
+                Some((s, st)) if s == signal => *st = state,
+                _ => matches.push((*signal, state)),
+            }
+        }
+        matches
+    }
+}
+
+/// Returns true if `topic` matches the MQTT topic filter `filter`, which may contain the
+/// wildcards `+` (a single level) and `#` (all remaining levels).
+///
+/// As in the MQTT specification, wildcards at the first level don't match topics starting
+/// with `$`, such as `$SYS/broker/uptime`.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for f in filter.split('/') {
+        if f == "#" {
+            return true;
+        }
+        match levels.next() {
+            Some(l) if f == "+" || f == l => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1032,4 +1249,258 @@ mod tests {
         );
         assert_eq!(&rows[..], EXPECTED2);
     }
+
+    #[test]
+    fn camera_motion_at() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut type_config = SignalTypeConfig::default();
+        type_config.values.insert(
+            1,
+            SignalTypeValueConfig {
+                name: "still".to_owned(),
+                ..Default::default()
+            },
+        );
+        type_config.values.insert(
+            2,
+            SignalTypeValueConfig {
+                name: "moving".to_owned(),
+                motion: true,
+                ..Default::default()
+            },
+        );
+        conn.execute(
+            "insert into signal_type (uuid, config) values (?, ?)",
+            params![
+                SqlUuid(Uuid::parse_str("ee66270f-d9c6-4819-8b33-9720d4cbca6b").unwrap()),
+                &type_config,
+            ],
+        )
+        .unwrap();
+        conn.execute_batch(
+            r#"
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"cameraAssociations": {"1": "direct", "2": "indirect"}}');
+            "#,
+        )
+        .unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        const START: recording::Time = recording::Time(140067462600000); // 2019-04-26T11:59:00
+        const NOW: recording::Time = recording::Time(140067468000000); // 2019-04-26T12:00:00
+        const SOON: recording::Time = recording::Time(140067473400000); // 2019-04-26T12:01:00
+        assert!(!s.camera_motion_at(1, START));
+        s.update_signals(START..NOW, &[1], &[2]).unwrap();
+        s.update_signals(NOW..SOON, &[1], &[1]).unwrap();
+        assert!(!s.camera_motion_at(1, START - recording::Duration(1)));
+        assert!(s.camera_motion_at(1, START));
+        assert!(s.camera_motion_at(1, NOW - recording::Duration(1)));
+        assert!(!s.camera_motion_at(1, NOW));
+        assert!(!s.camera_motion_at(1, SOON));
+        assert!(
+            !s.camera_motion_at(2, START),
+            "indirect associations don't count"
+        );
+        assert!(!s.camera_motion_at(3, START));
+        let later = SOON + recording::Duration(1);
+        assert_eq!(s.camera_motion_ranges(1, START..later), vec![START..NOW]);
+        assert_eq!(
+            s.camera_motion_ranges(1, START + recording::Duration(1)..later),
+            vec![START + recording::Duration(1)..NOW]
+        );
+        assert_eq!(
+            s.camera_motion_ranges(1, START..START + recording::Duration(1)),
+            vec![START..START + recording::Duration(1)]
+        );
+        assert!(s.camera_motion_ranges(1, NOW..later).is_empty());
+        assert!(s.camera_motion_ranges(2, START..later).is_empty());
+    }
+
+    #[test]
+    fn update_signal_changes() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        let mut type_config = SignalTypeConfig::default();
+        for value in [1, 2] {
+            type_config
+                .values
+                .insert(value, SignalTypeValueConfig::default());
+        }
+        let type_uuid = Uuid::parse_str("ee66270f-d9c6-4819-8b33-9720d4cbca6b").unwrap();
+        s.put_type(&conn, type_uuid, type_config).unwrap();
+        conn.execute_batch(
+            r#"
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B', '{}');
+            "#,
+        )
+        .unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        const START: recording::Time = recording::Time(140067462600000); // 2019-04-26T11:59:00
+        const NOW: recording::Time = recording::Time(140067468000000); // 2019-04-26T12:00:00
+        const SOON: recording::Time = recording::Time(140067473400000); // 2019-04-26T12:01:00
+        let list = |s: &State| {
+            let mut rows = Vec::new();
+            s.list_changes_by_time(
+                recording::Time::min_value()..recording::Time::max_value(),
+                &mut |r: &ListStateChangesRow| rows.push((r.when, r.state)),
+            );
+            rows
+        };
+
+        // Invalid changes are rejected without applying any.
+        assert_eq!(
+            s.update_signal_changes(1, &[(START, 2), (NOW, 3)], SOON)
+                .unwrap_err()
+                .kind(),
+            base::ErrorKind::FailedPrecondition
+        );
+        assert_eq!(
+            s.update_signal_changes(1, &[(NOW, 2), (START, 1)], SOON)
+                .unwrap_err()
+                .kind(),
+            base::ErrorKind::InvalidArgument
+        );
+        assert_eq!(list(&s), []);
+
+        s.update_signal_changes(1, &[(START, 2), (NOW, 1)], SOON)
+            .unwrap();
+        assert_eq!(list(&s), [(START, 2), (NOW, 1), (SOON, 0)]);
+    }
+
+    #[test]
+    fn topic_matches() {
+        assert!(super::topic_matches("a/b", "a/b"));
+        assert!(!super::topic_matches("a/b", "a/b/c"));
+        assert!(!super::topic_matches("a/b/c", "a/b"));
+        assert!(super::topic_matches("a/+/c", "a/b/c"));
+        assert!(!super::topic_matches("a/+", "a/b/c"));
+        assert!(super::topic_matches("a/#", "a"));
+        assert!(super::topic_matches("a/#", "a/b/c"));
+        assert!(super::topic_matches("#", "a/b"));
+        assert!(!super::topic_matches("#", "$SYS/uptime"));
+        assert!(super::topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn mqtt_rules() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        conn.execute_batch(
+            r#"
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"mqtt": [{"topic": "zigbee2mqtt/front_door",
+                                            "jsonPointer": "/contact",
+                                            "payloads": {"true": 1, "false": 2}}]}'),
+                               (2, x'A4A73D9A53424EBCB9F6366F1E5617FA',
+                                x'EE66270FD9C648198B339720D4CBCA6B',
+                                '{"mqtt": [{"topic": "frigate/+/person",
+                                            "payloads": {"0": 1, "1": 2}},
+                                           {"topic": "frigate/driveway/person",
+                                            "payloads": {"2": 2}}]}');
+            "#,
+        )
+        .unwrap();
+        let s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        let rules = s.mqtt_rules();
+        assert_eq!(
+            rules.topics().into_iter().collect::<Vec<_>>(),
+            [
+                "frigate/+/person",
+                "frigate/driveway/person",
+                "zigbee2mqtt/front_door"
+            ]
+        );
+        assert_eq!(
+            rules.matches(
+                "zigbee2mqtt/front_door",
+                br#"{"battery": 100, "contact": false}"#
+            ),
+            [(1, 2)]
+        );
+        assert_eq!(rules.matches("zigbee2mqtt/front_door", b"not json"), []);
+        assert_eq!(rules.matches("frigate/driveway/person", b"1"), [(2, 2)]);
+        assert_eq!(rules.matches("frigate/driveway/person", b"2"), [(2, 2)]);
+        assert_eq!(rules.matches("frigate/porch/person", b"0"), [(2, 1)]);
+        assert_eq!(rules.matches("frigate/porch/person", b"2"), []);
+        assert_eq!(rules.matches("frigate/porch/car", b"1"), []);
+    }
+
+    #[test]
+    fn put_and_delete_types() {
+        testutil::init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        let uuid = Uuid::parse_str("ee66270f-d9c6-4819-8b33-9720d4cbca6b").unwrap();
+        let mut config = SignalTypeConfig {
+            name: "motion".to_owned(),
+            color: "#888888".to_owned(),
+            ..Default::default()
+        };
+        config.values.insert(
+            1,
+            SignalTypeValueConfig {
+                name: "still".to_owned(),
+                ..Default::default()
+            },
+        );
+        s.put_type(&conn, uuid, config.clone()).unwrap();
+        config.values.insert(
+            2,
+            SignalTypeValueConfig {
+                name: "moving".to_owned(),
+                motion: true,
+                color: "#ff0000".to_owned(),
+                ..Default::default()
+            },
+        );
+        s.put_type(&conn, uuid, config.clone()).unwrap();
+        assert_eq!(s.types_by_uuid()[&uuid].valid_states, 0b111);
+
+        let mut bad = config.clone();
+        bad.values.insert(16, SignalTypeValueConfig::default());
+        assert_eq!(
+            s.put_type(&conn, uuid, bad).unwrap_err().kind(),
+            base::ErrorKind::OutOfRange
+        );
+
+        // Changes should be persisted.
+        let s2 = State::init(&conn, &GlobalConfig::default()).unwrap();
+        assert_eq!(s2.types_by_uuid()[&uuid].config, config);
+
+        // A type can't be deleted while in use.
+        conn.execute_batch(
+            r#"
+            insert into signal (id, uuid, type_uuid, config)
+                        values (1, x'1B3889C0A59F400DA24C94EBEB19CC3A',
+                                x'EE66270FD9C648198B339720D4CBCA6B', '{}');
+            "#,
+        )
+        .unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        assert_eq!(
+            s.delete_type(&conn, uuid).unwrap_err().kind(),
+            base::ErrorKind::FailedPrecondition
+        );
+        conn.execute("delete from signal", params![]).unwrap();
+        let mut s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        s.delete_type(&conn, uuid).unwrap();
+        assert!(s.types_by_uuid().is_empty());
+        assert_eq!(
+            s.delete_type(&conn, uuid).unwrap_err().kind(),
+            base::ErrorKind::NotFound
+        );
+        let s = State::init(&conn, &GlobalConfig::default()).unwrap();
+        assert!(s.types_by_uuid().is_empty());
+    }
 }
@@ -0,0 +1,230 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Database statistics, as reported by `moonfire-nvr db-stats`.
+//!
+//! Sizes come from SQLite's [`dbstat`](https://www.sqlite.org/dbstat.html) virtual table, which
+//! is only available if SQLite was compiled with `SQLITE_ENABLE_DBSTAT_VTAB` (as it is with
+//! `--features=bundled`). Without it, everything but sizes is still reported.
+
+use base::{err, Error};
+use rusqlite::params;
+use tracing::warn;
+
+pub struct Stats {
+    pub page_size: i64,
+    pub page_count: i64,
+
+    /// The number of unused pages, which `vacuum` would reclaim.
+    pub freelist_count: i64,
+
+    /// The size of the write-ahead log, or `None` if unknown (as for an in-memory database).
+    pub wal_bytes: Option<u64>,
+
+    /// Tables, ordered by name.
+    pub tables: Vec<Table>,
+
+    /// Indices, including those SQLite creates for unique constraints, ordered by name.
+    pub indices: Vec<Index>,
+
+    /// Streams, ordered by camera short name and stream type.
+    pub streams: Vec<Stream>,
+}
+
+/// The space used by a table or index, from `dbstat`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Size {
+    /// Total bytes of the pages holding this b-tree.
+    pub bytes: i64,
+
+    /// Bytes within those pages which are unused, a measure of fragmentation.
+    pub unused_bytes: i64,
+}
+
+pub struct Table {
+    pub name: String,
+    pub rows: i64,
+    pub size: Option<Size>,
+}
+
+pub struct Index {
+    pub name: String,
+    pub table: String,
+    pub size: Option<Size>,
+}
+
+pub struct Stream {
+    pub id: i32,
+    pub camera: String,
+    pub type_: String,
+    pub recordings: i64,
+    pub sample_file_bytes: i64,
+    pub wall_duration_90k: i64,
+}
+
+impl Stats {
+    /// Returns the fraction of the database file which is on the freelist.
+    pub fn free_fraction(&self) -> f64 {
+        if self.page_count == 0 {
+            return 0.;
+        }
+        self.freelist_count as f64 / self.page_count as f64
+    }
+}
+
+pub fn get(conn: &rusqlite::Connection) -> Result<Stats, Error> {
+    let pragma = |p: &str| conn.pragma_query_value(None, p, |r| r.get::<_, i64>(0));
+    let page_size = pragma("page_size")?;
+    let page_count = pragma("page_count")?;
+    let freelist_count = pragma("freelist_count")?;
+    let wal_bytes = match conn.path() {
+        Some(p) if !p.is_empty() => match std::fs::metadata(format!("{p}-wal")) {
+            Ok(m) => Some(m.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(0),
+            Err(e) => return Err(err!(e, msg("unable to stat {p}-wal"))),
+        },
+        _ => None,
+    };
+
+    let sizes = match get_sizes(conn) {
+        Ok(s) => Some(s),
+        Err(err) => {
+            warn!(%err, "unable to read dbstat; sizes won't be reported");
+            None
+        }
+    };
+    let size = |name: &str| {
+        sizes
+            .as_ref()
+            .map(|s| s.get(name).copied().unwrap_or_default())
+    };
+
+    let mut tables = Vec::new();
+    let mut indices = Vec::new();
+    {
+        // Include indices SQLite creates for unique constraints (`sqlite_autoindex_*`) but not
+        // its internal tables (`sqlite_sequence`, `sqlite_stat1`).
+        let mut stmt = conn.prepare(
+            r#"
+            select type, name, tbl_name from sqlite_master
+            where (type = 'table' and name not like 'sqlite_%') or type = 'index'
+            order by name
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let type_: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            let table: String = row.get(2)?;
+            let size = size(&name);
+            if type_ == "table" {
+                let rows: i64 = conn.query_row(
+                    &format!("select count(*) from \"{}\"", name.replace('"', "\"\"")),
+                    params![],
+                    |r| r.get(0),
+                )?;
+                tables.push(Table { name, rows, size });
+            } else {
+                indices.push(Index { name, table, size });
+            }
+        }
+    }
+
+    let mut streams = Vec::new();
+    {
+        let mut stmt = conn.prepare(
+            r#"
+            select
+              stream.id,
+              camera.short_name,
+              stream.type,
+              count(recording.composite_id),
+              coalesce(sum(recording.sample_file_bytes), 0),
+              coalesce(sum(recording.wall_duration_90k), 0)
+            from
+              stream
+              join camera on (stream.camera_id = camera.id)
+              left join recording on (recording.stream_id = stream.id)
+            group by stream.id
+            order by camera.short_name, stream.type
+            "#,
+        )?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            streams.push(Stream {
+                id: row.get(0)?,
+                camera: row.get(1)?,
+                type_: row.get(2)?,
+                recordings: row.get(3)?,
+                sample_file_bytes: row.get(4)?,
+                wall_duration_90k: row.get(5)?,
+            });
+        }
+    }
+
+    Ok(Stats {
+        page_size,
+        page_count,
+        freelist_count,
+        wal_bytes,
+        tables,
+        indices,
+        streams,
+    })
+}
+
+/// Returns the size of each table and index, by name.
+fn get_sizes(conn: &rusqlite::Connection) -> Result<base::FastHashMap<String, Size>, Error> {
+    let mut stmt =
+        conn.prepare("select name, sum(pgsize), sum(unused) from dbstat group by name")?;
+    let mut rows = stmt.query(params![])?;
+    let mut sizes = base::FastHashMap::default();
+    while let Some(row) = rows.next()? {
+        sizes.insert(
+            row.get(0)?,
+            Size {
+                bytes: row.get(1)?,
+                unused_bytes: row.get(2)?,
+            },
+        );
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn stats() {
+        testutil::init();
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::db::init(&mut conn).unwrap();
+        conn.execute_batch(
+            r#"
+            insert into camera (id, uuid, short_name, config)
+                values (1, x'00000000000000000000000000000001', 'driveway', '{}');
+            insert into stream (id, camera_id, type, config, cum_recordings, cum_media_duration_90k,
+                                cum_runs)
+                values (1, 1, 'main', '{}', 0, 0, 0);
+            "#,
+        )
+        .unwrap();
+        let s = get(&conn).unwrap();
+        assert!(s.page_count > 0);
+        assert_eq!(s.wal_bytes, None);
+        let camera = s.tables.iter().find(|t| t.name == "camera").unwrap();
+        assert_eq!(camera.rows, 1);
+        assert!(s.tables.iter().all(|t| !t.name.starts_with("sqlite_")));
+        assert!(s.indices.iter().any(|i| i.table == "recording"));
+        assert!(s
+            .indices
+            .iter()
+            .any(|i| i.name.starts_with("sqlite_autoindex_stream")));
+        assert_eq!(s.streams.len(), 1);
+        assert_eq!(s.streams[0].camera, "driveway");
+        assert_eq!(s.streams[0].recordings, 0);
+    }
+}
@@ -22,6 +22,9 @@ static INIT: std::sync::Once = std::sync::Once::new();
 pub const TEST_CAMERA_ID: i32 = 1;
 pub const TEST_STREAM_ID: i32 = 1;
 
+/// id of the sample file directory created by `TestDb::new` below.
+pub const TEST_DIR_ID: i32 = 1;
+
 pub const TEST_VIDEO_SAMPLE_ENTRY_DATA: &[u8] =
     b"\x00\x00\x00\x7D\x61\x76\x63\x31\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\
     \x00\x00\x00\x00\x00\x00\x00\x00\x00\x07\x80\x04\x38\x00\x48\x00\x00\x00\x48\x00\x00\x00\x00\
@@ -47,10 +50,10 @@ pub fn init() {
 
 pub struct TestDb<C: Clocks + Clone> {
     pub db: Arc<db::Database<C>>,
-    pub dirs_by_stream_id: Arc<FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+    pub dirs_by_id: Arc<FastHashMap<i32, Arc<dir::SampleFileDir>>>,
     pub shutdown_tx: base::shutdown::Sender,
     pub shutdown_rx: base::shutdown::Receiver,
-    pub syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    pub syncer_channel: writer::SyncerChannel<dir::SampleFile>,
     pub syncer_join: thread::JoinHandle<()>,
     pub tmpdir: TempDir,
     pub test_camera_uuid: Uuid,
@@ -77,6 +80,7 @@ impl<C: Clocks + Clone> TestDb<C> {
         {
             let mut l = db.lock();
             sample_file_dir_id = l.add_sample_file_dir(path).unwrap();
+            assert_eq!(TEST_DIR_ID, sample_file_dir_id);
             assert_eq!(
                 TEST_CAMERA_ID,
                 l.add_camera(db::CameraChange {
@@ -116,14 +120,14 @@ impl<C: Clocks + Clone> TestDb<C> {
                 .get()
                 .unwrap();
         }
-        let mut dirs_by_stream_id = FastHashMap::default();
-        dirs_by_stream_id.insert(TEST_STREAM_ID, dir);
+        let mut dirs_by_id = FastHashMap::default();
+        dirs_by_id.insert(sample_file_dir_id, dir);
         let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
         let (syncer_channel, syncer_join) =
             writer::start_syncer(db.clone(), shutdown_rx.clone(), sample_file_dir_id).unwrap();
         TestDb {
             db,
-            dirs_by_stream_id: Arc::new(dirs_by_stream_id),
+            dirs_by_id: Arc::new(dirs_by_id),
             shutdown_tx,
             shutdown_rx,
             syncer_channel,
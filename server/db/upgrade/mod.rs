@@ -6,8 +6,9 @@
 //!
 //! See `guide/schema.md` for more information.
 
+use crate::compare;
 use crate::db::{self, EXPECTED_SCHEMA_VERSION};
-use base::{bail, Error};
+use base::{bail, err, Error};
 use nix::NixPath;
 use rusqlite::params;
 use std::ffi::CStr;
@@ -16,20 +17,44 @@ use tracing::info;
 use uuid::Uuid;
 
 mod v0_to_v1;
+mod v10_to_v11;
+mod v11_to_v12;
+mod v12_to_v13;
+mod v13_to_v14;
+mod v14_to_v13;
+mod v14_to_v15;
+mod v15_to_v14;
+mod v15_to_v16;
+mod v16_to_v15;
+mod v16_to_v17;
+mod v17_to_v16;
+mod v17_to_v18;
+mod v18_to_v17;
 mod v1_to_v2;
 mod v2_to_v3;
 mod v3_to_v4;
 mod v4_to_v5;
 mod v5_to_v6;
 mod v6_to_v7;
+mod v7_to_v8;
+mod v8_to_v9;
+mod v9_to_v10;
 
 #[derive(Debug)]
 pub struct Args<'a> {
     pub sample_file_dir: Option<&'a std::path::Path>,
     pub preset_journal: &'a str,
     pub no_vacuum: bool,
+
+    /// Upgrades a temporary copy of the database and reports the changes, leaving the database
+    /// itself untouched.
+    pub dry_run: bool,
 }
 
+/// The first schema version from which upgrading doesn't touch the sample file directories, and
+/// so can be tried on a copy of the database.
+const MIN_DRY_RUN_VERSION: i32 = 5;
+
 fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(), Error> {
     assert!(!requested.contains(';')); // quick check for accidental sql injection.
     let actual = conn.query_row(
@@ -44,6 +69,16 @@ fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(),
     Ok(())
 }
 
+/// Returns the number of rows inserted, updated, or deleted since the connection was opened.
+fn total_changes(conn: &rusqlite::Connection) -> i64 {
+    // SAFETY: the handle is valid for the lifetime of `conn`.
+    i64::from(unsafe { rusqlite::ffi::sqlite3_total_changes(conn.handle()) })
+}
+
+fn get_version(conn: &rusqlite::Connection) -> Result<i32, Error> {
+    Ok(conn.query_row("select max(id) from version", params![], |row| row.get(0))?)
+}
+
 fn upgrade(
     args: &Args,
     target_schema_ver: i32,
@@ -58,12 +93,22 @@ fn upgrade(
         v4_to_v5::run,
         v5_to_v6::run,
         v6_to_v7::run,
+        v7_to_v8::run,
+        v8_to_v9::run,
+        v9_to_v10::run,
+        v10_to_v11::run,
+        v11_to_v12::run,
+        v12_to_v13::run,
+        v13_to_v14::run,
+        v14_to_v15::run,
+        v15_to_v16::run,
+        v16_to_v17::run,
+        v17_to_v18::run,
     ];
 
     {
         assert_eq!(upgraders.len(), db::EXPECTED_SCHEMA_VERSION as usize);
-        let old_schema_ver =
-            conn.query_row("select max(id) from version", params![], |row| row.get(0))?;
+        let old_schema_ver = get_version(conn)?;
         if old_schema_ver > EXPECTED_SCHEMA_VERSION {
             bail!(
                 FailedPrecondition,
@@ -86,6 +131,7 @@ fn upgrade(
                 ver,
                 ver + 1
             );
+            let changes_before = total_changes(conn);
             let tx = conn.transaction()?;
             upgraders[ver as usize](args, &tx)?;
             tx.execute(
@@ -96,6 +142,7 @@ fn upgrade(
                 params![ver + 1, format!("Upgraded using moonfire-nvr {sw_version}")],
             )?;
             tx.commit()?;
+            info!("...wrote {} rows", total_changes(conn) - changes_before - 1);
         }
     }
 
@@ -105,6 +152,9 @@ fn upgrade(
 pub fn run(args: &Args, sw_version: &str, conn: &mut rusqlite::Connection) -> Result<(), Error> {
     db::check_sqlite_version()?;
     db::set_integrity_pragmas(conn)?;
+    if args.dry_run {
+        return dry_run(args, sw_version, conn);
+    }
     set_journal_mode(conn, args.preset_journal)?;
     upgrade(args, EXPECTED_SCHEMA_VERSION, sw_version, conn)?;
 
@@ -115,11 +165,15 @@ pub fn run(args: &Args, sw_version: &str, conn: &mut rusqlite::Connection) -> Re
     //   https://www.sqlite.org/pragma.html#pragma_page_size
     // * vacuum is a huge transaction, and on old versions of SQLite3, that's best done in
     //   non-WAL mode. https://www.sqlite.org/wal.html
+    //
+    // Likewise, switching an existing database to incremental auto-vacuum (for scheduled
+    // maintenance; see `LockedDatabase::incremental_vacuum`) only takes effect on a vacuum.
     if !args.no_vacuum {
         info!("...vacuuming database after upgrade.");
         conn.execute_batch(
             r#"
             pragma page_size = 16384;
+            pragma auto_vacuum = incremental;
             vacuum;
             "#,
         )?;
@@ -131,6 +185,104 @@ pub fn run(args: &Args, sw_version: &str, conn: &mut rusqlite::Connection) -> Re
     Ok(())
 }
 
+/// Downgrades the database by one schema version, from [`EXPECTED_SCHEMA_VERSION`].
+///
+/// This allows returning to the previous release without restoring a backup. Only the most
+/// recent migration can be reversed, and only when doing so loses no data; otherwise this fails
+/// without modifying the database.
+pub fn downgrade(conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    let downgraders: [(i32, fn(&rusqlite::Transaction) -> Result<(), Error>); 5] = [
+        (14, v14_to_v13::run),
+        (15, v15_to_v14::run),
+        (16, v16_to_v15::run),
+        (17, v17_to_v16::run),
+        (18, v18_to_v17::run),
+    ];
+
+    db::check_sqlite_version()?;
+    db::set_integrity_pragmas(conn)?;
+    let old_schema_ver = get_version(conn)?;
+    let Some(&(_, downgrader)) = downgraders
+        .iter()
+        .find(|(v, _)| *v == EXPECTED_SCHEMA_VERSION)
+    else {
+        bail!(
+            Unimplemented,
+            msg("no downgrade is available from schema version {EXPECTED_SCHEMA_VERSION}"),
+        );
+    };
+    if old_schema_ver != EXPECTED_SCHEMA_VERSION {
+        bail!(
+            FailedPrecondition,
+            msg(
+                "database is at schema version {old_schema_ver}; only version \
+                 {EXPECTED_SCHEMA_VERSION} can be downgraded"
+            ),
+        );
+    }
+    info!(
+        "Downgrading database from schema version {} to schema version {}...",
+        old_schema_ver,
+        old_schema_ver - 1
+    );
+    let tx = conn.transaction()?;
+    downgrader(&tx)?;
+    tx.execute("delete from version where id = ?", params![old_schema_ver])?;
+    tx.commit()?;
+    info!("...done.");
+    Ok(())
+}
+
+/// Upgrades a temporary copy of the database, logging the rows written by each step and the
+/// resulting schema changes.
+fn dry_run(args: &Args, sw_version: &str, conn: &rusqlite::Connection) -> Result<(), Error> {
+    let old_schema_ver = get_version(conn)?;
+    if old_schema_ver < MIN_DRY_RUN_VERSION {
+        bail!(
+            Unimplemented,
+            msg(
+                "dry run isn't supported from schema version {old_schema_ver}, as upgrading \
+                 from versions before {MIN_DRY_RUN_VERSION} modifies sample file directories"
+            ),
+        );
+    }
+
+    // Put the copy alongside the database, which is more likely to have room than /tmp.
+    let tmpdir = match conn.path().map(std::path::Path::new) {
+        Some(p) if !p.as_os_str().is_empty() => tempfile::Builder::new()
+            .prefix("upgrade-dry-run")
+            .tempdir_in(p.parent().expect("db path has a parent")),
+        _ => tempfile::Builder::new().prefix("moonfire-nvr").tempdir(),
+    }
+    .map_err(|e| err!(e, msg("unable to create temporary directory")))?;
+    let copy_path = tmpdir.path().join("db");
+    info!("Copying database to {} for dry run...", copy_path.display());
+    let copy_path_str = copy_path.to_str().ok_or_else(|| {
+        err!(
+            InvalidArgument,
+            msg("non-UTF-8 path {}", copy_path.display())
+        )
+    })?;
+    conn.execute("vacuum into ?", params![copy_path_str])?;
+    let mut copy = rusqlite::Connection::open(&copy_path)?;
+    db::set_integrity_pragmas(&mut copy)?;
+    upgrade(args, EXPECTED_SCHEMA_VERSION, sw_version, &mut copy)?;
+    match compare::get_diffs(
+        &format!("version {old_schema_ver}"),
+        conn,
+        &format!("version {EXPECTED_SCHEMA_VERSION}"),
+        &copy,
+    )? {
+        Some(diffs) => info!("Schema changes:\n{diffs}"),
+        None => info!("No schema changes."),
+    }
+    if !args.no_vacuum {
+        info!("A real upgrade would then vacuum the database.");
+    }
+    info!("Dry run complete; the database was not modified.");
+    Ok(())
+}
+
 /// A uuid-based path, as used in version 0 and version 1 schemas.
 struct UuidPath([u8; 37]);
 
@@ -291,13 +443,25 @@ mod tests {
             (4, None), // transitional; don't compare schemas.
             (5, Some(include_str!("v5.sql"))),
             (6, Some(include_str!("v6.sql"))),
-            (7, Some(include_str!("../schema.sql"))),
+            (7, Some(include_str!("v7.sql"))),
+            (8, Some(include_str!("v8.sql"))),
+            (9, Some(include_str!("v9.sql"))),
+            (10, Some(include_str!("v10.sql"))),
+            (11, Some(include_str!("v11.sql"))),
+            (12, Some(include_str!("v12.sql"))),
+            (13, Some(include_str!("v13.sql"))),
+            (14, Some(include_str!("v14.sql"))),
+            (15, Some(include_str!("v15.sql"))),
+            (16, Some(include_str!("v16.sql"))),
+            (17, Some(include_str!("v17.sql"))),
+            (18, Some(include_str!("../schema.sql"))),
         ] {
             upgrade(
                 &Args {
                     sample_file_dir: Some(tmpdir.path()),
                     preset_journal: "delete",
                     no_vacuum: false,
+                    dry_run: false,
                 },
                 *ver,
                 "test",
@@ -369,4 +533,245 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn downgrade_and_upgrade() -> Result<(), Error> {
+        testutil::init();
+        let mut conn = new_conn()?;
+        db::init(&mut conn)?;
+        conn.execute_batch(
+            r#"
+            insert into user (id, username) values (1, 'slamb');
+            "#,
+        )?;
+        downgrade(&mut conn)?;
+        assert_eq!(get_version(&conn)?, EXPECTED_SCHEMA_VERSION - 1);
+        compare(&conn, EXPECTED_SCHEMA_VERSION - 1, include_str!("v17.sql"))?;
+
+        // A second downgrade isn't possible.
+        let e = downgrade(&mut conn).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+
+        upgrade(
+            &Args {
+                sample_file_dir: None,
+                preset_journal: "delete",
+                no_vacuum: false,
+                dry_run: false,
+            },
+            EXPECTED_SCHEMA_VERSION,
+            "test",
+            &mut conn,
+        )?;
+        compare(
+            &conn,
+            EXPECTED_SCHEMA_VERSION,
+            include_str!("../schema.sql"),
+        )?;
+
+        // Stream health history is discarded rather than preventing a downgrade.
+        conn.execute_batch(
+            r#"
+            insert into camera (id, uuid, short_name, config)
+                values (1, x'00000000000000000000000000000001', 'driveway', '{}');
+            insert into stream (id, camera_id, type, config, cum_recordings,
+                                cum_media_duration_90k, cum_runs)
+                values (1, 1, 'main', '{}', 0, 0, 0);
+            insert into stream_health (stream_id, day, monitored_sec, up_sec, reconnects,
+                                       frame_gaps, frames, packets_lost)
+                values (1, '2024-01-01', 86400, 86000, 3, 5, 2000000, 12);
+            "#,
+        )?;
+        downgrade(&mut conn)?;
+        assert_eq!(get_version(&conn)?, EXPECTED_SCHEMA_VERSION - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn downgrade_v17_to_v16() -> Result<(), Error> {
+        testutil::init();
+        let mut conn = new_conn()?;
+        conn.execute_batch(include_str!("v17.sql"))?;
+        conn.execute_batch(
+            r#"
+            insert into audit (time_sec, action, success) values (0, 'login', 1);
+            "#,
+        )?;
+
+        // Audit log entries would be lost, so refuse.
+        {
+            let tx = conn.transaction()?;
+            let e = v17_to_v16::run(&tx).unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+        }
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            r#"
+            drop trigger audit_no_delete;
+            delete from audit;
+            create trigger audit_no_delete before delete on audit
+            begin
+              select raise(abort, 'audit log is append-only');
+            end;
+            "#,
+        )?;
+        v17_to_v16::run(&tx)?;
+        tx.execute("delete from version where id = 17", params![])?;
+        tx.commit()?;
+        compare(&conn, 16, include_str!("v16.sql"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn downgrade_v16_to_v15() -> Result<(), Error> {
+        testutil::init();
+        let mut conn = new_conn()?;
+        conn.execute_batch(include_str!("v16.sql"))?;
+        conn.execute_batch(
+            r#"
+            insert into user (id, username) values (1, 'slamb');
+            insert into user_credential (user_id, credential_id, creation_time_sec, passkey)
+                values (1, x'00', 0, '{}');
+            "#,
+        )?;
+
+        // Passkeys would be lost, so refuse.
+        {
+            let tx = conn.transaction()?;
+            let e = v16_to_v15::run(&tx).unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+        }
+
+        conn.execute_batch("delete from user_credential")?;
+        let tx = conn.transaction()?;
+        v16_to_v15::run(&tx)?;
+        tx.execute("delete from version where id = 16", params![])?;
+        tx.commit()?;
+        compare(&conn, 15, include_str!("v15.sql"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn downgrade_v15_to_v14() -> Result<(), Error> {
+        testutil::init();
+        let mut conn = new_conn()?;
+        conn.execute_batch(include_str!("v15.sql"))?;
+        conn.execute_batch(
+            r#"
+            insert into user (id, username) values (1, 'slamb');
+            insert into api_token (token_hash, user_id, creation_time_sec)
+                values (zeroblob(24), 1, 0);
+            "#,
+        )?;
+
+        // API tokens would be lost, so refuse.
+        {
+            let tx = conn.transaction()?;
+            let e = v15_to_v14::run(&tx).unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+        }
+
+        conn.execute_batch("delete from api_token")?;
+        let tx = conn.transaction()?;
+        v15_to_v14::run(&tx)?;
+        tx.execute("delete from version where id = 15", params![])?;
+        tx.commit()?;
+        compare(&conn, 14, include_str!("v14.sql"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn downgrade_v14_to_v13() -> Result<(), Error> {
+        testutil::init();
+        let mut conn = new_conn()?;
+        conn.execute_batch(include_str!("v14.sql"))?;
+        conn.execute_batch(
+            r#"
+            insert into open (id, uuid) values (1, x'00000000000000000000000000000001');
+            insert into camera (id, uuid, short_name, config)
+                values (1, x'00000000000000000000000000000001', 'driveway', '{}');
+            insert into stream (id, camera_id, type, config, cum_recordings,
+                                cum_media_duration_90k, cum_runs)
+                values (1, 1, 'main', '{}', 1, 90000, 1);
+            insert into video_sample_entry (id, width, height, rfc6381_codec, data)
+                values (1, 1920, 1080, 'avc1.4d401e', zeroblob(100));
+            insert into recording (composite_id, open_id, stream_id, run_offset, flags,
+                                   sample_file_bytes, start_time_90k, prev_media_duration_90k,
+                                   prev_runs, wall_duration_90k, media_duration_delta_90k,
+                                   video_samples, video_sync_samples, video_sample_entry_id)
+                values (4294967296, 1, 1, 0, 3, 42, 1, 0, 0, 90000, 0, 1, 1, 1);
+            insert into recording_playback (composite_id, video_index, wrapped_key)
+                values (4294967296, x'00', x'00');
+            "#,
+        )?;
+
+        // Encrypted recordings would become unreadable, so refuse.
+        {
+            let tx = conn.transaction()?;
+            let e = v14_to_v13::run(&tx).unwrap_err();
+            assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+        }
+
+        conn.execute_batch(
+            r#"
+            update recording set flags = 1;
+            update recording_playback set wrapped_key = null;
+            "#,
+        )?;
+        let tx = conn.transaction()?;
+        v14_to_v13::run(&tx)?;
+        tx.execute("delete from version where id = 14", params![])?;
+        tx.commit()?;
+        compare(&conn, 13, include_str!("v13.sql"))?;
+        let video_index: Vec<u8> = conn.query_row(
+            "select video_index from recording_playback",
+            params![],
+            |r| r.get(0),
+        )?;
+        assert_eq!(video_index, b"\x00");
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_leaves_db_unchanged() -> Result<(), Error> {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()?;
+        let path = tmpdir.path().join("db");
+        let mut conn = rusqlite::Connection::open(&path)?;
+        conn.execute_batch(include_str!("v13.sql"))?;
+        run(
+            &Args {
+                sample_file_dir: None,
+                preset_journal: "delete",
+                no_vacuum: false,
+                dry_run: true,
+            },
+            "test",
+            &mut conn,
+        )?;
+        assert_eq!(get_version(&conn)?, 13);
+        let leftovers: Vec<_> = std::fs::read_dir(tmpdir.path())?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(leftovers, [std::ffi::OsString::from("db")]);
+
+        let mut old = new_conn()?;
+        old.execute_batch(include_str!("v3.sql"))?;
+        let e = run(
+            &Args {
+                sample_file_dir: None,
+                preset_journal: "delete",
+                no_vacuum: false,
+                dry_run: true,
+            },
+            "test",
+            &mut old,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::Unimplemented);
+        Ok(())
+    }
 }
@@ -0,0 +1,36 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 10 schema to a version 11 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Existing recordings are all in their streams' own directories, so null is correct as-is.
+    tx.execute_batch(
+        r#"
+        alter table recording add sample_file_dir_id integer
+            references sample_file_dir (id);
+
+        drop index recording_cover;
+        create index recording_cover on recording (
+          stream_id,
+          start_time_90k,
+          open_id,
+          wall_duration_90k,
+          media_duration_delta_90k,
+          video_samples,
+          video_sync_samples,
+          video_sample_entry_id,
+          sample_file_bytes,
+          run_offset,
+          flags,
+          audio_sample_entry_id,
+          audio_samples,
+          audio_sample_file_bytes,
+          sample_file_dir_id
+        );
+        "#,
+    )?;
+    Ok(())
+}
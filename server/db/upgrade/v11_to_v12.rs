@@ -0,0 +1,22 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 11 schema to a version 12 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table upload (
+          composite_id integer primary key,
+          object_key text not null,
+          sample_file_bytes integer not null check (sample_file_bytes > 0),
+          sample_file_sha256 blob not null check (length(sample_file_sha256) = 32),
+          upload_time_90k integer not null,
+          verify_time_90k integer
+        );
+        "#,
+    )?;
+    Ok(())
+}
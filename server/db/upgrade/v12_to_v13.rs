@@ -0,0 +1,19 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 12 schema to a version 13 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table replication (
+          composite_id integer primary key,
+          remote_path text not null,
+          replicate_time_90k integer not null
+        );
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,15 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 13 schema to a version 14 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        alter table recording_playback add column wrapped_key blob;
+        "#,
+    )?;
+    Ok(())
+}
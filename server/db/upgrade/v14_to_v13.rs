@@ -0,0 +1,53 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Downgrades a version 14 schema to a version 13 schema.
+///
+/// This is only possible when no recording uses a flag version 13 doesn't understand:
+/// "encrypted" (whose keys would be lost) or "corrupt".
+use base::{bail, Error};
+use rusqlite::params;
+
+pub fn run(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let (encrypted, corrupt): (i64, i64) = tx.query_row(
+        r#"
+        select
+          coalesce(sum(flags & 2 != 0), 0),
+          coalesce(sum(flags & 4 != 0), 0)
+        from
+          recording
+        "#,
+        params![],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    if encrypted > 0 || corrupt > 0 {
+        bail!(
+            FailedPrecondition,
+            msg(
+                "can't downgrade with {encrypted} encrypted and {corrupt} corrupt recordings, \
+                 which version 13 doesn't support; delete them first"
+            ),
+        );
+    }
+    tx.execute_batch(
+        r#"
+        alter table recording_playback rename to old_recording_playback;
+        create table recording_playback (
+          -- See description on recording table.
+          composite_id integer primary key references recording (composite_id),
+
+          -- See design/schema.md#video_index for a description of this field.
+          video_index blob not null check (length(video_index) > 0),
+
+          -- See design/schema.md#audio_index for a description of this field.
+          -- Present iff recording.audio_sample_entry_id is non-null.
+          audio_index blob
+        );
+        insert into recording_playback
+          select composite_id, video_index, audio_index from old_recording_playback;
+        drop table old_recording_playback;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,28 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 14 schema to a version 15 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table api_token (
+          id integer primary key,
+          token_hash blob unique not null check (length(token_hash) = 24),
+          user_id integer references user (id) not null,
+          description text,
+          creation_time_sec integer not null,
+          expiration_time_sec integer,
+          last_use_time_sec integer,
+          last_use_user_agent text,
+          last_use_peer_addr blob,
+          use_count not null default 0,
+          permissions blob not null default X''
+        );
+        create index api_token_uid on api_token (user_id);
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Downgrades a version 15 schema to a version 14 schema.
+///
+/// This is only possible when there are no API tokens, which version 14 can't represent.
+use base::{bail, Error};
+use rusqlite::params;
+
+pub fn run(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let tokens: i64 = tx.query_row("select count(*) from api_token", params![], |r| r.get(0))?;
+    if tokens > 0 {
+        bail!(
+            FailedPrecondition,
+            msg(
+                "can't downgrade with {tokens} API tokens, which version 14 doesn't support; \
+                 revoke them first"
+            ),
+        );
+    }
+    tx.execute_batch(
+        r#"
+        drop index api_token_uid;
+        drop table api_token;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,24 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 15 schema to a version 16 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table user_credential (
+          id integer primary key,
+          user_id integer references user (id) not null,
+          credential_id blob unique not null,
+          description text,
+          creation_time_sec integer not null,
+          last_use_time_sec integer,
+          passkey text not null
+        );
+        create index user_credential_uid on user_credential (user_id);
+        "#,
+    )?;
+    Ok(())
+}
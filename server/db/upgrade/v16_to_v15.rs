@@ -0,0 +1,33 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Downgrades a version 16 schema to a version 15 schema.
+///
+/// This is only possible when there are no WebAuthn credentials, which version 15 can't
+/// represent.
+use base::{bail, Error};
+use rusqlite::params;
+
+pub fn run(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let credentials: i64 =
+        tx.query_row("select count(*) from user_credential", params![], |r| {
+            r.get(0)
+        })?;
+    if credentials > 0 {
+        bail!(
+            FailedPrecondition,
+            msg(
+                "can't downgrade with {credentials} passkeys, which version 15 doesn't support; \
+                 delete them first"
+            ),
+        );
+    }
+    tx.execute_batch(
+        r#"
+        drop index user_credential_uid;
+        drop table user_credential;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,34 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 16 schema to a version 17 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table audit (
+          id integer primary key,
+          time_sec integer not null,
+          user_id integer,
+          username text,
+          peer_addr blob,
+          user_agent text,
+          action text not null,
+          success integer not null check (success in (0, 1)),
+          detail text
+        );
+        create index audit_time on audit (time_sec);
+        create trigger audit_no_update before update on audit
+        begin
+          select raise(abort, 'audit log is append-only');
+        end;
+        create trigger audit_no_delete before delete on audit
+        begin
+          select raise(abort, 'audit log is append-only');
+        end;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,32 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Downgrades a version 17 schema to a version 16 schema.
+///
+/// This is only possible when the audit log is empty. It's append-only, so in practice that
+/// means the audit log was never enabled.
+use base::{bail, Error};
+use rusqlite::params;
+
+pub fn run(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let entries: i64 = tx.query_row("select count(*) from audit", params![], |r| r.get(0))?;
+    if entries > 0 {
+        bail!(
+            FailedPrecondition,
+            msg(
+                "can't downgrade with {entries} audit log entries, which version 16 doesn't \
+                 support; restore a backup from before the upgrade instead"
+            ),
+        );
+    }
+    tx.execute_batch(
+        r#"
+        drop trigger audit_no_delete;
+        drop trigger audit_no_update;
+        drop index audit_time;
+        drop table audit;
+        "#,
+    )?;
+    Ok(())
+}
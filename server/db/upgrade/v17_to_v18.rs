@@ -0,0 +1,25 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 17 schema to a version 18 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(
+        r#"
+        create table stream_health (
+          stream_id integer not null references stream (id),
+          day text not null check (length(day) = 10),
+          monitored_sec integer not null check (monitored_sec >= 0),
+          up_sec integer not null check (up_sec >= 0 and up_sec <= monitored_sec),
+          reconnects integer not null check (reconnects >= 0),
+          frame_gaps integer not null check (frame_gaps >= 0),
+          frames integer not null check (frames >= 0),
+          packets_lost integer not null check (packets_lost >= 0),
+          primary key (stream_id, day)
+        ) without rowid;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,27 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Downgrades a version 18 schema to a version 17 schema.
+///
+/// Unlike other downgrades, this discards data: the stream health history. It's only a
+/// diagnostic aid and, as it's collected continually, would otherwise make downgrading
+/// impossible.
+use base::Error;
+use rusqlite::params;
+use tracing::warn;
+
+pub fn run(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let rows: i64 = tx.query_row("select count(*) from stream_health", params![], |r| {
+        r.get(0)
+    })?;
+    if rows > 0 {
+        warn!("discarding {rows} days of stream health history");
+    }
+    tx.execute_batch(
+        r#"
+        drop table stream_health;
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 7 schema to a version 8 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Existing recordings have no audio, so the new columns' defaults are correct as-is.
+    tx.execute_batch(
+        r#"
+        create table audio_sample_entry (
+          id integer primary key,
+          rfc6381_codec text not null,
+          sample_rate integer not null check (sample_rate > 0),
+          channels integer not null check (channels > 0),
+          data blob not null check (length(data) > 36)
+        );
+
+        alter table recording add audio_sample_entry_id integer
+            references audio_sample_entry (id);
+        alter table recording add audio_samples integer not null default 0
+            check (audio_samples >= 0);
+        alter table recording add audio_sample_file_bytes integer not null default 0
+            check (audio_sample_file_bytes >= 0);
+
+        alter table recording_playback add audio_index blob;
+
+        drop index recording_cover;
+        create index recording_cover on recording (
+          stream_id,
+          start_time_90k,
+          open_id,
+          wall_duration_90k,
+          media_duration_delta_90k,
+          video_samples,
+          video_sync_samples,
+          video_sample_entry_id,
+          sample_file_bytes,
+          run_offset,
+          flags,
+          audio_sample_entry_id,
+          audio_samples,
+          audio_sample_file_bytes
+        );
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 8 schema to a version 9 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Thumbnails are generated only for new recordings; existing ones go without.
+    tx.execute_batch(
+        r#"
+        create table thumbnail (
+          stream_id integer not null references stream (id),
+          start_time_90k integer not null,
+          width integer not null check (width > 0),
+          height integer not null check (height > 0),
+          data blob not null,
+          primary key (stream_id, start_time_90k)
+        );
+        "#,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,27 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception
+
+/// Upgrades a version 9 schema to a version 10 schema.
+use base::Error;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Detections are made only as new recordings are written; existing ones go without.
+    tx.execute_batch(
+        r#"
+        create table detection (
+          id integer primary key,
+          stream_id integer not null references stream (id),
+          time_90k integer not null,
+          class text not null,
+          score real not null check (score >= 0 and score <= 1),
+          box_left real not null,
+          box_top real not null,
+          box_width real not null,
+          box_height real not null
+        );
+        create index detection_stream_time on detection (stream_id, time_90k);
+        "#,
+    )?;
+    Ok(())
+}
@@ -7,6 +7,7 @@
 use crate::db::{self, CompositeId};
 use crate::dir;
 use crate::recording::{self, MAX_RECORDING_WALL_DURATION};
+use crate::retention;
 use base::clock::{self, Clocks};
 use base::shutdown::ShutdownError;
 use base::FastHashMap;
@@ -29,7 +30,11 @@ use tracing::{debug, trace, warn};
 pub trait DirWriter: 'static + Send {
     type File: FileWriter;
 
-    fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error>;
+    fn create_file(
+        &self,
+        id: CompositeId,
+        key: Option<Arc<dir::crypto::Key>>,
+    ) -> Result<Self::File, nix::Error>;
     fn sync(&self) -> Result<(), nix::Error>;
     fn unlink_file(&self, id: CompositeId) -> Result<(), nix::Error>;
 }
@@ -43,13 +48,32 @@ pub trait FileWriter: 'static {
 
     /// As in `std::io::Writer::write`.
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error>;
+
+    /// Writes out anything buffered by `write`. Called once, after the last `write`.
+    fn finish(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// As in [`dir::SampleFile::preallocate`]. Called at most once, before the first `write`.
+    fn preallocate(&self, _len: u64) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// As in [`dir::SampleFile::trim`]. Called after `finish` if `preallocate` succeeded.
+    fn trim(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
 }
 
 impl DirWriter for Arc<dir::SampleFileDir> {
-    type File = ::std::fs::File;
+    type File = dir::SampleFile;
 
-    fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
-        dir::SampleFileDir::create_file(self, id)
+    fn create_file(
+        &self,
+        id: CompositeId,
+        key: Option<Arc<dir::crypto::Key>>,
+    ) -> Result<Self::File, nix::Error> {
+        dir::SampleFileDir::create_sample_file(self, id, key)
     }
     fn sync(&self) -> Result<(), nix::Error> {
         dir::SampleFileDir::sync(self)
@@ -59,12 +83,39 @@ impl DirWriter for Arc<dir::SampleFileDir> {
     }
 }
 
-impl FileWriter for ::std::fs::File {
+impl FileWriter for dir::SampleFile {
     fn sync_all(&self) -> Result<(), io::Error> {
-        self.sync_all()
+        match self {
+            dir::SampleFile::Plain(f) => f.sync_all(),
+            dir::SampleFile::Encrypted(f) => f.sync_all(),
+            dir::SampleFile::Direct(f) => f.sync_all(),
+        }
     }
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        io::Write::write(self, buf)
+        match self {
+            dir::SampleFile::Plain(f) => {
+                #[cfg(feature = "io-uring")]
+                if let Some(r) = dir::uring::with_ring(|r| r.write(f, buf)) {
+                    return r;
+                }
+                io::Write::write(f, buf)
+            }
+            dir::SampleFile::Encrypted(f) => f.write(buf),
+            dir::SampleFile::Direct(f) => f.write(buf),
+        }
+    }
+    fn finish(&mut self) -> Result<(), io::Error> {
+        match self {
+            dir::SampleFile::Plain(_) => Ok(()),
+            dir::SampleFile::Encrypted(f) => f.finish(),
+            dir::SampleFile::Direct(f) => f.finish(),
+        }
+    }
+    fn preallocate(&self, len: u64) -> Result<(), io::Error> {
+        dir::SampleFile::preallocate(self, len)
+    }
+    fn trim(&self) -> Result<(), io::Error> {
+        dir::SampleFile::trim(self)
     }
 }
 
@@ -160,7 +211,7 @@ pub fn start_syncer<C>(
     db: Arc<db::Database<C>>,
     shutdown_rx: base::shutdown::Receiver,
     dir_id: i32,
-) -> Result<(SyncerChannel<::std::fs::File>, thread::JoinHandle<()>), Error>
+) -> Result<(SyncerChannel<dir::SampleFile>, thread::JoinHandle<()>), Error>
 where
     C: Clocks + Clone,
 {
@@ -214,6 +265,7 @@ pub fn lower_retention(
     let db2 = db.clone();
     let (_tx, rx) = base::shutdown::channel();
     let (mut syncer, _) = Syncer::new(&db.lock(), rx, db2, dir_id)?;
+    let now = recording::Time::new(db.clocks().realtime());
     syncer.do_rotation(|db| {
         for l in limits {
             let (fs_bytes_before, extra);
@@ -221,56 +273,85 @@ pub fn lower_retention(
                 let Some(stream) = db.streams_by_id().get(&l.stream_id) else {
                     bail!(NotFound, msg("no such stream {}", l.stream_id));
                 };
-                fs_bytes_before =
-                    stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete;
+                fs_bytes_before = stream.fs_bytes + stream.fs_bytes_to_add
+                    - stream.fs_bytes_to_delete
+                    - stream.fs_bytes_to_archive;
                 extra = stream.config.retain_bytes - l.limit;
             }
             if l.limit >= fs_bytes_before {
                 continue;
             }
-            delete_recordings(db, l.stream_id, extra)?;
+            delete_recordings(db, l.stream_id, extra, now)?;
         }
         Ok(())
     })
 }
 
-/// Enqueues deletion of recordings to bring a stream's disk usage within bounds.
+/// Enqueues deletion of recordings to bring a stream's disk usage within bounds and drop
+/// recordings past their age limits as of `now`, as decided by [`crate::retention::Policy`].
 /// The next flush will mark the recordings as garbage in the SQLite database, and then they can
 /// be deleted from disk.
 fn delete_recordings(
     db: &mut db::LockedDatabase,
     stream_id: i32,
     extra_bytes_needed: i64,
+    now: recording::Time,
 ) -> Result<(), Error> {
-    let fs_bytes_needed = {
-        let stream = match db.streams_by_id().get(&stream_id) {
-            None => bail!(NotFound, msg("no stream {stream_id}")),
-            Some(s) => s,
-        };
-        stream.fs_bytes + stream.fs_bytes_to_add - stream.fs_bytes_to_delete + extra_bytes_needed
-            - stream.config.retain_bytes
-    };
-    let mut fs_bytes_to_delete = 0;
-    if fs_bytes_needed <= 0 {
+    let mut policy = retention::Policy::new(db, stream_id, extra_bytes_needed, now)?;
+    if policy.is_satisfied() {
         debug!(
             "{}: have remaining quota of {}",
             stream_id,
-            base::strutil::encode_size(fs_bytes_needed)
+            base::strutil::encode_size(policy.fs_bytes_needed())
         );
         return Ok(());
     }
-    let mut n = 0;
-    db.delete_oldest_recordings(stream_id, &mut |row| {
-        if fs_bytes_needed >= fs_bytes_to_delete {
-            fs_bytes_to_delete += db::round_up(i64::from(row.sample_file_bytes));
-            n += 1;
-            return true;
-        }
-        false
-    })?;
+    db.delete_oldest_recordings(stream_id, &mut |row| policy.decide(row))?;
     Ok(())
 }
 
+/// Copies recordings queued for archival from `dir_id` to their archive directories, without
+/// holding the database lock. The next flush will point the recordings at the copies and mark
+/// the originals as garbage.
+///
+/// Returns the number of recordings copied.
+fn archive_recordings<C: Clocks + Clone>(
+    db: &db::Database<C>,
+    dir_id: i32,
+) -> Result<usize, Error> {
+    let (src, dests, to_archive) = {
+        let l = db.lock();
+        let to_archive = l.recordings_to_archive(dir_id);
+        if to_archive.is_empty() {
+            return Ok(0);
+        }
+        let get = |id| -> Result<Arc<dir::SampleFileDir>, Error> {
+            l.sample_file_dirs_by_id()
+                .get(&id)
+                .ok_or_else(|| err!(NotFound, msg("no dir {id}")))?
+                .get()
+        };
+        let mut dests = FastHashMap::default();
+        for &(_, d) in &to_archive {
+            if let std::collections::hash_map::Entry::Vacant(e) = dests.entry(d) {
+                e.insert(get(d)?);
+            }
+        }
+        (get(dir_id)?, dests, to_archive)
+    };
+    for &(id, d) in &to_archive {
+        src.copy_file(id, &dests[&d])?;
+    }
+    for d in dests.values() {
+        d.sync()?;
+    }
+    let mut l = db.lock();
+    for &(id, d) in &to_archive {
+        l.mark_archived(id, d)?;
+    }
+    Ok(to_archive.len())
+}
+
 impl<F: FileWriter> SyncerChannel<F> {
     /// Asynchronously syncs the given writer, closes it, records it into the database, and
     /// starts rotation.
@@ -375,10 +456,11 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
     /// Rotates files for all streams and deletes stale files from previous runs.
     /// Called from main thread.
     fn initial_rotation(&mut self) -> Result<(), Error> {
+        let now = recording::Time::new(self.db.clocks().realtime());
         self.do_rotation(|db| {
             let streams: Vec<i32> = db.streams_by_id().keys().copied().collect();
             for &stream_id in &streams {
-                delete_recordings(db, stream_id, 0)?;
+                delete_recordings(db, stream_id, 0, now)?;
             }
             Ok(())
         })
@@ -394,6 +476,9 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
             delete_recordings(&mut db)?;
             db.flush("synchronous deletion")?;
         }
+        if archive_recordings(&self.db, self.dir_id)? > 0 {
+            self.db.lock().flush("synchronous archival")?;
+        }
         let mut garbage: Vec<_> = {
             let l = self.db.lock();
             let d = l.sample_file_dirs_by_id().get(&self.dir_id).unwrap();
@@ -527,9 +612,10 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
         clock::retry(&self.db.clocks(), &self.shutdown_rx, &mut || {
             self.dir.sync()
         })?;
+        let now = recording::Time::new(self.db.clocks().realtime());
         let mut db = self.db.lock();
         db.mark_synced(id).unwrap();
-        delete_recordings(&mut db, stream_id, 0).unwrap();
+        delete_recordings(&mut db, stream_id, 0, now).unwrap();
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
@@ -553,6 +639,13 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
             recording: id,
             senders: Vec::new(),
         });
+        drop(db);
+
+        // Move recordings to the archive directory, if the above queued any. They'll be
+        // committed with the flush just planned.
+        clock::retry(&self.db.clocks(), &self.shutdown_rx, &mut || {
+            archive_recordings(&self.db, self.dir_id)
+        })?;
         Ok(())
     }
 
@@ -669,6 +762,35 @@ struct InnerWriter<F: FileWriter> {
     /// `unindexed_sample` should always be `Some`, except when a `write` call has aborted on
     /// shutdown. In that case, the close will be unable to write the full segment.
     unindexed_sample: Option<UnindexedSample>,
+
+    /// The pts of this recording's first video sample, once known.
+    start_pts_90k: Option<i64>,
+
+    /// Audio samples, which are buffered in memory and written after all video samples when the
+    /// recording is closed. See `design/schema.md#audio_index`.
+    audio: Option<PendingAudio>,
+
+    /// Used to abandon writing the buffered audio on shutdown.
+    shutdown_rx: base::shutdown::Receiver,
+
+    /// True if space was reserved for this recording, so any excess should be released on close.
+    preallocated: bool,
+}
+
+/// Audio samples which have not yet been written to disk.
+struct PendingAudio {
+    audio_sample_entry_id: i32,
+    e: recording::AudioSampleIndexEncoder,
+    data: Vec<u8>,
+
+    /// The start of the first sample, relative to the start of the recording, in 90 kHz units.
+    start_90k: i32,
+
+    /// The total duration of the samples so far, in units of the sample rate.
+    duration: i32,
+
+    /// Samples which have yet to be sent with a [`db::LiveSegment`].
+    live: Option<db::LiveAudio>,
 }
 
 /// A sample which has been written to disk but not included in the index yet.
@@ -687,6 +809,9 @@ struct UnindexedSample {
 struct PreviousWriter {
     end: recording::Time,
     run_offset: i32,
+
+    /// The size of the previous recording, from which the next recording's size is estimated.
+    sample_file_bytes: i32,
 }
 
 impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
@@ -726,23 +851,45 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             }
             WriterState::Closed(prev) => Some(prev),
         };
-        let (id, r) = self.db.lock().add_recording(
-            self.stream_id,
-            db::RecordingToInsert {
-                run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
-                start: prev
-                    .map(|p| p.end)
-                    .unwrap_or(recording::Time(i64::max_value())),
-                video_sample_entry_id,
-                flags: db::RecordingFlags::Growing as i32,
-                ..Default::default()
-            },
-        )?;
+        let (id, r, key) = {
+            let mut db = self.db.lock();
+            let (id, r) = db.add_recording(
+                self.stream_id,
+                db::RecordingToInsert {
+                    run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
+                    start: prev
+                        .map(|p| p.end)
+                        .unwrap_or(recording::Time(i64::max_value())),
+                    video_sample_entry_id,
+                    flags: db::RecordingFlags::Growing as i32,
+                    ..Default::default()
+                },
+            )?;
+            let key = db.recording_key(id)?;
+            (id, r, key)
+        };
         let f = clock::retry(&self.db.clocks(), shutdown_rx, &mut || {
-            self.dir.create_file(id)
+            self.dir.create_file(id, key.clone())
         })
         .map_err(|e| err!(Cancelled, source(e)))?;
 
+        // Reserve about the size of the run's previous recording, which reflects the stream's
+        // recent bitrate, plus some slack. The excess is released on close.
+        let preallocated = match prev {
+            Some(p) if p.sample_file_bytes > 0 => {
+                let len = u64::try_from(p.sample_file_bytes).unwrap();
+                let len = len + len / 8;
+                match f.preallocate(len) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!(%err, "unable to preallocate {len} bytes for recording {id}");
+                        false
+                    }
+                }
+            }
+            _ => false,
+        };
+
         self.state = WriterState::Open(InnerWriter {
             f,
             r,
@@ -752,6 +899,10 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             local_start: recording::Time(i64::max_value()),
             unindexed_sample: None,
             video_sample_entry_id,
+            start_pts_90k: None,
+            audio: None,
+            shutdown_rx: shutdown_rx.clone(),
+            preallocated,
         });
         Ok(())
     }
@@ -811,11 +962,13 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
                     )
                 }
             };
+            let audio = w.take_live_audio();
             if let Err(e) = w.add_sample(
                 duration,
                 unindexed.len,
                 unindexed.is_key,
                 unindexed.local_time,
+                audio,
                 self.db,
                 self.stream_id,
             ) {
@@ -845,10 +998,77 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             len: i32::try_from(pkt.len()).unwrap(),
             is_key,
         });
+        w.start_pts_90k.get_or_insert(pts_90k);
         w.hasher.update(pkt);
         Ok(())
     }
 
+    /// Buffers an audio frame to be written at the end of the current recording.
+    ///
+    /// `pts_90k` should be on the same timeline as the video frames passed to `write`;
+    /// `duration` is in units of the audio sample entry's sample rate. Frames which arrive
+    /// while no recording is open or which precede the recording's first video frame are
+    /// discarded.
+    pub fn write_audio(
+        &mut self,
+        pkt: &[u8],
+        pts_90k: i64,
+        duration: i32,
+        audio_sample_entry_id: i32,
+    ) -> Result<(), Error> {
+        let w = match self.state {
+            WriterState::Open(ref mut w) => w,
+            _ => return Ok(()),
+        };
+        let start_90k = match w.start_pts_90k {
+            Some(s) if pts_90k >= s => i32::try_from(pts_90k - s).map_err(|_| {
+                err!(
+                    InvalidArgument,
+                    msg("excessive audio pts jump from {s} to {pts_90k}")
+                )
+            })?,
+            _ => {
+                trace!("discarding audio frame with pts {pts_90k} before recording start");
+                return Ok(());
+            }
+        };
+        let a = w.audio.get_or_insert_with(|| PendingAudio {
+            audio_sample_entry_id,
+            e: recording::AudioSampleIndexEncoder::new(start_90k),
+            data: Vec::new(),
+            start_90k,
+            duration: 0,
+            live: None,
+        });
+        if a.audio_sample_entry_id != audio_sample_entry_id {
+            bail!(Internal, msg("inconsistent audio_sample_entry_id"));
+        }
+        if duration <= 0 || pkt.is_empty() {
+            bail!(
+                InvalidArgument,
+                msg(
+                    "invalid audio frame with duration {duration} and {} bytes",
+                    pkt.len()
+                ),
+            );
+        }
+        let bytes = i32::try_from(pkt.len()).unwrap();
+        a.e.add_sample(duration, bytes);
+        a.data.extend_from_slice(pkt);
+        let (recording_start_90k, start) = (a.start_90k, a.duration);
+        let live = a.live.get_or_insert_with(|| db::LiveAudio {
+            audio_sample_entry_id,
+            recording_start_90k,
+            start,
+            samples: Vec::new(),
+            data: Vec::new(),
+        });
+        live.samples.push((duration, bytes));
+        live.data.extend_from_slice(pkt);
+        a.duration += duration;
+        Ok(())
+    }
+
     /// Cleanly closes a single recording within this writer, using a supplied
     /// pts of the next sample for the last sample's duration (if known).
     ///
@@ -867,6 +1087,16 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         };
         Ok(())
     }
+
+    /// Closes any open recording as in [`Writer::close`], then ends the run, so that the next
+    /// recording starts a new run rather than being anchored to the end of this one.
+    ///
+    /// This is for intentional gaps, such as between signals in `on-signal` mode.
+    pub fn end_run(&mut self, next_pts: Option<i64>) -> Result<(), Error> {
+        self.close(next_pts, None)?;
+        self.state = WriterState::Unopened;
+        Ok(())
+    }
 }
 
 fn clamp(v: i64, min: i64, max: i64) -> i64 {
@@ -874,12 +1104,26 @@ fn clamp(v: i64, min: i64, max: i64) -> i64 {
 }
 
 impl<F: FileWriter> InnerWriter<F> {
+    /// Takes the audio to send with the next live segment: the samples received since the
+    /// previous one, or `None` if this recording has no audio so far.
+    fn take_live_audio(&mut self) -> Option<Arc<db::LiveAudio>> {
+        let a = self.audio.as_mut()?;
+        Some(Arc::new(a.live.take().unwrap_or_else(|| db::LiveAudio {
+            audio_sample_entry_id: a.audio_sample_entry_id,
+            recording_start_90k: a.start_90k,
+            start: a.duration,
+            samples: Vec::new(),
+            data: Vec::new(),
+        })))
+    }
+
     fn add_sample<C: Clocks + Clone>(
         &mut self,
         duration_90k: i32,
         bytes: i32,
         is_key: bool,
         pkt_local_time: recording::Time,
+        audio: Option<Arc<db::LiveAudio>>,
         db: &db::Database<C>,
         stream_id: i32,
     ) -> Result<(), Error> {
@@ -919,6 +1163,7 @@ impl<F: FileWriter> InnerWriter<F> {
                     recording: self.id.recording(),
                     is_key,
                     media_off_90k: prev_media_duration_90k..media_duration_90k,
+                    audio,
                 },
             )
             .unwrap();
@@ -958,13 +1203,52 @@ impl<F: FileWriter> InnerWriter<F> {
                 0,
             ),
         };
+        let live_audio = self.take_live_audio();
+        if let Some(a) = self.audio.take() {
+            let mut remaining = &a.data[..];
+            while !remaining.is_empty() {
+                let written = match clock::retry(&db.clocks(), &self.shutdown_rx, &mut || {
+                    self.f.write(remaining)
+                }) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        tracing::warn!(
+                            "abandoning incompletely written recording {} on shutdown",
+                            self.id
+                        );
+                        bail!(Cancelled, source(e));
+                    }
+                };
+                remaining = &remaining[written..];
+            }
+            self.hasher.update(&a.data);
+            let mut l = self.r.lock().unwrap();
+            l.sample_file_bytes += a.e.bytes;
+            l.audio_sample_entry_id = Some(a.audio_sample_entry_id);
+            l.audio_samples = a.e.samples;
+            l.audio_sample_file_bytes = a.e.bytes;
+            l.audio_index = a.e.index;
+        }
+        if let Err(e) = clock::retry(&db.clocks(), &self.shutdown_rx, &mut || self.f.finish()) {
+            tracing::warn!(
+                "abandoning incompletely written recording {} on shutdown",
+                self.id
+            );
+            bail!(Cancelled, source(e));
+        }
+        if self.preallocated {
+            if let Err(err) = self.f.trim() {
+                warn!(%err, "unable to release preallocated space of recording {}", self.id);
+            }
+        }
         let blake3 = self.hasher.finalize();
-        let (run_offset, end);
+        let (run_offset, end, sample_file_bytes);
         self.add_sample(
             last_sample_duration,
             unindexed.len,
             unindexed.is_key,
             unindexed.local_time,
+            live_audio,
             db,
             stream_id,
         )?;
@@ -973,17 +1257,22 @@ impl<F: FileWriter> InnerWriter<F> {
         let wall_duration;
         {
             let mut l = self.r.lock().unwrap();
-            l.flags = flags;
+            l.flags = flags | (l.flags & db::RecordingFlags::Encrypted as i32);
             l.local_time_delta = self.local_start - l.start;
             l.sample_file_blake3 = Some(*blake3.as_bytes());
             l.end_reason = reason;
             wall_duration = recording::Duration(i64::from(l.wall_duration_90k));
             run_offset = l.run_offset;
             end = l.start + wall_duration;
+            sample_file_bytes = l.sample_file_bytes;
         }
         drop(self.r);
         channel.async_save_recording(self.id, wall_duration, self.f);
-        Ok(PreviousWriter { end, run_offset })
+        Ok(PreviousWriter {
+            end,
+            run_offset,
+            sample_file_bytes,
+        })
     }
 }
 
@@ -1052,7 +1341,11 @@ mod tests {
     impl super::DirWriter for MockDir {
         type File = MockFile;
 
-        fn create_file(&self, id: CompositeId) -> Result<Self::File, nix::Error> {
+        fn create_file(
+            &self,
+            id: CompositeId,
+            _key: Option<Arc<crate::dir::crypto::Key>>,
+        ) -> Result<Self::File, nix::Error> {
             match self
                 .0
                 .lock()
@@ -1283,6 +1576,222 @@ mod tests {
         h.dir.ensure_done();
     }
 
+    #[test]
+    fn live_audio() {
+        testutil::init();
+        let mut h = new_harness(0);
+        let video_sample_entry_id =
+            h.db.lock()
+                .insert_video_sample_entry(VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+        let live = Arc::new(Mutex::new(Vec::new()));
+        h.db.lock()
+            .watch_live(
+                testutil::TEST_STREAM_ID,
+                Box::new({
+                    let live = live.clone();
+                    move |l| {
+                        live.lock().unwrap().push(l);
+                        true
+                    }
+                }),
+            )
+            .unwrap();
+        let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID);
+        let f = MockFile::new();
+        h.dir.expect(MockDirAction::Create(
+            CompositeId::new(1, 0),
+            Box::new({
+                let f = f.clone();
+                move |_id| Ok(f.clone())
+            }),
+        ));
+        f.expect(MockFileAction::Write(Box::new(|_| Ok(1))));
+        f.expect(MockFileAction::Write(Box::new(|_| Ok(1))));
+        w.write(
+            &mut h.shutdown_rx,
+            b"1",
+            recording::Time(1),
+            0,
+            true,
+            video_sample_entry_id,
+        )
+        .unwrap();
+        w.write_audio(b"ab", 10, 1024, 1).unwrap();
+        w.write_audio(b"cde", 20, 1024, 1).unwrap();
+        w.write(
+            &mut h.shutdown_rx,
+            b"2",
+            recording::Time(2),
+            3000,
+            false,
+            video_sample_entry_id,
+        )
+        .unwrap();
+        {
+            let l = live.lock().unwrap();
+            assert_eq!(l.len(), 1);
+            let a = l[0].audio.as_ref().unwrap();
+            assert_eq!(a.recording_start_90k, 10);
+            assert_eq!(a.start, 0);
+            assert_eq!(&a.samples[..], &[(1024, 2), (1024, 3)]);
+            assert_eq!(&a.data[..], b"abcde");
+        }
+
+        // The audio is written on close. The final live segment has no new samples.
+        f.expect(MockFileAction::Write(Box::new(|buf| {
+            assert_eq!(buf, b"abcde");
+            Ok(5)
+        })));
+        f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+        h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+        drop(w);
+        {
+            let l = live.lock().unwrap();
+            assert_eq!(l.len(), 2);
+            let a = l[1].audio.as_ref().unwrap();
+            assert_eq!(a.start, 2048);
+            assert!(a.samples.is_empty());
+        }
+        assert!(h.syncer.iter(&h.syncer_rx)); // AsyncSave
+        assert_eq!(h.syncer.planned_flushes.len(), 1);
+        assert!(h.syncer.iter(&h.syncer_rx)); // planned flush
+        assert_eq!(h.syncer.planned_flushes.len(), 0);
+        assert!(h.syncer.iter(&h.syncer_rx)); // DatabaseFlushed
+        f.ensure_done();
+        h.dir.ensure_done();
+    }
+
+    #[test]
+    fn retain_days() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let row = tdb.insert_recording_from_encoder(db::RecordingToInsert {
+            media_duration_90k: 90_000,
+            sample_file_bytes: 1,
+            ..Default::default()
+        });
+        let mut l = tdb.db.lock();
+        let mut change = db::CameraChange {
+            short_name: "test camera".to_owned(),
+            ..Default::default()
+        };
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        change.streams[0] = db::StreamChange {
+            sample_file_dir_id: s.sample_file_dir_id,
+            config: s.config.clone(),
+        };
+        change.streams[0].config.retain_days = 1;
+        l.update_camera(testutil::TEST_CAMERA_ID, change).unwrap();
+
+        // The recording is well within retain_bytes, so only its age matters.
+        const DAY: recording::Duration =
+            recording::Duration(86_400 * recording::TIME_UNITS_PER_SEC);
+        let end = row.start + recording::Duration(i64::from(row.wall_duration_90k));
+        super::delete_recordings(
+            &mut l,
+            testutil::TEST_STREAM_ID,
+            0,
+            end + DAY - recording::Duration(1),
+        )
+        .unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 0);
+        super::delete_recordings(&mut l, testutil::TEST_STREAM_ID, 0, end + DAY).unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 1);
+    }
+
+    #[test]
+    fn retain_event_days() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(SimulatedClocks::new(::time::Timespec::new(0, 0)));
+        let event = tdb.insert_recording_from_encoder(db::RecordingToInsert {
+            media_duration_90k: 90_000,
+            sample_file_bytes: 1,
+            ..Default::default()
+        });
+        let event_end = event.start + recording::Duration(i64::from(event.wall_duration_90k));
+        let mut l = tdb.db.lock();
+        let (plain_id, _) = l
+            .add_recording(
+                testutil::TEST_STREAM_ID,
+                db::RecordingToInsert {
+                    start: event_end,
+                    video_sample_entry_id: event.video_sample_entry_id,
+                    wall_duration_90k: 90_000,
+                    media_duration_90k: 90_000,
+                    sample_file_bytes: 2,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        l.mark_synced(plain_id).unwrap();
+        l.flush("add plain recording").unwrap();
+        l.insert_detections(
+            testutil::TEST_STREAM_ID,
+            &[db::Detection {
+                time: event.start,
+                class: "person".to_owned(),
+                score: 0.75,
+                left: 0.25,
+                top: 0.5,
+                width: 0.125,
+                height: 0.25,
+            }],
+        )
+        .unwrap();
+        let mut change = db::CameraChange {
+            short_name: "test camera".to_owned(),
+            ..Default::default()
+        };
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        change.streams[0] = db::StreamChange {
+            sample_file_dir_id: s.sample_file_dir_id,
+            config: s.config.clone(),
+        };
+        change.streams[0].config.retain_days = 1;
+        change.streams[0].config.retain_event_days = 3;
+        l.update_camera(testutil::TEST_CAMERA_ID, change).unwrap();
+
+        // A day after both end, only the plain recording is deleted, although it's the newer.
+        const DAY: recording::Duration =
+            recording::Duration(86_400 * recording::TIME_UNITS_PER_SEC);
+        let plain_end = event_end + recording::Duration(90_000);
+        super::delete_recordings(&mut l, testutil::TEST_STREAM_ID, 0, plain_end + DAY).unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 2);
+        l.flush("delete plain recording").unwrap();
+        let mut ids = Vec::new();
+        l.list_recordings_by_id(testutil::TEST_STREAM_ID, 0..i32::max_value(), &mut |r| {
+            ids.push(r.id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids, vec![event.id]);
+
+        // The event recording lasts until its own limit.
+        super::delete_recordings(
+            &mut l,
+            testutil::TEST_STREAM_ID,
+            0,
+            event_end + DAY * 3 - recording::Duration(1),
+        )
+        .unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 0);
+        super::delete_recordings(&mut l, testutil::TEST_STREAM_ID, 0, event_end + DAY * 3).unwrap();
+        let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+        assert_eq!(s.bytes_to_delete, 1);
+    }
+
     /// Tests the database flushing while a syncer is still processing a previous flush event.
     #[test]
     fn double_flush() {
@@ -0,0 +1,234 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! AAC audio handling.
+//!
+//! Retina depacketizes AAC (RFC 3640 `mpeg4-generic`) streams itself, supplying the ISO/IEC
+//! 14496-3 section 1.6.2.1 `AudioSpecificConfig` as the stream's "extra data" and raw AAC frames
+//! as samples. The frames can go into `.mp4` files as-is; this file builds the ISO/IEC 14496-14
+//! section 5.6 `MP4AudioSampleEntry` (`mp4a` box) which describes them.
+
+use base::{bail, err, Error};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use db::AudioSampleEntryToInsert;
+use std::convert::TryFrom;
+
+/// Sampling frequencies by `samplingFrequencyIndex`, ISO/IEC 14496-3 section 1.6.3.4.
+const SAMPLING_FREQUENCIES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The fields of an `AudioSpecificConfig` needed to build a sample entry.
+#[derive(Debug, PartialEq, Eq)]
+struct AudioSpecificConfig {
+    audio_object_type: u8,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// Reads big-endian bit fields from a byte slice.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: usize) -> Result<u32, Error> {
+        let mut v = 0;
+        for _ in 0..bits {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or_else(|| err!(InvalidArgument, msg("AudioSpecificConfig too short")))?;
+            v = (v << 1) | u32::from((byte >> (7 - (self.pos % 8))) & 1);
+            self.pos += 1;
+        }
+        Ok(v)
+    }
+}
+
+fn parse_audio_specific_config(config: &[u8]) -> Result<AudioSpecificConfig, Error> {
+    let mut r = BitReader {
+        data: config,
+        pos: 0,
+    };
+    let audio_object_type = match r.read(5)? {
+        31 => 32 + r.read(6)?,
+        t => t,
+    };
+    let sample_rate = match r.read(4)? {
+        0xf => r.read(24)?,
+        i => match SAMPLING_FREQUENCIES.get(i as usize) {
+            Some(&f) => f,
+            None => bail!(InvalidArgument, msg("reserved samplingFrequencyIndex {i}")),
+        },
+    };
+    let channels = match r.read(4)? {
+        0 => bail!(
+            Unimplemented,
+            msg("AAC channel configuration in program_config_element is unsupported")
+        ),
+        c @ 1..=6 => c as u16,
+        7 => 8,
+        c => bail!(InvalidArgument, msg("bad channelConfiguration {c}")),
+    };
+    if sample_rate == 0 {
+        bail!(InvalidArgument, msg("zero sample rate"));
+    }
+    Ok(AudioSpecificConfig {
+        audio_object_type: u8::try_from(audio_object_type).expect("at most 63"),
+        sample_rate,
+        channels,
+    })
+}
+
+/// Appends an MPEG-4 descriptor (ISO/IEC 14496-1 section 7.2.2.1) tag and length.
+fn append_descriptor_header(buf: &mut Vec<u8>, tag: u8, len: usize) -> Result<(), Error> {
+    let len = u8::try_from(len)
+        .ok()
+        .filter(|&l| l < 0x80)
+        .ok_or_else(|| err!(OutOfRange, msg("descriptor length {len} too large")))?;
+    buf.push(tag);
+    buf.push(len);
+    Ok(())
+}
+
+/// Builds an audio sample entry from the "extra data", an `AudioSpecificConfig`.
+pub fn parse_extra_data(extradata: &[u8]) -> Result<AudioSampleEntryToInsert, Error> {
+    let config = parse_audio_specific_config(extradata)?;
+    let mut sample_entry = Vec::with_capacity(64 + extradata.len());
+
+    // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
+    // length placeholder + type + reserved + data_reference_index = 1
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00mp4a\x00\x00\x00\x00\x00\x00\x00\x01");
+
+    // AudioSampleEntry, ISO/IEC 14496-12 section 12.2.3.
+    sample_entry.extend_from_slice(&[0; 8]); // reserved
+    sample_entry.write_u16::<BigEndian>(config.channels)?;
+    sample_entry.extend_from_slice(&[
+        0x00, 0x10, // samplesize = 16
+        0x00, 0x00, // pre_defined
+        0x00, 0x00, // reserved
+    ]);
+
+    // samplerate is a 16.16 fixed-point number, so rates >= 65536 Hz can't be represented.
+    // The media timescale is authoritative anyway.
+    let rate = if config.sample_rate < 0x10000 {
+        config.sample_rate << 16
+    } else {
+        0
+    };
+    sample_entry.write_u32::<BigEndian>(rate)?;
+
+    // ESDBox, ISO/IEC 14496-14 section 5.6.
+    let esds_len_pos = sample_entry.len();
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00esds\x00\x00\x00\x00");
+    let decoder_specific_info_len = 2 + extradata.len();
+    let decoder_config_len = 2 + 13 + decoder_specific_info_len;
+    let sl_config_len = 2 + 1;
+
+    // ES_Descriptor, ISO/IEC 14496-1 section 7.2.6.5.
+    append_descriptor_header(
+        &mut sample_entry,
+        0x03,
+        3 + decoder_config_len + sl_config_len,
+    )?;
+    sample_entry.extend_from_slice(&[
+        0x00, 0x00, // ES_ID
+        0x00, // flags
+    ]);
+
+    // DecoderConfigDescriptor, ISO/IEC 14496-1 section 7.2.6.6.
+    append_descriptor_header(&mut sample_entry, 0x04, decoder_config_len - 2)?;
+    sample_entry.extend_from_slice(&[
+        0x40, // objectTypeIndication = Audio ISO/IEC 14496-3
+        0x15, // streamType = AudioStream (0x05) << 2 | upStream = 0 | reserved = 1
+        0x00, 0x00, 0x00, // bufferSizeDB
+        0x00, 0x00, 0x00, 0x00, // maxBitrate
+        0x00, 0x00, 0x00, 0x00, // avgBitrate
+    ]);
+
+    // DecoderSpecificInfo, ISO/IEC 14496-1 section 7.2.6.7.
+    append_descriptor_header(&mut sample_entry, 0x05, extradata.len())?;
+    sample_entry.extend_from_slice(extradata);
+
+    // SLConfigDescriptor, ISO/IEC 14496-1 section 7.3.2.3.
+    append_descriptor_header(&mut sample_entry, 0x06, 1)?;
+    sample_entry.push(0x02); // predefined = reserved for use in MP4 files
+
+    // Fix up esds and mp4a box lengths.
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[esds_len_pos..esds_len_pos + 4],
+        u32::try_from(cur_pos - esds_len_pos).map_err(|_| err!(OutOfRange))?,
+    );
+    BigEndian::write_u32(
+        &mut sample_entry[0..4],
+        u32::try_from(cur_pos).map_err(|_| err!(OutOfRange))?,
+    );
+
+    Ok(AudioSampleEntryToInsert {
+        data: sample_entry,
+        rfc6381_codec: format!("mp4a.40.{}", config.audio_object_type),
+        sample_rate: config.sample_rate,
+        channels: config.channels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    #[test]
+    fn parse_audio_specific_config() {
+        testutil::init();
+
+        // AAC-LC, 48 kHz, mono.
+        assert_eq!(
+            super::parse_audio_specific_config(b"\x11\x88").unwrap(),
+            super::AudioSpecificConfig {
+                audio_object_type: 2,
+                sample_rate: 48_000,
+                channels: 1,
+            }
+        );
+
+        // AAC-LC, 16 kHz, stereo.
+        assert_eq!(
+            super::parse_audio_specific_config(b"\x14\x10").unwrap(),
+            super::AudioSpecificConfig {
+                audio_object_type: 2,
+                sample_rate: 16_000,
+                channels: 2,
+            }
+        );
+
+        super::parse_audio_specific_config(b"\x11").unwrap_err();
+    }
+
+    #[test]
+    fn sample_entry() {
+        testutil::init();
+        let e = super::parse_extra_data(b"\x11\x88").unwrap();
+        assert_eq!(e.rfc6381_codec, "mp4a.40.2");
+        assert_eq!(e.sample_rate, 48_000);
+        assert_eq!(e.channels, 1);
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x00, 0x00, 0x00, 0x4b, b'm', b'p', b'4', b'a',
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00,
+            0xbb, 0x80, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x27, b'e', b's', b'd', b's',
+            0x00, 0x00, 0x00, 0x00,
+            0x03, 0x19, 0x00, 0x00, 0x00,
+            0x04, 0x11, 0x40, 0x15, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x02, 0x11, 0x88,
+            0x06, 0x01, 0x02,
+        ];
+        assert_eq!(&e.data[..], expected);
+    }
+}
@@ -0,0 +1,503 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Object detection, accelerated by a Coral Edge TPU.
+//!
+//! A single [`ObjectDetector`] thread runs a TensorFlow Lite SSD model (such as Coral's
+//! `ssd_mobilenet_v2_coco_quant_postprocess_edgetpu.tflite`) on key frames sent by each
+//! stream's [`StreamDetector`], at most once per [`INTERVAL`]. Detections are stored in the
+//! database's `detection` table. Classes listed in the stream's
+//! [`db::json::DetectionConfig::signals`] also set signals while seen, in the same way as
+//! [`crate::motion`].
+//!
+//! This requires the `analytics` feature; otherwise [`ObjectDetector::start`] returns an
+//! `Unimplemented` error.
+
+#![cfg_attr(not(feature = "analytics"), allow(dead_code))]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use base::{bail, err, Error, FastHashMap};
+use bytes::Bytes;
+use db::json::DetectionConfig;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use tracing::{debug, warn};
+
+/// The minimum time between analyzed key frames of a stream.
+const INTERVAL: recording::Duration = recording::Duration(TIME_UNITS_PER_SEC);
+
+/// The number of frames which may be waiting for analysis before frames are dropped.
+const QUEUE_LEN: usize = 16;
+
+/// A stream's detection settings, shared with the detector thread.
+struct Stream {
+    id: i32,
+    short_name: String,
+    config: DetectionConfig,
+}
+
+/// A key frame queued for analysis.
+struct Request {
+    stream: Arc<Stream>,
+    time: recording::Time,
+    video_sample_entry_id: i32,
+    data: Bytes,
+}
+
+/// Runs object detection for all streams on a background thread.
+pub struct ObjectDetector {
+    tx: std::sync::mpsc::SyncSender<Request>,
+}
+
+impl ObjectDetector {
+    /// Loads the given model and labels, opens the Edge TPU, and starts the detector thread.
+    #[cfg(feature = "analytics")]
+    pub fn start<C: base::clock::Clocks + Clone>(
+        db: Arc<db::Database<C>>,
+        model_path: &Path,
+        labels_path: &Path,
+    ) -> Result<Self, Error> {
+        let labels = std::fs::read_to_string(labels_path)
+            .map_err(|e| err!(e, msg("unable to read labels {}", labels_path.display())))?;
+        let labels = parse_labels(&labels)?;
+        let model = std::fs::read(model_path)
+            .map_err(|e| err!(e, msg("unable to read model {}", model_path.display())))?;
+        let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_LEN);
+
+        // The interpreter is created on the detector thread, which reports back how that went.
+        let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+        std::thread::Builder::new()
+            .name("detector".to_owned())
+            .spawn(move || {
+                let model = match tflite::Model::new(model) {
+                    Ok(m) => {
+                        let _ = ready_tx.send(Ok(()));
+                        m
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                run(&db, model, &labels, rx)
+            })
+            .map_err(|e| err!(e, msg("unable to start detector thread")))?;
+        ready_rx
+            .recv()
+            .map_err(|_| err!(Internal, msg("detector thread exited during startup")))??;
+        Ok(ObjectDetector { tx })
+    }
+
+    #[cfg(not(feature = "analytics"))]
+    pub fn start<C: base::clock::Clocks + Clone>(
+        _db: Arc<db::Database<C>>,
+        _model_path: &Path,
+        _labels_path: &Path,
+    ) -> Result<Self, Error> {
+        bail!(
+            Unimplemented,
+            msg("object detection requires building Moonfire NVR with --features=analytics")
+        );
+    }
+
+    /// Returns a handle for sending frames of the given stream.
+    pub fn stream(
+        &self,
+        stream_id: i32,
+        short_name: String,
+        config: DetectionConfig,
+    ) -> StreamDetector {
+        StreamDetector {
+            tx: self.tx.clone(),
+            stream: Arc::new(Stream {
+                id: stream_id,
+                short_name,
+                config,
+            }),
+            next: recording::Time(i64::min_value()),
+        }
+    }
+}
+
+/// Sends a stream's frames to the [`ObjectDetector`].
+pub struct StreamDetector {
+    tx: std::sync::mpsc::SyncSender<Request>,
+    stream: Arc<Stream>,
+
+    /// The time at or after which the next key frame should be analyzed.
+    next: recording::Time,
+}
+
+impl StreamDetector {
+    /// Queues the given frame (in the length-prefixed form stored in sample files) for analysis
+    /// if it's a key frame and it's time for another.
+    ///
+    /// This never blocks. If the detector is falling behind, the frame is dropped.
+    pub fn send(
+        &mut self,
+        time: recording::Time,
+        data: &Bytes,
+        is_key: bool,
+        video_sample_entry_id: i32,
+    ) {
+        if !is_key || time < self.next {
+            return;
+        }
+        self.next = time + INTERVAL;
+        let req = Request {
+            stream: self.stream.clone(),
+            time,
+            video_sample_entry_id,
+            data: data.clone(),
+        };
+        if let Err(std::sync::mpsc::TrySendError::Full(_)) = self.tx.try_send(req) {
+            debug!(
+                "{}: object detection is falling behind; dropping frame",
+                self.stream.short_name
+            );
+        }
+    }
+}
+
+/// Parses a labels file, as distributed with Coral's models: one label per line, optionally
+/// preceded by its class index. Lines without an index are numbered by their position among
+/// the non-blank lines.
+///
+/// Returns labels indexed by class, with `None` for classes the file skips.
+fn parse_labels(input: &str) -> Result<Vec<Option<String>>, Error> {
+    let mut labels = Vec::new();
+    let lines = input.lines().map(str::trim).filter(|l| !l.is_empty());
+    for (i, line) in lines.enumerate() {
+        let (class, label) = line
+            .split_once(char::is_whitespace)
+            .and_then(|(n, label)| Some((n.parse::<usize>().ok()?, label.trim())))
+            .unwrap_or((i, line));
+        if class >= 1 << 16 {
+            bail!(InvalidArgument, msg("label index {class} is too large"));
+        }
+        if labels.len() <= class {
+            labels.resize(class + 1, None);
+        }
+        labels[class] = Some(label.to_owned());
+    }
+    if labels.is_empty() {
+        bail!(InvalidArgument, msg("labels file is empty"));
+    }
+    Ok(labels)
+}
+
+/// Converts an SSD model's postprocessed outputs to detections with at least `min_score`.
+///
+/// `boxes` has four elements per detection: `ymin`, `xmin`, `ymax`, and `xmax`, in fractions
+/// of the frame. Detections of classes without labels are skipped.
+fn detections(
+    time: recording::Time,
+    labels: &[Option<String>],
+    boxes: &[f32],
+    classes: &[f32],
+    scores: &[f32],
+    min_score: f32,
+) -> Vec<db::Detection> {
+    let mut out = Vec::new();
+    for ((b, &class), &score) in boxes.chunks_exact(4).zip(classes).zip(scores) {
+        if !(min_score..=1.).contains(&score) || class < 0. {
+            continue;
+        }
+        let Some(Some(class)) = labels.get(class as usize) else {
+            continue;
+        };
+        let (top, left) = (b[0].clamp(0., 1.), b[1].clamp(0., 1.));
+        let (bottom, right) = (b[2].clamp(top, 1.), b[3].clamp(left, 1.));
+        out.push(db::Detection {
+            time,
+            class: class.clone(),
+            score,
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        });
+    }
+    out
+}
+
+/// Stores detections and updates signals with the results of analyzing a frame.
+fn record<C: base::clock::Clocks + Clone>(
+    db: &db::Database<C>,
+    trackers: &mut FastHashMap<(i32, u32), crate::motion::Tracker>,
+    req: &Request,
+    detections: &[db::Detection],
+) {
+    let stream = &req.stream;
+    let mut l = db.lock();
+    if !detections.is_empty() {
+        if let Err(err) = l.insert_detections(stream.id, detections) {
+            warn!(%err, "{}: unable to store detections", stream.short_name);
+        }
+    }
+
+    // Several classes may share a signal; it's seen if any of them are.
+    let mut seen_by_signal: FastHashMap<u32, bool> = FastHashMap::default();
+    for (class, &signal) in &stream.config.signals {
+        *seen_by_signal.entry(signal).or_default() |= detections.iter().any(|d| &d.class == class);
+    }
+    for (signal, seen) in seen_by_signal {
+        let tracker = trackers.entry((stream.id, signal)).or_default();
+        let Some(range) = tracker.observe(req.time, seen) else {
+            continue;
+        };
+        if let Err(err) = l.update_signals(range, &[signal], &[stream.config.detected_state]) {
+            warn!(%err, "{}: unable to update signal {signal}", stream.short_name);
+        }
+    }
+}
+
+/// Analyzes frames from `rx` until all senders are dropped.
+#[cfg(feature = "analytics")]
+fn run<C: base::clock::Clocks + Clone>(
+    db: &db::Database<C>,
+    mut model: tflite::Model,
+    labels: &[Option<String>],
+    rx: std::sync::mpsc::Receiver<Request>,
+) {
+    tracing::info!("starting object detection");
+    let mut trackers = FastHashMap::default();
+    while let Ok(req) = rx.recv() {
+        let entry = db
+            .lock()
+            .video_sample_entries_by_id()
+            .get(&req.video_sample_entry_id)
+            .cloned();
+        let Some(entry) = entry else {
+            continue;
+        };
+        let outputs = match model.invoke(&entry, &req.data) {
+            Ok(o) => o,
+            Err(err) => {
+                warn!(%err, "{}: unable to detect objects", req.stream.short_name);
+                continue;
+            }
+        };
+        let min_score = f32::from(req.stream.config.min_score) / 100.;
+        let detections = detections(
+            req.time,
+            labels,
+            &outputs.boxes,
+            &outputs.classes,
+            &outputs.scores,
+            min_score,
+        );
+        record(db, &mut trackers, &req, &detections);
+    }
+    tracing::info!("ending object detection");
+}
+
+#[cfg(feature = "analytics")]
+mod tflite {
+    use base::{bail, err, Error};
+    use ffmpeg_next::format::Pixel;
+    use tracing::info;
+
+    /// The raw outputs of an SSD model with postprocessing.
+    pub(super) struct Outputs {
+        pub(super) boxes: Vec<f32>,
+        pub(super) classes: Vec<f32>,
+        pub(super) scores: Vec<f32>,
+    }
+
+    /// A model loaded onto the Edge TPU.
+    pub(super) struct Model {
+        interpreter: moonfire_tflite::Interpreter<'static>,
+
+        /// The width and height of the model's square RGB input.
+        side: usize,
+    }
+
+    impl Model {
+        pub(super) fn new(model: Vec<u8>) -> Result<Self, Error> {
+            // The model is loaded once and used for the life of the process.
+            let model: &'static [u8] = Box::leak(model.into_boxed_slice());
+            let model = moonfire_tflite::Model::from_static(model)
+                .map_err(|()| err!(InvalidArgument, msg("unable to load TensorFlow Lite model")))?;
+            let devices = moonfire_tflite::edgetpu::Devices::list();
+            let device = devices
+                .first()
+                .ok_or_else(|| err!(FailedPrecondition, msg("no Edge TPU device available")))?;
+            info!(
+                "using Edge TPU {:?}/{:?} for object detection",
+                device.type_(),
+                device.path()
+            );
+            let delegate = device
+                .create_delegate()
+                .map_err(|()| err!(Unknown, msg("unable to create Edge TPU delegate")))?;
+            let mut builder = moonfire_tflite::Interpreter::builder();
+            builder.add_owned_delegate(delegate);
+            let mut interpreter = builder
+                .build(&model)
+                .map_err(|()| err!(Unknown, msg("unable to create TensorFlow Lite interpreter")))?;
+            let input_len = interpreter.inputs()[0].bytes_mut().len();
+            let side = ((input_len / 3) as f64).sqrt() as usize;
+            if side * side * 3 != input_len || interpreter.outputs().len() < 3 {
+                bail!(
+                    InvalidArgument,
+                    msg("model should be an SSD model with postprocessing and square RGB input")
+                );
+            }
+            Ok(Model { interpreter, side })
+        }
+
+        /// Decodes the given key frame, scales it to the model's input size, and runs the model.
+        pub(super) fn invoke(
+            &mut self,
+            entry: &db::VideoSampleEntry,
+            frame: &[u8],
+        ) -> Result<Outputs, Error> {
+            let decoded = crate::jpeg::ffmpeg::decode_key_frame(entry, frame)?;
+            let side = self.side;
+            let rgb =
+                crate::jpeg::ffmpeg::scale_to(&decoded, Pixel::RGB24, side as u32, side as u32)?;
+            {
+                let mut inputs = self.interpreter.inputs();
+                let input = inputs[0].bytes_mut();
+                let (row_len, stride) = (3 * side, rgb.stride(0));
+                for y in 0..side {
+                    input[y * row_len..][..row_len]
+                        .copy_from_slice(&rgb.data(0)[y * stride..][..row_len]);
+                }
+            }
+            self.interpreter
+                .invoke()
+                .map_err(|()| err!(Unknown, msg("TensorFlow Lite invocation failed")))?;
+            let outputs = self.interpreter.outputs();
+            Ok(Outputs {
+                boxes: outputs[0].f32s().to_vec(),
+                classes: outputs[1].f32s().to_vec(),
+                scores: outputs[2].f32s().to_vec(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::FastHashMap;
+    use bytes::Bytes;
+    use db::json::DetectionConfig;
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::{testutil, Detection};
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_labels() {
+        testutil::init();
+        assert_eq!(
+            super::parse_labels("0  person\n1  bicycle\n\n3  motorcycle\n").unwrap(),
+            &[
+                Some("person".to_owned()),
+                Some("bicycle".to_owned()),
+                None,
+                Some("motorcycle".to_owned()),
+            ]
+        );
+        assert_eq!(
+            super::parse_labels("background\nperson\ntraffic light\n").unwrap(),
+            &[
+                Some("background".to_owned()),
+                Some("person".to_owned()),
+                Some("traffic light".to_owned()),
+            ]
+        );
+
+        // Blank lines don't shift the positions of later labels.
+        assert_eq!(
+            super::parse_labels("background\n\nperson\n  \ncar\n").unwrap(),
+            &[
+                Some("background".to_owned()),
+                Some("person".to_owned()),
+                Some("car".to_owned()),
+            ]
+        );
+        super::parse_labels("\n").unwrap_err();
+    }
+
+    #[test]
+    fn detections() {
+        testutil::init();
+        let labels = [Some("person".to_owned()), None, Some("car".to_owned())];
+        let t = recording::Time(90_000);
+        #[rustfmt::skip]
+        let boxes = [
+            0.25, 0.5, 0.75, 1.25, // clamped on the right.
+            0.,   0.,  1.,   1.,   // unlabeled class.
+            0.,   0.,  0.5,  0.5,  // score too low.
+        ];
+        let d = super::detections(t, &labels, &boxes, &[0., 1., 2.], &[0.75, 0.9, 0.25], 0.5);
+        assert_eq!(
+            d,
+            &[Detection {
+                time: t,
+                class: "person".to_owned(),
+                score: 0.75,
+                left: 0.5,
+                top: 0.25,
+                width: 0.5,
+                height: 0.5,
+            }]
+        );
+    }
+
+    #[test]
+    fn record() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(base::clock::RealClocks {});
+        let mut config = DetectionConfig {
+            min_score: DetectionConfig::DEFAULT_MIN_SCORE,
+            signals: Default::default(),
+            detected_state: DetectionConfig::DEFAULT_DETECTED_STATE,
+            unknown: Default::default(),
+        };
+        config.signals.insert("person".to_owned(), 1);
+        let stream = Arc::new(super::Stream {
+            id: testutil::TEST_STREAM_ID,
+            short_name: "test-main".to_owned(),
+            config,
+        });
+        let req = super::Request {
+            stream,
+            time: recording::Time(1430006400i64 * TIME_UNITS_PER_SEC),
+            video_sample_entry_id: 1,
+            data: Bytes::new(),
+        };
+        let car = Detection {
+            time: req.time,
+            class: "car".to_owned(),
+            score: 0.5,
+            left: 0.,
+            top: 0.,
+            width: 1.,
+            height: 1.,
+        };
+        let mut trackers = FastHashMap::default();
+
+        // The car is stored, and the person signal's tracker notes it wasn't seen.
+        super::record(&tdb.db, &mut trackers, &req, &[car.clone()]);
+        let mut rows = Vec::new();
+        tdb.db
+            .lock()
+            .list_detections(
+                testutil::TEST_STREAM_ID,
+                recording::Time::min_value()..recording::Time::max_value(),
+                None,
+                &mut |d| {
+                    rows.push(d);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(rows, &[car]);
+        assert!(trackers.contains_key(&(testutil::TEST_STREAM_ID, 1)));
+    }
+}
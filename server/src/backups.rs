@@ -0,0 +1,284 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Scheduled local backups of the database.
+//!
+//! A single task periodically writes an online backup (see [`db::Database::backup`]) into a
+//! directory as `moonfire-nvr-<UTC time>.db`, then removes all but the newest few. Each backup
+//! is written to a `.tmp` path and renamed into place once complete and synced, so any file with
+//! the final name is complete. The time of the newest backup is available via [`Status`] for
+//! `/api/`; on startup, it's taken from the existing files, so restarts don't cause extra
+//! backups.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{bail, err, Error};
+use chrono::TimeZone as _;
+use db::recording;
+use tracing::{info, warn};
+
+use crate::cmds::run::config::BackupConfig;
+
+const NAME_PREFIX: &str = "moonfire-nvr-";
+const NAME_SUFFIX: &str = ".db";
+const TMP_SUFFIX: &str = ".tmp";
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// The subdirectory of a sample file directory in which to write backups.
+const SAMPLE_FILE_DIR_SUBDIR: &str = "db-backups";
+
+/// How long to wait after a failed backup before trying again, if less than the interval.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The state of scheduled backups, as shared with the web interface.
+pub struct Status {
+    last_success: Mutex<Option<recording::Time>>,
+}
+
+impl Status {
+    /// Returns the time the newest complete backup was started, if any.
+    pub fn last_success(&self) -> Option<recording::Time> {
+        *self.last_success.lock().unwrap()
+    }
+}
+
+pub struct Backups {
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+    status: Arc<Status>,
+}
+
+impl Backups {
+    pub fn new(config: &BackupConfig, db: &db::LockedDatabase) -> Result<Self, Error> {
+        let dir = match (&config.dir, &config.sample_file_dir) {
+            (Some(d), None) => d.clone(),
+            (None, Some(p)) => {
+                if !db.sample_file_dirs_by_id().values().any(|d| d.path == *p) {
+                    bail!(
+                        NotFound,
+                        msg(
+                            "backup sampleFileDir {} isn't a sample file dir",
+                            p.display()
+                        )
+                    );
+                }
+                p.join(SAMPLE_FILE_DIR_SUBDIR)
+            }
+            _ => bail!(
+                InvalidArgument,
+                msg("backup config must set exactly one of dir and sampleFileDir")
+            ),
+        };
+        if config.interval_sec == 0 {
+            bail!(InvalidArgument, msg("backup intervalSec must be positive"));
+        }
+        if config.keep == 0 {
+            bail!(InvalidArgument, msg("backup keep must be positive"));
+        }
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| err!(e, msg("unable to create backup dir {}", dir.display())))?;
+        let status = Status {
+            last_success: Mutex::new(scan(&dir)?.last().map(|(t, _)| *t)),
+        };
+        Ok(Backups {
+            dir,
+            interval: Duration::from_secs(config.interval_sec),
+            keep: config.keep,
+            status: Arc::new(status),
+        })
+    }
+
+    pub fn status(&self) -> Arc<Status> {
+        self.status.clone()
+    }
+
+    /// Returns how long to wait before the next backup is due.
+    fn until_due(&self) -> Duration {
+        let Some(last) = self.status.last_success() else {
+            return Duration::ZERO;
+        };
+        let elapsed = chrono::Utc::now().timestamp() - last.unix_seconds();
+        self.interval
+            .saturating_sub(Duration::from_secs(u64::try_from(elapsed).unwrap_or(0)))
+    }
+
+    /// Writes a new backup, then removes old ones.
+    async fn backup<C: Clocks + Clone>(&self, db: &Arc<db::Database<C>>) -> Result<(), Error> {
+        let now = chrono::Utc::now();
+        let name = name(&now);
+        let path = self.dir.join(&name);
+        let tmp = self.dir.join(format!("{name}{TMP_SUFFIX}"));
+        tokio::task::spawn_blocking({
+            let db = db.clone();
+            let path = path.clone();
+            move || write(&db, &tmp, &path)
+        })
+        .await
+        .map_err(|e| err!(Internal, msg("backup task failed"), source(e)))??;
+        *self.status.last_success.lock().unwrap() = Some(recording::Time(
+            now.timestamp() * recording::TIME_UNITS_PER_SEC,
+        ));
+        info!("wrote database backup {}", path.display());
+        prune(&self.dir, self.keep)
+    }
+}
+
+/// Returns the file name of a backup started at `t`.
+fn name(t: &chrono::DateTime<chrono::Utc>) -> String {
+    format!("{NAME_PREFIX}{}{NAME_SUFFIX}", t.format(TIME_FORMAT))
+}
+
+/// Parses a backup file name into the time it was started.
+fn parse_name(name: &str) -> Option<recording::Time> {
+    let t = name.strip_prefix(NAME_PREFIX)?.strip_suffix(NAME_SUFFIX)?;
+    let t = chrono::NaiveDateTime::parse_from_str(t, TIME_FORMAT).ok()?;
+    Some(recording::Time(
+        chrono::Utc.from_utc_datetime(&t).timestamp() * recording::TIME_UNITS_PER_SEC,
+    ))
+}
+
+/// Writes a backup to `tmp`, syncs it, and renames it to `path`.
+fn write<C: Clocks + Clone>(db: &db::Database<C>, tmp: &Path, path: &Path) -> Result<(), Error> {
+    db.backup(tmp)?;
+    let result = std::fs::File::open(tmp)
+        .and_then(|f| f.sync_all())
+        .and_then(|()| std::fs::rename(tmp, path));
+    if let Err(e) = result {
+        if let Err(err) = std::fs::remove_file(tmp) {
+            warn!(%err, "unable to remove {}", tmp.display());
+        }
+        bail!(e, msg("unable to finish backup {}", path.display()));
+    }
+    let dir = path.parent().expect("backup path has a parent");
+    std::fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| err!(e, msg("unable to sync {}", dir.display())))?;
+    Ok(())
+}
+
+/// Returns the complete backups in `dir`, oldest first, removing any incomplete ones.
+fn scan(dir: &Path) -> Result<Vec<(recording::Time, PathBuf)>, Error> {
+    let mut backups = Vec::new();
+    for e in std::fs::read_dir(dir)
+        .map_err(|e| err!(e, msg("unable to read backup dir {}", dir.display())))?
+    {
+        let e = e?;
+        let name = e.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(NAME_PREFIX) && name.ends_with(TMP_SUFFIX) {
+            info!("removing incomplete backup {}", e.path().display());
+            std::fs::remove_file(e.path())?;
+        } else if let Some(t) = parse_name(name) {
+            backups.push((t, e.path()));
+        }
+    }
+    backups.sort_unstable();
+    Ok(backups)
+}
+
+/// Removes all but the newest `keep` backups in `dir`.
+fn prune(dir: &Path, keep: usize) -> Result<(), Error> {
+    let backups = scan(dir)?;
+    let excess = backups.len().saturating_sub(keep);
+    for (_, p) in &backups[..excess] {
+        std::fs::remove_file(p).map_err(|e| err!(e, msg("unable to remove {}", p.display())))?;
+        info!("removed old database backup {}", p.display());
+    }
+    Ok(())
+}
+
+/// Runs scheduled backups until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    backups: Backups,
+) {
+    info!(
+        "writing database backups to {} every {:?}, keeping {}",
+        backups.dir.display(),
+        backups.interval,
+        backups.keep
+    );
+    let mut wait = backups.until_due();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+        wait = match backups.backup(&db).await {
+            Ok(()) => backups.interval,
+            Err(err) => {
+                warn!(%err, "database backup failed");
+                backups.interval.min(RETRY_INTERVAL)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn names() {
+        let t = chrono::Utc.with_ymd_and_hms(2024, 10, 15, 1, 2, 3).unwrap();
+        let n = name(&t);
+        assert_eq!(n, "moonfire-nvr-20241015T010203Z.db");
+        assert_eq!(
+            parse_name(&n),
+            Some(recording::Time(
+                t.timestamp() * recording::TIME_UNITS_PER_SEC
+            ))
+        );
+        assert_eq!(parse_name("moonfire-nvr-20241015T010203Z.db.tmp"), None);
+        assert_eq!(parse_name("moonfire-nvr-junk.db"), None);
+        assert_eq!(parse_name("meta"), None);
+    }
+
+    #[test]
+    fn scan_and_prune() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let dir = tmpdir.path();
+        for n in [
+            "moonfire-nvr-20241013T000000Z.db",
+            "moonfire-nvr-20241011T000000Z.db",
+            "moonfire-nvr-20241012T000000Z.db",
+            "moonfire-nvr-20241014T000000Z.db.tmp",
+            "unrelated.db",
+        ] {
+            std::fs::write(dir.join(n), b"").unwrap();
+        }
+        let backups = scan(dir).unwrap();
+        let names: Vec<_> = backups
+            .iter()
+            .map(|(_, p)| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "moonfire-nvr-20241011T000000Z.db",
+                "moonfire-nvr-20241012T000000Z.db",
+                "moonfire-nvr-20241013T000000Z.db",
+            ]
+        );
+        assert!(!dir.join("moonfire-nvr-20241014T000000Z.db.tmp").exists());
+
+        prune(dir, 2).unwrap();
+        assert!(!dir.join("moonfire-nvr-20241011T000000Z.db").exists());
+        assert!(dir.join("moonfire-nvr-20241012T000000Z.db").exists());
+        assert!(dir.join("moonfire-nvr-20241013T000000Z.db").exists());
+        assert!(dir.join("unrelated.db").exists());
+    }
+}
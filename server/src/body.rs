@@ -13,6 +13,12 @@
 //! Some day I expect [bytes::Bytes] will expose its vtable (see link above),
 //! allowing us to minimize reference-counting while using the standard
 //! [hyper::Body].
+//!
+//! Sample data chunks refer to a `mmap()` of the sample file rather than a
+//! copy (see [`db::dir::FileChunk`]). hyper passes them unchanged to
+//! [`crate::web::accept::Conn`], which recognizes them and sends plain HTTP
+//! responses' sample data with `sendfile`. TLS connections still read it into
+//! userspace to encrypt it.
 
 use base::Error;
 use futures::{stream, Stream};
@@ -60,6 +66,15 @@ impl From<Vec<u8>> for Chunk {
     }
 }
 
+impl From<db::dir::FileChunk> for Chunk {
+    fn from(c: db::dir::FileChunk) -> Self {
+        match c {
+            db::dir::FileChunk::Owned(v) => Chunk(ARefss::new(v)),
+            db::dir::FileChunk::Mapped(m) => Chunk(ARefss::new(Box::new(m)).map(|m| m.as_ref())),
+        }
+    }
+}
+
 impl hyper::body::Buf for Chunk {
     fn remaining(&self) -> usize {
         self.0.len()
@@ -0,0 +1,164 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to make a consistent copy of the database.
+
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use base::{bail, err, Error};
+use bpaf::Bpaf;
+use db::dir;
+use hyper::body::HttpBody as _;
+use nix::fcntl::FlockArg;
+use tracing::{info, warn};
+
+use super::run::config::AddressConfig;
+
+/// Backs up the database to a new file.
+///
+/// If no server is running, this copies the database directly. Otherwise, it
+/// asks the running server for an online backup via its Unix-domain socket,
+/// which must have `ownUidIsPrivileged` set, so there's no need to stop the
+/// server. Either way, the result is a consistent snapshot which can be
+/// restored by stopping the server and putting it in place as the `db` file
+/// in the database directory.
+///
+/// Sample files aren't included.
+#[derive(Bpaf, Debug)]
+#[bpaf(command("backup"))]
+pub struct Args {
+    #[bpaf(external(crate::parse_db_dir))]
+    db_dir: PathBuf,
+
+    /// Path to the running server's configuration file, used to find its
+    /// Unix-domain socket.
+    #[bpaf(
+        short,
+        long,
+        argument("PATH"),
+        fallback("/etc/moonfire-nvr.toml".into()),
+        debug_fallback
+    )]
+    config: PathBuf,
+
+    /// New file in which to write the backup.
+    #[bpaf(argument("FILE"))]
+    out: PathBuf,
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    if args.out.exists() {
+        bail!(AlreadyExists, msg("{} already exists", args.out.display()));
+    }
+    if server_is_running(&args.db_dir)? {
+        info!("Database is locked by a running server; requesting an online backup.");
+        let sock = find_socket(&args.config)?;
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        if let Err(e) = rt.block_on(fetch(&sock, &args.out)) {
+            if args.out.exists() {
+                if let Err(err) = std::fs::remove_file(&args.out) {
+                    warn!(%err, "unable to remove partial backup {}", args.out.display());
+                }
+            }
+            return Err(e);
+        }
+    } else {
+        let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+        let out = args.out.to_str().ok_or_else(|| {
+            err!(
+                InvalidArgument,
+                msg("non-UTF-8 path {}", args.out.display())
+            )
+        })?;
+        conn.execute("vacuum into ?", [out])?;
+    }
+    info!("Wrote backup to {}.", args.out.display());
+    Ok(0)
+}
+
+/// Returns true if the database directory is exclusively locked, as by a read-write server.
+fn server_is_running(db_dir: &Path) -> Result<bool, Error> {
+    let dir = dir::Fd::open(db_dir, false)
+        .map_err(|e| err!(e, msg("unable to open db dir {}", db_dir.display())))?;
+    match dir.lock(FlockArg::LockSharedNonblock) {
+        Ok(()) => Ok(false),
+        Err(nix::Error::EWOULDBLOCK) => Ok(true),
+        Err(e) => Err(err!(e, msg("unable to lock db dir {}", db_dir.display()))),
+    }
+}
+
+/// Finds the path of a Unix-domain socket on which the server treats this process as privileged.
+fn find_socket(config_path: &Path) -> Result<PathBuf, Error> {
+    let config = super::run::read_config(config_path).map_err(|e| {
+        err!(
+            e,
+            msg("unable to load config file {}", config_path.display())
+        )
+    })?;
+    config
+        .binds
+        .into_iter()
+        .find_map(|b| match b.address {
+            AddressConfig::Unix(p) if b.own_uid_is_privileged => Some(p),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            err!(
+                FailedPrecondition,
+                msg(
+                    "{} has no Unix-domain socket bind with ownUidIsPrivileged set",
+                    config_path.display()
+                )
+            )
+        })
+}
+
+/// Fetches `/api/backup.db` from the server listening at `sock`, writing it to `out`.
+async fn fetch(sock: &Path, out: &Path) -> Result<(), Error> {
+    let stream = tokio::net::UnixStream::connect(sock)
+        .await
+        .map_err(|e| err!(e, msg("unable to connect to {}", sock.display())))?;
+    let (mut sender, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(|e| err!(Unavailable, source(e)))?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            warn!(%err, "backup connection failed");
+        }
+    });
+    let req = hyper::Request::get("/api/backup.db")
+        .header(http::header::HOST, "localhost")
+        .body(hyper::Body::empty())
+        .expect("request is valid");
+    let resp = sender
+        .send_request(req)
+        .await
+        .map_err(|e| err!(Unavailable, source(e)))?;
+    let status = resp.status();
+    let mut body = resp.into_body();
+    if !status.is_success() {
+        let msg = hyper::body::to_bytes(body).await.unwrap_or_default();
+        bail!(
+            Unknown,
+            msg(
+                "server returned {status}: {}",
+                String::from_utf8_lossy(&msg).trim()
+            )
+        );
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(out)
+        .map_err(|e| err!(e, msg("unable to create {}", out.display())))?;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| err!(Unavailable, source(e)))?;
+        f.write_all(&chunk)?;
+    }
+    f.sync_all()?;
+    Ok(())
+}
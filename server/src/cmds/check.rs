@@ -20,31 +20,89 @@ pub struct Args {
     compare_lens: bool,
 
     /// Trashes sample files without matching recording rows in the database.
-    /// This addresses `Missing ... row` errors. The ids are added to the
-    /// `garbage` table to indicate the files need to be deleted. Garbage is
-    /// collected on normal startup.
+    /// This addresses `Missing ... row` and `... for unknown stream` errors.
+    /// The ids are added to the `garbage` table to indicate the files need to
+    /// be deleted. Garbage is collected on normal startup.
     trash_orphan_sample_files: bool,
 
     /// Deletes recording rows in the database without matching sample files.
-    /// This addresses `Recording ... missing file` errors.
+    /// This addresses `Recording ... missing file` errors, unless
+    /// `--mark-missing-recordings` is also given.
     delete_orphan_rows: bool,
 
     /// Trashes recordings when their database rows appear corrupt.
-    /// This addresses "bad video_index" errors. The ids are added to the
+    /// This addresses "bad index" errors. The ids are added to the
     /// `garbage` table to indicate their files need to be deleted. Garbage is
     /// collected on normal startup.
     trash_corrupt_rows: bool,
+
+    /// Deletes leftover database rows of recordings which are already in the
+    /// `garbage` table, finishing their interrupted deletion. This addresses
+    /// `Unexpected ... row` errors for recordings being deleted.
+    requeue_garbage: bool,
+
+    /// Flags recordings whose sample files are missing as corrupt, keeping
+    /// their rows. This addresses `Recording ... missing file` errors.
+    mark_missing_recordings: bool,
+
+    /// Makes all of the repairs above: equivalent to
+    /// `--trash-orphan-sample-files --delete-orphan-rows --trash-corrupt-rows
+    /// --requeue-garbage --mark-missing-recordings`.
+    /// Consider running with `--dry-run` first.
+    fix: bool,
+
+    /// Logs the repairs that would be made, without making them.
+    dry_run: bool,
+
+    /// Prints the schema differences to stdout as JSON (`null` if the schema
+    /// is as expected), for use by scripts.
+    json: bool,
+
+    /// The number of threads with which to read each sample file directory
+    /// when comparing lengths or scrubbing. Directories are read in parallel
+    /// with each other regardless. Higher values may help on RAID arrays or
+    /// SSDs.
+    #[bpaf(argument("N"), fallback(4), debug_fallback)]
+    io_concurrency: usize,
+
+    /// Re-reads every sample file and compares it to the hash recorded when
+    /// it was written. Corrupt recordings are reported and flagged in the
+    /// database. This reads all recorded video, so it may take hours.
+    scrub: bool,
+
+    /// Limits the rate of scrub reads, in bytes per second, to leave I/O
+    /// capacity for other uses of the disks. 0 means no limit.
+    #[bpaf(argument("BYTES"), fallback(32 << 20), debug_fallback)]
+    scrub_bytes_per_sec: u64,
+
+    /// File holding the master key for encrypted sample files, as in the
+    /// `sampleFileKeyPath` of the `run` configuration. Without it, `--scrub`
+    /// skips encrypted recordings.
+    #[bpaf(argument("PATH"))]
+    sample_file_key_path: Option<PathBuf>,
 }
 
 pub fn run(args: Args) -> Result<i32, Error> {
+    let sample_file_key = match args.sample_file_key_path {
+        Some(ref p) => Some(db::dir::crypto::MasterKey::read(p)?),
+        None => None,
+    };
     let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
     check::run(
         &mut conn,
         &check::Options {
             compare_lens: args.compare_lens,
-            trash_orphan_sample_files: args.trash_orphan_sample_files,
-            delete_orphan_rows: args.delete_orphan_rows,
-            trash_corrupt_rows: args.trash_corrupt_rows,
+            trash_orphan_sample_files: args.fix || args.trash_orphan_sample_files,
+            delete_orphan_rows: args.fix || args.delete_orphan_rows,
+            trash_corrupt_rows: args.fix || args.trash_corrupt_rows,
+            requeue_garbage: args.fix || args.requeue_garbage,
+            mark_missing_recordings: args.fix || args.mark_missing_recordings,
+            dry_run: args.dry_run,
+            json: args.json,
+            io_concurrency: args.io_concurrency,
+            scrub: args.scrub,
+            scrub_bytes_per_sec: args.scrub_bytes_per_sec,
+            sample_file_key,
         },
     )
 }
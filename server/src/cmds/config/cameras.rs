@@ -22,16 +22,30 @@ struct Camera {
     onvif_base_url: String,
     username: String,
     password: String,
+    onvif_motion_signal: String,
+    onvif_tamper_signal: String,
     streams: [Stream; db::NUM_STREAM_TYPES],
 }
 
 #[derive(Debug, Default)]
 struct Stream {
     url: String,
-    record: bool,
+    mode: &'static str,
+    record_audio: bool,
+    transcode_audio: bool,
     flush_if_sec: String,
+    pre_roll_sec: String,
+    retain_days: String,
+    retain_event_days: String,
+    motion_signal: String,
+    motion_sensitivity: String,
     rtsp_transport: &'static str,
+    rtsp_connect_timeout_sec: String,
+    rtsp_frame_timeout_sec: String,
+    srtp: bool,
     sample_file_dir_id: Option<i32>,
+    archive_sample_file_dir_id: Option<i32>,
+    archive_retain: String,
 }
 
 /// Builds a `Camera` from an active `edit_camera_dialog`. No validation.
@@ -67,12 +81,26 @@ fn get_camera(siv: &mut Cursive) -> Camera {
         .get_content()
         .as_str()
         .to_owned();
+    let onvif_motion_signal = siv
+        .find_name::<views::EditView>("onvif_motion_signal")
+        .unwrap()
+        .get_content()
+        .as_str()
+        .to_owned();
+    let onvif_tamper_signal = siv
+        .find_name::<views::EditView>("onvif_tamper_signal")
+        .unwrap()
+        .get_content()
+        .as_str()
+        .to_owned();
     let mut camera = Camera {
         short_name,
         description,
         onvif_base_url,
         username,
         password,
+        onvif_motion_signal,
+        onvif_tamper_signal,
         streams: Default::default(),
     };
     for &t in &db::ALL_STREAM_TYPES {
@@ -82,8 +110,21 @@ fn get_camera(siv: &mut Cursive) -> Camera {
             .get_content()
             .as_str()
             .to_owned();
-        let record = siv
-            .find_name::<views::Checkbox>(&format!("{}_record", t))
+        let mode = *siv
+            .find_name::<views::SelectView<&'static str>>(&format!("{}_mode", t))
+            .unwrap()
+            .selection()
+            .unwrap();
+        let record_audio = siv
+            .find_name::<views::Checkbox>(&format!("{}_record_audio", t))
+            .unwrap()
+            .is_checked();
+        let transcode_audio = siv
+            .find_name::<views::Checkbox>(&format!("{}_transcode_audio", t))
+            .unwrap()
+            .is_checked();
+        let srtp = siv
+            .find_name::<views::Checkbox>(&format!("{}_srtp", t))
             .unwrap()
             .is_checked();
         let rtsp_transport = *siv
@@ -91,23 +132,88 @@ fn get_camera(siv: &mut Cursive) -> Camera {
             .unwrap()
             .selection()
             .unwrap();
+        let rtsp_connect_timeout_sec = siv
+            .find_name::<views::EditView>(&format!("{}_rtsp_connect_timeout_sec", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let rtsp_frame_timeout_sec = siv
+            .find_name::<views::EditView>(&format!("{}_rtsp_frame_timeout_sec", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
         let flush_if_sec = siv
             .find_name::<views::EditView>(&format!("{}_flush_if_sec", t))
             .unwrap()
             .get_content()
             .as_str()
             .to_owned();
+        let pre_roll_sec = siv
+            .find_name::<views::EditView>(&format!("{}_pre_roll_sec", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let retain_days = siv
+            .find_name::<views::EditView>(&format!("{}_retain_days", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let retain_event_days = siv
+            .find_name::<views::EditView>(&format!("{}_retain_event_days", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let motion_signal = siv
+            .find_name::<views::EditView>(&format!("{}_motion_signal", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
+        let motion_sensitivity = siv
+            .find_name::<views::EditView>(&format!("{}_motion_sensitivity", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
         let sample_file_dir_id = *siv
             .find_name::<views::SelectView<Option<i32>>>(&format!("{}_sample_file_dir", t))
             .unwrap()
             .selection()
             .unwrap();
+        let archive_sample_file_dir_id = *siv
+            .find_name::<views::SelectView<Option<i32>>>(&format!("{}_archive_dir", t))
+            .unwrap()
+            .selection()
+            .unwrap();
+        let archive_retain = siv
+            .find_name::<views::EditView>(&format!("{}_archive_retain", t))
+            .unwrap()
+            .get_content()
+            .as_str()
+            .to_owned();
         camera.streams[t.index()] = Stream {
             url,
-            record,
+            mode,
+            record_audio,
+            transcode_audio,
             flush_if_sec,
+            pre_roll_sec,
+            retain_days,
+            retain_event_days,
+            motion_signal,
+            motion_sensitivity,
             rtsp_transport,
+            rtsp_connect_timeout_sec,
+            rtsp_frame_timeout_sec,
+            srtp,
             sample_file_dir_id,
+            archive_sample_file_dir_id,
+            archive_retain,
         };
     }
     tracing::trace!("camera is: {:#?}", &camera);
@@ -154,7 +260,21 @@ fn parse_url(
 }
 
 fn parse_stream_url(type_: db::StreamType, raw: &str) -> Result<Option<Url>, Error> {
-    parse_url(&format!("{} stream url", type_.as_str()), raw, &["rtsp"])
+    parse_url(
+        &format!("{} stream url", type_.as_str()),
+        raw,
+        &["rtsp", "rtsps", "http", "https", "rtmp", "v4l2"],
+    )
+}
+
+/// Parses an optional signal id field.
+fn parse_signal(field_name: &str, raw: &str) -> Result<Option<u32>, Error> {
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    raw.parse()
+        .map(Some)
+        .map_err(|_| err!(InvalidArgument, msg("{field_name} must be a signal id")))
 }
 
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
@@ -172,23 +292,61 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
             parse_url("onvif_base_url", &camera.onvif_base_url, &["http", "https"])?;
         change.config.username = camera.username;
         change.config.password = camera.password;
+        let motion_signal_id = parse_signal("onvif motion signal", &camera.onvif_motion_signal)?;
+        let tamper_signal_id = parse_signal("onvif tamper signal", &camera.onvif_tamper_signal)?;
+        change.config.onvif_events = if motion_signal_id.is_none() && tamper_signal_id.is_none() {
+            None
+        } else {
+            if change.config.onvif_base_url.is_none() {
+                bail!(
+                    InvalidArgument,
+                    msg("ONVIF event signals require an onvif_base_url")
+                );
+            }
+            let mut events = change.config.onvif_events.take().unwrap_or_default();
+            events.motion_signal_id = motion_signal_id;
+            events.tamper_signal_id = tamper_signal_id;
+            Some(events)
+        };
         for (i, stream) in camera.streams.iter().enumerate() {
             let type_ = db::StreamType::from_index(i).unwrap();
-            if stream.record && (stream.url.is_empty() || stream.sample_file_dir_id.is_none()) {
+            if !stream.mode.is_empty()
+                && (stream.url.is_empty() || stream.sample_file_dir_id.is_none())
+            {
                 bail!(
                     InvalidArgument,
                     msg("can't record {type_} stream without RTSP URL and sample file directory"),
                 );
             }
             let stream_change = &mut change.streams[i];
-            stream_change.config.mode = (if stream.record {
-                db::json::STREAM_MODE_RECORD
-            } else {
-                ""
-            })
-            .to_owned();
+            stream_change.config.mode = stream.mode.to_owned();
             stream_change.config.url = parse_stream_url(type_, &stream.url)?;
             stream_change.config.rtsp_transport = stream.rtsp_transport.to_owned();
+            stream_change.config.rtsp_connect_timeout_sec =
+                if stream.rtsp_connect_timeout_sec.is_empty() {
+                    0
+                } else {
+                    stream.rtsp_connect_timeout_sec.parse().map_err(|_| {
+                        err!(
+                            InvalidArgument,
+                            msg("connect timeout sec for {type_} must be a non-negative integer"),
+                        )
+                    })?
+                };
+            stream_change.config.rtsp_frame_timeout_sec =
+                if stream.rtsp_frame_timeout_sec.is_empty() {
+                    0
+                } else {
+                    stream.rtsp_frame_timeout_sec.parse().map_err(|_| {
+                        err!(
+                            InvalidArgument,
+                            msg("frame timeout sec for {type_} must be a non-negative integer"),
+                        )
+                    })?
+                };
+            stream_change.config.srtp = stream.srtp;
+            stream_change.config.record_audio = stream.record_audio;
+            stream_change.config.transcode_audio = stream.transcode_audio;
             stream_change.sample_file_dir_id = stream.sample_file_dir_id;
             stream_change.config.flush_if_sec = if stream.flush_if_sec.is_empty() {
                 0
@@ -200,6 +358,77 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
                     )
                 })?
             };
+            stream_change.config.pre_roll_sec = if stream.pre_roll_sec.is_empty() {
+                0
+            } else {
+                stream.pre_roll_sec.parse().map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("pre-roll sec for {type_} must be a non-negative integer"),
+                    )
+                })?
+            };
+            stream_change.config.retain_days = if stream.retain_days.is_empty() {
+                0
+            } else {
+                stream.retain_days.parse().map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("retain days for {type_} must be a non-negative integer"),
+                    )
+                })?
+            };
+            stream_change.config.retain_event_days = if stream.retain_event_days.is_empty() {
+                0
+            } else {
+                stream.retain_event_days.parse().map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("retain event days for {type_} must be a non-negative integer"),
+                    )
+                })?
+            };
+            stream_change.config.archive_sample_file_dir_id = stream.archive_sample_file_dir_id;
+            stream_change.config.archive_retain_bytes = if stream.archive_retain.is_empty() {
+                0
+            } else {
+                decode_size(&stream.archive_retain).map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("archive retain for {type_} must be a size such as 2T"),
+                    )
+                })?
+            };
+            stream_change.config.motion = if stream.motion_signal.is_empty() {
+                None
+            } else {
+                let signal_id = stream.motion_signal.parse().map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("motion signal for {type_} must be a signal id"),
+                    )
+                })?;
+
+                // Keep settings not editable here, such as regions.
+                let mut motion = stream_change
+                    .config
+                    .motion
+                    .take()
+                    .unwrap_or_else(|| db::json::MotionConfig::new(signal_id));
+                motion.signal_id = signal_id;
+                motion.sensitivity = if stream.motion_sensitivity.is_empty() {
+                    db::json::MotionConfig::DEFAULT_SENSITIVITY
+                } else {
+                    match stream.motion_sensitivity.parse() {
+                        Ok(s @ 1..=100) => s,
+                        _ => bail!(
+                            InvalidArgument,
+                            msg("motion sensitivity for {type_} must be between 1 and 100"),
+                        ),
+                    }
+                };
+                Some(motion)
+            };
         }
         if let Some(id) = id {
             l.update_camera(id, change)
@@ -232,15 +461,30 @@ fn press_test_inner(
     username: String,
     password: String,
     transport: retina::client::Transport,
+    connect_timeout: std::time::Duration,
+    srtp: bool,
+    camera_config: db::json::CameraConfig,
 ) -> Result<String, Error> {
     let _enter = handle.enter();
+    let tls = if matches!(url.scheme(), "rtsps" | "https") {
+        Some(crate::rtsps::client_config(&camera_config)?)
+    } else {
+        None
+    };
     let options = stream::Options {
-        session: retina::client::SessionOptions::default().creds(if username.is_empty() {
+        session: retina::client::SessionOptions::default(),
+        setup: retina::client::SetupOptions::default().transport(transport),
+        audio_setup: None,
+        transcode_audio: false,
+        connect_timeout,
+        frame_timeout: stream::DEFAULT_TIMEOUT,
+        tls,
+        srtp,
+        creds: if username.is_empty() {
             None
         } else {
             Some(retina::client::Credentials { username, password })
-        }),
-        setup: retina::client::SetupOptions::default().transport(transport),
+        },
     };
     let stream = stream::OPENER.open("test stream".to_owned(), url, options)?;
     let video_sample_entry = stream.video_sample_entry();
@@ -258,10 +502,17 @@ fn press_test_inner(
     ))
 }
 
-fn press_test(siv: &mut Cursive, t: db::StreamType) {
+fn press_test(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>, t: db::StreamType) {
     let c = get_camera(siv);
+
+    // The dialog doesn't edit the rtsps certificate settings; use the saved ones, if any.
+    let camera_config = id
+        .and_then(|id| db.lock().cameras_by_id().get(&id).map(|c| c.config.clone()))
+        .unwrap_or_default();
     let s = &c.streams[t.index()];
     let transport = retina::client::Transport::from_str(s.rtsp_transport).unwrap_or_default();
+    let connect_timeout = stream::timeout(s.rtsp_connect_timeout_sec.parse().unwrap_or(0));
+    let srtp = s.srtp;
     let url = match parse_stream_url(t, &s.url) {
         Ok(Some(u)) => u,
         _ => panic!(
@@ -291,7 +542,16 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
     // is set up by the config subcommand's run().
     let handle = tokio::runtime::Handle::current();
     ::std::thread::spawn(move || {
-        let r = press_test_inner(handle, url.clone(), username, password, transport);
+        let r = press_test_inner(
+            handle,
+            url.clone(),
+            username,
+            password,
+            transport,
+            connect_timeout,
+            srtp,
+            camera_config,
+        );
         sink.send(Box::new(move |siv: &mut Cursive| {
             // Polling is no longer necessary.
             siv.set_fps(0);
@@ -449,6 +709,18 @@ fn edit_stream_url(type_: db::StreamType, content: &str, mut test_button: ViewRe
     test_button.set_enabled(enable_test);
 }
 
+/// Enables or disables a stream's "Test" button after its URL is set programmatically.
+pub(super) fn refresh_test_button(siv: &mut Cursive, type_: db::StreamType) {
+    let content = siv
+        .find_name::<views::EditView>(&format!("{}_url", type_))
+        .unwrap()
+        .get_content();
+    let test_button = siv
+        .find_name::<views::Button>(&format!("{}_test", type_))
+        .unwrap();
+    edit_stream_url(type_, &content, test_button);
+}
+
 fn load_camera_values(
     db: &Arc<db::Database>,
     camera_id: i32,
@@ -460,6 +732,7 @@ fn load_camera_values(
             db.lock()
                 .sample_file_dirs_by_id()
                 .iter()
+                .filter(|(_, d)| !d.archive)
                 .map(|(&id, d)| (d.path.to_owned(), Some(id))),
         )
         .collect();
@@ -473,13 +746,33 @@ fn load_camera_values(
             .expect("missing TextView");
     }
 
+    let archive_dirs: Vec<_> = l
+        .sample_file_dirs_by_id()
+        .iter()
+        .filter_map(|(&id, d)| if d.archive { Some(id) } else { None })
+        .collect();
+
     let mut bytes = 0;
     for (i, sid) in camera.streams.iter().enumerate() {
         let t = db::StreamType::from_index(i).unwrap();
 
         // Find the index into dirs of the stored sample file dir.
         let mut selected_dir = 0;
+        let mut selected_archive_dir = 0;
         if let Some(s) = sid.map(|sid| l.streams_by_id().get(&sid).unwrap()) {
+            if let Some(id) = s.config.archive_sample_file_dir_id {
+                if let Some(i) = archive_dirs.iter().position(|&d| d == id) {
+                    selected_archive_dir = i + 1;
+                }
+            }
+            if s.config.archive_retain_bytes != 0 {
+                dialog.call_on_name(
+                    &format!("{}_archive_retain", t),
+                    |v: &mut views::EditView| {
+                        v.set_content(encode_size(s.config.archive_retain_bytes))
+                    },
+                );
+            }
             if let Some(id) = s.sample_file_dir_id {
                 for (i, &(_, d_id)) in dirs.iter().skip(1).enumerate() {
                     if Some(id) == d_id {
@@ -517,11 +810,23 @@ fn load_camera_values(
                 |v: &mut views::TextView| v.set_content(u),
             );
             dialog.call_on_name(
-                &format!("{}_record", t.as_str()),
-                |v: &mut views::Checkbox| {
-                    v.set_checked(s.config.mode == db::json::STREAM_MODE_RECORD)
+                &format!("{}_mode", t.as_str()),
+                |v: &mut views::SelectView<&'static str>| {
+                    v.set_selection(match s.config.mode.as_str() {
+                        db::json::STREAM_MODE_RECORD => 1,
+                        db::json::STREAM_MODE_ON_SIGNAL => 2,
+                        _ => 0,
+                    })
                 },
             );
+            dialog.call_on_name(
+                &format!("{}_record_audio", t.as_str()),
+                |v: &mut views::Checkbox| v.set_checked(s.config.record_audio),
+            );
+            dialog.call_on_name(
+                &format!("{}_transcode_audio", t.as_str()),
+                |v: &mut views::Checkbox| v.set_checked(s.config.transcode_audio),
+            );
             dialog.call_on_name(
                 &format!("{}_rtsp_transport", t.as_str()),
                 |v: &mut views::SelectView<&'static str>| {
@@ -532,17 +837,63 @@ fn load_camera_values(
                     })
                 },
             );
+            dialog.call_on_name(&format!("{}_srtp", t), |v: &mut views::Checkbox| {
+                v.set_checked(s.config.srtp)
+            });
+            dialog.call_on_name(
+                &format!("{}_rtsp_connect_timeout_sec", t),
+                |v: &mut views::EditView| {
+                    v.set_content(s.config.rtsp_connect_timeout_sec.to_string())
+                },
+            );
+            dialog.call_on_name(
+                &format!("{}_rtsp_frame_timeout_sec", t),
+                |v: &mut views::EditView| {
+                    v.set_content(s.config.rtsp_frame_timeout_sec.to_string())
+                },
+            );
             dialog.call_on_name(&format!("{}_flush_if_sec", t), |v: &mut views::EditView| {
                 v.set_content(s.config.flush_if_sec.to_string())
             });
+            dialog.call_on_name(&format!("{}_pre_roll_sec", t), |v: &mut views::EditView| {
+                v.set_content(s.config.pre_roll_sec.to_string())
+            });
+            dialog.call_on_name(&format!("{}_retain_days", t), |v: &mut views::EditView| {
+                v.set_content(s.config.retain_days.to_string())
+            });
+            dialog.call_on_name(
+                &format!("{}_retain_event_days", t),
+                |v: &mut views::EditView| v.set_content(s.config.retain_event_days.to_string()),
+            );
+            if let Some(ref m) = s.config.motion {
+                dialog.call_on_name(
+                    &format!("{}_motion_signal", t),
+                    |v: &mut views::EditView| v.set_content(m.signal_id.to_string()),
+                );
+                dialog.call_on_name(
+                    &format!("{}_motion_sensitivity", t),
+                    |v: &mut views::EditView| v.set_content(m.sensitivity.to_string()),
+                );
+            }
         }
         tracing::debug!("setting {} dir to {}", t.as_str(), selected_dir);
         dialog.call_on_name(
             &format!("{}_sample_file_dir", t),
             |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_dir),
         );
+        dialog.call_on_name(
+            &format!("{}_archive_dir", t),
+            |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_archive_dir),
+        );
     }
     let name = camera.short_name.clone();
+    let events = camera.config.onvif_events.as_ref();
+    let onvif_motion_signal = events
+        .and_then(|e| e.motion_signal_id)
+        .map_or_else(String::new, |id| id.to_string());
+    let onvif_tamper_signal = events
+        .and_then(|e| e.tamper_signal_id)
+        .map_or_else(String::new, |id| id.to_string());
     for &(view_id, content) in &[
         ("short_name", &*camera.short_name),
         (
@@ -555,6 +906,8 @@ fn load_camera_values(
         ),
         ("username", &camera.config.username),
         ("password", &camera.config.password),
+        ("onvif_motion_signal", &onvif_motion_signal),
+        ("onvif_tamper_signal", &onvif_tamper_signal),
     ] {
         dialog
             .call_on_name(view_id, |v: &mut views::EditView| {
@@ -572,7 +925,7 @@ fn load_camera_values(
 
 /// Adds or updates a camera.
 /// (The former if `item` is None; the latter otherwise.)
-fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i32>) {
+pub(super) fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i32>) {
     let camera_list = views::ListView::new()
         .child(
             "id",
@@ -586,7 +939,15 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         )
         .child("username", views::EditView::new().with_name("username"))
         .child("password", views::EditView::new().with_name("password"))
-        .min_height(6);
+        .child(
+            "onvif motion signal",
+            views::EditView::new().with_name("onvif_motion_signal"),
+        )
+        .child(
+            "onvif tamper signal",
+            views::EditView::new().with_name("onvif_tamper_signal"),
+        )
+        .min_height(8);
     let mut layout = views::LinearLayout::vertical()
         .child(camera_list)
         .child(views::TextView::new("description"))
@@ -601,6 +962,16 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
             db.lock()
                 .sample_file_dirs_by_id()
                 .iter()
+                .filter(|(_, d)| !d.archive)
+                .map(|(&id, d)| (d.path.to_owned(), Some(id))),
+        )
+        .collect();
+    let archive_dirs: Vec<_> = ::std::iter::once(("<none>".into(), None))
+        .chain(
+            db.lock()
+                .sample_file_dirs_by_id()
+                .iter()
+                .filter(|(_, d)| d.archive)
                 .map(|(&id, d)| (d.path.to_owned(), Some(id))),
         )
         .collect();
@@ -622,9 +993,13 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     )
                     .child(views::DummyView)
                     .child(
-                        views::Button::new("Test", move |siv| press_test(siv, type_))
-                            .disabled()
-                            .with_name(format!("{}_test", type_)),
+                        views::Button::new("Test", {
+                            let db = db.clone();
+                            let id = *item;
+                            move |siv| press_test(siv, &db, id, type_)
+                        })
+                        .disabled()
+                        .with_name(format!("{}_test", type_)),
                     ),
             )
             .child(
@@ -636,7 +1011,22 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
             )
             .child(
                 "record",
-                views::Checkbox::new().with_name(format!("{}_record", type_)),
+                views::SelectView::<&str>::new()
+                    .with_all([
+                        ("off", ""),
+                        ("continuous", db::json::STREAM_MODE_RECORD),
+                        ("on signal", db::json::STREAM_MODE_ON_SIGNAL),
+                    ])
+                    .popup()
+                    .with_name(format!("{}_mode", type_)),
+            )
+            .child(
+                "record audio",
+                views::Checkbox::new().with_name(format!("{}_record_audio", type_)),
+            )
+            .child(
+                "transcode G.711 audio",
+                views::Checkbox::new().with_name(format!("{}_transcode_audio", type_)),
             )
             .child(
                 "rtsp_transport",
@@ -645,10 +1035,57 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                     .popup()
                     .with_name(format!("{}_rtsp_transport", type_)),
             )
+            .child(
+                "decrypt SRTP",
+                views::Checkbox::new().with_name(format!("{}_srtp", type_)),
+            )
+            .child(
+                "connect timeout sec",
+                views::EditView::new().with_name(format!("{}_rtsp_connect_timeout_sec", type_)),
+            )
+            .child(
+                "frame timeout sec",
+                views::EditView::new().with_name(format!("{}_rtsp_frame_timeout_sec", type_)),
+            )
             .child(
                 "flush_if_sec",
                 views::EditView::new().with_name(format!("{}_flush_if_sec", type_)),
             )
+            .child(
+                "pre-roll sec",
+                views::EditView::new().with_name(format!("{}_pre_roll_sec", type_)),
+            )
+            .child(
+                "retain days",
+                views::EditView::new().with_name(format!("{}_retain_days", type_)),
+            )
+            .child(
+                "retain event days",
+                views::EditView::new().with_name(format!("{}_retain_event_days", type_)),
+            )
+            .child(
+                "archive dir",
+                views::SelectView::<Option<i32>>::new()
+                    .with_all(
+                        archive_dirs
+                            .iter()
+                            .map(|(p, id)| (p.display().to_string(), *id)),
+                    )
+                    .popup()
+                    .with_name(format!("{}_archive_dir", type_)),
+            )
+            .child(
+                "archive retain",
+                views::EditView::new().with_name(format!("{}_archive_retain", type_)),
+            )
+            .child(
+                "motion signal",
+                views::EditView::new().with_name(format!("{}_motion_signal", type_)),
+            )
+            .child(
+                "motion sensitivity",
+                views::EditView::new().with_name(format!("{}_motion_sensitivity", type_)),
+            )
             .child(
                 "usage/capacity",
                 views::TextView::new("").with_name(format!("{}_usage_cap", type_)),
@@ -738,6 +1175,10 @@ pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
                 .full_width()
                 .scrollable(),
         )
+        .button("Discover", {
+            let db = db.clone();
+            move |siv| super::discover::discover_dialog(&db, siv)
+        })
         .dismiss_button("Done")
         .title("Edit cameras"),
     );
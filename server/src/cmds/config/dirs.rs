@@ -206,12 +206,14 @@ pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
                     }
                 })
                 .item("<new sample file dir>".to_string(), None)
-                .with_all(
-                    db.lock()
-                        .sample_file_dirs_by_id()
-                        .iter()
-                        .map(|(&id, d)| (d.path.display().to_string(), Some(id))),
-                )
+                .with_all(db.lock().sample_file_dirs_by_id().iter().map(|(&id, d)| {
+                    let label = if d.archive {
+                        format!("{} (archive)", d.path.display())
+                    } else {
+                        d.path.display().to_string()
+                    };
+                    (label, Some(id))
+                }))
                 .full_width(),
         )
         .dismiss_button("Done")
@@ -299,17 +301,48 @@ fn add_dir(db: &Arc<db::Database>, siv: &mut Cursive, path: &Path) {
     top_dialog(db, siv);
 }
 
-fn delete_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
+/// Shows the dialog for a directory no stream records into, which can be deleted or marked as
+/// an archive directory.
+fn unused_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
+    let archive = db.lock().sample_file_dirs_by_id()[&dir_id].archive;
+    let mut archive_cb = views::Checkbox::new();
+    archive_cb.set_checked(archive);
+    archive_cb.set_on_change({
+        let db = db.clone();
+        move |siv, archive| {
+            if let Err(e) = db.lock().set_sample_file_dir_archive(dir_id, archive) {
+                siv.call_on_name("archive", |v: &mut views::Checkbox| v.set_checked(!archive));
+                siv.add_layer(
+                    views::Dialog::text(format!("Unable to change dir id {dir_id}: {}", e.chain()))
+                        .dismiss_button("Back")
+                        .title("Error"),
+                );
+            }
+        }
+    });
     siv.add_layer(
-        views::Dialog::around(views::TextView::new("Empty (no associated streams)."))
-            .button("Delete", {
-                let db = db.clone();
-                move |siv| delete_dir(&db, siv, dir_id)
-            })
-            .button("Cancel", |siv| {
+        views::Dialog::around(
+            views::LinearLayout::vertical()
+                .child(views::TextView::new(
+                    "No streams record into this directory.",
+                ))
+                .child(views::DummyView)
+                .child(views::ListView::new().child("archive", archive_cb.with_name("archive"))),
+        )
+        .button("Delete", {
+            let db = db.clone();
+            move |siv| delete_dir(&db, siv, dir_id)
+        })
+        .button("Done", {
+            let db = db.clone();
+            move |siv| {
+                // Recreate the top dialog to show the archive status.
                 siv.pop_layer();
-            })
-            .title("Delete sample file directory"),
+                siv.pop_layer();
+                top_dialog(&db, siv);
+            }
+        })
+        .title("Unused sample file directory"),
     );
 }
 
@@ -351,7 +384,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                     Stream {
                         label: format!("{}: {}: {}", id, c.short_name, s.type_.as_str()),
                         used: s.fs_bytes,
-                        record: s.config.mode == db::json::STREAM_MODE_RECORD,
+                        record: s.config.is_recording(),
                         retain: Some(s.config.retain_bytes),
                     },
                 );
@@ -359,7 +392,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                 total_retain += s.config.retain_bytes;
             }
             if streams.is_empty() {
-                return delete_dir_dialog(db, siv, dir_id);
+                return unused_dir_dialog(db, siv, dir_id);
             }
             l.open_sample_file_dirs(&[dir_id]).unwrap(); // TODO: don't unwrap.
             let dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap();
@@ -0,0 +1,276 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Discovery of ONVIF cameras on the local network, to pre-fill the "Add camera" dialog.
+
+use crate::onvif::{self, discovery::Device, media::Profile};
+use base::Error;
+use cursive::traits::{Nameable, Resizable, Scrollable};
+use cursive::views;
+use cursive::Cursive;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait for responses to the discovery probe.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Runs `f` on a background thread within the tokio runtime, showing `message` meanwhile, then
+/// passes its result to `done` on the UI thread.
+fn in_background<T: Send + 'static>(
+    siv: &mut Cursive,
+    message: String,
+    f: impl std::future::Future<Output = Result<T, Error>> + Send + 'static,
+    done: impl FnOnce(&mut Cursive, T) + Send + 'static,
+) {
+    siv.add_layer(views::Dialog::text(message).title("Please wait"));
+
+    // siv.cb_sink doesn't actually wake up the event loop. Tell siv to poll, as a workaround.
+    siv.set_fps(5);
+    let sink = siv.cb_sink().clone();
+
+    // Note: this expects to be called within a tokio runtime, as set up by the config
+    // subcommand's run().
+    let handle = tokio::runtime::Handle::current();
+    ::std::thread::spawn(move || {
+        let r = handle.block_on(f);
+        sink.send(Box::new(move |siv: &mut Cursive| {
+            siv.set_fps(0);
+            siv.pop_layer();
+            match r {
+                Ok(t) => done(siv, t),
+                Err(e) => siv.add_layer(
+                    views::Dialog::text(e.chain().to_string())
+                        .title("Discovery failed")
+                        .dismiss_button("Back"),
+                ),
+            }
+        }))
+        .unwrap();
+    });
+}
+
+/// Probes the local network, then lists the cameras found.
+pub fn discover_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
+    let db = db.clone();
+    in_background(
+        siv,
+        "Searching the local network for ONVIF cameras...".to_owned(),
+        onvif::discovery::discover(DISCOVERY_TIMEOUT),
+        move |siv, devices| devices_dialog(&db, siv, devices),
+    );
+}
+
+fn device_label(d: &Device) -> String {
+    let host = d
+        .xaddrs
+        .first()
+        .and_then(|u| u.host_str())
+        .unwrap_or("<unknown host>");
+    match (&d.name, &d.hardware) {
+        (Some(n), Some(h)) => format!("{host}: {n} ({h})"),
+        (Some(n), None) => format!("{host}: {n}"),
+        (None, Some(h)) => format!("{host}: {h}"),
+        (None, None) => host.to_owned(),
+    }
+}
+
+fn devices_dialog(db: &Arc<db::Database>, siv: &mut Cursive, devices: Vec<Device>) {
+    if devices.is_empty() {
+        siv.add_layer(
+            views::Dialog::text(
+                "No ONVIF cameras responded. Check that they're on the same network segment \
+                 and have ONVIF and discovery enabled.",
+            )
+            .title("Discover cameras")
+            .dismiss_button("Back"),
+        );
+        return;
+    }
+    siv.add_layer(
+        views::Dialog::around(
+            views::SelectView::new()
+                .with_all(devices.into_iter().map(|d| (device_label(&d), d)))
+                .on_submit({
+                    let db = db.clone();
+                    move |siv, d: &Device| credentials_dialog(&db, siv, d.clone())
+                })
+                .full_width()
+                .scrollable(),
+        )
+        .dismiss_button("Back")
+        .title("Discovered cameras"),
+    );
+}
+
+/// Asks for the camera's credentials, which are typically required to list its streams.
+fn credentials_dialog(db: &Arc<db::Database>, siv: &mut Cursive, device: Device) {
+    let Some(onvif_base_url) = device.onvif_base_url() else {
+        siv.add_layer(
+            views::Dialog::text("This camera didn't report a usable device service URL.")
+                .title("Discover cameras")
+                .dismiss_button("Back"),
+        );
+        return;
+    };
+    siv.add_layer(
+        views::Dialog::around(
+            views::ListView::new()
+                .child("username", views::EditView::new().with_name("username"))
+                .child(
+                    "password",
+                    views::EditView::new().secret().with_name("password"),
+                )
+                .min_height(2),
+        )
+        .title(format!("Credentials for {}", device_label(&device)))
+        .button("Continue", {
+            let db = db.clone();
+            move |siv| {
+                let username = siv
+                    .find_name::<views::EditView>("username")
+                    .unwrap()
+                    .get_content()
+                    .as_str()
+                    .to_owned();
+                let password = siv
+                    .find_name::<views::EditView>("password")
+                    .unwrap()
+                    .get_content()
+                    .as_str()
+                    .to_owned();
+                siv.pop_layer();
+                probe(
+                    &db,
+                    siv,
+                    &device,
+                    onvif_base_url.clone(),
+                    username,
+                    password,
+                );
+            }
+        })
+        .dismiss_button("Cancel"),
+    );
+}
+
+fn probe(
+    db: &Arc<db::Database>,
+    siv: &mut Cursive,
+    device: &Device,
+    onvif_base_url: url::Url,
+    username: String,
+    password: String,
+) {
+    let db = db.clone();
+    let name = device.name.clone();
+    let client = onvif::Client::new(username.clone(), password.clone());
+    let url = onvif_base_url.clone();
+    in_background(
+        siv,
+        format!("Listing streams of {}...", device_label(device)),
+        async move { onvif::media::profiles(&client, &url).await },
+        move |siv, profiles| {
+            add_camera(&db, siv, name, onvif_base_url, username, password, profiles)
+        },
+    );
+}
+
+/// Returns a short name for the camera: its ONVIF name if any, or its host otherwise.
+fn short_name(name: Option<String>, onvif_base_url: &url::Url) -> String {
+    name.filter(|n| !n.is_empty())
+        .unwrap_or_else(|| onvif_base_url.host_str().unwrap_or_default().to_owned())
+}
+
+/// Describes the profiles, including their resolutions, for the camera's description.
+fn describe(profiles: &[Profile]) -> String {
+    let mut out = "Discovered via ONVIF. Profiles:".to_owned();
+    for p in profiles {
+        out.push_str(&format!("\n* {}", p.name));
+        if let Some(e) = &p.encoding {
+            out.push_str(&format!(" {e}"));
+        }
+        if let Some((w, h)) = p.resolution {
+            out.push_str(&format!(" {w}x{h}"));
+        }
+    }
+    out
+}
+
+/// Replaces the "Discovered cameras" dialog with the "Add camera" dialog, pre-filled with the
+/// discovered settings. Profiles with RTSP URLs become the main, sub, and ext streams, in order.
+fn add_camera(
+    db: &Arc<db::Database>,
+    siv: &mut Cursive,
+    name: Option<String>,
+    onvif_base_url: url::Url,
+    username: String,
+    password: String,
+    profiles: Vec<Profile>,
+) {
+    siv.pop_layer();
+    super::cameras::edit_camera_dialog(db, siv, &None);
+    let mut fields = vec![
+        ("short_name".to_owned(), short_name(name, &onvif_base_url)),
+        ("onvif_base_url".to_owned(), onvif_base_url.to_string()),
+        ("username".to_owned(), username),
+        ("password".to_owned(), password),
+    ];
+    let stream_urls = profiles.iter().filter_map(|p| p.stream_uri.as_ref());
+    for (t, url) in db::ALL_STREAM_TYPES.iter().zip(stream_urls) {
+        fields.push((format!("{}_url", t), url.to_string()));
+    }
+    for (view_id, content) in fields {
+        siv.call_on_name(&view_id, |v: &mut views::EditView| v.set_content(content))
+            .expect("missing EditView");
+    }
+    siv.call_on_name("description", |v: &mut views::TextArea| {
+        v.set_content(describe(&profiles))
+    })
+    .expect("missing TextArea");
+    for &t in &db::ALL_STREAM_TYPES {
+        super::cameras::refresh_test_button(siv, t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::onvif::media::Profile;
+    use db::testutil;
+
+    #[test]
+    fn describe() {
+        testutil::init();
+        let profiles = [
+            Profile {
+                token: "1".to_owned(),
+                name: "mainStream".to_owned(),
+                encoding: Some("H264".to_owned()),
+                resolution: Some((1920, 1080)),
+                ptz: false,
+                stream_uri: None,
+            },
+            Profile {
+                token: "2".to_owned(),
+                name: "jpeg".to_owned(),
+                encoding: None,
+                resolution: None,
+                ptz: false,
+                stream_uri: None,
+            },
+        ];
+        assert_eq!(
+            super::describe(&profiles),
+            "Discovered via ONVIF. Profiles:\n* mainStream H264 1920x1080\n* jpeg"
+        );
+    }
+
+    #[test]
+    fn short_name() {
+        testutil::init();
+        let u = url::Url::parse("http://192.168.5.10/onvif/").unwrap();
+        assert_eq!(super::short_name(Some("yard".to_owned()), &u), "yard");
+        assert_eq!(super::short_name(Some(String::new()), &u), "192.168.5.10");
+        assert_eq!(super::short_name(None, &u), "192.168.5.10");
+    }
+}
@@ -0,0 +1,568 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Declarative configuration as a TOML document, for `moonfire-nvr config export` and
+//! `moonfire-nvr config import`.
+//!
+//! The document describes sample file directories, cameras and their streams (including
+//! retention), and users. Rather than database ids, which differ between installations,
+//! directories are identified by path, cameras by short name, and users by username.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use base::{bail, err, Error};
+use db::json::{CameraConfig, StreamConfig, UserConfig};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Document {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sample_file_dirs: Vec<Dir>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cameras: Vec<Camera>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<User>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Dir {
+    pub path: PathBuf,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archive: bool,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct_io: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Camera {
+    pub short_name: String,
+
+    #[serde(flatten)]
+    pub config: CameraConfig,
+
+    /// Streams by type (`main`, `sub`, or `ext`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub streams: BTreeMap<String, Stream>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Stream {
+    /// The path of the directory to record into, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir: Option<PathBuf>,
+
+    /// The path of the directory to archive into, if any. This replaces the config's
+    /// `archiveSampleFileDirId`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_sample_file_dir: Option<PathBuf>,
+
+    #[serde(flatten)]
+    pub config: StreamConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub username: String,
+
+    /// The password hash, in PHC string format, or `None` if password login is impossible.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+
+    #[serde(default)]
+    pub permissions: crate::json::Permissions,
+
+    #[serde(flatten)]
+    pub config: UserConfig,
+}
+
+/// Describes the current configuration.
+pub fn export(db: &db::LockedDatabase) -> Document {
+    let dirs = db.sample_file_dirs_by_id();
+    let dir_path = |id: Option<i32>| id.and_then(|id| dirs.get(&id)).map(|d| d.path.clone());
+    let sample_file_dirs = dirs
+        .values()
+        .map(|d| Dir {
+            path: d.path.clone(),
+            archive: d.archive,
+            direct_io: d.direct_io,
+        })
+        .collect();
+    let cameras = db
+        .cameras_by_id()
+        .values()
+        .map(|c| {
+            let streams = c
+                .streams
+                .iter()
+                .enumerate()
+                .filter_map(|(i, id)| {
+                    let s = db.streams_by_id().get(id.as_ref()?).expect("stream exists");
+                    let mut config = s.config.clone();
+                    let archive_sample_file_dir =
+                        dir_path(config.archive_sample_file_dir_id.take());
+                    let type_ = db::StreamType::from_index(i).expect("valid stream index");
+                    Some((
+                        type_.as_str().to_owned(),
+                        Stream {
+                            sample_file_dir: dir_path(s.sample_file_dir_id),
+                            archive_sample_file_dir,
+                            config,
+                        },
+                    ))
+                })
+                .collect();
+            Camera {
+                short_name: c.short_name.clone(),
+                config: c.config.clone(),
+                streams,
+            }
+        })
+        .collect();
+    let users = db
+        .users_by_id()
+        .values()
+        .map(|u| User {
+            username: u.username.clone(),
+            password_hash: u.password_hash().map(str::to_owned),
+            permissions: u.permissions.clone().into(),
+            config: u.config.clone(),
+        })
+        .collect();
+    Document {
+        sample_file_dirs,
+        cameras,
+        users,
+    }
+}
+
+/// Checks for problems that can be found without changing the database.
+fn validate(doc: &Document) -> Result<(), Error> {
+    let mut paths = BTreeSet::new();
+    for d in &doc.sample_file_dirs {
+        if !paths.insert(&d.path) {
+            bail!(
+                InvalidArgument,
+                msg("sample file dir {} is listed twice", d.path.display())
+            );
+        }
+    }
+    let mut short_names = BTreeSet::new();
+    for c in &doc.cameras {
+        if !short_names.insert(&c.short_name) {
+            bail!(
+                InvalidArgument,
+                msg("camera {:?} is listed twice", c.short_name)
+            );
+        }
+        for (type_, s) in &c.streams {
+            if db::StreamType::parse(type_).is_none() {
+                bail!(
+                    InvalidArgument,
+                    msg(
+                        "camera {:?} has unknown stream type {type_:?}",
+                        c.short_name
+                    )
+                );
+            }
+            if s.config.archive_sample_file_dir_id.is_some() {
+                bail!(
+                    InvalidArgument,
+                    msg(
+                        "camera {:?} {type_} stream: use archiveSampleFileDir rather than \
+                         archiveSampleFileDirId",
+                        c.short_name
+                    ),
+                );
+            }
+            for p in [&s.sample_file_dir, &s.archive_sample_file_dir]
+                .into_iter()
+                .flatten()
+            {
+                if !paths.contains(p) {
+                    bail!(
+                        InvalidArgument,
+                        msg(
+                            "camera {:?} {type_} stream refers to unlisted sample file dir {}",
+                            c.short_name,
+                            p.display()
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    let mut usernames = BTreeSet::new();
+    for u in &doc.users {
+        if !usernames.insert(&u.username) {
+            bail!(
+                InvalidArgument,
+                msg("user {:?} is listed twice", u.username)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// What [`import`] does with sample file directories, cameras, and users which aren't listed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unlisted {
+    /// Deletes them. Deleting a directory or camera fails if it still has recordings.
+    Delete,
+
+    /// Keeps them but stops recording unlisted cameras' streams and disables unlisted users.
+    /// Unlisted directories are left as is.
+    Disable,
+}
+
+/// Reads a document from `path`, or from standard input if `path` is `-`.
+pub fn read(path: &Path) -> Result<Document, Error> {
+    let text = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| err!(e, msg("unable to read {}", path.display())))?
+    };
+    toml::from_str(&text).map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("unable to parse {}", path.display()),
+            source(e)
+        )
+    })
+}
+
+/// Makes the database match `doc`: adds and updates everything listed, and deletes or disables
+/// everything else as specified by `unlisted`.
+///
+/// Each change is applied separately, so on failure, earlier changes remain.
+pub fn import(
+    db: &mut db::LockedDatabase,
+    doc: &Document,
+    unlisted: Unlisted,
+) -> Result<(), Error> {
+    validate(doc)?;
+
+    // Directories first, so cameras can refer to them.
+    for d in &doc.sample_file_dirs {
+        let id = match dir_id(db, &d.path) {
+            Some(id) => id,
+            None => {
+                info!("adding sample file dir {}", d.path.display());
+                db.add_sample_file_dir(d.path.clone())?
+            }
+        };
+        db.set_sample_file_dir_archive(id, d.archive)?;
+        db.set_sample_file_dir_direct_io(id, d.direct_io)?;
+    }
+
+    let mut camera_ids_by_name: BTreeMap<String, i32> = db
+        .cameras_by_id()
+        .values()
+        .map(|c| (c.short_name.clone(), c.id))
+        .collect();
+    for c in &doc.cameras {
+        let mut change = db::CameraChange {
+            short_name: c.short_name.clone(),
+            config: c.config.clone(),
+            streams: Default::default(),
+        };
+        for (type_, s) in &c.streams {
+            let type_ = db::StreamType::parse(type_).expect("validated");
+            let mut config = s.config.clone();
+            config.archive_sample_file_dir_id = s
+                .archive_sample_file_dir
+                .as_ref()
+                .map(|p| dir_id(db, p).expect("validated"));
+            change.streams[type_.index()] = db::StreamChange {
+                sample_file_dir_id: s
+                    .sample_file_dir
+                    .as_ref()
+                    .map(|p| dir_id(db, p).expect("validated")),
+                config,
+            };
+        }
+        match camera_ids_by_name.remove(&c.short_name) {
+            Some(id) => {
+                if db.null_camera_change(id)? != change {
+                    info!("updating camera {:?}", c.short_name);
+                    db.update_camera(id, change)?;
+                }
+            }
+            None => {
+                info!("adding camera {:?}", c.short_name);
+                db.add_camera(change)?;
+            }
+        }
+    }
+    for (short_name, id) in camera_ids_by_name {
+        if unlisted == Unlisted::Disable {
+            let mut change = db.null_camera_change(id)?;
+            let mut changed = false;
+            for s in &mut change.streams {
+                if !s.config.mode.is_empty() {
+                    s.config.mode.clear();
+                    changed = true;
+                }
+            }
+            if changed {
+                info!("disabling unlisted camera {short_name:?}");
+                db.update_camera(id, change)?;
+            }
+            continue;
+        }
+        info!("deleting camera {short_name:?}");
+        db.delete_camera(id)
+            .map_err(|e| err!(e, msg("unable to delete camera {short_name:?}")))?;
+    }
+
+    let listed: BTreeSet<&PathBuf> = doc.sample_file_dirs.iter().map(|d| &d.path).collect();
+    let unlisted_dirs: Vec<(i32, PathBuf)> = db
+        .sample_file_dirs_by_id()
+        .values()
+        .filter(|d| unlisted == Unlisted::Delete && !listed.contains(&d.path))
+        .map(|d| (d.id, d.path.clone()))
+        .collect();
+    for (id, path) in unlisted_dirs {
+        info!("deleting sample file dir {}", path.display());
+        db.delete_sample_file_dir(id).map_err(|e| {
+            err!(
+                e,
+                msg("unable to delete sample file dir {}", path.display())
+            )
+        })?;
+    }
+
+    let mut user_ids_by_name: BTreeMap<String, i32> = db
+        .users_by_id()
+        .values()
+        .map(|u| (u.username.clone(), u.id))
+        .collect();
+    for u in &doc.users {
+        let existing = user_ids_by_name
+            .remove(&u.username)
+            .map(|id| db.users_by_id().get(&id).expect("user exists"));
+        let mut change = match existing {
+            Some(e) => {
+                if e.password_hash() == u.password_hash.as_deref()
+                    && db::Permissions::from(u.permissions.clone()) == e.permissions
+                    && u.config == e.config
+                {
+                    continue;
+                }
+                info!("updating user {:?}", u.username);
+                let mut change = e.change();
+                if e.password_hash() != u.password_hash.as_deref() {
+                    match &u.password_hash {
+                        Some(h) => change.set_password_hash(h.clone())?,
+                        None => change.clear_password(),
+                    }
+                }
+                change
+            }
+            None => {
+                info!("adding user {:?}", u.username);
+                let mut change = db::UserChange::add_user(u.username.clone());
+                if let Some(h) = &u.password_hash {
+                    change.set_password_hash(h.clone())?;
+                }
+                change
+            }
+        };
+        change.permissions = u.permissions.clone().into();
+        change.config = u.config.clone();
+        db.apply_user_change(change)?;
+    }
+    for (username, id) in user_ids_by_name {
+        if unlisted == Unlisted::Disable {
+            let u = db.users_by_id().get(&id).expect("user exists");
+            if !u.config.disabled {
+                info!("disabling unlisted user {username:?}");
+                let mut change = u.change();
+                change.config.disabled = true;
+                db.apply_user_change(change)?;
+            }
+            continue;
+        }
+        info!("deleting user {username:?}");
+        db.delete_user(id)?;
+    }
+    Ok(())
+}
+
+fn dir_id(db: &db::LockedDatabase, path: &PathBuf) -> Option<i32> {
+    db.sample_file_dirs_by_id()
+        .values()
+        .find(|d| d.path == *path)
+        .map(|d| d.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    fn new_db() -> db::Database {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        db::Database::new(base::clock::RealClocks {}, conn, true).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let main_dir = tmpdir.path().join("main");
+        let archive_dir = tmpdir.path().join("archive");
+        let unused_dir = tmpdir.path().join("unused");
+        let toml = format!(
+            r#"
+            [[sampleFileDirs]]
+            path = "{main}"
+            directIo = true
+
+            [[sampleFileDirs]]
+            path = "{archive}"
+            archive = true
+
+            [[cameras]]
+            shortName = "driveway"
+            description = "front of the house"
+            username = "admin"
+            password = "secret"
+
+            [cameras.streams.main]
+            sampleFileDir = "{main}"
+            archiveSampleFileDir = "{archive}"
+            mode = "record"
+            url = "rtsp://driveway/main"
+            retainBytes = 1048576
+            archiveRetainBytes = 2097152
+            retainDays = 7
+
+            [cameras.streams.sub]
+            url = "rtsp://driveway/sub"
+
+            [[users]]
+            username = "alice"
+            permissions = {{ viewVideo = true, adminUsers = true }}
+            "#,
+            main = main_dir.display(),
+            archive = archive_dir.display(),
+        );
+        let mut doc: Document = toml::from_str(&toml).unwrap();
+
+        let db = new_db();
+        {
+            let mut l = db.lock();
+            l.add_sample_file_dir(unused_dir.clone()).unwrap();
+            let bob = db::UserChange::add_user("bob".to_owned());
+            l.apply_user_change(bob).unwrap();
+            import(&mut l, &doc, Unlisted::Delete).unwrap();
+            assert_eq!(export(&l), doc);
+        }
+
+        // A text round trip is lossless.
+        let text = toml::to_string(&doc).unwrap();
+        assert_eq!(toml::from_str::<Document>(&text).unwrap(), doc);
+
+        // Changes apply to existing entries, including clearing a password.
+        let hash = {
+            let mut l = db.lock();
+            let mut c = l.get_user("alice").unwrap().change();
+            c.set_password("hunter2".to_owned());
+            l.apply_user_change(c).unwrap();
+            l.get_user("alice")
+                .unwrap()
+                .password_hash()
+                .unwrap()
+                .to_owned()
+        };
+        doc.cameras[0].streams.remove("sub");
+        doc.cameras[0]
+            .streams
+            .get_mut("main")
+            .unwrap()
+            .config
+            .retain_bytes = 4096;
+        let mut l = db.lock();
+        import(&mut l, &doc, Unlisted::Delete).unwrap();
+        assert_eq!(export(&l), doc);
+        assert!(!l.get_user("alice").unwrap().has_password());
+
+        doc.users[0].password_hash = Some(hash);
+        import(&mut l, &doc, Unlisted::Delete).unwrap();
+        assert_eq!(export(&l), doc);
+        let id = l.get_user("alice").unwrap().id;
+        assert!(l
+            .get_user_by_id_mut(id)
+            .unwrap()
+            .check_password(Some("hunter2"))
+            .unwrap());
+    }
+
+    #[test]
+    fn disable_unlisted() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let db = new_db();
+        let mut l = db.lock();
+        let dir_id = l.add_sample_file_dir(tmpdir.path().to_owned()).unwrap();
+        let mut camera = db::CameraChange {
+            short_name: "driveway".to_owned(),
+            ..Default::default()
+        };
+        camera.streams[0] = db::StreamChange {
+            sample_file_dir_id: Some(dir_id),
+            config: db::json::StreamConfig {
+                mode: db::json::STREAM_MODE_RECORD.to_owned(),
+                ..Default::default()
+            },
+        };
+        let camera_id = l.add_camera(camera).unwrap();
+        l.apply_user_change(db::UserChange::add_user("bob".to_owned()))
+            .unwrap();
+
+        import(&mut l, &Document::default(), Unlisted::Disable).unwrap();
+        assert_eq!(l.sample_file_dirs_by_id().len(), 1);
+        let stream_id = l.cameras_by_id()[&camera_id].streams[0].unwrap();
+        assert_eq!(l.streams_by_id()[&stream_id].config.mode, "");
+        assert!(l.get_user("bob").unwrap().config.disabled);
+    }
+
+    #[test]
+    fn rejects_unlisted_dir() {
+        testutil::init();
+        let doc: Document = toml::from_str(
+            r#"
+            [[cameras]]
+            shortName = "driveway"
+
+            [cameras.streams.main]
+            sampleFileDir = "/nonexistent"
+            "#,
+        )
+        .unwrap();
+        let db = new_db();
+        let e = import(&mut db.lock(), &doc, Unlisted::Delete).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::InvalidArgument);
+        assert!(db.lock().cameras_by_id().is_empty());
+    }
+}
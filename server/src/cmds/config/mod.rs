@@ -8,28 +8,99 @@
 //! configuration will likely be almost entirely done through a web-based UI.
 
 use base::clock;
-use base::Error;
+use base::{err, Error};
 use bpaf::Bpaf;
 use cursive::views;
 use cursive::Cursive;
-use std::path::PathBuf;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 mod cameras;
 mod dirs;
+mod discover;
+pub(super) mod document;
 mod tab_complete;
 mod users;
 
-/// Interactively edits configuration.
+/// Edits configuration, interactively or via a TOML file.
+///
+/// With no subcommand, this starts a text-based interface. Options such as
+/// `--db-dir` must come before any subcommand.
 #[derive(Bpaf, Debug)]
 #[bpaf(command("config"))]
 pub struct Args {
     #[bpaf(external(crate::parse_db_dir))]
     db_dir: PathBuf,
+
+    #[bpaf(external, optional)]
+    action: Option<Action>,
+}
+
+#[derive(Bpaf, Debug)]
+enum Action {
+    /// Writes sample file directories, cameras and streams (including
+    /// retention), and users to a TOML file. The file includes camera
+    /// passwords and user password hashes, so it's created readable only by
+    /// its owner.
+    #[bpaf(command)]
+    Export {
+        /// File to write, or `-` for standard output.
+        #[bpaf(positional("FILE"))]
+        out: PathBuf,
+    },
+
+    /// Makes the configuration match a TOML file as written by `export`,
+    /// adding, updating, and deleting sample file directories, cameras, and
+    /// users as needed. Directories and cameras which still have recordings
+    /// can't be deleted.
+    #[bpaf(command)]
+    Import {
+        /// File to read, or `-` for standard input.
+        #[bpaf(positional("FILE"))]
+        in_: PathBuf,
+    },
 }
 
 pub fn run(args: Args) -> Result<i32, Error> {
-    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    match args.action {
+        None => interactive(&args.db_dir),
+        Some(Action::Export { out }) => export(&args.db_dir, &out),
+        Some(Action::Import { in_ }) => import(&args.db_dir, &in_),
+    }
+}
+
+fn export(db_dir: &Path, out: &Path) -> Result<i32, Error> {
+    let (_db_dir, conn) = super::open_conn(db_dir, super::OpenMode::ReadOnly)?;
+    let db = db::Database::new(clock::RealClocks {}, conn, false)?;
+    let doc = document::export(&db.lock());
+    let text = toml::to_string(&doc).map_err(|e| err!(Internal, source(e)))?;
+    if out == Path::new("-") {
+        std::io::stdout().write_all(text.as_bytes())?;
+        return Ok(0);
+    }
+    use std::os::unix::fs::OpenOptionsExt as _;
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(out)
+        .map_err(|e| err!(e, msg("unable to create {}", out.display())))?;
+    f.write_all(text.as_bytes())?;
+    Ok(0)
+}
+
+fn import(db_dir: &Path, in_: &Path) -> Result<i32, Error> {
+    let doc = document::read(in_)?;
+    let (_db_dir, conn) = super::open_conn(db_dir, super::OpenMode::ReadWrite)?;
+    let db = db::Database::new(clock::RealClocks {}, conn, true)?;
+    document::import(&mut db.lock(), &doc, document::Unlisted::Delete)?;
+    Ok(0)
+}
+
+fn interactive(db_dir: &Path) -> Result<i32, Error> {
+    let (_db_dir, conn) = super::open_conn(db_dir, super::OpenMode::ReadWrite)?;
     let clocks = clock::RealClocks {};
     let db = Arc::new(db::Database::new(clocks, conn, true)?);
 
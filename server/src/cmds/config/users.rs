@@ -45,6 +45,12 @@ fn get_change(
             "perm_update_signals",
             &mut change.permissions.update_signals,
         ),
+        ("perm_ptz", &mut change.permissions.ptz),
+        (
+            "perm_update_camera_configs",
+            &mut change.permissions.update_camera_configs,
+        ),
+        ("perm_talk", &mut change.permissions.talk),
     ] {
         **b = siv.find_name::<views::Checkbox>(id).unwrap().is_checked();
     }
@@ -186,6 +192,9 @@ fn edit_user_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: Option<i32>
         ("view_video", permissions.view_video),
         ("read_camera_configs", permissions.read_camera_configs),
         ("update_signals", permissions.update_signals),
+        ("ptz", permissions.ptz),
+        ("update_camera_configs", permissions.update_camera_configs),
+        ("talk", permissions.talk),
     ] {
         let mut checkbox = views::Checkbox::new();
         checkbox.set_checked(*b);
@@ -0,0 +1,196 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to report database statistics.
+
+use base::Error;
+use bpaf::Bpaf;
+use db::{recording, stats};
+use std::path::PathBuf;
+
+/// Reports database statistics: table and index sizes, recordings per stream,
+/// fragmentation, and write-ahead log size.
+///
+/// This takes a shared lock on the database, so it can't run while a
+/// read-write server is running.
+#[derive(Bpaf, Debug)]
+#[bpaf(command("db-stats"))]
+pub struct Args {
+    #[bpaf(external(crate::parse_db_dir))]
+    db_dir: PathBuf,
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let s = stats::get(&conn)?;
+    print!("{}", format(&s));
+    Ok(0)
+}
+
+/// Formats a byte count in binary units with one decimal place.
+fn human(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = bytes as f64;
+    let mut unit = 0;
+    while v >= 1024. && unit + 1 < UNITS.len() {
+        v /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{v:.1} {}", UNITS[unit])
+    }
+}
+
+fn size_cols(size: Option<stats::Size>) -> (String, String) {
+    match size {
+        Some(s) => (human(s.bytes), human(s.unused_bytes)),
+        None => ("?".to_owned(), "?".to_owned()),
+    }
+}
+
+fn format(s: &stats::Stats) -> String {
+    use std::fmt::Write as _;
+    let mut out = String::new();
+    writeln!(
+        &mut out,
+        "database: {} ({} pages of {} bytes); {} free ({:.1}%)",
+        human(s.page_count * s.page_size),
+        s.page_count,
+        s.page_size,
+        human(s.freelist_count * s.page_size),
+        100. * s.free_fraction(),
+    )
+    .unwrap();
+    if let Some(w) = s.wal_bytes {
+        writeln!(&mut out, "write-ahead log: {}", human(w as i64)).unwrap();
+    }
+
+    let name_w = s
+        .tables
+        .iter()
+        .map(|t| t.name.len())
+        .chain(s.indices.iter().map(|i| i.name.len()))
+        .max()
+        .unwrap_or(0)
+        .max("index".len());
+    writeln!(
+        &mut out,
+        "\n{:name_w$}  {:>12}  {:>10}  {:>10}",
+        "table", "rows", "size", "unused"
+    )
+    .unwrap();
+    for t in &s.tables {
+        let (size, unused) = size_cols(t.size);
+        writeln!(
+            &mut out,
+            "{:name_w$}  {:>12}  {:>10}  {:>10}",
+            t.name, t.rows, size, unused
+        )
+        .unwrap();
+    }
+    writeln!(
+        &mut out,
+        "\n{:name_w$}  {:>12}  {:>10}  {:>10}",
+        "index", "table", "size", "unused"
+    )
+    .unwrap();
+    for i in &s.indices {
+        let (size, unused) = size_cols(i.size);
+        writeln!(
+            &mut out,
+            "{:name_w$}  {:>12}  {:>10}  {:>10}",
+            i.name, i.table, size, unused
+        )
+        .unwrap();
+    }
+
+    let stream_w = s
+        .streams
+        .iter()
+        .map(|st| st.camera.len() + 1 + st.type_.len())
+        .max()
+        .unwrap_or(0)
+        .max("stream".len());
+    writeln!(
+        &mut out,
+        "\n{:stream_w$}  {:>10}  {:>10}  duration",
+        "stream", "recordings", "size"
+    )
+    .unwrap();
+    for st in &s.streams {
+        writeln!(
+            &mut out,
+            "{:stream_w$}  {:>10}  {:>10}  {}",
+            format!("{}/{}", st.camera, st.type_),
+            st.recordings,
+            human(st.sample_file_bytes),
+            recording::Duration(st.wall_duration_90k),
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human(0), "0 B");
+        assert_eq!(human(1023), "1023 B");
+        assert_eq!(human(1536), "1.5 KiB");
+        assert_eq!(human(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn format_stats() {
+        let s = stats::Stats {
+            page_size: 4096,
+            page_count: 100,
+            freelist_count: 10,
+            wal_bytes: Some(8192),
+            tables: vec![stats::Table {
+                name: "recording".to_owned(),
+                rows: 42,
+                size: Some(stats::Size {
+                    bytes: 8192,
+                    unused_bytes: 100,
+                }),
+            }],
+            indices: vec![stats::Index {
+                name: "recording_cover".to_owned(),
+                table: "recording".to_owned(),
+                size: None,
+            }],
+            streams: vec![stats::Stream {
+                id: 1,
+                camera: "driveway".to_owned(),
+                type_: "main".to_owned(),
+                recordings: 42,
+                sample_file_bytes: 42 << 20,
+                wall_duration_90k: 42 * 60 * 90_000,
+            }],
+        };
+        let out = format(&s);
+        assert!(
+            out.starts_with(
+                "database: 400.0 KiB (100 pages of 4096 bytes); 40.0 KiB free (10.0%)\n"
+            ),
+            "{out}"
+        );
+        assert!(out.contains("write-ahead log: 8.0 KiB\n"), "{out}");
+        assert!(
+            out.contains("recording                  42     8.0 KiB       100 B\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("recording_cover     recording           ?           ?\n"),
+            "{out}"
+        );
+        assert!(out.contains("driveway/main"), "{out}");
+    }
+}
@@ -0,0 +1,28 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to reverse the latest schema upgrade.
+
+use base::Error;
+use bpaf::Bpaf;
+use std::path::PathBuf;
+
+/// Downgrades the database to the previous schema version.
+///
+/// This allows going back to the previous release of Moonfire NVR without
+/// restoring a backup. Only the latest schema upgrade can be reversed, and
+/// only if no data would be lost; otherwise the database is left untouched.
+/// See `guide/schema.md` for more information.
+#[derive(Bpaf, Debug)]
+#[bpaf(command("downgrade"))]
+pub struct Args {
+    #[bpaf(external(crate::parse_db_dir))]
+    db_dir: PathBuf,
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    db::upgrade::downgrade(&mut conn)?;
+    Ok(0)
+}
@@ -0,0 +1,244 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to export recordings as `.mp4` files.
+
+use std::io::Write as _;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base::clock;
+use base::{bail, err, Error, FastHashMap};
+use bpaf::Bpaf;
+use db::recording;
+use futures::StreamExt;
+use http_serve::Entity;
+use hyper::body::Buf;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::mp4;
+
+fn parse_stream_type(s: String) -> Result<db::StreamType, Error> {
+    db::StreamType::parse(&s).ok_or_else(|| err!(InvalidArgument, msg("no such stream type {s}")))
+}
+
+fn parse_time(s: String) -> Result<recording::Time, Error> {
+    recording::Time::parse(&s)
+}
+
+/// Exports recordings as `.mp4` files, one per run of recordings.
+///
+/// This reads the database and sample file directories directly rather than
+/// going through a running server, so it's suitable for offline archiving. It
+/// takes a shared lock on the database, so it can't run while a read-write
+/// server is running.
+///
+/// Files are named as in web downloads, by the time of their first frame.
+/// Existing files are left alone, so repeating an export writes only what's
+/// missing.
+#[derive(Bpaf, Debug)]
+#[bpaf(command("export"))]
+pub struct Args {
+    #[bpaf(external(crate::parse_db_dir))]
+    db_dir: PathBuf,
+
+    /// Camera to export, by short name or UUID.
+    #[bpaf(argument("CAMERA"))]
+    camera: String,
+
+    /// Stream to export: `main` or `sub`.
+    #[bpaf(
+        argument::<String>("STREAM"),
+        parse(parse_stream_type),
+        fallback(db::StreamType::Main),
+        debug_fallback
+    )]
+    stream: db::StreamType,
+
+    /// Start of the time range to export, in any format accepted by
+    /// `moonfire-nvr ts`.
+    #[bpaf(argument::<String>("TS"), parse(parse_time))]
+    start: recording::Time,
+
+    /// End of the time range to export, in any format accepted by
+    /// `moonfire-nvr ts`.
+    #[bpaf(argument::<String>("TS"), parse(parse_time))]
+    end: recording::Time,
+
+    /// Existing directory in which to write `.mp4` files.
+    #[bpaf(argument("DIR"))]
+    out: PathBuf,
+
+    /// File holding the master key for encrypted sample files, as in the
+    /// `sampleFileKeyPath` of the `run` configuration.
+    #[bpaf(argument("PATH"))]
+    sample_file_key_path: Option<PathBuf>,
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    if args.start >= args.end {
+        bail!(InvalidArgument, msg("--start must be before --end"));
+    }
+    let range = args.start..args.end;
+    let clocks = clock::RealClocks {};
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadOnly)?;
+    let db = Arc::new(db::Database::new(clocks, conn, false)?);
+    if let Some(ref p) = args.sample_file_key_path {
+        db.lock()
+            .set_sample_file_key(Some(db::dir::crypto::MasterKey::read(p)?));
+    }
+    let (camera_name, stream_id, dirs_by_id, runs) = {
+        let mut l = db.lock();
+        let camera = match Uuid::parse_str(&args.camera) {
+            Ok(uuid) => l.get_camera(uuid),
+            Err(_) => l
+                .cameras_by_id()
+                .values()
+                .find(|c| c.short_name == args.camera),
+        }
+        .ok_or_else(|| err!(NotFound, msg("no such camera {:?}", &args.camera)))?;
+        let camera_name = camera.short_name.clone();
+        let stream_id = camera.streams[args.stream.index()].ok_or_else(|| {
+            err!(
+                NotFound,
+                msg("no such stream {camera_name}/{}", args.stream)
+            )
+        })?;
+        let dir_id = l.streams_by_id()[&stream_id]
+            .sample_file_dir_id
+            .ok_or_else(|| {
+                err!(
+                    FailedPrecondition,
+                    msg(
+                        "stream {camera_name}/{} has no sample file dir",
+                        args.stream
+                    )
+                )
+            })?;
+        let mut dir_ids = vec![dir_id];
+        dir_ids.extend(l.sample_file_dirs_by_id().iter().filter_map(|(&id, d)| {
+            if d.archive {
+                Some(id)
+            } else {
+                None
+            }
+        }));
+        l.open_sample_file_dirs(&dir_ids)?;
+        let mut dirs_by_id = FastHashMap::default();
+        for id in dir_ids {
+            dirs_by_id.insert(id, l.sample_file_dirs_by_id()[&id].get()?);
+        }
+        let runs = list_runs(&l, stream_id, range.clone())?;
+        (camera_name, stream_id, Arc::new(dirs_by_id), runs)
+    };
+    info!("{} runs to export", runs.len());
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    for ids in runs {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+        let mut first_start = None;
+        {
+            let l = db.lock();
+            l.list_recordings_by_id(stream_id, ids.clone(), &mut |r| {
+                let start = builder.append_wall_range(&l, r, &range)?;
+                first_start.get_or_insert(start);
+                Ok(())
+            })?;
+        }
+        let Some(first_start) = first_start else {
+            continue; // recordings deleted since listing; shouldn't happen with the lock held.
+        };
+        let tm = time::at(time::Timespec {
+            sec: first_start.unix_seconds(),
+            nsec: 0,
+        });
+        let filename = format!(
+            "{}-{}-{}.mp4",
+            tm.strftime("%Y%m%d%H%M%S").unwrap(),
+            camera_name,
+            args.stream.as_str(),
+        );
+        let path = args.out.join(&filename);
+        if path.exists() {
+            info!(
+                "skipping recordings {ids:?}: {} already exists",
+                path.display()
+            );
+            continue;
+        }
+        builder.set_filename(&filename)?;
+        let mp4 = builder.build(db.clone(), dirs_by_id.clone())?;
+        rt.block_on(write(&mp4, &path))?;
+        println!("{}", path.display());
+    }
+    Ok(0)
+}
+
+/// Returns the recording id ranges of each run (or portion of a run with a single video sample
+/// entry) which overlaps `range`, in ascending order of start time.
+fn list_runs(
+    db: &db::LockedDatabase,
+    stream_id: i32,
+    range: Range<recording::Time>,
+) -> Result<Vec<Range<i32>>, Error> {
+    let mut runs = Vec::new();
+    db.list_aggregated_recordings(
+        stream_id,
+        range,
+        recording::Duration(i64::max_value()),
+        &mut |a| {
+            runs.push((a.time.start, a.ids.clone()));
+            Ok(())
+        },
+    )?;
+    runs.sort_by_key(|(start, _)| *start);
+    Ok(runs.into_iter().map(|(_, ids)| ids).collect())
+}
+
+/// Writes `mp4` to `path`, via a temporary file which is renamed into place once complete.
+async fn write(mp4: &mp4::File, path: &Path) -> Result<(), Error> {
+    let tmp = path.with_extension("mp4.partial");
+    let mut f = std::fs::File::create(&tmp)
+        .map_err(|e| err!(e, msg("unable to create {}", tmp.display())))?;
+    let mut body = Pin::from(mp4.get_range(0..mp4.len()));
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| err!(Unknown, source(e)))?;
+        f.write_all(chunk.chunk())?;
+    }
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use db::{recording, testutil};
+
+    #[test]
+    fn list_runs() {
+        testutil::init();
+        let db = testutil::TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(90_000, 1, true, &mut r);
+        let row = db.insert_recording_from_encoder(r);
+        let all = recording::Time::min_value()..recording::Time::max_value();
+        let l = db.db.lock();
+        let id = row.id.recording();
+        assert_eq!(
+            super::list_runs(&l, testutil::TEST_STREAM_ID, all).unwrap(),
+            vec![id..id + 1]
+        );
+        let before = recording::Time(0)..recording::Time(1);
+        assert!(super::list_runs(&l, testutil::TEST_STREAM_ID, before)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Subcommand to import `.mp4` files as recordings.
+
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use base::clock;
+use base::{bail, err, Error};
+use bpaf::Bpaf;
+use db::{recording, writer};
+use uuid::Uuid;
+
+fn parse_stream_type(s: String) -> Result<db::StreamType, Error> {
+    db::StreamType::parse(&s).ok_or_else(|| err!(InvalidArgument, msg("no such stream type {s}")))
+}
+
+fn parse_time(s: String) -> Result<recording::Time, Error> {
+    recording::Time::parse(&s)
+}
+
+/// Imports `.mp4` files, such as footage from a previous NVR, as recordings.
+///
+/// Each file's H.264 track is split into recordings as if it had been
+/// recorded live. Audio and other tracks are ignored. Files which would
+/// overlap existing recordings of the stream are rejected.
+///
+/// This takes an exclusive lock on the database, so it can't run while a
+/// server is running; use the server's `import` API instead.
+#[derive(Bpaf, Debug)]
+#[bpaf(command("import"))]
+pub struct Args {
+    #[bpaf(external(crate::parse_db_dir))]
+    db_dir: PathBuf,
+
+    /// Camera to import into, by short name or UUID.
+    #[bpaf(argument("CAMERA"))]
+    camera: String,
+
+    /// Stream to import into: `main` or `sub`.
+    #[bpaf(
+        argument::<String>("STREAM"),
+        parse(parse_stream_type),
+        fallback(db::StreamType::Main),
+        debug_fallback
+    )]
+    stream: db::StreamType,
+
+    /// Time of the file's first frame, in any format accepted by
+    /// `moonfire-nvr ts`. Defaults to the file's creation time. Requires
+    /// a single file.
+    #[bpaf(argument::<String>("TS"), parse(parse_time), optional)]
+    start: Option<recording::Time>,
+
+    /// File holding the master key for encrypted sample files, as in the
+    /// `sampleFileKeyPath` of the `run` configuration.
+    #[bpaf(argument("PATH"))]
+    sample_file_key_path: Option<PathBuf>,
+
+    /// `.mp4` files to import, in any order.
+    #[bpaf(positional("FILE"), some("at least one file is required"))]
+    files: Vec<PathBuf>,
+}
+
+pub fn run(args: Args) -> Result<i32, Error> {
+    if args.start.is_some() && args.files.len() > 1 {
+        bail!(InvalidArgument, msg("--start requires a single file"));
+    }
+    let clocks = clock::RealClocks {};
+    let (_db_dir, conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let db = Arc::new(db::Database::new(clocks, conn, true)?);
+    let (stream_id, dir_id) = {
+        let mut l = db.lock();
+        if let Some(ref p) = args.sample_file_key_path {
+            l.set_sample_file_key(Some(db::dir::crypto::MasterKey::read(p)?));
+        }
+        let camera = match Uuid::parse_str(&args.camera) {
+            Ok(uuid) => l.get_camera(uuid),
+            Err(_) => l
+                .cameras_by_id()
+                .values()
+                .find(|c| c.short_name == args.camera),
+        }
+        .ok_or_else(|| err!(NotFound, msg("no such camera {:?}", &args.camera)))?;
+        let camera_name = camera.short_name.clone();
+        let stream_id = camera.streams[args.stream.index()].ok_or_else(|| {
+            err!(
+                NotFound,
+                msg("no such stream {camera_name}/{}", args.stream)
+            )
+        })?;
+        let dir_id = l.streams_by_id()[&stream_id]
+            .sample_file_dir_id
+            .ok_or_else(|| {
+                err!(
+                    FailedPrecondition,
+                    msg(
+                        "stream {camera_name}/{} has no sample file dir",
+                        args.stream
+                    )
+                )
+            })?;
+        l.open_sample_file_dirs(&[dir_id])?;
+        (stream_id, dir_id)
+    };
+    let dir = db.lock().sample_file_dirs_by_id()[&dir_id].get()?;
+    let (shutdown_tx, mut shutdown_rx) = base::shutdown::channel();
+    let (channel, join) = writer::start_syncer(db.clone(), shutdown_rx.clone(), dir_id)?;
+    let mut result = Ok(());
+    for path in &args.files {
+        let r = std::fs::File::open(path)
+            .and_then(|f| Ok((f.metadata()?.len(), f)))
+            .map_err(Error::from)
+            .and_then(|(size, f)| {
+                crate::import::import(
+                    &db,
+                    &dir,
+                    &channel,
+                    &mut shutdown_rx,
+                    stream_id,
+                    BufReader::new(f),
+                    size,
+                    args.start,
+                )
+            });
+        match r {
+            Ok(s) => println!(
+                "{}: {} recordings from {} to {}",
+                path.display(),
+                s.recordings,
+                s.time.start,
+                s.time.end
+            ),
+            Err(e) => {
+                result = Err(err!(e, msg("unable to import {}", path.display())));
+                break;
+            }
+        }
+    }
+
+    // The syncer saves the recordings before exiting; then commit them.
+    drop(channel);
+    db.lock().clear_on_flush();
+    join.join().unwrap();
+    db.lock().flush("import")?;
+    drop(shutdown_tx);
+    result.map(|()| 0)
+}
@@ -8,8 +8,13 @@ use nix::fcntl::FlockArg;
 use std::path::Path;
 use tracing::info;
 
+pub mod backup;
 pub mod check;
 pub mod config;
+pub mod db_stats;
+pub mod downgrade;
+pub mod export;
+pub mod import;
 pub mod init;
 pub mod login;
 pub mod run;
@@ -5,6 +5,7 @@
 //! Runtime configuration file (`/etc/moonfire-nvr.toml`).
 //! See `ref/config.md` for more description.
 
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use serde::Deserialize;
@@ -28,6 +29,15 @@ pub struct ConfigFile {
     #[serde(default = "default_db_dir")]
     pub db_dir: PathBuf,
 
+    /// SQLite journal and I/O tuning.
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+
+    /// Scheduled database maintenance configuration. If set, the database's free pages are
+    /// reclaimed and its query planner statistics updated daily.
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceConfig>,
+
     /// Directory holding user interface files (`.html`, `.js`, etc).
     #[cfg_attr(not(feature = "bundled-ui"), serde(default))]
     #[cfg_attr(feature = "bundled-ui", serde(default))]
@@ -38,6 +48,717 @@ pub struct ConfigFile {
     /// Defaults to the number of cores on the system.
     #[serde(default)]
     pub worker_threads: Option<usize>,
+
+    /// Directory holding clip exports (`POST /api/exports`).
+    ///
+    /// Exports are disabled if unset.
+    #[serde(default)]
+    pub export_dir: Option<PathBuf>,
+
+    /// File holding the master key for sample file encryption: exactly 32 random bytes.
+    ///
+    /// If set, new recordings are encrypted at rest. The key is also needed to play back
+    /// recordings made while it was set.
+    #[serde(default)]
+    pub sample_file_key_path: Option<PathBuf>,
+
+    /// File holding the key with which to sign share links: exactly 32 random bytes.
+    ///
+    /// Share links (`POST /api/shares`) are disabled if unset. Changing the key invalidates all
+    /// existing links.
+    #[serde(default)]
+    pub share_link_key_path: Option<PathBuf>,
+
+    /// WebRTC live view configuration.
+    #[serde(default)]
+    pub webrtc: WebRtcConfig,
+
+    /// WebAuthn (passkey) login configuration. Passkeys are disabled if unset.
+    #[serde(default)]
+    pub webauthn: Option<WebAuthnConfig>,
+
+    /// OpenID Connect single sign-on configuration. SSO is disabled if unset.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+
+    /// Object detection configuration. If set, object detection runs on streams whose
+    /// configuration enables it.
+    #[serde(default)]
+    pub object_detection: Option<ObjectDetectionConfig>,
+
+    /// Upload configuration. If set, recordings are copied to S3-compatible object storage.
+    #[serde(default)]
+    pub upload: Option<UploadConfig>,
+
+    /// Replication configuration. If set, recordings and database backups are copied to a
+    /// remote host over SFTP.
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+
+    /// Scheduled backup configuration. If set, database backups are written to a local directory.
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
+
+    /// Audit log configuration. If set, security-relevant actions are recorded in the database.
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+
+    /// Notification configuration. If set, alerts about stream and disk failures are sent to
+    /// the configured webhooks and email recipients.
+    #[serde(default)]
+    pub notifications: Option<NotificationsConfig>,
+
+    /// MQTT configuration. If set, signal changes, detections, and stream status are published
+    /// to a broker, and its messages set signals with MQTT rules and optionally supply Frigate
+    /// events.
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+
+    /// GPIO input lines which set signals, such as ones wired to PIR motion sensors or alarm
+    /// panel relays. This requires building with `--features=gpio`.
+    #[serde(default)]
+    pub gpio: Vec<GpioConfig>,
+
+    /// RTSP restreaming configuration. If set, live streams are served to RTSP clients.
+    #[serde(default)]
+    pub rtsp: Option<RtspConfig>,
+
+    /// RTMP ingest configuration. If set, devices may publish to streams with `rtmp://` URLs.
+    #[serde(default)]
+    pub rtmp: Option<RtmpConfig>,
+
+    /// gRPC API configuration. If set, the gRPC service is served alongside the JSON API.
+    /// This requires building with `--features=grpc`.
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+
+    /// Transcoding configuration. If set, `view.mp4` requests whose `accept_codecs` parameter
+    /// excludes the recorded codec are transcoded to H.264 with `ffmpeg`.
+    #[serde(default)]
+    pub transcode: Option<TranscodeConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct SqliteConfig {
+    /// The WAL size, in pages, which triggers a checkpoint, or 0 to disable automatic
+    /// checkpoints.
+    ///
+    /// default: SQLite's default, 1000.
+    #[serde(default)]
+    pub wal_autocheckpoint_pages: Option<u32>,
+
+    /// SQLite's `synchronous` level: `off`, `normal`, `full`, or `extra`.
+    ///
+    /// default: `extra`.
+    #[serde(default)]
+    pub synchronous: Option<db::Synchronous>,
+
+    /// The maximum number of bytes of the database to access via memory-mapped I/O.
+    ///
+    /// default: SQLite's default, typically 0.
+    #[serde(default)]
+    pub mmap_size_bytes: Option<i64>,
+}
+
+impl SqliteConfig {
+    pub fn tuning(&self) -> db::Tuning {
+        db::Tuning {
+            wal_autocheckpoint_pages: self.wal_autocheckpoint_pages,
+            synchronous: self.synchronous,
+            mmap_size_bytes: self.mmap_size_bytes,
+        }
+    }
+}
+
+fn default_upload_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_upload_interval_sec() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadConfig {
+    /// The service's base URL, such as `https://s3.us-west-2.amazonaws.com`.
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    /// The region used in request signatures.
+    ///
+    /// default: `us-east-1`.
+    #[serde(default = "default_upload_region")]
+    pub region: String,
+
+    /// A prefix for all object keys, such as `nvr/`.
+    #[serde(default)]
+    pub prefix: String,
+
+    pub access_key_id: String,
+    pub secret_access_key: String,
+
+    /// How often to look for recordings to upload.
+    ///
+    /// default: 300.
+    #[serde(default = "default_upload_interval_sec")]
+    pub interval_sec: u64,
+
+    /// Deletes local copies of recordings once their uploads are verified.
+    #[serde(default)]
+    pub delete_local: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectDetectionConfig {
+    /// Path to a TensorFlow Lite SSD model compiled for the Edge TPU, with postprocessing.
+    pub model_path: PathBuf,
+
+    /// Path to the model's labels file.
+    pub labels_path: PathBuf,
+}
+
+fn default_replication_interval_sec() -> u64 {
+    300
+}
+
+fn default_replication_backup_interval_sec() -> u64 {
+    86_400
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicationConfig {
+    /// The remote host: a hostname or an alias from the SSH client configuration.
+    pub host: String,
+
+    /// The remote username, if not the default from the SSH client configuration.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// The remote port, if not the default from the SSH client configuration.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// The private key to authenticate with, if not the default from the SSH client
+    /// configuration.
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+
+    /// The existing directory on the remote host to copy into.
+    pub remote_dir: String,
+
+    /// How often to look for recordings to copy.
+    ///
+    /// default: 300.
+    #[serde(default = "default_replication_interval_sec")]
+    pub interval_sec: u64,
+
+    /// How often to copy a database backup, or 0 to never do so.
+    ///
+    /// default: 86400.
+    #[serde(default = "default_replication_backup_interval_sec")]
+    pub backup_interval_sec: u64,
+
+    /// The bandwidth limit, in Kbit/s.
+    #[serde(default)]
+    pub bandwidth_limit_kbps: Option<u32>,
+}
+
+fn default_maintenance_window_start_hour() -> u32 {
+    3
+}
+
+fn default_maintenance_window_end_hour() -> u32 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceConfig {
+    /// The local hour (0-23) at which the daily maintenance window starts.
+    ///
+    /// default: 3.
+    #[serde(default = "default_maintenance_window_start_hour")]
+    pub window_start_hour: u32,
+
+    /// The local hour (0-23) at which the daily maintenance window ends. This may be less than
+    /// `window_start_hour` for a window spanning midnight.
+    ///
+    /// default: 5.
+    #[serde(default = "default_maintenance_window_end_hour")]
+    pub window_end_hour: u32,
+}
+
+fn default_backup_interval_sec() -> u64 {
+    86_400
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupConfig {
+    /// The directory in which to write backups, created if necessary.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+
+    /// The path of a sample file directory, in whose `db-backups` subdirectory to write backups.
+    /// Exactly one of this and `dir` must be set.
+    #[serde(default)]
+    pub sample_file_dir: Option<PathBuf>,
+
+    /// How often to write a backup.
+    ///
+    /// default: 86400.
+    #[serde(default = "default_backup_interval_sec")]
+    pub interval_sec: u64,
+
+    /// The number of backups to keep; older ones are removed.
+    ///
+    /// default: 7.
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct WebRtcConfig {
+    /// STUN/TURN servers to offer to WebRTC peers.
+    ///
+    /// None are needed when the browser can reach the server directly, as on a LAN.
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+}
+
+fn default_stream_down_sec() -> u32 {
+    60
+}
+
+fn default_dir_min_available_bytes() -> i64 {
+    1 << 30
+}
+
+fn default_flush_lag_sec() -> u32 {
+    60
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationsConfig {
+    /// Endpoints to which each alert is `POST`ed as JSON.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// An SMTP relay through which alerts are emailed to users with `alertEmail` set.
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+
+    /// How long a stream must be down before alerting.
+    ///
+    /// default: 60.
+    #[serde(default = "default_stream_down_sec")]
+    pub stream_down_sec: u32,
+
+    /// Alerts when a sample file directory's filesystem has fewer bytes than this available.
+    ///
+    /// default: 1 GiB.
+    #[serde(default = "default_dir_min_available_bytes")]
+    pub dir_min_available_bytes: i64,
+
+    /// How long past its stream's `flushIfSec` a recording may wait to be committed to the
+    /// database before alerting.
+    ///
+    /// default: 60.
+    #[serde(default = "default_flush_lag_sec")]
+    pub flush_lag_sec: u32,
+}
+
+impl Default for NotificationsConfig {
+    /// Checks with the default thresholds but notifies no sinks, for `/api/events`.
+    fn default() -> Self {
+        NotificationsConfig {
+            webhooks: Vec::new(),
+            smtp: None,
+            stream_down_sec: default_stream_down_sec(),
+            dir_min_available_bytes: default_dir_min_available_bytes(),
+            flush_lag_sec: default_flush_lag_sec(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookConfig {
+    /// The URL, such as `https://hooks.example.com/moonfire`.
+    pub url: String,
+
+    /// Additional request headers, such as `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpConfig {
+    /// The relay's hostname. The connection must be upgraded via `STARTTLS`.
+    pub host: String,
+
+    /// default: 587.
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+
+    /// Credentials for the relay, if it requires authentication.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// The sender, such as `Moonfire NVR <nvr@example.com>`.
+    pub from: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "moonfire-nvr".to_owned()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "moonfire-nvr".to_owned()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+fn default_frigate_topic_prefix() -> String {
+    "frigate".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct MqttConfig {
+    /// The broker's hostname.
+    pub host: String,
+
+    /// default: 1883.
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+
+    /// If true, connects to the broker via TLS, verifying its certificate against the system's
+    /// root certificates.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// Credentials for the broker, if it requires authentication.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// default: `moonfire-nvr`.
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+
+    /// The prefix of all published topics.
+    ///
+    /// default: `moonfire-nvr`.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+
+    /// The prefix of Home Assistant discovery topics, or empty to disable discovery.
+    ///
+    /// default: `homeassistant`.
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+
+    /// Frigate configuration. If set, Frigate's object detection events are imported.
+    #[serde(default)]
+    pub frigate: Option<FrigateConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct FrigateConfig {
+    /// The prefix of Frigate's topics.
+    ///
+    /// default: `frigate`.
+    #[serde(default = "default_frigate_topic_prefix")]
+    pub topic_prefix: String,
+
+    /// Per-camera settings, keyed by Frigate camera name.
+    #[serde(default)]
+    pub cameras: BTreeMap<String, FrigateCameraConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct FrigateCameraConfig {
+    /// The short name of the matching Moonfire NVR camera.
+    ///
+    /// default: the Frigate camera name.
+    #[serde(default)]
+    pub camera: Option<String>,
+
+    /// The width and height of Frigate's detect resolution, to which bounding boxes are
+    /// relative.
+    ///
+    /// default: the resolution of the stream on which detections are stored.
+    #[serde(default)]
+    pub width: Option<u16>,
+    #[serde(default)]
+    pub height: Option<u16>,
+}
+
+fn default_gpio_chip() -> PathBuf {
+    "/dev/gpiochip0".into()
+}
+
+fn default_gpio_debounce_ms() -> u32 {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct GpioConfig {
+    /// The GPIO character device.
+    ///
+    /// default: `/dev/gpiochip0`, which on a Raspberry Pi holds the header pins.
+    #[serde(default = "default_gpio_chip")]
+    pub chip: PathBuf,
+
+    /// The line's offset within the chip. On a Raspberry Pi, this is the BCM (not physical
+    /// header) pin number.
+    pub line: u32,
+
+    /// If true, the line is active when low, as with a contact which pulls it to ground.
+    #[serde(default)]
+    pub active_low: bool,
+
+    /// The signal to set.
+    pub signal_id: u32,
+
+    /// The signal's state while the line is active.
+    pub active_state: u16,
+
+    /// The signal's state while the line is inactive.
+    pub inactive_state: u16,
+
+    /// How long the line must hold a new level before the change takes effect.
+    ///
+    /// default: 50.
+    #[serde(default = "default_gpio_debounce_ms")]
+    pub debounce_ms: u32,
+}
+
+fn default_rtsp_address() -> std::net::SocketAddr {
+    std::net::SocketAddr::from(([0, 0, 0, 0], 8554))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct RtspConfig {
+    /// The TCP address to listen on.
+    ///
+    /// default: `0.0.0.0:8554`.
+    #[serde(default = "default_rtsp_address")]
+    pub address: std::net::SocketAddr,
+
+    /// Allow unauthenticated access with the given permissions, as with a bind's option of the
+    /// same name. Only `viewVideo` (and `cameras`) matter here.
+    #[serde(default)]
+    pub allow_unauthenticated_permissions: Option<Permissions>,
+}
+
+fn default_rtmp_address() -> std::net::SocketAddr {
+    std::net::SocketAddr::from(([0, 0, 0, 0], 1935))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct RtmpConfig {
+    /// The TCP address to listen on.
+    ///
+    /// default: `0.0.0.0:1935`.
+    #[serde(default = "default_rtmp_address")]
+    pub address: std::net::SocketAddr,
+}
+
+fn default_grpc_address() -> std::net::SocketAddr {
+    std::net::SocketAddr::from(([0, 0, 0, 0], 50051))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct GrpcConfig {
+    /// The TCP address to listen on.
+    ///
+    /// default: `0.0.0.0:50051`.
+    #[serde(default = "default_grpc_address")]
+    pub address: std::net::SocketAddr,
+
+    /// Allow unauthenticated access with the given permissions, as with a bind's option of the
+    /// same name. Only `viewVideo` (and `cameras`) matter here.
+    #[serde(default)]
+    pub allow_unauthenticated_permissions: Option<Permissions>,
+}
+
+fn default_transcode_ffmpeg_path() -> PathBuf {
+    PathBuf::from("ffmpeg")
+}
+
+fn default_transcode_max_concurrent() -> usize {
+    2
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscodeConfig {
+    /// The `ffmpeg` binary to run, searched for in `PATH` if it has no directory component.
+    ///
+    /// default: `ffmpeg`.
+    #[serde(default = "default_transcode_ffmpeg_path")]
+    pub ffmpeg_path: PathBuf,
+
+    /// The maximum number of simultaneous transcodes. Further requests fail until one finishes.
+    ///
+    /// default: 2.
+    #[serde(default = "default_transcode_max_concurrent")]
+    pub max_concurrent: usize,
+
+    /// The video bitrate of transcoded output, in kilobits per second.
+    ///
+    /// default: `ffmpeg`'s default, which targets a constant quality instead.
+    #[serde(default)]
+    pub video_bitrate_kbps: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditConfig {
+    /// A syslog socket, such as `/dev/log`, to which each entry is also forwarded.
+    ///
+    /// Entries are sent with the `authpriv` facility. Forwarding is best-effort; entries
+    /// which can't be sent are still recorded in the database.
+    #[serde(default)]
+    pub syslog_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct WebAuthnConfig {
+    /// The relying party id: the domain name by which browsers reach Moonfire NVR, such as
+    /// `nvr.example.com`. Passkeys are bound to this; changing it invalidates them.
+    pub rp_id: String,
+
+    /// The origin of the web UI, such as `https://nvr.example.com`. Its host must be `rp_id` or
+    /// a subdomain of it.
+    pub origin: String,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
+
+fn default_oidc_username_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcConfig {
+    /// The identity provider's issuer URL, such as `https://auth.example.com`.
+    pub issuer_url: String,
+
+    pub client_id: String,
+    pub client_secret: String,
+
+    /// The URL of `/api/login/oidc/callback` as seen by browsers, such as
+    /// `https://nvr.example.com/api/login/oidc/callback`. This must be registered with the
+    /// identity provider.
+    pub redirect_url: String,
+
+    /// default: `["openid", "profile"]`.
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+
+    /// The ID token claim holding the Moonfire NVR username.
+    ///
+    /// default: `preferred_username`.
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+
+    /// If true, a user is created on first login if none exists with the given username.
+    #[serde(default)]
+    pub auto_provision: bool,
+
+    /// Permissions granted to every single sign-on session. If this or `group_permissions` is
+    /// set, sessions get these rather than the user's stored permissions.
+    #[serde(default)]
+    pub permissions: Option<Permissions>,
+
+    /// The ID token claim holding the user's groups.
+    ///
+    /// default: `groups`.
+    #[serde(default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+
+    /// Permissions granted to single sign-on sessions of users in the given groups.
+    #[serde(default)]
+    pub group_permissions: BTreeMap<String, Permissions>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    /// URLs such as `stun:stun.l.google.com:19302` or `turn:turn.example.com:3478`.
+    pub urls: Vec<String>,
+
+    /// The username, for TURN servers which require one.
+    #[serde(default)]
+    pub username: String,
+
+    /// The credential, for TURN servers which require one.
+    #[serde(default)]
+    pub credential: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -97,6 +818,29 @@ pub struct BindConfig {
     /// effective UID as privileged.
     #[serde(default)]
     pub own_uid_is_privileged: bool,
+
+    /// Trusts a header naming the user, as set by an authenticating proxy server.
+    #[serde(default)]
+    pub trust_remote_user: Option<RemoteUserConfig>,
+}
+
+fn default_remote_user_header() -> String {
+    "X-Remote-User".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteUserConfig {
+    /// The addresses of the proxy server. The header is ignored on requests from other
+    /// addresses.
+    pub proxy_addrs: Vec<std::net::IpAddr>,
+
+    /// The header naming the user.
+    ///
+    /// default: `X-Remote-User`.
+    #[serde(default = "default_remote_user_header")]
+    pub header: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -10,15 +10,13 @@ use base::err;
 use base::FastHashMap;
 use base::{bail, Error};
 use bpaf::Bpaf;
-use db::{dir, writer};
+use db::dir;
 use hyper::service::{make_service_fn, service_fn};
 use itertools::Itertools;
-use retina::client::SessionGroup;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
 use tokio::signal::unix::{signal, SignalKind};
 use tracing::error;
 use tracing::{info, warn};
@@ -27,8 +25,11 @@ use tracing::{info, warn};
 use libsystemd::daemon::{notify, NotifyState};
 
 use self::config::ConfigFile;
+use self::streamers::Streamers;
+use super::config::document;
 
 pub mod config;
+mod streamers;
 
 /// Runs the server, saving recordings and allowing web access.
 #[derive(Bpaf, Debug)]
@@ -42,6 +43,25 @@ pub struct Args {
     /// Note this is incompatible with session authentication; consider adding
     /// a bind with `allowUnauthenticatedPermissions` to your config.
     read_only: bool,
+
+    /// Path to a declarative configuration file in the format written by
+    /// `moonfire-nvr config export`. At startup and on each reload, its sample
+    /// file directories, cameras, streams, and users are created or updated to
+    /// match; cameras not listed stop recording, and users not listed are
+    /// disabled.
+    #[bpaf(argument("PATH"))]
+    config_file: Option<PathBuf>,
+
+    /// Path to a PEM file holding the TLS certificate chain. If set along with
+    /// `--tls-key`, TCP binds serve HTTPS rather than HTTP. Both files are
+    /// reread on `SIGHUP`, so a renewed certificate can be installed without
+    /// restarting.
+    #[bpaf(argument("PATH"))]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to a PEM file holding the TLS private key, for use with `--tls-cert`.
+    #[bpaf(argument("PATH"))]
+    tls_key: Option<PathBuf>,
 }
 
 // These are used in a hack to get the name of the current time zone (e.g. America/Los_Angeles).
@@ -127,12 +147,6 @@ fn resolve_zone() -> Result<String, Error> {
     }
 }
 
-struct Syncer {
-    dir: Arc<dir::SampleFileDir>,
-    channel: writer::SyncerChannel<::std::fs::File>,
-    join: thread::JoinHandle<()>,
-}
-
 #[cfg(target_os = "linux")]
 fn get_preopened_sockets() -> Result<FastHashMap<String, Listener>, Error> {
     use libsystemd::activation::IsType as _;
@@ -180,7 +194,7 @@ fn get_preopened_sockets() -> Result<FastHashMap<String, Listener>, Error> {
     Ok(FastHashMap::default())
 }
 
-fn read_config(path: &Path) -> Result<ConfigFile, Error> {
+pub(super) fn read_config(path: &Path) -> Result<ConfigFile, Error> {
     let config = std::fs::read(path)?;
     let config = std::str::from_utf8(&config).map_err(|e| err!(InvalidArgument, source(e)))?;
     let config = toml::from_str(config).map_err(|e| err!(InvalidArgument, source(e)))?;
@@ -198,13 +212,34 @@ pub fn run(args: Args) -> Result<i32, Error> {
         )
     })?;
 
+    if args.config_file.is_some() && args.read_only {
+        bail!(
+            InvalidArgument,
+            msg("--config-file is incompatible with --read-only")
+        );
+    }
+
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(web::tls::Tls::new(cert, key)?)),
+        (None, None) => None,
+        _ => bail!(
+            InvalidArgument,
+            msg("--tls-cert and --tls-key must be specified together")
+        ),
+    };
+
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
     if let Some(worker_threads) = config.worker_threads {
         builder.worker_threads(worker_threads);
     }
     let rt = builder.build()?;
-    let r = rt.block_on(async_run(args.read_only, &config));
+    let r = rt.block_on(async_run(
+        args.read_only,
+        &config,
+        args.config_file.as_deref(),
+        tls,
+    ));
 
     // tokio normally waits for all spawned tasks to complete, but:
     // * in the graceful shutdown path, we wait for specific tasks with logging.
@@ -214,14 +249,19 @@ pub fn run(args: Args) -> Result<i32, Error> {
     r
 }
 
-async fn async_run(read_only: bool, config: &ConfigFile) -> Result<i32, Error> {
+async fn async_run(
+    read_only: bool,
+    config: &ConfigFile,
+    config_file: Option<&Path>,
+    tls: Option<Arc<web::tls::Tls>>,
+) -> Result<i32, Error> {
     let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
     let mut shutdown_tx = Some(shutdown_tx);
 
     tokio::pin! {
         let int = signal(SignalKind::interrupt())?;
         let term = signal(SignalKind::terminate())?;
-        let inner = inner(read_only, config, shutdown_rx);
+        let inner = inner(read_only, config, config_file, tls, shutdown_rx);
     }
 
     tokio::select! {
@@ -312,6 +352,8 @@ fn make_listener(
 async fn inner(
     read_only: bool,
     config: &ConfigFile,
+    config_file: Option<&Path>,
+    tls: Option<Arc<web::tls::Tls>>,
     shutdown_rx: base::shutdown::Receiver,
 ) -> Result<i32, Error> {
     let clocks = clock::RealClocks {};
@@ -323,16 +365,50 @@ async fn inner(
             super::OpenMode::ReadWrite
         },
     )?;
-    let db = Arc::new(db::Database::new(clocks, conn, !read_only)?);
+    let db = Arc::new(db::Database::new_lazy(clocks, conn, !read_only)?);
+    db.lock().set_tuning(&config.sqlite.tuning())?;
     info!("Database is loaded.");
 
+    // Per-stream recording totals load in the background so the server can start serving
+    // without scanning every recording; /api/health reports progress.
+    {
+        let db = db.clone();
+        std::thread::Builder::new()
+            .name("load-recordings".to_owned())
+            .spawn(move || {
+                if let Err(err) = db.load_recordings() {
+                    error!(err = %err.chain(), "unable to load recordings");
+                }
+            })
+            .expect("creating load-recordings thread should succeed");
+    }
+    if let Some(ref p) = config.sample_file_key_path {
+        let key = dir::crypto::MasterKey::read(p)?;
+        db.lock().set_sample_file_key(Some(key));
+        info!("Sample file encryption is enabled.");
+    }
+    if let Some(p) = config_file {
+        let doc = document::read(p)?;
+        document::import(&mut db.lock(), &doc, document::Unlisted::Disable)?;
+        info!("Declarative configuration is applied.");
+    }
+
     {
         let mut l = db.lock();
-        let dirs_to_open: Vec<_> = l
+        let mut dirs_to_open: Vec<_> = l
             .streams_by_id()
             .values()
             .filter_map(|s| s.sample_file_dir_id)
             .collect();
+
+        // Archive directories may hold recordings of any stream.
+        dirs_to_open.extend(l.sample_file_dirs_by_id().iter().filter_map(|(&id, d)| {
+            if d.archive {
+                Some(id)
+            } else {
+                None
+            }
+        }));
         l.open_sample_file_dirs(&dirs_to_open)?;
     }
     info!("Directories are opened.");
@@ -340,111 +416,244 @@ async fn inner(
     let time_zone_name = resolve_zone()?;
     info!("Resolved timezone: {}", &time_zone_name);
 
-    // Start a streamer for each stream.
-    let mut streamers = Vec::new();
-    let mut session_groups_by_camera: FastHashMap<i32, Arc<retina::client::SessionGroup>> =
-        FastHashMap::default();
-    let syncers = if !read_only {
-        let l = db.lock();
-        let mut dirs = FastHashMap::with_capacity_and_hasher(
-            l.sample_file_dirs_by_id().len(),
-            Default::default(),
-        );
-        let streams = l.streams_by_id().len();
-        let env = streamer::Environment {
-            db: &db,
-            opener: &crate::stream::OPENER,
-            shutdown_rx: &shutdown_rx,
-        };
+    // Frames are shared between the streamers and the web interface(s) for WebRTC live view.
+    let live_frames = Arc::new(streamer::LiveFrames::default());
+
+    // Metrics are likewise shared between the streamers and the web interface(s).
+    let metrics = Arc::new(crate::metrics::Metrics::default());
+
+    // State changes are pushed to the web interface(s)' `/api/events` subscribers.
+    let events = Arc::new(crate::events::Events::default());
+
+    // Export jobs are shared between all binds' web interfaces.
+    let exports = Arc::new(match config.export_dir.as_ref() {
+        Some(d) => web::exports::Exports::new(d.clone())?,
+        None => web::exports::Exports::default(),
+    });
+
+    // Transcodes are likewise shared, so `maxConcurrent` applies across binds.
+    let transcoder = config
+        .transcode
+        .as_ref()
+        .map(|c| Arc::new(web::transcode::Transcoder::new(c)));
+
+    let share_key = config
+        .share_link_key_path
+        .as_deref()
+        .map(web::share::Key::read)
+        .transpose()?;
+
+    // Object detection is shared between all streams, as there's typically a single Edge TPU.
+    let object_detector = match config.object_detection {
+        Some(ref c) if !read_only => Some(crate::analytics::ObjectDetector::start(
+            db.clone(),
+            &c.model_path,
+            &c.labels_path,
+        )?),
+        _ => None,
+    };
 
-        // Get the directories that need syncers.
-        for stream in l.streams_by_id().values() {
-            if stream.config.mode != db::json::STREAM_MODE_RECORD {
-                continue;
-            }
-            if let Some(id) = stream.sample_file_dir_id {
-                dirs.entry(id).or_insert_with(|| {
-                    let d = l.sample_file_dirs_by_id().get(&id).unwrap();
-                    info!("Starting syncer for path {}", d.path.display());
-                    d.get().unwrap()
-                });
-            } else {
-                warn!(
-                    "Stream {} set to record but has no sample file dir id",
-                    stream.id
-                );
-            }
-        }
+    // Start ONVIF event ingestion for each configured camera.
+    let onvif_handles: Vec<_> = if !read_only {
+        let cameras: Vec<_> = db
+            .lock()
+            .cameras_by_id()
+            .values()
+            .filter_map(crate::onvif::events::Camera::new)
+            .collect();
+        cameras
+            .into_iter()
+            .map(|c| {
+                tokio::spawn(crate::onvif::events::run(
+                    db.clone(),
+                    shutdown_rx.clone(),
+                    c,
+                ))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Start vendor event stream ingestion for each configured camera.
+    let vendor_events_handles: Vec<_> = if !read_only {
+        let cameras: Vec<_> = db
+            .lock()
+            .cameras_by_id()
+            .values()
+            .filter_map(crate::vendor_events::Camera::new)
+            .collect();
+        cameras
+            .into_iter()
+            .map(|c| {
+                tokio::spawn(crate::vendor_events::run(
+                    db.clone(),
+                    shutdown_rx.clone(),
+                    c,
+                ))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Start uploading recordings, if configured.
+    let upload_handle = match config.upload {
+        Some(ref c) if !read_only => Some(tokio::spawn(crate::upload::run(
+            db.clone(),
+            shutdown_rx.clone(),
+            crate::upload::Uploader::new(c)?,
+        ))),
+        _ => None,
+    };
 
-        // Then, with the lock dropped, create syncers.
-        drop(l);
-        let mut syncers = FastHashMap::with_capacity_and_hasher(dirs.len(), Default::default());
-        for (id, dir) in dirs.drain() {
-            let (channel, join) = writer::start_syncer(db.clone(), shutdown_rx.clone(), id)?;
-            syncers.insert(id, Syncer { dir, channel, join });
+    // Start replicating recordings and database backups, if configured.
+    let replication_handle = match config.replication {
+        Some(ref c) if !read_only => Some(tokio::spawn(crate::replication::run(
+            db.clone(),
+            shutdown_rx.clone(),
+            crate::replication::Replicator::new(c, &config.db_dir)?,
+        ))),
+        _ => None,
+    };
+
+    // Start scheduled backups, if configured.
+    let (backup_status, backup_handle) = match config.backup {
+        Some(ref c) if !read_only => {
+            let backups = crate::backups::Backups::new(c, &db.lock())?;
+            let status = backups.status();
+            let handle = tokio::spawn(crate::backups::run(
+                db.clone(),
+                shutdown_rx.clone(),
+                backups,
+            ));
+            (Some(status), Some(handle))
         }
+        _ => (None, None),
+    };
 
-        // Then start up streams.
-        let handle = tokio::runtime::Handle::current();
-        let l = db.lock();
-        for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
-            if stream.config.mode != db::json::STREAM_MODE_RECORD {
-                continue;
-            }
-            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
-            let sample_file_dir_id = match stream.sample_file_dir_id {
-                Some(s) => s,
-                None => {
-                    warn!(
-                        "Can't record stream {} ({}/{}) because it has no sample file dir",
-                        id,
-                        camera.short_name,
-                        stream.type_.as_str()
-                    );
-                    continue;
-                }
-            };
-            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
-            let syncer = syncers.get(&sample_file_dir_id).unwrap();
-            let session_group = session_groups_by_camera
-                .entry(camera.id)
-                .or_insert_with(|| {
-                    Arc::new(SessionGroup::default().named(camera.short_name.clone()))
-                })
-                .clone();
-            let mut streamer = streamer::Streamer::new(
-                &env,
-                syncer.dir.clone(),
-                syncer.channel.clone(),
-                *id,
-                camera,
-                stream,
-                session_group,
-                rotate_offset_sec,
-                streamer::ROTATE_INTERVAL_SEC,
-            )?;
-            let span = tracing::info_span!("streamer", stream = streamer.short_name());
-            let thread_name = format!("s-{}", streamer.short_name());
-            let handle = handle.clone();
-            streamers.push(
-                thread::Builder::new()
-                    .name(thread_name)
-                    .spawn(move || {
-                        span.in_scope(|| {
-                            let _enter_tokio = handle.enter();
-                            info!("starting");
-                            streamer.run();
-                        })
-                    })
-                    .expect("can't create thread"),
-            );
+    // Start scheduled database maintenance, if configured.
+    let (maintenance_status, maintenance_handle) = match config.maintenance {
+        Some(ref c) if !read_only => {
+            let maintenance = crate::maintenance::Maintenance::new(c)?;
+            let status = maintenance.status();
+            let handle = tokio::spawn(crate::maintenance::run(
+                db.clone(),
+                shutdown_rx.clone(),
+                maintenance,
+            ));
+            (Some(status), Some(handle))
         }
-        drop(l);
-        Some(syncers)
+        _ => (None, None),
+    };
+
+    // Start checking for problems to notify about. Without a `[notifications]` section, alerts
+    // go only to `/api/events` subscribers.
+    let notify_handle = if read_only {
+        None
+    } else {
+        let notifier = match config.notifications {
+            Some(ref c) => crate::notify::Notifier::new(c)?,
+            None => crate::notify::Notifier::new(&Default::default())?,
+        };
+        Some(tokio::spawn(crate::notify::run(
+            db.clone(),
+            metrics.clone(),
+            events.clone(),
+            shutdown_rx.clone(),
+            notifier,
+        )))
+    };
+
+    // Start checking for state changes to push to `/api/events` subscribers.
+    let events_handle = (!read_only).then(|| {
+        tokio::spawn(crate::events::run(
+            db.clone(),
+            metrics.clone(),
+            events.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    // Start publishing to an MQTT broker, if configured.
+    let mqtt_handle = match config.mqtt {
+        Some(ref c) if !read_only => Some(tokio::spawn(crate::mqtt::run(
+            db.clone(),
+            metrics.clone(),
+            shutdown_rx.clone(),
+            crate::mqtt::Mqtt::new(c)?,
+        ))),
+        _ => None,
+    };
+
+    // Start setting signals from GPIO lines, if configured.
+    let gpio_handle = if !config.gpio.is_empty() && !read_only {
+        Some(tokio::spawn(crate::gpio::run(
+            db.clone(),
+            shutdown_rx.clone(),
+            crate::gpio::Gpio::new(&config.gpio)?,
+        )))
     } else {
         None
     };
 
+    // Start serving live streams over RTSP, if configured.
+    let rtsp_handle = match config.rtsp {
+        Some(ref c) if !read_only => Some(tokio::spawn(crate::rtsp::run(
+            db.clone(),
+            live_frames.clone(),
+            shutdown_rx.clone(),
+            crate::rtsp::Server::new(c)?,
+        ))),
+        _ => None,
+    };
+
+    // Start serving the gRPC API, if configured.
+    let grpc_handle = match config.grpc {
+        Some(ref c) => Some(tokio::spawn(crate::grpc::run(
+            db.clone(),
+            shutdown_rx.clone(),
+            crate::grpc::Server::new(c)?,
+        ))),
+        None => None,
+    };
+
+    // Start accepting RTMP publishers, if configured. This must precede starting streamers so
+    // that their `rtmp://` streams can wait for publishers.
+    let rtmp_handle = match config.rtmp {
+        Some(ref c) if !read_only => Some(tokio::spawn(crate::rtmp::run(
+            db.clone(),
+            shutdown_rx.clone(),
+            crate::rtmp::Server::new(c)?,
+        ))),
+        _ => None,
+    };
+
+    // Start keeping stream health history.
+    let health_handle = (!read_only).then(|| {
+        tokio::spawn(crate::health::run(
+            db.clone(),
+            metrics.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    // Start a streamer for each stream.
+    let streamers = Arc::new(Mutex::new(Streamers::new(
+        db.clone(),
+        shutdown_rx.clone(),
+        live_frames.clone(),
+        metrics.clone(),
+        object_detector,
+    )));
+    if !read_only {
+        reconcile(&streamers).await?;
+    }
+
+    // Reload and import requests from the web interface(s).
+    let (reload_tx, mut reload_rx) = tokio::sync::mpsc::channel(1);
+    let (import_tx, mut import_rx) = tokio::sync::mpsc::channel(1);
+
     // Start the web interface(s).
     let own_euid = nix::unistd::Uid::effective();
     let mut preopened = get_preopened_sockets()?;
@@ -462,6 +671,22 @@ async fn inner(
                 trust_forward_hdrs: b.trust_forward_headers,
                 time_zone_name: time_zone_name.clone(),
                 privileged_unix_uid: b.own_uid_is_privileged.then_some(own_euid),
+                trust_remote_user: b.trust_remote_user.as_ref(),
+                live_frames: live_frames.clone(),
+                metrics: metrics.clone(),
+                events: events.clone(),
+                ice_servers: &config.webrtc.ice_servers,
+                webauthn: config.webauthn.as_ref(),
+                oidc: config.oidc.as_ref(),
+                audit: config.audit.as_ref(),
+                share_key: share_key.clone(),
+                exports: exports.clone(),
+                transcoder: transcoder.clone(),
+                backup_tmp_dir: Some(config.db_dir.clone()),
+                backup_status: backup_status.clone(),
+                maintenance_status: maintenance_status.clone(),
+                reload_tx: (!read_only).then(|| reload_tx.clone()),
+                import_tx: (!read_only).then(|| import_tx.clone()),
             })?);
             let make_svc = make_service_fn(move |conn: &crate::web::accept::Conn| {
                 let conn_data = *conn.data();
@@ -470,7 +695,10 @@ async fn inner(
                     move |req| Arc::clone(&svc).serve(req, conn_data)
                 }))
             });
-            let listener = make_listener(&b.address, &mut preopened)?;
+            let mut listener = make_listener(&b.address, &mut preopened)?;
+            if let Some(ref tls) = tls {
+                listener = listener.with_tls(tls.clone());
+            }
             let server = ::hyper::Server::builder(listener).serve(make_svc);
             let server = server.with_graceful_shutdown(shutdown_rx.future());
             Ok(tokio::spawn(server))
@@ -484,6 +712,9 @@ async fn inner(
         );
     }
 
+    // Only now, with the database open, streamers spawned, and listeners bound, is the service
+    // ready. systemd holds back dependent units and (with `Type=notify`) `systemctl start` until
+    // this point.
     #[cfg(target_os = "linux")]
     {
         if let Err(err) = notify(false, &[NotifyState::Ready]) {
@@ -492,7 +723,56 @@ async fn inner(
     }
 
     info!("Ready to serve HTTP requests");
-    shutdown_rx.as_future().await;
+    drop(reload_tx);
+    drop(import_tx);
+    let mut hup = signal(SignalKind::hangup())?;
+    let shutdown = shutdown_rx.as_future();
+    tokio::pin!(shutdown);
+    let mut watchdog = watchdog_interval();
+    let mut watchdog_probe = None;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            period = watchdog_tick(&mut watchdog) => pet_watchdog(&db, period, &mut watchdog_probe),
+            _ = hup.recv() => {
+                if let Some(ref tls) = tls {
+                    match tls.reload() {
+                        Ok(()) => info!("Received SIGHUP; reloaded TLS certificate."),
+                        Err(err) => error!(err = %err.chain(), "TLS certificate reload failed"),
+                    }
+                }
+                if read_only {
+                    warn!("Received SIGHUP; ignoring because read-only mode can't reload.");
+                    continue;
+                }
+                info!("Received SIGHUP; reloading configuration.");
+                if let Err(err) = reload(&db, config_file, &streamers).await {
+                    error!(err = %err.chain(), "reload failed");
+                }
+            },
+            Some(cmd) = reload_rx.recv() => {
+                let config_file = if cmd.reapply_config_file {
+                    info!("Reloading configuration as requested via /api/reload.");
+                    config_file
+                } else {
+                    info!("Reconciling streamers after a camera change via the API.");
+                    None
+                };
+                let r = reload(&db, config_file, &streamers).await;
+                if let Err(ref err) = r {
+                    error!(err = %err.chain(), "reload failed");
+                }
+                let _ = cmd.responder.send(r);
+            },
+            Some(cmd) = import_rx.recv() => {
+                // Imports can be long; run them in the background so reloads aren't held up.
+                let db = db.clone();
+                let streamers = streamers.clone();
+                let shutdown_rx = shutdown_rx.clone();
+                tokio::task::spawn_blocking(move || import(&db, &streamers, shutdown_rx, cmd));
+            },
+        }
+    }
 
     #[cfg(target_os = "linux")]
     {
@@ -502,27 +782,79 @@ async fn inner(
     }
 
     info!("Shutting down streamers and syncers.");
-    tokio::task::spawn_blocking({
-        let db = db.clone();
-        move || {
-            for streamer in streamers.drain(..) {
-                if streamer.join().is_err() {
-                    tracing::error!("streamer panicked; look for previous panic message");
-                }
-            }
-            if let Some(mut ss) = syncers {
-                // The syncers shut down when all channels to them have been dropped.
-                // The database maintains one; and `ss` holds one. Drop both.
-                db.lock().clear_on_flush();
-                for (_, s) in ss.drain() {
-                    drop(s.channel);
-                    s.join.join().unwrap();
-                }
-            }
-        }
-    })
-    .await
-    .map_err(|e| err!(Unknown, source(e)))?;
+    let session_groups = tokio::task::spawn_blocking(move || streamers.lock().unwrap().stop())
+        .await
+        .map_err(|e| err!(Unknown, source(e)))?;
+
+    info!("Waiting for ONVIF event ingestion to stop.");
+    for h in onvif_handles {
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    info!("Waiting for vendor event ingestion to stop.");
+    for h in vendor_events_handles {
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = upload_handle {
+        info!("Waiting for uploads to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = replication_handle {
+        info!("Waiting for replication to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = backup_handle {
+        info!("Waiting for scheduled backups to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = maintenance_handle {
+        info!("Waiting for scheduled maintenance to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = notify_handle {
+        info!("Waiting for notifications to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = events_handle {
+        info!("Waiting for event pushing to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = mqtt_handle {
+        info!("Waiting for MQTT publishing to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = gpio_handle {
+        info!("Waiting for GPIO signals to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = rtsp_handle {
+        info!("Waiting for RTSP restreaming to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = grpc_handle {
+        info!("Waiting for gRPC requests to finish.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = rtmp_handle {
+        info!("Waiting for RTMP ingest to stop.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
+
+    if let Some(h) = health_handle {
+        info!("Waiting for stream health history to be saved.");
+        h.await.map_err(|e| err!(Unknown, source(e)))?;
+    }
 
     db.lock().clear_watches();
 
@@ -534,7 +866,7 @@ async fn inner(
     }
 
     info!("Waiting for TEARDOWN requests to complete.");
-    for g in session_groups_by_camera.values() {
+    for g in &session_groups {
         if let Err(err) = g.await_teardown().await {
             error!(%err, "teardown failed");
         }
@@ -543,3 +875,121 @@ async fn inner(
     info!("Exiting.");
     Ok(0)
 }
+
+/// Imports a file uploaded via the web interface. This blocks.
+fn import(
+    db: &db::Database,
+    streamers: &Mutex<Streamers>,
+    mut shutdown_rx: base::shutdown::Receiver,
+    cmd: web::import::Command,
+) {
+    let web::import::Command {
+        stream_id,
+        sample_file_dir_id,
+        path,
+        start,
+        responder,
+    } = cmd;
+    let r = (|| {
+        let (dir, channel) = streamers.lock().unwrap().syncer(sample_file_dir_id)?;
+        let f = std::fs::File::open(&path)
+            .map_err(|e| err!(e, msg("unable to open {}", path.display())))?;
+        let size = f.metadata()?.len();
+        crate::import::import(
+            db,
+            &dir,
+            &channel,
+            &mut shutdown_rx,
+            stream_id,
+            std::io::BufReader::new(f),
+            size,
+            start,
+        )
+    })();
+    if let Err(ref err) = r {
+        warn!(err = %err.chain(), "import of {} failed", path.display());
+    }
+    let _ = responder.send(r);
+}
+
+/// Starts, stops, and restarts streamers to match the database, on a blocking thread.
+async fn reconcile(streamers: &Arc<Mutex<Streamers>>) -> Result<(), Error> {
+    let streamers = streamers.clone();
+    tokio::task::spawn_blocking(move || streamers.lock().unwrap().reconcile())
+        .await
+        .map_err(|e| err!(Unknown, source(e)))?
+}
+
+/// Reloads configuration in response to `SIGHUP` or a request from the web interface.
+///
+/// This re-applies the declarative configuration file, if any, then reconciles streamers with
+/// the database, which may have been changed by the file or by the `config` subcommand.
+async fn reload(
+    db: &Arc<db::Database>,
+    config_file: Option<&Path>,
+    streamers: &Arc<Mutex<Streamers>>,
+) -> Result<(), Error> {
+    if let Some(p) = config_file {
+        let doc = document::read(p)?;
+        document::import(&mut db.lock(), &doc, document::Unlisted::Disable)?;
+    }
+    reconcile(streamers).await?;
+    info!("Configuration is reloaded.");
+    Ok(())
+}
+
+/// Returns a timer for notifying the systemd watchdog, if `WatchdogSec=` is set on the unit.
+#[cfg(target_os = "linux")]
+fn watchdog_interval() -> Option<tokio::time::Interval> {
+    let timeout = libsystemd::daemon::watchdog_enabled(false)?;
+
+    // systemd recommends notifying at half the timeout.
+    let mut interval = tokio::time::interval(timeout / 2);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    info!("Notifying systemd watchdog every {:?}.", timeout / 2);
+    Some(interval)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watchdog_interval() -> Option<tokio::time::Interval> {
+    None
+}
+
+/// Waits for the next watchdog tick, returning its period, or forever if there's no watchdog.
+async fn watchdog_tick(watchdog: &mut Option<tokio::time::Interval>) -> std::time::Duration {
+    match watchdog {
+        Some(w) => {
+            w.tick().await;
+            w.period()
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Notifies the systemd watchdog, if the process seems healthy.
+///
+/// Reaching this at all shows the main loop is running. Additionally, the database lock must
+/// be acquirable within `period`; if it's held indefinitely (as by a hung flush), recording has
+/// stalled too, and the watchdog should restart the process.
+///
+/// Each call starts `probe`, a blocking task which acquires the lock, and the next call checks
+/// that it has finished. While a probe is pending, no other is started, so a hung lock doesn't
+/// tie up more and more blocking threads.
+fn pet_watchdog(
+    db: &Arc<db::Database>,
+    period: std::time::Duration,
+    probe: &mut Option<tokio::task::JoinHandle<()>>,
+) {
+    if matches!(probe, Some(p) if !p.is_finished()) {
+        error!("database lock unavailable for {period:?}; not notifying systemd watchdog");
+        return;
+    }
+    let db = db.clone();
+    *probe = Some(tokio::task::spawn_blocking(move || drop(db.lock())));
+    #[cfg(target_os = "linux")]
+    {
+        if let Err(err) = notify(false, &[NotifyState::Watchdog]) {
+            tracing::warn!(%err, "unable to notify systemd watchdog");
+        }
+    }
+}
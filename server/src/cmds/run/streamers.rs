@@ -0,0 +1,286 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Starting and stopping streamers and syncers to match the database's configuration.
+//!
+//! On startup and on each configuration reload, [`Streamers::reconcile`] starts a streamer
+//! thread for each stream which should be recording and isn't, stops those which shouldn't be,
+//! and restarts those whose configuration has changed in a way that matters to the streamer.
+//! Retention limits aren't such a change: the syncer reads them from the database as each
+//! recording completes.
+
+use std::sync::Arc;
+use std::thread;
+
+use base::{Error, FastHashMap};
+use db::json::{CameraConfig, StreamConfig};
+use db::{dir, writer};
+use retina::client::SessionGroup;
+use tracing::{info, warn};
+
+use crate::streamer;
+
+struct Syncer {
+    dir: Arc<dir::SampleFileDir>,
+    channel: writer::SyncerChannel<dir::SampleFile>,
+    join: thread::JoinHandle<()>,
+}
+
+/// The configuration a streamer was started with. If this changes, the streamer is restarted.
+#[derive(PartialEq, Eq)]
+struct Key {
+    short_name: String,
+    camera: CameraConfig,
+    stream: StreamConfig,
+    sample_file_dir_id: i32,
+}
+
+impl Key {
+    fn new(camera: &db::Camera, stream: &db::Stream, sample_file_dir_id: i32) -> Self {
+        let mut stream_config = stream.config.clone();
+
+        // Retention limits are enforced by the syncer, not the streamer.
+        stream_config.retain_bytes = 0;
+        stream_config.archive_sample_file_dir_id = None;
+        stream_config.archive_retain_bytes = 0;
+        stream_config.retain_days = 0;
+        stream_config.retain_event_days = 0;
+        Key {
+            short_name: camera.short_name.clone(),
+            camera: camera.config.clone(),
+            stream: stream_config,
+            sample_file_dir_id,
+        }
+    }
+}
+
+struct Running {
+    key: Key,
+    shutdown_tx: base::shutdown::Sender,
+    join: thread::JoinHandle<()>,
+}
+
+pub(super) struct Streamers {
+    db: Arc<db::Database>,
+
+    /// The server-wide shutdown receiver, used by syncers. Each streamer has its own.
+    shutdown_rx: base::shutdown::Receiver,
+    live_frames: Arc<streamer::LiveFrames>,
+    metrics: Arc<crate::metrics::Metrics>,
+    object_detector: Option<crate::analytics::ObjectDetector>,
+    syncers: FastHashMap<i32, Syncer>,
+    running: FastHashMap<i32, Running>,
+    session_groups_by_camera: FastHashMap<i32, Arc<SessionGroup>>,
+}
+
+impl Streamers {
+    pub(super) fn new(
+        db: Arc<db::Database>,
+        shutdown_rx: base::shutdown::Receiver,
+        live_frames: Arc<streamer::LiveFrames>,
+        metrics: Arc<crate::metrics::Metrics>,
+        object_detector: Option<crate::analytics::ObjectDetector>,
+    ) -> Self {
+        Streamers {
+            db,
+            shutdown_rx,
+            live_frames,
+            metrics,
+            object_detector,
+            syncers: FastHashMap::default(),
+            running: FastHashMap::default(),
+            session_groups_by_camera: FastHashMap::default(),
+        }
+    }
+
+    /// Starts, stops, and restarts streamers (and starts syncers) to match the database.
+    ///
+    /// This blocks while stopping streamers, so it shouldn't be called from an async context.
+    pub(super) fn reconcile(&mut self) -> Result<(), Error> {
+        // Decide what should be running.
+        let mut wanted: FastHashMap<i32, Key> = FastHashMap::default();
+        let mut dirs: Vec<i32> = Vec::new();
+        {
+            let l = self.db.lock();
+            for (&id, stream) in l.streams_by_id() {
+                if !stream.config.is_recording() {
+                    continue;
+                }
+                let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+                let Some(sample_file_dir_id) = stream.sample_file_dir_id else {
+                    warn!(
+                        "Can't record stream {} ({}/{}) because it has no sample file dir",
+                        id,
+                        camera.short_name,
+                        stream.type_.as_str()
+                    );
+                    continue;
+                };
+                dirs.push(sample_file_dir_id);
+
+                // The archive directory's syncer collects the garbage of archived recordings.
+                dirs.extend(stream.config.archive_sample_file_dir_id);
+                wanted.insert(id, Key::new(camera, stream, sample_file_dir_id));
+            }
+        }
+
+        // Stop streamers which are no longer wanted or have changed. This must happen without
+        // the database lock, which the streamers need in order to finish.
+        let to_stop: Vec<i32> = self
+            .running
+            .iter()
+            .filter(|(id, r)| wanted.get(id) != Some(&r.key))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in to_stop {
+            let r = self.running.remove(&id).expect("running");
+            info!("Stopping streamer for stream {id}");
+            drop(r.shutdown_tx);
+            if r.join.join().is_err() {
+                tracing::error!("streamer panicked; look for previous panic message");
+            }
+        }
+
+        // Start syncers for any newly used directories.
+        self.start_syncers(dirs)?;
+
+        // Start streamers which aren't running.
+        let handle = tokio::runtime::Handle::current();
+        let l = self.db.lock();
+        let streams = l.streams_by_id().len();
+        for (i, (id, stream)) in l.streams_by_id().iter().enumerate() {
+            let Some(key) = wanted.remove(id) else {
+                continue;
+            };
+            if self.running.contains_key(id) {
+                continue;
+            }
+            let camera = l.cameras_by_id().get(&stream.camera_id).unwrap();
+            let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
+            let env = streamer::Environment {
+                db: &self.db,
+                opener: &crate::stream::OPENER,
+                shutdown_rx: &shutdown_rx,
+                live_frames: &self.live_frames,
+                metrics: &self.metrics,
+                object_detector: self.object_detector.as_ref(),
+            };
+            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
+            let syncer = &self.syncers[&key.sample_file_dir_id];
+            let session_group = self
+                .session_groups_by_camera
+                .entry(camera.id)
+                .or_insert_with(|| {
+                    Arc::new(SessionGroup::default().named(camera.short_name.clone()))
+                })
+                .clone();
+            let mut streamer = streamer::Streamer::new(
+                &env,
+                syncer.dir.clone(),
+                syncer.channel.clone(),
+                *id,
+                camera,
+                stream,
+                session_group,
+                rotate_offset_sec,
+                streamer::ROTATE_INTERVAL_SEC,
+            )?;
+            let span = tracing::info_span!("streamer", stream = streamer.short_name());
+            let thread_name = format!("s-{}", streamer.short_name());
+            let handle = handle.clone();
+            let join = thread::Builder::new()
+                .name(thread_name)
+                .spawn(move || {
+                    span.in_scope(|| {
+                        let _enter_tokio = handle.enter();
+                        info!("starting");
+                        streamer.run();
+                    })
+                })
+                .expect("can't create thread");
+            self.running.insert(
+                *id,
+                Running {
+                    key,
+                    shutdown_tx,
+                    join,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the given sample file directory's syncer, starting it if necessary.
+    ///
+    /// This is for writers other than streamers, such as imports.
+    pub(super) fn syncer(
+        &mut self,
+        dir_id: i32,
+    ) -> Result<
+        (
+            Arc<dir::SampleFileDir>,
+            writer::SyncerChannel<dir::SampleFile>,
+        ),
+        Error,
+    > {
+        self.start_syncers(vec![dir_id])?;
+        let s = &self.syncers[&dir_id];
+        Ok((s.dir.clone(), s.channel.clone()))
+    }
+
+    /// Starts syncers for any of the given directories which don't have them already.
+    fn start_syncers(&mut self, mut dirs: Vec<i32>) -> Result<(), Error> {
+        dirs.retain(|id| !self.syncers.contains_key(id));
+        dirs.sort_unstable();
+        dirs.dedup();
+        if !dirs.is_empty() {
+            let mut l = self.db.lock();
+            l.open_sample_file_dirs(&dirs)?;
+            for &id in &dirs {
+                info!(
+                    "Starting syncer for path {}",
+                    l.sample_file_dirs_by_id()[&id].path.display()
+                );
+            }
+        }
+        for id in dirs {
+            let dir = self.db.lock().sample_file_dirs_by_id()[&id].get()?;
+            let (channel, join) =
+                writer::start_syncer(self.db.clone(), self.shutdown_rx.clone(), id)?;
+            self.syncers.insert(id, Syncer { dir, channel, join });
+        }
+        Ok(())
+    }
+
+    /// Stops all streamers and syncers, returning the session groups to await teardown.
+    ///
+    /// This blocks, so it shouldn't be called from an async context.
+    pub(super) fn stop(&mut self) -> Vec<Arc<SessionGroup>> {
+        let running: Vec<_> = self
+            .running
+            .drain()
+            .map(|(_, r)| {
+                drop(r.shutdown_tx);
+                r.join
+            })
+            .collect();
+        for join in running {
+            if join.join().is_err() {
+                tracing::error!("streamer panicked; look for previous panic message");
+            }
+        }
+
+        // The syncers shut down when all channels to them have been dropped.
+        // The database maintains one; and `self.syncers` holds one. Drop both.
+        self.db.lock().clear_on_flush();
+        for (_, s) in self.syncers.drain() {
+            drop(s.channel);
+            s.join.join().unwrap();
+        }
+        self.session_groups_by_camera
+            .drain()
+            .map(|(_, g)| g)
+            .collect()
+    }
+}
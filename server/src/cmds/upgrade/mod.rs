@@ -28,16 +28,29 @@ pub struct Args {
 
     /// Skips the normal post-upgrade vacuum operation.
     no_vacuum: bool,
+
+    /// Upgrades a temporary copy of the database, reporting the rows written
+    /// by each step and the resulting schema changes, without modifying the
+    /// database itself. The copy is made in the database directory, so it
+    /// needs room for another copy of the database. Not supported when
+    /// upgrading from schema versions before 5.
+    dry_run: bool,
 }
 
 pub fn run(args: Args) -> Result<i32, Error> {
-    let (_db_dir, mut conn) = super::open_conn(&args.db_dir, super::OpenMode::ReadWrite)?;
+    let mode = if args.dry_run {
+        super::OpenMode::ReadOnly
+    } else {
+        super::OpenMode::ReadWrite
+    };
+    let (_db_dir, mut conn) = super::open_conn(&args.db_dir, mode)?;
 
     db::upgrade::run(
         &db::upgrade::Args {
             sample_file_dir: args.sample_file_dir.as_deref(),
             preset_journal: &args.preset_journal,
             no_vacuum: args.no_vacuum,
+            dry_run: args.dry_run,
         },
         crate::VERSION,
         &mut conn,
@@ -0,0 +1,332 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! State changes pushed to `/api/events` subscribers, so that clients needn't poll `/api/`.
+//!
+//! A single task checks for changes every [`CHECK_INTERVAL`], as [`crate::mqtt`] does, and
+//! publishes an [`Event`] for each recording committed to the database, each stream connection
+//! or disconnection, and each signal change. [`crate::notify`] additionally publishes its alerts,
+//! such as a sample file directory running low on space.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::FastHashMap;
+use db::recording;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::metrics::{Metrics, StreamStatus};
+
+/// How often to check for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of events a subscriber may fall behind before missing some.
+const CAPACITY: usize = 256;
+
+/// A state change, as sent to `/api/events` subscribers.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    /// A recording has been committed to the database.
+    #[serde(rename_all = "camelCase")]
+    RecordingFinalized {
+        camera_uuid: Uuid,
+        stream: &'static str,
+        id: i32,
+        start_time_90k: recording::Time,
+        end_time_90k: recording::Time,
+        sample_file_bytes: i32,
+    },
+
+    /// A stream has been opened.
+    #[serde(rename_all = "camelCase")]
+    StreamConnected {
+        camera_uuid: Uuid,
+        stream: &'static str,
+    },
+
+    /// A stream has stopped, or failed with the given error.
+    #[serde(rename_all = "camelCase")]
+    StreamDisconnected {
+        camera_uuid: Uuid,
+        stream: &'static str,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
+    /// A signal has changed state.
+    #[serde(rename_all = "camelCase")]
+    SignalChanged {
+        signal_id: u32,
+        state: u16,
+        time_90k: recording::Time,
+    },
+
+    /// A [`crate::notify`] alert has fired or resolved.
+    Alert(crate::notify::Event),
+
+    /// The subscriber fell behind and missed the given number of events, so it should refetch
+    /// any state it's tracking. Sent only to that subscriber.
+    Lagged { missed: u64 },
+}
+
+impl Event {
+    /// Returns the camera this event concerns, if any, for permission checks.
+    pub fn camera_uuid(&self) -> Option<Uuid> {
+        match self {
+            Event::RecordingFinalized { camera_uuid, .. }
+            | Event::StreamConnected { camera_uuid, .. }
+            | Event::StreamDisconnected { camera_uuid, .. } => Some(*camera_uuid),
+            Event::Alert(e) => e.alert.camera_uuid,
+            Event::SignalChanged { .. } | Event::Lagged { .. } => None,
+        }
+    }
+}
+
+/// Distributes events to `/api/events` subscribers.
+pub struct Events {
+    tx: broadcast::Sender<Arc<Event>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.tx.subscribe()
+    }
+
+    /// Publishes an event to any current subscribers.
+    pub fn publish(&self, event: Event) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(Arc::new(event));
+        }
+    }
+}
+
+/// Tracks the state as of the last check, to find changes.
+#[derive(Default)]
+struct Tracker {
+    /// `cum_recordings` by stream id.
+    recordings: FastHashMap<i32, i32>,
+
+    /// Whether each started stream is connected, by stream id.
+    streams: FastHashMap<i32, bool>,
+
+    /// Each signal's state, by signal id.
+    signals: FastHashMap<u32, u16>,
+}
+
+impl Tracker {
+    /// Returns events describing everything which has changed since the last update.
+    ///
+    /// Everything is new on the first update; it sets a baseline without producing events.
+    fn update(
+        &mut self,
+        l: &db::LockedDatabase,
+        statuses: &FastHashMap<i32, StreamStatus>,
+        now: recording::Time,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        self.update_recordings(l, &mut events);
+        self.update_streams(l, statuses, &mut events);
+        self.update_signals(l, now, &mut events);
+        events
+    }
+
+    fn update_recordings(&mut self, l: &db::LockedDatabase, events: &mut Vec<Event>) {
+        self.recordings
+            .retain(|id, _| l.streams_by_id().contains_key(id));
+        for (&id, s) in l.streams_by_id() {
+            let cur = s.cum_recordings();
+            let prev = std::mem::replace(self.recordings.entry(id).or_insert(cur), cur);
+            if prev >= cur {
+                continue;
+            }
+            let c = &l.cameras_by_id()[&s.camera_id];
+            let r = l.list_recordings_by_id(id, prev..cur, &mut |r| {
+                events.push(Event::RecordingFinalized {
+                    camera_uuid: c.uuid,
+                    stream: s.type_.as_str(),
+                    id: r.id.recording(),
+                    start_time_90k: r.start,
+                    end_time_90k: r.start + recording::Duration(i64::from(r.wall_duration_90k)),
+                    sample_file_bytes: r.sample_file_bytes,
+                });
+                Ok(())
+            });
+            if let Err(err) = r {
+                tracing::warn!(%err, "unable to list new recordings of stream {id}");
+            }
+        }
+    }
+
+    fn update_streams(
+        &mut self,
+        l: &db::LockedDatabase,
+        statuses: &FastHashMap<i32, StreamStatus>,
+        events: &mut Vec<Event>,
+    ) {
+        self.streams.retain(|id, _| statuses.contains_key(id));
+        for (&id, status) in statuses {
+            let Some(s) = l.streams_by_id().get(&id) else {
+                continue;
+            };
+            let connected = status.running && status.connected;
+            let prev = self.streams.insert(id, connected);
+            if prev.map_or(true, |p| p == connected) {
+                continue;
+            }
+            let camera_uuid = l.cameras_by_id()[&s.camera_id].uuid;
+            let stream = s.type_.as_str();
+            events.push(if connected {
+                Event::StreamConnected {
+                    camera_uuid,
+                    stream,
+                }
+            } else {
+                Event::StreamDisconnected {
+                    camera_uuid,
+                    stream,
+                    error: status.last_error.as_ref().map(|(_, e)| e.clone()),
+                }
+            });
+        }
+    }
+
+    fn update_signals(
+        &mut self,
+        l: &db::LockedDatabase,
+        now: recording::Time,
+        events: &mut Vec<Event>,
+    ) {
+        let mut cur = FastHashMap::default();
+        l.list_changes_by_time(now..now + recording::Duration(1), &mut |r| {
+            cur.insert(r.signal, r.state);
+        });
+        self.signals
+            .retain(|id, _| l.signals_by_id().contains_key(id));
+        for &id in l.signals_by_id().keys() {
+            let state = cur.get(&id).copied().unwrap_or(0);
+            let prev = self.signals.insert(id, state);
+            if prev.map_or(true, |p| p == state) {
+                continue;
+            }
+            events.push(Event::SignalChanged {
+                signal_id: id,
+                state,
+                time_90k: now,
+            });
+        }
+    }
+}
+
+/// Checks for changes and publishes them until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    metrics: Arc<Metrics>,
+    events: Arc<Events>,
+    shutdown_rx: base::shutdown::Receiver,
+) {
+    info!("checking for events to push every {CHECK_INTERVAL:?}");
+    let mut tracker = Tracker::default();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.as_future() => break,
+        }
+        let statuses: FastHashMap<_, _> = metrics
+            .streams()
+            .into_iter()
+            .map(|(id, c)| (id, c.status.lock().unwrap().clone()))
+            .collect();
+        let now = recording::Time::new(db.clocks().realtime());
+        let l = db.lock();
+        let new_events = tracker.update(&l, &statuses, now);
+        drop(l);
+        for e in new_events {
+            events.publish(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use base::FastHashMap;
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+
+    use super::{Event, Tracker};
+    use crate::metrics::StreamStatus;
+
+    #[test]
+    fn tracker() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let now = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut tracker = Tracker::default();
+        let mut statuses = FastHashMap::default();
+        statuses.insert(
+            TEST_STREAM_ID,
+            StreamStatus {
+                running: true,
+                ..Default::default()
+            },
+        );
+
+        // The first update sets a baseline.
+        assert!(tracker.update(&db.db.lock(), &statuses, now).is_empty());
+
+        statuses.get_mut(&TEST_STREAM_ID).unwrap().connected = true;
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(1, 1, true, &mut r);
+        let row = db.insert_recording_from_encoder(r);
+        let events = tracker.update(&db.db.lock(), &statuses, now);
+        assert_eq!(
+            events,
+            [
+                Event::RecordingFinalized {
+                    camera_uuid: db.test_camera_uuid,
+                    stream: "main",
+                    id: row.id.recording(),
+                    start_time_90k: row.start,
+                    end_time_90k: row.start + recording::Duration(1),
+                    sample_file_bytes: 1,
+                },
+                Event::StreamConnected {
+                    camera_uuid: db.test_camera_uuid,
+                    stream: "main",
+                },
+            ]
+        );
+
+        // Nothing has changed since.
+        assert!(tracker.update(&db.db.lock(), &statuses, now).is_empty());
+
+        statuses.get_mut(&TEST_STREAM_ID).unwrap().connected = false;
+        let events = tracker.update(&db.db.lock(), &statuses, now);
+        assert_eq!(
+            events,
+            [Event::StreamDisconnected {
+                camera_uuid: db.test_camera_uuid,
+                stream: "main",
+                error: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! G.711 audio encoding, decoding, and transcoding to AAC.
+//!
+//! Browsers can't play G.711 within `.mp4` files, so when requested, G.711 audio is decoded to
+//! linear PCM and re-encoded as AAC before it's written. Encoding uses the Fraunhofer FDK AAC
+//! library and is available only when built with the `fdk-aac` feature.
+//!
+//! Encoding to G.711 is for sending audio to cameras; see [`crate::onvif::backchannel`].
+
+use crate::stream::AudioFrame;
+use base::Error;
+
+#[cfg(not(feature = "fdk-aac"))]
+use base::bail;
+
+#[cfg(feature = "fdk-aac")]
+use base::{bail, err};
+
+/// The companding law of a G.711 stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Law {
+    /// µ-law, RTP encoding name `PCMU`.
+    Mu,
+
+    /// A-law, RTP encoding name `PCMA`.
+    A,
+}
+
+impl Law {
+    /// Returns the law for the given (lowercase) RTP encoding name, if it's G.711.
+    pub fn from_encoding_name(name: &str) -> Option<Self> {
+        match name {
+            "pcmu" => Some(Law::Mu),
+            "pcma" => Some(Law::A),
+            _ => None,
+        }
+    }
+
+    /// Returns the static RTP payload type of this law, as in RFC 3551 section 6.
+    pub fn payload_type(self) -> u8 {
+        match self {
+            Law::Mu => 0,
+            Law::A => 8,
+        }
+    }
+
+    fn decode(self, b: u8) -> i16 {
+        match self {
+            Law::Mu => decode_ulaw(b),
+            Law::A => decode_alaw(b),
+        }
+    }
+
+    /// Encodes a linear PCM sample.
+    pub fn encode(self, sample: i16) -> u8 {
+        match self {
+            Law::Mu => encode_ulaw(sample),
+            Law::A => encode_alaw(sample),
+        }
+    }
+}
+
+/// Decodes a µ-law sample, as in ITU-T G.711 Table 2a.
+fn decode_ulaw(b: u8) -> i16 {
+    let b = !b;
+    let exponent = (b >> 4) & 0x07;
+    let mantissa = i32::from(b & 0x0f);
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    let sample = if b & 0x80 != 0 { -magnitude } else { magnitude };
+    sample as i16
+}
+
+/// Decodes an A-law sample, as in ITU-T G.711 Table 1a.
+fn decode_alaw(b: u8) -> i16 {
+    let b = b ^ 0x55;
+    let exponent = (b >> 4) & 0x07;
+    let mantissa = i32::from(b & 0x0f);
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 0x08,
+        e => ((mantissa << 4) + 0x108) << (e - 1),
+    };
+    let sample = if b & 0x80 != 0 { magnitude } else { -magnitude };
+    sample as i16
+}
+
+/// Encodes a µ-law sample, the inverse of [`decode_ulaw`].
+fn encode_ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let sample = i32::from(sample);
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = sample.abs().min(CLIP) + BIAS;
+    let exponent = (31 - magnitude.leading_zeros() - 7) as u8;
+    let mantissa = ((magnitude >> (exponent + 3)) & 0x0f) as u8;
+    !(sign | (exponent << 4) | mantissa)
+}
+
+/// Encodes an A-law sample, the inverse of [`decode_alaw`].
+fn encode_alaw(sample: i16) -> u8 {
+    // The 13-bit magnitude at which each segment ends.
+    const SEGMENT_ENDS: [i32; 8] = [0x1f, 0x3f, 0x7f, 0xff, 0x1ff, 0x3ff, 0x7ff, 0xfff];
+    let sample = i32::from(sample) >> 3;
+    let (mask, magnitude) = if sample >= 0 {
+        (0xd5, sample)
+    } else {
+        (0x55, -sample - 1)
+    };
+    let Some(segment) = SEGMENT_ENDS.iter().position(|&end| magnitude <= end) else {
+        return 0x7f ^ mask;
+    };
+    let mantissa = if segment < 2 {
+        (magnitude >> 1) & 0x0f
+    } else {
+        (magnitude >> segment) & 0x0f
+    };
+    (((segment as u8) << 4) | mantissa as u8) ^ mask
+}
+
+/// Transcodes a mono G.711 stream to AAC-LC.
+#[cfg_attr(not(feature = "fdk-aac"), allow(dead_code))]
+pub struct Transcoder {
+    law: Law,
+    sample_rate: u32,
+
+    #[cfg(feature = "fdk-aac")]
+    encoder: fdk_aac::enc::Encoder,
+
+    /// The number of PCM samples in each AAC frame.
+    frame_length: usize,
+
+    /// Decoded PCM samples not yet encoded.
+    pcm: Vec<i16>,
+
+    /// The presentation timestamp of the first sample pushed, in 90 kHz units.
+    start_pts_90k: Option<i64>,
+
+    /// The number of samples encoded so far.
+    encoded_samples: i64,
+}
+
+impl Transcoder {
+    /// Creates a transcoder, also returning the sample entry for the AAC it produces.
+    #[cfg(feature = "fdk-aac")]
+    pub fn new(law: Law, sample_rate: u32) -> Result<(Self, db::AudioSampleEntryToInsert), Error> {
+        use fdk_aac::enc::{BitRate, ChannelMode, Encoder, EncoderParams, Transport};
+        let encoder = Encoder::new(EncoderParams {
+            bit_rate: BitRate::VbrMedium,
+            sample_rate,
+            transport: Transport::Raw,
+            channels: ChannelMode::Mono,
+        })
+        .map_err(|e| err!(Unknown, msg("unable to create AAC encoder: {e:?}")))?;
+        let info = encoder
+            .info()
+            .map_err(|e| err!(Unknown, msg("unable to get AAC encoder info: {e:?}")))?;
+        let sample_entry = crate::aac::parse_extra_data(&info.confBuf[..info.confSize as usize])?;
+        if sample_entry.sample_rate != sample_rate {
+            bail!(
+                Internal,
+                msg(
+                    "AAC encoder produced sample rate {}; expected {}",
+                    sample_entry.sample_rate,
+                    sample_rate
+                ),
+            );
+        }
+        Ok((
+            Transcoder {
+                law,
+                sample_rate,
+                encoder,
+                frame_length: info.frameLength as usize,
+                pcm: Vec::new(),
+                start_pts_90k: None,
+                encoded_samples: 0,
+            },
+            sample_entry,
+        ))
+    }
+
+    #[cfg(not(feature = "fdk-aac"))]
+    pub fn new(
+        _law: Law,
+        _sample_rate: u32,
+    ) -> Result<(Self, db::AudioSampleEntryToInsert), Error> {
+        bail!(
+            Unimplemented,
+            msg("transcoding audio requires building Moonfire NVR with --features=fdk-aac")
+        );
+    }
+
+    /// Decodes a G.711 frame with the given presentation timestamp (in 90 kHz units), returning
+    /// any AAC frames which are now complete.
+    ///
+    /// The stream is assumed to be continuous after the first frame; later timestamps are
+    /// ignored.
+    pub fn push(&mut self, pts_90k: i64, data: &[u8]) -> Result<Vec<AudioFrame>, Error> {
+        self.start_pts_90k.get_or_insert(pts_90k);
+        self.pcm.extend(data.iter().map(|&b| self.law.decode(b)));
+        self.encode()
+    }
+
+    #[cfg(feature = "fdk-aac")]
+    fn encode(&mut self) -> Result<Vec<AudioFrame>, Error> {
+        let start_pts_90k = self.start_pts_90k.expect("set by push");
+        let mut frames = Vec::new();
+        let mut out = [0u8; 8192];
+        let mut consumed = 0;
+        while self.pcm.len() - consumed >= self.frame_length {
+            let input = &self.pcm[consumed..consumed + self.frame_length];
+            let info = self
+                .encoder
+                .encode(input, &mut out)
+                .map_err(|e| err!(Unknown, msg("AAC encoding failed: {e:?}")))?;
+            if info.input_consumed == 0 {
+                bail!(Internal, msg("AAC encoder consumed no input"));
+            }
+            consumed += info.input_consumed;
+            if info.output_size > 0 {
+                let pts_90k =
+                    start_pts_90k + self.encoded_samples * 90_000 / i64::from(self.sample_rate);
+                frames.push(AudioFrame {
+                    pts: pts_90k,
+                    duration: self.frame_length as i32,
+                    data: bytes::Bytes::copy_from_slice(&out[..info.output_size]),
+                });
+                self.encoded_samples += self.frame_length as i64;
+            }
+        }
+        self.pcm.drain(..consumed);
+        Ok(frames)
+    }
+
+    #[cfg(not(feature = "fdk-aac"))]
+    fn encode(&mut self) -> Result<Vec<AudioFrame>, Error> {
+        unreachable!("Transcoder::new always fails without the fdk-aac feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    #[test]
+    fn decode_ulaw() {
+        testutil::init();
+        assert_eq!(super::decode_ulaw(0xff), 0);
+        assert_eq!(super::decode_ulaw(0x7f), 0);
+        assert_eq!(super::decode_ulaw(0x00), -32124);
+        assert_eq!(super::decode_ulaw(0x80), 32124);
+        assert_eq!(super::decode_ulaw(0xfe), 8);
+        assert_eq!(super::decode_ulaw(0x7e), -8);
+    }
+
+    #[test]
+    fn decode_alaw() {
+        testutil::init();
+        assert_eq!(super::decode_alaw(0xd5), 8);
+        assert_eq!(super::decode_alaw(0x55), -8);
+        assert_eq!(super::decode_alaw(0xaa), 32256);
+        assert_eq!(super::decode_alaw(0x2a), -32256);
+    }
+
+    #[test]
+    fn encode_ulaw() {
+        testutil::init();
+        assert_eq!(super::encode_ulaw(0), 0xff);
+        assert_eq!(super::encode_ulaw(8), 0xfe);
+        assert_eq!(super::encode_ulaw(-8), 0x7e);
+        assert_eq!(super::encode_ulaw(i16::MAX), 0x80);
+        assert_eq!(super::encode_ulaw(i16::MIN), 0x00);
+
+        // Every code but negative zero (0x7f) survives a round trip.
+        for b in (0..=u8::MAX).filter(|&b| b != 0x7f) {
+            assert_eq!(super::encode_ulaw(super::decode_ulaw(b)), b, "{b:#x}");
+        }
+    }
+
+    #[test]
+    fn encode_alaw() {
+        testutil::init();
+        assert_eq!(super::encode_alaw(8), 0xd5);
+        assert_eq!(super::encode_alaw(-8), 0x55);
+        assert_eq!(super::encode_alaw(i16::MAX), 0xaa);
+        assert_eq!(super::encode_alaw(i16::MIN), 0x2a);
+        for b in 0..=u8::MAX {
+            assert_eq!(super::encode_alaw(super::decode_alaw(b)), b, "{b:#x}");
+        }
+    }
+}
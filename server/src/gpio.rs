@@ -0,0 +1,319 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Setting signals from GPIO input lines, as configured by [`GpioConfig`].
+//!
+//! Each line, such as one wired to a PIR motion sensor's output or an alarm panel's relay, is
+//! sampled every [`POLL_INTERVAL`]. A change takes effect once the line has held its new level
+//! for the configured debounce time, and is recorded from when the level first changed. Signal
+//! states are held as described in [`crate::signal_hold`] while the line is readable.
+//!
+//! Reading lines requires building with `--features=gpio`, which is available only on Linux.
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::Error;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use tracing::{debug, info, warn};
+
+use crate::cmds::run::config::GpioConfig;
+use crate::signal_hold::Holder;
+
+/// How often each line is sampled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A signal state to set.
+#[derive(Debug, PartialEq, Eq)]
+struct Update {
+    signal: u32,
+    state: u16,
+    range: Range<recording::Time>,
+}
+
+/// Turns samples of a line's level into signal updates.
+struct Debouncer {
+    signal: u32,
+    active_state: u16,
+    inactive_state: u16,
+    debounce: recording::Duration,
+
+    /// A level which differs from `held`, and when it was first seen.
+    pending: Option<(bool, recording::Time)>,
+
+    /// The debounced level.
+    held: Holder<bool>,
+}
+
+impl Debouncer {
+    #[cfg_attr(not(feature = "gpio"), allow(dead_code))]
+    fn new(config: &GpioConfig) -> Self {
+        Debouncer {
+            signal: config.signal_id,
+            active_state: config.active_state,
+            inactive_state: config.inactive_state,
+            debounce: recording::Duration(
+                i64::from(config.debounce_ms) * TIME_UNITS_PER_SEC / 1_000,
+            ),
+            pending: None,
+            held: Holder::default(),
+        }
+    }
+
+    fn state(&self, active: bool) -> u16 {
+        match active {
+            true => self.active_state,
+            false => self.inactive_state,
+        }
+    }
+
+    /// Notes that the line is `active` as of `now`, returning a state to set, if any.
+    fn observe(&mut self, now: recording::Time, active: bool) -> Option<Update> {
+        if self.held.state() == Some(active) {
+            self.pending = None;
+            return self.extend(now);
+        }
+        let since = match self.pending {
+            Some((a, since)) if a == active => since,
+            _ => {
+                self.pending = Some((active, now));
+                now
+            }
+        };
+        if now - since < self.debounce {
+            return self.extend(now);
+        }
+        debug!(
+            "GPIO line sets signal {} to {}",
+            self.signal,
+            self.state(active)
+        );
+        self.pending = None;
+        let range = self.held.set(since, now, active)?;
+        Some(Update {
+            signal: self.signal,
+            state: self.state(active),
+            range,
+        })
+    }
+
+    /// Returns an extension of the held state, if due as of `now`.
+    fn extend(&mut self, now: recording::Time) -> Option<Update> {
+        let (range, active) = self.held.extend(now)?;
+        Some(Update {
+            signal: self.signal,
+            state: self.state(active),
+            range,
+        })
+    }
+
+    /// Forgets the line's level, as when it can't be read. The state lapses at the end of its
+    /// hold.
+    fn reset(&mut self) {
+        self.pending = None;
+        self.held.reset();
+    }
+}
+
+#[cfg_attr(not(feature = "gpio"), allow(dead_code))]
+struct Line {
+    /// A description of the line for log messages, such as `/dev/gpiochip0 line 17`.
+    label: String,
+
+    #[cfg(feature = "gpio")]
+    handle: gpio_cdev::LineHandle,
+
+    debouncer: Debouncer,
+
+    /// True if the last read failed, to log only the first of a run of failures.
+    failed: bool,
+}
+
+impl Line {
+    #[cfg(feature = "gpio")]
+    fn open(config: &GpioConfig) -> Result<Self, Error> {
+        use base::err;
+        use gpio_cdev::{Chip, LineRequestFlags};
+        let label = format!("{} line {}", config.chip.display(), config.line);
+        let mut flags = LineRequestFlags::INPUT;
+        if config.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        let handle = Chip::new(&config.chip)
+            .and_then(|mut c| c.get_line(config.line))
+            .and_then(|l| l.request(flags, 0, "moonfire-nvr"))
+            .map_err(|e| err!(Unavailable, msg("unable to open {label}"), source(e)))?;
+        Ok(Line {
+            label,
+            handle,
+            debouncer: Debouncer::new(config),
+            failed: false,
+        })
+    }
+
+    #[cfg(not(feature = "gpio"))]
+    fn open(_config: &GpioConfig) -> Result<Self, Error> {
+        base::bail!(
+            Unimplemented,
+            msg("GPIO signals require building Moonfire NVR with --features=gpio")
+        );
+    }
+
+    /// Returns true if the line is active.
+    #[cfg(feature = "gpio")]
+    fn read(&self) -> Result<bool, Error> {
+        self.handle
+            .get_value()
+            .map(|v| v != 0)
+            .map_err(|e| base::err!(Unavailable, msg("unable to read {}", self.label), source(e)))
+    }
+
+    #[cfg(not(feature = "gpio"))]
+    fn read(&self) -> Result<bool, Error> {
+        unreachable!("lines can't be opened without the gpio feature")
+    }
+}
+
+pub struct Gpio {
+    lines: Vec<Line>,
+}
+
+impl Gpio {
+    pub fn new(configs: &[GpioConfig]) -> Result<Self, Error> {
+        Ok(Gpio {
+            lines: configs.iter().map(Line::open).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Samples every line, returning states to set.
+    fn poll(&mut self, now: recording::Time) -> Vec<Update> {
+        let mut updates = Vec::new();
+        for line in &mut self.lines {
+            match line.read() {
+                Ok(active) => {
+                    line.failed = false;
+                    updates.extend(line.debouncer.observe(now, active));
+                }
+                Err(err) => {
+                    if !line.failed {
+                        warn!(%err, "unable to read GPIO line; its signal will lapse");
+                    }
+                    line.failed = true;
+                    line.debouncer.reset();
+                }
+            }
+        }
+        updates
+    }
+}
+
+/// Sets signals from GPIO lines until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    mut gpio: Gpio,
+) {
+    for line in &gpio.lines {
+        info!(
+            "setting signal {} from {}",
+            line.debouncer.signal, line.label
+        );
+    }
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+        let now = recording::Time::new(db.clocks().realtime());
+        let updates = gpio.poll(now);
+        if updates.is_empty() {
+            continue;
+        }
+        let mut l = db.lock();
+        for u in updates {
+            if let Err(err) = l.update_signals(u.range, &[u.signal], &[u.state]) {
+                warn!(%err, "unable to set signal {} from GPIO", u.signal);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal_hold::HOLD;
+
+    #[test]
+    fn debounce() {
+        let mut d = Debouncer::new(&GpioConfig {
+            chip: "/dev/gpiochip0".into(),
+            line: 17,
+            active_low: false,
+            signal_id: 1,
+            active_state: 2,
+            inactive_state: 1,
+            debounce_ms: 50,
+        });
+        let ms = |ms| recording::Duration(ms * TIME_UNITS_PER_SEC / 1_000);
+        let t0 = recording::Time(1_000 * TIME_UNITS_PER_SEC);
+
+        // The initial level takes effect once it's held for the debounce time.
+        assert_eq!(d.observe(t0, false), None);
+        assert_eq!(d.observe(t0 + ms(40), false), None);
+        assert_eq!(
+            d.observe(t0 + ms(50), false),
+            Some(Update {
+                signal: 1,
+                state: 1,
+                range: t0..t0 + ms(50) + HOLD,
+            })
+        );
+
+        // A brief glitch is ignored.
+        let t1 = t0 + ms(1_000);
+        assert_eq!(d.observe(t1, true), None);
+        assert_eq!(d.observe(t1 + ms(10), false), None);
+        assert_eq!(d.observe(t1 + ms(100), true), None);
+
+        // A change takes effect from when it was first seen.
+        let t2 = t1 + ms(100);
+        assert_eq!(
+            d.observe(t2 + ms(50), true),
+            Some(Update {
+                signal: 1,
+                state: 2,
+                range: t2..t2 + ms(50) + HOLD,
+            })
+        );
+
+        // The state is extended once enough time has passed.
+        let t3 = t2 + ms(50);
+        assert_eq!(d.observe(t3 + ms(14_990), true), None);
+        assert_eq!(
+            d.observe(t3 + ms(15_000), true),
+            Some(Update {
+                signal: 1,
+                state: 2,
+                range: t3 + HOLD..t3 + ms(15_000) + HOLD,
+            })
+        );
+
+        // After a reset, nothing is extended until the level is debounced again.
+        d.reset();
+        let t4 = t3 + ms(60_000);
+        assert_eq!(d.observe(t4, true), None);
+        assert_eq!(
+            d.observe(t4 + ms(50), true),
+            Some(Update {
+                signal: 1,
+                state: 2,
+                range: t4..t4 + ms(50) + HOLD,
+            })
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! gRPC API, as configured by [`GrpcConfig`] and described by `proto/nvr.proto`.
+//!
+//! This offers camera listing, recording search, and `.mp4` download to programmatic clients
+//! which prefer typed, streaming-friendly access to the JSON API. Calls authenticate with an API
+//! token sent as `authorization: Bearer <token>` metadata, as with the HTTP API. It requires
+//! building with `--features=grpc`, which in turn requires `protoc` at build time.
+
+use std::sync::Arc;
+
+use base::{err, Error};
+use tracing::info;
+
+use crate::cmds::run::config::GrpcConfig;
+
+pub struct Server {
+    listener: tokio::net::TcpListener,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+}
+
+impl Server {
+    pub fn new(config: &GrpcConfig) -> Result<Self, Error> {
+        if cfg!(not(feature = "grpc")) {
+            base::bail!(
+                Unimplemented,
+                msg("the gRPC API requires building Moonfire NVR with --features=grpc")
+            );
+        }
+        let listener = std::net::TcpListener::bind(config.address)
+            .map_err(|e| err!(e, msg("unable to bind gRPC socket {}", config.address)))?;
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            listener: tokio::net::TcpListener::from_std(listener)?,
+            allow_unauthenticated_permissions: config
+                .allow_unauthenticated_permissions
+                .clone()
+                .map(db::Permissions::from),
+        })
+    }
+}
+
+/// Serves gRPC requests until shutdown.
+pub async fn run(db: Arc<db::Database>, shutdown_rx: base::shutdown::Receiver, server: Server) {
+    if let Ok(addr) = server.listener.local_addr() {
+        info!("serving gRPC on {addr}");
+    }
+    #[cfg(feature = "grpc")]
+    service::serve(db, shutdown_rx, server).await;
+    #[cfg(not(feature = "grpc"))]
+    let _ = (db, shutdown_rx, server.allow_unauthenticated_permissions);
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    use base::{Error, ErrorKind};
+    use bytes::Buf;
+    use db::{auth, recording};
+    use futures::{Stream, StreamExt};
+    use http_serve::Entity;
+    use tonic::{Code, Request, Response, Status};
+    use tracing::warn;
+    use uuid::Uuid;
+
+    use crate::web::search::{self, Key, Query, MAX_LIMIT};
+
+    #[allow(clippy::all)]
+    pub(super) mod pb {
+        tonic::include_proto!("moonfire.nvr.v1");
+    }
+
+    use pb::nvr_server::{Nvr, NvrServer};
+
+    pub(super) async fn serve(
+        db: Arc<db::Database>,
+        shutdown_rx: base::shutdown::Receiver,
+        server: super::Server,
+    ) {
+        let incoming =
+            tonic::transport::server::TcpIncoming::from_listener(server.listener, true, None);
+        let incoming = match incoming {
+            Ok(i) => i,
+            Err(err) => {
+                warn!(%err, "unable to serve gRPC");
+                return;
+            }
+        };
+        let service = Service {
+            db,
+            allow_unauthenticated_permissions: server.allow_unauthenticated_permissions,
+        };
+        if let Err(err) = tonic::transport::Server::builder()
+            .add_service(NvrServer::new(service))
+            .serve_with_incoming_shutdown(incoming, shutdown_rx.as_future())
+            .await
+        {
+            warn!(%err, "gRPC server failed");
+        }
+    }
+
+    /// Converts an error to a gRPC status, as `web::from_base_error` does for HTTP.
+    fn status(err: Error) -> Status {
+        let code = match err.kind() {
+            ErrorKind::Unauthenticated => Code::Unauthenticated,
+            ErrorKind::PermissionDenied => Code::PermissionDenied,
+            ErrorKind::InvalidArgument => Code::InvalidArgument,
+            ErrorKind::FailedPrecondition => Code::FailedPrecondition,
+            ErrorKind::NotFound => Code::NotFound,
+            ErrorKind::Unavailable => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        Status::new(code, err.to_string())
+    }
+
+    fn parse_uuid(uuid: &str) -> Result<Uuid, Status> {
+        Uuid::parse_str(uuid).map_err(|_| Status::invalid_argument(format!("bad uuid {uuid:?}")))
+    }
+
+    fn parse_stream(stream: &str) -> Result<db::StreamType, Status> {
+        db::StreamType::parse(stream)
+            .ok_or_else(|| Status::invalid_argument(format!("bad stream {stream:?}")))
+    }
+
+    struct Service {
+        db: Arc<db::Database>,
+        allow_unauthenticated_permissions: Option<db::Permissions>,
+    }
+
+    impl Service {
+        /// Authenticates the request, returning the caller's permissions if it may view video.
+        fn authenticate<T>(&self, req: &Request<T>) -> Result<db::Permissions, Status> {
+            let permissions = match req.metadata().get("authorization") {
+                Some(h) => {
+                    let token = h
+                        .as_bytes()
+                        .strip_prefix(b"Bearer ")
+                        .and_then(|t| auth::RawApiToken::decode_base64(t).ok())
+                        .ok_or_else(|| Status::unauthenticated("malformed API token"))?;
+                    let authreq = auth::Request {
+                        when_sec: Some(self.db.clocks().realtime().sec),
+                        user_agent: req
+                            .metadata()
+                            .get("user-agent")
+                            .map(|u| u.as_bytes().to_vec()),
+                        addr: req.remote_addr().map(|a| a.ip()),
+                    };
+                    let mut l = self.db.lock();
+                    match l.authenticate_api_token(authreq, &token) {
+                        Ok((t, _)) => t.permissions.clone(),
+                        Err(err) if err.kind() == ErrorKind::Unauthenticated => {
+                            // As with HTTP, log the specific reason but don't tell the client.
+                            warn!(err = %err.chain(), "gRPC API token authentication failed");
+                            return Err(Status::unauthenticated("invalid API token"));
+                        }
+                        Err(err) => return Err(status(err)),
+                    }
+                }
+                None => self
+                    .allow_unauthenticated_permissions
+                    .clone()
+                    .ok_or_else(|| Status::unauthenticated("credentials required"))?,
+            };
+            if !permissions.view_video {
+                return Err(Status::permission_denied("view_video required"));
+            }
+            Ok(permissions)
+        }
+    }
+
+    fn query(req: pb::SearchRecordingsRequest) -> Result<Query, Status> {
+        let mut q = Query::default();
+        for c in &req.camera_uuids {
+            q.cameras.push(parse_uuid(c)?);
+        }
+        for s in &req.streams {
+            q.streams.push(parse_stream(s)?);
+        }
+        if let Some(t) = req.start_time_90k {
+            q.time.start = recording::Time(t);
+        }
+        if let Some(t) = req.end_time_90k {
+            q.time.end = recording::Time(t);
+        }
+        for t in &req.signal_types {
+            q.signal_types.push(parse_uuid(t)?);
+        }
+        q.detected_classes = req.detected_classes;
+        q.min_duration = recording::Duration(req.min_duration_90k);
+        q.min_height = u16::try_from(req.min_height)
+            .map_err(|_| Status::invalid_argument("min_height out of range"))?;
+        match usize::try_from(req.limit) {
+            Ok(0) => {}
+            Ok(l) if l <= MAX_LIMIT => q.limit = l,
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "limit must be between 1 and {MAX_LIMIT}"
+                )))
+            }
+        }
+        if !req.cursor.is_empty() {
+            q.cursor = Some(Key::decode(&req.cursor).map_err(status)?);
+        }
+        Ok(q)
+    }
+
+    #[tonic::async_trait]
+    impl Nvr for Service {
+        async fn list_cameras(
+            &self,
+            req: Request<pb::ListCamerasRequest>,
+        ) -> Result<Response<pb::ListCamerasResponse>, Status> {
+            let permissions = self.authenticate(&req)?;
+            let l = self.db.lock();
+            let mut cameras = Vec::new();
+            for c in l.cameras_by_id().values() {
+                if !permissions.allows_camera(c.uuid) {
+                    continue;
+                }
+                let mut streams = Vec::new();
+                for (i, id) in c.streams.iter().enumerate() {
+                    let (Some(id), Some(type_)) = (*id, db::StreamType::from_index(i)) else {
+                        continue;
+                    };
+                    let s = &l.streams_by_id()[&id];
+                    streams.push(pb::Stream {
+                        r#type: type_.as_str().to_owned(),
+                        min_start_time_90k: s.range.as_ref().map(|r| r.start.0),
+                        max_end_time_90k: s.range.as_ref().map(|r| r.end.0),
+                        total_duration_90k: s.duration.0,
+                        total_sample_file_bytes: s.sample_file_bytes,
+                    });
+                }
+                cameras.push(pb::Camera {
+                    uuid: c.uuid.to_string(),
+                    short_name: c.short_name.clone(),
+                    streams,
+                });
+            }
+            Ok(Response::new(pb::ListCamerasResponse { cameras }))
+        }
+
+        async fn search_recordings(
+            &self,
+            req: Request<pb::SearchRecordingsRequest>,
+        ) -> Result<Response<pb::SearchRecordingsResponse>, Status> {
+            let permissions = self.authenticate(&req)?;
+            let q = query(req.into_inner())?;
+            let l = self.db.lock();
+            let (results, next) = search::search(&l, &permissions, &q).map_err(status)?;
+            Ok(Response::new(pb::SearchRecordingsResponse {
+                recordings: results
+                    .into_iter()
+                    .map(|r| pb::Recording {
+                        camera_uuid: r.camera_uuid.to_string(),
+                        stream: r.stream.to_owned(),
+                        id: r.id,
+                        start_time_90k: r.start_time_90k.0,
+                        end_time_90k: r.end_time_90k.0,
+                        video_samples: r.video_samples,
+                        sample_file_bytes: r.sample_file_bytes,
+                        open_id: r.open_id,
+                        growing: r.growing,
+                    })
+                    .collect(),
+                next_cursor: next.map(|k| k.encode()).unwrap_or_default(),
+            }))
+        }
+
+        type DownloadSegmentStream =
+            Pin<Box<dyn Stream<Item = Result<pb::Chunk, Status>> + Send + 'static>>;
+
+        async fn download_segment(
+            &self,
+            req: Request<pb::DownloadSegmentRequest>,
+        ) -> Result<Response<Self::DownloadSegmentStream>, Status> {
+            let permissions = self.authenticate(&req)?;
+            let req = req.into_inner();
+            let uuid = parse_uuid(&req.camera_uuid)?;
+            let stream_type = parse_stream(&req.stream)?;
+            if !permissions.allows_camera(uuid) {
+                return Err(Status::permission_denied(format!(
+                    "camera {uuid} not allowed"
+                )));
+            }
+            if req.end_time_90k <= req.start_time_90k {
+                return Err(Status::invalid_argument(
+                    "end_time_90k must follow start_time_90k",
+                ));
+            }
+            let range = recording::Time(req.start_time_90k)..recording::Time(req.end_time_90k);
+            let mp4 = crate::web::exports::build_clip(&self.db, uuid, stream_type, range)
+                .map_err(status)?;
+            let total_bytes = mp4.len();
+            let mut first = true;
+            let chunks = Pin::from(mp4.get_range(0..total_bytes)).map(move |c| {
+                let mut c = c.map_err(|e| Status::internal(e.to_string()))?;
+                Ok(pb::Chunk {
+                    data: c.copy_to_bytes(c.remaining()),
+                    total_bytes: if std::mem::take(&mut first) {
+                        total_bytes
+                    } else {
+                        0
+                    },
+                })
+            });
+            Ok(Response::new(Box::pin(chunks)))
+        }
+    }
+}
@@ -44,7 +44,7 @@ const PIXEL_ASPECT_RATIOS: [((u16, u16), (u16, u16)); 6] = [
 ///
 /// Note that at least in the case of .mp4 muxing, we don't need to fix up the underlying SPS.
 /// PixelAspectRatioBox's definition says that it overrides the H.264-level declaration.
-fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
+pub(crate) fn default_pixel_aspect_ratio(width: u16, height: u16) -> (u16, u16) {
     if width >= height {
         PIXEL_ASPECT_RATIOS
             .iter()
@@ -175,19 +175,115 @@ pub fn parse_extra_data(extradata: &[u8]) -> Result<VideoSampleEntryToInsert, Er
     })
 }
 
+/// NAL unit types, ISO/IEC 14496-10 table 7-1.
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+/// Splits an Annex B byte stream into NAL units, excluding start codes and trailing zeros.
+pub(crate) fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut push = |mut nal: &[u8]| {
+        while let [rest @ .., 0] = nal {
+            nal = rest;
+        }
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+    };
+    let mut start = None;
+    for pos in memchr::memmem::find_iter(data, b"\x00\x00\x01") {
+        if let Some(s) = start {
+            push(&data[s..pos]);
+        }
+        start = Some(pos + 3);
+    }
+    if let Some(s) = start {
+        push(&data[s..]);
+    }
+    nals
+}
+
+/// Builds an `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 section 5.3.3.1) with 4-byte
+/// NAL unit lengths from the given SPS and PPS NAL units, suitable for [`parse_extra_data`].
+pub(crate) fn avc_decoder_config(sps: &[u8], pps: &[u8]) -> Result<Vec<u8>, Error> {
+    if sps.len() < 4 {
+        bail!(InvalidArgument, msg("SPS too short"));
+    }
+    let (Ok(sps_len), Ok(pps_len)) = (u16::try_from(sps.len()), u16::try_from(pps.len())) else {
+        bail!(OutOfRange, msg("parameter set too long"));
+    };
+    let mut config = Vec::with_capacity(11 + sps.len() + pps.len());
+    config.extend_from_slice(&[
+        0x01,   // configurationVersion
+        sps[1], // AVCProfileIndication
+        sps[2], // profile_compatibility
+        sps[3], // AVCLevelIndication
+        0xff,   // reserved + lengthSizeMinusOne = 3
+        0xe1,   // reserved + numOfSequenceParameterSets = 1
+    ]);
+    config.extend_from_slice(&sps_len.to_be_bytes());
+    config.extend_from_slice(sps);
+    config.push(1); // numOfPictureParameterSets
+    config.extend_from_slice(&pps_len.to_be_bytes());
+    config.extend_from_slice(pps);
+    Ok(config)
+}
+
+/// An access unit converted from Annex B to the AVC form stored in sample files.
+pub(crate) struct AccessUnit<'a> {
+    /// The NAL units with 4-byte length prefixes, excluding parameter sets and delimiters.
+    pub(crate) data: Vec<u8>,
+
+    /// The last SPS and PPS in the access unit, if any.
+    pub(crate) sps: Option<&'a [u8]>,
+    pub(crate) pps: Option<&'a [u8]>,
+
+    /// True if the access unit contains an IDR picture.
+    pub(crate) is_key: bool,
+}
+
+/// Converts an Annex B access unit, as output by encoders, to AVC form.
+pub(crate) fn convert_annex_b(data: &[u8]) -> Result<AccessUnit, Error> {
+    let mut au = AccessUnit {
+        data: Vec::with_capacity(data.len() + 16),
+        sps: None,
+        pps: None,
+        is_key: false,
+    };
+    for nal in split_annex_b(data) {
+        match nal[0] & 0x1f {
+            NAL_SPS => au.sps = Some(nal),
+            NAL_PPS => au.pps = Some(nal),
+            NAL_AUD => {}
+            t => {
+                au.is_key |= t == NAL_IDR;
+                let len = u32::try_from(nal.len()).map_err(|_| err!(OutOfRange))?;
+                au.data.extend_from_slice(&len.to_be_bytes());
+                au.data.extend_from_slice(nal);
+            }
+        }
+    }
+    Ok(au)
+}
+
+/// An `AVCDecoderConfigurationRecord` for a 1280x720 stream, shared by tests.
+#[cfg(test)]
+#[rustfmt::skip]
+pub(crate) const AVC_DECODER_CONFIG_TEST_INPUT: [u8; 38] = [
+    0x01, 0x4d, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x17,
+    0x67, 0x4d, 0x00, 0x1f, 0x9a, 0x66, 0x02, 0x80,
+    0x2d, 0xff, 0x35, 0x01, 0x01, 0x01, 0x40, 0x00,
+    0x00, 0xfa, 0x00, 0x00, 0x1d, 0x4c, 0x01, 0x01,
+    0x00, 0x04, 0x68, 0xee, 0x3c, 0x80,
+];
+
 #[cfg(test)]
 mod tests {
+    use super::AVC_DECODER_CONFIG_TEST_INPUT;
     use db::testutil;
 
-    #[rustfmt::skip]
-    const AVC_DECODER_CONFIG_TEST_INPUT: [u8; 38] = [
-        0x01, 0x4d, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x17,
-        0x67, 0x4d, 0x00, 0x1f, 0x9a, 0x66, 0x02, 0x80,
-        0x2d, 0xff, 0x35, 0x01, 0x01, 0x01, 0x40, 0x00,
-        0x00, 0xfa, 0x00, 0x00, 0x1d, 0x4c, 0x01, 0x01,
-        0x00, 0x04, 0x68, 0xee, 0x3c, 0x80,
-    ];
-
     #[rustfmt::skip]
     const TEST_OUTPUT: [u8; 132] = [
         0x00, 0x00, 0x00, 0x84, 0x61, 0x76, 0x63, 0x31,
@@ -219,6 +315,34 @@ mod tests {
         assert_eq!(e.rfc6381_codec, "avc1.4d001f");
     }
 
+    #[test]
+    fn annex_b() {
+        testutil::init();
+        let sps = &AVC_DECODER_CONFIG_TEST_INPUT[8..31];
+        let pps = &AVC_DECODER_CONFIG_TEST_INPUT[34..];
+        assert_eq!(
+            super::avc_decoder_config(sps, pps).unwrap(),
+            AVC_DECODER_CONFIG_TEST_INPUT
+        );
+
+        // An access unit delimiter, parameter sets, and an IDR slice, with both start code
+        // lengths and a trailing zero byte.
+        let mut data = b"\x00\x00\x00\x01\x09\xf0\x00\x00\x00\x01".to_vec();
+        data.extend_from_slice(sps);
+        data.extend_from_slice(b"\x00\x00\x01");
+        data.extend_from_slice(pps);
+        data.extend_from_slice(b"\x00\x00\x00\x01\x65\x88\x84\x00");
+        let au = super::convert_annex_b(&data).unwrap();
+        assert_eq!(au.sps, Some(sps));
+        assert_eq!(au.pps, Some(pps));
+        assert!(au.is_key);
+        assert_eq!(au.data, b"\x00\x00\x00\x03\x65\x88\x84");
+
+        let au = super::convert_annex_b(b"\x00\x00\x01\x41\x9a").unwrap();
+        assert!(!au.is_key);
+        assert_eq!(au.data, b"\x00\x00\x00\x02\x41\x9a");
+    }
+
     #[test]
     fn pixel_aspect_ratios() {
         use super::default_pixel_aspect_ratio;
@@ -0,0 +1,682 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! H.265 (HEVC) handling.
+//!
+//! Like `h264.rs`, this tries to understand only as much of the codec as is necessary to record
+//! it into `.mp4` files: enough of the sequence parameter set to fill in the
+//! `VisualSampleEntry`, and enough framing to build the ISO/IEC 14496-15 section 8.3.3.1
+//! `HEVCDecoderConfigurationRecord` and length-prefixed samples.
+//!
+//! Retina 0.4 doesn't depacketize H.265, so this also contains a minimal RFC 7798 depacketizer
+//! which operates on the raw RTP packets. It supports single NAL unit packets, aggregation
+//! packets, and fragmentation units without DONL fields (`sprop-max-don-diff=0`), which is what
+//! common IP cameras send. Parameter sets are expected in-band, before each IRAP picture. They're
+//! stripped from the samples and placed only in the `hvcC` box, so the sample entry can use the
+//! `hvc1` type, which is the one browsers support.
+
+use base::{bail, err, Error};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use db::VideoSampleEntryToInsert;
+use std::convert::TryFrom;
+
+const NAL_VPS: u8 = 32;
+const NAL_SPS: u8 = 33;
+const NAL_PPS: u8 = 34;
+const NAL_AUD: u8 = 35;
+
+/// RFC 7798 section 4.4.2: aggregation packet.
+const PAYLOAD_AP: u8 = 48;
+
+/// RFC 7798 section 4.4.3: fragmentation unit.
+const PAYLOAD_FU: u8 = 49;
+
+/// RFC 7798 section 4.4.4: PACI packet.
+const PAYLOAD_PACI: u8 = 50;
+
+fn nal_type(nal: &[u8]) -> u8 {
+    (nal[0] >> 1) & 0x3f
+}
+
+/// Returns true if the given NAL unit type is an IRAP (intra random access point) picture.
+/// See ITU-T H.265 section 7.4.2.2 and Table 7-1.
+fn is_irap(nal_type: u8) -> bool {
+    (16..=23).contains(&nal_type)
+}
+
+/// Reads bits from a NAL unit, skipping emulation prevention bytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte_pos: usize,
+    bit_pos: u8,
+    zeros: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            byte_pos: 0,
+            bit_pos: 0,
+            zeros: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        if self.bit_pos == 0 {
+            // Skip an emulation_prevention_three_byte.
+            if self.zeros >= 2 && self.data.get(self.byte_pos) == Some(&3) {
+                self.byte_pos += 1;
+                self.zeros = 0;
+            }
+            let Some(&b) = self.data.get(self.byte_pos) else {
+                bail!(InvalidArgument, msg("unexpected end of NAL unit"));
+            };
+            if b == 0 {
+                self.zeros += 1;
+            } else {
+                self.zeros = 0;
+            }
+        }
+        let b = self.data[self.byte_pos];
+        let bit = (b >> (7 - self.bit_pos)) & 1 == 1;
+        self.bit_pos += 1;
+        if self.bit_pos == 8 {
+            self.bit_pos = 0;
+            self.byte_pos += 1;
+        }
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Result<u64, Error> {
+        debug_assert!(n <= 64);
+        let mut v = 0;
+        for _ in 0..n {
+            v = (v << 1) | u64::from(self.read_bit()?);
+        }
+        Ok(v)
+    }
+
+    fn skip_bits(&mut self, n: u32) -> Result<(), Error> {
+        for _ in 0..n {
+            self.read_bit()?;
+        }
+        Ok(())
+    }
+
+    /// Reads an unsigned Exp-Golomb-coded value, as in ITU-T H.265 section 9.2.
+    fn read_ue(&mut self) -> Result<u32, Error> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!(InvalidArgument, msg("invalid Exp-Golomb code"));
+            }
+        }
+        let rest = self.read_bits(leading_zeros)?;
+        u32::try_from((1u64 << leading_zeros) - 1 + rest)
+            .map_err(|_| err!(InvalidArgument, msg("Exp-Golomb value out of range")))
+    }
+}
+
+/// The fields of a sequence parameter set which are relevant to Moonfire NVR.
+#[derive(Debug, PartialEq, Eq)]
+struct Sps {
+    general_profile_space: u8,
+    general_tier_flag: bool,
+    general_profile_idc: u8,
+    general_profile_compatibility_flags: u32,
+    general_constraint_indicator_flags: u64,
+    general_level_idc: u8,
+    max_sub_layers_minus1: u8,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    width: u16,
+    height: u16,
+}
+
+impl Sps {
+    /// Parses a SPS NAL unit (including its two-byte header), as in ITU-T H.265 section 7.3.2.2.
+    fn parse(nal: &[u8]) -> Result<Self, Error> {
+        if nal.len() < 3 || nal_type(nal) != NAL_SPS {
+            bail!(InvalidArgument, msg("not a SPS NAL unit"));
+        }
+        let mut r = BitReader::new(&nal[2..]);
+        r.skip_bits(4)?; // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        let temporal_id_nesting = r.read_bit()?;
+
+        // profile_tier_level(1, sps_max_sub_layers_minus1), section 7.3.3.
+        let general_profile_space = r.read_bits(2)? as u8;
+        let general_tier_flag = r.read_bit()?;
+        let general_profile_idc = r.read_bits(5)? as u8;
+        let general_profile_compatibility_flags = r.read_bits(32)? as u32;
+        let general_constraint_indicator_flags = r.read_bits(48)?;
+        let general_level_idc = r.read_bits(8)? as u8;
+        let mut sub_layers = [(false, false); 7];
+        let sub_layers = &mut sub_layers[..usize::from(max_sub_layers_minus1)];
+        for (profile_present, level_present) in sub_layers.iter_mut() {
+            *profile_present = r.read_bit()?;
+            *level_present = r.read_bit()?;
+        }
+        if max_sub_layers_minus1 > 0 {
+            r.skip_bits(2 * (8 - u32::from(max_sub_layers_minus1)))?; // reserved_zero_2bits
+        }
+        for &(profile_present, level_present) in sub_layers.iter() {
+            if profile_present {
+                r.skip_bits(88)?;
+            }
+            if level_present {
+                r.skip_bits(8)?;
+            }
+        }
+
+        r.read_ue()?; // sps_seq_parameter_set_id
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc > 3 {
+            bail!(
+                InvalidArgument,
+                msg("bad chroma_format_idc {chroma_format_idc}")
+            );
+        }
+        if chroma_format_idc == 3 {
+            r.skip_bits(1)?; // separate_colour_plane_flag
+        }
+        let pic_width = r.read_ue()?;
+        let pic_height = r.read_ue()?;
+        let (mut crop_h, mut crop_v) = (0, 0);
+        if r.read_bit()? {
+            // conformance_window_flag. Offsets are in chroma sample units; see Table 6-1.
+            let (sub_width_c, sub_height_c) = match chroma_format_idc {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let left = r.read_ue()?;
+            let right = r.read_ue()?;
+            let top = r.read_ue()?;
+            let bottom = r.read_ue()?;
+            crop_h = sub_width_c * (left + right);
+            crop_v = sub_height_c * (top + bottom);
+        }
+        let bit_depth_luma_minus8 = r.read_ue()?;
+        let bit_depth_chroma_minus8 = r.read_ue()?;
+        if bit_depth_luma_minus8 > 7 || bit_depth_chroma_minus8 > 7 {
+            bail!(InvalidArgument, msg("bad bit depth"));
+        }
+        let (Some(width), Some(height)) = (
+            pic_width
+                .checked_sub(crop_h)
+                .and_then(|w| u16::try_from(w).ok()),
+            pic_height
+                .checked_sub(crop_v)
+                .and_then(|h| u16::try_from(h).ok()),
+        ) else {
+            bail!(
+                InvalidArgument,
+                msg("bad dimensions {pic_width}x{pic_height} with cropping {crop_h}x{crop_v}")
+            );
+        };
+        Ok(Sps {
+            general_profile_space,
+            general_tier_flag,
+            general_profile_idc,
+            general_profile_compatibility_flags,
+            general_constraint_indicator_flags,
+            general_level_idc,
+            max_sub_layers_minus1,
+            temporal_id_nesting,
+            chroma_format_idc: chroma_format_idc as u8,
+            bit_depth_luma_minus8: bit_depth_luma_minus8 as u8,
+            bit_depth_chroma_minus8: bit_depth_chroma_minus8 as u8,
+            width,
+            height,
+        })
+    }
+
+    /// Returns the RFC 6381 codec string, as described in ISO/IEC 14496-15 Annex E.3.
+    fn rfc6381_codec(&self) -> String {
+        let profile_space = match self.general_profile_space {
+            0 => "",
+            1 => "A",
+            2 => "B",
+            _ => "C",
+        };
+        let mut codec = format!(
+            "hvc1.{}{}.{:X}.{}{}",
+            profile_space,
+            self.general_profile_idc,
+            self.general_profile_compatibility_flags.reverse_bits(),
+            if self.general_tier_flag { 'H' } else { 'L' },
+            self.general_level_idc,
+        );
+        let mut constraints = [0u8; 6];
+        BigEndian::write_u48(&mut constraints, self.general_constraint_indicator_flags);
+        let len = constraints
+            .iter()
+            .rposition(|&b| b != 0)
+            .map(|p| p + 1)
+            .unwrap_or(0);
+        for b in &constraints[..len] {
+            codec.push_str(&format!(".{b:X}"));
+        }
+        codec
+    }
+}
+
+/// Builds a `VideoSampleEntryToInsert` from the given VPS, SPS, and PPS NAL units, each
+/// including the NAL header but excluding any start code or length prefix.
+pub fn parse_parameter_sets(
+    vps: &[u8],
+    sps: &[u8],
+    pps: &[u8],
+) -> Result<VideoSampleEntryToInsert, Error> {
+    let parsed = Sps::parse(sps)?;
+    let (width, height) = (parsed.width, parsed.height);
+    let mut sample_entry = Vec::with_capacity(256);
+
+    // This is a concatenation of the following boxes/classes.
+
+    // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
+    let hvc1_len_pos = sample_entry.len();
+    // length placeholder + type + reserved + data_reference_index = 1
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00hvc1\x00\x00\x00\x00\x00\x00\x00\x01");
+
+    // VisualSampleEntry, ISO/IEC 14496-12 section 12.1.3.
+    sample_entry.extend_from_slice(&[0; 16]); // pre-defined + reserved
+    sample_entry.write_u16::<BigEndian>(width)?;
+    sample_entry.write_u16::<BigEndian>(height)?;
+    sample_entry.extend_from_slice(&[
+        0x00, 0x48, 0x00, 0x00, // horizresolution
+        0x00, 0x48, 0x00, 0x00, // vertresolution
+        0x00, 0x00, 0x00, 0x00, // reserved
+        0x00, 0x01, // frame count
+        0x00, 0x00, 0x00, 0x00, // compressorname
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, //
+        0x00, 0x18, 0xff, 0xff, // depth + pre_defined
+    ]);
+
+    // HEVCSampleEntry, ISO/IEC 14496-15 section 8.4.1.
+    // HEVCConfigurationBox, ISO/IEC 14496-15 section 8.4.1.
+    let hvcc_len_pos = sample_entry.len();
+    sample_entry.extend_from_slice(b"\x00\x00\x00\x00hvcC");
+
+    // HEVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 8.3.3.1.
+    sample_entry.push(1); // configurationVersion
+    sample_entry.push(
+        (parsed.general_profile_space << 6)
+            | (u8::from(parsed.general_tier_flag) << 5)
+            | parsed.general_profile_idc,
+    );
+    sample_entry.write_u32::<BigEndian>(parsed.general_profile_compatibility_flags)?;
+    sample_entry.write_u48::<BigEndian>(parsed.general_constraint_indicator_flags)?;
+    sample_entry.push(parsed.general_level_idc);
+    sample_entry.extend_from_slice(&[
+        0xf0, 0x00, // reserved + min_spatial_segmentation_idc (unknown)
+        0xfc, // reserved + parallelismType (unknown)
+    ]);
+    sample_entry.push(0xfc | parsed.chroma_format_idc);
+    sample_entry.push(0xf8 | parsed.bit_depth_luma_minus8);
+    sample_entry.push(0xf8 | parsed.bit_depth_chroma_minus8);
+    sample_entry.extend_from_slice(&[0x00, 0x00]); // avgFrameRate (unspecified)
+    sample_entry.push(
+        // constantFrameRate = 0, numTemporalLayers, temporalIdNested, lengthSizeMinusOne = 3.
+        ((parsed.max_sub_layers_minus1 + 1) << 3) | (u8::from(parsed.temporal_id_nesting) << 2) | 3,
+    );
+    sample_entry.push(3); // numOfArrays
+    for (t, nal) in [(NAL_VPS, vps), (NAL_SPS, sps), (NAL_PPS, pps)] {
+        sample_entry.push(0x80 | t); // array_completeness = 1 (as required by hvc1)
+        sample_entry.write_u16::<BigEndian>(1)?; // numNalus
+        sample_entry.write_u16::<BigEndian>(
+            u16::try_from(nal.len()).map_err(|_| err!(OutOfRange, msg("NAL unit too long")))?,
+        )?;
+        sample_entry.extend_from_slice(nal);
+    }
+
+    // Fix up hvcC box length.
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[hvcc_len_pos..hvcc_len_pos + 4],
+        u32::try_from(cur_pos - hvcc_len_pos).map_err(|_| err!(OutOfRange))?,
+    );
+
+    // PixelAspectRatioBox, ISO/IEC 14496-12 section 12.1.4.2.
+    // The VUI isn't parsed here, so always use the default.
+    let pasp = crate::h264::default_pixel_aspect_ratio(width, height);
+    if pasp != (1, 1) {
+        sample_entry.extend_from_slice(b"\x00\x00\x00\x10pasp"); // length + box name
+        sample_entry.write_u32::<BigEndian>(pasp.0.into())?;
+        sample_entry.write_u32::<BigEndian>(pasp.1.into())?;
+    }
+
+    let cur_pos = sample_entry.len();
+    BigEndian::write_u32(
+        &mut sample_entry[hvc1_len_pos..hvc1_len_pos + 4],
+        u32::try_from(cur_pos - hvc1_len_pos).map_err(|_| err!(OutOfRange))?,
+    );
+
+    Ok(VideoSampleEntryToInsert {
+        data: sample_entry,
+        rfc6381_codec: parsed.rfc6381_codec(),
+        width,
+        height,
+        pasp_h_spacing: pasp.0,
+        pasp_v_spacing: pasp.1,
+    })
+}
+
+/// A complete access unit, as returned by [`Depacketizer::push`].
+pub struct Frame {
+    /// The RTP timestamp of the access unit.
+    pub timestamp: retina::Timestamp,
+
+    /// True iff this access unit contains an IRAP picture.
+    pub is_key: bool,
+
+    /// The NAL units of the picture, each with a four-byte length prefix.
+    pub data: Bytes,
+
+    /// Set on the first frame which followed a change in parameters.
+    pub new_video_sample_entry: Option<VideoSampleEntryToInsert>,
+}
+
+#[derive(Default)]
+struct ParameterSets {
+    vps: Option<Bytes>,
+    sps: Option<Bytes>,
+    pps: Option<Bytes>,
+}
+
+/// RFC 7798 depacketizer.
+///
+/// Accumulates the NAL units of one access unit at a time, ending each access unit on a packet
+/// with the RTP marker bit or a change in RTP timestamp.
+#[derive(Default)]
+pub struct Depacketizer {
+    /// The timestamp of the access unit in progress, if any.
+    pending_timestamp: Option<retina::Timestamp>,
+    pending_data: BytesMut,
+    pending_is_key: bool,
+    pending_corrupt: bool,
+
+    /// The NAL unit in progress from fragmentation units, if any.
+    fu: Option<BytesMut>,
+
+    /// Parameter sets seen in-band for the current access unit.
+    new_params: ParameterSets,
+
+    /// Parameter sets used for `video_sample_entry`.
+    params: ParameterSets,
+
+    video_sample_entry: Option<VideoSampleEntryToInsert>,
+}
+
+impl Depacketizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current video sample entry, once parameters have been seen.
+    pub fn video_sample_entry(&self) -> Option<&VideoSampleEntryToInsert> {
+        self.video_sample_entry.as_ref()
+    }
+
+    /// Processes a single RTP packet, returning a frame if this packet completes one.
+    ///
+    /// `loss` is the number of packets lost immediately before this one; the affected
+    /// access unit is discarded.
+    pub fn push(
+        &mut self,
+        timestamp: retina::Timestamp,
+        mark: bool,
+        loss: u16,
+        payload: &[u8],
+    ) -> Result<Option<Frame>, Error> {
+        let mut out = None;
+        if let Some(t) = self.pending_timestamp {
+            if t.timestamp() != timestamp.timestamp() {
+                // The previous access unit's final packet (with the marker bit) was lost.
+                out = self.finish_access_unit()?;
+            }
+        }
+        if loss > 0 {
+            self.pending_corrupt = true;
+            self.fu = None;
+        }
+        self.pending_timestamp = Some(timestamp);
+        if payload.len() < 3 {
+            bail!(
+                InvalidArgument,
+                msg("RTP payload too short: {payload:02x?}")
+            );
+        }
+        match nal_type(payload) {
+            PAYLOAD_AP => {
+                let mut rest = &payload[2..];
+                while !rest.is_empty() {
+                    if rest.len() < 2 {
+                        bail!(InvalidArgument, msg("truncated aggregation packet"));
+                    }
+                    let len = usize::from(BigEndian::read_u16(rest));
+                    if len < 2 || rest.len() < 2 + len {
+                        bail!(
+                            InvalidArgument,
+                            msg("bad aggregation packet NAL length {len}")
+                        );
+                    }
+                    self.add_nal(&rest[2..2 + len])?;
+                    rest = &rest[2 + len..];
+                }
+            }
+            PAYLOAD_FU => {
+                let fu_header = payload[2];
+                let start = fu_header & 0x80 != 0;
+                let end = fu_header & 0x40 != 0;
+                let fu_type = fu_header & 0x3f;
+                if start {
+                    let mut nal = BytesMut::with_capacity(payload.len() * 16);
+                    nal.put_u8((payload[0] & 0x81) | (fu_type << 1));
+                    nal.put_u8(payload[1]);
+                    nal.extend_from_slice(&payload[3..]);
+                    self.fu = Some(nal);
+                } else if let Some(nal) = self.fu.as_mut() {
+                    nal.extend_from_slice(&payload[3..]);
+                } else {
+                    // The start of this NAL unit was lost.
+                    self.pending_corrupt = true;
+                }
+                if end {
+                    if let Some(nal) = self.fu.take() {
+                        self.add_nal(&nal)?;
+                    }
+                }
+            }
+            PAYLOAD_PACI => bail!(Unimplemented, msg("H.265 PACI packets are unsupported")),
+            _ => self.add_nal(payload)?,
+        }
+        if mark {
+            if let Some(f) = self.finish_access_unit()? {
+                // If both the previous and this access unit are complete, the previous one is
+                // necessarily corrupt (missing its marked packet) and so `out` is `None`.
+                out = Some(f);
+            }
+        }
+        Ok(out)
+    }
+
+    fn add_nal(&mut self, nal: &[u8]) -> Result<(), Error> {
+        let t = nal_type(nal);
+        match t {
+            NAL_VPS => self.new_params.vps = Some(Bytes::copy_from_slice(nal)),
+            NAL_SPS => self.new_params.sps = Some(Bytes::copy_from_slice(nal)),
+            NAL_PPS => self.new_params.pps = Some(Bytes::copy_from_slice(nal)),
+            NAL_AUD => {}
+            _ => {
+                self.pending_is_key |= is_irap(t);
+                self.pending_data.put_u32(
+                    u32::try_from(nal.len())
+                        .map_err(|_| err!(OutOfRange, msg("NAL unit too long")))?,
+                );
+                self.pending_data.extend_from_slice(nal);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_access_unit(&mut self) -> Result<Option<Frame>, Error> {
+        let timestamp = self
+            .pending_timestamp
+            .take()
+            .expect("access unit in progress");
+        let data = std::mem::take(&mut self.pending_data).freeze();
+        let is_key = std::mem::replace(&mut self.pending_is_key, false);
+        let corrupt = std::mem::replace(&mut self.pending_corrupt, false);
+        self.fu = None;
+        let new_params = std::mem::take(&mut self.new_params);
+        if corrupt || data.is_empty() {
+            return Ok(None);
+        }
+        let mut changed = false;
+        for (new, old) in [
+            (new_params.vps, &mut self.params.vps),
+            (new_params.sps, &mut self.params.sps),
+            (new_params.pps, &mut self.params.pps),
+        ] {
+            if let Some(new) = new {
+                if old.as_ref() != Some(&new) {
+                    *old = Some(new);
+                    changed = true;
+                }
+            }
+        }
+        let mut new_video_sample_entry = None;
+        if changed {
+            if let (Some(vps), Some(sps), Some(pps)) =
+                (&self.params.vps, &self.params.sps, &self.params.pps)
+            {
+                let e = parse_parameter_sets(vps, sps, pps)?;
+                self.video_sample_entry = Some(e.clone());
+                new_video_sample_entry = Some(e);
+            }
+        }
+        if self.video_sample_entry.is_none() {
+            // Frames can't be used until the parameters are known.
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            timestamp,
+            is_key,
+            data,
+            new_video_sample_entry,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+    use db::testutil;
+
+    // Parameter sets for a 2688x1520 Main profile stream. The VPS and PPS are opaque to this
+    // module; the SPS is meaningful through `bit_depth_chroma_minus8`.
+    #[rustfmt::skip]
+    const VPS: [u8; 24] = [
+        0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60,
+        0x00, 0x00, 0x03, 0x00, 0xb0, 0x00, 0x00, 0x03,
+        0x00, 0x00, 0x03, 0x00, 0x99, 0xac, 0x09, 0x80,
+    ];
+
+    #[rustfmt::skip]
+    const SPS: [u8; 25] = [
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03,
+        0x00, 0xb0, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+        0x00, 0x99, 0x50, 0x00, 0xa8, 0x10, 0x02, 0xf8,
+        0x96,
+    ];
+
+    #[rustfmt::skip]
+    const PPS: [u8; 7] = [0x44, 0x01, 0xc1, 0x72, 0xb0, 0x62, 0x40];
+
+    #[test]
+    fn parse_sps() {
+        testutil::init();
+        let sps = super::Sps::parse(&SPS).unwrap();
+        assert_eq!(sps.general_profile_idc, 1);
+        assert_eq!(sps.general_level_idc, 153);
+        assert!(!sps.general_tier_flag);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.width, sps.height), (2688, 1520));
+        assert_eq!(sps.rfc6381_codec(), "hvc1.1.6.L153.B0");
+    }
+
+    #[test]
+    fn sample_entry() {
+        testutil::init();
+        let e = super::parse_parameter_sets(&VPS, &SPS, &PPS).unwrap();
+        assert_eq!(&e.data[4..8], b"hvc1");
+        assert_eq!(&e.data[90..94], b"hvcC");
+        assert_eq!(
+            usize::try_from(BigEndian::read_u32(&e.data[0..4])).unwrap(),
+            e.data.len()
+        );
+        assert_eq!((e.width, e.height), (2688, 1520));
+        assert_eq!((e.pasp_h_spacing, e.pasp_v_spacing), (1, 1));
+        assert_eq!(e.rfc6381_codec, "hvc1.1.6.L153.B0");
+    }
+
+    fn ts(t: i64) -> retina::Timestamp {
+        retina::Timestamp::new(t, std::num::NonZeroU32::new(90_000).unwrap(), 0).unwrap()
+    }
+
+    #[test]
+    fn depacketize() {
+        testutil::init();
+        let mut d = super::Depacketizer::new();
+
+        // Aggregation packet holding the parameter sets.
+        let mut ap = vec![0x60, 0x01];
+        for nal in [&VPS[..], &SPS[..], &PPS[..]] {
+            ap.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            ap.extend_from_slice(nal);
+        }
+        assert!(d.push(ts(0), false, 0, &ap).unwrap().is_none());
+
+        // IDR_W_RADL (type 19) picture in two fragmentation units.
+        let fu_start = [0x62, 0x01, 0x80 | 19, 0xaa, 0xbb];
+        let fu_end = [0x62, 0x01, 0x40 | 19, 0xcc];
+        assert!(d.push(ts(0), false, 0, &fu_start).unwrap().is_none());
+        let f = d.push(ts(0), true, 0, &fu_end).unwrap().unwrap();
+        assert!(f.is_key);
+        assert_eq!(
+            &f.data[..],
+            &[0x00, 0x00, 0x00, 0x05, 0x26, 0x01, 0xaa, 0xbb, 0xcc][..]
+        );
+        let e = f.new_video_sample_entry.unwrap();
+        assert_eq!((e.width, e.height), (2688, 1520));
+
+        // TRAIL_R (type 1) picture in a single NAL unit packet.
+        let f = d
+            .push(ts(3000), true, 0, &[0x02, 0x01, 0xdd])
+            .unwrap()
+            .unwrap();
+        assert!(!f.is_key);
+        assert!(f.new_video_sample_entry.is_none());
+        assert_eq!(&f.data[..], &[0x00, 0x00, 0x00, 0x03, 0x02, 0x01, 0xdd][..]);
+
+        // A lost packet discards the access unit.
+        assert!(d
+            .push(ts(6000), true, 1, &[0x02, 0x01, 0xee])
+            .unwrap()
+            .is_none());
+    }
+}
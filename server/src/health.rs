@@ -0,0 +1,302 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Camera health history, for `/api/cameras/<uuid>/health`.
+//!
+//! Every [`SAMPLE_INTERVAL`], [`run`] samples each started stream's [`StreamCounters`] and
+//! status into its [`State`]: a rolling window of the last [`WINDOW_SEC`] seconds, and totals by
+//! day which haven't yet been saved. It saves those to the database's `stream_health` table
+//! every [`SAVE_INTERVAL`] and on shutdown, and deletes days older than [`HISTORY_DAYS`].
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::ErrorKind;
+use db::days;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use tracing::{info, warn};
+
+use crate::metrics::{Metrics, StreamCounters};
+
+const SAMPLE_SEC: i64 = 10;
+
+/// How often to sample each stream.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(SAMPLE_SEC as u64);
+
+/// The number of samples in the rolling window.
+const WINDOW_SAMPLES: usize = 360;
+
+/// The length of the rolling window.
+pub const WINDOW_SEC: i64 = SAMPLE_SEC * WINDOW_SAMPLES as i64;
+
+/// How often to save daily totals to the database.
+const SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How many days of history to keep.
+const HISTORY_DAYS: i64 = 90;
+
+/// Counter values as of the previous sample.
+#[derive(Default)]
+struct Counts {
+    reconnects: u64,
+    frame_gaps: u64,
+    frames: u64,
+    packets_lost: u64,
+}
+
+impl Counts {
+    fn load(c: &StreamCounters) -> Self {
+        Counts {
+            reconnects: c.reconnects.load(Ordering::Relaxed),
+            frame_gaps: c.frame_gaps.load(Ordering::Relaxed),
+            frames: c.frames_received.load(Ordering::Relaxed),
+            packets_lost: c.packets_lost.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A stream's recent and not-yet-saved health totals.
+#[derive(Default)]
+pub struct State {
+    /// The most recent samples, oldest first.
+    window: VecDeque<db::StreamHealth>,
+
+    /// Totals by day which haven't been saved to the database.
+    unsaved: BTreeMap<days::Key, db::StreamHealth>,
+
+    prev: Counts,
+}
+
+impl State {
+    /// Returns the totals over the rolling window.
+    pub fn window(&self) -> db::StreamHealth {
+        let mut total = db::StreamHealth::default();
+        for s in &self.window {
+            total.add(s);
+        }
+        total
+    }
+
+    /// Returns the totals by day which haven't yet been saved to the database.
+    pub fn unsaved(&self) -> &BTreeMap<days::Key, db::StreamHealth> {
+        &self.unsaved
+    }
+
+    /// Adds a sample, given the current counters and whether the stream is running and up.
+    fn push(&mut self, day: days::Key, cur: Counts, running: bool, up: bool) {
+        let delta = |cur: u64, prev: u64| cur.saturating_sub(prev) as i64;
+        let sample = db::StreamHealth {
+            monitored_sec: if running { SAMPLE_SEC } else { 0 },
+            up_sec: if running && up { SAMPLE_SEC } else { 0 },
+            reconnects: delta(cur.reconnects, self.prev.reconnects),
+            frame_gaps: delta(cur.frame_gaps, self.prev.frame_gaps),
+            frames: delta(cur.frames, self.prev.frames),
+            packets_lost: delta(cur.packets_lost, self.prev.packets_lost),
+        };
+        self.prev = cur;
+        if self.window.len() == WINDOW_SAMPLES {
+            self.window.pop_front();
+        }
+        self.window.push_back(sample);
+        if sample != db::StreamHealth::default() {
+            self.unsaved.entry(day).or_default().add(&sample);
+        }
+    }
+}
+
+/// Returns a score from 0 (worst) to 100 (best), or `None` if the stream wasn't monitored.
+///
+/// The score starts from the percentage of monitored time the stream was up, less penalties
+/// (each capped) for:
+///
+/// *   reconnects: 10 per hour, up to 30.
+/// *   frame gaps: 2 per hour, up to 20.
+/// *   packet loss: 1 per lost packet per hundred frames, up to 20.
+pub fn score(h: &db::StreamHealth) -> Option<u8> {
+    if h.monitored_sec <= 0 {
+        return None;
+    }
+    let monitored = h.monitored_sec as f64;
+    let hours = monitored / 3600.;
+    let uptime = 100. * h.up_sec as f64 / monitored;
+    let reconnects = (10. * h.reconnects as f64 / hours).min(30.);
+    let gaps = (2. * h.frame_gaps as f64 / hours).min(20.);
+    let loss = if h.frames > 0 {
+        (100. * h.packets_lost as f64 / h.frames as f64).min(20.)
+    } else if h.packets_lost > 0 {
+        20.
+    } else {
+        0.
+    };
+    Some((uptime - reconnects - gaps - loss).clamp(0., 100.).round() as u8)
+}
+
+/// Samples every started stream.
+fn sample(metrics: &Metrics, now: recording::Time) {
+    let day = match days::Key::containing(now) {
+        Ok(d) => d,
+        Err(err) => {
+            warn!(%err, "unable to find current day; skipping health sample");
+            return;
+        }
+    };
+    for (_, c) in metrics.streams() {
+        let status = c.status.lock().unwrap().clone();
+        let cur = Counts::load(&c);
+        c.health
+            .lock()
+            .unwrap()
+            .push(day, cur, status.running, !status.is_down(now));
+    }
+}
+
+/// Saves every stream's unsaved daily totals and deletes expired history.
+fn save<C: Clocks + Clone>(db: &db::Database<C>, metrics: &Metrics, now: recording::Time) {
+    let mut l = db.lock();
+    for (stream_id, c) in metrics.streams() {
+        let unsaved = std::mem::take(&mut c.health.lock().unwrap().unsaved);
+        for (day, h) in unsaved {
+            match l.add_stream_health(stream_id, day, &h) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {} // stream has been deleted.
+                Err(err) => {
+                    warn!(%err, "unable to save health of stream {stream_id}; will retry");
+                    c.health
+                        .lock()
+                        .unwrap()
+                        .unsaved
+                        .entry(day)
+                        .or_default()
+                        .add(&h);
+                }
+            }
+        }
+    }
+    let expiry = now - recording::Duration(HISTORY_DAYS * 24 * 60 * 60 * TIME_UNITS_PER_SEC);
+    let result = days::Key::containing(expiry).and_then(|d| l.delete_stream_health_before(d));
+    if let Err(err) = result {
+        warn!(%err, "unable to delete expired stream health history");
+    }
+}
+
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    metrics: Arc<Metrics>,
+    shutdown_rx: base::shutdown::Receiver,
+) {
+    // Each sample should cover a full interval, so skip the immediate first tick.
+    let start = tokio::time::Instant::now();
+    let mut sample_interval = tokio::time::interval_at(start + SAMPLE_INTERVAL, SAMPLE_INTERVAL);
+    sample_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut save_interval = tokio::time::interval_at(start + SAVE_INTERVAL, SAVE_INTERVAL);
+    save_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.as_future() => break,
+            _ = sample_interval.tick() => {
+                sample(&metrics, recording::Time::new(db.clocks().realtime()));
+            },
+            _ = save_interval.tick() => {
+                save(&db, &metrics, recording::Time::new(db.clocks().realtime()));
+            },
+        }
+    }
+    info!("saving stream health history");
+    save(&db, &metrics, recording::Time::new(db.clocks().realtime()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score() {
+        let hour = db::StreamHealth {
+            monitored_sec: 3600,
+            up_sec: 3600,
+            frames: 36_000,
+            ..Default::default()
+        };
+        assert_eq!(super::score(&db::StreamHealth::default()), None);
+        assert_eq!(super::score(&hour), Some(100));
+        assert_eq!(
+            super::score(&db::StreamHealth {
+                up_sec: 3240,
+                ..hour
+            }),
+            Some(90)
+        );
+        assert_eq!(
+            super::score(&db::StreamHealth {
+                reconnects: 1,
+                frame_gaps: 2,
+                packets_lost: 360,
+                ..hour
+            }),
+            Some(85)
+        );
+
+        // Each penalty is capped.
+        assert_eq!(
+            super::score(&db::StreamHealth {
+                reconnects: 100,
+                frame_gaps: 100,
+                packets_lost: 100_000,
+                ..hour
+            }),
+            Some(30)
+        );
+        assert_eq!(
+            super::score(&db::StreamHealth { up_sec: 0, ..hour }),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn state() {
+        let day1 = days::Key::containing(recording::Time(0)).unwrap();
+        let day2 =
+            days::Key::containing(recording::Time(2 * 24 * 60 * 60 * TIME_UNITS_PER_SEC)).unwrap();
+        let counts = |frames, reconnects| Counts {
+            frames,
+            reconnects,
+            ..Default::default()
+        };
+        let mut s = State::default();
+        s.push(day1, counts(100, 0), true, true);
+        s.push(day1, counts(150, 1), true, false);
+        s.push(day2, counts(150, 1), false, false);
+        assert_eq!(
+            s.window(),
+            db::StreamHealth {
+                monitored_sec: 2 * SAMPLE_SEC,
+                up_sec: SAMPLE_SEC,
+                reconnects: 1,
+                frames: 150,
+                ..Default::default()
+            }
+        );
+
+        // Samples with nothing to report don't create days.
+        assert_eq!(s.unsaved().keys().collect::<Vec<_>>(), [&day1]);
+        assert_eq!(s.unsaved()[&day1], s.window());
+
+        // The window is limited to the most recent samples.
+        for _ in 0..WINDOW_SAMPLES {
+            s.push(day2, counts(150, 1), true, true);
+        }
+        assert_eq!(
+            s.window(),
+            db::StreamHealth {
+                monitored_sec: WINDOW_SEC,
+                up_sec: WINDOW_SEC,
+                ..Default::default()
+            }
+        );
+        assert_eq!(s.unsaved()[&day2].monitored_sec, WINDOW_SEC);
+    }
+}
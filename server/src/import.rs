@@ -0,0 +1,231 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Importing existing `.mp4` files as recordings, for migrating footage from another NVR.
+//!
+//! The file's H.264 track is split at key frames into recordings of about
+//! [`crate::streamer::ROTATE_INTERVAL_SEC`], as the streamer would have written them. Each frame
+//! is timestamped by the file's creation time (or a supplied start time) plus its decode time.
+//! The syncer for the stream's sample file directory then commits the recordings as usual.
+//!
+//! Imported recordings are subject to the stream's retention limits like any other. As
+//! retention deletes recordings in the order they were added, footage imported into a stream
+//! which is already recording will outlive the (newer) footage recorded before the import.
+
+use std::io::{Read, Seek};
+use std::ops::Range;
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use db::{dir, writer};
+use tracing::info;
+
+use crate::streamer::ROTATE_INTERVAL_SEC;
+
+/// Seconds from the `.mp4` epoch (1904-01-01 00:00:00 UTC) to the Unix epoch.
+const MP4_EPOCH_OFFSET_SEC: i64 = 2_082_844_800;
+
+/// The outcome of a successful import.
+#[derive(Debug)]
+pub struct Summary {
+    pub recordings: usize,
+    pub frames: usize,
+    pub time: Range<recording::Time>,
+}
+
+/// Imports the H.264 track of an `.mp4` file of `size` bytes into the given stream.
+///
+/// `start` is the time of the file's first frame; if absent, it's taken from the file's creation
+/// time. Fails without writing anything if the file would overlap existing recordings.
+#[allow(clippy::too_many_arguments)]
+pub fn import<R: Read + Seek>(
+    db: &db::Database,
+    dir: &Arc<dir::SampleFileDir>,
+    channel: &writer::SyncerChannel<dir::SampleFile>,
+    shutdown_rx: &mut base::shutdown::Receiver,
+    stream_id: i32,
+    mp4: R,
+    size: u64,
+    start: Option<recording::Time>,
+) -> Result<Summary, Error> {
+    let reader = mp4::Mp4Reader::read_header(mp4, size)
+        .map_err(|e| err!(InvalidArgument, msg("unable to parse .mp4 file"), source(e)))?;
+    let track = reader
+        .tracks()
+        .values()
+        .find(|t| matches!(t.media_type(), Ok(mp4::MediaType::H264)))
+        .ok_or_else(|| err!(InvalidArgument, msg("expected a H.264 track")))?;
+    let video_sample_entry = crate::h264::parse_extra_data(
+        &track
+            .extra_data()
+            .map_err(|e| err!(InvalidArgument, source(e)))?[..],
+    )?;
+    if let Some(ref ctts) = track.trak.mdia.minf.stbl.ctts {
+        if ctts
+            .entries
+            .windows(2)
+            .any(|w| w[0].sample_offset != w[1].sample_offset)
+        {
+            bail!(
+                Unimplemented,
+                msg("H.264 track has B-frames, which Moonfire NVR can't store")
+            );
+        }
+    }
+    let track_id = track.track_id();
+    let samples = track.sample_count();
+    let timescale = i64::from(track.timescale());
+    if timescale == 0 || samples == 0 {
+        bail!(InvalidArgument, msg("H.264 track is empty"));
+    }
+    let to_90k = |t: u64| -> Result<i64, Error> {
+        i64::try_from(i128::from(t) * i128::from(TIME_UNITS_PER_SEC) / i128::from(timescale))
+            .map_err(|_| err!(OutOfRange, msg("timestamp {t} out of range")))
+    };
+    let start = match start {
+        Some(s) => s,
+        None => {
+            let created = reader.moov.mvhd.creation_time;
+            if created == 0 {
+                bail!(
+                    InvalidArgument,
+                    msg("file has no creation time; supply a start time")
+                );
+            }
+            recording::Time((created as i64 - MP4_EPOCH_OFFSET_SEC) * TIME_UNITS_PER_SEC)
+        }
+    };
+    let end = start + recording::Duration(to_90k(track.trak.mdia.mdhd.duration)?);
+
+    let video_sample_entry_id = {
+        let mut l = db.lock();
+        let mut overlapping = 0;
+        l.list_recordings_by_time(stream_id, start..end, &mut |_| {
+            overlapping += 1;
+            Ok(())
+        })?;
+        if overlapping > 0 {
+            bail!(
+                FailedPrecondition,
+                msg("{start}..{end} overlaps {overlapping} existing recordings")
+            );
+        }
+        l.insert_video_sample_entry(video_sample_entry)?
+    };
+
+    let mut w = writer::Writer::new(dir, db, channel, stream_id);
+    let mut recordings = 0;
+    let mut frames = 0;
+    let mut origin = None; // decode time of the file's first frame.
+    let mut first_pts = None; // decode time of the first frame written.
+    let mut recording_start = 0;
+    let mut next_pts = 0;
+    for sample_id in 1..=samples {
+        shutdown_rx
+            .check()
+            .map_err(|e| err!(Cancelled, source(e)))?;
+        let sample = reader
+            .read_sample(track_id, sample_id)
+            .map_err(|e| {
+                err!(
+                    InvalidArgument,
+                    msg("unable to read sample {sample_id}"),
+                    source(e)
+                )
+            })?
+            .ok_or_else(|| err!(InvalidArgument, msg("missing sample {sample_id}")))?;
+        let pts = to_90k(sample.start_time)?;
+        let file_start = *origin.get_or_insert(pts);
+        if first_pts.is_none() {
+            if !sample.is_sync {
+                continue; // a recording can't start mid-GOP.
+            }
+            first_pts = Some(pts);
+            recording_start = pts;
+        } else if sample.is_sync
+            && pts - recording_start >= ROTATE_INTERVAL_SEC * TIME_UNITS_PER_SEC
+        {
+            w.close(Some(pts), None)?;
+            recordings += 1;
+            recording_start = pts;
+        }
+        w.write(
+            shutdown_rx,
+            &sample.bytes,
+            start + recording::Duration(pts - file_start),
+            pts,
+            sample.is_sync,
+            video_sample_entry_id,
+        )?;
+        frames += 1;
+        next_pts = pts + to_90k(u64::from(sample.duration))?;
+    }
+    let (Some(origin), Some(first_pts)) = (origin, first_pts) else {
+        bail!(InvalidArgument, msg("H.264 track has no key frames"));
+    };
+    w.close(Some(next_pts), None)?;
+    recordings += 1;
+    let time = start + recording::Duration(first_pts - origin)
+        ..start + recording::Duration(next_pts - origin);
+    info!(
+        "imported {frames} frames as {recordings} recordings from {} to {}",
+        time.start, time.end
+    );
+    Ok(Summary {
+        recordings,
+        frames,
+        time,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use base::Error;
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil::{self, TestDb, TEST_DIR_ID, TEST_STREAM_ID};
+
+    fn import_clip(
+        db: &TestDb<RealClocks>,
+        start: recording::Time,
+    ) -> Result<super::Summary, Error> {
+        let f = std::fs::File::open("src/testdata/clip.mp4").unwrap();
+        let size = f.metadata().unwrap().len();
+        let mut shutdown_rx = db.shutdown_rx.clone();
+        super::import(
+            &db.db,
+            &db.dirs_by_id[&TEST_DIR_ID],
+            &db.syncer_channel,
+            &mut shutdown_rx,
+            TEST_STREAM_ID,
+            f,
+            size,
+            Some(start),
+        )
+    }
+
+    #[test]
+    fn import() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let start = recording::Time(1_000_000 * TIME_UNITS_PER_SEC);
+        let summary = import_clip(&db, start).unwrap();
+        assert_eq!(summary.recordings, 1);
+        assert_eq!(summary.time.start, start);
+        db.syncer_channel.flush();
+        let mut rows = Vec::new();
+        db.db
+            .lock()
+            .list_recordings_by_time(TEST_STREAM_ID, summary.time.clone(), &mut |r| {
+                rows.push((r.start, r.video_samples));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(rows, vec![(start, summary.frames as i32)]);
+
+        // Importing the same footage again would overlap.
+        import_clip(&db, start).unwrap_err();
+    }
+}
@@ -0,0 +1,317 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Decoding of key frames to JPEG images, for snapshots and thumbnails.
+//!
+//! Decoding and encoding use FFmpeg's libavcodec and are available only when built with the
+//! `ffmpeg` feature.
+
+use base::{bail, Error};
+
+/// Whether this build can decode and encode images. If not, the functions below return
+/// `Unimplemented` errors.
+pub const AVAILABLE: bool = cfg!(feature = "ffmpeg");
+
+/// The width of thumbnails produced by [`encode_thumbnail`].
+const THUMBNAIL_WIDTH: u16 = 160;
+
+/// A JPEG-encoded thumbnail.
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+    pub data: Vec<u8>,
+}
+
+/// Returns the size of a thumbnail of the given video: [`THUMBNAIL_WIDTH`] wide, with the
+/// height chosen to preserve the display aspect ratio. Both are even, as 4:2:0 chroma
+/// subsampling requires.
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+fn thumbnail_size(entry: &db::VideoSampleEntry) -> (u16, u16) {
+    let aspect = entry.aspect();
+    let height = u32::from(THUMBNAIL_WIDTH) * aspect.denom() / aspect.numer();
+    let height = (height.clamp(2, u32::from(u16::MAX)) as u16) & !1;
+    (THUMBNAIL_WIDTH, height)
+}
+
+/// Decodes the given key frame (in the length-prefixed form stored in sample files) and encodes
+/// it as a JPEG image. Frames of `jpeg` sample entries (see [`crate::mjpeg`]) are returned as-is.
+///
+/// This is CPU-intensive; async callers should use `tokio::task::spawn_blocking`.
+#[cfg(feature = "ffmpeg")]
+pub fn encode_key_frame(entry: &db::VideoSampleEntry, frame: &[u8]) -> Result<Vec<u8>, Error> {
+    if entry.box_type() == b"jpeg" {
+        return Ok(frame.to_vec());
+    }
+    let decoded = ffmpeg::decode_key_frame(entry, frame)?;
+    let yuv = ffmpeg::scale(&decoded, decoded.width(), decoded.height())?;
+    ffmpeg::encode(&yuv)
+}
+
+/// Like [`encode_key_frame`], but scales the image down to a thumbnail.
+#[cfg(feature = "ffmpeg")]
+pub fn encode_thumbnail(entry: &db::VideoSampleEntry, frame: &[u8]) -> Result<Thumbnail, Error> {
+    let (width, height) = thumbnail_size(entry);
+    let decoded = ffmpeg::decode_key_frame(entry, frame)?;
+    let yuv = ffmpeg::scale(&decoded, u32::from(width), u32::from(height))?;
+    Ok(Thumbnail {
+        width,
+        height,
+        data: ffmpeg::encode(&yuv)?,
+    })
+}
+
+/// Combines the given JPEG images into a single sprite sheet JPEG image, with `columns` tiles
+/// per row in row-major order. Each image is scaled to the given tile size.
+#[cfg(feature = "ffmpeg")]
+pub fn encode_sprite(
+    tiles: &[&[u8]],
+    columns: usize,
+    tile_width: u16,
+    tile_height: u16,
+) -> Result<Vec<u8>, Error> {
+    use ffmpeg_next::{format::Pixel, util::frame::video::Video};
+    if tiles.is_empty() || columns == 0 || tile_width % 2 != 0 || tile_height % 2 != 0 {
+        bail!(InvalidArgument, msg("invalid sprite layout"));
+    }
+    let rows = (tiles.len() + columns - 1) / columns;
+    let (tile_width, tile_height) = (usize::from(tile_width), usize::from(tile_height));
+    let mut sheet = Video::new(
+        Pixel::YUVJ420P,
+        u32::try_from(columns * tile_width).unwrap(),
+        u32::try_from(rows * tile_height).unwrap(),
+    );
+
+    // Start with black: full-range luma 0, neutral chroma.
+    for (plane, fill) in [(0, 0u8), (1, 128), (2, 128)] {
+        sheet.data_mut(plane).fill(fill);
+    }
+
+    for (i, tile) in tiles.iter().enumerate() {
+        let decoded = ffmpeg::decode_jpeg(tile)?;
+        let yuv = ffmpeg::scale(&decoded, tile_width as u32, tile_height as u32)?;
+        let (x, y) = ((i % columns) * tile_width, (i / columns) * tile_height);
+        for plane in 0..3 {
+            let shift = if plane == 0 { 0 } else { 1 };
+            let (x, y, w) = (x >> shift, y >> shift, tile_width >> shift);
+            let (src_stride, dst_stride) = (yuv.stride(plane), sheet.stride(plane));
+            for row in 0..tile_height >> shift {
+                let src = &yuv.data(plane)[row * src_stride..][..w];
+                sheet.data_mut(plane)[(y + row) * dst_stride + x..][..w].copy_from_slice(src);
+            }
+        }
+    }
+    ffmpeg::encode(&sheet)
+}
+
+#[cfg(feature = "ffmpeg")]
+pub(crate) mod ffmpeg {
+    use base::{bail, err, Error};
+    use ffmpeg_next::util::frame::video::Video;
+    use ffmpeg_next::{codec, decoder, encoder, format::Pixel, software::scaling, Packet};
+
+    pub(crate) fn init() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| ffmpeg_next::init().expect("ffmpeg should initialize"));
+    }
+
+    /// Decodes a single packet with the given decoder.
+    fn decode(mut decoder: decoder::Video, packet: &[u8]) -> Result<Video, Error> {
+        decoder
+            .send_packet(&Packet::copy(packet))
+            .and_then(|()| decoder.send_eof())
+            .map_err(|e| err!(InvalidArgument, msg("unable to decode frame"), source(e)))?;
+        let mut decoded = Video::empty();
+        decoder
+            .receive_frame(&mut decoded)
+            .map_err(|e| err!(InvalidArgument, msg("frame didn't decode"), source(e)))?;
+        Ok(decoded)
+    }
+
+    pub(crate) fn decode_key_frame(
+        entry: &db::VideoSampleEntry,
+        frame: &[u8],
+    ) -> Result<Video, Error> {
+        decode(open_decoder(entry)?, frame)
+    }
+
+    /// Opens a decoder for frames (in the length-prefixed form stored in sample files) of the
+    /// given video sample entry.
+    pub(crate) fn open_decoder(entry: &db::VideoSampleEntry) -> Result<decoder::Video, Error> {
+        init();
+        let codec_id = match entry.box_type() {
+            b"hvc1" => codec::Id::HEVC,
+            b"jpeg" => {
+                // Each frame is a self-contained JPEG image; there's no configuration record.
+                let codec = decoder::find(codec::Id::MJPEG)
+                    .ok_or_else(|| err!(Unimplemented, msg("FFmpeg has no JPEG decoder")))?;
+                return codec::context::Context::new_with_codec(codec)
+                    .decoder()
+                    .video()
+                    .map_err(|e| err!(Unknown, msg("unable to open decoder"), source(e)));
+            }
+            _ => codec::Id::H264,
+        };
+        let config = entry.decoder_config()?;
+        let codec = decoder::find(codec_id)
+            .ok_or_else(|| err!(Unimplemented, msg("FFmpeg has no {codec_id:?} decoder")))?;
+        let mut ctx = codec::context::Context::new_with_codec(codec);
+
+        // SAFETY: `extradata` must be allocated with `av_malloc` and padded; libavcodec frees it
+        // along with the context.
+        unsafe {
+            let padding = ffmpeg_next::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+            let extradata = ffmpeg_next::ffi::av_mallocz(config.len() + padding) as *mut u8;
+            if extradata.is_null() {
+                bail!(ResourceExhausted, msg("unable to allocate extradata"));
+            }
+            std::ptr::copy_nonoverlapping(config.as_ptr(), extradata, config.len());
+            let p = ctx.as_mut_ptr();
+            (*p).extradata = extradata;
+            (*p).extradata_size = config.len() as i32;
+        }
+
+        ctx.decoder()
+            .video()
+            .map_err(|e| err!(Unknown, msg("unable to open decoder"), source(e)))
+    }
+
+    pub(crate) fn decode_jpeg(data: &[u8]) -> Result<Video, Error> {
+        init();
+        let codec = decoder::find(codec::Id::MJPEG)
+            .ok_or_else(|| err!(Unimplemented, msg("FFmpeg has no JPEG decoder")))?;
+        let decoder = codec::context::Context::new_with_codec(codec)
+            .decoder()
+            .video()
+            .map_err(|e| err!(Unknown, msg("unable to open decoder"), source(e)))?;
+        decode(decoder, data)
+    }
+
+    /// Scales and converts the given frame to full-range YUV 4:2:0, as the MJPEG encoder wants.
+    pub(crate) fn scale(frame: &Video, width: u32, height: u32) -> Result<Video, Error> {
+        scale_to(frame, Pixel::YUVJ420P, width, height)
+    }
+
+    /// Scales and converts the given frame to the given pixel format.
+    pub(crate) fn scale_to(
+        frame: &Video,
+        format: Pixel,
+        width: u32,
+        height: u32,
+    ) -> Result<Video, Error> {
+        let mut out = Video::empty();
+        scaling::Context::get(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            format,
+            width,
+            height,
+            scaling::Flags::BILINEAR,
+        )
+        .and_then(|mut s| s.run(frame, &mut out))
+        .map_err(|e| err!(Unknown, msg("unable to convert frame"), source(e)))?;
+        Ok(out)
+    }
+
+    pub(super) fn encode(frame: &Video) -> Result<Vec<u8>, Error> {
+        init();
+        let jpeg = encoder::find(codec::Id::MJPEG)
+            .ok_or_else(|| err!(Unimplemented, msg("FFmpeg has no JPEG encoder")))?;
+        let mut encoder = codec::context::Context::new_with_codec(jpeg)
+            .encoder()
+            .video()
+            .map_err(|e| err!(Unknown, msg("unable to create encoder"), source(e)))?;
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_format(Pixel::YUVJ420P);
+        encoder.set_time_base((1, 1));
+        let mut encoder = encoder
+            .open()
+            .map_err(|e| err!(Unknown, msg("unable to open encoder"), source(e)))?;
+        encoder
+            .send_frame(frame)
+            .and_then(|()| encoder.send_eof())
+            .map_err(|e| err!(Unknown, msg("unable to encode frame"), source(e)))?;
+        let mut packet = Packet::empty();
+        encoder
+            .receive_packet(&mut packet)
+            .map_err(|e| err!(Unknown, msg("unable to encode frame"), source(e)))?;
+        Ok(packet.data().unwrap_or_default().to_vec())
+    }
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+const UNAVAILABLE: &str = "snapshots require building Moonfire NVR with --features=ffmpeg";
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn encode_key_frame(entry: &db::VideoSampleEntry, frame: &[u8]) -> Result<Vec<u8>, Error> {
+    if entry.box_type() == b"jpeg" {
+        return Ok(frame.to_vec());
+    }
+    bail!(Unimplemented, msg("{UNAVAILABLE}"));
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn encode_thumbnail(_entry: &db::VideoSampleEntry, _frame: &[u8]) -> Result<Thumbnail, Error> {
+    bail!(Unimplemented, msg("{UNAVAILABLE}"));
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn encode_sprite(
+    _tiles: &[&[u8]],
+    _columns: usize,
+    _tile_width: u16,
+    _tile_height: u16,
+) -> Result<Vec<u8>, Error> {
+    bail!(Unimplemented, msg("{UNAVAILABLE}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::h264::AVC_DECODER_CONFIG_TEST_INPUT;
+    use db::testutil;
+
+    fn test_entry() -> db::VideoSampleEntry {
+        let e = crate::h264::parse_extra_data(&AVC_DECODER_CONFIG_TEST_INPUT).unwrap();
+        db::VideoSampleEntry {
+            id: 1,
+            data: e.data,
+            rfc6381_codec: e.rfc6381_codec,
+            width: e.width,
+            height: e.height,
+            pasp_h_spacing: e.pasp_h_spacing,
+            pasp_v_spacing: e.pasp_v_spacing,
+        }
+    }
+
+    #[test]
+    fn decoder_config() {
+        testutil::init();
+        let mut e = test_entry();
+        assert_eq!(
+            e.decoder_config().unwrap(),
+            &AVC_DECODER_CONFIG_TEST_INPUT[..]
+        );
+        e.data[4..8].copy_from_slice(b"mp4v");
+        assert_eq!(
+            e.decoder_config().unwrap_err().kind(),
+            base::ErrorKind::Unimplemented
+        );
+    }
+
+    #[test]
+    fn thumbnail_size() {
+        testutil::init();
+        let mut e = test_entry();
+        assert_eq!((e.width, e.height), (1280, 720));
+        assert_eq!(super::thumbnail_size(&e), (160, 90));
+        e.height = 960;
+        assert_eq!(super::thumbnail_size(&e), (160, 120));
+        e.pasp_h_spacing = 3; // 3840:960 is 4:1.
+        assert_eq!(super::thumbnail_size(&e), (160, 40));
+        e.pasp_h_spacing = 1;
+        e.height = 1000; // 1280:1000 would be 125 pixels high; round down to even.
+        assert_eq!(super::thumbnail_size(&e), (160, 124));
+    }
+}
@@ -7,6 +7,7 @@
 use base::time::{Duration, Time};
 use base::{err, Error};
 use db::auth::SessionHash;
+use schemars::JsonSchema;
 use serde::ser::{Error as _, SerializeMap, SerializeSeq, Serializer};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::ops::Not;
@@ -21,8 +22,16 @@ pub struct TopLevel<'a> {
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
     // "days" and "camera_configs" attributes or not, according to the respective bools.
+    // Cameras the permissions don't allow are omitted. The time is the current time, for
+    // computing flush lag.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool, bool),
+    pub cameras: (
+        &'a db::LockedDatabase,
+        bool,
+        bool,
+        &'a db::Permissions,
+        Time,
+    ),
 
     pub permissions: Permissions,
 
@@ -34,6 +43,31 @@ pub struct TopLevel<'a> {
 
     #[serde(serialize_with = "TopLevel::serialize_signal_types")]
     pub signal_types: &'a db::LockedDatabase,
+
+    /// The start time of the newest scheduled database backup, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_backup_time_90k: Option<Time>,
+
+    /// The most recent successful scheduled database maintenance, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_maintenance: Option<Maintenance>,
+}
+
+/// A completed run of scheduled database maintenance, as in [`TopLevel::last_maintenance`].
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Maintenance {
+    pub start_time_90k: Time,
+    pub duration_90k: Duration,
+
+    /// False if the database doesn't use incremental auto-vacuum, so no pages were reclaimed.
+    pub incremental_vacuum: bool,
+
+    /// The bytes returned to the filesystem from the database's freelist.
+    pub freed_bytes: i64,
+
+    /// The bytes remaining on the freelist, as when the window ended before vacuuming finished.
+    pub free_bytes: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -75,12 +109,20 @@ pub struct Camera<'a> {
 pub struct Stream<'a> {
     pub id: i32,
     pub retain_bytes: i64,
+    pub retain_days: u32,
+    pub retain_event_days: u32,
     pub min_start_time_90k: Option<Time>,
     pub max_end_time_90k: Option<Time>,
     pub total_duration_90k: Duration,
     pub total_sample_file_bytes: i64,
     pub fs_bytes: i64,
+    pub archived_sample_file_bytes: i64,
+    pub archived_fs_bytes: i64,
     pub record: bool,
+    pub flush_if_sec: u32,
+
+    /// How long the oldest recording written to disk has waited to be committed to the database.
+    pub flush_lag_90k: Duration,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
@@ -105,21 +147,21 @@ pub struct Signal<'a> {
     pub days: Option<&'a db::days::Map<db::days::SignalValue>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(tag = "base", content = "rel90k", rename_all = "camelCase")]
 pub enum PostSignalsTimeBase {
     Epoch(Time),
     Now(Duration),
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct LoginRequest<'a> {
     pub username: &'a str,
     pub password: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct LogoutRequest<'a> {
     #[serde(borrow)]
@@ -128,6 +170,13 @@ pub struct LogoutRequest<'a> {
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct PostReloadRequest<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct PostSignalsRequest<'a> {
     #[serde(borrow)]
     pub csrf: Option<&'a str>,
@@ -137,12 +186,364 @@ pub struct PostSignalsRequest<'a> {
     pub end: PostSignalsTimeBase,
 }
 
-#[derive(Serialize)]
+#[derive(JsonSchema, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostSignalsResponse {
     pub time_90k: Time,
 }
 
+/// Request for `POST /api/signals/<id>/events`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostSignalEvents<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    /// State changes, in time order. Each state holds until the next change.
+    pub events: Vec<PostSignalEvent>,
+
+    /// How long the last state holds after its change, in seconds, before lapsing to unknown.
+    #[serde(default)]
+    pub hold_sec: Option<u32>,
+}
+
+/// An event in [`PostSignalEvents::events`].
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostSignalEvent {
+    /// The time of the change as reported by the client, or if absent, when the request was
+    /// received.
+    #[serde(default)]
+    pub time_90k: Option<Time>,
+
+    pub state: u16,
+}
+
+/// Response to `GET /api/signals/types`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignalTypes<'a> {
+    pub signal_types: Vec<SignalType<'a>>,
+}
+
+/// Request for `PUT /api/signals/types/<uuid>`, which replaces the type's configuration.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PutSignalType<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    #[serde(default)]
+    pub name: String,
+
+    #[serde(default)]
+    pub color: String,
+
+    #[serde(default)]
+    pub states: Vec<PutSignalTypeState>,
+}
+
+/// A state in [`PutSignalType::states`].
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PutSignalTypeState {
+    pub value: u8,
+    pub name: String,
+
+    #[serde(default)]
+    pub motion: bool,
+
+    #[serde(default)]
+    pub color: String,
+}
+
+/// Request for `DELETE /api/signals/types/<uuid>`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteSignalType<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PostPtzRequest<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    #[serde(flatten)]
+    pub op: PtzOp,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum PtzOp {
+    #[serde(rename_all = "camelCase")]
+    Move {
+        #[serde(default)]
+        pan: f32,
+        #[serde(default)]
+        tilt: f32,
+        #[serde(default)]
+        zoom: f32,
+        timeout_ms: Option<u32>,
+    },
+    Stop,
+    GotoPreset {
+        preset: String,
+    },
+}
+
+/// A text message from a client of the `/api/live.m4s` WebSocket.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum LiveRequest {
+    /// Starts sending the given stream's video, optionally only its key frames or with audio.
+    #[serde(rename_all = "camelCase")]
+    Subscribe {
+        camera_uuid: Uuid,
+        stream: String,
+
+        #[serde(default)]
+        keyframes_only: bool,
+
+        #[serde(default)]
+        audio: bool,
+    },
+
+    /// Stops sending the given stream's video.
+    #[serde(rename_all = "camelCase")]
+    Unsubscribe { camera_uuid: Uuid, stream: String },
+}
+
+/// Response to `GET /api/cameras/<uuid>/ptz`.
+#[derive(JsonSchema, Serialize)]
+pub struct PtzPresets {
+    pub presets: Vec<PtzPreset>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub struct PtzPreset {
+    pub token: String,
+    pub name: String,
+}
+
+/// Response to `GET /api/cameras/<uuid>/<type>/schedule`.
+#[derive(Serialize)]
+pub struct StreamSchedule<'a> {
+    pub schedule: Option<&'a db::json::ScheduleConfig>,
+}
+
+/// Response to `POST /api/cameras/<uuid>/<type>/import`.
+#[derive(JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportResponse {
+    pub recordings: usize,
+    pub frames: usize,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+}
+
+/// Request for `PUT /api/cameras/<uuid>/<type>/schedule`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PutStreamSchedule<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    pub schedule: db::json::ScheduleConfig,
+}
+
+/// Request for `DELETE /api/cameras/<uuid>/<type>/schedule`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteStreamSchedule<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Request for `POST /api/cameras/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostCameras<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub camera: CameraSubset,
+}
+
+/// Response to `POST /api/cameras/`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostCamerasResponse {
+    pub id: i32,
+    pub uuid: Uuid,
+}
+
+/// Request for `PATCH /api/cameras/<uuid>/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PatchCamera<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub update: CameraSubset,
+}
+
+/// Request for `DELETE /api/cameras/<uuid>/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteCamera<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Fields of a camera to set on creation or update. Absent fields are left alone.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct CameraSubset {
+    pub short_name: Option<String>,
+
+    /// A full replacement for the camera's config.
+    pub config: Option<db::json::CameraConfig>,
+
+    /// Streams to update, keyed by type (`main`, `sub`, or `ext`).
+    /// A `null` value removes the stream, which must have no recordings.
+    pub streams: Option<std::collections::BTreeMap<String, Option<StreamSubset>>>,
+}
+
+/// Fields of a stream to set on creation or update. Absent fields are left alone.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct StreamSubset {
+    /// The directory to record into. `Some(None)` (`null`) indicates there should be none.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub sample_file_dir_id: Option<Option<i32>>,
+
+    /// A full replacement for the stream's config, including its URL, mode, and retention.
+    pub config: Option<db::json::StreamConfig>,
+}
+
+/// Response to `GET /api/dirs/`.
+#[derive(Serialize)]
+pub struct GetDirsResponse<'a> {
+    pub dirs: Vec<Dir<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dir<'a> {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub path: &'a std::path::Path,
+    pub archive: bool,
+    pub direct_io: bool,
+
+    /// The bytes available to unprivileged users on the directory's filesystem, if the directory
+    /// is open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_available_bytes: Option<i64>,
+
+    /// Streams which record into this directory or archive into it.
+    pub streams: Vec<DirStream>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirStream {
+    pub id: i32,
+    pub camera_uuid: Uuid,
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub record: bool,
+
+    /// The stream's limit within this directory: `retainBytes` for its sample file dir, or
+    /// `archiveRetainBytes` for its archive dir.
+    pub retain_bytes: i64,
+
+    /// The bytes the stream uses within this directory.
+    pub fs_bytes: i64,
+}
+
+/// Request for `POST /api/dirs/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostDirs<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub path: std::path::PathBuf,
+    #[serde(default)]
+    pub archive: bool,
+    #[serde(default)]
+    pub direct_io: bool,
+}
+
+/// Response to `POST /api/dirs/`.
+#[derive(Serialize)]
+pub struct PostDirsResponse {
+    pub id: i32,
+}
+
+/// Request for `PATCH /api/dirs/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PatchDir<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub archive: Option<bool>,
+    pub direct_io: Option<bool>,
+
+    /// Per-stream changes, keyed by stream id. Each stream must record into this directory.
+    #[serde(default)]
+    pub streams: std::collections::BTreeMap<i32, DirStreamUpdate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DirStreamUpdate {
+    pub record: Option<bool>,
+    pub retain_bytes: Option<i64>,
+}
+
+/// Request for `DELETE /api/dirs/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteDir<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostWebRtcRequest<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    /// The browser's SDP offer.
+    pub offer: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostWebRtcResponse {
+    /// The server's SDP answer, with all ICE candidates included.
+    pub answer: String,
+}
+
 #[derive(Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Signals {
@@ -156,6 +557,12 @@ pub struct Signals {
 pub struct SignalType<'a> {
     pub uuid: Uuid,
 
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub name: &'a str,
+
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub color: &'a str,
+
     #[serde(serialize_with = "SignalType::serialize_states")]
     pub states: &'a db::signal::Type,
 }
@@ -177,6 +584,7 @@ impl<'a> Camera<'a> {
         db: &'a db::LockedDatabase,
         include_days: bool,
         include_config: bool,
+        now: Time,
     ) -> Result<Self, Error> {
         Ok(Camera {
             uuid: c.uuid,
@@ -187,9 +595,9 @@ impl<'a> Camera<'a> {
                 true => Some(&c.config),
             },
             streams: [
-                Stream::wrap(db, c.streams[0], include_days, include_config)?,
-                Stream::wrap(db, c.streams[1], include_days, include_config)?,
-                Stream::wrap(db, c.streams[2], include_days, include_config)?,
+                Stream::wrap(db, c.streams[0], include_days, include_config, now)?,
+                Stream::wrap(db, c.streams[1], include_days, include_config, now)?,
+                Stream::wrap(db, c.streams[2], include_days, include_config, now)?,
             ],
         })
     }
@@ -222,6 +630,7 @@ impl<'a> Stream<'a> {
         id: Option<i32>,
         include_days: bool,
         include_config: bool,
+        now: Time,
     ) -> Result<Option<Self>, Error> {
         let id = match id {
             Some(id) => id,
@@ -234,12 +643,18 @@ impl<'a> Stream<'a> {
         Ok(Some(Stream {
             id: s.id,
             retain_bytes: s.config.retain_bytes,
+            retain_days: s.config.retain_days,
+            retain_event_days: s.config.retain_event_days,
             min_start_time_90k: s.range.as_ref().map(|r| r.start),
             max_end_time_90k: s.range.as_ref().map(|r| r.end),
             total_duration_90k: s.duration,
             total_sample_file_bytes: s.sample_file_bytes,
             fs_bytes: s.fs_bytes,
-            record: s.config.mode == db::json::STREAM_MODE_RECORD,
+            archived_sample_file_bytes: s.archived_sample_file_bytes,
+            archived_fs_bytes: s.archived_fs_bytes,
+            record: s.config.is_recording(),
+            flush_if_sec: s.config.flush_if_sec,
+            flush_lag_90k: s.flush_lag(now),
             days: if include_days { Some(s.days()) } else { None },
             config: match include_config {
                 false => None,
@@ -267,6 +682,7 @@ impl<'a> Stream<'a> {
                 start_time_90k: bounds.start,
                 end_time_90k: bounds.end,
                 total_duration_90k: v.duration,
+                total_sample_file_bytes: v.bytes,
             })?;
         }
         map.end()
@@ -333,6 +749,8 @@ impl<'a> SignalType<'a> {
     pub fn wrap(uuid: Uuid, type_: &'a db::signal::Type) -> Self {
         SignalType {
             uuid,
+            name: &type_.config.name,
+            color: &type_.config.color,
             states: type_,
         }
     }
@@ -366,6 +784,7 @@ struct StreamDayValue {
     pub start_time_90k: Time,
     pub end_time_90k: Time,
     pub total_duration_90k: Duration,
+    pub total_sample_file_bytes: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -377,21 +796,26 @@ struct SignalDayValue<'a> {
 }
 
 impl<'a> TopLevel<'a> {
-    /// Serializes cameras as a list (rather than a map), optionally including the `days` and
-    /// `cameras` fields.
+    /// Serializes the allowed cameras as a list (rather than a map), optionally including the
+    /// `days` and `cameras` fields.
     fn serialize_cameras<S>(
-        cameras: &(&db::LockedDatabase, bool, bool),
+        cameras: &(&db::LockedDatabase, bool, bool, &db::Permissions, Time),
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (db, include_days, include_config) = *cameras;
-        let cs = db.cameras_by_id();
+        let (db, include_days, include_config, permissions, now) = *cameras;
+        let cs: Vec<_> = db
+            .cameras_by_id()
+            .values()
+            .filter(|c| permissions.allows_camera(c.uuid))
+            .collect();
         let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for c in cs.values() {
+        for c in cs {
             seq.serialize_element(
-                &Camera::wrap(c, db, include_days, include_config).map_err(S::Error::custom)?,
+                &Camera::wrap(c, db, include_days, include_config, now)
+                    .map_err(S::Error::custom)?,
             )?;
         }
         seq.end()
@@ -428,7 +852,7 @@ impl<'a> TopLevel<'a> {
     }
 }
 
-#[derive(Serialize)]
+#[derive(JsonSchema, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListRecordings<'a> {
     pub recordings: Vec<Recording>,
@@ -437,7 +861,12 @@ pub struct ListRecordings<'a> {
     // representing with an unordered Vec (and having O(n) insert-if-absent) is probably better
     // than dealing with a HashSet's code bloat.
     #[serde(serialize_with = "ListRecordings::serialize_video_sample_entries")]
+    #[schemars(with = "std::collections::BTreeMap<i32, VideoSampleEntry>")]
     pub video_sample_entries: (&'a db::LockedDatabase, Vec<i32>),
+
+    /// The URL of the next page of recordings, if `limit` was reached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
 }
 
 impl<'a> ListRecordings<'a> {
@@ -460,7 +889,65 @@ impl<'a> ListRecordings<'a> {
     }
 }
 
-#[derive(Debug, Serialize)]
+/// Response to `GET /api/recordings/search`.
+#[derive(JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchRecordings<'a> {
+    pub recordings: Vec<SearchRecording>,
+
+    #[serde(serialize_with = "ListRecordings::serialize_video_sample_entries")]
+    #[schemars(with = "std::collections::BTreeMap<i32, VideoSampleEntry>")]
+    pub video_sample_entries: (&'a db::LockedDatabase, Vec<i32>),
+
+    /// The URL of the next page of results, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchRecording {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub id: i32,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub video_samples: i32,
+    pub sample_file_bytes: i32,
+    pub video_sample_entry_id: i32,
+    pub open_id: u32,
+
+    #[serde(skip_serializing_if = "Not::not")]
+    pub growing: bool,
+}
+
+/// Response to `GET /api/usage`.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub streams: Vec<StreamUsage>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamUsage {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub intervals: Vec<UsageInterval>,
+}
+
+/// Storage used by a stream's recordings within a calendar day or hour.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageInterval {
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+    pub recordings: i64,
+    pub total_duration_90k: Duration,
+    pub total_sample_file_bytes: i64,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Recording {
     pub start_time_90k: i64,
@@ -485,7 +972,141 @@ pub struct Recording {
     pub has_trailing_zero: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListThumbnails {
+    /// The number of tiles in each row of every sprite sheet.
+    pub columns: usize,
+    pub sprites: Vec<ThumbnailSprite>,
+}
+
+/// A sprite sheet of thumbnails, as served by `thumbnails.jpg`.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThumbnailSprite {
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub tile_width: u16,
+    pub tile_height: u16,
+
+    /// The time of each tile, in row-major order.
+    pub times_90k: Vec<i64>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListDetections {
+    pub detections: Vec<Detection>,
+}
+
+/// An object found by object detection; see [`db::Detection`].
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Detection {
+    pub time_90k: i64,
+    pub class: String,
+    pub score: f32,
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl From<db::Detection> for Detection {
+    fn from(d: db::Detection) -> Self {
+        Detection {
+            time_90k: d.time.0,
+            class: d.class,
+            score: d.score,
+            left: d.left,
+            top: d.top,
+            width: d.width,
+            height: d.height,
+        }
+    }
+}
+
+/// Request body of `POST /api/exports`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostExport<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub camera_uuid: Uuid,
+
+    /// The stream type: `main` or `sub`.
+    pub stream: &'a str,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteExport<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Response to `GET /api/exports`.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListExports {
+    pub exports: Vec<Export>,
+}
+
+/// A clip export, as returned by `POST /api/exports` and `GET /api/exports/<id>`.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Export {
+    pub id: String,
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+
+    /// One of `running`, `done`, or `failed`.
+    pub status: &'static str,
+    pub written_bytes: u64,
+    pub total_bytes: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The URL of the finished `.mp4`, present only when `status` is `done`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+}
+
+/// Request body of `POST /api/shares`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostShare<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub camera_uuid: Uuid,
+
+    /// The stream type: `main` or `sub`.
+    pub stream: &'a str,
+    pub start_time_90k: Time,
+    pub end_time_90k: Time,
+
+    /// How long the link should remain valid, defaulting to a week.
+    pub expires_in_sec: Option<i64>,
+}
+
+/// Response to `POST /api/shares`.
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostShareResponse {
+    /// The path of the clip, which may be fetched without authentication.
+    pub url: String,
+    pub expires_sec: i64,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VideoSampleEntry {
     pub width: u16,
@@ -557,6 +1178,10 @@ pub struct UserSubset<'a> {
 
     pub preferences: Option<db::json::UserPreferences>,
 
+    /// Email delivery of alerts. `Some(None)` indicates alerts shouldn't be emailed.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub alert_email: Option<Option<db::json::AlertEmail>>,
+
     /// An optional password value.
     ///
     /// `None` indicates the password does not wish to check/update the password.
@@ -573,6 +1198,7 @@ impl<'a> From<&'a db::User> for UserSubset<'a> {
             username: Some(&u.username),
             disabled: Some(u.config.disabled),
             preferences: Some(u.config.preferences.clone()),
+            alert_email: Some(u.config.alert_email.clone()),
             password: Some(u.has_password().then_some("(censored)")),
             permissions: Some(u.permissions.clone().into()),
         }
@@ -605,6 +1231,23 @@ pub struct Permissions {
 
     #[serde(default)]
     pub admin_users: bool,
+
+    #[serde(default)]
+    pub ptz: bool,
+
+    #[serde(default)]
+    pub update_camera_configs: bool,
+
+    #[serde(default)]
+    pub talk: bool,
+
+    /// If non-empty, limits the camera-specific permissions to these cameras.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cameras: Vec<Uuid>,
+
+    /// If non-empty, limits `update_signals` to these signal ids.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signals: Vec<u32>,
 }
 
 impl From<Permissions> for db::schema::Permissions {
@@ -614,6 +1257,11 @@ impl From<Permissions> for db::schema::Permissions {
             read_camera_configs: p.read_camera_configs,
             update_signals: p.update_signals,
             admin_users: p.admin_users,
+            ptz: p.ptz,
+            update_camera_configs: p.update_camera_configs,
+            talk: p.talk,
+            cameras: p.cameras.iter().map(|c| c.as_bytes().to_vec()).collect(),
+            signals: p.signals,
             special_fields: Default::default(),
         }
     }
@@ -626,6 +1274,15 @@ impl From<db::schema::Permissions> for Permissions {
             read_camera_configs: p.read_camera_configs,
             update_signals: p.update_signals,
             admin_users: p.admin_users,
+            ptz: p.ptz,
+            update_camera_configs: p.update_camera_configs,
+            talk: p.talk,
+            cameras: p
+                .cameras
+                .iter()
+                .filter_map(|c| Uuid::from_slice(c).ok())
+                .collect(),
+            signals: p.signals,
         }
     }
 }
@@ -647,3 +1304,397 @@ pub struct UserWithId<'a> {
 pub struct PutUsersResponse {
     pub id: i32,
 }
+
+/// Response to `GET /api/users/<id>/sessions`.
+#[derive(Serialize)]
+pub struct GetUserSessionsResponse<'a> {
+    pub sessions: Vec<UserSession<'a>>,
+}
+
+/// An unrevoked session, as in `GET /api/users/<id>/sessions`.
+///
+/// The session id itself is never included; `id` identifies the session for revocation but
+/// can't be used to authenticate.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSession<'a> {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_time_sec: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_user_agent: Option<std::borrow::Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_peer_addr: Option<std::net::IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use_time_sec: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use_user_agent: Option<std::borrow::Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use_peer_addr: Option<std::net::IpAddr>,
+    pub use_count: i32,
+
+    /// True iff this is the session which made the request.
+    #[serde(skip_serializing_if = "Not::not")]
+    pub current: bool,
+}
+
+impl<'a> UserSession<'a> {
+    pub fn wrap(id: String, s: &'a db::auth::Session, current: bool) -> Self {
+        UserSession {
+            id,
+            description: s.description.as_deref(),
+            creation_time_sec: s.creation.when_sec,
+            creation_user_agent: s
+                .creation
+                .user_agent
+                .as_deref()
+                .map(String::from_utf8_lossy),
+            creation_peer_addr: s.creation.addr,
+            last_use_time_sec: s.last_use.when_sec,
+            last_use_user_agent: s
+                .last_use
+                .user_agent
+                .as_deref()
+                .map(String::from_utf8_lossy),
+            last_use_peer_addr: s.last_use.addr,
+            use_count: s.use_count,
+            current,
+        }
+    }
+}
+
+/// Request for `DELETE /api/users/<id>/sessions` and `DELETE /api/users/<id>/sessions/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteUserSessions<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Response to `GET /api/health`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetHealthResponse<'a> {
+    pub status: HealthStatus,
+
+    /// Details, which are present only for callers with `read_camera_configs`.
+    #[serde(flatten)]
+    pub details: Option<HealthDetails<'a>>,
+}
+
+/// A health status, ordered from best to worst.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthDetails<'a> {
+    /// The longest time any synced recording has been waiting to be committed to the database.
+    pub flush_lag_90k: Duration,
+
+    /// The number of committed recordings yet to be loaded into streams' totals at startup.
+    pub unloaded_recordings: i64,
+
+    pub streams: Vec<StreamHealth<'a>>,
+    pub dirs: Vec<DirHealth<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHealth<'a> {
+    pub camera_uuid: Uuid,
+    pub camera_short_name: &'a str,
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub status: HealthStatus,
+    pub running: bool,
+    pub connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_frame_time_90k: Option<Time>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_time_90k: Option<Time>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirHealth<'a> {
+    pub id: i32,
+    pub path: &'a std::path::Path,
+    pub status: HealthStatus,
+    pub open: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_available_bytes: Option<i64>,
+}
+
+/// Response to `GET /api/cameras/<uuid>/health`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCameraHealthResponse {
+    /// The length of the window described by each stream's `recent` totals.
+    pub window_sec: i64,
+
+    /// Streams by type.
+    pub streams: std::collections::BTreeMap<&'static str, CameraStreamHealth>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraStreamHealth {
+    pub recent: HealthTotals,
+
+    /// Totals by day, as `YYYY-mm-dd` in the server's time zone.
+    pub days: std::collections::BTreeMap<String, HealthTotals>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthTotals {
+    /// From 0 (worst) to 100 (best); absent if the stream wasn't monitored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<u8>,
+    pub monitored_sec: i64,
+    pub up_sec: i64,
+    pub reconnects: i64,
+    pub frame_gaps: i64,
+    pub frames: i64,
+    pub packets_lost: i64,
+}
+
+/// Response to `GET /api/audit`.
+#[derive(Serialize)]
+pub struct GetAuditResponse<'a> {
+    pub entries: Vec<AuditEntry<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry<'a> {
+    pub id: i64,
+    pub time_sec: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<std::net::IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<std::borrow::Cow<'a, str>>,
+    pub action: &'static str,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>,
+}
+
+impl<'a> AuditEntry<'a> {
+    pub fn wrap(e: &'a db::audit::Entry) -> Self {
+        AuditEntry {
+            id: e.id,
+            time_sec: e.time_sec,
+            user_id: e.user_id,
+            username: e.username.as_deref(),
+            peer_addr: e.peer_addr,
+            user_agent: e.user_agent.as_deref().map(String::from_utf8_lossy),
+            action: e.action.as_str(),
+            success: e.success,
+            detail: e.detail.as_deref(),
+        }
+    }
+}
+
+/// Response to `GET /api/tokens/`.
+#[derive(Serialize)]
+pub struct GetTokensResponse<'a> {
+    pub tokens: Vec<Token<'a>>,
+}
+
+/// An API token, as in `GET /api/tokens/`. The token itself is never included.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token<'a> {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    pub creation_time_sec: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_time_sec: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use_time_sec: Option<i64>,
+    pub use_count: i32,
+    pub permissions: Permissions,
+}
+
+impl<'a> Token<'a> {
+    pub fn wrap(t: &'a db::ApiToken) -> Self {
+        Token {
+            id: t.id,
+            user_id: t.user_id,
+            description: t.description.as_deref(),
+            creation_time_sec: t.creation_time_sec,
+            expiration_time_sec: t.expiration_time_sec,
+            last_use_time_sec: t.last_use.when_sec,
+            use_count: t.use_count,
+            permissions: t.permissions.clone().into(),
+        }
+    }
+}
+
+/// Request for `POST /api/tokens/`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct PostTokens<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+
+    /// The user to create the token for; defaults to the caller.
+    pub user_id: Option<i32>,
+
+    pub description: Option<String>,
+    pub expiration_time_sec: Option<i64>,
+    pub permissions: Permissions,
+}
+
+/// Response to `POST /api/tokens/`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostTokensResponse {
+    pub id: i32,
+
+    /// The token to send in `Authorization: Bearer` headers. This is the only time it's shown.
+    pub token: String,
+}
+
+/// Request for `DELETE /api/tokens/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteToken<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Request for `POST /api/webauthn/register/start`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct WebAuthnRegisterStart<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Response to `POST /api/webauthn/register/start` and `POST /api/webauthn/login/start`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebAuthnChallenge<T> {
+    /// The id to pass to the corresponding `finish` request.
+    pub challenge_id: String,
+
+    /// Options to pass to `navigator.credentials.create()` or `navigator.credentials.get()`.
+    pub options: T,
+}
+
+/// Request for `POST /api/webauthn/register/finish`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct WebAuthnRegisterFinish<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+    pub challenge_id: &'a str,
+    pub description: Option<String>,
+    pub credential: webauthn_rs::prelude::RegisterPublicKeyCredential,
+}
+
+/// Response to `POST /api/webauthn/register/finish`.
+#[derive(Serialize)]
+pub struct WebAuthnRegisterFinishResponse {
+    pub id: i32,
+}
+
+/// Request for `POST /api/webauthn/login/start`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct WebAuthnLoginStart<'a> {
+    pub username: &'a str,
+}
+
+/// Request for `POST /api/webauthn/login/finish`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct WebAuthnLoginFinish<'a> {
+    pub challenge_id: &'a str,
+    pub credential: webauthn_rs::prelude::PublicKeyCredential,
+}
+
+/// Response to `GET /api/webauthn/credentials/`.
+#[derive(Serialize)]
+pub struct GetCredentialsResponse<'a> {
+    pub credentials: Vec<Credential<'a>>,
+}
+
+/// A passkey, as in `GET /api/webauthn/credentials/`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credential<'a> {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    pub creation_time_sec: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_use_time_sec: Option<i64>,
+}
+
+impl<'a> Credential<'a> {
+    pub fn wrap(c: &'a db::Credential) -> Self {
+        Credential {
+            id: c.id,
+            user_id: c.user_id,
+            description: c.description.as_deref(),
+            creation_time_sec: c.creation_time_sec,
+            last_use_time_sec: c.last_use_time_sec,
+        }
+    }
+}
+
+/// Request for `DELETE /api/webauthn/credentials/<id>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteCredential<'a> {
+    #[serde(borrow)]
+    pub csrf: Option<&'a str>,
+}
+
+/// Response to `GET /api/ha/cameras`.
+#[derive(Serialize)]
+pub struct GetHaCamerasResponse<'a> {
+    pub cameras: Vec<HaCamera<'a>>,
+}
+
+/// A camera as described to Home Assistant.
+///
+/// Unlike the rest of the API, keys are `snake_case`, matching the options of Home Assistant's
+/// generic camera platform.
+#[derive(Serialize)]
+pub struct HaCamera<'a> {
+    pub uuid: Uuid,
+    pub name: &'a str,
+    pub still_image_url: String,
+    pub stream_source: String,
+    pub content_type: &'static str,
+    pub authentication: &'static str,
+}
@@ -10,14 +10,45 @@ use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use tracing::{debug, error};
 
+mod aac;
+mod analytics;
+mod backups;
 mod body;
 mod cmds;
+mod events;
+mod g711;
+mod gpio;
+mod grpc;
 mod h264;
+mod h265;
+mod health;
+mod import;
+mod jpeg;
 mod json;
+mod maintenance;
+mod metrics;
+mod mjpeg;
+mod mkv;
+mod motion;
 mod mp4;
+mod mqtt;
+mod multipart;
+mod notify;
+mod onvif;
+mod remux;
+mod replication;
+mod rtmp;
+mod rtsp;
+mod rtsps;
+mod signal_hold;
 mod slices;
+mod srtp;
 mod stream;
 mod streamer;
+mod ts;
+mod upload;
+mod v4l2;
+mod vendor_events;
 mod web;
 
 #[cfg(feature = "bundled-ui")]
@@ -31,10 +62,26 @@ const VERSION: &str = env!("VERSION");
 /// Moonfire NVR: security camera network video recorder.
 #[derive(Bpaf, Debug)]
 #[bpaf(options, version(VERSION))]
-enum Args {
+struct Args {
+    /// Log output format: `human`, `systemd`, or `json`. Defaults to the `MOONFIRE_FORMAT`
+    /// environment variable, or `human`.
+    #[bpaf(argument("FORMAT"), optional)]
+    log_format: Option<base::tracing_setup::Format>,
+
+    #[bpaf(external(command))]
+    command: Command,
+}
+
+#[derive(Bpaf, Debug)]
+enum Command {
     // See docstrings of `cmds::*::Args` structs for a description of the respective subcommands.
+    Backup(#[bpaf(external(cmds::backup::args))] cmds::backup::Args),
     Check(#[bpaf(external(cmds::check::args))] cmds::check::Args),
     Config(#[bpaf(external(cmds::config::args))] cmds::config::Args),
+    DbStats(#[bpaf(external(cmds::db_stats::args))] cmds::db_stats::Args),
+    Downgrade(#[bpaf(external(cmds::downgrade::args))] cmds::downgrade::Args),
+    Export(#[bpaf(external(cmds::export::args))] cmds::export::Args),
+    Import(#[bpaf(external(cmds::import::args))] cmds::import::Args),
     Init(#[bpaf(external(cmds::init::args))] cmds::init::Args),
     Login(#[bpaf(external(cmds::login::args))] cmds::login::Args),
     Run(#[bpaf(external(cmds::run::args))] cmds::run::Args),
@@ -43,17 +90,22 @@ enum Args {
     Upgrade(#[bpaf(external(cmds::upgrade::args))] cmds::upgrade::Args),
 }
 
-impl Args {
+impl Command {
     fn run(self) -> Result<i32, Error> {
         match self {
-            Args::Check(a) => cmds::check::run(a),
-            Args::Config(a) => cmds::config::run(a),
-            Args::Init(a) => cmds::init::run(a),
-            Args::Login(a) => cmds::login::run(a),
-            Args::Run(a) => cmds::run::run(a),
-            Args::Sql(a) => cmds::sql::run(a),
-            Args::Ts(a) => cmds::ts::run(a),
-            Args::Upgrade(a) => cmds::upgrade::run(a),
+            Command::Backup(a) => cmds::backup::run(a),
+            Command::Check(a) => cmds::check::run(a),
+            Command::Config(a) => cmds::config::run(a),
+            Command::DbStats(a) => cmds::db_stats::run(a),
+            Command::Downgrade(a) => cmds::downgrade::run(a),
+            Command::Export(a) => cmds::export::run(a),
+            Command::Import(a) => cmds::import::run(a),
+            Command::Init(a) => cmds::init::run(a),
+            Command::Login(a) => cmds::login::run(a),
+            Command::Run(a) => cmds::run::run(a),
+            Command::Sql(a) => cmds::sql::run(a),
+            Command::Ts(a) => cmds::ts::run(a),
+            Command::Upgrade(a) => cmds::upgrade::run(a),
         }
     }
 }
@@ -77,8 +129,6 @@ fn main() {
         std::process::exit(1);
     }
 
-    base::tracing_setup::install();
-
     // Get the program name from the OS (e.g. if invoked as `target/debug/nvr`: `nvr`),
     // falling back to the crate name if conversion to a path/UTF-8 string fails.
     // `bpaf`'s default logic is similar but doesn't have the fallback.
@@ -96,18 +146,21 @@ fn main() {
         Ok(a) => a,
         Err(e) => std::process::exit(e.exit_code()),
     };
+    base::tracing_setup::install(args.log_format);
     tracing::trace!("Parsed command-line arguments: {args:#?}");
 
-    match args.run() {
+    let rv = match args.command.run() {
         Err(e) => {
             error!(err = %e.chain(), "exiting due to error");
-            ::std::process::exit(1);
+            1
         }
         Ok(rv) => {
             debug!("exiting with status {}", rv);
-            std::process::exit(rv)
+            rv
         }
-    }
+    };
+    base::tracing_setup::shutdown();
+    std::process::exit(rv)
 }
 
 #[cfg(test)]
@@ -0,0 +1,215 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Scheduled database maintenance.
+//!
+//! As recordings are added and deleted, the `recording` and `recording_playback` tables churn,
+//! leaving free pages in the database file and the query planner's statistics out of date. Once
+//! a day, during a configured window of local time, a single task runs
+//! [`db::Database::maintain`], which reclaims free pages a step at a time until the window ends
+//! and then runs `analyze`. The result of the last successful run is available via [`Status`]
+//! for `/api/`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base::clock::Clocks;
+use base::{bail, err, Error};
+use chrono::Timelike as _;
+use db::recording;
+use tracing::{info, warn};
+
+use crate::cmds::run::config::MaintenanceConfig;
+use crate::json;
+
+const SECS_PER_DAY: u32 = 86_400;
+
+/// The state of scheduled maintenance, as shared with the web interface.
+#[derive(Default)]
+pub struct Status {
+    last: Mutex<Option<json::Maintenance>>,
+}
+
+impl Status {
+    /// Returns the most recent successful run, if any since startup.
+    pub fn last(&self) -> Option<json::Maintenance> {
+        self.last.lock().unwrap().clone()
+    }
+}
+
+pub struct Maintenance {
+    start_hour: u32,
+    end_hour: u32,
+    status: Arc<Status>,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> Result<Self, Error> {
+        for h in [config.window_start_hour, config.window_end_hour] {
+            if h >= 24 {
+                bail!(
+                    InvalidArgument,
+                    msg("maintenance window hours must be 0-23, not {h}")
+                );
+            }
+        }
+        if config.window_start_hour == config.window_end_hour {
+            bail!(
+                InvalidArgument,
+                msg("maintenance windowStartHour and windowEndHour must differ")
+            );
+        }
+        Ok(Maintenance {
+            start_hour: config.window_start_hour,
+            end_hour: config.window_end_hour,
+            status: Arc::new(Status::default()),
+        })
+    }
+
+    pub fn status(&self) -> Arc<Status> {
+        self.status.clone()
+    }
+
+    /// Returns true if the local time `now` is within the window.
+    fn in_window(&self, now: chrono::NaiveTime) -> bool {
+        let h = now.hour();
+        if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&h)
+        } else {
+            h >= self.start_hour || h < self.end_hour
+        }
+    }
+
+    /// Runs maintenance until `deadline`, then records the result.
+    async fn maintain<C: Clocks + Clone>(
+        &self,
+        db: &Arc<db::Database<C>>,
+        shutdown_rx: &base::shutdown::Receiver,
+        deadline: Instant,
+    ) -> Result<(), Error> {
+        let start = recording::Time::new(db.clocks().realtime());
+        let started = Instant::now();
+        let stats = tokio::task::spawn_blocking({
+            let db = db.clone();
+            let shutdown_rx = shutdown_rx.clone();
+            move || db.maintain(&shutdown_rx, deadline)
+        })
+        .await
+        .map_err(|e| err!(Internal, msg("maintenance task failed"), source(e)))??;
+        let elapsed = started.elapsed();
+        info!(
+            "database maintenance took {:?}; freed {} bytes, {} bytes remain free{}",
+            elapsed,
+            stats.freed_bytes,
+            stats.free_bytes,
+            if stats.incremental {
+                ""
+            } else {
+                " (incremental vacuum is disabled; run moonfire-nvr upgrade to enable it)"
+            },
+        );
+        *self.status.last.lock().unwrap() = Some(json::Maintenance {
+            start_time_90k: start,
+            duration_90k: recording::Duration::try_from(elapsed)
+                .map_err(|e| err!(OutOfRange, source(e)))?,
+            incremental_vacuum: stats.incremental,
+            freed_bytes: stats.freed_bytes,
+            free_bytes: stats.free_bytes,
+        });
+        Ok(())
+    }
+}
+
+/// Returns the time from the local time `now` until the next occurrence of `hour`:00.
+fn until_hour(now: chrono::NaiveTime, hour: u32) -> Duration {
+    let secs = (hour * 3600 + SECS_PER_DAY - now.num_seconds_from_midnight()) % SECS_PER_DAY;
+    Duration::from_secs(u64::from(secs))
+}
+
+/// Runs scheduled maintenance until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    maintenance: Maintenance,
+) {
+    info!(
+        "running database maintenance daily between {:02}:00 and {:02}:00 local time",
+        maintenance.start_hour, maintenance.end_hour
+    );
+    loop {
+        let now = chrono::Local::now().time();
+        let wait = if maintenance.in_window(now) {
+            Duration::ZERO
+        } else {
+            until_hour(now, maintenance.start_hour)
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+        let deadline =
+            Instant::now() + until_hour(chrono::Local::now().time(), maintenance.end_hour);
+        if let Err(err) = maintenance.maintain(&db, &shutdown_rx, deadline).await {
+            warn!(%err, "database maintenance failed");
+        }
+
+        // Run only once per window.
+        let wait = until_hour(chrono::Local::now().time(), maintenance.end_hour);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(h: u32, m: u32) -> chrono::NaiveTime {
+        chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn window() {
+        let m = Maintenance::new(&MaintenanceConfig {
+            window_start_hour: 3,
+            window_end_hour: 5,
+        })
+        .unwrap();
+        assert!(!m.in_window(t(2, 59)));
+        assert!(m.in_window(t(3, 0)));
+        assert!(m.in_window(t(4, 59)));
+        assert!(!m.in_window(t(5, 0)));
+
+        let m = Maintenance::new(&MaintenanceConfig {
+            window_start_hour: 23,
+            window_end_hour: 1,
+        })
+        .unwrap();
+        assert!(!m.in_window(t(22, 59)));
+        assert!(m.in_window(t(23, 0)));
+        assert!(m.in_window(t(0, 30)));
+        assert!(!m.in_window(t(1, 0)));
+
+        for (start, end) in [(3, 3), (3, 24)] {
+            Maintenance::new(&MaintenanceConfig {
+                window_start_hour: start,
+                window_end_hour: end,
+            })
+            .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn until() {
+        assert_eq!(until_hour(t(2, 30), 3), Duration::from_secs(30 * 60));
+        assert_eq!(until_hour(t(3, 0), 3), Duration::ZERO);
+        assert_eq!(
+            until_hour(t(3, 30), 3),
+            Duration::from_secs(23 * 3600 + 30 * 60)
+        );
+        assert_eq!(until_hour(t(23, 0), 1), Duration::from_secs(2 * 3600));
+    }
+}
@@ -0,0 +1,143 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Metrics gathered since startup by the streamers and web interface(s), for `/metrics`,
+//! `/api/health`, and camera health history.
+//!
+//! Metrics which can be derived from the database at scrape time, such as disk usage, aren't
+//! kept here.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base::metrics::{Histogram, LATENCY_BUCKETS};
+use base::FastHashMap;
+use db::recording;
+use http::{Method, StatusCode};
+
+/// Counters describing a stream's ingest, updated by its streamer with `Ordering::Relaxed`.
+///
+/// These persist across streamer restarts, as when the stream's configuration changes.
+#[derive(Default)]
+pub struct StreamCounters {
+    /// Video frames received from the camera.
+    pub frames_received: AtomicU64,
+
+    /// Video frames discarded because they arrived before the first key frame of a session.
+    pub frames_dropped: AtomicU64,
+
+    /// Video bytes written to sample files.
+    pub bytes_recorded: AtomicU64,
+
+    /// Times the stream has been reopened after an error.
+    pub reconnects: AtomicU64,
+
+    /// RTP packets known to have been lost.
+    pub packets_lost: AtomicU64,
+
+    /// Times a connected stream went longer than [`FRAME_GAP`] between video frames.
+    pub frame_gaps: AtomicU64,
+
+    pub status: Mutex<StreamStatus>,
+
+    /// Recent and not-yet-saved health totals, updated by [`crate::health::run`].
+    pub health: Mutex<crate::health::State>,
+}
+
+/// How long a connected stream may go between video frames before it's counted as a gap.
+pub const FRAME_GAP: recording::Duration = recording::Duration(5 * recording::TIME_UNITS_PER_SEC);
+
+/// How long a connected stream may go without a frame before it's considered down.
+const MAX_FRAME_AGE: recording::Duration = recording::Duration(30 * recording::TIME_UNITS_PER_SEC);
+
+/// The current state of a stream's ingest, updated by its streamer.
+#[derive(Clone, Default)]
+pub struct StreamStatus {
+    /// True while a streamer is running for the stream.
+    pub running: bool,
+
+    /// True from successfully opening the stream until the next error.
+    pub connected: bool,
+
+    /// The wall time at which the most recent video frame was received.
+    pub last_frame: Option<recording::Time>,
+
+    /// The time and description of the most recent error.
+    pub last_error: Option<(recording::Time, String)>,
+}
+
+impl StreamStatus {
+    /// Returns true if the stream should be receiving frames but isn't, as of `now`.
+    pub fn is_down(&self, now: recording::Time) -> bool {
+        self.running
+            && (!self.connected || self.last_frame.map_or(true, |t| now - t > MAX_FRAME_AGE))
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    streams: Mutex<FastHashMap<i32, Arc<StreamCounters>>>,
+
+    /// HTTP request latency histograms, by method and status code.
+    http_requests: Mutex<BTreeMap<(&'static str, u16), Histogram>>,
+}
+
+impl Metrics {
+    /// Returns the counters for the given stream, creating them if necessary.
+    pub fn stream(&self, stream_id: i32) -> Arc<StreamCounters> {
+        self.streams
+            .lock()
+            .unwrap()
+            .entry(stream_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the counters of all streams which have been started, ordered by stream id.
+    pub fn streams(&self) -> Vec<(i32, Arc<StreamCounters>)> {
+        let mut streams: Vec<_> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, c)| (id, c.clone()))
+            .collect();
+        streams.sort_unstable_by_key(|&(id, _)| id);
+        streams
+    }
+
+    /// Records a served HTTP request, given its latency to response headers.
+    pub fn observe_http_request(&self, method: &Method, status: StatusCode, latency: Duration) {
+        // Limit the method label to the standard methods, so that arbitrary client requests
+        // can't create arbitrarily many histograms.
+        let method = match *method {
+            Method::GET => "GET",
+            Method::HEAD => "HEAD",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::OPTIONS => "OPTIONS",
+            _ => "other",
+        };
+        self.http_requests
+            .lock()
+            .unwrap()
+            .entry((method, status.as_u16()))
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Returns a snapshot of the HTTP request histograms, ordered by method and status code.
+    pub fn http_requests(&self) -> Vec<((&'static str, u16), Histogram)> {
+        self.http_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&k, h)| (k, h.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,330 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! MJPEG over HTTP, for cameras which offer no RTSP stream.
+//!
+//! Such cameras serve a `multipart/x-mixed-replace` response whose parts are JPEG images. Each
+//! image is stored as-is as a key frame, under a `jpeg` (QuickTime "Photo - JPEG") video sample
+//! entry. There are no timestamps in the stream, so frames are timed by their arrival.
+//!
+//! The resulting `.mp4` and `.mkv` files play in VLC, mpv, and other FFmpeg-based players, but
+//! not in web browsers, so live view and HLS don't work with these streams. Snapshots need no
+//! decoding; thumbnails and motion detection decode via FFmpeg as for other codecs.
+
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use tokio_rustls::rustls;
+use tracing::Instrument;
+use url::Url;
+
+use crate::multipart;
+use crate::stream::{Options, Stream, VideoFrame};
+
+/// The longest part accepted, including its headers.
+const MAX_PART_LEN: usize = 16 << 20;
+
+/// The `rfc6381_codec` of sample entries; there's no registered value for JPEG.
+const CODEC: &str = "jpeg";
+
+/// Returns a video sample entry for JPEG images of the given dimensions.
+pub fn sample_entry(width: u16, height: u16) -> db::VideoSampleEntryToInsert {
+    let mut data = Vec::with_capacity(86);
+
+    // SampleEntry, ISO/IEC 14496-12 section 8.5.2.
+    data.extend_from_slice(b"\x00\x00\x00\x56jpeg\x00\x00\x00\x00\x00\x00\x00\x01");
+
+    // VisualSampleEntry, ISO/IEC 14496-12 section 12.1.3.
+    data.extend_from_slice(&[0; 16]); // pre-defined + reserved
+    data.extend_from_slice(&width.to_be_bytes());
+    data.extend_from_slice(&height.to_be_bytes());
+    data.extend_from_slice(&[
+        0x00, 0x48, 0x00, 0x00, // horizresolution
+        0x00, 0x48, 0x00, 0x00, // vertresolution
+        0x00, 0x00, 0x00, 0x00, // reserved
+        0x00, 0x01, // frame count
+    ]);
+    data.extend_from_slice(&[0; 32]); // compressorname
+    data.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]); // depth + pre_defined
+    debug_assert_eq!(data.len(), 86);
+    db::VideoSampleEntryToInsert {
+        data,
+        rfc6381_codec: CODEC.to_owned(),
+        width,
+        height,
+        pasp_h_spacing: 1,
+        pasp_v_spacing: 1,
+    }
+}
+
+/// Returns the width and height of a JPEG image, from its start-of-frame segment.
+fn dimensions(jpeg: &[u8]) -> Result<(u16, u16), Error> {
+    if !jpeg.starts_with(b"\xff\xd8") {
+        bail!(InvalidArgument, msg("not a JPEG image"));
+    }
+    let mut pos = 2;
+    loop {
+        let Some(&[0xff, marker, len_hi, len_lo]) = jpeg.get(pos..pos + 4) else {
+            bail!(InvalidArgument, msg("JPEG image has no start of frame"));
+        };
+        if marker == 0xff {
+            pos += 1; // fill byte.
+            continue;
+        }
+
+        // SOF0 through SOF15, except DHT (0xc4), JPG (0xc8), and DAC (0xcc).
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let Some(&[_precision, h_hi, h_lo, w_hi, w_lo]) = jpeg.get(pos + 4..pos + 9) else {
+                bail!(InvalidArgument, msg("truncated JPEG start of frame"));
+            };
+            let (width, height) = (
+                u16::from_be_bytes([w_hi, w_lo]),
+                u16::from_be_bytes([h_hi, h_lo]),
+            );
+            if width == 0 || height == 0 {
+                bail!(InvalidArgument, msg("bad JPEG dimensions {width}x{height}"));
+            }
+            return Ok((width, height));
+        }
+        if marker == 0xda {
+            bail!(InvalidArgument, msg("JPEG image has no start of frame"));
+        }
+        pos += 2 + usize::from(u16::from_be_bytes([len_hi, len_lo]));
+    }
+}
+
+/// Returns the next JPEG image from `parts`, or `None` if more data is needed.
+///
+/// Parts which aren't JPEG images are skipped.
+fn next_jpeg(parts: &mut multipart::Parts) -> Result<Option<Bytes>, Error> {
+    while let Some(part) = parts.next()? {
+        let is_jpeg = part
+            .content_type
+            .as_ref()
+            .map_or(true, |t| t == "image/jpeg" || t == "image/jpg");
+        if is_jpeg && part.body.starts_with(b"\xff\xd8") {
+            return Ok(Some(part.body));
+        }
+    }
+    Ok(None)
+}
+
+/// The parts of an [`MjpegStream`] used from within the tokio reactor.
+struct Inner {
+    body: hyper::Body,
+    parts: multipart::Parts,
+    start: tokio::time::Instant,
+}
+
+impl Inner {
+    async fn connect(
+        url: Url,
+        tls: Option<Arc<rustls::ClientConfig>>,
+        creds: Option<retina::client::Credentials>,
+    ) -> Result<(Box<Self>, Bytes), Error> {
+        let client = multipart::client(tls);
+        let resp = multipart::get(&client, &url, creds.as_ref()).await?;
+        let content_type = resp
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let Some(boundary) = multipart::boundary(content_type) else {
+            bail!(
+                FailedPrecondition,
+                msg("{url} returned Content-Type {content_type:?}, not a multipart MJPEG stream")
+            );
+        };
+        let mut inner = Box::new(Inner {
+            parts: multipart::Parts::new(boundary, MAX_PART_LEN),
+            body: resp.into_body(),
+            start: tokio::time::Instant::now(),
+        });
+        let first = inner.next_image().await?;
+        inner.start = tokio::time::Instant::now();
+        Ok((inner, first))
+    }
+
+    async fn next_image(&mut self) -> Result<Bytes, Error> {
+        loop {
+            if let Some(image) = next_jpeg(&mut self.parts)? {
+                return Ok(image);
+            }
+            match self.body.data().await {
+                Some(Ok(data)) => self.parts.push(&data),
+                Some(Err(e)) => bail!(Unavailable, msg("unable to read MJPEG stream"), source(e)),
+                None => bail!(Unavailable, msg("end of stream")),
+            }
+        }
+    }
+
+    /// Fetches a non-initial image, with its arrival time in 90 kHz units since the first.
+    async fn fetch_next_image(mut self: Box<Self>) -> Result<(Box<Self>, i64, Bytes), Error> {
+        let image = self.next_image().await?;
+        let pts = pts_90k(self.start.elapsed());
+        Ok((self, pts, image))
+    }
+}
+
+fn pts_90k(elapsed: std::time::Duration) -> i64 {
+    i64::try_from(elapsed.as_micros() * 9 / 100).expect("stream shouldn't run for 3 million years")
+}
+
+/// An MJPEG stream, which blocks on tokio operations as `RetinaStream` does.
+struct MjpegStream {
+    inner: Option<Box<Inner>>,
+    rt_handle: tokio::runtime::Handle,
+    video_sample_entry: db::VideoSampleEntryToInsert,
+
+    /// The first image, if not yet returned from `next`.
+    first_image: Option<Bytes>,
+
+    frame_timeout: std::time::Duration,
+}
+
+/// Opens a MJPEG stream from a `http://` or `https://` URL. The stream's audio and RTSP options
+/// are ignored.
+pub fn open(label: String, url: Url, options: Options) -> Result<Box<dyn Stream>, Error> {
+    if options.audio_setup.is_some() {
+        tracing::warn!("{label}: audio is unsupported with MJPEG streams");
+    }
+    let rt_handle = tokio::runtime::Handle::current();
+    let (inner, first_image) = rt_handle
+        .block_on(
+            rt_handle.spawn(
+                tokio::time::timeout(
+                    options.connect_timeout,
+                    Inner::connect(url, options.tls, options.creds),
+                )
+                .in_current_span(),
+            ),
+        )
+        .expect("MJPEG connect task panicked, see earlier error")
+        .map_err(|e| {
+            err!(
+                DeadlineExceeded,
+                msg("timeout getting first image"),
+                source(e)
+            )
+        })??;
+    let (width, height) = dimensions(&first_image)?;
+    tracing::debug!("{label}: MJPEG stream is {width}x{height}");
+    Ok(Box::new(MjpegStream {
+        inner: Some(inner),
+        rt_handle,
+        video_sample_entry: sample_entry(width, height),
+        first_image: Some(first_image),
+        frame_timeout: options.frame_timeout,
+    }))
+}
+
+impl Stream for MjpegStream {
+    fn tool(&self) -> Option<&retina::client::Tool> {
+        None
+    }
+
+    fn video_sample_entry(&self) -> &db::VideoSampleEntryToInsert {
+        &self.video_sample_entry
+    }
+
+    fn next(&mut self) -> Result<VideoFrame, Error> {
+        let (pts, image) = match self.first_image.take() {
+            Some(image) => (0, image),
+            None => {
+                let inner = self.inner.take().unwrap();
+                let (inner, pts, image) = self
+                    .rt_handle
+                    .block_on(
+                        self.rt_handle.spawn(
+                            tokio::time::timeout(self.frame_timeout, inner.fetch_next_image())
+                                .in_current_span(),
+                        ),
+                    )
+                    .expect("fetch_next_image task panicked, see earlier error")
+                    .map_err(|e| {
+                        err!(
+                            DeadlineExceeded,
+                            msg("timeout getting next frame"),
+                            source(e)
+                        )
+                    })??;
+                self.inner = Some(inner);
+                (pts, image)
+            }
+        };
+        let (width, height) = dimensions(&image)?;
+        let e = &self.video_sample_entry;
+        let new_video_sample_entry = (width, height) != (e.width, e.height);
+        if new_video_sample_entry {
+            self.video_sample_entry = sample_entry(width, height);
+        }
+        Ok(VideoFrame {
+            pts,
+            duration: 0,
+            is_key: true,
+            data: image,
+            new_video_sample_entry,
+            loss: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    /// The start of a 640x480 baseline JPEG: SOI, an APP0 segment, and SOF0.
+    const JPEG: &[u8] = b"\xff\xd8\
+                          \xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00\
+                          \xff\xc0\x00\x11\x08\x01\xe0\x02\x80\x03\x01\x22\x00\x02\x11\x01\x03\x11\x01\
+                          \xff\xd9";
+
+    #[test]
+    fn jpeg_dimensions() {
+        testutil::init();
+        assert_eq!(dimensions(JPEG).unwrap(), (640, 480));
+        dimensions(b"\xff\xd8\xff\xd9").unwrap_err();
+        dimensions(b"GIF89a").unwrap_err();
+    }
+
+    #[test]
+    fn sample_entry_box() {
+        testutil::init();
+        let e = sample_entry(640, 480);
+        assert_eq!(&e.data[4..8], b"jpeg");
+        assert_eq!(
+            u32::from_be_bytes(e.data[0..4].try_into().unwrap()) as usize,
+            e.data.len()
+        );
+        assert_eq!(&e.data[32..36], b"\x02\x80\x01\xe0");
+    }
+
+    #[test]
+    fn parts() {
+        testutil::init();
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--foo\r\nContent-Type: image/jpeg\r\nContent-Length: ");
+        body.extend_from_slice(JPEG.len().to_string().as_bytes());
+        body.extend_from_slice(b"\r\n\r\n");
+        body.extend_from_slice(JPEG);
+        body.extend_from_slice(b"\r\n--foo\r\nContent-Type: text/plain\r\n\r\nhello\r\n");
+        body.extend_from_slice(b"--foo\nContent-Type: image/jpeg\n\n");
+        body.extend_from_slice(JPEG);
+        body.extend_from_slice(b"\r\n--foo\r\n");
+
+        // Feed the body a byte at a time to exercise partial reads.
+        let mut p = multipart::Parts::new("foo", MAX_PART_LEN);
+        let mut images = Vec::new();
+        for b in body.chunks(1) {
+            p.push(b);
+            while let Some(image) = next_jpeg(&mut p).unwrap() {
+                images.push(image);
+            }
+        }
+        assert_eq!(images, [JPEG, JPEG]);
+    }
+}
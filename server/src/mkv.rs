@@ -0,0 +1,629 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Matroska (`.mkv`) file builder, serving the same recording ranges as `mp4::FileBuilder`.
+//!
+//! The file is laid out as follows:
+//!
+//!    * an EBML header.
+//!    * a `Segment` with a fixed 8-byte size, containing:
+//!        * a `SeekHead` pointing to the `Info`, `Tracks`, and `Cues` elements.
+//!        * `Info` and `Tracks`, describing the single video track.
+//!        * one `Cluster` per key frame (or more if the group of pictures is too long for a
+//!          block's 16-bit relative timestamp), each holding a `SimpleBlock` per frame.
+//!        * `Cues` pointing to every cluster.
+//!
+//! Everything but the `SimpleBlock`s is generated by `FileBuilder::build` into a buffer. Each
+//! cluster's blocks are generated on request by reading the cluster's frames from the sample
+//! file, which are contiguous, and interleaving the block headers. The frames are passed through
+//! unmodified: the length-prefixed NAL units used in `.mp4` files are also the Matroska format
+//! for `V_MPEG4/ISO/AVC` and `V_MPEGH/ISO/HEVC`.
+//!
+//! Unlike `.mp4` files, Matroska has no edit lists, so frames before the desired start (back to
+//! the preceding key frame) are presented rather than skipped. Audio isn't included.
+
+use crate::body::{BoxedError, Chunk};
+use crate::remux;
+use crate::slices::{self, Slices};
+use base::{bail, err, Error};
+use byteorder::{BigEndian, WriteBytesExt};
+use db::dir;
+use db::recording;
+use futures::{stream, Stream};
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::trace;
+
+/// This value should be incremented any time a change is made to this file that causes different
+/// bytes or headers to be output for a particular set of `FileBuilder` options. Incrementing this
+/// value will cause the etag to change as well.
+const FORMAT_VERSION: [u8; 1] = [0x00];
+
+/// The `TimestampScale`, in nanoseconds: millisecond precision, the Matroska default.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+
+/// The number of 90 kHz units in one `TIMESTAMP_SCALE_NS` tick.
+const UNITS_PER_TICK: u64 = 90;
+
+/// Seconds from the Unix epoch to the Matroska `DateUTC` epoch, 2001-01-01 00:00:00 UTC.
+const MATROSKA_EPOCH_UNIX_SECS: i64 = 978_307_200;
+
+/// The length of a `SimpleBlock` header following the element's id and size: a 1-byte track
+/// number, a 16-bit relative timestamp, and a flags byte.
+const SIMPLE_BLOCK_HEADER_LEN: u64 = 4;
+
+/// The number of the (only) track.
+const VIDEO_TRACK_NUMBER: u8 = 1;
+
+// Element ids, as in RFC 9559 section 5.1 and RFC 8794 section 11.2.
+const ID_EBML: u32 = 0x1A45_DFA3;
+const ID_EBML_VERSION: u32 = 0x4286;
+const ID_EBML_READ_VERSION: u32 = 0x42F7;
+const ID_EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const ID_EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const ID_DOC_TYPE: u32 = 0x4282;
+const ID_DOC_TYPE_VERSION: u32 = 0x4287;
+const ID_DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const ID_SEGMENT: u32 = 0x1853_8067;
+const ID_SEEK_HEAD: u32 = 0x114D_9B74;
+const ID_SEEK: u32 = 0x4DBB;
+const ID_SEEK_ID: u32 = 0x53AB;
+const ID_SEEK_POSITION: u32 = 0x53AC;
+const ID_INFO: u32 = 0x1549_A966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const ID_DURATION: u32 = 0x4489;
+const ID_DATE_UTC: u32 = 0x4461;
+const ID_MUXING_APP: u32 = 0x4D80;
+const ID_WRITING_APP: u32 = 0x5741;
+const ID_TRACKS: u32 = 0x1654_AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_TRACK_UID: u32 = 0x73C5;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_FLAG_LACING: u32 = 0x9C;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63A2;
+const ID_VIDEO: u32 = 0xE0;
+const ID_PIXEL_WIDTH: u32 = 0xB0;
+const ID_PIXEL_HEIGHT: u32 = 0xBA;
+const ID_DISPLAY_WIDTH: u32 = 0x54B0;
+const ID_DISPLAY_HEIGHT: u32 = 0x54BA;
+const ID_DISPLAY_UNIT: u32 = 0x54B2;
+const ID_CLUSTER: u32 = 0x1F43_B675;
+const ID_TIMESTAMP: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+const ID_CUES: u32 = 0x1C53_BB6B;
+const ID_CUE_POINT: u32 = 0xBB;
+const ID_CUE_TIME: u32 = 0xB3;
+const ID_CUE_TRACK_POSITIONS: u32 = 0xB7;
+const ID_CUE_TRACK: u32 = 0xF7;
+const ID_CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// Appends an element id. Ids include their own length marker, so this just omits leading zeros.
+fn put_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8) as usize;
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+/// Returns the length of the shortest variable-length integer which can represent `v` as an
+/// element data size. The all-ones value of each length is reserved to mean "unknown".
+fn size_len(v: u64) -> usize {
+    (1..=8)
+        .find(|&n| v < (1 << (7 * n)) - 1)
+        .expect("element sizes should be less than 2^56 - 1")
+}
+
+/// Appends an element data size as a variable-length integer of the shortest possible length.
+fn put_size(buf: &mut Vec<u8>, v: u64) {
+    let n = size_len(v);
+    let bytes = v.to_be_bytes();
+    buf.push(bytes[8 - n] | (0x80 >> (n - 1)));
+    buf.extend_from_slice(&bytes[8 - n + 1..]);
+}
+
+/// Appends an element with the given binary data.
+fn put_bytes(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Appends an unsigned integer element, using the fewest bytes possible.
+fn put_uint(buf: &mut Vec<u8>, id: u32, v: u64) {
+    let bytes = v.to_be_bytes();
+    let skip = cmp::min(7, (v.leading_zeros() / 8) as usize);
+    put_bytes(buf, id, &bytes[skip..]);
+}
+
+/// Appends an unsigned integer element, always using 8 bytes so its length is independent of
+/// its value.
+fn put_uint_fixed(buf: &mut Vec<u8>, id: u32, v: u64) {
+    put_bytes(buf, id, &v.to_be_bytes());
+}
+
+/// Appends a master element whose children are written by `f`.
+fn put_master<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, id: u32, f: F) {
+    let mut children = Vec::new();
+    f(&mut children);
+    put_bytes(buf, id, &children);
+}
+
+/// Returns the full length of a `SimpleBlock` element holding a frame of the given size.
+fn simple_block_len(frame_bytes: u32) -> u64 {
+    let data_len = SIMPLE_BLOCK_HEADER_LEN + u64::from(frame_bytes);
+    1 + size_len(data_len) as u64 + data_len
+}
+
+/// Converts from a `recording::Time` to nanoseconds since the Matroska epoch.
+fn to_date_utc(t: recording::Time) -> i64 {
+    let units = recording::TIME_UNITS_PER_SEC;
+    let secs = t.0.div_euclid(units) - MATROSKA_EPOCH_UNIX_SECS;
+    let nanos = t.0.rem_euclid(units) * 1_000_000_000 / units;
+    secs * 1_000_000_000 + nanos
+}
+
+/// A single frame within a `Cluster`.
+#[derive(Debug)]
+struct Frame {
+    /// The timestamp relative to the cluster's, in `TIMESTAMP_SCALE_NS` units.
+    rel_timestamp: i16,
+    bytes: u32,
+    is_key: bool,
+}
+
+/// A Matroska `Cluster`: a run of frames from a single segment, starting at a key frame.
+#[derive(Debug)]
+struct Cluster {
+    /// Index into the `File`'s segments.
+    segment: usize,
+
+    /// The cluster's timestamp, in `TIMESTAMP_SCALE_NS` units.
+    timestamp: u64,
+
+    /// The range within the segment's sample file of this cluster's frames.
+    sample_file_range: Range<u64>,
+
+    frames: Vec<Frame>,
+
+    /// The total length of the cluster's `SimpleBlock` elements.
+    blocks_len: u64,
+}
+
+impl Cluster {
+    /// Appends the cluster's id, size, and `Timestamp` element: everything before the blocks.
+    fn put_header(&self, buf: &mut Vec<u8>) {
+        let mut timestamp = Vec::new();
+        put_uint(&mut timestamp, ID_TIMESTAMP, self.timestamp);
+        put_id(buf, ID_CLUSTER);
+        put_size(buf, timestamp.len() as u64 + self.blocks_len);
+        buf.extend_from_slice(&timestamp);
+    }
+
+    /// Returns the cluster's `SimpleBlock` elements, given its frames as read from the sample file.
+    fn blocks(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let expected_len = self.sample_file_range.end - self.sample_file_range.start;
+        if data.len() as u64 != expected_len {
+            bail!(
+                Internal,
+                msg(
+                    "expected {} bytes of sample data; got {}",
+                    expected_len,
+                    data.len()
+                ),
+            );
+        }
+        let mut v = Vec::with_capacity(usize::try_from(self.blocks_len).unwrap());
+        let mut pos = 0;
+        for f in &self.frames {
+            let bytes = f.bytes as usize;
+            put_id(&mut v, ID_SIMPLE_BLOCK);
+            put_size(&mut v, SIMPLE_BLOCK_HEADER_LEN + u64::from(f.bytes));
+            v.push(0x80 | VIDEO_TRACK_NUMBER);
+            v.write_i16::<BigEndian>(f.rel_timestamp)
+                .expect("Vec write shouldn't fail");
+            v.push(if f.is_key { 0x80 } else { 0x00 });
+            v.extend_from_slice(&data[pos..pos + bytes]);
+            pos += bytes;
+        }
+        if v.len() as u64 != self.blocks_len {
+            bail!(
+                Internal,
+                msg(
+                    "expected {} bytes of blocks; got {}",
+                    self.blocks_len,
+                    v.len()
+                ),
+            );
+        }
+        Ok(v)
+    }
+}
+
+/// Divides frames into `Cluster`s as they're pushed in presentation order.
+#[derive(Default)]
+struct Clusters(Vec<Cluster>);
+
+impl Clusters {
+    /// Pushes a frame, starting a new cluster if necessary. `pts_90k` is relative to the start of
+    /// the file; `pos` is relative to the start of the segment's sample file.
+    fn push(
+        &mut self,
+        segment: usize,
+        pts_90k: u64,
+        pos: u64,
+        bytes: u32,
+        is_key: bool,
+    ) -> Result<(), Error> {
+        let timestamp = pts_90k / UNITS_PER_TICK;
+        let need_new = match self.0.last() {
+            None => true,
+            Some(c) => {
+                is_key
+                    || c.segment != segment
+                    || c.sample_file_range.end != pos
+                    || timestamp - c.timestamp > i16::MAX as u64
+            }
+        };
+        if need_new {
+            self.0.push(Cluster {
+                segment,
+                timestamp,
+                sample_file_range: pos..pos,
+                frames: Vec::new(),
+                blocks_len: 0,
+            });
+        }
+        let c = self.0.last_mut().expect("cluster was just pushed");
+        let rel_timestamp = i16::try_from(timestamp - c.timestamp)
+            .map_err(|_| err!(Internal, msg("relative timestamp out of range")))?;
+        c.frames.push(Frame {
+            rel_timestamp,
+            bytes,
+            is_key,
+        });
+        c.sample_file_range.end += u64::from(bytes);
+        c.blocks_len += simple_block_len(bytes);
+        Ok(())
+    }
+}
+
+/// The `.mkv`-specific state of a `File`.
+pub struct Matroska {
+    clusters: Vec<Cluster>,
+
+    /// Everything but the `SimpleBlock`s.
+    buf: Vec<u8>,
+}
+
+impl remux::Format for Matroska {
+    const NAME: &'static str = "mkv";
+    const CONTENT_TYPE: &'static str = "video/x-matroska";
+    const FORMAT_VERSION: [u8; 1] = FORMAT_VERSION;
+    type Slice = Slice;
+}
+
+pub type FileBuilder = remux::FileBuilder<Matroska>;
+pub type File = remux::File<Matroska>;
+
+impl FileBuilder {
+    /// Builds the `File`, consuming the builder.
+    pub fn build(
+        self,
+        db: Arc<db::Database>,
+        dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+    ) -> Result<File, Error> {
+        let tracks = tracks_element(self.video_sample_entry()?)?;
+        let mut clusters = Clusters::default();
+        let layout = self.lay_out(&db, |i, pts_90k, pos, bytes, is_key| {
+            clusters.push(i, pts_90k, pos, bytes, is_key)
+        })?;
+        let clusters = clusters.0;
+        let info = info_element(layout.duration_90k, layout.start);
+
+        // The SeekHead's length doesn't depend on the positions it holds, so lay it out once
+        // with placeholders to learn where everything else goes.
+        let seek_head_len = seek_head_element(0, 0, 0).len() as u64;
+        let info_pos = seek_head_len;
+        let tracks_pos = info_pos + info.len() as u64;
+        let mut pos = tracks_pos + tracks.len() as u64;
+        let mut cluster_headers = Vec::with_capacity(clusters.len());
+        let mut cues = Vec::new();
+        for c in &clusters {
+            let mut header = Vec::new();
+            c.put_header(&mut header);
+            if c.frames.first().map(|f| f.is_key).unwrap_or(false) {
+                put_master(&mut cues, ID_CUE_POINT, |b| {
+                    put_uint(b, ID_CUE_TIME, c.timestamp);
+                    put_master(b, ID_CUE_TRACK_POSITIONS, |b| {
+                        put_uint(b, ID_CUE_TRACK, u64::from(VIDEO_TRACK_NUMBER));
+                        put_uint(b, ID_CUE_CLUSTER_POSITION, pos);
+                    });
+                });
+            }
+            pos += header.len() as u64 + c.blocks_len;
+            cluster_headers.push(header);
+        }
+        let cues_pos = pos;
+        let mut cues_element = Vec::new();
+        put_bytes(&mut cues_element, ID_CUES, &cues);
+        let segment_len = cues_pos + cues_element.len() as u64;
+
+        // Assemble the buffer and slices.
+        let mut buf = Vec::new();
+        put_master(&mut buf, ID_EBML, |b| {
+            put_uint(b, ID_EBML_VERSION, 1);
+            put_uint(b, ID_EBML_READ_VERSION, 1);
+            put_uint(b, ID_EBML_MAX_ID_LENGTH, 4);
+            put_uint(b, ID_EBML_MAX_SIZE_LENGTH, 8);
+            put_bytes(b, ID_DOC_TYPE, b"matroska");
+            put_uint(b, ID_DOC_TYPE_VERSION, 4);
+            put_uint(b, ID_DOC_TYPE_READ_VERSION, 2);
+        });
+        put_id(&mut buf, ID_SEGMENT);
+        buf.push(0x01); // 8-byte size.
+        buf.extend_from_slice(&segment_len.to_be_bytes()[1..]);
+        buf.extend_from_slice(&seek_head_element(info_pos, tracks_pos, cues_pos));
+        buf.extend_from_slice(&info);
+        buf.extend_from_slice(&tracks);
+        let mut slices = Slices::new();
+        slices.reserve(2 * clusters.len() + 2);
+        slices.append(Slice {
+            end: buf.len() as u64,
+            t: SliceType::Buf(0),
+        })?;
+        for (i, header) in cluster_headers.iter().enumerate() {
+            let header_start = buf.len();
+            buf.extend_from_slice(header);
+            slices.append(Slice {
+                end: slices.len() + header.len() as u64,
+                t: SliceType::Buf(header_start),
+            })?;
+            slices.append(Slice {
+                end: slices.len() + clusters[i].blocks_len,
+                t: SliceType::Blocks(i),
+            })?;
+        }
+        let cues_start = buf.len();
+        buf.extend_from_slice(&cues_element);
+        slices.append(Slice {
+            end: slices.len() + cues_element.len() as u64,
+            t: SliceType::Buf(cues_start),
+        })?;
+        trace!("slices: {:?}", slices);
+        Ok(self.into_file(layout, dirs_by_id, slices, Matroska { clusters, buf }))
+    }
+}
+
+/// Returns a `SeekHead` element pointing to the given positions.
+fn seek_head_element(info_pos: u64, tracks_pos: u64, cues_pos: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_master(&mut buf, ID_SEEK_HEAD, |b| {
+        for (id, pos) in [
+            (ID_INFO, info_pos),
+            (ID_TRACKS, tracks_pos),
+            (ID_CUES, cues_pos),
+        ] {
+            put_master(b, ID_SEEK, |b| {
+                let mut id_bytes = Vec::new();
+                put_id(&mut id_bytes, id);
+                put_bytes(b, ID_SEEK_ID, &id_bytes);
+                put_uint_fixed(b, ID_SEEK_POSITION, pos);
+            });
+        }
+    });
+    buf
+}
+
+/// Returns an `Info` element.
+fn info_element(duration_90k: u64, date: Option<recording::Time>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_master(&mut buf, ID_INFO, |b| {
+        put_uint(b, ID_TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        put_bytes(
+            b,
+            ID_DURATION,
+            &(duration_90k as f64 / UNITS_PER_TICK as f64).to_be_bytes(),
+        );
+        if let Some(d) = date {
+            put_bytes(b, ID_DATE_UTC, &to_date_utc(d).to_be_bytes());
+        }
+        put_bytes(b, ID_MUXING_APP, b"moonfire-nvr");
+        put_bytes(b, ID_WRITING_APP, b"moonfire-nvr");
+    });
+    buf
+}
+
+/// Returns a `Tracks` element describing the video track.
+fn tracks_element(e: &db::VideoSampleEntry) -> Result<Vec<u8>, Error> {
+    let codec_id: &[u8] = match e.box_type() {
+        b"avc1" => b"V_MPEG4/ISO/AVC",
+        b"hvc1" => b"V_MPEGH/ISO/HEVC",
+        b"jpeg" => b"V_MJPEG",
+        t => bail!(
+            Unimplemented,
+            msg(
+                "unsupported video sample entry type {:?} for .mkv",
+                String::from_utf8_lossy(t)
+            ),
+        ),
+    };
+
+    // The decoder configuration record, if any, is the CodecPrivate.
+    let codec_private = match e.box_type() {
+        b"jpeg" => &[][..],
+        _ => e.decoder_config()?,
+    };
+
+    let aspect = e.aspect();
+    let mut buf = Vec::new();
+    put_master(&mut buf, ID_TRACKS, |b| {
+        put_master(b, ID_TRACK_ENTRY, |b| {
+            put_uint(b, ID_TRACK_NUMBER, u64::from(VIDEO_TRACK_NUMBER));
+            put_uint(b, ID_TRACK_UID, u64::from(VIDEO_TRACK_NUMBER));
+            put_uint(b, ID_TRACK_TYPE, 1); // video
+            put_uint(b, ID_FLAG_LACING, 0);
+            put_bytes(b, ID_CODEC_ID, codec_id);
+            if !codec_private.is_empty() {
+                put_bytes(b, ID_CODEC_PRIVATE, codec_private);
+            }
+            put_master(b, ID_VIDEO, |b| {
+                put_uint(b, ID_PIXEL_WIDTH, u64::from(e.width));
+                put_uint(b, ID_PIXEL_HEIGHT, u64::from(e.height));
+                if e.pasp_h_spacing != e.pasp_v_spacing {
+                    put_uint(b, ID_DISPLAY_WIDTH, u64::from(*aspect.numer()));
+                    put_uint(b, ID_DISPLAY_HEIGHT, u64::from(*aspect.denom()));
+                    put_uint(b, ID_DISPLAY_UNIT, 3); // display aspect ratio
+                }
+            });
+        });
+    });
+    Ok(buf)
+}
+
+/// A single slice of a `File`, for use with a `Slices` object.
+pub struct Slice {
+    end: u64,
+    t: SliceType,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum SliceType {
+    /// Bytes from `Matroska::buf`, starting at the given position.
+    Buf(usize),
+
+    /// The `SimpleBlock`s of the given index into `Matroska::clusters`.
+    Blocks(usize),
+}
+
+impl slices::Slice for Slice {
+    type Ctx = File;
+    type Chunk = Chunk;
+
+    fn end(&self) -> u64 {
+        self.end
+    }
+
+    fn get_range(
+        &self,
+        f: &File,
+        range: Range<u64>,
+        len: u64,
+    ) -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Send + Sync> {
+        trace!("getting mkv slice {:?}'s range {:?} / {}", self, range, len);
+        match self.t {
+            SliceType::Buf(p) => {
+                let chunk =
+                    f.format_chunk(|m| &m.buf[p + range.start as usize..p + range.end as usize]);
+                Box::new(stream::once(futures::future::ok(chunk)))
+            }
+            SliceType::Blocks(i) => {
+                let c = &f.format().clusters[i];
+                f.get_group(
+                    c.segment,
+                    c.sample_file_range.clone(),
+                    range,
+                    move |m, data| m.clusters[i].blocks(data),
+                )
+            }
+        }
+    }
+
+    fn get_slices(ctx: &File) -> &Slices<Self> {
+        ctx.slices()
+    }
+}
+
+impl fmt::Debug for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // Omit end; Slices writes that part.
+        write!(f, "{:?}", self.t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn sizes() {
+        testutil::init();
+        let mut buf = Vec::new();
+        put_size(&mut buf, 0);
+        put_size(&mut buf, 126);
+        put_size(&mut buf, 127);
+        put_size(&mut buf, 0x3FFE);
+        put_size(&mut buf, 0x3FFF);
+        assert_eq!(
+            &buf[..],
+            &[0x80, 0xFE, 0x40, 0x7F, 0x7F, 0xFE, 0x20, 0x3F, 0xFF]
+        );
+    }
+
+    #[test]
+    fn elements() {
+        testutil::init();
+        let mut buf = Vec::new();
+        put_uint(&mut buf, ID_TRACK_NUMBER, 1);
+        put_uint(&mut buf, ID_TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        put_uint(&mut buf, ID_CUE_TIME, 0);
+        put_master(&mut buf, ID_VIDEO, |b| put_uint(b, ID_PIXEL_WIDTH, 1920));
+        #[rustfmt::skip]
+        assert_eq!(
+            &buf[..],
+            &[
+                0xD7, 0x81, 0x01,
+                0x2A, 0xD7, 0xB1, 0x83, 0x0F, 0x42, 0x40,
+                0xB3, 0x81, 0x00,
+                0xE0, 0x84, 0xB0, 0x82, 0x07, 0x80,
+            ]
+        );
+    }
+
+    #[test]
+    fn date_utc() {
+        testutil::init();
+        let t = recording::Time(MATROSKA_EPOCH_UNIX_SECS * recording::TIME_UNITS_PER_SEC);
+        assert_eq!(to_date_utc(t), 0);
+        assert_eq!(to_date_utc(t + recording::Duration(45_000)), 500_000_000);
+        assert_eq!(to_date_utc(t + recording::Duration(-1)), -11_112);
+    }
+
+    #[test]
+    fn clusters() {
+        testutil::init();
+        let mut c = Clusters::default();
+        c.push(0, 0, 0, 10, true).unwrap();
+        c.push(0, 3000, 10, 5, false).unwrap();
+        c.push(0, 6000, 15, 5, true).unwrap(); // new key frame.
+        c.push(1, 9000, 0, 10, true).unwrap(); // new segment.
+        c.push(1, 9000 + 90 * 40_000, 10, 5, false).unwrap(); // too far from cluster start.
+        let c = c.0;
+        assert_eq!(c.len(), 4);
+        assert_eq!(c[0].timestamp, 0);
+        assert_eq!(c[0].sample_file_range, 0..15);
+        assert_eq!(c[0].frames[1].rel_timestamp, 33);
+        assert_eq!(c[0].blocks_len, 2 + 4 + 10 + 2 + 4 + 5);
+        assert_eq!(c[1].timestamp, 66);
+        assert_eq!(c[1].sample_file_range, 15..20);
+        assert_eq!(c[2].segment, 1);
+        assert_eq!(c[2].sample_file_range, 0..10);
+        assert_eq!(c[3].timestamp, 100 + 40_000);
+        assert!(!c[3].frames[0].is_key);
+
+        let mut header = Vec::new();
+        c[1].put_header(&mut header);
+        assert_eq!(&header[..], &[0x1F, 0x43, 0xB6, 0x75, 0x8E, 0xE7, 0x81, 66]);
+        let blocks = c[1].blocks(b"abcde").unwrap();
+        assert_eq!(
+            &blocks[..],
+            &[0xA3, 0x89, 0x81, 0x00, 0x00, 0x80, b'a', b'b', b'c', b'd', b'e']
+        );
+        c[1].blocks(b"abcd").unwrap_err();
+    }
+}
@@ -0,0 +1,417 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Built-in motion detection by frame differencing.
+//!
+//! For each stream with a [`db::json::MotionConfig`], an [`Analyzer`] decodes frames on a
+//! dedicated thread, scales each to a small grayscale image, and compares it to the previous
+//! one. While there's motion, the configured signal is set to the motion state, extending a few
+//! seconds past the last frame with motion.
+//!
+//! Decoding uses FFmpeg's libavcodec, so this is available only when built with the `ffmpeg`
+//! feature; otherwise [`Analyzer::start`] returns an `Unimplemented` error.
+
+#![cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+
+use std::ops::Range;
+
+use base::Error;
+use bytes::Bytes;
+use db::json::{MotionConfig, MotionRegion};
+use db::recording::{self, TIME_UNITS_PER_SEC};
+
+use crate::signal_hold::Holder;
+
+/// The width of the grayscale image compared between frames.
+const WIDTH: usize = 64;
+
+/// The height of the grayscale image compared between frames.
+const HEIGHT: usize = 48;
+
+/// How long the motion state lasts after the last frame with motion.
+const HOLD: recording::Duration = recording::Duration(5 * TIME_UNITS_PER_SEC);
+
+/// The minimum extension of the motion state worth a database update.
+const MIN_EXTENSION: recording::Duration = recording::Duration(TIME_UNITS_PER_SEC);
+
+/// The number of frames which may be waiting for analysis before frames are dropped.
+const QUEUE_LEN: usize = 32;
+
+/// Decides if there's motion between successive `WIDTH`x`HEIGHT` grayscale images.
+struct Detector {
+    /// Whether each pixel (in row-major order) is within a configured region.
+    mask: Vec<bool>,
+
+    /// The minimum difference in a pixel's luma to consider it changed.
+    pixel_threshold: u8,
+
+    /// The minimum number of changed pixels within the mask to consider it motion.
+    min_changed: usize,
+
+    prev: Option<Vec<u8>>,
+}
+
+impl Detector {
+    fn new(config: &MotionConfig) -> Self {
+        let mask = mask(&config.regions);
+        let masked = mask.iter().filter(|&&m| m).count();
+
+        // At sensitivity 100, a change of 8 luma levels in 0.1% of examined pixels is motion.
+        // At sensitivity 1, it takes a change of 64 levels in 10% of examined pixels.
+        let insensitivity = 100 - usize::from(config.sensitivity.clamp(1, 100));
+        Detector {
+            mask,
+            pixel_threshold: u8::try_from(8 + insensitivity * 56 / 99).unwrap(),
+            min_changed: std::cmp::max(1, masked * (1 + insensitivity) / 1000),
+            prev: None,
+        }
+    }
+
+    /// Examines the next image, returning true if it differs enough from the previous one.
+    fn observe(&mut self, luma: Vec<u8>) -> bool {
+        assert_eq!(luma.len(), WIDTH * HEIGHT);
+        let motion = match self.prev {
+            None => false,
+            Some(ref prev) => {
+                let changed = prev
+                    .iter()
+                    .zip(luma.iter())
+                    .zip(self.mask.iter())
+                    .filter(|&((&p, &c), &m)| m && p.abs_diff(c) >= self.pixel_threshold)
+                    .count();
+                changed >= self.min_changed
+            }
+        };
+        self.prev = Some(luma);
+        motion
+    }
+}
+
+/// Returns a mask of the pixels whose centers are within any of the given regions, or all
+/// pixels if there are no regions.
+fn mask(regions: &[MotionRegion]) -> Vec<bool> {
+    if regions.is_empty() {
+        return vec![true; WIDTH * HEIGHT];
+    }
+
+    // Compare in units of 1/200th of a pixel, so that pixel centers and percentages are both
+    // integers.
+    let within = |pixel: usize, size: usize, start: u8, len: u8| {
+        let center = (2 * pixel + 1) * 100;
+        let start = 2 * size * usize::from(start);
+        let end = start + 2 * size * usize::from(len);
+        (start..end).contains(&center)
+    };
+    let mut mask = Vec::with_capacity(WIDTH * HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            mask.push(
+                regions.iter().any(|r| {
+                    within(x, WIDTH, r.left, r.width) && within(y, HEIGHT, r.top, r.height)
+                }),
+            );
+        }
+    }
+    mask
+}
+
+/// Turns per-frame decisions (such as whether there's motion) into ranges over which to set a
+/// signal's state. The state lasts for [`HOLD`] after the last frame in which it was seen.
+pub(crate) struct Tracker(Holder<()>);
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker(Holder::with_durations(HOLD, MIN_EXTENSION))
+    }
+}
+
+impl Tracker {
+    /// Notes whether the state was seen in the frame at `when`, returning a range to update if
+    /// appropriate.
+    ///
+    /// A continuing state extends the previous range, but only once it's worth
+    /// [`MIN_EXTENSION`], to limit database updates.
+    pub(crate) fn observe(
+        &mut self,
+        when: recording::Time,
+        seen: bool,
+    ) -> Option<Range<recording::Time>> {
+        if !seen {
+            return None;
+        }
+        self.0.set(when, when, ())
+    }
+}
+
+/// A frame queued for analysis.
+struct Frame {
+    when: recording::Time,
+    data: Bytes,
+    is_key: bool,
+    video_sample_entry_id: i32,
+}
+
+/// Runs motion detection on a stream's frames in the background.
+///
+/// The analysis thread stops when the `Analyzer` is dropped.
+pub struct Analyzer {
+    tx: std::sync::mpsc::SyncSender<Frame>,
+
+    /// True if frames are being dropped until the next key frame.
+    dropping: bool,
+
+    short_name: String,
+}
+
+impl Analyzer {
+    /// Starts analysis for the given stream.
+    ///
+    /// This doesn't lock the database, so it may be called while the caller holds the lock.
+    #[cfg(feature = "ffmpeg")]
+    pub fn start<C: base::clock::Clocks + Clone>(
+        db: std::sync::Arc<db::Database<C>>,
+        short_name: String,
+        config: MotionConfig,
+    ) -> Result<Self, Error> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_LEN);
+        let thread_name = short_name.clone();
+        std::thread::Builder::new()
+            .name(format!("m-{short_name}"))
+            .spawn(move || ffmpeg::run(&db, &thread_name, &config, rx))
+            .map_err(|e| base::err!(e, msg("unable to start motion thread")))?;
+        Ok(Analyzer {
+            tx,
+            dropping: false,
+            short_name,
+        })
+    }
+
+    #[cfg(not(feature = "ffmpeg"))]
+    pub fn start<C: base::clock::Clocks + Clone>(
+        _db: std::sync::Arc<db::Database<C>>,
+        _short_name: String,
+        _config: MotionConfig,
+    ) -> Result<Self, Error> {
+        base::bail!(
+            Unimplemented,
+            msg("motion detection requires building Moonfire NVR with --features=ffmpeg")
+        );
+    }
+
+    /// Queues a frame (in the length-prefixed form stored in sample files) for analysis.
+    ///
+    /// This never blocks. If analysis is falling behind, frames are dropped until the next
+    /// key frame, as later frames can't be decoded without the dropped ones.
+    pub fn send(
+        &mut self,
+        when: recording::Time,
+        data: &Bytes,
+        is_key: bool,
+        video_sample_entry_id: i32,
+    ) {
+        if self.dropping && !is_key {
+            return;
+        }
+        let frame = Frame {
+            when,
+            data: data.clone(),
+            is_key,
+            video_sample_entry_id,
+        };
+        match self.tx.try_send(frame) {
+            Ok(()) => self.dropping = false,
+            Err(std::sync::mpsc::TrySendError::Full(_)) => {
+                if !self.dropping {
+                    tracing::warn!(
+                        "{}: motion detection is falling behind; dropping frames",
+                        self.short_name
+                    );
+                }
+                self.dropping = true;
+            }
+
+            // The analysis thread has already logged why it exited.
+            Err(std::sync::mpsc::TrySendError::Disconnected(_)) => self.dropping = true,
+        }
+    }
+}
+
+#[cfg(feature = "ffmpeg")]
+mod ffmpeg {
+    use super::{Detector, Frame, Tracker, HEIGHT, WIDTH};
+    use base::{clock::Clocks, err, Error};
+    use db::json::MotionConfig;
+    use ffmpeg_next::{decoder, util::frame::video::Video, Packet};
+    use tracing::{info, warn};
+
+    /// Analyzes frames from `rx` until the `Analyzer` is dropped.
+    pub(super) fn run<C: Clocks + Clone>(
+        db: &db::Database<C>,
+        short_name: &str,
+        config: &MotionConfig,
+        rx: std::sync::mpsc::Receiver<Frame>,
+    ) {
+        info!("{short_name}: starting motion detection");
+        let mut detector = Detector::new(config);
+        let mut tracker = Tracker::default();
+        let mut decoder: Option<(i32, decoder::Video)> = None;
+        let mut update_failed = false;
+        while let Ok(frame) = rx.recv() {
+            let luma = match decode(db, &mut decoder, &frame) {
+                Ok(Some(l)) => l,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!(%err, "{short_name}: unable to decode frame for motion detection");
+                    decoder = None;
+                    continue;
+                }
+            };
+            let Some(range) = tracker.observe(frame.when, detector.observe(luma)) else {
+                continue;
+            };
+            match db
+                .lock()
+                .update_signals(range, &[config.signal_id], &[config.motion_state])
+            {
+                Ok(()) => update_failed = false,
+                Err(err) if !update_failed => {
+                    warn!(%err, "{short_name}: unable to update motion signal");
+                    update_failed = true;
+                }
+                Err(_) => {}
+            }
+        }
+        info!("{short_name}: ending motion detection");
+    }
+
+    /// Decodes the given frame, returning its luma scaled to `WIDTH`x`HEIGHT` if the decoder
+    /// produced a picture.
+    ///
+    /// Opens a new decoder when the video sample entry changes. Until a decoder is open, frames
+    /// other than key frames are skipped.
+    fn decode<C: Clocks + Clone>(
+        db: &db::Database<C>,
+        decoder: &mut Option<(i32, decoder::Video)>,
+        frame: &Frame,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if decoder.as_ref().map(|(id, _)| *id) != Some(frame.video_sample_entry_id) {
+            *decoder = None;
+            if !frame.is_key {
+                return Ok(None);
+            }
+            let entry = db
+                .lock()
+                .video_sample_entries_by_id()
+                .get(&frame.video_sample_entry_id)
+                .cloned()
+                .ok_or_else(|| err!(Internal, msg("no such video sample entry")))?;
+            *decoder = Some((
+                frame.video_sample_entry_id,
+                crate::jpeg::ffmpeg::open_decoder(&entry)?,
+            ));
+        }
+        let (_, d) = decoder.as_mut().expect("decoder was just opened");
+        d.send_packet(&Packet::copy(&frame.data))
+            .map_err(|e| err!(InvalidArgument, msg("unable to decode frame"), source(e)))?;
+
+        // With reordering, a packet may yield no picture or several; keep only the latest.
+        let mut latest = None;
+        loop {
+            let mut decoded = Video::empty();
+            match d.receive_frame(&mut decoded) {
+                Ok(()) => latest = Some(decoded),
+                Err(ffmpeg_next::Error::Other {
+                    errno: ffmpeg_next::util::error::EAGAIN,
+                }) => break,
+                Err(e) => return Err(err!(InvalidArgument, msg("frame didn't decode"), source(e))),
+            }
+        }
+        let Some(decoded) = latest else {
+            return Ok(None);
+        };
+        let yuv = crate::jpeg::ffmpeg::scale(&decoded, WIDTH as u32, HEIGHT as u32)?;
+        let stride = yuv.stride(0);
+        let mut luma = Vec::with_capacity(WIDTH * HEIGHT);
+        for row in 0..HEIGHT {
+            luma.extend_from_slice(&yuv.data(0)[row * stride..][..WIDTH]);
+        }
+        Ok(Some(luma))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detector, Tracker, HEIGHT, HOLD, WIDTH};
+    use db::json::{MotionConfig, MotionRegion};
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil;
+
+    /// Returns a uniform gray image with the given rectangle (in pixels) brightened.
+    fn image(x: std::ops::Range<usize>, y: std::ops::Range<usize>) -> Vec<u8> {
+        let mut luma = vec![100; WIDTH * HEIGHT];
+        for row in y {
+            luma[row * WIDTH..][x.clone()].fill(200);
+        }
+        luma
+    }
+
+    #[test]
+    fn detector() {
+        testutil::init();
+        let mut d = Detector::new(&MotionConfig::new(1));
+        assert!(!d.observe(image(0..0, 0..0))); // no previous image.
+        assert!(!d.observe(image(0..0, 0..0))); // no change.
+        assert!(d.observe(image(0..16, 0..16))); // a quarter-width square appears.
+        assert!(!d.observe(image(0..16, 0..16))); // ...and stays still.
+        assert!(!d.observe(image(0..16, 0..17))); // one row changes: 16 pixels of 3,072 (0.5%).
+
+        // At maximum sensitivity, that small change is motion.
+        let mut config = MotionConfig::new(1);
+        config.sensitivity = 100;
+        let mut d = Detector::new(&config);
+        d.observe(image(0..16, 0..16));
+        assert!(d.observe(image(0..16, 0..17)));
+    }
+
+    #[test]
+    fn detector_regions() {
+        testutil::init();
+        let mut config = MotionConfig::new(1);
+
+        // The right half of the frame.
+        config.regions.push(MotionRegion {
+            left: 50,
+            top: 0,
+            width: 50,
+            height: 100,
+        });
+        let mut d = Detector::new(&config);
+        assert_eq!(d.mask.iter().filter(|&&m| m).count(), WIDTH * HEIGHT / 2);
+        assert!(!d.mask[WIDTH / 2 - 1]);
+        assert!(d.mask[WIDTH / 2]);
+        d.observe(image(0..0, 0..0));
+        assert!(!d.observe(image(0..WIDTH / 2, 0..HEIGHT))); // the left half changes.
+        assert!(d.observe(image(0..WIDTH, 0..HEIGHT))); // the right half changes.
+    }
+
+    #[test]
+    fn tracker() {
+        testutil::init();
+        let mut t = Tracker::default();
+        let sec = |s: i64| recording::Time(s * TIME_UNITS_PER_SEC);
+        assert_eq!(t.observe(sec(0), false), None);
+        assert_eq!(t.observe(sec(1), true), Some(sec(1)..sec(1) + HOLD));
+
+        // Continuing motion isn't worth an update until it extends the range by a second.
+        assert_eq!(
+            t.observe(sec(1) + recording::Duration(TIME_UNITS_PER_SEC / 2), true),
+            None
+        );
+        assert_eq!(t.observe(sec(2), true), Some(sec(1) + HOLD..sec(2) + HOLD));
+        assert_eq!(t.observe(sec(3), false), None);
+
+        // Motion after the range has ended starts a new one.
+        assert_eq!(t.observe(sec(20), true), Some(sec(20)..sec(20) + HOLD));
+    }
+}
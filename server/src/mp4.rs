@@ -51,6 +51,23 @@
 //! ***** stsz (samples sizes (framing))
 //! ***** co64 (64-bit chunk offset)
 //!
+//! ** (optional) trak (audio: container for an individual track or stream)
+//! *** tkhd (track header, overall information about the track)
+//! *** (optional) edts (edit list container)
+//! **** elst (an edit list)
+//! *** mdia (container for the media information in a track)
+//! **** mdhd (media header, overall information about the media)
+//! *** minf (media information container)
+//! **** smhd (sound media header, overall information (sound track only))
+//! **** dinf (data information box, container)
+//! ***** dref (data reference box, declares source(s) of media data in track)
+//! **** stbl (sample table box, container for the time/space map)
+//! ***** stsd (sample descriptions (codec types, initilization etc.)
+//! ***** stts ((decoding) time-to-sample)
+//! ***** stsc (sample-to-chunk, partial data-offset information)
+//! ***** stsz (samples sizes (framing))
+//! ***** co64 (64-bit chunk offset)
+//!
 //! * mdat (media data container)
 //! ```
 
@@ -61,7 +78,7 @@ use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use bytes::BytesMut;
 use db::dir;
 use db::recording::{self, rescale, TIME_UNITS_PER_SEC};
-use futures::stream::{self, TryStreamExt};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use futures::Stream;
 use http::header::HeaderValue;
 use hyper::body::Buf;
@@ -74,8 +91,9 @@ use std::fmt;
 use std::io;
 use std::mem;
 use std::ops::Range;
-use std::sync::Arc;
+use std::pin::Pin;
 use std::sync::Once;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use tracing::{debug, error, trace, warn};
 
@@ -96,6 +114,19 @@ const NORMAL_FTYP_BOX: &[u8] = &[
     b'm', b'p', b'4', b'1', // compatible_brands[3]
 ];
 
+/// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box for H.265 video.
+/// This is the same as `NORMAL_FTYP_BOX` but with `hvc1` in place of `avc1`.
+const HEVC_FTYP_BOX: &[u8] = &[
+    0x00, 0x00, 0x00, 0x20, // length = 32, sizeof(HEVC_FTYP_BOX)
+    b'f', b't', b'y', b'p', // type
+    b'i', b's', b'o', b'm', // major_brand
+    0x00, 0x00, 0x00, 0x00, // minor_version
+    b'i', b's', b'o', b'm', // compatible_brands[0]
+    b'i', b's', b'o', b'2', // compatible_brands[1]
+    b'h', b'v', b'c', b'1', // compatible_brands[2]
+    b'm', b'p', b'4', b'1', // compatible_brands[3]
+];
+
 /// An `ftyp` (ISO/IEC 14496-12 section 4.3 `FileType`) box for an initialization segment.
 /// More restrictive brands because of the default-base-is-moof flag.
 /// Eg ISO/IEC 14496-12 section A.2 says "NOTE The default‐base‐is‐moof flag
@@ -136,6 +167,19 @@ const SUBTITLE_HDLR_BOX: &[u8] = &[
     0x00, // name, zero-terminated (empty)
 ];
 
+/// An `hdlr` (ISO/IEC 14496-12 section 8.4.3 `HandlerBox`) box suitable for audio.
+const AUDIO_HDLR_BOX: &[u8] = &[
+    0x00, 0x00, 0x00, 0x21, // length == sizeof(kHdlrBox)
+    b'h', b'd', b'l', b'r', // type == hdlr, ISO/IEC 14496-12 section 8.4.3.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // pre_defined
+    b's', b'o', b'u', b'n', // handler = soun
+    0x00, 0x00, 0x00, 0x00, // reserved[0]
+    0x00, 0x00, 0x00, 0x00, // reserved[1]
+    0x00, 0x00, 0x00, 0x00, // reserved[2]
+    0x00, // name, zero-terminated (empty)
+];
+
 /// Part of an `mvhd` (`MovieHeaderBox` version 0, ISO/IEC 14496-12 section 8.2.2), used from
 /// `append_mvhd`.
 const MVHD_JUNK: &[u8] = &[
@@ -179,6 +223,24 @@ const TKHD_JUNK: &[u8] = &[
     0x40, 0x00, 0x00, 0x00, // matrix[8]
 ];
 
+/// Part of a `tkhd` (`TrackHeaderBox` version 0, ISO/IEC 14496-12 section 8.3.2), used from
+/// `append_audio_tkhd`. This is the same as `TKHD_JUNK` but with full volume.
+const AUDIO_TKHD_JUNK: &[u8] = &[
+    0x00, 0x00, 0x00, 0x00, // reserved
+    0x00, 0x00, 0x00, 0x00, // reserved
+    0x00, 0x00, 0x00, 0x00, // layer + alternate_group
+    0x01, 0x00, 0x00, 0x00, // volume + reserved
+    0x00, 0x01, 0x00, 0x00, // matrix[0]
+    0x00, 0x00, 0x00, 0x00, // matrix[1]
+    0x00, 0x00, 0x00, 0x00, // matrix[2]
+    0x00, 0x00, 0x00, 0x00, // matrix[3]
+    0x00, 0x01, 0x00, 0x00, // matrix[4]
+    0x00, 0x00, 0x00, 0x00, // matrix[5]
+    0x00, 0x00, 0x00, 0x00, // matrix[6]
+    0x00, 0x00, 0x00, 0x00, // matrix[7]
+    0x40, 0x00, 0x00, 0x00, // matrix[8]
+];
+
 /// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
 /// `append_video_minf`.
 const VIDEO_MINF_JUNK: &[u8] = &[
@@ -224,6 +286,28 @@ const SUBTITLE_MINF_JUNK: &[u8] = &[
     0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
 ];
 
+/// Part of a `minf` (`MediaInformationBox`, ISO/IEC 14496-12 section 8.4.4), used from
+/// `append_audio_minf`.
+const AUDIO_MINF_JUNK: &[u8] = &[
+    b'm', b'i', b'n', b'f', // type = minf, ISO/IEC 14496-12 section 8.4.4.
+    // A smhd box.
+    0x00, 0x00, 0x00, 0x10, // length == sizeof(kSmhdBox)
+    b's', b'm', b'h', b'd', // type = smhd, ISO/IEC 14496-12 section 12.2.2.
+    0x00, 0x00, 0x00, 0x00, // version + flags
+    0x00, 0x00, 0x00, 0x00, // balance + reserved
+    // A dinf box suitable for a "self-contained" .mp4 file (no URL/URN
+    // references to external data).
+    0x00, 0x00, 0x00, 0x24, // length == sizeof(kDinfBox)
+    b'd', b'i', b'n', b'f', // type = dinf, ISO/IEC 14496-12 section 8.7.1.
+    0x00, 0x00, 0x00, 0x1c, // length
+    b'd', b'r', b'e', b'f', // type = dref, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x00, // version and flags
+    0x00, 0x00, 0x00, 0x01, // entry_count
+    0x00, 0x00, 0x00, 0x0c, // length
+    b'u', b'r', b'l', b' ', // type = url, ISO/IEC 14496-12 section 8.7.2.
+    0x00, 0x00, 0x00, 0x01, // version=0, flags=self-contained
+];
+
 /// Part of a `stbl` (`SampleTableBox`, ISO/IEC 14496 section 8.5.1) used from
 /// `append_subtitle_stbl`.
 #[rustfmt::skip]
@@ -267,15 +351,19 @@ const SUBTITLE_STBL_JUNK: &[u8] = &[
 
 /// Pointers to each static bytestrings.
 /// The order here must match the `StaticBytestring` enum.
-const STATIC_BYTESTRINGS: [&[u8]; 9] = [
+const STATIC_BYTESTRINGS: [&[u8]; 13] = [
     NORMAL_FTYP_BOX,
+    HEVC_FTYP_BOX,
     INIT_SEGMENT_FTYP_BOX,
     VIDEO_HDLR_BOX,
     SUBTITLE_HDLR_BOX,
+    AUDIO_HDLR_BOX,
     MVHD_JUNK,
     TKHD_JUNK,
+    AUDIO_TKHD_JUNK,
     VIDEO_MINF_JUNK,
     SUBTITLE_MINF_JUNK,
+    AUDIO_MINF_JUNK,
     SUBTITLE_STBL_JUNK,
 ];
 
@@ -285,13 +373,17 @@ const STATIC_BYTESTRINGS: [&[u8]; 9] = [
 #[derive(Copy, Clone, Debug)]
 enum StaticBytestring {
     NormalFtypBox,
+    HevcFtypBox,
     InitSegmentFtypBox,
     VideoHdlrBox,
     SubtitleHdlrBox,
+    AudioHdlrBox,
     MvhdJunk,
     TkhdJunk,
+    AudioTkhdJunk,
     VideoMinfJunk,
     SubtitleMinfJunk,
+    AudioMinfJunk,
     SubtitleStblJunk,
 }
 
@@ -303,6 +395,17 @@ const SUBTITLE_TEMPLATE: &str = "%Y-%m-%d %H:%M:%S %z";
 /// The length of the output of `SUBTITLE_TEMPLATE`.
 const SUBTITLE_LENGTH: usize = 25; // "2015-07-02 17:10:00 -0700".len();
 
+/// The minimum number of segments for which `FileInner::prefetch_indexes` builds indexes ahead
+/// in parallel. Below this, building each as its slices are written is fast enough.
+const PARALLEL_INDEX_MIN_SEGMENTS: usize = 64;
+
+/// The maximum number of threads `FileInner::prefetch_indexes` uses.
+const MAX_INDEX_THREADS: usize = 8;
+
+/// How many segments' indexes `FileInner::prefetch_indexes` builds ahead of the one being
+/// written.
+const INDEX_PREFETCH_SEGMENTS: usize = 128;
+
 /// The lengths of the indexes associated with a `Segment`; for use within `Segment` only.
 struct SegmentLengths {
     stts: usize,
@@ -338,6 +441,15 @@ struct Segment {
     /// The 1-indexed frame number in the `File` of the first frame in this segment.
     first_frame_num: u32,
     num_subtitle_samples: u16,
+
+    /// The adjustment to the duration of the segment's final audio sample, in units of the audio
+    /// sample entry's sample rate. This keeps the following segment's audio aligned with its
+    /// video despite accumulated differences between the audio and video clocks.
+    audio_end_adjust: i32,
+
+    /// In timelapse mode, the duration for which to present this segment's single key frame,
+    /// in place of `rel_media_range_90k`'s length. See `FileBuilder::timelapse`.
+    timelapse_duration_90k: Option<i32>,
 }
 
 // Manually implement Debug because `index` and `index_once` are not Debug.
@@ -357,6 +469,8 @@ impl fmt::Debug for Segment {
             .field("rel_media_range_90k", &self.rel_media_range_90k)
             .field("first_frame_num", &self.first_frame_num)
             .field("num_subtitle_samples", &self.num_subtitle_samples)
+            .field("audio_end_adjust", &self.audio_end_adjust)
+            .field("timelapse_duration_90k", &self.timelapse_duration_90k)
             .finish()
     }
 }
@@ -382,6 +496,16 @@ impl Segment {
             index_once: Once::new(),
             first_frame_num,
             num_subtitle_samples: 0,
+            audio_end_adjust: 0,
+            timelapse_duration_90k: None,
+        })
+    }
+
+    /// Returns the duration of this segment as presented, after applying the edit list.
+    fn presented_duration_90k(&self) -> i32 {
+        self.timelapse_duration_90k.unwrap_or_else(|| {
+            let md = &self.rel_media_range_90k;
+            md.end - md.start
         })
     }
 
@@ -401,19 +525,31 @@ impl Segment {
         )
     }
 
-    fn get_index<'a, F>(&'a self, db: &db::Database, f: F) -> Result<&'a [u8], Error>
-    where
-        F: FnOnce(&[u8], SegmentLengths) -> &[u8],
-    {
+    /// Builds the index if it hasn't been built already.
+    ///
+    /// The database lock is held only to copy the recording's playback data, so that
+    /// `FileInner::prefetch_indexes` can build indexes on several threads at once.
+    fn prepare_index(&self, db: &db::Database) {
         self.index_once.call_once(|| {
             let index = unsafe { &mut *self.index.get() };
-            *index = db
+            let playback = db
                 .lock()
-                .with_recording_playback(self.s.id, &mut |playback| self.build_index(playback))
+                .with_recording_playback(self.s.id, &mut |playback| {
+                    Ok(playback.to_owned_playback())
+                });
+            *index = playback
+                .and_then(|playback| self.build_index(&playback.as_playback()))
                 .map_err(|err| {
                     error!(%err, recording_id = %self.s.id, "unable to build index for segment");
                 });
         });
+    }
+
+    fn get_index<'a, F>(&'a self, db: &db::Database, f: F) -> Result<&'a [u8], Error>
+    where
+        F: FnOnce(&[u8], SegmentLengths) -> &[u8],
+    {
+        self.prepare_index(db);
         let index: &'a _ = unsafe { &*self.index.get() };
         match *index {
             Ok(ref b) => Ok(f(&b[..], self.lens())),
@@ -487,7 +623,10 @@ impl Segment {
             // Doing this after the fact is more efficient than having a condition on every
             // iteration.
             if let Some((last_start, dur)) = last_start_and_dur {
-                let min = cmp::min(self.rel_media_range_90k.end - last_start, dur);
+                let min = match self.timelapse_duration_90k {
+                    Some(d) => d,
+                    None => cmp::min(self.rel_media_range_90k.end - last_start, dur),
+                };
                 BigEndian::write_u32(&mut stts[8 * frame - 4..], u32::try_from(min).unwrap());
             }
         }
@@ -495,6 +634,46 @@ impl Segment {
         Ok(buf)
     }
 
+    /// Builds the `stts` entries for this segment's audio samples, one per sample.
+    fn audio_stts(&self, playback: &db::RecordingPlayback) -> Result<Vec<u8>, Error> {
+        let a = self
+            .s
+            .audio
+            .as_ref()
+            .ok_or_else(|| err!(Internal, msg("{}: no audio", self.s.id)))?;
+        let mut v = Vec::with_capacity(2 * mem::size_of::<u32>() * a.samples as usize);
+        let mut n = 0;
+        a.foreach(playback, |it| {
+            n += 1;
+            let duration = if n == a.samples {
+                cmp::max(it.duration + self.audio_end_adjust, 1)
+            } else {
+                it.duration
+            };
+            v.write_u32::<BigEndian>(1).err_kind(ErrorKind::Internal)?; // count
+            v.write_u32::<BigEndian>(duration as u32)
+                .err_kind(ErrorKind::Internal)?;
+            Ok(())
+        })?;
+        Ok(v)
+    }
+
+    /// Builds the `stsz` entries for this segment's audio samples.
+    fn audio_stsz(&self, playback: &db::RecordingPlayback) -> Result<Vec<u8>, Error> {
+        let a = self
+            .s
+            .audio
+            .as_ref()
+            .ok_or_else(|| err!(Internal, msg("{}: no audio", self.s.id)))?;
+        let mut v = Vec::with_capacity(mem::size_of::<u32>() * a.samples as usize);
+        a.foreach(playback, |it| {
+            v.write_u32::<BigEndian>(it.bytes as u32)
+                .err_kind(ErrorKind::Internal)?;
+            Ok(())
+        })?;
+        Ok(v)
+    }
+
     fn truns_len(&self) -> usize {
         self.s.key_frames as usize * (mem::size_of::<u32>() * 6)
             + self.s.frames as usize * (mem::size_of::<u32>() * 2)
@@ -642,8 +821,18 @@ pub struct FileBuilder {
     /// appear in the video.
     segments: Vec<Segment>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+    audio_sample_entries: SmallVec<[Arc<db::AudioSampleEntry>; 1]>,
     next_frame_num: u32,
 
+    /// The audio sample entry, iff there's an audio track. Decided in `build`.
+    audio_sample_entry: Option<Arc<db::AudioSampleEntry>>,
+
+    /// The presentation time at which the audio track starts and its total duration, both in
+    /// units of the audio sample rate. Valid only if `audio_sample_entry` is set.
+    audio_start: u64,
+    audio_duration: u64,
+    audio_co64_pos: Option<usize>,
+
     /// The total media time, after applying edit lists (if applicable) to skip unwanted portions.
     media_duration_90k: u64,
     num_subtitle_samples: u32,
@@ -652,7 +841,18 @@ pub struct FileBuilder {
     type_: Type,
     prev_media_duration_and_cur_runs: Option<(recording::Duration, i32)>,
     include_timestamp_subtitle_track: bool,
+    include_base_media_decode_time: bool,
     content_disposition: Option<HeaderValue>,
+    timelapse: Option<Timelapse>,
+}
+
+/// Timelapse mode state; see `FileBuilder::timelapse`.
+struct Timelapse {
+    interval: recording::Duration,
+    frame_duration_90k: i32,
+
+    /// The wall time at or after which the next key frame should be selected.
+    next: Option<recording::Time>,
 }
 
 /// The portion of `FileBuilder` which is mutated while building the body of the file.
@@ -693,6 +893,10 @@ enum SliceType {
     VideoSampleData = 7,    // param is index into m.segments
     SubtitleSampleData = 8, // param is index into m.segments
     Truns = 9,              // param is index into m.segments
+    AudioSampleEntry = 10,  // param is unused
+    AudioStts = 11,         // param is index into m.segments
+    AudioStsz = 12,         // param is index into m.segments
+    AudioSampleData = 13,   // param is index into m.segments
 
                             // There must be no value > 15, as this is packed into 4 bits in Slice.
 }
@@ -753,6 +957,45 @@ impl Slice {
         Ok(truns.map(|t| &t[r.start as usize..r.end as usize]).into())
     }
 
+    fn wrap_audio_index(
+        &self,
+        mp4: &File,
+        r: Range<u64>,
+        len: u64,
+        f: fn(&Segment, &db::RecordingPlayback) -> Result<Vec<u8>, Error>,
+    ) -> Result<Chunk, Error> {
+        let s = &mp4.0.segments[self.p()];
+        let v = mp4
+            .0
+            .db
+            .lock()
+            .with_recording_playback(s.s.id, &mut |playback| f(s, playback))
+            .err_kind(ErrorKind::Unknown)?;
+        if u64::try_from(v.len()).unwrap() != len {
+            bail!(Internal, msg("expected len {} got {}", len, v.len()));
+        }
+        Ok(ARefss::new(v)
+            .map(|v| &v[r.start as usize..r.end as usize])
+            .into())
+    }
+
+    fn wrap_audio_sample_entry(&self, f: &File, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
+        let mp4 = ARefss::new(f.0.clone());
+        Ok(mp4
+            .try_map(|mp4| {
+                let data = &mp4
+                    .audio_sample_entry
+                    .as_ref()
+                    .ok_or_else(|| err!(Internal, msg("no audio sample entry")))?
+                    .data;
+                if u64::try_from(data.len()).unwrap() != len {
+                    bail!(Internal, msg("expected len {} got len {}", len, data.len()));
+                }
+                Ok::<_, Error>(&data[r.start as usize..r.end as usize])
+            })?
+            .into())
+    }
+
     fn wrap_video_sample_entry(&self, f: &File, r: Range<u64>, len: u64) -> Result<Chunk, Error> {
         let mp4 = ARefss::new(f.0.clone());
         Ok(mp4
@@ -782,6 +1025,32 @@ impl slices::Slice for Slice {
     ) -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Send + Sync> {
         trace!("getting mp4 slice {:?}'s range {:?} / {}", self, range, len);
         let p = self.p();
+        if matches!(
+            self.t(),
+            SliceType::Stts | SliceType::Stsz | SliceType::Stss
+        ) && f.0.index_pending(p)
+        {
+            // Build this segment's index on a blocking thread, and those of the following
+            // segments in the background, then try again.
+            let (f, slice) = (f.clone(), Slice(self.0));
+            return Box::new(
+                stream::once(async move {
+                    f.0.prefetch_indexes(p);
+                    let f2 = f.clone();
+                    if let Err(e) = tokio::task::spawn_blocking(move || {
+                        f2.0.segments[p].prepare_index(&f2.0.db)
+                    })
+                    .await
+                    {
+                        let e = err!(Internal, msg("index building failed"), source(e));
+                        return Box::pin(stream::once(futures::future::err(wrap_error(e))))
+                            as Pin<Box<dyn Stream<Item = _> + Send + Sync>>;
+                    }
+                    Box::into_pin(slices::Slice::get_range(&slice, &f, range, len))
+                })
+                .flatten(),
+            );
+        }
         let res = match self.t() {
             SliceType::Static => {
                 let s = STATIC_BYTESTRINGS[p];
@@ -810,6 +1079,14 @@ impl slices::Slice for Slice {
             SliceType::VideoSampleData => return f.0.get_video_sample_data(p, range),
             SliceType::SubtitleSampleData => f.0.get_subtitle_sample_data(p, range.clone(), len),
             SliceType::Truns => self.wrap_truns(f, range.clone(), len as usize),
+            SliceType::AudioSampleEntry => self.wrap_audio_sample_entry(f, range.clone(), len),
+            SliceType::AudioStts => {
+                self.wrap_audio_index(f, range.clone(), len, Segment::audio_stts)
+            }
+            SliceType::AudioStsz => {
+                self.wrap_audio_index(f, range.clone(), len, Segment::audio_stsz)
+            }
+            SliceType::AudioSampleData => return f.0.get_audio_sample_data(p, range),
         };
         Box::new(stream::once(futures::future::ready(
             res.map_err(wrap_error).and_then(move |c| {
@@ -829,6 +1106,25 @@ impl slices::Slice for Slice {
         )))
     }
 
+    fn file_range(&self, f: &File, r: Range<u64>) -> Option<slices::FileRange> {
+        let s = match self.t() {
+            SliceType::VideoSampleData | SliceType::AudioSampleData => &f.0.segments[self.p()],
+            _ => return None,
+        };
+        let sr = match self.t() {
+            SliceType::AudioSampleData => s.s.audio.as_ref()?.sample_file_range(),
+            _ => s.s.sample_file_range(),
+        };
+        if s.s.key.is_some() {
+            return None; // encrypted files must be decrypted in userspace.
+        }
+        Some(slices::FileRange {
+            dir: f.0.dirs_by_id.get(&s.s.sample_file_dir_id)?.clone(),
+            composite_id: s.s.id,
+            range: (r.start + sr.start)..(r.end + sr.start),
+        })
+    }
+
     fn get_slices(ctx: &File) -> &Slices<Self> {
         &ctx.0.slices
     }
@@ -880,7 +1176,12 @@ impl FileBuilder {
         FileBuilder {
             segments: Vec::new(),
             video_sample_entries: SmallVec::new(),
+            audio_sample_entries: SmallVec::new(),
             next_frame_num: 1,
+            audio_sample_entry: None,
+            audio_start: 0,
+            audio_duration: 0,
+            audio_co64_pos: None,
             media_duration_90k: 0,
             num_subtitle_samples: 0,
             subtitle_co64_pos: None,
@@ -891,14 +1192,22 @@ impl FileBuilder {
             },
             type_,
             include_timestamp_subtitle_track: false,
+            include_base_media_decode_time: false,
             content_disposition: None,
             prev_media_duration_and_cur_runs: None,
+            timelapse: None,
         }
     }
 
     /// Sets if the generated `.mp4` should include a subtitle track with second-level timestamps.
     /// Default is false.
     pub fn include_timestamp_subtitle_track(&mut self, b: bool) -> Result<(), Error> {
+        if b && self.timelapse.is_some() {
+            bail!(
+                InvalidArgument,
+                msg("timestamp subtitles aren't supported on timelapses")
+            );
+        }
         if b && self.type_ == Type::MediaSegment {
             // There's no support today for timestamp truns or for timestamps without edit lists.
             // The latter would invalidate the code's assumption that desired timespan == actual
@@ -912,6 +1221,66 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Sets if a media segment's `tfdt` box should hold its start time within the stream's
+    /// cumulative media timeline, so that consecutive segments can be played back without a
+    /// `timestampOffset`. Default is false, in which case the base media decode time is zero.
+    ///
+    /// When true, the first appended recording must have `prev_media_duration_and_runs` set, as
+    /// `list_recordings_by_id` does.
+    pub fn include_base_media_decode_time(&mut self, b: bool) -> Result<(), Error> {
+        if b && self.type_ != Type::MediaSegment {
+            bail!(
+                InvalidArgument,
+                msg("base media decode time is only supported on media segments")
+            );
+        }
+        self.include_base_media_decode_time = b;
+        Ok(())
+    }
+
+    /// Switches to timelapse mode, in which subsequent calls to `append` select only the first
+    /// key frame at or after every `interval` of wall time and present each for
+    /// `frame_duration_90k`. This condenses a long time range without transcoding. The result
+    /// has no audio track.
+    ///
+    /// Must be called before any segments are appended.
+    pub fn timelapse(
+        &mut self,
+        interval: recording::Duration,
+        frame_duration_90k: i32,
+    ) -> Result<(), Error> {
+        if self.type_ != Type::Normal {
+            bail!(
+                InvalidArgument,
+                msg("timelapses are only supported on normal .mp4 files")
+            );
+        }
+        if self.include_timestamp_subtitle_track {
+            bail!(
+                InvalidArgument,
+                msg("timestamp subtitles aren't supported on timelapses")
+            );
+        }
+        if !self.segments.is_empty() {
+            bail!(
+                FailedPrecondition,
+                msg("timelapse mode must be set before appending segments")
+            );
+        }
+        if interval.0 <= 0 || frame_duration_90k <= 0 {
+            bail!(
+                InvalidArgument,
+                msg("timelapse interval and frame duration must be positive")
+            );
+        }
+        self.timelapse = Some(Timelapse {
+            interval,
+            frame_duration_90k,
+            next: None,
+        });
+        Ok(())
+    }
+
     /// Reserves space for the given number of additional segments.
     pub fn reserve(&mut self, additional: usize) {
         self.segments.reserve(additional);
@@ -921,6 +1290,21 @@ impl FileBuilder {
         self.video_sample_entries.push(ent);
     }
 
+    /// Sets the audio sample entry for live audio, which is otherwise taken from the appended
+    /// recordings. An init segment gets an audio track with this entry. A media segment is
+    /// unaffected except for its `Content-Type`, as its audio is appended separately by
+    /// [`append_live_audio_fragment`].
+    pub fn set_audio_sample_entry(&mut self, ent: Arc<db::AudioSampleEntry>) -> Result<(), Error> {
+        if self.type_ == Type::Normal {
+            bail!(
+                InvalidArgument,
+                msg("explicit audio sample entries aren't supported on normal .mp4 files")
+            );
+        }
+        self.audio_sample_entry = Some(ent);
+        Ok(())
+    }
+
     /// Appends a segment for (a subset of) the given recording.
     /// `rel_media_range_90k` is the media time range within the recording.
     /// Eg `0 .. row.media_duration_90k` means the full recording.
@@ -949,16 +1333,19 @@ impl FileBuilder {
                 .prev_media_duration_and_runs
                 .map(|(d, r)| (d, r + if row.open_id == 0 { 1 } else { 0 }));
         }
-        let s = Segment::new(
-            db,
-            &row,
-            rel_media_range_90k,
-            self.next_frame_num,
-            start_at_key,
-        )?;
-
-        self.next_frame_num += s.s.frames as u32;
-        self.segments.push(s);
+        if self.timelapse.is_some() {
+            self.append_timelapse(db, &row, rel_media_range_90k)?;
+        } else {
+            let s = Segment::new(
+                db,
+                &row,
+                rel_media_range_90k,
+                self.next_frame_num,
+                start_at_key,
+            )?;
+            self.next_frame_num += s.s.frames as u32;
+            self.segments.push(s);
+        }
         if !self
             .video_sample_entries
             .iter()
@@ -970,9 +1357,74 @@ impl FileBuilder {
                 .unwrap();
             self.video_sample_entries.push(vse.clone());
         }
+        if let Some(a) = row.audio_sample_entry_id {
+            if !self.audio_sample_entries.iter().any(|e| e.id == a) {
+                let ase = db.audio_sample_entries_by_id().get(&a).unwrap();
+                self.audio_sample_entries.push(ase.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a single-frame segment for each selected key frame within the given range of
+    /// the recording, in timelapse mode.
+    fn append_timelapse(
+        &mut self,
+        db: &db::LockedDatabase,
+        row: &db::ListRecordingsRow,
+        rel_media_range_90k: Range<i32>,
+    ) -> Result<(), Error> {
+        let t = self.timelapse.as_mut().expect("timelapse mode");
+        let mut frames = Vec::new();
+        db.with_recording_playback(row.id, &mut |playback| {
+            let mut it = recording::SampleIndexIterator::default();
+            while it.next(&playback.video_index)? {
+                if it.start_90k >= rel_media_range_90k.end {
+                    break;
+                }
+                if !it.is_key() || it.start_90k < rel_media_range_90k.start || it.duration_90k == 0
+                {
+                    continue;
+                }
+                let wall_90k = rescale(it.start_90k, row.media_duration_90k, row.wall_duration_90k);
+                let wall = row.start + recording::Duration(i64::from(wall_90k));
+                if t.next.map(|n| wall >= n).unwrap_or(true) {
+                    frames.push(it.start_90k..it.start_90k + it.duration_90k);
+                    t.next = Some(wall + t.interval);
+                }
+            }
+            Ok(())
+        })?;
+        let frame_duration_90k = t.frame_duration_90k;
+        self.segments.reserve(frames.len());
+        for r in frames {
+            let mut s = Segment::new(db, row, r, self.next_frame_num, true)?;
+            s.timelapse_duration_90k = Some(frame_duration_90k);
+            self.next_frame_num += s.s.frames as u32;
+            self.segments.push(s);
+        }
         Ok(())
     }
 
+    /// Appends the portion of a recording which overlaps the given wall time range, starting at
+    /// the preceding key frame. Returns the wall time at which the requested portion starts.
+    pub fn append_wall_range(
+        &mut self,
+        db: &db::LockedDatabase,
+        row: db::ListRecordingsRow,
+        wall: &Range<recording::Time>,
+    ) -> Result<recording::Time, Error> {
+        let wd = i64::from(row.wall_duration_90k);
+        let start = cmp::max(0, cmp::min(wd, (wall.start - row.start).0));
+        let end = cmp::max(start, cmp::min(wd, (wall.end - row.start).0));
+        let (start_90k, end_90k) = (i32::try_from(start).unwrap(), i32::try_from(end).unwrap());
+        let media = rescale(start_90k, row.wall_duration_90k, row.media_duration_90k)
+            ..rescale(end_90k, row.wall_duration_90k, row.media_duration_90k);
+        let actual_start = row.start + recording::Duration(start);
+        self.append(db, row, media, true)?;
+        Ok(actual_start)
+    }
+
     pub fn set_filename(&mut self, filename: &str) -> Result<(), Error> {
         self.content_disposition = Some(
             HeaderValue::try_from(format!("attachment; filename=\"{filename}\""))
@@ -985,22 +1437,35 @@ impl FileBuilder {
     pub fn build(
         mut self,
         db: Arc<db::Database>,
-        dirs_by_stream_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+        dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
     ) -> Result<File, Error> {
+        let span = tracing::info_span!("mp4_build", segments = self.segments.len());
+        let _enter = span.enter();
         let mut max_end = None;
         let mut etag = blake3::Hasher::new();
         etag.update(&FORMAT_VERSION[..]);
         if self.include_timestamp_subtitle_track {
             etag.update(b":ts:");
         }
+        if self.include_base_media_decode_time {
+            etag.update(b":tfdt:");
+        }
         if let Some(cd) = self.content_disposition.as_ref() {
             etag.update(b":cd:");
             etag.update(cd.as_bytes());
         }
+        if let Some(t) = self.timelapse.as_ref() {
+            etag.update(
+                format!(":timelapse:{}:{}:", t.interval.0, t.frame_duration_90k).as_bytes(),
+            );
+        }
         match self.type_ {
             Type::Normal => {}
             Type::InitSegment => {
                 etag.update(b":init:");
+                if let Some(a) = self.audio_sample_entry.as_ref() {
+                    etag.update(format!(":audio:{}:", a.id).as_bytes());
+                }
             }
             Type::MediaSegment => {
                 etag.update(b":media:");
@@ -1015,7 +1480,8 @@ impl FileBuilder {
                 Type::MediaSegment => s.s.actual_start_90k(),
                 _ => md.start,
             };
-            self.media_duration_90k += u64::try_from(md.end - start).unwrap();
+            self.media_duration_90k +=
+                u64::try_from(s.presented_duration_90k() + md.start - start).unwrap();
             let wall = s.recording_start + recording::Duration(i64::from(s.wall(md.start)))
                 ..s.recording_start + recording::Duration(i64::from(s.wall(md.end)));
             max_end = match max_end {
@@ -1052,6 +1518,9 @@ impl FileBuilder {
                 .err_kind(ErrorKind::Internal)?;
             etag.update(cursor.into_inner());
         }
+        if self.type_ == Type::Normal && self.timelapse.is_none() {
+            self.prepare_audio()?;
+        }
         let max_end = match max_end {
             None => 0,
             Some(v) => v.unix_seconds(),
@@ -1061,6 +1530,9 @@ impl FileBuilder {
         if self.include_timestamp_subtitle_track {
             est_slices += 16 + self.segments.len();
         }
+        if self.audio_sample_entry.is_some() {
+            est_slices += 16 + 3 * self.segments.len();
+        }
         self.body.slices.reserve(est_slices);
         const EST_BUF_LEN: usize = 2048;
         self.body.buf.reserve(EST_BUF_LEN);
@@ -1091,7 +1563,16 @@ impl FileBuilder {
                 0
             }
             Type::Normal => {
-                self.body.append_static(StaticBytestring::NormalFtypBox)?;
+                let ftyp = if self
+                    .video_sample_entries
+                    .iter()
+                    .all(|e| e.box_type() == b"hvc1")
+                {
+                    StaticBytestring::HevcFtypBox
+                } else {
+                    StaticBytestring::NormalFtypBox
+                };
+                self.body.append_static(ftyp)?;
                 self.append_moov(creation_ts)?;
                 self.append_normal_mdat()?
             }
@@ -1130,11 +1611,12 @@ impl FileBuilder {
         let etag = etag.finalize();
         Ok(File(Arc::new(FileInner {
             db,
-            dirs_by_stream_id,
+            dirs_by_id,
             segments: self.segments,
             slices: self.body.slices,
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
+            audio_sample_entry: self.audio_sample_entry,
             initial_sample_byte_pos,
             last_modified,
             etag: HeaderValue::try_from(format!("\"{}\"", etag.to_hex().as_str()))
@@ -1142,15 +1624,95 @@ impl FileBuilder {
             content_disposition: self.content_disposition,
             prev_media_duration_and_cur_runs: self.prev_media_duration_and_cur_runs,
             type_: self.type_,
+            index_prefetch: Mutex::new(IndexPrefetch::default()),
         })))
     }
 
+    /// Decides if the `.mp4` should have an audio track and, if so, lays out its timeline.
+    ///
+    /// Audio is included only if every segment has audio with the same sample entry. Each
+    /// segment's first audio sample is placed at its offset from the segment's desired start,
+    /// and the preceding segment's final sample is lengthened or shortened to meet it.
+    fn prepare_audio(&mut self) -> Result<(), Error> {
+        let mut id = None;
+        for s in &self.segments {
+            let Some(a) = s.s.audio.as_ref() else {
+                return Ok(());
+            };
+            match id {
+                None => id = Some(a.audio_sample_entry_id),
+                Some(i) if i == a.audio_sample_entry_id => {}
+                Some(_) => return Ok(()),
+            }
+        }
+        let Some(entry) = id.and_then(|id| {
+            self.audio_sample_entries
+                .iter()
+                .find(|e| e.id == id)
+                .cloned()
+        }) else {
+            return Ok(());
+        };
+        let rate = u64::from(entry.sample_rate);
+
+        // (segment index, start, natural end) of each segment with audio samples, in units of
+        // the sample rate.
+        let mut spans = Vec::with_capacity(self.segments.len());
+        let mut pres_start_90k = 0;
+        for (i, s) in self.segments.iter().enumerate() {
+            let md = &s.rel_media_range_90k;
+            let a = s.s.audio.as_ref().expect("checked above");
+            if a.samples > 0 {
+                let off_90k = u64::try_from(a.start_90k - md.start)
+                    .map_err(|_| err!(Internal, msg("audio before segment start: {:#?}", s)))?;
+                let start = (pres_start_90k + off_90k) * rate / TIME_UNITS_PER_SEC as u64;
+                spans.push((i, start, start + u64::try_from(a.duration).unwrap()));
+            }
+            pres_start_90k += u64::try_from(md.end - md.start).unwrap();
+        }
+        let (Some(&(_, first_start, _)), Some(&(_, _, last_end))) = (spans.first(), spans.last())
+        else {
+            return Ok(()); // no audio samples at all.
+        };
+        for w in spans.windows(2) {
+            let (i, _, end) = w[0];
+            let (_, next_start, _) = w[1];
+            self.segments[i].audio_end_adjust = i32::try_from(next_start as i64 - end as i64)
+                .map_err(|_| {
+                    err!(
+                        OutOfRange,
+                        msg(
+                            "audio out of sync by {} samples",
+                            next_start as i64 - end as i64
+                        )
+                    )
+                })?;
+        }
+        self.audio_start = first_start;
+        self.audio_duration = last_end - first_start;
+        self.audio_sample_entry = Some(entry);
+        Ok(())
+    }
+
     fn append_mdat_contents(&mut self) -> Result<(), Error> {
         for (i, s) in self.segments.iter().enumerate() {
             let r = s.s.sample_file_range();
             self.body
                 .append_slice(r.end - r.start, SliceType::VideoSampleData, i)?;
         }
+        if let Some(mut p) = self.audio_co64_pos {
+            for (i, s) in self.segments.iter().enumerate() {
+                let a = s.s.audio.as_ref().expect("audio_co64_pos implies audio");
+                if a.samples == 0 {
+                    continue;
+                }
+                BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
+                p += 8;
+                let r = a.sample_file_range();
+                self.body
+                    .append_slice(r.end - r.start, SliceType::AudioSampleData, i)?;
+            }
+        }
         if let Some(p) = self.subtitle_co64_pos {
             BigEndian::write_u64(&mut self.body.buf[p..p + 8], self.body.slices.len());
             for (i, s) in self.segments.iter().enumerate() {
@@ -1218,6 +1780,9 @@ impl FileBuilder {
             if self.include_timestamp_subtitle_track {
                 self.append_subtitle_trak(creation_ts)?;
             }
+            if self.audio_sample_entry.is_some() {
+                self.append_audio_trak(creation_ts)?;
+            }
             if self.type_ == Type::InitSegment {
                 self.append_mvex()?;
             }
@@ -1248,6 +1813,23 @@ impl FileBuilder {
                                             // sample_degradation_priority: 0
                 ]);
             })?;
+
+            // ...and for the audio track, if any, whose samples are all sync samples.
+            if self.audio_sample_entry.is_some() {
+                write_length!(self, {
+                    self.body.buf.extend_from_slice(b"trex\x00\x00\x00\x00");
+                    self.body.append_u32(self.audio_track_id());
+                    #[rustfmt::skip]
+                    self.body.buf.extend_from_slice(&[
+                        0x00, 0x00, 0x00, 0x01, // default_sample_description_index
+                        0x00, 0x00, 0x00, 0x00, // default_sample_duration
+                        0x00, 0x00, 0x00, 0x00, // default_sample_size
+                        0x02, 0x00, 0x00, 0x00, // default_sample_flags:
+                                                // sample_depends_on: does not depend on others
+                                                // sample_is_non_sync_sample: 0
+                    ]);
+                })?;
+            }
         })
     }
 
@@ -1281,17 +1863,47 @@ impl FileBuilder {
                 // Fragment Base Media Decode Time Box, if present, shall be
                 // positioned after the Track Fragment Header Box and before the
                 // first Track Fragment Run box." Safari cares deeply that this rule is followed.
-                write_length!(self, {
-                    self.body.buf.extend_from_slice(&[
-                        b't', b'f', b'd', b't', 0x00, 0x00, 0x00, 0x00, // version + flags
-                        0x00, 0x00, 0x00, 0x00, // TODO: baseMediaDecodeTime
-                    ]);
-                })?;
+                match self.base_media_decode_time()? {
+                    None => write_length!(self, {
+                        self.body.buf.extend_from_slice(&[
+                            b't', b'f', b'd', b't', 0x00, 0x00, 0x00, 0x00, // version + flags
+                            0x00, 0x00, 0x00, 0x00, // baseMediaDecodeTime
+                        ]);
+                    })?,
+                    Some(t) => write_length!(self, {
+                        self.body.buf.extend_from_slice(&[
+                            b't', b'f', b'd', b't', 0x01, 0x00, 0x00, 0x00, // version + flags
+                        ]);
+                        self.body.append_u64(t); // baseMediaDecodeTime
+                    })?,
+                }
                 self.append_truns()?;
             })?;
         })
     }
 
+    /// Returns the `tfdt` base media decode time, if it should be included.
+    fn base_media_decode_time(&self) -> Result<Option<u64>, Error> {
+        if !self.include_base_media_decode_time {
+            return Ok(None);
+        }
+        let (prev_media_duration, _) = self.prev_media_duration_and_cur_runs.ok_or_else(|| {
+            err!(
+                Internal,
+                msg("base media decode time requires prev_media_duration_and_runs")
+            )
+        })?;
+        let start = self
+            .segments
+            .first()
+            .map(|s| s.s.actual_start_90k())
+            .unwrap_or(0);
+        Ok(Some(
+            u64::try_from(prev_media_duration.0 + i64::from(start))
+                .map_err(|_| err!(OutOfRange))?,
+        ))
+    }
+
     fn append_truns(&mut self) -> Result<(), Error> {
         self.body.flush_buf()?;
         for (i, s) in self.segments.iter().enumerate() {
@@ -1311,7 +1923,9 @@ impl FileBuilder {
             let d = self.media_duration_90k;
             self.body.append_u64(d);
             self.body.append_static(StaticBytestring::MvhdJunk)?;
-            let next_track_id = if self.include_timestamp_subtitle_track {
+            let next_track_id = if self.audio_sample_entry.is_some() {
+                self.audio_track_id() + 1
+            } else if self.include_timestamp_subtitle_track {
                 3
             } else {
                 2
@@ -1397,7 +2011,7 @@ impl FileBuilder {
             let actual_start_90k = s.s.actual_start_90k();
             let md = &s.rel_media_range_90k;
             let skip = md.start - actual_start_90k;
-            let keep = md.end - md.start;
+            let keep = s.presented_duration_90k();
             if skip < 0 || keep < 0 {
                 bail!(
                     Internal,
@@ -1447,7 +2061,11 @@ impl FileBuilder {
     fn append_video_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::VideoHdlrBox)?;
             self.append_video_minf()?;
         })
@@ -1457,21 +2075,29 @@ impl FileBuilder {
     fn append_subtitle_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdia");
-            self.append_mdhd(creation_ts)?;
+            self.append_mdhd(
+                creation_ts,
+                TIME_UNITS_PER_SEC as u32,
+                self.media_duration_90k,
+            )?;
             self.body.append_static(StaticBytestring::SubtitleHdlrBox)?;
             self.append_subtitle_minf()?;
         })
     }
 
-    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2) suitable for either the video
-    /// or subtitle track.
-    fn append_mdhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+    /// Appends a `MediaHeaderBox` (ISO/IEC 14496-12 section 8.4.2).
+    fn append_mdhd(
+        &mut self,
+        creation_ts: u32,
+        timescale: u32,
+        duration: u64,
+    ) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"mdhd\x01\x00\x00\x00");
             self.body.append_u64(u64::from(creation_ts));
             self.body.append_u64(u64::from(creation_ts));
-            self.body.append_u32(TIME_UNITS_PER_SEC as u32);
-            self.body.append_u64(self.media_duration_90k);
+            self.body.append_u32(timescale);
+            self.body.append_u64(duration);
             self.body.append_u32(0x55c40000); // language=und + pre_defined
         })
     }
@@ -1734,6 +2360,223 @@ impl FileBuilder {
     }
 }
 
+impl FileBuilder {
+    /// Returns the track id of the audio track, which follows the video and subtitle tracks.
+    fn audio_track_id(&self) -> u32 {
+        if self.include_timestamp_subtitle_track {
+            3
+        } else {
+            2
+        }
+    }
+
+    /// Converts a duration in units of the audio sample rate to the movie timescale.
+    fn audio_to_90k(&self, v: u64) -> u64 {
+        let rate = self
+            .audio_sample_entry
+            .as_ref()
+            .expect("audio_sample_entry should be set")
+            .sample_rate;
+        v * TIME_UNITS_PER_SEC as u64 / u64::from(rate)
+    }
+
+    /// Appends a `TrackBox` (ISO/IEC 14496-12 section 8.3.1) suitable for audio.
+    fn append_audio_trak(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"trak");
+            self.append_audio_tkhd(creation_ts)?;
+            self.maybe_append_audio_edts()?;
+            self.append_audio_mdia(creation_ts)?;
+        })
+    }
+
+    /// Appends a `TrackHeaderBox` (ISO/IEC 14496-12 section 8.3.2) suitable for audio.
+    fn append_audio_tkhd(&mut self, creation_ts: u32) -> Result<(), Error> {
+        write_length!(self, {
+            // flags 7: track_enabled | track_in_movie | track_in_preview
+            self.body.buf.extend_from_slice(b"tkhd\x01\x00\x00\x07");
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u64(creation_ts as u64);
+            self.body.append_u32(self.audio_track_id());
+            self.body.append_u32(0); // reserved
+            self.body
+                .append_u64(self.audio_to_90k(self.audio_start + self.audio_duration));
+            self.body.append_static(StaticBytestring::AudioTkhdJunk)?;
+            self.body.append_u32(0); // width, unused.
+            self.body.append_u32(0); // height, unused.
+        })
+    }
+
+    /// Appends an `EditBox` (ISO/IEC 14496-12 section 8.6.5) suitable for audio, if necessary.
+    /// The only edit needed is an empty one to delay the start of the audio.
+    fn maybe_append_audio_edts(&mut self) -> Result<(), Error> {
+        if self.audio_start == 0 {
+            return Ok(()); // use implicit one-to-one mapping.
+        }
+        let empty = self.audio_to_90k(self.audio_start);
+        let duration = self.audio_to_90k(self.audio_duration);
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"edts");
+            write_length!(self, {
+                // Use version 1 for 64-bit times.
+                self.body.buf.extend_from_slice(b"elst\x01\x00\x00\x00");
+                self.body.append_u32(2);
+
+                // An empty edit: media_time of -1.
+                self.body.append_u64(empty);
+                self.body.append_u64(u64::MAX);
+                self.body.buf.extend_from_slice(b"\x00\x01\x00\x00");
+
+                self.body.append_u64(duration);
+                self.body.append_u64(0);
+                self.body.buf.extend_from_slice(b"\x00\x01\x00\x00");
+            })?;
+        })
+    }
+
+    /// Appends a `MediaBox` (ISO/IEC 14496-12 section 8.4.1) suitable for audio.
+    fn append_audio_mdia(&mut self, creation_ts: u32) -> Result<(), Error> {
+        let rate = self
+            .audio_sample_entry
+            .as_ref()
+            .expect("audio_sample_entry should be set")
+            .sample_rate;
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"mdia");
+            self.append_mdhd(creation_ts, rate, self.audio_duration)?;
+            self.body.append_static(StaticBytestring::AudioHdlrBox)?;
+            self.append_audio_minf()?;
+        })
+    }
+
+    /// Appends a `MediaInformationBox` (ISO/IEC 14496-12 section 8.4.4) suitable for audio.
+    fn append_audio_minf(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.append_static(StaticBytestring::AudioMinfJunk)?;
+            self.append_audio_stbl()?;
+        })
+    }
+
+    /// Appends a `SampleTableBox` (ISO/IEC 14496-12 section 8.5.1) suitable for audio.
+    fn append_audio_stbl(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stbl");
+            self.append_audio_stsd()?;
+            self.append_audio_stts()?;
+            self.append_audio_stsc()?;
+            self.append_audio_stsz()?;
+            self.append_audio_co64()?;
+        })
+    }
+
+    /// Appends a `SampleDescriptionBox` (ISO/IEC 14496-12 section 8.5.2) suitable for audio.
+    fn append_audio_stsd(&mut self) -> Result<(), Error> {
+        let len = self
+            .audio_sample_entry
+            .as_ref()
+            .expect("audio_sample_entry should be set")
+            .data
+            .len();
+        write_length!(self, {
+            self.body
+                .buf
+                .extend_from_slice(b"stsd\x00\x00\x00\x00\x00\x00\x00\x01");
+            self.body.flush_buf()?;
+            self.body
+                .append_slice(len as u64, SliceType::AudioSampleEntry, 0)?;
+        })
+    }
+
+    /// Returns the total number of audio samples.
+    fn audio_samples(&self) -> u32 {
+        self.segments
+            .iter()
+            .map(|s| s.s.audio.as_ref().map(|a| a.samples).unwrap_or(0))
+            .sum()
+    }
+
+    /// Appends an `stts` / `TimeToSampleBox` (ISO/IEC 14496-12 section 8.6.1) for audio.
+    fn append_audio_stts(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
+            self.body.append_u32(self.audio_samples());
+            self.body.flush_buf()?;
+            for (i, s) in self.segments.iter().enumerate() {
+                let samples = s.s.audio.as_ref().map(|a| a.samples).unwrap_or(0);
+                if samples > 0 {
+                    self.body.append_slice(
+                        2 * (mem::size_of::<u32>() as u64) * u64::from(samples),
+                        SliceType::AudioStts,
+                        i,
+                    )?;
+                }
+            }
+        })
+    }
+
+    /// Appends a `SampleToChunkBox` (ISO/IEC 14496-12 section 8.7.4) suitable for audio.
+    /// Each segment's audio is a single chunk.
+    fn append_audio_stsc(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"stsc\x00\x00\x00\x00");
+            let entry_count_pos = self.body.buf.len();
+            self.body.append_u32(0); // placeholder for entry_count
+            let mut chunks = 0;
+            for s in &self.segments {
+                let samples = s.s.audio.as_ref().map(|a| a.samples).unwrap_or(0);
+                if samples > 0 {
+                    chunks += 1;
+                    self.body.append_u32(chunks);
+                    self.body.append_u32(samples);
+                    self.body.append_u32(1); // sample_description_index
+                }
+            }
+            BigEndian::write_u32(
+                &mut self.body.buf[entry_count_pos..entry_count_pos + 4],
+                chunks,
+            );
+        })
+    }
+
+    /// Appends a `SampleSizeBox` (ISO/IEC 14496-12 section 8.7.3) suitable for audio.
+    fn append_audio_stsz(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body
+                .buf
+                .extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
+            self.body.append_u32(self.audio_samples());
+            self.body.flush_buf()?;
+            for (i, s) in self.segments.iter().enumerate() {
+                let samples = s.s.audio.as_ref().map(|a| a.samples).unwrap_or(0);
+                if samples > 0 {
+                    self.body.append_slice(
+                        (mem::size_of::<u32>() as u64) * u64::from(samples),
+                        SliceType::AudioStsz,
+                        i,
+                    )?;
+                }
+            }
+        })
+    }
+
+    /// Appends a `ChunkLargeOffsetBox` (ISO/IEC 14496-12 section 8.7.5) suitable for audio.
+    fn append_audio_co64(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"co64\x00\x00\x00\x00");
+            let chunks = self
+                .segments
+                .iter()
+                .filter(|s| s.s.audio.as_ref().map(|a| a.samples > 0).unwrap_or(false))
+                .count();
+            self.body.append_u32(chunks as u32);
+
+            // Write placeholders; the actual values will be filled in later.
+            self.audio_co64_pos = Some(self.body.buf.len());
+            self.body.buf.resize(self.body.buf.len() + 8 * chunks, 0);
+        })
+    }
+}
+
 impl BodyState {
     fn append_u32(&mut self, v: u32) {
         self.buf
@@ -1775,22 +2618,137 @@ impl BodyState {
     }
 }
 
+/// Appends a fragment (a `moof` and `mdat`) holding the given live audio, to follow a live media
+/// segment whose init segment has an audio track (see [`FileBuilder::set_audio_sample_entry`]).
+///
+/// `base_media_decode_time` is in units of the audio sample entry's sample rate.
+pub fn append_live_audio_fragment(
+    v: &mut Vec<u8>,
+    audio: &db::LiveAudio,
+    base_media_decode_time: u64,
+) {
+    // Init segments never have a subtitle track, so audio is always the second track.
+    const TRACK_ID: u32 = 2;
+    let samples = u32::try_from(audio.samples.len()).unwrap();
+    let trun_len = 20 + 8 * samples;
+    let traf_len = 8 + 16 + 20 + trun_len;
+    let moof_len = 8 + 16 + traf_len;
+
+    // MovieFragmentBox (ISO/IEC 14496-12 section 8.8.4).
+    v.extend_from_slice(&moof_len.to_be_bytes());
+    v.extend_from_slice(b"moof");
+
+    // MovieFragmentHeaderBox (ISO/IEC 14496-12 section 8.8.5).
+    v.extend_from_slice(b"\x00\x00\x00\x10mfhd\x00\x00\x00\x00");
+    v.extend_from_slice(&1_u32.to_be_bytes()); // sequence_number
+
+    // TrackFragmentBox (ISO/IEC 14496-12 section 8.8.6).
+    v.extend_from_slice(&traf_len.to_be_bytes());
+    v.extend_from_slice(b"traf");
+
+    // TrackFragmentHeaderBox, tfhd (ISO/IEC 14496-12 section 8.8.7), with
+    // default-base-is-moof.
+    v.extend_from_slice(b"\x00\x00\x00\x10tfhd\x00\x02\x00\x00");
+    v.extend_from_slice(&TRACK_ID.to_be_bytes());
+
+    // TrackFragmentBaseMediaDecodeTimeBox, tfdt (ISO/IEC 14496-12 section 8.8.12), version 1.
+    v.extend_from_slice(b"\x00\x00\x00\x14tfdt\x01\x00\x00\x00");
+    v.extend_from_slice(&base_media_decode_time.to_be_bytes());
+
+    // TrackRunBox, trun (ISO/IEC 14496-12 section 8.8.8), with tr_flags
+    // data-offset-present | sample-duration-present | sample-size-present.
+    v.extend_from_slice(&trun_len.to_be_bytes());
+    v.extend_from_slice(b"trun\x00\x00\x03\x01");
+    v.extend_from_slice(&samples.to_be_bytes());
+    v.extend_from_slice(&(moof_len + 8).to_be_bytes()); // data_offset: just past the mdat header.
+    for &(duration, bytes) in &audio.samples {
+        v.extend_from_slice(&(duration as u32).to_be_bytes());
+        v.extend_from_slice(&(bytes as u32).to_be_bytes());
+    }
+
+    // MediaDataBox (ISO/IEC 14496-12 section 8.1.1).
+    v.extend_from_slice(&(8 + u32::try_from(audio.data.len()).unwrap()).to_be_bytes());
+    v.extend_from_slice(b"mdat");
+    v.extend_from_slice(&audio.data);
+}
+
 struct FileInner {
     db: Arc<db::Database>,
-    dirs_by_stream_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+    dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
     segments: Vec<Segment>,
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+    audio_sample_entry: Option<Arc<db::AudioSampleEntry>>,
     initial_sample_byte_pos: u64,
     last_modified: SystemTime,
     etag: HeaderValue,
     content_disposition: Option<HeaderValue>,
     prev_media_duration_and_cur_runs: Option<(recording::Duration, i32)>,
     type_: Type,
+    index_prefetch: Mutex<IndexPrefetch>,
+}
+
+/// The progress of `FileInner::prefetch_indexes`.
+#[derive(Default)]
+struct IndexPrefetch {
+    /// The next segment to hand to a worker.
+    next: usize,
+
+    /// The end of the segments to build, exclusive.
+    limit: usize,
+
+    /// The number of workers running.
+    workers: usize,
 }
 
 impl FileInner {
+    /// Returns true if the index of segment `i` should be built on a blocking thread before its
+    /// slices are written, as it hasn't been built yet and this file has many segments.
+    fn index_pending(&self, i: usize) -> bool {
+        self.segments.len() >= PARALLEL_INDEX_MIN_SEGMENTS
+            && !self.segments[i].index_once.is_completed()
+    }
+
+    /// Builds the indexes of segment `i` and the following segments in the background, on
+    /// several blocking threads.
+    ///
+    /// The `moov` holds every segment's `stts`, `stsz`, and `stss`. Each is built lazily as its
+    /// slices are written, so the response starts as soon as the first segment's index is ready
+    /// rather than after all of them. Building a window of the following segments' indexes
+    /// ahead keeps the rest of the `moov` from waiting on each in turn, and stopping at that
+    /// window bounds the wasted work if the client goes away.
+    fn prefetch_indexes(self: &Arc<Self>, i: usize) {
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_INDEX_THREADS);
+        let mut p = self.index_prefetch.lock().unwrap();
+        p.next = cmp::max(p.next, i);
+        p.limit = cmp::max(
+            p.limit,
+            cmp::min(i + INDEX_PREFETCH_SEGMENTS, self.segments.len()),
+        );
+        while p.workers < threads && p.workers < p.limit.saturating_sub(p.next) {
+            p.workers += 1;
+            let weak = Arc::downgrade(self);
+            tokio::task::spawn_blocking(move || loop {
+                let Some(f) = weak.upgrade() else {
+                    return; // the file has been dropped.
+                };
+                let i = {
+                    let mut p = f.index_prefetch.lock().unwrap();
+                    if p.next >= p.limit {
+                        p.workers -= 1;
+                        return;
+                    }
+                    p.next += 1;
+                    p.next - 1
+                };
+                f.segments[i].prepare_index(&f.db);
+            });
+        }
+    }
+
     fn get_co64(&self, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
         let mut v = Vec::with_capacity(l as usize);
         let mut pos = self.initial_sample_byte_pos;
@@ -1808,9 +2766,9 @@ impl FileInner {
     /// Gets a `Chunk` of video sample data from disk.
     /// This works by `mmap()`ing in the data. There are a couple caveats:
     ///
-    ///    * The thread which reads the resulting slice is likely to experience major page faults.
-    ///      Eventually this will likely be rewritten to `mmap()` the memory in another thread, and
-    ///      `mlock()` and send chunks of it to be read and `munlock()`ed to avoid this problem.
+    ///    * The sample file reader thread faults in each chunk's pages before returning it, but
+    ///      they may be evicted again under memory pressure before the chunk is sent, causing a
+    ///      major page fault in a tokio thread (or a blocking `sendfile`).
     ///
     ///    * If the backing file is truncated, the program will crash with `SIGBUS`. This shouldn't
     ///      happen because nothing should be touching Moonfire NVR's files but itself.
@@ -1821,14 +2779,48 @@ impl FileInner {
     ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
         let s = &self.segments[i];
         let sr = s.s.sample_file_range();
-        let f = match self.dirs_by_stream_id.get(&s.s.id.stream()) {
+        let f = match self.dirs_by_id.get(&s.s.sample_file_dir_id) {
             None => {
                 return Box::new(stream::iter(std::iter::once(Err(wrap_error(err!(
                     NotFound,
-                    msg("{}: stream not found", s.s.id)
+                    msg("{}: dir {} not found", s.s.id, s.s.sample_file_dir_id)
                 ))))))
             }
-            Some(d) => d.open_file(s.s.id, (r.start + sr.start)..(r.end + sr.start)),
+            Some(d) => d.open_mapped_file(
+                s.s.id,
+                (r.start + sr.start)..(r.end + sr.start),
+                s.s.key.clone(),
+            ),
+        };
+        Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
+    }
+
+    /// Gets a `Chunk` of audio sample data from disk, as in `get_video_sample_data`.
+    fn get_audio_sample_data(
+        &self,
+        i: usize,
+        r: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync> {
+        let s = &self.segments[i];
+        let Some(a) = s.s.audio.as_ref() else {
+            return Box::new(stream::iter(std::iter::once(Err(wrap_error(err!(
+                Internal,
+                msg("{}: no audio", s.s.id)
+            ))))));
+        };
+        let sr = a.sample_file_range();
+        let f = match self.dirs_by_id.get(&s.s.sample_file_dir_id) {
+            None => {
+                return Box::new(stream::iter(std::iter::once(Err(wrap_error(err!(
+                    NotFound,
+                    msg("{}: dir {} not found", s.s.id, s.s.sample_file_dir_id)
+                ))))))
+            }
+            Some(d) => d.open_mapped_file(
+                s.s.id,
+                (r.start + sr.start)..(r.end + sr.start),
+                s.s.key.clone(),
+            ),
         };
         Box::new(f.map_ok(Chunk::from).map_err(wrap_error))
     }
@@ -1869,6 +2861,14 @@ impl FileInner {
 pub struct File(Arc<FileInner>);
 
 impl File {
+    /// Returns the parts of `range`, as in [`Slices::get_parts`].
+    pub fn get_parts(
+        &self,
+        range: Range<u64>,
+    ) -> Result<impl Iterator<Item = slices::Part<Chunk>> + '_, Error> {
+        self.0.slices.get_parts(self, range)
+    }
+
     pub async fn append_into_vec(self, v: &mut Vec<u8>) -> Result<(), Error> {
         use http_serve::Entity;
         v.reserve(usize::try_from(self.len()).map_err(|_| {
@@ -1882,7 +2882,6 @@ impl File {
         })?);
         let mut b = std::pin::Pin::from(self.get_range(0..self.len()));
         loop {
-            use futures::stream::StreamExt;
             match b.next().await {
                 Some(r) => {
                     let mut chunk = r.map_err(|e| err!(Unknown, source(e)))?;
@@ -1915,6 +2914,10 @@ impl http_serve::Entity for File {
             }
             mime.extend_from_slice(e.rfc6381_codec.as_bytes());
         }
+        if let Some(e) = self.0.audio_sample_entry.as_ref() {
+            mime.extend_from_slice(b", ");
+            mime.extend_from_slice(e.rfc6381_codec.as_bytes());
+        }
         mime.extend_from_slice(b"\"");
         hdrs.insert(
             http::header::CONTENT_TYPE,
@@ -2304,7 +3307,7 @@ mod tests {
             .lock()
             .insert_video_sample_entry(input.video_sample_entry().clone())
             .unwrap();
-        let dir = db.dirs_by_stream_id.get(&TEST_STREAM_ID).unwrap();
+        let dir = db.dirs_by_id.get(&testutil::TEST_DIR_ID).unwrap();
         let mut output = writer::Writer::new(dir, &db.db, &db.syncer_channel, TEST_STREAM_ID);
 
         // end_pts is the pts of the end of the most recent frame (start + duration).
@@ -2371,7 +3374,7 @@ mod tests {
             .unwrap();
         }
         builder
-            .build(tdb.db.clone(), tdb.dirs_by_stream_id.clone())
+            .build(tdb.db.clone(), tdb.dirs_by_id.clone())
             .unwrap()
     }
 
@@ -2495,7 +3498,7 @@ mod tests {
                 .append(&db.db.lock(), row, d_start..d_end, start_at_key)
                 .unwrap();
         }
-        builder.build(db.db.clone(), db.dirs_by_stream_id.clone())
+        builder.build(db.db.clone(), db.dirs_by_id.clone())
     }
 
     /// Tests sample table for a simple video index of all sync frames.
@@ -2684,6 +3687,158 @@ mod tests {
         assert_eq!(cursor.get_u32(12).await, 2);
     }
 
+    /// Tests that a `.mp4` with many segments starts sending its `moov` before building all of
+    /// the segments' indexes, and that those built ahead (off the async thread) match those built
+    /// one at a time.
+    #[tokio::test]
+    async fn test_prefetch_indexes() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let n = cmp::max(PARALLEL_INDEX_MIN_SEGMENTS, INDEX_PREFETCH_SEGMENTS + 1);
+        testutil::add_dummy_recordings_to_db(&db.db, n);
+        let mp4 = create_mp4_from_db(&db, 0, 0, false);
+        assert_eq!(mp4.0.segments.len(), n);
+        assert!(mp4.0.index_pending(0));
+        let mut body = Pin::from(http_serve::Entity::get_range(
+            &mp4,
+            0..mp4.0.initial_sample_byte_pos,
+        ));
+
+        // Read through the first segment's `stts`. The last segment's index is beyond the
+        // prefetch window, so it can't have been built yet.
+        let mut received = 0;
+        while !mp4.0.segments[0].index_once.is_completed() {
+            received += body.try_next().await.unwrap().unwrap().remaining();
+        }
+        assert!(mp4.0.index_pending(n - 1));
+
+        // Read the rest.
+        while let Some(chunk) = body.try_next().await.unwrap() {
+            received += chunk.remaining();
+        }
+        assert_eq!(
+            received,
+            usize::try_from(mp4.0.initial_sample_byte_pos).unwrap()
+        );
+        for s in &mp4.0.segments {
+            assert!(s.index_once.is_completed());
+            let expected = db
+                .db
+                .lock()
+                .with_recording_playback(s.s.id, &mut |playback| s.build_index(playback))
+                .unwrap();
+            assert_eq!(s.get_index(&db.db, |b, _| b).unwrap(), &expected[..]);
+        }
+    }
+
+    /// Tests sample table of a timelapse, which should have only the selected key frames.
+    #[tokio::test]
+    async fn test_timelapse() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(10, 1, true, &mut r); // [0, 10): selected.
+        encoder.add_sample(10, 2, false, &mut r); // [10, 20)
+        encoder.add_sample(10, 3, true, &mut r); // [20, 30): too soon after the first.
+        encoder.add_sample(10, 4, false, &mut r); // [30, 40)
+        encoder.add_sample(10, 5, true, &mut r); // [40, 50): selected.
+        encoder.add_sample(10, 6, true, &mut r); // [50, 60): too soon after the second.
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.timelapse(recording::Duration(25), 3000).unwrap();
+        builder.append(&db.db.lock(), row, 0..60, true).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_id.clone()).unwrap();
+        traverse(mp4.clone()).await;
+        let track = find_track(mp4, 1).await;
+        assert!(track.edts_cursor.is_none());
+        let mut cursor = track.stbl_cursor;
+        cursor.down().await;
+        cursor.find(b"stts").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry_count
+                // entries
+                0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x0b, 0xb8, // run length / timestamps.
+                0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x0b, 0xb8,
+            ]
+        );
+
+        cursor.find(b"stsz").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x00, // sample_size
+                0x00, 0x00, 0x00, 0x02, // sample_count
+                // entries
+                0x00, 0x00, 0x00, 0x01, // size
+                0x00, 0x00, 0x00, 0x05,
+            ]
+        );
+
+        cursor.find(b"stss").await;
+        assert_eq!(
+            cursor.get_all().await,
+            &[
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry_count
+                // entries
+                0x00, 0x00, 0x00, 0x01, // sample_number
+                0x00, 0x00, 0x00, 0x02,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timelapse_media_segment() {
+        testutil::init();
+        let mut builder = FileBuilder::new(Type::MediaSegment);
+        let e = builder
+            .timelapse(recording::Duration(90_000), 3000)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn test_append_wall_range() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        for i in 0..6 {
+            encoder.add_sample(10, 1, i % 2 == 0, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let start = row.start;
+        let mut builder = FileBuilder::new(Type::Normal);
+
+        // Clipped on both ends.
+        let actual = builder
+            .append_wall_range(
+                &db.db.lock(),
+                row,
+                &(start + recording::Duration(15)..start + recording::Duration(45)),
+            )
+            .unwrap();
+        assert_eq!(actual, start + recording::Duration(15));
+        assert_eq!(builder.segments[0].rel_media_range_90k, 15..45);
+
+        // Extends beyond the recording on both ends.
+        let mut builder = FileBuilder::new(Type::Normal);
+        let actual = builder
+            .append_wall_range(
+                &db.db.lock(),
+                row,
+                &(start - recording::Duration(100)..start + recording::Duration(100)),
+            )
+            .unwrap();
+        assert_eq!(actual, start);
+        assert_eq!(builder.segments[0].rel_media_range_90k, 0..60);
+    }
+
     #[tokio::test]
     async fn test_zero_duration_recording() {
         testutil::init();
@@ -2731,15 +3886,83 @@ mod tests {
         };
         let mut builder = FileBuilder::new(Type::InitSegment);
         builder.append_video_sample_entry(ent);
-        let mp4 = builder
-            .build(db.db.clone(), db.dirs_by_stream_id.clone())
-            .unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_id.clone()).unwrap();
         let mut hdrs = http::header::HeaderMap::new();
         mp4.add_headers(&mut hdrs);
         assert_eq!(hdrs.get("X-Aspect").unwrap(), "16:9");
         traverse(mp4.clone()).await;
     }
 
+    #[tokio::test]
+    async fn test_init_segment_with_audio() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let (video, audio) = {
+            let mut l = db.db.lock();
+            let video = l
+                .insert_video_sample_entry(db::VideoSampleEntryToInsert {
+                    width: 1920,
+                    height: 1080,
+                    pasp_h_spacing: 1,
+                    pasp_v_spacing: 1,
+                    data: [0u8; 100].to_vec(),
+                    rfc6381_codec: "avc1.000000".to_owned(),
+                })
+                .unwrap();
+            let audio = l
+                .insert_audio_sample_entry(db::AudioSampleEntryToInsert {
+                    data: b"\x00\x00\x00\x08mp4a".to_vec(),
+                    rfc6381_codec: "mp4a.40.2".to_owned(),
+                    sample_rate: 8_000,
+                    channels: 1,
+                })
+                .unwrap();
+            (
+                l.video_sample_entries_by_id().get(&video).unwrap().clone(),
+                l.audio_sample_entries_by_id().get(&audio).unwrap().clone(),
+            )
+        };
+        let mut builder = FileBuilder::new(Type::InitSegment);
+        builder.append_video_sample_entry(video);
+        builder.set_audio_sample_entry(audio).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_id.clone()).unwrap();
+        let mut hdrs = http::header::HeaderMap::new();
+        mp4.add_headers(&mut hdrs);
+        assert_eq!(
+            hdrs.get(http::header::CONTENT_TYPE).unwrap(),
+            "video/mp4; codecs=\"avc1.000000, mp4a.40.2\""
+        );
+        traverse(mp4.clone()).await;
+        find_track(mp4, 2).await;
+    }
+
+    #[test]
+    fn test_live_audio_fragment() {
+        let audio = db::LiveAudio {
+            audio_sample_entry_id: 1,
+            recording_start_90k: 0,
+            start: 0,
+            samples: vec![(1024, 2), (1024, 3)],
+            data: b"abcde".to_vec(),
+        };
+        let mut v = Vec::new();
+        append_live_audio_fragment(&mut v, &audio, 42);
+        let moof_len = BigEndian::read_u32(&v[0..4]) as usize;
+        assert_eq!(&v[4..8], b"moof");
+        assert_eq!(&v[moof_len + 4..moof_len + 8], b"mdat");
+        assert_eq!(&v[moof_len + 8..], b"abcde");
+
+        // The trun's data offset should point at the data, relative to the moof.
+        let trun = v.windows(4).position(|w| w == b"trun").unwrap() - 4;
+        assert_eq!(BigEndian::read_u32(&v[trun + 12..trun + 16]), 2); // sample_count
+        assert_eq!(
+            BigEndian::read_u32(&v[trun + 16..trun + 20]) as usize,
+            moof_len + 8
+        );
+        let tfdt = v.windows(4).position(|w| w == b"tfdt").unwrap() - 4;
+        assert_eq!(BigEndian::read_u64(&v[tfdt + 12..tfdt + 20]), 42);
+    }
+
     #[tokio::test]
     async fn test_media_segment() {
         testutil::init();
@@ -2858,6 +4081,39 @@ mod tests {
         db.syncer_join.join().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_get_parts() {
+        testutil::init();
+        let mut db = TestDb::new(RealClocks {});
+        copy_mp4_to_db(&mut db);
+        let mp4 = create_mp4_from_db(&db, 0, 0, false);
+        let filename = db.tmpdir.path().join("parts.mp4");
+        let out = fs::File::create(&filename).unwrap();
+        use std::io::Write;
+        let mut files = 0;
+        for part in mp4.get_parts(0..mp4.len()).unwrap() {
+            match part {
+                slices::Part::Chunks(c) => {
+                    let chunks: Vec<Chunk> = c.try_collect().await.unwrap();
+                    for c in chunks {
+                        (&out).write_all(c.chunk()).unwrap();
+                    }
+                }
+                slices::Part::File(r) => {
+                    r.copy_to(&out).unwrap();
+                    files += 1;
+                }
+            }
+        }
+        assert!(files > 0);
+        let mut expected = Vec::new();
+        mp4.clone().append_into_vec(&mut expected).await.unwrap();
+        assert_eq!(fs::read(&filename).unwrap(), expected);
+        drop(db.syncer_channel);
+        db.db.lock().clear_on_flush();
+        db.syncer_join.join().unwrap();
+    }
+
     #[tokio::test]
     async fn test_round_trip_with_subtitles() {
         testutil::init();
@@ -0,0 +1,376 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Importing object detection events from [Frigate](https://frigate.video/), as configured by
+//! [`FrigateConfig`].
+//!
+//! Frigate publishes an `events` message as each tracked object appears (`new`), changes
+//! (`update`), and is lost (`end`). Each `new` or `update` is stored as a detection on a stream
+//! of the Moonfire NVR camera matching the Frigate camera's name. While an object is tracked,
+//! its class's signal from that stream's [`db::json::DetectionConfig::signals`] is set, as with
+//! built-in object detection.
+
+use std::collections::BTreeMap;
+
+use base::FastHashMap;
+use db::json::DetectionConfig;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::cmds::run::config::{FrigateCameraConfig, FrigateConfig};
+use crate::motion::Tracker;
+
+/// The stream types on which to store detections, in order of preference, when none has a
+/// `detection` config. Frigate usually detects on a camera's sub stream.
+const STREAM_TYPES: [db::StreamType; 3] = [
+    db::StreamType::Sub,
+    db::StreamType::Main,
+    db::StreamType::Ext,
+];
+
+/// A message on Frigate's `events` topic.
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    type_: String,
+    after: TrackedObject,
+}
+
+/// A tracked object, as described in an [`Event`]. Frigate sends many more fields.
+#[derive(Deserialize)]
+struct TrackedObject {
+    id: String,
+    camera: String,
+    label: String,
+    #[serde(default)]
+    score: f32,
+
+    /// True if Frigate considers the object a false positive (sent only by older versions).
+    #[serde(default)]
+    false_positive: Option<bool>,
+
+    /// The time of the frame described, in seconds since epoch.
+    #[serde(default)]
+    frame_time: Option<f64>,
+
+    /// The bounding box: left, top, right, and bottom, in pixels of the detect resolution.
+    #[serde(default, rename = "box")]
+    box_: Option<[f32; 4]>,
+}
+
+/// The stream on which to record a Frigate camera's events.
+struct Target {
+    stream_id: i32,
+    config: DetectionConfig,
+
+    /// The width and height to which bounding boxes are relative, if known.
+    dimensions: Option<(u16, u16)>,
+}
+
+pub(super) struct Bridge {
+    events_topic: String,
+    available_topic: String,
+    cameras: BTreeMap<String, FrigateCameraConfig>,
+
+    /// The stream and signal (with state) of each tracked object, by Frigate's event id.
+    tracked: BTreeMap<String, (i32, Option<(u32, u16)>)>,
+    trackers: FastHashMap<(i32, u32), Tracker>,
+}
+
+impl Bridge {
+    pub(super) fn new(config: &FrigateConfig) -> Self {
+        Bridge {
+            events_topic: format!("{}/events", config.topic_prefix),
+            available_topic: format!("{}/available", config.topic_prefix),
+            cameras: config.cameras.clone(),
+            tracked: BTreeMap::new(),
+            trackers: FastHashMap::default(),
+        }
+    }
+
+    /// Returns the topics to subscribe to on each connection.
+    pub(super) fn topics(&self) -> [&str; 2] {
+        [&self.events_topic, &self.available_topic]
+    }
+
+    /// Handles a message, if it's on one of the bridge's topics.
+    pub(super) fn message(
+        &mut self,
+        l: &mut db::LockedDatabase,
+        now: recording::Time,
+        topic: &str,
+        payload: &[u8],
+    ) {
+        if topic == self.available_topic {
+            if payload != b"online" {
+                // Frigate has stopped, so its objects won't be ended explicitly.
+                self.reset();
+            }
+            return;
+        }
+        if topic != self.events_topic {
+            return;
+        }
+        let event: Event = match serde_json::from_slice(payload) {
+            Ok(e) => e,
+            Err(err) => {
+                warn!(%err, "ignoring malformed Frigate event");
+                return;
+            }
+        };
+        let o = event.after;
+        if event.type_ == "end" || o.false_positive == Some(true) {
+            self.tracked.remove(&o.id);
+            return;
+        }
+        let Some(target) = self.target(l, &o.camera) else {
+            debug!("ignoring Frigate event of unknown camera {:?}", &o.camera);
+            return;
+        };
+        if o.score < f32::from(target.config.min_score) / 100. {
+            return;
+        }
+        match (target.dimensions, o.box_) {
+            (Some((w, h)), Some([x1, y1, x2, y2])) if w > 0 && h > 0 => {
+                let (w, h) = (f32::from(w), f32::from(h));
+                let (left, top) = ((x1 / w).clamp(0., 1.), (y1 / h).clamp(0., 1.));
+                let (right, bottom) = ((x2 / w).clamp(left, 1.), (y2 / h).clamp(top, 1.));
+                let time = o.frame_time.map_or(now, |t| {
+                    recording::Time((t * TIME_UNITS_PER_SEC as f64).round() as i64)
+                });
+                let d = db::Detection {
+                    time,
+                    class: o.label.clone(),
+                    score: o.score,
+                    left,
+                    top,
+                    width: right - left,
+                    height: bottom - top,
+                };
+                if let Err(err) = l.insert_detections(target.stream_id, &[d]) {
+                    warn!(%err, "unable to store Frigate detection from {}", &o.camera);
+                }
+            }
+            _ => debug!(
+                "not storing Frigate detection from {} of unknown position",
+                &o.camera
+            ),
+        }
+        let signal = target
+            .config
+            .signals
+            .get(&o.label)
+            .map(|&s| (s, target.config.detected_state));
+        self.tracked.insert(o.id, (target.stream_id, signal));
+        self.extend(l, now);
+    }
+
+    /// Sets or extends the signals of tracked objects.
+    pub(super) fn extend(&mut self, l: &mut db::LockedDatabase, now: recording::Time) {
+        let mut signals: Vec<_> = self
+            .tracked
+            .values()
+            .filter_map(|&(stream_id, signal)| Some((stream_id, signal?)))
+            .collect();
+        signals.sort_unstable();
+        signals.dedup();
+        for (stream_id, (signal, state)) in signals {
+            let tracker = self.trackers.entry((stream_id, signal)).or_default();
+            let Some(range) = tracker.observe(now, true) else {
+                continue;
+            };
+            if let Err(err) = l.update_signals(range, &[signal], &[state]) {
+                warn!(%err, "unable to set signal {signal} from Frigate");
+            }
+        }
+    }
+
+    /// Forgets all tracked objects, as when the connection is lost. Their signals lapse.
+    pub(super) fn reset(&mut self) {
+        self.tracked.clear();
+    }
+
+    /// Finds where to record events of the given Frigate camera.
+    fn target(&self, l: &db::LockedDatabase, frigate_camera: &str) -> Option<Target> {
+        let c = self.cameras.get(frigate_camera);
+        let name = c
+            .and_then(|c| c.camera.as_deref())
+            .unwrap_or(frigate_camera);
+        let camera = l
+            .cameras_by_id()
+            .values()
+            .find(|cam| cam.short_name == name)?;
+        let streams: Vec<_> = STREAM_TYPES
+            .iter()
+            .filter_map(|t| l.streams_by_id().get(&camera.streams[t.index()]?))
+            .collect();
+        let stream = streams
+            .iter()
+            .find(|s| s.config.detection.is_some())
+            .or_else(|| streams.first())?;
+        let config = stream
+            .config
+            .detection
+            .clone()
+            .unwrap_or_else(|| DetectionConfig {
+                min_score: DetectionConfig::DEFAULT_MIN_SCORE,
+                signals: BTreeMap::new(),
+                detected_state: DetectionConfig::DEFAULT_DETECTED_STATE,
+                unknown: BTreeMap::new(),
+            });
+        let dimensions = match c {
+            Some(FrigateCameraConfig {
+                width: Some(w),
+                height: Some(h),
+                ..
+            }) => Some((*w, *h)),
+            _ => stream_dimensions(l, stream),
+        };
+        Some(Target {
+            stream_id: stream.id,
+            config,
+            dimensions,
+        })
+    }
+}
+
+/// Returns the width and height of the stream's most recent recording, if any.
+fn stream_dimensions(l: &db::LockedDatabase, stream: &db::Stream) -> Option<(u16, u16)> {
+    let end = stream.range.as_ref()?.end;
+    let mut video_sample_entry_id = None;
+    l.list_recordings_by_time(stream.id, end - recording::Duration(1)..end, &mut |r| {
+        video_sample_entry_id = Some(r.video_sample_entry_id);
+        Ok(())
+    })
+    .ok()?;
+    let e = l
+        .video_sample_entries_by_id()
+        .get(&video_sample_entry_id?)?;
+    Some((e.width, e.height))
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil::{self, TestDb};
+
+    use super::*;
+
+    fn list_detections(db: &TestDb<base::clock::RealClocks>) -> Vec<db::Detection> {
+        let mut rows = Vec::new();
+        db.db
+            .lock()
+            .list_detections(
+                testutil::TEST_STREAM_ID,
+                recording::Time::min_value()..recording::Time::max_value(),
+                None,
+                &mut |d| {
+                    rows.push(d);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        rows
+    }
+
+    #[test]
+    fn events() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        {
+            let mut l = db.db.lock();
+            let mut c = l.null_camera_change(testutil::TEST_CAMERA_ID).unwrap();
+            let mut config = DetectionConfig {
+                min_score: DetectionConfig::DEFAULT_MIN_SCORE,
+                signals: BTreeMap::new(),
+                detected_state: DetectionConfig::DEFAULT_DETECTED_STATE,
+                unknown: BTreeMap::new(),
+            };
+            config.signals.insert("person".to_owned(), 1);
+            c.streams[0].config.detection = Some(config);
+            l.update_camera(testutil::TEST_CAMERA_ID, c).unwrap();
+        }
+        let mut b = Bridge::new(&FrigateConfig {
+            topic_prefix: "frigate".to_owned(),
+            cameras: [(
+                "front".to_owned(),
+                FrigateCameraConfig {
+                    camera: Some("test camera".to_owned()),
+                    width: Some(1000),
+                    height: Some(500),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        });
+        let now = recording::Time(1_000 * TIME_UNITS_PER_SEC);
+        let event = |type_: &str, camera: &str, score: f32| {
+            serde_json::to_vec(&serde_json::json!({
+                "type": type_,
+                "before": {},
+                "after": {
+                    "id": "1700000000.0-abc",
+                    "camera": camera,
+                    "label": "person",
+                    "score": score,
+                    "frame_time": 1000.5,
+                    "box": [100, 50, 600, 550],
+                },
+            }))
+            .unwrap()
+        };
+
+        // Events of unknown cameras or with low scores are ignored.
+        let mut l = db.db.lock();
+        b.message(&mut l, now, "frigate/events", &event("new", "back", 0.9));
+        b.message(&mut l, now, "frigate/events", &event("new", "front", 0.2));
+        b.message(&mut l, now, "other", &event("new", "front", 0.9));
+        drop(l);
+        assert_eq!(list_detections(&db), []);
+        assert!(b.tracked.is_empty());
+
+        b.message(
+            &mut db.db.lock(),
+            now,
+            "frigate/events",
+            &event("new", "front", 0.9),
+        );
+        assert_eq!(
+            list_detections(&db),
+            [db::Detection {
+                time: recording::Time(1_000 * TIME_UNITS_PER_SEC + TIME_UNITS_PER_SEC / 2),
+                class: "person".to_owned(),
+                score: 0.9,
+                left: 0.1,
+                top: 0.1,
+                width: 0.5,
+                height: 0.9,
+            }]
+        );
+        assert_eq!(
+            b.tracked.values().collect::<Vec<_>>(),
+            [&(testutil::TEST_STREAM_ID, Some((1, 2)))]
+        );
+        assert!(b.trackers.contains_key(&(testutil::TEST_STREAM_ID, 1)));
+
+        b.message(
+            &mut db.db.lock(),
+            now,
+            "frigate/events",
+            &event("end", "front", 0.9),
+        );
+        assert!(b.tracked.is_empty());
+
+        // Frigate going offline forgets tracked objects.
+        b.message(
+            &mut db.db.lock(),
+            now,
+            "frigate/events",
+            &event("update", "front", 0.9),
+        );
+        assert_eq!(b.tracked.len(), 1);
+        b.message(&mut db.db.lock(), now, "frigate/available", b"offline");
+        assert!(b.tracked.is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Publishing to and subscribing from an MQTT broker, for home automation systems such as
+//! Home Assistant.
+//!
+//! A single task holds the connection and checks for changes every [`CHECK_INTERVAL`]. Under
+//! the configured topic prefix, it publishes:
+//!
+//! *   `status`: `online` or `offline` (the latter as the connection's last will), retained.
+//! *   `signals/<id>`: each signal's current value, retained.
+//! *   `cameras/<uuid>/<type>/status`: `up` or `down` for each started stream, retained.
+//! *   `cameras/<uuid>/<type>/detections`: each object detection, as it happens.
+//!
+//! Unless disabled, it also publishes Home Assistant discovery configs for the signals and
+//! streams. Everything retained is republished on each (re)connection.
+//!
+//! It also subscribes to the topics of signals' MQTT rules, setting signals as described in
+//! [`subscribe`], and if configured, to Frigate's events, as described in [`frigate`].
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{bail, Error, FastHashMap};
+use db::recording;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport,
+};
+use tracing::{info, warn};
+
+use crate::cmds::run::config::MqttConfig;
+use crate::metrics::Metrics;
+
+mod frigate;
+mod publish;
+mod subscribe;
+
+/// How often to check for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of messages which may wait to be sent before further messages are dropped.
+const QUEUE_LEN: usize = 1024;
+
+/// The delay before reconnecting after a connection error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long to wait for the `offline` status to be sent on shutdown.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Mqtt {
+    options: MqttOptions,
+    broker: String,
+    publisher: publish::Publisher,
+    frigate: Option<frigate::Bridge>,
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttConfig) -> Result<Self, Error> {
+        if config.topic_prefix.is_empty() {
+            bail!(InvalidArgument, msg("MQTT topicPrefix must be non-empty"));
+        }
+        let publisher = publish::Publisher::new(
+            &config.topic_prefix,
+            &config.discovery_prefix,
+            &config.client_id,
+        );
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            publisher.status_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        match (&config.username, &config.password) {
+            (Some(u), Some(p)) => {
+                options.set_credentials(u, p);
+            }
+            (None, None) => {}
+            _ => bail!(
+                InvalidArgument,
+                msg("MQTT username and password must be set together")
+            ),
+        }
+        if config.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+        Ok(Mqtt {
+            options,
+            broker: format!("{}:{}", config.host, config.port),
+            publisher,
+            frigate: config.frigate.as_ref().map(frigate::Bridge::new),
+        })
+    }
+}
+
+/// Queues messages for sending, returning false if the queue is full.
+fn publish(client: &AsyncClient, msgs: Vec<publish::Message>) -> bool {
+    for m in msgs {
+        if let Err(err) = client.try_publish(m.topic, QoS::AtLeastOnce, m.retain, m.payload) {
+            warn!(%err, "unable to queue MQTT message");
+            return false;
+        }
+    }
+    true
+}
+
+/// Sends the `offline` status and disconnects, waiting up to [`DISCONNECT_TIMEOUT`].
+async fn disconnect(client: &AsyncClient, eventloop: &mut EventLoop, status_topic: String) {
+    let _ = client.try_publish(status_topic, QoS::AtLeastOnce, true, "offline");
+    let _ = client.try_disconnect();
+    let drain = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    };
+    if tokio::time::timeout(DISCONNECT_TIMEOUT, drain)
+        .await
+        .is_err()
+    {
+        warn!("timed out disconnecting from MQTT broker");
+    }
+}
+
+/// Extends signals set from messages, then publishes everything which has changed since the
+/// last check.
+fn check<C: Clocks + Clone>(
+    db: &db::Database<C>,
+    metrics: &Metrics,
+    client: &AsyncClient,
+    publisher: &mut publish::Publisher,
+    subscriber: &mut subscribe::Subscriber,
+    frigate: Option<&mut frigate::Bridge>,
+) {
+    let statuses: FastHashMap<_, _> = metrics
+        .streams()
+        .into_iter()
+        .map(|(id, c)| (id, c.status.lock().unwrap().clone()))
+        .collect();
+    let now = recording::Time::new(db.clocks().realtime());
+    let mut l = db.lock();
+    subscribe::apply(&mut l, subscriber.extend(now));
+    if let Some(f) = frigate {
+        f.extend(&mut l, now);
+    }
+    let msgs = publisher.update(&l, &statuses, now);
+    drop(l);
+    if !publish(client, msgs) {
+        // Some changes may have been lost; start over.
+        publisher.reset();
+    }
+}
+
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    metrics: Arc<Metrics>,
+    shutdown_rx: base::shutdown::Receiver,
+    mqtt: Mqtt,
+) {
+    let mut publisher = mqtt.publisher;
+    let mut frigate = mqtt.frigate;
+    let mut subscriber = subscribe::Subscriber::new(db.lock().signal_mqtt_rules());
+    info!(
+        "using MQTT broker {}; subscribing to {} topic filters",
+        &mqtt.broker,
+        subscriber.topics().len()
+    );
+    let (client, mut eventloop) = AsyncClient::new(mqtt.options, QUEUE_LEN);
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut connected = false;
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.as_future() => break,
+            e = eventloop.poll() => match e {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker {}", &mqtt.broker);
+                    connected = true;
+                    let frigate_topics = frigate.iter().flat_map(|f| f.topics());
+                    for topic in subscriber.topics().into_iter().chain(frigate_topics) {
+                        if let Err(err) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            warn!(%err, "unable to subscribe to MQTT topic {topic}");
+                        }
+                    }
+                    publisher.reset();
+                    let online = publish::Message {
+                        topic: publisher.status_topic(),
+                        payload: b"online".to_vec(),
+                        retain: true,
+                    };
+                    if publish(&client, vec![online]) {
+                        check(
+                            &db,
+                            &metrics,
+                            &client,
+                            &mut publisher,
+                            &mut subscriber,
+                            frigate.as_mut(),
+                        );
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    let now = recording::Time::new(db.clocks().realtime());
+                    let updates = subscriber.message(now, &p.topic, &p.payload);
+                    if !updates.is_empty() {
+                        subscribe::apply(&mut db.lock(), updates);
+                    }
+                    if let Some(ref mut f) = frigate {
+                        f.message(&mut db.lock(), now, &p.topic, &p.payload);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    if connected {
+                        warn!(%err, "lost connection to MQTT broker {}", &mqtt.broker);
+                    } else {
+                        warn!(%err, "unable to connect to MQTT broker {}", &mqtt.broker);
+                    }
+                    connected = false;
+                    subscriber.reset();
+                    if let Some(ref mut f) = frigate {
+                        f.reset();
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                        _ = shutdown_rx.as_future() => break,
+                    }
+                }
+            },
+            _ = interval.tick(), if connected => {
+                check(
+                    &db,
+                    &metrics,
+                    &client,
+                    &mut publisher,
+                    &mut subscriber,
+                    frigate.as_mut(),
+                );
+            },
+        }
+    }
+    if connected {
+        disconnect(&client, &mut eventloop, publisher.status_topic()).await;
+    }
+}
@@ -0,0 +1,480 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Computing the messages to publish: state changes since the last check, new detections, and
+//! Home Assistant discovery configs for newly seen entities.
+
+use base::FastHashMap;
+use db::recording;
+use serde_json::json;
+use tracing::warn;
+
+use crate::metrics::StreamStatus;
+
+/// A message to publish.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Message {
+    pub(super) topic: String,
+    pub(super) payload: Vec<u8>,
+    pub(super) retain: bool,
+}
+
+impl Message {
+    fn retained(topic: String, payload: impl Into<Vec<u8>>) -> Self {
+        Message {
+            topic,
+            payload: payload.into(),
+            retain: true,
+        }
+    }
+}
+
+/// A published entity: a signal or stream.
+struct Entity<S> {
+    /// The last published state.
+    state: S,
+
+    /// The topic of the (retained) state.
+    topic: String,
+
+    /// The topic of the (retained) Home Assistant discovery config, if any.
+    discovery_topic: Option<String>,
+}
+
+impl<S> Entity<S> {
+    /// Returns messages which clear the entity's retained messages, after it's been deleted.
+    fn clear(self, msgs: &mut Vec<Message>) {
+        msgs.push(Message::retained(self.topic, ""));
+        if let Some(t) = self.discovery_topic {
+            msgs.push(Message::retained(t, ""));
+        }
+    }
+}
+
+/// Tracks what has been published to the broker.
+pub(super) struct Publisher {
+    topic_prefix: String,
+
+    /// The Home Assistant discovery prefix, or `None` if discovery is disabled.
+    discovery_prefix: Option<String>,
+
+    /// The Home Assistant node id: the client id, restricted to characters valid in topics.
+    node_id: String,
+
+    /// Signals by id, with their value.
+    signals: FastHashMap<u32, Entity<u16>>,
+
+    /// Streams by id, with true if up.
+    streams: FastHashMap<i32, Entity<bool>>,
+
+    /// The time of the latest published detection of each stream with object detection.
+    detections_after: FastHashMap<i32, recording::Time>,
+}
+
+impl Publisher {
+    pub(super) fn new(topic_prefix: &str, discovery_prefix: &str, client_id: &str) -> Self {
+        Publisher {
+            topic_prefix: topic_prefix.trim_end_matches('/').to_owned(),
+            discovery_prefix: (!discovery_prefix.is_empty())
+                .then(|| discovery_prefix.trim_end_matches('/').to_owned()),
+            node_id: client_id
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect(),
+            signals: FastHashMap::default(),
+            streams: FastHashMap::default(),
+            detections_after: FastHashMap::default(),
+        }
+    }
+
+    /// Returns the topic of the availability of Moonfire NVR as a whole: `online` or `offline`.
+    pub(super) fn status_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// Forgets what has been published, so that the next [`Publisher::update`] publishes all
+    /// discovery configs and states. This should be called on each (re)connection, as the
+    /// broker may have lost retained messages.
+    ///
+    /// Detections aren't retained, so the next update still publishes only new ones.
+    pub(super) fn reset(&mut self) {
+        self.signals.clear();
+        self.streams.clear();
+    }
+
+    /// Returns messages describing everything which has changed since the last update.
+    ///
+    /// `statuses` holds the status of each started stream, as from
+    /// [`crate::metrics::Metrics::streams`].
+    pub(super) fn update(
+        &mut self,
+        l: &db::LockedDatabase,
+        statuses: &FastHashMap<i32, StreamStatus>,
+        now: recording::Time,
+    ) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        self.update_signals(l, now, &mut msgs);
+        self.update_streams(l, statuses, now, &mut msgs);
+        self.update_detections(l, now, &mut msgs);
+        msgs
+    }
+
+    fn update_signals(
+        &mut self,
+        l: &db::LockedDatabase,
+        now: recording::Time,
+        msgs: &mut Vec<Message>,
+    ) {
+        let mut cur = FastHashMap::default();
+        l.list_changes_by_time(now..now + recording::Duration(1), &mut |r| {
+            cur.insert(r.signal, r.state);
+        });
+        for id in self
+            .signals
+            .keys()
+            .filter(|id| !l.signals_by_id().contains_key(id))
+            .copied()
+            .collect::<Vec<_>>()
+        {
+            self.signals.remove(&id).expect("id exists").clear(msgs);
+        }
+        for (&id, s) in l.signals_by_id() {
+            let state = cur.get(&id).copied().unwrap_or(0);
+            let type_ = l.signal_types_by_uuid().get(&s.type_);
+            match self.signals.get_mut(&id) {
+                Some(e) if e.state == state => continue,
+                Some(e) => e.state = state,
+                None => {
+                    let topic = format!("{}/signals/{id}", self.topic_prefix);
+                    let discovery = self.signal_discovery(s, type_, &topic);
+                    let discovery_topic = discovery.as_ref().map(|m| m.topic.clone());
+                    msgs.extend(discovery);
+                    self.signals.insert(
+                        id,
+                        Entity {
+                            state,
+                            topic,
+                            discovery_topic,
+                        },
+                    );
+                }
+            }
+            let mut payload = json!({ "value": state });
+            if let Some(v) = type_.and_then(|t| t.config.values.get(&u8::try_from(state).ok()?)) {
+                payload["name"] = v.name.clone().into();
+                payload["motion"] = v.motion.into();
+            }
+            msgs.push(Message::retained(
+                self.signals[&id].topic.clone(),
+                payload.to_string(),
+            ));
+        }
+    }
+
+    fn update_streams(
+        &mut self,
+        l: &db::LockedDatabase,
+        statuses: &FastHashMap<i32, StreamStatus>,
+        now: recording::Time,
+        msgs: &mut Vec<Message>,
+    ) {
+        for id in self
+            .streams
+            .keys()
+            .filter(|id| !l.streams_by_id().contains_key(id))
+            .copied()
+            .collect::<Vec<_>>()
+        {
+            self.streams.remove(&id).expect("id exists").clear(msgs);
+        }
+        for (&id, status) in statuses {
+            let Some(s) = l.streams_by_id().get(&id) else {
+                continue;
+            };
+            let up = status.running && !status.is_down(now);
+            match self.streams.get_mut(&id) {
+                Some(e) if e.state == up => continue,
+                Some(e) => e.state = up,
+                None => {
+                    let c = &l.cameras_by_id()[&s.camera_id];
+                    let topic = format!(
+                        "{}/cameras/{}/{}/status",
+                        self.topic_prefix,
+                        c.uuid,
+                        s.type_.as_str()
+                    );
+                    let discovery = self.stream_discovery(c, s, &topic);
+                    let discovery_topic = discovery.as_ref().map(|m| m.topic.clone());
+                    msgs.extend(discovery);
+                    self.streams.insert(
+                        id,
+                        Entity {
+                            state: up,
+                            topic,
+                            discovery_topic,
+                        },
+                    );
+                }
+            }
+            msgs.push(Message::retained(
+                self.streams[&id].topic.clone(),
+                if up { "up" } else { "down" },
+            ));
+        }
+    }
+
+    fn update_detections(
+        &mut self,
+        l: &db::LockedDatabase,
+        now: recording::Time,
+        msgs: &mut Vec<Message>,
+    ) {
+        self.detections_after.retain(|id, _| {
+            l.streams_by_id()
+                .get(id)
+                .map_or(false, |s| s.config.detection.is_some())
+        });
+        for (&id, s) in l.streams_by_id() {
+            if s.config.detection.is_none() {
+                continue;
+            }
+
+            // Detections from before the first check are old news.
+            let after = self.detections_after.entry(id).or_insert(now);
+            let c = &l.cameras_by_id()[&s.camera_id];
+            let topic = format!(
+                "{}/cameras/{}/{}/detections",
+                self.topic_prefix,
+                c.uuid,
+                s.type_.as_str()
+            );
+            let r = l.list_detections(
+                id,
+                *after + recording::Duration(1)..recording::Time::max_value(),
+                None,
+                &mut |d| {
+                    *after = std::cmp::max(*after, d.time);
+                    let payload = json!({
+                        "time90k": d.time.0,
+                        "class": d.class,
+                        "score": d.score,
+                        "left": d.left,
+                        "top": d.top,
+                        "width": d.width,
+                        "height": d.height,
+                    });
+                    msgs.push(Message {
+                        topic: topic.clone(),
+                        payload: payload.to_string().into_bytes(),
+                        retain: false,
+                    });
+                    Ok(())
+                },
+            );
+            if let Err(err) = r {
+                warn!(err = %err.chain(), "unable to list detections of {}", c.short_name);
+            }
+        }
+    }
+
+    /// Returns a signal's Home Assistant discovery config, if discovery is enabled.
+    ///
+    /// The signal is a motion sensor if any of its type's values represent motion, or a plain
+    /// sensor of its value's name otherwise.
+    fn signal_discovery(
+        &self,
+        s: &db::Signal,
+        type_: Option<&db::signal::Type>,
+        state_topic: &str,
+    ) -> Option<Message> {
+        let prefix = self.discovery_prefix.as_ref()?;
+        let mut config = json!({
+            "name": &s.config.short_name,
+            "unique_id": format!("{}_signal_{}", self.node_id, s.uuid),
+            "state_topic": state_topic,
+            "availability_topic": self.status_topic(),
+            "device": self.nvr_device(),
+        });
+        let component = if type_.map_or(false, |t| t.config.values.values().any(|v| v.motion)) {
+            config["device_class"] = "motion".into();
+            config["value_template"] = "{{ 'ON' if value_json.motion else 'OFF' }}".into();
+            "binary_sensor"
+        } else {
+            config["value_template"] = "{{ value_json.name | default(value_json.value) }}".into();
+            "sensor"
+        };
+        Some(Message::retained(
+            format!(
+                "{prefix}/{component}/{}/signal_{}/config",
+                self.node_id, s.id
+            ),
+            config.to_string(),
+        ))
+    }
+
+    /// Returns a stream's Home Assistant discovery config, if discovery is enabled.
+    fn stream_discovery(
+        &self,
+        c: &db::Camera,
+        s: &db::Stream,
+        state_topic: &str,
+    ) -> Option<Message> {
+        let prefix = self.discovery_prefix.as_ref()?;
+        let object_id = format!("{}_{}_status", c.uuid, s.type_.as_str());
+        let config = json!({
+            "name": format!("{} stream", s.type_.as_str()),
+            "unique_id": format!("{}_{}", self.node_id, object_id),
+            "state_topic": state_topic,
+            "payload_on": "up",
+            "payload_off": "down",
+            "device_class": "connectivity",
+            "availability_topic": self.status_topic(),
+            "device": {
+                "identifiers": [format!("{}_{}", self.node_id, c.uuid)],
+                "name": &c.short_name,
+                "via_device": &self.node_id,
+            },
+        });
+        Some(Message::retained(
+            format!("{prefix}/binary_sensor/{}/{object_id}/config", self.node_id),
+            config.to_string(),
+        ))
+    }
+
+    fn nvr_device(&self) -> serde_json::Value {
+        json!({
+            "identifiers": [&self.node_id],
+            "name": "Moonfire NVR",
+            "manufacturer": "Moonfire NVR",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil::{self, TestDb};
+
+    use super::*;
+
+    #[test]
+    fn update() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let uuid = db.db.lock().cameras_by_id()[&testutil::TEST_CAMERA_ID].uuid;
+        let mut p = Publisher::new("nvr/", "homeassistant", "moonfire-nvr");
+        let now = recording::Time(1_000 * recording::TIME_UNITS_PER_SEC);
+        let mut statuses = FastHashMap::default();
+        statuses.insert(
+            testutil::TEST_STREAM_ID,
+            StreamStatus {
+                running: true,
+                connected: true,
+                last_frame: Some(now),
+                last_error: None,
+            },
+        );
+
+        // The first update announces and publishes the stream.
+        let state_topic = format!("nvr/cameras/{uuid}/main/status");
+        let msgs = p.update(&db.db.lock(), &statuses, now);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(
+            msgs[0].topic,
+            format!("homeassistant/binary_sensor/moonfire-nvr/{uuid}_main_status/config")
+        );
+        let config: serde_json::Value = serde_json::from_slice(&msgs[0].payload).unwrap();
+        assert_eq!(config["state_topic"], state_topic.as_str());
+        assert_eq!(config["availability_topic"], "nvr/status");
+        assert_eq!(msgs[1], Message::retained(state_topic.clone(), "up"));
+
+        // Nothing changed, so nothing is published.
+        assert_eq!(p.update(&db.db.lock(), &statuses, now), []);
+
+        // A stream without recent frames is down.
+        let later = now + recording::Duration(60 * recording::TIME_UNITS_PER_SEC);
+        assert_eq!(
+            p.update(&db.db.lock(), &statuses, later),
+            [Message::retained(state_topic.clone(), "down")]
+        );
+
+        // After a reconnection, the discovery config and state are published again.
+        p.reset();
+        let msgs = p.update(&db.db.lock(), &statuses, later);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1], Message::retained(state_topic, "down"));
+    }
+
+    #[test]
+    fn detections() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let uuid = {
+            let mut l = db.db.lock();
+            let mut c = l.null_camera_change(testutil::TEST_CAMERA_ID).unwrap();
+            c.streams[0].config.detection = Some(db::json::DetectionConfig {
+                min_score: db::json::DetectionConfig::DEFAULT_MIN_SCORE,
+                signals: Default::default(),
+                detected_state: db::json::DetectionConfig::DEFAULT_DETECTED_STATE,
+                unknown: Default::default(),
+            });
+            l.update_camera(testutil::TEST_CAMERA_ID, c).unwrap();
+            l.cameras_by_id()[&testutil::TEST_CAMERA_ID].uuid
+        };
+        let mut p = Publisher::new("nvr", "", "moonfire-nvr");
+        let now = recording::Time(1_000 * recording::TIME_UNITS_PER_SEC);
+        let detection = |time, class: &str| db::Detection {
+            time,
+            class: class.to_owned(),
+            score: 0.75,
+            left: 0.,
+            top: 0.,
+            width: 0.5,
+            height: 0.5,
+        };
+
+        // Detections from before the first update aren't published.
+        db.db
+            .lock()
+            .insert_detections(
+                testutil::TEST_STREAM_ID,
+                &[detection(now - recording::Duration(1), "cat")],
+            )
+            .unwrap();
+        assert_eq!(p.update(&db.db.lock(), &FastHashMap::default(), now), []);
+
+        db.db
+            .lock()
+            .insert_detections(
+                testutil::TEST_STREAM_ID,
+                &[
+                    detection(now + recording::Duration(1), "person"),
+                    detection(now + recording::Duration(1), "dog"),
+                ],
+            )
+            .unwrap();
+        let msgs = p.update(&db.db.lock(), &FastHashMap::default(), now);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].topic, format!("nvr/cameras/{uuid}/main/detections"));
+        assert!(!msgs[0].retain);
+        let classes: Vec<_> = msgs
+            .iter()
+            .map(|m| {
+                let d: serde_json::Value = serde_json::from_slice(&m.payload).unwrap();
+                d["class"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert!(classes.contains(&"person".to_owned()));
+        assert!(classes.contains(&"dog".to_owned()));
+
+        // Each detection is published once.
+        assert_eq!(p.update(&db.db.lock(), &FastHashMap::default(), now), []);
+    }
+}
@@ -0,0 +1,165 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Setting signals from MQTT messages, as described by each signal's
+//! [`db::json::SignalConfig::mqtt`] rules.
+//!
+//! Signal states are held as described in [`crate::signal_hold`] while connected to the
+//! broker. On reconnection, the broker redelivers retained messages, restoring the states of
+//! sources which retain theirs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use db::recording;
+use db::signal::MqttRules;
+use tracing::{debug, warn};
+
+use crate::signal_hold::Holder;
+
+/// A signal state to set.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Update {
+    signal: u32,
+    state: u16,
+    range: Range<recording::Time>,
+}
+
+pub(super) struct Subscriber {
+    rules: MqttRules,
+    held: BTreeMap<u32, Holder>,
+}
+
+impl Subscriber {
+    pub(super) fn new(rules: MqttRules) -> Self {
+        Subscriber {
+            rules,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the topic filters to subscribe to on each connection.
+    pub(super) fn topics(&self) -> BTreeSet<&str> {
+        self.rules.topics()
+    }
+
+    /// Notes a received message, returning states to set.
+    pub(super) fn message(
+        &mut self,
+        now: recording::Time,
+        topic: &str,
+        payload: &[u8],
+    ) -> Vec<Update> {
+        let mut updates = Vec::new();
+        for (signal, state) in self.rules.matches(topic, payload) {
+            debug!("MQTT message on {topic} sets signal {signal} to {state}");
+            let Some(range) = self.held.entry(signal).or_default().set(now, now, state) else {
+                continue;
+            };
+            updates.push(Update {
+                signal,
+                state,
+                range,
+            });
+        }
+        updates
+    }
+
+    /// Returns extensions of held states which are due as of `now`.
+    pub(super) fn extend(&mut self, now: recording::Time) -> Vec<Update> {
+        let mut updates = Vec::new();
+        for (&signal, h) in &mut self.held {
+            if let Some((range, state)) = h.extend(now) {
+                updates.push(Update {
+                    signal,
+                    state,
+                    range,
+                });
+            }
+        }
+        updates
+    }
+
+    /// Forgets all states, as when the connection is lost. They lapse at the end of their holds.
+    pub(super) fn reset(&mut self) {
+        self.held.clear();
+    }
+}
+
+/// Applies the given updates to the database, logging failures.
+pub(super) fn apply(l: &mut db::LockedDatabase, updates: Vec<Update>) {
+    for u in updates {
+        if let Err(err) = l.update_signals(u.range, &[u.signal], &[u.state]) {
+            warn!(%err, "unable to set signal {} from MQTT", u.signal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::recording::TIME_UNITS_PER_SEC;
+    use db::testutil;
+
+    use super::*;
+    use crate::signal_hold::HOLD;
+
+    fn rules() -> MqttRules {
+        let mut config = db::json::SignalConfig::default();
+        config.mqtt.push(db::json::SignalMqttRule {
+            topic: "door".to_owned(),
+            payloads: [("open".to_owned(), 2), ("closed".to_owned(), 1)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        });
+        MqttRules::from_signal_configs([(1, &config)])
+    }
+
+    #[test]
+    fn hold() {
+        testutil::init();
+        let mut s = Subscriber::new(rules());
+        let sec = |s| recording::Duration(s * TIME_UNITS_PER_SEC);
+        let t0 = recording::Time(1_000 * TIME_UNITS_PER_SEC);
+        assert_eq!(s.message(t0, "other", b"open"), []);
+        assert_eq!(s.message(t0, "door", b"ajar"), []);
+        assert_eq!(
+            s.message(t0, "door", b"open"),
+            [Update {
+                signal: 1,
+                state: 2,
+                range: t0..t0 + HOLD,
+            }]
+        );
+
+        // A repeated message doesn't change anything.
+        assert_eq!(s.message(t0 + sec(1), "door", b"open"), []);
+
+        // The state is extended once enough time has passed.
+        assert_eq!(s.extend(t0 + sec(1)), []);
+        assert_eq!(
+            s.extend(t0 + sec(15)),
+            [Update {
+                signal: 1,
+                state: 2,
+                range: t0 + HOLD..t0 + sec(15) + HOLD,
+            }]
+        );
+
+        // A change takes effect immediately.
+        let t1 = t0 + sec(20);
+        assert_eq!(
+            s.message(t1, "door", b"closed"),
+            [Update {
+                signal: 1,
+                state: 1,
+                range: t1..t1 + HOLD,
+            }]
+        );
+
+        // After a reset, nothing is extended.
+        s.reset();
+        assert_eq!(s.extend(t1 + sec(20)), []);
+    }
+}
@@ -0,0 +1,259 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Fetching long-lived `multipart/*` HTTP responses from cameras and splitting them into parts,
+//! as used by MJPEG streams and vendor event streams.
+
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use bytes::{Buf, Bytes, BytesMut};
+use memchr::memmem;
+use tokio_rustls::rustls;
+use url::Url;
+
+pub type Client = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// Returns a client for `http://` and `https://` URLs, trusting the given TLS configuration's
+/// roots or, by default, the web PKI roots.
+pub fn client(tls: Option<Arc<rustls::ClientConfig>>) -> Client {
+    let builder = hyper_rustls::HttpsConnectorBuilder::new();
+    let builder = match tls {
+        Some(c) => builder.with_tls_config((*c).clone()),
+        None => builder.with_webpki_roots(),
+    };
+    hyper::Client::builder().build(builder.https_or_http().enable_http1().build())
+}
+
+/// Fetches `url`, retrying with `Authorization` if challenged.
+pub async fn get(
+    client: &Client,
+    url: &Url,
+    creds: Option<&retina::client::Credentials>,
+) -> Result<hyper::Response<hyper::Body>, Error> {
+    let mut authorization = None;
+    loop {
+        let mut req = hyper::Request::get(url.as_str()).header(
+            http::header::USER_AGENT,
+            format!("Moonfire NVR {}", env!("CARGO_PKG_VERSION")),
+        );
+        if let Some(a) = &authorization {
+            req = req.header(http::header::AUTHORIZATION, a);
+        }
+        let req = req
+            .body(hyper::Body::empty())
+            .map_err(|e| err!(InvalidArgument, msg("bad request for {url}"), source(e)))?;
+        let resp = client
+            .request(req)
+            .await
+            .map_err(|e| err!(Unavailable, msg("unable to fetch {url}"), source(e)))?;
+        let status = resp.status();
+        if status == http::StatusCode::UNAUTHORIZED && authorization.is_none() {
+            if let Some(c) = creds {
+                let challenges: Vec<&str> = resp
+                    .headers()
+                    .get_all(http::header::WWW_AUTHENTICATE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .collect();
+                let mut client = http_auth::PasswordClient::try_from(
+                    challenges.join(", ").as_str(),
+                )
+                .map_err(|e| {
+                    err!(
+                        Unauthenticated,
+                        msg("unsupported authentication challenge: {e}")
+                    )
+                })?;
+                let a = client
+                    .respond(&http_auth::PasswordParams {
+                        username: &c.username,
+                        password: &c.password,
+                        uri: &url[url::Position::BeforePath..url::Position::AfterQuery],
+                        method: "GET",
+                        body: Some(&[]),
+                    })
+                    .map_err(|e| err!(Unauthenticated, msg("unable to authenticate: {e}")))?;
+                authorization = Some(a);
+                continue;
+            }
+        }
+        if status == http::StatusCode::UNAUTHORIZED {
+            bail!(Unauthenticated, msg("{url} rejected credentials"));
+        }
+        if !status.is_success() {
+            bail!(Unavailable, msg("{url} returned HTTP status {status}"));
+        }
+        return Ok(resp);
+    }
+}
+
+/// Returns the boundary of a `multipart/*` content type, without any leading `--`.
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    params.split(';').find_map(|p| {
+        let (name, value) = p.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"').trim_start_matches("--");
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+/// Returns the end of the first blank line (`\r\n\r\n` or `\n\n`) in `buf`.
+fn head_end(buf: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    while let Some(i) = memchr::memchr(b'\n', &buf[pos..]) {
+        let after = pos + i + 1;
+        match buf.get(after..) {
+            Some([b'\n', ..]) => return Some(after + 1),
+            Some([b'\r', b'\n', ..]) => return Some(after + 2),
+            _ => pos = after,
+        }
+    }
+    None
+}
+
+/// A part of a multipart body.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Part {
+    /// The part's `Content-Type`, lowercased, if supplied.
+    pub content_type: Option<String>,
+
+    /// The part's body, without trailing line breaks.
+    pub body: Bytes,
+}
+
+/// Splits a multipart body into its parts.
+///
+/// Parts end at their `Content-Length` if supplied or the next delimiter otherwise.
+pub struct Parts {
+    /// `--` followed by the boundary.
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+
+    /// The longest part accepted, including its headers.
+    max_part_len: usize,
+}
+
+impl Parts {
+    pub fn new(boundary: &str, max_part_len: usize) -> Self {
+        Parts {
+            delimiter: format!("--{boundary}").into_bytes(),
+            buf: BytesMut::new(),
+            max_part_len,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next part, or `None` if more data is needed.
+    pub fn next(&mut self) -> Result<Option<Part>, Error> {
+        if self.buf.len() > self.max_part_len {
+            bail!(
+                OutOfRange,
+                msg("multipart part exceeds {} bytes", self.max_part_len)
+            );
+        }
+        let Some(start) = memmem::find(&self.buf, &self.delimiter) else {
+            return Ok(None);
+        };
+        let Some(head_len) = head_end(&self.buf[start..]) else {
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&self.buf[start..start + head_len]);
+        let mut content_type = None;
+        let mut content_length = None;
+        for line in head.lines().skip(1) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.to_ascii_lowercase());
+            } else if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(value.parse::<usize>().map_err(|_| {
+                    err!(
+                        InvalidArgument,
+                        msg("bad multipart Content-Length {value:?}")
+                    )
+                })?);
+            }
+        }
+        let body_start = start + head_len;
+        let body_len = match content_length {
+            Some(l) if self.buf.len() >= body_start + l => l,
+            Some(_) => return Ok(None),
+            None => match memmem::find(&self.buf[body_start..], &self.delimiter) {
+                Some(l) => l,
+                None => return Ok(None),
+            },
+        };
+        self.buf.advance(body_start);
+        let mut body = self.buf.split_to(body_len).freeze();
+        while body.ends_with(b"\n") || body.ends_with(b"\r") {
+            body.truncate(body.len() - 1);
+        }
+        Ok(Some(Part { content_type, body }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn content_type_boundary() {
+        testutil::init();
+        assert_eq!(
+            boundary("multipart/x-mixed-replace; boundary=myboundary"),
+            Some("myboundary")
+        );
+        assert_eq!(
+            boundary("multipart/x-mixed-replace;boundary=\"--foo\""),
+            Some("foo")
+        );
+        assert_eq!(boundary("image/jpeg"), None);
+        assert_eq!(boundary("text/html; charset=utf-8"), None);
+    }
+
+    #[test]
+    fn parts() {
+        testutil::init();
+        let body = b"--foo\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello\r\n\
+                     --foo\r\nContent-Type: Application/XML\r\n\r\n<a/>\r\n\r\n--foo\r\n";
+        let mut p = Parts::new("foo", 1 << 10);
+        let mut parts = Vec::new();
+        for b in body.chunks(3) {
+            p.push(b);
+            while let Some(part) = p.next().unwrap() {
+                parts.push(part);
+            }
+        }
+        assert_eq!(
+            parts,
+            [
+                Part {
+                    content_type: Some("text/plain".to_owned()),
+                    body: Bytes::from_static(b"hello"),
+                },
+                Part {
+                    content_type: Some("application/xml".to_owned()),
+                    body: Bytes::from_static(b"<a/>"),
+                },
+            ]
+        );
+
+        let mut p = Parts::new("foo", 4);
+        p.push(b"--foo\r\n");
+        p.next().unwrap_err();
+    }
+}
@@ -0,0 +1,219 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Email notifications: each event is sent through an SMTP relay to the users whose
+//! `alertEmail` asks for alerts of its severity.
+
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{bail, err, Error};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::warn;
+
+use super::{Event, Severity};
+use crate::cmds::run::config::SmtpConfig;
+
+/// The timeout for each SMTP command.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    host: String,
+}
+
+impl Email {
+    pub fn new(config: &SmtpConfig) -> Result<Self, Error> {
+        let from: Mailbox = config.from.parse().map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad SMTP from address {:?}", config.from),
+                source(e)
+            )
+        })?;
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+            .map_err(|e| {
+                err!(
+                    InvalidArgument,
+                    msg("bad SMTP host {:?}", config.host),
+                    source(e)
+                )
+            })?
+            .port(config.port)
+            .timeout(Some(TIMEOUT));
+        match (&config.username, &config.password) {
+            (Some(u), Some(p)) => {
+                builder = builder.credentials(Credentials::new(u.clone(), p.clone()));
+            }
+            (None, None) => {}
+            _ => bail!(
+                InvalidArgument,
+                msg("SMTP username and password must be set together")
+            ),
+        }
+        Ok(Email {
+            transport: builder.build(),
+            from,
+            host: config.host.clone(),
+        })
+    }
+
+    pub(super) fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Sends an event to all interested users, if any.
+    ///
+    /// Returns an `InvalidArgument` error if the relay rejected the message permanently.
+    pub(super) async fn send<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        event: &Event,
+    ) -> Result<(), Error> {
+        let to = recipients(&db.lock(), event.alert.severity);
+        if to.is_empty() {
+            return Ok(());
+        }
+        let mut m = Message::builder()
+            .from(self.from.clone())
+            .subject(subject(event))
+            .header(ContentType::TEXT_PLAIN);
+        for t in to {
+            m = m.to(t);
+        }
+        let m = m
+            .body(body(event))
+            .map_err(|e| err!(Internal, msg("unable to build alert email"), source(e)))?;
+        self.transport.send(m).await.map_err(|e| {
+            if e.is_permanent() {
+                err!(
+                    InvalidArgument,
+                    msg("SMTP relay rejected alert email"),
+                    source(e)
+                )
+            } else {
+                err!(Unavailable, msg("unable to send alert email"), source(e))
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Checks a user's `alertEmail` before it's saved.
+pub fn check_alert_email(e: &db::json::AlertEmail) -> Result<(), Error> {
+    if let Err(err) = Address::from_str(&e.address) {
+        bail!(
+            InvalidArgument,
+            msg("bad alert email address {:?}", e.address),
+            source(err)
+        );
+    }
+    Ok(())
+}
+
+/// Returns the mailboxes of enabled users who want alerts of the given severity.
+fn recipients(l: &db::LockedDatabase, severity: Severity) -> Vec<Mailbox> {
+    l.users_by_id()
+        .values()
+        .filter(|u| !u.config.disabled)
+        .filter_map(|u| {
+            let e = u.config.alert_email.as_ref()?;
+            if severity < Severity::parse_min(&e.min_severity) {
+                return None;
+            }
+            match Address::from_str(&e.address) {
+                Ok(a) => Some(Mailbox::new(Some(u.username.clone()), a)),
+                Err(err) => {
+                    warn!(%err, "ignoring bad alert email address of user {}", u.username);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn subject(event: &Event) -> String {
+    format!(
+        "[Moonfire NVR] {}: {}",
+        event.state.as_str().to_uppercase(),
+        event.alert.summary
+    )
+}
+
+fn body(event: &Event) -> String {
+    let mut b = format!(
+        "{}\n\nstate: {}\nseverity: {}\nsince: {}\n",
+        event.alert.summary,
+        event.state.as_str(),
+        event.alert.severity.as_str(),
+        event.since_90k,
+    );
+    if let Some(uuid) = event.alert.camera_uuid {
+        writeln!(&mut b, "camera: {uuid}").expect("String write is infallible");
+    }
+    writeln!(&mut b, "key: {}", event.alert.key).expect("String write is infallible");
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil::{self, TestDb};
+
+    use super::*;
+
+    fn add_user(db: &TestDb<base::clock::RealClocks>, name: &str, min_severity: &str) {
+        let mut c = db::UserChange::add_user(name.to_owned());
+        c.config.alert_email = Some(db::json::AlertEmail {
+            address: format!("{name}@example.com"),
+            min_severity: min_severity.to_owned(),
+            ..Default::default()
+        });
+        db.db.lock().apply_user_change(c).unwrap();
+    }
+
+    #[test]
+    fn recipients() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        add_user(&db, "all", "");
+        add_user(&db, "crit", "critical");
+        let mut c = db::UserChange::add_user("disabled".to_owned());
+        c.config.disabled = true;
+        c.config.alert_email = Some(db::json::AlertEmail {
+            address: "disabled@example.com".to_owned(),
+            ..Default::default()
+        });
+        db.db.lock().apply_user_change(c).unwrap();
+
+        let addresses = |severity| {
+            let mut a: Vec<String> = super::recipients(&db.db.lock(), severity)
+                .into_iter()
+                .map(|m| m.email.to_string())
+                .collect();
+            a.sort();
+            a
+        };
+        assert_eq!(addresses(Severity::Warning), ["all@example.com"]);
+        assert_eq!(
+            addresses(Severity::Critical),
+            ["all@example.com", "crit@example.com"]
+        );
+    }
+
+    #[test]
+    fn check_alert_email() {
+        let mut e = db::json::AlertEmail {
+            address: "nvr@example.com".to_owned(),
+            ..Default::default()
+        };
+        super::check_alert_email(&e).unwrap();
+        e.address = "nope".to_owned();
+        super::check_alert_email(&e).unwrap_err();
+    }
+}
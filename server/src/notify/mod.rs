@@ -0,0 +1,522 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Notifications of stream and disk failures, via webhooks and email.
+//!
+//! A single task checks for problems every [`CHECK_INTERVAL`]. Each problem is an [`Alert`]
+//! identified by a key such as `stream/<camera uuid>/main`. An [`Event`] is sent when an alert
+//! starts firing and another when it resolves, so each sink sees each transition once no matter
+//! how long the problem lasts. Each sink has its own queue and task, which delivers events in
+//! order and retries failures.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{Error, FastHashMap};
+use db::recording;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::cmds::run::config::NotificationsConfig;
+use crate::metrics::Metrics;
+
+pub mod email;
+pub mod webhook;
+
+/// How often to check for problems.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of events which may wait for delivery to a sink before further events are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// The number of times to try delivering an event before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry; it doubles with each subsequent retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// An alert's severity, ordered from least to most severe.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+
+    /// Parses a user's `minSeverity`, treating unknown values as the least severe.
+    fn parse_min(s: &str) -> Self {
+        match s {
+            "critical" => Severity::Critical,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertKind {
+    /// A stream has been disconnected or without frames for at least `streamDownSec`.
+    StreamDown,
+
+    /// A sample file directory needed for recording isn't open or can't be examined.
+    DirError,
+
+    /// A sample file directory's filesystem has less than `dirMinAvailableBytes` available.
+    DirLowSpace,
+
+    /// A recording has waited too long to be committed to the database.
+    FlushLag,
+}
+
+/// A problem found by a check.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    /// Identifies the alert across checks, for de-duplication.
+    pub key: String,
+    pub kind: AlertKind,
+    pub severity: Severity,
+
+    /// A human-readable description of the problem, as of the latest check.
+    pub summary: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_type: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir_id: Option<i32>,
+
+    /// How long the problem must persist before the alert fires.
+    #[serde(skip)]
+    hold: recording::Duration,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum State {
+    Firing,
+    Resolved,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Firing => "firing",
+            State::Resolved => "resolved",
+        }
+    }
+}
+
+/// A transition of an alert, as sent to sinks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub state: State,
+
+    /// The time of this transition.
+    pub time_90k: recording::Time,
+
+    /// When the problem was first observed.
+    pub since_90k: recording::Time,
+
+    #[serde(flatten)]
+    pub alert: Alert,
+}
+
+struct Active {
+    alert: Alert,
+    since: recording::Time,
+    fired: bool,
+}
+
+/// Tracks alerts across checks, producing events for transitions.
+#[derive(Default)]
+struct Tracker {
+    /// Problems present as of the latest check, by key.
+    active: FastHashMap<String, Active>,
+}
+
+impl Tracker {
+    /// Updates with the problems found by a check at `now`, returning events to send.
+    fn update(&mut self, now: recording::Time, alerts: Vec<Alert>) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut next = FastHashMap::default();
+        for alert in alerts {
+            let mut a = match self.active.remove(&alert.key) {
+                Some(mut a) => {
+                    a.alert = alert;
+                    a
+                }
+                None => Active {
+                    alert,
+                    since: now,
+                    fired: false,
+                },
+            };
+            if !a.fired && now - a.since >= a.alert.hold {
+                a.fired = true;
+                events.push(Event {
+                    state: State::Firing,
+                    time_90k: now,
+                    since_90k: a.since,
+                    alert: a.alert.clone(),
+                });
+            }
+            next.insert(a.alert.key.clone(), a);
+        }
+        let mut resolved: Vec<_> = std::mem::replace(&mut self.active, next)
+            .into_values()
+            .filter(|a| a.fired)
+            .map(|a| Event {
+                state: State::Resolved,
+                time_90k: now,
+                since_90k: a.since,
+                alert: a.alert,
+            })
+            .collect();
+        resolved.sort_unstable_by(|a, b| a.alert.key.cmp(&b.alert.key));
+        events.extend(resolved);
+        events
+    }
+}
+
+/// Thresholds for alerting, from [`NotificationsConfig`].
+struct Limits {
+    stream_down: recording::Duration,
+    flush_grace: recording::Duration,
+    dir_min_available_bytes: i64,
+}
+
+impl Limits {
+    /// Returns the problems present at `now`.
+    fn check<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        metrics: &Metrics,
+        now: recording::Time,
+    ) -> Vec<Alert> {
+        let statuses: FastHashMap<_, _> = metrics
+            .streams()
+            .into_iter()
+            .map(|(id, c)| (id, c.status.lock().unwrap().clone()))
+            .collect();
+        let l = db.lock();
+        let mut alerts = Vec::new();
+        let mut worst_flush_lag: Option<(recording::Duration, String)> = None;
+        for (id, s) in l.streams_by_id() {
+            let c = &l.cameras_by_id()[&s.camera_id];
+            let name = format!("{}-{}", c.short_name, s.type_.as_str());
+            if let Some(status) = statuses.get(id).filter(|st| st.is_down(now)) {
+                let summary = match status.last_error {
+                    Some((_, ref e)) if !status.connected => format!("{name} is down: {e}"),
+                    _ => format!("{name} is receiving no video"),
+                };
+                alerts.push(Alert {
+                    key: format!("stream/{}/{}", c.uuid, s.type_.as_str()),
+                    kind: AlertKind::StreamDown,
+                    severity: Severity::Warning,
+                    summary,
+                    camera_uuid: Some(c.uuid),
+                    stream_type: Some(s.type_.as_str()),
+                    dir_id: None,
+                    hold: self.stream_down,
+                });
+            }
+            if let Some(end) = s.oldest_unflushed_end() {
+                let lag = now - end;
+                let allowed = recording::Duration(
+                    i64::from(s.config.flush_if_sec) * recording::TIME_UNITS_PER_SEC,
+                ) + self.flush_grace;
+                if lag > allowed && worst_flush_lag.as_ref().map_or(true, |w| lag > w.0) {
+                    worst_flush_lag = Some((lag, name));
+                }
+            }
+        }
+        if let Some((lag, name)) = worst_flush_lag {
+            alerts.push(Alert {
+                key: "db/flush".to_owned(),
+                kind: AlertKind::FlushLag,
+                severity: Severity::Critical,
+                summary: format!(
+                    "recordings are not being committed to the database; {name} has waited {} s",
+                    lag.0 / recording::TIME_UNITS_PER_SEC
+                ),
+                camera_uuid: None,
+                stream_type: None,
+                dir_id: None,
+                hold: recording::Duration(0),
+            });
+        }
+        for (&id, d) in l.sample_file_dirs_by_id() {
+            let needed = l.streams_by_id().values().any(|s| {
+                s.config.is_recording()
+                    && (s.sample_file_dir_id == Some(id)
+                        || s.config.archive_sample_file_dir_id == Some(id))
+            });
+            let path = d.path.display();
+            let (kind, severity, summary) = match d.get() {
+                Err(_) if needed => (
+                    AlertKind::DirError,
+                    Severity::Critical,
+                    format!("sample file directory {path} isn't open"),
+                ),
+                Err(_) => continue,
+                Ok(d) => match d.statfs() {
+                    Err(e) => (
+                        AlertKind::DirError,
+                        Severity::Critical,
+                        format!("unable to examine sample file directory {path}: {e}"),
+                    ),
+                    Ok(stat) => {
+                        let available = stat.block_size() as i64 * stat.blocks_available() as i64;
+                        if available >= self.dir_min_available_bytes {
+                            continue;
+                        }
+                        (
+                            AlertKind::DirLowSpace,
+                            Severity::Warning,
+                            format!(
+                                "sample file directory {path} has only {} available",
+                                base::strutil::encode_size(available)
+                            ),
+                        )
+                    }
+                },
+            };
+            alerts.push(Alert {
+                key: format!("dir/{id}"),
+                kind,
+                severity,
+                summary,
+                camera_uuid: None,
+                stream_type: None,
+                dir_id: Some(id),
+                hold: recording::Duration(0),
+            });
+        }
+        alerts
+    }
+}
+
+/// A destination for events.
+enum Sink {
+    Webhook(webhook::Webhook),
+    Email(email::Email),
+}
+
+impl Sink {
+    async fn send<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        event: &Event,
+    ) -> Result<(), Error> {
+        match self {
+            Sink::Webhook(w) => w.send(event).await,
+            Sink::Email(e) => e.send(db, event).await,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Sink::Webhook(w) => w.url(),
+            Sink::Email(e) => e.host(),
+        }
+    }
+}
+
+/// Delivers events to a sink in order until the channel closes or shutdown.
+async fn deliver<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    sink: Sink,
+    mut rx: mpsc::Receiver<Arc<Event>>,
+    shutdown_rx: base::shutdown::Receiver,
+) {
+    loop {
+        let event = tokio::select! {
+            e = rx.recv() => match e {
+                Some(e) => e,
+                None => return,
+            },
+            _ = shutdown_rx.as_future() => return,
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let err = match sink.send(&db, &event).await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            if attempt == MAX_ATTEMPTS || err.kind() == base::ErrorKind::InvalidArgument {
+                warn!(
+                    err = %err.chain(),
+                    "giving up on sending {} notification to {}",
+                    event.alert.key,
+                    sink.name(),
+                );
+                break;
+            }
+            warn!(
+                err = %err.chain(),
+                "unable to send notification to {}; retrying in {backoff:?}",
+                sink.name(),
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown_rx.as_future() => return,
+            }
+            backoff *= 2;
+        }
+    }
+}
+
+/// Checks for problems and sends notifications until shutdown.
+pub struct Notifier {
+    limits: Limits,
+    sinks: Vec<Sink>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationsConfig) -> Result<Self, Error> {
+        let mut sinks = config
+            .webhooks
+            .iter()
+            .map(|w| webhook::Webhook::new(w).map(Sink::Webhook))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(ref smtp) = config.smtp {
+            sinks.push(Sink::Email(email::Email::new(smtp)?));
+        }
+        Ok(Notifier {
+            limits: Limits {
+                stream_down: recording::Duration(
+                    i64::from(config.stream_down_sec) * recording::TIME_UNITS_PER_SEC,
+                ),
+                flush_grace: recording::Duration(
+                    i64::from(config.flush_lag_sec) * recording::TIME_UNITS_PER_SEC,
+                ),
+                dir_min_available_bytes: config.dir_min_available_bytes,
+            },
+            sinks,
+        })
+    }
+}
+
+/// Checks for problems until shutdown, notifying sinks and `/api/events` subscribers of each
+/// transition.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    metrics: Arc<Metrics>,
+    events: Arc<crate::events::Events>,
+    shutdown_rx: base::shutdown::Receiver,
+    notifier: Notifier,
+) {
+    info!(
+        "checking for problems to notify {} sinks every {CHECK_INTERVAL:?}",
+        notifier.sinks.len()
+    );
+    let mut txs = Vec::with_capacity(notifier.sinks.len());
+    let mut handles = Vec::with_capacity(notifier.sinks.len());
+    for sink in notifier.sinks {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        txs.push(tx);
+        handles.push(tokio::spawn(deliver(
+            db.clone(),
+            sink,
+            rx,
+            shutdown_rx.clone(),
+        )));
+    }
+    let mut tracker = Tracker::default();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown_rx.as_future() => break,
+        }
+        let now = recording::Time::new(db.clocks().realtime());
+        let alerts = notifier.limits.check(&db, &metrics, now);
+        for event in tracker.update(now, alerts) {
+            info!(
+                "alert {} is {:?}: {}",
+                event.alert.key, event.state, event.alert.summary
+            );
+            events.publish(crate::events::Event::Alert(event.clone()));
+            let event = Arc::new(event);
+            for tx in &txs {
+                if tx.try_send(event.clone()).is_err() {
+                    warn!("notification queue is full; dropping {}", event.alert.key);
+                }
+            }
+        }
+    }
+    drop(txs);
+    for h in handles {
+        let _ = h.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(key: &str, hold_sec: i64) -> Alert {
+        Alert {
+            key: key.to_owned(),
+            kind: AlertKind::StreamDown,
+            severity: Severity::Warning,
+            summary: format!("{key} is down"),
+            camera_uuid: None,
+            stream_type: None,
+            dir_id: None,
+            hold: recording::Duration(hold_sec * recording::TIME_UNITS_PER_SEC),
+        }
+    }
+
+    fn summarize(events: &[Event]) -> Vec<(State, &str)> {
+        events
+            .iter()
+            .map(|e| (e.state, e.alert.key.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn tracker() {
+        let t = |sec| recording::Time(sec * recording::TIME_UNITS_PER_SEC);
+        let mut tracker = Tracker::default();
+        assert!(tracker.update(t(0), vec![]).is_empty());
+
+        // "a" fires immediately; "b" must persist for 20 seconds first.
+        let events = tracker.update(t(10), vec![alert("a", 0), alert("b", 20)]);
+        assert_eq!(summarize(&events), [(State::Firing, "a")]);
+        let events = tracker.update(t(20), vec![alert("a", 0), alert("b", 20)]);
+        assert!(events.is_empty());
+        let events = tracker.update(t(30), vec![alert("a", 0), alert("b", 20)]);
+        assert_eq!(summarize(&events), [(State::Firing, "b")]);
+        assert_eq!(events[0].since_90k, t(10));
+
+        // Both resolve at once.
+        let events = tracker.update(t(40), vec![]);
+        assert_eq!(
+            summarize(&events),
+            [(State::Resolved, "a"), (State::Resolved, "b")]
+        );
+
+        // A problem which goes away before its hold isn't reported at all.
+        assert!(tracker.update(t(50), vec![alert("b", 20)]).is_empty());
+        assert!(tracker.update(t(60), vec![]).is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Webhook notifications: each event is `POST`ed to a URL as a JSON object.
+
+use std::time::Duration;
+
+use base::{bail, err, Error};
+use http::header::{HeaderName, HeaderValue};
+use http::{header, Method};
+
+use super::Event;
+use crate::cmds::run::config::WebhookConfig;
+
+/// The timeout for each request.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Webhook {
+    http: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+    url: String,
+    uri: hyper::Uri,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Result<Self, Error> {
+        let uri: hyper::Uri = config.url.parse().map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad webhook URL {:?}", config.url),
+                source(e)
+            )
+        })?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+            bail!(
+                InvalidArgument,
+                msg(
+                    "bad webhook URL {:?}; expected an http or https URL",
+                    config.url
+                )
+            );
+        }
+        let headers = config
+            .headers
+            .iter()
+            .map(|(k, v)| {
+                let k = HeaderName::try_from(k.as_str()).map_err(|e| {
+                    err!(
+                        InvalidArgument,
+                        msg("bad webhook header name {k:?}"),
+                        source(e)
+                    )
+                })?;
+                let v = HeaderValue::try_from(v.as_str()).map_err(|e| {
+                    err!(
+                        InvalidArgument,
+                        msg("bad webhook header value for {k}"),
+                        source(e)
+                    )
+                })?;
+                Ok((k, v))
+            })
+            .collect::<Result<_, Error>>()?;
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Webhook {
+            http: hyper::Client::builder().build(connector),
+            url: config.url.clone(),
+            uri,
+            headers,
+        })
+    }
+
+    pub(super) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Sends an event.
+    ///
+    /// Returns an `InvalidArgument` error if the server rejected the request in a way that
+    /// retrying won't fix.
+    pub(super) async fn send(&self, event: &Event) -> Result<(), Error> {
+        let body = serde_json::to_vec(event).expect("events are serializable");
+        let mut req = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::USER_AGENT,
+                concat!("moonfire-nvr/", env!("CARGO_PKG_VERSION")),
+            );
+        for (k, v) in &self.headers {
+            req = req.header(k, v);
+        }
+        let req = req
+            .body(hyper::Body::from(body))
+            .map_err(|e| err!(Internal, msg("bad webhook request"), source(e)))?;
+        let resp = tokio::time::timeout(TIMEOUT, self.http.request(req))
+            .await
+            .map_err(|_| err!(DeadlineExceeded, msg("timed out calling webhook")))?
+            .map_err(|e| err!(Unavailable, msg("unable to call webhook"), source(e)))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status.is_client_error() && status != http::StatusCode::TOO_MANY_REQUESTS {
+            bail!(InvalidArgument, msg("webhook returned status {status}"));
+        }
+        bail!(Unavailable, msg("webhook returned status {status}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::notify::{Alert, AlertKind, Severity, State};
+    use db::recording;
+
+    #[tokio::test]
+    async fn send() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let make_svc = hyper::service::make_service_fn({
+            let received = received.clone();
+            move |_conn| {
+                let received = received.clone();
+                futures::future::ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                    move |req: hyper::Request<hyper::Body>| {
+                        let received = received.clone();
+                        async move {
+                            let auth = req.headers().get(header::AUTHORIZATION).cloned();
+                            let body = hyper::body::to_bytes(req.into_body()).await?;
+                            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                            received.lock().unwrap().push((auth, body));
+                            Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::empty()))
+                        }
+                    },
+                ))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let webhook = Webhook::new(&WebhookConfig {
+            url: format!("http://{addr}/hook"),
+            headers: [("Authorization".to_owned(), "Bearer secret".to_owned())]
+                .into_iter()
+                .collect(),
+        })
+        .unwrap();
+        webhook
+            .send(&Event {
+                state: State::Firing,
+                time_90k: recording::Time(2),
+                since_90k: recording::Time(1),
+                alert: Alert {
+                    key: "dir/1".to_owned(),
+                    kind: AlertKind::DirLowSpace,
+                    severity: Severity::Warning,
+                    summary: "low on space".to_owned(),
+                    camera_uuid: None,
+                    stream_type: None,
+                    dir_id: Some(1),
+                    hold: recording::Duration(0),
+                },
+            })
+            .await
+            .unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0].0.as_ref().unwrap().to_str().unwrap(),
+            "Bearer secret"
+        );
+        assert_eq!(
+            received[0].1,
+            serde_json::json!({
+                "state": "firing",
+                "time90k": 2,
+                "since90k": 1,
+                "key": "dir/1",
+                "kind": "dirLowSpace",
+                "severity": "warning",
+                "summary": "low on space",
+                "dirId": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn bad_url() {
+        let e = Webhook::new(&WebhookConfig {
+            url: "ftp://example.com/".to_owned(),
+            headers: Default::default(),
+        })
+        .err()
+        .unwrap();
+        assert_eq!(e.kind(), base::ErrorKind::InvalidArgument);
+    }
+}
@@ -0,0 +1,637 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! ONVIF audio backchannel, as in the ONVIF Streaming Specification section 5.3, for sending
+//! audio to a camera's speaker.
+//!
+//! Retina only receives media, so this conducts its own minimal RTSP session on the camera's
+//! main stream URL: `DESCRIBE` with the backchannel `Require` tag, `SETUP` of the `sendonly`
+//! audio medium with interleaved TCP transport, and `PLAY`. Audio is then sent as G.711 RTP
+//! packets on the RTSP connection itself.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::{bail, err, Error};
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader, WriteHalf};
+use tokio_rustls::rustls;
+use tracing::{debug, warn, Instrument};
+use url::Url;
+
+use crate::g711::Law;
+use crate::rtsp::message::{self, interleaved, Message, Rtsp};
+use crate::rtsps::Conn;
+
+/// The feature tag which requests the backchannel.
+const REQUIRE: &str = "www.onvif.org/ver20/backchannel";
+
+/// The G.711 sample rate, the only one supported.
+pub const SAMPLE_RATE: u32 = 8_000;
+
+/// The number of samples in each RTP packet: 20 milliseconds.
+const SAMPLES_PER_PACKET: usize = 160;
+
+/// The session timeout assumed when the camera doesn't specify one, as in RFC 2326 section 12.37.
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The time allowed for each request during setup.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The backchannel medium of a `DESCRIBE` response.
+#[derive(Debug, PartialEq, Eq)]
+struct Medium {
+    law: Law,
+    payload_type: u8,
+
+    /// The URL for `SETUP`.
+    control: Url,
+
+    /// The URL for `PLAY` and other session-wide requests.
+    aggregate: Url,
+}
+
+/// Resolves a `a=control` attribute against the base URL, as Retina does.
+fn join_control(base: &Url, control: &str) -> Result<Url, Error> {
+    if control == "*" {
+        return Ok(base.clone());
+    }
+    if let Ok(url) = Url::parse(control) {
+        return Ok(url);
+    }
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        let path = format!("{}/", base.path());
+        base.set_path(&path);
+    }
+    base.join(control).map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("bad control URL {control:?}"),
+            source(e)
+        )
+    })
+}
+
+/// Returns the `a=control` attribute among the given SDP lines.
+fn control<'a>(lines: &[&'a str]) -> Option<&'a str> {
+    lines
+        .iter()
+        .find_map(|&l| l.strip_prefix("a=control:"))
+        .map(str::trim)
+}
+
+/// Finds the backchannel medium in a `DESCRIBE` response's SDP: the first `sendonly` audio
+/// medium offering G.711 at 8 kHz.
+fn backchannel_medium(base: &Url, sdp: &str) -> Result<Medium, Error> {
+    let mut sections = vec![Vec::new()];
+    for line in sdp.lines() {
+        let line = line.trim_end();
+        if line.starts_with("m=") {
+            sections.push(Vec::new());
+        }
+        sections.last_mut().expect("non-empty").push(line);
+    }
+    let aggregate = match control(&sections[0]) {
+        Some(c) => join_control(base, c)?,
+        None => base.clone(),
+    };
+    for lines in &sections[1..] {
+        let mut fields = lines[0]["m=".len()..].split(' ');
+        if fields.next() != Some("audio") || !lines.contains(&"a=sendonly") {
+            continue;
+        }
+        let payload_types = fields.skip(2).filter_map(|pt| pt.parse::<u8>().ok());
+        for payload_type in payload_types {
+            let rtpmap = lines.iter().find_map(|l| {
+                let (pt, map) = l.strip_prefix("a=rtpmap:")?.split_once(' ')?;
+                (pt.parse::<u8>().ok() == Some(payload_type)).then_some(map.trim())
+            });
+            let law = match rtpmap {
+                Some(map) => {
+                    let (name, rate) = map.split_once('/').unwrap_or((map, ""));
+                    let rate = rate.split('/').next().unwrap_or(rate);
+                    if rate.parse::<u32>().ok() != Some(SAMPLE_RATE) {
+                        continue;
+                    }
+                    Law::from_encoding_name(&name.to_ascii_lowercase())
+                }
+                None => [Law::Mu, Law::A]
+                    .into_iter()
+                    .find(|l| l.payload_type() == payload_type),
+            };
+            let Some(law) = law else {
+                continue;
+            };
+            let control = match control(lines) {
+                Some(c) => join_control(base, c)?,
+                None => aggregate.clone(),
+            };
+            return Ok(Medium {
+                law,
+                payload_type,
+                control,
+                aggregate,
+            });
+        }
+    }
+    bail!(
+        FailedPrecondition,
+        msg("camera offers no G.711 audio backchannel")
+    )
+}
+
+/// Parses a `Session` header value into its id and timeout.
+fn parse_session(value: &str) -> (String, Duration) {
+    let mut parts = value.split(';');
+    let id = parts.next().unwrap_or_default().trim().to_owned();
+    let timeout = parts
+        .find_map(|p| p.trim().strip_prefix("timeout="))
+        .and_then(|t| t.parse().ok())
+        .map_or(DEFAULT_SESSION_TIMEOUT, Duration::from_secs);
+    (id, timeout)
+}
+
+/// Returns `url` without credentials, as sent in requests.
+fn request_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+/// Sends requests and reads their responses during setup, before media flows.
+struct Requester<'a> {
+    conn: &'a mut BufReader<Box<dyn Conn>>,
+    username: &'a str,
+    password: &'a str,
+    cseq: u32,
+    auth: Option<http_auth::PasswordClient>,
+}
+
+impl Requester<'_> {
+    /// Returns the request's `Authorization` header value, if credentials are needed.
+    fn authorization(&mut self, method: &str, url: &Url) -> Result<Option<String>, Error> {
+        let Some(auth) = self.auth.as_mut() else {
+            return Ok(None);
+        };
+        auth.respond(&http_auth::PasswordParams {
+            username: self.username,
+            password: self.password,
+            uri: url.as_str(),
+            method,
+            body: Some(&[]),
+        })
+        .map(Some)
+        .map_err(|e| err!(Unauthenticated, msg("unable to authenticate: {e}")))
+    }
+
+    /// Sends a request and returns its successful response, authenticating if challenged.
+    async fn request(
+        &mut self,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, &str)],
+    ) -> Result<Rtsp, Error> {
+        let mut challenged = false;
+        loop {
+            let resp = tokio::time::timeout(REQUEST_TIMEOUT, self.send(method, url, headers))
+                .await
+                .map_err(|_| {
+                    err!(
+                        DeadlineExceeded,
+                        msg("timed out waiting for {method} response")
+                    )
+                })??;
+            match resp.status() {
+                Some(401) if !challenged && !self.username.is_empty() => {
+                    let challenges: Vec<&str> = resp
+                        .headers
+                        .iter()
+                        .filter(|(n, _)| n.eq_ignore_ascii_case("WWW-Authenticate"))
+                        .map(|(_, v)| v.as_str())
+                        .collect();
+                    let client =
+                        http_auth::PasswordClient::try_from(challenges.join(", ").as_str())
+                            .map_err(|e| {
+                                err!(
+                                    Unauthenticated,
+                                    msg("unsupported authentication challenge: {e}")
+                                )
+                            })?;
+                    self.auth = Some(client);
+                    challenged = true;
+                }
+                Some(401) => bail!(Unauthenticated, msg("camera rejected credentials")),
+                Some(551) => bail!(
+                    FailedPrecondition,
+                    msg("camera doesn't support the ONVIF audio backchannel")
+                ),
+                Some(s) if (200..300).contains(&s) => return Ok(resp),
+                _ => bail!(Unavailable, msg("{method} failed: {}", resp.start)),
+            }
+        }
+    }
+
+    async fn send(
+        &mut self,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, &str)],
+    ) -> Result<Rtsp, Error> {
+        self.cseq += 1;
+        let cseq = self.cseq.to_string();
+        let mut req = Rtsp {
+            start: format!("{method} {url} RTSP/1.0"),
+            headers: vec![
+                ("CSeq".to_owned(), cseq.clone()),
+                ("User-Agent".to_owned(), "moonfire-nvr".to_owned()),
+            ],
+            body: Bytes::new(),
+        };
+        if let Some(a) = self.authorization(method, url)? {
+            req.headers.push(("Authorization".to_owned(), a));
+        }
+        for &(name, value) in headers {
+            req.headers.push((name.to_owned(), value.to_owned()));
+        }
+        self.conn
+            .get_mut()
+            .write_all(&message::encode(&Message::Rtsp(req)))
+            .await
+            .map_err(|e| err!(e, msg("unable to send {method} request")))?;
+        loop {
+            match message::read(self.conn).await? {
+                None => bail!(Unavailable, msg("camera closed connection during {method}")),
+                Some(Message::Rtsp(resp)) if resp.cseq() == Some(cseq.as_str()) => return Ok(resp),
+                Some(_) => {} // media or a stray message.
+            }
+        }
+    }
+}
+
+/// An open backchannel session.
+///
+/// Dropping it closes the connection without a `TEARDOWN`; prefer [`Backchannel::close`].
+pub struct Backchannel {
+    w: WriteHalf<BufReader<Box<dyn Conn>>>,
+    aggregate: Url,
+    session: String,
+    session_timeout: Duration,
+    cseq: u32,
+    law: Law,
+    payload_type: u8,
+    channel: u8,
+
+    /// Samples not yet sent, fewer than [`SAMPLES_PER_PACKET`].
+    pending: Vec<i16>,
+
+    sequence_number: u16,
+    timestamp: u32,
+    ssrc: u32,
+    marker: bool,
+
+    /// Drains responses and media sent by the camera.
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Backchannel {
+    /// Opens a backchannel using the given stream URL, which may be `rtsp://` or `rtsps://`.
+    /// The latter requires `tls`.
+    pub async fn open(
+        url: &Url,
+        username: &str,
+        password: &str,
+        tls: Option<Arc<rustls::ClientConfig>>,
+    ) -> Result<Self, Error> {
+        let conn = tokio::time::timeout(REQUEST_TIMEOUT, crate::rtsps::connect(url, tls))
+            .await
+            .map_err(|_| err!(DeadlineExceeded, msg("timed out connecting to {url}")))??;
+        Self::start(conn, url, username, password, SystemRandom::new()).await
+    }
+
+    async fn start(
+        conn: Box<dyn Conn>,
+        url: &Url,
+        username: &str,
+        password: &str,
+        rand: SystemRandom,
+    ) -> Result<Self, Error> {
+        let url = request_url(url);
+        let mut conn = BufReader::new(conn);
+        let mut r = Requester {
+            conn: &mut conn,
+            username,
+            password,
+            cseq: 0,
+            auth: None,
+        };
+        let describe = r
+            .request(
+                "DESCRIBE",
+                &url,
+                &[("Accept", "application/sdp"), ("Require", REQUIRE)],
+            )
+            .await?;
+        let base = describe
+            .header("Content-Base")
+            .or_else(|| describe.header("Content-Location"))
+            .and_then(|b| Url::parse(b).ok())
+            .unwrap_or_else(|| url.clone());
+        let sdp = std::str::from_utf8(&describe.body)
+            .map_err(|_| err!(InvalidArgument, msg("DESCRIBE response isn't UTF-8")))?;
+        let medium = backchannel_medium(&base, sdp)?;
+        debug!(?medium, "found backchannel");
+        let setup = r
+            .request(
+                "SETUP",
+                &medium.control,
+                &[
+                    ("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1"),
+                    ("Require", REQUIRE),
+                ],
+            )
+            .await?;
+        let (session, session_timeout) = parse_session(
+            setup
+                .header("Session")
+                .ok_or_else(|| err!(Unavailable, msg("SETUP response has no Session")))?,
+        );
+        let channel = setup
+            .header("Transport")
+            .and_then(interleaved)
+            .map_or(0, |(rtp, _)| rtp);
+        r.request(
+            "PLAY",
+            &medium.aggregate,
+            &[("Session", session.as_str()), ("Range", "npt=0.000-")],
+        )
+        .await?;
+        let cseq = r.cseq;
+        let (read, w) = tokio::io::split(conn);
+        let reader = tokio::spawn(drain(BufReader::new(read)).in_current_span());
+        let mut rand_bytes = [0u8; 6];
+        rand.fill(&mut rand_bytes)
+            .map_err(|_| err!(Internal, msg("unable to generate RTP identifiers")))?;
+        Ok(Backchannel {
+            w,
+            aggregate: medium.aggregate,
+            session,
+            session_timeout,
+            cseq,
+            law: medium.law,
+            payload_type: medium.payload_type,
+            channel,
+            pending: Vec::with_capacity(SAMPLES_PER_PACKET),
+            sequence_number: u16::from_be_bytes([rand_bytes[0], rand_bytes[1]]),
+            timestamp: 0,
+            ssrc: u32::from_be_bytes([rand_bytes[2], rand_bytes[3], rand_bytes[4], rand_bytes[5]]),
+            marker: true,
+            reader,
+        })
+    }
+
+    /// Returns how often to call [`Backchannel::keepalive`] to keep the session from expiring.
+    pub fn keepalive_interval(&self) -> Duration {
+        (self.session_timeout / 2).max(Duration::from_secs(1))
+    }
+
+    /// Sends 16-bit mono linear PCM samples at [`SAMPLE_RATE`].
+    ///
+    /// Samples are sent in packets of 20 milliseconds; any remainder waits for the next call.
+    pub async fn send(&mut self, pcm: &[i16]) -> Result<(), Error> {
+        self.pending.extend_from_slice(pcm);
+        let full = self.pending.len() / SAMPLES_PER_PACKET * SAMPLES_PER_PACKET;
+        if full == 0 {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let mut out = Vec::with_capacity(full / SAMPLES_PER_PACKET * (4 + 12 + SAMPLES_PER_PACKET));
+        for samples in pending[..full].chunks(SAMPLES_PER_PACKET) {
+            out.extend_from_slice(&message::encode(&Message::Data {
+                channel: self.channel,
+                payload: self.packet(samples),
+            }));
+        }
+        self.pending.extend_from_slice(&pending[full..]);
+        self.write(&out).await
+    }
+
+    /// Returns an RTP packet of the given samples.
+    fn packet(&mut self, samples: &[i16]) -> Bytes {
+        let mut p = Vec::with_capacity(12 + samples.len());
+        p.push(0x80); // version 2, no padding, no extension, no CSRCs.
+        let marker = if self.marker { 0x80 } else { 0 };
+        p.push(marker | self.payload_type);
+        p.extend_from_slice(&self.sequence_number.to_be_bytes());
+        p.extend_from_slice(&self.timestamp.to_be_bytes());
+        p.extend_from_slice(&self.ssrc.to_be_bytes());
+        p.extend(samples.iter().map(|&s| self.law.encode(s)));
+        self.marker = false;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(samples.len() as u32);
+        p.into()
+    }
+
+    /// Keeps the session alive with a `GET_PARAMETER` request.
+    pub async fn keepalive(&mut self) -> Result<(), Error> {
+        self.request("GET_PARAMETER").await
+    }
+
+    /// Ends the session with a `TEARDOWN` request.
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.request("TEARDOWN").await?;
+        self.w
+            .shutdown()
+            .await
+            .map_err(|e| err!(e, msg("unable to close backchannel connection")))
+    }
+
+    /// Sends a session-wide request without waiting for its response.
+    ///
+    /// Requests after setup use no credentials: cameras authenticate the session, and a digest
+    /// response would need the reader task's state.
+    async fn request(&mut self, method: &str) -> Result<(), Error> {
+        self.cseq += 1;
+        let req = Rtsp {
+            start: format!("{method} {} RTSP/1.0", self.aggregate),
+            headers: vec![
+                ("CSeq".to_owned(), self.cseq.to_string()),
+                ("User-Agent".to_owned(), "moonfire-nvr".to_owned()),
+                ("Session".to_owned(), self.session.clone()),
+            ],
+            body: Bytes::new(),
+        };
+        self.write(&message::encode(&Message::Rtsp(req))).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.w
+            .write_all(data)
+            .await
+            .map_err(|e| err!(Unavailable, msg("unable to write to camera"), source(e)))
+    }
+}
+
+impl Drop for Backchannel {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Reads and discards messages from the camera, logging failed responses.
+async fn drain<R: AsyncRead + Unpin>(mut r: BufReader<R>) {
+    loop {
+        match message::read(&mut r).await {
+            Ok(Some(Message::Rtsp(resp))) => match resp.status() {
+                Some(s) if (200..300).contains(&s) => {}
+                _ => warn!(response = %resp.start, "backchannel request failed"),
+            },
+            Ok(Some(Message::Data { .. })) => {}
+            Ok(None) => return,
+            Err(err) => {
+                debug!(%err, "unable to read backchannel connection");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    const SDP: &str = "v=0\r\n\
+                       o=- 0 0 IN IP4 192.168.1.10\r\n\
+                       s=Session\r\n\
+                       t=0 0\r\n\
+                       a=control:*\r\n\
+                       m=video 0 RTP/AVP 96\r\n\
+                       a=rtpmap:96 H264/90000\r\n\
+                       a=control:trackID=1\r\n\
+                       a=recvonly\r\n\
+                       m=audio 0 RTP/AVP 0\r\n\
+                       a=rtpmap:0 PCMU/8000\r\n\
+                       a=control:trackID=2\r\n\
+                       a=recvonly\r\n\
+                       m=audio 0 RTP/AVP 97 8\r\n\
+                       a=rtpmap:97 MPEG4-GENERIC/16000/1\r\n\
+                       a=control:trackID=3\r\n\
+                       a=sendonly\r\n";
+
+    #[test]
+    fn medium() {
+        testutil::init();
+        let base = Url::parse("rtsp://192.168.1.10/Streaming/Channels/101").unwrap();
+        let m = backchannel_medium(&base, SDP).unwrap();
+        assert_eq!(
+            m,
+            Medium {
+                law: Law::A,
+                payload_type: 8,
+                control: Url::parse("rtsp://192.168.1.10/Streaming/Channels/101/trackID=3")
+                    .unwrap(),
+                aggregate: base.clone(),
+            }
+        );
+
+        // Without a sendonly medium, there's no backchannel.
+        let e = backchannel_medium(&base, &SDP.replace("a=sendonly", "a=recvonly")).unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::FailedPrecondition);
+    }
+
+    #[test]
+    fn session() {
+        testutil::init();
+        assert_eq!(
+            parse_session("12345678;timeout=30"),
+            ("12345678".to_owned(), Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_session("abc"),
+            ("abc".to_owned(), DEFAULT_SESSION_TIMEOUT)
+        );
+    }
+
+    /// Responds to the next request as a camera would.
+    async fn respond<C: Conn>(
+        camera: &mut BufReader<C>,
+        method: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Rtsp {
+        let Some(Message::Rtsp(req)) = message::read(camera).await.unwrap() else {
+            panic!("expected {method} request");
+        };
+        assert!(req.start.starts_with(method), "{}", req.start);
+        let mut resp = Rtsp {
+            start: "RTSP/1.0 200 OK".to_owned(),
+            headers: vec![("CSeq".to_owned(), req.cseq().unwrap().to_owned())],
+            body: Bytes::new(),
+        };
+        for &(n, v) in headers {
+            resp.headers.push((n.to_owned(), v.to_owned()));
+        }
+        resp.set_body(Bytes::copy_from_slice(body.as_bytes()));
+        camera
+            .get_mut()
+            .write_all(&message::encode(&Message::Rtsp(resp)))
+            .await
+            .unwrap();
+        req
+    }
+
+    #[tokio::test]
+    async fn session_flow() {
+        testutil::init();
+        let (conn, camera) = tokio::io::duplex(4096);
+        let camera = tokio::spawn(async move {
+            let mut camera = BufReader::new(camera);
+            let describe = respond(&mut camera, "DESCRIBE", &[], SDP).await;
+            assert_eq!(describe.header("Require"), Some(REQUIRE));
+            let setup = respond(
+                &mut camera,
+                "SETUP",
+                &[
+                    ("Session", "1234;timeout=20"),
+                    ("Transport", "RTP/AVP/TCP;unicast;interleaved=4-5"),
+                ],
+                "",
+            )
+            .await;
+            assert_eq!(
+                setup.start,
+                "SETUP rtsp://192.168.1.10/Streaming/Channels/101/trackID=3 RTSP/1.0"
+            );
+            let play = respond(&mut camera, "PLAY", &[], "").await;
+            assert_eq!(play.header("Session"), Some("1234"));
+            let Some(Message::Data { channel, payload }) =
+                message::read(&mut camera).await.unwrap()
+            else {
+                panic!("expected media");
+            };
+            assert_eq!(channel, 4);
+            assert_eq!(payload.len(), 12 + SAMPLES_PER_PACKET);
+            assert_eq!(payload[0], 0x80);
+            assert_eq!(payload[1], 0x80 | 8, "marker and PCMA payload type");
+            assert_eq!(&payload[4..8], &[0, 0, 0, 0], "timestamp");
+            assert!(payload[12..].iter().all(|&b| b == 0xd5), "A-law zero");
+            let Some(Message::Rtsp(teardown)) = message::read(&mut camera).await.unwrap() else {
+                panic!("expected TEARDOWN");
+            };
+            assert!(teardown.start.starts_with("TEARDOWN "));
+        });
+        let url = Url::parse("rtsp://192.168.1.10/Streaming/Channels/101").unwrap();
+        let mut bc = Backchannel::start(Box::new(conn), &url, "", "", SystemRandom::new())
+            .await
+            .unwrap();
+        assert_eq!(bc.keepalive_interval(), Duration::from_secs(10));
+        bc.send(&[0; SAMPLES_PER_PACKET - 1]).await.unwrap();
+        bc.send(&[0; 1]).await.unwrap();
+        bc.close().await.unwrap();
+        camera.await.unwrap();
+    }
+}
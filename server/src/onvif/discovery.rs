@@ -0,0 +1,196 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! WS-Discovery of ONVIF cameras on the local network.
+//!
+//! This sends a single multicast `Probe` for `NetworkVideoTransmitter`s and collects the
+//! `ProbeMatch`es which arrive before a timeout.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use base::{err, Error};
+use tracing::debug;
+use url::Url;
+
+use super::Element;
+
+const MULTICAST_ADDR: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+    Ipv4Addr::new(239, 255, 255, 250),
+    3702,
+));
+
+/// A camera which responded to a probe.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+    /// The endpoint reference, typically a `urn:uuid:...` which identifies the device.
+    pub endpoint: String,
+
+    /// The device's name, from its `onvif://www.onvif.org/name/...` scope, if any.
+    pub name: Option<String>,
+
+    /// The device's hardware model, from its `onvif://www.onvif.org/hardware/...` scope, if
+    /// any.
+    pub hardware: Option<String>,
+
+    /// URLs of the device service.
+    pub xaddrs: Vec<Url>,
+}
+
+impl Device {
+    /// Returns the ONVIF base URL to store in the camera's config, as used by
+    /// [`super::events`]: the first device service URL with the last path segment removed.
+    pub fn onvif_base_url(&self) -> Option<Url> {
+        let u = self.xaddrs.first()?;
+        u.join(".").ok()
+    }
+}
+
+/// Probes for devices, returning those which respond within `timeout`.
+pub async fn discover(timeout: Duration) -> Result<Vec<Device>, Error> {
+    let sock = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| err!(e, msg("unable to bind UDP socket for discovery")))?;
+    let probe = probe_message(&uuid::Uuid::new_v4());
+    sock.send_to(probe.as_bytes(), MULTICAST_ADDR)
+        .await
+        .map_err(|e| err!(e, msg("unable to send discovery probe")))?;
+    let mut devices: Vec<Device> = Vec::new();
+    let mut buf = vec![0u8; 65_536];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let (len, from) = match tokio::time::timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            Err(_) => break,
+            Ok(r) => r.map_err(|e| err!(e, msg("unable to receive discovery response")))?,
+        };
+        let matches = match parse_probe_matches(&String::from_utf8_lossy(&buf[..len])) {
+            Ok(m) => m,
+            Err(err) => {
+                debug!(%err, "ignoring bad discovery response from {from}");
+                continue;
+            }
+        };
+        for m in matches {
+            if !devices.iter().any(|d| d.endpoint == m.endpoint) {
+                devices.push(m);
+            }
+        }
+    }
+    Ok(devices)
+}
+
+fn probe_message(message_id: &uuid::Uuid) -> String {
+    format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" "#,
+            r#"xmlns:a="http://schemas.xmlsoap.org/ws/2004/08/addressing" "#,
+            r#"xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery" "#,
+            r#"xmlns:dn="http://www.onvif.org/ver10/network/wsdl">"#,
+            "<s:Header>",
+            r#"<a:Action s:mustUnderstand="1">http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</a:Action>"#,
+            "<a:MessageID>uuid:{message_id}</a:MessageID>",
+            "<a:ReplyTo><a:Address>http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous</a:Address></a:ReplyTo>",
+            r#"<a:To s:mustUnderstand="1">urn:schemas-xmlsoap-org:ws:2005:04:discovery</a:To>"#,
+            "</s:Header>",
+            "<s:Body><d:Probe><d:Types>dn:NetworkVideoTransmitter</d:Types></d:Probe></s:Body>",
+            "</s:Envelope>",
+        ),
+        message_id = message_id,
+    )
+}
+
+/// Parses a `ProbeMatches` message.
+fn parse_probe_matches(xml: &str) -> Result<Vec<Device>, Error> {
+    let envelope = Element::parse(xml)?;
+    let mut matches = Vec::new();
+    envelope.find_all("ProbeMatch", &mut matches);
+    Ok(matches
+        .into_iter()
+        .filter_map(|m| {
+            let endpoint = m.find("Address")?.text.trim().to_owned();
+            let xaddrs = m
+                .child("XAddrs")?
+                .text
+                .split_whitespace()
+                .filter_map(|u| Url::parse(u).ok())
+                .collect();
+            let scopes = m.child("Scopes").map_or("", |s| s.text.as_str());
+            Some(Device {
+                endpoint,
+                name: scope(scopes, "name"),
+                hardware: scope(scopes, "hardware"),
+                xaddrs,
+            })
+        })
+        .collect())
+}
+
+/// Returns the percent-decoded value of the given ONVIF scope, if present.
+fn scope(scopes: &str, key: &str) -> Option<String> {
+    let prefix = format!("onvif://www.onvif.org/{key}/");
+    scopes.split_whitespace().find_map(|s| {
+        let v = s.strip_prefix(&prefix)?;
+        Some(
+            percent_encoding::percent_decode_str(v)
+                .decode_utf8_lossy()
+                .into_owned(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    #[test]
+    fn probe_message() {
+        testutil::init();
+        let id = uuid::Uuid::parse_str("3a1e0a55-1b0b-4a4f-9d1e-7a58c1e6f3b1").unwrap();
+        let e = crate::onvif::Element::parse(&super::probe_message(&id)).unwrap();
+        assert_eq!(
+            e.find("MessageID").unwrap().text,
+            "uuid:3a1e0a55-1b0b-4a4f-9d1e-7a58c1e6f3b1"
+        );
+        assert_eq!(e.find("Types").unwrap().text, "dn:NetworkVideoTransmitter");
+    }
+
+    #[test]
+    fn parse_probe_matches() {
+        testutil::init();
+        let devices = super::parse_probe_matches(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <SOAP-ENV:Envelope xmlns:SOAP-ENV="http://www.w3.org/2003/05/soap-envelope"
+                xmlns:wsa="http://schemas.xmlsoap.org/ws/2004/08/addressing"
+                xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery">
+              <SOAP-ENV:Body>
+                <d:ProbeMatches>
+                  <d:ProbeMatch>
+                    <wsa:EndpointReference>
+                      <wsa:Address>urn:uuid:4a6f8b2e-0000-0000-0000-bcbac0ffee01</wsa:Address>
+                    </wsa:EndpointReference>
+                    <d:Types>dn:NetworkVideoTransmitter tds:Device</d:Types>
+                    <d:Scopes>onvif://www.onvif.org/type/video_encoder
+                      onvif://www.onvif.org/name/Back%20Yard
+                      onvif://www.onvif.org/hardware/DS-2CD2032</d:Scopes>
+                    <d:XAddrs>http://192.168.5.10/onvif/device_service http://[fe80::1]/onvif/device_service</d:XAddrs>
+                    <d:MetadataVersion>10</d:MetadataVersion>
+                  </d:ProbeMatch>
+                </d:ProbeMatches>
+              </SOAP-ENV:Body>
+            </SOAP-ENV:Envelope>"#,
+        )
+        .unwrap();
+        assert_eq!(devices.len(), 1);
+        let d = &devices[0];
+        assert_eq!(d.endpoint, "urn:uuid:4a6f8b2e-0000-0000-0000-bcbac0ffee01");
+        assert_eq!(d.name.as_deref(), Some("Back Yard"));
+        assert_eq!(d.hardware.as_deref(), Some("DS-2CD2032"));
+        assert_eq!(d.xaddrs.len(), 2);
+        assert_eq!(
+            d.onvif_base_url().unwrap().as_str(),
+            "http://192.168.5.10/onvif/"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Ingestion of ONVIF motion and tamper events into signals.
+//!
+//! Each configured camera gets a task which creates a pull point subscription on the camera's
+//! event service and repeatedly pulls messages from it. Cameras send the current state of each
+//! property when the subscription is created and on each change; the task translates these to
+//! signal states as described in [`db::json::OnvifEventsConfig`].
+//!
+//! Signal states are held as described in [`crate::signal_hold`] while the subscription is
+//! healthy.
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::{err, Error};
+use db::json::OnvifEventsConfig;
+use db::recording;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::{Client, Element, HTTP_TIMEOUT};
+use crate::signal_hold::Holder;
+
+/// How long the camera may wait for messages in each `PullMessages` call.
+const PULL_TIMEOUT: &str = "PT10S";
+
+/// The HTTP timeout for `PullMessages`, which must exceed [`PULL_TIMEOUT`].
+const PULL_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The subscription's lifetime, as requested on creation and each renewal.
+const TERMINATION_TIME: &str = "PT120S";
+
+/// How often the subscription is renewed; well within [`TERMINATION_TIME`].
+const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying after an error.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+const SIGNAL_STATE_INACTIVE: u16 = 1;
+const SIGNAL_STATE_ACTIVE: u16 = 2;
+
+/// The kind of event reported by a notification.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Motion,
+    Tamper,
+}
+
+/// Everything needed to ingest a single camera's events.
+pub struct Camera {
+    pub short_name: String,
+    pub onvif_base_url: Url,
+    pub username: String,
+    pub password: String,
+    pub config: OnvifEventsConfig,
+}
+
+impl Camera {
+    /// Returns the ingestion parameters for `c`, or `None` if it's not configured for ONVIF
+    /// events.
+    pub fn new(c: &db::Camera) -> Option<Self> {
+        let config = c.config.onvif_events.as_ref()?;
+        if config.motion_signal_id.is_none() && config.tamper_signal_id.is_none() {
+            return None;
+        }
+        let Some(url) = c.config.onvif_base_url.as_ref() else {
+            warn!(
+                "{}: ignoring ONVIF events config without an ONVIF base URL",
+                c.short_name
+            );
+            return None;
+        };
+        Some(Camera {
+            short_name: c.short_name.clone(),
+            onvif_base_url: url.clone(),
+            username: c.config.username.clone(),
+            password: c.config.password.clone(),
+            config: config.clone(),
+        })
+    }
+}
+
+/// The state of a single signal driven by events.
+#[derive(Debug)]
+struct Signal {
+    id: u32,
+    holder: Holder,
+}
+
+impl Signal {
+    fn new(id: u32) -> Self {
+        Signal {
+            id,
+            holder: Holder::default(),
+        }
+    }
+
+    /// Notes the camera's reported state (if any) as of `now`, returning a range and state to
+    /// set if appropriate.
+    fn update(
+        &mut self,
+        now: recording::Time,
+        active: Option<bool>,
+    ) -> Option<(Range<recording::Time>, u16)> {
+        let Some(active) = active else {
+            return self.holder.extend(now);
+        };
+        let state = match active {
+            true => SIGNAL_STATE_ACTIVE,
+            false => SIGNAL_STATE_INACTIVE,
+        };
+        let range = self.holder.set(now, now, state)?;
+        Some((range, state))
+    }
+
+    /// Forgets the camera's reported state, as when the subscription is lost.
+    fn reset(&mut self) {
+        self.holder.reset();
+    }
+}
+
+/// Ingests events from `camera` until shutdown.
+pub async fn run<C: base::clock::Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    camera: Camera,
+) {
+    let client = Client::new(camera.username.clone(), camera.password.clone());
+    let mut signals: Vec<(Kind, Signal)> = [
+        (Kind::Motion, camera.config.motion_signal_id),
+        (Kind::Tamper, camera.config.tamper_signal_id),
+    ]
+    .into_iter()
+    .filter_map(|(k, id)| Some((k, Signal::new(id?))))
+    .collect();
+    info!("{}: starting ONVIF event ingestion", camera.short_name);
+    loop {
+        tokio::select! {
+            r = subscribe(&db, &client, &camera, &mut signals) => {
+                if let Err(err) = r {
+                    warn!(%err, "{}: ONVIF event subscription failed", camera.short_name);
+                }
+            }
+            _ = shutdown_rx.as_future() => return,
+        }
+        for (_, s) in &mut signals {
+            s.reset();
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+    }
+}
+
+/// Subscribes to events and processes them until error.
+async fn subscribe<C: base::clock::Clocks + Clone>(
+    db: &db::Database<C>,
+    client: &Client,
+    camera: &Camera,
+    signals: &mut [(Kind, Signal)],
+) -> Result<(), Error> {
+    let events_url = client.service_url(&camera.onvif_base_url, "Events").await?;
+    let resp = client
+        .call(
+            &events_url,
+            "http://www.onvif.org/ver10/events/wsdl/EventPortType/CreatePullPointSubscriptionRequest",
+            &format!(
+                "<tev:CreatePullPointSubscription><tev:InitialTerminationTime>{TERMINATION_TIME}\
+                 </tev:InitialTerminationTime></tev:CreatePullPointSubscription>"
+            ),
+            HTTP_TIMEOUT,
+        )
+        .await?;
+    let subscription_url = resp
+        .find("SubscriptionReference")
+        .and_then(|r| r.child("Address"))
+        .ok_or_else(|| err!(InvalidArgument, msg("subscription response has no address")))?;
+    let subscription_url = Url::parse(subscription_url.text.trim())
+        .map_err(|e| err!(InvalidArgument, msg("bad subscription URL"), source(e)))?;
+    info!(
+        "{}: subscribed to ONVIF events at {subscription_url}",
+        camera.short_name
+    );
+    let mut last_renewal = tokio::time::Instant::now();
+    loop {
+        if last_renewal.elapsed() >= RENEW_INTERVAL {
+            client
+                .call(
+                    &subscription_url,
+                    "http://docs.oasis-open.org/wsn/bw-2/SubscriptionManager/RenewRequest",
+                    &format!(
+                        "<wsnt:Renew><wsnt:TerminationTime>{TERMINATION_TIME}\
+                         </wsnt:TerminationTime></wsnt:Renew>"
+                    ),
+                    HTTP_TIMEOUT,
+                )
+                .await?;
+            last_renewal = tokio::time::Instant::now();
+        }
+        let resp = client
+            .call(
+                &subscription_url,
+                "http://www.onvif.org/ver10/events/wsdl/PullPointSubscription/PullMessagesRequest",
+                &format!(
+                    "<tev:PullMessages><tev:Timeout>{PULL_TIMEOUT}</tev:Timeout>\
+                     <tev:MessageLimit>100</tev:MessageLimit></tev:PullMessages>"
+                ),
+                PULL_HTTP_TIMEOUT,
+            )
+            .await?;
+        let mut messages = Vec::new();
+        resp.find_all("NotificationMessage", &mut messages);
+        let now = recording::Time::new(db.clocks().realtime());
+        let mut l = db.lock();
+        for (kind, signal) in signals.iter_mut() {
+            // Use the latest reported state of this kind, if any.
+            let active = messages
+                .iter()
+                .filter_map(|m| parse_notification(m))
+                .filter(|(k, _)| k == kind)
+                .last()
+                .map(|(_, active)| active);
+            if let Some(active) = active {
+                debug!("{}: {kind:?} active={active}", camera.short_name);
+            }
+            let Some((range, state)) = signal.update(now, active) else {
+                continue;
+            };
+            if let Err(err) = l.update_signals(range, &[signal.id], &[state]) {
+                warn!(%err, "{}: unable to update signal {}", camera.short_name, signal.id);
+            }
+        }
+    }
+}
+
+/// Parses a `NotificationMessage` into the kind of event and whether it's active, or `None` if
+/// it's not a recognized event.
+fn parse_notification(msg: &Element) -> Option<(Kind, bool)> {
+    let topic = msg.child("Topic")?.text.as_str();
+    let kind = if topic.contains("Tamper") || topic.contains("GlobalSceneChange") {
+        Kind::Tamper
+    } else if topic.contains("Motion") {
+        Kind::Motion
+    } else {
+        return None;
+    };
+    let data = msg.find("Data")?;
+    let mut items = Vec::new();
+    data.find_all("SimpleItem", &mut items);
+    items.iter().find_map(|i| {
+        if !matches!(
+            i.attr("Name")?,
+            "IsMotion" | "IsTamper" | "State" | "LogicalState"
+        ) {
+            return None;
+        }
+        match i.attr("Value")? {
+            "true" | "1" => Some((kind, true)),
+            "false" | "0" => Some((kind, false)),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kind, Signal, SIGNAL_STATE_ACTIVE, SIGNAL_STATE_INACTIVE};
+    use crate::onvif::Element;
+    use crate::signal_hold::HOLD;
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use db::testutil;
+
+    fn notification(topic: &str, name: &str, value: &str) -> Element {
+        Element::parse(&format!(
+            r#"<wsnt:NotificationMessage xmlns:wsnt="http://docs.oasis-open.org/wsn/b-2"
+                   xmlns:tns1="http://www.onvif.org/ver10/topics"
+                   xmlns:tt="http://www.onvif.org/ver10/schema">
+                 <wsnt:Topic Dialect="http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet">{topic}</wsnt:Topic>
+                 <wsnt:Message>
+                   <tt:Message UtcTime="2024-03-01T12:34:56Z" PropertyOperation="Changed">
+                     <tt:Source>
+                       <tt:SimpleItem Name="VideoSourceConfigurationToken" Value="1"/>
+                     </tt:Source>
+                     <tt:Data><tt:SimpleItem Name="{name}" Value="{value}"/></tt:Data>
+                   </tt:Message>
+                 </wsnt:Message>
+               </wsnt:NotificationMessage>"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_notification() {
+        testutil::init();
+        let p = |topic, name, value| super::parse_notification(&notification(topic, name, value));
+        assert_eq!(
+            p(
+                "tns1:RuleEngine/CellMotionDetector/Motion",
+                "IsMotion",
+                "true"
+            ),
+            Some((Kind::Motion, true))
+        );
+        assert_eq!(
+            p("tns1:VideoSource/MotionAlarm", "State", "false"),
+            Some((Kind::Motion, false))
+        );
+        assert_eq!(
+            p("tns1:RuleEngine/TamperDetector/Tamper", "IsTamper", "1"),
+            Some((Kind::Tamper, true))
+        );
+        assert_eq!(
+            p(
+                "tns1:VideoSource/GlobalSceneChange/ImagingService",
+                "State",
+                "0"
+            ),
+            Some((Kind::Tamper, false))
+        );
+        assert_eq!(
+            p("tns1:Device/Trigger/DigitalInput", "LogicalState", "true"),
+            None
+        );
+        assert_eq!(
+            p(
+                "tns1:RuleEngine/CellMotionDetector/Motion",
+                "IsMotion",
+                "maybe"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn signal() {
+        testutil::init();
+        let sec = |s: i64| recording::Time(s * TIME_UNITS_PER_SEC);
+        let mut s = Signal::new(1);
+
+        // Nothing is set until the camera reports a state.
+        assert_eq!(s.update(sec(0), None), None);
+
+        // A new state starts now.
+        assert_eq!(
+            s.update(sec(1), Some(true)),
+            Some((sec(1)..sec(1) + HOLD, SIGNAL_STATE_ACTIVE))
+        );
+
+        // A continuing state is extended, but not too often.
+        assert_eq!(s.update(sec(2), None), None);
+        assert_eq!(s.update(sec(15), Some(true)), None);
+        assert_eq!(
+            s.update(sec(20), None),
+            Some((sec(1) + HOLD..sec(20) + HOLD, SIGNAL_STATE_ACTIVE))
+        );
+
+        // A change takes effect immediately.
+        assert_eq!(
+            s.update(sec(21), Some(false)),
+            Some((sec(21)..sec(21) + HOLD, SIGNAL_STATE_INACTIVE))
+        );
+
+        // After a reset, nothing is set until the camera reports a state again.
+        s.reset();
+        assert_eq!(s.update(sec(22), None), None);
+        assert_eq!(
+            s.update(sec(23), Some(false)),
+            Some((sec(23)..sec(23) + HOLD, SIGNAL_STATE_INACTIVE))
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Minimal ONVIF client: SOAP 1.2 over plain HTTP with WS-Security `UsernameToken`
+//! authentication, and just enough XML parsing to pick values out of responses.
+
+use std::time::Duration;
+
+use base::{bail, err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use quick_xml::events::{BytesStart, Event};
+use ring::rand::{SecureRandom, SystemRandom};
+use url::Url;
+
+pub mod backchannel;
+pub mod discovery;
+pub mod events;
+pub mod media;
+pub mod ptz;
+
+/// The HTTP timeout for ordinary (non-long-polling) calls.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Namespace declarations used in requests.
+const NAMESPACES: &str = concat!(
+    r#"xmlns:s="http://www.w3.org/2003/05/soap-envelope" "#,
+    r#"xmlns:a="http://www.w3.org/2005/08/addressing" "#,
+    r#"xmlns:tds="http://www.onvif.org/ver10/device/wsdl" "#,
+    r#"xmlns:tev="http://www.onvif.org/ver10/events/wsdl" "#,
+    r#"xmlns:trt="http://www.onvif.org/ver10/media/wsdl" "#,
+    r#"xmlns:tptz="http://www.onvif.org/ver20/ptz/wsdl" "#,
+    r#"xmlns:wsnt="http://docs.oasis-open.org/wsn/b-2" "#,
+    r#"xmlns:tt="http://www.onvif.org/ver10/schema""#,
+);
+
+/// A parsed XML element, with namespace prefixes dropped from element and attribute names.
+///
+/// ONVIF responses vary in their choice of prefixes, and the local names are unambiguous
+/// enough for picking out the values Moonfire NVR needs.
+#[derive(Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn parse(xml: &str) -> Result<Element, Error> {
+        let mut reader = quick_xml::Reader::from_str(xml);
+        reader.trim_text(true);
+        let mut stack: Vec<Element> = Vec::new();
+        let mut root = None;
+        let close = |stack: &mut Vec<Element>, root: &mut Option<Element>, e: Element| match stack
+            .last_mut()
+        {
+            Some(parent) => parent.children.push(e),
+            None => *root = Some(e),
+        };
+        loop {
+            let event = reader
+                .read_event()
+                .map_err(|e| err!(InvalidArgument, msg("invalid XML"), source(e)))?;
+            match event {
+                Event::Start(e) => stack.push(Element::start(&e)?),
+                Event::Empty(e) => {
+                    let e = Element::start(&e)?;
+                    close(&mut stack, &mut root, e);
+                }
+                Event::End(_) => {
+                    let e = stack
+                        .pop()
+                        .ok_or_else(|| err!(InvalidArgument, msg("unbalanced XML")))?;
+                    close(&mut stack, &mut root, e);
+                }
+                Event::Text(t) => {
+                    if let Some(e) = stack.last_mut() {
+                        let t = t
+                            .unescape()
+                            .map_err(|e| err!(InvalidArgument, msg("invalid XML"), source(e)))?;
+                        e.text.push_str(&t);
+                    }
+                }
+                Event::CData(t) => {
+                    if let Some(e) = stack.last_mut() {
+                        e.text.push_str(&String::from_utf8_lossy(&t));
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        root.ok_or_else(|| err!(InvalidArgument, msg("XML has no root element")))
+    }
+
+    fn start(e: &BytesStart) -> Result<Element, Error> {
+        let mut attrs = Vec::new();
+        for a in e.attributes() {
+            let a = a.map_err(|e| err!(InvalidArgument, msg("invalid XML"), source(e)))?;
+            let value = a
+                .unescape_value()
+                .map_err(|e| err!(InvalidArgument, msg("invalid XML"), source(e)))?;
+            attrs.push((
+                String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned(),
+                value.into_owned(),
+            ));
+        }
+        Ok(Element {
+            name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+            attrs,
+            children: Vec::new(),
+            text: String::new(),
+        })
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// Returns the first descendant with the given name, in depth-first order.
+    pub fn find(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|c| {
+            if c.name == name {
+                Some(c)
+            } else {
+                c.find(name)
+            }
+        })
+    }
+
+    /// Returns all descendants with the given name, in depth-first order. Descendants of
+    /// matching elements aren't examined.
+    pub fn find_all<'a>(&'a self, name: &str, out: &mut Vec<&'a Element>) {
+        for c in &self.children {
+            if c.name == name {
+                out.push(c);
+            } else {
+                c.find_all(name, out);
+            }
+        }
+    }
+}
+
+/// A client for a camera's ONVIF services.
+pub struct Client {
+    http: hyper::Client<hyper::client::HttpConnector>,
+    username: String,
+    password: String,
+    rand: SystemRandom,
+}
+
+impl Client {
+    /// Creates a client which authenticates with the given credentials, or not at all if
+    /// `username` is empty.
+    pub fn new(username: String, password: String) -> Self {
+        Client {
+            http: hyper::Client::new(),
+            username,
+            password,
+            rand: SystemRandom::new(),
+        }
+    }
+
+    /// Calls `action` at `url` with the given SOAP body, returning the response's `Body`
+    /// element.
+    pub async fn call(
+        &self,
+        url: &Url,
+        action: &str,
+        body: &str,
+        timeout: Duration,
+    ) -> Result<Element, Error> {
+        if url.scheme() != "http" {
+            bail!(
+                Unimplemented,
+                msg("unsupported ONVIF URL {url}; only http is supported")
+            );
+        }
+        let security = if self.username.is_empty() {
+            String::new()
+        } else {
+            let mut nonce = [0u8; 16];
+            self.rand
+                .fill(&mut nonce)
+                .map_err(|_| err!(Internal, msg("unable to generate nonce")))?;
+            let created = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+            security_header(&self.username, &self.password, &nonce, &created)
+        };
+        let envelope = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <s:Envelope {NAMESPACES}><s:Header>{security}\
+             <a:Action>{action}</a:Action><a:To>{to}</a:To></s:Header>\
+             <s:Body>{body}</s:Body></s:Envelope>",
+            action = quick_xml::escape::escape(action),
+            to = quick_xml::escape::escape(url.as_str()),
+        );
+        let req = hyper::Request::post(url.as_str())
+            .header(
+                http::header::CONTENT_TYPE,
+                format!("application/soap+xml; charset=utf-8; action=\"{action}\""),
+            )
+            .body(hyper::Body::from(envelope))
+            .map_err(|e| err!(InvalidArgument, msg("bad ONVIF request"), source(e)))?;
+        let (status, body) = tokio::time::timeout(timeout, async {
+            let resp = self.http.request(req).await?;
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+            Ok::<_, hyper::Error>((status, body))
+        })
+        .await
+        .map_err(|_| err!(DeadlineExceeded, msg("timed out calling {url}")))?
+        .map_err(|e| err!(Unavailable, msg("unable to call {url}"), source(e)))?;
+        let body = String::from_utf8_lossy(&body);
+        if status == http::StatusCode::UNAUTHORIZED {
+            bail!(Unauthenticated, msg("{url} rejected credentials"));
+        }
+        let envelope = Element::parse(&body)?;
+        let body = envelope
+            .children
+            .into_iter()
+            .find(|c| c.name == "Body")
+            .ok_or_else(|| err!(InvalidArgument, msg("{url} returned no SOAP body")))?;
+        if let Some(fault) = body.child("Fault") {
+            let reason = fault
+                .find("Text")
+                .or_else(|| fault.find("faultstring"))
+                .map_or("unknown", |t| t.text.as_str());
+            bail!(Unknown, msg("{url} returned SOAP fault: {reason}"));
+        }
+        if !status.is_success() {
+            bail!(Unknown, msg("{url} returned HTTP status {status}"));
+        }
+        Ok(body)
+    }
+
+    /// Returns the URL of the service in the given `GetCapabilities` category (such as `Events`
+    /// or `Media`), using the device service under `onvif_base_url`.
+    pub async fn service_url(&self, onvif_base_url: &Url, category: &str) -> Result<Url, Error> {
+        let device_url = onvif_base_url
+            .join("device_service")
+            .map_err(|e| err!(InvalidArgument, source(e)))?;
+        let resp = self
+            .call(
+                &device_url,
+                "http://www.onvif.org/ver10/device/wsdl/GetCapabilities",
+                &format!(
+                    "<tds:GetCapabilities><tds:Category>{category}</tds:Category>\
+                     </tds:GetCapabilities>"
+                ),
+                HTTP_TIMEOUT,
+            )
+            .await?;
+        let url = resp
+            .find(category)
+            .and_then(|e| e.child("XAddr"))
+            .ok_or_else(|| err!(Unimplemented, msg("camera has no ONVIF {category} service")))?;
+        Url::parse(url.text.trim()).map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad ONVIF {category} service URL"),
+                source(e)
+            )
+        })
+    }
+}
+
+/// Returns a WS-Security header with a `UsernameToken` using a password digest, as ONVIF
+/// requires.
+fn security_header(username: &str, password: &str, nonce: &[u8], created: &str) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(nonce);
+    ctx.update(created.as_bytes());
+    ctx.update(password.as_bytes());
+    let digest = STANDARD.encode(ctx.finish());
+    format!(
+        concat!(
+            r#"<wsse:Security s:mustUnderstand="1" "#,
+            r#"xmlns:wsse="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd" "#,
+            r#"xmlns:wsu="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">"#,
+            "<wsse:UsernameToken><wsse:Username>{username}</wsse:Username>",
+            r#"<wsse:Password Type="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest">"#,
+            "{digest}</wsse:Password>",
+            r#"<wsse:Nonce EncodingType="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary">"#,
+            "{nonce}</wsse:Nonce><wsu:Created>{created}</wsu:Created>",
+            "</wsse:UsernameToken></wsse:Security>",
+        ),
+        username = quick_xml::escape::escape(username),
+        digest = digest,
+        nonce = STANDARD.encode(nonce),
+        created = created,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Element;
+    use db::testutil;
+
+    #[test]
+    fn parse() {
+        testutil::init();
+        let e = Element::parse(
+            r#"<?xml version="1.0"?>
+            <s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:tt="x">
+              <s:Body>
+                <tt:Foo tt:Name="a&amp;b"><tt:Bar>one &lt; two</tt:Bar><tt:Bar/></tt:Foo>
+              </s:Body>
+            </s:Envelope>"#,
+        )
+        .unwrap();
+        assert_eq!(e.name, "Envelope");
+        let foo = e.find("Foo").unwrap();
+        assert_eq!(foo.attr("Name"), Some("a&b"));
+        assert_eq!(foo.child("Bar").unwrap().text, "one < two");
+        let mut bars = Vec::new();
+        e.find_all("Bar", &mut bars);
+        assert_eq!(bars.len(), 2);
+        assert!(e.find("Baz").is_none());
+        Element::parse("<a><b></a>").unwrap_err();
+    }
+
+    #[test]
+    fn security_header() {
+        testutil::init();
+        let nonce: Vec<u8> = (0..16).collect();
+        let h = super::security_header("admin", "p@ss", &nonce, "2024-03-01T12:34:56Z");
+        let e = Element::parse(&h).unwrap();
+        assert_eq!(e.find("Username").unwrap().text, "admin");
+        assert_eq!(
+            e.find("Password").unwrap().text,
+            "WTyGo430/YxNfDKljTZuMIX+7VU="
+        );
+        assert_eq!(e.find("Nonce").unwrap().text, "AAECAwQFBgcICQoLDA0ODw==");
+        assert_eq!(e.find("Created").unwrap().text, "2024-03-01T12:34:56Z");
+    }
+}
@@ -0,0 +1,212 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! ONVIF PTZ (pan/tilt/zoom) service.
+
+use std::time::Duration;
+
+use base::{err, Error};
+use quick_xml::escape::escape;
+use url::Url;
+
+use super::{Client, Element, HTTP_TIMEOUT};
+
+/// A preset position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Preset {
+    pub token: String,
+    pub name: String,
+}
+
+/// A connection to a camera's PTZ service, using its first PTZ-capable media profile.
+pub struct Ptz {
+    client: Client,
+    url: Url,
+    profile_token: String,
+}
+
+impl Ptz {
+    /// Finds the PTZ service and profile, failing if the camera doesn't support PTZ.
+    pub async fn connect(client: Client, onvif_base_url: &Url) -> Result<Self, Error> {
+        let url = client.service_url(onvif_base_url, "PTZ").await?;
+        let media_url = client.service_url(onvif_base_url, "Media").await?;
+        let profile_token = super::media::profiles_without_uris(&client, &media_url)
+            .await?
+            .into_iter()
+            .find(|p| p.ptz)
+            .ok_or_else(|| err!(FailedPrecondition, msg("camera has no PTZ profile")))?
+            .token;
+        Ok(Ptz {
+            client,
+            url,
+            profile_token,
+        })
+    }
+
+    async fn call(&self, action: &str, body: &str) -> Result<Element, Error> {
+        self.client
+            .call(
+                &self.url,
+                &format!("http://www.onvif.org/ver20/ptz/wsdl/{action}"),
+                body,
+                HTTP_TIMEOUT,
+            )
+            .await
+    }
+
+    /// Starts moving with the given velocities, each in `[-1, 1]`, until stopped or until
+    /// `timeout` (if supplied) expires.
+    pub async fn continuous_move(
+        &self,
+        pan: f32,
+        tilt: f32,
+        zoom: f32,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.call(
+            "ContinuousMove",
+            &continuous_move_body(&self.profile_token, pan, tilt, zoom, timeout),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Stops any pan, tilt, and zoom movement.
+    pub async fn stop(&self) -> Result<(), Error> {
+        self.call(
+            "Stop",
+            &format!(
+                "<tptz:Stop><tptz:ProfileToken>{}</tptz:ProfileToken>\
+                 <tptz:PanTilt>true</tptz:PanTilt><tptz:Zoom>true</tptz:Zoom></tptz:Stop>",
+                escape(&self.profile_token),
+            ),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Lists preset positions.
+    pub async fn presets(&self) -> Result<Vec<Preset>, Error> {
+        let resp = self
+            .call(
+                "GetPresets",
+                &format!(
+                    "<tptz:GetPresets><tptz:ProfileToken>{}</tptz:ProfileToken></tptz:GetPresets>",
+                    escape(&self.profile_token),
+                ),
+            )
+            .await?;
+        Ok(parse_presets(&resp))
+    }
+
+    /// Moves to the given preset position.
+    pub async fn goto_preset(&self, preset_token: &str) -> Result<(), Error> {
+        self.call(
+            "GotoPreset",
+            &format!(
+                "<tptz:GotoPreset><tptz:ProfileToken>{}</tptz:ProfileToken>\
+                 <tptz:PresetToken>{}</tptz:PresetToken></tptz:GotoPreset>",
+                escape(&self.profile_token),
+                escape(preset_token),
+            ),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+fn continuous_move_body(
+    profile_token: &str,
+    pan: f32,
+    tilt: f32,
+    zoom: f32,
+    timeout: Option<Duration>,
+) -> String {
+    let timeout = timeout.map_or_else(String::new, |t| {
+        format!(
+            "<tptz:Timeout>PT{}.{:03}S</tptz:Timeout>",
+            t.as_secs(),
+            t.subsec_millis()
+        )
+    });
+    format!(
+        "<tptz:ContinuousMove><tptz:ProfileToken>{}</tptz:ProfileToken><tptz:Velocity>\
+         <tt:PanTilt x=\"{pan}\" y=\"{tilt}\"/><tt:Zoom x=\"{zoom}\"/></tptz:Velocity>\
+         {timeout}</tptz:ContinuousMove>",
+        escape(profile_token),
+    )
+}
+
+/// Parses a `GetPresetsResponse` body.
+fn parse_presets(body: &Element) -> Vec<Preset> {
+    let mut elements = Vec::new();
+    body.find_all("Preset", &mut elements);
+    elements
+        .into_iter()
+        .filter_map(|p| {
+            Some(Preset {
+                token: p.attr("token")?.to_owned(),
+                name: p.child("Name").map_or_else(String::new, |n| n.text.clone()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Preset;
+    use crate::onvif::Element;
+    use db::testutil;
+    use std::time::Duration;
+
+    #[test]
+    fn continuous_move_body() {
+        testutil::init();
+        let body = super::continuous_move_body(
+            "Profile<1>",
+            0.5,
+            -1.0,
+            0.0,
+            Some(Duration::from_millis(1500)),
+        );
+        assert_eq!(
+            body,
+            "<tptz:ContinuousMove><tptz:ProfileToken>Profile&lt;1&gt;</tptz:ProfileToken>\
+             <tptz:Velocity><tt:PanTilt x=\"0.5\" y=\"-1\"/><tt:Zoom x=\"0\"/></tptz:Velocity>\
+             <tptz:Timeout>PT1.500S</tptz:Timeout></tptz:ContinuousMove>"
+        );
+        assert!(!super::continuous_move_body("p", 0.0, 0.0, 1.0, None).contains("Timeout"));
+    }
+
+    #[test]
+    fn parse_presets() {
+        testutil::init();
+        let body = Element::parse(
+            r#"<s:Body xmlns:s="http://www.w3.org/2003/05/soap-envelope"
+                   xmlns:tptz="http://www.onvif.org/ver20/ptz/wsdl"
+                   xmlns:tt="http://www.onvif.org/ver10/schema">
+                 <tptz:GetPresetsResponse>
+                   <tptz:Preset token="1"><tt:Name>driveway</tt:Name>
+                     <tt:PTZPosition><tt:PanTilt x="0.1" y="0.2"/></tt:PTZPosition>
+                   </tptz:Preset>
+                   <tptz:Preset token="2"/>
+                 </tptz:GetPresetsResponse>
+               </s:Body>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            super::parse_presets(&body),
+            vec![
+                Preset {
+                    token: "1".to_owned(),
+                    name: "driveway".to_owned(),
+                },
+                Preset {
+                    token: "2".to_owned(),
+                    name: String::new(),
+                },
+            ]
+        );
+    }
+}
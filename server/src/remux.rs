@@ -0,0 +1,383 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Plumbing shared by the `.mkv` and `.ts` file builders.
+//!
+//! Both serve the same recording ranges as `mp4::FileBuilder` by remuxing frames into groups
+//! (Matroska clusters or transport stream groups of pictures), each of which is generated on
+//! request by reading its frames from the sample file, where they're contiguous. A [`Format`]
+//! lays out its groups and generates their bytes; this module handles the segments, the etag and
+//! `Last-Modified`, reading groups from disk, and serving the result via `http_serve`.
+
+use crate::body::{wrap_error, BoxedError, Chunk};
+use crate::slices::{self, Slices};
+use base::{bail, err, Error, ErrorKind, ResultExt};
+use byteorder::{BigEndian, WriteBytesExt};
+use db::dir;
+use db::recording::{self, rescale};
+use futures::stream::{self, TryStreamExt};
+use futures::{FutureExt, Stream};
+use http::header::HeaderValue;
+use reffers::ARefss;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A container format built from remuxed groups of frames.
+pub trait Format: Send + Sync + Sized + 'static {
+    /// The usual file extension, such as `mkv`.
+    const NAME: &'static str;
+
+    /// The `Content-Type` header value.
+    const CONTENT_TYPE: &'static str;
+
+    /// Included in the etag; see each format's `FORMAT_VERSION` constant.
+    const FORMAT_VERSION: [u8; 1];
+
+    type Slice: slices::Slice<Ctx = File<Self>, Chunk = Chunk> + Send;
+}
+
+/// A wrapper around `recording::Segment` that keeps some additional state.
+#[derive(Debug)]
+struct Segment {
+    s: recording::Segment,
+
+    /// The absolute timestamp of the recording's start time.
+    recording_start: recording::Time,
+
+    recording_wall_duration_90k: i32,
+    recording_media_duration_90k: i32,
+
+    /// The _desired_, _relative_, _media_ time range covered by this recording, as in
+    /// `mp4::Segment`.
+    rel_media_range_90k: Range<i32>,
+}
+
+impl Segment {
+    fn wall(&self, rel_media_90k: i32) -> i32 {
+        rescale(
+            rel_media_90k,
+            self.recording_media_duration_90k,
+            self.recording_wall_duration_90k,
+        )
+    }
+}
+
+/// The results of [`FileBuilder::lay_out`].
+pub(crate) struct Layout {
+    /// The total duration of the frames, in 90 kHz units.
+    pub(crate) duration_90k: u64,
+
+    /// The wall time of the first frame, if any.
+    pub(crate) start: Option<recording::Time>,
+
+    etag: HeaderValue,
+    last_modified: SystemTime,
+}
+
+pub struct FileBuilder<F> {
+    segments: Vec<Segment>,
+    video_sample_entry: Option<Arc<db::VideoSampleEntry>>,
+    content_disposition: Option<HeaderValue>,
+    format: PhantomData<fn() -> F>,
+}
+
+impl<F> Default for FileBuilder<F> {
+    fn default() -> Self {
+        FileBuilder {
+            segments: Vec::new(),
+            video_sample_entry: None,
+            content_disposition: None,
+            format: PhantomData,
+        }
+    }
+}
+
+impl<F: Format> FileBuilder<F> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves space for the given number of additional segments.
+    pub fn reserve(&mut self, additional: usize) {
+        self.segments.reserve(additional);
+    }
+
+    /// Appends a segment for (a subset of) the given recording, as in
+    /// `mp4::FileBuilder::append`. All segments must share a video sample entry.
+    pub fn append(
+        &mut self,
+        db: &db::LockedDatabase,
+        row: db::ListRecordingsRow,
+        rel_media_range_90k: Range<i32>,
+        start_at_key: bool,
+    ) -> Result<(), Error> {
+        if let Some(prev) = self.segments.last() {
+            if prev.s.have_trailing_zero() {
+                bail!(
+                    InvalidArgument,
+                    msg(
+                        "unable to append recording {} after recording {} with trailing zero",
+                        row.id,
+                        prev.s.id,
+                    ),
+                );
+            }
+        }
+        match self.video_sample_entry {
+            Some(ref e) if e.id != row.video_sample_entry_id => bail!(
+                InvalidArgument,
+                msg(
+                    "recording {} changes video sample entry; unsupported in .{} files",
+                    row.id,
+                    F::NAME,
+                ),
+            ),
+            Some(_) => {}
+            None => {
+                let vse = db
+                    .video_sample_entries_by_id()
+                    .get(&row.video_sample_entry_id)
+                    .unwrap();
+                self.video_sample_entry = Some(vse.clone());
+            }
+        }
+        self.segments.push(Segment {
+            s: recording::Segment::new(db, &row, rel_media_range_90k.clone(), start_at_key)
+                .err_kind(ErrorKind::Unknown)?,
+            recording_start: row.start,
+            recording_wall_duration_90k: row.wall_duration_90k,
+            recording_media_duration_90k: row.media_duration_90k,
+            rel_media_range_90k,
+        });
+        Ok(())
+    }
+
+    pub fn set_filename(&mut self, filename: &str) -> Result<(), Error> {
+        self.content_disposition = Some(
+            HeaderValue::try_from(format!("attachment; filename=\"{filename}\""))
+                .err_kind(ErrorKind::InvalidArgument)?,
+        );
+        Ok(())
+    }
+
+    /// Returns the video sample entry shared by all segments.
+    pub(crate) fn video_sample_entry(&self) -> Result<&db::VideoSampleEntry, Error> {
+        self.video_sample_entry
+            .as_deref()
+            .ok_or_else(|| err!(InvalidArgument, msg("no video_sample_entries")))
+    }
+
+    /// Calls `push` for each frame in decode order, starting from each segment's actual start,
+    /// with the index of its segment, its time relative to the start of the file (in 90 kHz
+    /// units), its position within the segment's sample file, its length, and if it's a key
+    /// frame.
+    pub(crate) fn lay_out(
+        &self,
+        db: &db::Database,
+        mut push: impl FnMut(usize, u64, u64, u32, bool) -> Result<(), Error>,
+    ) -> Result<Layout, Error> {
+        let mut etag = blake3::Hasher::new();
+        etag.update(&F::FORMAT_VERSION[..]);
+        if let Some(cd) = self.content_disposition.as_ref() {
+            etag.update(b":cd:");
+            etag.update(cd.as_bytes());
+        }
+        let mut max_end = None;
+        let mut duration_90k = 0;
+        for (i, s) in self.segments.iter().enumerate() {
+            let md = &s.rel_media_range_90k;
+            let wall_end = s.recording_start + recording::Duration(i64::from(s.wall(md.end)));
+            max_end = Some(cmp::max(max_end.unwrap_or(wall_end), wall_end));
+
+            let start_90k = s.s.actual_start_90k();
+            let mut end_90k = start_90k;
+            db.lock()
+                .with_recording_playback(s.s.id, &mut |playback| {
+                    s.s.foreach(playback, |it| {
+                        let rel_90k = u64::try_from(it.start_90k - start_90k).unwrap();
+                        push(
+                            i,
+                            duration_90k + rel_90k,
+                            u64::try_from(it.pos).unwrap(),
+                            u32::try_from(it.bytes).unwrap(),
+                            it.is_key(),
+                        )?;
+                        end_90k = it.start_90k + it.duration_90k;
+                        Ok(())
+                    })
+                })
+                .err_kind(ErrorKind::Unknown)?;
+            duration_90k += u64::try_from(end_90k - start_90k).unwrap();
+
+            // Update the etag to reflect this segment.
+            let mut data = [0_u8; 28];
+            let mut cursor = io::Cursor::new(&mut data[..]);
+            cursor
+                .write_i64::<BigEndian>(s.s.id.0)
+                .err_kind(ErrorKind::Internal)?;
+            cursor
+                .write_i64::<BigEndian>(s.recording_start.0)
+                .err_kind(ErrorKind::Internal)?;
+            cursor
+                .write_u32::<BigEndian>(s.s.open_id)
+                .err_kind(ErrorKind::Internal)?;
+            cursor
+                .write_i32::<BigEndian>(md.start)
+                .err_kind(ErrorKind::Internal)?;
+            cursor
+                .write_i32::<BigEndian>(md.end)
+                .err_kind(ErrorKind::Internal)?;
+            etag.update(cursor.into_inner());
+        }
+        let start = self.segments.first().map(|s| {
+            s.recording_start + recording::Duration(i64::from(s.wall(s.s.actual_start_90k())))
+        });
+        let max_end = max_end.map(|t| t.unix_seconds()).unwrap_or(0);
+        let etag = etag.finalize();
+        Ok(Layout {
+            duration_90k,
+            start,
+            etag: HeaderValue::try_from(format!("\"{}\"", etag.to_hex().as_str()))
+                .expect("hex string should be valid UTF-8"),
+            last_modified: ::std::time::UNIX_EPOCH
+                + ::std::time::Duration::from_secs(max_end as u64),
+        })
+    }
+
+    /// Builds the `File`, consuming the builder.
+    pub(crate) fn into_file(
+        self,
+        layout: Layout,
+        dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+        slices: Slices<F::Slice>,
+        format: F,
+    ) -> File<F> {
+        File(Arc::new(FileInner {
+            dirs_by_id,
+            segments: self.segments,
+            slices,
+            last_modified: layout.last_modified,
+            etag: layout.etag,
+            content_disposition: self.content_disposition,
+            format,
+        }))
+    }
+}
+
+struct FileInner<F: Format> {
+    dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+    segments: Vec<Segment>,
+    slices: Slices<F::Slice>,
+    last_modified: SystemTime,
+    etag: HeaderValue,
+    content_disposition: Option<HeaderValue>,
+    format: F,
+}
+
+pub struct File<F: Format>(Arc<FileInner<F>>);
+
+impl<F: Format> Clone for File<F> {
+    fn clone(&self) -> Self {
+        File(self.0.clone())
+    }
+}
+
+impl<F: Format> File<F> {
+    /// Returns the format-specific state.
+    pub(crate) fn format(&self) -> &F {
+        &self.0.format
+    }
+
+    pub(crate) fn slices(&self) -> &Slices<F::Slice> {
+        &self.0.slices
+    }
+
+    /// Returns a chunk of the format-specific state, such as a buffer laid out up front.
+    pub(crate) fn format_chunk(&self, f: impl FnOnce(&F) -> &[u8]) -> Chunk {
+        ARefss::new(self.0.clone()).map(|i| f(&i.format)).into()
+    }
+
+    /// Gets a range of a group, reading the group's frames (`sample_file_range` within the
+    /// given segment's sample file) from disk and passing them to `generate`.
+    pub(crate) fn get_group<G>(
+        &self,
+        segment: usize,
+        sample_file_range: Range<u64>,
+        r: Range<u64>,
+        generate: G,
+    ) -> Box<dyn Stream<Item = Result<Chunk, BoxedError>> + Send + Sync>
+    where
+        G: FnOnce(&F, &[u8]) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+    {
+        let s = &self.0.segments[segment].s;
+        let d = match self.0.dirs_by_id.get(&s.sample_file_dir_id) {
+            None => {
+                return Box::new(stream::iter(std::iter::once(Err(wrap_error(err!(
+                    NotFound,
+                    msg("{}: dir {} not found", s.id, s.sample_file_dir_id)
+                ))))))
+            }
+            Some(d) => d,
+        };
+        let f = self.clone();
+        Box::new(stream::once(
+            d.open_file(s.id, sample_file_range, s.key.clone())
+                .try_concat()
+                .map(move |data| -> Result<Chunk, BoxedError> {
+                    let data = data.map_err(wrap_error)?;
+                    let group = generate(&f.0.format, &data).map_err(wrap_error)?;
+                    Ok(ARefss::new(group)
+                        .map(|g| &g[r.start as usize..r.end as usize])
+                        .into())
+                }),
+        ))
+    }
+}
+
+impl<F: Format> http_serve::Entity for File<F> {
+    type Data = Chunk;
+    type Error = BoxedError;
+
+    fn add_headers(&self, hdrs: &mut http::header::HeaderMap) {
+        hdrs.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static(F::CONTENT_TYPE),
+        );
+        if let Some(cd) = self.0.content_disposition.as_ref() {
+            hdrs.insert(http::header::CONTENT_DISPOSITION, cd.clone());
+        }
+    }
+    fn last_modified(&self) -> Option<SystemTime> {
+        Some(self.0.last_modified)
+    }
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(self.0.etag.clone())
+    }
+    fn len(&self) -> u64 {
+        self.0.slices.len()
+    }
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.0.slices.get_range(self, range)
+    }
+}
+
+impl<F: Format> fmt::Debug for File<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(&format!("{}::File", F::NAME))
+            .field("last_modified", &self.0.last_modified)
+            .field("etag", &self.0.etag)
+            .field("slices", &self.0.slices)
+            .field("segments", &self.0.segments)
+            .finish()
+    }
+}
@@ -0,0 +1,361 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Off-site replication of recordings and database backups over SFTP.
+//!
+//! A single task periodically copies each committed recording's sample file to a remote host
+//! and records the copy in the `replication` table. Less often, it also copies a backup of the
+//! SQLite database, which holds the indexes needed to play back the sample files.
+//!
+//! Copies are made with OpenSSH's `sftp` command in batch mode, so authentication, host key
+//! checking, and connection settings come from the usual SSH client configuration. Bandwidth
+//! is limited via `sftp -l`. Each file is written to a `.part` path and renamed into place once
+//! complete; if a transfer is interrupted, the next attempt resumes it with `reput`.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base::clock::Clocks;
+use base::{bail, err, Error, FastHashSet};
+use db::recording;
+use db::CompositeId;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::cmds::run::config::ReplicationConfig;
+
+/// The number of recordings to look up at once.
+const BATCH_LEN: usize = 64;
+
+/// A recording to copy.
+struct ToCopy {
+    local_path: PathBuf,
+
+    /// The remote directories to create, parents first.
+    remote_dirs: [String; 2],
+    remote_path: String,
+}
+
+pub struct Replicator {
+    /// The `sftp` destination: `host` or `user@host`.
+    destination: String,
+    port: Option<u16>,
+    identity_file: Option<PathBuf>,
+    remote_dir: String,
+    interval: Duration,
+    backup_interval: Option<Duration>,
+    bandwidth_limit_kbps: Option<u32>,
+
+    /// Where to write database backups before copying them.
+    backup_path: PathBuf,
+    last_backup: Option<Instant>,
+
+    /// Recordings whose last copy attempt failed, which may have left a partial `.part` file to
+    /// resume.
+    interrupted: FastHashSet<CompositeId>,
+}
+
+impl Replicator {
+    pub fn new(config: &ReplicationConfig, db_dir: &Path) -> Result<Self, Error> {
+        if config.host.is_empty() {
+            bail!(InvalidArgument, msg("replication host must be non-empty"));
+        }
+        let remote_dir = config.remote_dir.trim_end_matches('/');
+        if remote_dir.is_empty() {
+            bail!(
+                InvalidArgument,
+                msg("replication remoteDir must be a directory other than /")
+            );
+        }
+        Ok(Replicator {
+            destination: match config.user {
+                Some(ref u) => format!("{u}@{}", config.host),
+                None => config.host.clone(),
+            },
+            port: config.port,
+            identity_file: config.identity_file.clone(),
+            remote_dir: remote_dir.to_owned(),
+            interval: Duration::from_secs(config.interval_sec),
+            backup_interval: match config.backup_interval_sec {
+                0 => None,
+                s => Some(Duration::from_secs(s)),
+            },
+            bandwidth_limit_kbps: config.bandwidth_limit_kbps,
+            backup_path: db_dir.join("replication-backup.db"),
+            last_backup: None,
+            interrupted: FastHashSet::default(),
+        })
+    }
+
+    /// Runs `sftp` with the given batch script, failing if any command not prefixed with `-`
+    /// fails.
+    async fn sftp(&self, script: &str) -> Result<(), Error> {
+        let mut cmd = tokio::process::Command::new("sftp");
+        cmd.arg("-b").arg("-").arg("-o").arg("BatchMode=yes");
+        if let Some(l) = self.bandwidth_limit_kbps {
+            cmd.arg("-l").arg(l.to_string());
+        }
+        if let Some(p) = self.port {
+            cmd.arg("-P").arg(p.to_string());
+        }
+        if let Some(ref i) = self.identity_file {
+            cmd.arg("-i").arg(i);
+        }
+        cmd.arg(&self.destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| err!(e, msg("unable to run sftp")))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin
+            .write_all(script.as_bytes())
+            .await
+            .map_err(|e| err!(e, msg("unable to write sftp batch script")))?;
+        drop(stdin);
+        let out = child
+            .wait_with_output()
+            .await
+            .map_err(|e| err!(e, msg("unable to wait for sftp")))?;
+        if !out.status.success() {
+            bail!(
+                Unavailable,
+                msg(
+                    "sftp to {} failed with {}: {}",
+                    self.destination,
+                    out.status,
+                    String::from_utf8_lossy(&out.stderr).trim(),
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Copies all recordings not yet replicated, then a database backup if one is due.
+    async fn pass<C: Clocks + Clone>(&mut self, db: &db::Database<C>) -> Result<(), Error> {
+        let mut after = None;
+        let mut copied = 0;
+        loop {
+            let ids = db.lock().list_recordings_to_replicate(after, BATCH_LEN)?;
+            let Some(&last) = ids.last() else {
+                break;
+            };
+            after = Some(last);
+            for id in ids {
+                let Some(c) = self.lookup(db, id)? else {
+                    continue;
+                };
+                let resume = self.interrupted.remove(&id);
+                let script = copy_script(&c.remote_dirs, &c.local_path, &c.remote_path, resume)?;
+                if let Err(e) = self.sftp(&script).await {
+                    // The recording may have been deleted meanwhile; if so, move on.
+                    if self.lookup(db, id)?.is_none() {
+                        continue;
+                    }
+
+                    // Resume next time, unless this was a resumption attempt, which may have
+                    // failed because there was no partial file to resume.
+                    if !resume {
+                        self.interrupted.insert(id);
+                    }
+                    return Err(e);
+                }
+                db.lock().insert_replication(
+                    id,
+                    &c.remote_path,
+                    recording::Time::new(db.clocks().realtime()),
+                )?;
+                copied += 1;
+            }
+        }
+        if copied > 0 {
+            info!("replicated {copied} recordings to {}", self.destination);
+        }
+
+        if let Some(i) = self.backup_interval {
+            if self.last_backup.map_or(true, |l| l.elapsed() >= i) {
+                self.backup(db).await?;
+                self.last_backup = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of a recording to copy, or `None` if it has been deleted.
+    fn lookup<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        id: CompositeId,
+    ) -> Result<Option<ToCopy>, Error> {
+        let l = db.lock();
+        let mut row = None;
+        l.list_recordings_by_id(id.stream(), id.recording()..id.recording() + 1, &mut |r| {
+            row = Some(r);
+            Ok(())
+        })?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let stream = l
+            .streams_by_id()
+            .get(&id.stream())
+            .ok_or_else(|| err!(Internal, msg("no stream for recording {id}")))?;
+        let camera = l
+            .cameras_by_id()
+            .get(&stream.camera_id)
+            .ok_or_else(|| err!(Internal, msg("no camera for recording {id}")))?;
+        let dir_id = row
+            .sample_file_dir_id
+            .or(stream.sample_file_dir_id)
+            .ok_or_else(|| err!(Internal, msg("no sample file dir for recording {id}")))?;
+        let dir = l
+            .sample_file_dirs_by_id()
+            .get(&dir_id)
+            .ok_or_else(|| err!(Internal, msg("no dir {dir_id}")))?;
+        let camera_dir = format!("{}/{}", self.remote_dir, camera.uuid);
+        let stream_dir = format!("{camera_dir}/{}", stream.type_.as_str());
+        Ok(Some(ToCopy {
+            local_path: dir.path.join(format!("{:016x}", id.0)),
+            remote_path: format!("{stream_dir}/{:016x}", id.0),
+            remote_dirs: [camera_dir, stream_dir],
+        }))
+    }
+
+    /// Writes a database backup and copies it to `<remote dir>/db/`.
+    async fn backup<C: Clocks + Clone>(&self, db: &db::Database<C>) -> Result<(), Error> {
+        match std::fs::remove_file(&self.backup_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!(
+                e,
+                msg("unable to remove stale {}", self.backup_path.display())
+            ),
+        }
+        db.lock().backup_to(&self.backup_path)?;
+        let remote_dir = format!("{}/db", self.remote_dir);
+        let remote_path = format!(
+            "{remote_dir}/moonfire-nvr-{}.db",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        let script = copy_script(
+            std::slice::from_ref(&remote_dir),
+            &self.backup_path,
+            &remote_path,
+            false,
+        )?;
+        let result = self.sftp(&script).await;
+        if let Err(err) = std::fs::remove_file(&self.backup_path) {
+            warn!(%err, "unable to remove {}", self.backup_path.display());
+        }
+        result?;
+        info!(
+            "copied database backup to {}:{remote_path}",
+            self.destination
+        );
+        Ok(())
+    }
+}
+
+/// Quotes an argument for an `sftp` batch script, escaping glob characters so it's taken
+/// literally.
+fn quote(arg: &str) -> String {
+    let mut out = String::with_capacity(arg.len() + 2);
+    out.push('"');
+    for c in arg.chars() {
+        if matches!(c, '"' | '\\' | '*' | '?' | '[' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+/// Returns an `sftp` batch script which creates `dirs` (ignoring failures, as they may exist)
+/// and copies `local` to `remote` via a `.part` file, resuming an earlier partial copy if
+/// `resume`.
+fn copy_script(dirs: &[String], local: &Path, remote: &str, resume: bool) -> Result<String, Error> {
+    let local = local
+        .to_str()
+        .ok_or_else(|| err!(InvalidArgument, msg("non-UTF-8 path {}", local.display())))?;
+    let part = format!("{remote}.part");
+    let mut script = String::new();
+    for d in dirs {
+        writeln!(&mut script, "-mkdir {}", quote(d)).unwrap();
+    }
+    writeln!(
+        &mut script,
+        "{} {} {}",
+        if resume { "reput" } else { "put" },
+        quote(local),
+        quote(&part)
+    )
+    .unwrap();
+    writeln!(&mut script, "-rm {}", quote(remote)).unwrap();
+    writeln!(&mut script, "rename {} {}", quote(&part), quote(remote)).unwrap();
+    Ok(script)
+}
+
+/// Runs the replicator until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    mut replicator: Replicator,
+) {
+    info!(
+        "starting replication to {} every {:?}",
+        replicator.destination, replicator.interval
+    );
+    loop {
+        tokio::select! {
+            r = replicator.pass(&db) => {
+                if let Err(err) = r {
+                    warn!(%err, "replication failed");
+                }
+            }
+            _ = shutdown_rx.as_future() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(replicator.interval) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use std::path::Path;
+
+    #[test]
+    fn copy_script() {
+        testutil::init();
+        assert_eq!(
+            super::copy_script(
+                &["/backup/cam".to_owned(), "/backup/cam/main".to_owned()],
+                Path::new("/media/my \"nvr\"/0000000100000002"),
+                "/backup/cam/main/0000000100000002",
+                false,
+            )
+            .unwrap(),
+            "-mkdir \"/backup/cam\"\n\
+             -mkdir \"/backup/cam/main\"\n\
+             put \"/media/my \\\"nvr\\\"/0000000100000002\" \
+             \"/backup/cam/main/0000000100000002.part\"\n\
+             -rm \"/backup/cam/main/0000000100000002\"\n\
+             rename \"/backup/cam/main/0000000100000002.part\" \
+             \"/backup/cam/main/0000000100000002\"\n"
+        );
+        let resumed = super::copy_script(&[], Path::new("/a*"), "/b", true).unwrap();
+        assert_eq!(
+            resumed,
+            "reput \"/a\\*\" \"/b.part\"\n-rm \"/b\"\nrename \"/b.part\" \"/b\"\n"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! AMF0 (Action Message Format) encoding of RTMP commands.
+//!
+//! Only the types which appear in publishing clients' commands and metadata are supported.
+
+use base::{bail, err, Error};
+
+const NUMBER: u8 = 0x00;
+const BOOLEAN: u8 = 0x01;
+const STRING: u8 = 0x02;
+const OBJECT: u8 = 0x03;
+const NULL: u8 = 0x05;
+const UNDEFINED: u8 = 0x06;
+const ECMA_ARRAY: u8 = 0x08;
+const OBJECT_END: u8 = 0x09;
+const STRICT_ARRAY: u8 = 0x0a;
+const DATE: u8 = 0x0b;
+const LONG_STRING: u8 = 0x0c;
+
+/// The deepest nesting of objects and arrays accepted.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Value {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+
+    /// An anonymous object or ECMA array, as properties in order.
+    Object(Vec<(String, Value)>),
+    Null,
+    Undefined,
+    Array(Vec<Value>),
+    Date(f64),
+}
+
+impl Value {
+    pub(super) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the given property of an object.
+    pub(super) fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Object(props) => props.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes all the values in `data`.
+pub(super) fn decode_all(mut data: &[u8]) -> Result<Vec<Value>, Error> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        values.push(decode(&mut data, 0)?);
+    }
+    Ok(values)
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if data.len() < n {
+        bail!(InvalidArgument, msg("truncated AMF0 value"));
+    }
+    let (taken, rest) = data.split_at(n);
+    *data = rest;
+    Ok(taken)
+}
+
+fn decode_u16(data: &mut &[u8]) -> Result<u16, Error> {
+    Ok(u16::from_be_bytes(take(data, 2)?.try_into().unwrap()))
+}
+
+fn decode_u32(data: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_be_bytes(take(data, 4)?.try_into().unwrap()))
+}
+
+fn decode_f64(data: &mut &[u8]) -> Result<f64, Error> {
+    Ok(f64::from_be_bytes(take(data, 8)?.try_into().unwrap()))
+}
+
+fn decode_string(data: &mut &[u8], len: usize) -> Result<String, Error> {
+    String::from_utf8(take(data, len)?.to_vec())
+        .map_err(|_| err!(InvalidArgument, msg("AMF0 string isn't UTF-8")))
+}
+
+/// Decodes the properties of an object or ECMA array, through the object end marker.
+fn decode_properties(data: &mut &[u8], depth: usize) -> Result<Vec<(String, Value)>, Error> {
+    let mut props = Vec::new();
+    loop {
+        let len = usize::from(decode_u16(data)?);
+        if len == 0 && data.first() == Some(&OBJECT_END) {
+            *data = &data[1..];
+            return Ok(props);
+        }
+        let name = decode_string(data, len)?;
+        props.push((name, decode(data, depth + 1)?));
+    }
+}
+
+fn decode(data: &mut &[u8], depth: usize) -> Result<Value, Error> {
+    if depth > MAX_DEPTH {
+        bail!(InvalidArgument, msg("AMF0 value nested too deeply"));
+    }
+    let marker = take(data, 1)?[0];
+    Ok(match marker {
+        NUMBER => Value::Number(decode_f64(data)?),
+        BOOLEAN => Value::Boolean(take(data, 1)?[0] != 0),
+        STRING => {
+            let len = usize::from(decode_u16(data)?);
+            Value::String(decode_string(data, len)?)
+        }
+        LONG_STRING => {
+            let len = decode_u32(data)? as usize;
+            Value::String(decode_string(data, len)?)
+        }
+        OBJECT => Value::Object(decode_properties(data, depth)?),
+        ECMA_ARRAY => {
+            decode_u32(data)?; // approximate count; the end marker is authoritative.
+            Value::Object(decode_properties(data, depth)?)
+        }
+        STRICT_ARRAY => {
+            let count = decode_u32(data)? as usize;
+            if count > data.len() {
+                bail!(InvalidArgument, msg("truncated AMF0 array"));
+            }
+            let mut values = Vec::with_capacity(count);
+            for _ in 0..count {
+                values.push(decode(data, depth + 1)?);
+            }
+            Value::Array(values)
+        }
+        DATE => {
+            let d = decode_f64(data)?;
+            take(data, 2)?; // time zone, which is reserved.
+            Value::Date(d)
+        }
+        NULL => Value::Null,
+        UNDEFINED => Value::Undefined,
+        m => bail!(Unimplemented, msg("unsupported AMF0 marker {m:#04x}")),
+    })
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    let len = u16::try_from(s.len()).expect("AMF0 strings written are short");
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Appends the encoding of `value` to `out`.
+pub(super) fn encode(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Number(n) => {
+            out.push(NUMBER);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        Value::Boolean(b) => out.extend_from_slice(&[BOOLEAN, u8::from(*b)]),
+        Value::String(s) => {
+            out.push(STRING);
+            encode_str(s, out);
+        }
+        Value::Object(props) => {
+            out.push(OBJECT);
+            for (name, value) in props {
+                encode_str(name, out);
+                encode(value, out);
+            }
+            out.extend_from_slice(&[0, 0, OBJECT_END]);
+        }
+        Value::Null => out.push(NULL),
+        Value::Undefined => out.push(UNDEFINED),
+        Value::Array(values) => {
+            out.push(STRICT_ARRAY);
+            out.extend_from_slice(&(values.len() as u32).to_be_bytes());
+            for v in values {
+                encode(v, out);
+            }
+        }
+        Value::Date(d) => {
+            out.push(DATE);
+            out.extend_from_slice(&d.to_be_bytes());
+            out.extend_from_slice(&[0, 0]);
+        }
+    }
+}
+
+/// Returns an object with the given properties.
+pub(super) fn object(props: &[(&str, Value)]) -> Value {
+    Value::Object(
+        props
+            .iter()
+            .map(|(n, v)| ((*n).to_owned(), v.clone()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let values = [
+            Value::String("connect".to_owned()),
+            Value::Number(1.0),
+            object(&[
+                ("app", Value::String("live".to_owned())),
+                ("fpad", Value::Boolean(false)),
+                ("nested", object(&[("x", Value::Null)])),
+            ]),
+            Value::Null,
+            Value::Undefined,
+            Value::Array(vec![Value::Number(2.0), Value::Date(3.0)]),
+        ];
+        let mut out = Vec::new();
+        for v in &values {
+            encode(v, &mut out);
+        }
+        assert_eq!(decode_all(&out).unwrap(), values);
+        decode_all(&out[..out.len() - 1]).unwrap_err();
+    }
+
+    #[test]
+    fn ecma_array() {
+        testutil::init();
+        // As in ffmpeg's `@setDataFrame` metadata: count, one property, and the end marker.
+        let data =
+            b"\x08\x00\x00\x00\x01\x00\x05width\x00\x40\x94\x00\x00\x00\x00\x00\x00\x00\x00\x09";
+        let values = decode_all(data).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(
+            values[0].get("width").and_then(Value::as_number),
+            Some(1280.0)
+        );
+    }
+
+    #[test]
+    fn too_deep() {
+        testutil::init();
+        let data = [OBJECT, 0, 1, b'x'].repeat(MAX_DEPTH + 2);
+        decode_all(&data).unwrap_err();
+    }
+}
@@ -0,0 +1,401 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! The RTMP handshake and chunk stream, as in sections 5.2 and 5.3 of the [RTMP
+//! specification](https://rtmp.veriskope.com/docs/spec/).
+
+use std::collections::HashMap;
+
+use base::{bail, err, Error};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The length of C1/S1 and C2/S2.
+const HANDSHAKE_LEN: usize = 1536;
+
+/// The chunk size in effect before either side sends "Set Chunk Size".
+pub(super) const DEFAULT_CHUNK_SIZE: usize = 128;
+
+/// The largest chunk size accepted from the peer.
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// The longest message accepted from the peer.
+const MAX_MESSAGE_LEN: usize = 16 << 20;
+
+/// The most chunk streams a peer may interleave.
+const MAX_CHUNK_STREAMS: usize = 64;
+
+pub(super) const TYPE_SET_CHUNK_SIZE: u8 = 1;
+pub(super) const TYPE_ABORT: u8 = 2;
+pub(super) const TYPE_ACKNOWLEDGEMENT: u8 = 3;
+pub(super) const TYPE_USER_CONTROL: u8 = 4;
+pub(super) const TYPE_WINDOW_ACK_SIZE: u8 = 5;
+pub(super) const TYPE_SET_PEER_BANDWIDTH: u8 = 6;
+pub(super) const TYPE_AUDIO: u8 = 8;
+pub(super) const TYPE_VIDEO: u8 = 9;
+pub(super) const TYPE_DATA_AMF3: u8 = 15;
+pub(super) const TYPE_COMMAND_AMF3: u8 = 17;
+pub(super) const TYPE_DATA_AMF0: u8 = 18;
+pub(super) const TYPE_COMMAND_AMF0: u8 = 20;
+
+/// Performs the server side of the (unsigned) handshake.
+pub(super) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(), Error> {
+    let mut c0c1 = vec![0; 1 + HANDSHAKE_LEN];
+    stream
+        .read_exact(&mut c0c1)
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to read C0+C1"), source(e)))?;
+    if c0c1[0] != 3 {
+        bail!(InvalidArgument, msg("unsupported RTMP version {}", c0c1[0]));
+    }
+
+    // S0, then S1 with zero time and random bytes, then S2 echoing C1.
+    let mut out = Vec::with_capacity(1 + 2 * HANDSHAKE_LEN);
+    out.push(3);
+    out.extend_from_slice(&[0; 8]);
+    let mut random = [0; HANDSHAKE_LEN - 8];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random)
+        .map_err(|_| err!(Internal, msg("unable to generate S1")))?;
+    out.extend_from_slice(&random);
+    out.extend_from_slice(&c0c1[1..]);
+    stream
+        .write_all(&out)
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to write S0+S1+S2"), source(e)))?;
+
+    let mut c2 = vec![0; HANDSHAKE_LEN];
+    stream
+        .read_exact(&mut c2)
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to read C2"), source(e)))?;
+    Ok(())
+}
+
+/// A complete message, reassembled from chunks.
+#[derive(Debug, PartialEq)]
+pub(super) struct Message {
+    /// The timestamp in milliseconds, which may wrap.
+    pub(super) timestamp: u32,
+    pub(super) type_id: u8,
+    pub(super) stream_id: u32,
+    pub(super) payload: Bytes,
+}
+
+/// The state of one chunk stream.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    timestamp_delta: u32,
+    length: usize,
+    type_id: u8,
+    stream_id: u32,
+
+    /// If the last header had an extended timestamp, which type 3 headers then repeat.
+    extended: bool,
+
+    /// The payload of the message in progress.
+    partial: BytesMut,
+}
+
+/// Reassembles messages from chunks.
+pub(super) struct Reader {
+    buf: BytesMut,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+}
+
+impl Reader {
+    pub(super) fn new() -> Self {
+        Reader {
+            buf: BytesMut::with_capacity(4096),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+        }
+    }
+
+    pub(super) fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Handles the protocol control messages which affect reading.
+    fn handle_control(&mut self, m: &Message) -> Result<(), Error> {
+        match m.type_id {
+            TYPE_SET_CHUNK_SIZE => {
+                let Some(size) = m.payload.get(0..4) else {
+                    bail!(InvalidArgument, msg("short Set Chunk Size message"));
+                };
+                let size = (u32::from_be_bytes(size.try_into().unwrap()) & 0x7fff_ffff) as usize;
+                if size == 0 || size > MAX_CHUNK_SIZE {
+                    bail!(OutOfRange, msg("bad chunk size {size}"));
+                }
+                self.chunk_size = size;
+            }
+            TYPE_ABORT => {
+                if let Some(csid) = m.payload.get(0..4) {
+                    let csid = u32::from_be_bytes(csid.try_into().unwrap());
+                    if let Some(s) = self.streams.get_mut(&csid) {
+                        s.partial.clear();
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the next complete message from the buffered data, or `None` if more is needed.
+    pub(super) fn next(&mut self) -> Result<Option<Message>, Error> {
+        loop {
+            let Some((m, consumed)) = self.parse_chunk()? else {
+                return Ok(None);
+            };
+            self.buf.advance(consumed);
+            if let Some(m) = m {
+                self.handle_control(&m)?;
+                return Ok(Some(m));
+            }
+        }
+    }
+
+    /// Parses a chunk from the start of the buffer without consuming it, returning the message
+    /// it completes (if any) and the chunk's length.
+    fn parse_chunk(&mut self) -> Result<Option<(Option<Message>, usize)>, Error> {
+        let b = &self.buf[..];
+        let Some(&first) = b.first() else {
+            return Ok(None);
+        };
+        let fmt = first >> 6;
+        let (csid, mut pos) = match first & 0x3f {
+            0 => match b.get(1) {
+                Some(&b1) => (64 + u32::from(b1), 2),
+                None => return Ok(None),
+            },
+            1 => match b.get(1..3) {
+                Some(&[b1, b2]) => (64 + u32::from(b1) + 256 * u32::from(b2), 3),
+                _ => return Ok(None),
+            },
+            c => (u32::from(c), 1),
+        };
+        let header_len = [11, 7, 3, 0][usize::from(fmt)];
+        let Some(header) = b.get(pos..pos + header_len) else {
+            return Ok(None);
+        };
+        pos += header_len;
+        if !self.streams.contains_key(&csid) {
+            if fmt != 0 {
+                bail!(
+                    InvalidArgument,
+                    msg("chunk stream {csid} starts with type {fmt} header")
+                );
+            }
+            if self.streams.len() >= MAX_CHUNK_STREAMS {
+                bail!(OutOfRange, msg("too many chunk streams"));
+            }
+        }
+        let prev = self.streams.get(&csid);
+        let u24 = |b: &[u8]| u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        let (ts_field, extended) = if fmt == 3 {
+            (0, prev.map_or(false, |s| s.extended))
+        } else {
+            let t = u24(&header[0..3]);
+            (t, t == 0xff_ffff)
+        };
+        let ts_field = if extended {
+            let Some(ext) = b.get(pos..pos + 4) else {
+                return Ok(None);
+            };
+            pos += 4;
+            u32::from_be_bytes(ext.try_into().unwrap())
+        } else {
+            ts_field
+        };
+        let mut s = match prev {
+            Some(s) => ChunkStream {
+                partial: BytesMut::new(),
+                ..*s
+            },
+            None => ChunkStream::default(),
+        };
+        let starting = prev.map_or(true, |s| s.partial.is_empty());
+        s.extended = extended;
+        match fmt {
+            0 => {
+                s.timestamp = ts_field;
+                s.timestamp_delta = 0;
+            }
+            1 | 2 => {
+                s.timestamp_delta = ts_field;
+                s.timestamp = s.timestamp.wrapping_add(ts_field);
+            }
+            _ if starting => s.timestamp = s.timestamp.wrapping_add(s.timestamp_delta),
+            _ => {}
+        }
+        if fmt <= 1 {
+            s.length = u24(&header[3..6]) as usize;
+            s.type_id = header[6];
+            if s.length > MAX_MESSAGE_LEN {
+                bail!(OutOfRange, msg("message length {} too large", s.length));
+            }
+        }
+        if fmt == 0 {
+            s.stream_id = u32::from_le_bytes(header[7..11].try_into().unwrap());
+        }
+        if !starting && fmt != 3 {
+            bail!(
+                InvalidArgument,
+                msg("chunk stream {csid} has a new message header mid-message")
+            );
+        }
+        let already = prev.map_or(0, |s| s.partial.len());
+        let n = std::cmp::min(self.chunk_size, s.length - already);
+        let Some(data) = b.get(pos..pos + n) else {
+            return Ok(None);
+        };
+        pos += n;
+
+        // The chunk is complete; commit the new state.
+        let data = Bytes::copy_from_slice(data);
+        let entry = self.streams.entry(csid).or_default();
+        s.partial = std::mem::take(&mut entry.partial);
+        s.partial.extend_from_slice(&data);
+        let done = s.partial.len() == s.length;
+        let m = done.then(|| Message {
+            timestamp: s.timestamp,
+            type_id: s.type_id,
+            stream_id: s.stream_id,
+            payload: std::mem::take(&mut s.partial).freeze(),
+        });
+        *entry = s;
+        Ok(Some((m, pos)))
+    }
+}
+
+/// Encodes messages into chunks.
+pub(super) struct Writer {
+    chunk_size: usize,
+}
+
+impl Writer {
+    pub(super) fn new() -> Self {
+        Writer {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Sets the chunk size of subsequent messages; the caller must send "Set Chunk Size" first.
+    pub(super) fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size;
+    }
+
+    /// Appends `m` to `out` on the given chunk stream, with a type 0 header and type 3 headers
+    /// on any continuation chunks.
+    pub(super) fn encode(&self, csid: u8, m: &Message, out: &mut Vec<u8>) {
+        assert!((2..64).contains(&csid));
+        let len = u32::try_from(m.payload.len()).expect("messages written are short");
+        assert!(len < 1 << 24);
+        let extended = m.timestamp >= 0xff_ffff;
+        out.push(csid);
+        out.extend_from_slice(&std::cmp::min(m.timestamp, 0xff_ffff).to_be_bytes()[1..]);
+        out.extend_from_slice(&len.to_be_bytes()[1..]);
+        out.push(m.type_id);
+        out.extend_from_slice(&m.stream_id.to_le_bytes());
+        for (i, chunk) in m.payload.chunks(self.chunk_size).enumerate() {
+            if i > 0 {
+                out.push(0xc0 | csid);
+            }
+            if extended {
+                out.extend_from_slice(&m.timestamp.to_be_bytes());
+            }
+            out.extend_from_slice(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    fn message(timestamp: u32, type_id: u8, len: usize) -> Message {
+        Message {
+            timestamp,
+            type_id,
+            stream_id: 1,
+            payload: (0..len).map(|i| i as u8).collect::<Vec<_>>().into(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let mut w = Writer::new();
+        let mut out = Vec::new();
+        let messages = [
+            message(0, TYPE_VIDEO, 300),
+            message(40, TYPE_AUDIO, 10),
+            message(0x0100_0000, TYPE_VIDEO, 200), // extended timestamp.
+        ];
+        w.encode(6, &messages[0], &mut out);
+        w.encode(4, &messages[1], &mut out);
+
+        // Change chunk sizes mid-stream, as the peer would.
+        let set_chunk_size = Message {
+            timestamp: 0,
+            type_id: TYPE_SET_CHUNK_SIZE,
+            stream_id: 0,
+            payload: Bytes::from_static(b"\x00\x00\x00\x40"),
+        };
+        w.encode(2, &set_chunk_size, &mut out);
+        w.set_chunk_size(64);
+        w.encode(6, &messages[2], &mut out);
+
+        // Feed a byte at a time to exercise partial chunks.
+        let mut r = Reader::new();
+        let mut got = Vec::new();
+        for &b in &out {
+            r.buf_mut().extend_from_slice(&[b]);
+            while let Some(m) = r.next().unwrap() {
+                got.push(m);
+            }
+        }
+        assert_eq!(got.len(), 4);
+        assert_eq!(got[0], messages[0]);
+        assert_eq!(got[1], messages[1]);
+        assert_eq!(got[3], messages[2]);
+    }
+
+    #[test]
+    fn compressed_headers() {
+        testutil::init();
+        // A type 0 header, then type 2 (delta only) and type 3 (repeat delta) headers, as
+        // publishers use for evenly-spaced audio.
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x04\x00\x00\x0a\x00\x00\x02\x08\x01\x00\x00\x00ab");
+        data.extend_from_slice(b"\x84\x00\x00\x15cd");
+        data.extend_from_slice(b"\xc4ef");
+        let mut r = Reader::new();
+        r.buf_mut().extend_from_slice(&data);
+        let timestamps: Vec<_> = std::iter::from_fn(|| r.next().unwrap())
+            .map(|m| (m.timestamp, m.payload))
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                (10, Bytes::from_static(b"ab")),
+                (31, Bytes::from_static(b"cd")),
+                (52, Bytes::from_static(b"ef")),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_header_must_be_full() {
+        testutil::init();
+        let mut r = Reader::new();
+        r.buf_mut().extend_from_slice(b"\xc4ab");
+        r.next().unwrap_err();
+    }
+}
@@ -0,0 +1,130 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! The FLV `VIDEODATA` and `AUDIODATA` payloads of RTMP video and audio messages, as in
+//! section E.4.3 of the [FLV specification](https://veovera.org/docs/legacy/video-file-format-v10-1-spec.pdf).
+
+use base::{bail, Error};
+use bytes::Bytes;
+
+/// `CodecID` of AVC (H.264) video.
+const CODEC_AVC: u8 = 7;
+
+/// `SoundFormat` of AAC audio.
+const SOUND_FORMAT_AAC: u8 = 10;
+
+#[derive(Debug, PartialEq)]
+pub(super) enum Tag {
+    /// An `AVCDecoderConfigurationRecord`.
+    VideoConfig(Bytes),
+
+    /// One or more length-prefixed NAL units making up an access unit.
+    Video {
+        is_key: bool,
+
+        /// The difference between presentation and decode time, in milliseconds.
+        composition_time: i32,
+        data: Bytes,
+    },
+
+    /// An `AudioSpecificConfig`.
+    AudioConfig(Bytes),
+
+    /// A raw AAC frame.
+    Audio(Bytes),
+
+    /// Anything else which is valid but uninteresting, such as an end of sequence marker.
+    Ignored,
+}
+
+/// Parses a video message's payload.
+pub(super) fn parse_video(mut payload: Bytes) -> Result<Tag, Error> {
+    let Some(&[first, packet_type, c0, c1, c2]) = payload.get(0..5) else {
+        bail!(InvalidArgument, msg("short video message"));
+    };
+    if first & 0x80 != 0 {
+        bail!(
+            Unimplemented,
+            msg("enhanced RTMP video (such as H.265) is unsupported")
+        );
+    }
+    let (frame_type, codec) = (first >> 4, first & 0x0f);
+    if codec != CODEC_AVC {
+        bail!(
+            Unimplemented,
+            msg("unsupported video codec id {codec}; only H.264 is supported")
+        );
+    }
+    let data = payload.split_off(5);
+    Ok(match (frame_type, packet_type) {
+        (5, _) => Tag::Ignored, // video info/command frame.
+        (_, 0) => Tag::VideoConfig(data),
+        (_, 1) => Tag::Video {
+            is_key: frame_type == 1,
+            composition_time: i32::from_be_bytes([c0, c1, c2, 0]) >> 8,
+            data,
+        },
+        _ => Tag::Ignored,
+    })
+}
+
+/// Parses an audio message's payload. Returns `Ignored` for codecs other than AAC.
+pub(super) fn parse_audio(mut payload: Bytes) -> Result<Tag, Error> {
+    let Some(&first) = payload.first() else {
+        return Ok(Tag::Ignored); // an empty message, as some publishers send to start.
+    };
+    if first >> 4 != SOUND_FORMAT_AAC {
+        return Ok(Tag::Ignored);
+    }
+    let Some(&packet_type) = payload.get(1) else {
+        bail!(InvalidArgument, msg("short AAC audio message"));
+    };
+    let data = payload.split_off(2);
+    Ok(match packet_type {
+        0 => Tag::AudioConfig(data),
+        _ => Tag::Audio(data),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn video() {
+        testutil::init();
+        assert_eq!(
+            parse_video(Bytes::from_static(b"\x17\x00\x00\x00\x00config")).unwrap(),
+            Tag::VideoConfig(Bytes::from_static(b"config"))
+        );
+        assert_eq!(
+            parse_video(Bytes::from_static(b"\x27\x01\xff\xff\xd8nalus")).unwrap(),
+            Tag::Video {
+                is_key: false,
+                composition_time: -40,
+                data: Bytes::from_static(b"nalus"),
+            }
+        );
+        parse_video(Bytes::from_static(b"\x12\x01\x00\x00\x00")).unwrap_err(); // H.263
+        parse_video(Bytes::from_static(b"\x17\x01")).unwrap_err();
+    }
+
+    #[test]
+    fn audio() {
+        testutil::init();
+        assert_eq!(
+            parse_audio(Bytes::from_static(b"\xaf\x00\x12\x10")).unwrap(),
+            Tag::AudioConfig(Bytes::from_static(b"\x12\x10"))
+        );
+        assert_eq!(
+            parse_audio(Bytes::from_static(b"\xaf\x01frame")).unwrap(),
+            Tag::Audio(Bytes::from_static(b"frame"))
+        );
+        assert_eq!(
+            parse_audio(Bytes::from_static(b"\x2f\x01mp3")).unwrap(),
+            Tag::Ignored
+        );
+    }
+}
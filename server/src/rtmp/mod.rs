@@ -0,0 +1,769 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTMP ingest, as configured by [`RtmpConfig`].
+//!
+//! Devices which push RTMP rather than serving RTSP (some doorbells, drones, OBS) publish to
+//! `rtmp://<host>:1935/<app>/<name>`. A stream whose URL is `rtmp://<anything>/<app>/<name>`
+//! records that publisher: [`open`] waits for it to publish, then converts its FLV-tagged H.264
+//! and AAC into [`crate::stream::Stream`] frames for the usual writer pipeline. Only publishing
+//! to the path of a recording stream is allowed, so the path acts as a stream key.
+//!
+//! This is a deliberately small RTMP server. It supports only publishing, with the unsigned
+//! handshake that FFmpeg, OBS, and most devices accept, and not playback.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{bail, err, Error};
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn, Instrument};
+use url::Url;
+
+use self::amf::Value;
+use crate::cmds::run::config::RtmpConfig;
+use crate::stream::{AudioFrame, Options, Stream, VideoFrame};
+
+mod amf;
+mod chunk;
+mod flv;
+
+/// How long a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection may go without receiving anything before it's closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a publisher may wait for its stream to take its frames before it's disconnected.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of messages buffered between a publisher and its stream.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// The chunk size of messages sent to clients.
+const CHUNK_SIZE: usize = 4096;
+
+/// The acknowledgement window and peer bandwidth advertised to clients.
+const WINDOW_SIZE: u32 = 2_500_000;
+
+/// The id of the single message stream created for publishing.
+const STREAM_ID: u32 = 1;
+
+/// The chunk stream ids of messages sent to clients.
+const CSID_CONTROL: u8 = 2;
+const CSID_COMMAND: u8 = 3;
+
+/// The duration of each AAC frame, in samples.
+const AAC_FRAME_LENGTH: i32 = 1024;
+
+/// A media message from a publisher.
+struct Item {
+    /// The decode timestamp in milliseconds, which may wrap.
+    timestamp: u32,
+    tag: flv::Tag,
+}
+
+struct Publisher {
+    id: u64,
+    rx: mpsc::Receiver<Item>,
+}
+
+/// Publishers not yet taken by [`open`], keyed by path.
+struct Registry {
+    publishers: std::sync::Mutex<HashMap<String, Publisher>>,
+    published: tokio::sync::Notify,
+    next_id: AtomicU64,
+}
+
+/// The registry, present iff RTMP ingest is enabled in this process.
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Removes a publisher from the registry when its connection ends, if not taken already.
+struct Registration {
+    path: String,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let registry = REGISTRY
+            .get()
+            .expect("registered publishers imply a registry");
+        let mut l = registry.publishers.lock().unwrap();
+        if l.get(&self.path).map_or(false, |p| p.id == self.id) {
+            l.remove(&self.path);
+        }
+    }
+}
+
+/// Returns the path which publishers to the given stream URL use: `<app>/<name>`.
+fn stream_path(url: &Url) -> String {
+    percent_decode_str(url.path().trim_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    pub fn new(config: &RtmpConfig) -> Result<Self, Error> {
+        let listener = std::net::TcpListener::bind(config.address)
+            .map_err(|e| err!(e, msg("unable to bind RTMP socket {}", config.address)))?;
+        listener.set_nonblocking(true)?;
+        REGISTRY.get_or_init(|| Registry {
+            publishers: std::sync::Mutex::new(HashMap::new()),
+            published: tokio::sync::Notify::new(),
+            next_id: AtomicU64::new(0),
+        });
+        Ok(Server {
+            listener: TcpListener::from_std(listener)?,
+        })
+    }
+}
+
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    server: Server,
+) {
+    if let Ok(addr) = server.listener.local_addr() {
+        info!("accepting RTMP on {addr}");
+    }
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown_rx.as_future() => break,
+            r = server.listener.accept() => match r {
+                Ok(a) => a,
+                Err(err) => {
+                    warn!(%err, "unable to accept RTMP connection");
+                    continue;
+                }
+            },
+        };
+        let conn = Connection {
+            db: db.clone(),
+            reader: chunk::Reader::new(),
+            writer: chunk::Writer::new(),
+            out: Vec::new(),
+            app: None,
+            publishing: None,
+            window_ack_size: 0,
+            received: 0,
+            acked: 0,
+        };
+        let shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = conn.run(stream, shutdown_rx).await {
+                    debug!(err = %err.chain(), "RTMP connection failed");
+                }
+            }
+            .instrument(tracing::info_span!("rtmp", %peer)),
+        );
+    }
+}
+
+struct Publishing {
+    tx: mpsc::Sender<Item>,
+    _registration: Registration,
+}
+
+struct Connection<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    reader: chunk::Reader,
+    writer: chunk::Writer,
+
+    /// Messages to send after handling the current one.
+    out: Vec<u8>,
+
+    /// The application name supplied with `connect`.
+    app: Option<String>,
+    publishing: Option<Publishing>,
+
+    /// The peer's acknowledgement window, or 0 if it hasn't set one.
+    window_ack_size: u32,
+    received: u64,
+    acked: u64,
+}
+
+/// Whether to keep handling a connection after a message.
+enum Flow {
+    Continue,
+    Stop,
+}
+
+impl<C: Clocks + Clone> Connection<C> {
+    async fn run(
+        mut self,
+        mut stream: TcpStream,
+        shutdown_rx: base::shutdown::Receiver,
+    ) -> Result<(), Error> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, chunk::handshake(&mut stream))
+            .await
+            .map_err(|_| err!(DeadlineExceeded, msg("handshake timed out")))??;
+        loop {
+            let n = tokio::select! {
+                _ = shutdown_rx.as_future() => return Ok(()),
+                r = tokio::time::timeout(IDLE_TIMEOUT, stream.read_buf(self.reader.buf_mut())) => {
+                    r.map_err(|_| err!(DeadlineExceeded, msg("idle RTMP connection")))?
+                        .map_err(|e| err!(Unavailable, msg("read failed"), source(e)))?
+                }
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            self.received += n as u64;
+            if self.window_ack_size > 0
+                && self.received - self.acked >= u64::from(self.window_ack_size)
+            {
+                self.acked = self.received;
+                self.send(
+                    CSID_CONTROL,
+                    chunk::TYPE_ACKNOWLEDGEMENT,
+                    0,
+                    (self.received as u32).to_be_bytes().to_vec(),
+                );
+            }
+            let mut flow = Flow::Continue;
+            while let Some(m) = self.reader.next()? {
+                flow = self.handle(m).await?;
+                if matches!(flow, Flow::Stop) {
+                    break;
+                }
+            }
+            if !self.out.is_empty() {
+                stream
+                    .write_all(&self.out)
+                    .await
+                    .map_err(|e| err!(Unavailable, msg("write failed"), source(e)))?;
+                self.out.clear();
+            }
+            if matches!(flow, Flow::Stop) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Queues a message to send.
+    fn send(&mut self, csid: u8, type_id: u8, stream_id: u32, payload: Vec<u8>) {
+        self.writer.encode(
+            csid,
+            &chunk::Message {
+                timestamp: 0,
+                type_id,
+                stream_id,
+                payload: payload.into(),
+            },
+            &mut self.out,
+        );
+    }
+
+    /// Queues an AMF0 command to send.
+    fn send_command(&mut self, stream_id: u32, values: &[Value]) {
+        let mut payload = Vec::new();
+        for v in values {
+            amf::encode(v, &mut payload);
+        }
+        self.send(CSID_COMMAND, chunk::TYPE_COMMAND_AMF0, stream_id, payload);
+    }
+
+    /// Queues an `onStatus` command to send on the publishing message stream.
+    fn send_status(&mut self, level: &str, code: &str, description: &str) {
+        self.send_command(
+            STREAM_ID,
+            &[
+                Value::String("onStatus".to_owned()),
+                Value::Number(0.0),
+                Value::Null,
+                amf::object(&[
+                    ("level", Value::String(level.to_owned())),
+                    ("code", Value::String(code.to_owned())),
+                    ("description", Value::String(description.to_owned())),
+                ]),
+            ],
+        );
+    }
+
+    async fn handle(&mut self, m: chunk::Message) -> Result<Flow, Error> {
+        match m.type_id {
+            chunk::TYPE_WINDOW_ACK_SIZE => {
+                if let Some(s) = m.payload.get(0..4) {
+                    self.window_ack_size = u32::from_be_bytes(s.try_into().unwrap());
+                }
+            }
+            chunk::TYPE_COMMAND_AMF0 => return self.handle_command(&m.payload),
+            chunk::TYPE_COMMAND_AMF3 => {
+                // An AMF3 command message starts with a format byte, then is AMF0-encoded.
+                return self.handle_command(m.payload.get(1..).unwrap_or_default());
+            }
+            chunk::TYPE_VIDEO | chunk::TYPE_AUDIO => {
+                let Some(p) = self.publishing.as_ref() else {
+                    bail!(FailedPrecondition, msg("media before publish"));
+                };
+                let tag = if m.type_id == chunk::TYPE_VIDEO {
+                    flv::parse_video(m.payload)?
+                } else {
+                    flv::parse_audio(m.payload)?
+                };
+                if matches!(tag, flv::Tag::Ignored) {
+                    return Ok(Flow::Continue);
+                }
+                let item = Item {
+                    timestamp: m.timestamp,
+                    tag,
+                };
+                match p.tx.send_timeout(item, SEND_TIMEOUT).await {
+                    Ok(()) => {}
+                    Err(mpsc::error::SendTimeoutError::Timeout(_)) => {
+                        bail!(DeadlineExceeded, msg("stream isn't taking frames"))
+                    }
+                    Err(mpsc::error::SendTimeoutError::Closed(_)) => {
+                        debug!("stream closed; disconnecting publisher");
+                        return Ok(Flow::Stop);
+                    }
+                }
+            }
+            chunk::TYPE_DATA_AMF0 | chunk::TYPE_DATA_AMF3 => {} // metadata, such as `onMetaData`.
+            _ => {} // acknowledgements, user control, and the like.
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn handle_command(&mut self, payload: &[u8]) -> Result<Flow, Error> {
+        let values = amf::decode_all(payload)?;
+        let name = values.first().and_then(Value::as_str).unwrap_or_default();
+        let txid = values.get(1).and_then(Value::as_number).unwrap_or(0.0);
+        debug!(name, txid, "RTMP command");
+        match name {
+            "connect" => {
+                let app = values
+                    .get(2)
+                    .and_then(|o| o.get("app"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                self.app = Some(app.trim_matches('/').to_owned());
+                self.send(
+                    CSID_CONTROL,
+                    chunk::TYPE_WINDOW_ACK_SIZE,
+                    0,
+                    WINDOW_SIZE.to_be_bytes().to_vec(),
+                );
+                let mut bandwidth = WINDOW_SIZE.to_be_bytes().to_vec();
+                bandwidth.push(2); // dynamic limit.
+                self.send(CSID_CONTROL, chunk::TYPE_SET_PEER_BANDWIDTH, 0, bandwidth);
+                self.send(
+                    CSID_CONTROL,
+                    chunk::TYPE_SET_CHUNK_SIZE,
+                    0,
+                    (CHUNK_SIZE as u32).to_be_bytes().to_vec(),
+                );
+                self.writer.set_chunk_size(CHUNK_SIZE);
+                self.send_command(
+                    0,
+                    &[
+                        Value::String("_result".to_owned()),
+                        Value::Number(txid),
+                        amf::object(&[
+                            ("fmsVer", Value::String("FMS/3,0,1,123".to_owned())),
+                            ("capabilities", Value::Number(31.0)),
+                        ]),
+                        amf::object(&[
+                            ("level", Value::String("status".to_owned())),
+                            (
+                                "code",
+                                Value::String("NetConnection.Connect.Success".to_owned()),
+                            ),
+                            (
+                                "description",
+                                Value::String("Connection succeeded.".to_owned()),
+                            ),
+                            ("objectEncoding", Value::Number(0.0)),
+                        ]),
+                    ],
+                );
+            }
+            "releaseStream" | "FCPublish" if txid != 0.0 => {
+                self.send_command(
+                    0,
+                    &[
+                        Value::String("_result".to_owned()),
+                        Value::Number(txid),
+                        Value::Null,
+                        Value::Undefined,
+                    ],
+                );
+            }
+            "createStream" => {
+                self.send_command(
+                    0,
+                    &[
+                        Value::String("_result".to_owned()),
+                        Value::Number(txid),
+                        Value::Null,
+                        Value::Number(f64::from(STREAM_ID)),
+                    ],
+                );
+            }
+            "publish" => {
+                let Some(app) = self.app.as_ref() else {
+                    bail!(FailedPrecondition, msg("publish before connect"));
+                };
+                let stream_name = values.get(3).and_then(Value::as_str).unwrap_or_default();
+                let path = format!("{app}/{stream_name}");
+                if self.publishing.is_some() {
+                    bail!(FailedPrecondition, msg("already publishing"));
+                }
+                if !self.accepts(&path) {
+                    self.send_status(
+                        "error",
+                        "NetStream.Publish.BadName",
+                        "no recording stream has this path",
+                    );
+                    warn!(%path, "rejecting RTMP publish to unknown path");
+                    return Ok(Flow::Stop);
+                }
+                self.publishing = Some(register(path.clone()));
+                info!(%path, "RTMP publishing started");
+
+                // User Control "Stream Begin".
+                let mut begin = vec![0, 0];
+                begin.extend_from_slice(&STREAM_ID.to_be_bytes());
+                self.send(CSID_CONTROL, chunk::TYPE_USER_CONTROL, 0, begin);
+                self.send_status("status", "NetStream.Publish.Start", "Publishing started.");
+            }
+            "FCUnpublish" | "deleteStream" | "closeStream" => {
+                if self.publishing.is_some() {
+                    info!("RTMP publishing ended");
+                    return Ok(Flow::Stop);
+                }
+            }
+            _ => {}
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Returns whether a recording stream has an `rtmp://` URL with the given path.
+    fn accepts(&self, path: &str) -> bool {
+        self.db.lock().streams_by_id().values().any(|s| {
+            s.config.is_recording()
+                && s.config
+                    .url
+                    .as_ref()
+                    .map_or(false, |u| u.scheme() == "rtmp" && stream_path(u) == path)
+        })
+    }
+}
+
+/// Registers a publisher to the given path, replacing any not yet taken.
+fn register(path: String) -> Publishing {
+    let registry = REGISTRY.get().expect("server implies registry");
+    let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    registry
+        .publishers
+        .lock()
+        .unwrap()
+        .insert(path.clone(), Publisher { id, rx });
+    registry.published.notify_waiters();
+    Publishing {
+        tx,
+        _registration: Registration { path, id },
+    }
+}
+
+/// Converts wrapping millisecond timestamps to a 90 kHz timeline.
+#[derive(Default)]
+struct Timeline {
+    prev: Option<u32>,
+    elapsed_ms: i64,
+}
+
+impl Timeline {
+    fn advance(&mut self, timestamp: u32) -> i64 {
+        if let Some(prev) = self.prev {
+            // Allow for small backward steps, as between interleaved audio and video.
+            self.elapsed_ms += i64::from(timestamp.wrapping_sub(prev) as i32);
+        }
+        self.prev = Some(timestamp);
+        self.elapsed_ms * 90
+    }
+}
+
+struct Audio {
+    sample_entry: db::AudioSampleEntryToInsert,
+
+    /// Frames received but not yet taken by [`Stream::take_audio_frames`].
+    pending: Vec<AudioFrame>,
+}
+
+/// The parts of an [`RtmpStream`] used from within the tokio reactor.
+struct Inner {
+    label: String,
+    rx: mpsc::Receiver<Item>,
+    timeline: Timeline,
+
+    /// The current video sample entry; `None` only until the first frame is fetched.
+    video_sample_entry: Option<db::VideoSampleEntryToInsert>,
+
+    /// True if the video sample entry has changed since the last frame returned.
+    new_video_sample_entry: bool,
+
+    want_audio: bool,
+    audio: Option<Audio>,
+}
+
+impl Inner {
+    /// Waits for a publisher to the given path, then returns its first key frame.
+    async fn start(
+        registry: &'static Registry,
+        label: String,
+        path: String,
+        want_audio: bool,
+    ) -> Result<(Box<Self>, VideoFrame), Error> {
+        let rx = loop {
+            // Create the future before checking, so a publish in between isn't missed.
+            let published = registry.published.notified();
+            let publisher = registry.publishers.lock().unwrap().remove(&path);
+            if let Some(p) = publisher {
+                break p.rx;
+            }
+            published.await;
+        };
+        debug!("{label}: RTMP publisher to {path} found");
+        let mut self_ = Box::new(Inner {
+            label,
+            rx,
+            timeline: Timeline::default(),
+            video_sample_entry: None,
+            new_video_sample_entry: false,
+            want_audio,
+            audio: None,
+        });
+        let first_frame = loop {
+            let f = self_.next_frame().await?;
+            if f.is_key {
+                break f;
+            }
+        };
+        Ok((self_, first_frame))
+    }
+
+    async fn next_frame(&mut self) -> Result<VideoFrame, Error> {
+        loop {
+            let Some(item) = self.rx.recv().await else {
+                bail!(Unavailable, msg("end of stream"));
+            };
+            let pts = self.timeline.advance(item.timestamp);
+            match item.tag {
+                flv::Tag::VideoConfig(config) => self.update_video_sample_entry(&config)?,
+                flv::Tag::Video {
+                    is_key,
+                    composition_time,
+                    data,
+                } => {
+                    if self.video_sample_entry.is_none() {
+                        continue; // can't be decoded without the configuration.
+                    }
+                    return Ok(VideoFrame {
+                        pts: pts + i64::from(composition_time) * 90,
+                        duration: 0,
+                        is_key,
+                        data,
+                        new_video_sample_entry: std::mem::take(&mut self.new_video_sample_entry),
+                        loss: 0,
+                    });
+                }
+                flv::Tag::AudioConfig(config) => {
+                    if !self.want_audio || self.audio.is_some() {
+                        continue;
+                    }
+                    match crate::aac::parse_extra_data(&config) {
+                        Ok(sample_entry) => {
+                            self.audio = Some(Audio {
+                                sample_entry,
+                                pending: Vec::new(),
+                            })
+                        }
+                        Err(e) => {
+                            warn!(
+                                err = %e.chain(),
+                                "{}: unable to parse AAC parameters; not recording audio",
+                                &self.label
+                            );
+                            self.want_audio = false;
+                        }
+                    }
+                }
+                flv::Tag::Audio(data) => {
+                    if let Some(a) = self.audio.as_mut() {
+                        a.pending.push(AudioFrame {
+                            pts,
+                            duration: AAC_FRAME_LENGTH,
+                            data,
+                        });
+                    }
+                }
+                flv::Tag::Ignored => {}
+            }
+        }
+    }
+
+    fn update_video_sample_entry(&mut self, config: &Bytes) -> Result<(), Error> {
+        // Frames are stored with 4-byte lengths, as the sample entry's configuration says.
+        if config.get(4).map(|b| b & 0b11) != Some(3) {
+            bail!(
+                Unimplemented,
+                msg("only 4-byte NAL unit lengths are supported")
+            );
+        }
+        let e = crate::h264::parse_extra_data(config)?;
+        if self.video_sample_entry.as_ref().map(|old| &old.data) != Some(&e.data) {
+            if self.video_sample_entry.is_some() {
+                debug!("{}: video sample entry changed", &self.label);
+                self.new_video_sample_entry = true;
+            }
+            self.video_sample_entry = Some(e);
+        }
+        Ok(())
+    }
+
+    async fn fetch_next_frame(mut self: Box<Self>) -> Result<(Box<Self>, VideoFrame), Error> {
+        let frame = self.next_frame().await?;
+        Ok((self, frame))
+    }
+}
+
+/// A stream from an RTMP publisher, which blocks on tokio operations as `RetinaStream` does.
+struct RtmpStream {
+    inner: Option<Box<Inner>>,
+    rt_handle: tokio::runtime::Handle,
+    first_frame: Option<VideoFrame>,
+    frame_timeout: Duration,
+}
+
+/// Opens the stream published to the given `rtmp://` URL's path, waiting for a publisher if
+/// necessary. The stream's RTSP and TLS options are ignored.
+pub fn open(label: String, url: Url, options: Options) -> Result<Box<dyn Stream>, Error> {
+    let Some(registry) = REGISTRY.get() else {
+        bail!(
+            FailedPrecondition,
+            msg("RTMP ingest is disabled; add an `rtmp` section to the config file")
+        );
+    };
+    let path = stream_path(&url);
+    let rt_handle = tokio::runtime::Handle::current();
+    let (inner, first_frame) = rt_handle
+        .block_on(
+            rt_handle.spawn(
+                tokio::time::timeout(
+                    options.connect_timeout,
+                    Inner::start(registry, label, path, options.audio_setup.is_some()),
+                )
+                .in_current_span(),
+            ),
+        )
+        .expect("RtmpStream::start task panicked, see earlier error")
+        .map_err(|e| {
+            err!(
+                DeadlineExceeded,
+                msg("timeout waiting for RTMP publisher"),
+                source(e)
+            )
+        })??;
+    Ok(Box::new(RtmpStream {
+        inner: Some(inner),
+        rt_handle,
+        first_frame: Some(first_frame),
+        frame_timeout: options.frame_timeout,
+    }))
+}
+
+impl Stream for RtmpStream {
+    fn tool(&self) -> Option<&retina::client::Tool> {
+        None
+    }
+
+    fn video_sample_entry(&self) -> &db::VideoSampleEntryToInsert {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .video_sample_entry
+            .as_ref()
+            .expect("first frame implies video sample entry")
+    }
+
+    fn next(&mut self) -> Result<VideoFrame, Error> {
+        if let Some(f) = self.first_frame.take() {
+            return Ok(f);
+        }
+        let inner = self.inner.take().unwrap();
+        let (inner, frame) = self
+            .rt_handle
+            .block_on(
+                self.rt_handle.spawn(
+                    tokio::time::timeout(self.frame_timeout, inner.fetch_next_frame())
+                        .in_current_span(),
+                ),
+            )
+            .expect("fetch_next_frame task panicked, see earlier error")
+            .map_err(|e| {
+                err!(
+                    DeadlineExceeded,
+                    msg("timeout getting next frame"),
+                    source(e)
+                )
+            })??;
+        self.inner = Some(inner);
+        Ok(frame)
+    }
+
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .audio
+            .as_ref()
+            .map(|a| &a.sample_entry)
+    }
+
+    fn take_audio_frames(&mut self) -> Vec<AudioFrame> {
+        match self.inner.as_mut().unwrap().audio.as_mut() {
+            Some(a) => std::mem::take(&mut a.pending),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn path() {
+        testutil::init();
+        let url = Url::parse("rtmp://nvr.example.com:1935/live/front%20door").unwrap();
+        assert_eq!(stream_path(&url), "live/front door");
+        let url = Url::parse("rtmp://localhost/live/abc/").unwrap();
+        assert_eq!(stream_path(&url), "live/abc");
+    }
+
+    #[test]
+    fn timeline() {
+        testutil::init();
+        let mut t = Timeline::default();
+        assert_eq!(t.advance(u32::MAX - 10), 0);
+        assert_eq!(t.advance(u32::MAX - 20), -900); // small backward step.
+        assert_eq!(t.advance(9), 20 * 90); // wrapped.
+    }
+}
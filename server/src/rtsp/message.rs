@@ -0,0 +1,259 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTSP message framing for connections to cameras, as used by the SRTP proxy
+//! ([`crate::srtp`]) and the ONVIF audio backchannel ([`crate::onvif::backchannel`]).
+//!
+//! Unlike the server's request parser, this handles the interleaved data frames which cameras
+//! send amid their responses.
+
+use base::{bail, err, Error};
+use bytes::Bytes;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+/// The maximum length of a request or response's start line and headers.
+const MAX_HEAD_LEN: usize = 64 << 10;
+
+/// The maximum length of a request or response's body.
+const MAX_BODY_LEN: usize = 1 << 20;
+
+/// A message on an RTSP connection.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Message {
+    /// An interleaved data frame, as in RFC 2326 section 10.12.
+    Data {
+        channel: u8,
+        payload: Bytes,
+    },
+
+    Rtsp(Rtsp),
+}
+
+/// A request or response.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Rtsp {
+    /// The request or status line.
+    pub(crate) start: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Bytes,
+}
+
+impl Rtsp {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn header_mut(&mut self, name: &str) -> Option<&mut String> {
+        self.headers
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub(crate) fn cseq(&self) -> Option<&str> {
+        self.header("CSeq")
+    }
+
+    /// Returns the status code, if this is a response.
+    pub(crate) fn status(&self) -> Option<u16> {
+        let mut parts = self.start.split(' ');
+        if !parts.next()?.starts_with("RTSP/") {
+            return None;
+        }
+        parts.next()?.parse().ok()
+    }
+
+    pub(crate) fn set_body(&mut self, body: Bytes) {
+        let len = body.len().to_string();
+        match self.header_mut("Content-Length") {
+            Some(v) => *v = len,
+            None => self.headers.push(("Content-Length".to_owned(), len)),
+        }
+        self.body = body;
+    }
+}
+
+/// Reads the next message, or returns `None` at the end of the connection.
+pub(crate) async fn read<R: AsyncRead + Unpin>(
+    r: &mut BufReader<R>,
+) -> Result<Option<Message>, Error> {
+    let buf = r
+        .fill_buf()
+        .await
+        .map_err(|e| err!(e, msg("unable to read RTSP connection")))?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] == b'$' {
+        let mut hdr = [0u8; 4];
+        r.read_exact(&mut hdr)
+            .await
+            .map_err(|e| err!(e, msg("unable to read interleaved frame header")))?;
+        let mut payload = vec![0u8; usize::from(u16::from_be_bytes([hdr[2], hdr[3]]))];
+        r.read_exact(&mut payload)
+            .await
+            .map_err(|e| err!(e, msg("unable to read interleaved frame")))?;
+        return Ok(Some(Message::Data {
+            channel: hdr[1],
+            payload: payload.into(),
+        }));
+    }
+    let mut start = String::new();
+    let mut headers = Vec::new();
+    let mut head_len = 0;
+    loop {
+        let mut line = String::new();
+        let n = (&mut *r)
+            .take((MAX_HEAD_LEN - head_len) as u64)
+            .read_line(&mut line)
+            .await
+            .map_err(|e| err!(e, msg("unable to read RTSP message")))?;
+        head_len += n;
+        if !line.ends_with('\n') {
+            if n == 0 && head_len < MAX_HEAD_LEN {
+                bail!(DataLoss, msg("RTSP connection closed mid-message"));
+            }
+            bail!(
+                OutOfRange,
+                msg("RTSP message head exceeds {MAX_HEAD_LEN} bytes")
+            );
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if start.is_empty() {
+            start = line.to_owned(); // may be empty, skipping a stray line break.
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| err!(InvalidArgument, msg("bad RTSP header line {line:?}")))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let mut m = Rtsp {
+        start,
+        headers,
+        body: Bytes::new(),
+    };
+    if let Some(len) = m.header("Content-Length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| err!(InvalidArgument, msg("bad Content-Length {len:?}")))?;
+        if len > MAX_BODY_LEN {
+            bail!(
+                OutOfRange,
+                msg("RTSP message body exceeds {MAX_BODY_LEN} bytes")
+            );
+        }
+        let mut body = vec![0u8; len];
+        r.read_exact(&mut body)
+            .await
+            .map_err(|e| err!(e, msg("unable to read RTSP message body")))?;
+        m.body = body.into();
+    }
+    Ok(Some(Message::Rtsp(m)))
+}
+
+/// Encodes a message for the wire.
+///
+/// Panics if a data frame's payload doesn't fit in its 16-bit length.
+pub(crate) fn encode(m: &Message) -> Vec<u8> {
+    match m {
+        Message::Data { channel, payload } => {
+            let len = u16::try_from(payload.len()).expect("interleaved payload fits in 16 bits");
+            let mut out = Vec::with_capacity(4 + payload.len());
+            out.push(b'$');
+            out.push(*channel);
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(payload);
+            out
+        }
+        Message::Rtsp(m) => {
+            let mut out = Vec::with_capacity(1024 + m.body.len());
+            out.extend_from_slice(m.start.as_bytes());
+            out.extend_from_slice(b"\r\n");
+            for (name, value) in &m.headers {
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(&m.body);
+            out
+        }
+    }
+}
+
+/// Returns the RTP and RTCP channels from a `Transport` header value's `interleaved` parameter.
+pub(crate) fn interleaved(transport: &str) -> Option<(u8, u8)> {
+    let value = transport
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("interleaved="))?;
+    match value.split_once('-') {
+        Some((rtp, rtcp)) => Some((rtp.parse().ok()?, rtcp.parse().ok()?)),
+        None => {
+            let rtp: u8 = value.parse().ok()?;
+            Some((rtp, rtp.checked_add(1)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn round_trip() {
+        testutil::init();
+        let mut resp = Rtsp {
+            start: "RTSP/1.0 200 OK".to_owned(),
+            headers: vec![("CSeq".to_owned(), "2".to_owned())],
+            body: Bytes::new(),
+        };
+        resp.set_body(Bytes::from_static(b"v=0\r\n"));
+        let data = Message::Data {
+            channel: 1,
+            payload: Bytes::from_static(b"\x80\x00"),
+        };
+        let resp = Message::Rtsp(resp);
+        let mut buf = encode(&data);
+        buf.extend_from_slice(&encode(&resp));
+        let mut r = BufReader::new(&buf[..]);
+        assert_eq!(read(&mut r).await.unwrap(), Some(data));
+        let Some(Message::Rtsp(m)) = read(&mut r).await.unwrap() else {
+            panic!("expected response");
+        };
+        assert_eq!(m.status(), Some(200));
+        assert_eq!(m.cseq(), Some("2"));
+        assert_eq!(m.header("content-length"), Some("5"));
+        assert_eq!(Message::Rtsp(m), resp);
+        assert_eq!(read(&mut r).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        testutil::init();
+        let mut r = BufReader::new(&b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n"[..]);
+        read(&mut r).await.unwrap_err();
+    }
+
+    #[test]
+    fn interleaved_channels() {
+        assert_eq!(
+            interleaved("RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some((2, 3))
+        );
+        assert_eq!(
+            interleaved("RTP/AVP/TCP;unicast;interleaved=4"),
+            Some((4, 5))
+        );
+        assert_eq!(interleaved("RTP/AVP;unicast;client_port=5000-5001"), None);
+    }
+}
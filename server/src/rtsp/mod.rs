@@ -0,0 +1,823 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTSP restreaming, as configured by [`RtspConfig`].
+//!
+//! Each running stream is served at `rtsp://<host>:8554/<camera>/<type>`, where `<camera>` is the
+//! camera's short name (percent-encoded) or UUID and `<type>` is `main`, `sub`, or `ext`. Access
+//! units are taken from [`LiveFrames`] as soon as they're received and re-packetized into RTP,
+//! so other consumers which speak only RTSP can share Moonfire NVR's connection to the camera.
+//!
+//! This is a deliberately small RTSP/1.0 (RFC 2326) server. It supports one session per
+//! connection, with RTP carried over the connection itself (interleaved TCP), which all common
+//! clients support. It sends no RTCP. Clients authenticate with HTTP Basic credentials whose
+//! password is an API token, as with `/api/ha/`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::{bail, err, Error, ErrorKind};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BytesMut};
+use db::auth;
+use percent_encoding::percent_decode_str;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn, Instrument};
+use uuid::Uuid;
+
+use crate::cmds::run::config::RtspConfig;
+use crate::streamer::{LiveFrame, LiveFrames};
+
+pub(crate) mod message;
+mod rtp;
+
+/// The longest request accepted, including headers and body.
+const MAX_REQUEST_LEN: usize = 64 << 10;
+
+/// How long a connection may go without a request before it's closed, unless it's playing.
+/// This is also advertised as the session timeout.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `DESCRIBE` and `SETUP` wait for a key frame, if none has been received since startup.
+const KEY_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The control URL of the single media section, relative to the stream's URL.
+const CONTROL: &str = "trackID=0";
+
+const PUBLIC_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER";
+
+pub struct Server {
+    listener: TcpListener,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+}
+
+impl Server {
+    pub fn new(config: &RtspConfig) -> Result<Self, Error> {
+        let listener = std::net::TcpListener::bind(config.address)
+            .map_err(|e| err!(e, msg("unable to bind RTSP socket {}", config.address)))?;
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            listener: TcpListener::from_std(listener)?,
+            allow_unauthenticated_permissions: config
+                .allow_unauthenticated_permissions
+                .clone()
+                .map(db::Permissions::from),
+        })
+    }
+}
+
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    live_frames: Arc<LiveFrames>,
+    shutdown_rx: base::shutdown::Receiver,
+    server: Server,
+) {
+    if let Ok(addr) = server.listener.local_addr() {
+        info!("serving RTSP on {addr}");
+    }
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown_rx.as_future() => break,
+            r = server.listener.accept() => match r {
+                Ok(a) => a,
+                Err(err) => {
+                    warn!(%err, "unable to accept RTSP connection");
+                    continue;
+                }
+            },
+        };
+        let conn = Connection {
+            db: db.clone(),
+            live_frames: live_frames.clone(),
+            allow_unauthenticated_permissions: server.allow_unauthenticated_permissions.clone(),
+            peer,
+            session: None,
+        };
+        let shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = conn.run(stream, shutdown_rx).await {
+                    debug!(err = %err.chain(), "RTSP connection failed");
+                }
+            }
+            .instrument(tracing::info_span!("rtsp", %peer)),
+        );
+    }
+}
+
+/// An RTSP request.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+enum Message {
+    Request(Request),
+
+    /// An interleaved binary frame, such as an RTCP receiver report, which is ignored.
+    Interleaved,
+}
+
+/// Parses a message from the start of `buf`, removing it if complete.
+fn parse(buf: &mut BytesMut) -> Result<Option<Message>, Error> {
+    while matches!(buf.first(), Some(b'\r' | b'\n')) {
+        buf.advance(1);
+    }
+    if buf.first() == Some(&b'$') {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = 4 + usize::from(BigEndian::read_u16(&buf[2..4]));
+        if buf.len() < len {
+            return Ok(None);
+        }
+        buf.advance(len);
+        return Ok(Some(Message::Interleaved));
+    }
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if buf.len() > MAX_REQUEST_LEN {
+            bail!(InvalidArgument, msg("request too long"));
+        }
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| err!(InvalidArgument, msg("request isn't UTF-8")))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().expect("split returns at least one item");
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(url), Some("RTSP/1.0"), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!(InvalidArgument, msg("bad request line {request_line:?}"));
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| err!(InvalidArgument, msg("bad header line {line:?}")))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let req = Request {
+        method: method.to_owned(),
+        url: url.to_owned(),
+        headers,
+    };
+    let content_len = match req.header("Content-Length") {
+        None => 0,
+        Some(l) => l
+            .parse::<usize>()
+            .ok()
+            .filter(|&l| l <= MAX_REQUEST_LEN)
+            .ok_or_else(|| err!(InvalidArgument, msg("bad Content-Length {l:?}")))?,
+    };
+    let len = head_len + 4 + content_len;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    buf.advance(len);
+    Ok(Some(Message::Request(req)))
+}
+
+/// Parses a request URL into a camera (short name or UUID) and stream type, ignoring any further
+/// path components, such as the control URL in a `SETUP` request.
+fn parse_url(url: &str) -> Result<(String, db::StreamType), Error> {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |i| &rest[i..]),
+        None => url,
+    };
+    let path = path
+        .split('?')
+        .next()
+        .expect("split returns at least one item");
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    let (Some(camera), Some(stream_type)) = (segments.next(), segments.next()) else {
+        bail!(
+            NotFound,
+            msg("expected /<camera>/<stream type>, got {url:?}")
+        );
+    };
+    let camera = percent_decode_str(camera)
+        .decode_utf8()
+        .map_err(|_| err!(NotFound, msg("camera name isn't UTF-8")))?
+        .into_owned();
+    let stream_type = db::StreamType::parse(stream_type)
+        .ok_or_else(|| err!(NotFound, msg("no such stream type {stream_type:?}")))?;
+    Ok((camera, stream_type))
+}
+
+/// Returns the interleaved channel requested by a `Transport` header, or `None` if it doesn't
+/// offer `RTP/AVP/TCP`.
+fn interleaved_channel(transport: &str) -> Option<u8> {
+    for spec in transport.split(',') {
+        let mut params = spec.split(';');
+        let protocol = params.next().unwrap_or_default().trim();
+        if !protocol.eq_ignore_ascii_case("RTP/AVP/TCP") {
+            continue;
+        }
+        let channel = match params.find_map(|p| p.trim().strip_prefix("interleaved=")) {
+            None => 0,
+            Some(c) => match c.split('-').next().and_then(|c| c.parse::<u8>().ok()) {
+                Some(c) if c < u8::MAX => c,
+                _ => continue,
+            },
+        };
+        return Some(channel);
+    }
+    None
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str) -> Self {
+        Response {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn ok() -> Self {
+        Self::new(200, "OK")
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn from_base_error(err: &Error) -> Self {
+        let (status, reason) = match err.kind() {
+            ErrorKind::Unauthenticated => (401, "Unauthorized"),
+            ErrorKind::PermissionDenied => (403, "Forbidden"),
+            ErrorKind::NotFound => (404, "Not Found"),
+            ErrorKind::InvalidArgument => (400, "Bad Request"),
+            ErrorKind::FailedPrecondition => (455, "Method Not Valid in This State"),
+            ErrorKind::Unavailable => (503, "Service Unavailable"),
+            ErrorKind::Unimplemented => (501, "Not Implemented"),
+            _ => (500, "Internal Server Error"),
+        };
+        let mut resp = Response::new(status, reason);
+        if status == 401 {
+            resp = resp.header("WWW-Authenticate", "Basic realm=\"Moonfire NVR\"");
+        }
+        resp
+    }
+
+    fn encode(&self, cseq: Option<&str>, out: &mut Vec<u8>) {
+        out.extend_from_slice(format!("RTSP/1.0 {} {}\r\n", self.status, self.reason).as_bytes());
+        if let Some(cseq) = cseq {
+            out.extend_from_slice(format!("CSeq: {cseq}\r\n").as_bytes());
+        }
+        for (name, value) in &self.headers {
+            out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        if !self.body.is_empty() {
+            out.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(self.body.as_bytes());
+    }
+}
+
+/// Returns the next frame from `frames`, or never if not playing.
+async fn next_frame(
+    frames: &mut Option<broadcast::Receiver<Arc<LiveFrame>>>,
+) -> Result<Arc<LiveFrame>, broadcast::error::RecvError> {
+    match frames {
+        Some(f) => f.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+struct Session {
+    id: String,
+    stream_id: i32,
+    control_url: String,
+    channel: u8,
+    video_sample_entry_id: i32,
+    description: rtp::Description,
+    packetizer: rtp::Packetizer,
+}
+
+struct Connection<C: Clocks + Clone> {
+    db: Arc<db::Database<C>>,
+    live_frames: Arc<LiveFrames>,
+    allow_unauthenticated_permissions: Option<db::Permissions>,
+    peer: SocketAddr,
+    session: Option<Session>,
+}
+
+impl<C: Clocks + Clone> Connection<C> {
+    async fn run(
+        mut self,
+        mut stream: TcpStream,
+        shutdown_rx: base::shutdown::Receiver,
+    ) -> Result<(), Error> {
+        let (mut reader, mut writer) = stream.split();
+        let mut buf = BytesMut::with_capacity(4096);
+        let mut out = Vec::new();
+        let mut frames = None;
+
+        // True once a key frame has been sent since starting or falling behind.
+        let mut synced = false;
+        let mut idle_deadline = tokio::time::Instant::now() + SESSION_TIMEOUT;
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.as_future() => return Ok(()),
+                _ = tokio::time::sleep_until(idle_deadline), if frames.is_none() => {
+                    debug!("closing idle RTSP connection");
+                    return Ok(());
+                }
+                r = reader.read_buf(&mut buf) => {
+                    if r.map_err(|e| err!(Unavailable, msg("read failed"), source(e)))? == 0 {
+                        return Ok(());
+                    }
+                    idle_deadline = tokio::time::Instant::now() + SESSION_TIMEOUT;
+                    while let Some(m) = parse(&mut buf)? {
+                        let Message::Request(req) = m else {
+                            continue;
+                        };
+                        debug!(method = %req.method, url = %req.url, "RTSP request");
+                        let resp = self.handle(&req).await;
+                        out.clear();
+                        resp.encode(req.header("CSeq"), &mut out);
+                        writer
+                            .write_all(&out)
+                            .await
+                            .map_err(|e| err!(Unavailable, msg("write failed"), source(e)))?;
+                        match req.method.as_str() {
+                            "PLAY" if resp.status == 200 && frames.is_none() => {
+                                let s = self.session.as_ref().expect("PLAY requires session");
+                                frames = Some(self.live_frames.subscribe(s.stream_id));
+                                synced = false;
+                            }
+                            "TEARDOWN" if resp.status == 200 => return Ok(()),
+                            _ => {}
+                        }
+                    }
+                }
+                f = next_frame(&mut frames) => {
+                    let frame = match f {
+                        Ok(f) => f,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!(skipped, "RTSP client fell behind; waiting for next key frame");
+                            synced = false;
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    };
+                    if !synced && !frame.is_key {
+                        continue;
+                    }
+                    synced = true;
+                    out.clear();
+                    self.packetize(&frame, &mut out)?;
+                    writer
+                        .write_all(&out)
+                        .await
+                        .map_err(|e| err!(Unavailable, msg("write failed"), source(e)))?;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, req: &Request) -> Response {
+        let r = match req.method.as_str() {
+            "OPTIONS" => Ok(Response::ok().header("Public", PUBLIC_METHODS)),
+            "DESCRIBE" => self.describe(req).await,
+            "SETUP" => self.setup(req).await,
+            "PLAY" => self.play(req),
+            "TEARDOWN" | "GET_PARAMETER" => self.check_session(req).map(|()| Response::ok()),
+            _ => Ok(Response::new(501, "Not Implemented").header("Public", PUBLIC_METHODS)),
+        };
+        let mut resp = r.unwrap_or_else(|err| {
+            if err.kind() == ErrorKind::Internal {
+                warn!(err = %err.chain(), "RTSP {} failed", &req.method);
+            } else {
+                debug!(err = %err.chain(), "RTSP {} failed", &req.method);
+            }
+            Response::from_base_error(&err)
+        });
+        if let Some(s) = self.session.as_ref() {
+            if !resp.headers.iter().any(|(n, _)| *n == "Session") {
+                resp.headers.push(("Session", s.id.clone()));
+            }
+        }
+        resp
+    }
+
+    /// Authenticates the request and returns the id of the stream it names.
+    fn authorize(&self, req: &Request) -> Result<i32, Error> {
+        let (camera, stream_type) = parse_url(&req.url)?;
+        let mut l = self.db.lock();
+        let credentials = req
+            .header("Authorization")
+            .and_then(|h| h.strip_prefix("Basic "));
+        let permissions = match credentials {
+            Some(c) => {
+                let token = crate::web::basic_api_token(c.as_bytes())
+                    .ok_or_else(|| err!(Unauthenticated, msg("password isn't an API token")))?;
+                let authreq = auth::Request {
+                    when_sec: Some(self.db.clocks().realtime().sec),
+                    user_agent: req.header("User-Agent").map(|u| u.as_bytes().to_vec()),
+                    addr: Some(self.peer.ip()),
+                };
+                match l.authenticate_api_token(authreq, &token) {
+                    Ok((t, _)) => t.permissions.clone(),
+                    Err(err) if err.kind() == ErrorKind::Unauthenticated => {
+                        // As with HTTP, log the specific reason but don't tell the client.
+                        warn!(err = %err.chain(), "RTSP API token authentication failed");
+                        bail!(Unauthenticated, msg("invalid API token"));
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            None => self
+                .allow_unauthenticated_permissions
+                .clone()
+                .ok_or_else(|| err!(Unauthenticated, msg("credentials required")))?,
+        };
+        if !permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let c = match Uuid::parse_str(&camera) {
+            Ok(uuid) => l.get_camera(uuid),
+            Err(_) => l.cameras_by_id().values().find(|c| c.short_name == camera),
+        }
+        .ok_or_else(|| err!(NotFound, msg("no such camera {camera:?}")))?;
+        if !permissions.allows_camera(c.uuid) {
+            bail!(PermissionDenied, msg("camera {} not allowed", c.uuid));
+        }
+        let stream_id = c.streams[stream_type.index()]
+            .ok_or_else(|| err!(NotFound, msg("no such stream {camera}/{stream_type}")))?;
+        let stream = l
+            .streams_by_id()
+            .get(&stream_id)
+            .expect("stream_id refed by camera");
+        if !stream.config.is_recording() {
+            bail!(
+                Unavailable,
+                msg("stream {camera}/{stream_type} isn't running")
+            );
+        }
+        Ok(stream_id)
+    }
+
+    /// Returns the video sample entry id and description of the stream's latest key frame,
+    /// waiting for one if none has been received since startup.
+    async fn describe_stream(&self, stream_id: i32) -> Result<(i32, rtp::Description), Error> {
+        let frame = match self.live_frames.latest_key_frame(stream_id) {
+            Some(f) => f,
+            None => {
+                let mut frames = self.live_frames.subscribe(stream_id);
+                let key_frame = async {
+                    loop {
+                        match frames.recv().await {
+                            Ok(f) if f.is_key => return Some(f),
+                            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                };
+                tokio::time::timeout(KEY_FRAME_TIMEOUT, key_frame)
+                    .await
+                    .ok()
+                    .flatten()
+                    .ok_or_else(|| err!(Unavailable, msg("no key frame received")))?
+            }
+        };
+        let id = frame.video_sample_entry_id;
+        let l = self.db.lock();
+        let entry = l
+            .video_sample_entries_by_id()
+            .get(&id)
+            .ok_or_else(|| err!(Internal, msg("no video sample entry {id}")))?;
+        Ok((id, rtp::Description::new(entry)?))
+    }
+
+    async fn describe(&mut self, req: &Request) -> Result<Response, Error> {
+        let stream_id = self.authorize(req)?;
+        let (_, description) = self.describe_stream(stream_id).await?;
+        let base = req.url.trim_end_matches('/');
+        let mut resp = Response::ok()
+            .header("Content-Base", format!("{base}/"))
+            .header("Content-Type", "application/sdp");
+        resp.body = description.sdp(CONTROL);
+        Ok(resp)
+    }
+
+    async fn setup(&mut self, req: &Request) -> Result<Response, Error> {
+        if self.session.is_some() {
+            bail!(
+                FailedPrecondition,
+                msg("only one stream may be set up per connection")
+            );
+        }
+        let stream_id = self.authorize(req)?;
+        let Some(channel) = req.header("Transport").and_then(interleaved_channel) else {
+            return Ok(Response::new(461, "Unsupported Transport"));
+        };
+        let (video_sample_entry_id, description) = self.describe_stream(stream_id).await?;
+        let mut random = [0u8; 18];
+        SystemRandom::new()
+            .fill(&mut random)
+            .map_err(|_| err!(Internal, msg("unable to generate random values")))?;
+        let packetizer = rtp::Packetizer::new(
+            description.codec,
+            BigEndian::read_u32(&random[0..4]),
+            BigEndian::read_u16(&random[4..6]),
+            BigEndian::read_u32(&random[6..10]),
+        );
+        let id = base::strutil::hex(&random[10..18]);
+        let resp = Response::ok()
+            .header(
+                "Transport",
+                format!(
+                    "RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                    channel,
+                    channel + 1,
+                    packetizer.ssrc()
+                ),
+            )
+            .header(
+                "Session",
+                format!("{id};timeout={}", SESSION_TIMEOUT.as_secs()),
+            );
+        self.session = Some(Session {
+            id,
+            stream_id,
+            control_url: req.url.clone(),
+            channel,
+            video_sample_entry_id,
+            description,
+            packetizer,
+        });
+        Ok(resp)
+    }
+
+    fn play(&mut self, req: &Request) -> Result<Response, Error> {
+        self.check_session(req)?;
+        let s = self
+            .session
+            .as_ref()
+            .ok_or_else(|| err!(FailedPrecondition, msg("PLAY requires SETUP")))?;
+        Ok(Response::ok().header("Range", "npt=0.000-").header(
+            "RTP-Info",
+            format!("url={};seq={}", &s.control_url, s.packetizer.next_seq()),
+        ))
+    }
+
+    /// Checks that the request's `Session` header, if any, names the current session.
+    fn check_session(&self, req: &Request) -> Result<(), Error> {
+        let Some(id) = req.header("Session") else {
+            return Ok(());
+        };
+        let id = id
+            .split(';')
+            .next()
+            .expect("split returns at least one item");
+        match self.session.as_ref() {
+            Some(s) if s.id == id => Ok(()),
+            _ => bail!(NotFound, msg("no such session {id:?}")),
+        }
+    }
+
+    /// Packetizes a frame of the playing session, sending parameter sets before each key frame.
+    fn packetize(&mut self, frame: &LiveFrame, out: &mut Vec<u8>) -> Result<(), Error> {
+        let s = self.session.as_mut().expect("playing implies session");
+        if frame.is_key && frame.video_sample_entry_id != s.video_sample_entry_id {
+            let id = frame.video_sample_entry_id;
+            let l = self.db.lock();
+            let entry = l
+                .video_sample_entries_by_id()
+                .get(&id)
+                .ok_or_else(|| err!(Internal, msg("no video sample entry {id}")))?;
+            let description = rtp::Description::new(entry)?;
+            if description.codec != s.description.codec {
+                bail!(FailedPrecondition, msg("stream's codec changed"));
+            }
+            s.video_sample_entry_id = id;
+            s.description = description;
+        }
+        let prefix: &[Vec<u8>] = if frame.is_key {
+            &s.description.parameter_sets
+        } else {
+            &[]
+        };
+        s.packetizer
+            .packetize(frame.pts_90k, prefix, &frame.data, s.channel, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use db::testutil::{self, TestDb};
+
+    fn parse_str(s: &str) -> Result<Option<Message>, Error> {
+        parse(&mut BytesMut::from(s.as_bytes()))
+    }
+
+    #[test]
+    fn parse_messages() {
+        testutil::init();
+        let mut buf = BytesMut::from(
+            &b"OPTIONS rtsp://nvr:8554/front/main RTSP/1.0\r\n\
+               CSeq: 1\r\n\
+               User-Agent: test\r\n\r\n\
+               $\x01\x00\x02ab\
+               SET_PARAMETER * RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 3\r\n\r\nabcDESC"[..],
+        );
+        let Some(Message::Request(r)) = parse(&mut buf).unwrap() else {
+            panic!();
+        };
+        assert_eq!(r.method, "OPTIONS");
+        assert_eq!(r.url, "rtsp://nvr:8554/front/main");
+        assert_eq!(r.header("cseq"), Some("1"));
+        assert_eq!(r.header("user-agent"), Some("test"));
+        assert!(matches!(
+            parse(&mut buf).unwrap(),
+            Some(Message::Interleaved)
+        ));
+        let Some(Message::Request(r)) = parse(&mut buf).unwrap() else {
+            panic!();
+        };
+        assert_eq!(r.method, "SET_PARAMETER");
+        assert_eq!(r.header("CSeq"), Some("2"));
+
+        // Incomplete requests are left in the buffer.
+        assert!(parse(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"DESC");
+
+        parse_str("GET / HTTP/1.1\r\n\r\n").unwrap_err();
+        parse_str("OPTIONS * RTSP/1.0\r\nbad header\r\n\r\n").unwrap_err();
+        assert!(
+            parse_str("OPTIONS * RTSP/1.0\r\nContent-Length: 5\r\n\r\nab")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn urls() {
+        testutil::init();
+        assert_eq!(
+            parse_url("rtsp://nvr:8554/front%20door/main").unwrap(),
+            ("front door".to_owned(), db::StreamType::Main)
+        );
+        assert_eq!(
+            parse_url("rtsp://nvr/front/sub/trackID=0").unwrap(),
+            ("front".to_owned(), db::StreamType::Sub)
+        );
+        assert_eq!(
+            parse_url("/front/ext/?foo=bar").unwrap(),
+            ("front".to_owned(), db::StreamType::Ext)
+        );
+        parse_url("rtsp://nvr/front").unwrap_err();
+        parse_url("rtsp://nvr/front/other").unwrap_err();
+    }
+
+    #[test]
+    fn transports() {
+        testutil::init();
+        assert_eq!(
+            interleaved_channel("RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some(2)
+        );
+        assert_eq!(
+            interleaved_channel("RTP/AVP;unicast;client_port=5000-5001,RTP/AVP/TCP;unicast"),
+            Some(0)
+        );
+        assert_eq!(
+            interleaved_channel("RTP/AVP;unicast;client_port=5000-5001"),
+            None
+        );
+        assert_eq!(
+            interleaved_channel("RTP/AVP/TCP;unicast;interleaved=255"),
+            None
+        );
+    }
+
+    /// Sends a request and returns the response's head.
+    async fn request(stream: &mut TcpStream, req: &str) -> String {
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = Vec::new();
+        while !resp.ends_with(b"\r\n\r\n") {
+            resp.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(resp).unwrap()
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let token = {
+            let mut l = db.db.lock();
+            let mut c = db::UserChange::add_user("slamb".to_owned());
+            c.permissions.view_video = true;
+            let uid = l.apply_user_change(c).unwrap().id;
+            let permissions = db::Permissions {
+                view_video: true,
+                ..Default::default()
+            };
+            let (token, _) = l.make_api_token(uid, None, 0, None, permissions).unwrap();
+            token.encode_base64()
+        };
+        let server = Server::new(&RtspConfig {
+            address: "127.0.0.1:0".parse().unwrap(),
+            allow_unauthenticated_permissions: None,
+        })
+        .unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = base::shutdown::channel();
+        let handle = tokio::spawn(run(
+            db.db.clone(),
+            Arc::new(LiveFrames::default()),
+            shutdown_rx,
+            server,
+        ));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let resp = request(&mut stream, "OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n").await;
+        assert!(resp.starts_with("RTSP/1.0 200 OK\r\nCSeq: 1\r\n"), "{resp}");
+        assert!(resp.contains("Public: OPTIONS, DESCRIBE"), "{resp}");
+
+        // Streams require credentials, and clients are told to send them.
+        let describe = |authorization: &str| {
+            format!(
+                "DESCRIBE rtsp://{addr}/test%20camera/main RTSP/1.0\r\n\
+                 CSeq: 2\r\n{authorization}\r\n"
+            )
+        };
+        let resp = request(&mut stream, &describe("")).await;
+        assert!(resp.starts_with("RTSP/1.0 401 Unauthorized\r\n"), "{resp}");
+        assert!(resp.contains("WWW-Authenticate: Basic"), "{resp}");
+        let bad = format!(
+            "Authorization: Basic {}\r\n",
+            STANDARD.encode("slamb:hunter2")
+        );
+        let resp = request(&mut stream, &describe(&bad)).await;
+        assert!(resp.starts_with("RTSP/1.0 401 Unauthorized\r\n"), "{resp}");
+
+        // With a token, cameras are looked up and transports are checked.
+        let authorization = format!(
+            "Authorization: Basic {}\r\n",
+            STANDARD.encode(format!("rtsp:{token}"))
+        );
+        let resp = request(
+            &mut stream,
+            &format!(
+                "DESCRIBE rtsp://{addr}/other/main RTSP/1.0\r\nCSeq: 3\r\n{authorization}\r\n"
+            ),
+        )
+        .await;
+        assert!(resp.starts_with("RTSP/1.0 404 Not Found\r\n"), "{resp}");
+        let resp = request(
+            &mut stream,
+            &format!(
+                "SETUP rtsp://{addr}/test%20camera/main/trackID=0 RTSP/1.0\r\nCSeq: 4\r\n\
+                 Transport: RTP/AVP;unicast;client_port=5000-5001\r\n{authorization}\r\n"
+            ),
+        )
+        .await;
+        assert!(
+            resp.starts_with("RTSP/1.0 461 Unsupported Transport\r\n"),
+            "{resp}"
+        );
+        let resp = request(&mut stream, "PLAY * RTSP/1.0\r\nCSeq: 5\r\n\r\n").await;
+        assert!(
+            resp.starts_with("RTSP/1.0 455 Method Not Valid in This State\r\n"),
+            "{resp}"
+        );
+
+        drop(shutdown_tx);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,434 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTP packetization of live frames, and SDP descriptions of the resulting media.
+//!
+//! H.264 is packetized as in RFC 6184 (packetization mode 1) and H.265 as in RFC 7798, using
+//! single NAL unit packets and fragmentation units but never aggregation packets. Parameter sets
+//! are sent in-band before each key frame as well as described in the SDP, so clients can start
+//! (or restart after falling behind) at any key frame.
+
+use base::{bail, err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+/// The dynamic RTP payload type used for video.
+pub(super) const PAYLOAD_TYPE: u8 = 96;
+
+/// The maximum RTP payload size. Larger NAL units are fragmented. Interleaved packets aren't
+/// limited by the path MTU, but clients may expect packets which would fit in one.
+const MAX_PAYLOAD: usize = 1400;
+
+/// RFC 6184 section 5.8: FU-A.
+const H264_FU_A: u8 = 28;
+
+/// RFC 7798 section 4.4.3: fragmentation unit.
+const H265_FU: u8 = 49;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Codec {
+    H264,
+    H265,
+}
+
+/// A stream's codec and parameter sets, as taken from its video sample entry.
+pub(super) struct Description {
+    pub(super) codec: Codec,
+
+    /// Parameter set NAL units (without start codes or length prefixes) in decoder
+    /// configuration record order: SPS then PPS for H.264; VPS, SPS, then PPS for H.265.
+    pub(super) parameter_sets: Vec<Vec<u8>>,
+}
+
+/// Reads a NAL unit with a 16-bit length prefix, as in decoder configuration records.
+fn read_nal(data: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let len = data
+        .get(0..2)
+        .map(|l| usize::from(BigEndian::read_u16(l)))
+        .ok_or_else(|| err!(InvalidArgument, msg("truncated parameter set length")))?;
+    let nal = data
+        .get(2..2 + len)
+        .ok_or_else(|| err!(InvalidArgument, msg("truncated parameter set")))?;
+    if nal.is_empty() {
+        bail!(InvalidArgument, msg("empty parameter set"));
+    }
+    *data = &data[2 + len..];
+    Ok(nal.to_vec())
+}
+
+impl Description {
+    pub(super) fn new(entry: &db::VideoSampleEntry) -> Result<Self, Error> {
+        let config = entry.decoder_config()?;
+        let mut parameter_sets = Vec::new();
+        let codec = match entry.box_type() {
+            b"avc1" => {
+                // AVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 5.3.3.1.
+                let (&num_sps, mut data) = config
+                    .get(5..)
+                    .and_then(|d| d.split_first())
+                    .ok_or_else(|| err!(InvalidArgument, msg("truncated avcC box")))?;
+                for _ in 0..(num_sps & 0x1f) {
+                    parameter_sets.push(read_nal(&mut data)?);
+                }
+                let (&num_pps, mut data) = data
+                    .split_first()
+                    .ok_or_else(|| err!(InvalidArgument, msg("truncated avcC box")))?;
+                for _ in 0..num_pps {
+                    parameter_sets.push(read_nal(&mut data)?);
+                }
+                Codec::H264
+            }
+            _ => {
+                // HEVCDecoderConfigurationRecord, ISO/IEC 14496-15 section 8.3.3.1.
+                let (&num_arrays, mut data) = config
+                    .get(22..)
+                    .and_then(|d| d.split_first())
+                    .ok_or_else(|| err!(InvalidArgument, msg("truncated hvcC box")))?;
+                for _ in 0..num_arrays {
+                    let num_nalus = data
+                        .get(1..3)
+                        .map(BigEndian::read_u16)
+                        .ok_or_else(|| err!(InvalidArgument, msg("truncated hvcC box")))?;
+                    data = &data[3..];
+                    for _ in 0..num_nalus {
+                        parameter_sets.push(read_nal(&mut data)?);
+                    }
+                }
+                Codec::H265
+            }
+        };
+        Ok(Description {
+            codec,
+            parameter_sets,
+        })
+    }
+
+    /// Returns the parameter sets of the given NAL unit type, base64-encoded and
+    /// comma-separated, as in SDP `fmtp` attributes.
+    fn sprop(&self, nal_type: u8) -> String {
+        let mut out = String::new();
+        for p in self
+            .parameter_sets
+            .iter()
+            .filter(|p| self.nal_type(p) == nal_type)
+        {
+            if !out.is_empty() {
+                out.push(',');
+            }
+            STANDARD.encode_string(p, &mut out);
+        }
+        out
+    }
+
+    fn nal_type(&self, nal: &[u8]) -> u8 {
+        match self.codec {
+            Codec::H264 => nal[0] & 0x1f,
+            Codec::H265 => (nal[0] >> 1) & 0x3f,
+        }
+    }
+
+    /// Returns an SDP session description with a single video media section, whose control URL
+    /// is `control`.
+    pub(super) fn sdp(&self, control: &str) -> String {
+        let (encoding, fmtp) = match self.codec {
+            Codec::H264 => {
+                let profile_level_id = self
+                    .parameter_sets
+                    .iter()
+                    .find(|p| self.nal_type(p) == 7 && p.len() >= 4)
+                    .map(|sps| {
+                        format!(
+                            ";profile-level-id={:02X}{:02X}{:02X}",
+                            sps[1], sps[2], sps[3]
+                        )
+                    })
+                    .unwrap_or_default();
+                (
+                    "H264",
+                    format!(
+                        "packetization-mode=1{profile_level_id};sprop-parameter-sets={},{}",
+                        self.sprop(7),
+                        self.sprop(8)
+                    ),
+                )
+            }
+            Codec::H265 => (
+                "H265",
+                format!(
+                    "sprop-vps={};sprop-sps={};sprop-pps={}",
+                    self.sprop(32),
+                    self.sprop(33),
+                    self.sprop(34)
+                ),
+            ),
+        };
+        format!(
+            "v=0\r\n\
+             o=- 0 0 IN IP4 0.0.0.0\r\n\
+             s=Moonfire NVR\r\n\
+             t=0 0\r\n\
+             m=video 0 RTP/AVP {PAYLOAD_TYPE}\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             a=rtpmap:{PAYLOAD_TYPE} {encoding}/90000\r\n\
+             a=fmtp:{PAYLOAD_TYPE} {fmtp}\r\n\
+             a=control:{control}\r\n"
+        )
+    }
+}
+
+/// Packetizes access units of a single RTP stream.
+pub(super) struct Packetizer {
+    codec: Codec,
+    ssrc: u32,
+    next_seq: u16,
+    timestamp_offset: u32,
+}
+
+impl Packetizer {
+    /// Creates a packetizer. The `ssrc`, initial sequence number, and timestamp offset should be
+    /// random, as recommended by RFC 3550 section 5.1.
+    pub(super) fn new(codec: Codec, ssrc: u32, seq: u16, timestamp_offset: u32) -> Self {
+        Packetizer {
+            codec,
+            ssrc,
+            next_seq: seq,
+            timestamp_offset,
+        }
+    }
+
+    pub(super) fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub(super) fn next_seq(&self) -> u16 {
+        self.next_seq
+    }
+
+    /// Packetizes an access unit, given in AVC (length-prefixed) format, after the NAL units in
+    /// `prefix`. Each packet is appended to `out` with an RTSP interleaved frame header
+    /// (RFC 2326 section 10.12) for `channel`.
+    pub(super) fn packetize(
+        &mut self,
+        pts_90k: i64,
+        prefix: &[Vec<u8>],
+        mut data: &[u8],
+        channel: u8,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut nals: Vec<&[u8]> = prefix.iter().map(Vec::as_slice).collect();
+        while !data.is_empty() {
+            if data.len() < 4 {
+                bail!(InvalidArgument, msg("truncated NAL length"));
+            }
+            let len = BigEndian::read_u32(&data[0..4]) as usize;
+            let nal = data.get(4..4 + len).ok_or_else(|| {
+                err!(
+                    InvalidArgument,
+                    msg(
+                        "NAL length {len} exceeds remaining {} bytes",
+                        data.len() - 4
+                    )
+                )
+            })?;
+            nals.push(nal);
+            data = &data[4 + len..];
+        }
+
+        // The RTP timestamp is deliberately truncated to 32 bits.
+        let timestamp = (pts_90k as u32).wrapping_add(self.timestamp_offset);
+        let header_len = match self.codec {
+            Codec::H264 => 1,
+            Codec::H265 => 2,
+        };
+        for (i, nal) in nals.iter().enumerate() {
+            let last_nal = i == nals.len() - 1;
+            if nal.len() < header_len {
+                bail!(
+                    InvalidArgument,
+                    msg("NAL unit of {} bytes is too short", nal.len())
+                );
+            }
+            if nal.len() <= MAX_PAYLOAD {
+                self.write_packet(channel, last_nal, timestamp, &[], nal, out);
+                continue;
+            }
+            let (payload_header, fu_type) = match self.codec {
+                Codec::H264 => ([(nal[0] & 0xe0) | H264_FU_A, 0], nal[0] & 0x1f),
+                Codec::H265 => (
+                    [(nal[0] & 0x81) | (H265_FU << 1), nal[1]],
+                    (nal[0] >> 1) & 0x3f,
+                ),
+            };
+            let mut chunks = nal[header_len..]
+                .chunks(MAX_PAYLOAD - header_len - 1)
+                .peekable();
+            let mut start = true;
+            while let Some(chunk) = chunks.next() {
+                let end = chunks.peek().is_none();
+                let fu_header = (u8::from(start) << 7) | (u8::from(end) << 6) | fu_type;
+                let mut header = [0; 3];
+                header[..header_len].copy_from_slice(&payload_header[..header_len]);
+                header[header_len] = fu_header;
+                self.write_packet(
+                    channel,
+                    last_nal && end,
+                    timestamp,
+                    &header[..=header_len],
+                    chunk,
+                    out,
+                );
+                start = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_packet(
+        &mut self,
+        channel: u8,
+        marker: bool,
+        timestamp: u32,
+        payload_header: &[u8],
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        let len = 12 + payload_header.len() + payload.len();
+        out.reserve(4 + len);
+        out.push(b'$');
+        out.push(channel);
+        out.write_u16::<BigEndian>(len as u16).expect("Vec write");
+        out.push(0x80); // version 2, no padding, no extension, no CSRCs.
+        out.push((u8::from(marker) << 7) | PAYLOAD_TYPE);
+        out.write_u16::<BigEndian>(self.next_seq)
+            .expect("Vec write");
+        out.write_u32::<BigEndian>(timestamp).expect("Vec write");
+        out.write_u32::<BigEndian>(self.ssrc).expect("Vec write");
+        out.extend_from_slice(payload_header);
+        out.extend_from_slice(payload);
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AVC_DECODER_CONFIG_TEST_INPUT;
+    use db::testutil;
+
+    /// Splits interleaved frames into their channels and RTP packets.
+    fn split(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut packets = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(data[0], b'$');
+            let len = usize::from(BigEndian::read_u16(&data[2..4]));
+            packets.push((data[1], &data[4..4 + len]));
+            data = &data[4 + len..];
+        }
+        packets
+    }
+
+    #[test]
+    fn h264_description() {
+        testutil::init();
+        let e = crate::h264::parse_extra_data(&AVC_DECODER_CONFIG_TEST_INPUT).unwrap();
+        let e = db::VideoSampleEntry {
+            id: 1,
+            width: e.width,
+            height: e.height,
+            pasp_h_spacing: e.pasp_h_spacing,
+            pasp_v_spacing: e.pasp_v_spacing,
+            data: e.data,
+            rfc6381_codec: e.rfc6381_codec,
+        };
+        let d = Description::new(&e).unwrap();
+        assert_eq!(d.codec, Codec::H264);
+        assert_eq!(
+            d.parameter_sets,
+            [
+                &AVC_DECODER_CONFIG_TEST_INPUT[8..31],
+                &AVC_DECODER_CONFIG_TEST_INPUT[34..38]
+            ]
+        );
+        let sdp = d.sdp("trackID=0");
+        assert!(sdp.contains("a=rtpmap:96 H264/90000\r\n"), "{sdp}");
+        assert!(
+            sdp.contains(
+                "a=fmtp:96 packetization-mode=1;profile-level-id=4D001F;\
+                 sprop-parameter-sets=Z00AH5pmAoAt/zUBAQFAAAD6AAAdTAE=,aO48gA==\r\n"
+            ),
+            "{sdp}"
+        );
+        assert!(sdp.ends_with("a=control:trackID=0\r\n"), "{sdp}");
+    }
+
+    #[test]
+    fn h264_packetize() {
+        testutil::init();
+        let mut p = Packetizer::new(Codec::H264, 0x1234_5678, 0xffff, 10);
+
+        // A parameter set and a small NAL unit are each sent in a single packet; a large NAL unit
+        // is fragmented. Only the last packet of the access unit has the marker bit set.
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x00\x00\x00\x02\x06\x05");
+        let big: Vec<u8> = std::iter::once(0x65)
+            .chain((0..2000).map(|i| i as u8))
+            .collect();
+        data.write_u32::<BigEndian>(big.len() as u32).unwrap();
+        data.extend_from_slice(&big);
+        let mut out = Vec::new();
+        p.packetize(90_000, &[b"\x67\x4d".to_vec()], &data, 2, &mut out)
+            .unwrap();
+        let packets = split(&out);
+        assert_eq!(packets.len(), 4);
+        for (i, &(channel, pkt)) in packets.iter().enumerate() {
+            assert_eq!(channel, 2);
+            assert_eq!(pkt[0], 0x80);
+            assert_eq!(pkt[1], (u8::from(i == 3) << 7) | PAYLOAD_TYPE);
+            assert_eq!(
+                BigEndian::read_u16(&pkt[2..4]),
+                0xffffu16.wrapping_add(i as u16)
+            );
+            assert_eq!(BigEndian::read_u32(&pkt[4..8]), 90_010);
+            assert_eq!(BigEndian::read_u32(&pkt[8..12]), 0x1234_5678);
+        }
+        assert_eq!(&packets[0].1[12..], b"\x67\x4d");
+        assert_eq!(&packets[1].1[12..], b"\x06\x05");
+        assert_eq!(&packets[2].1[12..14], &[0x60 | H264_FU_A, 0x80 | 5]);
+        assert_eq!(&packets[3].1[12..14], &[0x60 | H264_FU_A, 0x40 | 5]);
+        let mut reassembled = vec![0x65];
+        reassembled.extend_from_slice(&packets[2].1[14..]);
+        reassembled.extend_from_slice(&packets[3].1[14..]);
+        assert_eq!(reassembled, big);
+        assert_eq!(p.next_seq(), 3);
+
+        p.packetize(0, &[], b"\x00\x00\x00\x05\x65", 0, &mut out)
+            .unwrap_err();
+        p.packetize(0, &[], b"\x00\x00", 0, &mut out).unwrap_err();
+    }
+
+    #[test]
+    fn h265_packetize() {
+        testutil::init();
+        let mut p = Packetizer::new(Codec::H265, 1, 0, 0);
+        let big: Vec<u8> = [0x26, 0x01]
+            .into_iter()
+            .chain((0..1500).map(|i| i as u8))
+            .collect();
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(big.len() as u32).unwrap();
+        data.extend_from_slice(&big);
+        let mut out = Vec::new();
+        p.packetize(0, &[], &data, 0, &mut out).unwrap();
+        let packets = split(&out);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0].1[12..15], &[H265_FU << 1, 0x01, 0x80 | 19]);
+        assert_eq!(&packets[1].1[12..15], &[H265_FU << 1, 0x01, 0x40 | 19]);
+        assert_eq!(packets[0].1[1], PAYLOAD_TYPE);
+        assert_eq!(packets[1].1[1], 0x80 | PAYLOAD_TYPE);
+        let mut reassembled = vec![0x26, 0x01];
+        reassembled.extend_from_slice(&packets[0].1[15..]);
+        reassembled.extend_from_slice(&packets[1].1[15..]);
+        assert_eq!(reassembled, big);
+    }
+}
@@ -0,0 +1,331 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! RTSP over TLS (`rtsps://` URLs) for camera streams.
+//!
+//! Retina speaks only plain RTSP, so a [`Proxy`] listens on a loopback port and forwards each
+//! connection to the camera over TLS. The stream is then opened with a `rtsp://127.0.0.1:<port>/`
+//! URL in place of the configured one. Media must be interleaved on the RTSP connection, so only
+//! the TCP transport is supported. The same proxy (with or without TLS) also serves to decrypt
+//! SRTP media; see [`crate::srtp`].
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{rustls, TlsConnector};
+use tracing::{debug, warn, Instrument};
+use url::Url;
+
+/// The default port of `rtsps://` URLs, as in RFC 7826 section 19.2.
+const DEFAULT_PORT: u16 = 322;
+
+/// The default port of `rtsp://` URLs.
+const DEFAULT_RTSP_PORT: u16 = 554;
+
+/// Returns the TLS client configuration for the given camera.
+///
+/// If the camera has a pinned certificate digest, only that certificate is accepted, regardless
+/// of its issuer, name, or expiration. Otherwise, the camera's CA certificates are trusted if
+/// supplied, or the usual web PKI roots if not.
+pub fn client_config(c: &db::json::CameraConfig) -> Result<Arc<rustls::ClientConfig>, Error> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let config = if !c.rtsps_cert_sha256.is_empty() {
+        let pin = parse_sha256(&c.rtsps_cert_sha256)?;
+        builder
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier(pin)))
+            .with_no_client_auth()
+    } else if !c.rtsps_ca_certs.is_empty() {
+        let certs = rustls_pemfile::certs(&mut c.rtsps_ca_certs.as_bytes()).map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("unable to read rtspsCaCerts"),
+                source(e)
+            )
+        })?;
+        if certs.is_empty() {
+            bail!(InvalidArgument, msg("no certificates in rtspsCaCerts"));
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in certs {
+            roots.add(&rustls::Certificate(cert)).map_err(|e| {
+                err!(
+                    InvalidArgument,
+                    msg("bad certificate in rtspsCaCerts"),
+                    source(e)
+                )
+            })?;
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        hyper_rustls::ConfigBuilderExt::with_webpki_roots(builder).with_no_client_auth()
+    };
+    Ok(Arc::new(config))
+}
+
+/// Parses a SHA-256 digest as hex, optionally with colons between bytes, as printed by
+/// `openssl x509 -noout -fingerprint -sha256`.
+fn parse_sha256(s: &str) -> Result<[u8; 32], Error> {
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b':').collect();
+    let bad = || {
+        err!(
+            InvalidArgument,
+            msg("rtspsCertSha256 {s:?} isn't a hex SHA-256 digest")
+        )
+    };
+    if hex.len() != 64 {
+        return Err(bad());
+    }
+    let mut out = [0u8; 32];
+    for (o, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| bad())?;
+        *o = u8::from_str_radix(pair, 16).map_err(|_| bad())?;
+    }
+    Ok(out)
+}
+
+/// Accepts only the server certificate with the given SHA-256 digest.
+struct PinnedVerifier([u8; 32]);
+
+impl rustls::client::ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        if digest.as_ref() != self.0 {
+            warn!(
+                "camera certificate has SHA-256 {}, not the pinned digest",
+                base::strutil::hex(digest.as_ref())
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// A connection to the camera, with or without TLS.
+pub trait Conn: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Conn for T {}
+
+/// Where a [`Proxy`] or [`connect`] connects.
+#[derive(Clone)]
+struct Upstream {
+    /// The TLS connector and server name, for `rtsps://` URLs.
+    tls: Option<(TlsConnector, rustls::ServerName)>,
+    host: String,
+    port: u16,
+
+    /// If true, decrypt SRTP via [`crate::srtp::forward`].
+    srtp: bool,
+}
+
+impl Upstream {
+    /// Returns where to connect for `url`, which must be a `rtsp://` or `rtsps://` URL. The
+    /// latter requires `tls`.
+    fn new(url: &Url, tls: Option<Arc<rustls::ClientConfig>>, srtp: bool) -> Result<Self, Error> {
+        let (tls, default_port) = match url.scheme() {
+            "rtsp" => (None, DEFAULT_RTSP_PORT),
+            "rtsps" => match tls {
+                Some(tls) => (Some(TlsConnector::from(tls)), DEFAULT_PORT),
+                None => bail!(InvalidArgument, msg("no TLS configuration for {url}")),
+            },
+            _ => bail!(InvalidArgument, msg("{url} isn't a rtsp or rtsps URL")),
+        };
+        let (host, server_name) = match url.host() {
+            Some(url::Host::Domain(d)) => (
+                d.to_owned(),
+                rustls::ServerName::try_from(d)
+                    .map_err(|e| err!(InvalidArgument, msg("bad host in {url}"), source(e)))?,
+            ),
+            Some(url::Host::Ipv4(a)) => (a.to_string(), rustls::ServerName::IpAddress(a.into())),
+            Some(url::Host::Ipv6(a)) => (a.to_string(), rustls::ServerName::IpAddress(a.into())),
+            None => bail!(InvalidArgument, msg("{url} has no host")),
+        };
+        Ok(Upstream {
+            tls: tls.map(|c| (c, server_name)),
+            host,
+            port: url.port().unwrap_or(default_port),
+            srtp,
+        })
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Conn>, Error> {
+        let (host, port) = (&self.host, self.port);
+        let tcp = TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| err!(e, msg("unable to connect to {host}:{port}")))?;
+        tcp.set_nodelay(true)
+            .map_err(|e| err!(e, msg("unable to set TCP_NODELAY")))?;
+        let Some((connector, server_name)) = &self.tls else {
+            return Ok(Box::new(tcp));
+        };
+        let tls = connector
+            .connect(server_name.clone(), tcp)
+            .await
+            .map_err(|e| err!(e, msg("TLS handshake with {host}:{port} failed")))?;
+        Ok(Box::new(tls))
+    }
+}
+
+/// Opens a connection to the server of `url`, which must be a `rtsp://` or `rtsps://` URL. The
+/// latter requires `tls`.
+///
+/// This is for RTSP sessions Moonfire NVR conducts itself rather than through Retina, such as
+/// the ONVIF audio backchannel.
+pub async fn connect(
+    url: &Url,
+    tls: Option<Arc<rustls::ClientConfig>>,
+) -> Result<Box<dyn Conn>, Error> {
+    Upstream::new(url, tls, false)?.connect().await
+}
+
+/// A loopback listener which forwards connections to a stream URL's server, adding TLS for
+/// `rtsps://` URLs and/or decrypting SRTP.
+///
+/// It stops accepting connections when dropped. Connections already accepted continue until
+/// either side closes them, so Retina can finish tearing down the session.
+pub struct Proxy {
+    url: Url,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Proxy {
+    /// Starts a proxy for `url`, which must be a `rtsp://` or `rtsps://` URL. The latter
+    /// requires `tls`.
+    ///
+    /// This makes the first connection before returning, so connection and certificate
+    /// problems are reported here rather than as an unexplained closed connection.
+    pub async fn start(
+        url: &Url,
+        tls: Option<Arc<rustls::ClientConfig>>,
+        srtp: bool,
+    ) -> Result<Self, Error> {
+        let upstream = Upstream::new(url, tls, srtp)?;
+        let first = upstream.connect().await?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(|e| err!(e, msg("unable to bind loopback listener")))?;
+        let local = listener
+            .local_addr()
+            .map_err(|e| err!(e, msg("unable to get loopback listener address")))?;
+        let mut proxy_url = url.clone();
+        proxy_url
+            .set_scheme("rtsp")
+            .expect("rtsps to rtsp scheme change is allowed");
+        proxy_url
+            .set_ip_host(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .expect("rtsp URLs can have IP hosts");
+        proxy_url
+            .set_port(Some(local.port()))
+            .expect("rtsp URLs can have ports");
+        let task = tokio::spawn(accept(listener, upstream, first).in_current_span());
+        Ok(Proxy {
+            url: proxy_url,
+            task,
+        })
+    }
+
+    /// Returns the `rtsp://` URL to use in place of the original.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Accepts loopback connections, forwarding each to the upstream server.
+///
+/// The first uses the already-established `first` connection; later ones (as when Retina
+/// reconnects to tear down a session) make their own.
+async fn accept(listener: TcpListener, upstream: Upstream, first: Box<dyn Conn>) {
+    let mut first = Some(first);
+    loop {
+        let plain = match listener.accept().await {
+            Ok((plain, _)) => plain,
+            Err(err) => {
+                warn!(%err, "unable to accept proxy connection");
+                return;
+            }
+        };
+        let first = first.take();
+        let upstream = upstream.clone();
+        tokio::spawn(
+            async move {
+                let conn = match first {
+                    Some(conn) => conn,
+                    None => match upstream.connect().await {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            warn!(%err, "unable to open proxied connection");
+                            return;
+                        }
+                    },
+                };
+                if let Err(err) = forward(plain, conn, upstream.srtp).await {
+                    debug!(%err, "proxy connection failed");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+async fn forward(mut plain: TcpStream, mut conn: Box<dyn Conn>, srtp: bool) -> Result<(), Error> {
+    plain
+        .set_nodelay(true)
+        .map_err(|e| err!(e, msg("unable to set TCP_NODELAY")))?;
+    if srtp {
+        return crate::srtp::forward(plain, conn).await;
+    }
+    tokio::io::copy_bidirectional(&mut plain, &mut conn)
+        .await
+        .map_err(|e| err!(e, msg("unable to forward proxied connection")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256() {
+        let hex = "5e:88:48:98:da:28:04:71:51:d0:e5:6f:8d:c6:29:27:73:60:3d:0d:6a:ab:bd:d6:2a:11:\
+                   ef:72:1d:15:42:d8";
+        let parsed = parse_sha256(hex).unwrap();
+        assert_eq!(parsed[0], 0x5e);
+        assert_eq!(parsed[31], 0xd8);
+        assert_eq!(parse_sha256(&hex.replace(':', "")).unwrap(), parsed);
+        assert_eq!(
+            parse_sha256(&hex.to_uppercase()).unwrap(),
+            parsed,
+            "hex digits are case-insensitive"
+        );
+        parse_sha256("5e88").unwrap_err();
+        parse_sha256(&"zz".repeat(32)).unwrap_err();
+    }
+
+    #[test]
+    fn config() {
+        let mut c = db::json::CameraConfig::default();
+        client_config(&c).unwrap();
+        c.rtsps_ca_certs = "not a certificate".to_owned();
+        client_config(&c).unwrap_err();
+        c.rtsps_cert_sha256 = "00".repeat(32);
+        client_config(&c).unwrap(); // the pin takes precedence.
+    }
+}
@@ -0,0 +1,124 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Holding signal states set from external sources.
+//!
+//! Sources such as ONVIF events, MQTT messages, and GPIO lines set a signal's state only a short
+//! time ([`HOLD`]) into the future, and extend it while the source remains healthy. If the source
+//! becomes unreachable or Moonfire NVR exits, the signal lapses to unknown rather than claiming a
+//! stale state indefinitely. Extensions are made only once they're worth at least
+//! [`MIN_EXTENSION`], to limit database updates.
+
+use std::ops::Range;
+
+use db::recording::{self, TIME_UNITS_PER_SEC};
+
+/// How far into the future signal states are set.
+pub(crate) const HOLD: recording::Duration = recording::Duration(30 * TIME_UNITS_PER_SEC);
+
+/// The minimum extension of a continuing state.
+pub(crate) const MIN_EXTENSION: recording::Duration = recording::Duration(15 * TIME_UNITS_PER_SEC);
+
+/// The state most recently set for a single signal, and the end of its range.
+#[derive(Debug)]
+pub(crate) struct Holder<S = u16> {
+    hold: recording::Duration,
+    min_extension: recording::Duration,
+
+    /// The state most recently set and the end of its range, if any.
+    held: Option<(S, recording::Time)>,
+}
+
+impl<S: Copy + PartialEq> Default for Holder<S> {
+    fn default() -> Self {
+        Self::with_durations(HOLD, MIN_EXTENSION)
+    }
+}
+
+impl<S: Copy + PartialEq> Holder<S> {
+    /// Returns a holder with the given durations rather than [`HOLD`] and [`MIN_EXTENSION`].
+    pub(crate) fn with_durations(
+        hold: recording::Duration,
+        min_extension: recording::Duration,
+    ) -> Self {
+        Holder {
+            hold,
+            min_extension,
+            held: None,
+        }
+    }
+
+    /// Returns the state most recently set, if any.
+    pub(crate) fn state(&self) -> Option<S> {
+        self.held.map(|(s, _)| s)
+    }
+
+    /// Notes that the signal is in `state` as of `now`, returning a range to set if appropriate.
+    ///
+    /// A changed state takes effect from `start`, which may be before `now` (as when a state was
+    /// debounced). An unchanged state is extended as in [`Holder::extend`].
+    pub(crate) fn set(
+        &mut self,
+        start: recording::Time,
+        now: recording::Time,
+        state: S,
+    ) -> Option<Range<recording::Time>> {
+        if self.state() == Some(state) {
+            return self.extend(now).map(|(r, _)| r);
+        }
+        let end = now + self.hold;
+        self.held = Some((state, end));
+        Some(start..end)
+    }
+
+    /// Returns an extension of the held state as of `now`, if any and worth an update. If the
+    /// previous range has already lapsed, the new one starts at `now`.
+    pub(crate) fn extend(&mut self, now: recording::Time) -> Option<(Range<recording::Time>, S)> {
+        let (state, until) = self.held.as_mut()?;
+        let end = now + self.hold;
+        let start = match *until {
+            u if u >= now && end - u < self.min_extension => return None,
+            u if u >= now => u,
+            _ => now,
+        };
+        *until = end;
+        Some((start..end, *state))
+    }
+
+    /// Forgets the held state, as when the source is lost. It lapses at the end of its range.
+    pub(crate) fn reset(&mut self) {
+        self.held = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn hold() {
+        testutil::init();
+        let sec = |s: i64| recording::Time(s * TIME_UNITS_PER_SEC);
+        let mut h = Holder::default();
+        assert_eq!(h.extend(sec(0)), None);
+        assert_eq!(h.set(sec(0), sec(1), 2), Some(sec(0)..sec(1) + HOLD));
+        assert_eq!(h.state(), Some(2));
+
+        // Continuing states aren't worth an update until they extend the range enough.
+        assert_eq!(h.set(sec(2), sec(2), 2), None);
+        assert_eq!(h.extend(sec(15)), None);
+        assert_eq!(h.extend(sec(16)), Some((sec(1) + HOLD..sec(16) + HOLD, 2)));
+
+        // A change takes effect immediately.
+        assert_eq!(h.set(sec(17), sec(17), 1), Some(sec(17)..sec(17) + HOLD));
+
+        // After the range lapses, an extension starts afresh.
+        assert_eq!(h.extend(sec(100)), Some((sec(100)..sec(100) + HOLD, 1)));
+
+        h.reset();
+        assert_eq!(h.state(), None);
+        assert_eq!(h.extend(sec(101)), None);
+    }
+}
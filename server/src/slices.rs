@@ -5,11 +5,16 @@
 //! Tools for implementing a `http_serve::Entity` body composed from many "slices".
 
 use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
 use std::ops::Range;
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use crate::body::{wrap_error, BoxedError};
 use base::{bail, err, Error};
+use db::dir::SampleFileDir;
+use db::CompositeId;
 use futures::{stream, stream::StreamExt, Stream};
 use tracing_futures::Instrument;
 
@@ -34,9 +39,92 @@ pub trait Slice: fmt::Debug + Sized + Sync + 'static {
         len: u64,
     ) -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Sync + Send>;
 
+    /// Returns where the bytes indicated by `r` (relative to this slice's start) are stored
+    /// verbatim on disk, if they are. Such bytes can be copied by the kernel; see
+    /// [`Slices::get_parts`].
+    fn file_range(&self, _ctx: &Self::Ctx, _r: Range<u64>) -> Option<FileRange> {
+        None
+    }
+
     fn get_slices(ctx: &Self::Ctx) -> &Slices<Self>;
 }
 
+/// A byte range of an unencrypted sample file.
+#[derive(Debug)]
+pub struct FileRange {
+    pub dir: Arc<SampleFileDir>,
+    pub composite_id: CompositeId,
+    pub range: Range<u64>,
+}
+
+impl FileRange {
+    /// Appends this range to `out` at its current position.
+    ///
+    /// This uses `copy_file_range`, so the bytes needn't pass through userspace, falling back to
+    /// an ordinary copy when the kernel can't copy between the two filesystems. It blocks, so
+    /// async callers should use `tokio::task::spawn_blocking`.
+    pub fn copy_to(&self, out: &std::fs::File) -> Result<(), Error> {
+        let mut f = self
+            .dir
+            .open_raw_file(self.composite_id)
+            .map_err(|e| err!(e, msg("unable to open sample file {}", self.composite_id)))?;
+        let mut off_in = libc::loff_t::try_from(self.range.start).unwrap();
+        let mut remaining = self.range.end - self.range.start;
+        while remaining > 0 {
+            let len = usize::try_from(remaining).unwrap_or(usize::MAX);
+
+            // SAFETY: both file descriptors are open for the duration of the call. A null
+            // `off_out` means to use and advance `out`'s file position.
+            let n = unsafe {
+                libc::copy_file_range(
+                    f.as_raw_fd(),
+                    &mut off_in,
+                    out.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    len,
+                    0,
+                )
+            };
+            match n {
+                -1 => {
+                    let e = std::io::Error::last_os_error();
+                    match e.raw_os_error() {
+                        Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL) => {
+                            f.seek(SeekFrom::Start(off_in as u64))?;
+                            let copied = std::io::copy(&mut (&mut f).take(remaining), &mut &*out)?;
+                            remaining -= copied;
+                            break;
+                        }
+                        _ => return Err(err!(e, msg("unable to copy {}", self.composite_id))),
+                    }
+                }
+                0 => break,
+                n => remaining -= n as u64,
+            }
+        }
+        if remaining > 0 {
+            bail!(
+                OutOfRange,
+                msg(
+                    "sample file {} ended {remaining} bytes short of {:?}",
+                    self.composite_id,
+                    self.range
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A part of a [`Slices`] as returned by [`Slices::get_parts`].
+pub enum Part<C> {
+    /// Bytes produced in memory.
+    Chunks(Pin<Box<dyn Stream<Item = Result<C, BoxedError>> + Sync + Send>>),
+
+    /// Bytes to copy from a sample file.
+    File(FileRange),
+}
+
 /// Helper to serve byte ranges from a body which is broken down into many "slices".
 /// This is used to implement `.mp4` serving in `mp4::File` from `mp4::Slice` enums.
 pub struct Slices<S>
@@ -126,6 +214,48 @@ where
         self.slices.len()
     }
 
+    /// Returns the index of the first slice containing `pos` and that slice's starting position.
+    fn first_slice(&self, pos: u64) -> (usize, u64) {
+        match self.slices.binary_search_by_key(&pos, |s| s.end()) {
+            Ok(i) => (i + 1, self.slices[i].end()), // desired start == slice i's end; first is i+1!
+            Err(0) => (0, 0),                       // desired start < slice 0's end; first is 0.
+            Err(i) => (i, self.slices[i - 1].end()), // desired start < slice i's end; first is i.
+        }
+    }
+
+    /// Returns the parts of `range`, in order.
+    ///
+    /// This is for writing into files, where [`FileRange::copy_to`] avoids copying sample data
+    /// through userspace. HTTP responses use `get_range` instead, as hyper needs a stream of
+    /// chunks; those avoid the copy on plain connections as described in [`crate::body`].
+    pub fn get_parts<'a>(
+        &'a self,
+        ctx: &'a S::Ctx,
+        range: Range<u64>,
+    ) -> Result<impl Iterator<Item = Part<S::Chunk>> + 'a, Error> {
+        if range.start > range.end || range.end > self.len {
+            bail!(
+                Internal,
+                msg("bad range {:?} for slice of length {}", range, self.len),
+            );
+        }
+        let (i, mut slice_start) = self.first_slice(range.start);
+        let mut start_pos = range.start - slice_start;
+        Ok(self.slices[i..].iter().map_while(move |s| {
+            if range.end == slice_start + start_pos {
+                return None;
+            }
+            let s_end = s.end();
+            let r = start_pos..std::cmp::min(range.end, s_end) - slice_start;
+            let part = match s.file_range(ctx, r.clone()) {
+                Some(f) => Part::File(f),
+                None => Part::Chunks(Pin::from(s.get_range(ctx, r, s_end - slice_start))),
+            };
+            (slice_start, start_pos) = (s_end, 0);
+            Some(part)
+        }))
+    }
+
     /// Writes `range` to `out`.
     /// This interface mirrors `http_serve::Entity::write_to`, with the additional `ctx` argument.
     pub fn get_range(
@@ -143,11 +273,7 @@ where
 
         // Binary search for the first slice of the range to write, determining its index and
         // (from the preceding slice) the start of its range.
-        let (i, slice_start) = match self.slices.binary_search_by_key(&range.start, |s| s.end()) {
-            Ok(i) => (i + 1, self.slices[i].end()), // desired start == slice i's end; first is i+1!
-            Err(0) => (0, 0),                       // desired start < slice 0's end; first is 0.
-            Err(i) => (i, self.slices[i - 1].end()), // desired start < slice i's end; first is i.
-        };
+        let (i, slice_start) = self.first_slice(range.start);
 
         // Iterate through and write each slice until the end.
 
@@ -179,7 +305,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{Slice, Slices};
+    use super::{Part, Slice, Slices};
     use crate::body::BoxedError;
     use db::testutil;
     use futures::stream::{self, Stream, TryStreamExt};
@@ -315,6 +441,23 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    pub async fn parts() {
+        // FakeSlices have no file ranges, so each part is the slice's chunks.
+        testutil::init();
+        let slices = slices();
+        let mut out = Vec::new();
+        for p in slices.get_parts(&slices, 17..26).unwrap() {
+            match p {
+                Part::Chunks(c) => out.extend(c.try_collect::<Vec<_>>().await.unwrap()),
+                Part::File(f) => panic!("unexpected {f:?}"),
+            }
+        }
+        assert_eq!(out, get_range(17..26).await);
+        assert_eq!(slices.get_parts(&slices, 61..61).unwrap().count(), 0);
+        assert!(slices.get_parts(&slices, 0..62).is_err());
+    }
+
     #[tokio::test]
     pub async fn at_end() {
         testutil::init();
@@ -0,0 +1,582 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! SRTP media decryption, for cameras which protect their media with SRTP (as allowed by ONVIF
+//! Profile T).
+//!
+//! Retina supports only plain RTP, so such streams go through a [`crate::rtsps::Proxy`], which
+//! filters the RTSP connection with [`forward`]:
+//!
+//! *   In the `DESCRIBE` response's SDP, it takes each medium's SDES key from its `a=crypto`
+//!     attribute (RFC 4568), drops those attributes, and changes the `RTP/SAVP` profile to
+//!     `RTP/AVP`.
+//! *   It changes the profile in `SETUP` requests' `Transport` headers back to `RTP/SAVP`, and
+//!     in their responses to `RTP/AVP`.
+//! *   It decrypts SRTP and SRTCP packets on the interleaved channels assigned by `SETUP`.
+//!
+//! Only the `AES_CM_128_HMAC_SHA1_80` crypto suite is supported. SDES keys are visible to
+//! anyone who can observe the RTSP connection, so this should be combined with a `rtsps://`
+//! URL.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base::{err, Error};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, warn};
+use webrtc::srtp::context::Context;
+use webrtc::srtp::protection_profile::ProtectionProfile;
+
+use crate::rtsp::message::{self, interleaved, Message, Rtsp};
+
+/// The only supported crypto suite.
+const SUITE: &str = "AES_CM_128_HMAC_SHA1_80";
+
+const MASTER_KEY_LEN: usize = 16;
+const MASTER_SALT_LEN: usize = 14;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Key {
+    master_key: [u8; MASTER_KEY_LEN],
+    master_salt: [u8; MASTER_SALT_LEN],
+}
+
+/// A medium from the session description.
+#[derive(Debug, PartialEq, Eq)]
+struct Medium {
+    /// The `a=control` attribute value, which identifies the medium in `SETUP` requests.
+    control: String,
+
+    /// The SDES key, if the medium uses SRTP with a supported crypto suite.
+    key: Option<Key>,
+}
+
+/// Parses the value of an `a=crypto` attribute, returning `None` if it's unsupported.
+fn parse_crypto(value: &str) -> Option<Key> {
+    let mut parts = value.split_ascii_whitespace();
+    let _tag = parts.next()?;
+    if parts.next()? != SUITE {
+        return None;
+    }
+
+    // Use the first key. Lifetime is ignored; keys with a master key identifier (MKI) are
+    // unsupported.
+    let key_params = parts.next()?.split(';').next()?;
+    let mut fields = key_params.strip_prefix("inline:")?.split('|');
+    let raw = STANDARD.decode(fields.next()?).ok()?;
+    if fields.any(|f| f.contains(':')) || raw.len() != MASTER_KEY_LEN + MASTER_SALT_LEN {
+        return None;
+    }
+    let (master_key, master_salt) = raw.split_at(MASTER_KEY_LEN);
+    Some(Key {
+        master_key: master_key.try_into().unwrap(),
+        master_salt: master_salt.try_into().unwrap(),
+    })
+}
+
+/// Rewrites an SDP session description for Retina, returning it along with the media.
+///
+/// Media with a supported key are changed to the `RTP/AVP` profile and lose their `a=crypto`
+/// attributes; others are left as-is.
+fn rewrite_sdp(sdp: &str) -> (String, Vec<Medium>) {
+    struct Lines<'a> {
+        m_line: Option<&'a str>,
+        attrs: Vec<&'a str>,
+    }
+    let mut sections = vec![Lines {
+        m_line: None,
+        attrs: Vec::new(),
+    }];
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            sections.push(Lines {
+                m_line: Some(line),
+                attrs: Vec::new(),
+            });
+        } else {
+            sections.last_mut().unwrap().attrs.push(line);
+        }
+    }
+    let mut out = String::with_capacity(sdp.len());
+    let mut media = Vec::new();
+    for s in &sections {
+        let Some(m_line) = s.m_line else {
+            for a in &s.attrs {
+                out.push_str(a);
+                out.push_str("\r\n");
+            }
+            continue;
+        };
+        let control = s
+            .attrs
+            .iter()
+            .find_map(|a| a.strip_prefix("a=control:"))
+            .unwrap_or_default();
+        let cryptos: Vec<_> = s
+            .attrs
+            .iter()
+            .filter_map(|a| a.strip_prefix("a=crypto:"))
+            .collect();
+        let key = cryptos.iter().find_map(|c| parse_crypto(c));
+        if key.is_none() && !cryptos.is_empty() {
+            warn!("SRTP medium {control:?} has no supported key; need {SUITE} without MKI");
+        }
+        let rewrite = key.is_some() && m_line.contains(" RTP/SAVP ");
+        if rewrite {
+            out.push_str(&m_line.replacen(" RTP/SAVP ", " RTP/AVP ", 1));
+        } else {
+            out.push_str(m_line);
+        }
+        out.push_str("\r\n");
+        for a in &s.attrs {
+            if rewrite && a.starts_with("a=crypto:") {
+                continue;
+            }
+            out.push_str(a);
+            out.push_str("\r\n");
+        }
+        media.push(Medium {
+            control: control.to_owned(),
+            key: if rewrite { key } else { None },
+        });
+    }
+    (out, media)
+}
+
+/// Returns the index of the medium set up by a `SETUP` request for `url`.
+fn medium_for(media: &[Medium], url: &str) -> Option<usize> {
+    let path = |u: &str| {
+        url::Url::parse(u)
+            .ok()
+            .map(|u| u[url::Position::BeforePath..].to_owned())
+    };
+    let req = path(url)?;
+    let i = media.iter().position(|m| match path(&m.control) {
+        Some(control) => control == req, // absolute.
+        None => {
+            !m.control.is_empty()
+                && req
+                    .strip_suffix(m.control.as_str())
+                    .is_some_and(|base| base.ends_with('/'))
+        }
+    });
+    i.or_else(|| (media.len() == 1).then_some(0))
+}
+
+/// Changes the profile of each transport spec in a `Transport` header value from `from` to `to`.
+fn set_profile(transport: &str, from: &str, to: &str) -> String {
+    transport
+        .split(',')
+        .map(|spec| {
+            let spec = spec.trim();
+            let proto_end = spec.find(';').unwrap_or(spec.len());
+            let proto = &spec[..proto_end];
+            match proto.strip_prefix(from) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    format!("{to}{rest}{}", &spec[proto_end..])
+                }
+                _ => spec.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// State shared between the two directions of a connection.
+#[derive(Default)]
+struct State {
+    /// Media from the most recent session description.
+    media: Vec<Medium>,
+
+    /// The medium of each `SETUP` request awaiting a response, by `CSeq`.
+    setups: HashMap<String, usize>,
+
+    contexts: Vec<Context>,
+
+    /// The index within `contexts` and whether it's RTCP, by interleaved channel.
+    channels: HashMap<u8, (usize, bool)>,
+}
+
+impl State {
+    /// Rewrites a request from Retina to the camera.
+    fn request(&mut self, req: &mut Rtsp) {
+        let i = {
+            let mut parts = req.start.split(' ');
+            let (Some("SETUP"), Some(url)) = (parts.next(), parts.next()) else {
+                return;
+            };
+            match medium_for(&self.media, url) {
+                Some(i) if self.media[i].key.is_some() => i,
+                _ => return,
+            }
+        };
+        if let Some(t) = req.header_mut("Transport") {
+            *t = set_profile(t, "RTP/AVP", "RTP/SAVP");
+        }
+        if let Some(cseq) = req.cseq() {
+            self.setups.insert(cseq.to_owned(), i);
+        }
+    }
+
+    /// Rewrites a message from the camera to Retina, or returns `None` to drop it.
+    fn response(&mut self, m: Message) -> Option<Message> {
+        let mut resp = match m {
+            Message::Data { channel, payload } => {
+                let Some(&(i, rtcp)) = self.channels.get(&channel) else {
+                    return Some(Message::Data { channel, payload });
+                };
+                let ctx = &mut self.contexts[i];
+                let result = if rtcp {
+                    ctx.decrypt_rtcp(&payload)
+                } else {
+                    ctx.decrypt_rtp(&payload)
+                };
+                return match result {
+                    Ok(payload) => Some(Message::Data { channel, payload }),
+                    Err(err) => {
+                        debug!(%err, channel, "dropping packet which failed decryption");
+                        None
+                    }
+                };
+            }
+            Message::Rtsp(resp) => resp,
+        };
+        let Some(status) = resp.status() else {
+            return Some(Message::Rtsp(resp)); // a request from the camera.
+        };
+        let setup = resp.cseq().and_then(|c| self.setups.remove(c));
+        if status != 200 {
+            return Some(Message::Rtsp(resp));
+        }
+        let is_sdp = resp
+            .header("Content-Type")
+            .is_some_and(|t| t.starts_with("application/sdp"));
+        if is_sdp {
+            if let Ok(sdp) = std::str::from_utf8(&resp.body) {
+                let (sdp, media) = rewrite_sdp(sdp);
+                if media.iter().any(|m| m.key.is_some()) {
+                    resp.set_body(sdp.into());
+                }
+                self.media = media;
+            }
+        }
+        if let Some(i) = setup {
+            self.setup(i, &mut resp);
+        }
+        Some(Message::Rtsp(resp))
+    }
+
+    /// Handles a successful `SETUP` response for an SRTP medium.
+    fn setup(&mut self, i: usize, resp: &mut Rtsp) {
+        let Some(key) = self.media[i].key.clone() else {
+            return;
+        };
+        let Some(t) = resp.header_mut("Transport") else {
+            warn!("SETUP response has no Transport header");
+            return;
+        };
+        *t = set_profile(t, "RTP/SAVP", "RTP/AVP");
+        let Some((rtp, rtcp)) = interleaved(t) else {
+            warn!("SETUP response has no interleaved channels: {t:?}");
+            return;
+        };
+        let ctx = match Context::new(
+            &key.master_key,
+            &key.master_salt,
+            ProtectionProfile::Aes128CmHmacSha1_80,
+            None,
+            None,
+        ) {
+            Ok(c) => c,
+            Err(err) => {
+                warn!(%err, "unable to create SRTP context");
+                return;
+            }
+        };
+        self.contexts.push(ctx);
+        let c = self.contexts.len() - 1;
+        self.channels.insert(rtp, (c, false));
+        self.channels.insert(rtcp, (c, true));
+    }
+}
+
+/// Forwards a connection between Retina (`client`) and the camera (`server`), decrypting SRTP
+/// as described in the module documentation.
+pub async fn forward<C, S>(client: C, server: S) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client_r, client_w) = tokio::io::split(client);
+    let (server_r, server_w) = tokio::io::split(server);
+    let state = Mutex::new(State::default());
+    tokio::try_join!(
+        requests(BufReader::new(client_r), server_w, &state),
+        responses(BufReader::new(server_r), client_w, &state),
+    )?;
+    Ok(())
+}
+
+async fn requests<R, W>(mut r: BufReader<R>, mut w: W, state: &Mutex<State>) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(mut m) = message::read(&mut r).await? {
+        if let Message::Rtsp(req) = &mut m {
+            state.lock().unwrap().request(req);
+        }
+        w.write_all(&message::encode(&m))
+            .await
+            .map_err(|e| err!(e, msg("unable to write to camera")))?;
+    }
+    w.shutdown()
+        .await
+        .map_err(|e| err!(e, msg("unable to shut down camera connection")))
+}
+
+async fn responses<R, W>(mut r: BufReader<R>, mut w: W, state: &Mutex<State>) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(m) = message::read(&mut r).await? {
+        let m = state.lock().unwrap().response(m);
+        let Some(m) = m else {
+            continue;
+        };
+        w.write_all(&message::encode(&m))
+            .await
+            .map_err(|e| err!(e, msg("unable to write to client")))?;
+    }
+    w.shutdown()
+        .await
+        .map_err(|e| err!(e, msg("unable to shut down client connection")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use db::testutil;
+
+    const KEY: &str = "WVNfX19zZW1jdGwgKCkgewkyMjA7fQp9CnVubGVz";
+
+    const SDP: &str = "v=0\r\n\
+                       o=- 1 1 IN IP4 192.168.5.10\r\n\
+                       s=Session\r\n\
+                       t=0 0\r\n\
+                       m=video 0 RTP/SAVP 96\r\n\
+                       a=rtpmap:96 H264/90000\r\n\
+                       a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:WVNfX19zZW1jdGwgKCkgewkyMjA7fQp9CnVubGVz|2^31\r\n\
+                       a=control:trackID=1\r\n\
+                       m=audio 0 RTP/AVP 0\r\n\
+                       a=control:trackID=2\r\n";
+
+    fn key() -> Key {
+        let raw = STANDARD.decode(KEY).unwrap();
+        Key {
+            master_key: raw[..16].try_into().unwrap(),
+            master_salt: raw[16..].try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn crypto() {
+        assert_eq!(
+            parse_crypto(&format!("1 {SUITE} inline:{KEY}")),
+            Some(key())
+        );
+        assert_eq!(
+            parse_crypto(&format!("1 {SUITE} inline:{KEY}|2^20")),
+            Some(key())
+        );
+        assert_eq!(
+            parse_crypto(&format!("1 {SUITE} inline:{KEY}|2^20|1:4")),
+            None
+        );
+        assert_eq!(
+            parse_crypto(&format!("1 AES_256_CM_HMAC_SHA1_80 inline:{KEY}")),
+            None
+        );
+        assert_eq!(parse_crypto(&format!("1 {SUITE} inline:AAAA")), None);
+    }
+
+    #[test]
+    fn sdp() {
+        let (sdp, media) = rewrite_sdp(SDP);
+        assert_eq!(
+            media,
+            [
+                Medium {
+                    control: "trackID=1".to_owned(),
+                    key: Some(key()),
+                },
+                Medium {
+                    control: "trackID=2".to_owned(),
+                    key: None,
+                },
+            ]
+        );
+        assert!(sdp.contains("m=video 0 RTP/AVP 96\r\n"));
+        assert!(!sdp.contains("a=crypto"));
+        assert!(sdp.contains("m=audio 0 RTP/AVP 0\r\na=control:trackID=2\r\n"));
+    }
+
+    #[test]
+    fn setup_url() {
+        let (_, media) = rewrite_sdp(SDP);
+        assert_eq!(
+            medium_for(&media, "rtsp://127.0.0.1:1234/live/trackID=1"),
+            Some(0)
+        );
+        assert_eq!(
+            medium_for(&media, "rtsp://127.0.0.1:1234/live/trackID=2"),
+            Some(1)
+        );
+        assert_eq!(
+            medium_for(&media, "rtsp://127.0.0.1:1234/live/xtrackID=1"),
+            None
+        );
+        let absolute = [
+            Medium {
+                control: "rtsp://192.168.5.10/live/video".to_owned(),
+                key: None,
+            },
+            Medium {
+                control: "rtsp://192.168.5.10/live/audio".to_owned(),
+                key: None,
+            },
+        ];
+        assert_eq!(
+            medium_for(&absolute, "rtsp://127.0.0.1:1234/live/audio"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn transport() {
+        assert_eq!(
+            set_profile("RTP/AVP/TCP;unicast;interleaved=0-1", "RTP/AVP", "RTP/SAVP"),
+            "RTP/SAVP/TCP;unicast;interleaved=0-1"
+        );
+        assert_eq!(
+            set_profile("RTP/AVPF;unicast", "RTP/AVP", "RTP/SAVP"),
+            "RTP/AVPF;unicast"
+        );
+    }
+
+    /// Runs a describe/setup exchange and an SRTP packet through [`forward`].
+    #[tokio::test]
+    async fn forward_connection() {
+        testutil::init();
+        let (client, client_peer) = tokio::io::duplex(4096);
+        let (server, server_peer) = tokio::io::duplex(4096);
+        let fwd = tokio::spawn(forward(client_peer, server_peer));
+        let mut client = BufReader::new(client);
+        let mut server = BufReader::new(server);
+
+        let request = |start: &str, headers: &[(&str, &str)]| {
+            Message::Rtsp(Rtsp {
+                start: start.to_owned(),
+                headers: headers
+                    .iter()
+                    .map(|&(n, v)| (n.to_owned(), v.to_owned()))
+                    .collect(),
+                body: Bytes::new(),
+            })
+        };
+        let send = |m: &Message| message::encode(m);
+
+        // DESCRIBE.
+        let describe = request(
+            "DESCRIBE rtsp://127.0.0.1:1234/live RTSP/1.0",
+            &[("CSeq", "1")],
+        );
+        client.write_all(&send(&describe)).await.unwrap();
+        assert_eq!(message::read(&mut server).await.unwrap(), Some(describe));
+        let mut resp = Rtsp {
+            start: "RTSP/1.0 200 OK".to_owned(),
+            headers: vec![
+                ("CSeq".to_owned(), "1".to_owned()),
+                ("Content-Type".to_owned(), "application/sdp".to_owned()),
+            ],
+            body: Bytes::new(),
+        };
+        resp.set_body(Bytes::from_static(SDP.as_bytes()));
+        server.write_all(&send(&Message::Rtsp(resp))).await.unwrap();
+        let Some(Message::Rtsp(resp)) = message::read(&mut client).await.unwrap() else {
+            panic!("expected DESCRIBE response");
+        };
+        assert_eq!(resp.body, rewrite_sdp(SDP).0.as_bytes());
+
+        // SETUP.
+        client
+            .write_all(&send(&request(
+                "SETUP rtsp://127.0.0.1:1234/live/trackID=1 RTSP/1.0",
+                &[
+                    ("CSeq", "2"),
+                    ("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1"),
+                ],
+            )))
+            .await
+            .unwrap();
+        let Some(Message::Rtsp(req)) = message::read(&mut server).await.unwrap() else {
+            panic!("expected SETUP request");
+        };
+        assert_eq!(
+            req.header("Transport"),
+            Some("RTP/SAVP/TCP;unicast;interleaved=0-1")
+        );
+        let resp = request(
+            "RTSP/1.0 200 OK",
+            &[
+                ("CSeq", "2"),
+                ("Transport", "RTP/SAVP/TCP;unicast;interleaved=0-1"),
+            ],
+        );
+        server.write_all(&send(&resp)).await.unwrap();
+        let Some(Message::Rtsp(resp)) = message::read(&mut client).await.unwrap() else {
+            panic!("expected SETUP response");
+        };
+        assert_eq!(
+            resp.header("Transport"),
+            Some("RTP/AVP/TCP;unicast;interleaved=0-1")
+        );
+
+        // An SRTP packet.
+        let k = key();
+        let mut encrypter = Context::new(
+            &k.master_key,
+            &k.master_salt,
+            ProtectionProfile::Aes128CmHmacSha1_80,
+            None,
+            None,
+        )
+        .unwrap();
+        let plain: &[u8] = b"\x80\x60\x00\x01\x00\x00\x00\x00\x12\x34\x56\x78hello world";
+        let encrypted = encrypter.encrypt_rtp(plain).unwrap();
+        assert_ne!(&encrypted[..], plain);
+        server
+            .write_all(&send(&Message::Data {
+                channel: 0,
+                payload: encrypted,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(
+            message::read(&mut client).await.unwrap(),
+            Some(Message::Data {
+                channel: 0,
+                payload: Bytes::from_static(plain),
+            })
+        );
+
+        // Closing both sides ends the forwarding.
+        drop(client);
+        drop(server);
+        fwd.await.unwrap().unwrap();
+    }
+}
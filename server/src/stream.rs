@@ -2,7 +2,7 @@
 // Copyright (C) 2016 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-use crate::h264;
+use crate::{aac, g711, h264, h265};
 use base::{bail, err, Error};
 use bytes::Bytes;
 use futures::StreamExt;
@@ -10,14 +10,47 @@ use retina::client::Demuxed;
 use retina::codec::CodecItem;
 use std::pin::Pin;
 use std::result::Result;
+use std::sync::Arc;
 use tracing::Instrument;
 use url::Url;
 
-static RETINA_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// The default for [`Options::connect_timeout`] and [`Options::frame_timeout`].
+pub const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Returns the timeout for a configured number of seconds, where 0 means [`DEFAULT_TIMEOUT`].
+pub fn timeout(sec: u32) -> std::time::Duration {
+    if sec == 0 {
+        DEFAULT_TIMEOUT
+    } else {
+        std::time::Duration::from_secs(sec.into())
+    }
+}
 
 pub struct Options {
     pub session: retina::client::SessionOptions,
     pub setup: retina::client::SetupOptions,
+
+    /// If present, also set up the stream's AAC audio (if any) with these options.
+    /// See [`Stream::take_audio_frames`].
+    pub audio_setup: Option<retina::client::SetupOptions>,
+
+    /// If true, fall back to G.711 audio when there's no AAC audio, transcoding it to AAC.
+    pub transcode_audio: bool,
+
+    /// How long to wait for the stream to start playing and return its first frame.
+    pub connect_timeout: std::time::Duration,
+
+    /// How long to wait for each subsequent frame.
+    pub frame_timeout: std::time::Duration,
+
+    /// The TLS configuration, required for `rtsps://` URLs. See [`crate::rtsps::client_config`].
+    pub tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+
+    /// If true, decrypt SRTP media. See [`crate::srtp`].
+    pub srtp: bool,
+
+    /// The camera's credentials, if any.
+    pub creds: Option<retina::client::Credentials>,
 }
 
 /// Opens a RTSP stream. This is a trait for test injection.
@@ -40,12 +73,35 @@ pub struct VideoFrame {
     pub data: Bytes,
 
     pub new_video_sample_entry: bool,
+
+    /// The number of RTP packets known to have been lost since the previous frame.
+    pub loss: u32,
+}
+
+pub struct AudioFrame {
+    /// The presentation timestamp, in 90 kHz units on the video stream's timeline.
+    pub pts: i64,
+
+    /// The duration, in units of the audio sample entry's sample rate.
+    pub duration: i32,
+
+    pub data: Bytes,
 }
 
 pub trait Stream: Send {
     fn tool(&self) -> Option<&retina::client::Tool>;
     fn video_sample_entry(&self) -> &db::VideoSampleEntryToInsert;
     fn next(&mut self) -> Result<VideoFrame, Error>;
+
+    /// Returns the audio sample entry, if audio was requested and is available.
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        None
+    }
+
+    /// Takes audio frames received since the last call, in order.
+    fn take_audio_frames(&mut self) -> Vec<AudioFrame> {
+        Vec::new()
+    }
 }
 
 pub struct RealOpener;
@@ -59,17 +115,42 @@ impl Opener for RealOpener {
         url: Url,
         mut options: Options,
     ) -> Result<Box<dyn Stream>, Error> {
+        match url.scheme() {
+            "http" | "https" => return crate::mjpeg::open(label, url, options),
+            "rtmp" => return crate::rtmp::open(label, url, options),
+            "v4l2" => return crate::v4l2::open(label, url, options),
+            _ => {}
+        }
         options.session = options
             .session
+            .creds(options.creds.take())
             .user_agent(format!("Moonfire NVR {}", env!("CARGO_PKG_VERSION")));
+        let proxied = url.scheme() == "rtsps" || options.srtp;
+        if proxied {
+            // Media can't be sent over UDP through the proxy.
+            let tcp = || retina::client::Transport::Tcp(Default::default());
+            options.setup = options.setup.transport(tcp());
+            options.audio_setup = options.audio_setup.map(|s| s.transport(tcp()));
+        }
+        let tls = options.tls.take();
+        let srtp = options.srtp;
+        let connect_timeout = options.connect_timeout;
+        let frame_timeout = options.frame_timeout;
         let rt_handle = tokio::runtime::Handle::current();
-        let (inner, first_frame) = rt_handle
+        let (proxy, inner, first_frame) = rt_handle
             .block_on(
                 rt_handle.spawn(
-                    tokio::time::timeout(
-                        RETINA_TIMEOUT,
-                        RetinaStreamInner::play(label, url, options),
-                    )
+                    tokio::time::timeout(connect_timeout, async move {
+                        let proxy = if proxied {
+                            Some(crate::rtsps::Proxy::start(&url, tls, srtp).await?)
+                        } else {
+                            None
+                        };
+                        let url = proxy.as_ref().map_or(url, |p| p.url().clone());
+                        let (inner, first_frame) =
+                            RetinaStreamInner::play(label, url, options).await?;
+                        Ok::<_, Error>((proxy, inner, first_frame))
+                    })
                     .in_current_span(),
                 ),
             )
@@ -77,8 +158,10 @@ impl Opener for RealOpener {
             .map_err(|e| err!(Unknown, source(e)))??;
         Ok(Box::new(RetinaStream {
             inner: Some(inner),
+            _proxy: proxy,
             rt_handle,
             first_frame: Some(first_frame),
+            frame_timeout,
         }))
     }
 }
@@ -105,13 +188,50 @@ struct RetinaStream {
     ///
     /// This frame is special because we sometimes need to fetch it as part of getting the video
     /// parameters.
-    first_frame: Option<retina::codec::VideoFrame>,
+    first_frame: Option<VideoFrame>,
+
+    /// How long to wait for each frame after the first.
+    frame_timeout: std::time::Duration,
+
+    /// The proxy, for `rtsps://` URLs or SRTP.
+    _proxy: Option<crate::rtsps::Proxy>,
+}
+
+/// The session, in a form appropriate to the video codec.
+enum Session {
+    /// H.264, depacketized by Retina.
+    H264(Demuxed),
+
+    /// H.265, which Retina can't depacketize, so RTP packets are handled by [`h265`].
+    H265 {
+        session: retina::client::Session<retina::client::Playing>,
+        depacketizer: h265::Depacketizer,
+    },
 }
 
 struct RetinaStreamInner {
     label: String,
-    session: Demuxed,
-    video_sample_entry: db::VideoSampleEntryToInsert,
+    session: Session,
+    video_i: usize,
+
+    /// The current video sample entry; `None` only until the first frame is fetched.
+    video_sample_entry: Option<db::VideoSampleEntryToInsert>,
+
+    /// The audio stream, if set up.
+    audio: Option<Audio>,
+}
+
+struct Audio {
+    stream_i: usize,
+
+    /// The sample entry of the AAC audio, after transcoding (if applicable).
+    sample_entry: db::AudioSampleEntryToInsert,
+
+    /// The G.711 to AAC transcoder, if the stream's audio is G.711.
+    transcoder: Option<g711::Transcoder>,
+
+    /// Frames received but not yet taken by [`Stream::take_audio_frames`].
+    pending: Vec<AudioFrame>,
 }
 
 impl RetinaStreamInner {
@@ -120,7 +240,7 @@ impl RetinaStreamInner {
         label: String,
         url: Url,
         options: Options,
-    ) -> Result<(Box<Self>, retina::codec::VideoFrame), Error> {
+    ) -> Result<(Box<Self>, VideoFrame), Error> {
         let mut session = retina::client::Session::describe(url, options.session)
             .await
             .map_err(|e| err!(Unknown, source(e)))?;
@@ -128,145 +248,329 @@ impl RetinaStreamInner {
         let video_i = session
             .streams()
             .iter()
-            .position(|s| s.media() == "video" && s.encoding_name() == "h264")
-            .ok_or_else(|| err!(FailedPrecondition, msg("couldn't find H.264 video stream")))?;
+            .position(|s| s.media() == "video" && matches!(s.encoding_name(), "h264" | "h265"))
+            .ok_or_else(|| {
+                err!(
+                    FailedPrecondition,
+                    msg("couldn't find H.264 or H.265 video stream")
+                )
+            })?;
+        let is_h265 = session.streams()[video_i].encoding_name() == "h265";
+        let audio_i = if options.audio_setup.is_none() {
+            None
+        } else if is_h265 {
+            tracing::warn!("{}: audio is unsupported with H.265 video", &label);
+            None
+        } else {
+            let streams = session.streams();
+            let i = streams
+                .iter()
+                .position(|s| s.media() == "audio" && s.encoding_name() == "mpeg4-generic")
+                .or_else(|| {
+                    if !options.transcode_audio {
+                        return None;
+                    }
+                    streams.iter().position(|s| {
+                        s.media() == "audio"
+                            && g711::Law::from_encoding_name(s.encoding_name()).is_some()
+                    })
+                });
+            if i.is_none() {
+                tracing::warn!("{}: audio requested but no suitable stream found", &label);
+            }
+            i
+        };
         session
             .setup(video_i, options.setup)
             .await
             .map_err(|e| err!(Unknown, source(e)))?;
+        let mut audio = None;
+        if let (Some(stream_i), Some(audio_setup)) = (audio_i, options.audio_setup) {
+            session
+                .setup(stream_i, audio_setup)
+                .await
+                .map_err(|e| err!(Unknown, source(e)))?;
+            let stream = &session.streams()[stream_i];
+            if let Some(law) = g711::Law::from_encoding_name(stream.encoding_name()) {
+                match g711::Transcoder::new(law, stream.clock_rate_hz()) {
+                    Ok((transcoder, sample_entry)) => {
+                        audio = Some(Audio {
+                            stream_i,
+                            sample_entry,
+                            transcoder: Some(transcoder),
+                            pending: Vec::new(),
+                        })
+                    }
+                    Err(e) => tracing::warn!(
+                        err = %e.chain(),
+                        "{}: unable to transcode G.711 audio; not recording audio",
+                        &label
+                    ),
+                }
+            } else {
+                match stream.parameters() {
+                    Some(retina::codec::ParametersRef::Audio(a)) => {
+                        match aac::parse_extra_data(a.extra_data()) {
+                            Ok(sample_entry) => {
+                                audio = Some(Audio {
+                                    stream_i,
+                                    sample_entry,
+                                    transcoder: None,
+                                    pending: Vec::new(),
+                                })
+                            }
+                            Err(e) => tracing::warn!(
+                                err = %e.chain(),
+                                "{}: unable to parse AAC parameters; not recording audio",
+                                &label
+                            ),
+                        }
+                    }
+                    _ => tracing::warn!("{}: no AAC parameters; not recording audio", &label),
+                }
+            }
+        }
         let session = session
             .play(retina::client::PlayOptions::default())
             .await
             .map_err(|e| err!(Unknown, source(e)))?;
-        let mut session = session.demuxed().map_err(|e| err!(Unknown, source(e)))?;
+        let session = if is_h265 {
+            Session::H265 {
+                session,
+                depacketizer: h265::Depacketizer::new(),
+            }
+        } else {
+            Session::H264(session.demuxed().map_err(|e| err!(Unknown, source(e)))?)
+        };
+        let mut self_ = Box::new(Self {
+            label,
+            session,
+            video_i,
+            video_sample_entry: None,
+            audio,
+        });
 
         // First frame.
         let first_frame = loop {
-            match Pin::new(&mut session).next().await {
-                None => bail!(Unavailable, msg("stream closed before first frame")),
-                Some(Err(e)) => bail!(Unknown, msg("unable to get first frame"), source(e)),
-                Some(Ok(CodecItem::VideoFrame(v))) => {
-                    if v.is_random_access_point() {
-                        break v;
-                    }
+            match self_.next_frame().await {
+                Err(e) if e.kind() == base::ErrorKind::Unavailable => {
+                    bail!(Unavailable, msg("stream closed before first frame"))
                 }
-                Some(Ok(_)) => {}
+                Err(e) => bail!(Unknown, msg("unable to get first frame"), source(e)),
+                Ok(f) if f.is_key => break f,
+                Ok(_) => {}
             }
         };
-        let video_params = match session.streams()[video_i].parameters() {
-            Some(retina::codec::ParametersRef::Video(v)) => v.clone(),
-            Some(_) => unreachable!(),
-            None => bail!(Unknown, msg("couldn't find H.264 parameters")),
-        };
-        let video_sample_entry = h264::parse_extra_data(video_params.extra_data())?;
-        let self_ = Box::new(Self {
-            label,
-            session,
-            video_sample_entry,
-        });
         Ok((self_, first_frame))
     }
 
-    /// Fetches a non-initial frame.
-    async fn fetch_next_frame(
-        mut self: Box<Self>,
-    ) -> Result<
-        (
-            Box<Self>,
-            retina::codec::VideoFrame,
-            Option<retina::codec::VideoParameters>,
-        ),
-        Error,
-    > {
-        loop {
-            match Pin::new(&mut self.session)
-                .next()
-                .await
-                .transpose()
-                .map_err(|e| err!(Unknown, source(e)))?
-            {
-                None => bail!(Unavailable, msg("end of stream")),
-                Some(CodecItem::VideoFrame(v)) => {
-                    if v.loss() > 0 {
-                        tracing::warn!(
-                            "{}: lost {} RTP packets @ {}",
-                            &self.label,
-                            v.loss(),
-                            v.start_ctx()
-                        );
+    /// Fetches the next frame, updating `video_sample_entry` on parameter changes.
+    async fn next_frame(&mut self) -> Result<VideoFrame, Error> {
+        let mut loss = 0;
+        match &mut self.session {
+            Session::H264(session) => loop {
+                match Pin::new(&mut *session)
+                    .next()
+                    .await
+                    .transpose()
+                    .map_err(|e| err!(Unknown, source(e)))?
+                {
+                    None => bail!(Unavailable, msg("end of stream")),
+                    Some(CodecItem::VideoFrame(v)) => {
+                        if v.loss() > 0 {
+                            tracing::warn!(
+                                "{}: lost {} RTP packets @ {}",
+                                &self.label,
+                                v.loss(),
+                                v.start_ctx()
+                            );
+                        }
+                        loss += u32::from(v.loss());
+                        let mut new_video_sample_entry = false;
+                        if v.has_new_parameters() || self.video_sample_entry.is_none() {
+                            let p = match session.streams()[self.video_i].parameters() {
+                                Some(retina::codec::ParametersRef::Video(v)) => v.clone(),
+                                Some(_) => unreachable!(),
+                                None if !v.is_random_access_point() => continue,
+                                None => bail!(Unknown, msg("couldn't find H.264 parameters")),
+                            };
+                            let video_sample_entry = h264::parse_extra_data(p.extra_data())?;
+                            new_video_sample_entry = Self::update_video_sample_entry(
+                                &self.label,
+                                &mut self.video_sample_entry,
+                                video_sample_entry,
+                            );
+                        }
+                        return Ok(VideoFrame {
+                            pts: v.timestamp().elapsed(),
+                            duration: 0,
+                            is_key: v.is_random_access_point(),
+                            data: v.into_data().into(),
+                            new_video_sample_entry,
+                            loss,
+                        });
                     }
-                    let p = if v.has_new_parameters() {
-                        Some(match self.session.streams()[v.stream_id()].parameters() {
-                            Some(retina::codec::ParametersRef::Video(v)) => v.clone(),
-                            _ => unreachable!(),
-                        })
-                    } else {
-                        None
-                    };
-                    return Ok((self, v, p));
+                    Some(CodecItem::AudioFrame(a)) => {
+                        let Some(audio) = self.audio.as_mut() else {
+                            continue;
+                        };
+                        if a.stream_id() != audio.stream_i {
+                            continue;
+                        }
+                        let clock_rate = i64::from(a.timestamp().clock_rate().get());
+                        let pts = a.timestamp().elapsed() * 90_000 / clock_rate;
+                        if let Some(t) = audio.transcoder.as_mut() {
+                            let frames = t.push(pts, a.data())?;
+                            audio.pending.extend(frames);
+                            continue;
+                        }
+                        audio.pending.push(AudioFrame {
+                            pts,
+                            duration: i32::try_from(a.frame_length().get())
+                                .map_err(|_| err!(OutOfRange, msg("bad AAC frame length")))?,
+                            data: Bytes::copy_from_slice(a.data()),
+                        });
+                    }
+                    Some(_) => {}
                 }
-                Some(_) => {}
+            },
+            Session::H265 {
+                session,
+                depacketizer,
+            } => loop {
+                match Pin::new(&mut *session)
+                    .next()
+                    .await
+                    .transpose()
+                    .map_err(|e| err!(Unknown, source(e)))?
+                {
+                    None => bail!(Unavailable, msg("end of stream")),
+                    Some(retina::client::PacketItem::Rtp(p)) if p.stream_id() == self.video_i => {
+                        if p.loss() > 0 {
+                            tracing::warn!(
+                                "{}: lost {} RTP packets @ {}",
+                                &self.label,
+                                p.loss(),
+                                p.ctx()
+                            );
+                        }
+                        loss += u32::from(p.loss());
+                        let Some(f) =
+                            depacketizer.push(p.timestamp(), p.mark(), p.loss(), p.payload())?
+                        else {
+                            continue;
+                        };
+                        let new_video_sample_entry = match f.new_video_sample_entry {
+                            Some(e) => Self::update_video_sample_entry(
+                                &self.label,
+                                &mut self.video_sample_entry,
+                                e,
+                            ),
+                            None => false,
+                        };
+                        return Ok(VideoFrame {
+                            pts: f.timestamp.elapsed(),
+                            duration: 0,
+                            is_key: f.is_key,
+                            data: f.data,
+                            new_video_sample_entry,
+                            loss,
+                        });
+                    }
+                    Some(_) => {}
+                }
+            },
+        }
+    }
+
+    /// Replaces `cur` with `new`, returning true iff this is a change from previous parameters.
+    fn update_video_sample_entry(
+        label: &str,
+        cur: &mut Option<db::VideoSampleEntryToInsert>,
+        new: db::VideoSampleEntryToInsert,
+    ) -> bool {
+        match cur {
+            None => {
+                *cur = Some(new);
+                false
+            }
+            Some(c) if *c == new => false,
+            Some(c) => {
+                tracing::debug!(
+                    "{}: parameter change:\nold: {:?}\nnew: {:?}",
+                    label,
+                    c,
+                    &new
+                );
+                *c = new;
+                true
             }
         }
     }
+
+    /// Fetches a non-initial frame.
+    async fn fetch_next_frame(mut self: Box<Self>) -> Result<(Box<Self>, VideoFrame), Error> {
+        let frame = self.next_frame().await?;
+        Ok((self, frame))
+    }
 }
 
 impl Stream for RetinaStream {
     fn tool(&self) -> Option<&retina::client::Tool> {
-        self.inner.as_ref().unwrap().session.tool()
+        match &self.inner.as_ref().unwrap().session {
+            Session::H264(s) => s.tool(),
+            Session::H265 { session, .. } => session.tool(),
+        }
     }
 
     fn video_sample_entry(&self) -> &db::VideoSampleEntryToInsert {
-        &self.inner.as_ref().unwrap().video_sample_entry
+        self.inner
+            .as_ref()
+            .unwrap()
+            .video_sample_entry
+            .as_ref()
+            .expect("video_sample_entry is set by the first frame")
     }
 
     fn next(&mut self) -> Result<VideoFrame, Error> {
-        let (frame, new_video_sample_entry) = self
-            .first_frame
-            .take()
-            .map(|f| Ok((f, false)))
-            .unwrap_or_else(move || {
-                let inner = self.inner.take().unwrap();
-                let (mut inner, frame, new_parameters) = self
-                    .rt_handle
-                    .block_on(
-                        self.rt_handle.spawn(
-                            tokio::time::timeout(RETINA_TIMEOUT, inner.fetch_next_frame())
-                                .in_current_span(),
-                        ),
-                    )
-                    .expect("fetch_next_frame task panicked, see earlier error")
-                    .map_err(|e| {
-                        err!(
-                            DeadlineExceeded,
-                            msg("timeout getting next frame"),
-                            source(e)
-                        )
-                    })??;
-                let mut new_video_sample_entry = false;
-                if let Some(p) = new_parameters {
-                    let video_sample_entry = h264::parse_extra_data(p.extra_data())?;
-                    if video_sample_entry != inner.video_sample_entry {
-                        tracing::debug!(
-                            "{}: parameter change:\nold: {:?}\nnew: {:?}",
-                            &inner.label,
-                            &inner.video_sample_entry,
-                            &video_sample_entry
-                        );
-                        inner.video_sample_entry = video_sample_entry;
-                        new_video_sample_entry = true;
-                    }
-                };
-                self.inner = Some(inner);
-                Ok::<_, Error>((frame, new_video_sample_entry))
-            })?;
-        Ok(VideoFrame {
-            pts: frame.timestamp().elapsed(),
-            duration: 0,
-            is_key: frame.is_random_access_point(),
-            data: frame.into_data().into(),
-            new_video_sample_entry,
-        })
+        if let Some(f) = self.first_frame.take() {
+            return Ok(f);
+        }
+        let inner = self.inner.take().unwrap();
+        let (inner, frame) = self
+            .rt_handle
+            .block_on(
+                self.rt_handle.spawn(
+                    tokio::time::timeout(self.frame_timeout, inner.fetch_next_frame())
+                        .in_current_span(),
+                ),
+            )
+            .expect("fetch_next_frame task panicked, see earlier error")
+            .map_err(|e| {
+                err!(
+                    DeadlineExceeded,
+                    msg("timeout getting next frame"),
+                    source(e)
+                )
+            })??;
+        self.inner = Some(inner);
+        Ok(frame)
+    }
+
+    fn audio_sample_entry(&self) -> Option<&db::AudioSampleEntryToInsert> {
+        self.inner
+            .as_ref()
+            .unwrap()
+            .audio
+            .as_ref()
+            .map(|a| &a.sample_entry)
+    }
+
+    fn take_audio_frames(&mut self) -> Vec<AudioFrame> {
+        match self.inner.as_mut().unwrap().audio.as_mut() {
+            Some(a) => std::mem::take(&mut a.pending),
+            None => Vec::new(),
+        }
     }
 }
 
@@ -349,6 +653,7 @@ pub mod testutil {
                 is_key: sample.is_sync,
                 data: sample.bytes,
                 new_video_sample_entry: false,
+                loss: 0,
             })
         }
 
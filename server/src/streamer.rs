@@ -4,16 +4,163 @@
 
 use crate::stream;
 use base::clock::{Clocks, TimerGuard};
-use base::{bail, err, Error};
-use db::{dir, recording, writer, Camera, Database, Stream};
+use base::{bail, err, Error, FastHashMap};
+use bytes::Bytes;
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use db::{dir, writer, Camera, Database, Stream};
+use std::collections::VecDeque;
 use std::result::Result;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
 use tracing::{debug, info, trace, warn, Instrument};
 use url::Url;
 
 pub static ROTATE_INTERVAL_SEC: i64 = 60;
 
+/// The minimum interval between thumbnails of a stream. See [`crate::jpeg::encode_thumbnail`].
+const THUMBNAIL_INTERVAL_SEC: i64 = 60;
+
+/// The number of frames a [`LiveFrames`] subscriber may fall behind before missing some.
+const LIVE_FRAMES_CAPACITY: usize = 64;
+
+/// The most memory a stream's pre-roll buffer may use, regardless of its `preRollSec`.
+const MAX_PRE_ROLL_BYTES: usize = 64 << 20;
+
+/// A video frame as received from the camera, for live viewing.
+pub struct LiveFrame {
+    pub pts_90k: i64,
+    pub is_key: bool,
+
+    /// The access unit, in AVC (length-prefixed) format.
+    pub data: Bytes,
+    pub video_sample_entry_id: i32,
+}
+
+/// Distributes frames from streamers to live viewers without waiting for them to be written.
+///
+/// Unlike [`db::LiveSegment`]s, which describe frames which have been written to a sample file,
+/// these carry the frame data itself, as soon as it's received. The most recent key frame of each
+/// stream is also kept for snapshots.
+#[derive(Default)]
+pub struct LiveFrames {
+    senders: Mutex<FastHashMap<i32, broadcast::Sender<Arc<LiveFrame>>>>,
+    latest_key_frames: Mutex<FastHashMap<i32, Arc<LiveFrame>>>,
+}
+
+impl LiveFrames {
+    /// Subscribes to frames for the given stream, which may or may not currently be running.
+    pub fn subscribe(&self, stream_id: i32) -> broadcast::Receiver<Arc<LiveFrame>> {
+        self.senders
+            .lock()
+            .unwrap()
+            .entry(stream_id)
+            .or_insert_with(|| broadcast::channel(LIVE_FRAMES_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Returns the most recent key frame received on the given stream since startup, if any.
+    pub fn latest_key_frame(&self, stream_id: i32) -> Option<Arc<LiveFrame>> {
+        self.latest_key_frames
+            .lock()
+            .unwrap()
+            .get(&stream_id)
+            .cloned()
+    }
+
+    /// Publishes a frame to any current subscribers of the given stream.
+    fn publish(&self, stream_id: i32, frame: LiveFrame) {
+        let frame = Arc::new(frame);
+        if frame.is_key {
+            self.latest_key_frames
+                .lock()
+                .unwrap()
+                .insert(stream_id, frame.clone());
+        }
+        let l = self.senders.lock().unwrap();
+        if let Some(tx) = l.get(&stream_id) {
+            if tx.receiver_count() > 0 {
+                let _ = tx.send(frame);
+            }
+        }
+    }
+}
+
+/// A video frame and the audio frames received after it, held in a [`PreRollBuffer`].
+struct BufferedFrame {
+    local_time: recording::Time,
+    pts: i64,
+    is_key: bool,
+    data: Bytes,
+    video_sample_entry_id: i32,
+    audio: Vec<stream::AudioFrame>,
+}
+
+impl BufferedFrame {
+    fn bytes(&self) -> usize {
+        self.data.len() + self.audio.iter().map(|a| a.data.len()).sum::<usize>()
+    }
+}
+
+/// The most recent groups of pictures of a stream which isn't currently being written, so that
+/// `on-signal` recordings can include video from before the signal.
+///
+/// The buffer is empty or starts with a key frame. It keeps the latest key frame at least
+/// `duration` older than the newest frame and everything after, within
+/// [`MAX_PRE_ROLL_BYTES`].
+struct PreRollBuffer {
+    duration: recording::Duration,
+    frames: VecDeque<BufferedFrame>,
+    bytes: usize,
+}
+
+impl PreRollBuffer {
+    fn new(duration: recording::Duration) -> Self {
+        PreRollBuffer {
+            duration,
+            frames: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, frame: BufferedFrame) {
+        if self.frames.is_empty() && !frame.is_key {
+            return; // can't start a recording here.
+        }
+        self.bytes += frame.bytes();
+        self.frames.push_back(frame);
+
+        // Drop leading groups of pictures which aren't needed.
+        let newest = self.frames.back().expect("just pushed").local_time;
+        while let Some(next_key) = self.frames.iter().skip(1).position(|f| f.is_key) {
+            let next_key = next_key + 1;
+            if self.bytes <= MAX_PRE_ROLL_BYTES
+                && self.frames[next_key].local_time > newest - self.duration
+            {
+                break;
+            }
+            for f in self.frames.drain(..next_key) {
+                self.bytes -= f.bytes();
+            }
+        }
+        if self.bytes > MAX_PRE_ROLL_BYTES {
+            self.clear(); // a single group of pictures is too large.
+        }
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes and returns all buffered frames, oldest first.
+    fn take(&mut self) -> VecDeque<BufferedFrame> {
+        self.bytes = 0;
+        std::mem::take(&mut self.frames)
+    }
+}
+
 /// Common state that can be used by multiple `Streamer` instances.
 pub struct Environment<'a, 'tmp, C>
 where
@@ -22,6 +169,9 @@ where
     pub opener: &'a dyn stream::Opener,
     pub db: &'tmp Arc<Database<C>>,
     pub shutdown_rx: &'tmp base::shutdown::Receiver,
+    pub live_frames: &'tmp Arc<LiveFrames>,
+    pub metrics: &'tmp Arc<crate::metrics::Metrics>,
+    pub object_detector: Option<&'tmp crate::analytics::ObjectDetector>,
 }
 
 /// Connects to a given RTSP stream and writes recordings to the database via [`writer::Writer`].
@@ -37,10 +187,31 @@ where
     rotate_interval_sec: i64,
     db: Arc<Database<C>>,
     dir: Arc<dir::SampleFileDir>,
-    syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    syncer_channel: writer::SyncerChannel<dir::SampleFile>,
     opener: &'a dyn stream::Opener,
+    live_frames: Arc<LiveFrames>,
+    counters: Arc<crate::metrics::StreamCounters>,
     transport: retina::client::Transport,
+    record_audio: bool,
+    transcode_audio: bool,
+    connect_timeout: std::time::Duration,
+    frame_timeout: std::time::Duration,
+    tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+    srtp: bool,
+    camera_id: i32,
     stream_id: i32,
+
+    /// While in `on-signal` mode, how much video to buffer for the start of each recording.
+    pre_roll: recording::Duration,
+
+    /// The time at or after which the next key frame should be thumbnailed.
+    next_thumbnail: recording::Time,
+
+    /// Motion detection, if configured for this stream.
+    motion: Option<crate::motion::Analyzer>,
+
+    /// Object detection, if configured for this stream.
+    detection: Option<crate::analytics::StreamDetector>,
     session_group: Arc<retina::client::SessionGroup>,
     short_name: String,
     url: Url,
@@ -56,7 +227,7 @@ where
     pub fn new<'tmp>(
         env: &Environment<'a, 'tmp, C>,
         dir: Arc<dir::SampleFileDir>,
-        syncer_channel: writer::SyncerChannel<::std::fs::File>,
+        syncer_channel: writer::SyncerChannel<dir::SampleFile>,
         stream_id: i32,
         c: &Camera,
         s: &Stream,
@@ -75,6 +246,11 @@ where
                 msg("RTSP URL shouldn't include credentials")
             );
         }
+        let tls = if matches!(url.scheme(), "rtsps" | "https") {
+            Some(crate::rtsps::client_config(&c.config)?)
+        } else {
+            None
+        };
         let stream_transport = if s.config.rtsp_transport.is_empty() {
             None
         } else {
@@ -91,6 +267,28 @@ where
                 }
             }
         };
+        let short_name = format!("{}-{}", c.short_name, s.type_.as_str());
+        let motion = match s.config.motion {
+            None => None,
+            Some(ref m) => {
+                match crate::motion::Analyzer::start(env.db.clone(), short_name.clone(), m.clone())
+                {
+                    Ok(a) => Some(a),
+                    Err(err) => {
+                        warn!(%err, "{short_name}: motion detection disabled");
+                        None
+                    }
+                }
+            }
+        };
+        let detection = match (&s.config.detection, env.object_detector) {
+            (None, _) => None,
+            (Some(d), Some(o)) => Some(o.stream(stream_id, short_name.clone(), d.clone())),
+            (Some(_), None) => {
+                warn!("{short_name}: object detection disabled; objectDetection isn't configured");
+                None
+            }
+        };
         Ok(Streamer {
             shutdown_rx: env.shutdown_rx.clone(),
             rotate_offset_sec,
@@ -99,10 +297,23 @@ where
             dir,
             syncer_channel,
             opener: env.opener,
+            live_frames: env.live_frames.clone(),
+            counters: env.metrics.stream(stream_id),
             transport: stream_transport.unwrap_or_default(),
+            record_audio: s.config.record_audio,
+            transcode_audio: s.config.transcode_audio,
+            connect_timeout: stream::timeout(s.config.rtsp_connect_timeout_sec),
+            frame_timeout: stream::timeout(s.config.rtsp_frame_timeout_sec),
+            tls,
+            srtp: s.config.srtp,
+            camera_id: c.id,
             stream_id,
+            pre_roll: recording::Duration(i64::from(s.config.pre_roll_sec) * TIME_UNITS_PER_SEC),
+            next_thumbnail: recording::Time(i64::min_value()),
+            motion,
+            detection,
             session_group,
-            short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
+            short_name,
             url: url.clone(),
             username: c.config.username.clone(),
             password: c.config.password.clone(),
@@ -118,16 +329,31 @@ where
     /// Note: despite the blocking interface, this expects to be called from
     /// the context of a multithreaded tokio runtime with IO and time enabled.
     pub fn run(&mut self) {
+        self.counters.status.lock().unwrap().running = true;
         while self.shutdown_rx.check().is_ok() {
             if let Err(err) = self.run_once() {
+                {
+                    let mut status = self.counters.status.lock().unwrap();
+                    status.connected = false;
+                    status.last_error = Some((
+                        recording::Time::new(self.db.clocks().realtime()),
+                        err.chain().to_string(),
+                    ));
+                }
                 let sleep_time = time::Duration::seconds(1);
                 warn!(
                     err = %err.chain(),
                     "sleeping for 1 s after error"
                 );
                 self.db.clocks().sleep(sleep_time);
+                self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
             }
         }
+        {
+            let mut status = self.counters.status.lock().unwrap();
+            status.running = false;
+            status.connected = false;
+        }
         info!("shutting down");
     }
 
@@ -167,20 +393,29 @@ where
             let _t = TimerGuard::new(&clocks, || format!("opening {}", self.url));
             let options = stream::Options {
                 session: retina::client::SessionOptions::default()
-                    .creds(if self.username.is_empty() {
-                        None
-                    } else {
-                        Some(retina::client::Credentials {
-                            username: self.username.clone(),
-                            password: self.password.clone(),
-                        })
-                    })
                     .session_group(self.session_group.clone()),
                 setup: retina::client::SetupOptions::default().transport(self.transport.clone()),
+                audio_setup: self.record_audio.then(|| {
+                    retina::client::SetupOptions::default().transport(self.transport.clone())
+                }),
+                transcode_audio: self.transcode_audio,
+                connect_timeout: self.connect_timeout,
+                frame_timeout: self.frame_timeout,
+                tls: self.tls.clone(),
+                srtp: self.srtp,
+                creds: if self.username.is_empty() {
+                    None
+                } else {
+                    Some(retina::client::Credentials {
+                        username: self.username.clone(),
+                        password: self.password.clone(),
+                    })
+                },
             };
             self.opener
                 .open(self.short_name.clone(), self.url.clone(), options)?
         };
+        self.counters.status.lock().unwrap().connected = true;
         let realtime_offset = self.db.clocks().realtime() - clocks.monotonic();
         let mut video_sample_entry_id = {
             let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
@@ -188,11 +423,26 @@ where
                 .lock()
                 .insert_video_sample_entry(stream.video_sample_entry().clone())?
         };
+        let audio_sample_entry_id = match stream.audio_sample_entry() {
+            Some(e) => {
+                let _t = TimerGuard::new(&clocks, || "inserting audio sample entry");
+                Some(self.db.lock().insert_audio_sample_entry(e.clone())?)
+            }
+            None => None,
+        };
         let mut seen_key_frame = false;
+        let mut prev_frame_time: Option<recording::Time> = None;
 
         // Seconds since epoch at which to next rotate. See comment at start
         // of while loop.
         let mut rotate: Option<i64> = None;
+
+        // Whether frames should be written and, if not, whether they should be buffered for
+        // pre-roll. These change only on key frames; see `recording_policy`.
+        let mut writing = false;
+        let mut buffering = false;
+        let mut pre_roll =
+            (self.pre_roll > recording::Duration(0)).then(|| PreRollBuffer::new(self.pre_roll));
         let mut w = writer::Writer::new(&self.dir, &self.db, &self.syncer_channel, self.stream_id);
         while self.shutdown_rx.check().is_ok() {
             // `rotate` should now be set iff `w` has an open recording.
@@ -208,14 +458,26 @@ where
                     return Err(e);
                 }
             };
+            let frame_realtime = clocks.monotonic() + realtime_offset;
+            let local_time = recording::Time::new(frame_realtime);
+            self.counters
+                .frames_received
+                .fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .packets_lost
+                .fetch_add(u64::from(frame.loss), Ordering::Relaxed);
+            if prev_frame_time.map_or(false, |t| local_time - t > crate::metrics::FRAME_GAP) {
+                self.counters.frame_gaps.fetch_add(1, Ordering::Relaxed);
+            }
+            prev_frame_time = Some(local_time);
+            self.counters.status.lock().unwrap().last_frame = Some(local_time);
             if !seen_key_frame && !frame.is_key {
+                self.counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
                 continue;
             } else if !seen_key_frame {
                 debug!("have first key frame");
                 seen_key_frame = true;
             }
-            let frame_realtime = clocks.monotonic() + realtime_offset;
-            let local_time = recording::Time::new(frame_realtime);
             rotate = if let Some(r) = rotate {
                 if frame_realtime.sec > r && frame.is_key {
                     trace!("close on normal rotation");
@@ -240,10 +502,38 @@ where
                     Some(r)
                 }
             } else {
+                if frame.new_video_sample_entry {
+                    // Not currently recording; just keep track of the new parameters. Buffered
+                    // frames can't be written alongside the new ones in one recording.
+                    if let Some(b) = pre_roll.as_mut() {
+                        b.clear();
+                    }
+                    video_sample_entry_id = {
+                        let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
+                        self.db
+                            .lock()
+                            .insert_video_sample_entry(stream.video_sample_entry().clone())?
+                    };
+                }
                 None
             };
+            if frame.is_key {
+                (writing, buffering) = self.recording_policy(local_time);
+                if !writing && rotate.is_some() {
+                    trace!("close on end of schedule or signal");
+                    let _t = TimerGuard::new(&clocks, || "closing writer");
+                    w.end_run(Some(frame.pts))?;
+                    rotate = None;
+                }
+                if !buffering {
+                    if let Some(b) = pre_roll.as_mut() {
+                        b.clear();
+                    }
+                }
+            }
             let r = match rotate {
-                Some(r) => r,
+                _ if !writing => None,
+                Some(r) => Some(r),
                 None => {
                     let sec = frame_realtime.sec;
                     let r = sec - (sec % self.rotate_interval_sec) + self.rotate_offset_sec;
@@ -263,19 +553,84 @@ where
                         self.rotate_interval_sec
                     };
                     let _t = TimerGuard::new(&clocks, || "creating writer");
-                    r
+                    Some(r)
                 }
             };
-            let _t = TimerGuard::new(&clocks, || format!("writing {} bytes", frame.data.len()));
-            w.write(
-                &mut self.shutdown_rx,
-                &frame.data[..],
-                local_time,
-                frame.pts,
-                frame.is_key,
-                video_sample_entry_id,
-            )?;
-            rotate = Some(r);
+            if r.is_some() {
+                for f in pre_roll
+                    .as_mut()
+                    .map(PreRollBuffer::take)
+                    .unwrap_or_default()
+                {
+                    let _t = TimerGuard::new(&clocks, || "writing pre-roll frame");
+                    w.write(
+                        &mut self.shutdown_rx,
+                        &f.data[..],
+                        f.local_time,
+                        f.pts,
+                        f.is_key,
+                        f.video_sample_entry_id,
+                    )?;
+                    self.counters
+                        .bytes_recorded
+                        .fetch_add(f.data.len() as u64, Ordering::Relaxed);
+                    if let Some(id) = audio_sample_entry_id {
+                        for a in f.audio {
+                            w.write_audio(&a.data[..], a.pts, a.duration, id)?;
+                        }
+                    }
+                }
+                let _t = TimerGuard::new(&clocks, || format!("writing {} bytes", frame.data.len()));
+                w.write(
+                    &mut self.shutdown_rx,
+                    &frame.data[..],
+                    local_time,
+                    frame.pts,
+                    frame.is_key,
+                    video_sample_entry_id,
+                )?;
+                self.counters
+                    .bytes_recorded
+                    .fetch_add(frame.data.len() as u64, Ordering::Relaxed);
+            }
+            self.live_frames.publish(
+                self.stream_id,
+                LiveFrame {
+                    pts_90k: frame.pts,
+                    is_key: frame.is_key,
+                    data: frame.data.clone(),
+                    video_sample_entry_id,
+                },
+            );
+            if let Some(ref mut m) = self.motion {
+                m.send(local_time, &frame.data, frame.is_key, video_sample_entry_id);
+            }
+            if let Some(ref mut d) = self.detection {
+                d.send(local_time, &frame.data, frame.is_key, video_sample_entry_id);
+            }
+            if frame.is_key && crate::jpeg::AVAILABLE && local_time >= self.next_thumbnail {
+                self.next_thumbnail =
+                    local_time + recording::Duration(THUMBNAIL_INTERVAL_SEC * TIME_UNITS_PER_SEC);
+                self.spawn_thumbnail(&handle, local_time, video_sample_entry_id, &frame.data);
+            }
+            let audio = stream.take_audio_frames();
+            if r.is_some() {
+                if let Some(id) = audio_sample_entry_id {
+                    for a in audio {
+                        w.write_audio(&a.data[..], a.pts, a.duration, id)?;
+                    }
+                }
+            } else if let (true, Some(b)) = (buffering, pre_roll.as_mut()) {
+                b.push(BufferedFrame {
+                    local_time,
+                    pts: frame.pts,
+                    is_key: frame.is_key,
+                    data: frame.data,
+                    video_sample_entry_id,
+                    audio,
+                });
+            }
+            rotate = r;
         }
         if rotate.is_some() {
             let _t = TimerGuard::new(&clocks, || "closing writer");
@@ -283,6 +638,61 @@ where
         }
         Ok(())
     }
+
+    /// Returns whether to write frames starting with a key frame received at `when` and, if not,
+    /// whether to buffer them for pre-roll. This follows the stream's current mode and schedule
+    /// and, in `on-signal` mode, [`db::LockedDatabase::camera_motion_at`].
+    fn recording_policy(&self, when: recording::Time) -> (bool, bool) {
+        let db = self.db.lock();
+        let Some(s) = db.streams_by_id().get(&self.stream_id) else {
+            return (false, false);
+        };
+        match s.config.mode_at(when) {
+            db::json::STREAM_MODE_RECORD => (true, false),
+            db::json::STREAM_MODE_ON_SIGNAL => {
+                let motion = db.camera_motion_at(self.camera_id, when);
+                (motion, !motion)
+            }
+            _ => (false, false),
+        }
+    }
+
+    /// Decodes the given key frame and stores a thumbnail of it in the background, so as to not
+    /// delay ingest. Failures are logged but otherwise ignored.
+    fn spawn_thumbnail(
+        &self,
+        handle: &tokio::runtime::Handle,
+        start: recording::Time,
+        video_sample_entry_id: i32,
+        data: &Bytes,
+    ) {
+        let db = self.db.clone();
+        let Some(entry) = db
+            .lock()
+            .video_sample_entries_by_id()
+            .get(&video_sample_entry_id)
+            .cloned()
+        else {
+            return;
+        };
+        let (data, stream_id) = (data.clone(), self.stream_id);
+        let short_name = self.short_name.clone();
+        handle.spawn_blocking(move || {
+            let t = match crate::jpeg::encode_thumbnail(&entry, &data) {
+                Ok(t) => t,
+                Err(err) => {
+                    warn!(%err, "{short_name}: unable to create thumbnail");
+                    return;
+                }
+            };
+            if let Err(err) = db
+                .lock()
+                .insert_thumbnail(stream_id, start, t.width, t.height, &t.data)
+            {
+                warn!(%err, "{short_name}: unable to store thumbnail");
+            }
+        });
+    }
 }
 
 #[cfg(test)]
@@ -293,6 +703,7 @@ mod tests {
     use db::{recording, testutil, CompositeId};
     use std::cmp;
     use std::convert::TryFrom;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
     use std::sync::Mutex;
     use tracing::trace;
@@ -426,6 +837,35 @@ mod tests {
         .unwrap()
     }
 
+    #[test]
+    fn pre_roll_buffer() {
+        testutil::init();
+        let frame = |sec: i64, is_key: bool| super::BufferedFrame {
+            local_time: recording::Time(sec * recording::TIME_UNITS_PER_SEC),
+            pts: sec * recording::TIME_UNITS_PER_SEC,
+            is_key,
+            data: bytes::Bytes::from_static(b"x"),
+            video_sample_entry_id: 1,
+            audio: Vec::new(),
+        };
+        let mut b =
+            super::PreRollBuffer::new(recording::Duration(2 * recording::TIME_UNITS_PER_SEC));
+        b.push(frame(0, false));
+        assert!(b.frames.is_empty(), "must start with a key frame");
+        for (sec, is_key) in [(1, true), (2, false), (3, true), (4, false)] {
+            b.push(frame(sec, is_key));
+        }
+        assert_eq!(b.frames.len(), 4, "key frame at 3 isn't yet 2 seconds old");
+        b.push(frame(5, true));
+        let secs: Vec<i64> = b
+            .take()
+            .into_iter()
+            .map(|f| f.pts / recording::TIME_UNITS_PER_SEC)
+            .collect();
+        assert_eq!(secs, [3, 4, 5]);
+        assert_eq!(b.bytes, 0);
+    }
+
     #[tokio::test]
     async fn basic() {
         testutil::init();
@@ -446,21 +886,22 @@ mod tests {
             shutdown_tx: Mutex::new(Some(shutdown_tx)),
         };
         let db = testutil::TestDb::new(clocks);
+        let live_frames = Arc::new(super::LiveFrames::default());
+        let metrics = Arc::new(crate::metrics::Metrics::default());
         let env = super::Environment {
             opener: &opener,
             db: &db.db,
             shutdown_rx: &shutdown_rx,
+            live_frames: &live_frames,
+            metrics: &metrics,
+            object_detector: None,
         };
         let mut stream;
         {
             let l = db.db.lock();
             let camera = l.cameras_by_id().get(&testutil::TEST_CAMERA_ID).unwrap();
             let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
-            let dir = db
-                .dirs_by_stream_id
-                .get(&testutil::TEST_STREAM_ID)
-                .unwrap()
-                .clone();
+            let dir = db.dirs_by_id.get(&testutil::TEST_DIR_ID).unwrap().clone();
             stream = super::Streamer::new(
                 &env,
                 dir,
@@ -476,6 +917,27 @@ mod tests {
         }
         stream.run();
         assert!(opener.streams.lock().unwrap().is_empty());
+
+        // The latest key frame should be kept for snapshots.
+        let key_frame = live_frames
+            .latest_key_frame(testutil::TEST_STREAM_ID)
+            .unwrap();
+        assert!(key_frame.is_key);
+        assert!(live_frames
+            .latest_key_frame(testutil::TEST_STREAM_ID + 1)
+            .is_none());
+
+        let counters = metrics.stream(testutil::TEST_STREAM_ID);
+        assert_eq!(counters.frames_received.load(Ordering::Relaxed), 10);
+        assert_eq!(counters.frames_dropped.load(Ordering::Relaxed), 0);
+        assert!(counters.bytes_recorded.load(Ordering::Relaxed) > 0);
+        {
+            let status = counters.status.lock().unwrap();
+            assert!(!status.running);
+            assert!(!status.connected);
+            assert!(status.last_frame.is_some());
+        }
+
         db.syncer_channel.flush();
         let db = db.db.lock();
 
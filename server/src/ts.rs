@@ -0,0 +1,664 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! MPEG transport stream (`.ts`) file builder, serving the same recording ranges as
+//! `mp4::FileBuilder`.
+//!
+//! The stream has a single program with a single video elementary stream. It's divided into
+//! groups of pictures, each of which starts with a PAT and PMT so that a reader can join at any
+//! key frame. Each frame is a PES packet holding an access unit delimiter, the parameter sets (on
+//! key frames), and the frame's NAL units, converted from the length-prefixed form stored in the
+//! sample file to Annex B form. As Moonfire NVR always stores 4-byte lengths, this conversion
+//! doesn't change the frame's size, so the layout of the entire stream is known up front and
+//! byte ranges can be served by generating only the affected groups of pictures.
+//!
+//! Like `.mkv` files, there are no edit lists, so frames before the desired start (back to the
+//! preceding key frame) are presented rather than skipped. Audio isn't included.
+
+use crate::body::{BoxedError, Chunk};
+use crate::remux;
+use crate::slices::{self, Slices};
+use base::{bail, err, Error};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use db::dir;
+use futures::Stream;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::trace;
+
+/// This value should be incremented any time a change is made to this file that causes different
+/// bytes or headers to be output for a particular set of `FileBuilder` options. Incrementing this
+/// value will cause the etag to change as well.
+const FORMAT_VERSION: [u8; 1] = [0x00];
+
+const PACKET_LEN: usize = 188;
+const PACKET_HEADER_LEN: usize = 4;
+const PACKET_PAYLOAD_LEN: usize = PACKET_LEN - PACKET_HEADER_LEN;
+
+/// The length of an adaptation field holding a PCR: length, flags, and the 6-byte PCR.
+const PCR_ADAPTATION_FIELD_LEN: usize = 8;
+
+const PID_PAT: u16 = 0x0000;
+const PID_PMT: u16 = 0x1000;
+const PID_VIDEO: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// The PES `stream_id` of the first video stream.
+const STREAM_ID_VIDEO: u8 = 0xE0;
+
+/// The length of a PES header with a PTS but no DTS.
+const PES_HEADER_LEN: usize = 14;
+
+/// The offset between the PCR and PTS, in 90 kHz units. The PCR starts at zero; presentation
+/// starts this much later to give the decoder time to buffer, as with ffmpeg's default `muxdelay`.
+const PTS_OFFSET_90K: u64 = 63_000;
+
+/// Mask for 33-bit PTS and PCR values.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+const START_CODE: &[u8] = b"\x00\x00\x00\x01";
+
+/// Returns the number of transport stream packets needed for a PES packet of the given length.
+/// The first packet of each has a PCR, as in `put_pes_packets`.
+fn pes_packets(pes_len: usize) -> usize {
+    let first = PACKET_PAYLOAD_LEN - PCR_ADAPTATION_FIELD_LEN;
+    1 + (pes_len.saturating_sub(first) + PACKET_PAYLOAD_LEN - 1) / PACKET_PAYLOAD_LEN
+}
+
+/// Computes the CRC-32 used by MPEG-2 PSI sections (ISO/IEC 13818-1 Annex A).
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &b in data {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Returns a PSI section with the given `table_id`, `table_id_extension`, and body, including
+/// its header and CRC.
+fn psi_section(table_id: u8, table_id_extension: u16, body: &[u8]) -> Vec<u8> {
+    let mut s = Vec::with_capacity(12 + body.len());
+    s.push(table_id);
+    let section_len = 5 + body.len() + 4;
+    s.write_u16::<BigEndian>(0xB000 | section_len as u16)
+        .expect("Vec write shouldn't fail");
+    s.write_u16::<BigEndian>(table_id_extension)
+        .expect("Vec write shouldn't fail");
+    s.push(0xC1); // version 0, current_next_indicator 1.
+    s.push(0); // section_number
+    s.push(0); // last_section_number
+    s.extend_from_slice(body);
+    let crc = crc32_mpeg2(&s);
+    s.write_u32::<BigEndian>(crc)
+        .expect("Vec write shouldn't fail");
+    s
+}
+
+/// Returns the program association section, mapping the single program to `PID_PMT`.
+fn pat_section() -> Vec<u8> {
+    let mut body = Vec::with_capacity(4);
+    body.write_u16::<BigEndian>(PROGRAM_NUMBER)
+        .expect("Vec write shouldn't fail");
+    body.write_u16::<BigEndian>(0xE000 | PID_PMT)
+        .expect("Vec write shouldn't fail");
+    psi_section(0x00, 1, &body)
+}
+
+/// Returns the program map section, describing the single video stream.
+fn pmt_section(stream_type: u8) -> Vec<u8> {
+    let mut body = Vec::with_capacity(9);
+    body.write_u16::<BigEndian>(0xE000 | PID_VIDEO) // PCR_PID
+        .expect("Vec write shouldn't fail");
+    body.write_u16::<BigEndian>(0xF000) // program_info_length
+        .expect("Vec write shouldn't fail");
+    body.push(stream_type);
+    body.write_u16::<BigEndian>(0xE000 | PID_VIDEO)
+        .expect("Vec write shouldn't fail");
+    body.write_u16::<BigEndian>(0xF000) // ES_info_length
+        .expect("Vec write shouldn't fail");
+    psi_section(0x02, PROGRAM_NUMBER, &body)
+}
+
+fn put_packet_header(out: &mut Vec<u8>, pid: u16, start: bool, adaptation: bool, cc: u8) {
+    out.push(0x47);
+    out.push(if start { 0x40 } else { 0x00 } | (pid >> 8) as u8);
+    out.push(pid as u8);
+    out.push(if adaptation { 0x30 } else { 0x10 } | (cc & 0x0F));
+}
+
+/// Appends a packet holding a single PSI section, padded with stuffing bytes.
+fn put_psi_packet(out: &mut Vec<u8>, pid: u16, cc: u8, section: &[u8]) {
+    put_packet_header(out, pid, true, false, cc);
+    out.push(0); // pointer_field
+    out.extend_from_slice(section);
+    out.resize(out.len() + PACKET_PAYLOAD_LEN - 1 - section.len(), 0xFF);
+}
+
+/// Appends the 6-byte encoding of a PCR with the given base and zero extension.
+fn put_pcr(out: &mut Vec<u8>, pcr_90k: u64) {
+    let base = pcr_90k & TIMESTAMP_MASK;
+    out.push((base >> 25) as u8);
+    out.push((base >> 17) as u8);
+    out.push((base >> 9) as u8);
+    out.push((base >> 1) as u8);
+    out.push((((base & 1) as u8) << 7) | 0x7E);
+    out.push(0);
+}
+
+/// Appends a PES header with the given PTS and an unbounded `PES_packet_length`.
+fn put_pes_header(out: &mut Vec<u8>, pts_90k: u64) {
+    let pts = pts_90k & TIMESTAMP_MASK;
+    out.extend_from_slice(&[0x00, 0x00, 0x01, STREAM_ID_VIDEO]);
+    out.extend_from_slice(&[0x00, 0x00]); // PES_packet_length: unbounded.
+    out.push(0x80); // marker bits.
+    out.push(0x80); // PTS_DTS_flags: PTS only.
+    out.push(5); // PES_header_data_length
+    out.push(0x21 | ((pts >> 29) & 0x0E) as u8);
+    out.push((pts >> 22) as u8);
+    out.push(0x01 | ((pts >> 14) & 0xFE) as u8);
+    out.push((pts >> 7) as u8);
+    out.push(0x01 | ((pts << 1) & 0xFE) as u8);
+}
+
+/// Appends transport stream packets holding the given PES packet, updating the continuity
+/// counter `cc`. The first packet carries a PCR and, if `random_access`, the
+/// `random_access_indicator`. The last is padded via adaptation field stuffing.
+fn put_pes_packets(out: &mut Vec<u8>, cc: &mut u8, pcr_90k: u64, random_access: bool, pes: &[u8]) {
+    let mut pes = pes;
+    let mut first = true;
+    while first || !pes.is_empty() {
+        let min_adaptation = if first { PCR_ADAPTATION_FIELD_LEN } else { 0 };
+        let n = cmp::min(PACKET_PAYLOAD_LEN - min_adaptation, pes.len());
+        let adaptation_len = PACKET_PAYLOAD_LEN - n;
+        put_packet_header(out, PID_VIDEO, first, adaptation_len > 0, *cc);
+        if adaptation_len > 0 {
+            out.push((adaptation_len - 1) as u8);
+            if adaptation_len > 1 {
+                let mut flags = 0;
+                if first {
+                    flags |= 0x10; // PCR_flag
+                    if random_access {
+                        flags |= 0x40; // random_access_indicator
+                    }
+                }
+                out.push(flags);
+                let mut used = 2;
+                if first {
+                    put_pcr(out, pcr_90k);
+                    used += 6;
+                }
+                out.resize(out.len() + adaptation_len - used, 0xFF);
+            }
+        }
+        out.extend_from_slice(&pes[..n]);
+        pes = &pes[n..];
+        *cc = (*cc + 1) & 0x0F;
+        first = false;
+    }
+}
+
+/// Appends an access unit converted from length-prefixed to Annex B form. Requires 4-byte
+/// lengths, so the output is the same size as the input.
+fn put_annex_b(out: &mut Vec<u8>, mut data: &[u8]) -> Result<(), Error> {
+    while !data.is_empty() {
+        if data.len() < 4 {
+            bail!(InvalidArgument, msg("truncated NAL length"));
+        }
+        let len = BigEndian::read_u32(&data[0..4]) as usize;
+        let nal = data.get(4..4 + len).ok_or_else(|| {
+            err!(
+                InvalidArgument,
+                msg(
+                    "NAL length {} exceeds remaining {} bytes",
+                    len,
+                    data.len() - 4
+                )
+            )
+        })?;
+        out.extend_from_slice(START_CODE);
+        out.extend_from_slice(nal);
+        data = &data[4 + len..];
+    }
+    Ok(())
+}
+
+/// Codec-specific details of the video elementary stream.
+#[derive(Debug)]
+struct Codec {
+    /// The PMT `stream_type`.
+    stream_type: u8,
+
+    /// The access unit delimiter NAL, in Annex B form.
+    aud: &'static [u8],
+
+    /// The parameter sets, in Annex B form, to send before each key frame.
+    parameter_sets: Vec<u8>,
+}
+
+impl Codec {
+    fn new(e: &db::VideoSampleEntry) -> Result<Self, Error> {
+        match e.box_type() {
+            b"avc1" => Ok(Codec {
+                stream_type: 0x1B,
+                aud: b"\x00\x00\x00\x01\x09\xF0",
+                parameter_sets: avc_parameter_sets(e.decoder_config()?)?,
+            }),
+            b"hvc1" => Ok(Codec {
+                stream_type: 0x24,
+                aud: b"\x00\x00\x00\x01\x46\x01\x50",
+                parameter_sets: hevc_parameter_sets(e.decoder_config()?)?,
+            }),
+            t => bail!(
+                Unimplemented,
+                msg(
+                    "unsupported video sample entry type {:?} for .ts",
+                    String::from_utf8_lossy(t)
+                ),
+            ),
+        }
+    }
+
+    /// Returns the length of a frame's elementary stream data.
+    fn es_len(&self, bytes: u32, is_key: bool) -> usize {
+        self.aud.len() + if is_key { self.parameter_sets.len() } else { 0 } + bytes as usize
+    }
+}
+
+/// Reads a 16-bit-length-prefixed NAL from `data`, advancing it.
+fn read_nal<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let len = data
+        .get(0..2)
+        .map(|l| usize::from(BigEndian::read_u16(l)))
+        .ok_or_else(|| err!(InvalidArgument, msg("truncated parameter set length")))?;
+    let nal = data
+        .get(2..2 + len)
+        .ok_or_else(|| err!(InvalidArgument, msg("truncated parameter set")))?;
+    *data = &data[2 + len..];
+    Ok(nal)
+}
+
+/// Returns the SPS and PPS NAL units from an `AVCDecoderConfigurationRecord` in Annex B form.
+fn avc_parameter_sets(mut record: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    if record.len() < 6 {
+        bail!(
+            InvalidArgument,
+            msg("truncated AVCDecoderConfigurationRecord")
+        );
+    }
+    let num_sps = record[5] & 0x1F;
+    record = &record[6..];
+    for _ in 0..num_sps {
+        out.extend_from_slice(START_CODE);
+        out.extend_from_slice(read_nal(&mut record)?);
+    }
+    let (&num_pps, rest) = record.split_first().ok_or_else(|| {
+        err!(
+            InvalidArgument,
+            msg("truncated AVCDecoderConfigurationRecord")
+        )
+    })?;
+    record = rest;
+    for _ in 0..num_pps {
+        out.extend_from_slice(START_CODE);
+        out.extend_from_slice(read_nal(&mut record)?);
+    }
+    Ok(out)
+}
+
+/// Returns the VPS, SPS, and PPS NAL units from an `HEVCDecoderConfigurationRecord` in Annex B
+/// form.
+fn hevc_parameter_sets(record: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    if record.len() < 23 {
+        bail!(
+            InvalidArgument,
+            msg("truncated HEVCDecoderConfigurationRecord")
+        );
+    }
+    let num_arrays = record[22];
+    let mut record = &record[23..];
+    for _ in 0..num_arrays {
+        if record.len() < 3 {
+            bail!(
+                InvalidArgument,
+                msg("truncated HEVCDecoderConfigurationRecord")
+            );
+        }
+        let nal_type = record[0] & 0x3F;
+        let num_nalus = BigEndian::read_u16(&record[1..3]);
+        record = &record[3..];
+        for _ in 0..num_nalus {
+            let nal = read_nal(&mut record)?;
+            if (32..=34).contains(&nal_type) {
+                out.extend_from_slice(START_CODE);
+                out.extend_from_slice(nal);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A single frame within a `Gop`.
+#[derive(Debug)]
+struct Frame {
+    /// The decode time relative to the start of the file, in 90 kHz units. This is also the PCR
+    /// of the frame's first packet.
+    time_90k: u64,
+    bytes: u32,
+    is_key: bool,
+}
+
+/// A run of frames from a single segment, starting with a PAT and PMT.
+#[derive(Debug)]
+struct Gop {
+    /// Index into the `File`'s segments.
+    segment: usize,
+
+    /// The range within the segment's sample file of this group's frames.
+    sample_file_range: Range<u64>,
+
+    frames: Vec<Frame>,
+
+    /// The continuity counter of the PAT and PMT packets.
+    psi_cc: u8,
+
+    /// The continuity counter of the first video packet.
+    video_cc: u8,
+
+    /// The total length of this group's packets.
+    len: u64,
+}
+
+impl Gop {
+    /// Returns the group's packets, given its frames as read from the sample file.
+    fn packets(&self, codec: &Codec, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let expected_len = self.sample_file_range.end - self.sample_file_range.start;
+        if data.len() as u64 != expected_len {
+            bail!(
+                Internal,
+                msg(
+                    "expected {} bytes of sample data; got {}",
+                    expected_len,
+                    data.len()
+                ),
+            );
+        }
+        let mut out = Vec::with_capacity(usize::try_from(self.len).unwrap());
+        put_psi_packet(&mut out, PID_PAT, self.psi_cc, &pat_section());
+        put_psi_packet(
+            &mut out,
+            PID_PMT,
+            self.psi_cc,
+            &pmt_section(codec.stream_type),
+        );
+        let mut cc = self.video_cc;
+        let mut pes = Vec::new();
+        let mut pos = 0;
+        for f in &self.frames {
+            let bytes = f.bytes as usize;
+            pes.clear();
+            put_pes_header(&mut pes, f.time_90k + PTS_OFFSET_90K);
+            pes.extend_from_slice(codec.aud);
+            if f.is_key {
+                pes.extend_from_slice(&codec.parameter_sets);
+            }
+            put_annex_b(&mut pes, &data[pos..pos + bytes])?;
+            pos += bytes;
+            put_pes_packets(&mut out, &mut cc, f.time_90k, f.is_key, &pes);
+        }
+        if out.len() as u64 != self.len {
+            bail!(
+                Internal,
+                msg("expected {} bytes of packets; got {}", self.len, out.len()),
+            );
+        }
+        Ok(out)
+    }
+}
+
+/// Divides frames into `Gop`s as they're pushed in decode order.
+struct Gops<'a> {
+    codec: &'a Codec,
+    gops: Vec<Gop>,
+    next_video_cc: u8,
+}
+
+impl<'a> Gops<'a> {
+    fn new(codec: &'a Codec) -> Self {
+        Gops {
+            codec,
+            gops: Vec::new(),
+            next_video_cc: 0,
+        }
+    }
+
+    /// Pushes a frame, starting a new group if necessary. `time_90k` is relative to the start of
+    /// the file; `pos` is relative to the start of the segment's sample file.
+    fn push(&mut self, segment: usize, time_90k: u64, pos: u64, bytes: u32, is_key: bool) {
+        let need_new = match self.gops.last() {
+            None => true,
+            Some(g) => is_key || g.segment != segment || g.sample_file_range.end != pos,
+        };
+        if need_new {
+            self.gops.push(Gop {
+                segment,
+                sample_file_range: pos..pos,
+                frames: Vec::new(),
+                psi_cc: (self.gops.len() & 0x0F) as u8,
+                video_cc: self.next_video_cc,
+                len: 2 * PACKET_LEN as u64,
+            });
+        }
+        let g = self.gops.last_mut().expect("group was just pushed");
+        g.frames.push(Frame {
+            time_90k,
+            bytes,
+            is_key,
+        });
+        g.sample_file_range.end += u64::from(bytes);
+        let packets = pes_packets(PES_HEADER_LEN + self.codec.es_len(bytes, is_key));
+        g.len += (packets * PACKET_LEN) as u64;
+        self.next_video_cc = ((usize::from(self.next_video_cc) + packets) & 0x0F) as u8;
+    }
+}
+
+/// The `.ts`-specific state of a `File`.
+pub struct TransportStream {
+    codec: Codec,
+    gops: Vec<Gop>,
+}
+
+impl remux::Format for TransportStream {
+    const NAME: &'static str = "ts";
+    const CONTENT_TYPE: &'static str = "video/mp2t";
+    const FORMAT_VERSION: [u8; 1] = FORMAT_VERSION;
+    type Slice = Slice;
+}
+
+pub type FileBuilder = remux::FileBuilder<TransportStream>;
+pub type File = remux::File<TransportStream>;
+
+impl FileBuilder {
+    /// Builds the `File`, consuming the builder.
+    pub fn build(
+        self,
+        db: Arc<db::Database>,
+        dirs_by_id: Arc<::base::FastHashMap<i32, Arc<dir::SampleFileDir>>>,
+    ) -> Result<File, Error> {
+        let codec = Codec::new(self.video_sample_entry()?)?;
+        let mut gops = Gops::new(&codec);
+        let layout = self.lay_out(&db, |i, time_90k, pos, bytes, is_key| {
+            gops.push(i, time_90k, pos, bytes, is_key);
+            Ok(())
+        })?;
+        let gops = gops.gops;
+        let mut slices = Slices::new();
+        slices.reserve(gops.len());
+        for (i, g) in gops.iter().enumerate() {
+            slices.append(Slice {
+                end: slices.len() + g.len,
+                gop: i,
+            })?;
+        }
+        trace!("slices: {:?}", slices);
+        Ok(self.into_file(layout, dirs_by_id, slices, TransportStream { codec, gops }))
+    }
+}
+
+/// A single slice of a `File`, for use with a `Slices` object: one group of pictures.
+pub struct Slice {
+    end: u64,
+
+    /// Index into `TransportStream::gops`.
+    gop: usize,
+}
+
+impl slices::Slice for Slice {
+    type Ctx = File;
+    type Chunk = Chunk;
+
+    fn end(&self) -> u64 {
+        self.end
+    }
+
+    fn get_range(
+        &self,
+        f: &File,
+        range: Range<u64>,
+        len: u64,
+    ) -> Box<dyn Stream<Item = Result<Self::Chunk, BoxedError>> + Send + Sync> {
+        trace!("getting ts slice {:?}'s range {:?} / {}", self, range, len);
+        let i = self.gop;
+        let g = &f.format().gops[i];
+        f.get_group(
+            g.segment,
+            g.sample_file_range.clone(),
+            range,
+            move |t, data| t.gops[i].packets(&t.codec, data),
+        )
+    }
+
+    fn get_slices(ctx: &File) -> &Slices<Self> {
+        ctx.slices()
+    }
+}
+
+impl fmt::Debug for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // Omit end; Slices writes that part.
+        write!(f, "Gop {}", self.gop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::AVC_DECODER_CONFIG_TEST_INPUT;
+    use db::testutil;
+
+    #[test]
+    fn psi() {
+        testutil::init();
+        assert_eq!(
+            &pat_section()[..],
+            b"\x00\xB0\x0D\x00\x01\xC1\x00\x00\x00\x01\xF0\x00\x2A\xB1\x04\xB2"
+        );
+        assert_eq!(
+            &pmt_section(0x1B)[..],
+            b"\x02\xB0\x12\x00\x01\xC1\x00\x00\xE1\x00\xF0\x00\x1B\xE1\x00\xF0\x00\
+              \x15\xBD\x4D\x56"
+        );
+        let mut out = Vec::new();
+        put_psi_packet(&mut out, PID_PMT, 3, &pmt_section(0x1B));
+        assert_eq!(out.len(), PACKET_LEN);
+        assert_eq!(&out[..5], b"\x47\x50\x00\x13\x00");
+        assert_eq!(out[PACKET_LEN - 1], 0xFF);
+    }
+
+    #[test]
+    fn pes_header() {
+        testutil::init();
+        let mut out = Vec::new();
+        put_pes_header(&mut out, 0x1_2345_6789);
+        assert_eq!(out.len(), PES_HEADER_LEN);
+        assert_eq!(
+            &out[..],
+            b"\x00\x00\x01\xE0\x00\x00\x80\x80\x05\x29\x8D\x15\xCF\x13"
+        );
+    }
+
+    #[test]
+    fn packetize() {
+        testutil::init();
+        for (pes_len, want_packets) in [(1, 1), (176, 1), (177, 2), (176 + 184, 2), (361, 3)] {
+            assert_eq!(pes_packets(pes_len), want_packets, "pes_len={pes_len}");
+            let pes: Vec<u8> = (0..pes_len).map(|i| i as u8).collect();
+            let mut out = Vec::new();
+            let mut cc = 15;
+            put_pes_packets(&mut out, &mut cc, 90_000, true, &pes);
+            assert_eq!(out.len(), want_packets * PACKET_LEN, "pes_len={pes_len}");
+            assert_eq!(usize::from(cc), (15 + want_packets) & 0x0F);
+
+            // First packet: payload_unit_start_indicator, adaptation field with PCR.
+            assert_eq!(&out[..4], b"\x47\x41\x00\x3F");
+            assert_eq!(out[5], 0x50);
+            assert_eq!(&out[6..12], b"\x00\x00\xAF\xC8\x7E\x00");
+
+            // The payload is at the end of each packet.
+            let mut payload = Vec::new();
+            for p in out.chunks(PACKET_LEN) {
+                let start = if p[3] & 0x20 != 0 {
+                    PACKET_HEADER_LEN + 1 + usize::from(p[4])
+                } else {
+                    PACKET_HEADER_LEN
+                };
+                payload.extend_from_slice(&p[start..]);
+            }
+            assert_eq!(payload, pes, "pes_len={pes_len}");
+        }
+    }
+
+    #[test]
+    fn parameter_sets() {
+        testutil::init();
+        let e = crate::h264::parse_extra_data(&AVC_DECODER_CONFIG_TEST_INPUT).unwrap();
+        let e = db::VideoSampleEntry {
+            id: 1,
+            data: e.data,
+            rfc6381_codec: e.rfc6381_codec,
+            width: e.width,
+            height: e.height,
+            pasp_h_spacing: e.pasp_h_spacing,
+            pasp_v_spacing: e.pasp_v_spacing,
+        };
+        let codec = Codec::new(&e).unwrap();
+        assert_eq!(codec.stream_type, 0x1B);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(START_CODE);
+        expected.extend_from_slice(&AVC_DECODER_CONFIG_TEST_INPUT[8..8 + 0x17]);
+        expected.extend_from_slice(START_CODE);
+        expected.extend_from_slice(&AVC_DECODER_CONFIG_TEST_INPUT[34..]);
+        assert_eq!(codec.parameter_sets, expected);
+    }
+
+    #[test]
+    fn annex_b() {
+        testutil::init();
+        let mut out = Vec::new();
+        put_annex_b(&mut out, b"\x00\x00\x00\x02\x65\x88\x00\x00\x00\x01\x06").unwrap();
+        assert_eq!(&out[..], b"\x00\x00\x00\x01\x65\x88\x00\x00\x00\x01\x06");
+        put_annex_b(&mut out, b"\x00\x00\x00\x05\x65").unwrap_err();
+    }
+}
@@ -0,0 +1,442 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Uploading recordings to S3-compatible object storage.
+//!
+//! A single task periodically looks for committed recordings without a row in the `upload`
+//! table. For each, it uploads the sample file and an index manifest (a JSON [`Manifest`],
+//! which holds everything needed to play back the sample file without the SQLite database),
+//! records the upload, and then verifies both objects are present. Optionally, it then deletes
+//! the local copies of verified recordings.
+//!
+//! Objects are named `<prefix><camera uuid>/<stream type>/<16 hex digit recording id>`, with
+//! the manifest adding a `.json` suffix.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::clock::Clocks;
+use base::strutil::hex;
+use base::{bail, err, Error, ErrorKind};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use db::recording;
+use db::CompositeId;
+use futures::TryStreamExt;
+use ring::digest;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::cmds::run::config::UploadConfig;
+
+pub mod s3;
+
+/// The number of recordings to look up at once.
+const BATCH_LEN: usize = 64;
+
+/// The timeout for each S3 request.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The index manifest uploaded alongside each sample file.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    camera_uuid: Uuid,
+    stream_type: &'static str,
+    recording_id: i32,
+    start_time_90k: i64,
+    wall_duration_90k: i32,
+    media_duration_90k: i32,
+    run_offset: i32,
+    flags: i32,
+    sample_file_bytes: i32,
+
+    /// The hex-encoded SHA-256 of the sample file.
+    sample_file_sha256: String,
+    video_samples: i32,
+    video_sync_samples: i32,
+    video_sample_entry: VideoSampleEntry,
+
+    /// The base64-encoded video index; see `design/schema.md#video_index`.
+    video_index: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<Audio>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VideoSampleEntry {
+    rfc6381_codec: String,
+    width: u16,
+    height: u16,
+    pasp_h_spacing: u16,
+    pasp_v_spacing: u16,
+
+    /// The base64-encoded `avc1` or `hvc1` box.
+    data: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Audio {
+    rfc6381_codec: String,
+    sample_rate: u32,
+    channels: u16,
+
+    /// The base64-encoded `mp4a` box.
+    sample_entry_data: String,
+    samples: i32,
+    sample_file_bytes: i32,
+
+    /// The base64-encoded audio index; see `design/schema.md#audio_index`.
+    index: String,
+}
+
+/// A recording read from local storage, ready to upload.
+struct Prepared {
+    id: CompositeId,
+    key: String,
+    sample_file: Vec<u8>,
+    sample_file_sha256: [u8; 32],
+    manifest: Vec<u8>,
+}
+
+pub struct Uploader {
+    client: s3::Client,
+    prefix: String,
+    interval: Duration,
+    delete_local: bool,
+}
+
+impl Uploader {
+    pub fn new(config: &UploadConfig) -> Result<Self, Error> {
+        let endpoint = url::Url::parse(&config.endpoint).map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad upload endpoint {:?}", config.endpoint),
+                source(e)
+            )
+        })?;
+        Ok(Uploader {
+            client: s3::Client::new(
+                endpoint,
+                config.bucket.clone(),
+                config.region.clone(),
+                s3::Credentials {
+                    access_key_id: config.access_key_id.clone(),
+                    secret_access_key: config.secret_access_key.clone(),
+                },
+            )?,
+            prefix: config.prefix.clone(),
+            interval: Duration::from_secs(config.interval_sec),
+            delete_local: config.delete_local,
+        })
+    }
+
+    /// Uploads and verifies all recordings not yet uploaded, then deletes local copies if
+    /// configured to do so.
+    async fn pass<C: Clocks + Clone>(&self, db: &db::Database<C>) -> Result<(), Error> {
+        // Verify uploads left unverified by an earlier failure or shutdown.
+        let unverified = db.lock().list_unverified_uploads()?;
+        for u in unverified {
+            if !self.verify(db, &u).await? {
+                warn!("upload of recording {} is missing; re-uploading", u.id);
+                if let Some(p) = prepare(db, &self.prefix, u.id).await? {
+                    self.upload(db, p).await?;
+                }
+            }
+        }
+
+        let mut after = None;
+        let mut uploaded = 0;
+        loop {
+            let ids = db.lock().list_recordings_to_upload(after, BATCH_LEN)?;
+            let Some(&last) = ids.last() else {
+                break;
+            };
+            after = Some(last);
+            for id in ids {
+                // Problems reading local recordings affect only that recording, so skip it.
+                // Problems talking to the service likely affect all, so give up until next time.
+                let p = match prepare(db, &self.prefix, id).await {
+                    Ok(Some(p)) => p,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        warn!(%err, "unable to read recording {id} for upload");
+                        continue;
+                    }
+                };
+                self.upload(db, p).await?;
+                uploaded += 1;
+            }
+        }
+        if uploaded > 0 {
+            info!("uploaded {uploaded} recordings");
+        }
+
+        if self.delete_local {
+            let stream_ids: Vec<i32> = db.lock().streams_by_id().keys().copied().collect();
+            for stream_id in stream_ids {
+                let n = db.lock().delete_uploaded_recordings(stream_id)?;
+                if n > 0 {
+                    debug!("{stream_id}: deleting {n} uploaded recordings locally");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads a prepared recording's objects, records the upload, and verifies it.
+    async fn upload<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        p: Prepared,
+    ) -> Result<(), Error> {
+        let sample_file_bytes = i32::try_from(p.sample_file.len())
+            .map_err(|_| err!(Internal, msg("recording {} is too large", p.id)))?;
+        self.client
+            .put_object(&p.key, "application/octet-stream", p.sample_file, TIMEOUT)
+            .await?;
+        self.client
+            .put_object(
+                &format!("{}.json", p.key),
+                "application/json",
+                p.manifest,
+                TIMEOUT,
+            )
+            .await?;
+        let upload = db::Upload {
+            id: p.id,
+            object_key: p.key,
+            sample_file_bytes,
+            sample_file_sha256: p.sample_file_sha256,
+            upload_time: recording::Time::new(db.clocks().realtime()),
+            verify_time: None,
+        };
+        db.lock().insert_upload(&upload)?;
+        if !self.verify(db, &upload).await? {
+            bail!(
+                Unknown,
+                msg(
+                    "upload of recording {} is missing immediately after upload",
+                    upload.id
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that an upload's objects are present, with the sample file at the expected size.
+    /// If so, marks the upload as verified and returns true.
+    async fn verify<C: Clocks + Clone>(
+        &self,
+        db: &db::Database<C>,
+        u: &db::Upload,
+    ) -> Result<bool, Error> {
+        let Some(len) = self.client.head_object(&u.object_key, TIMEOUT).await? else {
+            return Ok(false);
+        };
+        if len != u64::try_from(u.sample_file_bytes).unwrap_or(0) {
+            warn!(
+                "upload of recording {} has {len} bytes; expected {}",
+                u.id, u.sample_file_bytes
+            );
+            return Ok(false);
+        }
+        let manifest_key = format!("{}.json", u.object_key);
+        if self
+            .client
+            .head_object(&manifest_key, TIMEOUT)
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        db.lock()
+            .mark_upload_verified(u.id, recording::Time::new(db.clocks().realtime()))?;
+        Ok(true)
+    }
+}
+
+/// Returns the object key of the given recording's sample file.
+fn object_key(
+    prefix: &str,
+    camera_uuid: Uuid,
+    stream_type: db::StreamType,
+    id: CompositeId,
+) -> String {
+    format!(
+        "{prefix}{camera_uuid}/{}/{:016x}",
+        stream_type.as_str(),
+        id.0
+    )
+}
+
+/// Reads a recording from local storage and builds its manifest, or returns `None` if it has
+/// since been deleted.
+async fn prepare<C: Clocks + Clone>(
+    db: &db::Database<C>,
+    prefix: &str,
+    id: CompositeId,
+) -> Result<Option<Prepared>, Error> {
+    let (dir, sample_file_key, key, mut manifest) = {
+        let l = db.lock();
+        let mut row = None;
+        l.list_recordings_by_id(id.stream(), id.recording()..id.recording() + 1, &mut |r| {
+            row = Some(r);
+            Ok(())
+        })?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let stream = l
+            .streams_by_id()
+            .get(&id.stream())
+            .ok_or_else(|| err!(Internal, msg("no stream for recording {id}")))?;
+        let camera = l
+            .cameras_by_id()
+            .get(&stream.camera_id)
+            .ok_or_else(|| err!(Internal, msg("no camera for recording {id}")))?;
+        let dir_id = row
+            .sample_file_dir_id
+            .or(stream.sample_file_dir_id)
+            .ok_or_else(|| err!(Internal, msg("no sample file dir for recording {id}")))?;
+        let dir = l
+            .sample_file_dirs_by_id()
+            .get(&dir_id)
+            .ok_or_else(|| err!(Internal, msg("no dir {dir_id}")))?
+            .get()?;
+        let sample_file_key = l.recording_key(id)?;
+        let (video_index, audio_index) = l.with_recording_playback(id, &mut |p| {
+            Ok((
+                STANDARD.encode(p.video_index),
+                p.audio_index.map(|a| STANDARD.encode(a)),
+            ))
+        })?;
+        let vse = l
+            .video_sample_entries_by_id()
+            .get(&row.video_sample_entry_id)
+            .ok_or_else(|| err!(Internal, msg("no video sample entry for recording {id}")))?;
+        let audio = match (row.audio_sample_entry_id, audio_index) {
+            (Some(ase_id), Some(index)) => {
+                let ase = l
+                    .audio_sample_entries_by_id()
+                    .get(&ase_id)
+                    .ok_or_else(|| err!(Internal, msg("no audio sample entry {ase_id}")))?;
+                Some(Audio {
+                    rfc6381_codec: ase.rfc6381_codec.clone(),
+                    sample_rate: ase.sample_rate,
+                    channels: ase.channels,
+                    sample_entry_data: STANDARD.encode(&ase.data),
+                    samples: row.audio_samples,
+                    sample_file_bytes: row.audio_sample_file_bytes,
+                    index,
+                })
+            }
+            _ => None,
+        };
+        let manifest = Manifest {
+            camera_uuid: camera.uuid,
+            stream_type: stream.type_.as_str(),
+            recording_id: id.recording(),
+            start_time_90k: row.start.0,
+            wall_duration_90k: row.wall_duration_90k,
+            media_duration_90k: row.media_duration_90k,
+            run_offset: row.run_offset,
+            // The uploaded sample file is decrypted.
+            flags: row.flags & !(db::RecordingFlags::Encrypted as i32),
+            sample_file_bytes: row.sample_file_bytes,
+            sample_file_sha256: String::new(),
+            video_samples: row.video_samples,
+            video_sync_samples: row.video_sync_samples,
+            video_sample_entry: VideoSampleEntry {
+                rfc6381_codec: vse.rfc6381_codec.clone(),
+                width: vse.width,
+                height: vse.height,
+                pasp_h_spacing: vse.pasp_h_spacing,
+                pasp_v_spacing: vse.pasp_v_spacing,
+                data: STANDARD.encode(&vse.data),
+            },
+            video_index,
+            audio,
+        };
+        (
+            dir,
+            sample_file_key,
+            object_key(prefix, camera.uuid, stream.type_, id),
+            manifest,
+        )
+    };
+    let len = u64::try_from(manifest.sample_file_bytes).unwrap_or(0);
+    let sample_file: Vec<u8> = match dir
+        .open_file(id, 0..len, sample_file_key)
+        .try_concat()
+        .await
+    {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if sample_file.len() as u64 != len {
+        bail!(
+            DataLoss,
+            msg(
+                "recording {id} has {} bytes; expected {len}",
+                sample_file.len()
+            )
+        );
+    }
+    let sha256 = digest::digest(&digest::SHA256, &sample_file);
+    let sample_file_sha256: [u8; 32] = sha256.as_ref().try_into().expect("SHA-256 is 32 bytes");
+    manifest.sample_file_sha256 = hex(&sample_file_sha256);
+    let manifest = serde_json::to_vec(&manifest).expect("manifest is serializable");
+    Ok(Some(Prepared {
+        id,
+        key,
+        sample_file,
+        sample_file_sha256,
+        manifest,
+    }))
+}
+
+/// Runs the uploader until shutdown.
+pub async fn run<C: Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    uploader: Uploader,
+) {
+    info!("starting uploads every {:?}", uploader.interval);
+    loop {
+        tokio::select! {
+            r = uploader.pass(&db) => {
+                if let Err(err) = r {
+                    warn!(%err, "upload failed");
+                }
+            }
+            _ = shutdown_rx.as_future() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(uploader.interval) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use db::{CompositeId, StreamType};
+    use uuid::Uuid;
+
+    #[test]
+    fn object_key() {
+        testutil::init();
+        let uuid = Uuid::parse_str("7f2e4f8e-4a49-4a6a-a3c1-0c6b2f3e8d7a").unwrap();
+        assert_eq!(
+            super::object_key("nvr/", uuid, StreamType::Sub, CompositeId::new(1, 2)),
+            "nvr/7f2e4f8e-4a49-4a6a-a3c1-0c6b2f3e8d7a/sub/0000000100000002"
+        );
+    }
+}
@@ -0,0 +1,468 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Capture from local Video4Linux2 devices, such as a Raspberry Pi's USB or CSI camera.
+//!
+//! A stream URL such as `v4l2:///dev/video0?width=1280&height=720&fps=15` names the device and
+//! optionally its capture format. If the device encodes H.264 itself (as many USB webcams and the
+//! Raspberry Pi's camera stack can), its output is stored as-is. Otherwise, when built with the
+//! `ffmpeg` feature, raw YUYV or MJPEG frames are encoded via FFmpeg: by default with the
+//! `h264_v4l2m2m` hardware encoder if available, falling back to `libx264`. The `encoder`
+//! parameter chooses another, and `bitrate` sets its target in bits per second.
+//!
+//! Capture requires building with the `v4l2` feature, which is available only on Linux.
+
+use base::Error;
+use url::Url;
+
+use crate::stream::{Options, Stream};
+
+/// Capture parameters from the URL's query string.
+#[cfg_attr(not(feature = "v4l2"), allow(dead_code))]
+#[derive(Debug, Default, PartialEq)]
+struct Params {
+    width: Option<u32>,
+    height: Option<u32>,
+    fps: Option<u32>,
+    encoder: Option<String>,
+    bitrate: Option<usize>,
+}
+
+#[cfg_attr(not(feature = "v4l2"), allow(dead_code))]
+impl Params {
+    fn parse(url: &Url) -> Result<Self, Error> {
+        let mut p = Params::default();
+        for (key, value) in url.query_pairs() {
+            fn num<T: std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, Error> {
+                value.parse().map(Some).map_err(|_| {
+                    base::err!(InvalidArgument, msg("bad V4L2 {key} parameter {value:?}"))
+                })
+            }
+            match &*key {
+                "width" => p.width = num(&key, &value)?,
+                "height" => p.height = num(&key, &value)?,
+                "fps" => p.fps = num(&key, &value)?,
+                "bitrate" => p.bitrate = num(&key, &value)?,
+                "encoder" => p.encoder = Some(value.into_owned()),
+                _ => base::bail!(InvalidArgument, msg("unknown V4L2 parameter {key:?}")),
+            }
+        }
+        if url.path().is_empty() || url.path() == "/" {
+            base::bail!(
+                InvalidArgument,
+                msg("V4L2 URL should name a device, such as v4l2:///dev/video0")
+            );
+        }
+        Ok(p)
+    }
+}
+
+/// Opens the V4L2 device named by a `v4l2://` URL. The stream's RTSP, TLS, and audio options are
+/// ignored.
+#[cfg(feature = "v4l2")]
+pub fn open(label: String, url: Url, options: Options) -> Result<Box<dyn Stream>, Error> {
+    capture::open(label, url, options)
+}
+
+#[cfg(not(feature = "v4l2"))]
+pub fn open(_label: String, url: Url, _options: Options) -> Result<Box<dyn Stream>, Error> {
+    Params::parse(&url)?;
+    base::bail!(
+        Unimplemented,
+        msg("V4L2 capture requires building Moonfire NVR with --features=v4l2")
+    );
+}
+
+#[cfg(feature = "v4l2")]
+mod capture {
+    use std::time::Instant;
+
+    use base::{bail, err, Error};
+    use bytes::Bytes;
+    use tracing::{debug, info, warn};
+    use url::Url;
+    use v4l::buffer::Type;
+    use v4l::io::mmap::Stream as MmapStream;
+    use v4l::io::traits::CaptureStream;
+    use v4l::video::Capture;
+    use v4l::FourCC;
+
+    use super::Params;
+    use crate::stream::{Options, Stream, VideoFrame};
+
+    /// The number of buffers to queue with the driver.
+    const BUFFERS: u32 = 4;
+
+    const H264: FourCC = FourCC { repr: *b"H264" };
+    const YUYV: FourCC = FourCC { repr: *b"YUYV" };
+    const MJPG: FourCC = FourCC { repr: *b"MJPG" };
+
+    enum Source {
+        /// The device supplies H.264 in Annex B form.
+        H264,
+
+        /// Raw frames are encoded via FFmpeg.
+        #[cfg(feature = "ffmpeg")]
+        Encode(Box<super::encode::Encoder>),
+    }
+
+    struct V4l2Stream {
+        label: String,
+        stream: MmapStream<'static>,
+        source: Source,
+        video_sample_entry: db::VideoSampleEntryToInsert,
+
+        /// The first frame, if not yet returned from `next`.
+        first_frame: Option<VideoFrame>,
+
+        /// Encoded access units (pts and Annex B data) not yet returned.
+        pending: std::collections::VecDeque<(i64, Vec<u8>)>,
+
+        /// The first capture timestamp in microseconds, and when it was captured.
+        start: Option<(i64, Instant)>,
+    }
+
+    pub(super) fn open(
+        label: String,
+        url: Url,
+        options: Options,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let params = Params::parse(&url)?;
+        if options.audio_setup.is_some() {
+            warn!("{label}: audio is unsupported with V4L2 devices");
+        }
+        let path = url.path();
+        let dev = v4l::Device::with_path(path)
+            .map_err(|e| err!(e, msg("unable to open V4L2 device {path}")))?;
+        let offered: Vec<FourCC> = dev
+            .enum_formats()
+            .map_err(|e| err!(e, msg("unable to list formats of {path}")))?
+            .into_iter()
+            .map(|d| d.fourcc)
+            .collect();
+        let fourcc = if offered.contains(&H264) {
+            H264
+        } else if cfg!(feature = "ffmpeg") {
+            match [YUYV, MJPG].into_iter().find(|f| offered.contains(f)) {
+                Some(f) => f,
+                None => bail!(
+                    Unimplemented,
+                    msg("{path} offers none of H264, YUYV, or MJPG; it offers {offered:?}")
+                ),
+            }
+        } else {
+            bail!(
+                Unimplemented,
+                msg(
+                    "{path} doesn't encode H.264 itself; software encoding requires building \
+                     with --features=ffmpeg"
+                )
+            );
+        };
+        let mut format = dev
+            .format()
+            .map_err(|e| err!(e, msg("unable to get format")))?;
+        format.fourcc = fourcc;
+        if let Some(w) = params.width {
+            format.width = w;
+        }
+        if let Some(h) = params.height {
+            format.height = h;
+        }
+        let format = dev
+            .set_format(&format)
+            .map_err(|e| err!(e, msg("unable to set format of {path}")))?;
+        if format.fourcc != fourcc {
+            bail!(
+                FailedPrecondition,
+                msg("{path} chose format {} rather than {fourcc}", format.fourcc)
+            );
+        }
+        if let Some(fps) = params.fps {
+            dev.set_params(&v4l::video::capture::Parameters::with_fps(fps))
+                .map_err(|e| err!(e, msg("unable to set frame rate of {path}")))?;
+        }
+        info!(
+            "{label}: capturing {}x{} {} from {path}",
+            format.width, format.height, format.fourcc
+        );
+        let source = if fourcc == H264 {
+            Source::H264
+        } else {
+            encode_source(&params, &format)?
+        };
+        let mut stream = MmapStream::with_buffers(&dev, Type::VideoCapture, BUFFERS)
+            .map_err(|e| err!(e, msg("unable to map buffers of {path}")))?;
+        stream.set_timeout(options.connect_timeout);
+        let mut self_ = V4l2Stream {
+            label,
+            stream,
+            source,
+            video_sample_entry: db::VideoSampleEntryToInsert {
+                data: Vec::new(),
+                rfc6381_codec: String::new(),
+                width: 0,
+                height: 0,
+                pasp_h_spacing: 1,
+                pasp_v_spacing: 1,
+            },
+            first_frame: None,
+            pending: std::collections::VecDeque::new(),
+            start: None,
+        };
+
+        // The first frame must be a key frame with parameter sets.
+        let deadline = Instant::now() + options.connect_timeout;
+        let first_frame = loop {
+            if Instant::now() >= deadline {
+                bail!(DeadlineExceeded, msg("timeout getting first key frame"));
+            }
+            let f = self_.next_frame()?;
+            if f.is_key && !self_.video_sample_entry.data.is_empty() {
+                break f;
+            }
+        };
+        self_.first_frame = Some(VideoFrame {
+            new_video_sample_entry: false,
+            ..first_frame
+        });
+        self_.stream.set_timeout(options.frame_timeout);
+        Ok(Box::new(self_))
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    fn encode_source(params: &Params, format: &v4l::Format) -> Result<Source, Error> {
+        Ok(Source::Encode(Box::new(super::encode::Encoder::new(
+            params, format,
+        )?)))
+    }
+
+    #[cfg(not(feature = "ffmpeg"))]
+    fn encode_source(_params: &Params, _format: &v4l::Format) -> Result<Source, Error> {
+        unreachable!("raw formats are chosen only with ffmpeg")
+    }
+
+    impl V4l2Stream {
+        /// Returns the pts of a capture with the given timestamp, in 90 kHz units since the first.
+        fn pts(&mut self, timestamp: v4l::Timestamp) -> i64 {
+            let micros = i64::from(timestamp.sec) * 1_000_000 + i64::from(timestamp.usec);
+            let now = Instant::now();
+            let &mut (start_micros, start_instant) = self.start.get_or_insert((micros, now));
+            let elapsed = if micros == 0 {
+                // Some drivers don't supply timestamps.
+                i64::try_from(now.duration_since(start_instant).as_micros()).unwrap_or(i64::MAX)
+            } else {
+                micros - start_micros
+            };
+            elapsed * 9 / 100
+        }
+
+        /// Captures until an access unit is available, returning its pts and Annex B data.
+        fn next_access_unit(&mut self) -> Result<(i64, Vec<u8>), Error> {
+            loop {
+                if let Some(au) = self.pending.pop_front() {
+                    return Ok(au);
+                }
+                let (buf, meta) = CaptureStream::next(&mut self.stream).map_err(|e| {
+                    if e.kind() == std::io::ErrorKind::TimedOut {
+                        err!(DeadlineExceeded, msg("timeout getting next frame"))
+                    } else {
+                        err!(e, msg("unable to capture frame"))
+                    }
+                })?;
+                let data = buf[..std::cmp::min(meta.bytesused as usize, buf.len())].to_vec();
+                let timestamp = meta.timestamp;
+                let pts = self.pts(timestamp);
+                match &mut self.source {
+                    Source::H264 => return Ok((pts, data)),
+                    #[cfg(feature = "ffmpeg")]
+                    Source::Encode(e) => self.pending.extend(e.push(&data, pts)?),
+                }
+            }
+        }
+
+        fn next_frame(&mut self) -> Result<VideoFrame, Error> {
+            loop {
+                let (pts, data) = self.next_access_unit()?;
+                let au = crate::h264::convert_annex_b(&data)?;
+                let mut new_video_sample_entry = false;
+                if let (Some(sps), Some(pps)) = (au.sps, au.pps) {
+                    let config = crate::h264::avc_decoder_config(sps, pps)?;
+                    let e = crate::h264::parse_extra_data(&config)?;
+                    if e.data != self.video_sample_entry.data {
+                        debug!("{}: new video sample entry", &self.label);
+                        self.video_sample_entry = e;
+                        new_video_sample_entry = true;
+                    }
+                }
+                if au.data.is_empty() || self.video_sample_entry.data.is_empty() {
+                    continue;
+                }
+                return Ok(VideoFrame {
+                    pts,
+                    duration: 0,
+                    is_key: au.is_key,
+                    data: Bytes::from(au.data),
+                    new_video_sample_entry,
+                    loss: 0,
+                });
+            }
+        }
+    }
+
+    impl Stream for V4l2Stream {
+        fn tool(&self) -> Option<&retina::client::Tool> {
+            None
+        }
+
+        fn video_sample_entry(&self) -> &db::VideoSampleEntryToInsert {
+            &self.video_sample_entry
+        }
+
+        fn next(&mut self) -> Result<VideoFrame, Error> {
+            if let Some(f) = self.first_frame.take() {
+                return Ok(f);
+            }
+            self.next_frame()
+        }
+    }
+}
+
+/// Software H.264 encoding of raw captures via FFmpeg.
+#[cfg(all(feature = "v4l2", feature = "ffmpeg"))]
+mod encode {
+    use base::{bail, err, Error};
+    use ffmpeg_next::util::frame::video::Video;
+    use ffmpeg_next::{codec, encoder, format::Pixel, Dictionary, Packet};
+    use tracing::info;
+
+    use super::Params;
+
+    /// Encoders to try, in order, when the URL doesn't name one.
+    const DEFAULT_ENCODERS: [&str; 2] = ["h264_v4l2m2m", "libx264"];
+
+    const DEFAULT_BITRATE: usize = 2_000_000;
+
+    pub(super) struct Encoder {
+        encoder: encoder::video::Encoder,
+        fourcc: v4l::FourCC,
+        width: u32,
+        height: u32,
+        stride: usize,
+    }
+
+    impl Encoder {
+        pub(super) fn new(params: &Params, format: &v4l::Format) -> Result<Self, Error> {
+            crate::jpeg::ffmpeg::init();
+            let codec = match params.encoder.as_deref() {
+                Some(name) => encoder::find_by_name(name)
+                    .ok_or_else(|| err!(NotFound, msg("FFmpeg has no encoder {name:?}")))?,
+                None => DEFAULT_ENCODERS
+                    .iter()
+                    .find_map(|n| encoder::find_by_name(n))
+                    .ok_or_else(|| err!(Unimplemented, msg("FFmpeg has no H.264 encoder")))?,
+            };
+            info!("encoding with FFmpeg's {}", codec.name());
+            let mut e = codec::context::Context::new_with_codec(codec)
+                .encoder()
+                .video()
+                .map_err(|e| err!(Unknown, msg("unable to create encoder"), source(e)))?;
+            e.set_width(format.width);
+            e.set_height(format.height);
+            e.set_format(Pixel::YUV420P);
+            e.set_time_base((1, 90_000));
+            let fps = params.fps.unwrap_or(15);
+            e.set_frame_rate(Some((fps as i32, 1)));
+            e.set_gop(2 * fps);
+            e.set_max_b_frames(0);
+            e.set_bit_rate(params.bitrate.unwrap_or(DEFAULT_BITRATE));
+            let mut opts = Dictionary::new();
+            if codec.name() == "libx264" {
+                opts.set("preset", "veryfast");
+                opts.set("tune", "zerolatency");
+            }
+            let encoder = e
+                .open_with(opts)
+                .map_err(|e| err!(Unknown, msg("unable to open encoder"), source(e)))?;
+            Ok(Encoder {
+                encoder,
+                fourcc: format.fourcc,
+                width: format.width,
+                height: format.height,
+                stride: format.stride as usize,
+            })
+        }
+
+        /// Converts a raw capture to a decoded frame.
+        fn decode(&self, raw: &[u8]) -> Result<Video, Error> {
+            if self.fourcc == v4l::FourCC::new(b"MJPG") {
+                return crate::jpeg::ffmpeg::decode_jpeg(raw);
+            }
+            let mut frame = Video::new(Pixel::YUYV422, self.width, self.height);
+            let row_len = 2 * self.width as usize;
+            let dst_stride = frame.stride(0);
+            for row in 0..self.height as usize {
+                let Some(src) = raw.get(row * self.stride..row * self.stride + row_len) else {
+                    bail!(
+                        InvalidArgument,
+                        msg("short YUYV capture of {} bytes", raw.len())
+                    );
+                };
+                frame.data_mut(0)[row * dst_stride..][..row_len].copy_from_slice(src);
+            }
+            Ok(frame)
+        }
+
+        /// Encodes a raw capture, returning any access units (pts and Annex B data) now ready.
+        pub(super) fn push(&mut self, raw: &[u8], pts: i64) -> Result<Vec<(i64, Vec<u8>)>, Error> {
+            let decoded = self.decode(raw)?;
+            let mut yuv =
+                crate::jpeg::ffmpeg::scale_to(&decoded, Pixel::YUV420P, self.width, self.height)?;
+            yuv.set_pts(Some(pts));
+            self.encoder
+                .send_frame(&yuv)
+                .map_err(|e| err!(Unknown, msg("unable to encode frame"), source(e)))?;
+            let mut out = Vec::new();
+            loop {
+                let mut packet = Packet::empty();
+                match self.encoder.receive_packet(&mut packet) {
+                    Ok(()) => out.push((
+                        packet.pts().unwrap_or(pts),
+                        packet.data().unwrap_or_default().to_vec(),
+                    )),
+                    Err(ffmpeg_next::Error::Other {
+                        errno: ffmpeg_next::util::error::EAGAIN,
+                    }) => break,
+                    Err(e) => bail!(Unknown, msg("unable to encode frame"), source(e)),
+                }
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    #[test]
+    fn params() {
+        testutil::init();
+        let url = Url::parse("v4l2:///dev/video0?width=1280&height=720&fps=15").unwrap();
+        assert_eq!(url.path(), "/dev/video0");
+        assert_eq!(
+            Params::parse(&url).unwrap(),
+            Params {
+                width: Some(1280),
+                height: Some(720),
+                fps: Some(15),
+                ..Default::default()
+            }
+        );
+        Params::parse(&Url::parse("v4l2:///dev/video0?fps=x").unwrap()).unwrap_err();
+        Params::parse(&Url::parse("v4l2:///dev/video0?zoom=2").unwrap()).unwrap_err();
+        Params::parse(&Url::parse("v4l2:///").unwrap()).unwrap_err();
+    }
+}
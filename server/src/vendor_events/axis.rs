@@ -0,0 +1,350 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Axis VAPIX event streaming over WebSocket.
+//!
+//! Moonfire NVR fetches a session token from `wssession.cgi` with the camera's credentials,
+//! opens `/vapix/ws-data-stream` with that token, and sends an `events:configure` request with a
+//! topic filter for each configured signal. The camera then sends an `events:notify` message as
+//! each matching event changes. Stateful events report their state in `data` under a name which
+//! varies by topic: `State` for `tns1:VideoSource/MotionAlarm`, `active` for ACAP applications'
+//! events such as AXIS Object Analytics scenarios, and so on.
+
+use std::time::Duration;
+
+use base::{bail, err, Error};
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+use super::{Action, Camera, Event, Kind};
+use crate::multipart;
+
+pub(super) type Stream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// How long to wait for the camera to acknowledge the `events:configure` request.
+const CONFIGURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `context` of requests, echoed in responses.
+const CONTEXT: &str = "moonfire-nvr";
+
+/// Topics for each built-in kind of event. Descendants also match, such as
+/// `tnsaxis:CameraApplicationPlatform/VMD/Camera1Profile1` for a VMD 4 profile.
+const TOPICS: &[(Kind, &[&str])] = &[
+    (
+        Kind::Motion,
+        &[
+            "tns1:VideoSource/MotionAlarm",
+            "tnsaxis:CameraApplicationPlatform/VMD",
+        ],
+    ),
+    (
+        Kind::LineCrossing,
+        &[
+            "tnsaxis:CameraApplicationPlatform/CrossLineDetection",
+            "tnsaxis:CameraApplicationPlatform/FenceGuard",
+        ],
+    ),
+    (
+        Kind::Tamper,
+        &[
+            "tns1:VideoSource/Tampering",
+            "tns1:VideoSource/GlobalSceneChange/ImagingService",
+        ],
+    ),
+];
+
+/// Names of `data` items which report a stateful event's state.
+const STATE_ITEMS: &[&str] = &["active", "State", "tampering", "LogicalState", "triggered"];
+
+/// Returns the `events:configure` request for the given signals.
+fn configure_request(signals: &[(Kind, u32)]) -> String {
+    let mut filters = Vec::new();
+    for (kind, _) in signals {
+        match kind {
+            Kind::Topic(t) => filters.push(serde_json::json!({ "topicFilter": t })),
+            _ => {
+                let topics = TOPICS.iter().filter(|(k, _)| k == kind);
+                for t in topics.flat_map(|(_, t)| t.iter()) {
+                    filters.push(serde_json::json!({ "topicFilter": format!("{t}//.") }));
+                }
+            }
+        }
+    }
+    serde_json::json!({
+        "apiVersion": "1.0",
+        "context": CONTEXT,
+        "method": "events:configure",
+        "params": { "eventFilterList": filters },
+    })
+    .to_string()
+}
+
+/// Connects to `camera`'s event stream and configures it.
+pub(super) async fn connect(client: &multipart::Client, camera: &Camera) -> Result<Stream, Error> {
+    let join = |path: &str| {
+        camera
+            .base_url
+            .join(path)
+            .map_err(|e| err!(InvalidArgument, msg("bad base URL"), source(e)))
+    };
+    let resp = multipart::get(
+        client,
+        &join("axis-cgi/wssession.cgi")?,
+        camera.creds.as_ref(),
+    )
+    .await?;
+    let token = hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to read session token"), source(e)))?;
+    let token = std::str::from_utf8(&token)
+        .map_err(|e| err!(InvalidArgument, msg("session token isn't UTF-8"), source(e)))?
+        .trim();
+    let mut url = join("vapix/ws-data-stream")?;
+    if url.scheme() != "http" {
+        bail!(
+            Unimplemented,
+            msg("Axis event streams are only supported with http base URLs")
+        );
+    }
+    url.set_scheme("ws").expect("http URLs can be ws");
+    url.query_pairs_mut()
+        .append_pair("wssession", token)
+        .append_pair("sources", "events");
+    let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to open event stream"), source(e)))?;
+    ws.send(tungstenite::Message::Text(configure_request(
+        &camera.signals,
+    )))
+    .await
+    .map_err(|e| {
+        err!(
+            Unavailable,
+            msg("unable to configure event stream"),
+            source(e)
+        )
+    })?;
+    tokio::time::timeout(CONFIGURE_TIMEOUT, configured(&mut ws))
+        .await
+        .map_err(|_| err!(DeadlineExceeded, msg("event configuration timed out")))??;
+    Ok(ws)
+}
+
+/// Waits for the response to the `events:configure` request.
+async fn configured(ws: &mut Stream) -> Result<(), Error> {
+    loop {
+        let Some(text) = next(ws).await? else {
+            continue;
+        };
+        let m: Value = serde_json::from_str(&text)
+            .map_err(|e| err!(InvalidArgument, msg("bad event stream message"), source(e)))?;
+        if m["method"] != "events:configure" {
+            continue;
+        }
+        if let Some(e) = m.get("error") {
+            bail!(
+                FailedPrecondition,
+                msg("camera rejected event configuration: {e}")
+            );
+        }
+        return Ok(());
+    }
+}
+
+/// Returns the next text message, or `None` for other messages, such as pongs.
+pub(super) async fn next(ws: &mut Stream) -> Result<Option<String>, Error> {
+    match ws.next().await {
+        Some(Ok(tungstenite::Message::Text(t))) => Ok(Some(t)),
+        Some(Ok(tungstenite::Message::Close(_))) | None => {
+            bail!(Unavailable, msg("end of event stream"))
+        }
+        Some(Ok(_)) => Ok(None),
+        Some(Err(e)) => bail!(Unavailable, msg("unable to read event stream"), source(e)),
+    }
+}
+
+/// Prompts the camera to send a pong, so a healthy but idle stream isn't considered stalled.
+pub(super) async fn ping(ws: &mut Stream) -> Result<(), Error> {
+    ws.send(tungstenite::Message::Ping(Vec::new()))
+        .await
+        .map_err(|e| err!(Unavailable, msg("unable to ping event stream"), source(e)))
+}
+
+/// Returns the state reported by a `data` value, if it's boolean.
+fn parse_state(v: &Value) -> Option<bool> {
+    match v {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => match s.as_str() {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        },
+        Value::Number(n) => match n.as_u64()? {
+            1 => Some(true),
+            0 => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parses a message, returning its events. Each stateful event is returned under its topic and
+/// also its built-in kind, if any.
+pub(super) fn parse_message(text: &str) -> Result<Vec<Event>, Error> {
+    let m: Value = serde_json::from_str(text)
+        .map_err(|e| err!(InvalidArgument, msg("bad event stream message"), source(e)))?;
+    if m["method"] != "events:notify" {
+        return Ok(Vec::new());
+    }
+    let notification = &m["params"]["notification"];
+    let topic = notification["topic"]
+        .as_str()
+        .ok_or_else(|| err!(InvalidArgument, msg("notification has no topic")))?;
+    let data = &notification["message"]["data"];
+    let Some(active) = STATE_ITEMS.iter().find_map(|&n| parse_state(data.get(n)?)) else {
+        return Ok(Vec::new());
+    };
+    let action = match active {
+        true => Action::Start,
+        false => Action::Stop,
+    };
+    let mut events = vec![Event {
+        kind: Kind::Topic(topic.to_owned()),
+        channel: None,
+        action,
+    }];
+    for (kind, topics) in TOPICS {
+        let matches = |t: &&str| {
+            topic
+                .strip_prefix(t)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        if topics.iter().any(matches) {
+            events.push(Event {
+                kind: kind.clone(),
+                channel: None,
+                action,
+            });
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    fn notify(topic: &str, data: &str) -> String {
+        format!(
+            r#"{{"apiVersion": "1.0", "method": "events:notify", "params": {{"notification": {{
+                  "topic": "{topic}", "timestamp": 1709296496000,
+                  "message": {{"source": {{}}, "key": {{}}, "data": {data}}}}}}}}}"#
+        )
+    }
+
+    #[test]
+    fn parse() {
+        testutil::init();
+        let topic_event = |topic: &str, action| Event {
+            kind: Kind::Topic(topic.to_owned()),
+            channel: None,
+            action,
+        };
+        let builtin_event = |kind, action| Event {
+            kind,
+            channel: None,
+            action,
+        };
+        assert_eq!(
+            parse_message(&notify("tns1:VideoSource/MotionAlarm", r#"{"State": "1"}"#)).unwrap(),
+            [
+                topic_event("tns1:VideoSource/MotionAlarm", Action::Start),
+                builtin_event(Kind::Motion, Action::Start),
+            ]
+        );
+        assert_eq!(
+            parse_message(&notify(
+                "tnsaxis:CameraApplicationPlatform/VMD/Camera1ProfileANY",
+                r#"{"active": "0"}"#
+            ))
+            .unwrap(),
+            [
+                topic_event(
+                    "tnsaxis:CameraApplicationPlatform/VMD/Camera1ProfileANY",
+                    Action::Stop
+                ),
+                builtin_event(Kind::Motion, Action::Stop),
+            ]
+        );
+        assert_eq!(
+            parse_message(&notify(
+                "tnsaxis:CameraApplicationPlatform/ObjectAnalytics/Device1Scenario2",
+                r#"{"active": "1", "triggerTime": "2024-03-01T12:34:56Z"}"#
+            ))
+            .unwrap(),
+            [topic_event(
+                "tnsaxis:CameraApplicationPlatform/ObjectAnalytics/Device1Scenario2",
+                Action::Start
+            )]
+        );
+
+        // A topic which merely starts with a built-in topic isn't that kind.
+        assert_eq!(
+            parse_message(&notify(
+                "tnsaxis:CameraApplicationPlatform/VMDX",
+                r#"{"active": true}"#
+            ))
+            .unwrap(),
+            [topic_event(
+                "tnsaxis:CameraApplicationPlatform/VMDX",
+                Action::Start
+            )]
+        );
+
+        // Stateless events and other messages are ignored.
+        assert_eq!(
+            parse_message(&notify(
+                "tns1:Device/Trigger/Relay",
+                r#"{"RelayToken": "0"}"#
+            ))
+            .unwrap(),
+            []
+        );
+        assert_eq!(
+            parse_message(
+                r#"{"apiVersion": "1.0", "context": "moonfire-nvr",
+                    "method": "events:configure", "data": {}}"#
+            )
+            .unwrap(),
+            []
+        );
+        parse_message("not json").unwrap_err();
+    }
+
+    #[test]
+    fn configure() {
+        testutil::init();
+        let req: Value = serde_json::from_str(&configure_request(&[
+            (Kind::Tamper, 1),
+            (
+                Kind::Topic(
+                    "tnsaxis:CameraApplicationPlatform/ObjectAnalytics/Device1Scenario1".to_owned(),
+                ),
+                2,
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(req["method"], "events:configure");
+        assert_eq!(
+            req["params"]["eventFilterList"],
+            serde_json::json!([
+                {"topicFilter": "tns1:VideoSource/Tampering//."},
+                {"topicFilter": "tns1:VideoSource/GlobalSceneChange/ImagingService//."},
+                {"topicFilter": "tnsaxis:CameraApplicationPlatform/ObjectAnalytics/Device1Scenario1"},
+            ])
+        );
+    }
+}
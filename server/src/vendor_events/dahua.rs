@@ -0,0 +1,122 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Dahua's `eventManager.cgi` attachment.
+//!
+//! Each part is text with one or more lines such as `Code=VideoMotion;action=Start;index=0`,
+//! possibly followed by `;data={...}` with event-specific JSON. The `heartbeat` parameter asks
+//! the camera to send a `Heartbeat` line every few seconds when nothing is happening.
+
+use base::{err, Error};
+
+use super::{Action, Event, Kind};
+use crate::multipart;
+
+pub(super) const PATH: &str = "cgi-bin/eventManager.cgi?action=attach&codes=[All]&heartbeat=5";
+
+pub(super) fn parse_part(part: &multipart::Part) -> Result<Vec<Event>, Error> {
+    let body = std::str::from_utf8(&part.body).map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("eventManager part isn't UTF-8"),
+            source(e)
+        )
+    })?;
+    let mut events = Vec::new();
+    for line in body.lines() {
+        // Skip heartbeats and continuation lines of `data`.
+        let line = line.trim();
+        if !line.starts_with("Code=") {
+            continue;
+        }
+        let (mut code, mut action, mut index) = (None, None, None);
+
+        // `data` is last and may itself contain `;`, so stop there.
+        let fields = line.split_once(";data=").map_or(line, |(f, _)| f);
+        for field in fields.split(';') {
+            match field.split_once('=') {
+                Some(("Code", v)) => code = Some(v),
+                Some(("action", v)) => action = Some(v),
+                Some(("index", v)) => index = Some(v),
+                _ => {}
+            }
+        }
+        let kind = match code {
+            Some("VideoMotion") => Kind::Motion,
+            Some("CrossLineDetection") => Kind::LineCrossing,
+            Some("VideoBlind" | "VideoAbnormalDetection") => Kind::Tamper,
+            _ => continue,
+        };
+        let action = match action {
+            Some("Start") => Action::Start,
+            Some("Stop") => Action::Stop,
+            Some("Pulse") => Action::Pulse,
+            _ => return Err(err!(InvalidArgument, msg("event has bad action: {line:?}"))),
+        };
+
+        // Dahua numbers channels from 0.
+        let channel = index
+            .and_then(|i| i.parse::<u32>().ok())
+            .and_then(|i| i.checked_add(1));
+        events.push(Event {
+            kind,
+            channel,
+            action,
+        });
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use db::testutil;
+
+    fn part(body: &'static str) -> multipart::Part {
+        multipart::Part {
+            content_type: Some("text/plain".to_owned()),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        testutil::init();
+        assert_eq!(
+            parse_part(&part("Code=VideoMotion;action=Start;index=0\r\n")).unwrap(),
+            [Event {
+                kind: Kind::Motion,
+                channel: Some(1),
+                action: Action::Start,
+            }]
+        );
+        assert_eq!(
+            parse_part(&part(
+                "Code=CrossLineDetection;action=Pulse;index=1;data={\n\
+                 \"Name\" : \"Rule1\",\n\
+                 \"Direction\" : \"LeftToRight\"\n}\r\n"
+            ))
+            .unwrap(),
+            [Event {
+                kind: Kind::LineCrossing,
+                channel: Some(2),
+                action: Action::Pulse,
+            }]
+        );
+        assert_eq!(
+            parse_part(&part(
+                "Code=VideoBlind;action=Stop;index=0\r\nCode=NTPAdjustTime;action=Pulse;index=0\r\n"
+            ))
+            .unwrap(),
+            [Event {
+                kind: Kind::Tamper,
+                channel: Some(1),
+                action: Action::Stop,
+            }]
+        );
+        assert_eq!(parse_part(&part("Heartbeat\r\n")).unwrap(), []);
+        parse_part(&part("Code=VideoMotion;action=Maybe;index=0")).unwrap_err();
+    }
+}
@@ -0,0 +1,137 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Hikvision's ISAPI `alertStream`.
+//!
+//! Each part is an `EventNotificationAlert` XML document. While a condition is active, the
+//! camera repeats an `active` notification about once a second, so these are treated as pulses;
+//! some event types (such as motion) also send `inactive` when the condition ends. When nothing
+//! is happening, the camera sends `videoloss` notifications with `eventState` of `inactive` as a
+//! heartbeat.
+
+use base::{err, Error};
+
+use super::{Action, Event, Kind};
+use crate::multipart;
+use crate::onvif::Element;
+
+pub(super) const PATH: &str = "ISAPI/Event/notification/alertStream";
+
+pub(super) fn parse_part(part: &multipart::Part) -> Result<Vec<Event>, Error> {
+    // Some cameras include snapshots of the event as separate `image/jpeg` parts.
+    if matches!(&part.content_type, Some(t) if !t.contains("xml")) {
+        return Ok(Vec::new());
+    }
+    let body = std::str::from_utf8(&part.body).map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("alertStream part isn't UTF-8"),
+            source(e)
+        )
+    })?;
+    let alert = Element::parse(body)?;
+    if alert.name != "EventNotificationAlert" {
+        return Ok(Vec::new());
+    }
+    let text = |name: &str| alert.child(name).map(|e| e.text.trim());
+    let kind = match text("eventType")
+        .unwrap_or("")
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "vmd" => Kind::Motion,
+        "linedetection" => Kind::LineCrossing,
+        "tamperdetection" | "shelteralarm" => Kind::Tamper,
+        _ => return Ok(Vec::new()),
+    };
+    let action = match text("eventState") {
+        Some("active") => Action::Pulse,
+        Some("inactive") => Action::Stop,
+        s => return Err(err!(InvalidArgument, msg("unknown eventState {s:?}"))),
+    };
+    let channel = text("channelID")
+        .or_else(|| text("dynChannelID"))
+        .and_then(|c| c.parse().ok());
+    Ok(vec![Event {
+        kind,
+        channel,
+        action,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use db::testutil;
+
+    fn alert(event_type: &str, state: &str) -> multipart::Part {
+        multipart::Part {
+            content_type: Some("application/xml; charset=\"utf-8\"".to_owned()),
+            body: Bytes::from(format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+                   <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+                     <ipAddress>192.168.1.64</ipAddress>
+                     <portNo>80</portNo>
+                     <protocol>HTTP</protocol>
+                     <macAddress>44:19:b6:00:00:00</macAddress>
+                     <channelID>1</channelID>
+                     <dateTime>2024-03-01T12:34:56+00:00</dateTime>
+                     <activePostCount>1</activePostCount>
+                     <eventType>{event_type}</eventType>
+                     <eventState>{state}</eventState>
+                     <eventDescription>event</eventDescription>
+                   </EventNotificationAlert>"#
+            )),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        testutil::init();
+        let p = |event_type, state| parse_part(&alert(event_type, state)).unwrap();
+        assert_eq!(
+            p("VMD", "active"),
+            [Event {
+                kind: Kind::Motion,
+                channel: Some(1),
+                action: Action::Pulse,
+            }]
+        );
+        assert_eq!(
+            p("VMD", "inactive"),
+            [Event {
+                kind: Kind::Motion,
+                channel: Some(1),
+                action: Action::Stop,
+            }]
+        );
+        assert_eq!(
+            p("linedetection", "active"),
+            [Event {
+                kind: Kind::LineCrossing,
+                channel: Some(1),
+                action: Action::Pulse,
+            }]
+        );
+        assert_eq!(
+            p("shelteralarm", "active"),
+            [Event {
+                kind: Kind::Tamper,
+                channel: Some(1),
+                action: Action::Pulse,
+            }]
+        );
+        assert_eq!(p("videoloss", "inactive"), []);
+        parse_part(&alert("VMD", "bogus")).unwrap_err();
+        assert_eq!(
+            parse_part(&multipart::Part {
+                content_type: Some("image/jpeg".to_owned()),
+                body: Bytes::from_static(b"\xff\xd8"),
+            })
+            .unwrap(),
+            []
+        );
+    }
+}
@@ -0,0 +1,499 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Ingestion of events from cameras' proprietary event streams into signals.
+//!
+//! Each configured camera gets a task which holds open a connection to the camera's event API,
+//! as described in [`db::json::VendorEventsConfig`]: a long-lived `multipart/*` HTTP request for
+//! Hikvision and Dahua cameras or a WebSocket for Axis cameras. The API's module translates what
+//! the camera sends into [`Event`]s. Events either start or stop a condition or pulse it, as for
+//! line crossing; a pulsed condition stays active for [`PULSE`] after the last pulse.
+//!
+//! Signal states are held as described in [`crate::signal_hold`] while the stream is healthy.
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::{bail, err, Error};
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use hyper::body::HttpBody;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::multipart;
+use crate::signal_hold::Holder;
+
+mod axis;
+mod dahua;
+mod hikvision;
+
+/// How long a pulsed condition stays active without another pulse.
+const PULSE: recording::Duration = recording::Duration(5 * TIME_UNITS_PER_SEC);
+
+/// How often pulses are expired and states extended.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the stream may go without data before Moonfire NVR prompts the camera to send
+/// something, for APIs which support it.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How long the stream may go without data before it's considered stalled. Hikvision and Dahua
+/// cameras send heartbeats well within this; Axis cameras answer keepalives.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying after an error.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// The longest part accepted; event notifications are small.
+const MAX_PART_LEN: usize = 1 << 20;
+
+const SIGNAL_STATE_INACTIVE: u16 = 1;
+const SIGNAL_STATE_ACTIVE: u16 = 2;
+
+/// A supported event API.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Api {
+    Hikvision,
+    Dahua,
+    Axis,
+}
+
+impl Api {
+    fn parse(api: &str) -> Option<Self> {
+        match api {
+            "hikvision" => Some(Api::Hikvision),
+            "dahua" => Some(Api::Dahua),
+            "axis" => Some(Api::Axis),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of condition reported by an event.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Motion,
+    LineCrossing,
+    Tamper,
+
+    /// An event with the given topic, as configured via [`db::json::VendorEventsConfig::topics`].
+    Topic(String),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Action {
+    Start,
+    Stop,
+    Pulse,
+}
+
+/// An event parsed from a camera's event stream.
+#[derive(Debug, Eq, PartialEq)]
+struct Event {
+    kind: Kind,
+
+    /// The channel, numbering from 1, if supplied.
+    channel: Option<u32>,
+
+    action: Action,
+}
+
+/// Everything needed to ingest a single camera's events.
+pub struct Camera {
+    short_name: String,
+    api: Api,
+    base_url: Url,
+    creds: Option<retina::client::Credentials>,
+    channel: Option<u32>,
+    signals: Vec<(Kind, u32)>,
+}
+
+impl Camera {
+    /// Returns the ingestion parameters for `c`, or `None` if it's not configured for vendor
+    /// events.
+    pub fn new(c: &db::Camera) -> Option<Self> {
+        let config = c.config.vendor_events.as_ref()?;
+        let signals: Vec<_> = [
+            (Kind::Motion, config.motion_signal_id),
+            (Kind::LineCrossing, config.line_crossing_signal_id),
+            (Kind::Tamper, config.tamper_signal_id),
+        ]
+        .into_iter()
+        .filter_map(|(k, id)| Some((k, id?)))
+        .chain(
+            config
+                .topics
+                .iter()
+                .map(|(t, &id)| (Kind::Topic(t.clone()), id)),
+        )
+        .collect();
+        if signals.is_empty() {
+            return None;
+        }
+        let Some(api) = Api::parse(&config.api) else {
+            warn!(
+                "{}: ignoring vendor events config with unsupported api {:?}",
+                c.short_name, config.api
+            );
+            return None;
+        };
+        if api != Api::Axis && !config.topics.is_empty() {
+            warn!(
+                "{}: ignoring vendor events topics, which are only supported for axis",
+                c.short_name
+            );
+        }
+        let Some(base_url) = config.base_url.as_ref() else {
+            warn!(
+                "{}: ignoring vendor events config without a base URL",
+                c.short_name
+            );
+            return None;
+        };
+        Some(Camera {
+            short_name: c.short_name.clone(),
+            api,
+            base_url: base_url.clone(),
+            creds: (!c.config.username.is_empty()).then(|| retina::client::Credentials {
+                username: c.config.username.clone(),
+                password: c.config.password.clone(),
+            }),
+            channel: config.channel,
+            signals,
+        })
+    }
+}
+
+/// Returns the signal state for a condition which is `active` or not.
+fn state(active: bool) -> u16 {
+    match active {
+        true => SIGNAL_STATE_ACTIVE,
+        false => SIGNAL_STATE_INACTIVE,
+    }
+}
+
+/// The state of a single signal driven by events.
+#[derive(Debug)]
+struct Signal {
+    id: u32,
+
+    /// Whether the condition is active, as most recently reported by the camera.
+    holder: Holder<bool>,
+
+    /// The end of the active state, if it was pulsed.
+    pulse_end: Option<recording::Time>,
+}
+
+impl Signal {
+    fn new(id: u32) -> Self {
+        Signal {
+            id,
+            holder: Holder::default(),
+            pulse_end: None,
+        }
+    }
+
+    /// Notes that the stream has (re)connected as of `now`, so the condition is presumed
+    /// inactive until the camera says otherwise.
+    fn connect(&mut self, now: recording::Time) -> Option<(Range<recording::Time>, u16)> {
+        self.set(now, now, false)
+    }
+
+    /// Notes an event as of `now`, returning a range and state to set if appropriate.
+    fn event(
+        &mut self,
+        now: recording::Time,
+        action: Action,
+    ) -> Option<(Range<recording::Time>, u16)> {
+        let (active, pulse_end) = match action {
+            Action::Start => (true, None),
+            Action::Stop => (false, None),
+            Action::Pulse => (true, Some(now + PULSE)),
+        };
+        self.pulse_end = pulse_end;
+        self.set(now, now, active)
+    }
+
+    /// Notes the passage of time, returning a range and state to set if a pulse has ended or
+    /// the state is due for extension.
+    fn tick(&mut self, now: recording::Time) -> Option<(Range<recording::Time>, u16)> {
+        match self.pulse_end {
+            Some(p) if p <= now => {
+                self.pulse_end = None;
+                self.set(p, now, false)
+            }
+            _ => self
+                .holder
+                .extend(now)
+                .map(|(r, active)| (r, state(active))),
+        }
+    }
+
+    /// Sets the condition to `active` from `start`, or extends it if unchanged.
+    fn set(
+        &mut self,
+        start: recording::Time,
+        now: recording::Time,
+        active: bool,
+    ) -> Option<(Range<recording::Time>, u16)> {
+        let range = self.holder.set(start, now, active)?;
+        Some((range, state(active)))
+    }
+
+    /// Forgets the camera's reported state, as when the stream is lost.
+    fn reset(&mut self) {
+        self.holder.reset();
+        self.pulse_end = None;
+    }
+}
+
+/// Ingests events from `camera` until shutdown.
+pub async fn run<C: base::clock::Clocks + Clone>(
+    db: Arc<db::Database<C>>,
+    shutdown_rx: base::shutdown::Receiver,
+    camera: Camera,
+) {
+    let client = multipart::client(None);
+    let mut signals: Vec<(Kind, Signal)> = camera
+        .signals
+        .iter()
+        .map(|(k, id)| (k.clone(), Signal::new(*id)))
+        .collect();
+    info!(
+        "{}: starting {:?} event ingestion from {}",
+        camera.short_name, camera.api, camera.base_url
+    );
+    loop {
+        tokio::select! {
+            r = stream(&db, &client, &camera, &mut signals) => {
+                if let Err(err) = r {
+                    warn!(%err, "{}: event stream failed", camera.short_name);
+                }
+            }
+            _ = shutdown_rx.as_future() => return,
+        }
+        for (_, s) in &mut signals {
+            s.reset();
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+            _ = shutdown_rx.as_future() => return,
+        }
+    }
+}
+
+/// Sets signal states, logging any failure.
+fn update<C: base::clock::Clocks + Clone>(
+    db: &db::Database<C>,
+    camera: &Camera,
+    updates: Vec<(u32, Range<recording::Time>, u16)>,
+) {
+    if updates.is_empty() {
+        return;
+    }
+    let mut l = db.lock();
+    for (id, range, state) in updates {
+        if let Err(err) = l.update_signals(range, &[id], &[state]) {
+            warn!(%err, "{}: unable to update signal {id}", camera.short_name);
+        }
+    }
+}
+
+/// Parses a part of a multipart event stream, returning its recognized events.
+type ParsePart = fn(&multipart::Part) -> Result<Vec<Event>, Error>;
+
+/// A connected event stream.
+enum Source {
+    Multipart {
+        body: hyper::Body,
+        parts: multipart::Parts,
+        parse: ParsePart,
+    },
+    Axis(axis::Stream),
+}
+
+impl Source {
+    async fn connect(client: &multipart::Client, camera: &Camera) -> Result<Self, Error> {
+        let (path, parse): (_, ParsePart) = match camera.api {
+            Api::Hikvision => (hikvision::PATH, hikvision::parse_part),
+            Api::Dahua => (dahua::PATH, dahua::parse_part),
+            Api::Axis => return Ok(Source::Axis(axis::connect(client, camera).await?)),
+        };
+        let url = camera
+            .base_url
+            .join(path)
+            .map_err(|e| err!(InvalidArgument, msg("bad base URL"), source(e)))?;
+        let resp = multipart::get(client, &url, camera.creds.as_ref()).await?;
+        let content_type = resp
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let Some(boundary) = multipart::boundary(content_type) else {
+            bail!(
+                InvalidArgument,
+                msg("expected multipart event stream, got content type {content_type:?}")
+            );
+        };
+        let parts = multipart::Parts::new(boundary, MAX_PART_LEN);
+        Ok(Source::Multipart {
+            body: resp.into_body(),
+            parts,
+            parse,
+        })
+    }
+
+    /// Returns the events in the next data received, skipping any unparseable events.
+    ///
+    /// This is cancel-safe.
+    async fn next(&mut self, camera: &Camera) -> Result<Vec<Event>, Error> {
+        let mut events = Vec::new();
+        match self {
+            Source::Multipart { body, parts, parse } => {
+                match body.data().await {
+                    Some(Ok(d)) => parts.push(&d),
+                    Some(Err(e)) => {
+                        bail!(Unavailable, msg("unable to read event stream"), source(e))
+                    }
+                    None => bail!(Unavailable, msg("end of event stream")),
+                }
+                while let Some(part) = parts.next()? {
+                    match parse(&part) {
+                        Ok(e) => events.extend(e),
+                        Err(err) => warn!(%err, "{}: ignoring bad event", camera.short_name),
+                    }
+                }
+            }
+            Source::Axis(ws) => {
+                if let Some(text) = axis::next(ws).await? {
+                    match axis::parse_message(&text) {
+                        Ok(e) => events.extend(e),
+                        Err(err) => warn!(%err, "{}: ignoring bad event", camera.short_name),
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Prompts the camera to send something, if supported.
+    async fn keepalive(&mut self) -> Result<(), Error> {
+        match self {
+            Source::Multipart { .. } => Ok(()),
+            Source::Axis(ws) => axis::ping(ws).await,
+        }
+    }
+}
+
+/// Reads the event stream and processes its events until error.
+async fn stream<C: base::clock::Clocks + Clone>(
+    db: &db::Database<C>,
+    client: &multipart::Client,
+    camera: &Camera,
+    signals: &mut [(Kind, Signal)],
+) -> Result<(), Error> {
+    let mut source = Source::connect(client, camera).await?;
+    info!("{}: connected to event stream", camera.short_name);
+    let now = recording::Time::new(db.clocks().realtime());
+    update(
+        db,
+        camera,
+        signals
+            .iter_mut()
+            .filter_map(|(_, s)| s.connect(now).map(|(r, st)| (s.id, r, st)))
+            .collect(),
+    );
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_data = tokio::time::Instant::now();
+    loop {
+        let events = tokio::select! {
+            e = source.next(camera) => Some(e?),
+            _ = interval.tick() => None,
+        };
+        if events.is_some() {
+            last_data = tokio::time::Instant::now();
+        } else if last_data.elapsed() >= STALL_TIMEOUT {
+            bail!(DeadlineExceeded, msg("event stream stalled"));
+        } else if last_data.elapsed() >= KEEPALIVE_INTERVAL {
+            source.keepalive().await?;
+        }
+        let now = recording::Time::new(db.clocks().realtime());
+        let mut updates = Vec::new();
+        if let Some(events) = events {
+            for e in events {
+                if camera.channel.is_some() && e.channel.is_some() && camera.channel != e.channel {
+                    continue;
+                }
+                debug!("{}: {e:?}", camera.short_name);
+                for (kind, s) in signals.iter_mut() {
+                    if *kind == e.kind {
+                        updates.extend(s.event(now, e.action).map(|(r, st)| (s.id, r, st)));
+                    }
+                }
+            }
+        } else {
+            for (_, s) in signals.iter_mut() {
+                updates.extend(s.tick(now).map(|(r, st)| (s.id, r, st)));
+            }
+        }
+        update(db, camera, updates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal_hold::HOLD;
+    use db::testutil;
+
+    #[test]
+    fn signal() {
+        testutil::init();
+        let sec = |s: i64| recording::Time(s * TIME_UNITS_PER_SEC);
+        let mut s = Signal::new(1);
+
+        // Nothing is set until connected.
+        assert_eq!(s.tick(sec(0)), None);
+
+        // On connection, the condition is presumed inactive.
+        assert_eq!(
+            s.connect(sec(1)),
+            Some((sec(1)..sec(1) + HOLD, SIGNAL_STATE_INACTIVE))
+        );
+
+        // A start takes effect immediately; a repeated start only extends it, not too often.
+        assert_eq!(
+            s.event(sec(2), Action::Start),
+            Some((sec(2)..sec(2) + HOLD, SIGNAL_STATE_ACTIVE))
+        );
+        assert_eq!(s.event(sec(3), Action::Start), None);
+        assert_eq!(s.tick(sec(16)), None);
+        assert_eq!(
+            s.tick(sec(17)),
+            Some((sec(2) + HOLD..sec(17) + HOLD, SIGNAL_STATE_ACTIVE))
+        );
+        assert_eq!(
+            s.event(sec(18), Action::Stop),
+            Some((sec(18)..sec(18) + HOLD, SIGNAL_STATE_INACTIVE))
+        );
+
+        // A pulse stays active until PULSE after the last pulse.
+        assert_eq!(
+            s.event(sec(20), Action::Pulse),
+            Some((sec(20)..sec(20) + HOLD, SIGNAL_STATE_ACTIVE))
+        );
+        assert_eq!(s.event(sec(22), Action::Pulse), None);
+        assert_eq!(s.tick(sec(26)), None);
+        assert_eq!(
+            s.tick(sec(27)),
+            Some((sec(22) + PULSE..sec(27) + HOLD, SIGNAL_STATE_INACTIVE))
+        );
+
+        // After a reset, nothing is set until connected again.
+        s.reset();
+        assert_eq!(s.tick(sec(28)), None);
+    }
+}
@@ -2,15 +2,97 @@
 // Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-//! Unified [`hyper::server::accept::Accept`] impl for TCP and Unix sockets.
+//! Unified [`hyper::server::accept::Accept`] impl for TCP, TLS, and Unix sockets.
+//!
+//! On TCP and Unix connections, vectored writes of sample file bytes (see
+//! [`db::dir::find_mapped`]) go through `sendfile` rather than copying them
+//! into the socket from userspace.
 
+use std::future::Future;
+use std::io::IoSlice;
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Poll};
+use std::time::Duration;
 
+use futures::stream::{FuturesUnordered, StreamExt as _};
 use hyper::server::accept::Accept;
+use tracing::debug;
+
+use super::tls::Tls;
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub enum Listener {
     Tcp(tokio::net::TcpListener),
     Unix(tokio::net::UnixListener),
+    Tls(TlsListener),
+}
+
+impl Listener {
+    /// Wraps a TCP listener to serve TLS. Unix listeners are returned unchanged.
+    pub fn with_tls(self, tls: Arc<Tls>) -> Self {
+        match self {
+            Listener::Tcp(tcp) => Listener::Tls(TlsListener {
+                tcp,
+                tls,
+                handshakes: FuturesUnordered::new(),
+            }),
+            l => l,
+        }
+    }
+}
+
+/// A TCP listener whose connections complete a TLS handshake before being returned.
+///
+/// Handshakes proceed concurrently, so that a slow client doesn't hold up others.
+pub struct TlsListener {
+    tcp: tokio::net::TcpListener,
+    tls: Arc<Tls>,
+    handshakes: FuturesUnordered<Pin<Box<dyn Future<Output = Option<Conn>> + Send>>>,
+}
+
+impl TlsListener {
+    fn poll_accept(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Conn, std::io::Error>>> {
+        while let Poll::Ready((s, a)) = self.tcp.poll_accept(cx)? {
+            s.set_nodelay(true)?;
+            let acceptor = self.tls.acceptor();
+            self.handshakes.push(Box::pin(async move {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(s)).await {
+                    Ok(Ok(s)) => Some(Conn {
+                        stream: Stream::Tls(Box::new(s)),
+                        data: ConnData {
+                            client_unix_uid: None,
+                            client_addr: Some(a),
+                            tls: true,
+                        },
+                    }),
+                    Ok(Err(err)) => {
+                        debug!(%err, addr = %a, "TLS handshake failed");
+                        None
+                    }
+                    Err(_) => {
+                        debug!(addr = %a, "TLS handshake timed out");
+                        None
+                    }
+                }
+            }));
+        }
+
+        // Failed handshakes are dropped rather than returned as errors, which would stop the
+        // server.
+        while let Poll::Ready(Some(c)) = self.handshakes.poll_next_unpin(cx) {
+            if let Some(c) = c {
+                return Poll::Ready(Some(Ok(c)));
+            }
+        }
+        Poll::Pending
+    }
 }
 
 impl Accept for Listener {
@@ -31,6 +113,7 @@ impl Accept for Listener {
                     data: ConnData {
                         client_unix_uid: None,
                         client_addr: Some(a),
+                        tls: false,
                     },
                 }))
             }),
@@ -44,9 +127,11 @@ impl Accept for Listener {
                     data: ConnData {
                         client_unix_uid: Some(nix::unistd::Uid::from_raw(ucred.uid())),
                         client_addr: None,
+                        tls: false,
                     },
                 }))
             }),
+            Listener::Tls(l) => l.poll_accept(cx),
         }
     }
 }
@@ -62,6 +147,9 @@ pub struct Conn {
 pub struct ConnData {
     pub client_unix_uid: Option<nix::unistd::Uid>,
     pub client_addr: Option<std::net::SocketAddr>,
+
+    /// True iff the connection uses TLS, as when the `--tls-cert` option is set.
+    pub tls: bool,
 }
 
 impl Conn {
@@ -79,6 +167,7 @@ impl tokio::io::AsyncRead for Conn {
         match self.stream {
             Stream::Tcp(ref mut s) => Pin::new(s).poll_read(cx, buf),
             Stream::Unix(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
@@ -92,6 +181,28 @@ impl tokio::io::AsyncWrite for Conn {
         match self.stream {
             Stream::Tcp(ref mut s) => Pin::new(s).poll_write(cx, buf),
             Stream::Unix(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => poll_sendfile_vectored(s, cx, bufs),
+            Stream::Unix(ref mut s) => poll_sendfile_vectored(s, cx, bufs),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    /// Returns true for plain connections so that `hyper` passes along the body's chunks
+    /// themselves, which `poll_write_vectored` needs to recognize sample file bytes.
+    fn is_write_vectored(&self) -> bool {
+        match self.stream {
+            Stream::Tcp(_) | Stream::Unix(_) => true,
+            Stream::Tls(ref s) => s.is_write_vectored(),
         }
     }
 
@@ -102,6 +213,7 @@ impl tokio::io::AsyncWrite for Conn {
         match self.stream {
             Stream::Tcp(ref mut s) => Pin::new(s).poll_flush(cx),
             Stream::Unix(ref mut s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -112,6 +224,7 @@ impl tokio::io::AsyncWrite for Conn {
         match self.stream {
             Stream::Tcp(ref mut s) => Pin::new(s).poll_shutdown(cx),
             Stream::Unix(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
         }
     }
 }
@@ -123,4 +236,152 @@ impl tokio::io::AsyncWrite for Conn {
 enum Stream {
     Tcp(tokio::net::TcpStream),
     Unix(tokio::net::UnixStream),
+    Tls(Box<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>),
+}
+
+/// A plain socket which can be the destination of `sendfile`.
+trait SendfileSocket: tokio::io::AsyncWrite + AsRawFd + Unpin {
+    fn poll_write_ready(&self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>>;
+    fn try_write_io<R>(&self, f: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R>;
+}
+
+impl SendfileSocket for tokio::net::TcpStream {
+    fn poll_write_ready(&self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        tokio::net::TcpStream::poll_write_ready(self, cx)
+    }
+    fn try_write_io<R>(&self, f: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R> {
+        self.try_io(tokio::io::Interest::WRITABLE, f)
+    }
+}
+
+impl SendfileSocket for tokio::net::UnixStream {
+    fn poll_write_ready(&self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        tokio::net::UnixStream::poll_write_ready(self, cx)
+    }
+    fn try_write_io<R>(&self, f: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R> {
+        self.try_io(tokio::io::Interest::WRITABLE, f)
+    }
+}
+
+/// Writes `bufs` to `s`, using `sendfile` for sample file bytes.
+///
+/// Like any vectored write, this may write only a prefix of `bufs`: either the buffers before
+/// the first sample file bytes, or (a prefix of) those bytes.
+fn poll_sendfile_vectored<S: SendfileSocket>(
+    s: &mut S,
+    cx: &mut std::task::Context<'_>,
+    bufs: &[IoSlice<'_>],
+) -> Poll<std::io::Result<usize>> {
+    let Some(first) = bufs.iter().position(|b| !b.is_empty()) else {
+        return Poll::Ready(Ok(0));
+    };
+    let bufs = &bufs[first..];
+    let Some(m) = db::dir::find_mapped(&bufs[0]) else {
+        let end = bufs[1..]
+            .iter()
+            .position(|b| db::dir::find_mapped(b).is_some())
+            .map_or(bufs.len(), |p| p + 1);
+        return Pin::new(s).poll_write_vectored(cx, &bufs[..end]);
+    };
+    let mut offset = libc::off_t::try_from(m.offset).expect("file offset fits in off_t");
+    loop {
+        ready!(s.poll_write_ready(cx))?;
+        let fd = s.as_raw_fd();
+        match s.try_write_io(|| {
+            // SAFETY: both fds are open, and `offset` is a valid pointer.
+            let n = unsafe { libc::sendfile(fd, m.file().as_raw_fd(), &mut offset, m.len) };
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(n as usize)
+        }) {
+            Ok(n) => return Poll::Ready(Ok(n)),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+                // The filesystem doesn't support `sendfile`; copy from userspace instead.
+                return Pin::new(s).poll_write(cx, &bufs[0]);
+            }
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+    use std::pin::Pin;
+
+    use base::clock::RealClocks;
+    use db::testutil::{self, TestDb};
+    use futures::TryStreamExt as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWrite};
+
+    use super::{Conn, ConnData, Stream};
+
+    /// Writes sample file bytes between in-memory buffers, as `hyper` does when serving a
+    /// `.mp4`, and checks the peer receives them intact.
+    // io_uring reads don't `mmap()` files.
+    #[cfg(not(feature = "io-uring"))]
+    #[tokio::test]
+    async fn sendfile() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let dir = db.dirs_by_id.get(&testutil::TEST_DIR_ID).unwrap();
+        let id = db::CompositeId::new(testutil::TEST_STREAM_ID, 0);
+        let data: Vec<u8> = (0..3u32 << 20).map(|i| (i % 251) as u8).collect();
+        std::io::Write::write_all(&mut dir.create_file(id).unwrap(), &data).unwrap();
+        let chunks: Vec<db::dir::FileChunk> = dir
+            .open_mapped_file(id, 1..data.len() as u64, None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(chunks.len() > 1);
+
+        let mut expected = b"header".to_vec();
+        let mut bufs: Vec<&[u8]> = vec![b"header"];
+        for c in &chunks {
+            let db::dir::FileChunk::Mapped(m) = c else {
+                panic!("expected mapped chunk");
+            };
+            bufs.push(m.as_ref());
+            expected.extend_from_slice(m.as_ref());
+        }
+        bufs.push(b"trailer");
+        expected.extend_from_slice(b"trailer");
+
+        let (a, mut b) = tokio::net::UnixStream::pair().unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            b.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut conn = Conn {
+            stream: Stream::Unix(a),
+            data: ConnData {
+                client_unix_uid: None,
+                client_addr: None,
+                tls: false,
+            },
+        };
+        assert!(conn.is_write_vectored());
+        while !bufs.is_empty() {
+            let slices: Vec<IoSlice> = bufs.iter().map(|b| IoSlice::new(b)).collect();
+            let mut n =
+                futures::future::poll_fn(|cx| Pin::new(&mut conn).poll_write_vectored(cx, &slices))
+                    .await
+                    .unwrap();
+            assert!(n > 0);
+            while n > 0 {
+                if n >= bufs[0].len() {
+                    n -= bufs[0].len();
+                    bufs.remove(0);
+                } else {
+                    bufs[0] = &bufs[0][n..];
+                    n = 0;
+                }
+            }
+        }
+        drop(conn);
+        assert!(reader.await.unwrap() == expected);
+    }
 }
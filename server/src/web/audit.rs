@@ -0,0 +1,383 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Audit logging of security-relevant requests, and `/api/audit`.
+//!
+//! Logins and logouts are recorded by their handlers, which know the user in question even when
+//! the attempt fails. Other audited requests are recorded generically by `Service::serve` once
+//! the response status is known; see `action`.
+
+use std::borrow::Borrow;
+use std::fmt::Write as _;
+use std::os::unix::net::UnixDatagram;
+
+use base::{bail, err, Error};
+use db::audit::{Action, NewEntry};
+use db::auth;
+use http::{Method, Request, StatusCode};
+use tracing::{error, warn};
+use url::form_urlencoded;
+
+use super::{path::Path, plain_response, serve_json, Caller, ResponseResult, Service};
+use crate::json;
+
+/// The default and maximum number of entries returned by `GET /api/audit`.
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// syslog facility `authpriv`, as in RFC 5424 section 6.2.1.
+const FACILITY_AUTHPRIV: u8 = 10;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+
+pub(super) struct Audit {
+    enabled: bool,
+    syslog: Option<UnixDatagram>,
+}
+
+impl Audit {
+    pub(super) fn new(
+        config: Option<&crate::cmds::run::config::AuditConfig>,
+    ) -> Result<Self, Error> {
+        let Some(config) = config else {
+            return Ok(Audit {
+                enabled: false,
+                syslog: None,
+            });
+        };
+        let syslog = match config.syslog_socket {
+            None => None,
+            Some(ref path) => {
+                let s = UnixDatagram::unbound()?;
+                s.connect(path).map_err(|e| {
+                    err!(
+                        e,
+                        msg("unable to connect to syslog socket {}", path.display())
+                    )
+                })?;
+
+                // Never let a slow syslog daemon stall request handling.
+                s.set_nonblocking(true)?;
+                Some(s)
+            }
+        };
+        Ok(Audit {
+            enabled: true,
+            syslog,
+        })
+    }
+
+    /// Records `entry` if the audit log is enabled.
+    ///
+    /// Failures are logged rather than returned; by now, the action has typically already
+    /// happened.
+    pub(super) fn record(&self, db: &db::LockedDatabase, entry: &NewEntry) {
+        if !self.enabled {
+            return;
+        }
+        if let Err(err) = db.add_audit_entry(entry) {
+            error!(err = %err.chain(), "unable to write audit log entry");
+        }
+        if let Some(ref s) = self.syslog {
+            if let Err(err) = s.send(syslog_message(entry, std::process::id()).as_bytes()) {
+                warn!(%err, "unable to forward audit log entry to syslog");
+            }
+        }
+    }
+}
+
+/// A request to be recorded by `Service::serve` once its response status is known.
+pub(super) struct Pending {
+    pub(super) req: auth::Request,
+    pub(super) user: Option<(i32, String)>,
+    pub(super) action: Action,
+
+    /// The request method and path, such as `POST /api/cameras/`.
+    pub(super) detail: String,
+}
+
+impl Pending {
+    /// Records the request, given its outcome.
+    pub(super) fn record(
+        self,
+        audit: &Audit,
+        db: &db::LockedDatabase,
+        status: StatusCode,
+        error: Option<&Error>,
+    ) {
+        let mut detail = self.detail;
+        if let Some(e) = error {
+            write!(&mut detail, ": {e}").expect("String write is infallible");
+        }
+        audit.record(
+            db,
+            &NewEntry {
+                req: &self.req,
+                user_id: self.user.as_ref().map(|u| u.0),
+                username: self.user.as_ref().map(|u| u.1.as_str()),
+                action: self.action,
+                success: !status.is_client_error() && !status.is_server_error(),
+                detail: Some(&detail),
+            },
+        );
+    }
+}
+
+/// Returns the action to record for a request handled generically, or `None` if the request
+/// isn't audited.
+pub(super) fn action(path: &Path, method: &Method) -> Option<Action> {
+    if *method == Method::GET {
+        return match path {
+            Path::StreamViewMp4(_, _, false)
+            | Path::StreamViewTs(_, _, false)
+            | Path::ExportDownload(_)
+            | Path::ShareDownload(_)
+            | Path::Backup => Some(Action::Download),
+            _ => None,
+        };
+    }
+    if matches!(*method, Method::HEAD | Method::OPTIONS) {
+        return None;
+    }
+    match path {
+        Path::Users
+        | Path::User(_)
+        | Path::UserSessions(_)
+        | Path::UserSession(..)
+        | Path::Tokens
+        | Path::Token(_)
+        | Path::WebAuthnRegisterFinish
+        | Path::WebAuthnCredential(_) => Some(Action::UserChange),
+        Path::Cameras
+        | Path::Camera(_)
+        | Path::Dirs
+        | Path::Dir(_)
+        | Path::StreamSchedule(..)
+        | Path::SignalType(_)
+        | Path::Reload => Some(Action::ConfigChange),
+        Path::Exports | Path::Export(_) | Path::Shares => Some(Action::Export),
+        _ => None,
+    }
+}
+
+/// Formats an entry as a RFC 3164-style message for the local syslog daemon, which supplies
+/// the timestamp and hostname.
+fn syslog_message(entry: &NewEntry, pid: u32) -> String {
+    let severity = if entry.success {
+        SEVERITY_NOTICE
+    } else {
+        SEVERITY_WARNING
+    };
+    let mut msg = format!(
+        "<{}>moonfire-nvr[{pid}]: audit action={} success={}",
+        FACILITY_AUTHPRIV * 8 + severity,
+        entry.action,
+        entry.success,
+    );
+    if let Some(u) = entry.username {
+        write!(&mut msg, " user={u:?}").expect("String write is infallible");
+    }
+    if let Some(a) = entry.req.addr {
+        write!(&mut msg, " addr={a}").expect("String write is infallible");
+    }
+    if let Some(d) = entry.detail {
+        write!(&mut msg, " detail={d:?}").expect("String write is infallible");
+    }
+    msg
+}
+
+impl Service {
+    /// Records a login attempt in the audit log.
+    pub(super) fn audit_login(
+        &self,
+        db: &db::LockedDatabase,
+        req: &auth::Request,
+        user: (Option<i32>, Option<&str>),
+        method: &str,
+        result: Result<(), &Error>,
+    ) {
+        let detail = match result {
+            Ok(()) => method.to_owned(),
+            Err(e) => format!("{method}: {e}"),
+        };
+        self.audit.record(
+            db,
+            &NewEntry {
+                req,
+                user_id: user.0,
+                username: user.1,
+                action: Action::Login,
+                success: result.is_ok(),
+                detail: Some(&detail),
+            },
+        );
+    }
+
+    pub(super) fn audit_entries(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        if !caller.permissions.admin_users {
+            bail!(PermissionDenied, msg("admin_users required"));
+        }
+        if !self.audit.enabled {
+            bail!(
+                FailedPrecondition,
+                msg("audit log is disabled; set audit in the config file")
+            );
+        }
+        let mut filter = db::audit::Filter {
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value): (_, &str) = (key.borrow(), value.borrow());
+                let bad = || err!(InvalidArgument, msg("unparseable {key}"));
+                match key {
+                    "startTimeSec" => {
+                        filter.start_time_sec = Some(value.parse().map_err(|_| bad())?)
+                    }
+                    "endTimeSec" => filter.end_time_sec = Some(value.parse().map_err(|_| bad())?),
+                    "userId" => filter.user_id = Some(value.parse().map_err(|_| bad())?),
+                    "beforeId" => filter.before_id = Some(value.parse().map_err(|_| bad())?),
+                    "limit" => filter.limit = value.parse().map_err(|_| bad())?,
+                    "action" => {
+                        filter.action = Some(Action::parse(value).ok_or_else(|| {
+                            err!(InvalidArgument, msg("no such action {value:?}"))
+                        })?)
+                    }
+                    _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+                }
+            }
+        }
+        if filter.limit > MAX_LIMIT {
+            bail!(InvalidArgument, msg("limit must be at most {MAX_LIMIT}"));
+        }
+        let entries = self.db.lock().list_audit_entries(&filter)?;
+        serve_json(
+            req,
+            &json::GetAuditResponse {
+                entries: entries.iter().map(json::AuditEntry::wrap).collect(),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::testutil;
+
+    use crate::web::tests::Server;
+
+    #[test]
+    fn action() {
+        let uuid = uuid::Uuid::nil();
+        let main = db::StreamType::Main;
+        assert_eq!(
+            super::action(&Path::StreamViewMp4(uuid, main, false), &Method::GET),
+            Some(Action::Download)
+        );
+        assert_eq!(
+            super::action(&Path::StreamViewMp4(uuid, main, true), &Method::GET),
+            None
+        );
+        assert_eq!(super::action(&Path::Cameras, &Method::GET), None);
+        assert_eq!(
+            super::action(&Path::Cameras, &Method::POST),
+            Some(Action::ConfigChange)
+        );
+        assert_eq!(
+            super::action(&Path::User(1), &Method::PATCH),
+            Some(Action::UserChange)
+        );
+        assert_eq!(super::action(&Path::Signals, &Method::POST), None);
+        assert_eq!(
+            super::action(&Path::SignalType(uuid), &Method::PUT),
+            Some(Action::ConfigChange)
+        );
+    }
+
+    #[test]
+    fn syslog_message() {
+        let req = auth::Request {
+            when_sec: Some(42),
+            addr: Some(std::net::Ipv4Addr::new(192, 168, 1, 2).into()),
+            user_agent: None,
+        };
+        let entry = NewEntry {
+            req: &req,
+            user_id: None,
+            username: Some("slamb"),
+            action: Action::Login,
+            success: false,
+            detail: Some("password: incorrect password"),
+        };
+        assert_eq!(
+            super::syslog_message(&entry, 123),
+            "<84>moonfire-nvr[123]: audit action=login success=false user=\"slamb\" \
+             addr=192.168.1.2 detail=\"password: incorrect password\""
+        );
+    }
+
+    #[tokio::test]
+    async fn records_requests() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+
+        // A failed login.
+        let resp = cli
+            .post(&format!("{}/api/login", &s.base_url))
+            .json(&serde_json::json!({"username": "slamb", "password": "wrong"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // A user change.
+        let resp = cli
+            .post(&format!("{}/api/users", &s.base_url))
+            .json(&serde_json::json!({"user": {"username": "viewer"}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let resp = cli
+            .get(&format!("{}/api/audit", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let entries = resp["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["action"], "user_change");
+        assert_eq!(entries[0]["success"], true);
+        assert_eq!(entries[0]["detail"], "POST /api/users");
+        assert_eq!(entries[1]["action"], "login");
+        assert_eq!(entries[1]["success"], false);
+        assert_eq!(entries[1]["username"], "slamb");
+
+        let resp = cli
+            .get(&format!("{}/api/audit?action=login&limit=1", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(resp["entries"][0]["action"], "login");
+    }
+}
@@ -0,0 +1,62 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Online database backups: `/api/backup.db`.
+
+use base::{bail, err, ErrorKind, ResultExt};
+use http::header::{self, HeaderValue};
+use http::{Method, Request};
+use ulid::Ulid;
+
+use crate::body::{BoxedError, Chunk};
+
+use super::{Caller, ResponseResult, Service};
+
+impl Service {
+    /// Writes a backup to a temporary file, then serves it.
+    pub(super) async fn backup(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            bail!(InvalidArgument, msg("GET or HEAD expected"));
+        }
+        if !caller.permissions.admin_users {
+            bail!(PermissionDenied, msg("admin_users required"));
+        }
+        let Some(ref dir) = self.backup_tmp_dir else {
+            bail!(
+                Unimplemented,
+                msg("backups aren't supported by this server")
+            );
+        };
+        let path = dir.join(format!("backup-{}.db.tmp", Ulid::new()));
+        let db = self.db.clone();
+        let p = path.clone();
+        tokio::task::spawn_blocking(move || db.backup(&p))
+            .await
+            .map_err(|e| err!(Internal, msg("backup task failed"), source(e)))??;
+
+        // The open file stays readable after it's unlinked, and unlinking now ensures it's
+        // cleaned up even if the client disconnects.
+        let f = std::fs::File::open(&path);
+        if let Err(err) = std::fs::remove_file(&path) {
+            tracing::warn!(%err, "unable to remove {}", path.display());
+        }
+        let f = f.map_err(|e| err!(e, msg("unable to open {}", path.display())))?;
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/vnd.sqlite3"),
+        );
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"moonfire-nvr.db\""),
+        );
+        let e = http_serve::ChunkedReadFile::<Chunk, BoxedError>::new(f, headers)
+            .err_kind(ErrorKind::Internal)?;
+        Ok(http_serve::serve(e, req))
+    }
+}
@@ -0,0 +1,347 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Camera management: `/api/cameras/` and `/api/cameras/<uuid>/`.
+
+use std::str::FromStr as _;
+
+use base::clock::Clocks;
+use base::{bail, err, Error, ErrorKind, ResultExt as _};
+use db::recording;
+use http::{Method, Request, StatusCode};
+use uuid::Uuid;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+use crate::json;
+
+impl Service {
+    pub(super) async fn cameras(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.update_camera_configs || !caller.permissions.cameras.is_empty() {
+            bail!(
+                PermissionDenied,
+                msg("update_camera_configs for all cameras required")
+            );
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostCameras = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut change = db::CameraChange::default();
+        apply_subset(&mut change, r.camera)?;
+        if change.short_name.is_empty() {
+            bail!(InvalidArgument, msg("shortName must be specified"));
+        }
+        let (id, uuid) = {
+            let mut l = self.db.lock();
+            let id = l.add_camera(change)?;
+            (id, l.cameras_by_id()[&id].uuid)
+        };
+        self.reconcile_streamers().await?;
+        serve_json(&req, &json::PostCamerasResponse { id, uuid })
+    }
+
+    pub(super) async fn camera(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_camera(&req, uuid),
+            Method::PATCH => self.patch_camera(req, caller, uuid).await,
+            Method::DELETE => self.delete_camera(req, caller, uuid).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, PATCH, or DELETE expected",
+            )),
+        }
+    }
+
+    fn get_camera(&self, req: &Request<hyper::Body>, uuid: Uuid) -> ResponseResult {
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+        serve_json(
+            req,
+            &json::Camera::wrap(camera, &db, true, false, now).err_kind(ErrorKind::Internal)?,
+        )
+    }
+
+    async fn patch_camera(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PatchCamera = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        {
+            let mut l = self.db.lock();
+            let id = camera_id(&l, uuid)?;
+            let mut change = l.null_camera_change(id)?;
+            apply_subset(&mut change, r.update)?;
+            if change.short_name.is_empty() {
+                bail!(InvalidArgument, msg("shortName can't be empty"));
+            }
+            l.update_camera(id, change)?;
+        }
+        self.reconcile_streamers().await?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    async fn delete_camera(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteCamera = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        {
+            let mut l = self.db.lock();
+            let id = camera_id(&l, uuid)?;
+            let camera = &l.cameras_by_id()[&id];
+            if camera
+                .streams
+                .iter()
+                .flatten()
+                .any(|s| l.streams_by_id()[s].config.is_recording())
+            {
+                bail!(
+                    FailedPrecondition,
+                    msg("camera {uuid} has recording streams; stop them first")
+                );
+            }
+            l.delete_camera(id)?;
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+fn camera_id(db: &db::LockedDatabase, uuid: Uuid) -> Result<i32, Error> {
+    match db.get_camera(uuid) {
+        Some(c) => Ok(c.id),
+        None => bail!(NotFound, msg("no such camera {uuid}")),
+    }
+}
+
+/// Applies the fields present in `subset` to `change`.
+fn apply_subset(change: &mut db::CameraChange, subset: json::CameraSubset) -> Result<(), Error> {
+    let json::CameraSubset {
+        short_name,
+        config,
+        streams,
+    } = subset;
+    if let Some(n) = short_name {
+        change.short_name = n;
+    }
+    if let Some(c) = config {
+        change.config = c;
+    }
+    for (type_, stream) in streams.unwrap_or_default() {
+        let Some(type_) = db::StreamType::parse(&type_) else {
+            bail!(InvalidArgument, msg("no such stream type {type_:?}"));
+        };
+        let sc = &mut change.streams[type_.index()];
+        let Some(json::StreamSubset {
+            sample_file_dir_id,
+            config,
+        }) = stream
+        else {
+            *sc = db::StreamChange::default();
+            continue;
+        };
+        if let Some(d) = sample_file_dir_id {
+            sc.sample_file_dir_id = d;
+        }
+        if let Some(c) = config {
+            if !c.rtsp_transport.is_empty()
+                && retina::client::Transport::from_str(&c.rtsp_transport).is_err()
+            {
+                bail!(
+                    InvalidArgument,
+                    msg(
+                        "{type_} stream has unsupported rtspTransport {:?}; expected tcp or udp",
+                        c.rtsp_transport
+                    ),
+                );
+            }
+            sc.config = c;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn add_update_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/cameras/", &s.base_url))
+            .json(&serde_json::json!({
+                "camera": {
+                    "shortName": "driveway",
+                    "streams": {
+                        "main": {
+                            "sampleFileDirId": testutil::TEST_DIR_ID,
+                            "config": {
+                                "url": "rtsp://192.168.1.101/main",
+                                "retainBytes": 1_000_000,
+                            },
+                        },
+                    },
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let uuid = Uuid::parse_str(resp["uuid"].as_str().unwrap()).unwrap();
+        {
+            let l = s.db.db.lock();
+            let c = l.get_camera(uuid).unwrap();
+            assert_eq!(c.short_name, "driveway");
+            let main = &l.streams_by_id()[&c.streams[0].unwrap()];
+            assert_eq!(main.sample_file_dir_id, Some(testutil::TEST_DIR_ID));
+            assert_eq!(main.config.retain_bytes, 1_000_000);
+            assert!(c.streams[1].is_none());
+        }
+
+        let camera_url = format!("{}/api/cameras/{}/", &s.base_url, uuid);
+        let resp = cli
+            .patch(&camera_url)
+            .json(&serde_json::json!({
+                "update": {
+                    "shortName": "front driveway",
+                    "streams": {
+                        "main": null,
+                        "sub": { "config": { "url": "rtsp://192.168.1.101/sub" } },
+                    },
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let c = l.get_camera(uuid).unwrap();
+            assert_eq!(c.short_name, "front driveway");
+            assert!(c.streams[0].is_none());
+            let sub = &l.streams_by_id()[&c.streams[1].unwrap()];
+            assert_eq!(
+                sub.config.url.as_ref().unwrap().as_str(),
+                "rtsp://192.168.1.101/sub"
+            );
+        }
+
+        let resp = cli
+            .delete(&camera_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(s.db.db.lock().get_camera(uuid).is_none());
+    }
+
+    #[tokio::test]
+    async fn rtsp_options() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let camera_url = format!("{}/api/cameras/{}/", &s.base_url, s.db.test_camera_uuid);
+        let patch = |config: serde_json::Value| {
+            cli.patch(&camera_url)
+                .json(&serde_json::json!({
+                    "update": { "streams": { "sub": { "config": config } } },
+                }))
+                .send()
+        };
+        let resp = patch(serde_json::json!({
+            "url": "rtsp://192.168.1.101/sub",
+            "rtspTransport": "udp",
+            "rtspConnectTimeoutSec": 60,
+            "rtspFrameTimeoutSec": 10,
+        }))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let l = s.db.db.lock();
+            let c = l.get_camera(s.db.test_camera_uuid).unwrap();
+            let sub = &l.streams_by_id()[&c.streams[1].unwrap()];
+            assert_eq!(sub.config.rtsp_transport, "udp");
+            assert_eq!(sub.config.rtsp_connect_timeout_sec, 60);
+            assert_eq!(sub.config.rtsp_frame_timeout_sec, 10);
+        }
+
+        // Retina doesn't support multicast, so neither does the API.
+        let resp = patch(serde_json::json!({
+            "url": "rtsp://192.168.1.101/sub",
+            "rtspTransport": "udp-multicast",
+        }))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let l = s.db.db.lock();
+        let c = l.get_camera(s.db.test_camera_uuid).unwrap();
+        let sub = &l.streams_by_id()[&c.streams[1].unwrap()];
+        assert_eq!(sub.config.rtsp_transport, "udp");
+    }
+
+    #[tokio::test]
+    async fn requires_permission() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .delete(&format!(
+                "{}/api/cameras/{}/",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        assert!(s.db.db.lock().get_camera(s.db.test_camera_uuid).is_some());
+    }
+}
@@ -0,0 +1,59 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/<type>/detections` handling.
+
+use std::borrow::Borrow;
+
+use base::{bail, err};
+use db::recording;
+use http::Request;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{serve_json, ResponseResult, Service};
+use crate::json;
+
+impl Service {
+    pub(super) fn stream_detections(
+        &self,
+        req: &Request<hyper::Body>,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        let mut time = recording::Time::min_value()..recording::Time::max_value();
+        let mut class = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        time.start = recording::Time::parse(value)
+                            .map_err(|_| err!(InvalidArgument, msg("unparseable startTime90k")))?
+                    }
+                    "endTime90k" => {
+                        time.end = recording::Time::parse(value)
+                            .map_err(|_| err!(InvalidArgument, msg("unparseable endTime90k")))?
+                    }
+                    "class" => class = Some(value.to_owned()),
+                    _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+                }
+            }
+        }
+        let db = self.db.lock();
+        let Some(camera) = db.get_camera(uuid) else {
+            bail!(NotFound, msg("no such camera {uuid}"));
+        };
+        let Some(stream_id) = camera.streams[stream_type.index()] else {
+            bail!(NotFound, msg("no such stream {uuid}/{stream_type}"));
+        };
+        let mut detections = Vec::new();
+        db.list_detections(stream_id, time, class.as_deref(), &mut |d| {
+            detections.push(json::Detection::from(d));
+            Ok(())
+        })?;
+        drop(db);
+        serve_json(req, &json::ListDetections { detections })
+    }
+}
@@ -0,0 +1,280 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Sample file directory management: `/api/dirs/` and `/api/dirs/<id>`.
+
+use base::{bail, Error};
+use http::{Method, Request, StatusCode};
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+use crate::json;
+
+impl Service {
+    pub(super) async fn dirs(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_dirs(&req, caller),
+            Method::POST => self.post_dirs(req, caller).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )),
+        }
+    }
+
+    fn get_dirs(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.read_camera_configs {
+            bail!(PermissionDenied, msg("read_camera_configs required"));
+        }
+        let l = self.db.lock();
+        let mut dirs = Vec::with_capacity(l.sample_file_dirs_by_id().len());
+        for (&id, d) in l.sample_file_dirs_by_id() {
+            let fs_available_bytes = match d.get() {
+                Ok(d) => {
+                    let stat = d.statfs()?;
+                    Some(stat.block_size() as i64 * stat.blocks_available() as i64)
+                }
+                Err(_) => None,
+            };
+            let mut streams = Vec::new();
+            for (&stream_id, s) in l.streams_by_id() {
+                let (retain_bytes, fs_bytes) = if s.sample_file_dir_id == Some(id) {
+                    (s.config.retain_bytes, s.fs_bytes)
+                } else if s.config.archive_sample_file_dir_id == Some(id) {
+                    (s.config.archive_retain_bytes, s.archived_fs_bytes)
+                } else {
+                    continue;
+                };
+                let camera_uuid = l.cameras_by_id()[&s.camera_id].uuid;
+                if !caller.permissions.allows_camera(camera_uuid) {
+                    continue;
+                }
+                streams.push(json::DirStream {
+                    id: stream_id,
+                    camera_uuid,
+                    type_: s.type_.as_str(),
+                    record: s.config.is_recording(),
+                    retain_bytes,
+                    fs_bytes,
+                });
+            }
+            dirs.push(json::Dir {
+                id,
+                uuid: d.uuid,
+                path: &d.path,
+                archive: d.archive,
+                direct_io: d.direct_io,
+                fs_available_bytes,
+                streams,
+            });
+        }
+        serve_json(req, &json::GetDirsResponse { dirs })
+    }
+
+    async fn post_dirs(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostDirs = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        if !r.path.is_absolute() {
+            bail!(InvalidArgument, msg("path must be absolute"));
+        }
+        let id = {
+            let mut l = self.db.lock();
+            let id = l.add_sample_file_dir(r.path)?;
+            if r.archive {
+                l.set_sample_file_dir_archive(id, true)?;
+            }
+            if r.direct_io {
+                l.set_sample_file_dir_direct_io(id, true)?;
+            }
+            id
+        };
+        serve_json(&req, &json::PostDirsResponse { id })
+    }
+
+    pub(super) async fn dir(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::PATCH => self.patch_dir(req, caller, id).await,
+            Method::DELETE => self.delete_dir(req, caller, id).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "PATCH or DELETE expected",
+            )),
+        }
+    }
+
+    async fn patch_dir(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PatchDir = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        {
+            let mut l = self.db.lock();
+            if !l.sample_file_dirs_by_id().contains_key(&id) {
+                bail!(NotFound, msg("no such dir {id}"));
+            }
+            let changes = retention_changes(&l, id, &r.streams)?;
+            if let Some(archive) = r.archive {
+                l.set_sample_file_dir_archive(id, archive)?;
+            }
+            if let Some(direct_io) = r.direct_io {
+                l.set_sample_file_dir_direct_io(id, direct_io)?;
+            }
+            l.update_retention(&changes)?;
+        }
+        if !r.streams.is_empty() {
+            self.reconcile_streamers().await?;
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    async fn delete_dir(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteDir = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        self.db.lock().delete_sample_file_dir(id)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+/// Converts the per-stream updates of a `PATCH /api/dirs/<id>` request to retention changes,
+/// keeping each stream's current value for any field that isn't specified.
+fn retention_changes(
+    db: &db::LockedDatabase,
+    dir_id: i32,
+    updates: &std::collections::BTreeMap<i32, json::DirStreamUpdate>,
+) -> Result<Vec<db::RetentionChange>, Error> {
+    let mut changes = Vec::with_capacity(updates.len());
+    for (&stream_id, u) in updates {
+        let Some(s) = db.streams_by_id().get(&stream_id) else {
+            bail!(NotFound, msg("no such stream {stream_id}"));
+        };
+        if s.sample_file_dir_id != Some(dir_id) {
+            bail!(
+                InvalidArgument,
+                msg("stream {stream_id} doesn't record into dir {dir_id}")
+            );
+        }
+        let new_limit = u.retain_bytes.unwrap_or(s.config.retain_bytes);
+        if new_limit < 0 {
+            bail!(InvalidArgument, msg("retainBytes must be non-negative"));
+        }
+        changes.push(db::RetentionChange {
+            stream_id,
+            new_record: u.record.unwrap_or_else(|| s.config.is_recording()),
+            new_limit,
+        });
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn add_patch_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        permissions.update_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let resp = cli
+            .post(&format!("{}/api/dirs/", &s.base_url))
+            .json(&serde_json::json!({ "path": tmpdir.path(), "archive": true }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let id = resp["id"].as_i64().unwrap() as i32;
+        assert!(s.db.db.lock().sample_file_dirs_by_id()[&id].archive);
+
+        let resp = cli
+            .get(&format!("{}/api/dirs/", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let dirs = resp["dirs"].as_array().unwrap();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0]["streams"][0]["id"], testutil::TEST_STREAM_ID);
+
+        // Adjust the test stream's allocation in the test dir, and write it with O_DIRECT.
+        let resp = cli
+            .patch(&format!(
+                "{}/api/dirs/{}",
+                &s.base_url,
+                testutil::TEST_DIR_ID
+            ))
+            .json(&serde_json::json!({
+                "directIo": true,
+                "streams": { testutil::TEST_STREAM_ID.to_string(): { "retainBytes": 42 } },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert_eq!(
+            s.db.db.lock().streams_by_id()[&testutil::TEST_STREAM_ID]
+                .config
+                .retain_bytes,
+            42
+        );
+        assert!(s.db.db.lock().sample_file_dirs_by_id()[&testutil::TEST_DIR_ID].direct_io);
+
+        // The test dir is in use, so it can't be deleted; the new one can.
+        let resp = cli
+            .delete(&format!(
+                "{}/api/dirs/{}",
+                &s.base_url,
+                testutil::TEST_DIR_ID
+            ))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PRECONDITION_FAILED);
+        let resp = cli
+            .delete(&format!("{}/api/dirs/{}", &s.base_url, id))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(!s.db.db.lock().sample_file_dirs_by_id().contains_key(&id));
+    }
+}
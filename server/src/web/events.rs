@@ -0,0 +1,55 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Event push websocket handling: `/api/events`.
+
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use futures::SinkExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::{tungstenite, WebSocketStream};
+
+use super::{Caller, Service};
+use crate::events::Event;
+
+impl Service {
+    pub(super) async fn events(
+        self: Arc<Self>,
+        ws: &mut WebSocketStream<hyper::upgrade::Upgraded>,
+        caller: Result<Caller, Error>,
+    ) -> Result<(), Error> {
+        let caller = caller?;
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let mut rx = self.events.subscribe();
+        let mut keepalive = tokio::time::interval(std::time::Duration::new(30, 0));
+        loop {
+            let event = tokio::select! {
+                e = rx.recv() => match e {
+                    Ok(e) => e,
+                    Err(RecvError::Lagged(missed)) => Arc::new(Event::Lagged { missed }),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = keepalive.tick() => {
+                    if ws.send(tungstenite::Message::Ping(Vec::new())).await.is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            if let Some(uuid) = event.camera_uuid() {
+                if !caller.permissions.allows_camera(uuid) {
+                    continue;
+                }
+            }
+            let text = serde_json::to_string(&*event)
+                .map_err(|e| err!(Internal, msg("unable to serialize event"), source(e)))?;
+            if ws.send(tungstenite::Message::Text(text)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
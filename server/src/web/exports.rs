@@ -0,0 +1,547 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Clip exports: `/api/exports` and `/api/exports/<id>/*`.
+//!
+//! An export builds a `.mp4` in the background, writing it into the configured export directory.
+//! Unlike `view.mp4`, which is streamed synchronously, the client can poll for progress,
+//! disconnect, and later download the finished file with range requests.
+
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, Instant};
+
+use base::{bail, err, Error, ErrorKind, FastHashMap, ResultExt};
+use db::recording;
+use futures::StreamExt;
+use http::{Method, Request, StatusCode};
+use http_serve::Entity;
+use hyper::body::Buf;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn, Instrument};
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::body::{BoxedError, Chunk};
+use crate::json;
+use crate::mp4;
+use crate::slices;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+
+/// The maximum number of exports which may be building at once.
+const MAX_RUNNING: usize = 4;
+
+/// How long a finished (or failed) export is kept before its file is removed.
+const RETENTION: StdDuration = StdDuration::from_secs(24 * 60 * 60);
+
+/// Export jobs, shared by all binds' `Service`s.
+///
+/// Jobs are kept only in RAM; any files left over from a previous run are removed on startup.
+#[derive(Default)]
+pub struct Exports {
+    /// The directory holding export files, or `None` if exports are disabled.
+    dir: Option<PathBuf>,
+
+    jobs: Mutex<FastHashMap<Ulid, Arc<Job>>>,
+}
+
+struct Job {
+    id: Ulid,
+
+    /// The user who created the export, if any. Only this user can see it.
+    user_id: Option<i32>,
+    camera_uuid: Uuid,
+    stream_type: db::StreamType,
+    range: Range<recording::Time>,
+
+    /// HTTP headers for the download, as supplied by the `mp4::File`.
+    headers: http::HeaderMap,
+    total_bytes: u64,
+    progress: Mutex<Progress>,
+
+    /// Set when the job is deleted, so that a running build stops early.
+    cancelled: AtomicBool,
+}
+
+struct Progress {
+    written_bytes: u64,
+    status: Status,
+}
+
+enum Status {
+    Running,
+    Done { finished: Instant },
+    Failed { finished: Instant, error: String },
+}
+
+impl Exports {
+    /// Creates an `Exports` which writes files into `dir`, creating it if necessary.
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| err!(e, msg("unable to create export dir {}", dir.display())))?;
+        for e in std::fs::read_dir(&dir)? {
+            let e = e?;
+            let name = e.file_name();
+            let Some(name) = name.to_str() else { continue };
+            let stem = name
+                .strip_suffix(".mp4")
+                .or_else(|| name.strip_suffix(".partial"));
+            if stem.map(|s| Ulid::from_string(s).is_ok()).unwrap_or(false) {
+                info!("removing stale export {}", e.path().display());
+                std::fs::remove_file(e.path())?;
+            }
+        }
+        Ok(Exports {
+            dir: Some(dir),
+            jobs: Mutex::new(FastHashMap::default()),
+        })
+    }
+
+    fn path(&self, id: Ulid) -> PathBuf {
+        self.dir
+            .as_ref()
+            .expect("jobs only exist with an export dir")
+            .join(format!("{id}.mp4"))
+    }
+
+    fn partial_path(&self, id: Ulid) -> PathBuf {
+        self.dir
+            .as_ref()
+            .expect("jobs only exist with an export dir")
+            .join(format!("{id}.partial"))
+    }
+
+    /// Removes a job's file, if any.
+    fn remove_file(&self, id: Ulid) {
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                warn!(%e, "unable to remove export {id}");
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes jobs which finished more than `RETENTION` ago.
+    fn prune(&self, now: Instant) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|&id, job| {
+            let finished = match job.progress.lock().unwrap().status {
+                Status::Running => return true,
+                Status::Done { finished } | Status::Failed { finished, .. } => finished,
+            };
+            if now.duration_since(finished) < RETENTION {
+                return true;
+            }
+            self.remove_file(id);
+            false
+        });
+    }
+
+    /// Looks up a job visible to `caller`.
+    fn get(&self, caller: &Caller, id: Ulid) -> Result<Arc<Job>, Error> {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs.get(&id) {
+            Some(job)
+                if job.user_id == user_id(caller)
+                    && caller.permissions.allows_camera(job.camera_uuid) =>
+            {
+                Ok(job.clone())
+            }
+            _ => bail!(NotFound, msg("no such export {id}")),
+        }
+    }
+
+    /// Writes the `.mp4` to disk, updating the job's progress as it goes.
+    async fn write(&self, job: &Job, mp4: mp4::File) -> Result<(), Error> {
+        let partial = self.partial_path(job.id);
+        let mut f = tokio::fs::File::create(&partial).await?;
+        for part in mp4.get_parts(0..job.total_bytes)? {
+            if job.cancelled.load(Ordering::Relaxed) {
+                bail!(Cancelled, msg("export was deleted"));
+            }
+            match part {
+                slices::Part::Chunks(mut chunks) => {
+                    while let Some(chunk) = chunks.next().await {
+                        let chunk = chunk.map_err(|e| err!(Unknown, source(e)))?;
+                        f.write_all(chunk.chunk()).await?;
+                        job.progress.lock().unwrap().written_bytes += chunk.remaining() as u64;
+                    }
+                }
+                slices::Part::File(r) => {
+                    // Have the kernel copy sample data rather than reading it into memory.
+                    f.flush().await?;
+                    let out = f.try_clone().await?.into_std().await;
+                    let len = r.range.end - r.range.start;
+                    tokio::task::spawn_blocking(move || r.copy_to(&out))
+                        .await
+                        .map_err(|e| err!(Internal, msg("export copy task failed"), source(e)))??;
+                    job.progress.lock().unwrap().written_bytes += len;
+                }
+            }
+        }
+        f.sync_all().await?;
+        if job.cancelled.load(Ordering::Relaxed) {
+            bail!(Cancelled, msg("export was deleted"));
+        }
+        tokio::fs::rename(&partial, self.path(job.id)).await?;
+        Ok(())
+    }
+
+    /// Runs a job to completion, recording the outcome.
+    async fn run(self: Arc<Self>, job: Arc<Job>, mp4: mp4::File) {
+        let result = self.write(&job, mp4).await;
+        let finished = Instant::now();
+        let mut progress = job.progress.lock().unwrap();
+        progress.status = match result {
+            Ok(()) => {
+                info!(id = %job.id, "export done");
+                Status::Done { finished }
+            }
+            Err(e) => {
+                warn!(id = %job.id, err = %e.chain(), "export failed");
+                match std::fs::remove_file(self.partial_path(job.id)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        warn!(%e, "unable to remove partial export {}", job.id);
+                    }
+                    _ => {}
+                }
+                Status::Failed {
+                    finished,
+                    error: e.to_string(),
+                }
+            }
+        };
+    }
+}
+
+/// Builds a `.mp4` of all recordings of the given stream which overlap `range`.
+pub(crate) fn build_clip(
+    db: &Arc<db::Database>,
+    uuid: Uuid,
+    stream_type: db::StreamType,
+    range: Range<recording::Time>,
+) -> Result<mp4::File, Error> {
+    let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+    {
+        let l = db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+        let mut first_start = None;
+        l.list_recordings_by_time(stream_id, range.clone(), &mut |r| {
+            let start = builder.append_wall_range(&l, r, &range)?;
+            first_start.get_or_insert(start);
+            Ok(())
+        })?;
+        let Some(first_start) = first_start else {
+            bail!(
+                NotFound,
+                msg("no recordings of {uuid}/{stream_type} in the requested range")
+            );
+        };
+        let tm = time::at(time::Timespec {
+            sec: first_start.unix_seconds(),
+            nsec: 0,
+        });
+        builder.set_filename(&format!(
+            "{}-{}-{}.mp4",
+            tm.strftime("%Y%m%d%H%M%S").unwrap(),
+            camera.short_name,
+            stream_type.as_str(),
+        ))?;
+    }
+    builder.build(db.clone(), super::dirs_by_id(db))
+}
+
+fn user_id(caller: &Caller) -> Option<i32> {
+    caller.user.as_ref().map(|u| u.id)
+}
+
+impl Job {
+    fn to_json(&self) -> json::Export {
+        let progress = self.progress.lock().unwrap();
+        let (status, error, download_url) = match &progress.status {
+            Status::Running => ("running", None, None),
+            Status::Done { .. } => (
+                "done",
+                None,
+                Some(format!("/api/exports/{}/clip.mp4", self.id)),
+            ),
+            Status::Failed { error, .. } => ("failed", Some(error.clone()), None),
+        };
+        json::Export {
+            id: self.id.to_string(),
+            camera_uuid: self.camera_uuid,
+            stream: self.stream_type.as_str(),
+            start_time_90k: self.range.start,
+            end_time_90k: self.range.end,
+            status,
+            written_bytes: progress.written_bytes,
+            total_bytes: self.total_bytes,
+            error,
+            download_url,
+        }
+    }
+}
+
+impl Service {
+    pub(super) async fn exports(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => self.list_exports(req, caller),
+            Method::POST => self.post_export(req, caller).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )),
+        }
+    }
+
+    fn list_exports(&self, req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        self.exports.prune(Instant::now());
+        let mut exports: Vec<_> = {
+            let jobs = self.exports.jobs.lock().unwrap();
+            jobs.values()
+                .filter(|j| {
+                    j.user_id == user_id(&caller) && caller.permissions.allows_camera(j.camera_uuid)
+                })
+                .map(|j| j.to_json())
+                .collect()
+        };
+        exports.sort_by(|a, b| a.id.cmp(&b.id));
+        serve_json(&req, &json::ListExports { exports })
+    }
+
+    async fn post_export(&self, mut req: Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostExport = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        if !caller.permissions.allows_camera(r.camera_uuid) {
+            bail!(
+                PermissionDenied,
+                msg("not permitted to access camera {}", r.camera_uuid)
+            );
+        }
+        if self.exports.dir.is_none() {
+            bail!(
+                FailedPrecondition,
+                msg("exports are disabled; set exportDir in the config file")
+            );
+        }
+        let stream_type = db::StreamType::parse(r.stream)
+            .ok_or_else(|| err!(InvalidArgument, msg("no such stream type {}", r.stream)))?;
+        let range = r.start_time_90k..r.end_time_90k;
+        if range.start >= range.end {
+            bail!(
+                InvalidArgument,
+                msg("startTime90k must be before endTime90k")
+            );
+        }
+        let now = Instant::now();
+        self.exports.prune(now);
+        {
+            let jobs = self.exports.jobs.lock().unwrap();
+            let running = jobs
+                .values()
+                .filter(|j| matches!(j.progress.lock().unwrap().status, Status::Running))
+                .count();
+            if running >= MAX_RUNNING {
+                bail!(
+                    ResourceExhausted,
+                    msg("{running} exports are already running; try again later")
+                );
+            }
+        }
+
+        let mp4 = self.build_export(r.camera_uuid, stream_type, range.clone())?;
+        let mut headers = http::HeaderMap::new();
+        mp4.add_headers(&mut headers);
+        let job = Arc::new(Job {
+            id: Ulid::new(),
+            user_id: user_id(&caller),
+            camera_uuid: r.camera_uuid,
+            stream_type,
+            range,
+            headers,
+            total_bytes: mp4.len(),
+            progress: Mutex::new(Progress {
+                written_bytes: 0,
+                status: Status::Running,
+            }),
+            cancelled: AtomicBool::new(false),
+        });
+        info!(id = %job.id, "starting export of {} bytes", job.total_bytes);
+        self.exports
+            .jobs
+            .lock()
+            .unwrap()
+            .insert(job.id, job.clone());
+        let span = tracing::info_span!("export", id = %job.id);
+        tokio::spawn(self.exports.clone().run(job.clone(), mp4).instrument(span));
+        serve_json(&req, &job.to_json())
+    }
+
+    /// Builds a `.mp4` of all recordings of the given stream which overlap `range`.
+    pub(super) fn build_export(
+        &self,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+        range: Range<recording::Time>,
+    ) -> Result<mp4::File, Error> {
+        build_clip(&self.db, uuid, stream_type, range)
+    }
+
+    pub(super) async fn export(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        id: Ulid,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let job = self.exports.get(&caller, id)?;
+                serve_json(&req, &job.to_json())
+            }
+            Method::DELETE => self.delete_export(req, caller, id).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or DELETE expected",
+            )),
+        }
+    }
+
+    async fn delete_export(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: Ulid,
+    ) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteExport = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let job = self.exports.get(&caller, id)?;
+        job.cancelled.store(true, Ordering::Relaxed);
+        self.exports.jobs.lock().unwrap().remove(&id);
+        self.exports.remove_file(id);
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    pub(super) fn export_download(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: Ulid,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let job = self.exports.get(&caller, id)?;
+        if !matches!(job.progress.lock().unwrap().status, Status::Done { .. }) {
+            bail!(FailedPrecondition, msg("export {id} isn't done"));
+        }
+        let f = std::fs::File::open(self.exports.path(id))?;
+        let e = http_serve::ChunkedReadFile::<Chunk, BoxedError>::new(f, job.headers.clone())
+            .err_kind(ErrorKind::Internal)?;
+        Ok(http_serve::serve(e, req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn job(id: Ulid, status: Status) -> Arc<Job> {
+        Arc::new(Job {
+            id,
+            user_id: None,
+            camera_uuid: Uuid::nil(),
+            stream_type: db::StreamType::Main,
+            range: recording::Time(0)..recording::Time(90_000),
+            headers: http::HeaderMap::new(),
+            total_bytes: 0,
+            progress: Mutex::new(Progress {
+                written_bytes: 0,
+                status,
+            }),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    #[test]
+    fn prune() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let exports = Exports::new(tmpdir.path().to_owned()).unwrap();
+        let now = Instant::now() + RETENTION + Duration::from_secs(60);
+        let (running, recent, old) = (Ulid::new(), Ulid::new(), Ulid::new());
+        std::fs::write(exports.path(old), b"old").unwrap();
+        {
+            let mut jobs = exports.jobs.lock().unwrap();
+            jobs.insert(running, job(running, Status::Running));
+            jobs.insert(
+                recent,
+                job(
+                    recent,
+                    Status::Done {
+                        finished: now - Duration::from_secs(60),
+                    },
+                ),
+            );
+            jobs.insert(
+                old,
+                job(
+                    old,
+                    Status::Done {
+                        finished: now - RETENTION - Duration::from_secs(1),
+                    },
+                ),
+            );
+        }
+        exports.prune(now);
+        let jobs = exports.jobs.lock().unwrap();
+        assert!(jobs.contains_key(&running));
+        assert!(jobs.contains_key(&recent));
+        assert!(!jobs.contains_key(&old));
+        assert!(!exports.path(old).exists());
+    }
+
+    #[test]
+    fn stale_files_removed() {
+        testutil::init();
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let stale = tmpdir.path().join(format!("{}.partial", Ulid::new()));
+        let other = tmpdir.path().join("other.mp4");
+        std::fs::write(&stale, b"").unwrap();
+        std::fs::write(&other, b"").unwrap();
+        Exports::new(tmpdir.path().to_owned()).unwrap();
+        assert!(!stale.exists());
+        assert!(other.exists());
+    }
+}
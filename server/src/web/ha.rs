@@ -0,0 +1,185 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Home Assistant integration: `/api/ha/...` handling.
+//!
+//! Home Assistant's generic camera platform fetches a still image URL itself and hands a stream
+//! URL to ffmpeg, authenticating both with HTTP Basic credentials. These endpoints accept an API
+//! token as the password and, unlike the rest of the API, challenge unauthenticated requests
+//! for credentials. `GET /api/ha/cameras` describes each camera with the platform's options, so
+//! adding a camera needs no URL templates.
+
+use base::{bail, err};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use super::{from_base_error, plain_response, serve_json, Caller, ResponseResult, Service};
+use crate::body::Body;
+use crate::json;
+
+/// The stream types to serve as a camera's live stream, in order of preference.
+const LIVE_STREAM_TYPES: [db::StreamType; 2] = [db::StreamType::Main, db::StreamType::Sub];
+
+/// The stream types to serve as a camera's still image, in order of preference. Home Assistant
+/// fetches still images often, and a sub stream's key frames are cheaper to decode.
+const STILL_STREAM_TYPES: [db::StreamType; 2] = [db::StreamType::Sub, db::StreamType::Main];
+
+/// Returns the first of `types` which `camera` has.
+fn first_stream(camera: &db::Camera, types: &[db::StreamType]) -> Option<db::StreamType> {
+    types
+        .iter()
+        .copied()
+        .find(|t| camera.streams[t.index()].is_some())
+}
+
+/// Returns an unauthenticated response which asks for HTTP Basic credentials.
+pub(super) fn challenge(err: &base::Error) -> Response<Body> {
+    let mut resp = from_base_error(err);
+    resp.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"Moonfire NVR\""),
+    );
+    resp
+}
+
+impl Service {
+    pub(super) fn ha_cameras(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .ok_or_else(|| err!(InvalidArgument, msg("Host header required")))?;
+        let scheme = if self.is_secure(req) { "https" } else { "http" };
+        let base = format!("{scheme}://{host}/api/ha/cameras");
+        let l = self.db.lock();
+        let mut cameras = Vec::new();
+        for c in l.cameras_by_id().values() {
+            if !caller.permissions.allows_camera(c.uuid) {
+                continue;
+            }
+            let Some(live) = first_stream(c, &LIVE_STREAM_TYPES) else {
+                continue;
+            };
+            cameras.push(json::HaCamera {
+                uuid: c.uuid,
+                name: &c.short_name,
+                still_image_url: format!("{base}/{}/snapshot.jpg", c.uuid),
+                stream_source: format!("{base}/{}/{live}/hls/playlist.m3u8", c.uuid),
+                content_type: "image/jpeg",
+                authentication: "basic",
+            });
+        }
+        serve_json(req, &json::GetHaCamerasResponse { cameras })
+    }
+
+    /// Serves a snapshot of the camera's preferred still image stream.
+    pub(super) async fn ha_camera_snapshot(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        let stream_type = {
+            let l = self.db.lock();
+            let camera = l
+                .get_camera(uuid)
+                .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+            first_stream(camera, &STILL_STREAM_TYPES)
+                .ok_or_else(|| err!(NotFound, msg("camera {uuid} has no streams")))?
+        };
+        self.stream_snapshot(req, caller, uuid, stream_type).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn basic_auth() {
+        testutil::init();
+        let s = Server::new(None);
+        let token = {
+            let mut l = s.db.db.lock();
+            let mut c = l.get_user("slamb").unwrap().change();
+            c.permissions.view_video = true;
+            let uid = l.apply_user_change(c).unwrap().id;
+            let permissions = db::Permissions {
+                view_video: true,
+                ..Default::default()
+            };
+            let (token, _) = l.make_api_token(uid, None, 0, None, permissions).unwrap();
+            token.encode_base64()
+        };
+        let cli = reqwest::Client::new();
+        let url = format!("{}/api/ha/cameras", &s.base_url);
+
+        // Unauthenticated requests are challenged here, but not elsewhere.
+        let resp = cli.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert!(resp
+            .headers()
+            .contains_key(reqwest::header::WWW_AUTHENTICATE));
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert!(!resp
+            .headers()
+            .contains_key(reqwest::header::WWW_AUTHENTICATE));
+
+        // A password which isn't a token is ignored.
+        let resp = cli
+            .get(&url)
+            .basic_auth("slamb", Some("hunter2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let resp = cli
+            .get(&url)
+            .basic_auth("homeassistant", Some(&token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let camera_url = format!("{}/{}", &url, s.db.test_camera_uuid);
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "cameras": [{
+                    "uuid": s.db.test_camera_uuid,
+                    "name": "test camera",
+                    "still_image_url": format!("{camera_url}/snapshot.jpg"),
+                    "stream_source": format!("{camera_url}/main/hls/playlist.m3u8"),
+                    "content_type": "image/jpeg",
+                    "authentication": "basic",
+                }],
+            })
+        );
+
+        // The stream source is authorized by the same credentials.
+        let resp = cli
+            .get(&format!("{camera_url}/main/hls/playlist.m3u8"))
+            .basic_auth("homeassistant", Some(&token))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    }
+}
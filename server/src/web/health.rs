@@ -0,0 +1,300 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Health checks: `/api/health` and `/api/cameras/<uuid>/health`.
+//!
+//! The overall status is available to anyone, so that load balancers and uptime monitors can
+//! check it without credentials. The per-stream and per-directory details require
+//! `read_camera_configs`, as does a camera's health history.
+
+use std::collections::BTreeMap;
+
+use base::clock::Clocks;
+use base::time::{Duration, Time, TIME_UNITS_PER_SEC};
+use base::{bail, err, FastHashMap};
+use http::{Method, Request, StatusCode};
+use uuid::Uuid;
+
+use super::{plain_response, serve_json, Caller, ResponseResult, Service};
+use crate::json::{self, HealthStatus};
+
+/// How far past its `flush_if_sec` a synced recording may wait to be committed before the
+/// database is considered stalled.
+const FLUSH_GRACE: Duration = Duration(60 * TIME_UNITS_PER_SEC);
+
+impl Service {
+    pub(super) fn health(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        let statuses: FastHashMap<_, _> = self
+            .metrics
+            .streams()
+            .into_iter()
+            .map(|(id, c)| (id, c.status.lock().unwrap().clone()))
+            .collect();
+        let l = self.db.lock();
+        let now = Time::new(self.db.clocks().realtime());
+        let mut status = HealthStatus::Ok;
+        let mut flush_lag = Duration(0);
+        let mut unloaded_recordings = 0;
+        let mut streams = Vec::new();
+        for (id, s) in l.streams_by_id() {
+            unloaded_recordings += i64::from(s.unloaded_recordings());
+            let lag = s.flush_lag(now);
+            let allowed = Duration(i64::from(s.config.flush_if_sec) * TIME_UNITS_PER_SEC);
+            if lag > allowed + FLUSH_GRACE {
+                status = HealthStatus::Unhealthy;
+            }
+            flush_lag = std::cmp::max(flush_lag, lag);
+            let stream_status = statuses.get(id).cloned().unwrap_or_default();
+            let stream_health = if stream_status.is_down(now) {
+                HealthStatus::Degraded
+            } else {
+                HealthStatus::Ok
+            };
+            status = std::cmp::max(status, stream_health);
+            let c = &l.cameras_by_id()[&s.camera_id];
+            if !caller.permissions.read_camera_configs || !caller.permissions.allows_camera(c.uuid)
+            {
+                continue;
+            }
+            let (last_error_time_90k, last_error) = stream_status.last_error.unzip();
+            streams.push(json::StreamHealth {
+                camera_uuid: c.uuid,
+                camera_short_name: &c.short_name,
+                type_: s.type_.as_str(),
+                status: stream_health,
+                running: stream_status.running,
+                connected: stream_status.connected,
+                last_frame_time_90k: stream_status.last_frame,
+                last_error_time_90k,
+                last_error,
+            });
+        }
+        let mut dirs = Vec::with_capacity(l.sample_file_dirs_by_id().len());
+        for (&id, d) in l.sample_file_dirs_by_id() {
+            let (open, fs_available_bytes) = match d.get() {
+                Ok(d) => {
+                    let stat = d.statfs()?;
+                    (
+                        true,
+                        Some(stat.block_size() as i64 * stat.blocks_available() as i64),
+                    )
+                }
+                Err(_) => (false, None),
+            };
+
+            // A directory which no recording stream uses is expected to be closed.
+            let needed = l.streams_by_id().values().any(|s| {
+                s.config.is_recording()
+                    && (s.sample_file_dir_id == Some(id)
+                        || s.config.archive_sample_file_dir_id == Some(id))
+            });
+            let dir_health = if needed && !open {
+                HealthStatus::Unhealthy
+            } else {
+                HealthStatus::Ok
+            };
+            status = std::cmp::max(status, dir_health);
+            dirs.push(json::DirHealth {
+                id,
+                path: &d.path,
+                status: dir_health,
+                open,
+                fs_available_bytes,
+            });
+        }
+        let details = caller
+            .permissions
+            .read_camera_configs
+            .then_some(json::HealthDetails {
+                flush_lag_90k: flush_lag,
+                unloaded_recordings,
+                streams,
+                dirs,
+            });
+        let mut resp = serve_json(req, &json::GetHealthResponse { status, details })?;
+        if status == HealthStatus::Unhealthy {
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        Ok(resp)
+    }
+
+    pub(super) fn camera_health(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        if !caller.permissions.read_camera_configs {
+            bail!(PermissionDenied, msg("read_camera_configs required"));
+        }
+        let counters: FastHashMap<_, _> = self.metrics.streams().into_iter().collect();
+
+        // Hold the database lock while reading unsaved totals, so that none are concurrently
+        // saved and thus counted twice.
+        let l = self.db.lock();
+        let camera = l
+            .get_camera(uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+        let mut streams = BTreeMap::new();
+        for (i, id) in camera.streams.iter().enumerate() {
+            let Some(id) = *id else {
+                continue;
+            };
+            let mut days = BTreeMap::new();
+            l.list_stream_health(id, &mut |day, h| {
+                days.insert(day, h);
+                Ok(())
+            })?;
+            let recent = match counters.get(&id) {
+                Some(c) => {
+                    let state = c.health.lock().unwrap();
+                    for (&day, h) in state.unsaved() {
+                        days.entry(day).or_default().add(h);
+                    }
+                    state.window()
+                }
+                None => db::StreamHealth::default(),
+            };
+            let type_ = db::StreamType::from_index(i).expect("valid stream type index");
+            streams.insert(
+                type_.as_str(),
+                json::CameraStreamHealth {
+                    recent: totals(&recent),
+                    days: days
+                        .iter()
+                        .map(|(day, h)| (day.as_ref().to_owned(), totals(h)))
+                        .collect(),
+                },
+            );
+        }
+        serve_json(
+            req,
+            &json::GetCameraHealthResponse {
+                window_sec: crate::health::WINDOW_SEC,
+                streams,
+            },
+        )
+    }
+}
+
+fn totals(h: &db::StreamHealth) -> json::HealthTotals {
+    json::HealthTotals {
+        score: crate::health::score(h),
+        monitored_sec: h.monitored_sec,
+        up_sec: h.up_sec,
+        reconnects: h.reconnects,
+        frame_gaps: h.frame_gaps,
+        frames: h.frames,
+        packets_lost: h.packets_lost,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    use crate::web::tests::Server;
+
+    #[tokio::test]
+    async fn unauthenticated() {
+        testutil::init();
+        let s = Server::new(None);
+        let resp = reqwest::get(&format!("{}/api/health", &s.base_url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp, serde_json::json!({"status": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn details() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!("{}/api/health", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["status"], "ok");
+        assert_eq!(resp["flushLag90k"], 0);
+        assert_eq!(resp["unloadedRecordings"], 0);
+        assert_eq!(resp["streams"][0]["running"], false);
+        assert_eq!(resp["dirs"][0]["open"], true);
+
+        // A running streamer which hasn't connected degrades the status.
+        s.metrics
+            .stream(testutil::TEST_STREAM_ID)
+            .status
+            .lock()
+            .unwrap()
+            .running = true;
+        let resp = cli
+            .get(&format!("{}/api/health", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["status"], "degraded");
+        assert_eq!(resp["streams"][0]["status"], "degraded");
+        assert_eq!(resp["streams"][0]["connected"], false);
+    }
+
+    #[tokio::test]
+    async fn camera_history() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        s.db.db
+            .lock()
+            .add_stream_health(
+                testutil::TEST_STREAM_ID,
+                db::days::Key::containing(base::time::Time(0)).unwrap(),
+                &db::StreamHealth {
+                    monitored_sec: 3600,
+                    up_sec: 3600,
+                    reconnects: 1,
+                    frames: 36_000,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let resp = reqwest::get(&format!(
+            "{}/api/cameras/{}/health",
+            &s.base_url, s.db.test_camera_uuid
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["windowSec"], 3600);
+        let main = &resp["streams"]["main"];
+        assert_eq!(main["recent"]["monitoredSec"], 0);
+        assert!(main["recent"].get("score").is_none());
+        let days = main["days"].as_object().unwrap();
+        assert_eq!(days.len(), 1);
+        let day = days.values().next().unwrap();
+        assert_eq!(day["score"], 90);
+        assert_eq!(day["reconnects"], 1);
+    }
+}
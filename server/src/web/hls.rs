@@ -0,0 +1,531 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! HTTP Live Streaming (RFC 8216): `/api/cameras/<uuid>/<type>/hls/...` handling.
+//!
+//! Playlists are generated from the recording index. Each recording is divided into HLS
+//! segments at key frames roughly every [`TARGET_SEGMENT_DURATION_90K`]; each segment is served
+//! as an fMP4 media segment whose `tfdt` places it on the stream's cumulative media timeline, so
+//! consecutive segments (even across recordings) play back continuously.
+
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use base::{bail, clock::Clocks, err, Error, FastHashMap};
+use db::recording;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use crate::mp4;
+
+use super::{Caller, ResponseResult, Service};
+
+/// The desired duration of a segment. Segments end at the first key frame at least this long
+/// after their start, or at the end of the recording.
+const TARGET_SEGMENT_DURATION_90K: i32 = 4 * recording::TIME_UNITS_PER_SEC as i32;
+
+/// How far back to look for recordings when building a live playlist.
+const LIVE_LOOKBACK: recording::Duration =
+    recording::Duration(3 * 60 * recording::TIME_UNITS_PER_SEC);
+
+/// The number of segments in a live playlist.
+const LIVE_SEGMENTS: usize = 6;
+
+/// The longest time range allowed in a historical playlist.
+const MAX_VOD_DURATION: recording::Duration =
+    recording::Duration(24 * 60 * 60 * recording::TIME_UNITS_PER_SEC);
+
+/// Media sequence numbers are assigned to recordings in blocks of this size when there's no
+/// prior assignment to continue from. It exceeds the number of segments in any recording, so
+/// numbers still increase across server restarts.
+const COLD_SEQUENCE_STRIDE: u64 = 1024;
+
+/// The most recordings to count through when continuing a prior media sequence assignment.
+const MAX_SEQUENCE_CATCHUP: i32 = 16;
+
+/// Per-stream media sequence numbers for live playlists.
+///
+/// HLS clients match segments between reloads of a live playlist by their media sequence
+/// number, so these must be stable. Each entry is a recording id and the media sequence number
+/// of its first segment, from which later numbers are derived by counting segments.
+#[derive(Default)]
+pub(super) struct MediaSequences(Mutex<FastHashMap<i32, (i32, u64)>>);
+
+/// A segment within a playlist.
+#[derive(Debug, PartialEq, Eq)]
+struct PlaylistSegment {
+    recording_id: i32,
+    open_id: u32,
+    video_sample_entry_id: i32,
+
+    /// The media time range within the recording.
+    media_range_90k: Range<i32>,
+}
+
+/// The `s` query parameter of `hls/segment.m4s`: `RECORDING_ID@OPEN_ID.START-END`, where
+/// `START` and `END` are media times within the recording.
+#[derive(Debug, PartialEq, Eq)]
+struct SegmentParam {
+    recording_id: i32,
+    open_id: u32,
+    media_range_90k: Range<i32>,
+}
+
+impl FromStr for SegmentParam {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (recording_id, s) = s.split_once('@').ok_or(())?;
+        let (open_id, s) = s.split_once('.').ok_or(())?;
+        let (start, end) = s.split_once('-').ok_or(())?;
+        let p = SegmentParam {
+            recording_id: i32::from_str(recording_id).map_err(|_| ())?,
+            open_id: u32::from_str(open_id).map_err(|_| ())?,
+            media_range_90k: i32::from_str(start).map_err(|_| ())?
+                ..i32::from_str(end).map_err(|_| ())?,
+        };
+        if p.media_range_90k.start < 0 || p.media_range_90k.end <= p.media_range_90k.start {
+            return Err(());
+        }
+        Ok(p)
+    }
+}
+
+/// Returns the complete segments of a recording, as media time ranges within it.
+///
+/// The final segment of a growing recording is incomplete and so is omitted.
+fn recording_segments(
+    db: &db::LockedDatabase,
+    row: &db::ListRecordingsRow,
+) -> Result<Vec<Range<i32>>, Error> {
+    let growing = (row.flags & db::RecordingFlags::Growing as i32) != 0;
+    db.with_recording_playback(row.id, &mut |playback| {
+        let mut segments = Vec::new();
+        let mut it = recording::SampleIndexIterator::default();
+        let mut start = None;
+        while it.next(&playback.video_index)? {
+            if !it.is_key() {
+                continue;
+            }
+            match start {
+                None => start = Some(it.start_90k),
+                Some(s) if it.start_90k - s >= TARGET_SEGMENT_DURATION_90K => {
+                    segments.push(s..it.start_90k);
+                    start = Some(it.start_90k);
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(s) = start {
+            if !growing && s < row.media_duration_90k {
+                segments.push(s..row.media_duration_90k);
+            }
+        }
+        Ok(segments)
+    })
+}
+
+/// Writes a media playlist. Historical (`vod`) playlists are complete; live ones are reloaded.
+fn write_playlist(segments: &[PlaylistSegment], media_sequence: u64, vod: bool) -> String {
+    let target_duration = segments
+        .iter()
+        .map(|s| {
+            (s.media_range_90k.end - s.media_range_90k.start + recording::TIME_UNITS_PER_SEC as i32
+                - 1)
+                / recording::TIME_UNITS_PER_SEC as i32
+        })
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let mut out = String::new();
+    out.push_str("#EXTM3U\n#EXT-X-VERSION:7\n");
+    let _ = writeln!(out, "#EXT-X-TARGETDURATION:{target_duration}");
+    let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{media_sequence}");
+    if vod {
+        out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    }
+    out.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let mut video_sample_entry_id = None;
+    for s in segments {
+        if video_sample_entry_id != Some(s.video_sample_entry_id) {
+            // The playlist is at `/api/cameras/<uuid>/<type>/hls/playlist.m3u8`.
+            let _ = writeln!(
+                out,
+                "#EXT-X-MAP:URI=\"../../../../init/{}.mp4\"",
+                s.video_sample_entry_id
+            );
+            video_sample_entry_id = Some(s.video_sample_entry_id);
+        }
+        let duration_90k = s.media_range_90k.end - s.media_range_90k.start;
+        let _ = writeln!(
+            out,
+            "#EXTINF:{}.{:03},\nsegment.m4s?s={}@{}.{}-{}",
+            duration_90k / 90_000,
+            (duration_90k % 90_000) / 90,
+            s.recording_id,
+            s.open_id,
+            s.media_range_90k.start,
+            s.media_range_90k.end
+        );
+    }
+    if vod {
+        out.push_str("#EXT-X-ENDLIST\n");
+    }
+    out
+}
+
+impl Service {
+    pub(super) fn stream_hls_playlist(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let (mut start_time, mut end_time) = (None, None);
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => {
+                        start_time =
+                            Some(recording::Time::parse(value).map_err(|_| {
+                                err!(InvalidArgument, msg("unparseable startTime90k"))
+                            })?)
+                    }
+                    "endTime90k" => {
+                        end_time =
+                            Some(recording::Time::parse(value).map_err(|_| {
+                                err!(InvalidArgument, msg("unparseable endTime90k"))
+                            })?)
+                    }
+                    _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+                }
+            }
+        }
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let vod = start_time.is_some() || end_time.is_some();
+        let desired_time = if vod {
+            let end = end_time.unwrap_or(now);
+            let start = start_time.unwrap_or(end - MAX_VOD_DURATION);
+            if end - start > MAX_VOD_DURATION {
+                bail!(
+                    InvalidArgument,
+                    msg("playlist time range may be at most 24 hours")
+                );
+            }
+            start..end
+        } else {
+            now - LIVE_LOOKBACK..recording::Time::max_value()
+        };
+
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+        let mut rows = Vec::new();
+        db.list_recordings_by_time(stream_id, desired_time.clone(), &mut |r| {
+            rows.push(r);
+            Ok(())
+        })?;
+        rows.sort_by_key(|r| r.id.recording());
+
+        let mut segments = Vec::new();
+        let mut segments_by_recording = FastHashMap::default();
+        for row in &rows {
+            let s = recording_segments(&db, row)?;
+            segments_by_recording.insert(row.id.recording(), s.len());
+            for media_range_90k in s {
+                if vod {
+                    // Skip segments outside the desired time range.
+                    let wall = |t| {
+                        row.start
+                            + recording::Duration(i64::from(recording::rescale(
+                                t,
+                                row.media_duration_90k,
+                                row.wall_duration_90k,
+                            )))
+                    };
+                    if wall(media_range_90k.end) <= desired_time.start
+                        || wall(media_range_90k.start) >= desired_time.end
+                    {
+                        continue;
+                    }
+                }
+                segments.push(PlaylistSegment {
+                    recording_id: row.id.recording(),
+                    open_id: row.open_id,
+                    video_sample_entry_id: row.video_sample_entry_id,
+                    media_range_90k,
+                });
+            }
+        }
+
+        let media_sequence = if vod {
+            0
+        } else {
+            let skip = segments.len().saturating_sub(LIVE_SEGMENTS);
+            segments.drain(..skip);
+            match segments.first() {
+                None => 0,
+                Some(first) => {
+                    let first_recording_seq = self.hls_sequences.get(
+                        &db,
+                        stream_id,
+                        first.recording_id,
+                        &segments_by_recording,
+                    )?;
+                    let index = segments_by_recording[&first.recording_id]
+                        - segments
+                            .iter()
+                            .filter(|s| s.recording_id == first.recording_id)
+                            .count();
+                    first_recording_seq + index as u64
+                }
+            }
+        };
+        drop(db);
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/vnd.apple.mpegurl"),
+            )
+            .body(write_playlist(&segments, media_sequence, vod).into())
+            .expect("hardcoded head should be valid"))
+    }
+
+    pub(super) fn stream_hls_segment(
+        &self,
+        req: &Request<::hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let mut param = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => {
+                        param = Some(SegmentParam::from_str(value).map_err(|()| {
+                            err!(InvalidArgument, msg("invalid s parameter: {value}"))
+                        })?)
+                    }
+                    _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+                }
+            }
+        }
+        let param = param.ok_or_else(|| err!(InvalidArgument, msg("s parameter required")))?;
+        let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+        builder.include_base_media_decode_time(true)?;
+        {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+            let mut found = false;
+            db.list_recordings_by_id(
+                stream_id,
+                param.recording_id..param.recording_id + 1,
+                &mut |r| {
+                    if r.open_id != param.open_id {
+                        bail!(
+                            NotFound,
+                            msg(
+                                "recording {} has open id {}, requested {}",
+                                r.id,
+                                r.open_id,
+                                param.open_id,
+                            ),
+                        );
+                    }
+                    if param.media_range_90k.end > r.media_duration_90k {
+                        bail!(
+                            NotFound,
+                            msg(
+                                "recording {} has media duration {}, requested end {}",
+                                r.id,
+                                r.media_duration_90k,
+                                param.media_range_90k.end,
+                            ),
+                        );
+                    }
+                    found = true;
+                    builder.append(&db, r, param.media_range_90k.clone(), true)
+                },
+            )?;
+            if !found {
+                bail!(
+                    NotFound,
+                    msg("no such recording {}/{}", stream_id, param.recording_id)
+                );
+            }
+        }
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_id())?;
+        Ok(http_serve::serve(mp4, req))
+    }
+}
+
+impl MediaSequences {
+    /// Returns the media sequence number of the first segment of the given recording.
+    ///
+    /// `segments_by_recording` has the segment counts of recordings already examined; others
+    /// are counted as needed.
+    fn get(
+        &self,
+        db: &db::LockedDatabase,
+        stream_id: i32,
+        recording_id: i32,
+        segments_by_recording: &FastHashMap<i32, usize>,
+    ) -> Result<u64, Error> {
+        let mut l = self.0.lock().unwrap();
+        let cold =
+            u64::try_from(recording_id).map_err(|_| err!(OutOfRange))? * COLD_SEQUENCE_STRIDE;
+        let seq = match l.get(&stream_id) {
+            Some(&(prev_id, prev_seq)) if prev_id <= recording_id => {
+                match count_segments(db, stream_id, prev_id..recording_id, segments_by_recording)? {
+                    Some(n) => prev_seq + n,
+                    None => cold,
+                }
+            }
+            Some(&(prev_id, prev_seq)) => {
+                match count_segments(db, stream_id, recording_id..prev_id, segments_by_recording)? {
+                    Some(n) if n <= prev_seq => prev_seq - n,
+                    _ => cold,
+                }
+            }
+            None => cold,
+        };
+        l.insert(stream_id, (recording_id, seq));
+        Ok(seq)
+    }
+}
+
+/// Counts the segments in the given recordings, or returns `None` if it's impractical because
+/// there are too many or some no longer exist.
+fn count_segments(
+    db: &db::LockedDatabase,
+    stream_id: i32,
+    ids: Range<i32>,
+    segments_by_recording: &FastHashMap<i32, usize>,
+) -> Result<Option<u64>, Error> {
+    if ids.end - ids.start > MAX_SEQUENCE_CATCHUP {
+        return Ok(None);
+    }
+    let mut total = 0;
+    let mut missing = Vec::new();
+    for id in ids.clone() {
+        match segments_by_recording.get(&id) {
+            Some(&n) => total += n as u64,
+            None => missing.push(id),
+        }
+    }
+    let mut found = 0;
+    for id in &missing {
+        db.list_recordings_by_id(stream_id, *id..*id + 1, &mut |r| {
+            total += recording_segments(db, &r)?.len() as u64;
+            found += 1;
+            Ok(())
+        })?;
+    }
+    if found < missing.len() {
+        return Ok(None);
+    }
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PlaylistSegment, SegmentParam};
+    use db::testutil;
+    use std::str::FromStr;
+
+    #[test]
+    fn segment_param() {
+        testutil::init();
+        assert_eq!(
+            SegmentParam::from_str("42@1.180000-540000").unwrap(),
+            SegmentParam {
+                recording_id: 42,
+                open_id: 1,
+                media_range_90k: 180_000..540_000,
+            }
+        );
+        SegmentParam::from_str("42@1.180000").unwrap_err();
+        SegmentParam::from_str("42.0-1").unwrap_err();
+        SegmentParam::from_str("42@1.5-5").unwrap_err();
+        SegmentParam::from_str("42@1.-5-10").unwrap_err();
+    }
+
+    #[test]
+    fn write_playlist() {
+        testutil::init();
+        let segments = [
+            PlaylistSegment {
+                recording_id: 42,
+                open_id: 1,
+                video_sample_entry_id: 3,
+                media_range_90k: 0..360_000,
+            },
+            PlaylistSegment {
+                recording_id: 42,
+                open_id: 1,
+                video_sample_entry_id: 3,
+                media_range_90k: 360_000..765_000,
+            },
+            PlaylistSegment {
+                recording_id: 43,
+                open_id: 1,
+                video_sample_entry_id: 4,
+                media_range_90k: 0..360_000,
+            },
+        ];
+        assert_eq!(
+            super::write_playlist(&segments, 0, true),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:5\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"../../../../init/3.mp4\"\n\
+             #EXTINF:4.000,\n\
+             segment.m4s?s=42@1.0-360000\n\
+             #EXTINF:4.500,\n\
+             segment.m4s?s=42@1.360000-765000\n\
+             #EXT-X-MAP:URI=\"../../../../init/4.mp4\"\n\
+             #EXTINF:4.000,\n\
+             segment.m4s?s=43@1.0-360000\n\
+             #EXT-X-ENDLIST\n"
+        );
+        assert_eq!(
+            super::write_playlist(&segments[2..], 43 * 1024, false),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:4\n\
+             #EXT-X-MEDIA-SEQUENCE:44032\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"../../../../init/4.mp4\"\n\
+             #EXTINF:4.000,\n\
+             segment.m4s?s=43@1.0-360000\n"
+        );
+    }
+}
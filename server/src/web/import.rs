@@ -0,0 +1,136 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Importing `.mp4` files as recordings: `/api/cameras/<uuid>/<type>/import`.
+
+use std::borrow::Borrow;
+use std::path::PathBuf;
+
+use base::{bail, err, Error};
+use db::recording;
+use futures::StreamExt;
+use http::{Method, Request};
+use tokio::io::AsyncWriteExt;
+use ulid::Ulid;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{require_csrf_if_session, serve_json, Caller, ResponseResult, Service};
+use crate::json;
+
+/// A request to import a file, sent to the server's main task, which owns the syncers.
+pub struct Command {
+    pub stream_id: i32,
+    pub sample_file_dir_id: i32,
+
+    /// The uploaded `.mp4` file. The caller removes it once the import is complete.
+    pub path: PathBuf,
+    pub start: Option<recording::Time>,
+
+    /// Receives the result once the import is complete.
+    pub responder: tokio::sync::oneshot::Sender<Result<crate::import::Summary, Error>>,
+}
+
+impl Service {
+    pub(super) async fn stream_import(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            bail!(InvalidArgument, msg("POST expected"));
+        }
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let mut start = None;
+        let mut csrf = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "start" => start = Some(recording::Time::parse(value)?),
+                    "csrf" => csrf = Some(value.to_owned()),
+                    _ => bail!(InvalidArgument, msg("unknown parameter {key:?}")),
+                }
+            }
+        }
+        require_csrf_if_session(&caller, csrf.as_deref())?;
+        let (Some(import_tx), Some(tmp_dir)) = (&self.import_tx, &self.backup_tmp_dir) else {
+            bail!(
+                Unimplemented,
+                msg("imports aren't supported by this server")
+            );
+        };
+        let (stream_id, sample_file_dir_id) = {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+            let dir_id = db.streams_by_id()[&stream_id]
+                .sample_file_dir_id
+                .ok_or_else(|| {
+                    err!(
+                        FailedPrecondition,
+                        msg("stream {uuid}/{stream_type} has no sample file dir")
+                    )
+                })?;
+            (stream_id, dir_id)
+        };
+
+        let (parts, body) = req.into_parts();
+        let req = Request::from_parts(parts, hyper::Body::empty());
+        let path = tmp_dir.join(format!("import-{}.mp4.tmp", Ulid::new()));
+        let r = async {
+            write_body(body, &path).await?;
+            let (responder, rx) = tokio::sync::oneshot::channel();
+            import_tx
+                .send(Command {
+                    stream_id,
+                    sample_file_dir_id,
+                    path: path.clone(),
+                    start,
+                    responder,
+                })
+                .await
+                .map_err(|_| err!(Unavailable, msg("server is shutting down")))?;
+            rx.await
+                .map_err(|_| err!(Unavailable, msg("server is shutting down")))?
+        }
+        .await;
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!(%err, "unable to remove {}", path.display());
+            }
+        }
+        let summary = r?;
+        serve_json(
+            &req,
+            &json::ImportResponse {
+                recordings: summary.recordings,
+                frames: summary.frames,
+                start_time_90k: summary.time.start,
+                end_time_90k: summary.time.end,
+            },
+        )
+    }
+}
+
+/// Writes an uploaded file to `path`.
+async fn write_body(mut body: hyper::Body, path: &std::path::Path) -> Result<(), Error> {
+    let mut f = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| err!(e, msg("unable to create {}", path.display())))?;
+    while let Some(chunk) = body.next().await {
+        let chunk =
+            chunk.map_err(|e| err!(Unavailable, msg("unable to read upload"), source(e)))?;
+        f.write_all(&chunk).await?;
+    }
+    f.flush().await?;
+    Ok(())
+}
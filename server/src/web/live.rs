@@ -2,20 +2,105 @@
 // Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-//! Live video websocket handling.
+//! Live video websocket handling: `/api/cameras/<uuid>/<type>/live.m4s` for a single stream, and
+//! `/api/live.m4s` for any number of streams over one connection.
 
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use base::{bail, err, Error};
+use base::{bail, err, Error, FastHashMap};
+use db::recording::TIME_UNITS_PER_SEC;
 use futures::{future::Either, SinkExt, StreamExt};
 use http::header;
 use tokio_tungstenite::{tungstenite, WebSocketStream};
+use url::form_urlencoded;
 use uuid::Uuid;
 
-use crate::mp4;
+use crate::{json, mp4};
 
 use super::{Caller, Service};
 
+/// The most streams a single `/api/live.m4s` connection may subscribe to at once.
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+/// A stream subscribed to via `/api/live.m4s`.
+struct Subscription {
+    camera_uuid: Uuid,
+    stream_type: db::StreamType,
+
+    /// Cleared on unsubscribe, so the database drops the watch on the next segment.
+    active: Arc<AtomicBool>,
+
+    /// True until the first segment is sent, which should start at a key frame.
+    start_at_key: bool,
+
+    /// If audio should follow each media segment; see [`LiveOptions`].
+    audio: bool,
+}
+
+type LiveSender = futures::channel::mpsc::UnboundedSender<(i32, db::LiveSegment)>;
+
+/// Returns whether a live segment should be sent to a subscriber.
+///
+/// With `keyframes_only`, only segments starting at a key frame are sent, for low-bandwidth
+/// viewing. Filtering in the watch callback means other frames are never even queued.
+fn wanted(l: &db::LiveSegment, keyframes_only: bool) -> bool {
+    l.is_key || !keyframes_only
+}
+
+/// Options for a live stream, as parsed from `live.m4s` query parameters or subscriptions.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct LiveOptions {
+    /// If only segments starting at a key frame should be sent; see [`wanted`].
+    keyframes_only: bool,
+
+    /// If audio fragments should follow each media segment with audio.
+    audio: bool,
+}
+
+impl LiveOptions {
+    fn new(keyframes_only: bool, audio: bool) -> Result<Self, Error> {
+        if keyframes_only && audio {
+            bail!(
+                InvalidArgument,
+                msg("audio isn't supported in keyframes-only mode")
+            );
+        }
+        Ok(LiveOptions {
+            keyframes_only,
+            audio,
+        })
+    }
+}
+
+/// Parses the query parameters of `live.m4s` requests.
+pub(super) fn parse_live_query(query: Option<&str>) -> Result<LiveOptions, Error> {
+    let mut keyframes_only = false;
+    let mut audio = false;
+    if let Some(q) = query {
+        for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+            match &*key {
+                "keyframesOnly" => keyframes_only = value == "true",
+                "audio" => audio = value == "true",
+                _ => {}
+            }
+        }
+    }
+    LiveOptions::new(keyframes_only, audio)
+}
+
+/// Returns the open id, failing if the database is read-only and so has no live streams.
+fn open_id(db: &db::LockedDatabase) -> Result<u32, Error> {
+    match db.open {
+        None => bail!(
+            FailedPrecondition,
+            msg("database is read-only; there are no live streams"),
+        ),
+        Some(o) => Ok(o.id),
+    }
+}
+
 impl Service {
     pub(super) async fn stream_live_m4s(
         self: Arc<Self>,
@@ -23,6 +108,7 @@ impl Service {
         caller: Result<Caller, Error>,
         uuid: Uuid,
         stream_type: db::StreamType,
+        options: LiveOptions,
     ) -> Result<(), Error> {
         let caller = caller?;
         if !caller.permissions.view_video {
@@ -34,15 +120,7 @@ impl Service {
         let (sub_tx, sub_rx) = futures::channel::mpsc::unbounded();
         {
             let mut db = self.db.lock();
-            open_id = match db.open {
-                None => {
-                    bail!(
-                        FailedPrecondition,
-                        msg("database is read-only; there are no live streams"),
-                    );
-                }
-                Some(o) => o.id,
-            };
+            open_id = self::open_id(&db)?;
             let camera = db
                 .get_camera(uuid)
                 .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
@@ -50,7 +128,9 @@ impl Service {
                 .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
             db.watch_live(
                 stream_id,
-                Box::new(move |l| sub_tx.unbounded_send(l).is_ok()),
+                Box::new(move |l| {
+                    !wanted(&l, options.keyframes_only) || sub_tx.unbounded_send(l).is_ok()
+                }),
             )
             .expect("stream_id refed by camera");
         }
@@ -74,7 +154,15 @@ impl Service {
             match next {
                 Either::Left(live) => {
                     if !self
-                        .stream_live_m4s_chunk(open_id, stream_id, ws, live, start_at_key)
+                        .stream_live_m4s_chunk(
+                            open_id,
+                            stream_id,
+                            ws,
+                            live,
+                            start_at_key,
+                            options.audio,
+                            None,
+                        )
                         .await?
                     {
                         return Ok(());
@@ -94,8 +182,161 @@ impl Service {
         }
     }
 
+    pub(super) async fn live_m4s(
+        self: Arc<Self>,
+        ws: &mut WebSocketStream<hyper::upgrade::Upgraded>,
+        caller: Result<Caller, Error>,
+    ) -> Result<(), Error> {
+        let caller = caller?;
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let open_id = open_id(&self.db.lock())?;
+        let (sub_tx, mut sub_rx) = futures::channel::mpsc::unbounded();
+        let mut subs = FastHashMap::default();
+        let mut keepalive = tokio::time::interval(std::time::Duration::new(30, 0));
+        let r = loop {
+            tokio::select! {
+                msg = ws.next() => {
+                    let text = match msg {
+                        Some(Ok(tungstenite::Message::Text(t))) => t,
+                        Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => {
+                            break Ok(());
+                        }
+                        Some(Ok(_)) => continue,
+                    };
+                    if let Err(e) = self.live_m4s_request(&caller, &text, &sub_tx, &mut subs) {
+                        let e = serde_json::json!({ "error": e.to_string() }).to_string();
+                        if ws.send(tungstenite::Message::Text(e)).await.is_err() {
+                            break Ok(());
+                        }
+                    }
+                }
+                Some((stream_id, live)) = sub_rx.next() => {
+                    // Segments may still be queued from an unsubscribed stream.
+                    let Some(s) = subs.get_mut(&stream_id) else {
+                        continue;
+                    };
+                    let label = Some((s.camera_uuid, s.stream_type));
+                    let start_at_key = std::mem::replace(&mut s.start_at_key, false);
+                    let audio = s.audio;
+                    match self
+                        .stream_live_m4s_chunk(
+                            open_id,
+                            stream_id,
+                            ws,
+                            live,
+                            start_at_key,
+                            audio,
+                            label,
+                        )
+                        .await
+                    {
+                        Ok(true) => {}
+                        Ok(false) => break Ok(()),
+                        Err(e) => break Err(e),
+                    }
+                }
+                _ = keepalive.tick() => {
+                    if ws.send(tungstenite::Message::Ping(Vec::new())).await.is_err() {
+                        break Ok(());
+                    }
+                }
+            }
+        };
+        for s in subs.values() {
+            s.active.store(false, Ordering::Relaxed);
+        }
+        r
+    }
+
+    /// Handles a subscribe or unsubscribe message from an `/api/live.m4s` client.
+    fn live_m4s_request(
+        &self,
+        caller: &Caller,
+        text: &str,
+        sub_tx: &LiveSender,
+        subs: &mut FastHashMap<i32, Subscription>,
+    ) -> Result<(), Error> {
+        let req: json::LiveRequest = serde_json::from_str(text)
+            .map_err(|e| err!(InvalidArgument, msg("bad request"), source(e)))?;
+        let (options, camera_uuid, stream) = match req {
+            json::LiveRequest::Subscribe {
+                camera_uuid,
+                stream,
+                keyframes_only,
+                audio,
+            } => (
+                Some(LiveOptions::new(keyframes_only, audio)?),
+                camera_uuid,
+                stream,
+            ),
+            json::LiveRequest::Unsubscribe {
+                camera_uuid,
+                stream,
+            } => (None, camera_uuid, stream),
+        };
+        let stream_type = db::StreamType::parse(&stream)
+            .ok_or_else(|| err!(InvalidArgument, msg("bad stream {stream:?}")))?;
+        let mut db = self.db.lock();
+        let camera = db
+            .get_camera(camera_uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {camera_uuid}")))?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| err!(NotFound, msg("no such stream {camera_uuid}/{stream_type}")))?;
+        let Some(options) = options else {
+            if let Some(s) = subs.remove(&stream_id) {
+                s.active.store(false, Ordering::Relaxed);
+            }
+            return Ok(());
+        };
+        if !caller.permissions.allows_camera(camera_uuid) {
+            bail!(
+                PermissionDenied,
+                msg("not permitted to access camera {camera_uuid}")
+            );
+        }
+        if subs.contains_key(&stream_id) {
+            return Ok(());
+        }
+        if subs.len() >= MAX_SUBSCRIPTIONS {
+            bail!(
+                ResourceExhausted,
+                msg("at most {MAX_SUBSCRIPTIONS} streams may be subscribed at once")
+            );
+        }
+        let active = Arc::new(AtomicBool::new(true));
+        let (cb_active, cb_tx) = (active.clone(), sub_tx.clone());
+        db.watch_live(
+            stream_id,
+            Box::new(move |l| {
+                cb_active.load(Ordering::Relaxed)
+                    && (!wanted(&l, options.keyframes_only)
+                        || cb_tx.unbounded_send((stream_id, l)).is_ok())
+            }),
+        )
+        .expect("stream_id refed by camera");
+        subs.insert(
+            stream_id,
+            Subscription {
+                camera_uuid,
+                stream_type,
+                active,
+                start_at_key: true,
+                audio: options.audio,
+            },
+        );
+        Ok(())
+    }
+
     /// Sends a single live segment chunk of a `live.m4s` stream, returning `Ok(false)` when
     /// the connection is lost.
+    ///
+    /// With `audio`, the chunk's media segment is followed by a fragment of the audio received
+    /// with it, and both have base media decode times on the stream's media timeline, so that
+    /// they stay aligned. `label` identifies the stream in the headers, for connections carrying
+    /// several streams.
+    #[allow(clippy::too_many_arguments)]
     async fn stream_live_m4s_chunk(
         &self,
         open_id: u32,
@@ -103,9 +344,12 @@ impl Service {
         ws: &mut tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
         live: db::LiveSegment,
         start_at_key: bool,
+        audio: bool,
+        label: Option<(Uuid, db::StreamType)>,
     ) -> Result<bool, Error> {
         let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
         let mut row = None;
+        let mut audio_entry = None;
         {
             let db = self.db.lock();
             let mut rows = 0;
@@ -115,22 +359,37 @@ impl Service {
                 builder.append(&db, r, live.media_off_90k.clone(), start_at_key)?;
                 Ok(())
             })?;
+            if let (true, Some(a)) = (audio, live.audio.as_ref()) {
+                let e = db
+                    .audio_sample_entries_by_id()
+                    .get(&a.audio_sample_entry_id)
+                    .ok_or_else(|| {
+                        err!(
+                            Internal,
+                            msg("no such audio sample entry {}", a.audio_sample_entry_id)
+                        )
+                    })?
+                    .clone();
+                builder.include_base_media_decode_time(true)?;
+                builder.set_audio_sample_entry(e.clone())?;
+                audio_entry = Some(e);
+            }
         }
         let row = row.ok_or_else(|| err!(Internal, msg("unable to find {live:?}")))?;
         use http_serve::Entity;
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        let mp4 = builder.build(self.db.clone(), self.dirs_by_id())?;
         let mut hdrs = header::HeaderMap::new();
         mp4.add_headers(&mut hdrs);
         let mime_type = hdrs.get(header::CONTENT_TYPE).unwrap();
         let (prev_media_duration, prev_runs) = row.prev_media_duration_and_runs.unwrap();
-        let hdr = format!(
+        let mut hdr = format!(
             "Content-Type: {}\r\n\
             X-Recording-Start: {}\r\n\
             X-Recording-Id: {}.{}\r\n\
             X-Media-Time-Range: {}-{}\r\n\
             X-Prev-Media-Duration: {}\r\n\
             X-Runs: {}\r\n\
-            X-Video-Sample-Entry-Id: {}\r\n\r\n",
+            X-Video-Sample-Entry-Id: {}\r\n",
             mime_type.to_str().unwrap(),
             row.start.0,
             open_id,
@@ -141,8 +400,66 @@ impl Service {
             prev_runs + if row.run_offset == 0 { 1 } else { 0 },
             &row.video_sample_entry_id
         );
+        if let Some(e) = audio_entry.as_ref() {
+            write!(hdr, "X-Audio-Sample-Entry-Id: {}\r\n", e.id).expect("String write");
+        }
+        hdr.push_str("\r\n");
+        let hdr = match label {
+            Some((uuid, type_)) => format!("X-Camera-Uuid: {uuid}\r\nX-Stream: {type_}\r\n{hdr}"),
+            None => hdr,
+        };
         let mut v = hdr.into_bytes();
         mp4.append_into_vec(&mut v).await?;
+        if let (Some(e), Some(a)) = (audio_entry, live.audio.as_ref()) {
+            if !a.samples.is_empty() {
+                let start_90k = prev_media_duration.0 + i64::from(a.recording_start_90k);
+                let start = u64::try_from(start_90k).map_err(|_| err!(OutOfRange))?
+                    * u64::from(e.sample_rate)
+                    / TIME_UNITS_PER_SEC as u64
+                    + u64::try_from(a.start).map_err(|_| err!(OutOfRange))?;
+                mp4::append_live_audio_fragment(&mut v, a, start);
+            }
+        }
         Ok(ws.send(tungstenite::Message::Binary(v)).await.is_ok())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn keyframes_only() {
+        let key = db::LiveSegment {
+            recording: 1,
+            is_key: true,
+            media_off_90k: 0..3000,
+            audio: None,
+        };
+        let other = db::LiveSegment {
+            is_key: false,
+            media_off_90k: 3000..6000,
+            ..key.clone()
+        };
+        assert!(super::wanted(&key, true));
+        assert!(!super::wanted(&other, true));
+        assert!(super::wanted(&other, false));
+        assert!(
+            super::parse_live_query(Some("keyframesOnly=true"))
+                .unwrap()
+                .keyframes_only
+        );
+        assert!(
+            !super::parse_live_query(Some("keyframesOnly=false"))
+                .unwrap()
+                .keyframes_only
+        );
+        assert!(!super::parse_live_query(None).unwrap().keyframes_only);
+    }
+
+    #[test]
+    fn audio() {
+        let o = super::parse_live_query(Some("audio=true")).unwrap();
+        assert!(o.audio && !o.keyframes_only);
+        assert!(!super::parse_live_query(None).unwrap().audio);
+        super::parse_live_query(Some("audio=true&keyframesOnly=true")).unwrap_err();
+    }
+}
@@ -0,0 +1,273 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Prometheus metrics: `/metrics`.
+
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base::metrics::Encoder;
+use base::{bail, Error, FastHashMap};
+use http::{header, HeaderValue, Method, Request, StatusCode};
+
+use super::{plain_response, Caller, ResponseResult, Service};
+use crate::metrics::StreamCounters;
+
+impl Service {
+    pub(super) fn prometheus_metrics(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        if !caller.permissions.read_camera_configs {
+            bail!(PermissionDenied, msg("read_camera_configs required"));
+        }
+        let mut e = Encoder::default();
+        {
+            let l = self.db.lock();
+            self.encode_streams(&l, &caller, &mut e);
+            encode_dirs(&l, &mut e)?;
+            e.family(
+                "moonfire_db_flush_duration_seconds",
+                "histogram",
+                "Time taken to commit recordings and other changes to the database.",
+            );
+            e.histogram(
+                "moonfire_db_flush_duration_seconds",
+                &[],
+                l.flush_duration(),
+            );
+            e.family("moonfire_sessions", "gauge", "Unrevoked login sessions.");
+            e.sample("moonfire_sessions", &[], l.session_count()?);
+        }
+        e.family(
+            "moonfire_http_request_duration_seconds",
+            "histogram",
+            "Time from receiving HTTP request headers to sending response headers.",
+        );
+        for ((method, code), h) in self.metrics.http_requests() {
+            let code = code.to_string();
+            e.histogram(
+                "moonfire_http_request_duration_seconds",
+                &[("method", method), ("code", code.as_str())],
+                &h,
+            );
+        }
+
+        let (mut resp, writer) = http_serve::streaming_body(req).build();
+        resp.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        if let Some(mut w) = writer {
+            w.write_all(e.finish().as_bytes())?;
+        }
+        Ok(resp)
+    }
+
+    /// Encodes per-stream metrics for the streams of cameras `caller` may access.
+    fn encode_streams(&self, l: &db::LockedDatabase, caller: &Caller, e: &mut Encoder) {
+        let counters: FastHashMap<_, _> = self.metrics.streams().into_iter().collect();
+        let mut streams = Vec::new();
+        for (id, s) in l.streams_by_id() {
+            let c = &l.cameras_by_id()[&s.camera_id];
+            if !caller.permissions.allows_camera(c.uuid) {
+                continue;
+            }
+            streams.push((c.short_name.as_str(), s, counters.get(id)));
+        }
+        e.family(
+            "moonfire_stream_sample_file_bytes",
+            "gauge",
+            "Bytes of sample files currently retained for the stream, including any archive.",
+        );
+        for &(camera, s, _) in &streams {
+            e.sample(
+                "moonfire_stream_sample_file_bytes",
+                &[("camera", camera), ("stream", s.type_.as_str())],
+                s.fs_bytes + s.archived_fs_bytes,
+            );
+        }
+        let counter = |e: &mut Encoder, name, help, field: fn(&StreamCounters) -> &AtomicU64| {
+            e.family(name, "counter", help);
+            for &(camera, s, counters) in &streams {
+                if let Some(c) = counters {
+                    e.sample(
+                        name,
+                        &[("camera", camera), ("stream", s.type_.as_str())],
+                        field(c).load(Ordering::Relaxed),
+                    );
+                }
+            }
+        };
+        counter(
+            e,
+            "moonfire_stream_frames_received_total",
+            "Video frames received from the camera.",
+            |c| &c.frames_received,
+        );
+        counter(
+            e,
+            "moonfire_stream_frames_dropped_total",
+            "Video frames discarded while waiting for a key frame.",
+            |c| &c.frames_dropped,
+        );
+        counter(
+            e,
+            "moonfire_stream_recorded_bytes_total",
+            "Video bytes written to sample files.",
+            |c| &c.bytes_recorded,
+        );
+        counter(
+            e,
+            "moonfire_stream_reconnects_total",
+            "Times the stream has been reopened after an error.",
+            |c| &c.reconnects,
+        );
+        counter(
+            e,
+            "moonfire_stream_packets_lost_total",
+            "RTP packets known to have been lost.",
+            |c| &c.packets_lost,
+        );
+        counter(
+            e,
+            "moonfire_stream_frame_gaps_total",
+            "Times the stream went unusually long between video frames.",
+            |c| &c.frame_gaps,
+        );
+    }
+}
+
+/// Encodes disk usage of each sample file directory.
+fn encode_dirs(l: &db::LockedDatabase, e: &mut Encoder) -> Result<(), Error> {
+    let mut dirs = Vec::with_capacity(l.sample_file_dirs_by_id().len());
+    for (&id, d) in l.sample_file_dirs_by_id() {
+        let used: i64 = l
+            .streams_by_id()
+            .values()
+            .map(|s| {
+                if s.sample_file_dir_id == Some(id) {
+                    s.fs_bytes
+                } else if s.config.archive_sample_file_dir_id == Some(id) {
+                    s.archived_fs_bytes
+                } else {
+                    0
+                }
+            })
+            .sum();
+
+        // Directories which aren't open (as when no stream records to them) have no filesystem
+        // statistics.
+        let stat = match d.get() {
+            Ok(d) => Some(d.statfs()?),
+            Err(_) => None,
+        };
+        dirs.push((d.path.display().to_string(), used, stat));
+    }
+    e.family(
+        "moonfire_sample_file_dir_used_bytes",
+        "gauge",
+        "Bytes of sample files in the directory.",
+    );
+    for (path, used, _) in &dirs {
+        e.sample(
+            "moonfire_sample_file_dir_used_bytes",
+            &[("dir", path.as_str())],
+            used,
+        );
+    }
+    e.family(
+        "moonfire_sample_file_dir_available_bytes",
+        "gauge",
+        "Bytes available to unprivileged users on the directory's filesystem.",
+    );
+    for (path, _, stat) in &dirs {
+        if let Some(stat) = stat {
+            e.sample(
+                "moonfire_sample_file_dir_available_bytes",
+                &[("dir", path.as_str())],
+                stat.fragment_size() as u64 * stat.blocks_available() as u64,
+            );
+        }
+    }
+    e.family(
+        "moonfire_sample_file_dir_size_bytes",
+        "gauge",
+        "Total size of the directory's filesystem.",
+    );
+    for (path, _, stat) in &dirs {
+        if let Some(stat) = stat {
+            e.sample(
+                "moonfire_sample_file_dir_size_bytes",
+                &[("dir", path.as_str())],
+                stat.fragment_size() as u64 * stat.blocks() as u64,
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+
+    use crate::web::tests::Server;
+
+    #[tokio::test]
+    async fn metrics() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        testutil::add_dummy_recordings_to_db(&s.db.db, 1);
+        let cli = reqwest::Client::new();
+
+        // Make a request to be counted.
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let resp = cli
+            .get(&format!("{}/metrics", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let body = resp.text().await.unwrap();
+        assert!(
+            body.contains(
+                "moonfire_stream_sample_file_bytes{camera=\"test camera\",stream=\"main\"}"
+            ),
+            "{body}"
+        );
+        assert!(body.contains("# TYPE moonfire_sample_file_dir_used_bytes gauge\n"));
+        assert!(body.contains("moonfire_sessions 0\n"), "{body}");
+        assert!(
+            body.contains(
+                "moonfire_http_request_duration_seconds_count{method=\"GET\",code=\"200\"} 1\n"
+            ),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn permission_denied() {
+        testutil::init();
+        let s = Server::new(Some(db::Permissions::new()));
+        let resp = reqwest::get(&format!("{}/metrics", &s.base_url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
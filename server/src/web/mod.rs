@@ -3,17 +3,46 @@
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
 pub mod accept;
+mod audit;
+mod backup;
+mod cameras;
+mod detections;
+mod dirs;
+mod events;
+pub mod exports;
+mod ha;
+mod health;
+mod hls;
+pub mod import;
 mod live;
+mod metrics;
+mod oidc;
+mod openapi;
 mod path;
+mod ptz;
+mod reload;
+mod schedule;
+pub(crate) mod search;
 mod session;
+pub mod share;
 mod signals;
+mod snapshot;
 mod static_file;
+mod talk;
+mod thumbnails;
+pub mod tls;
+mod tokens;
+pub mod transcode;
+mod usage;
 mod users;
 mod view;
+mod webauthn;
+mod webrtc;
 mod websocket;
 
 use self::accept::ConnData;
 use self::path::Path;
+use self::view::Container;
 use crate::body::Body;
 use crate::json;
 use crate::mp4;
@@ -23,6 +52,10 @@ use base::Error;
 use base::FastHashMap;
 use base::ResultExt;
 use base::{bail, clock::Clocks, ErrorKind};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
 use core::borrow::Borrow;
 use core::str::FromStr;
 use db::dir::SampleFileDir;
@@ -31,6 +64,7 @@ use http::header::{self, HeaderValue};
 use http::{status::StatusCode, Request, Response};
 use hyper::body::Bytes;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::warn;
 use tracing::Instrument;
@@ -98,6 +132,35 @@ fn serve_json<T: serde::ser::Serialize>(req: &Request<hyper::Body>, out: &T) ->
     Ok(resp)
 }
 
+/// Returns the URL of the next page of `req`'s results: the same path and parameters, with the
+/// given opaque `cursor`.
+fn next_page_url(req: &Request<hyper::Body>, cursor: &str) -> String {
+    let mut q = form_urlencoded::Serializer::new(String::new());
+    if let Some(query) = req.uri().query() {
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key != "cursor" {
+                q.append_pair(&key, &value);
+            }
+        }
+    }
+    q.append_pair("cursor", cursor);
+    format!("{}?{}", req.uri().path(), q.finish())
+}
+
+/// Encodes the opaque `cursor` of `/api/cameras/<uuid>/<type>/recordings`: the first
+/// recording id of the next page.
+fn encode_recordings_cursor(first_id: i32) -> String {
+    URL_SAFE_NO_PAD.encode(first_id.to_be_bytes())
+}
+
+fn decode_recordings_cursor(cursor: &str) -> Result<i32, Error> {
+    let mut buf = [0u8; 4];
+    match URL_SAFE_NO_PAD.decode_slice(cursor, &mut buf[..]) {
+        Ok(4) => Ok(i32::from_be_bytes(buf)),
+        _ => bail!(InvalidArgument, msg("bad cursor")),
+    }
+}
+
 fn csrf_matches(csrf: &str, session: auth::SessionHash) -> bool {
     let mut b64 = [0u8; 32];
     session.encode_base64(&mut b64);
@@ -122,6 +185,45 @@ fn extract_sid(req: &Request<hyper::Body>) -> Option<auth::RawSessionId> {
     None
 }
 
+/// Extracts an API token from the `Authorization` header. Does not authenticate.
+///
+/// The token may be sent as `Bearer <token>` or, for clients such as Home Assistant's generic
+/// camera which support only HTTP Basic authentication, as the password (with any username).
+/// Other schemes, and Basic credentials whose password isn't a well-formed token, are ignored,
+/// as a proxy in front of Moonfire NVR may use them.
+fn extract_api_token(req: &Request<hyper::Body>) -> Result<Option<auth::RawApiToken>, base::Error> {
+    let Some(h) = req.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    if let Some(token) = h.as_bytes().strip_prefix(b"Bearer ") {
+        return auth::RawApiToken::decode_base64(token)
+            .map(Some)
+            .map_err(|_| err!(Unauthenticated, msg("malformed API token")));
+    }
+    Ok(h.as_bytes()
+        .strip_prefix(b"Basic ")
+        .and_then(basic_api_token))
+}
+
+/// Extracts an API token from HTTP Basic `credentials` (the base64 following `Basic `), as the
+/// password with any username. Returns `None` if the password isn't a well-formed token.
+pub(crate) fn basic_api_token(credentials: &[u8]) -> Option<auth::RawApiToken> {
+    let credentials = STANDARD.decode(credentials).ok()?;
+    let i = credentials.iter().position(|&b| b == b':')?;
+    auth::RawApiToken::decode_base64(&credentials[i + 1..]).ok()
+}
+
+/// Returns the open sample file directories; see [`Service::dirs_by_id`].
+pub(crate) fn dirs_by_id(db: &db::Database) -> Arc<FastHashMap<i32, Arc<SampleFileDir>>> {
+    let l = db.lock();
+    Arc::new(
+        l.sample_file_dirs_by_id()
+            .iter()
+            .filter_map(|(&id, d)| Some((id, d.get().ok()?)))
+            .collect(),
+    )
+}
+
 /// Extracts an `application/json` POST body from a request.
 ///
 /// This returns the request body as bytes rather than performing
@@ -167,16 +269,117 @@ pub struct Config<'a> {
     pub time_zone_name: String,
     pub allow_unauthenticated_permissions: Option<db::Permissions>,
     pub privileged_unix_uid: Option<nix::unistd::Uid>,
+    pub trust_remote_user: Option<&'a crate::cmds::run::config::RemoteUserConfig>,
+    pub live_frames: Arc<crate::streamer::LiveFrames>,
+    pub metrics: Arc<crate::metrics::Metrics>,
+    pub events: Arc<crate::events::Events>,
+    pub ice_servers: &'a [crate::cmds::run::config::IceServer],
+    pub webauthn: Option<&'a crate::cmds::run::config::WebAuthnConfig>,
+    pub oidc: Option<&'a crate::cmds::run::config::OidcConfig>,
+    pub audit: Option<&'a crate::cmds::run::config::AuditConfig>,
+
+    /// The key with which to sign share links, or `None` to disable them.
+    pub share_key: Option<share::Key>,
+    pub exports: Arc<exports::Exports>,
+
+    /// The transcoder for `view.mp4?accept_codecs=...`, or `None` to disable transcoding.
+    pub transcoder: Option<Arc<transcode::Transcoder>>,
+
+    /// The directory in which to write online backups before serving them, or `None` to
+    /// disable `/api/backup.db`.
+    pub backup_tmp_dir: Option<PathBuf>,
+
+    /// The state of scheduled backups, if configured.
+    pub backup_status: Option<Arc<crate::backups::Status>>,
+
+    /// The state of scheduled database maintenance, if configured.
+    pub maintenance_status: Option<Arc<crate::maintenance::Status>>,
+
+    /// Where to send `/api/reload` requests, or `None` if reloading isn't supported.
+    pub reload_tx: Option<tokio::sync::mpsc::Sender<reload::Command>>,
+
+    /// Where to send imports, or `None` if importing isn't supported. Uploads are written to
+    /// `backup_tmp_dir` first.
+    pub import_tx: Option<tokio::sync::mpsc::Sender<import::Command>>,
 }
 
 pub struct Service {
     db: Arc<db::Database>,
     ui: Ui,
-    dirs_by_stream_id: Arc<FastHashMap<i32, Arc<SampleFileDir>>>,
     time_zone_name: String,
     allow_unauthenticated_permissions: Option<db::Permissions>,
     trust_forward_hdrs: bool,
     privileged_unix_uid: Option<nix::unistd::Uid>,
+    trust_remote_user: Option<RemoteUser>,
+    live_frames: Arc<crate::streamer::LiveFrames>,
+    metrics: Arc<crate::metrics::Metrics>,
+    events: Arc<crate::events::Events>,
+    webrtc: webrtc::WebRtc,
+    webauthn: webauthn::WebAuthn,
+    oidc: oidc::Oidc,
+    audit: audit::Audit,
+    share_key: Option<share::Key>,
+    hls_sequences: hls::MediaSequences,
+    exports: Arc<exports::Exports>,
+    transcoder: Option<Arc<transcode::Transcoder>>,
+    backup_tmp_dir: Option<PathBuf>,
+    backup_status: Option<Arc<crate::backups::Status>>,
+    maintenance_status: Option<Arc<crate::maintenance::Status>>,
+    reload_tx: Option<tokio::sync::mpsc::Sender<reload::Command>>,
+    import_tx: Option<tokio::sync::mpsc::Sender<import::Command>>,
+    ptz: ptz::Connections,
+}
+
+/// Trusted proxy server authentication, as in `RemoteUserConfig`.
+struct RemoteUser {
+    header: header::HeaderName,
+    proxy_addrs: Vec<IpAddr>,
+}
+
+impl RemoteUser {
+    fn new(config: &crate::cmds::run::config::RemoteUserConfig) -> Result<Self, Error> {
+        let header = header::HeaderName::from_str(&config.header).map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad remote user header {:?}", config.header),
+                source(e)
+            )
+        })?;
+        Ok(RemoteUser {
+            header,
+            proxy_addrs: config.proxy_addrs.clone(),
+        })
+    }
+
+    /// Returns the username set by the proxy server, if this request came from it.
+    fn extract<'r>(
+        &self,
+        req: &'r Request<hyper::Body>,
+        conn_data: &ConnData,
+    ) -> Result<Option<&'r str>, base::Error> {
+        let Some(addr) = conn_data.client_addr else {
+            return Ok(None);
+        };
+
+        // IPv4 clients of an IPv6 bind have IPv4-mapped addresses.
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        if !self.proxy_addrs.contains(&ip) {
+            return Ok(None);
+        }
+        let Some(v) = req.headers().get(&self.header) else {
+            return Ok(None);
+        };
+        let v = v
+            .to_str()
+            .map_err(|_| err!(Unauthenticated, msg("malformed {} header", self.header)))?;
+        Ok(Some(v))
+    }
 }
 
 /// Useful HTTP `Cache-Control` values to set on successful (HTTP 200) API responses.
@@ -196,46 +399,71 @@ enum CacheControl {
 impl Service {
     pub fn new(config: Config) -> Result<Self, Error> {
         let ui_dir = config.ui_dir.map(Ui::from).unwrap_or(Ui::None);
-        let dirs_by_stream_id = {
-            let l = config.db.lock();
-            let mut d =
-                FastHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?);
-            }
-            Arc::new(d)
-        };
-
         Ok(Service {
             db: config.db,
-            dirs_by_stream_id,
             ui: ui_dir,
             allow_unauthenticated_permissions: config.allow_unauthenticated_permissions,
             trust_forward_hdrs: config.trust_forward_hdrs,
             time_zone_name: config.time_zone_name,
             privileged_unix_uid: config.privileged_unix_uid,
+            trust_remote_user: config.trust_remote_user.map(RemoteUser::new).transpose()?,
+            live_frames: config.live_frames,
+            metrics: config.metrics,
+            events: config.events,
+            webrtc: webrtc::WebRtc::new(config.ice_servers)?,
+            webauthn: webauthn::WebAuthn::new(config.webauthn)?,
+            oidc: oidc::Oidc::new(config.oidc)?,
+            audit: audit::Audit::new(config.audit)?,
+            share_key: config.share_key,
+            hls_sequences: hls::MediaSequences::default(),
+            exports: config.exports,
+            transcoder: config.transcoder,
+            backup_tmp_dir: config.backup_tmp_dir,
+            backup_status: config.backup_status,
+            maintenance_status: config.maintenance_status,
+            reload_tx: config.reload_tx,
+            import_tx: config.import_tx,
+            ptz: ptz::Connections::default(),
         })
     }
 
+    /// Returns the open sample file directories, for reading recordings.
+    ///
+    /// This is computed per request rather than once at startup, so that a directory opened
+    /// later (when a stream starts recording into a newly added directory) is served too.
+    /// The caller must not hold the database lock.
+    fn dirs_by_id(&self) -> Arc<FastHashMap<i32, Arc<SampleFileDir>>> {
+        dirs_by_id(&self.db)
+    }
+
     /// Serves an HTTP request.
     ///
     /// The `Err` return path will cause the `serve` wrapper to log the error,
-    /// as well as returning it to the HTTP client.
+    /// as well as returning it to the HTTP client. Likewise, the wrapper records
+    /// any request placed in `pending_audit` once the response status is known.
     async fn serve_inner(
         self: Arc<Self>,
         req: Request<::hyper::Body>,
         authreq: auth::Request,
         conn_data: ConnData,
+        pending_audit: &mut Option<audit::Pending>,
     ) -> ResponseResult {
         let path = Path::decode(req.uri().path());
         tracing::trace!(?path, "path");
         let always_allow_unauthenticated = matches!(
             path,
-            Path::NotFound | Path::Request | Path::Login | Path::Logout | Path::Static
+            Path::NotFound
+                | Path::Health
+                | Path::Request
+                | Path::Login
+                | Path::LoginOidc
+                | Path::LoginOidcCallback
+                | Path::Logout
+                | Path::OpenApi
+                | Path::ShareDownload(_)
+                | Path::Static
+                | Path::WebAuthnLoginStart
+                | Path::WebAuthnLoginFinish
         );
         let caller = self.authenticate(&req, &authreq, &conn_data, always_allow_unauthenticated);
         if let Some(username) = caller
@@ -246,17 +474,54 @@ impl Service {
         {
             tracing::Span::current().record("enduser.id", tracing::field::display(username));
         }
+        if let Some(action) = audit::action(&path, req.method()) {
+            let user = caller.as_ref().ok().and_then(|c| c.user.as_ref());
+            *pending_audit = Some(audit::Pending {
+                req: authreq.clone(),
+                user: user.map(|u| (u.id, u.name.clone())),
+                action,
+                detail: format!("{} {}", req.method(), req.uri().path()),
+            });
+        }
+
+        // Per-camera grants apply to every path under `/api/cameras/<uuid>/`.
+        let caller = caller.and_then(|c| match path.camera_uuid() {
+            Some(uuid) if !c.permissions.allows_camera(uuid) => {
+                bail!(
+                    PermissionDenied,
+                    msg("not permitted to access camera {uuid}")
+                )
+            }
+            _ => Ok(c),
+        });
 
         // WebSocket stuff is handled separately, because most authentication
         // errors are returned as text messages over the protocol, rather than
         // HTTP-level errors.
         if let Path::StreamLiveMp4Segments(uuid, type_) = path {
+            let options = live::parse_live_query(req.uri().query())?;
             return websocket::upgrade(req, move |ws| {
-                Box::pin(self.stream_live_m4s(ws, caller, uuid, type_))
+                Box::pin(self.stream_live_m4s(ws, caller, uuid, type_, options))
             });
         }
+        if let Path::CameraTalk(uuid) = path {
+            return websocket::upgrade(req, move |ws| Box::pin(self.camera_talk(ws, caller, uuid)));
+        }
+        if let Path::Events = path {
+            return websocket::upgrade(req, move |ws| Box::pin(self.events(ws, caller)));
+        }
+        if let Path::LiveMp4Segments = path {
+            return websocket::upgrade(req, move |ws| Box::pin(self.live_m4s(ws, caller)));
+        }
 
-        let caller = caller?;
+        // Home Assistant's stream worker (ffmpeg) sends credentials only when challenged.
+        // Other paths don't challenge, as browsers would then prompt for a password.
+        let caller = match caller {
+            Err(e) if e.kind() == ErrorKind::Unauthenticated && path.is_home_assistant() => {
+                return Ok(ha::challenge(&e));
+            }
+            c => c?,
+        };
         let (cache, mut response) = match path {
             Path::InitSegment(sha1, debug) => (
                 CacheControl::PrivateStatic,
@@ -267,41 +532,237 @@ impl Service {
                 CacheControl::PrivateDynamic,
                 self.request(&req, &authreq, caller)?,
             ),
-            Path::Camera(uuid) => (CacheControl::PrivateDynamic, self.camera(&req, uuid)?),
+            Path::Cameras => (
+                CacheControl::PrivateDynamic,
+                self.cameras(req, caller).await?,
+            ),
+            Path::Camera(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera(req, caller, uuid).await?,
+            ),
+            Path::CameraPtz(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_ptz(req, caller, uuid).await?,
+            ),
+            Path::CameraHealth(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.camera_health(&req, caller, uuid)?,
+            ),
             Path::StreamRecordings(uuid, type_) => (
                 CacheControl::PrivateDynamic,
                 self.stream_recordings(&req, uuid, type_)?,
             ),
             Path::StreamViewMp4(uuid, type_, debug) => (
                 CacheControl::PrivateStatic,
-                self.stream_view_mp4(&req, caller, uuid, type_, mp4::Type::Normal, debug)?,
+                self.stream_view(
+                    &req,
+                    caller,
+                    uuid,
+                    type_,
+                    Container::Mp4(mp4::Type::Normal),
+                    debug,
+                )?,
             ),
             Path::StreamViewMp4Segment(uuid, type_, debug) => (
                 CacheControl::PrivateStatic,
-                self.stream_view_mp4(&req, caller, uuid, type_, mp4::Type::MediaSegment, debug)?,
+                self.stream_view(
+                    &req,
+                    caller,
+                    uuid,
+                    type_,
+                    Container::Mp4(mp4::Type::MediaSegment),
+                    debug,
+                )?,
+            ),
+            Path::StreamViewTs(uuid, type_, debug) => (
+                CacheControl::PrivateStatic,
+                self.stream_view(&req, caller, uuid, type_, Container::Ts, debug)?,
             ),
             Path::StreamLiveMp4Segments(..) => {
                 unreachable!("StreamLiveMp4Segments should have already been handled")
             }
+            Path::CameraTalk(..) => {
+                unreachable!("CameraTalk should have already been handled")
+            }
+            Path::Events => unreachable!("Events should have already been handled"),
+            Path::LiveMp4Segments => {
+                unreachable!("LiveMp4Segments should have already been handled")
+            }
+            Path::StreamHlsPlaylist(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_hls_playlist(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamHlsSegment(uuid, type_) => (
+                CacheControl::PrivateStatic,
+                self.stream_hls_segment(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamWebRtc(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_webrtc(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamSnapshot(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_snapshot(&req, caller, uuid, type_).await?,
+            ),
+            Path::StreamDetections(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_detections(&req, uuid, type_)?,
+            ),
+            Path::StreamSchedule(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_schedule(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamImport(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_import(req, caller, uuid, type_).await?,
+            ),
+            Path::StreamThumbnails(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_thumbnails(&req, caller, uuid, type_)?,
+            ),
+            Path::StreamThumbnailSprite(uuid, type_) => (
+                CacheControl::PrivateStatic,
+                self.stream_thumbnail_sprite(&req, caller, uuid, type_)
+                    .await?,
+            ),
+            Path::Exports => (
+                CacheControl::PrivateDynamic,
+                self.exports(req, caller).await?,
+            ),
+            Path::Export(id) => (
+                CacheControl::PrivateDynamic,
+                self.export(req, caller, id).await?,
+            ),
+            Path::ExportDownload(id) => (
+                CacheControl::PrivateStatic,
+                self.export_download(&req, caller, id)?,
+            ),
+            Path::Backup => (
+                CacheControl::PrivateDynamic,
+                self.backup(&req, caller).await?,
+            ),
+            Path::Dirs => (CacheControl::PrivateDynamic, self.dirs(req, caller).await?),
+            Path::Dir(id) => (
+                CacheControl::PrivateDynamic,
+                self.dir(req, caller, id).await?,
+            ),
+            Path::HaCameras => (CacheControl::PrivateDynamic, self.ha_cameras(&req, caller)?),
+            Path::HaCameraSnapshot(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.ha_camera_snapshot(&req, caller, uuid).await?,
+            ),
+            Path::HaStreamHlsPlaylist(uuid, type_) => (
+                CacheControl::PrivateDynamic,
+                self.stream_hls_playlist(&req, caller, uuid, type_)?,
+            ),
+            Path::HaStreamHlsSegment(uuid, type_) => (
+                CacheControl::PrivateStatic,
+                self.stream_hls_segment(&req, caller, uuid, type_)?,
+            ),
+            Path::HaInitSegment(id) => (
+                CacheControl::PrivateStatic,
+                self.init_segment(id, false, &req)?,
+            ),
+            Path::Health => (CacheControl::PrivateDynamic, self.health(&req, caller)?),
             Path::NotFound => return Err(err!(NotFound, msg("path not understood"))),
             Path::Login => (
                 CacheControl::PrivateDynamic,
                 self.login(req, authreq).await?,
             ),
+            Path::LoginOidc => (CacheControl::PrivateDynamic, self.login_oidc(req).await?),
+            Path::LoginOidcCallback => (
+                CacheControl::PrivateDynamic,
+                self.login_oidc_callback(req, authreq).await?,
+            ),
             Path::Logout => (
                 CacheControl::PrivateDynamic,
                 self.logout(req, authreq).await?,
             ),
+            Path::Metrics => (
+                CacheControl::PrivateDynamic,
+                self.prometheus_metrics(&req, caller)?,
+            ),
+            Path::OpenApi => (CacheControl::PrivateDynamic, self.openapi(&req)?),
+            Path::Reload => (
+                CacheControl::PrivateDynamic,
+                self.reload(req, caller).await?,
+            ),
+            Path::Audit => (
+                CacheControl::PrivateDynamic,
+                self.audit_entries(&req, caller)?,
+            ),
+            Path::Shares => (
+                CacheControl::PrivateDynamic,
+                self.shares(req, &authreq, caller).await?,
+            ),
+            Path::ShareDownload(token) => (
+                CacheControl::PrivateStatic,
+                self.share_download(&req, &authreq, &token)?,
+            ),
+            Path::RecordingsSearch => (
+                CacheControl::PrivateDynamic,
+                self.search_recordings(&req, caller)?,
+            ),
             Path::Signals => (
                 CacheControl::PrivateDynamic,
                 self.signals(req, caller).await?,
             ),
+            Path::SignalTypes => (CacheControl::PrivateDynamic, self.signal_types(&req)?),
+            Path::SignalEvents(id) => (
+                CacheControl::PrivateDynamic,
+                self.signal_events(req, caller, id).await?,
+            ),
+            Path::SignalType(uuid) => (
+                CacheControl::PrivateDynamic,
+                self.signal_type(req, caller, uuid).await?,
+            ),
             Path::Static => (CacheControl::None, self.static_file(req).await?),
+            Path::Tokens => (
+                CacheControl::PrivateDynamic,
+                self.tokens(req, &authreq, caller).await?,
+            ),
+            Path::Token(id) => (
+                CacheControl::PrivateDynamic,
+                self.token(req, caller, id).await?,
+            ),
+            Path::Usage => (CacheControl::PrivateDynamic, self.usage(&req, caller)?),
             Path::Users => (CacheControl::PrivateDynamic, self.users(req, caller).await?),
             Path::User(id) => (
                 CacheControl::PrivateDynamic,
                 self.user(req, caller, id).await?,
             ),
+            Path::UserSessions(id) => (
+                CacheControl::PrivateDynamic,
+                self.user_sessions(req, &authreq, caller, id).await?,
+            ),
+            Path::UserSession(id, hash) => (
+                CacheControl::PrivateDynamic,
+                self.user_session(req, &authreq, caller, id, hash).await?,
+            ),
+            Path::WebAuthnRegisterStart => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_register_start(req, caller).await?,
+            ),
+            Path::WebAuthnRegisterFinish => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_register_finish(req, &authreq, caller).await?,
+            ),
+            Path::WebAuthnLoginStart => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_login_start(req).await?,
+            ),
+            Path::WebAuthnLoginFinish => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_login_finish(req, authreq).await?,
+            ),
+            Path::WebAuthnCredentials => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_credentials(req, caller).await?,
+            ),
+            Path::WebAuthnCredential(id) => (
+                CacheControl::PrivateDynamic,
+                self.webauthn_credential(req, caller, id).await?,
+            ),
         };
         match cache {
             CacheControl::PrivateStatic => {
@@ -330,9 +791,10 @@ impl Service {
     /// them to hyper as `Ok` results.
     pub async fn serve(
         self: Arc<Self>,
-        req: Request<::hyper::Body>,
+        mut req: Request<::hyper::Body>,
         conn_data: ConnData,
     ) -> Result<Response<Body>, std::convert::Infallible> {
+        req.extensions_mut().insert(conn_data);
         let request_id = ulid::Ulid::new();
         let authreq = auth::Request {
             when_sec: Some(self.db.clocks().realtime().sec),
@@ -361,18 +823,35 @@ impl Service {
             http.target = %req.uri(),
             http.status_code = tracing::field::Empty,
             enduser.id = tracing::field::Empty,
+            otel.kind = "server",
+            otel.status_code = tracing::field::Empty,
         );
         tracing::debug!(parent: &span, "received request headers");
-        let response = self
-            .serve_inner(req, authreq, conn_data)
+        let method = req.method().clone();
+        let mut pending_audit = None;
+        let response = Arc::clone(&self)
+            .serve_inner(req, authreq, conn_data, &mut pending_audit)
             .instrument(span.clone())
             .await;
         let (response, error) = match response {
             Ok(r) => (r, None),
             Err(e) => (from_base_error(&e), Some(e)),
         };
+        if let Some(p) = pending_audit {
+            p.record(
+                &self.audit,
+                &self.db.lock(),
+                response.status(),
+                error.as_ref(),
+            );
+        }
         span.record("http.status_code", response.status().as_u16());
+        if response.status().is_server_error() {
+            span.record("otel.status_code", "ERROR");
+        }
         let latency = std::time::Instant::now().duration_since(start);
+        self.metrics
+            .observe_http_request(&method, response.status(), latency);
         if response.status().is_server_error() {
             tracing::error!(
                 parent: &span,
@@ -416,41 +895,36 @@ impl Service {
             bail!(PermissionDenied, msg("read_camera_configs required"));
         }
 
+        let now = recording::Time::new(self.db.clocks().realtime());
         let db = self.db.lock();
         serve_json(
             req,
             &json::TopLevel {
                 time_zone_name: &self.time_zone_name,
                 server_version: env!("CARGO_PKG_VERSION"),
-                cameras: (&db, days, camera_configs),
+                cameras: (&db, days, camera_configs, &caller.permissions, now),
                 user: caller.user,
                 signals: (&db, days),
                 signal_types: &db,
-                permissions: caller.permissions.into(),
+                permissions: caller.permissions.clone().into(),
+                last_backup_time_90k: self.backup_status.as_ref().and_then(|s| s.last_success()),
+                last_maintenance: self.maintenance_status.as_ref().and_then(|s| s.last()),
             },
         )
     }
 
-    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> ResponseResult {
-        let db = self.db.lock();
-        let camera = db
-            .get_camera(uuid)
-            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
-        serve_json(
-            req,
-            &json::Camera::wrap(camera, &db, true, false).err_kind(ErrorKind::Internal)?,
-        )
-    }
-
     fn stream_recordings(
         &self,
         req: &Request<::hyper::Body>,
         uuid: Uuid,
         type_: db::StreamType,
     ) -> ResponseResult {
-        let (r, split) = {
+        let (r, split, detected_class, limit, first_id) = {
             let mut time = recording::Time::min_value()..recording::Time::max_value();
             let mut split = recording::Duration(i64::max_value());
+            let mut detected_class = None;
+            let mut limit = None;
+            let mut first_id = 0;
             if let Some(q) = req.uri().query() {
                 for (key, value) in form_urlencoded::parse(q.as_bytes()) {
                     let (key, value) = (key.borrow(), value.borrow());
@@ -470,26 +944,53 @@ impl Service {
                                     err!(InvalidArgument, msg("unparseable split90k"))
                                 })?)
                         }
+                        "detectedClass" => detected_class = Some(value.to_owned()),
+                        "limit" => match usize::from_str(value) {
+                            Ok(l) if l > 0 => limit = Some(l),
+                            _ => bail!(InvalidArgument, msg("limit must be a positive integer")),
+                        },
+                        "cursor" => first_id = decode_recordings_cursor(value)?,
                         _ => {}
                     }
                 }
             }
-            (time, split)
-        };
-        let db = self.db.lock();
-        let mut out = json::ListRecordings {
-            recordings: Vec::new(),
-            video_sample_entries: (&db, Vec::new()),
-        };
-        let Some(camera) = db.get_camera(uuid) else {
-            bail!(NotFound, msg("no such camera {uuid}"));
+            (time, split, detected_class, limit, first_id)
         };
-        let Some(stream_id) = camera.streams[type_.index()] else {
-            bail!(NotFound, msg("no such stream {uuid}/{type_}"));
+        let (stream_id, detection_times) = {
+            let db = self.db.lock();
+            let Some(camera) = db.get_camera(uuid) else {
+                bail!(NotFound, msg("no such camera {uuid}"));
+            };
+            let Some(stream_id) = camera.streams[type_.index()] else {
+                bail!(NotFound, msg("no such stream {uuid}/{type_}"));
+            };
+
+            // When filtering by detected class, note the (ascending) times of matching detections.
+            let detection_times = match detected_class {
+                None => None,
+                Some(ref c) => {
+                    let mut times = Vec::new();
+                    db.list_detections(stream_id, r.clone(), Some(c), &mut |d| {
+                        times.push(d.time);
+                        Ok(())
+                    })?;
+                    Some(times)
+                }
+            };
+            (stream_id, detection_times)
         };
-        db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
+
+        // Aggregate without holding the database lock, which is only taken per page of rows.
+        let mut recordings = Vec::new();
+        let mut push = |row: &db::ListAggregatedRecordingsRow| -> Result<(), base::Error> {
+            if let Some(ref times) = detection_times {
+                let i = times.partition_point(|&t| t < row.time.start);
+                if times.get(i).map_or(true, |&t| t >= row.time.end) {
+                    return Ok(());
+                }
+            }
             let end = row.ids.end - 1; // in api, ids are inclusive.
-            out.recordings.push(json::Recording {
+            recordings.push(json::Recording {
                 start_id: row.ids.start,
                 end_id: if end == row.ids.start {
                     None
@@ -507,28 +1008,73 @@ impl Service {
                 growing: row.growing,
                 has_trailing_zero: row.has_trailing_zero,
             });
+            Ok(())
+        };
+        let mut aggregator = db::RecordingAggregator::new(stream_id, split, first_id);
+        for row in self.db.recordings_by_time(stream_id, r) {
+            let row = row.err_kind(ErrorKind::Internal)?;
+            aggregator
+                .push(row, &mut push)
+                .err_kind(ErrorKind::Internal)?;
+        }
+        aggregator.finish(&mut push).err_kind(ErrorKind::Internal)?;
+
+        let db = self.db.lock();
+        let mut out = json::ListRecordings {
+            recordings,
+            video_sample_entries: (&db, Vec::new()),
+            next: None,
+        };
+
+        // Pages are in ascending order of id, so each page resumes after the last one's ids.
+        if let Some(limit) = limit {
+            out.recordings.sort_unstable_by_key(|r| r.start_id);
+            if out.recordings.len() > limit {
+                out.recordings.truncate(limit);
+                let last = &out.recordings[limit - 1];
+                let next_id = last.end_id.unwrap_or(last.start_id) + 1;
+                out.next = Some(next_page_url(req, &encode_recordings_cursor(next_id)));
+            }
+        }
+        for r in &out.recordings {
             if !out
                 .video_sample_entries
                 .1
-                .contains(&row.video_sample_entry_id)
+                .contains(&r.video_sample_entry_id)
             {
-                out.video_sample_entries.1.push(row.video_sample_entry_id);
+                out.video_sample_entries.1.push(r.video_sample_entry_id);
             }
-            Ok(())
-        })
-        .err_kind(ErrorKind::Internal)?;
+        }
         serve_json(req, &out)
     }
 
     fn init_segment(&self, id: i32, debug: bool, req: &Request<::hyper::Body>) -> ResponseResult {
+        let mut audio_sample_entry_id = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                if key == "audioSampleEntryId" {
+                    audio_sample_entry_id = Some(i32::from_str(&value).map_err(|_| {
+                        err!(InvalidArgument, msg("bad audioSampleEntryId {value:?}"))
+                    })?);
+                }
+            }
+        }
         let mut builder = mp4::FileBuilder::new(mp4::Type::InitSegment);
-        let db = self.db.lock();
-        let Some(ent) = db.video_sample_entries_by_id().get(&id) else {
-            bail!(NotFound, msg("no such init segment"));
-        };
-        builder.append_video_sample_entry(ent.clone());
+        {
+            let db = self.db.lock();
+            let Some(ent) = db.video_sample_entries_by_id().get(&id) else {
+                bail!(NotFound, msg("no such init segment"));
+            };
+            builder.append_video_sample_entry(ent.clone());
+            if let Some(a) = audio_sample_entry_id {
+                let Some(ent) = db.audio_sample_entries_by_id().get(&a) else {
+                    bail!(NotFound, msg("no such audio sample entry"));
+                };
+                builder.set_audio_sample_entry(ent.clone())?;
+            }
+        }
         let mp4 = builder
-            .build(self.db.clone(), self.dirs_by_stream_id.clone())
+            .build(self.db.clone(), self.dirs_by_id())
             .err_kind(ErrorKind::Internal)?;
         if debug {
             Ok(plain_response(StatusCode::OK, format!("{mp4:#?}")))
@@ -576,11 +1122,13 @@ impl Service {
         ))
     }
 
-    /// Returns true iff the client is connected over `https`.
-    /// Moonfire NVR currently doesn't directly serve `https`, but it supports
-    /// proxies which set the `X-Forwarded-Proto` header. See `guide/secure.md`
-    /// for more information.
+    /// Returns true iff the client is connected over `https`, either directly (with
+    /// `--tls-cert`) or via a proxy which sets the `X-Forwarded-Proto` header. See
+    /// `guide/secure.md` for more information.
     fn is_secure(&self, req: &Request<::hyper::Body>) -> bool {
+        if req.extensions().get::<ConnData>().is_some_and(|c| c.tls) {
+            return true;
+        }
         self.trust_forward_hdrs
             && req
                 .headers()
@@ -589,9 +1137,11 @@ impl Service {
                 .unwrap_or(false)
     }
 
-    /// Authenticates the session (if any) and returns a Caller.
+    /// Authenticates the API token, proxy-supplied user, or session (if any) and returns a
+    /// Caller.
     ///
-    /// If there's no session,
+    /// An API token or proxy-supplied user which fails to authenticate is an error, rather than
+    /// falling back to the steps below as a session does. If there's none of these,
     /// 1.  if connected via Unix domain socket from the same effective uid
     ///     as Moonfire NVR itself, return with all privileges.
     /// 2.  if `allow_unauthenticated_permissions` is configured, returns okay
@@ -610,6 +1160,56 @@ impl Service {
         conn_data: &ConnData,
         unauth_path: bool,
     ) -> Result<Caller, base::Error> {
+        if let Some(token) = extract_api_token(req)? {
+            return match self
+                .db
+                .lock()
+                .authenticate_api_token(authreq.clone(), &token)
+            {
+                Ok((t, u)) => Ok(Caller {
+                    permissions: t.permissions.clone(),
+                    user: Some(json::ToplevelUser {
+                        id: t.user_id,
+                        name: u.username.clone(),
+                        preferences: u.config.preferences.clone(),
+                        session: None,
+                    }),
+                }),
+                Err(err) if err.kind() == base::ErrorKind::Unauthenticated => {
+                    // As with sessions, log the specific reason but don't tell the client.
+                    warn!(err = %err.chain(), "API token authentication failed");
+                    Err(err!(Unauthenticated, msg("invalid API token")))
+                }
+                Err(err) => Err(err),
+            };
+        }
+
+        if let Some(username) = self
+            .trust_remote_user
+            .as_ref()
+            .map(|r| r.extract(req, conn_data))
+            .transpose()?
+            .flatten()
+        {
+            let l = self.db.lock();
+            let u = match l.get_user(username) {
+                Some(u) if !u.config.disabled => u,
+                _ => {
+                    warn!(username, "proxy-supplied user is unknown or disabled");
+                    bail!(Unauthenticated, msg("invalid proxy-supplied user"));
+                }
+            };
+            return Ok(Caller {
+                permissions: u.permissions.clone(),
+                user: Some(json::ToplevelUser {
+                    id: u.id,
+                    name: u.username.clone(),
+                    preferences: u.config.preferences.clone(),
+                    session: None,
+                }),
+            });
+        }
+
         if let Some(sid) = extract_sid(req) {
             match self
                 .db
@@ -644,6 +1244,9 @@ impl Service {
                     read_camera_configs: true,
                     update_signals: true,
                     admin_users: true,
+                    ptz: true,
+                    update_camera_configs: true,
+                    talk: true,
                     ..Default::default()
                 },
                 user: None,
@@ -678,6 +1281,7 @@ mod tests {
     pub(super) struct Server {
         pub(super) db: TestDb<base::clock::RealClocks>,
         pub(super) base_url: String,
+        pub(super) metrics: Arc<crate::metrics::Metrics>,
         //test_camera_uuid: Uuid,
         handle: Option<::std::thread::JoinHandle<()>>,
         shutdown_tx: Option<futures::channel::oneshot::Sender<()>>,
@@ -686,6 +1290,7 @@ mod tests {
     impl Server {
         pub(super) fn new(allow_unauthenticated_permissions: Option<db::Permissions>) -> Server {
             let db = TestDb::new(base::clock::RealClocks {});
+            let metrics = Arc::new(crate::metrics::Metrics::default());
             let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
             let service = Arc::new(
                 super::Service::new(super::Config {
@@ -695,6 +1300,22 @@ mod tests {
                     trust_forward_hdrs: true,
                     time_zone_name: "".to_owned(),
                     privileged_unix_uid: None,
+                    trust_remote_user: None,
+                    live_frames: Default::default(),
+                    metrics: metrics.clone(),
+                    events: Default::default(),
+                    ice_servers: &[],
+                    webauthn: None,
+                    oidc: None,
+                    audit: Some(&crate::cmds::run::config::AuditConfig::default()),
+                    share_key: Some(super::share::Key::new(&[0u8; 32]).unwrap()),
+                    exports: Default::default(),
+                    transcoder: None,
+                    backup_tmp_dir: None,
+                    backup_status: None,
+                    maintenance_status: None,
+                    reload_tx: None,
+                    import_tx: None,
                 })
                 .unwrap(),
             );
@@ -707,6 +1328,7 @@ mod tests {
                             super::accept::ConnData {
                                 client_unix_uid: None,
                                 client_addr: None,
+                                tls: false,
                             },
                         )
                     }
@@ -737,6 +1359,7 @@ mod tests {
             Server {
                 db,
                 base_url: format!("http://{}:{}", addr.ip(), addr.port()),
+                metrics,
                 handle: Some(handle),
                 shutdown_tx: Some(shutdown_tx),
             }
@@ -763,6 +1386,116 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
     }
 
+    #[tokio::test]
+    async fn camera_restriction() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        permissions
+            .cameras
+            .push(uuid::Uuid::from_u128(1).as_bytes().to_vec());
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["cameras"].as_array().unwrap().len(), 0);
+        let resp = cli
+            .get(&format!(
+                "{}/api/cameras/{}/main/recordings",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn top_level_flush_lag() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        let resp = reqwest::get(&format!("{}/api/", &s.base_url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let main = &resp["cameras"][0]["streams"]["main"];
+        assert_eq!(main["flushIfSec"], 0);
+        assert_eq!(main["flushLag90k"], 0);
+    }
+
+    #[test]
+    fn remote_user() {
+        testutil::init();
+        let db = TestDb::new(base::clock::RealClocks {});
+        let mut c = db::UserChange::add_user("slamb".to_owned());
+        c.permissions.view_video = true;
+        db.db.lock().apply_user_change(c).unwrap();
+        let config = crate::cmds::run::config::RemoteUserConfig {
+            proxy_addrs: vec!["127.0.0.1".parse().unwrap()],
+            header: "X-Remote-User".to_owned(),
+        };
+        let service = super::Service::new(super::Config {
+            db: db.db.clone(),
+            ui_dir: None,
+            allow_unauthenticated_permissions: None,
+            trust_forward_hdrs: false,
+            time_zone_name: "".to_owned(),
+            privileged_unix_uid: None,
+            trust_remote_user: Some(&config),
+            live_frames: Default::default(),
+            metrics: Default::default(),
+            events: Default::default(),
+            ice_servers: &[],
+            webauthn: None,
+            oidc: None,
+            audit: None,
+            share_key: None,
+            exports: Default::default(),
+            transcoder: None,
+            backup_tmp_dir: None,
+            backup_status: None,
+            maintenance_status: None,
+            reload_tx: None,
+            import_tx: None,
+        })
+        .unwrap();
+        let authenticate = |user: &str, addr: &str| {
+            let req = Request::builder()
+                .header("X-Remote-User", user)
+                .body(hyper::Body::empty())
+                .unwrap();
+            let conn_data = super::accept::ConnData {
+                client_unix_uid: None,
+                client_addr: Some(addr.parse().unwrap()),
+                tls: false,
+            };
+            service.authenticate(&req, &db::auth::Request::default(), &conn_data, false)
+        };
+
+        let caller = authenticate("slamb", "127.0.0.1:1234").unwrap();
+        assert_eq!(caller.user.unwrap().name, "slamb");
+        assert!(caller.permissions.view_video);
+        authenticate("slamb", "[::ffff:127.0.0.1]:1234").unwrap();
+        assert!(matches!(
+            authenticate("nobody", "127.0.0.1:1234"),
+            Err(e) if e.kind() == base::ErrorKind::Unauthenticated
+        ));
+
+        // The header is ignored from other addresses.
+        assert!(matches!(
+            authenticate("slamb", "192.168.1.2:1234"),
+            Err(e) if e.kind() == base::ErrorKind::Unauthenticated
+        ));
+    }
+
     #[test]
     fn test_extract_sid() {
         let req = Request::builder()
@@ -776,6 +1509,16 @@ mod tests {
         let sid = super::extract_sid(&req).unwrap();
         assert_eq!(sid.as_ref(), &b":\xc2\xfa\n\x0e\"\x90\xbc:P\x85\xceOo-#\xeb\xcf{=\xeaX\x00\xa8\xbc\x8f\xa7,u\xb2\x8e\xc5\xb5\x11\x15\xfc\xde\xa4k9\x1d\xe0\xb8\xa7\x9ds\xc2\x0f"[..]);
     }
+
+    #[test]
+    fn recordings_cursor() {
+        for id in [0, 1, 12345, i32::MAX] {
+            let cursor = super::encode_recordings_cursor(id);
+            assert_eq!(super::decode_recordings_cursor(&cursor).unwrap(), id);
+        }
+        super::decode_recordings_cursor("").unwrap_err();
+        super::decode_recordings_cursor("AAAAAAAA").unwrap_err();
+    }
 }
 
 #[cfg(all(test, feature = "nightly"))]
@@ -805,6 +1548,22 @@ mod bench {
                     trust_forward_hdrs: false,
                     time_zone_name: "".to_owned(),
                     privileged_unix_uid: None,
+                    trust_remote_user: None,
+                    live_frames: Default::default(),
+                    metrics: Default::default(),
+                    events: Default::default(),
+                    ice_servers: &[],
+                    webauthn: None,
+                    oidc: None,
+                    audit: None,
+                    share_key: None,
+                    exports: Default::default(),
+                    transcoder: None,
+                    backup_tmp_dir: None,
+                    backup_status: None,
+                    maintenance_status: None,
+                    reload_tx: None,
+                    import_tx: None,
                 })
                 .unwrap(),
             );
@@ -817,6 +1576,7 @@ mod bench {
                             super::accept::ConnData {
                                 client_unix_uid: None,
                                 client_addr: None,
+                                tls: false,
                             },
                         )
                     }
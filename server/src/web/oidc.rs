@@ -0,0 +1,613 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! OpenID Connect single sign-on: `/api/login/oidc` and `/api/login/oidc/callback`.
+//!
+//! Moonfire NVR acts as a confidential client using the authorization code flow with PKCE. The
+//! ID token comes directly from the identity provider's token endpoint over TLS, so as permitted
+//! by [OpenID Connect Core 1.0 section
+//! 3.1.3.7](https://openid.net/specs/openid-connect-core-1_0.html#IDTokenValidation), TLS
+//! authenticates its issuer in place of checking its signature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base::{bail, err, Error, ErrorKind, FastHashMap, ResultExt as _};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use db::auth;
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use ring::rand::{SecureRandom as _, SystemRandom};
+use serde::Deserialize;
+use tracing::info;
+use url::Url;
+
+use crate::body::Body;
+use crate::cmds::run::config::OidcConfig;
+
+use super::session::session_cookie;
+use super::{plain_response, ResponseResult, Service};
+
+/// How long the user has to log in at the identity provider.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The maximum number of logins in progress at once, as `/api/login/oidc` requires no
+/// authentication.
+const MAX_PENDING: usize = 1024;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The relevant subset of the identity provider's `/.well-known/openid-configuration`.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A login in progress, keyed by its `state` parameter.
+struct Pending {
+    start: Instant,
+    nonce: String,
+    code_verifier: String,
+}
+
+struct Provider {
+    issuer_url: Url,
+    client_id: String,
+    client_secret: String,
+    redirect_url: Url,
+    scopes: String,
+    username_claim: String,
+    auto_provision: bool,
+    permissions: Option<db::Permissions>,
+    groups_claim: String,
+    group_permissions: BTreeMap<String, db::Permissions>,
+    http: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+
+    /// The discovery document, fetched on first use.
+    discovery: tokio::sync::OnceCell<Discovery>,
+}
+
+/// Shared OpenID Connect state for a [`Service`].
+pub(super) struct Oidc {
+    /// The identity provider, or `None` if single sign-on is disabled.
+    provider: Option<Provider>,
+
+    pending: Mutex<FastHashMap<String, Pending>>,
+    rand: SystemRandom,
+}
+
+impl Oidc {
+    pub(super) fn new(config: Option<&OidcConfig>) -> Result<Self, Error> {
+        let provider = match config {
+            None => None,
+            Some(c) => {
+                let issuer_url = parse_url("issuerUrl", &c.issuer_url)?;
+                let redirect_url = parse_url("redirectUrl", &c.redirect_url)?;
+                if !c.scopes.iter().any(|s| s == "openid") {
+                    bail!(InvalidArgument, msg("oidc scopes must include openid"));
+                }
+                let connector = hyper_rustls::HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build();
+                Some(Provider {
+                    issuer_url,
+                    client_id: c.client_id.clone(),
+                    client_secret: c.client_secret.clone(),
+                    redirect_url,
+                    scopes: c.scopes.join(" "),
+                    username_claim: c.username_claim.clone(),
+                    auto_provision: c.auto_provision,
+                    permissions: c.permissions.clone().map(db::Permissions::from),
+                    groups_claim: c.groups_claim.clone(),
+                    group_permissions: c
+                        .group_permissions
+                        .iter()
+                        .map(|(g, p)| (g.clone(), db::Permissions::from(p.clone())))
+                        .collect(),
+                    http: hyper::Client::builder().build(connector),
+                    discovery: tokio::sync::OnceCell::new(),
+                })
+            }
+        };
+        Ok(Oidc {
+            provider,
+            pending: Mutex::new(FastHashMap::default()),
+            rand: SystemRandom::new(),
+        })
+    }
+
+    fn get(&self) -> Result<&Provider, Error> {
+        self.provider.as_ref().ok_or_else(|| {
+            err!(
+                FailedPrecondition,
+                msg("single sign-on is disabled; set oidc in the config file")
+            )
+        })
+    }
+
+    /// Returns a random URL-safe string.
+    fn random_string(&self) -> String {
+        let mut buf = [0u8; 32];
+        self.rand.fill(&mut buf).unwrap();
+        URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    /// Records a login in progress, returning its state.
+    fn insert(&self, pending: Pending) -> Result<String, Error> {
+        let mut l = self.pending.lock().unwrap();
+        l.retain(|_, p| p.start.elapsed() < PENDING_TIMEOUT);
+        if l.len() >= MAX_PENDING {
+            bail!(
+                ResourceExhausted,
+                msg("too many single sign-on logins in progress")
+            );
+        }
+        let state = self.random_string();
+        l.insert(state.clone(), pending);
+        Ok(state)
+    }
+
+    /// Removes and returns the given login in progress, which can be completed only once.
+    fn take(&self, state: &str) -> Result<Pending, Error> {
+        match self.pending.lock().unwrap().remove(state) {
+            Some(p) if p.start.elapsed() < PENDING_TIMEOUT => Ok(p),
+            _ => bail!(
+                Unauthenticated,
+                msg("no such single sign-on login; it may have expired")
+            ),
+        }
+    }
+}
+
+fn parse_url(name: &str, url: &str) -> Result<Url, Error> {
+    let url = Url::parse(url)
+        .map_err(|e| err!(InvalidArgument, msg("bad oidc {name} {url:?}"), source(e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!(
+            InvalidArgument,
+            msg("oidc {name} {url} must be http or https")
+        );
+    }
+    Ok(url)
+}
+
+impl Provider {
+    async fn discovery(&self) -> Result<&Discovery, Error> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let mut url = self.issuer_url.clone();
+                url.path_segments_mut()
+                    .map_err(|()| err!(InvalidArgument, msg("bad oidc issuerUrl")))?
+                    .pop_if_empty()
+                    .extend([".well-known", "openid-configuration"]);
+                let req = hyper::Request::get(url.as_str())
+                    .header(header::ACCEPT, "application/json")
+                    .body(hyper::Body::empty())
+                    .map_err(|e| err!(Internal, source(e)))?;
+                let body = self.call(req).await?;
+                let d: Discovery = serde_json::from_slice(&body).map_err(|e| {
+                    err!(
+                        Unavailable,
+                        msg("bad oidc discovery document from {url}"),
+                        source(e)
+                    )
+                })?;
+                Ok(d)
+            })
+            .await
+    }
+
+    /// Sends a request, returning the body of a successful response.
+    async fn call(&self, req: hyper::Request<hyper::Body>) -> Result<hyper::body::Bytes, Error> {
+        let url = req.uri().clone();
+        let (status, body) = tokio::time::timeout(HTTP_TIMEOUT, async {
+            let resp = self.http.request(req).await?;
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+            Ok::<_, hyper::Error>((status, body))
+        })
+        .await
+        .map_err(|_| err!(DeadlineExceeded, msg("timed out calling {url}")))?
+        .map_err(|e| err!(Unavailable, msg("unable to call {url}"), source(e)))?;
+        if !status.is_success() {
+            bail!(
+                Unavailable,
+                msg(
+                    "{url} returned {status}: {}",
+                    String::from_utf8_lossy(&body)
+                )
+            );
+        }
+        Ok(body)
+    }
+
+    /// Exchanges an authorization code for an ID token, returning its validated claims.
+    async fn exchange(
+        &self,
+        code: &str,
+        pending: &Pending,
+        now_sec: i64,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+        let discovery = self.discovery().await?;
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", self.redirect_url.as_str())
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret)
+            .append_pair("code_verifier", &pending.code_verifier)
+            .finish();
+        let req = hyper::Request::post(discovery.token_endpoint.as_str())
+            .header(header::ACCEPT, "application/json")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(hyper::Body::from(body))
+            .map_err(|e| err!(Internal, source(e)))?;
+        let resp = self.call(req).await?;
+        let resp: TokenResponse = serde_json::from_slice(&resp)
+            .map_err(|e| err!(Unavailable, msg("bad oidc token response"), source(e)))?;
+        validate_id_token(
+            &resp.id_token,
+            &discovery.issuer,
+            &self.client_id,
+            &pending.nonce,
+            now_sec,
+        )
+    }
+
+    /// Returns the permissions for a session with the given claims, or `None` if sessions
+    /// should get the user's stored permissions.
+    fn claim_permissions(
+        &self,
+        claims: &serde_json::Map<String, serde_json::Value>,
+    ) -> Option<db::Permissions> {
+        if self.permissions.is_none() && self.group_permissions.is_empty() {
+            return None;
+        }
+        let mut permissions = self.permissions.clone().unwrap_or_default();
+        let groups = match claims.get(&self.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => groups.iter().collect(),
+            Some(g) => vec![g],
+            None => Vec::new(),
+        };
+        for g in groups.into_iter().filter_map(serde_json::Value::as_str) {
+            if let Some(p) = self.group_permissions.get(g) {
+                permissions.union_with(p);
+            }
+        }
+        Some(permissions)
+    }
+}
+
+/// Decodes the claims of an ID token received directly from the token endpoint and validates
+/// them as in OpenID Connect Core 1.0 section 3.1.3.7.
+fn validate_id_token(
+    id_token: &str,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now_sec: i64,
+) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+    let mut parts = id_token.split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!(Unauthenticated, msg("malformed ID token"));
+    };
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|_| err!(Unauthenticated, msg("malformed ID token payload")))?;
+    let claims: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&payload)
+        .map_err(|e| err!(Unauthenticated, msg("malformed ID token claims"), source(e)))?;
+    if claims.get("iss").and_then(|v| v.as_str()) != Some(issuer) {
+        bail!(Unauthenticated, msg("ID token has wrong issuer"));
+    }
+    let aud_ok = match claims.get("aud") {
+        Some(serde_json::Value::String(a)) => a == client_id,
+        Some(serde_json::Value::Array(a)) => a.iter().any(|a| a.as_str() == Some(client_id)),
+        _ => false,
+    };
+    if !aud_ok {
+        bail!(Unauthenticated, msg("ID token has wrong audience"));
+    }
+    match claims.get("exp").and_then(|v| v.as_i64()) {
+        Some(exp) if exp > now_sec => {}
+        _ => bail!(Unauthenticated, msg("ID token has expired")),
+    }
+    if claims.get("nonce").and_then(|v| v.as_str()) != Some(nonce) {
+        bail!(Unauthenticated, msg("ID token has wrong nonce"));
+    }
+    Ok(claims)
+}
+
+/// Extracts the `oidc_state` cookie, which binds a login in progress to the browser which
+/// started it.
+fn extract_state_cookie(req: &Request<hyper::Body>) -> Option<&str> {
+    for hdr in req.headers().get_all(header::COOKIE) {
+        let Ok(hdr) = hdr.to_str() else {
+            continue;
+        };
+        for cookie in hdr.split(';') {
+            if let Some(v) = cookie.trim_start().strip_prefix("oidc_state=") {
+                return Some(v);
+            }
+        }
+    }
+    None
+}
+
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(
+            header::LOCATION,
+            HeaderValue::try_from(location).expect("location can't have invalid bytes"),
+        )
+        .body(b""[..].into())
+        .unwrap()
+}
+
+impl Service {
+    /// Starts a single sign-on login by redirecting to the identity provider.
+    pub(super) async fn login_oidc(&self, req: Request<hyper::Body>) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        let provider = self.oidc.get()?;
+        let discovery = provider.discovery().await?;
+        let nonce = self.oidc.random_string();
+        let code_verifier = self.oidc.random_string();
+        let code_challenge = URL_SAFE_NO_PAD.encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            code_verifier.as_bytes(),
+        ));
+        let mut url = Url::parse(&discovery.authorization_endpoint).map_err(|e| {
+            err!(
+                Unavailable,
+                msg("bad oidc authorization endpoint"),
+                source(e)
+            )
+        })?;
+        let state = self.oidc.insert(Pending {
+            start: Instant::now(),
+            nonce: nonce.clone(),
+            code_verifier,
+        })?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", provider.redirect_url.as_str())
+            .append_pair("scope", &provider.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        let mut cookie = format!("oidc_state={state}; HttpOnly; SameSite=Lax");
+        if self.is_secure(&req) {
+            cookie.push_str("; Secure");
+        }
+        write!(
+            &mut cookie,
+            "; Max-Age={}; Path=/api/login/oidc",
+            PENDING_TIMEOUT.as_secs()
+        )
+        .unwrap();
+        let mut resp = redirect(url.as_str());
+        resp.headers_mut().insert(
+            header::SET_COOKIE,
+            HeaderValue::try_from(cookie).expect("cookie can't have invalid bytes"),
+        );
+        Ok(resp)
+    }
+
+    /// Completes a single sign-on login, creating a session and redirecting to the UI.
+    pub(super) async fn login_oidc_callback(
+        &self,
+        req: Request<hyper::Body>,
+        authreq: auth::Request,
+    ) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        let provider = self.oidc.get()?;
+        let mut code = None;
+        let mut state = None;
+        let mut error = None;
+        for (key, value) in url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        {
+            match &*key {
+                "code" => code = Some(value),
+                "state" => state = Some(value),
+                "error" => error = Some(value),
+                _ => {}
+            }
+        }
+        if let Some(error) = error {
+            bail!(
+                Unauthenticated,
+                msg("identity provider returned error {error:?}")
+            );
+        }
+        let (Some(code), Some(state)) = (code, state) else {
+            bail!(InvalidArgument, msg("code and state must be specified"));
+        };
+
+        // Check the state is bound to this browser, so an attacker can't log a victim into the
+        // attacker's account.
+        if extract_state_cookie(&req) != Some(&*state) {
+            bail!(
+                Unauthenticated,
+                msg("single sign-on login was started by another browser")
+            );
+        }
+        let pending = self.oidc.take(&state)?;
+        let now = authreq
+            .when_sec
+            .ok_or_else(|| err!(Internal, msg("request has no time")))?;
+        let (domain, flags) = self.session_params(&req)?;
+        let claims = match provider.exchange(&code, &pending, now).await {
+            Ok(c) => c,
+            Err(e) => {
+                self.audit_login(
+                    &self.db.lock(),
+                    &authreq,
+                    (None, None),
+                    "single sign-on",
+                    Err(&e),
+                );
+                return Err(e.into());
+            }
+        };
+        let username = claims
+            .get(&provider.username_claim)
+            .and_then(|v| v.as_str());
+        let mut l = self.db.lock();
+        let result = (|| -> Result<_, Error> {
+            let Some(username) = username else {
+                bail!(
+                    Unauthenticated,
+                    msg("ID token has no {:?} claim", provider.username_claim)
+                );
+            };
+            let claim_permissions = provider.claim_permissions(&claims);
+            let (uid, permissions) = match l.get_user(username) {
+                Some(u) => (u.id, u.permissions.clone()),
+                None if provider.auto_provision => {
+                    info!("provisioning user {username:?} via single sign-on");
+                    let mut change = db::UserChange::add_user(username.to_owned());
+                    change.permissions = claim_permissions.clone().unwrap_or_default();
+                    let u = l.apply_user_change(change)?;
+                    (u.id, u.permissions.clone())
+                }
+                None => bail!(Unauthenticated, msg("no such user {username:?}")),
+            };
+            let permissions = claim_permissions.unwrap_or(permissions);
+            let (sid, _) = l
+                .make_session(authreq.clone(), uid, Some(domain), flags, permissions)
+                .err_kind(ErrorKind::Unauthenticated)?;
+            Ok((uid, sid))
+        })();
+        let user_id = match &result {
+            Ok((uid, _)) => Some(*uid),
+            Err(_) => username.and_then(|n| l.get_user(n)).map(|u| u.id),
+        };
+        self.audit_login(
+            &l,
+            &authreq,
+            (user_id, username),
+            "single sign-on",
+            result.as_ref().map(|_| ()),
+        );
+        let (_, sid) = result?;
+        let mut resp = redirect("/");
+        resp.headers_mut()
+            .append(header::SET_COOKIE, session_cookie(sid, flags));
+        resp.headers_mut().append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("oidc_state=; Max-Age=0; Path=/api/login/oidc"),
+        );
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::ErrorKind;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use db::testutil;
+
+    use crate::web::tests::Server;
+
+    fn id_token(claims: serde_json::Value) -> String {
+        format!(
+            "e30.{}.c2ln",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap())
+        )
+    }
+
+    #[test]
+    fn validate_id_token() {
+        let claims = serde_json::json!({
+            "iss": "https://auth.example.com",
+            "aud": ["moonfire-nvr", "other"],
+            "exp": 1000,
+            "nonce": "n",
+            "preferred_username": "slamb",
+        });
+        let validate = |token: &str, now| {
+            super::validate_id_token(token, "https://auth.example.com", "moonfire-nvr", "n", now)
+        };
+        let claims = validate(&id_token(claims), 999).unwrap();
+        assert_eq!(claims["preferred_username"], "slamb");
+
+        let expired = serde_json::json!({
+            "iss": "https://auth.example.com", "aud": "moonfire-nvr", "exp": 1000, "nonce": "n",
+        });
+        let e = validate(&id_token(expired), 1000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+
+        let wrong_aud = serde_json::json!({
+            "iss": "https://auth.example.com", "aud": "other", "exp": 1000, "nonce": "n",
+        });
+        validate(&id_token(wrong_aud), 999).unwrap_err();
+
+        let wrong_nonce = serde_json::json!({
+            "iss": "https://auth.example.com", "aud": "moonfire-nvr", "exp": 1000, "nonce": "x",
+        });
+        validate(&id_token(wrong_nonce), 999).unwrap_err();
+        validate("asdf", 999).unwrap_err();
+    }
+
+    #[test]
+    fn claim_permissions() {
+        let config: crate::cmds::run::config::OidcConfig = toml::from_str(
+            r#"
+            issuerUrl = "https://auth.example.com"
+            clientId = "moonfire-nvr"
+            clientSecret = "secret"
+            redirectUrl = "https://nvr.example.com/api/login/oidc/callback"
+            permissions = { viewVideo = true }
+            groupPermissions = { admins = { adminUsers = true } }
+            "#,
+        )
+        .unwrap();
+        let oidc = super::Oidc::new(Some(&config)).unwrap();
+        let provider = oidc.get().unwrap();
+        let claims = serde_json::json!({ "groups": ["users", "admins"] });
+        let p = provider
+            .claim_permissions(claims.as_object().unwrap())
+            .unwrap();
+        assert!(p.view_video && p.admin_users);
+        let claims = serde_json::json!({ "groups": "users" });
+        let p = provider
+            .claim_permissions(claims.as_object().unwrap())
+            .unwrap();
+        assert!(p.view_video && !p.admin_users);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        testutil::init();
+        let s = Server::new(None);
+        let resp = reqwest::get(&format!("{}/api/login/oidc", &s.base_url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PRECONDITION_FAILED);
+    }
+}
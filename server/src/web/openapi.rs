@@ -0,0 +1,339 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! An OpenAPI 3 description of the JSON API: `/api/openapi.json`.
+//!
+//! Request and response schemas are derived from the serde types in [`crate::json`]. Responses
+//! serialized directly from database state, such as `GET /api/`, are described only as objects;
+//! `ref/api.md` remains the authoritative reference for those.
+
+use std::collections::BTreeMap;
+
+use base::bail;
+use http::{Method, Request};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use serde_json::{json, Map, Value};
+
+use super::{serve_json, ResponseResult, Service};
+use crate::json;
+
+/// The body of a request or response.
+enum Body {
+    None,
+
+    /// A JSON object whose schema isn't derived; see `ref/api.md`.
+    Object,
+
+    /// A JSON value described by the given (derived) schema.
+    Json(fn(&mut SchemaGenerator) -> Schema),
+
+    /// A binary body of the given content type.
+    Binary(&'static str),
+}
+
+struct Operation {
+    /// The lowercase HTTP method.
+    method: &'static str,
+
+    /// The path template, with parameters in braces.
+    path: &'static str,
+    summary: &'static str,
+    request: Body,
+    status: u16,
+    response: Body,
+}
+
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: "get",
+        path: "/api/",
+        summary: "Gets the server's top-level state: cameras, streams, signals, and user.",
+        request: Body::None,
+        status: 200,
+        response: Body::Object,
+    },
+    Operation {
+        method: "post",
+        path: "/api/login",
+        summary: "Logs in, setting a session cookie.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::LoginRequest<'static>>),
+        status: 204,
+        response: Body::None,
+    },
+    Operation {
+        method: "post",
+        path: "/api/logout",
+        summary: "Logs out, revoking the current session.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::LogoutRequest<'static>>),
+        status: 204,
+        response: Body::None,
+    },
+    Operation {
+        method: "get",
+        path: "/api/cameras/{cameraUuid}/{stream}/recordings",
+        summary: "Lists a stream's recordings, optionally aggregated and paginated.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::ListRecordings<'static>>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/cameras/{cameraUuid}/{stream}/thumbnails",
+        summary: "Lists the thumbnail sprite sheets covering a time range.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::ListThumbnails>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/cameras/{cameraUuid}/{stream}/detections",
+        summary: "Lists objects detected within a time range.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::ListDetections>),
+    },
+    Operation {
+        method: "post",
+        path: "/api/cameras/{cameraUuid}/{stream}/import",
+        summary: "Imports a `.mp4` file as recordings.",
+        request: Body::Binary("video/mp4"),
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::ImportResponse>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/cameras/{cameraUuid}/ptz",
+        summary: "Lists a camera's pan-tilt-zoom presets.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::PtzPresets>),
+    },
+    Operation {
+        method: "post",
+        path: "/api/cameras/{cameraUuid}/ptz",
+        summary: "Moves a camera, stops it, or sends it to a preset.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PostPtzRequest<'static>>),
+        status: 204,
+        response: Body::None,
+    },
+    Operation {
+        method: "get",
+        path: "/api/recordings/search",
+        summary: "Searches recordings across cameras and streams.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::SearchRecordings<'static>>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/signals",
+        summary: "Lists signal changes within a time range.",
+        request: Body::None,
+        status: 200,
+        response: Body::Object,
+    },
+    Operation {
+        method: "post",
+        path: "/api/signals",
+        summary: "Sets signal states over a time range.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PostSignalsRequest<'static>>),
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::PostSignalsResponse>),
+    },
+    Operation {
+        method: "post",
+        path: "/api/signals/{signalId}/events",
+        summary: "Records a signal's state changes as reported by a client.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PostSignalEvents<'static>>),
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::PostSignalsResponse>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/signals/types",
+        summary: "Lists signal types and their states.",
+        request: Body::None,
+        status: 200,
+        response: Body::Object,
+    },
+    Operation {
+        method: "put",
+        path: "/api/signals/types/{signalTypeUuid}",
+        summary: "Creates or replaces a signal type's name, color, and states.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PutSignalType<'static>>),
+        status: 204,
+        response: Body::None,
+    },
+    Operation {
+        method: "delete",
+        path: "/api/signals/types/{signalTypeUuid}",
+        summary: "Deletes a signal type which no signal uses.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::DeleteSignalType<'static>>),
+        status: 204,
+        response: Body::None,
+    },
+    Operation {
+        method: "get",
+        path: "/api/usage",
+        summary: "Gets storage usage per stream per day or hour.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::Usage>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/exports",
+        summary: "Lists clip exports.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::ListExports>),
+    },
+    Operation {
+        method: "post",
+        path: "/api/exports",
+        summary: "Starts a clip export.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PostExport<'static>>),
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::Export>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/exports/{id}",
+        summary: "Gets a clip export's progress.",
+        request: Body::None,
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::Export>),
+    },
+    Operation {
+        method: "post",
+        path: "/api/shares",
+        summary: "Creates a link to a clip which may be fetched without authentication.",
+        request: Body::Json(SchemaGenerator::subschema_for::<json::PostShare<'static>>),
+        status: 200,
+        response: Body::Json(SchemaGenerator::subschema_for::<json::PostShareResponse>),
+    },
+    Operation {
+        method: "get",
+        path: "/api/openapi.json",
+        summary: "Gets this description.",
+        request: Body::None,
+        status: 200,
+        response: Body::Object,
+    },
+];
+
+impl Body {
+    /// Returns the `content` of a request or response with this body, if any.
+    fn content(&self, gen: &mut SchemaGenerator) -> Option<Value> {
+        let (type_, schema) = match self {
+            Body::None => return None,
+            Body::Object => ("application/json", json!({ "type": "object" })),
+            Body::Json(f) => ("application/json", json!(f(gen))),
+            Body::Binary(t) => (*t, json!({ "type": "string", "format": "binary" })),
+        };
+        Some(json!({ type_: { "schema": schema } }))
+    }
+}
+
+/// Returns the path parameters of `path`, such as `cameraUuid` in `/api/cameras/{cameraUuid}/`.
+fn path_parameters(path: &str) -> Vec<Value> {
+    path.split('/')
+        .filter_map(|c| c.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect()
+}
+
+/// Builds the OpenAPI document.
+fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = BTreeMap::new();
+    for op in OPERATIONS {
+        let mut o = Map::new();
+        o.insert("summary".to_owned(), op.summary.into());
+        let parameters = path_parameters(op.path);
+        if !parameters.is_empty() {
+            o.insert("parameters".to_owned(), parameters.into());
+        }
+        if let Some(content) = op.request.content(&mut gen) {
+            o.insert(
+                "requestBody".to_owned(),
+                json!({ "required": true, "content": content }),
+            );
+        }
+        let mut response = json!({ "description": "success" });
+        if let Some(content) = op.response.content(&mut gen) {
+            response["content"] = content;
+        }
+        o.insert(
+            "responses".to_owned(),
+            json!({ op.status.to_string(): response }),
+        );
+        paths
+            .entry(op.path)
+            .or_insert_with(Map::new)
+            .insert(op.method.to_owned(), Value::Object(o));
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Moonfire NVR",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": gen.definitions() },
+    })
+}
+
+impl Service {
+    pub(super) fn openapi(&self, req: &Request<hyper::Body>) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            bail!(InvalidArgument, msg("GET or HEAD expected"));
+        }
+        serve_json(req, &document())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    /// Checks that every `$ref` in `v` resolves to a schema in `schemas`.
+    fn check_refs(v: &Value, schemas: &serde_json::Map<String, Value>) {
+        match v {
+            Value::Object(m) => {
+                if let Some(Value::String(r)) = m.get("$ref") {
+                    let name = r.strip_prefix("#/components/schemas/").unwrap();
+                    assert!(schemas.contains_key(name), "unresolved {r}");
+                }
+                m.values().for_each(|v| check_refs(v, schemas));
+            }
+            Value::Array(a) => a.iter().for_each(|v| check_refs(v, schemas)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn document() {
+        let doc = super::document();
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("ListRecordings"));
+        assert!(schemas.contains_key("Usage"));
+        check_refs(&doc, schemas);
+        let recordings = &doc["paths"]["/api/cameras/{cameraUuid}/{stream}/recordings"]["get"];
+        assert_eq!(recordings["parameters"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            recordings["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ListRecordings"
+        );
+        assert!(doc["paths"]["/api/signals"]["post"]["requestBody"].is_object());
+    }
+}
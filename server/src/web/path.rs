@@ -4,6 +4,8 @@
 
 //! Decodes request paths.
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use db::auth::SessionHash;
 use std::str::FromStr;
 use uuid::Uuid;
 
@@ -13,33 +15,109 @@ pub(super) enum Path {
     TopLevel,                                         // "/api/"
     Request,                                          // "/api/request"
     InitSegment(i32, bool),                           // "/api/init/<id>.mp4{.txt}"
+    Cameras,                                          // "/api/cameras/"
     Camera(Uuid),                                     // "/api/cameras/<uuid>/"
+    CameraPtz(Uuid),                                  // "/api/cameras/<uuid>/ptz"
+    CameraHealth(Uuid),                               // "/api/cameras/<uuid>/health"
+    CameraTalk(Uuid),                                 // "/api/cameras/<uuid>/talk"
     Signals,                                          // "/api/signals"
+    SignalTypes,                                      // "/api/signals/types"
+    SignalEvents(u32),                                // "/api/signals/<id>/events"
+    SignalType(Uuid),                                 // "/api/signals/types/<uuid>"
     StreamRecordings(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/recordings"
     StreamViewMp4(Uuid, db::StreamType, bool),        // "/api/cameras/<uuid>/<type>/view.mp4{.txt}"
     StreamViewMp4Segment(Uuid, db::StreamType, bool), // "/api/cameras/<uuid>/<type>/view.m4s{.txt}"
+    StreamViewTs(Uuid, db::StreamType, bool),         // "/api/cameras/<uuid>/<type>/view.ts{.txt}"
     StreamLiveMp4Segments(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/live.m4s"
-    Login,                                            // "/api/login"
-    Logout,                                           // "/api/logout"
-    Static,                                           // (anything that doesn't start with "/api/")
-    Users,                                            // "/api/users"
-    User(i32),                                        // "/api/users/<id>"
+    StreamWebRtc(Uuid, db::StreamType),               // "/api/cameras/<uuid>/<type>/webrtc"
+    StreamHlsPlaylist(Uuid, db::StreamType), // "/api/cameras/<uuid>/<type>/hls/playlist.m3u8"
+    StreamHlsSegment(Uuid, db::StreamType),  // "/api/cameras/<uuid>/<type>/hls/segment.m4s"
+    StreamSnapshot(Uuid, db::StreamType),    // "/api/cameras/<uuid>/<type>/snapshot.jpg"
+    StreamThumbnails(Uuid, db::StreamType),  // "/api/cameras/<uuid>/<type>/thumbnails"
+    StreamDetections(Uuid, db::StreamType),  // "/api/cameras/<uuid>/<type>/detections"
+    StreamSchedule(Uuid, db::StreamType),    // "/api/cameras/<uuid>/<type>/schedule"
+    StreamImport(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/import"
+    StreamThumbnailSprite(Uuid, db::StreamType), // "/api/cameras/<uuid>/<type>/thumbnails.jpg"
+    Audit,                                   // "/api/audit"
+    Backup,                                  // "/api/backup.db"
+    Dirs,                                    // "/api/dirs/"
+    Dir(i32),                                // "/api/dirs/<id>"
+    Events,                                  // "/api/events"
+    Exports,                                 // "/api/exports"
+    Export(ulid::Ulid),                      // "/api/exports/<id>"
+    ExportDownload(ulid::Ulid),              // "/api/exports/<id>/clip.mp4"
+    HaCameras,                               // "/api/ha/cameras"
+    HaCameraSnapshot(Uuid),                  // "/api/ha/cameras/<uuid>/snapshot.jpg"
+    HaStreamHlsPlaylist(Uuid, db::StreamType), // "/api/ha/cameras/<uuid>/<type>/hls/playlist.m3u8"
+    HaStreamHlsSegment(Uuid, db::StreamType), // "/api/ha/cameras/<uuid>/<type>/hls/segment.m4s"
+    HaInitSegment(i32),                      // "/api/ha/init/<id>.mp4"
+    Health,                                  // "/api/health"
+    LiveMp4Segments,                         // "/api/live.m4s"
+    Login,                                   // "/api/login"
+    LoginOidc,                               // "/api/login/oidc"
+    LoginOidcCallback,                       // "/api/login/oidc/callback"
+    Logout,                                  // "/api/logout"
+    Metrics,                                 // "/metrics"
+    OpenApi,                                 // "/api/openapi.json"
+    RecordingsSearch,                        // "/api/recordings/search"
+    Reload,                                  // "/api/reload"
+    Shares,                                  // "/api/shares"
+    ShareDownload(String),                   // "/api/shares/<token>/clip.mp4"
+    Static,                                  // (anything that doesn't start with "/api/")
+    Tokens,                                  // "/api/tokens"
+    Token(i32),                              // "/api/tokens/<id>"
+    Usage,                                   // "/api/usage"
+    Users,                                   // "/api/users"
+    User(i32),                               // "/api/users/<id>"
+    UserSessions(i32),                       // "/api/users/<id>/sessions"
+    UserSession(i32, SessionHash),           // "/api/users/<id>/sessions/<session id>"
+    WebAuthnRegisterStart,                   // "/api/webauthn/register/start"
+    WebAuthnRegisterFinish,                  // "/api/webauthn/register/finish"
+    WebAuthnLoginStart,                      // "/api/webauthn/login/start"
+    WebAuthnLoginFinish,                     // "/api/webauthn/login/finish"
+    WebAuthnCredentials,                     // "/api/webauthn/credentials/"
+    WebAuthnCredential(i32),                 // "/api/webauthn/credentials/<id>"
     NotFound,
 }
 
 impl Path {
     /// Decodes a request path, notably not including any request parameters.
     pub(super) fn decode(path: &str) -> Self {
+        if path == "/metrics" {
+            return Path::Metrics;
+        }
         let path = match path.strip_prefix("/api/") {
             Some(p) => p,
             None => return Path::Static,
         };
         match path {
             "" => return Path::TopLevel,
+            "audit" => return Path::Audit,
+            "backup.db" => return Path::Backup,
+            "health" => return Path::Health,
+            "live.m4s" => return Path::LiveMp4Segments,
             "login" => return Path::Login,
+            "login/oidc" => return Path::LoginOidc,
+            "login/oidc/callback" => return Path::LoginOidcCallback,
             "logout" => return Path::Logout,
+            "openapi.json" => return Path::OpenApi,
+            "recordings/search" => return Path::RecordingsSearch,
+            "reload" => return Path::Reload,
+            "events" => return Path::Events,
+            "exports" => return Path::Exports,
+            "ha/cameras" | "ha/cameras/" => return Path::HaCameras,
             "request" => return Path::Request,
+            "shares" => return Path::Shares,
             "signals" => return Path::Signals,
+            "signals/types" => return Path::SignalTypes,
+            "tokens" => return Path::Tokens,
+            "usage" => return Path::Usage,
+            "users" => return Path::Users,
+            "webauthn/register/start" => return Path::WebAuthnRegisterStart,
+            "webauthn/register/finish" => return Path::WebAuthnRegisterFinish,
+            "webauthn/login/start" => return Path::WebAuthnLoginStart,
+            "webauthn/login/finish" => return Path::WebAuthnLoginFinish,
+            "webauthn/credentials" => return Path::WebAuthnCredentials,
             _ => {}
         };
         if let Some(path) = path.strip_prefix("init/") {
@@ -56,6 +134,9 @@ impl Path {
             }
             Path::NotFound
         } else if let Some(path) = path.strip_prefix("cameras/") {
+            if path.is_empty() {
+                return Path::Cameras;
+            }
             let (uuid, path) = match path.split_once('/') {
                 Some(pair) => pair,
                 None => return Path::NotFound,
@@ -70,6 +151,15 @@ impl Path {
             if path.is_empty() {
                 return Path::Camera(uuid);
             }
+            if path == "ptz" {
+                return Path::CameraPtz(uuid);
+            }
+            if path == "health" {
+                return Path::CameraHealth(uuid);
+            }
+            if path == "talk" {
+                return Path::CameraTalk(uuid);
+            }
 
             let (type_, path) = match path.split_once('/') {
                 Some(pair) => pair,
@@ -87,21 +177,185 @@ impl Path {
                 "view.mp4.txt" => Path::StreamViewMp4(uuid, type_, true),
                 "view.m4s" => Path::StreamViewMp4Segment(uuid, type_, false),
                 "view.m4s.txt" => Path::StreamViewMp4Segment(uuid, type_, true),
+                "view.ts" => Path::StreamViewTs(uuid, type_, false),
+                "view.ts.txt" => Path::StreamViewTs(uuid, type_, true),
                 "live.m4s" => Path::StreamLiveMp4Segments(uuid, type_),
+                "webrtc" => Path::StreamWebRtc(uuid, type_),
+                "hls/playlist.m3u8" => Path::StreamHlsPlaylist(uuid, type_),
+                "hls/segment.m4s" => Path::StreamHlsSegment(uuid, type_),
+                "snapshot.jpg" => Path::StreamSnapshot(uuid, type_),
+                "thumbnails" => Path::StreamThumbnails(uuid, type_),
+                "thumbnails.jpg" => Path::StreamThumbnailSprite(uuid, type_),
+                "detections" => Path::StreamDetections(uuid, type_),
+                "schedule" => Path::StreamSchedule(uuid, type_),
+                "import" => Path::StreamImport(uuid, type_),
                 _ => Path::NotFound,
             }
-        } else if let Some(path) = path.strip_prefix("users/") {
+        } else if let Some(path) = path.strip_prefix("signals/types/") {
+            match Uuid::parse_str(path) {
+                Ok(u) => Path::SignalType(u),
+                Err(_) => Path::NotFound,
+            }
+        } else if let Some(path) = path.strip_prefix("signals/") {
+            match path.strip_suffix("/events").map(str::parse) {
+                Some(Ok(id)) => Path::SignalEvents(id),
+                _ => Path::NotFound,
+            }
+        } else if let Some(path) = path.strip_prefix("ha/") {
+            decode_ha(path)
+        } else if let Some(path) = path.strip_prefix("exports/") {
+            let (id, download) = match path.strip_suffix("/clip.mp4") {
+                Some(id) => (id, true),
+                None => (path, false),
+            };
+            match (ulid::Ulid::from_string(id), download) {
+                (Ok(id), false) => Path::Export(id),
+                (Ok(id), true) => Path::ExportDownload(id),
+                (Err(_), _) => Path::NotFound,
+            }
+        } else if let Some(path) = path.strip_prefix("shares/") {
+            match path.strip_suffix("/clip.mp4") {
+                Some(token) if !token.is_empty() && !token.contains('/') => {
+                    Path::ShareDownload(token.to_owned())
+                }
+                _ => Path::NotFound,
+            }
+        } else if let Some(path) = path.strip_prefix("dirs/") {
             if let Ok(id) = i32::from_str(path) {
-                return Path::User(id);
+                return Path::Dir(id);
             }
+            if path.is_empty() {
+                return Path::Dirs;
+            }
+            Path::NotFound
+        } else if let Some(path) = path.strip_prefix("tokens/") {
+            if let Ok(id) = i32::from_str(path) {
+                return Path::Token(id);
+            }
+            if path.is_empty() {
+                return Path::Tokens;
+            }
+            Path::NotFound
+        } else if let Some(path) = path.strip_prefix("users/") {
             if path.is_empty() {
                 return Path::Users;
             }
+            let (id, path) = path.split_once('/').unwrap_or((path, ""));
+            let Ok(id) = i32::from_str(id) else {
+                return Path::NotFound;
+            };
+            match path {
+                "" => Path::User(id),
+                "sessions" | "sessions/" => Path::UserSessions(id),
+                p => match p.strip_prefix("sessions/").and_then(decode_session_id) {
+                    Some(hash) => Path::UserSession(id, hash),
+                    None => Path::NotFound,
+                },
+            }
+        } else if let Some(path) = path.strip_prefix("webauthn/credentials/") {
+            if let Ok(id) = i32::from_str(path) {
+                return Path::WebAuthnCredential(id);
+            }
+            if path.is_empty() {
+                return Path::WebAuthnCredentials;
+            }
             Path::NotFound
         } else {
             Path::NotFound
         }
     }
+
+    /// Returns the camera this path refers to, if any.
+    pub(super) fn camera_uuid(&self) -> Option<Uuid> {
+        match *self {
+            Path::Camera(uuid)
+            | Path::CameraPtz(uuid)
+            | Path::CameraHealth(uuid)
+            | Path::CameraTalk(uuid)
+            | Path::StreamRecordings(uuid, _)
+            | Path::StreamViewMp4(uuid, _, _)
+            | Path::StreamViewMp4Segment(uuid, _, _)
+            | Path::StreamViewTs(uuid, _, _)
+            | Path::StreamLiveMp4Segments(uuid, _)
+            | Path::StreamWebRtc(uuid, _)
+            | Path::StreamHlsPlaylist(uuid, _)
+            | Path::StreamHlsSegment(uuid, _)
+            | Path::StreamSnapshot(uuid, _)
+            | Path::StreamThumbnails(uuid, _)
+            | Path::StreamDetections(uuid, _)
+            | Path::StreamSchedule(uuid, _)
+            | Path::StreamImport(uuid, _)
+            | Path::StreamThumbnailSprite(uuid, _)
+            | Path::HaCameraSnapshot(uuid)
+            | Path::HaStreamHlsPlaylist(uuid, _)
+            | Path::HaStreamHlsSegment(uuid, _) => Some(uuid),
+            _ => None,
+        }
+    }
+
+    /// Returns true iff this path is one of the Home Assistant endpoints, which challenge
+    /// unauthenticated requests for HTTP Basic credentials.
+    pub(super) fn is_home_assistant(&self) -> bool {
+        matches!(
+            self,
+            Path::HaCameras
+                | Path::HaCameraSnapshot(_)
+                | Path::HaStreamHlsPlaylist(..)
+                | Path::HaStreamHlsSegment(..)
+                | Path::HaInitSegment(_)
+        )
+    }
+}
+
+/// Decodes the remainder of a path under `/api/ha/`.
+///
+/// These mirror the paths under `/api/`, so that relative references within HLS playlists to
+/// segments and initialization segments stay under `/api/ha/`.
+fn decode_ha(path: &str) -> Path {
+    if let Some(path) = path.strip_prefix("init/") {
+        return match path.strip_suffix(".mp4").map(i32::from_str) {
+            Some(Ok(id)) => Path::HaInitSegment(id),
+            _ => Path::NotFound,
+        };
+    }
+    let Some(path) = path.strip_prefix("cameras/") else {
+        return Path::NotFound;
+    };
+    let Some((uuid, path)) = path.split_once('/') else {
+        return Path::NotFound;
+    };
+    let Ok(uuid) = Uuid::parse_str(uuid) else {
+        return Path::NotFound;
+    };
+    if path == "snapshot.jpg" {
+        return Path::HaCameraSnapshot(uuid);
+    }
+    let Some((type_, path)) = path.split_once('/') else {
+        return Path::NotFound;
+    };
+    let Some(type_) = db::StreamType::parse(type_) else {
+        return Path::NotFound;
+    };
+    match path {
+        "hls/playlist.m3u8" => Path::HaStreamHlsPlaylist(uuid, type_),
+        "hls/segment.m4s" => Path::HaStreamHlsSegment(uuid, type_),
+        _ => Path::NotFound,
+    }
+}
+
+/// Decodes a session id as in `/api/users/<id>/sessions/<session id>`: the session's hash,
+/// encoded with the URL-safe base64 alphabet.
+pub(super) fn decode_session_id(id: &str) -> Option<SessionHash> {
+    let mut hash = SessionHash::default();
+    match URL_SAFE_NO_PAD.decode_slice(id, &mut hash.0[..]) {
+        Ok(24) => Some(hash),
+        _ => None,
+    }
+}
+
+/// Encodes a session id; the inverse of `decode_session_id`.
+pub(super) fn encode_session_id(hash: &SessionHash) -> String {
+    URL_SAFE_NO_PAD.encode(hash.0)
 }
 
 #[cfg(test)]
@@ -112,6 +366,8 @@ mod tests {
         use uuid::Uuid;
         let cam_uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
         assert_eq!(Path::decode("/foo"), Path::Static);
+        assert_eq!(Path::decode("/metrics"), Path::Metrics);
+        assert_eq!(Path::decode("/metrics/"), Path::Static);
         assert_eq!(Path::decode("/api/"), Path::TopLevel);
         assert_eq!(
             Path::decode("/api/init/42.mp4"),
@@ -126,7 +382,20 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/"),
             Path::Camera(cam_uuid)
         );
+        assert_eq!(Path::decode("/api/cameras/"), Path::Cameras);
         assert_eq!(Path::decode("/api/cameras/asdf/"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/ptz"),
+            Path::CameraPtz(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/health"),
+            Path::CameraHealth(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/talk"),
+            Path::CameraTalk(cam_uuid)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/recordings"),
             Path::StreamRecordings(cam_uuid, db::StreamType::Main)
@@ -155,20 +424,181 @@ mod tests {
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.m4s.txt"),
             Path::StreamViewMp4Segment(cam_uuid, db::StreamType::Main, true)
         );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.ts"),
+            Path::StreamViewTs(cam_uuid, db::StreamType::Main, false)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.ts.txt"),
+            Path::StreamViewTs(cam_uuid, db::StreamType::Main, true)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/live.m4s"),
             Path::StreamLiveMp4Segments(cam_uuid, db::StreamType::Main)
         );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/webrtc"),
+            Path::StreamWebRtc(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode(
+                "/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/hls/playlist.m3u8"
+            ),
+            Path::StreamHlsPlaylist(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/hls/segment.m4s"),
+            Path::StreamHlsSegment(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/snapshot.jpg"),
+            Path::StreamSnapshot(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/thumbnails"),
+            Path::StreamThumbnails(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/thumbnails.jpg"),
+            Path::StreamThumbnailSprite(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/detections"),
+            Path::StreamDetections(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/schedule"),
+            Path::StreamSchedule(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/import"),
+            Path::StreamImport(cam_uuid, db::StreamType::Main)
+        );
         assert_eq!(
             Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/junk"),
             Path::NotFound
         );
+        assert_eq!(Path::decode("/api/backup.db"), Path::Backup);
+        assert_eq!(Path::decode("/api/reload"), Path::Reload);
+        assert_eq!(
+            Path::decode("/api/recordings/search"),
+            Path::RecordingsSearch
+        );
+        let export_id = ulid::Ulid::from_string("01HQ3V5Q8M7Y2K4W6X9Z0A1B2C").unwrap();
+        assert_eq!(Path::decode("/api/events"), Path::Events);
+        assert_eq!(Path::decode("/api/exports"), Path::Exports);
+        assert_eq!(
+            Path::decode("/api/exports/01HQ3V5Q8M7Y2K4W6X9Z0A1B2C"),
+            Path::Export(export_id)
+        );
+        assert_eq!(
+            Path::decode("/api/exports/01HQ3V5Q8M7Y2K4W6X9Z0A1B2C/clip.mp4"),
+            Path::ExportDownload(export_id)
+        );
+        assert_eq!(Path::decode("/api/exports/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/ha/cameras"), Path::HaCameras);
+        assert_eq!(
+            Path::decode("/api/ha/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/snapshot.jpg"),
+            Path::HaCameraSnapshot(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode(
+                "/api/ha/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/hls/playlist.m3u8"
+            ),
+            Path::HaStreamHlsPlaylist(cam_uuid, db::StreamType::Sub)
+        );
+        assert_eq!(
+            Path::decode(
+                "/api/ha/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/hls/segment.m4s"
+            ),
+            Path::HaStreamHlsSegment(cam_uuid, db::StreamType::Main)
+        );
+        assert_eq!(Path::decode("/api/ha/init/42.mp4"), Path::HaInitSegment(42));
+        assert_eq!(Path::decode("/api/ha/init/42.mp4.txt"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/ha/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/main/view.mp4"),
+            Path::NotFound
+        );
+        assert_eq!(Path::decode("/api/audit"), Path::Audit);
+        assert_eq!(Path::decode("/api/health"), Path::Health);
+        assert_eq!(Path::decode("/api/live.m4s"), Path::LiveMp4Segments);
+        assert_eq!(Path::decode("/api/shares"), Path::Shares);
+        assert_eq!(
+            Path::decode("/api/shares/abc-_123/clip.mp4"),
+            Path::ShareDownload("abc-_123".to_owned())
+        );
+        assert_eq!(Path::decode("/api/shares/abc"), Path::NotFound);
+        assert_eq!(Path::decode("/api/shares//clip.mp4"), Path::NotFound);
         assert_eq!(Path::decode("/api/login"), Path::Login);
         assert_eq!(Path::decode("/api/logout"), Path::Logout);
+        assert_eq!(Path::decode("/api/openapi.json"), Path::OpenApi);
         assert_eq!(Path::decode("/api/signals"), Path::Signals);
+        assert_eq!(Path::decode("/api/signals/types"), Path::SignalTypes);
+        assert_eq!(
+            Path::decode("/api/signals/types/ee66270f-d9c6-4819-8b33-9720d4cbca6b"),
+            Path::SignalType(Uuid::parse_str("ee66270f-d9c6-4819-8b33-9720d4cbca6b").unwrap())
+        );
+        assert_eq!(Path::decode("/api/signals/types/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/signals/1/events"), Path::SignalEvents(1));
+        assert_eq!(Path::decode("/api/signals/junk/events"), Path::NotFound);
+        assert_eq!(Path::decode("/api/signals/1"), Path::NotFound);
+        assert_eq!(Path::decode("/api/usage"), Path::Usage);
         assert_eq!(Path::decode("/api/junk"), Path::NotFound);
+        assert_eq!(Path::decode("/api/dirs/"), Path::Dirs);
+        assert_eq!(Path::decode("/api/dirs/1"), Path::Dir(1));
+        assert_eq!(Path::decode("/api/dirs/asdf"), Path::NotFound);
         assert_eq!(Path::decode("/api/users/42"), Path::User(42));
         assert_eq!(Path::decode("/api/users/asdf"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/users/42/sessions"),
+            Path::UserSessions(42)
+        );
+        let hash = db::auth::SessionHash([0xfb; 24]);
+        let session_path = format!("/api/users/42/sessions/{}", super::encode_session_id(&hash));
+        assert!(!session_path.contains('+'));
+        assert_eq!(Path::decode(&session_path), Path::UserSession(42, hash));
+        assert_eq!(Path::decode("/api/users/42/sessions/asdf"), Path::NotFound);
+        assert_eq!(Path::decode("/api/users/42/junk"), Path::NotFound);
         assert_eq!(Path::decode("/api/users/"), Path::Users);
+        assert_eq!(Path::decode("/api/users"), Path::Users);
+        assert_eq!(Path::decode("/api/tokens/"), Path::Tokens);
+        assert_eq!(Path::decode("/api/tokens/7"), Path::Token(7));
+        assert_eq!(Path::decode("/api/tokens/asdf"), Path::NotFound);
+        assert_eq!(
+            Path::decode("/api/webauthn/login/start"),
+            Path::WebAuthnLoginStart
+        );
+        assert_eq!(
+            Path::decode("/api/webauthn/credentials/"),
+            Path::WebAuthnCredentials
+        );
+        assert_eq!(
+            Path::decode("/api/webauthn/credentials/3"),
+            Path::WebAuthnCredential(3)
+        );
+        assert_eq!(Path::decode("/api/webauthn/"), Path::NotFound);
+        assert_eq!(Path::decode("/api/login/oidc"), Path::LoginOidc);
+        assert_eq!(
+            Path::decode("/api/login/oidc/callback"),
+            Path::LoginOidcCallback
+        );
+    }
+
+    #[test]
+    fn camera_uuid() {
+        use super::Path;
+        use uuid::Uuid;
+        let cam_uuid = Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap();
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/").camera_uuid(),
+            Some(cam_uuid)
+        );
+        assert_eq!(
+            Path::decode("/api/cameras/35144640-ff1e-4619-b0d5-4c74c185741c/sub/live.m4s")
+                .camera_uuid(),
+            Some(cam_uuid)
+        );
+        assert_eq!(Path::decode("/api/").camera_uuid(), None);
+        assert_eq!(Path::decode("/api/cameras/").camera_uuid(), None);
     }
 }
@@ -0,0 +1,139 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/ptz` handling.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base::{bail, err, Error, FastHashMap};
+use http::{Method, Request, StatusCode};
+use uuid::Uuid;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+use crate::json;
+use crate::onvif;
+
+/// PTZ connections by camera id, reused across requests to avoid repeating service lookups.
+#[derive(Default)]
+pub(super) struct Connections(Mutex<FastHashMap<i32, Arc<onvif::ptz::Ptz>>>);
+
+impl Service {
+    pub(super) async fn camera_ptz(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.ptz {
+            bail!(PermissionDenied, msg("ptz required"));
+        }
+        match *req.method() {
+            Method::POST => self.post_ptz(req, caller, uuid).await,
+            Method::GET | Method::HEAD => self.get_ptz(&req, uuid).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST, GET, or HEAD expected",
+            )),
+        }
+    }
+
+    async fn get_ptz(&self, req: &Request<hyper::Body>, uuid: Uuid) -> ResponseResult {
+        let (camera_id, ptz) = self.ptz_connection(uuid).await?;
+        let presets = self.ptz_result(camera_id, ptz.presets().await)?;
+        serve_json(
+            req,
+            &json::PtzPresets {
+                presets: presets
+                    .into_iter()
+                    .map(|p| json::PtzPreset {
+                        token: p.token,
+                        name: p.name,
+                    })
+                    .collect(),
+            },
+        )
+    }
+
+    async fn post_ptz(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostPtzRequest = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        if let json::PtzOp::Move {
+            pan, tilt, zoom, ..
+        } = r.op
+        {
+            if ![pan, tilt, zoom].iter().all(|v| (-1.0..=1.0).contains(v)) {
+                bail!(
+                    InvalidArgument,
+                    msg("pan, tilt, and zoom must be between -1 and 1")
+                );
+            }
+        }
+        let (camera_id, ptz) = self.ptz_connection(uuid).await?;
+        let result = match r.op {
+            json::PtzOp::Move {
+                pan,
+                tilt,
+                zoom,
+                timeout_ms,
+            } => {
+                let timeout = timeout_ms.map(|ms| Duration::from_millis(u64::from(ms)));
+                ptz.continuous_move(pan, tilt, zoom, timeout).await
+            }
+            json::PtzOp::Stop => ptz.stop().await,
+            json::PtzOp::GotoPreset { preset } => ptz.goto_preset(&preset).await,
+        };
+        self.ptz_result(camera_id, result)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// Returns the camera's id and a PTZ connection, creating one if necessary.
+    async fn ptz_connection(&self, uuid: Uuid) -> Result<(i32, Arc<onvif::ptz::Ptz>), Error> {
+        let (camera_id, onvif_base_url, username, password) = {
+            let db = self.db.lock();
+            let Some(camera) = db.get_camera(uuid) else {
+                bail!(NotFound, msg("no such camera {uuid}"));
+            };
+            let Some(url) = camera.config.onvif_base_url.clone() else {
+                bail!(
+                    FailedPrecondition,
+                    msg("camera {uuid} has no ONVIF base URL")
+                );
+            };
+            (
+                camera.id,
+                url,
+                camera.config.username.clone(),
+                camera.config.password.clone(),
+            )
+        };
+        if let Some(ptz) = self.ptz.0.lock().unwrap().get(&camera_id) {
+            return Ok((camera_id, ptz.clone()));
+        }
+        let ptz = onvif::ptz::Ptz::connect(onvif::Client::new(username, password), &onvif_base_url)
+            .await
+            .map_err(|e| err!(e, msg("unable to connect to PTZ service of camera {uuid}")))?;
+        let ptz = Arc::new(ptz);
+        self.ptz.0.lock().unwrap().insert(camera_id, ptz.clone());
+        Ok((camera_id, ptz))
+    }
+
+    /// Passes along the result of a PTZ call, forgetting the camera's connection on error so
+    /// that the next request looks up its services again.
+    fn ptz_result<T>(&self, camera_id: i32, result: Result<T, Error>) -> Result<T, Error> {
+        if result.is_err() {
+            self.ptz.0.lock().unwrap().remove(&camera_id);
+        }
+        result
+    }
+}
@@ -0,0 +1,73 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Configuration reloads: `/api/reload`.
+
+use base::{bail, err, Error};
+use http::{Method, Request, StatusCode};
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, Caller,
+    ResponseResult, Service,
+};
+use crate::json;
+
+/// A request to reload configuration, sent to the server's main task.
+pub struct Command {
+    /// If true, re-applies the `--config-file` (if any) before reconciling streamers with the
+    /// database. Camera edits via the API set this to false, so the file doesn't revert them.
+    pub reapply_config_file: bool,
+
+    /// Receives the result once the reload is complete.
+    pub responder: tokio::sync::oneshot::Sender<Result<(), Error>>,
+}
+
+impl Service {
+    pub(super) async fn reload(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.admin_users {
+            bail!(PermissionDenied, msg("admin_users required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostReloadRequest = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        self.send_reload(true).await?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    /// Starts, stops, and restarts streamers to match a database change made via the API, if
+    /// this server records.
+    pub(super) async fn reconcile_streamers(&self) -> Result<(), Error> {
+        if self.reload_tx.is_none() {
+            return Ok(());
+        }
+        self.send_reload(false).await
+    }
+
+    /// Asks the server's main task to reload and waits for the result.
+    pub(super) async fn send_reload(&self, reapply_config_file: bool) -> Result<(), Error> {
+        let Some(ref reload_tx) = self.reload_tx else {
+            bail!(Unimplemented, msg("reload isn't supported by this server"));
+        };
+        let (responder, rx) = tokio::sync::oneshot::channel();
+        reload_tx
+            .send(Command {
+                reapply_config_file,
+                responder,
+            })
+            .await
+            .map_err(|_| err!(Unavailable, msg("server is shutting down")))?;
+        rx.await
+            .map_err(|_| err!(Unavailable, msg("server is shutting down")))?
+    }
+}
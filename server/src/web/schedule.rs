@@ -0,0 +1,108 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/<type>/schedule` handling.
+
+use base::{bail, Error};
+use http::{Method, Request, StatusCode};
+use uuid::Uuid;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+use crate::json;
+
+impl Service {
+    pub(super) async fn stream_schedule(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_schedule(&req, caller, uuid, stream_type),
+            Method::PUT => self.put_schedule(req, caller, uuid, stream_type).await,
+            Method::DELETE => self.delete_schedule(req, caller, uuid, stream_type).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, PUT, or DELETE expected",
+            )),
+        }
+    }
+
+    fn get_schedule(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.read_camera_configs {
+            bail!(PermissionDenied, msg("read_camera_configs required"));
+        }
+        let db = self.db.lock();
+        let stream_id = schedule_stream_id(&db, uuid, stream_type)?;
+        let stream = db.streams_by_id().get(&stream_id).expect("stream exists");
+        serve_json(
+            req,
+            &json::StreamSchedule {
+                schedule: stream.config.schedule.as_ref(),
+            },
+        )
+    }
+
+    async fn put_schedule(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PutStreamSchedule = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut db = self.db.lock();
+        let stream_id = schedule_stream_id(&db, uuid, stream_type)?;
+        db.update_stream_schedule(stream_id, Some(r.schedule))?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    async fn delete_schedule(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs {
+            bail!(PermissionDenied, msg("update_camera_configs required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteStreamSchedule = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut db = self.db.lock();
+        let stream_id = schedule_stream_id(&db, uuid, stream_type)?;
+        db.update_stream_schedule(stream_id, None)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+fn schedule_stream_id(
+    db: &db::LockedDatabase,
+    uuid: Uuid,
+    stream_type: db::StreamType,
+) -> Result<i32, Error> {
+    let Some(camera) = db.get_camera(uuid) else {
+        bail!(NotFound, msg("no such camera {uuid}"));
+    };
+    let Some(stream_id) = camera.streams[stream_type.index()] else {
+        bail!(NotFound, msg("no such stream {uuid}/{stream_type}"));
+    };
+    Ok(stream_id)
+}
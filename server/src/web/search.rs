@@ -0,0 +1,413 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Searching recordings across cameras: `/api/recordings/search`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::str::FromStr;
+
+use base::{bail, err, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use db::recording;
+use http::{Method, Request};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{next_page_url, serve_json, Caller, ResponseResult, Service};
+use crate::json;
+
+const DEFAULT_LIMIT: usize = 100;
+pub(crate) const MAX_LIMIT: usize = 1000;
+
+/// The sort key of a result. Results are returned in descending order, newest first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Key {
+    start: recording::Time,
+    stream_id: i32,
+    recording_id: i32,
+}
+
+impl Key {
+    /// Encodes as an opaque cursor, which resumes the search after this result.
+    pub(crate) fn encode(&self) -> String {
+        let mut buf = [0u8; 16];
+        buf[..8].copy_from_slice(&self.start.0.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.stream_id.to_be_bytes());
+        buf[12..].copy_from_slice(&self.recording_id.to_be_bytes());
+        URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    pub(crate) fn decode(cursor: &str) -> Result<Self, Error> {
+        let mut buf = [0u8; 16];
+        match URL_SAFE_NO_PAD.decode_slice(cursor, &mut buf[..]) {
+            Ok(16) => {}
+            _ => bail!(InvalidArgument, msg("bad cursor")),
+        }
+        Ok(Key {
+            start: recording::Time(i64::from_be_bytes(buf[..8].try_into().unwrap())),
+            stream_id: i32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            recording_id: i32::from_be_bytes(buf[12..].try_into().unwrap()),
+        })
+    }
+}
+
+/// The parsed query parameters.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Query {
+    pub(crate) cameras: Vec<Uuid>,
+    pub(crate) streams: Vec<db::StreamType>,
+    pub(crate) time: Range<recording::Time>,
+    pub(crate) signal_types: Vec<Uuid>,
+    pub(crate) detected_classes: Vec<String>,
+    pub(crate) min_duration: recording::Duration,
+    pub(crate) min_height: u16,
+    pub(crate) limit: usize,
+    pub(crate) cursor: Option<Key>,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Query {
+            cameras: Vec::new(),
+            streams: Vec::new(),
+            time: recording::Time::min_value()..recording::Time::max_value(),
+            signal_types: Vec::new(),
+            detected_classes: Vec::new(),
+            min_duration: recording::Duration(0),
+            min_height: 0,
+            limit: DEFAULT_LIMIT,
+            cursor: None,
+        }
+    }
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Result<Self, Error> {
+        let mut q = Query::default();
+        let Some(query) = query else {
+            return Ok(q);
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let (key, value) = (key.borrow(), value.borrow());
+            let bad = || err!(InvalidArgument, msg("unparseable {key}"));
+            match key {
+                "cameraUuid" => q.cameras.push(Uuid::parse_str(value).map_err(|_| bad())?),
+                "stream" => q
+                    .streams
+                    .push(db::StreamType::parse(value).ok_or_else(bad)?),
+                "startTime90k" => {
+                    q.time.start = recording::Time::parse(value).map_err(|_| bad())?
+                }
+                "endTime90k" => q.time.end = recording::Time::parse(value).map_err(|_| bad())?,
+                "signalType" => q
+                    .signal_types
+                    .push(Uuid::parse_str(value).map_err(|_| bad())?),
+                "detectedClass" => q.detected_classes.push(value.to_owned()),
+                "minDuration90k" => {
+                    q.min_duration = recording::Duration(i64::from_str(value).map_err(|_| bad())?)
+                }
+                "minHeight" => q.min_height = u16::from_str(value).map_err(|_| bad())?,
+                "limit" => {
+                    q.limit = usize::from_str(value).map_err(|_| bad())?;
+                    if q.limit == 0 || q.limit > MAX_LIMIT {
+                        bail!(
+                            InvalidArgument,
+                            msg("limit must be between 1 and {MAX_LIMIT}")
+                        );
+                    }
+                }
+                "cursor" => q.cursor = Some(Key::decode(value)?),
+                _ => {}
+            };
+        }
+        Ok(q)
+    }
+
+    fn has_event_filter(&self) -> bool {
+        !self.signal_types.is_empty() || !self.detected_classes.is_empty()
+    }
+}
+
+/// Finds the recordings matching `q` which `permissions` allow, newest first.
+///
+/// Returns at most `q.limit` recordings and, if there are more, the key to resume after.
+pub(crate) fn search(
+    db: &db::LockedDatabase,
+    permissions: &db::Permissions,
+    q: &Query,
+) -> Result<(Vec<json::SearchRecording>, Option<Key>), Error> {
+    // Later pages' recordings start no later than the cursor. Events are still considered
+    // across the whole range, as a recording may extend beyond the cursor.
+    let mut time = q.time.clone();
+    if let Some(c) = q.cursor {
+        time.end = std::cmp::min(time.end, c.start + recording::Duration(1));
+    }
+    let mut results = Vec::new();
+    for camera in db.cameras_by_id().values() {
+        if !permissions.allows_camera(camera.uuid)
+            || (!q.cameras.is_empty() && !q.cameras.contains(&camera.uuid))
+        {
+            continue;
+        }
+        let signals: Vec<u32> = db
+            .signals_by_id()
+            .values()
+            .filter(|s| {
+                q.signal_types.contains(&s.type_)
+                    && s.config.camera_associations.contains_key(&camera.id)
+            })
+            .map(|s| s.id)
+            .collect();
+        let signal_ranges = active_ranges(db, &signals, q.time.clone());
+        for (i, stream_id) in camera.streams.iter().enumerate() {
+            let (Some(stream_id), Some(type_)) = (*stream_id, db::StreamType::from_index(i)) else {
+                continue;
+            };
+            if !q.streams.is_empty() && !q.streams.contains(&type_) {
+                continue;
+            }
+            let mut detection_times = Vec::new();
+            if !q.detected_classes.is_empty() {
+                db.list_detections(stream_id, q.time.clone(), None, &mut |d| {
+                    if q.detected_classes.contains(&d.class) {
+                        detection_times.push(d.time);
+                    }
+                    Ok(())
+                })?;
+                detection_times.sort_unstable();
+            }
+            db.list_recordings_by_time(stream_id, time.clone(), &mut |row| {
+                let key = Key {
+                    start: row.start,
+                    stream_id,
+                    recording_id: row.id.recording(),
+                };
+                let wall =
+                    row.start..row.start + recording::Duration(i64::from(row.wall_duration_90k));
+                if q.cursor.map_or(false, |c| key >= c)
+                    || wall.end - wall.start < q.min_duration
+                    || wall.end <= q.time.start
+                    || wall.start >= q.time.end
+                {
+                    return Ok(());
+                }
+                if q.min_height > 0 {
+                    let height = db
+                        .video_sample_entries_by_id()
+                        .get(&row.video_sample_entry_id)
+                        .map_or(0, |e| e.height);
+                    if height < q.min_height {
+                        return Ok(());
+                    }
+                }
+                if q.has_event_filter()
+                    && !overlaps(&signal_ranges, &wall)
+                    && !contains_any(&detection_times, &wall)
+                {
+                    return Ok(());
+                }
+                results.push((
+                    key,
+                    json::SearchRecording {
+                        camera_uuid: camera.uuid,
+                        stream: type_.as_str(),
+                        id: key.recording_id,
+                        start_time_90k: wall.start,
+                        end_time_90k: wall.end,
+                        video_samples: row.video_samples,
+                        sample_file_bytes: row.sample_file_bytes,
+                        video_sample_entry_id: row.video_sample_entry_id,
+                        open_id: row.open_id,
+                        growing: (row.flags & db::RecordingFlags::Growing as i32) != 0,
+                    },
+                ));
+                Ok(())
+            })?;
+        }
+    }
+    results.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+    let next = if results.len() > q.limit {
+        results.truncate(q.limit);
+        Some(results[q.limit - 1].0)
+    } else {
+        None
+    };
+    Ok((results.into_iter().map(|(_, r)| r).collect(), next))
+}
+
+impl Service {
+    pub(super) fn search_recordings(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            bail!(InvalidArgument, msg("GET or HEAD expected"));
+        }
+        let q = Query::parse(req.uri().query())?;
+        let db = self.db.lock();
+        let (results, next) = search(&db, &caller.permissions, &q)?;
+        let next = next.map(|k| next_page_url(req, &k.encode()));
+        let mut out = json::SearchRecordings {
+            recordings: Vec::with_capacity(results.len()),
+            video_sample_entries: (&db, Vec::new()),
+            next,
+        };
+        for r in results {
+            if !out
+                .video_sample_entries
+                .1
+                .contains(&r.video_sample_entry_id)
+            {
+                out.video_sample_entries.1.push(r.video_sample_entry_id);
+            }
+            out.recordings.push(r);
+        }
+        serve_json(req, &out)
+    }
+}
+
+/// Returns the ascending, disjoint ranges within `range` during which any of `signals` is in a
+/// nonzero state.
+fn active_ranges(
+    db: &db::LockedDatabase,
+    signals: &[u32],
+    range: Range<recording::Time>,
+) -> Vec<Range<recording::Time>> {
+    if signals.is_empty() {
+        return Vec::new();
+    }
+    let mut changes = Vec::new();
+    db.list_changes_by_time(range.clone(), &mut |c| {
+        if signals.contains(&c.signal) {
+            changes.push(*c);
+        }
+    });
+    ranges_from_changes(&changes, range)
+}
+
+/// Returns the ranges within `range` during which any signal in `changes` is nonzero.
+/// `changes` must be in ascending time order, as returned by `list_changes_by_time`.
+fn ranges_from_changes(
+    changes: &[db::signal::ListStateChangesRow],
+    range: Range<recording::Time>,
+) -> Vec<Range<recording::Time>> {
+    let mut out = Vec::new();
+    let mut active = BTreeSet::new();
+    let mut active_since = None;
+    for c in changes {
+        if c.state == 0 {
+            active.remove(&c.signal);
+        } else {
+            active.insert(c.signal);
+        }
+        let when = std::cmp::max(c.when, range.start);
+        match (active_since, active.is_empty()) {
+            (None, false) => active_since = Some(when),
+            (Some(s), true) => {
+                if s < when {
+                    out.push(s..when);
+                }
+                active_since = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = active_since {
+        out.push(s..range.end);
+    }
+    out
+}
+
+/// Returns true if `r` overlaps any of the ascending, disjoint `ranges`.
+fn overlaps(ranges: &[Range<recording::Time>], r: &Range<recording::Time>) -> bool {
+    let i = ranges.partition_point(|x| x.end <= r.start);
+    ranges.get(i).map_or(false, |x| x.start < r.end)
+}
+
+/// Returns true if `r` contains any of the ascending `times`.
+fn contains_any(times: &[recording::Time], r: &Range<recording::Time>) -> bool {
+    let i = times.partition_point(|&t| t < r.start);
+    times.get(i).map_or(false, |&t| t < r.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::signal::ListStateChangesRow;
+    use db::testutil;
+
+    #[test]
+    fn cursor_round_trip() {
+        testutil::init();
+        let k = Key {
+            start: recording::Time(130_000_000_000_000),
+            stream_id: 3,
+            recording_id: 42,
+        };
+        assert_eq!(Key::decode(&k.encode()).unwrap(), k);
+        Key::decode("junk").unwrap_err();
+    }
+
+    #[test]
+    fn parse_query() {
+        testutil::init();
+        let q = Query::parse(Some(
+            "cameraUuid=35144640-ff1e-4619-b0d5-4c74c185741c&stream=main&detectedClass=person\
+             &detectedClass=car&minDuration90k=90000&minHeight=720&limit=5",
+        ))
+        .unwrap();
+        assert_eq!(
+            q.cameras,
+            vec![Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap()]
+        );
+        assert_eq!(q.streams, vec![db::StreamType::Main]);
+        assert_eq!(q.detected_classes, vec!["person", "car"]);
+        assert_eq!(q.min_duration, recording::Duration(90_000));
+        assert_eq!(q.min_height, 720);
+        assert_eq!(q.limit, 5);
+        assert!(q.has_event_filter());
+        assert_eq!(Query::parse(None).unwrap().limit, DEFAULT_LIMIT);
+        Query::parse(Some("limit=0")).unwrap_err();
+        Query::parse(Some("stream=junk")).unwrap_err();
+    }
+
+    #[test]
+    fn signal_ranges() {
+        testutil::init();
+        let row = |when, signal, state| ListStateChangesRow {
+            when: recording::Time(when),
+            signal,
+            state,
+        };
+        let changes = [
+            row(0, 1, 1), // state before the range.
+            row(20, 1, 0),
+            row(30, 1, 2),
+            row(40, 2, 1),
+            row(50, 1, 0),
+            row(60, 2, 0),
+            row(70, 2, 1),
+        ];
+        let ranges = ranges_from_changes(&changes, recording::Time(10)..recording::Time(100));
+        assert_eq!(
+            ranges,
+            vec![
+                recording::Time(10)..recording::Time(20),
+                recording::Time(30)..recording::Time(60),
+                recording::Time(70)..recording::Time(100),
+            ]
+        );
+        let r = |s, e| recording::Time(s)..recording::Time(e);
+        assert!(overlaps(&ranges, &r(15, 25)));
+        assert!(!overlaps(&ranges, &r(20, 30)));
+        assert!(overlaps(&ranges, &r(95, 200)));
+        let times = [recording::Time(5), recording::Time(25)];
+        assert!(contains_any(&times, &r(20, 30)));
+        assert!(contains_any(&times, &r(25, 30)));
+        assert!(!contains_any(&times, &r(26, 30)));
+    }
+}
@@ -6,12 +6,13 @@
 
 use base::{bail, ErrorKind, ResultExt};
 use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use db::audit::{Action, NewEntry};
 use db::auth;
 use http::{header, HeaderValue, Method, Request, Response, StatusCode};
 use memchr::memchr;
 use tracing::{info, warn};
 
-use crate::{json, web::parse_json_body};
+use crate::{body::Body, json, web::parse_json_body};
 
 use super::{
     csrf_matches, extract_json_body, extract_sid, plain_response, ResponseResult, Service,
@@ -32,6 +33,50 @@ impl Service {
         }
         let r = extract_json_body(&mut req).await?;
         let r: json::LoginRequest = parse_json_body(&r)?;
+        let (domain, flags) = self.session_params(&req)?;
+        let mut l = self.db.lock();
+        let result = l
+            .login_by_password(authreq.clone(), r.username, r.password, Some(domain), flags)
+            .map(|(sid, s)| (sid, s.user_id))
+            .map_err(|e| match e.kind() {
+                ErrorKind::ResourceExhausted => e,
+                _ => base::Error::wrap(ErrorKind::Unauthenticated, e),
+            });
+        let user_id = match result {
+            Ok((_, id)) => Some(id),
+            Err(_) => l.get_user(r.username).map(|u| u.id),
+        };
+        self.audit_login(
+            &l,
+            &authreq,
+            (user_id, Some(r.username)),
+            "password",
+            result.as_ref().map(|_| ()),
+        );
+        let (sid, _) = match result {
+            Ok(s) => s,
+            Err(e) if e.kind() == ErrorKind::ResourceExhausted => {
+                let retry_after_sec = authreq.when_sec.and_then(|now| {
+                    l.login_blocked_until(authreq.addr, r.username, now)
+                        .map(|until| until - now)
+                });
+                let mut resp = plain_response(StatusCode::TOO_MANY_REQUESTS, e.to_string());
+                if let Some(s) = retry_after_sec {
+                    resp.headers_mut()
+                        .insert(header::RETRY_AFTER, HeaderValue::from(s));
+                }
+                return Ok(resp);
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(session_response(sid, flags))
+    }
+
+    /// Returns the cookie domain and session flags for a session created by this request.
+    pub(super) fn session_params(
+        &self,
+        req: &Request<::hyper::Body>,
+    ) -> Result<(Vec<u8>, i32), base::Error> {
         let Some(host) = req.headers().get(header::HOST) else {
             bail!(InvalidArgument, msg("missing Host header"));
         };
@@ -41,11 +86,10 @@ impl Service {
             None => host,
         }
         .to_owned();
-        let mut l = self.db.lock();
 
         // If the request came in over https, tell the browser to only send the cookie on https
         // requests also.
-        let is_secure = self.is_secure(&req);
+        let is_secure = self.is_secure(req);
 
         // Use SameSite=Lax rather than SameSite=Strict. Safari apparently doesn't send
         // SameSite=Strict cookies on WebSocket upgrade requests. There's no real security
@@ -60,18 +104,7 @@ impl Service {
             } else {
                 0
             };
-        let (sid, _) = l
-            .login_by_password(authreq, r.username, r.password, Some(domain), flags)
-            .err_kind(ErrorKind::Unauthenticated)?;
-        let cookie = encode_sid(sid, flags);
-        Ok(Response::builder()
-            .header(
-                header::SET_COOKIE,
-                HeaderValue::try_from(cookie).expect("cookie can't have invalid bytes"),
-            )
-            .status(StatusCode::NO_CONTENT)
-            .body(b""[..].into())
-            .unwrap())
+        Ok((domain, flags))
     }
 
     pub(super) async fn logout(
@@ -93,13 +126,30 @@ impl Service {
             let mut l = self.db.lock();
             let hash = sid.hash();
             match l.authenticate_session(authreq.clone(), &hash) {
-                Ok((s, _)) => {
+                Ok((s, u)) => {
                     if !csrf_matches(r.csrf, s.csrf()) {
                         bail!(InvalidArgument, msg("logout with incorrect csrf token"));
                     }
+                    let (user_id, username) = (u.id, u.username.clone());
                     info!("revoking session");
-                    l.revoke_session(auth::RevocationReason::LoggedOut, None, authreq, &hash)
-                        .err_kind(ErrorKind::Internal)?;
+                    l.revoke_session(
+                        auth::RevocationReason::LoggedOut,
+                        None,
+                        authreq.clone(),
+                        &hash,
+                    )
+                    .err_kind(ErrorKind::Internal)?;
+                    self.audit.record(
+                        &l,
+                        &NewEntry {
+                            req: &authreq,
+                            user_id: Some(user_id),
+                            username: Some(&username),
+                            action: Action::Logout,
+                            success: true,
+                            detail: None,
+                        },
+                    );
                 }
                 Err(err) => {
                     // TODO: distinguish "no such session", "session is no longer valid", and
@@ -121,6 +171,20 @@ impl Service {
     }
 }
 
+/// Returns a successful login response, which sets the session cookie.
+pub(super) fn session_response(sid: db::RawSessionId, flags: i32) -> Response<Body> {
+    Response::builder()
+        .header(header::SET_COOKIE, session_cookie(sid, flags))
+        .status(StatusCode::NO_CONTENT)
+        .body(b""[..].into())
+        .unwrap()
+}
+
+/// Returns a `Set-Cookie` header value for the given session.
+pub(super) fn session_cookie(sid: db::RawSessionId, flags: i32) -> HeaderValue {
+    HeaderValue::try_from(encode_sid(sid, flags)).expect("cookie can't have invalid bytes")
+}
+
 /// Encodes a session into `Set-Cookie` header value form.
 fn encode_sid(sid: db::RawSessionId, flags: i32) -> String {
     let mut cookie = String::with_capacity(128);
@@ -185,6 +249,35 @@ mod tests {
         assert_eq!(resp.status(), reqwest::StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn login_throttle() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let login_url = format!("{}/api/login", &s.base_url);
+        let mut p = FastHashMap::default();
+        p.insert("username", "slamb");
+        p.insert("password", "asdf");
+
+        // The exact number of failures allowed depends on timing; eventually, the server should
+        // refuse to check passwords at all.
+        let mut throttled = None;
+        for _ in 0..10 {
+            let resp = cli.post(&login_url).json(&p).send().await.unwrap();
+            if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+                throttled = Some(resp);
+                break;
+            }
+        }
+        let resp = throttled.expect("login should be throttled");
+        assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(reqwest::header::RETRY_AFTER));
+
+        p.insert("password", "hunter2");
+        let resp = cli.post(&login_url).json(&p).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+
     #[tokio::test]
     async fn logout() {
         testutil::init();
@@ -0,0 +1,278 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Share links: `/api/shares` and `/api/shares/<token>/clip.mp4`.
+//!
+//! A share link allows unauthenticated download of a single clip until it expires. Nothing is
+//! stored server-side; the token itself names the clip and carries an HMAC-SHA256 signature
+//! made with the key from the config file's `shareLinkKeyPath`. Consequently, individual links
+//! can't be revoked; changing the key revokes all of them.
+
+use std::ops::Range;
+use std::path::Path;
+
+use base::{bail, err, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use db::recording;
+use http::{Method, Request, StatusCode};
+use ring::hmac;
+use uuid::Uuid;
+
+use crate::json;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+
+const KEY_LEN: usize = 32;
+
+/// The format version, the first byte of each token.
+const VERSION: u8 = 1;
+
+/// The length of a token's signed portion: version, camera uuid, stream type, start time, end
+/// time, and expiration time.
+const PAYLOAD_LEN: usize = 1 + 16 + 1 + 8 + 8 + 8;
+
+const DEFAULT_EXPIRES_IN_SEC: i64 = 7 * 24 * 60 * 60;
+const MAX_EXPIRES_IN_SEC: i64 = 90 * 24 * 60 * 60;
+
+/// The key with which share links are signed.
+#[derive(Clone)]
+pub struct Key(hmac::Key);
+
+impl Key {
+    /// Creates a key from its 32 raw bytes.
+    pub fn new(raw: &[u8]) -> Result<Self, Error> {
+        if raw.len() != KEY_LEN {
+            bail!(
+                InvalidArgument,
+                msg("share link key must be {KEY_LEN} bytes; got {}", raw.len())
+            );
+        }
+        Ok(Key(hmac::Key::new(hmac::HMAC_SHA256, raw)))
+    }
+
+    /// Reads a key from a file holding exactly 32 random bytes, as created by
+    /// `head -c 32 /dev/urandom`.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let raw = std::fs::read(path)
+            .map_err(|e| err!(e, msg("unable to read share link key {}", path.display())))?;
+        Self::new(&raw).map_err(|e| err!(e, msg("bad share link key {}", path.display())))
+    }
+}
+
+/// The clip named by a share link.
+#[derive(Debug, Eq, PartialEq)]
+struct Share {
+    camera_uuid: Uuid,
+    stream_type: db::StreamType,
+    range: Range<recording::Time>,
+    expires_sec: i64,
+}
+
+impl Share {
+    fn encode(&self, key: &Key) -> String {
+        let mut buf = Vec::with_capacity(PAYLOAD_LEN + ring::digest::SHA256_OUTPUT_LEN);
+        buf.push(VERSION);
+        buf.extend_from_slice(self.camera_uuid.as_bytes());
+        buf.push(self.stream_type.index() as u8);
+        buf.extend_from_slice(&self.range.start.0.to_be_bytes());
+        buf.extend_from_slice(&self.range.end.0.to_be_bytes());
+        buf.extend_from_slice(&self.expires_sec.to_be_bytes());
+        let tag = hmac::sign(&key.0, &buf);
+        buf.extend_from_slice(tag.as_ref());
+        URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    /// Decodes a token, verifying its signature but not its expiration.
+    fn decode(key: &Key, token: &str) -> Result<Self, Error> {
+        let bad = || err!(PermissionDenied, msg("invalid share link"));
+        let buf = URL_SAFE_NO_PAD.decode(token).map_err(|_| bad())?;
+        if buf.len() <= PAYLOAD_LEN || buf[0] != VERSION {
+            return Err(bad());
+        }
+        let (payload, tag) = buf.split_at(PAYLOAD_LEN);
+        hmac::verify(&key.0, payload, tag).map_err(|_| bad())?;
+        let i64_at = |i: usize| i64::from_be_bytes(payload[i..i + 8].try_into().unwrap());
+        Ok(Share {
+            camera_uuid: Uuid::from_slice(&payload[1..17]).expect("uuid is 16 bytes"),
+            stream_type: db::StreamType::from_index(usize::from(payload[17])).ok_or_else(bad)?,
+            range: recording::Time(i64_at(18))..recording::Time(i64_at(26)),
+            expires_sec: i64_at(34),
+        })
+    }
+}
+
+impl Service {
+    fn share_key(&self) -> Result<&Key, Error> {
+        self.share_key.as_ref().ok_or_else(|| {
+            err!(
+                FailedPrecondition,
+                msg("share links are disabled; set shareLinkKeyPath in the config file")
+            )
+        })
+    }
+
+    pub(super) async fn shares(
+        &self,
+        mut req: Request<hyper::Body>,
+        authreq: &db::auth::Request,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostShare = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        if !caller.permissions.allows_camera(r.camera_uuid) {
+            bail!(
+                PermissionDenied,
+                msg("not permitted to access camera {}", r.camera_uuid)
+            );
+        }
+        let key = self.share_key()?;
+        let stream_type = db::StreamType::parse(r.stream)
+            .ok_or_else(|| err!(InvalidArgument, msg("no such stream type {}", r.stream)))?;
+        if r.start_time_90k >= r.end_time_90k {
+            bail!(
+                InvalidArgument,
+                msg("startTime90k must be before endTime90k")
+            );
+        }
+        let expires_in_sec = r.expires_in_sec.unwrap_or(DEFAULT_EXPIRES_IN_SEC);
+        if !(1..=MAX_EXPIRES_IN_SEC).contains(&expires_in_sec) {
+            bail!(
+                InvalidArgument,
+                msg("expiresInSec must be between 1 and {MAX_EXPIRES_IN_SEC}")
+            );
+        }
+        let now = authreq
+            .when_sec
+            .ok_or_else(|| err!(Internal, msg("request has no time")))?;
+        let share = Share {
+            camera_uuid: r.camera_uuid,
+            stream_type,
+            range: r.start_time_90k..r.end_time_90k,
+            expires_sec: now + expires_in_sec,
+        };
+
+        // Fail now rather than hand out a link that can never work.
+        self.build_export(share.camera_uuid, share.stream_type, share.range.clone())?;
+        serve_json(
+            &req,
+            &json::PostShareResponse {
+                url: format!("/api/shares/{}/clip.mp4", share.encode(key)),
+                expires_sec: share.expires_sec,
+            },
+        )
+    }
+
+    pub(super) fn share_download(
+        &self,
+        req: &Request<hyper::Body>,
+        authreq: &db::auth::Request,
+        token: &str,
+    ) -> ResponseResult {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        let share = Share::decode(self.share_key()?, token)?;
+        let now = authreq
+            .when_sec
+            .ok_or_else(|| err!(Internal, msg("request has no time")))?;
+        if now >= share.expires_sec {
+            bail!(PermissionDenied, msg("share link has expired"));
+        }
+        let mp4 = self.build_export(share.camera_uuid, share.stream_type, share.range)?;
+        Ok(http_serve::serve(mp4, req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::ErrorKind;
+    use db::testutil;
+
+    use crate::web::tests::Server;
+
+    #[test]
+    fn round_trip() {
+        let key = Key::new(&[0u8; KEY_LEN]).unwrap();
+        let share = Share {
+            camera_uuid: Uuid::parse_str("35144640-ff1e-4619-b0d5-4c74c185741c").unwrap(),
+            stream_type: db::StreamType::Sub,
+            range: recording::Time(90_000)..recording::Time(180_000),
+            expires_sec: 1_700_000_000,
+        };
+        let token = share.encode(&key);
+        assert_eq!(Share::decode(&key, &token).unwrap(), share);
+
+        // Another key's signature isn't accepted.
+        let other = Key::new(&[1u8; KEY_LEN]).unwrap();
+        let e = Share::decode(&other, &token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+
+        // Nor is a modified token.
+        let mut buf = URL_SAFE_NO_PAD.decode(&token).unwrap();
+        buf[PAYLOAD_LEN - 1] ^= 1;
+        let e = Share::decode(&key, &URL_SAFE_NO_PAD.encode(buf)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        Share::decode(&key, "garbage").unwrap_err();
+        Key::new(&[0u8; 16]).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn share_link() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        testutil::add_dummy_recordings_to_db(&s.db.db, 1);
+        let start = 1430006400 * recording::TIME_UNITS_PER_SEC;
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/shares", &s.base_url))
+            .json(&serde_json::json!({
+                "cameraUuid": s.db.test_camera_uuid,
+                "stream": "main",
+                "startTime90k": start,
+                "endTime90k": start + 90_000,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let url = resp["url"].as_str().unwrap();
+        assert!(url.starts_with("/api/shares/"));
+
+        let resp = cli
+            .head(&format!("{}{url}", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let content_type = resp.headers().get(reqwest::header::CONTENT_TYPE).unwrap();
+        assert!(content_type.to_str().unwrap().starts_with("video/mp4"));
+
+        let resp = cli
+            .head(&format!("{}/api/shares/garbage/clip.mp4", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
@@ -5,9 +5,11 @@
 //! `/api/signals` handling.
 
 use base::{bail, clock::Clocks, err};
-use db::recording;
+use db::json::{SignalTypeConfig, SignalTypeValueConfig};
+use db::recording::{self, TIME_UNITS_PER_SEC};
 use http::{Method, Request, StatusCode};
 use url::form_urlencoded;
+use uuid::Uuid;
 
 use crate::json;
 
@@ -18,6 +20,9 @@ use super::{
 
 use std::borrow::Borrow;
 
+/// How long the last state of a `POST /api/signals/<id>/events` request holds by default.
+const DEFAULT_EVENT_HOLD_SEC: u32 = 60;
+
 impl Service {
     pub(super) async fn signals(
         &self,
@@ -41,6 +46,16 @@ impl Service {
         let r = extract_json_body(&mut req).await?;
         let r: json::PostSignalsRequest = parse_json_body(&r)?;
         require_csrf_if_session(&caller, r.csrf)?;
+        if let Some(&id) = r
+            .signal_ids
+            .iter()
+            .find(|&&id| !caller.permissions.allows_signal(id))
+        {
+            bail!(
+                PermissionDenied,
+                msg("update_signals on signal {id} required")
+            );
+        }
         let now = recording::Time::new(self.db.clocks().realtime());
         let mut l = self.db.lock();
         let start = match r.start {
@@ -55,6 +70,45 @@ impl Service {
         serve_json(&req, &json::PostSignalsResponse { time_90k: now })
     }
 
+    pub(super) async fn signal_events(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: u32,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.update_signals || !caller.permissions.allows_signal(id) {
+            bail!(
+                PermissionDenied,
+                msg("update_signals on signal {id} required")
+            );
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostSignalEvents = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let now = recording::Time::new(self.db.clocks().realtime());
+        let changes: Vec<_> = r
+            .events
+            .iter()
+            .map(|e| (e.time_90k.unwrap_or(now), e.state))
+            .collect();
+        let Some(&(last, _)) = changes.last() else {
+            bail!(InvalidArgument, msg("at least one event must be specified"));
+        };
+        let hold = recording::Duration(
+            i64::from(r.hold_sec.unwrap_or(DEFAULT_EVENT_HOLD_SEC)) * TIME_UNITS_PER_SEC,
+        );
+        self.db
+            .lock()
+            .update_signal_changes(id, &changes, last + hold)?;
+        serve_json(&req, &json::PostSignalsResponse { time_90k: now })
+    }
+
     fn get_signals(&self, req: &Request<hyper::Body>) -> ResponseResult {
         let mut time = recording::Time::min_value()..recording::Time::max_value();
         if let Some(q) = req.uri().query() {
@@ -84,4 +138,243 @@ impl Service {
             });
         serve_json(req, &signals)
     }
+
+    pub(super) fn signal_types(&self, req: &Request<hyper::Body>) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET or HEAD expected",
+            ));
+        }
+        let l = self.db.lock();
+        let mut signal_types: Vec<_> = l
+            .signal_types_by_uuid()
+            .iter()
+            .map(|(u, t)| json::SignalType::wrap(*u, t))
+            .collect();
+        signal_types.sort_unstable_by_key(|t| t.uuid);
+        serve_json(req, &json::SignalTypes { signal_types })
+    }
+
+    pub(super) async fn signal_type(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::PUT => self.put_signal_type(req, caller, uuid).await,
+            Method::DELETE => self.delete_signal_type(req, caller, uuid).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "PUT or DELETE expected",
+            )),
+        }
+    }
+
+    async fn put_signal_type(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs || !caller.permissions.cameras.is_empty() {
+            bail!(
+                PermissionDenied,
+                msg("update_camera_configs for all cameras required")
+            );
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PutSignalType = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut l = self.db.lock();
+
+        // Keep any properties this version doesn't understand.
+        let old = l.signal_types_by_uuid().get(&uuid).map(|t| &t.config);
+        let mut config = SignalTypeConfig {
+            name: r.name,
+            color: r.color,
+            unknown: old.map(|c| c.unknown.clone()).unwrap_or_default(),
+            ..Default::default()
+        };
+        for s in r.states {
+            if s.value == 0 || s.value >= 16 {
+                bail!(
+                    InvalidArgument,
+                    msg("state value {} out of accepted range [1, 16)", s.value)
+                );
+            }
+            if s.name.is_empty() {
+                bail!(InvalidArgument, msg("state {} must have a name", s.value));
+            }
+            let unknown = old
+                .and_then(|c| c.values.get(&s.value))
+                .map(|v| v.unknown.clone())
+                .unwrap_or_default();
+            let value = SignalTypeValueConfig {
+                name: s.name,
+                motion: s.motion,
+                color: s.color,
+                unknown,
+            };
+            if config.values.insert(s.value, value).is_some() {
+                bail!(InvalidArgument, msg("duplicate state {}", s.value));
+            }
+        }
+        l.put_signal_type(uuid, config)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+
+    async fn delete_signal_type(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+    ) -> ResponseResult {
+        if !caller.permissions.update_camera_configs || !caller.permissions.cameras.is_empty() {
+            bail!(
+                PermissionDenied,
+                msg("update_camera_configs for all cameras required")
+            );
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteSignalType = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        self.db.lock().delete_signal_type(uuid)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn put_and_delete_type() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let type_url = format!(
+            "{}/api/signals/types/ee66270f-d9c6-4819-8b33-9720d4cbca6b",
+            &s.base_url
+        );
+        let resp = cli
+            .put(&type_url)
+            .json(&serde_json::json!({
+                "name": "motion",
+                "color": "#888888",
+                "states": [
+                    { "value": 1, "name": "still" },
+                    { "value": 2, "name": "moving", "motion": true, "color": "#ff0000" },
+                ],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let resp = cli
+            .get(&format!("{}/api/signals/types", &s.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "signalTypes": [{
+                    "uuid": "ee66270f-d9c6-4819-8b33-9720d4cbca6b",
+                    "name": "motion",
+                    "color": "#888888",
+                    "states": [
+                        { "value": 1, "name": "still", "color": "" },
+                        { "value": 2, "name": "moving", "motion": true, "color": "#ff0000" },
+                    ],
+                }],
+            })
+        );
+
+        // Duplicate and out-of-range states are rejected.
+        for states in [
+            serde_json::json!([{ "value": 1, "name": "a" }, { "value": 1, "name": "b" }]),
+            serde_json::json!([{ "value": 16, "name": "a" }]),
+        ] {
+            let resp = cli
+                .put(&type_url)
+                .json(&serde_json::json!({ "states": states }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+
+        let resp = cli
+            .delete(&type_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(s.db.db.lock().signal_types_by_uuid().is_empty());
+        let resp = cli
+            .delete(&type_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn signal_events() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.update_signals = true;
+        permissions.signals.push(1);
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let events = serde_json::json!({
+            "events": [
+                { "time90k": 140067462600000_i64, "state": 2 },
+                { "state": 1 },
+            ],
+        });
+
+        // The token's scope doesn't include signal 2.
+        let resp = cli
+            .post(&format!("{}/api/signals/2/events", &s.base_url))
+            .json(&events)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        let resp = cli
+            .post(&format!("{}/api/signals", &s.base_url))
+            .json(&serde_json::json!({
+                "signalIds": [1, 2],
+                "states": [1, 1],
+                "start": { "base": "now", "rel90k": 0 },
+                "end": { "base": "now", "rel90k": 5400000 },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // Signal 1 is in scope but doesn't exist in the test database.
+        let url = format!("{}/api/signals/1/events", &s.base_url);
+        let resp = cli.post(&url).json(&events).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = cli
+            .post(&url)
+            .json(&serde_json::json!({ "events": [] }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
 }
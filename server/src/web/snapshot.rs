@@ -0,0 +1,200 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/<type>/snapshot.jpg` handling.
+
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use bytes::Bytes;
+use db::recording::{self, rescale};
+use futures::TryStreamExt;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{Caller, ResponseResult, Service};
+
+impl Service {
+    pub(super) async fn stream_snapshot(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let mut time = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "time90k" => {
+                        time = Some(
+                            recording::Time::parse(value)
+                                .map_err(|_| err!(InvalidArgument, msg("unparseable time90k")))?,
+                        )
+                    }
+                    _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+                }
+            }
+        }
+
+        let (entry, source) = self.snapshot_source(uuid, stream_type, time)?;
+        let data = match source {
+            Source::Frame(data) => data.to_vec(),
+            Source::File(dir_id, id, range, key) => {
+                let dir = self
+                    .dirs_by_id()
+                    .get(&dir_id)
+                    .cloned()
+                    .ok_or_else(|| err!(NotFound, msg("{id}: dir {dir_id} not found")))?;
+                dir.open_file(id, range, key).try_concat().await?
+            }
+        };
+
+        let jpeg =
+            tokio::task::spawn_blocking(move || crate::jpeg::encode_key_frame(&entry, &data))
+                .await
+                .map_err(|e| err!(Internal, msg("snapshot task failed"), source(e)))??;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .expect("hardcoded head should be valid"))
+    }
+
+    /// Finds the key frame to decode: the latest received if `time` is `None`, or otherwise the
+    /// recorded key frame nearest `time`.
+    fn snapshot_source(
+        &self,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+        time: Option<recording::Time>,
+    ) -> Result<(Arc<db::VideoSampleEntry>, Source), Error> {
+        let db = self.db.lock();
+        let camera = db
+            .get_camera(uuid)
+            .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+        let stream_id = camera.streams[stream_type.index()]
+            .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+        let (video_sample_entry_id, source) = match time {
+            None => {
+                let frame = self
+                    .live_frames
+                    .latest_key_frame(stream_id)
+                    .ok_or_else(|| {
+                        err!(
+                            NotFound,
+                            msg("no key frame received from {uuid}/{stream_type} yet")
+                        )
+                    })?;
+                (
+                    frame.video_sample_entry_id,
+                    Source::Frame(frame.data.clone()),
+                )
+            }
+            Some(t) => {
+                let mut row = None;
+                db.list_recordings_by_time(stream_id, t..t + recording::Duration(1), &mut |r| {
+                    row.get_or_insert(r);
+                    Ok(())
+                })?;
+                let row = row.ok_or_else(|| {
+                    err!(NotFound, msg("no recording of {uuid}/{stream_type} at {t}"))
+                })?;
+                let rel_wall_90k = i32::try_from((t - row.start).0)
+                    .unwrap_or(0)
+                    .clamp(0, row.wall_duration_90k);
+                let rel_media_90k =
+                    rescale(rel_wall_90k, row.wall_duration_90k, row.media_duration_90k);
+                let range = db.with_recording_playback(row.id, &mut |playback| {
+                    nearest_key_frame(&playback.video_index, rel_media_90k)
+                })?;
+                let dir_id = row
+                    .sample_file_dir_id
+                    .or_else(|| db.streams_by_id().get(&stream_id)?.sample_file_dir_id)
+                    .ok_or_else(|| err!(Internal, msg("no dir for recording {}", row.id)))?;
+                let key = db.recording_key(row.id)?;
+                (
+                    row.video_sample_entry_id,
+                    Source::File(dir_id, row.id, range, key),
+                )
+            }
+        };
+        let entry = db
+            .video_sample_entries_by_id()
+            .get(&video_sample_entry_id)
+            .ok_or_else(|| err!(Internal, msg("no such video sample entry")))?
+            .clone();
+        Ok((entry, source))
+    }
+}
+
+/// Where to find the key frame for a snapshot.
+enum Source {
+    /// A frame received from the camera, as kept by `LiveFrames`.
+    Frame(Bytes),
+
+    /// A frame within a sample file: the sample file dir id, recording id, byte range, and key
+    /// (if the file is encrypted).
+    File(
+        i32,
+        db::CompositeId,
+        Range<u64>,
+        Option<Arc<db::dir::crypto::Key>>,
+    ),
+}
+
+/// Returns the sample file byte range of the key frame whose start is nearest `rel_media_90k`.
+fn nearest_key_frame(video_index: &[u8], rel_media_90k: i32) -> Result<Range<u64>, Error> {
+    let mut it = recording::SampleIndexIterator::default();
+    let mut best: Option<(i32, Range<u64>)> = None;
+    while it.next(video_index)? {
+        if !it.is_key() {
+            continue;
+        }
+        let distance = (it.start_90k - rel_media_90k).abs();
+        if best.as_ref().map(|(d, _)| distance < *d).unwrap_or(true) {
+            let pos = u64::try_from(it.pos).unwrap();
+            best = Some((distance, pos..pos + u64::try_from(it.bytes).unwrap()));
+        }
+        if it.start_90k >= rel_media_90k {
+            break; // later key frames are only farther away.
+        }
+    }
+    best.map(|(_, r)| r)
+        .ok_or_else(|| err!(Internal, msg("recording has no key frames")))
+}
+
+#[cfg(test)]
+mod tests {
+    use db::{recording, testutil};
+
+    #[test]
+    fn nearest_key_frame() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::default();
+        encoder.add_sample(10, 100, true, &mut r); // [0, 10), bytes [0, 100)
+        encoder.add_sample(10, 5, false, &mut r); // [10, 20), bytes [100, 105)
+        encoder.add_sample(10, 50, true, &mut r); // [20, 30), bytes [105, 155)
+        encoder.add_sample(10, 5, false, &mut r); // [30, 40), bytes [155, 160)
+        assert_eq!(super::nearest_key_frame(&r.video_index, 0).unwrap(), 0..100);
+        assert_eq!(super::nearest_key_frame(&r.video_index, 9).unwrap(), 0..100);
+        assert_eq!(
+            super::nearest_key_frame(&r.video_index, 11).unwrap(),
+            105..155
+        );
+        assert_eq!(
+            super::nearest_key_frame(&r.video_index, 39).unwrap(),
+            105..155
+        );
+    }
+}
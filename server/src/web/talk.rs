@@ -0,0 +1,91 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/talk` WebSocket handling: relays audio from the browser to the camera's
+//! ONVIF audio backchannel.
+
+use std::sync::Arc;
+
+use base::{bail, err, Error};
+use futures::StreamExt;
+use tokio_tungstenite::{tungstenite, WebSocketStream};
+use uuid::Uuid;
+
+use crate::onvif::backchannel::Backchannel;
+
+use super::{Caller, Service};
+
+impl Service {
+    pub(super) async fn camera_talk(
+        self: Arc<Self>,
+        ws: &mut WebSocketStream<hyper::upgrade::Upgraded>,
+        caller: Result<Caller, Error>,
+        uuid: Uuid,
+    ) -> Result<(), Error> {
+        let caller = caller?;
+        if !caller.permissions.talk {
+            bail!(PermissionDenied, msg("talk required"));
+        }
+        let (url, username, password, tls) = {
+            let db = self.db.lock();
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+            let stream_id = camera.streams[db::StreamType::Main.index()]
+                .ok_or_else(|| err!(NotFound, msg("camera {uuid} has no main stream")))?;
+            let url = db.streams_by_id()[&stream_id]
+                .config
+                .url
+                .clone()
+                .ok_or_else(|| {
+                    err!(
+                        FailedPrecondition,
+                        msg("main stream of camera {uuid} has no RTSP URL")
+                    )
+                })?;
+            let tls = if url.scheme() == "rtsps" {
+                Some(crate::rtsps::client_config(&camera.config)?)
+            } else {
+                None
+            };
+            (
+                url,
+                camera.config.username.clone(),
+                camera.config.password.clone(),
+                tls,
+            )
+        };
+        let mut bc = Backchannel::open(&url, &username, &password, tls)
+            .await
+            .map_err(|e| err!(e, msg("unable to open audio backchannel of camera {uuid}")))?;
+        let interval = bc.keepalive_interval();
+        let mut keepalive =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let mut pcm = Vec::new();
+        loop {
+            tokio::select! {
+                m = ws.next() => match m {
+                    Some(Ok(tungstenite::Message::Binary(b))) => {
+                        if b.len() % 2 != 0 {
+                            bail!(
+                                InvalidArgument,
+                                msg("audio message has odd length {}", b.len())
+                            );
+                        }
+                        pcm.clear();
+                        pcm.extend(b.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]])));
+                        bc.send(&pcm).await?;
+                    }
+                    Some(Ok(tungstenite::Message::Text(_))) => {
+                        bail!(InvalidArgument, msg("expected binary audio messages"));
+                    }
+                    Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {} // ping or pong.
+                },
+                _ = keepalive.tick() => bc.keepalive().await?,
+            }
+        }
+        bc.close().await
+    }
+}
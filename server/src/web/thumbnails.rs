@@ -0,0 +1,226 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! `/api/cameras/<uuid>/<type>/thumbnails{.jpg}` handling.
+
+use std::borrow::Borrow;
+use std::ops::Range;
+
+use base::{bail, err, Error};
+use db::recording;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{serve_json, Caller, ResponseResult, Service};
+use crate::json;
+
+/// The number of tiles in each row of a sprite sheet.
+const SPRITE_COLUMNS: usize = 10;
+
+/// The maximum number of tiles in a sprite sheet.
+const SPRITE_MAX_TILES: usize = 100;
+
+impl Service {
+    pub(super) fn stream_thumbnails(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let time = parse_time_range(req)?;
+        let db = self.db.lock();
+        let stream_id = stream_id(&db, uuid, stream_type)?;
+        let mut thumbnails = Vec::new();
+        db.list_thumbnails(stream_id, time, &mut |row| {
+            thumbnails.push(row);
+            Ok(())
+        })?;
+        drop(db);
+        serve_json(
+            req,
+            &json::ListThumbnails {
+                columns: SPRITE_COLUMNS,
+                sprites: sprites(&thumbnails),
+            },
+        )
+    }
+
+    pub(super) async fn stream_thumbnail_sprite(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let time = parse_time_range(req)?;
+        let mut tile_size = None;
+        let mut tiles = Vec::new();
+        {
+            let db = self.db.lock();
+            let stream_id = stream_id(&db, uuid, stream_type)?;
+            db.list_thumbnails_with_data(stream_id, time, &mut |row, data| {
+                if tiles.len() == SPRITE_MAX_TILES {
+                    bail!(
+                        InvalidArgument,
+                        msg("more than {SPRITE_MAX_TILES} thumbnails in range")
+                    );
+                }
+                tile_size.get_or_insert((row.width, row.height));
+                tiles.push(data.to_owned());
+                Ok(())
+            })?;
+        }
+        let Some((tile_width, tile_height)) = tile_size else {
+            bail!(NotFound, msg("no thumbnails in range"));
+        };
+        let jpeg = tokio::task::spawn_blocking(move || {
+            let tiles: Vec<&[u8]> = tiles.iter().map(|t| &t[..]).collect();
+            crate::jpeg::encode_sprite(&tiles, SPRITE_COLUMNS, tile_width, tile_height)
+        })
+        .await
+        .map_err(|e| err!(Internal, msg("sprite task failed"), source(e)))??;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"))
+            .body(jpeg.into())
+            .expect("hardcoded head should be valid"))
+    }
+}
+
+fn stream_id(
+    db: &db::LockedDatabase,
+    uuid: Uuid,
+    stream_type: db::StreamType,
+) -> Result<i32, Error> {
+    let Some(camera) = db.get_camera(uuid) else {
+        bail!(NotFound, msg("no such camera {uuid}"));
+    };
+    let Some(stream_id) = camera.streams[stream_type.index()] else {
+        bail!(NotFound, msg("no such stream {uuid}/{stream_type}"));
+    };
+    Ok(stream_id)
+}
+
+fn parse_time_range(req: &Request<hyper::Body>) -> Result<Range<recording::Time>, Error> {
+    let mut time = recording::Time::min_value()..recording::Time::max_value();
+    if let Some(q) = req.uri().query() {
+        for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+            let (key, value) = (key.borrow(), value.borrow());
+            match key {
+                "startTime90k" => {
+                    time.start = recording::Time::parse(value)
+                        .map_err(|_| err!(InvalidArgument, msg("unparseable startTime90k")))?
+                }
+                "endTime90k" => {
+                    time.end = recording::Time::parse(value)
+                        .map_err(|_| err!(InvalidArgument, msg("unparseable endTime90k")))?
+                }
+                _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
+            }
+        }
+    }
+    Ok(time)
+}
+
+/// Groups the given thumbnails (in ascending order by time) into sprite sheets, each with at
+/// most `SPRITE_MAX_TILES` tiles of a single size.
+fn sprites(thumbnails: &[db::ListThumbnailsRow]) -> Vec<json::ThumbnailSprite> {
+    let mut sprites: Vec<json::ThumbnailSprite> = Vec::new();
+    for t in thumbnails {
+        let fits = sprites.last().map_or(false, |s| {
+            s.times_90k.len() < SPRITE_MAX_TILES
+                && (s.tile_width, s.tile_height) == (t.width, t.height)
+        });
+        if !fits {
+            sprites.push(json::ThumbnailSprite {
+                start_time_90k: t.start.0,
+                end_time_90k: t.start.0,
+                tile_width: t.width,
+                tile_height: t.height,
+                times_90k: Vec::new(),
+            });
+        }
+        let s = sprites.last_mut().unwrap();
+        s.end_time_90k = t.start.0 + 1;
+        s.times_90k.push(t.start.0);
+    }
+    sprites
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::recording::Time;
+    use db::testutil;
+
+    #[test]
+    fn sprites() {
+        testutil::init();
+        let row = |t, height| db::ListThumbnailsRow {
+            start: Time(t),
+            width: 160,
+            height,
+        };
+        let mut thumbnails: Vec<_> = (0..150).map(|i| row(i * 10, 90)).collect();
+        thumbnails.push(row(1500, 120));
+        let sprites = super::sprites(&thumbnails);
+        let summary: Vec<_> = sprites
+            .iter()
+            .map(|s| {
+                (
+                    s.start_time_90k,
+                    s.end_time_90k,
+                    s.tile_height,
+                    s.times_90k.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            &[
+                (0, 991, 90, 100),
+                (1000, 1491, 90, 50),
+                (1500, 1501, 120, 1)
+            ]
+        );
+        assert_eq!(&sprites[1].times_90k[..2], &[1000, 1010]);
+        assert!(super::sprites(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn requires_view_video() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.read_camera_configs = true;
+        let s = Server::new(Some(permissions));
+        for suffix in ["thumbnails", "thumbnails.jpg"] {
+            let resp = reqwest::get(&format!(
+                "{}/api/cameras/{}/main/{suffix}",
+                &s.base_url, s.db.test_camera_uuid
+            ))
+            .await
+            .unwrap();
+            assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN, "{suffix}");
+        }
+
+        let mut permissions = db::Permissions::new();
+        permissions.view_video = true;
+        let s = Server::new(Some(permissions));
+        let resp = reqwest::get(&format!(
+            "{}/api/cameras/{}/main/thumbnails",
+            &s.base_url, s.db.test_camera_uuid
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    }
+}
@@ -0,0 +1,127 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Native TLS (HTTPS) support, as configured by `moonfire-nvr run --tls-cert --tls-key`.
+
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use base::{bail, err, Error};
+use tokio_rustls::rustls;
+
+/// The TLS certificate and key, which can be reloaded without restarting.
+pub struct Tls {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    config: Mutex<Arc<rustls::ServerConfig>>,
+}
+
+impl Tls {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Error> {
+        let config = Mutex::new(Arc::new(load(&cert_path, &key_path)?));
+        Ok(Tls {
+            cert_path,
+            key_path,
+            config,
+        })
+    }
+
+    /// Rereads the certificate and key, as when a renewed certificate has been installed.
+    ///
+    /// On failure, the previous certificate remains in use. Established connections are
+    /// unaffected either way.
+    pub fn reload(&self) -> Result<(), Error> {
+        let config = load(&self.cert_path, &self.key_path)?;
+        *self.config.lock().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    pub(super) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        tokio_rustls::TlsAcceptor::from(self.config.lock().unwrap().clone())
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<std::fs::File>, Error> {
+    let f =
+        std::fs::File::open(path).map_err(|e| err!(e, msg("unable to open {}", path.display())))?;
+    Ok(BufReader::new(f))
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<rustls::ServerConfig, Error> {
+    let certs: Vec<_> = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|e| err!(e, msg("unable to read {}", cert_path.display())))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!(
+            InvalidArgument,
+            msg("no certificates in {}", cert_path.display())
+        );
+    }
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|e| err!(e, msg("unable to read {}", key_path.display())))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(rustls::PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            err!(
+                InvalidArgument,
+                msg("no private key in {}", key_path.display())
+            )
+        })?;
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg(
+                    "bad TLS certificate {} or key {}",
+                    cert_path.display(),
+                    key_path.display()
+                ),
+                source(e)
+            )
+        })?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_and_reload() {
+        let tmpdir = tempfile::Builder::new()
+            .prefix("moonfire-nvr-test")
+            .tempdir()
+            .unwrap();
+        let cert_path = tmpdir.path().join("cert.pem");
+        let key_path = tmpdir.path().join("key.pem");
+        std::fs::copy("src/testdata/tls-cert.pem", &cert_path).unwrap();
+        std::fs::copy("src/testdata/tls-key.pem", &key_path).unwrap();
+        let tls = Tls::new(cert_path.clone(), key_path.clone()).unwrap();
+        tls.acceptor();
+
+        // A bad key fails to load, leaving the previous one in place.
+        std::fs::write(&key_path, b"").unwrap();
+        let e = tls.reload().unwrap_err();
+        assert!(e.to_string().contains("no private key"), "{e}");
+
+        // The cert alone isn't a key either.
+        std::fs::copy("src/testdata/tls-cert.pem", &key_path).unwrap();
+        tls.reload().unwrap_err();
+
+        std::fs::copy("src/testdata/tls-key.pem", &key_path).unwrap();
+        tls.reload().unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! API token management: `/api/tokens/` and `/api/tokens/<id>`.
+
+use base::{bail, err};
+use db::auth;
+use http::{Method, Request, StatusCode};
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+use crate::json;
+
+impl Service {
+    pub(super) async fn tokens(
+        &self,
+        req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_tokens(&req, caller),
+            Method::POST => self.post_tokens(req, authreq, caller).await,
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or POST expected",
+            )),
+        }
+    }
+
+    /// Lists the caller's own tokens, or all tokens if the caller has `admin_users`.
+    fn get_tokens(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        let caller_id = caller.user.as_ref().map(|u| u.id);
+        let l = self.db.lock();
+        let tokens = l
+            .api_tokens_by_id()
+            .values()
+            .filter(|t| caller.permissions.admin_users || Some(t.user_id) == caller_id)
+            .map(json::Token::wrap)
+            .collect();
+        serve_json(req, &json::GetTokensResponse { tokens })
+    }
+
+    async fn post_tokens(
+        &self,
+        mut req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+    ) -> ResponseResult {
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostTokens = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let caller_id = caller.user.as_ref().map(|u| u.id);
+        let Some(user_id) = r.user_id.or(caller_id) else {
+            bail!(InvalidArgument, msg("userId must be specified"));
+        };
+        let permissions = db::Permissions::from(r.permissions);
+        let mut l = self.db.lock();
+
+        // A token can't do anything its creator couldn't. When creating a token for
+        // another user (such as a service account), that's bounded by the user's own permissions.
+        let available = if Some(user_id) == caller_id {
+            &caller.permissions
+        } else if caller.permissions.admin_users {
+            &l.users_by_id()
+                .get(&user_id)
+                .ok_or_else(|| err!(NotFound, msg("no such user {user_id}")))?
+                .permissions
+        } else {
+            bail!(
+                PermissionDenied,
+                msg("admin_users required to create tokens for another user")
+            );
+        };
+        if !permissions.is_subset_of(available) {
+            bail!(
+                PermissionDenied,
+                msg("token permissions must be a subset of the user's")
+            );
+        }
+        let now = authreq
+            .when_sec
+            .ok_or_else(|| err!(Internal, msg("request has no time")))?;
+        let (token, t) = l.make_api_token(
+            user_id,
+            r.description,
+            now,
+            r.expiration_time_sec,
+            permissions,
+        )?;
+        serve_json(
+            &req,
+            &json::PostTokensResponse {
+                id: t.id,
+                token: token.encode_base64(),
+            },
+        )
+    }
+
+    pub(super) async fn token(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::DELETE {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteToken = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut l = self.db.lock();
+        let Some(t) = l.api_tokens_by_id().get(&id) else {
+            bail!(NotFound, msg("no such token {id}"));
+        };
+        if !caller.permissions.admin_users && caller.user.as_ref().map(|u| u.id) != Some(t.user_id)
+        {
+            bail!(NotFound, msg("no such token {id}"));
+        }
+        l.delete_api_token(id)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn create_use_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let uid = {
+            let mut l = s.db.db.lock();
+            let mut c = l.get_user("slamb").unwrap().change();
+            c.permissions.view_video = true;
+            l.apply_user_change(c).unwrap().id
+        };
+        let cli = reqwest::Client::new();
+
+        // The token can't have permissions its user lacks.
+        let resp = cli
+            .post(&format!("{}/api/tokens/", &s.base_url))
+            .json(&serde_json::json!({
+                "userId": uid,
+                "permissions": { "viewVideo": true, "updateSignals": true },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let resp = cli
+            .post(&format!("{}/api/tokens/", &s.base_url))
+            .json(&serde_json::json!({
+                "userId": uid,
+                "description": "script",
+                "permissions": { "viewVideo": true },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let id = resp["id"].as_i64().unwrap();
+        let token = resp["token"].as_str().unwrap().to_owned();
+
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(resp["user"]["name"], "slamb");
+        assert_eq!(resp["permissions"]["viewVideo"], true);
+
+        let resp = cli
+            .delete(&format!("{}/api/tokens/{}", &s.base_url, id))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let resp = cli
+            .get(&format!("{}/api/", &s.base_url))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+}
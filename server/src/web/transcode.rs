@@ -0,0 +1,195 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! On-the-fly transcoding of `view.mp4` for clients which can't decode the recorded codec.
+//!
+//! Clients list the codecs they can decode in the `accept_codecs` query parameter. When some
+//! requested recording's codec isn't among them, the `.mp4` is piped through an `ffmpeg` child
+//! process which re-encodes the video as H.264 and streams back a fragmented `.mp4`. Unlike the
+//! usual response, this has no etag and no support for range requests.
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use base::err;
+use futures::StreamExt;
+use http::{header, Response, StatusCode};
+use http_serve::Entity;
+use hyper::body::Buf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::body::{wrap_error, Body, BodyStream, BoxedError, Chunk};
+use crate::cmds::run::config::TranscodeConfig;
+use crate::mp4;
+
+use super::ResponseResult;
+
+/// The RFC 6381 codec of transcoded video: H.264 High profile, level 4.0.
+pub(super) const OUTPUT_CODEC: &str = "avc1.640028";
+
+/// The size of each read from `ffmpeg`'s output.
+const CHUNK_BYTES: usize = 64 << 10;
+
+pub struct Transcoder {
+    ffmpeg_path: PathBuf,
+    video_bitrate_kbps: Option<u32>,
+
+    /// Limits the number of simultaneous `ffmpeg` processes to `maxConcurrent`.
+    permits: Arc<Semaphore>,
+}
+
+/// Parses the `accept_codecs` query parameter: a comma-separated list of RFC 6381 codecs.
+pub(super) fn parse_accept_codecs(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Returns true if `codec`, such as `hvc1.1.6.L93.B0`, is acceptable given `accept_codecs`.
+///
+/// Each entry either matches a codec exactly or is a prefix ending before a `.`, so `avc1`
+/// accepts any H.264 stream while `avc1.42E01E` accepts only Baseline profile, level 3.0.
+/// Comparisons are case-insensitive, as the hex digits in codec strings vary in case.
+pub(super) fn accepts(accept_codecs: &[String], codec: &str) -> bool {
+    let codec = codec.as_bytes();
+    accept_codecs.iter().any(|a| {
+        let a = a.as_bytes();
+        codec
+            .get(..a.len())
+            .map_or(false, |p| p.eq_ignore_ascii_case(a))
+            && matches!(codec.get(a.len()), None | Some(b'.'))
+    })
+}
+
+impl Transcoder {
+    pub fn new(config: &TranscodeConfig) -> Self {
+        Transcoder {
+            ffmpeg_path: config.ffmpeg_path.clone(),
+            video_bitrate_kbps: config.video_bitrate_kbps,
+            permits: Arc::new(Semaphore::new(config.max_concurrent)),
+        }
+    }
+
+    /// Serves `mp4` transcoded to [`OUTPUT_CODEC`].
+    ///
+    /// `ffmpeg` is killed when the response body is dropped, such as when the client disconnects.
+    pub(super) fn serve(&self, mp4: mp4::File) -> ResponseResult {
+        let permit = Arc::clone(&self.permits).try_acquire_owned().map_err(|_| {
+            err!(
+                ResourceExhausted,
+                msg("too many transcodes in progress; try again later")
+            )
+        })?;
+        let mut cmd = tokio::process::Command::new(&self.ffmpeg_path);
+        cmd.args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+            .args(["-map", "0:v:0", "-map", "0:a?"])
+            .args(["-c:v", "libx264", "-preset", "veryfast"])
+            .args(["-pix_fmt", "yuv420p"])
+            .args(["-profile:v", "high", "-level:v", "4.0"]);
+        if let Some(b) = self.video_bitrate_kbps {
+            cmd.arg("-b:v").arg(format!("{b}k"));
+        }
+        cmd.args(["-c:a", "copy", "-f", "mp4"])
+            .args(["-movflags", "frag_keyframe+empty_moov+default_base_moof"])
+            .arg("pipe:1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| err!(e, msg("unable to run {}", self.ffmpeg_path.display())))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        // Feed the `.mp4` to ffmpeg. Write errors just mean ffmpeg has exited, likely because
+        // the response was dropped; the body stream reports any failure.
+        tokio::spawn(async move {
+            let mut body = Pin::from(mp4.get_range(0..mp4.len()));
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(c) => c,
+                    Err(err) => {
+                        warn!(%err, "unable to read .mp4 for transcoding");
+                        return;
+                    }
+                };
+                if stdin.write_all(chunk.chunk()).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let body: BodyStream = Box::new(futures::stream::unfold(
+            Some(Output {
+                stdout,
+                child,
+                _permit: permit,
+            }),
+            |state| async move {
+                let mut state = state?;
+                let mut buf = vec![0; CHUNK_BYTES];
+                match state.stdout.read(&mut buf).await {
+                    Ok(0) => match state.child.wait().await {
+                        Ok(s) if s.success() => None,
+                        Ok(s) => Some((
+                            Err(wrap_error(err!(Unknown, msg("ffmpeg failed with {s}")))),
+                            None,
+                        )),
+                        Err(e) => Some((Err(Box::new(e) as BoxedError), None)),
+                    },
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(Chunk::from(buf)), Some(state)))
+                    }
+                    Err(e) => Some((Err(Box::new(e) as BoxedError), None)),
+                }
+            },
+        ));
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                header::CONTENT_TYPE,
+                format!("video/mp4; codecs=\"{OUTPUT_CODEC}\""),
+            )
+            .body(Body::from(body))
+            .expect("response is valid"))
+    }
+}
+
+/// The state of a transcode's response body.
+struct Output {
+    stdout: tokio::process::ChildStdout,
+    child: tokio::process::Child,
+    _permit: OwnedSemaphorePermit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts, parse_accept_codecs};
+
+    #[test]
+    fn accept_codecs() {
+        let a = parse_accept_codecs("avc1.42E01E, mp4a.40.2,,");
+        assert_eq!(a, ["avc1.42E01E", "mp4a.40.2"]);
+        assert!(accepts(&a, "avc1.42e01e"));
+        assert!(!accepts(&a, "avc1.640028"));
+        assert!(!accepts(&a, "avc1.42E01E0"));
+        assert!(!accepts(&a, "hvc1.1.6.L93.B0"));
+
+        let a = parse_accept_codecs("avc1,hvc1");
+        assert!(accepts(&a, "avc1.640028"));
+        assert!(accepts(&a, "hvc1.1.6.L93.B0"));
+        assert!(!accepts(&a, "hev1.1.6.L93.B0"));
+        assert!(!accepts(&parse_accept_codecs("avc"), "avc1.640028"));
+        assert!(!accepts(&[], "avc1.640028"));
+    }
+}
@@ -0,0 +1,246 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! Storage usage per stream per day or hour: `/api/usage`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use base::{bail, err, Error};
+use db::days::{self, StreamValue};
+use db::recording::{self, TIME_UNITS_PER_SEC};
+use http::{Method, Request};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use super::{serve_json, Caller, ResponseResult, Service};
+use crate::json;
+
+/// The longest range allowed with `granularity=hour`, as it requires scanning each recording.
+const MAX_HOURLY_RANGE: recording::Duration =
+    recording::Duration(31 * 24 * 60 * 60 * TIME_UNITS_PER_SEC);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Granularity {
+    Day,
+    Hour,
+}
+
+/// The parsed query parameters.
+#[derive(Debug, PartialEq)]
+struct Query {
+    cameras: Vec<Uuid>,
+    streams: Vec<db::StreamType>,
+    time: Range<recording::Time>,
+    granularity: Granularity,
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Result<Self, Error> {
+        let mut q = Query {
+            cameras: Vec::new(),
+            streams: Vec::new(),
+            time: recording::Time::min_value()..recording::Time::max_value(),
+            granularity: Granularity::Day,
+        };
+        if let Some(query) = query {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                let (key, value) = (key.borrow(), value.borrow());
+                let bad = || err!(InvalidArgument, msg("unparseable {key}"));
+                match key {
+                    "cameraUuid" => q.cameras.push(Uuid::parse_str(value).map_err(|_| bad())?),
+                    "stream" => q
+                        .streams
+                        .push(db::StreamType::parse(value).ok_or_else(bad)?),
+                    "startTime90k" => {
+                        q.time.start = recording::Time::parse(value).map_err(|_| bad())?
+                    }
+                    "endTime90k" => {
+                        q.time.end = recording::Time::parse(value).map_err(|_| bad())?
+                    }
+                    "granularity" => {
+                        q.granularity = match value {
+                            "day" => Granularity::Day,
+                            "hour" => Granularity::Hour,
+                            _ => return Err(bad()),
+                        }
+                    }
+                    _ => {}
+                };
+            }
+        }
+        if q.granularity == Granularity::Hour
+            && (q.time.start == recording::Time::min_value()
+                || q.time.end == recording::Time::max_value()
+                || q.time.end - q.time.start > MAX_HOURLY_RANGE)
+        {
+            bail!(
+                InvalidArgument,
+                msg("granularity=hour requires startTime90k and endTime90k at most 31 days apart")
+            );
+        }
+        Ok(q)
+    }
+}
+
+/// Adds the recording spanning `r` to the hours it overlaps, apportioning its bytes by duration.
+fn add_by_hour(
+    hours: &mut BTreeMap<recording::Time, StreamValue>,
+    r: Range<recording::Time>,
+    sample_file_bytes: i64,
+) {
+    let whole = (r.end - r.start).0;
+    let mut start = r.start;
+    let mut remaining_bytes = sample_file_bytes;
+    loop {
+        let hour = days::hour_containing(start);
+        let end = std::cmp::min(r.end, hour.end);
+        let bytes = if end == r.end {
+            remaining_bytes
+        } else {
+            days::apportion(sample_file_bytes, (end - start).0, whole)
+        };
+        remaining_bytes -= bytes;
+        let v = hours.entry(hour.start).or_default();
+        v.recordings += 1;
+        v.duration += end - start;
+        v.bytes += bytes;
+        if end == r.end {
+            return;
+        }
+        start = end;
+    }
+}
+
+impl Service {
+    pub(super) fn usage(&self, req: &Request<hyper::Body>, caller: Caller) -> ResponseResult {
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            bail!(InvalidArgument, msg("GET or HEAD expected"));
+        }
+        let q = Query::parse(req.uri().query())?;
+        let db = self.db.lock();
+        let mut out = json::Usage {
+            streams: Vec::new(),
+        };
+        for camera in db.cameras_by_id().values() {
+            if !caller.permissions.allows_camera(camera.uuid)
+                || (!q.cameras.is_empty() && !q.cameras.contains(&camera.uuid))
+            {
+                continue;
+            }
+            for (i, stream_id) in camera.streams.iter().enumerate() {
+                let (Some(stream_id), Some(type_)) = (*stream_id, db::StreamType::from_index(i))
+                else {
+                    continue;
+                };
+                if !q.streams.is_empty() && !q.streams.contains(&type_) {
+                    continue;
+                }
+                let mut intervals = Vec::new();
+                match q.granularity {
+                    Granularity::Day => {
+                        for (k, v) in &db.streams_by_id()[&stream_id].days() {
+                            let bounds = k.bounds();
+                            if bounds.end > q.time.start && bounds.start < q.time.end {
+                                intervals.push(interval(bounds, v));
+                            }
+                        }
+                    }
+                    Granularity::Hour => {
+                        let mut hours = BTreeMap::new();
+                        db.list_recordings_by_time(stream_id, q.time.clone(), &mut |r| {
+                            let d = recording::Duration(i64::from(r.wall_duration_90k));
+                            add_by_hour(
+                                &mut hours,
+                                r.start..r.start + d,
+                                r.sample_file_bytes.into(),
+                            );
+                            Ok(())
+                        })?;
+                        let hour = recording::Duration(60 * 60 * TIME_UNITS_PER_SEC);
+                        for (start, v) in &hours {
+                            let bounds = *start..*start + hour;
+                            if bounds.end > q.time.start && bounds.start < q.time.end {
+                                intervals.push(interval(bounds, v));
+                            }
+                        }
+                    }
+                }
+                out.streams.push(json::StreamUsage {
+                    camera_uuid: camera.uuid,
+                    stream: type_.as_str(),
+                    intervals,
+                });
+            }
+        }
+        serve_json(req, &out)
+    }
+}
+
+fn interval(bounds: Range<recording::Time>, v: &StreamValue) -> json::UsageInterval {
+    json::UsageInterval {
+        start_time_90k: bounds.start,
+        end_time_90k: bounds.end,
+        recordings: v.recordings,
+        total_duration_90k: v.duration,
+        total_sample_file_bytes: v.bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use db::days::StreamValue;
+    use db::recording::{Duration, Time, TIME_UNITS_PER_SEC};
+    use db::testutil;
+
+    use super::{Granularity, Query};
+
+    #[test]
+    fn parse_query() {
+        let q = Query::parse(None).unwrap();
+        assert_eq!(q.granularity, Granularity::Day);
+        let q = Query::parse(Some("granularity=hour&startTime90k=0&endTime90k=90000")).unwrap();
+        assert_eq!(q.granularity, Granularity::Hour);
+        assert_eq!(q.time, Time(0)..Time(90_000));
+        Query::parse(Some("granularity=hour")).unwrap_err();
+        Query::parse(Some("granularity=minute")).unwrap_err();
+    }
+
+    #[test]
+    fn add_by_hour() {
+        testutil::init();
+        let hr = Duration(60 * 60 * TIME_UNITS_PER_SEC);
+        let min = Duration(60 * TIME_UNITS_PER_SEC);
+        let start = Time(130646844000000i64); // 2015-12-31 23:00:00 (Pacific).
+        let mut hours = BTreeMap::new();
+
+        // A recording from 23:59 to 00:02 is split 1:2 between the hours.
+        super::add_by_hour(&mut hours, start + min * 59..start + min * 62, 3001);
+        super::add_by_hour(&mut hours, start..start + min, 1000);
+        assert_eq!(
+            hours,
+            BTreeMap::from([
+                (
+                    start,
+                    StreamValue {
+                        recordings: 2,
+                        duration: min * 2,
+                        bytes: 2000,
+                    }
+                ),
+                (
+                    start + hr,
+                    StreamValue {
+                        recordings: 1,
+                        duration: min * 2,
+                        bytes: 2001,
+                    }
+                ),
+            ])
+        );
+    }
+}
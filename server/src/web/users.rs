@@ -5,13 +5,14 @@
 //! User management: `/api/users/*`.
 
 use base::{bail, err};
+use db::auth;
 use http::{Method, Request, StatusCode};
 
 use crate::json::{self, PutUsersResponse, UserSubset, UserWithId};
 
 use super::{
-    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
-    Caller, ResponseResult, Service,
+    extract_json_body, extract_sid, parse_json_body, path, plain_response, require_csrf_if_session,
+    serve_json, Caller, ResponseResult, Service,
 };
 
 impl Service {
@@ -61,9 +62,18 @@ impl Service {
         if let Some(preferences) = r.user.preferences.take() {
             change.config.preferences = preferences;
         }
+        if let Some(e) = r.user.alert_email.take() {
+            if let Some(ref e) = e {
+                crate::notify::email::check_alert_email(e)?;
+            }
+            change.config.alert_email = e;
+        }
         if let Some(permissions) = r.user.permissions.take() {
             change.permissions = permissions.into();
         }
+        if let Some(d) = r.user.disabled.take() {
+            change.config.disabled = d;
+        }
         if r.user != Default::default() {
             bail!(Unimplemented, msg("unsupported user fields: {r:#?}"));
         }
@@ -111,6 +121,9 @@ impl Service {
         let r = extract_json_body(&mut req).await?;
         let r: json::DeleteUser = parse_json_body(&r)?;
         require_csrf_if_session(&caller, r.csrf)?;
+        if is_self(&caller, id) {
+            bail!(FailedPrecondition, msg("can't delete own user"));
+        }
         let mut l = self.db.lock();
         l.delete_user(id)?;
         Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
@@ -150,6 +163,10 @@ impl Service {
             {
                 bail!(FailedPrecondition, msg("preferences mismatch"));
             }
+            if matches!(precondition.alert_email.take(), Some(ref e) if e != &user.config.alert_email)
+            {
+                bail!(FailedPrecondition, msg("alert_email mismatch"));
+            }
             if let Some(p) = precondition.password.take() {
                 if !user.check_password(p)? {
                     bail!(FailedPrecondition, msg("password mismatch")); // or Unauthenticated?
@@ -176,6 +193,12 @@ impl Service {
             if let Some(preferences) = update.preferences.take() {
                 change.config.preferences = preferences;
             }
+            if let Some(e) = update.alert_email.take() {
+                if let Some(ref e) = e {
+                    crate::notify::email::check_alert_email(e)?;
+                }
+                change.config.alert_email = e;
+            }
             match update.password.take() {
                 None => {}
                 Some(None) => change.clear_password(),
@@ -187,12 +210,21 @@ impl Service {
                 bail!(Unauthenticated, msg("must have admin_users permission"));
             }
             if let Some(d) = update.disabled.take() {
+                if d && is_self(&caller, id) {
+                    bail!(FailedPrecondition, msg("can't disable own user"));
+                }
                 change.config.disabled = d;
             }
             if let Some(n) = update.username.take() {
                 change.username = n.to_string();
             }
             if let Some(permissions) = update.permissions.take() {
+                if !permissions.admin_users && is_self(&caller, id) {
+                    bail!(
+                        FailedPrecondition,
+                        msg("can't remove own admin_users permission")
+                    );
+                }
                 change.permissions = permissions.into();
             }
 
@@ -206,10 +238,119 @@ impl Service {
         }
         Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
     }
+
+    pub(super) async fn user_sessions(
+        &self,
+        req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => self.get_user_sessions(&req, caller, id),
+            Method::DELETE => {
+                self.delete_user_sessions(req, authreq, caller, id, None)
+                    .await
+            }
+            _ => Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "GET, HEAD, or DELETE expected",
+            )),
+        }
+    }
+
+    fn get_user_sessions(
+        &self,
+        req: &Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let current = extract_sid(req).map(|sid| sid.hash());
+        let mut l = self.db.lock();
+        if !l.users_by_id().contains_key(&id) {
+            bail!(NotFound, msg("can't find requested user"));
+        }
+        let sessions = l
+            .user_sessions(id)?
+            .into_iter()
+            .map(|(hash, s)| {
+                json::UserSession::wrap(path::encode_session_id(&hash), s, Some(hash) == current)
+            })
+            .collect();
+        serve_json(req, &json::GetUserSessionsResponse { sessions })
+    }
+
+    pub(super) async fn user_session(
+        &self,
+        req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+        id: i32,
+        hash: auth::SessionHash,
+    ) -> ResponseResult {
+        if *req.method() != Method::DELETE {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        self.delete_user_sessions(req, authreq, caller, id, Some(hash))
+            .await
+    }
+
+    /// Revokes the given session of the given user or, if `which` is `None`, all of the user's
+    /// sessions except the one making this request.
+    async fn delete_user_sessions(
+        &self,
+        mut req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+        id: i32,
+        which: Option<auth::SessionHash>,
+    ) -> ResponseResult {
+        require_same_or_admin(&caller, id)?;
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteUserSessions = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let current = extract_sid(&req).map(|sid| sid.hash());
+        let detail = caller
+            .user
+            .as_ref()
+            .map(|u| format!("revoked by user {:?}", &u.name));
+        let mut l = self.db.lock();
+        let mut hashes: Vec<_> = l
+            .user_sessions(id)?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        match which {
+            Some(h) if !hashes.contains(&h) => bail!(NotFound, msg("no such session")),
+            Some(h) => hashes = vec![h],
+            None => hashes.retain(|&h| Some(h) != current),
+        }
+        for hash in hashes {
+            l.revoke_session(
+                auth::RevocationReason::Revoked,
+                detail.clone(),
+                authreq.clone(),
+                &hash,
+            )?;
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+/// Returns true if the caller is authenticated as the given user.
+///
+/// Admins may not delete, disable, or demote themselves this way, so that a mistake can't
+/// leave nobody able to administer users.
+fn is_self(caller: &Caller, id: i32) -> bool {
+    caller.user.as_ref().map(|u| u.id) == Some(id)
 }
 
 fn require_same_or_admin(caller: &Caller, id: i32) -> Result<(), base::Error> {
-    if caller.user.as_ref().map(|u| u.id) != Some(id) && !caller.permissions.admin_users {
+    if !is_self(caller, id) && !caller.permissions.admin_users {
         bail!(
             Unauthenticated,
             msg("must be authenticated as supplied user or have admin_users permission"),
@@ -217,3 +358,151 @@ fn require_same_or_admin(caller: &Caller, id: i32) -> Result<(), base::Error> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn add_update_delete() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/users", &s.base_url))
+            .json(&serde_json::json!({
+                "user": {
+                    "username": "viewer",
+                    "password": "correct horse battery staple",
+                    "permissions": { "viewVideo": true },
+                    "disabled": true,
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let resp: serde_json::Value = resp.json().await.unwrap();
+        let id = resp["id"].as_i64().unwrap() as i32;
+        {
+            let mut l = s.db.db.lock();
+            let u = l.get_user_by_id_mut(id).unwrap();
+            assert_eq!(u.username, "viewer");
+            assert!(u.config.disabled);
+            assert!(u.permissions.view_video);
+            assert!(!u.permissions.admin_users);
+            assert!(u
+                .check_password(Some("correct horse battery staple"))
+                .unwrap());
+        }
+
+        let user_url = format!("{}/api/users/{}", &s.base_url, id);
+        let resp = cli
+            .patch(&user_url)
+            .json(&serde_json::json!({
+                "update": {
+                    "disabled": false,
+                    "password": "hunter3",
+                    "permissions": { "viewVideo": true, "updateSignals": true },
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        {
+            let mut l = s.db.db.lock();
+            let u = l.get_user_by_id_mut(id).unwrap();
+            assert!(!u.config.disabled);
+            assert!(u.permissions.update_signals);
+            assert!(u.check_password(Some("hunter3")).unwrap());
+        }
+
+        let resp = cli
+            .delete(&user_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(!s.db.db.lock().users_by_id().contains_key(&id));
+    }
+
+    async fn list_sessions(cli: &reqwest::Client, url: &str) -> Vec<serde_json::Value> {
+        let resp = cli.get(url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let mut resp: serde_json::Value = resp.json().await.unwrap();
+        match resp["sessions"].take() {
+            serde_json::Value::Array(a) => a,
+            o => panic!("expected sessions array, got {o:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions() {
+        testutil::init();
+        let mut permissions = db::Permissions::new();
+        permissions.admin_users = true;
+        let s = Server::new(Some(permissions));
+        let id = {
+            let mut l = s.db.db.lock();
+            let id = l.get_user("slamb").unwrap().id;
+            let req = db::auth::Request {
+                when_sec: Some(42),
+                user_agent: Some(b"some ua".to_vec()),
+                addr: None,
+            };
+            for _ in 0..3 {
+                l.login_by_password(req.clone(), "slamb", "hunter2".to_owned(), None, 0)
+                    .unwrap();
+            }
+            id
+        };
+        let cli = reqwest::Client::new();
+        let sessions_url = format!("{}/api/users/{}/sessions", &s.base_url, id);
+        let sessions = list_sessions(&cli, &sessions_url).await;
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0]["creationTimeSec"], 42);
+        assert_eq!(sessions[0]["creationUserAgent"], "some ua");
+
+        let resp = cli
+            .delete(&format!(
+                "{}/{}",
+                &sessions_url,
+                sessions[0]["id"].as_str().unwrap()
+            ))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        let remaining = list_sessions(&cli, &sessions_url).await;
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().all(|r| r["id"] != sessions[0]["id"]));
+
+        // Revoking an already-revoked session fails.
+        let resp = cli
+            .delete(&format!(
+                "{}/{}",
+                &sessions_url,
+                sessions[0]["id"].as_str().unwrap()
+            ))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let resp = cli
+            .delete(&sessions_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(list_sessions(&cli, &sessions_url).await.is_empty());
+    }
+}
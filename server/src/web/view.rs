@@ -2,7 +2,7 @@
 // Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-//! `/view.mp4` and `/view.m4s` handling.
+//! `/view.mp4`, `/view.m4s`, and `/view.ts` handling.
 
 use base::{bail, err};
 use db::recording::{self, rescale};
@@ -20,19 +20,19 @@ use tracing::trace;
 use url::form_urlencoded;
 use uuid::Uuid;
 
-use crate::mp4;
 use crate::web::plain_response;
+use crate::{mkv, mp4, ts};
 
-use super::{Caller, ResponseResult, Service};
+use super::{transcode, Caller, ResponseResult, Service};
 
 impl Service {
-    pub(super) fn stream_view_mp4(
+    pub(super) fn stream_view(
         &self,
         req: &Request<::hyper::Body>,
         caller: Caller,
         uuid: Uuid,
         stream_type: db::StreamType,
-        mp4_type: mp4::Type,
+        container: Container,
         debug: bool,
     ) -> ResponseResult {
         if !caller.permissions.view_video {
@@ -49,16 +49,19 @@ impl Service {
                 .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
         };
         let mut start_time_for_filename = None;
-        let mut builder = mp4::FileBuilder::new(mp4_type);
+        let mut accept_codecs = None;
+        let mut video_sample_entry_ids = Vec::new();
+        let mut builder = Builder::new(req, container)?;
         if let Some(q) = req.uri().query() {
             for (key, value) in form_urlencoded::parse(q.as_bytes()) {
                 let (key, value) = (key.borrow(), value.borrow());
                 match key {
+                    "format" | "timelapse90k" => {} // handled by Builder::new.
                     "s" => {
                         let s = Segments::from_str(value).map_err(|()| {
                             err!(InvalidArgument, msg("invalid s parameter: {value}"))
                         })?;
-                        trace!("stream_view_mp4: appending s={:?}", s);
+                        trace!("stream_view: appending s={:?}", s);
                         let mut est_segments = usize::try_from(s.ids.end - s.ids.start).unwrap();
                         if let Some(end) = s.end_time {
                             // There should be roughly ceil((end - start) /
@@ -125,6 +128,9 @@ impl Service {
                                     wr,
                                     wd
                                 );
+                                if !video_sample_entry_ids.contains(&r.video_sample_entry_id) {
+                                    video_sample_entry_ids.push(r.video_sample_entry_id);
+                                }
                                 if start_time_for_filename.is_none() {
                                     start_time_for_filename =
                                         Some(r.start + recording::Duration(start));
@@ -170,10 +176,54 @@ impl Service {
                         }
                     }
                     "ts" => builder.include_timestamp_subtitle_track(value == "true")?,
+                    "accept_codecs" => {
+                        accept_codecs = Some(transcode::parse_accept_codecs(value));
+                    }
                     _ => bail!(InvalidArgument, msg("parameter {key} not understood")),
                 }
             }
         }
+        let needs_transcode = match accept_codecs {
+            None => false,
+            Some(a) => {
+                let db = self.db.lock();
+                let entries = db.video_sample_entries_by_id();
+                let needs_transcode = video_sample_entry_ids
+                    .iter()
+                    .any(|id| !transcode::accepts(&a, &entries[id].rfc6381_codec));
+                if needs_transcode && !transcode::accepts(&a, transcode::OUTPUT_CODEC) {
+                    bail!(
+                        InvalidArgument,
+                        msg(
+                            "no acceptable codec; transcoding produces {}",
+                            transcode::OUTPUT_CODEC
+                        ),
+                    );
+                }
+                needs_transcode
+            }
+        };
+        if needs_transcode
+            && !matches!(
+                (&builder, container),
+                (Builder::Mp4(_), Container::Mp4(mp4::Type::Normal))
+            )
+        {
+            bail!(
+                InvalidArgument,
+                msg("transcoding is only supported for view.mp4 without format=mkv")
+            );
+        }
+        let transcoder = if needs_transcode {
+            Some(self.transcoder.as_ref().ok_or_else(|| {
+                err!(
+                    FailedPrecondition,
+                    msg("transcoding requires a [transcode] config section")
+                )
+            })?)
+        } else {
+            None
+        };
         if let Some(start) = start_time_for_filename {
             let tm = time::at(time::Timespec {
                 sec: start.unix_seconds(),
@@ -184,10 +234,11 @@ impl Service {
             } else {
                 "sub"
             };
-            let suffix = if mp4_type == mp4::Type::Normal {
-                "mp4"
-            } else {
-                "m4s"
+            let suffix = match (&builder, container) {
+                (Builder::Mkv(_), _) => "mkv",
+                (Builder::Ts(_), _) => "ts",
+                (Builder::Mp4(_), Container::Mp4(mp4::Type::Normal)) => "mp4",
+                (Builder::Mp4(_), _) => "m4s",
             };
             builder.set_filename(&format!(
                 "{}-{}-{}.{}",
@@ -197,11 +248,135 @@ impl Service {
                 suffix
             ))?;
         }
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
-        if debug {
-            return Ok(plain_response(StatusCode::OK, format!("{mp4:#?}")));
+        match builder {
+            Builder::Mp4(b) => {
+                let mp4 = b.build(self.db.clone(), self.dirs_by_id())?;
+                if debug {
+                    return Ok(plain_response(StatusCode::OK, format!("{mp4:#?}")));
+                }
+                if let Some(t) = transcoder {
+                    return t.serve(mp4);
+                }
+                Ok(http_serve::serve(mp4, req))
+            }
+            Builder::Mkv(b) => {
+                let mkv = b.build(self.db.clone(), self.dirs_by_id())?;
+                if debug {
+                    return Ok(plain_response(StatusCode::OK, format!("{mkv:#?}")));
+                }
+                Ok(http_serve::serve(mkv, req))
+            }
+            Builder::Ts(b) => {
+                let ts = b.build(self.db.clone(), self.dirs_by_id())?;
+                if debug {
+                    return Ok(plain_response(StatusCode::OK, format!("{ts:#?}")));
+                }
+                Ok(http_serve::serve(ts, req))
+            }
+        }
+    }
+}
+
+/// The container requested by the path.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Container {
+    /// `/view.mp4` or `/view.m4s`. The former can be overridden with `format=mkv`.
+    Mp4(mp4::Type),
+
+    /// `/view.ts`.
+    Ts,
+}
+
+/// A builder for the container format selected by the path and `format` query parameter.
+enum Builder {
+    Mp4(mp4::FileBuilder),
+    Mkv(mkv::FileBuilder),
+    Ts(ts::FileBuilder),
+}
+
+/// The presentation duration of each frame in a timelapse: 30 frames per second.
+const TIMELAPSE_FRAME_DURATION_90K: i32 = 3000;
+
+impl Builder {
+    fn new(req: &Request<::hyper::Body>, container: Container) -> Result<Self, base::Error> {
+        let param = |name: &str| {
+            req.uri().query().and_then(|q| {
+                form_urlencoded::parse(q.as_bytes())
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value)
+            })
+        };
+        let mut builder = Self::new_for_format(container, param("format").as_deref())?;
+        if let Some(interval) = param("timelapse90k") {
+            let interval = i64::from_str(&interval)
+                .map_err(|_| err!(InvalidArgument, msg("unparseable timelapse90k")))?;
+            match &mut builder {
+                Builder::Mp4(b) => {
+                    b.timelapse(recording::Duration(interval), TIMELAPSE_FRAME_DURATION_90K)?
+                }
+                Builder::Mkv(_) | Builder::Ts(_) => bail!(
+                    InvalidArgument,
+                    msg("timelapses are only supported in .mp4 files")
+                ),
+            }
+        }
+        Ok(builder)
+    }
+
+    fn new_for_format(container: Container, format: Option<&str>) -> Result<Self, base::Error> {
+        match (container, format) {
+            (Container::Mp4(t), None | Some("mp4")) => Ok(Builder::Mp4(mp4::FileBuilder::new(t))),
+            (Container::Mp4(mp4::Type::Normal), Some("mkv")) => {
+                Ok(Builder::Mkv(mkv::FileBuilder::new()))
+            }
+            (Container::Ts, None | Some("ts")) => Ok(Builder::Ts(ts::FileBuilder::new())),
+            (_, Some("mkv")) => bail!(
+                InvalidArgument,
+                msg("format=mkv is only supported on view.mp4")
+            ),
+            (_, Some(f)) => bail!(InvalidArgument, msg("unsupported format {f:?}")),
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        match self {
+            Builder::Mp4(b) => b.reserve(additional),
+            Builder::Mkv(b) => b.reserve(additional),
+            Builder::Ts(b) => b.reserve(additional),
+        }
+    }
+
+    fn append(
+        &mut self,
+        db: &db::LockedDatabase,
+        row: db::ListRecordingsRow,
+        rel_media_range_90k: Range<i32>,
+        start_at_key: bool,
+    ) -> Result<(), base::Error> {
+        match self {
+            Builder::Mp4(b) => b.append(db, row, rel_media_range_90k, start_at_key),
+            Builder::Mkv(b) => b.append(db, row, rel_media_range_90k, start_at_key),
+            Builder::Ts(b) => b.append(db, row, rel_media_range_90k, start_at_key),
+        }
+    }
+
+    fn include_timestamp_subtitle_track(&mut self, b: bool) -> Result<(), base::Error> {
+        match self {
+            Builder::Mp4(m) => m.include_timestamp_subtitle_track(b),
+            Builder::Mkv(_) | Builder::Ts(_) if b => bail!(
+                InvalidArgument,
+                msg("timestamp subtitles are only supported in .mp4 files")
+            ),
+            Builder::Mkv(_) | Builder::Ts(_) => Ok(()),
+        }
+    }
+
+    fn set_filename(&mut self, filename: &str) -> Result<(), base::Error> {
+        match self {
+            Builder::Mp4(b) => b.set_filename(filename),
+            Builder::Mkv(b) => b.set_filename(filename),
+            Builder::Ts(b) => b.set_filename(filename),
         }
-        Ok(http_serve::serve(mp4, req))
     }
 }
 
@@ -0,0 +1,470 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! WebAuthn (passkey) registration and login: `/api/webauthn/*`.
+//!
+//! Registration and login are each two-step ceremonies. The `start` request returns options for
+//! the browser to pass to `navigator.credentials`; the `finish` request returns the browser's
+//! response for verification. Ceremonies in progress are kept only in RAM.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base::{bail, err, Error, FastHashMap};
+use db::auth;
+use http::{Method, Request, StatusCode};
+use ulid::Ulid;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CredentialID, Passkey, PasskeyAuthentication, PasskeyRegistration, Url, Webauthn,
+    WebauthnBuilder,
+};
+
+use crate::json;
+
+use super::session::session_response;
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+
+/// How long the browser has to complete a ceremony.
+const CEREMONY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of ceremonies in progress at once, as `login/start` requires no
+/// authentication.
+const MAX_CEREMONIES: usize = 1024;
+
+enum CeremonyState {
+    Register(PasskeyRegistration),
+    Login(PasskeyAuthentication),
+}
+
+struct Ceremony {
+    user_id: i32,
+    start: Instant,
+    state: CeremonyState,
+}
+
+/// Shared WebAuthn state for a [`Service`].
+pub(super) struct WebAuthn {
+    /// The relying party, or `None` if passkeys are disabled.
+    webauthn: Option<Webauthn>,
+
+    ceremonies: Mutex<FastHashMap<Ulid, Ceremony>>,
+}
+
+impl WebAuthn {
+    pub(super) fn new(
+        config: Option<&crate::cmds::run::config::WebAuthnConfig>,
+    ) -> Result<Self, Error> {
+        let webauthn = match config {
+            None => None,
+            Some(c) => {
+                let origin = Url::parse(&c.origin).map_err(|e| {
+                    err!(
+                        InvalidArgument,
+                        msg("bad webauthn origin {:?}", c.origin),
+                        source(e)
+                    )
+                })?;
+                let webauthn = WebauthnBuilder::new(&c.rp_id, &origin)
+                    .and_then(|b| b.rp_name("Moonfire NVR").build())
+                    .map_err(|e| err!(InvalidArgument, msg("bad webauthn config"), source(e)))?;
+                Some(webauthn)
+            }
+        };
+        Ok(WebAuthn {
+            webauthn,
+            ceremonies: Mutex::new(FastHashMap::default()),
+        })
+    }
+
+    fn get(&self) -> Result<&Webauthn, Error> {
+        self.webauthn.as_ref().ok_or_else(|| {
+            err!(
+                FailedPrecondition,
+                msg("passkeys are disabled; set webauthn in the config file")
+            )
+        })
+    }
+
+    /// Records a ceremony in progress, returning its id.
+    fn insert(&self, user_id: i32, state: CeremonyState) -> Result<Ulid, Error> {
+        let mut l = self.ceremonies.lock().unwrap();
+        l.retain(|_, c| c.start.elapsed() < CEREMONY_TIMEOUT);
+        if l.len() >= MAX_CEREMONIES {
+            bail!(
+                ResourceExhausted,
+                msg("too many passkey ceremonies in progress")
+            );
+        }
+        let id = Ulid::new();
+        l.insert(
+            id,
+            Ceremony {
+                user_id,
+                start: Instant::now(),
+                state,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Removes and returns the given ceremony, which can be used only once.
+    fn take(&self, id: &str) -> Result<Ceremony, Error> {
+        let id = Ulid::from_string(id)
+            .map_err(|_| err!(InvalidArgument, msg("bad challenge id {id:?}")))?;
+        match self.ceremonies.lock().unwrap().remove(&id) {
+            Some(c) if c.start.elapsed() < CEREMONY_TIMEOUT => Ok(c),
+            _ => bail!(
+                Unauthenticated,
+                msg("no such challenge {id}; it may have expired")
+            ),
+        }
+    }
+}
+
+/// Returns the WebAuthn user handle for the given user.
+///
+/// Moonfire NVR always asks for a username before a passkey login, so this is simply derived
+/// from the user id rather than stored.
+fn user_handle(user_id: i32) -> Uuid {
+    Uuid::from_u128(user_id as u128)
+}
+
+fn parse_passkey(c: &db::Credential) -> Result<Passkey, Error> {
+    serde_json::from_str(&c.passkey).map_err(|e| {
+        err!(
+            DataLoss,
+            msg("unable to parse stored passkey {}", c.id),
+            source(e)
+        )
+    })
+}
+
+fn encode_passkey(passkey: &Passkey) -> Result<String, Error> {
+    serde_json::to_string(passkey)
+        .map_err(|e| err!(Internal, msg("unable to encode passkey"), source(e)))
+}
+
+impl Service {
+    pub(super) async fn webauthn_register_start(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::WebAuthnRegisterStart = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let user_id = session_user_id(&caller)?;
+        let webauthn = self.webauthn.get()?;
+        let (options, state) = {
+            let l = self.db.lock();
+            let username = &l.users_by_id()[&user_id].username;
+            let exclude: Vec<CredentialID> = l
+                .credentials_by_id()
+                .values()
+                .filter(|c| c.user_id == user_id)
+                .map(|c| c.credential_id.clone().into())
+                .collect();
+            webauthn
+                .start_passkey_registration(user_handle(user_id), username, username, Some(exclude))
+                .map_err(|e| {
+                    err!(
+                        Internal,
+                        msg("unable to start passkey registration"),
+                        source(e)
+                    )
+                })?
+        };
+        let id = self
+            .webauthn
+            .insert(user_id, CeremonyState::Register(state))?;
+        serve_json(
+            &req,
+            &json::WebAuthnChallenge {
+                challenge_id: id.to_string(),
+                options,
+            },
+        )
+    }
+
+    pub(super) async fn webauthn_register_finish(
+        &self,
+        mut req: Request<hyper::Body>,
+        authreq: &auth::Request,
+        caller: Caller,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::WebAuthnRegisterFinish = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let user_id = session_user_id(&caller)?;
+        let webauthn = self.webauthn.get()?;
+        let ceremony = self.webauthn.take(r.challenge_id)?;
+        let CeremonyState::Register(state) = ceremony.state else {
+            bail!(InvalidArgument, msg("challenge is not for registration"));
+        };
+        if ceremony.user_id != user_id {
+            bail!(PermissionDenied, msg("challenge is for another user"));
+        }
+        let passkey = webauthn
+            .finish_passkey_registration(&r.credential, &state)
+            .map_err(|e| {
+                err!(
+                    InvalidArgument,
+                    msg("passkey registration failed"),
+                    source(e)
+                )
+            })?;
+        let now = authreq
+            .when_sec
+            .ok_or_else(|| err!(Internal, msg("request has no time")))?;
+        let id = self
+            .db
+            .lock()
+            .add_credential(
+                user_id,
+                passkey.cred_id().to_vec(),
+                r.description,
+                now,
+                encode_passkey(&passkey)?,
+            )?
+            .id;
+        serve_json(&req, &json::WebAuthnRegisterFinishResponse { id })
+    }
+
+    pub(super) async fn webauthn_login_start(
+        &self,
+        mut req: Request<hyper::Body>,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::WebAuthnLoginStart = parse_json_body(&r)?;
+        let webauthn = self.webauthn.get()?;
+        let (user_id, passkeys) = {
+            let l = self.db.lock();
+            let Some(u) = l.get_user(r.username) else {
+                bail!(
+                    Unauthenticated,
+                    msg("no passkeys for user {:?}", r.username)
+                );
+            };
+            let passkeys = l
+                .credentials_by_id()
+                .values()
+                .filter(|c| c.user_id == u.id)
+                .map(parse_passkey)
+                .collect::<Result<Vec<_>, _>>()?;
+            (u.id, passkeys)
+        };
+        if passkeys.is_empty() {
+            bail!(
+                Unauthenticated,
+                msg("no passkeys for user {:?}", r.username)
+            );
+        }
+        let (options, state) = webauthn
+            .start_passkey_authentication(&passkeys)
+            .map_err(|e| {
+                err!(
+                    Internal,
+                    msg("unable to start passkey authentication"),
+                    source(e)
+                )
+            })?;
+        let id = self.webauthn.insert(user_id, CeremonyState::Login(state))?;
+        serve_json(
+            &req,
+            &json::WebAuthnChallenge {
+                challenge_id: id.to_string(),
+                options,
+            },
+        )
+    }
+
+    pub(super) async fn webauthn_login_finish(
+        &self,
+        mut req: Request<hyper::Body>,
+        authreq: auth::Request,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::WebAuthnLoginFinish = parse_json_body(&r)?;
+        let webauthn = self.webauthn.get()?;
+        let ceremony = self.webauthn.take(r.challenge_id)?;
+        let CeremonyState::Login(state) = ceremony.state else {
+            bail!(InvalidArgument, msg("challenge is not for login"));
+        };
+        let (domain, flags) = self.session_params(&req)?;
+        let result = webauthn
+            .finish_passkey_authentication(&r.credential, &state)
+            .map_err(|e| {
+                err!(
+                    Unauthenticated,
+                    msg("passkey authentication failed"),
+                    source(e)
+                )
+            });
+        let mut l = self.db.lock();
+        let result = result.and_then(|result| {
+            let Some(c) = l.credentials_by_id().values().find(|c| {
+                c.user_id == ceremony.user_id && c.credential_id[..] == result.cred_id()[..]
+            }) else {
+                bail!(Unauthenticated, msg("passkey has been removed"));
+            };
+            let id = c.id;
+
+            // Store the updated signature counter and backup state.
+            let mut passkey = parse_passkey(c)?;
+            passkey.update_credential(&result);
+            let (sid, _) = l.login_by_credential(
+                authreq.clone(),
+                id,
+                encode_passkey(&passkey)?,
+                Some(domain),
+                flags,
+            )?;
+            Ok(sid)
+        });
+        let username = l
+            .users_by_id()
+            .get(&ceremony.user_id)
+            .map(|u| u.username.as_str());
+        self.audit_login(
+            &l,
+            &authreq,
+            (Some(ceremony.user_id), username),
+            "passkey",
+            result.as_ref().map(|_| ()),
+        );
+        Ok(session_response(result?, flags))
+    }
+
+    pub(super) async fn webauthn_credentials(
+        &self,
+        req: Request<hyper::Body>,
+        caller: Caller,
+    ) -> ResponseResult {
+        match *req.method() {
+            Method::GET | Method::HEAD => {}
+            _ => {
+                return Ok(plain_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "GET or HEAD expected",
+                ))
+            }
+        }
+
+        // Lists the caller's own passkeys, or all passkeys if the caller has `admin_users`.
+        let caller_id = caller.user.as_ref().map(|u| u.id);
+        let l = self.db.lock();
+        let credentials = l
+            .credentials_by_id()
+            .values()
+            .filter(|c| caller.permissions.admin_users || Some(c.user_id) == caller_id)
+            .map(json::Credential::wrap)
+            .collect();
+        serve_json(&req, &json::GetCredentialsResponse { credentials })
+    }
+
+    pub(super) async fn webauthn_credential(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        id: i32,
+    ) -> ResponseResult {
+        if *req.method() != Method::DELETE {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "DELETE expected",
+            ));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::DeleteCredential = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+        let mut l = self.db.lock();
+        let Some(c) = l.credentials_by_id().get(&id) else {
+            bail!(NotFound, msg("no such passkey {id}"));
+        };
+        if !caller.permissions.admin_users && Some(c.user_id) != caller.user.as_ref().map(|u| u.id)
+        {
+            bail!(
+                PermissionDenied,
+                msg("admin_users required to delete another user's passkey")
+            );
+        }
+        l.delete_credential(id)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, &b""[..]))
+    }
+}
+
+/// Returns the id of the caller's user, who must be authenticated via session.
+///
+/// API tokens can't register passkeys, as a passkey grants all of its user's permissions.
+fn session_user_id(caller: &Caller) -> Result<i32, Error> {
+    match caller.user.as_ref() {
+        Some(u) if u.session.is_some() => Ok(u.id),
+        Some(_) => bail!(
+            PermissionDenied,
+            msg("passkeys must be registered from a login session")
+        ),
+        None => bail!(
+            Unauthenticated,
+            msg("must be logged in to register a passkey")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::web::tests::Server;
+    use db::testutil;
+
+    #[tokio::test]
+    async fn disabled() {
+        testutil::init();
+        let s = Server::new(None);
+        let cli = reqwest::Client::new();
+        let resp = cli
+            .post(&format!("{}/api/webauthn/login/start", &s.base_url))
+            .json(&serde_json::json!({ "username": "slamb" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn ceremonies() {
+        let w = super::WebAuthn::new(None).unwrap();
+        assert!(w.get().is_err());
+        let e = w.take("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::Unauthenticated);
+        let e = w.take("asdf").unwrap_err();
+        assert_eq!(e.kind(), base::ErrorKind::InvalidArgument);
+    }
+}
@@ -0,0 +1,433 @@
+// This file is part of Moonfire NVR, a security camera network video recorder.
+// Copyright (C) 2024 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
+// SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
+
+//! WebRTC live view: `/api/cameras/<uuid>/<type>/webrtc` handling.
+//!
+//! The client POSTs an SDP offer and receives an SDP answer with all ICE candidates included
+//! (no trickle ICE). Once the peer connection is established, H.264 access units are forwarded
+//! from the streamer as soon as they're received, rather than after being written to a sample
+//! file as with `live.m4s`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::webrtc::api::interceptor_registry::register_default_interceptors;
+use ::webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use ::webrtc::api::{APIBuilder, API};
+use ::webrtc::ice_transport::ice_server::RTCIceServer;
+use ::webrtc::interceptor::registry::Registry;
+use ::webrtc::media::Sample;
+use ::webrtc::peer_connection::configuration::RTCConfiguration;
+use ::webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use ::webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use ::webrtc::peer_connection::RTCPeerConnection;
+use ::webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use ::webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use ::webrtc::track::track_local::TrackLocal;
+use base::{bail, err, Error};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use http::{Method, Request, StatusCode};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, Instrument};
+use uuid::Uuid;
+
+use crate::json;
+use crate::streamer::LiveFrames;
+
+use super::{
+    extract_json_body, parse_json_body, plain_response, require_csrf_if_session, serve_json,
+    Caller, ResponseResult, Service,
+};
+
+const START_CODE: &[u8] = b"\x00\x00\x00\x01";
+
+/// How long to wait for a peer to connect after sending it an answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shared WebRTC state for a [`Service`].
+pub(super) struct WebRtc {
+    api: API,
+    ice_servers: Vec<RTCIceServer>,
+}
+
+impl WebRtc {
+    pub(super) fn new(ice_servers: &[crate::cmds::run::config::IceServer]) -> Result<Self, Error> {
+        let mut media_engine = MediaEngine::default();
+        media_engine
+            .register_default_codecs()
+            .map_err(|e| err!(Internal, msg("unable to register WebRTC codecs"), source(e)))?;
+        let registry =
+            register_default_interceptors(Registry::new(), &mut media_engine).map_err(|e| {
+                err!(
+                    Internal,
+                    msg("unable to register WebRTC interceptors"),
+                    source(e)
+                )
+            })?;
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+        Ok(WebRtc {
+            api,
+            ice_servers: ice_servers
+                .iter()
+                .map(|s| RTCIceServer {
+                    urls: s.urls.clone(),
+                    username: s.username.clone(),
+                    credential: s.credential.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Service {
+    pub(super) async fn stream_webrtc(
+        &self,
+        mut req: Request<hyper::Body>,
+        caller: Caller,
+        uuid: Uuid,
+        stream_type: db::StreamType,
+    ) -> ResponseResult {
+        if *req.method() != Method::POST {
+            return Ok(plain_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "POST expected",
+            ));
+        }
+        if !caller.permissions.view_video {
+            bail!(PermissionDenied, msg("view_video required"));
+        }
+        let r = extract_json_body(&mut req).await?;
+        let r: json::PostWebRtcRequest = parse_json_body(&r)?;
+        require_csrf_if_session(&caller, r.csrf)?;
+
+        let stream_id = {
+            let db = self.db.lock();
+            if db.open.is_none() {
+                bail!(
+                    FailedPrecondition,
+                    msg("database is read-only; there are no live streams"),
+                );
+            }
+            let camera = db
+                .get_camera(uuid)
+                .ok_or_else(|| err!(NotFound, msg("no such camera {uuid}")))?;
+            let stream_id = camera.streams[stream_type.index()]
+                .ok_or_else(|| err!(NotFound, msg("no such stream {uuid}/{stream_type}")))?;
+            let stream = db
+                .streams_by_id()
+                .get(&stream_id)
+                .expect("stream_id refed by camera");
+            if !stream.config.is_recording() {
+                bail!(
+                    FailedPrecondition,
+                    msg("stream {uuid}/{stream_type} isn't running")
+                );
+            }
+            stream_id
+        };
+
+        let pc = Arc::new(
+            self.webrtc
+                .api
+                .new_peer_connection(RTCConfiguration {
+                    ice_servers: self.webrtc.ice_servers.clone(),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| err!(Internal, msg("unable to create peer connection"), source(e)))?,
+        );
+        let (state_tx, state_rx) = watch::channel(RTCPeerConnectionState::New);
+        pc.on_peer_connection_state_change(Box::new(move |s| {
+            debug!(state = %s, "WebRTC peer connection state changed");
+            let _ = state_tx.send(s);
+            Box::pin(async {})
+        }));
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90_000,
+                ..Default::default()
+            },
+            "video".to_owned(),
+            format!("{uuid}-{stream_type}"),
+        ));
+        let answer = match negotiate(&pc, &track, r.offer).await {
+            Ok(a) => a,
+            Err(e) => {
+                let _ = pc.close().await;
+                return Err(e.into());
+            }
+        };
+
+        let forwarder = Forwarder {
+            db: self.db.clone(),
+            live_frames: self.live_frames.clone(),
+            stream_id,
+            track,
+            state_rx,
+        };
+        tokio::spawn(
+            async move {
+                if let Err(e) = forwarder.run().await {
+                    debug!(err = %e.chain(), "WebRTC session failed");
+                }
+                let _ = pc.close().await;
+            }
+            .in_current_span(),
+        );
+        serve_json(&req, &json::PostWebRtcResponse { answer })
+    }
+}
+
+/// Adds `track` to `pc` and answers `offer`, returning the answer's SDP.
+async fn negotiate(
+    pc: &RTCPeerConnection,
+    track: &Arc<TrackLocalStaticSample>,
+    offer: String,
+) -> Result<String, Error> {
+    let sender = pc
+        .add_track(Arc::clone(track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .map_err(|e| err!(Internal, msg("unable to add track"), source(e)))?;
+
+    // Incoming RTCP packets must be read for the interceptors (NACK handling, etc.) to work.
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while sender.read(&mut buf).await.is_ok() {}
+    });
+
+    let offer = RTCSessionDescription::offer(offer)
+        .map_err(|e| err!(InvalidArgument, msg("bad SDP offer"), source(e)))?;
+    pc.set_remote_description(offer).await.map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("unable to accept SDP offer"),
+            source(e)
+        )
+    })?;
+    let answer = pc.create_answer(None).await.map_err(|e| {
+        err!(
+            InvalidArgument,
+            msg("unable to create SDP answer"),
+            source(e)
+        )
+    })?;
+
+    // Gather all ICE candidates before answering, so the client doesn't need a separate
+    // channel to trickle them in.
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer)
+        .await
+        .map_err(|e| err!(Internal, msg("unable to set local description"), source(e)))?;
+    let _ = gathering_complete.recv().await;
+    let answer = pc
+        .local_description()
+        .await
+        .ok_or_else(|| err!(Internal, msg("no local description after gathering")))?;
+    Ok(answer.sdp)
+}
+
+/// Forwards frames from a stream to a WebRTC peer for the life of the peer connection.
+struct Forwarder {
+    db: Arc<db::Database>,
+    live_frames: Arc<LiveFrames>,
+    stream_id: i32,
+    track: Arc<TrackLocalStaticSample>,
+    state_rx: watch::Receiver<RTCPeerConnectionState>,
+}
+
+impl Forwarder {
+    async fn run(mut self) -> Result<(), Error> {
+        // Wait for the connection before subscribing, so the peer's first frame is a fresh key
+        // frame rather than one which has been queued during negotiation.
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            loop {
+                match *self.state_rx.borrow_and_update() {
+                    RTCPeerConnectionState::Connected => return true,
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                        return false
+                    }
+                    _ => {}
+                }
+                if self.state_rx.changed().await.is_err() {
+                    return false;
+                }
+            }
+        })
+        .await
+        .map_err(|_| err!(DeadlineExceeded, msg("peer didn't connect")))?;
+        if !connected {
+            return Ok(());
+        }
+
+        let mut frames = self.live_frames.subscribe(self.stream_id);
+
+        // Annex B SPS and PPS, to be prepended to each key frame, and the video sample entry id
+        // they were taken from.
+        let mut parameter_sets: Option<(i32, Vec<u8>)> = None;
+        let mut prev_pts_90k: Option<i64> = None;
+        loop {
+            let frame = tokio::select! {
+                r = self.state_rx.changed() => {
+                    // Disconnected is transient; the connection may recover.
+                    if r.is_err() || matches!(
+                        *self.state_rx.borrow(),
+                        RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                    ) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                f = frames.recv() => match f {
+                    Ok(f) => f,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!(skipped, "WebRTC peer fell behind; waiting for next key frame");
+                        prev_pts_90k = None;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            };
+            if prev_pts_90k.is_none() && !frame.is_key {
+                continue;
+            }
+            let prefix: &[u8] = if frame.is_key {
+                let id = frame.video_sample_entry_id;
+                if parameter_sets.as_ref().map(|p| p.0) != Some(id) {
+                    let sets = {
+                        let l = self.db.lock();
+                        let entry = l
+                            .video_sample_entries_by_id()
+                            .get(&id)
+                            .ok_or_else(|| err!(Internal, msg("no video sample entry {id}")))?;
+                        annex_b_parameter_sets(entry)?
+                    };
+                    parameter_sets = Some((id, sets));
+                }
+                &parameter_sets.as_ref().expect("set above").1[..]
+            } else {
+                &[]
+            };
+            let data = to_annex_b(prefix, &frame.data)?;
+
+            // The track advances the RTP timestamp by each sample's duration. The true duration
+            // isn't known until the next frame arrives, so use the previous frame's duration
+            // instead. This assumes a roughly constant frame rate but adds no latency.
+            let duration_90k = prev_pts_90k
+                .map(|p| frame.pts_90k - p)
+                .filter(|&d| d > 0)
+                .unwrap_or(0);
+            prev_pts_90k = Some(frame.pts_90k);
+            self.track
+                .write_sample(&Sample {
+                    data,
+                    duration: Duration::from_nanos(duration_90k as u64 * 100_000 / 9),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| err!(Unavailable, msg("unable to write sample"), source(e)))?;
+        }
+    }
+}
+
+/// Returns the SPS and PPS NAL units within an `avc1` sample entry, in Annex B format.
+fn annex_b_parameter_sets(entry: &db::VideoSampleEntry) -> Result<Vec<u8>, Error> {
+    if entry.box_type() != b"avc1" {
+        bail!(Unimplemented, msg("WebRTC live view supports only H.264"));
+    }
+    let record = entry.decoder_config()?;
+    let record =
+        h264_reader::avcc::AvcDecoderConfigurationRecord::try_from(record).map_err(|e| {
+            err!(
+                InvalidArgument,
+                msg("bad AvcDecoderConfigurationRecord: {:?}", e)
+            )
+        })?;
+    let mut out = Vec::new();
+    for nal in record
+        .sequence_parameter_sets()
+        .chain(record.picture_parameter_sets())
+    {
+        let nal = nal.map_err(|e| err!(InvalidArgument, msg("bad parameter set: {:?}", e)))?;
+        out.extend_from_slice(START_CODE);
+        out.extend_from_slice(nal);
+    }
+    Ok(out)
+}
+
+/// Converts an access unit from AVC (length-prefixed) to Annex B (start code-prefixed) format,
+/// after the given Annex B `prefix`.
+fn to_annex_b(prefix: &[u8], mut data: &[u8]) -> Result<Bytes, Error> {
+    let mut out = Vec::with_capacity(prefix.len() + data.len());
+    out.extend_from_slice(prefix);
+    while !data.is_empty() {
+        if data.len() < 4 {
+            bail!(InvalidArgument, msg("truncated NAL length"));
+        }
+        let len = BigEndian::read_u32(&data[0..4]) as usize;
+        let nal = data.get(4..4 + len).ok_or_else(|| {
+            err!(
+                InvalidArgument,
+                msg(
+                    "NAL length {} exceeds remaining {} bytes",
+                    len,
+                    data.len() - 4
+                )
+            )
+        })?;
+        out.extend_from_slice(START_CODE);
+        out.extend_from_slice(nal);
+        data = &data[4 + len..];
+    }
+    Ok(Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::h264::AVC_DECODER_CONFIG_TEST_INPUT;
+    use db::testutil;
+
+    #[test]
+    fn parameter_sets() {
+        testutil::init();
+        let e = crate::h264::parse_extra_data(&AVC_DECODER_CONFIG_TEST_INPUT).unwrap();
+        let e = db::VideoSampleEntry {
+            id: 1,
+            data: e.data,
+            rfc6381_codec: e.rfc6381_codec,
+            width: e.width,
+            height: e.height,
+            pasp_h_spacing: e.pasp_h_spacing,
+            pasp_v_spacing: e.pasp_v_spacing,
+        };
+        let sets = super::annex_b_parameter_sets(&e).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"\x00\x00\x00\x01");
+        expected.extend_from_slice(&AVC_DECODER_CONFIG_TEST_INPUT[8..31]);
+        expected.extend_from_slice(b"\x00\x00\x00\x01");
+        expected.extend_from_slice(&AVC_DECODER_CONFIG_TEST_INPUT[34..38]);
+        assert_eq!(sets, expected);
+    }
+
+    #[test]
+    fn to_annex_b() {
+        testutil::init();
+        let out = super::to_annex_b(
+            b"\x00\x00\x00\x01\x67",
+            b"\x00\x00\x00\x02\x65\x88\x00\x00\x00\x01\x06",
+        )
+        .unwrap();
+        assert_eq!(
+            &out[..],
+            b"\x00\x00\x00\x01\x67\x00\x00\x00\x01\x65\x88\x00\x00\x00\x01\x06"
+        );
+        super::to_annex_b(b"", b"\x00\x00\x00\x05\x65").unwrap_err();
+        super::to_annex_b(b"", b"\x00\x00").unwrap_err();
+    }
+}
@@ -2,8 +2,8 @@
 // Copyright (C) 2021 The Moonfire NVR Authors; see AUTHORS and LICENSE.txt.
 // SPDX-License-Identifier: GPL-v3.0-or-later WITH GPL-3.0-linking-exception.
 
-//! Common code for WebSockets, including the live view WebSocket and a future
-//! WebSocket for watching database changes.
+//! Common code for WebSockets, including the live view WebSocket and the
+//! `/api/events` WebSocket for watching state changes.
 
 use std::pin::Pin;
 